@@ -9,7 +9,7 @@ use std::sync::Arc;
 
 use bytes::Bytes;
 use failure::{err_msg, Error};
-use futures::{Future, IntoFuture};
+use futures::{future, Future, IntoFuture};
 use futures::sync::oneshot;
 use futures_ext::{BoxFuture, FutureExt};
 use slog::Logger;
@@ -59,19 +59,25 @@ impl MononokeRepo {
                     "Missing myrouter port, unable to open BlobManifold repo",
                 )).into_future()
                     .left_future(),
-                Some(myrouter_port) => myrouter::wait_for_myrouter(myrouter_port, &args.db_address)
-                    .and_then({
-                        cloned!(logger);
-                        move |()| {
-                            BlobRepo::new_manifold_no_postcommit(
-                                logger,
-                                &args,
-                                repoid,
-                                myrouter_port,
-                            )
-                        }
-                    })
-                    .right_future(),
+                Some(myrouter_port) => {
+                    let myrouter_ready = args.distinct_db_addresses()
+                        .into_iter()
+                        .map(|db_address| myrouter::wait_for_myrouter(myrouter_port, db_address))
+                        .collect::<Vec<_>>();
+                    future::join_all(myrouter_ready)
+                        .and_then({
+                            cloned!(logger);
+                            move |_| {
+                                BlobRepo::new_manifold_no_postcommit(
+                                    logger,
+                                    &args,
+                                    repoid,
+                                    myrouter_port,
+                                )
+                            }
+                        })
+                        .right_future()
+                }
             },
             _ => Err(err_msg("Unsupported repo type."))
                 .into_future()
@@ -24,6 +24,7 @@ extern crate cmdlib;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate fb303;
+extern crate fd_accounting;
 extern crate futures;
 #[macro_use]
 extern crate futures_ext;
@@ -62,6 +63,7 @@ mod from_string;
 mod middleware;
 mod thrift;
 
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -93,6 +95,28 @@ use errors::ErrorKind;
 
 mod config {
     pub const SCUBA_TABLE: &str = "mononoke_apiserver";
+    pub const DEFAULT_FD_WARNING_FRACTION: f64 = 0.8;
+}
+
+/// Open file descriptors of the server, per component
+#[derive(Serialize)]
+struct FdUsage {
+    total: usize,
+    components: BTreeMap<&'static str, usize>,
+}
+
+impl FdUsage {
+    fn current() -> Self {
+        let registry = fd_accounting::fd_registry();
+        FdUsage {
+            total: registry.total(),
+            components: registry
+                .usage()
+                .into_iter()
+                .map(|(component, count)| (component.name(), count))
+                .collect(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -385,6 +409,12 @@ fn main() -> Result<()> {
                     .long("myrouter-port")
                     .value_name("PORT")
                     .help("port for local myrouter instance"),
+            )
+            .arg(
+                Arg::with_name("fd-warning-fraction")
+                    .long("fd-warning-fraction")
+                    .value_name("FRACTION")
+                    .help("fraction of the open files limit to warn at"),
             ),
         false, /* hide_advanced_args */
     ).get_matches();
@@ -408,9 +438,17 @@ fn main() -> Result<()> {
         None => None,
     };
 
+    let fd_warning_fraction = match matches.value_of("fd-warning-fraction") {
+        Some(fraction) => fraction
+            .parse::<f64>()
+            .expect("Provided --fd-warning-fraction is not a number"),
+        None => config::DEFAULT_FD_WARNING_FRACTION,
+    };
+
     let address = format!("{}:{}", host, port);
 
     let root_logger = setup_logger(debug);
+    fd_accounting::init_fd_warning(&root_logger, fd_warning_fraction);
     let actix_logger = root_logger.clone();
     let mononoke_logger = root_logger.clone();
     let thrift_logger = root_logger.clone();
@@ -504,6 +542,14 @@ fn main() -> Result<()> {
                     HttpResponse::Ok().body("ok")
                 },
             )
+            .route(
+                "/status/fds",
+                http::Method::GET,
+                |req: HttpRequest<HttpServerState>| {
+                    req.extensions_mut().remove::<ScubaSampleBuilder>();
+                    HttpResponse::Ok().json(FdUsage::current())
+                },
+            )
             .scope("/{repo}", |repo| {
                 repo.resource("/raw/{changeset}/{path:.*}", |r| {
                     r.method(http::Method::GET).with_async(get_raw_file)
@@ -19,12 +19,12 @@ where
     entrylimit: usize,  // max number of entries
     weightlimit: usize, // max weight of entries
 
-    entrysizes: usize, // sum of (completed) entry weights
+    entrysizes: usize, // sum of key and (completed) value weights
 }
 
 impl<K, V> BoundedHash<K, V>
 where
-    K: Eq + Hash,
+    K: Eq + Hash + Weight,
     V: Weight,
 {
     pub fn new(entrylimit: usize, weightlimit: usize) -> Self {
@@ -50,15 +50,15 @@ where
         self.hash.is_empty()
     }
 
-    fn remove_one(&mut self, v: &V) {
-        self.entrysizes -= v.get_weight();
+    fn remove_one(&mut self, k: &K, v: &V) {
+        self.entrysizes -= k.get_weight() + v.get_weight();
     }
 
     /// Trim an entry with LRU policy
     fn trim_one(&mut self) -> bool {
         match self.hash.pop_front() {
-            Some((_k, v)) => {
-                self.remove_one(&v);
+            Some((k, v)) => {
+                self.remove_one(&k, &v);
                 true
             }
             None => false,
@@ -113,7 +113,7 @@ where
     /// Trim a specific key, returning it if it existed, after updating the weight
     pub fn remove(&mut self, key: &K) -> Option<V> {
         self.hash.remove(key).map(|v| {
-            self.remove_one(&v);
+            self.remove_one(key, &v);
             v
         })
     }
@@ -125,7 +125,7 @@ where
         // Remove the key if it's already in the hash
         let oldv = self.hash.remove(&k);
         if let Some(ref removed) = oldv {
-            self.entrysizes -= removed.get_weight();
+            self.remove_one(&k, removed);
         }
 
         if !self.trim_entries(1) {
@@ -133,13 +133,13 @@ where
             return Err((k, v));
         }
 
-        let vw = v.get_weight();
+        let weight = k.get_weight() + v.get_weight();
 
-        if !self.trim_weight(vw) {
+        if !self.trim_weight(weight) {
             return Err((k, v));
         }
 
-        self.entrysizes += vw;
+        self.entrysizes += weight;
 
         self.hash.insert(k, v);
         Ok(oldv)
@@ -190,7 +190,7 @@ mod test {
         let mut c = BoundedHash::new(10, 1000);
 
         {
-            let ok = c.insert("hello", Weighted("world", 100)).is_ok();
+            let ok = c.insert(Weighted("hello", 0), Weighted("world", 100)).is_ok();
 
             assert!(ok, "insert failed");
             assert_eq!(c.total_weight(), 100);
@@ -198,12 +198,12 @@ mod test {
         }
 
         {
-            let v = c.get(&"hello").expect("get failed");
+            let v = c.get(&Weighted("hello", 0)).expect("get failed");
             assert_eq!(v, &Weighted("world", 100));
         }
 
         {
-            let ok = c.remove(&"hello").is_some();
+            let ok = c.remove(&Weighted("hello", 0)).is_some();
 
             assert!(ok, "remove failed");
             assert_eq!(c.total_weight(), 0);
@@ -215,22 +215,44 @@ mod test {
     fn toobig() {
         let mut c = BoundedHash::new(10, 1000);
 
-        let ok = c.insert("hello", Weighted("world", 100)).is_ok();
+        let ok = c.insert(Weighted("hello", 0), Weighted("world", 100)).is_ok();
 
         assert!(ok, "insert failed");
         assert_eq!(c.total_weight(), 100);
         assert_eq!(c.len(), 1);
 
-        let err = c.insert("bubble", Weighted("lead", 1001)).is_err();
+        let err = c.insert(Weighted("bubble", 0), Weighted("lead", 1001)).is_err();
         assert!(err, "insert worked?");
 
         assert_eq!(c.total_weight(), 100);
         assert_eq!(c.len(), 1);
 
-        let ok = c.insert("bubble", Weighted("balloon", 880)).is_ok();
+        let ok = c.insert(Weighted("bubble", 0), Weighted("balloon", 880)).is_ok();
         assert!(ok, "insert failed?");
 
         assert_eq!(c.total_weight(), 100 + 880);
         assert_eq!(c.len(), 2);
     }
+
+    #[test]
+    fn key_weight() {
+        let mut c = BoundedHash::new(10, 1000);
+
+        let ok = c.insert(Weighted("hello", 50), Weighted("world", 100)).is_ok();
+        assert!(ok, "insert failed");
+        assert_eq!(c.total_weight(), 50 + 100);
+
+        // The key counts against the limit too
+        let err = c.insert(Weighted("bubble", 500), Weighted("lead", 501)).is_err();
+        assert!(err, "insert worked?");
+        assert_eq!(c.total_weight(), 50 + 100);
+
+        let ok = c.insert(Weighted("hello", 50), Weighted("world", 200)).is_ok();
+        assert!(ok, "insert failed");
+        assert_eq!(c.total_weight(), 50 + 200);
+
+        let ok = c.remove(&Weighted("hello", 50)).is_some();
+        assert!(ok, "remove failed");
+        assert_eq!(c.total_weight(), 0);
+    }
 }
@@ -70,7 +70,7 @@ mod boundedhash;
 mod weight;
 
 use boundedhash::BoundedHash;
-pub use weight::Weight;
+pub use weight::{weight_matches_estimate, Weight};
 
 define_stats! {
     prefix = "asyncmemo";
@@ -5,61 +5,112 @@
 // GNU General Public License version 2 or any later version.
 
 use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
 use std::mem;
 
 use heapsize::HeapSizeOf;
 
 /// Return the "weight" of a type.
 ///
-/// This is an abstract value which can mean anything, but it typically
-/// relates to memory consumption. The expectation is that calling `get_weight()`
-/// fairly cheap - ideally O(1).
+/// The weight of a value is an estimate of the memory it takes once it is cached, in bytes. It
+/// is the fixed overhead of the value itself, `mem::size_of::<Self>()`, plus the heap bytes it
+/// owns: the buffer of a `String`, the elements of a `Vec`, and so on. Buffers that are shared
+/// with values outside of the cache, like the data of a `Bytes`, count as owned, as the cache
+/// keeps them alive. The same weights are used for the limits of `Asyncmemo` and to size the
+/// items of cachelib pools, so every type should follow these rules.
+///
+/// The expectation is that calling `get_weight()` is fairly cheap - ideally O(1), and O(n) in
+/// the number of elements for collections.
 pub trait Weight {
     fn get_weight(&self) -> usize;
 }
 
-impl Weight for String {
-    #[inline]
-    fn get_weight(&self) -> usize {
-        mem::size_of::<Self>() + self.heap_size_of_children()
+/// Whether `weight` is consistent with `estimate`, the size of the same value measured in some
+/// other way, e.g. from its serialized form. A weight is allowed to be larger than the estimate,
+/// because of spare capacity and inline overheads that serialization doesn't see, but not by
+/// more than double, and it must never be smaller.
+pub fn weight_matches_estimate(weight: usize, estimate: usize) -> bool {
+    weight >= estimate && weight <= 2 * estimate
+}
+
+macro_rules! impl_weight_for_inline {
+    ($($ty:ty),*) => {
+        $(
+            /// Only the value itself, which owns no heap memory
+            impl Weight for $ty {
+                #[inline]
+                fn get_weight(&self) -> usize {
+                    mem::size_of::<Self>()
+                }
+            }
+        )*
     }
 }
 
-impl Weight for u32 {
+impl_weight_for_inline!(bool, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// The `String` and its buffer
+impl Weight for String {
     #[inline]
     fn get_weight(&self) -> usize {
-        mem::size_of::<Self>()
+        mem::size_of::<Self>() + self.heap_size_of_children()
     }
 }
 
-impl Weight for u64 {
+/// The handle and the data it points to, even if it is shared with other handles
+impl Weight for Bytes {
     #[inline]
     fn get_weight(&self) -> usize {
-        mem::size_of::<Self>()
+        mem::size_of::<Self>() + self.len()
     }
 }
 
-impl Weight for i32 {
-    #[inline]
+/// The `Vec`, its elements with whatever they own, and its spare capacity
+impl<T> Weight for Vec<T>
+where
+    T: Weight,
+{
     fn get_weight(&self) -> usize {
-        mem::size_of::<Self>()
+        let spare = (self.capacity() - self.len()) * mem::size_of::<T>();
+        mem::size_of::<Self>() + spare + self.iter().map(Weight::get_weight).sum::<usize>()
     }
 }
 
-impl Weight for i64 {
-    #[inline]
+/// The map, its entries with whatever they own, its spare capacity and the hash stored for
+/// every bucket
+impl<K, V, S> Weight for HashMap<K, V, S>
+where
+    K: Weight + Eq + Hash,
+    V: Weight,
+    S: BuildHasher,
+{
     fn get_weight(&self) -> usize {
-        mem::size_of::<Self>()
+        let spare = (self.capacity() - self.len()) * (mem::size_of::<K>() + mem::size_of::<V>());
+        let hashes = self.capacity() * mem::size_of::<u64>();
+        let entries: usize = self.iter()
+            .map(|(k, v)| k.get_weight() + v.get_weight())
+            .sum();
+        mem::size_of::<Self>() + spare + hashes + entries
     }
 }
 
-impl Weight for Bytes {
-    #[inline]
+/// The map and its entries with whatever they own. The spare slots of the tree nodes are not
+/// counted, as the map doesn't expose them.
+impl<K, V> Weight for BTreeMap<K, V>
+where
+    K: Weight,
+    V: Weight,
+{
     fn get_weight(&self) -> usize {
-        self.len()
+        let entries: usize = self.iter()
+            .map(|(k, v)| k.get_weight() + v.get_weight())
+            .sum();
+        mem::size_of::<Self>() + entries
     }
 }
 
+/// The elements with whatever they own, ignoring the padding between them
 impl<A, B> Weight for (A, B)
 where
     A: Weight,
@@ -71,6 +122,7 @@ where
     }
 }
 
+/// The elements with whatever they own, ignoring the padding between them
 impl<A, B, C> Weight for (A, B, C)
 where
     A: Weight,
@@ -83,16 +135,87 @@ where
     }
 }
 
+/// The `Option`, which is never smaller than its value, and whatever the value owns
 impl<A> Weight for Option<A>
 where
     A: Weight,
 {
     #[inline]
     fn get_weight(&self) -> usize {
-        let inner_size = self.as_ref()
-            .map(Weight::get_weight)
+        let inner_heap = self.as_ref()
+            .map(|inner| inner.get_weight() - mem::size_of::<A>())
             .unwrap_or(0);
 
-        mem::size_of::<Self>() - mem::size_of::<A>() + inner_size
+        mem::size_of::<Self>() + inner_heap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_inline_weights() {
+        assert_eq!(1u8.get_weight(), 1);
+        assert_eq!(1u64.get_weight(), 8);
+        assert_eq!((1u32, 1i64).get_weight(), 12);
+    }
+
+    #[test]
+    fn test_bytes_weight() {
+        let bytes = Bytes::from(vec![0u8; 1000]);
+        assert_eq!(bytes.get_weight(), mem::size_of::<Bytes>() + 1000);
+        assert!(weight_matches_estimate(bytes.get_weight(), 1000));
+    }
+
+    #[test]
+    fn test_option_weight() {
+        let none: Option<String> = None;
+        assert_eq!(none.get_weight(), mem::size_of::<Option<String>>());
+
+        let some = Some(String::from("HELLO"));
+        let string_weight = String::from("HELLO").get_weight();
+        assert_eq!(
+            some.get_weight(),
+            mem::size_of::<Option<String>>() - mem::size_of::<String>() + string_weight
+        );
+    }
+
+    #[test]
+    fn test_vec_weight() {
+        let mut v: Vec<u64> = Vec::with_capacity(10);
+        v.push(1);
+        v.push(2);
+        assert_eq!(v.get_weight(), mem::size_of::<Vec<u64>>() + 10 * 8);
+
+        let strings = vec![String::from("a"), String::from("bcd")];
+        let elements: usize = strings.iter().map(Weight::get_weight).sum();
+        assert_eq!(strings.get_weight(), mem::size_of::<Vec<String>>() + elements);
+    }
+
+    #[test]
+    fn test_map_weights() {
+        let mut hashmap = HashMap::new();
+        let mut btreemap = BTreeMap::new();
+        for i in 0..100u64 {
+            hashmap.insert(i, String::from("value"));
+            btreemap.insert(i, String::from("value"));
+        }
+        let entries = 100 * (8 + String::from("value").get_weight());
+
+        assert_eq!(
+            btreemap.get_weight(),
+            mem::size_of::<BTreeMap<u64, String>>() + entries
+        );
+        // Every bucket of the hashmap costs at least its hash
+        assert!(hashmap.get_weight() >= mem::size_of::<HashMap<u64, String>>() + entries + 800);
+    }
+
+    #[test]
+    fn test_weight_matches_estimate() {
+        assert!(weight_matches_estimate(100, 100));
+        assert!(weight_matches_estimate(150, 100));
+        assert!(!weight_matches_estimate(99, 100));
+        assert!(!weight_matches_estimate(201, 100));
     }
 }
@@ -0,0 +1,449 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Snapshots of the bookmarks of a repo, kept in the blobstore of the repo.
+//!
+//! A snapshot is an independent record of where every bookmark pointed at some point in time, so
+//! that bookmarks can be restored if the bookmarks table is lost or corrupted. Every snapshot is
+//! stored once under its own key, which starts with `BOOKMARK_SNAPSHOT_PREFIX`, and blobstores
+//! can't list their keys, so the keys of the retained snapshots are recorded in a
+//! `BookmarkSnapshotIndex`.
+//!
+//! Blobstores can't delete blobs, so a snapshot that falls out of the retained ones is only
+//! removed from the index.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json;
+use uuid::Uuid;
+
+use blobstore::Blobstore;
+use bookmarks::{Bookmark, BookmarkPrefix, BookmarkSnapshotIndex};
+use mercurial_types::HgChangesetId;
+use mononoke_types::{BlobstoreBytes, ChangesetId, DateTime};
+
+use errors::*;
+use repo::BlobRepo;
+
+pub const BOOKMARK_SNAPSHOT_PREFIX: &str = "bookmark_snapshot.";
+
+/// Changeset a bookmark points to
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BookmarkTarget {
+    pub hg: HgChangesetId,
+    pub bonsai: ChangesetId,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BookmarkSnapshot {
+    /// Unix timestamp of the snapshot, in seconds
+    pub created_at: i64,
+    pub bookmarks: BTreeMap<Bookmark, BookmarkTarget>,
+    /// Sequence number of the last bookmark update that the snapshot includes, for bookmark
+    /// stores that keep a log of the updates
+    pub update_log_sequence: Option<u64>,
+}
+
+/// Serialized form of `BookmarkSnapshot`
+#[derive(Serialize, Deserialize)]
+struct RawBookmarkSnapshot {
+    created_at: i64,
+    bookmarks: BTreeMap<String, RawBookmarkTarget>,
+    #[serde(default)]
+    update_log_sequence: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawBookmarkTarget {
+    hg: String,
+    bonsai: String,
+}
+
+impl BookmarkSnapshot {
+    fn into_raw(self) -> RawBookmarkSnapshot {
+        RawBookmarkSnapshot {
+            created_at: self.created_at,
+            bookmarks: self.bookmarks
+                .into_iter()
+                .map(|(bookmark, target)| {
+                    let target = RawBookmarkTarget {
+                        hg: target.hg.to_hex().to_string(),
+                        bonsai: target.bonsai.to_hex().to_string(),
+                    };
+                    (bookmark.to_string(), target)
+                })
+                .collect(),
+            update_log_sequence: self.update_log_sequence,
+        }
+    }
+
+    fn from_raw(raw: RawBookmarkSnapshot) -> Result<Self> {
+        let bookmarks = raw.bookmarks
+            .into_iter()
+            .map(|(bookmark, target)| {
+                let target = BookmarkTarget {
+                    hg: HgChangesetId::from_str(&target.hg)?,
+                    bonsai: ChangesetId::from_str(&target.bonsai)?,
+                };
+                Ok((Bookmark::new(bookmark)?, target))
+            })
+            .collect::<Result<_>>()?;
+        Ok(BookmarkSnapshot {
+            created_at: raw.created_at,
+            bookmarks,
+            update_log_sequence: raw.update_log_sequence,
+        })
+    }
+}
+
+/// Change that restoring a snapshot makes to a bookmark
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BookmarkSnapshotChange {
+    pub bookmark: Bookmark,
+    /// Current target of the bookmark, None if the bookmark doesn't exist
+    pub current: Option<BookmarkTarget>,
+    /// Target of the bookmark in the snapshot, None if the bookmark didn't exist
+    pub restored: Option<BookmarkTarget>,
+}
+
+impl fmt::Display for BookmarkSnapshotChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.current, &self.restored) {
+            (None, Some(restored)) => write!(f, "create {} at {}", self.bookmark, restored.hg),
+            (Some(current), Some(restored)) => write!(
+                f,
+                "move {} from {} to {}",
+                self.bookmark, current.hg, restored.hg
+            ),
+            (Some(current), None) => write!(f, "delete {} at {}", self.bookmark, current.hg),
+            (None, None) => write!(f, "keep {} deleted", self.bookmark),
+        }
+    }
+}
+
+/// Current targets of all the bookmarks of the repo
+fn current_bookmarks(repo: &BlobRepo) -> BoxFuture<BTreeMap<Bookmark, BookmarkTarget>, Error> {
+    repo.get_bookmarks_object()
+        .list_by_prefix(&BookmarkPrefix::empty(), &repo.get_repoid())
+        .map({
+            cloned!(repo);
+            move |(bookmark, bonsai)| {
+                repo.get_hg_from_bonsai_changeset(bonsai)
+                    .map(move |hg| (bookmark, BookmarkTarget { hg, bonsai }))
+            }
+        })
+        .buffered(100)
+        .collect()
+        .map(|bookmarks| bookmarks.into_iter().collect())
+        .boxify()
+}
+
+/// Returns the keys of the retained snapshots of the repo, oldest first
+pub fn list_bookmark_snapshots(
+    repo: &BlobRepo,
+    index: &BookmarkSnapshotIndex,
+) -> BoxFuture<Vec<String>, Error> {
+    index.list(&repo.get_repoid())
+}
+
+/// Takes a snapshot of the bookmarks of the repo and returns its key. Only the `retain` newest
+/// snapshots are kept in the index. The snapshot is added to the index once it is stored, so the
+/// index never has the key of a missing snapshot.
+pub fn take_bookmark_snapshot(
+    repo: &BlobRepo,
+    index: Arc<BookmarkSnapshotIndex>,
+    retain: usize,
+) -> BoxFuture<String, Error> {
+    let blobstore = repo.get_blobstore();
+    let created_at = DateTime::now().timestamp_secs();
+    let key = format!(
+        "{}{}.{}",
+        BOOKMARK_SNAPSHOT_PREFIX,
+        created_at,
+        Uuid::new_v4().simple()
+    );
+
+    current_bookmarks(repo)
+        .and_then({
+            cloned!(key);
+            move |bookmarks| {
+                let snapshot = BookmarkSnapshot {
+                    created_at,
+                    bookmarks,
+                    // Bookmark updates are not logged, so there is no sequence to record
+                    update_log_sequence: None,
+                };
+                let blob = try_boxfuture!(serde_json::to_vec(&snapshot.into_raw()));
+                blobstore
+                    .put(key, BlobstoreBytes::from_bytes(Bytes::from(blob)))
+                    .boxify()
+            }
+        })
+        .and_then({
+            let repoid = repo.get_repoid();
+            move |()| {
+                index
+                    .add(&repoid, &key, created_at, retain)
+                    .map(move |()| key)
+            }
+        })
+        .boxify()
+}
+
+pub fn load_bookmark_snapshot(repo: &BlobRepo, key: &str) -> BoxFuture<BookmarkSnapshot, Error> {
+    let key = key.to_string();
+    repo.get_blobstore()
+        .get(key.clone())
+        .and_then(move |blob| {
+            let blob = blob.ok_or_else(|| ErrorKind::BookmarkSnapshotMissing(key.clone()))?;
+            let raw = serde_json::from_slice(blob.as_bytes().as_ref())
+                .map_err(|err| ErrorKind::BookmarkSnapshotInvalid(format!("{}: {}", key, err)))?;
+            BookmarkSnapshot::from_raw(raw)
+        })
+        .boxify()
+}
+
+/// Returns the changes that restoring `snapshot` makes to the bookmarks that start with
+/// `prefix`, sorted by bookmark. Bookmarks that were created after the snapshot are deleted.
+pub fn diff_bookmark_snapshot(
+    repo: &BlobRepo,
+    snapshot: &BookmarkSnapshot,
+    prefix: &BookmarkPrefix,
+) -> BoxFuture<Vec<BookmarkSnapshotChange>, Error> {
+    let prefix = prefix.to_string();
+    let restored: BTreeMap<_, _> = snapshot
+        .bookmarks
+        .iter()
+        .filter(|(bookmark, _)| bookmark.to_string().starts_with(&prefix))
+        .map(|(bookmark, target)| (bookmark.clone(), *target))
+        .collect();
+
+    current_bookmarks(repo)
+        .map(move |current| {
+            let mut current: BTreeMap<_, _> = current
+                .into_iter()
+                .filter(|(bookmark, _)| bookmark.to_string().starts_with(&prefix))
+                .collect();
+
+            let mut changes: Vec<_> = restored
+                .into_iter()
+                .filter_map(|(bookmark, restored)| {
+                    let current = current.remove(&bookmark);
+                    if current == Some(restored) {
+                        None
+                    } else {
+                        Some(BookmarkSnapshotChange {
+                            bookmark,
+                            current,
+                            restored: Some(restored),
+                        })
+                    }
+                })
+                .collect();
+            changes.extend(current.into_iter().map(|(bookmark, current)| {
+                BookmarkSnapshotChange {
+                    bookmark,
+                    current: Some(current),
+                    restored: None,
+                }
+            }));
+            changes.sort_by(|a, b| a.bookmark.cmp(&b.bookmark));
+            changes
+        })
+        .boxify()
+}
+
+/// Applies `changes` in a single bookmark transaction. A bookmark that was moved since `changes`
+/// were computed makes the transaction fail, which is reported as `false`.
+pub fn apply_bookmark_snapshot_changes(
+    repo: &BlobRepo,
+    changes: &[BookmarkSnapshotChange],
+) -> BoxFuture<bool, Error> {
+    let mut transaction = repo.update_bookmark_transaction();
+    for change in changes {
+        let res = match (&change.current, &change.restored) {
+            (None, Some(restored)) => transaction.create(&change.bookmark, &restored.bonsai),
+            (Some(current), Some(restored)) => {
+                transaction.update(&change.bookmark, &restored.bonsai, &current.bonsai)
+            }
+            (Some(current), None) => transaction.delete(&change.bookmark, &current.bonsai),
+            (None, None) => Ok(()),
+        };
+        try_boxfuture!(res);
+    }
+    transaction.commit()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use dbbookmarks::SqliteBookmarkSnapshotIndex;
+    use fixtures::linear;
+
+    fn bookmark(name: &str) -> Bookmark {
+        Bookmark::new(name).unwrap()
+    }
+
+    fn target(repo: &BlobRepo, hg: &str) -> BookmarkTarget {
+        let hg = HgChangesetId::from_str(hg).unwrap();
+        let bonsai = repo.get_bonsai_from_hg(&hg).wait().unwrap().unwrap();
+        BookmarkTarget { hg, bonsai }
+    }
+
+    fn snapshot_index() -> Arc<BookmarkSnapshotIndex> {
+        Arc::new(SqliteBookmarkSnapshotIndex::in_memory().unwrap())
+    }
+
+    fn set_bookmark(repo: &BlobRepo, name: &str, target: Option<BookmarkTarget>) {
+        let mut transaction = repo.update_bookmark_transaction();
+        match target {
+            Some(target) => transaction.force_set(&bookmark(name), &target.bonsai),
+            None => transaction.force_delete(&bookmark(name)),
+        }.unwrap();
+        assert!(transaction.commit().wait().unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let snapshot = BookmarkSnapshot {
+                created_at: 1234,
+                bookmarks: btreemap! {
+                    bookmark("master") => target(&repo, "79a13814c5ce7330173ec04d279bf95ab3f652fb"),
+                },
+                update_log_sequence: Some(5),
+            };
+
+            let raw = serde_json::to_vec(&snapshot.clone().into_raw()).unwrap();
+            let roundtripped =
+                BookmarkSnapshot::from_raw(serde_json::from_slice(&raw).unwrap()).unwrap();
+            assert_eq!(roundtripped, snapshot);
+        })
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let head = target(&repo, "79a13814c5ce7330173ec04d279bf95ab3f652fb");
+            let root = target(&repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+            set_bookmark(&repo, "master", Some(head));
+            set_bookmark(&repo, "release", Some(root));
+
+            let index = snapshot_index();
+            let key = take_bookmark_snapshot(&repo, index.clone(), 10)
+                .wait()
+                .unwrap();
+            assert!(key.starts_with(BOOKMARK_SNAPSHOT_PREFIX));
+            assert_eq!(
+                list_bookmark_snapshots(&repo, &*index).wait().unwrap(),
+                vec![key.clone()]
+            );
+
+            set_bookmark(&repo, "master", Some(root));
+            set_bookmark(&repo, "release", None);
+            set_bookmark(&repo, "new", Some(head));
+
+            let snapshot = load_bookmark_snapshot(&repo, &key).wait().unwrap();
+            let changes = diff_bookmark_snapshot(&repo, &snapshot, &BookmarkPrefix::empty())
+                .wait()
+                .unwrap();
+            let changes: Vec<_> = changes.iter().map(|change| change.to_string()).collect();
+            assert_eq!(
+                changes,
+                vec![
+                    format!("move master from {} to {}", root.hg, head.hg),
+                    format!("delete new at {}", head.hg),
+                    format!("create release at {}", root.hg),
+                ]
+            );
+
+            // Only the bookmarks with the prefix are restored
+            let prefix = BookmarkPrefix::new("ma").unwrap();
+            let changes = diff_bookmark_snapshot(&repo, &snapshot, &prefix)
+                .wait()
+                .unwrap();
+            assert_eq!(changes.len(), 1);
+            assert!(apply_bookmark_snapshot_changes(&repo, &changes).wait().unwrap());
+            assert_eq!(
+                repo.get_bookmark(&bookmark("master")).wait().unwrap(),
+                Some(head.hg)
+            );
+            assert_eq!(
+                repo.get_bookmark(&bookmark("new")).wait().unwrap(),
+                Some(head.hg)
+            );
+
+            let changes = diff_bookmark_snapshot(&repo, &snapshot, &BookmarkPrefix::empty())
+                .wait()
+                .unwrap();
+            assert!(apply_bookmark_snapshot_changes(&repo, &changes).wait().unwrap());
+            let changes = diff_bookmark_snapshot(&repo, &snapshot, &BookmarkPrefix::empty())
+                .wait()
+                .unwrap();
+            assert!(changes.is_empty());
+            assert_eq!(repo.get_bookmark(&bookmark("new")).wait().unwrap(), None);
+            assert_eq!(
+                repo.get_bookmark(&bookmark("release")).wait().unwrap(),
+                Some(root.hg)
+            );
+        })
+    }
+
+    #[test]
+    fn test_snapshot_retention() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let index = snapshot_index();
+            let keys: Vec<_> = (0..3)
+                .map(|_| {
+                    take_bookmark_snapshot(&repo, index.clone(), 2)
+                        .wait()
+                        .unwrap()
+                })
+                .collect();
+            assert_eq!(
+                list_bookmark_snapshots(&repo, &*index).wait().unwrap(),
+                keys[1..].to_vec()
+            );
+            // Snapshots that are no longer retained are still in the blobstore
+            assert!(load_bookmark_snapshot(&repo, &keys[0]).wait().is_ok());
+        })
+    }
+
+    #[test]
+    fn test_stale_changes_are_not_applied() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let head = target(&repo, "79a13814c5ce7330173ec04d279bf95ab3f652fb");
+            let root = target(&repo, "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
+            set_bookmark(&repo, "master", Some(head));
+            let key = take_bookmark_snapshot(&repo, snapshot_index(), 10)
+                .wait()
+                .unwrap();
+            set_bookmark(&repo, "master", Some(root));
+
+            let snapshot = load_bookmark_snapshot(&repo, &key).wait().unwrap();
+            let changes = diff_bookmark_snapshot(&repo, &snapshot, &BookmarkPrefix::empty())
+                .wait()
+                .unwrap();
+            // Someone else moves the bookmark in the meantime
+            set_bookmark(&repo, "master", None);
+            assert!(!apply_bookmark_snapshot_changes(&repo, &changes).wait().unwrap());
+            assert_eq!(repo.get_bookmark(&bookmark("master")).wait().unwrap(), None);
+        })
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Metrics of the creation of changesets. They are only collected for a `CreateChangeset` that is
+//! given a `ChangesetMetricsCollector`, as counting the bytes of the entries fetches them again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use failure::Error;
+use futures::Future;
+use futures::future::Either;
+use futures_ext::{BoxFuture, FutureExt};
+use futures_stats::Timed;
+
+use mercurial_types::{Entry, HgChangesetId, Type};
+
+use file::HgBlobEntry;
+
+/// What it took to create a changeset
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChangesetMetrics {
+    pub file_entries: u64,
+    pub tree_entries: u64,
+    /// Size of the contents of the file entries
+    pub file_bytes: u64,
+    /// Size of the raw manifests of the tree entries
+    pub tree_bytes: u64,
+    /// Uploading and processing the entries
+    pub upload_entries: Duration,
+    /// Computing the changed files and checking for case conflicts
+    pub changed_files: Duration,
+    /// Creating the bonsai changeset
+    pub bonsai: Duration,
+    /// Saving the changesets and the filenodes
+    pub save: Duration,
+    /// Inserting into the mapping and changesets tables
+    pub commit: Duration,
+}
+
+/// Collects the metrics of the changesets that are created with it, until they are taken
+#[derive(Clone, Default)]
+pub struct ChangesetMetricsCollector {
+    metrics: Arc<Mutex<HashMap<HgChangesetId, ChangesetMetrics>>>,
+}
+
+impl ChangesetMetricsCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The metrics of a changeset, once `get_completed_changeset()` returned it
+    pub fn take(&self, csid: &HgChangesetId) -> Option<ChangesetMetrics> {
+        self.metrics.lock().expect("lock poisoned").remove(csid)
+    }
+}
+
+/// The metrics of a changeset that is being created
+#[derive(Clone)]
+pub(crate) struct PendingMetrics {
+    collector: ChangesetMetricsCollector,
+    metrics: Arc<Mutex<ChangesetMetrics>>,
+}
+
+impl PendingMetrics {
+    pub(crate) fn new(collector: ChangesetMetricsCollector) -> Self {
+        PendingMetrics {
+            collector,
+            metrics: Arc::new(Mutex::new(ChangesetMetrics::default())),
+        }
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut ChangesetMetrics),
+    {
+        f(&mut self.metrics.lock().expect("lock poisoned"))
+    }
+
+    pub(crate) fn count_entry(&self, entry: &HgBlobEntry) -> BoxFuture<(), Error> {
+        let this = self.clone();
+        match entry.get_type() {
+            Type::Tree => entry
+                .get_raw_content()
+                .map(move |blob| {
+                    this.update(|metrics| {
+                        metrics.tree_entries += 1;
+                        metrics.tree_bytes += blob.size() as u64;
+                    })
+                })
+                .boxify(),
+            Type::File(_) => entry
+                .get_size()
+                .map(move |size| {
+                    this.update(|metrics| {
+                        metrics.file_entries += 1;
+                        metrics.file_bytes += size.unwrap_or(0) as u64;
+                    })
+                })
+                .boxify(),
+        }
+    }
+
+    /// Hands the metrics over to the collector
+    pub(crate) fn finish(&self, csid: HgChangesetId) {
+        let metrics = self.metrics.lock().expect("lock poisoned").clone();
+        self.collector
+            .metrics
+            .lock()
+            .expect("lock poisoned")
+            .insert(csid, metrics);
+    }
+}
+
+/// Adds the time that `fut` takes to one of the phases of `metrics`, if they are collected
+pub(crate) fn time_phase<F>(
+    metrics: &Option<PendingMetrics>,
+    phase: fn(&mut ChangesetMetrics) -> &mut Duration,
+    fut: F,
+) -> impl Future<Item = F::Item, Error = F::Error>
+where
+    F: Future,
+{
+    match metrics.clone() {
+        Some(metrics) => Either::A(fut.timed(move |stats, _| {
+            metrics.update(|metrics| *phase(metrics) += stats.completion_time);
+            Ok(())
+        })),
+        None => Either::B(fut),
+    }
+}
@@ -104,4 +104,8 @@ pub enum ErrorKind {
            from_path, from_node, to_path, to_node)]
     IncorrectCopyInfo{from_path: MPath, from_node: HgNodeHash, to_path: MPath, to_node: HgNodeHash},
     #[fail(display = "Case conflict in a commit")] CaseConflict(MPath),
+    #[fail(display = "Bookmark snapshot {} not found", _0)] BookmarkSnapshotMissing(String),
+    #[fail(display = "Invalid bookmark snapshot: {}", _0)] BookmarkSnapshotInvalid(String),
+    #[fail(display = "Invalid storage attribution of {}: {}", _0, _1)]
+    StorageAttributionInvalid(ChangesetId, String),
 }
@@ -6,8 +6,12 @@
 
 //! Plain files, symlinks
 
+use std::cmp;
+
+use bytes::Bytes;
 use failure::{Error, FutureFailureErrorExt};
 use futures::future::{self, Future};
+use futures::stream::{self, Stream};
 use futures_ext::{BoxFuture, FutureExt};
 
 use mercurial::file;
@@ -86,6 +90,77 @@ pub fn fetch_file_content_from_blobstore(
     })
 }
 
+/// Size of the chunks yielded by `BlobRepo::get_file_content_stream`.
+pub const FILE_CONTENT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Turn file contents into a stream of chunks of at most `chunk_size` bytes. The chunks are slices
+/// of the contents, which stay in memory as a whole until the last chunk is dropped: this bounds
+/// the writes of consumers, not the memory used to read the file.
+pub fn file_contents_chunks(
+    contents: FileContents,
+    chunk_size: usize,
+) -> impl Stream<Item = Bytes, Error = Error> {
+    assert!(chunk_size > 0, "chunk size must be positive");
+    match contents {
+        FileContents::Bytes(bytes) => {
+            let len = bytes.len();
+            let chunks = (0..len)
+                .step_by(chunk_size)
+                .map(move |start| bytes.slice(start, cmp::min(start + chunk_size, len)));
+            stream::iter_ok(chunks)
+        }
+    }
+}
+
+/// Clamps the `len` bytes at `offset` to a file of `file_len` bytes. A range that ends past the
+/// end of the file is truncated, one that starts past it is empty.
+fn clamp_range(file_len: usize, offset: u64, len: u64) -> (usize, usize) {
+    let file_len = file_len as u64;
+    let start = cmp::min(offset, file_len);
+    let end = cmp::min(offset.saturating_add(len), file_len);
+    (start as usize, end as usize)
+}
+
+/// The `len` bytes of `contents` at `offset`, truncated at the end of the file.
+pub fn file_contents_range(contents: FileContents, offset: u64, len: u64) -> Bytes {
+    match contents {
+        FileContents::Bytes(bytes) => {
+            let (start, end) = clamp_range(bytes.len(), offset, len);
+            bytes.slice(start, end)
+        }
+    }
+}
+
+/// Like `file_contents_chunks`, but only yields the chunks that overlap the `len` bytes at
+/// `offset`, trimmed to that range.
+pub fn file_contents_range_chunks(
+    contents: FileContents,
+    chunk_size: usize,
+    offset: u64,
+    len: u64,
+) -> impl Stream<Item = Bytes, Error = Error> {
+    assert!(chunk_size > 0, "chunk size must be positive");
+    match contents {
+        FileContents::Bytes(bytes) => {
+            let (start, end) = clamp_range(bytes.len(), offset, len);
+            // Chunks start at multiples of chunk_size, so the first one may start before the
+            // range does
+            let first = if start < end {
+                start - start % chunk_size
+            } else {
+                end
+            };
+            let chunks = (first..end).step_by(chunk_size).map(move |chunk_start| {
+                bytes.slice(
+                    cmp::max(chunk_start, start),
+                    cmp::min(chunk_start + chunk_size, end),
+                )
+            });
+            stream::iter_ok(chunks)
+        }
+    }
+}
+
 pub fn fetch_rename_from_blobstore(
     blobstore: &RepoBlobstore,
     node_id: HgNodeHash,
@@ -277,3 +352,86 @@ impl Entry for HgBlobEntry {
         self.name.as_ref()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn collect_chunks(contents: &[u8], chunk_size: usize) -> Vec<Bytes> {
+        file_contents_chunks(FileContents::new_bytes(contents), chunk_size)
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_file_contents_chunks() {
+        let contents: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let chunks = collect_chunks(&contents, 64);
+
+        assert_eq!(chunks.len(), 16);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 64));
+        assert_eq!(chunks.last().unwrap().len(), 1000 % 64);
+        assert_eq!(chunks.concat(), contents);
+    }
+
+    #[test]
+    fn test_file_contents_chunks_exact_and_empty() {
+        let chunks = collect_chunks(b"abcdef", 3);
+        assert_eq!(chunks, vec![Bytes::from("abc"), Bytes::from("def")]);
+
+        assert!(collect_chunks(b"", 3).is_empty());
+    }
+
+    fn collect_range_chunks(contents: &[u8], offset: u64, len: u64) -> Vec<Bytes> {
+        file_contents_range_chunks(FileContents::new_bytes(contents), 4, offset, len)
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_file_contents_range_within_chunk() {
+        let contents = b"0123456789";
+        assert_eq!(collect_range_chunks(contents, 5, 2), vec![Bytes::from("56")]);
+        assert_eq!(collect_range_chunks(contents, 4, 4), vec![Bytes::from("4567")]);
+        assert_eq!(
+            file_contents_range(FileContents::new_bytes(&contents[..]), 5, 2),
+            Bytes::from("56")
+        );
+    }
+
+    #[test]
+    fn test_file_contents_range_spanning_chunks() {
+        let contents = b"0123456789";
+        assert_eq!(
+            collect_range_chunks(contents, 2, 7),
+            vec![Bytes::from("23"), Bytes::from("4567"), Bytes::from("8")]
+        );
+        assert_eq!(
+            file_contents_range(FileContents::new_bytes(&contents[..]), 2, 7),
+            Bytes::from("2345678")
+        );
+    }
+
+    #[test]
+    fn test_file_contents_range_past_eof() {
+        let contents = b"0123456789";
+        assert_eq!(
+            collect_range_chunks(contents, 6, 100),
+            vec![Bytes::from("67"), Bytes::from("89")]
+        );
+        assert_eq!(
+            file_contents_range(FileContents::new_bytes(&contents[..]), 6, u64::max_value()),
+            Bytes::from("6789")
+        );
+
+        assert!(collect_range_chunks(contents, 10, 5).is_empty());
+        assert!(collect_range_chunks(contents, 20, 5).is_empty());
+        assert!(collect_range_chunks(contents, 3, 0).is_empty());
+        assert_eq!(
+            file_contents_range(FileContents::new_bytes(&contents[..]), 20, 5),
+            Bytes::new()
+        );
+    }
+}
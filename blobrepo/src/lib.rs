@@ -18,6 +18,7 @@ extern crate bincode;
 extern crate bonsai_utils;
 extern crate bytes;
 extern crate db;
+extern crate db_conn;
 #[macro_use]
 extern crate lazy_static;
 extern crate serde;
@@ -72,8 +73,10 @@ extern crate mercurial_types_mocks;
 
 mod alias;
 mod bonsai_generation;
+pub mod bookmark_snapshot;
 mod changeset;
 mod changeset_fetcher;
+mod changeset_metrics;
 mod errors;
 mod file;
 mod manifest;
@@ -81,6 +84,9 @@ mod memory_manifest;
 mod post_commit;
 mod repo;
 mod repo_commit;
+mod retrying;
+mod sql_stores;
+pub mod storage_attribution;
 mod utils;
 
 pub use alias::*;
@@ -88,12 +94,16 @@ pub use errors::*;
 
 pub use changeset::{HgBlobChangeset, HgChangesetContent};
 pub use changeset_fetcher::ChangesetFetcher;
-pub use file::HgBlobEntry;
+pub use changeset_metrics::{ChangesetMetrics, ChangesetMetricsCollector};
+pub use file::{file_contents_chunks, file_contents_range, file_contents_range_chunks,
+               HgBlobEntry, FILE_CONTENT_CHUNK_SIZE};
 pub use manifest::BlobManifest;
-pub use repo::{save_bonsai_changesets, BlobRepo, ChangesetMetadata, ContentBlobInfo,
-               ContentBlobMeta, CreateChangeset, ManifoldArgs, UploadHgFileContents,
-               UploadHgFileEntry, UploadHgNodeHash, UploadHgTreeEntry};
-pub use repo_commit::ChangesetHandle;
+pub use repo::{default_blobstore_retry_policy, default_sql_retry_policy, save_bonsai_changesets,
+               BlobRepo, ChangesetMetadata, ContentBlobInfo, ContentBlobMeta, CreateChangeset,
+               ManifoldArgs, UploadHgFileContents, UploadHgFileEntry, UploadHgNodeHash,
+               UploadHgTreeEntry};
+pub use repo_commit::{ChangesetHandle, LiveChangesetHandles};
+pub use sql_stores::StoreDbAddresses;
 // TODO: This is exported for testing - is this the right place for it?
 pub use repo_commit::compute_changed_files;
 
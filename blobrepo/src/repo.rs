@@ -15,12 +15,13 @@ use std::usize;
 
 use bytes::Bytes;
 use db::{get_connection_params, InstanceRequirement, ProxyRequirement};
+use db_conn::{is_transient_sql_error, MysqlConnInner};
 use failure::{Error, FutureFailureErrorExt, FutureFailureExt, Result, prelude::*};
 use futures::{Async, IntoFuture, Poll};
 use futures::future::{self, loop_fn, ok, Either, Future, Loop};
 use futures::stream::{self, FuturesUnordered, Stream};
 use futures::sync::oneshot;
-use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, RetryPolicy, StreamExt};
 use futures_stats::{FutureStats, Timed};
 use scribe::ScribeClient;
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
@@ -32,9 +33,10 @@ use uuid::Uuid;
 use super::alias::{get_sha256_alias, get_sha256_alias_key};
 use super::changeset::HgChangesetContent;
 use super::changeset_fetcher::{CachingChangesetFetcher, ChangesetFetcher, SimpleChangesetFetcher};
+use super::changeset_metrics::{time_phase, ChangesetMetricsCollector, PendingMetrics};
 use super::utils::{sort_topological, IncompleteFilenodeInfo, IncompleteFilenodes};
-use blobstore::{new_cachelib_blobstore, new_memcache_blobstore, Blobstore, EagerMemblob,
-                MemWritesBlobstore, PrefixBlobstore};
+use blobstore::{is_transient_blobstore_error, new_cachelib_blobstore, new_memcache_blobstore,
+                Blobstore, EagerMemblob, MemWritesBlobstore, PrefixBlobstore, RetryingBlobstore};
 use bonsai_generation::{create_bonsai_changeset_object, save_bonsai_changeset_object};
 use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, CachingBonsaiHgMapping,
                         MysqlBonsaiHgMapping, SqliteBonsaiHgMapping};
@@ -45,13 +47,14 @@ use changesets::{CachingChangests, ChangesetEntry, ChangesetInsert, Changesets,
 use dbbookmarks::{MysqlDbBookmarks, SqliteDbBookmarks};
 use delayblob::DelayBlob;
 use fileblob::Fileblob;
-use filenodes::{CachingFilenodes, FilenodeInfo, Filenodes};
+use filenodes::{CachingFilenodes, FilenodeInfo, Filenodes, FilenodesPage, FilenodesPageToken};
 use manifoldblob::ThriftManifoldBlob;
 use mercurial::file::File;
-use mercurial_types::{Changeset, Entry, HgBlob, HgBlobNode, HgChangesetId, HgFileEnvelopeMut,
-                      HgFileNodeId, HgManifestEnvelopeMut, HgManifestId, HgNodeHash, HgParents,
-                      Manifest, RepoPath, RepositoryId, Type};
+use mercurial_types::{Changeset, Entry, HgBlob, HgBlobNode, HgChangesetId, HgChangesetIdPrefix,
+                      HgFileEnvelope, HgFileEnvelopeMut, HgFileNodeId, HgManifestEnvelopeMut,
+                      HgManifestId, HgNodeHash, HgParents, Manifest, RepoPath, RepositoryId, Type};
 use mercurial_types::manifest::Content;
+use mercurial_types::manifest_utils::{self, PathFilter};
 use mononoke_types::{Blob, BlobstoreBytes, BlobstoreValue, BonsaiChangeset, ChangesetId,
                      ContentId, DateTime, FileChange, FileContents, FileType, Generation, MPath,
                      MPathElement, MononokeId, hash::Blake2, hash::Sha256};
@@ -62,16 +65,24 @@ use sqlfilenodes::SqlFilenodes;
 use BlobManifest;
 use HgBlobChangeset;
 use errors::*;
-use file::{fetch_file_content_from_blobstore, fetch_file_contents, fetch_raw_filenode_bytes,
-           fetch_rename_from_blobstore, HgBlobEntry};
+use file::{fetch_file_content_from_blobstore, fetch_file_contents, fetch_file_envelope,
+           fetch_raw_filenode_bytes, fetch_rename_from_blobstore, file_contents_chunks,
+           file_contents_range, HgBlobEntry, FILE_CONTENT_CHUNK_SIZE};
 use memory_manifest::MemoryRootManifest;
 use post_commit::{self, PostCommitQueue};
 use repo_commit::*;
+use retrying::{RetryingBonsaiHgMapping, RetryingBookmarks, RetryingChangesets, RetryingFilenodes,
+               SqlRetries};
+use sql_stores::{open_pooled_stores, StoreDbAddresses};
+use storage_attribution::attribute_changeset;
 
 define_stats! {
     prefix = "mononoke.blobrepo";
     get_bonsai_changeset: timeseries(RATE, SUM),
     get_file_content: timeseries(RATE, SUM),
+    get_file_content_stream: timeseries(RATE, SUM),
+    get_file_content_range: timeseries(RATE, SUM),
+    get_file_envelope: timeseries(RATE, SUM),
     get_raw_hg_content: timeseries(RATE, SUM),
     get_changesets: timeseries(RATE, SUM),
     get_heads: timeseries(RATE, SUM),
@@ -83,12 +94,15 @@ define_stats! {
     get_hg_from_bonsai_changeset: timeseries(RATE, SUM),
     get_manifest_by_nodeid: timeseries(RATE, SUM),
     get_root_entry: timeseries(RATE, SUM),
+    walk_manifest: timeseries(RATE, SUM),
     get_bookmark: timeseries(RATE, SUM),
     get_bookmarks: timeseries(RATE, SUM),
     get_bonsai_from_hg: timeseries(RATE, SUM),
+    get_hg_changesets_by_prefix: timeseries(RATE, SUM),
     update_bookmark_transaction: timeseries(RATE, SUM),
     get_linknode: timeseries(RATE, SUM),
     get_all_filenodes: timeseries(RATE, SUM),
+    get_filenodes_page: timeseries(RATE, SUM),
     get_generation_number: timeseries(RATE, SUM),
     get_generation_number_by_bonsai: timeseries(RATE, SUM),
     upload_blob: timeseries(RATE, SUM),
@@ -113,8 +127,34 @@ pub struct ManifoldArgs {
     pub bucket: String,
     /// Prefix to be prepended to all the keys. In prod it should be ""
     pub prefix: String,
-    /// Identifies the SQL database to connect to.
+    /// Identifies the main SQL database of the repo
     pub db_address: String,
+    /// The SQL stores that are in a database other than the main one
+    pub store_db_addresses: StoreDbAddresses,
+    /// How failed Manifold requests are retried
+    pub blobstore_retry: RetryPolicy,
+    /// How failed SQL reads are retried
+    pub sql_retry: RetryPolicy,
+}
+
+/// Retry policy for Manifold requests, unless overridden in the repo config
+pub fn default_blobstore_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(50),
+        max_delay: Duration::from_secs(1),
+        jitter: true,
+    }
+}
+
+/// Retry policy for SQL reads, unless overridden in the repo config
+pub fn default_sql_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(20),
+        max_delay: Duration::from_millis(500),
+        jitter: true,
+    }
 }
 
 pub struct BlobRepo {
@@ -129,6 +169,7 @@ pub struct BlobRepo {
     // (for example, revsets).
     changeset_fetcher_factory: Arc<Fn() -> Arc<ChangesetFetcher + Send + Sync> + Send + Sync>,
     postcommit_queue: Arc<PostCommitQueue>,
+    live_changeset_handles: LiveChangesetHandles,
 }
 
 impl BlobRepo {
@@ -162,6 +203,7 @@ impl BlobRepo {
             repoid,
             changeset_fetcher_factory: Arc::new(changeset_fetcher_factory),
             postcommit_queue,
+            live_changeset_handles: LiveChangesetHandles::new(repoid),
         }
     }
 
@@ -186,6 +228,7 @@ impl BlobRepo {
             repoid,
             changeset_fetcher_factory,
             postcommit_queue,
+            live_changeset_handles: LiveChangesetHandles::new(repoid),
         }
     }
 
@@ -309,15 +352,24 @@ impl BlobRepo {
     ) -> Result<Self> {
         // TODO(stash): T28429403 use local region first, fallback to master if not found
         let connection_params = get_connection_params(
-            &args.db_address,
+            args.bookmarks_db_address(),
             InstanceRequirement::Master,
             None,
             Some(ProxyRequirement::Forbidden),
         )?;
+        let sql_retries = SqlRetries::new(args.sql_retry, is_transient_sql_error);
+
         let bookmarks = MysqlDbBookmarks::open(&connection_params)
             .chain_err(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+        let bookmarks = RetryingBookmarks::new(Arc::new(bookmarks), sql_retries);
 
         let blobstore = ThriftManifoldBlob::new(args.bucket.clone())?;
+        let blobstore = RetryingBlobstore::new(
+            "manifold",
+            blobstore,
+            args.blobstore_retry,
+            is_transient_blobstore_error,
+        );
         let blobstore = PrefixBlobstore::new(blobstore, format!("flat/{}", args.prefix));
         let blobstore = new_memcache_blobstore(blobstore, "manifold", args.bucket.as_ref())?;
         let blob_pool = Arc::new(cachelib::get_pool("blobstore-blobs").ok_or(Error::from(
@@ -329,26 +381,30 @@ impl BlobRepo {
             ))?);
         let blobstore = Arc::new(new_cachelib_blobstore(blobstore, blob_pool, presence_pool));
 
-        let filenodes = SqlFilenodes::with_myrouter(&args.db_address, myrouter_port);
+        let filenodes = SqlFilenodes::with_myrouter(args.filenodes_db_address(), myrouter_port);
+        let filenodes = RetryingFilenodes::new(Arc::new(filenodes), sql_retries);
         let filenodes = CachingFilenodes::new(
             Arc::new(filenodes),
             cachelib::get_pool("filenodes").ok_or(Error::from(ErrorKind::MissingCachePool(
                 "filenodes".to_string(),
             )))?,
             "dieselfilenodes",
-            &args.db_address,
+            args.filenodes_db_address(),
         );
 
-        let changesets = MysqlChangesets::open(&args.db_address)
-            .chain_err(ErrorKind::StateOpen(StateOpenError::Changesets))?;
+        let pooled_stores = open_pooled_stores(args, MysqlConnInner::open)?;
+
+        let changesets = MysqlChangesets::from(pooled_stores.changesets);
+        let changesets = RetryingChangesets::new(Arc::new(changesets), sql_retries);
         let changesets_cache_pool = cachelib::get_pool("changesets").ok_or(Error::from(
             ErrorKind::MissingCachePool("changesets".to_string()),
         ))?;
         let changesets = CachingChangests::new(Arc::new(changesets), changesets_cache_pool.clone());
         let changesets = Arc::new(changesets);
 
-        let bonsai_hg_mapping = MysqlBonsaiHgMapping::open(&args.db_address)
-            .chain_err(ErrorKind::StateOpen(StateOpenError::BonsaiHgMapping))?;
+        let bonsai_hg_mapping = MysqlBonsaiHgMapping::from(pooled_stores.bonsai_hg_mapping);
+        let bonsai_hg_mapping =
+            RetryingBonsaiHgMapping::new(Arc::new(bonsai_hg_mapping), sql_retries);
         let bonsai_hg_mapping = CachingBonsaiHgMapping::new(
             Arc::new(bonsai_hg_mapping),
             cachelib::get_pool("bonsai_hg_mapping").ok_or(Error::from(
@@ -420,6 +476,37 @@ impl BlobRepo {
         )
     }
 
+    /// Replace the filenodes storage of this BlobRepo. Used by tests to simulate a repo with
+    /// missing filenodes.
+    pub fn with_filenodes(self, filenodes: Arc<Filenodes>) -> BlobRepo {
+        let BlobRepo {
+            logger,
+            bookmarks,
+            blobstore,
+            changesets,
+            bonsai_hg_mapping,
+            repoid,
+            changeset_fetcher_factory,
+            postcommit_queue,
+            ..
+        } = self;
+
+        // Drop the PrefixBlobstore (it will be wrapped up in one again)
+        let blobstore = blobstore.into_inner();
+
+        BlobRepo::new_with_changeset_fetcher_factory(
+            logger,
+            bookmarks,
+            blobstore,
+            filenodes,
+            changesets,
+            bonsai_hg_mapping,
+            repoid,
+            postcommit_queue,
+            changeset_fetcher_factory,
+        )
+    }
+
     fn fetch<K>(&self, key: &K) -> impl Future<Item = K::Value, Error = Error> + Send
     where
         K: MononokeId,
@@ -467,6 +554,54 @@ impl BlobRepo {
         fetch_file_content_from_blobstore(&self.blobstore, *key).boxify()
     }
 
+    /// The `len` bytes at `offset` of the content of the file `key`, truncated at the end of the
+    /// file. Useful when only a header of the file is needed.
+    pub fn get_file_content_range(
+        &self,
+        key: &HgNodeHash,
+        offset: u64,
+        len: u64,
+    ) -> BoxFuture<Bytes, Error> {
+        STATS::get_file_content_range.add_value(1);
+        fetch_file_content_from_blobstore(&self.blobstore, *key)
+            .map(move |contents| file_contents_range(contents, offset, len))
+            .boxify()
+    }
+
+    /// The envelope of the file `key`, i.e. its parents, metadata, and the id and size of its
+    /// content, without the content itself
+    pub fn get_file_envelope(&self, key: &HgNodeHash) -> BoxFuture<HgFileEnvelope, Error> {
+        STATS::get_file_envelope.add_value(1);
+        fetch_file_envelope(&self.blobstore, *key).boxify()
+    }
+
+    /// The content of the file at `path` in changeset `changesetid`, in chunks of at most
+    /// `FILE_CONTENT_CHUNK_SIZE` bytes. File content is stored as a single blob, so it is fetched
+    /// whole before the first chunk is yielded.
+    pub fn get_file_content_stream(
+        &self,
+        changesetid: &HgChangesetId,
+        path: &MPath,
+    ) -> BoxStream<Bytes, Error> {
+        STATS::get_file_content_stream.add_value(1);
+        let repo = self.clone();
+        let path = path.clone();
+
+        self.get_changeset_by_changesetid(changesetid)
+            .and_then({
+                cloned!(repo, path);
+                move |cs| repo.find_file_in_manifest(&path, *cs.manifestid())
+            })
+            .and_then(move |filenode| match filenode {
+                Some(filenode) => Ok(filenode),
+                None => Err(ErrorKind::PathNotFound(path).into()),
+            })
+            .and_then(move |filenode| repo.get_file_content(&filenode.into_nodehash()))
+            .map(|contents| file_contents_chunks(contents, FILE_CONTENT_CHUNK_SIZE))
+            .flatten_stream()
+            .boxify()
+    }
+
     pub fn upload_file_content_by_alias(
         &self,
         _alias: Sha256,
@@ -678,6 +813,20 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Returns the entries of the manifest selected by `filter`, see
+    /// `manifest_utils::walk_manifest`
+    pub fn walk_manifest(
+        &self,
+        manifestid: &HgManifestId,
+        filter: PathFilter,
+    ) -> BoxStream<(MPath, Box<Entry + Sync>), Error> {
+        STATS::walk_manifest.add_value(1);
+        self.get_manifest_by_nodeid(manifestid)
+            .map(move |manifest| manifest_utils::walk_manifest(&manifest, None, filter))
+            .flatten_stream()
+            .boxify()
+    }
+
     pub fn get_root_entry(&self, manifestid: &HgManifestId) -> Box<Entry + Sync> {
         STATS::get_root_entry.add_value(1);
         Box::new(HgBlobEntry::new_root(self.blobstore.clone(), *manifestid))
@@ -750,6 +899,17 @@ impl BlobRepo {
         self.filenodes.get_all_filenodes(&path, &self.repoid)
     }
 
+    /// A page of the filenodes of the path, see `Filenodes::get_filenodes_page`
+    pub fn get_filenodes_page(
+        &self,
+        path: RepoPath,
+        after: Option<FilenodesPageToken>,
+        limit: usize,
+    ) -> BoxFuture<FilenodesPage, Error> {
+        STATS::get_filenodes_page.add_value(1);
+        self.filenodes.get_filenodes_page(&path, &self.repoid, after, limit)
+    }
+
     pub fn get_bonsai_from_hg(
         &self,
         hg_cs_id: &HgChangesetId,
@@ -759,6 +919,18 @@ impl BlobRepo {
             .get_bonsai_from_hg(self.repoid, *hg_cs_id)
     }
 
+    /// The hg changesets whose id starts with `prefix`, in order of their ids. At most `limit`
+    /// of them.
+    pub fn get_hg_changesets_by_prefix(
+        &self,
+        prefix: HgChangesetIdPrefix,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        STATS::get_hg_changesets_by_prefix.add_value(1);
+        self.bonsai_hg_mapping
+            .get_many_hg_by_prefix(self.repoid, prefix, limit)
+    }
+
     pub fn get_bonsai_changeset(
         &self,
         bonsai_cs_id: ChangesetId,
@@ -867,6 +1039,10 @@ impl BlobRepo {
         self.blobstore.clone()
     }
 
+    pub fn get_bookmarks_object(&self) -> Arc<Bookmarks> {
+        self.bookmarks.clone()
+    }
+
     pub fn get_logger(&self) -> Logger {
         self.logger.clone()
     }
@@ -875,6 +1051,11 @@ impl BlobRepo {
         self.repoid
     }
 
+    /// ChangesetHandles of this repo that are alive, shared by all clones of the repo.
+    pub fn live_changeset_handles(&self) -> &LiveChangesetHandles {
+        &self.live_changeset_handles
+    }
+
     pub fn get_filenodes(&self) -> Arc<Filenodes> {
         self.filenodes.clone()
     }
@@ -1091,6 +1272,34 @@ impl BlobRepo {
         })
     }
 
+    /// The manifest of the directory at `path` in `manifest`, the root manifest if `path` is
+    /// None, or None if there is no such directory
+    pub fn find_tree_in_manifest(
+        &self,
+        path: Option<MPath>,
+        manifest: HgManifestId,
+    ) -> impl Future<Item = Option<HgManifestId>, Error = Error> + Send {
+        let path = match path {
+            None => return future::ok(Some(manifest)).left_future(),
+            Some(path) => path,
+        };
+        let (dirname, basename) = path.split_dirname();
+        let basename = basename.clone();
+        self.find_path_in_manifest(dirname, manifest)
+            .map(move |content| match content {
+                Some(Content::Tree(manifest)) => match manifest.lookup(&basename) {
+                    Some(entry) => if entry.get_type() == Type::Tree {
+                        Some(HgManifestId::new(entry.get_hash().into_nodehash()))
+                    } else {
+                        None
+                    },
+                    None => None,
+                },
+                _ => None,
+            })
+            .right_future()
+    }
+
     pub fn get_manifest_from_bonsai(
         &self,
         bcs: BonsaiChangeset,
@@ -1701,7 +1910,12 @@ pub fn save_bonsai_changesets(
                 parents: bcs.parents().into_iter().cloned().collect(),
             };
             let pc = post_commit::PreCommitInfo::new(repo.repoid, bcs_id, bcs);
-            bonsai_complete_futs.push(complete_changesets.add(completion_record).and_then({
+            // Attribution goes first, so that parents are attributed before their children
+            let attribution = attribute_changeset(blobstore.clone(), bcs);
+            bonsai_complete_futs.push(attribution.and_then({
+                cloned!(complete_changesets);
+                move |_| complete_changesets.add(completion_record)
+            }).and_then({
                 cloned!(repo);
                 move |_| {
                     repo.get_generation_number_by_bonsai(pc.get_changeset_id())
@@ -1747,6 +1961,8 @@ pub struct CreateChangeset {
     pub sub_entries: BoxStream<(HgBlobEntry, RepoPath), Error>,
     pub cs_metadata: ChangesetMetadata,
     pub must_check_case_conflicts: bool,
+    /// Collects the metrics of the changeset, if they are wanted
+    pub metrics: Option<ChangesetMetricsCollector>,
 }
 
 impl CreateChangeset {
@@ -1764,12 +1980,37 @@ impl CreateChangeset {
         );
         let (signal_parent_ready, can_be_parent) = oneshot::channel();
         let expected_nodeid = self.expected_nodeid;
+        let metrics = self.metrics.map(PendingMetrics::new);
 
-        let upload_entries = process_entries(
-            repo.clone(),
-            &entry_processor,
-            self.root_manifest,
-            self.sub_entries,
+        let (root_manifest, sub_entries) = match metrics.clone() {
+            Some(metrics) => {
+                let root_manifest = self.root_manifest
+                    .and_then({
+                        cloned!(metrics);
+                        move |root_manifest| match root_manifest {
+                            Some((entry, path)) => metrics
+                                .count_entry(&entry)
+                                .map(move |()| Some((entry, path)))
+                                .left_future(),
+                            None => future::ok(None).right_future(),
+                        }
+                    })
+                    .boxify();
+                let sub_entries = self.sub_entries
+                    .map(move |(entry, path)| {
+                        metrics.count_entry(&entry).map(move |()| (entry, path))
+                    })
+                    .buffer_unordered(100)
+                    .boxify();
+                (root_manifest, sub_entries)
+            }
+            None => (self.root_manifest, self.sub_entries),
+        };
+
+        let upload_entries = time_phase(
+            &metrics,
+            |metrics| &mut metrics.upload_entries,
+            process_entries(repo.clone(), &entry_processor, root_manifest, sub_entries),
         ).context("While processing entries");
 
         let parents_complete = extract_parents_complete(&self.p1, &self.p2);
@@ -1782,7 +2023,7 @@ impl CreateChangeset {
                 .join(parents_data)
                 .from_err()
                 .and_then({
-                    cloned!(repo, repo.filenodes, repo.blobstore, mut scuba_logger);
+                    cloned!(repo, repo.filenodes, repo.blobstore, mut scuba_logger, metrics);
                     let expected_files = self.expected_files;
                     let cs_metadata = self.cs_metadata;
 
@@ -1816,19 +2057,28 @@ impl CreateChangeset {
                             future::ok(()).right_future()
                         };
 
-                        let changesets = files
-                            .join(check_case_conflicts)
+                        let changed_files = time_phase(
+                            &metrics,
+                            |metrics| &mut metrics.changed_files,
+                            files.join(check_case_conflicts),
+                        );
+                        let changesets = changed_files
                             .and_then(move |(files, ())| {
                                 STATS::create_changeset_cf_count.add_value(files.len() as i64);
                                 make_new_changeset(parents, root_hash, cs_metadata, files)
                             })
-                            .and_then(move |hg_cs| {
-                                create_bonsai_changeset_object(
-                                    hg_cs.clone(),
-                                    parent_manifest_hashes,
-                                    bonsai_parents,
-                                    repo.clone(),
-                                ).map(|bonsai_cs| (hg_cs, bonsai_cs))
+                            .and_then({
+                                cloned!(metrics);
+                                move |hg_cs| {
+                                    let bonsai_cs = create_bonsai_changeset_object(
+                                        hg_cs.clone(),
+                                        parent_manifest_hashes,
+                                        bonsai_parents,
+                                        repo.clone(),
+                                    );
+                                    time_phase(&metrics, |metrics| &mut metrics.bonsai, bonsai_cs)
+                                        .map(|bonsai_cs| (hg_cs, bonsai_cs))
+                                }
                             });
 
                         changesets
@@ -1878,7 +2128,7 @@ impl CreateChangeset {
                                             bonsai_cs.clone(),
                                         );
 
-                                        blobcs
+                                        let save = blobcs
                                             .save(blobstore)
                                             .join(bonsai_cs_fut)
                                             .context("While writing to blobstore")
@@ -1887,7 +2137,8 @@ impl CreateChangeset {
                                                     .finalize(filenodes, cs_id)
                                                     .context("While finalizing processing"),
                                             )
-                                            .from_err()
+                                            .from_err();
+                                        time_phase(&metrics, |metrics| &mut metrics.save, save)
                                             .map(move |_| (blobcs, bonsai_cs))
                                             .boxify()
                                     })();
@@ -1925,13 +2176,23 @@ impl CreateChangeset {
             });
 
         let complete_changesets = repo.changesets.clone();
+        let handle_repo = repo;
         cloned!(repo, repo.repoid);
         ChangesetHandle::new_pending(
+            handle_repo,
             can_be_parent.shared(),
             changeset
                 .join(parents_complete)
                 .and_then({
-                    cloned!(repo.bonsai_hg_mapping);
+                    cloned!(repo.blobstore);
+                    move |((hg_cs, bonsai_cs), _)| {
+                        attribute_changeset(blobstore, &bonsai_cs)
+                            .map(move |_| ((hg_cs, bonsai_cs), ()))
+                            .context("While attributing storage")
+                    }
+                })
+                .and_then({
+                    cloned!(repo.bonsai_hg_mapping, metrics);
                     move |((hg_cs, bonsai_cs), _)| {
                         let bcs_id = bonsai_cs.get_changeset_id();
                         let bonsai_hg_entry = BonsaiHgMappingEntry {
@@ -1940,39 +2201,48 @@ impl CreateChangeset {
                             bcs_id,
                         };
 
-                        bonsai_hg_mapping
-                            .add(bonsai_hg_entry)
+                        let add_mapping = bonsai_hg_mapping.add(bonsai_hg_entry);
+                        time_phase(&metrics, |metrics| &mut metrics.commit, add_mapping)
                             .map(move |_| (hg_cs, bonsai_cs))
                             .context("While inserting mapping")
                     }
                 })
-                .and_then(move |(hg_cs, bonsai_cs)| {
-                    let pc = post_commit::PreCommitInfo::new(
-                        repoid,
-                        bonsai_cs.get_changeset_id(),
-                        &bonsai_cs,
-                    );
-                    let completion_record = ChangesetInsert {
-                        repo_id: repo.repoid,
-                        cs_id: bonsai_cs.get_changeset_id(),
-                        parents: bonsai_cs.parents().into_iter().cloned().collect(),
-                    };
-                    complete_changesets
-                        .add(completion_record)
-                        .and_then({
-                            cloned!(repo);
-                            move |_| {
-                                repo.get_generation_number_by_bonsai(pc.get_changeset_id())
-                                    .map(move |gen| {
-                                        pc.complete(gen.expect(
-                                            "Just inserted changeset has no generation number",
-                                        ))
-                                    })
-                                    .and_then(move |pc| repo.postcommit_queue.queue_commit(pc))
-                            }
-                        })
-                        .map(|_| (bonsai_cs, hg_cs))
-                        .context("While inserting into changeset table")
+                .and_then({
+                    cloned!(metrics);
+                    move |(hg_cs, bonsai_cs)| {
+                        let pc = post_commit::PreCommitInfo::new(
+                            repoid,
+                            bonsai_cs.get_changeset_id(),
+                            &bonsai_cs,
+                        );
+                        let completion_record = ChangesetInsert {
+                            repo_id: repo.repoid,
+                            cs_id: bonsai_cs.get_changeset_id(),
+                            parents: bonsai_cs.parents().into_iter().cloned().collect(),
+                        };
+                        let add_changeset = complete_changesets.add(completion_record);
+                        time_phase(&metrics, |metrics| &mut metrics.commit, add_changeset)
+                            .and_then({
+                                cloned!(repo);
+                                move |_| {
+                                    repo.get_generation_number_by_bonsai(pc.get_changeset_id())
+                                        .map(move |gen| {
+                                            pc.complete(gen.expect(
+                                                "Just inserted changeset has no generation number",
+                                            ))
+                                        })
+                                        .and_then(move |pc| repo.postcommit_queue.queue_commit(pc))
+                                }
+                            })
+                            .map(|_| (bonsai_cs, hg_cs))
+                            .context("While inserting into changeset table")
+                    }
+                })
+                .map(move |(bonsai_cs, hg_cs)| {
+                    if let Some(metrics) = metrics {
+                        metrics.finish(hg_cs.get_changeset_id());
+                    }
+                    (bonsai_cs, hg_cs)
                 })
                 .with_context(move |_| {
                     format!(
@@ -1991,8 +2261,7 @@ impl CreateChangeset {
                         Ok(())
                     }
                 })
-                .boxify()
-                .shared(),
+                .boxify(),
         )
     }
 }
@@ -2009,6 +2278,7 @@ impl Clone for BlobRepo {
             repoid: self.repoid.clone(),
             changeset_fetcher_factory: self.changeset_fetcher_factory.clone(),
             postcommit_queue: self.postcommit_queue.clone(),
+            live_changeset_handles: self.live_changeset_handles.clone(),
         }
     }
 }
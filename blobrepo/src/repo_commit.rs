@@ -7,6 +7,7 @@
 use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use failure::{err_msg, Compat, Error, FutureFailureErrorExt, Result, StreamFailureErrorExt,
               prelude::*};
@@ -17,7 +18,7 @@ use futures::sync::oneshot;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use futures_stats::Timed;
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
-use stats::Timeseries;
+use stats::{DynamicTimeseries, Timeseries};
 
 use blobstore::Blobstore;
 use filenodes::{FilenodeInfo, Filenodes};
@@ -48,6 +49,52 @@ define_stats! {
     finalize_uploaded_filenodes: timeseries(RATE, AVG, SUM),
     finalize_uploaded_manifests: timeseries(RATE, AVG, SUM),
     finalize_compute_copy_from_info: timeseries(RATE, SUM),
+    live_changeset_handles: dynamic_timeseries(
+        "{}.live_changeset_handles", (repoid: i32); AVG, MAX),
+}
+
+/// Accounting of the ChangesetHandles of a repo that are still alive, either because a clone of
+/// the handle is still held or because its upload has not finished yet. Every push should bring
+/// this back to where it started, whether it succeeded or failed; anything else means that
+/// per-push state is leaking.
+#[derive(Clone)]
+pub struct LiveChangesetHandles {
+    repoid: RepositoryId,
+    count: Arc<AtomicUsize>,
+}
+
+impl LiveChangesetHandles {
+    pub fn new(repoid: RepositoryId) -> Self {
+        Self {
+            repoid,
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    fn track(&self) -> Arc<LiveChangesetHandle> {
+        let live = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        STATS::live_changeset_handles.add_value(live as i64, (self.repoid.id(),));
+        Arc::new(LiveChangesetHandle {
+            handles: self.clone(),
+        })
+    }
+}
+
+/// Shared by all clones of a ChangesetHandle and by its upload future; dropping the last of
+/// them releases the handle from the accounting.
+struct LiveChangesetHandle {
+    handles: LiveChangesetHandles,
+}
+
+impl Drop for LiveChangesetHandle {
+    fn drop(&mut self) {
+        let live = self.handles.count.fetch_sub(1, Ordering::SeqCst) - 1;
+        STATS::live_changeset_handles.add_value(live as i64, (self.handles.repoid.id(),));
+    }
 }
 
 /// A handle to a possibly incomplete HgBlobChangeset. This is used instead of
@@ -64,16 +111,32 @@ pub struct ChangesetHandle {
     //   SharedError) doesn't implement Fail, and only implements Error if the wrapped type
     //   implements Error.
     completion_future: Shared<BoxFuture<(BonsaiChangeset, HgBlobChangeset), Compat<Error>>>,
+    _live: Arc<LiveChangesetHandle>,
 }
 
 impl ChangesetHandle {
+    /// Create a handle for a changeset that is still being uploaded. The upload future keeps the
+    /// handle accounted as live until it completes, even if all clones of the handle are dropped.
     pub fn new_pending(
+        repo: &BlobRepo,
         can_be_parent: Shared<oneshot::Receiver<(ChangesetId, HgNodeHash, HgManifestId)>>,
-        completion_future: Shared<BoxFuture<(BonsaiChangeset, HgBlobChangeset), Compat<Error>>>,
+        completion_future: BoxFuture<(BonsaiChangeset, HgBlobChangeset), Compat<Error>>,
     ) -> Self {
+        let live = repo.live_changeset_handles().track();
+        let completion_future = completion_future
+            .then({
+                let live = live.clone();
+                move |res| {
+                    drop(live);
+                    res
+                }
+            })
+            .boxify()
+            .shared();
         Self {
             can_be_parent,
             completion_future,
+            _live: live,
         }
     }
 
@@ -92,6 +155,7 @@ impl ChangesetHandle {
         let (trigger, can_be_parent) = oneshot::channel();
         let fut = bonsai_cs.join(cs);
         Self {
+            _live: repo.live_changeset_handles().track(),
             can_be_parent: can_be_parent.shared(),
             completion_future: fut.map_err(Error::compat)
                 .inspect(move |(bonsai_cs, hg_cs)| {
@@ -0,0 +1,276 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Wrappers that retry read queries of the SQL-backed storage after transient errors.
+//!
+//! Writes are passed through as they are: a retried insert whose first attempt did commit would
+//! report a different result than the first attempt (e.g. that the changeset already existed), so
+//! none of them can be treated as idempotent.
+
+use std::sync::Arc;
+
+use failure::Error;
+use futures::{stream, Future, Stream};
+use futures_ext::{retry, BoxFuture, BoxStream, FutureExt, RetryPolicy, StreamExt};
+use stats::DynamicTimeseries;
+
+use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, BonsaiOrHgChangesetId};
+use bookmarks::{Bookmark, BookmarkPrefix, BookmarkUpdateLogEntry, Bookmarks, Transaction};
+use changesets::{ChangesetEntry, ChangesetInsert, Changesets};
+use filenodes::{FilenodeInfo, Filenodes, FilenodesPage, FilenodesPageToken};
+use mercurial_types::{HgChangesetId, HgChangesetIdPrefix, HgFileNodeId, RepoPath, RepositoryId};
+use mononoke_types::ChangesetId;
+
+define_stats! {
+    prefix = "mononoke.sql";
+    retry: dynamic_timeseries("{}.retry", (name: &'static str); RATE, SUM),
+}
+
+/// Policy and error classification shared by all the wrappers of a repo
+#[derive(Clone, Copy)]
+pub struct SqlRetries {
+    name: &'static str,
+    policy: RetryPolicy,
+    is_retryable: fn(&Error) -> bool,
+}
+
+impl SqlRetries {
+    pub fn new(policy: RetryPolicy, is_retryable: fn(&Error) -> bool) -> Self {
+        Self {
+            name: "",
+            policy,
+            is_retryable,
+        }
+    }
+
+    fn named(self, name: &'static str) -> Self {
+        Self { name, ..self }
+    }
+
+    fn run<I, F>(&self, mut func: F) -> BoxFuture<I, Error>
+    where
+        I: Send + 'static,
+        F: FnMut() -> BoxFuture<I, Error> + Send + 'static,
+    {
+        let name = self.name;
+        let mut attempt = 0;
+        retry(self.policy, self.is_retryable, move || {
+            attempt += 1;
+            if attempt > 1 {
+                STATS::retry.add_value(1, (name,));
+            }
+            func()
+        }).boxify()
+    }
+}
+
+pub struct RetryingChangesets {
+    changesets: Arc<Changesets>,
+    retries: SqlRetries,
+}
+
+impl RetryingChangesets {
+    pub fn new(changesets: Arc<Changesets>, retries: SqlRetries) -> Self {
+        Self {
+            changesets,
+            retries: retries.named("changesets"),
+        }
+    }
+}
+
+impl Changesets for RetryingChangesets {
+    fn add(&self, cs: ChangesetInsert) -> BoxFuture<bool, Error> {
+        self.changesets.add(cs)
+    }
+
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: ChangesetId,
+    ) -> BoxFuture<Option<ChangesetEntry>, Error> {
+        let changesets = self.changesets.clone();
+        self.retries.run(move || changesets.get(repo_id, cs_id))
+    }
+}
+
+pub struct RetryingBonsaiHgMapping {
+    mapping: Arc<BonsaiHgMapping>,
+    retries: SqlRetries,
+}
+
+impl RetryingBonsaiHgMapping {
+    pub fn new(mapping: Arc<BonsaiHgMapping>, retries: SqlRetries) -> Self {
+        Self {
+            mapping,
+            retries: retries.named("bonsai_hg_mapping"),
+        }
+    }
+}
+
+impl BonsaiHgMapping for RetryingBonsaiHgMapping {
+    fn add(&self, entry: BonsaiHgMappingEntry) -> BoxFuture<bool, Error> {
+        self.mapping.add(entry)
+    }
+
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        cs_id: BonsaiOrHgChangesetId,
+    ) -> BoxFuture<Option<BonsaiHgMappingEntry>, Error> {
+        let mapping = self.mapping.clone();
+        self.retries.run(move || mapping.get(repo_id, cs_id))
+    }
+
+    fn get_many_hg_by_prefix(
+        &self,
+        repo_id: RepositoryId,
+        prefix: HgChangesetIdPrefix,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        let mapping = self.mapping.clone();
+        self.retries.run(move || mapping.get_many_hg_by_prefix(repo_id, prefix, limit))
+    }
+}
+
+pub struct RetryingFilenodes {
+    filenodes: Arc<Filenodes>,
+    retries: SqlRetries,
+}
+
+impl RetryingFilenodes {
+    pub fn new(filenodes: Arc<Filenodes>, retries: SqlRetries) -> Self {
+        Self {
+            filenodes,
+            retries: retries.named("filenodes"),
+        }
+    }
+}
+
+impl Filenodes for RetryingFilenodes {
+    fn add_filenodes(
+        &self,
+        info: BoxStream<FilenodeInfo, Error>,
+        repo_id: &RepositoryId,
+    ) -> BoxFuture<(), Error> {
+        self.filenodes.add_filenodes(info, repo_id)
+    }
+
+    fn get_filenode(
+        &self,
+        path: &RepoPath,
+        filenode: &HgFileNodeId,
+        repo_id: &RepositoryId,
+    ) -> BoxFuture<Option<FilenodeInfo>, Error> {
+        let filenodes = self.filenodes.clone();
+        let (path, filenode, repo_id) = (path.clone(), *filenode, *repo_id);
+        self.retries
+            .run(move || filenodes.get_filenode(&path, &filenode, &repo_id))
+    }
+
+    fn get_all_filenodes(
+        &self,
+        path: &RepoPath,
+        repo_id: &RepositoryId,
+    ) -> BoxFuture<Vec<FilenodeInfo>, Error> {
+        let filenodes = self.filenodes.clone();
+        let (path, repo_id) = (path.clone(), *repo_id);
+        self.retries
+            .run(move || filenodes.get_all_filenodes(&path, &repo_id))
+    }
+
+    fn get_filenodes_page(
+        &self,
+        path: &RepoPath,
+        repo_id: &RepositoryId,
+        after: Option<FilenodesPageToken>,
+        limit: usize,
+    ) -> BoxFuture<FilenodesPage, Error> {
+        let filenodes = self.filenodes.clone();
+        let (path, repo_id) = (path.clone(), *repo_id);
+        self.retries
+            .run(move || filenodes.get_filenodes_page(&path, &repo_id, after, limit))
+    }
+}
+
+pub struct RetryingBookmarks {
+    bookmarks: Arc<Bookmarks>,
+    retries: SqlRetries,
+}
+
+impl RetryingBookmarks {
+    pub fn new(bookmarks: Arc<Bookmarks>, retries: SqlRetries) -> Self {
+        Self {
+            bookmarks,
+            retries: retries.named("bookmarks"),
+        }
+    }
+}
+
+impl Bookmarks for RetryingBookmarks {
+    fn get(&self, name: &Bookmark, repoid: &RepositoryId) -> BoxFuture<Option<ChangesetId>, Error> {
+        let bookmarks = self.bookmarks.clone();
+        let (name, repoid) = (name.clone(), *repoid);
+        self.retries.run(move || bookmarks.get(&name, &repoid))
+    }
+
+    fn list_by_prefix(
+        &self,
+        prefix: &BookmarkPrefix,
+        repoid: &RepositoryId,
+    ) -> BoxStream<(Bookmark, ChangesetId), Error> {
+        // The list is collected, so that a failure in the middle of it doesn't produce
+        // duplicated bookmarks when the query is retried
+        let bookmarks = self.bookmarks.clone();
+        let (prefix, repoid) = (prefix.clone(), *repoid);
+        self.retries
+            .run(move || bookmarks.list_by_prefix(&prefix, &repoid).collect().boxify())
+            .map(stream::iter_ok)
+            .flatten_stream()
+            .boxify()
+    }
+
+    fn list_by_prefix_with_timestamps(
+        &self,
+        prefix: &BookmarkPrefix,
+        repoid: &RepositoryId,
+    ) -> BoxStream<(Bookmark, ChangesetId, Option<i64>), Error> {
+        // Collected for the same reason as in `list_by_prefix`
+        let bookmarks = self.bookmarks.clone();
+        let (prefix, repoid) = (prefix.clone(), *repoid);
+        self.retries
+            .run(move || {
+                bookmarks
+                    .list_by_prefix_with_timestamps(&prefix, &repoid)
+                    .collect()
+                    .boxify()
+            })
+            .map(stream::iter_ok)
+            .flatten_stream()
+            .boxify()
+    }
+
+    fn read_update_log(
+        &self,
+        name: &Bookmark,
+        repoid: &RepositoryId,
+    ) -> BoxStream<BookmarkUpdateLogEntry, Error> {
+        let bookmarks = self.bookmarks.clone();
+        let (name, repoid) = (name.clone(), *repoid);
+        self.retries
+            .run(move || bookmarks.read_update_log(&name, &repoid).collect().boxify())
+            .map(stream::iter_ok)
+            .flatten_stream()
+            .boxify()
+    }
+
+    fn create_transaction(&self, repoid: &RepositoryId) -> Box<Transaction> {
+        self.bookmarks.create_transaction(repoid)
+    }
+
+    fn atomic_transactions(&self) -> bool {
+        self.bookmarks.atomic_transactions()
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The SQL stores of a Manifold repo can each be in a database of their own, so that large
+//! stores like filenodes can be moved to their own shard.
+
+use std::collections::HashMap;
+
+use failure::Result;
+
+use repo::ManifoldArgs;
+
+/// Databases of the SQL stores of a Manifold repo. Stores that don't have one of their own are
+/// in the main database of the repo.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StoreDbAddresses {
+    pub bookmarks: Option<String>,
+    pub changesets: Option<String>,
+    pub filenodes: Option<String>,
+    pub bonsai_hg_mapping: Option<String>,
+}
+
+impl ManifoldArgs {
+    pub fn bookmarks_db_address(&self) -> &str {
+        self.store_db_address(&self.store_db_addresses.bookmarks)
+    }
+
+    pub fn changesets_db_address(&self) -> &str {
+        self.store_db_address(&self.store_db_addresses.changesets)
+    }
+
+    pub fn filenodes_db_address(&self) -> &str {
+        self.store_db_address(&self.store_db_addresses.filenodes)
+    }
+
+    pub fn bonsai_hg_mapping_db_address(&self) -> &str {
+        self.store_db_address(&self.store_db_addresses.bonsai_hg_mapping)
+    }
+
+    /// All the databases that the repo connects to, each once, starting with the main one
+    pub fn distinct_db_addresses(&self) -> Vec<&str> {
+        let mut addresses = vec![self.db_address.as_str()];
+        for address in &[
+            self.bookmarks_db_address(),
+            self.changesets_db_address(),
+            self.filenodes_db_address(),
+            self.bonsai_hg_mapping_db_address(),
+        ] {
+            if !addresses.contains(address) {
+                addresses.push(address);
+            }
+        }
+        addresses
+    }
+
+    fn store_db_address<'a>(&'a self, address: &'a Option<String>) -> &'a str {
+        match *address {
+            Some(ref address) => address,
+            None => &self.db_address,
+        }
+    }
+}
+
+/// Connections of the stores that connect through a pool of their own rather than myrouter
+pub(crate) struct PooledStores<T> {
+    pub changesets: T,
+    pub bonsai_hg_mapping: T,
+}
+
+/// Connects the pooled stores of the repo with `connect`, which is called once per distinct
+/// database, so that stores in the same database share their connections.
+pub(crate) fn open_pooled_stores<T, F>(
+    args: &ManifoldArgs,
+    mut connect: F,
+) -> Result<PooledStores<T>>
+where
+    T: Clone,
+    F: FnMut(&str) -> Result<T>,
+{
+    let mut connections: HashMap<String, T> = HashMap::new();
+    let mut get_connection = |address: &str| -> Result<T> {
+        if let Some(connection) = connections.get(address) {
+            return Ok(connection.clone());
+        }
+        let connection = connect(address)?;
+        connections.insert(address.to_string(), connection.clone());
+        Ok(connection)
+    };
+
+    Ok(PooledStores {
+        changesets: get_connection(args.changesets_db_address())?,
+        bonsai_hg_mapping: get_connection(args.bonsai_hg_mapping_db_address())?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use repo::{default_blobstore_retry_policy, default_sql_retry_policy};
+
+    fn manifold_args(store_db_addresses: StoreDbAddresses) -> ManifoldArgs {
+        ManifoldArgs {
+            bucket: "bucket".to_string(),
+            prefix: "".to_string(),
+            db_address: "xdb.main".to_string(),
+            store_db_addresses,
+            blobstore_retry: default_blobstore_retry_policy(),
+            sql_retry: default_sql_retry_policy(),
+        }
+    }
+
+    /// Opens the pooled stores with a connector that records the addresses it connects to, and
+    /// whose connections are the addresses themselves
+    fn open_stub(args: &ManifoldArgs) -> (PooledStores<String>, Vec<String>) {
+        let mut connected = vec![];
+        let stores = open_pooled_stores(args, |address| {
+            connected.push(address.to_string());
+            Ok(address.to_string())
+        }).unwrap();
+        (stores, connected)
+    }
+
+    #[test]
+    fn test_default_db_addresses() {
+        let args = manifold_args(StoreDbAddresses::default());
+        assert_eq!(args.bookmarks_db_address(), "xdb.main");
+        assert_eq!(args.changesets_db_address(), "xdb.main");
+        assert_eq!(args.filenodes_db_address(), "xdb.main");
+        assert_eq!(args.bonsai_hg_mapping_db_address(), "xdb.main");
+        assert_eq!(args.distinct_db_addresses(), vec!["xdb.main"]);
+
+        let (stores, connected) = open_stub(&args);
+        assert_eq!(stores.changesets, "xdb.main");
+        assert_eq!(stores.bonsai_hg_mapping, "xdb.main");
+        assert_eq!(connected, vec!["xdb.main"]);
+    }
+
+    #[test]
+    fn test_store_db_addresses() {
+        let args = manifold_args(StoreDbAddresses {
+            bookmarks: None,
+            changesets: Some("xdb.changesets".to_string()),
+            filenodes: Some("xdb.filenodes".to_string()),
+            bonsai_hg_mapping: Some("xdb.changesets".to_string()),
+        });
+        assert_eq!(args.bookmarks_db_address(), "xdb.main");
+        assert_eq!(args.changesets_db_address(), "xdb.changesets");
+        assert_eq!(args.filenodes_db_address(), "xdb.filenodes");
+        assert_eq!(args.bonsai_hg_mapping_db_address(), "xdb.changesets");
+        assert_eq!(
+            args.distinct_db_addresses(),
+            vec!["xdb.main", "xdb.changesets", "xdb.filenodes"]
+        );
+
+        // Changesets and the mapping are in the same database, so they share a connection
+        let (stores, connected) = open_stub(&args);
+        assert_eq!(stores.changesets, "xdb.changesets");
+        assert_eq!(stores.bonsai_hg_mapping, "xdb.changesets");
+        assert_eq!(connected, vec!["xdb.changesets"]);
+    }
+
+    #[test]
+    fn test_connect_failure() {
+        let args = manifold_args(StoreDbAddresses {
+            bonsai_hg_mapping: Some("xdb.mapping".to_string()),
+            ..Default::default()
+        });
+        let res = open_pooled_stores(&args, |address| {
+            if address == "xdb.mapping" {
+                Err(format_err!("can't connect to {}", address))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(res.is_err());
+    }
+}
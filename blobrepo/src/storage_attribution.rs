@@ -0,0 +1,179 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Attribution of the bytes of file contents to the changeset that introduced them, kept in the
+//! blobstore of the repo.
+//!
+//! When a changeset is created, each distinct content id of its file changes is checked against a
+//! marker under `CONTENT_INTRODUCED_PREFIX`. The contents without a marker are introduced by the
+//! changeset: their markers are written with its id, and their sizes are summed into the
+//! `StorageAttribution` of the changeset, stored under `STORAGE_ATTRIBUTION_PREFIX`.
+//!
+//! Accounting is by content, not by path, so:
+//! - a copy or a move of a file without changing it introduces no bytes,
+//! - a revert, or any change that brings back content the repo already had, introduces no bytes,
+//!   even if no parent has the content any more,
+//! - two files with the same content in one changeset count once,
+//! - a deletion introduces no bytes.
+//!
+//! Parents are attributed before their children, but changesets that are created concurrently
+//! and aren't ancestors of each other can both claim the same new content. Changesets created
+//! before attribution existed have no attribution, and their contents have no markers.
+
+use std::collections::HashSet;
+use std::str;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json;
+
+use blobstore::Blobstore;
+use mononoke_types::{BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId};
+
+use errors::*;
+use repo::{BlobRepo, RepoBlobstore};
+
+pub const CONTENT_INTRODUCED_PREFIX: &str = "content_introduced.";
+pub const STORAGE_ATTRIBUTION_PREFIX: &str = "storage_attribution.";
+
+/// Markers checked at once when attributing a changeset
+const ATTRIBUTION_CONCURRENCY: usize = 100;
+
+/// File contents that a changeset introduced to the repo
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StorageAttribution {
+    /// Number of distinct contents
+    pub new_contents: u64,
+    /// Sum of the sizes of the contents
+    pub new_bytes: u64,
+}
+
+fn content_introduced_key(content_id: &ContentId) -> String {
+    format!("{}{}", CONTENT_INTRODUCED_PREFIX, content_id.to_hex())
+}
+
+fn storage_attribution_key(changeset_id: &ChangesetId) -> String {
+    format!("{}{}", STORAGE_ATTRIBUTION_PREFIX, changeset_id.to_hex())
+}
+
+/// Computes and stores the attribution of `bcs`, whose parents must already be attributed
+pub(crate) fn attribute_changeset(
+    blobstore: RepoBlobstore,
+    bcs: &BonsaiChangeset,
+) -> BoxFuture<StorageAttribution, Error> {
+    let changeset_id = bcs.get_changeset_id();
+    let mut seen = HashSet::new();
+    let contents: Vec<_> = bcs.file_changes()
+        .filter_map(|(_, change)| change)
+        .filter(|change| seen.insert(*change.content_id()))
+        .map(|change| (*change.content_id(), change.size()))
+        .collect();
+
+    stream::iter_ok(contents)
+        .map({
+            cloned!(blobstore);
+            move |(content_id, size)| {
+                let key = content_introduced_key(&content_id);
+                cloned!(blobstore);
+                blobstore.is_present(key.clone()).and_then(move |present| {
+                    if present {
+                        future::ok(None).left_future()
+                    } else {
+                        let introduced_by = changeset_id.to_hex().to_string();
+                        blobstore
+                            .put(key, BlobstoreBytes::from_bytes(Bytes::from(introduced_by)))
+                            .map(move |()| Some(size))
+                            .right_future()
+                    }
+                })
+            }
+        })
+        .buffer_unordered(ATTRIBUTION_CONCURRENCY)
+        .fold(StorageAttribution::default(), |mut attribution, size| {
+            if let Some(size) = size {
+                attribution.new_contents += 1;
+                attribution.new_bytes += size;
+            }
+            Ok::<_, Error>(attribution)
+        })
+        .and_then(move |attribution| {
+            put_storage_attribution(&blobstore, changeset_id, attribution)
+                .map(move |()| attribution)
+        })
+        .boxify()
+}
+
+fn put_storage_attribution(
+    blobstore: &RepoBlobstore,
+    changeset_id: ChangesetId,
+    attribution: StorageAttribution,
+) -> BoxFuture<(), Error> {
+    let blob = try_boxfuture!(serde_json::to_vec(&attribution));
+    blobstore
+        .put(
+            storage_attribution_key(&changeset_id),
+            BlobstoreBytes::from_bytes(Bytes::from(blob)),
+        )
+        .boxify()
+}
+
+/// The attribution of a changeset, None if it was created before attribution existed
+pub fn get_storage_attribution(
+    repo: &BlobRepo,
+    changeset_id: ChangesetId,
+) -> BoxFuture<Option<StorageAttribution>, Error> {
+    repo.get_blobstore()
+        .get(storage_attribution_key(&changeset_id))
+        .and_then(move |blob| match blob {
+            Some(blob) => serde_json::from_slice(blob.as_bytes().as_ref())
+                .map(Some)
+                .map_err(|err| {
+                    Error::from(ErrorKind::StorageAttributionInvalid(
+                        changeset_id,
+                        err.to_string(),
+                    ))
+                }),
+            None => Ok(None),
+        })
+        .boxify()
+}
+
+/// Gives `to` the attribution of `from`. A changeset that is rewritten, e.g. rebased, introduces
+/// the same contents as the original, but finds the markers of the original.
+pub fn copy_storage_attribution(
+    repo: &BlobRepo,
+    from: ChangesetId,
+    to: ChangesetId,
+) -> BoxFuture<(), Error> {
+    let blobstore = repo.get_blobstore();
+    get_storage_attribution(repo, from)
+        .and_then(move |attribution| match attribution {
+            Some(attribution) => put_storage_attribution(&blobstore, to, attribution),
+            None => future::ok(()).boxify(),
+        })
+        .boxify()
+}
+
+/// The changeset that introduced a content, None if no attributed changeset did
+pub fn get_content_introduction(
+    repo: &BlobRepo,
+    content_id: ContentId,
+) -> BoxFuture<Option<ChangesetId>, Error> {
+    repo.get_blobstore()
+        .get(content_introduced_key(&content_id))
+        .and_then(|blob| -> Result<Option<ChangesetId>, Error> {
+            match blob {
+                Some(blob) => {
+                    let introduced_by = str::from_utf8(blob.as_bytes().as_ref())?;
+                    Ok(Some(ChangesetId::from_str(introduced_by)?))
+                }
+                None => Ok(None),
+            }
+        })
+        .boxify()
+}
@@ -26,34 +26,42 @@ extern crate mercurial;
 extern crate mercurial_types;
 extern crate mercurial_types_mocks;
 extern crate mononoke_types;
+extern crate mononoke_types_mocks;
 extern crate tests_utils;
 
 use failure::Error;
 use fixtures::{many_files_dirs, merge_uneven};
-use futures::Future;
+use futures::{future, Future, Stream};
 use futures_ext::{BoxFuture, FutureExt};
 use quickcheck::{quickcheck, Arbitrary, Gen, TestResult, Testable};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::marker::PhantomData;
-use std::sync::Arc;
-
-use blobrepo::{compute_changed_files, BlobRepo, ErrorKind};
-use blobstore::{Blobstore, LazyMemblob, PrefixBlobstore};
+use std::sync::{Arc, Mutex};
+
+use blobrepo::{compute_changed_files, BlobRepo, ChangesetMetricsCollector, ErrorKind,
+               FILE_CONTENT_CHUNK_SIZE};
+use blobrepo::storage_attribution::{copy_storage_attribution, get_content_introduction,
+                                    get_storage_attribution};
+use blobstore::{Blobstore, EagerMemblob, LazyMemblob, PrefixBlobstore};
+use mercurial_types::manifest_utils::PathFilter;
 use mercurial_types::{manifest, Changeset, Entry, FileType, HgChangesetId, HgEntryId,
-                      HgManifestId, HgParents, MPath, MPathElement, RepoPath, RepositoryId};
-use mononoke_types::{BonsaiChangeset, ChangesetId, ContentId, DateTime, FileChange, FileContents,
-                     MononokeId};
+                      HgManifestId, HgParents, MPath, MPathElement, PathMatcher, RepoPath,
+                      RepositoryId};
+use mononoke_types::{BlobstoreBytes, BonsaiChangeset, ChangesetId, ContentId, DateTime, FileChange,
+                     FileContents, MononokeId};
 use mononoke_types::bonsai_changeset::BonsaiChangesetMut;
+use mononoke_types_mocks::changesetid::ONES_CSID;
 
 #[macro_use]
 mod utils;
 mod memory_manifest;
 
-use utils::{create_changeset_no_parents, create_changeset_one_parent, get_empty_eager_repo,
-            get_empty_lazy_repo, run_future, string_to_nodehash, upload_file_no_parents,
-            upload_file_one_parent, upload_manifest_no_parents, upload_manifest_one_parent};
+use utils::{create_changeset_no_parents, create_changeset_no_parents_with_metrics,
+            create_changeset_one_parent, get_empty_eager_repo, get_empty_lazy_repo, run_future,
+            string_to_nodehash, upload_file_no_parents, upload_file_one_parent,
+            upload_manifest_no_parents, upload_manifest_one_parent};
 
-use tests_utils::{create_commit, store_files};
+use tests_utils::{create_commit, store_files, store_rename};
 
 fn upload_blob_no_parents(repo: BlobRepo) {
     let expected_hash = string_to_nodehash("c3127cdbf2eae0f09653f9237d85c8436425b246");
@@ -216,6 +224,47 @@ test_both_repotypes!(
     create_one_changeset_eager
 );
 
+fn create_changeset_with_metrics(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("dir/file").expect("Can't generate fake RepoPath");
+    let fake_dir_path = RepoPath::dir("dir").expect("Can't generate fake RepoPath");
+
+    let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &fake_file_path);
+    let dir_manifest = format!("file\0{}\n", filehash);
+    let (dirhash, manifest_dir_future) =
+        upload_manifest_no_parents(&repo, dir_manifest.clone(), &fake_dir_path);
+    let root_manifest = format!("dir\0{}t\n", dirhash);
+    let (_, root_manifest_future) =
+        upload_manifest_no_parents(&repo, root_manifest.clone(), &RepoPath::root());
+
+    let collector = ChangesetMetricsCollector::new();
+    let commit = create_changeset_no_parents_with_metrics(
+        &repo,
+        root_manifest_future.map(Some).boxify(),
+        vec![file_future, manifest_dir_future],
+        Some(collector.clone()),
+    );
+    let csid = run_future(commit.get_completed_changeset())
+        .unwrap()
+        .1
+        .get_changeset_id();
+
+    let metrics = collector.take(&csid).expect("metrics are missing");
+    assert_eq!(metrics.file_entries, 1);
+    assert_eq!(metrics.file_bytes, 4);
+    assert_eq!(metrics.tree_entries, 2);
+    assert_eq!(
+        metrics.tree_bytes,
+        (dir_manifest.len() + root_manifest.len()) as u64
+    );
+    assert_eq!(collector.take(&csid), None);
+}
+
+test_both_repotypes!(
+    create_changeset_with_metrics,
+    create_changeset_with_metrics_lazy,
+    create_changeset_with_metrics_eager
+);
+
 fn create_two_changesets(repo: BlobRepo) {
     let fake_file_path = RepoPath::file("dir/file").expect("Can't generate fake RepoPath");
     let fake_dir_path = RepoPath::dir("dir").expect("Can't generate fake RepoPath");
@@ -915,6 +964,65 @@ fn test_case_conflict_inside_one_changeset() {
     });
 }
 
+#[test]
+fn test_failed_changesets_release_handles() {
+    async_unit::tokio_unit_test(|| {
+        let repo = get_empty_lazy_repo();
+        assert_eq!(repo.live_changeset_handles().count(), 0);
+
+        for _ in 0..5 {
+            // A stack of changesets on top of one that fails because of a case conflict, so
+            // that all of them fail together like a rejected push
+            let fake_file_path_1 = RepoPath::file("file").expect("Can't generate fake RepoPath");
+            let (filehash_1, file_future_1) =
+                upload_file_no_parents(&repo, "blob", &fake_file_path_1);
+            let fake_file_path_2 = RepoPath::file("FILE").expect("Can't generate fake RepoPath");
+            let (filehash_2, file_future_2) =
+                upload_file_no_parents(&repo, "blob", &fake_file_path_2);
+            let (_roothash, root_manifest_future) = upload_manifest_no_parents(
+                &repo,
+                format!("file\0{}\nFILE\0{}", filehash_1, filehash_2),
+                &RepoPath::root(),
+            );
+
+            let mut commits = vec![
+                create_changeset_no_parents(
+                    &repo,
+                    root_manifest_future.map(Some).boxify(),
+                    vec![file_future_1, file_future_2],
+                ),
+            ];
+            for i in 0..10 {
+                let path = RepoPath::file(format!("file{}", i).as_str())
+                    .expect("Can't generate fake RepoPath");
+                let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &path);
+                let (_roothash, root_manifest_future) = upload_manifest_no_parents(
+                    &repo,
+                    format!("file{}\0{}\n", i, filehash),
+                    &RepoPath::root(),
+                );
+                let parent = commits.last().expect("stack is not empty").clone();
+                commits.push(create_changeset_one_parent(
+                    &repo,
+                    root_manifest_future.map(Some).boxify(),
+                    vec![file_future],
+                    parent,
+                ));
+            }
+            assert_eq!(repo.live_changeset_handles().count(), commits.len());
+
+            let completed = future::join_all(
+                commits
+                    .into_iter()
+                    .map(|commit| commit.get_completed_changeset())
+                    .collect::<Vec<_>>(),
+            );
+            assert!(run_future(completed).is_err());
+            assert_eq!(repo.live_changeset_handles().count(), 0);
+        }
+    });
+}
+
 #[test]
 fn test_no_case_conflict_removal() {
     async_unit::tokio_unit_test(|| {
@@ -1017,3 +1125,369 @@ fn test_no_case_conflict_removal_dir() {
         );
     });
 }
+
+fn create_manifest_with_files(repo: &BlobRepo, files: &[&str]) -> HgManifestId {
+    let files = files.iter().map(|path| (*path, Some("content"))).collect();
+    let bcs_id = create_commit(repo.clone(), vec![], store_files(files, repo.clone()));
+    let hg_cs_id = run_future(repo.get_hg_from_bonsai_changeset(bcs_id)).unwrap();
+    *run_future(repo.get_changeset_by_changesetid(&hg_cs_id))
+        .unwrap()
+        .manifestid()
+}
+
+#[derive(Clone, Debug)]
+struct WalkManifestCase {
+    files: Vec<String>,
+    filter: PathFilter,
+}
+
+impl Arbitrary for WalkManifestCase {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        // Directories and files have different names, so that no path is both a file and a
+        // directory
+        fn arbitrary_dir<G: Gen>(g: &mut G, max_len: usize) -> String {
+            let len = g.gen_range(1, max_len + 1);
+            (0..len)
+                .map(|_| g.choose(&["a", "b", "c"]).unwrap().to_string())
+                .collect::<Vec<_>>()
+                .join("/")
+        }
+
+        let mut files: Vec<_> = (0..g.gen_range(1, 10))
+            .map(|_| {
+                let name = g.choose(&["f", "g"]).unwrap().to_string();
+                if g.gen() {
+                    name
+                } else {
+                    format!("{}/{}", arbitrary_dir(g, 3), name)
+                }
+            })
+            .collect();
+        files.sort();
+        files.dedup();
+
+        let includes: Vec<_> = (0..g.gen_range(0, 3))
+            .map(|_| MPath::new(arbitrary_dir(g, 2)).unwrap())
+            .collect();
+        let excludes: Vec<_> = (0..g.gen_range(0, 3))
+            .map(|_| MPath::new(arbitrary_dir(g, 2)).unwrap())
+            .collect();
+        let max_depth = if g.gen() {
+            Some(g.gen_range(0, 5))
+        } else {
+            None
+        };
+
+        let mut filter = PathFilter::all();
+        for include in includes {
+            filter = filter.include(include);
+        }
+        for exclude in excludes {
+            filter = filter.exclude(exclude);
+        }
+        if let Some(max_depth) = max_depth {
+            filter = filter.max_depth(max_depth);
+        }
+
+        WalkManifestCase { files, filter }
+    }
+}
+
+fn walk_paths(repo: &BlobRepo, mfid: &HgManifestId, filter: PathFilter) -> Vec<MPath> {
+    run_future(
+        repo.walk_manifest(mfid, filter)
+            .map(|(path, _)| path)
+            .collect(),
+    ).unwrap()
+}
+
+#[test]
+fn test_walk_manifest_matches_filtered_full_walk() {
+    fn prop(case: WalkManifestCase) -> bool {
+        let repo = get_empty_eager_repo();
+        let files: Vec<_> = case.files.iter().map(|path| path.as_str()).collect();
+        let mfid = create_manifest_with_files(&repo, &files);
+
+        let expected: Vec<_> = walk_paths(&repo, &mfid, PathFilter::all())
+            .into_iter()
+            .filter(|path| case.filter.matches(path, path.num_components()))
+            .collect();
+        let actual = walk_paths(&repo, &mfid, case.filter.clone());
+
+        actual == expected
+    }
+
+    async_unit::tokio_unit_test(|| {
+        quickcheck(prop as fn(WalkManifestCase) -> bool);
+    })
+}
+
+#[test]
+fn test_walk_manifest_full_walk_is_sorted() {
+    async_unit::tokio_unit_test(|| {
+        let repo = get_empty_eager_repo();
+        let mfid = create_manifest_with_files(&repo, &["b/f", "a/b/f", "a/f", "f", "a/a/g"]);
+
+        let expected: Vec<_> = vec!["a", "a/a", "a/a/g", "a/b", "a/b/f", "a/f", "b", "b/f", "f"]
+            .into_iter()
+            .map(|path| MPath::new(path).unwrap())
+            .collect();
+        assert_eq!(walk_paths(&repo, &mfid, PathFilter::all()), expected);
+
+        let filter = PathFilter::all()
+            .include(MPath::new("a").unwrap())
+            .exclude(MPath::new("a/b").unwrap())
+            .max_depth(2);
+        let expected: Vec<_> = vec!["a", "a/a", "a/f"]
+            .into_iter()
+            .map(|path| MPath::new(path).unwrap())
+            .collect();
+        assert_eq!(walk_paths(&repo, &mfid, filter), expected);
+    })
+}
+
+#[test]
+fn test_walk_manifest_with_matcher() {
+    async_unit::tokio_unit_test(|| {
+        let repo = get_empty_eager_repo();
+        let mfid = create_manifest_with_files(
+            &repo,
+            &["src/lib.rs", "src/gen/out.rs", "src/README", "test/t.rs", "lib.rs"],
+        );
+
+        let matcher =
+            PathMatcher::new(vec!["glob:**/*.rs"], vec!["glob:src/gen"]).expect("invalid patterns");
+        let expected: Vec<_> = vec!["lib.rs", "src/lib.rs", "test/t.rs"]
+            .into_iter()
+            .map(|path| MPath::new(path).unwrap())
+            .collect();
+        assert_eq!(
+            walk_paths(&repo, &mfid, PathFilter::with_matcher(matcher)),
+            expected
+        );
+    })
+}
+
+#[test]
+fn test_find_tree_in_manifest() {
+    async_unit::tokio_unit_test(|| {
+        let repo = get_empty_eager_repo();
+        let mfid = create_manifest_with_files(&repo, &["src/lib.rs", "src/gen/out.rs", "lib.rs"]);
+        let find = |mfid: HgManifestId, path: &str| {
+            let path = if path.is_empty() {
+                None
+            } else {
+                Some(MPath::new(path).unwrap())
+            };
+            run_future(repo.find_tree_in_manifest(path, mfid)).unwrap()
+        };
+
+        assert_eq!(find(mfid, ""), Some(mfid));
+        let src = find(mfid, "src").expect("src is a directory");
+        assert!(find(mfid, "src/gen").is_some());
+        assert_ne!(find(mfid, "src/gen"), Some(src));
+        assert_eq!(find(mfid, "lib.rs"), None);
+        assert_eq!(find(mfid, "src/lib.rs/x"), None);
+        assert_eq!(find(mfid, "test"), None);
+
+        // A directory that didn't change has the same manifest in another root manifest
+        let same_src = create_manifest_with_files(&repo, &["src/lib.rs", "src/gen/out.rs", "a"]);
+        assert_eq!(find(same_src, "src"), Some(src));
+        let other_src = create_manifest_with_files(&repo, &["src/lib.rs", "lib.rs"]);
+        assert_ne!(find(other_src, "src"), Some(src));
+    })
+}
+
+/// Records the keys of all the blobs that are fetched
+#[derive(Clone, Debug)]
+struct FetchRecordingBlobstore {
+    inner: EagerMemblob,
+    fetched: Arc<Mutex<Vec<String>>>,
+}
+
+impl FetchRecordingBlobstore {
+    fn new() -> Self {
+        FetchRecordingBlobstore {
+            inner: EagerMemblob::new(),
+            fetched: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    fn take_fetched(&self) -> Vec<String> {
+        self.fetched.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl Blobstore for FetchRecordingBlobstore {
+    fn get(&self, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        self.fetched.lock().unwrap().push(key.clone());
+        self.inner.get(key)
+    }
+
+    fn put(&self, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+        self.inner.put(key, value)
+    }
+}
+
+#[test]
+fn test_walk_manifest_does_not_fetch_pruned_subtrees() {
+    async_unit::tokio_unit_test(|| {
+        let blobstore = FetchRecordingBlobstore::new();
+        let repo = BlobRepo::new_memblob_empty(None, Some(Arc::new(blobstore.clone())))
+            .expect("cannot create empty repo");
+        let mfid = create_manifest_with_files(&repo, &["a/f", "b/f", "b/c/f", "d/f"]);
+
+        let tree_hashes: HashMap<_, _> = run_future(
+            repo.walk_manifest(&mfid, PathFilter::all())
+                .filter(|&(_, ref entry)| entry.get_type() == manifest::Type::Tree)
+                .map(|(path, entry)| (path, entry.get_hash().into_nodehash().to_string()))
+                .collect(),
+        ).unwrap()
+            .into_iter()
+            .collect();
+        let tree_hash = |path: &str| tree_hashes[&MPath::new(path).unwrap()].clone();
+        let was_fetched = |fetched: &[String], path: &str| {
+            let hash = tree_hash(path);
+            fetched.iter().any(|key| key.contains(&hash))
+        };
+
+        blobstore.take_fetched();
+        let paths = walk_paths(
+            &repo,
+            &mfid,
+            PathFilter::all().exclude(MPath::new("b").unwrap()),
+        );
+        let fetched = blobstore.take_fetched();
+
+        assert!(!paths.contains(&MPath::new("b").unwrap()));
+        assert!(was_fetched(&fetched, "a"));
+        assert!(was_fetched(&fetched, "d"));
+        assert!(!was_fetched(&fetched, "b"));
+        assert!(!was_fetched(&fetched, "b/c"));
+
+        // Trees at the maximum depth are returned, but not fetched
+        let paths = walk_paths(&repo, &mfid, PathFilter::all().max_depth(1));
+        let fetched = blobstore.take_fetched();
+
+        assert!(paths.contains(&MPath::new("b").unwrap()));
+        assert!(!was_fetched(&fetched, "a"));
+        assert!(!was_fetched(&fetched, "b"));
+        assert!(!was_fetched(&fetched, "d"));
+    })
+}
+
+#[test]
+fn test_get_file_content_stream() {
+    async_unit::tokio_unit_test(|| {
+        let repo = get_empty_eager_repo();
+
+        // Binary content spanning several chunks, with a partial chunk at the end
+        let content: Vec<u8> = (0..FILE_CONTENT_CHUNK_SIZE * 3 + 123)
+            .map(|i| (i % 256) as u8)
+            .collect();
+        let path = MPath::new("dir/large").unwrap();
+        let file_change = run_future(make_file_change(&content, &repo)).unwrap();
+        let bcs_id = create_commit(
+            repo.clone(),
+            vec![],
+            btreemap!{path.clone() => Some(file_change)},
+        );
+        let hg_cs_id = run_future(repo.get_hg_from_bonsai_changeset(bcs_id)).unwrap();
+
+        let chunks = run_future(repo.get_file_content_stream(&hg_cs_id, &path).collect()).unwrap();
+
+        assert_eq!(chunks.len(), 4);
+        assert!(
+            chunks
+                .iter()
+                .all(|chunk| chunk.len() <= FILE_CONTENT_CHUNK_SIZE)
+        );
+        assert_eq!(chunks.last().unwrap().len(), 123);
+        assert_eq!(chunks.concat(), content);
+
+        let missing = MPath::new("dir/missing").unwrap();
+        let err = run_future(repo.get_file_content_stream(&hg_cs_id, &missing).collect())
+            .expect_err("fetching a missing path should fail");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::PathNotFound(path)) => assert_eq!(path, missing),
+            _ => panic!("unexpected error"),
+        }
+    })
+}
+
+fn attribution(repo: &BlobRepo, bcs_id: ChangesetId) -> (u64, u64) {
+    let attribution = run_future(get_storage_attribution(repo, bcs_id))
+        .unwrap()
+        .expect("changeset should be attributed");
+    (attribution.new_contents, attribution.new_bytes)
+}
+
+#[test]
+fn test_storage_attribution() {
+    async_unit::tokio_unit_test(|| {
+        let repo = get_empty_eager_repo();
+
+        // Two files with the same content count once
+        let first = create_commit(
+            repo.clone(),
+            vec![],
+            store_files(
+                btreemap!{"a" => Some("aaaa"), "b" => Some("bb"), "c" => Some("bb")},
+                repo.clone(),
+            ),
+        );
+        assert_eq!(attribution(&repo, first), (2, 6));
+
+        // A copy without changes introduces nothing
+        let copy_src = (MPath::new("a").unwrap(), first);
+        let (path, change) = store_rename(copy_src, "d", "aaaa", repo.clone());
+        let copy = create_commit(repo.clone(), vec![first], btreemap!{path => change});
+        assert_eq!(attribution(&repo, copy), (0, 0));
+
+        let modify = create_commit(
+            repo.clone(),
+            vec![copy],
+            store_files(btreemap!{"a" => Some("aaaaaaaa")}, repo.clone()),
+        );
+        assert_eq!(attribution(&repo, modify), (1, 8));
+
+        // A revert and a deletion introduce nothing
+        let revert = create_commit(
+            repo.clone(),
+            vec![modify],
+            store_files(btreemap!{"a" => Some("aaaa"), "b" => None}, repo.clone()),
+        );
+        assert_eq!(attribution(&repo, revert), (0, 0));
+
+        let content_id = *store_files(btreemap!{"a" => Some("aaaa")}, repo.clone())
+            .values()
+            .next()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .content_id();
+        assert_eq!(
+            run_future(get_content_introduction(&repo, content_id)).unwrap(),
+            Some(first)
+        );
+    })
+}
+
+#[test]
+fn test_storage_attribution_missing() {
+    async_unit::tokio_unit_test(|| {
+        let repo = get_empty_eager_repo();
+        let bcs_id = create_commit(
+            repo.clone(),
+            vec![],
+            store_files(btreemap!{"a" => Some("a")}, repo.clone()),
+        );
+
+        assert_eq!(
+            run_future(get_storage_attribution(&repo, ONES_CSID)).unwrap(),
+            None
+        );
+        run_future(copy_storage_attribution(&repo, bcs_id, ONES_CSID)).unwrap();
+        assert_eq!(attribution(&repo, ONES_CSID), (1, 1));
+    })
+}
@@ -15,8 +15,9 @@ use futures::stream::futures_unordered;
 use futures_ext::{BoxFuture, StreamExt};
 use scuba_ext::ScubaSampleBuilder;
 
-use blobrepo::{BlobRepo, ChangesetHandle, ChangesetMetadata, CreateChangeset, HgBlobEntry,
-               UploadHgFileContents, UploadHgFileEntry, UploadHgNodeHash, UploadHgTreeEntry};
+use blobrepo::{BlobRepo, ChangesetHandle, ChangesetMetadata, ChangesetMetricsCollector,
+               CreateChangeset, HgBlobEntry, UploadHgFileContents, UploadHgFileEntry,
+               UploadHgNodeHash, UploadHgTreeEntry};
 use blobstore::{EagerMemblob, LazyMemblob};
 use mercurial_types::{FileType, HgBlobNode, HgNodeHash, RepoPath};
 use mononoke_types::DateTime;
@@ -173,6 +174,15 @@ pub fn create_changeset_no_parents(
     repo: &BlobRepo,
     root_manifest: BoxFuture<Option<(HgBlobEntry, RepoPath)>, Error>,
     other_nodes: Vec<BoxFuture<(HgBlobEntry, RepoPath), Error>>,
+) -> ChangesetHandle {
+    create_changeset_no_parents_with_metrics(repo, root_manifest, other_nodes, None)
+}
+
+pub fn create_changeset_no_parents_with_metrics(
+    repo: &BlobRepo,
+    root_manifest: BoxFuture<Option<(HgBlobEntry, RepoPath)>, Error>,
+    other_nodes: Vec<BoxFuture<(HgBlobEntry, RepoPath), Error>>,
+    metrics: Option<ChangesetMetricsCollector>,
 ) -> ChangesetHandle {
     let cs_metadata = ChangesetMetadata {
         user: "author <author@fb.com>".into(),
@@ -189,6 +199,7 @@ pub fn create_changeset_no_parents(
         sub_entries: futures_unordered(other_nodes).boxify(),
         cs_metadata,
         must_check_case_conflicts: true,
+        metrics,
     };
     create_changeset.create(repo, ScubaSampleBuilder::with_discard())
 }
@@ -214,6 +225,7 @@ pub fn create_changeset_one_parent(
         sub_entries: futures_unordered(other_nodes).boxify(),
         cs_metadata,
         must_check_case_conflicts: true,
+        metrics: None,
     };
     create_changeset.create(repo, ScubaSampleBuilder::with_discard())
 }
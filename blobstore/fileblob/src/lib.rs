@@ -14,20 +14,23 @@ extern crate url;
 extern crate futures_ext;
 
 extern crate blobstore;
+extern crate fd_accounting;
 extern crate mononoke_types;
 
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, read_dir, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
 use failure::{Error, Result};
 use futures::Async;
 use futures::future::{poll_fn, Future};
-use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
+use url::percent_encoding::{percent_decode, percent_encode, DEFAULT_ENCODE_SET};
 
 use futures_ext::{BoxFuture, FutureExt};
 
-use blobstore::Blobstore;
+use blobstore::{enumeration_page, Blobstore, BlobstoreEnumerate, BlobstoreEnumeration,
+                BlobstoreKeyEntry};
+use fd_accounting::FdComponent;
 use mononoke_types::BlobstoreBytes;
 
 const PREFIX: &str = "blob";
@@ -60,6 +63,18 @@ impl Fileblob {
         let key = percent_encode(key.as_bytes(), DEFAULT_ENCODE_SET);
         self.base.join(format!("{}-{}", PREFIX, key))
     }
+
+    /// Reverse of `path`, for file names of blobs
+    fn key(file_name: &str) -> Option<String> {
+        let prefix = format!("{}-", PREFIX);
+        if !file_name.starts_with(&prefix) {
+            return None;
+        }
+        percent_decode(file_name[prefix.len()..].as_bytes())
+            .decode_utf8()
+            .ok()
+            .map(|key| key.into_owned())
+    }
 }
 
 impl Blobstore for Fileblob {
@@ -67,6 +82,7 @@ impl Blobstore for Fileblob {
         let p = self.path(&key);
 
         poll_fn(move || {
+            let _fd = fd_accounting::track(FdComponent::LocalBlobstoreFiles);
             let mut v = Vec::new();
             let ret = match File::open(&p) {
                 Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
@@ -85,8 +101,45 @@ impl Blobstore for Fileblob {
         let p = self.path(&key);
 
         poll_fn::<_, Error, _>(move || {
+            let _fd = fd_accounting::track(FdComponent::LocalBlobstoreFiles);
             File::create(&p)?.write_all(value.as_bytes().as_ref())?;
             Ok(Async::Ready(()))
         }).boxify()
     }
 }
+
+impl BlobstoreEnumerate for Fileblob {
+    fn enumerate(
+        &self,
+        prefix: String,
+        continuation: Option<String>,
+        limit: usize,
+    ) -> BoxFuture<BlobstoreEnumeration, Error> {
+        let base = self.base.clone();
+
+        poll_fn::<_, Error, _>(move || {
+            let _fd = fd_accounting::track(FdComponent::LocalBlobstoreFiles);
+            let mut entries = Vec::new();
+            for dir_entry in read_dir(&base)? {
+                let dir_entry = dir_entry?;
+                let key = match dir_entry.file_name().to_str().and_then(Fileblob::key) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if key.starts_with(&prefix) {
+                    entries.push(BlobstoreKeyEntry {
+                        key,
+                        size: Some(dir_entry.metadata()?.len()),
+                    });
+                }
+            }
+            let continuation = continuation.as_ref().map(|c| c.as_str());
+            Ok(Async::Ready(enumeration_page(
+                entries,
+                &prefix,
+                continuation,
+                limit,
+            )))
+        }).boxify()
+    }
+}
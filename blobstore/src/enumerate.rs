@@ -0,0 +1,120 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use failure::Error;
+
+use futures_ext::BoxFuture;
+
+/// A key found by `BlobstoreEnumerate::enumerate`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlobstoreKeyEntry {
+    pub key: String,
+    /// Size of the value, if the store knows it without fetching the value
+    pub size: Option<u64>,
+}
+
+/// A page of keys, in key order
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlobstoreEnumeration {
+    pub entries: Vec<BlobstoreKeyEntry>,
+    /// Pass this to the next call to get the keys that follow this page. None once all the keys
+    /// were returned.
+    pub continuation: Option<String>,
+}
+
+/// Blobstores that can list the keys they store. This is expensive, and only meant for offline
+/// tools: remote blobstores like Manifold don't implement it.
+pub trait BlobstoreEnumerate: Send + Sync + 'static {
+    /// Lists up to `limit` keys that start with `prefix` and come after `continuation`
+    fn enumerate(
+        &self,
+        prefix: String,
+        continuation: Option<String>,
+        limit: usize,
+    ) -> BoxFuture<BlobstoreEnumeration, Error>;
+}
+
+impl BlobstoreEnumerate for Arc<BlobstoreEnumerate> {
+    fn enumerate(
+        &self,
+        prefix: String,
+        continuation: Option<String>,
+        limit: usize,
+    ) -> BoxFuture<BlobstoreEnumeration, Error> {
+        self.as_ref().enumerate(prefix, continuation, limit)
+    }
+}
+
+/// Builds the page of `entries` that follows `continuation`, for stores that can't seek to a key
+pub fn enumeration_page<I>(
+    entries: I,
+    prefix: &str,
+    continuation: Option<&str>,
+    limit: usize,
+) -> BlobstoreEnumeration
+where
+    I: IntoIterator<Item = BlobstoreKeyEntry>,
+{
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| entry.key.starts_with(prefix))
+        .filter(|entry| match continuation {
+            Some(continuation) => entry.key.as_str() > continuation,
+            None => true,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let continuation = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|entry| entry.key.clone())
+    } else {
+        None
+    };
+    BlobstoreEnumeration {
+        entries,
+        continuation,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(key: &str) -> BlobstoreKeyEntry {
+        BlobstoreKeyEntry {
+            key: key.to_string(),
+            size: Some(key.len() as u64),
+        }
+    }
+
+    fn keys(page: &BlobstoreEnumeration) -> Vec<&str> {
+        page.entries.iter().map(|entry| entry.key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_enumeration_page() {
+        let entries = vec![entry("repo0001.c"), entry("repo0000.b"), entry("repo0000.a")];
+
+        let page = enumeration_page(entries.clone(), "repo0000.", None, 10);
+        assert_eq!(keys(&page), vec!["repo0000.a", "repo0000.b"]);
+        assert_eq!(page.continuation, None);
+
+        let page = enumeration_page(entries.clone(), "", None, 1);
+        assert_eq!(keys(&page), vec!["repo0000.a"]);
+        assert_eq!(page.continuation, Some("repo0000.a".to_string()));
+
+        let page = enumeration_page(entries.clone(), "", Some("repo0000.a"), 1);
+        assert_eq!(keys(&page), vec!["repo0000.b"]);
+        assert_eq!(page.continuation, Some("repo0000.b".to_string()));
+
+        let page = enumeration_page(entries, "", Some("repo0000.b"), 1);
+        assert_eq!(keys(&page), vec!["repo0001.c"]);
+        assert_eq!(page.continuation, None);
+    }
+}
@@ -10,7 +10,6 @@ extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
-extern crate inlinable_string;
 extern crate tokio;
 extern crate tokio_timer;
 
@@ -43,6 +42,10 @@ pub use counted_blobstore::CountedBlobstore;
 
 mod dummy_lease;
 
+mod enumerate;
+pub use enumerate::{enumeration_page, BlobstoreEnumerate, BlobstoreEnumeration,
+                    BlobstoreKeyEntry};
+
 mod in_process_lease;
 
 mod locking_cache;
@@ -60,6 +63,9 @@ pub use mem_writes::MemWritesBlobstore;
 mod prefix;
 pub use prefix::PrefixBlobstore;
 
+mod retrying_blobstore;
+pub use retrying_blobstore::{is_transient_blobstore_error, RetryingBlobstore};
+
 mod errors;
 pub use errors::ErrorKind;
 
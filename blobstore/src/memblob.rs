@@ -14,7 +14,7 @@ use futures_ext::{BoxFuture, FutureExt};
 
 use mononoke_types::BlobstoreBytes;
 
-use Blobstore;
+use {enumeration_page, Blobstore, BlobstoreEnumerate, BlobstoreEnumeration, BlobstoreKeyEntry};
 
 /// In-memory "blob store"
 ///
@@ -83,6 +83,51 @@ impl Blobstore for LazyMemblob {
     }
 }
 
+fn enumerate_hash(
+    hash: &HashMap<String, BlobstoreBytes>,
+    prefix: &str,
+    continuation: Option<&str>,
+    limit: usize,
+) -> BlobstoreEnumeration {
+    let entries = hash.iter().map(|(key, value)| BlobstoreKeyEntry {
+        key: key.clone(),
+        size: Some(value.len() as u64),
+    });
+    enumeration_page(entries, prefix, continuation, limit)
+}
+
+impl BlobstoreEnumerate for EagerMemblob {
+    fn enumerate(
+        &self,
+        prefix: String,
+        continuation: Option<String>,
+        limit: usize,
+    ) -> BoxFuture<BlobstoreEnumeration, Error> {
+        let inner = self.hash.lock().expect("lock poison");
+        let continuation = continuation.as_ref().map(|c| c.as_str());
+        Ok(enumerate_hash(&inner, &prefix, continuation, limit))
+            .into_future()
+            .boxify()
+    }
+}
+
+impl BlobstoreEnumerate for LazyMemblob {
+    fn enumerate(
+        &self,
+        prefix: String,
+        continuation: Option<String>,
+        limit: usize,
+    ) -> BoxFuture<BlobstoreEnumeration, Error> {
+        let hash = self.hash.clone();
+
+        lazy(move || {
+            let inner = hash.lock().expect("lock poison");
+            let continuation = continuation.as_ref().map(|c| c.as_str());
+            Ok(enumerate_hash(&inner, &prefix, continuation, limit)).into_future()
+        }).boxify()
+    }
+}
+
 impl fmt::Debug for EagerMemblob {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("EagerMemblob")
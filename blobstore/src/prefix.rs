@@ -5,25 +5,22 @@
 // GNU General Public License version 2 or any later version.
 
 use failure::Error;
-use inlinable_string::InlinableString;
 
 use futures_ext::BoxFuture;
 
-use mononoke_types::BlobstoreBytes;
+use mononoke_types::{BlobstoreBytes, RepoPrefix};
 
 use {Blobstore, CacheBlobstoreExt};
 
-/// A layer over an existing blobstore that prepends a fixed string to each get and put.
+/// A layer over an existing blobstore that prepends the prefix of a repo to each get and put.
 #[derive(Clone, Debug)]
 pub struct PrefixBlobstore<T: Blobstore + Clone> {
-    // Try to inline the prefix to ensure copies remain cheap. Most prefixes are short anyway.
-    prefix: InlinableString,
+    prefix: RepoPrefix,
     blobstore: T,
 }
 
 impl<T: Blobstore + Clone> PrefixBlobstore<T> {
-    pub fn new<S: Into<InlinableString>>(blobstore: T, prefix: S) -> Self {
-        let prefix = prefix.into();
+    pub fn new(blobstore: T, prefix: RepoPrefix) -> Self {
         Self { prefix, blobstore }
     }
 
@@ -34,7 +31,7 @@ impl<T: Blobstore + Clone> PrefixBlobstore<T> {
 
     #[inline]
     fn prepend(&self, key: String) -> String {
-        [&self.prefix, key.as_str()].concat()
+        self.prefix.key(&key)
     }
 }
 
@@ -79,9 +76,9 @@ mod test {
     #[test]
     fn test_prefix() {
         let base = EagerMemblob::new();
-        let prefixed = PrefixBlobstore::new(base.clone(), "prefix123-");
+        let prefixed = PrefixBlobstore::new(base.clone(), RepoPrefix::new(123));
         let unprefixed_key = "foobar".to_string();
-        let prefixed_key = "prefix123-foobar".to_string();
+        let prefixed_key = "repo0123.foobar".to_string();
 
         // This is EagerMemblob (immediate future completion) so calling wait() is fine.
         prefixed
@@ -0,0 +1,236 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fmt;
+use std::sync::Arc;
+
+use failure::Error;
+use futures_ext::{retry, BoxFuture, FutureExt, RetryPolicy};
+use stats::DynamicTimeseries;
+
+use mononoke_types::BlobstoreBytes;
+
+use {Blobstore, ErrorKind};
+
+define_stats! {
+    prefix = "mononoke.blobstore";
+    get_retry: dynamic_timeseries("{}.get.retry", (name: &'static str); RATE, SUM),
+    put_retry: dynamic_timeseries("{}.put.retry", (name: &'static str); RATE, SUM),
+    is_present_retry: dynamic_timeseries("{}.is_present.retry", (name: &'static str); RATE, SUM),
+    assert_present_retry: dynamic_timeseries(
+        "{}.assert_present.retry", (name: &'static str); RATE, SUM),
+}
+
+/// Returns false for errors that retrying can't fix, i.e. a blob not being present.
+pub fn is_transient_blobstore_error(err: &Error) -> bool {
+    match err.downcast_ref::<ErrorKind>() {
+        Some(&ErrorKind::NotFound(_)) => false,
+        _ => true,
+    }
+}
+
+/// Retries failed operations of the inner blobstore according to `policy`. Puts are retried as
+/// well: a key is only ever associated with a single value, so repeating a put is harmless.
+pub struct RetryingBlobstore<T: Blobstore> {
+    name: &'static str,
+    blobstore: Arc<T>,
+    policy: RetryPolicy,
+    is_retryable: fn(&Error) -> bool,
+}
+
+impl<T: Blobstore> RetryingBlobstore<T> {
+    pub fn new(
+        name: &'static str,
+        blobstore: T,
+        policy: RetryPolicy,
+        is_retryable: fn(&Error) -> bool,
+    ) -> Self {
+        Self {
+            name,
+            blobstore: Arc::new(blobstore),
+            policy,
+            is_retryable,
+        }
+    }
+
+    pub fn as_inner(&self) -> &T {
+        &self.blobstore
+    }
+
+    fn with_retries<I, F>(&self, on_retry: fn(&'static str), mut func: F) -> BoxFuture<I, Error>
+    where
+        I: Send + 'static,
+        F: FnMut(&T) -> BoxFuture<I, Error> + Send + 'static,
+    {
+        let name = self.name;
+        let blobstore = self.blobstore.clone();
+        let mut attempt = 0;
+        retry(self.policy, self.is_retryable, move || {
+            attempt += 1;
+            if attempt > 1 {
+                on_retry(name);
+            }
+            func(&blobstore)
+        }).boxify()
+    }
+}
+
+impl<T: Blobstore> fmt::Debug for RetryingBlobstore<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryingBlobstore")
+            .field("name", &self.name)
+            .field("blobstore", &self.blobstore)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl<T: Blobstore> Blobstore for RetryingBlobstore<T> {
+    fn get(&self, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+        self.with_retries(
+            |name| STATS::get_retry.add_value(1, (name,)),
+            move |blobstore| blobstore.get(key.clone()),
+        )
+    }
+
+    fn put(&self, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+        self.with_retries(
+            |name| STATS::put_retry.add_value(1, (name,)),
+            move |blobstore| blobstore.put(key.clone(), value.clone()),
+        )
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.with_retries(
+            |name| STATS::is_present_retry.add_value(1, (name,)),
+            move |blobstore| blobstore.is_present(key.clone()),
+        )
+    }
+
+    fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
+        self.with_retries(
+            |name| STATS::assert_present_retry.add_value(1, (name,)),
+            move |blobstore| blobstore.assert_present(key.clone()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::{future, Future};
+
+    use memblob::EagerMemblob;
+
+    /// Fails the first `failures` operations, then forwards them to a memblob
+    #[derive(Debug)]
+    struct FlakyBlobstore {
+        failures: AtomicUsize,
+        attempts: Arc<AtomicUsize>,
+        inner: EagerMemblob,
+    }
+
+    impl FlakyBlobstore {
+        fn new(failures: usize) -> (Self, Arc<AtomicUsize>) {
+            let attempts = Arc::new(AtomicUsize::new(0));
+            let blobstore = FlakyBlobstore {
+                failures: AtomicUsize::new(failures),
+                attempts: attempts.clone(),
+                inner: EagerMemblob::new(),
+            };
+            (blobstore, attempts)
+        }
+
+        fn fail(&self) -> bool {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            let failures = self.failures.load(Ordering::Relaxed);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::Relaxed);
+                true
+            } else {
+                false
+            }
+        }
+    }
+
+    impl Blobstore for FlakyBlobstore {
+        fn get(&self, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+            if self.fail() {
+                future::err(format_err!("transient error")).boxify()
+            } else {
+                self.inner.get(key)
+            }
+        }
+
+        fn put(&self, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+            if self.fail() {
+                future::err(format_err!("transient error")).boxify()
+            } else {
+                self.inner.put(key, value)
+            }
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            jitter: false,
+        }
+    }
+
+    #[test]
+    fn test_retries_transient_errors() {
+        let (flaky, attempts) = FlakyBlobstore::new(2);
+        let blobstore =
+            RetryingBlobstore::new("flaky", flaky, policy(), is_transient_blobstore_error);
+
+        // This is EagerMemblob (immediate future completion) so calling wait() is fine.
+        blobstore
+            .put("key".to_string(), BlobstoreBytes::from_bytes("value"))
+            .wait()
+            .expect("put should succeed after retries");
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        assert!(
+            blobstore
+                .get("key".to_string())
+                .wait()
+                .expect("get should succeed")
+                .is_some()
+        );
+        assert_eq!(attempts.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let (flaky, attempts) = FlakyBlobstore::new(5);
+        let blobstore =
+            RetryingBlobstore::new("flaky", flaky, policy(), is_transient_blobstore_error);
+
+        assert!(blobstore.get("key".to_string()).wait().is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_missing_blob_not_retried() {
+        let (flaky, attempts) = FlakyBlobstore::new(0);
+        let blobstore =
+            RetryingBlobstore::new("flaky", flaky, policy(), is_transient_blobstore_error);
+
+        let err = blobstore
+            .assert_present("missing".to_string())
+            .wait()
+            .expect_err("blob should be missing");
+        assert!(!is_transient_blobstore_error(&err));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+}
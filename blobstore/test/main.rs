@@ -24,7 +24,7 @@ use bytes::Bytes;
 use futures::Future;
 use tempdir::TempDir;
 
-use blobstore::{Blobstore, EagerMemblob};
+use blobstore::{Blobstore, BlobstoreEnumerate, BlobstoreKeyEntry, EagerMemblob};
 use fileblob::Fileblob;
 use mononoke_types::BlobstoreBytes;
 use rocksblob::Rocksblob;
@@ -67,6 +67,37 @@ where
     assert_eq!(out.into_bytes(), Bytes::from_static(b"bar"));
 }
 
+fn enumerate<B>(blobstore: B)
+where
+    B: Blobstore + BlobstoreEnumerate,
+{
+    for key in vec!["repo0000.b", "repo0000.a.c", "repo0001.a", "repo0000.a"] {
+        blobstore
+            .put(key.to_string(), BlobstoreBytes::from_bytes(key.as_bytes()))
+            .wait()
+            .expect("put failed");
+    }
+
+    let entry = |key: &str| BlobstoreKeyEntry {
+        key: key.to_string(),
+        size: Some(key.len() as u64),
+    };
+
+    let page = blobstore
+        .enumerate("repo0000.".to_string(), None, 2)
+        .wait()
+        .expect("enumerate failed");
+    assert_eq!(page.entries, vec![entry("repo0000.a"), entry("repo0000.a.c")]);
+    assert_eq!(page.continuation, Some("repo0000.a.c".to_string()));
+
+    let page = blobstore
+        .enumerate("repo0000.".to_string(), page.continuation, 2)
+        .wait()
+        .expect("enumerate failed");
+    assert_eq!(page.entries, vec![entry("repo0000.b")]);
+    assert_eq!(page.continuation, None);
+}
+
 macro_rules! blobstore_test_impl {
     ($mod_name: ident => {
         state: $state: expr,
@@ -121,3 +152,14 @@ blobstore_test_impl! {
         persistent: true,
     }
 }
+
+#[test]
+fn test_memblob_enumerate() {
+    enumerate(EagerMemblob::new());
+}
+
+#[test]
+fn test_fileblob_enumerate() {
+    let dir = TempDir::new("fileblob_test").unwrap();
+    enumerate(Fileblob::open(&dir).unwrap());
+}
@@ -12,6 +12,7 @@
 extern crate abomonation;
 #[macro_use]
 extern crate abomonation_derive;
+extern crate asyncmemo;
 extern crate cachelib;
 extern crate db_conn;
 #[macro_use]
@@ -33,6 +34,7 @@ extern crate mononoke_types;
 #[macro_use]
 extern crate stats;
 
+use std::mem;
 use std::result;
 use std::sync::{Arc, MutexGuard};
 
@@ -42,10 +44,11 @@ use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
 use diesel::result::{DatabaseErrorKind, Error as DieselError};
 
+use asyncmemo::Weight;
 use cachelib::{get_cached_or_fill, LruCachePool};
 use futures::Future;
 use futures_ext::{asynchronize, BoxFuture, FutureExt};
-use mercurial_types::{HgChangesetId, RepositoryId};
+use mercurial_types::{HgChangesetId, HgChangesetIdPrefix, RepositoryId};
 use mononoke_types::ChangesetId;
 use stats::Timeseries;
 
@@ -61,6 +64,7 @@ define_stats! {
     prefix = "mononoke.bonsai-hg-mapping";
     gets: timeseries(RATE, SUM),
     gets_master: timeseries(RATE, SUM),
+    gets_by_prefix: timeseries(RATE, SUM),
     adds: timeseries(RATE, SUM),
 }
 
@@ -71,6 +75,13 @@ pub struct BonsaiHgMappingEntry {
     pub bcs_id: ChangesetId,
 }
 
+/// Only the entry itself, which owns no heap memory
+impl Weight for BonsaiHgMappingEntry {
+    fn get_weight(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, HeapSizeOf)]
 pub enum BonsaiOrHgChangesetId {
     Bonsai(ChangesetId),
@@ -98,6 +109,15 @@ pub trait BonsaiHgMapping: Send + Sync {
         cs_id: BonsaiOrHgChangesetId,
     ) -> BoxFuture<Option<BonsaiHgMappingEntry>, Error>;
 
+    /// The hg changesets whose id starts with `prefix`, in order of their ids. At most `limit`
+    /// of them.
+    fn get_many_hg_by_prefix(
+        &self,
+        repo_id: RepositoryId,
+        prefix: HgChangesetIdPrefix,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error>;
+
     fn get_hg_from_bonsai(
         &self,
         repo_id: RepositoryId,
@@ -131,6 +151,15 @@ impl BonsaiHgMapping for Arc<BonsaiHgMapping> {
     ) -> BoxFuture<Option<BonsaiHgMappingEntry>, Error> {
         (**self).get(repo_id, cs_id)
     }
+
+    fn get_many_hg_by_prefix(
+        &self,
+        repo_id: RepositoryId,
+        prefix: HgChangesetIdPrefix,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        (**self).get_many_hg_by_prefix(repo_id, prefix, limit)
+    }
 }
 
 pub struct CachingBonsaiHgMapping {
@@ -162,6 +191,16 @@ impl BonsaiHgMapping for CachingBonsaiHgMapping {
             self.mapping.get(repo_id, cs)
         })
     }
+
+    fn get_many_hg_by_prefix(
+        &self,
+        repo_id: RepositoryId,
+        prefix: HgChangesetIdPrefix,
+        limit: usize,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        // Changesets can be added with any prefix at any time, so don't cache the answers
+        self.mapping.get_many_hg_by_prefix(repo_id, prefix, limit)
+    }
 }
 
 #[derive(Clone)]
@@ -206,7 +245,7 @@ pub struct MysqlBonsaiHgMapping {
 }
 
 impl MysqlBonsaiHgMapping {
-    fn from(inner: MysqlConnInner) -> Self {
+    pub fn from(inner: MysqlConnInner) -> Self {
         Self { inner }
     }
 
@@ -265,6 +304,30 @@ macro_rules! impl_bonsai_hg_mapping {
                 .boxify()
             }
 
+            fn get_many_hg_by_prefix(
+                &self,
+                repo_id: RepositoryId,
+                prefix: HgChangesetIdPrefix,
+                limit: usize,
+            ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+                STATS::gets_by_prefix.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    let connection = db.get_conn()?;
+                    bonsai_hg_mapping::table
+                        .select(bonsai_hg_mapping::hg_cs_id)
+                        .filter(bonsai_hg_mapping::repo_id.eq(repo_id))
+                        .filter(bonsai_hg_mapping::hg_cs_id.ge(prefix.min_cs_id()))
+                        .filter(bonsai_hg_mapping::hg_cs_id.le(prefix.max_cs_id()))
+                        .order(bonsai_hg_mapping::hg_cs_id)
+                        .limit(limit as i64)
+                        .load::<HgChangesetId>(&*connection)
+                        .map_err(failure::Error::from)
+                })
+                .boxify()
+            }
+
             fn add(&self, entry: BonsaiHgMappingEntry) -> BoxFuture<bool, Error> {
                 STATS::adds.add_value(1);
                 let db = self.clone();
@@ -8,24 +8,30 @@
 
 #![deny(warnings)]
 
+extern crate abomonation;
 #[macro_use]
 extern crate assert_matches;
 extern crate async_unit;
 extern crate failure_ext as failure;
 extern crate futures;
 
+extern crate asyncmemo;
 extern crate bonsai_hg_mapping;
+extern crate mercurial_types;
 extern crate mercurial_types_mocks;
 extern crate mononoke_types_mocks;
 
+use std::str::FromStr;
 use std::sync::Arc;
 
 use futures::Future;
 
+use asyncmemo::{weight_matches_estimate, Weight};
 use bonsai_hg_mapping::{BonsaiHgMapping, BonsaiHgMappingEntry, ErrorKind, MysqlBonsaiHgMapping,
                         SqliteBonsaiHgMapping};
+use mercurial_types::{HgChangesetId, HgChangesetIdPrefix};
 use mercurial_types_mocks::nodehash as hg;
-use mercurial_types_mocks::repo::REPO_ZERO;
+use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
 use mononoke_types_mocks::changesetid as bonsai;
 
 fn add_and_get<M: BonsaiHgMapping>(mapping: M) {
@@ -102,6 +108,37 @@ fn missing<M: BonsaiHgMapping>(mapping: M) {
     assert_eq!(result, None);
 }
 
+fn get_many_hg_by_prefix<M: BonsaiHgMapping>(mapping: M) {
+    let also_ones = HgChangesetId::from_str(&format!("{}2", "1".repeat(39))).unwrap();
+    let entries = vec![
+        (hg::ONES_CSID, bonsai::ONES_CSID),
+        (also_ones, bonsai::TWOS_CSID),
+        (hg::TWOS_CSID, bonsai::THREES_CSID),
+    ];
+    for (hg_cs_id, bcs_id) in entries {
+        let entry = BonsaiHgMappingEntry {
+            repo_id: REPO_ZERO,
+            hg_cs_id,
+            bcs_id,
+        };
+        assert!(mapping.add(entry).wait().expect("Adding new entry failed"));
+    }
+
+    let get = |repo_id, prefix: &str, limit| {
+        let prefix = HgChangesetIdPrefix::from_str(prefix).unwrap();
+        mapping
+            .get_many_hg_by_prefix(repo_id, prefix, limit)
+            .wait()
+            .expect("Failed to get changesets by prefix")
+    };
+    assert_eq!(get(REPO_ZERO, "1", 10), vec![hg::ONES_CSID, also_ones]);
+    assert_eq!(get(REPO_ZERO, "1", 1), vec![hg::ONES_CSID]);
+    assert_eq!(get(REPO_ZERO, "22", 10), vec![hg::TWOS_CSID]);
+    assert_eq!(get(REPO_ZERO, &also_ones.to_string(), 10), vec![also_ones]);
+    assert_eq!(get(REPO_ZERO, "3", 10), vec![]);
+    assert_eq!(get(REPO_ONE, "1", 10), vec![]);
+}
+
 macro_rules! bonsai_hg_mapping_test_impl {
     ($mod_name:ident =>  { new: $new_cb:expr, }) => {
         mod $mod_name {
@@ -120,6 +157,13 @@ macro_rules! bonsai_hg_mapping_test_impl {
                     missing($new_cb());
                 });
             }
+
+            #[test]
+            fn test_get_many_hg_by_prefix() {
+                async_unit::tokio_unit_test(|| {
+                    get_many_hg_by_prefix($new_cb());
+                });
+            }
         }
     };
 }
@@ -166,3 +210,16 @@ fn new_mysql() -> MysqlBonsaiHgMapping {
 fn new_mysql_arced() -> Arc<BonsaiHgMapping> {
     Arc::new(new_mysql())
 }
+
+#[test]
+fn test_entry_weight() {
+    let entry = BonsaiHgMappingEntry {
+        repo_id: REPO_ZERO,
+        hg_cs_id: hg::ONES_CSID,
+        bcs_id: bonsai::ONES_CSID,
+    };
+    assert!(weight_matches_estimate(
+        entry.get_weight(),
+        abomonation::measure(&entry)
+    ));
+}
@@ -0,0 +1,9 @@
+CREATE TABLE bookmark_snapshots (
+  id BIGINT UNSIGNED PRIMARY KEY AUTO_INCREMENT NOT NULL,
+  repo_id INT UNSIGNED NOT NULL,
+  -- Key of the blob of the snapshot, in the blobstore of the repo
+  snapshot_key VARCHAR(255) NOT NULL,
+  -- Unix timestamp in seconds
+  created_at BIGINT NOT NULL,
+  INDEX repo_id (repo_id, id)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE bookmark_move_intents (
+  token VARCHAR(32) PRIMARY KEY NOT NULL,
+  owner VARCHAR(255) NOT NULL,
+  repo_id INT UNSIGNED NOT NULL,
+  name VARCHAR(512) NOT NULL,
+  old_changeset_id VARBINARY(32),
+  new_changeset_id VARBINARY(32) NOT NULL,
+  -- Unix timestamp in seconds from which the intent has expired
+  deadline BIGINT NOT NULL,
+  -- A bookmark has at most one intent, live or expired
+  UNIQUE (repo_id, name)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE bookmark_snapshots (
+  -- Sqlite doesn't support autoincrement UNSIGNED BIGINT
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  repo_id INT UNSIGNED NOT NULL,
+  -- Key of the blob of the snapshot, in the blobstore of the repo
+  snapshot_key VARCHAR(255) NOT NULL,
+  -- Unix timestamp in seconds
+  created_at BIGINT NOT NULL
+);
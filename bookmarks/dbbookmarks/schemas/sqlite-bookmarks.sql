@@ -2,5 +2,18 @@ CREATE TABLE bookmarks (
   repo_id INT UNSIGNED NOT NULL,
   name VARCHAR(512) NOT NULL,
   changeset_id VARBINARY(32) NOT NULL,
+  -- Unix timestamp in seconds, NULL for bookmarks last updated before it was recorded
+  last_updated BIGINT,
   PRIMARY KEY (repo_id, name)
 );
+
+CREATE TABLE bookmarks_update_log (
+  -- Sqlite doesn't support autoincrement UNSIGNED BIGINT
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  repo_id INT UNSIGNED NOT NULL,
+  name VARCHAR(512) NOT NULL,
+  from_changeset_id VARBINARY(32),
+  to_changeset_id VARBINARY(32),
+  reason VARCHAR(32) NOT NULL,
+  timestamp BIGINT NOT NULL
+);
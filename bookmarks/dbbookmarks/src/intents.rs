@@ -0,0 +1,188 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! SQL storage of prepared bookmark moves, shared by every server of a repo
+
+use std::result;
+use std::str::FromStr;
+use std::sync::MutexGuard;
+
+use bookmarks::{Bookmark, BookmarkIntentStore, BookmarkMoveIntent, BookmarkMoveToken};
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{delete, insert_into, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use failure::{Error, Result};
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use models::BookmarkMoveIntentRow;
+use schema::bookmark_move_intents;
+
+#[derive(Clone)]
+pub struct SqliteBookmarkIntentStore {
+    inner: SqliteConnInner,
+}
+
+impl SqliteBookmarkIntentStore {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-bookmark-intents.sql")
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    /// Open a SQLite database, and create the tables if they are missing
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlBookmarkIntentStore {
+    inner: MysqlConnInner,
+}
+
+impl MysqlBookmarkIntentStore {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-bookmark-intents.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+fn intent_to_row(intent: BookmarkMoveIntent) -> BookmarkMoveIntentRow {
+    BookmarkMoveIntentRow {
+        token: intent.token.to_string(),
+        owner: intent.owner,
+        repo_id: intent.repoid,
+        name: intent.bookmark.to_string(),
+        old_changeset_id: intent.old_cs,
+        new_changeset_id: intent.new_cs,
+        deadline: intent.deadline,
+    }
+}
+
+fn intent_from_row(row: BookmarkMoveIntentRow) -> Result<BookmarkMoveIntent> {
+    Ok(BookmarkMoveIntent {
+        token: BookmarkMoveToken::from_str(&row.token)?,
+        owner: row.owner,
+        repoid: row.repo_id,
+        bookmark: Bookmark::new(row.name)?,
+        old_cs: row.old_changeset_id,
+        new_cs: row.new_changeset_id,
+        deadline: row.deadline,
+    })
+}
+
+macro_rules! impl_bookmark_intent_store {
+    ($struct: ty) => {
+        impl BookmarkIntentStore for $struct {
+            fn add(&self, intent: BookmarkMoveIntent, now: i64) -> BoxFuture<bool, Error> {
+                #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                let connection = try_boxfuture!(self.get_conn());
+
+                let row = intent_to_row(intent);
+                let txnres = connection.transaction::<_, DieselError, _>(|| {
+                    let same_bookmark = || {
+                        bookmark_move_intents::table
+                            .filter(bookmark_move_intents::repo_id.eq(row.repo_id))
+                            .filter(bookmark_move_intents::name.eq(row.name.clone()))
+                    };
+
+                    delete(same_bookmark().filter(bookmark_move_intents::deadline.le(now)))
+                        .execute(&*connection)?;
+                    let live = same_bookmark()
+                        .select(bookmark_move_intents::token)
+                        .first::<String>(&*connection)
+                        .optional()?;
+                    if live.is_some() {
+                        return Ok(false);
+                    }
+
+                    insert_into(bookmark_move_intents::table)
+                        .values(&row)
+                        .execute(&*connection)?;
+                    Ok(true)
+                });
+                let txnres = match txnres {
+                    // Another server recorded an intent for the bookmark in the meantime
+                    Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                        Ok(false)
+                    }
+                    txnres => txnres,
+                };
+                future::result(txnres).from_err().boxify()
+            }
+
+            fn take(
+                &self,
+                token: &BookmarkMoveToken,
+                owner: &str,
+            ) -> BoxFuture<Option<BookmarkMoveIntent>, Error> {
+                #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                let connection = try_boxfuture!(self.get_conn());
+
+                let txnres = connection.transaction::<_, DieselError, _>(|| {
+                    let owned = || {
+                        bookmark_move_intents::table
+                            .filter(bookmark_move_intents::token.eq(token.to_string()))
+                            .filter(bookmark_move_intents::owner.eq(owner.to_string()))
+                    };
+
+                    let row = owned()
+                        .first::<BookmarkMoveIntentRow>(&*connection)
+                        .optional()?;
+                    // Only the caller that deletes the intent gets it, if two take it at once
+                    let num_deleted_rows = delete(owned()).execute(&*connection)?;
+                    Ok(if num_deleted_rows == 1 { row } else { None })
+                });
+                future::result(txnres)
+                    .from_err()
+                    .and_then(|row| match row {
+                        Some(row) => intent_from_row(row).map(Some),
+                        None => Ok(None),
+                    })
+                    .boxify()
+            }
+        }
+    }
+}
+
+impl_bookmark_intent_store!(SqliteBookmarkIntentStore);
+impl_bookmark_intent_store!(MysqlBookmarkIntentStore);
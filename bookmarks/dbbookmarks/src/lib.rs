@@ -28,8 +28,14 @@ extern crate storage_types;
 
 mod schema;
 mod models;
+mod intents;
+mod snapshots;
 
-use bookmarks::{Bookmark, BookmarkPrefix, Bookmarks, Transaction};
+pub use intents::{MysqlBookmarkIntentStore, SqliteBookmarkIntentStore};
+pub use snapshots::{MysqlBookmarkSnapshotIndex, SqliteBookmarkSnapshotIndex};
+
+use bookmarks::{Bookmark, BookmarkPrefix, BookmarkUpdateLogEntry, BookmarkUpdateReason, Bookmarks,
+                Transaction};
 use db_conn::{MysqlConnInner, SqliteConnInner};
 use diesel::{delete, insert_into, replace_into, update, MysqlConnection, SqliteConnection};
 use diesel::prelude::*;
@@ -41,18 +47,42 @@ use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use db::ConnectionParams;
 use mercurial_types::RepositoryId;
 use mononoke_types::ChangesetId;
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
 use std::result;
-use std::sync::MutexGuard;
+use std::str::FromStr;
+use std::sync::{Arc, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the times of the updates of bookmarks, as unix timestamps in seconds
+pub type Clock = Arc<Fn() -> i64 + Send + Sync>;
+
+fn system_clock() -> Clock {
+    Arc::new(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs() as i64)
+            .unwrap_or(0)
+    })
+}
 
 #[derive(Clone)]
 pub struct SqliteDbBookmarks {
     inner: SqliteConnInner,
+    clock: Clock,
 }
 
 impl SqliteDbBookmarks {
     fn from(inner: SqliteConnInner) -> SqliteDbBookmarks {
-        SqliteDbBookmarks { inner } // one true constructor
+        SqliteDbBookmarks {
+            inner,
+            clock: system_clock(),
+        } // one true constructor
+    }
+
+    /// Replaces the clock that timestamps updates, e.g. with a fake one in tests
+    pub fn with_clock(self, clock: Clock) -> Self {
+        SqliteDbBookmarks { clock, ..self }
     }
 
     fn get_up_query() -> &'static str {
@@ -81,11 +111,20 @@ impl SqliteDbBookmarks {
 #[derive(Clone)]
 pub struct MysqlDbBookmarks {
     inner: MysqlConnInner,
+    clock: Clock,
 }
 
 impl MysqlDbBookmarks {
     fn from(inner: MysqlConnInner) -> MysqlDbBookmarks {
-        MysqlDbBookmarks { inner } // one true constructor
+        MysqlDbBookmarks {
+            inner,
+            clock: system_clock(),
+        } // one true constructor
+    }
+
+    /// Replaces the clock that timestamps updates, e.g. with a fake one in tests
+    pub fn with_clock(self, clock: Clock) -> Self {
+        MysqlDbBookmarks { clock, ..self }
     }
 
     pub fn open(params: &ConnectionParams) -> Result<Self> {
@@ -165,6 +204,67 @@ macro_rules! impl_bookmarks {
                     .boxify()
             }
 
+            fn list_by_prefix_with_timestamps(
+                &self,
+                prefix: &BookmarkPrefix,
+                repo_id: &RepositoryId,
+            ) -> BoxStream<(Bookmark, ChangesetId, Option<i64>), Error> {
+                #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                let connection = match self.get_conn() {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        return stream::once(Err(err)).boxify();
+                    },
+                };
+
+                schema::bookmarks::table
+                    .filter(schema::bookmarks::repo_id.eq(repo_id))
+                    .filter(schema::bookmarks::name.like(format!("{}%", prefix.to_string())))
+                    .get_results::<models::BookmarkRow>(&*connection)
+                    .into_future()
+                    .from_err()
+                    .map(|rows| stream::iter_ok(rows))
+                    .flatten_stream()
+                    .and_then(|row| {
+                        Ok((Bookmark::new(row.name)?, row.changeset_id, row.last_updated))
+                    })
+                    .boxify()
+            }
+
+            fn read_update_log(
+                &self,
+                name: &Bookmark,
+                repo_id: &RepositoryId,
+            ) -> BoxStream<BookmarkUpdateLogEntry, Error> {
+                #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                let connection = match self.get_conn() {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        return stream::once(Err(err)).boxify();
+                    },
+                };
+
+                schema::bookmarks_update_log::table
+                    .filter(schema::bookmarks_update_log::repo_id.eq(repo_id))
+                    .filter(schema::bookmarks_update_log::name.eq(name.to_string()))
+                    .order(schema::bookmarks_update_log::id.asc())
+                    .get_results::<models::BookmarkUpdateLogRow>(&*connection)
+                    .into_future()
+                    .from_err()
+                    .map(|rows| stream::iter_ok(rows))
+                    .flatten_stream()
+                    .and_then(|row| {
+                        Ok(BookmarkUpdateLogEntry {
+                            bookmark: Bookmark::new(row.name)?,
+                            from_changeset_id: row.from_changeset_id,
+                            to_changeset_id: row.to_changeset_id,
+                            reason: BookmarkUpdateReason::from_str(&row.reason)?,
+                            timestamp: row.timestamp,
+                        })
+                    })
+                    .boxify()
+            }
+
             fn create_transaction(&self, repoid: &RepositoryId) -> Box<Transaction> {
                 Box::new($transaction_struct::new(
                     self.clone(),
@@ -181,6 +281,7 @@ macro_rules! impl_bookmarks {
             force_deletes: HashSet<Bookmark>,
             deletes: HashMap<Bookmark, ChangesetId>,
             repo_id: RepositoryId,
+            reason: BookmarkUpdateReason,
         }
 
         impl $transaction_struct {
@@ -193,6 +294,7 @@ macro_rules! impl_bookmarks {
                     force_deletes: HashSet::new(),
                     deletes: HashMap::new(),
                     repo_id: *repo_id,
+                    reason: BookmarkUpdateReason::Unknown,
                 }
             }
 
@@ -249,56 +351,84 @@ macro_rules! impl_bookmarks {
                 Ok(())
             }
 
+            fn set_reason(&mut self, reason: BookmarkUpdateReason) {
+                self.reason = reason;
+            }
+
             fn commit(&self) -> BoxFuture<bool, Error> {
                 #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
                 let connection = try_boxfuture!(self.db.get_conn());
 
-                let txnres = connection.transaction::<_, Error, _>(|| {
+                let now = (self.db.clock)();
+                let log = LogRows::new(self.repo_id, self.reason, now);
+                // A conflict rolls back the whole transaction
+                let conflict = Cell::new(false);
+                let txnres = connection.transaction::<_, diesel::result::Error, _>(|| {
                     replace_into(schema::bookmarks::table)
-                        .values(&create_bookmarks_rows(self.repo_id, &self.force_sets))
+                        .values(&create_bookmarks_rows(self.repo_id, &self.force_sets, now))
                         .execute(&*connection)?;
 
                     insert_into(schema::bookmarks::table)
-                        .values(&create_bookmarks_rows(self.repo_id, &self.creates))
+                        .values(&create_bookmarks_rows(self.repo_id, &self.creates, now))
                         .execute(&*connection)?;
 
+                    let mut log_rows = Vec::new();
+                    for (key, new_cs) in self.force_sets.iter().chain(self.creates.iter()) {
+                        log_rows.push(log.row(key, None, Some(*new_cs)));
+                    }
+
                     for (key, &BookmarkSetData { new_cs, old_cs }) in self.sets.iter() {
-                        let key = key.to_string();
                         let num_affected_rows = update(
                             schema::bookmarks::table
                                 .filter(schema::bookmarks::repo_id.eq(self.repo_id))
-                                .filter(schema::bookmarks::name.eq(key.clone()))
+                                .filter(schema::bookmarks::name.eq(key.to_string()))
                                 .filter(schema::bookmarks::changeset_id.eq(old_cs)),
-                        ).set(schema::bookmarks::changeset_id.eq(new_cs))
+                        ).set((
+                            schema::bookmarks::changeset_id.eq(new_cs),
+                            schema::bookmarks::last_updated.eq(Some(now)),
+                        ))
                             .execute(&*connection)?;
                         if num_affected_rows != 1 {
-                            return Ok(false) // conflict
+                            conflict.set(true);
+                            return Err(diesel::result::Error::RollbackTransaction);
                         }
+                        log_rows.push(log.row(key, Some(old_cs), Some(new_cs)));
                     }
 
                     for key in self.force_deletes.iter() {
-                        let key = key.to_string();
-                        delete(schema::bookmarks::table
+                        let num_deleted_rows = delete(schema::bookmarks::table
                                 .filter(schema::bookmarks::repo_id.eq(self.repo_id))
-                                .filter(schema::bookmarks::name.eq(key))
+                                .filter(schema::bookmarks::name.eq(key.to_string()))
                             )
                             .execute(&*connection)?;
+                        if num_deleted_rows > 0 {
+                            log_rows.push(log.row(key, None, None));
+                        }
                     }
 
                     for (key, old_cs) in self.deletes.iter() {
-                        let key = key.to_string();
                         let num_deleted_rows = delete(
                             schema::bookmarks::table
                                 .filter(schema::bookmarks::repo_id.eq(self.repo_id))
-                                .filter(schema::bookmarks::name.eq(key.clone()))
+                                .filter(schema::bookmarks::name.eq(key.to_string()))
                                 .filter(schema::bookmarks::changeset_id.eq(old_cs)),
                         ).execute(&*connection)?;
                         if num_deleted_rows != 1 {
-                            return Ok(false) // conflict
+                            conflict.set(true);
+                            return Err(diesel::result::Error::RollbackTransaction);
                         }
+                        log_rows.push(log.row(key, Some(*old_cs), None));
                     }
+
+                    insert_into(schema::bookmarks_update_log::table)
+                        .values(&log_rows)
+                        .execute(&*connection)?;
                     Ok(true)
                 });
+                let txnres = match txnres {
+                    Err(_) if conflict.get() => Ok(false),
+                    txnres => txnres,
+                };
                 future::result(txnres).from_err().boxify()
             }
         }
@@ -316,12 +446,47 @@ struct BookmarkSetData {
 fn create_bookmarks_rows(
     repo_id: RepositoryId,
     map: &HashMap<Bookmark, ChangesetId>,
+    now: i64,
 ) -> Vec<models::BookmarkRow> {
     map.iter()
         .map(|(name, changeset_id)| models::BookmarkRow {
             repo_id,
             name: name.to_string(),
             changeset_id: *changeset_id,
+            last_updated: Some(now),
         })
         .collect()
 }
+
+/// The rows that a transaction adds to the update log
+struct LogRows {
+    repo_id: RepositoryId,
+    reason: BookmarkUpdateReason,
+    timestamp: i64,
+}
+
+impl LogRows {
+    fn new(repo_id: RepositoryId, reason: BookmarkUpdateReason, timestamp: i64) -> Self {
+        LogRows {
+            repo_id,
+            reason,
+            timestamp,
+        }
+    }
+
+    fn row(
+        &self,
+        name: &Bookmark,
+        from_changeset_id: Option<ChangesetId>,
+        to_changeset_id: Option<ChangesetId>,
+    ) -> models::BookmarkUpdateLogInsertRow {
+        models::BookmarkUpdateLogInsertRow {
+            repo_id: self.repo_id,
+            name: name.to_string(),
+            from_changeset_id,
+            to_changeset_id,
+            reason: self.reason.as_str().to_string(),
+            timestamp: self.timestamp,
+        }
+    }
+}
@@ -7,7 +7,7 @@
 use mercurial_types::RepositoryId;
 use mononoke_types::ChangesetId;
 
-use schema::bookmarks;
+use schema::{bookmark_move_intents, bookmark_snapshots, bookmarks, bookmarks_update_log};
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 #[derive(Queryable, Insertable)]
@@ -17,4 +17,51 @@ pub(crate) struct BookmarkRow {
     // TODO(stash): make AsciiString Insertable
     pub name: String,
     pub changeset_id: ChangesetId,
+    pub last_updated: Option<i64>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable)]
+pub(crate) struct BookmarkUpdateLogRow {
+    pub id: i64,
+    pub repo_id: RepositoryId,
+    pub name: String,
+    pub from_changeset_id: Option<ChangesetId>,
+    pub to_changeset_id: Option<ChangesetId>,
+    pub reason: String,
+    pub timestamp: i64,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Insertable)]
+#[table_name = "bookmarks_update_log"]
+pub(crate) struct BookmarkUpdateLogInsertRow {
+    pub repo_id: RepositoryId,
+    pub name: String,
+    pub from_changeset_id: Option<ChangesetId>,
+    pub to_changeset_id: Option<ChangesetId>,
+    pub reason: String,
+    pub timestamp: i64,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "bookmark_move_intents"]
+pub(crate) struct BookmarkMoveIntentRow {
+    pub token: String,
+    pub owner: String,
+    pub repo_id: RepositoryId,
+    pub name: String,
+    pub old_changeset_id: Option<ChangesetId>,
+    pub new_changeset_id: ChangesetId,
+    pub deadline: i64,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Insertable)]
+#[table_name = "bookmark_snapshots"]
+pub(crate) struct BookmarkSnapshotInsertRow {
+    pub repo_id: RepositoryId,
+    pub snapshot_key: String,
+    pub created_at: i64,
 }
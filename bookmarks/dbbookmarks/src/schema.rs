@@ -9,7 +9,7 @@
 //! changes it will need to be updated here as well.
 
 table! {
-    use diesel::sql_types::{Integer, Text};
+    use diesel::sql_types::{BigInt, Integer, Nullable, Text};
 
     use mononoke_types::sql_types::ChangesetIdSql;
 
@@ -17,5 +17,49 @@ table! {
         repo_id -> Integer,
         name -> Text,
         changeset_id -> ChangesetIdSql,
+        last_updated -> Nullable<BigInt>,
+    }
+}
+
+table! {
+    use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+
+    use mononoke_types::sql_types::ChangesetIdSql;
+
+    bookmarks_update_log {
+        id -> BigInt,
+        repo_id -> Integer,
+        name -> Text,
+        from_changeset_id -> Nullable<ChangesetIdSql>,
+        to_changeset_id -> Nullable<ChangesetIdSql>,
+        reason -> Text,
+        timestamp -> BigInt,
+    }
+}
+
+table! {
+    use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+
+    use mononoke_types::sql_types::ChangesetIdSql;
+
+    bookmark_move_intents (token) {
+        token -> Text,
+        owner -> Text,
+        repo_id -> Integer,
+        name -> Text,
+        old_changeset_id -> Nullable<ChangesetIdSql>,
+        new_changeset_id -> ChangesetIdSql,
+        deadline -> BigInt,
+    }
+}
+
+table! {
+    use diesel::sql_types::{BigInt, Integer, Text};
+
+    bookmark_snapshots (id) {
+        id -> BigInt,
+        repo_id -> Integer,
+        snapshot_key -> Text,
+        created_at -> BigInt,
     }
 }
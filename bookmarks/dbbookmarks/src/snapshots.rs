@@ -0,0 +1,148 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! SQL index of the bookmark snapshots of repos, shared by every server of a repo
+
+use std::result;
+use std::sync::MutexGuard;
+
+use bookmarks::BookmarkSnapshotIndex;
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{delete, insert_into, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::result::Error as DieselError;
+use failure::{Error, Result};
+use futures::future;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::RepositoryId;
+
+use models::BookmarkSnapshotInsertRow;
+use schema::bookmark_snapshots;
+
+#[derive(Clone)]
+pub struct SqliteBookmarkSnapshotIndex {
+    inner: SqliteConnInner,
+}
+
+impl SqliteBookmarkSnapshotIndex {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-bookmark-snapshots.sql")
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    /// Open a SQLite database, and create the tables if they are missing
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlBookmarkSnapshotIndex {
+    inner: MysqlConnInner,
+}
+
+impl MysqlBookmarkSnapshotIndex {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-bookmark-snapshots.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+macro_rules! impl_bookmark_snapshot_index {
+    ($struct: ty) => {
+        impl BookmarkSnapshotIndex for $struct {
+            fn add(
+                &self,
+                repoid: &RepositoryId,
+                key: &str,
+                created_at: i64,
+                retain: usize,
+            ) -> BoxFuture<(), Error> {
+                #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                let connection = try_boxfuture!(self.get_conn());
+
+                let row = BookmarkSnapshotInsertRow {
+                    repo_id: *repoid,
+                    snapshot_key: key.to_string(),
+                    created_at,
+                };
+                let txnres = connection.transaction::<_, DieselError, _>(|| {
+                    let of_repo = || {
+                        bookmark_snapshots::table.filter(bookmark_snapshots::repo_id.eq(*repoid))
+                    };
+
+                    insert_into(bookmark_snapshots::table)
+                        .values(&row)
+                        .execute(&*connection)?;
+                    // Ids increase with every insert, so the newest snapshots have the highest
+                    // ones, even when two servers take a snapshot in the same second
+                    let oldest_retained = of_repo()
+                        .select(bookmark_snapshots::id)
+                        .order(bookmark_snapshots::id.desc())
+                        .offset(retain.saturating_sub(1) as i64)
+                        .first::<i64>(&*connection)
+                        .optional()?;
+                    if let Some(oldest) = oldest_retained {
+                        delete(of_repo().filter(bookmark_snapshots::id.lt(oldest)))
+                            .execute(&*connection)?;
+                    }
+                    Ok(())
+                });
+                future::result(txnres).from_err().boxify()
+            }
+
+            fn list(&self, repoid: &RepositoryId) -> BoxFuture<Vec<String>, Error> {
+                #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                let connection = try_boxfuture!(self.get_conn());
+
+                let keys = bookmark_snapshots::table
+                    .filter(bookmark_snapshots::repo_id.eq(*repoid))
+                    .order(bookmark_snapshots::id.asc())
+                    .select(bookmark_snapshots::snapshot_key)
+                    .load::<String>(&*connection);
+                future::result(keys).from_err().boxify()
+            }
+        }
+    }
+}
+
+impl_bookmark_snapshot_index!(SqliteBookmarkSnapshotIndex);
+impl_bookmark_snapshot_index!(MysqlBookmarkSnapshotIndex);
@@ -22,10 +22,15 @@ extern crate mononoke_types;
 extern crate mononoke_types_mocks;
 extern crate tokio;
 
-use bookmarks::{Bookmark, BookmarkPrefix};
-use dbbookmarks::{MysqlDbBookmarks, SqliteDbBookmarks};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bookmarks::{Bookmark, BookmarkIntents, BookmarkMoveToken, BookmarkPrefix,
+                BookmarkSnapshotIndex, BookmarkUpdateLogEntry, BookmarkUpdateReason};
+use dbbookmarks::{Clock, MysqlBookmarkIntentStore, MysqlBookmarkSnapshotIndex, MysqlDbBookmarks,
+                  SqliteBookmarkIntentStore, SqliteBookmarkSnapshotIndex, SqliteDbBookmarks};
 use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
-use mononoke_types_mocks::changesetid::{ONES_CSID, TWOS_CSID};
+use mononoke_types_mocks::changesetid::{ONES_CSID, THREES_CSID, TWOS_CSID};
 
 fn create_bookmark(book: &str) -> Bookmark {
     Bookmark::new(book.to_string()).unwrap()
@@ -35,9 +40,36 @@ fn create_prefix(book: &str) -> BookmarkPrefix {
     BookmarkPrefix::new(book.to_string()).unwrap()
 }
 
+/// A clock that stays at the time it was last set to
+fn fake_clock(now: i64) -> (Arc<Mutex<i64>>, Clock) {
+    let time = Arc::new(Mutex::new(now));
+    let clock: Clock = {
+        let time = time.clone();
+        Arc::new(move || *time.lock().unwrap())
+    };
+    (time, clock)
+}
+
+#[test]
+fn test_bookmark_move_token_from_str() {
+    let token = "0123456789abcdef0123456789ABCDEF"
+        .parse::<BookmarkMoveToken>()
+        .unwrap();
+    assert_eq!(token.as_str(), "0123456789abcdef0123456789abcdef");
+
+    assert!("0".parse::<BookmarkMoveToken>().is_err());
+    assert!(
+        "0123456789abcdef0123456789abcdeg"
+            .parse::<BookmarkMoveToken>()
+            .is_err()
+    );
+}
+
 macro_rules! bookmarks_test_impl {
     ($mod_name: ident => {
         new: $new_cb: expr,
+        new_intents: $new_intents_cb: expr,
+        new_snapshots: $new_snapshots_cb: expr,
     }) => {
         mod $mod_name {
             use super::*;
@@ -315,6 +347,87 @@ macro_rules! bookmarks_test_impl {
                 );
             }
 
+            #[test]
+            fn test_conflict_rolls_back_transaction() {
+                let bookmarks = $new_cb();
+                let name_1 = create_bookmark("book1");
+                let name_2 = create_bookmark("book2");
+
+                let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+                txn.create(&name_1, &ONES_CSID).unwrap();
+                assert!(txn.commit().wait().unwrap());
+
+                let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+                txn.create(&name_2, &TWOS_CSID).unwrap();
+                txn.update(&name_1, &TWOS_CSID, &THREES_CSID).unwrap();
+                assert_eq!(txn.commit().wait().unwrap(), false);
+
+                assert_eq!(bookmarks.get(&name_2, &REPO_ZERO).wait().unwrap(), None);
+                assert_eq!(
+                    bookmarks.read_update_log(&name_2, &REPO_ZERO).collect().wait().unwrap(),
+                    vec![]
+                );
+            }
+
+            #[test]
+            fn test_update_timestamps_and_log() {
+                let (time, clock) = fake_clock(100);
+                let bookmarks = $new_cb().with_clock(clock);
+                let name_1 = create_bookmark("scratch/alice/1");
+                let name_2 = create_bookmark("scratch/bob/1");
+
+                let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+                txn.create(&name_1, &ONES_CSID).unwrap();
+                txn.create(&name_2, &ONES_CSID).unwrap();
+                assert!(txn.commit().wait().unwrap());
+
+                *time.lock().unwrap() = 200;
+                let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+                txn.update(&name_1, &TWOS_CSID, &ONES_CSID).unwrap();
+                assert!(txn.commit().wait().unwrap());
+
+                let mut listed = bookmarks
+                    .list_by_prefix_with_timestamps(&create_prefix("scratch/"), &REPO_ZERO)
+                    .collect()
+                    .wait()
+                    .unwrap();
+                listed.sort();
+                assert_eq!(
+                    listed,
+                    vec![
+                        (name_1.clone(), TWOS_CSID, Some(200)),
+                        (name_2.clone(), ONES_CSID, Some(100)),
+                    ]
+                );
+
+                *time.lock().unwrap() = 300;
+                let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+                txn.set_reason(BookmarkUpdateReason::Retention);
+                txn.delete(&name_1, &TWOS_CSID).unwrap();
+                assert!(txn.commit().wait().unwrap());
+
+                let entry = |from, to, reason, timestamp| BookmarkUpdateLogEntry {
+                    bookmark: name_1.clone(),
+                    from_changeset_id: from,
+                    to_changeset_id: to,
+                    reason,
+                    timestamp,
+                };
+                assert_eq!(
+                    bookmarks.read_update_log(&name_1, &REPO_ZERO).collect().wait().unwrap(),
+                    vec![
+                        entry(None, Some(ONES_CSID), BookmarkUpdateReason::Unknown, 100),
+                        entry(Some(ONES_CSID), Some(TWOS_CSID), BookmarkUpdateReason::Unknown, 200),
+                        entry(Some(TWOS_CSID), None, BookmarkUpdateReason::Retention, 300),
+                    ]
+                );
+                // The log is per repo
+                assert_eq!(
+                    bookmarks.read_update_log(&name_1, &REPO_ONE).collect().wait().unwrap(),
+                    vec![]
+                );
+            }
+
             #[test]
             fn test_create_different_repos() {
                 let bookmarks = $new_cb();
@@ -348,16 +461,217 @@ macro_rules! bookmarks_test_impl {
                 txn.delete(&name_1, &ONES_CSID).unwrap();
                 assert_eq!(txn.commit().wait().unwrap(), false);
             }
+
+            fn new_intents(bookmarks: Arc<Bookmarks>) -> BookmarkIntents {
+                BookmarkIntents::new(bookmarks, Arc::new($new_intents_cb()))
+            }
+
+            #[test]
+            fn test_two_phase_move_in_two_repos() {
+                let bookmarks = Arc::new($new_cb());
+                let intents = new_intents(bookmarks.clone());
+                let name = create_bookmark("book");
+                let ttl = Duration::from_secs(60);
+
+                let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+                txn.force_set(&name, &ONES_CSID).unwrap();
+                assert!(txn.commit().wait().unwrap());
+
+                let token_zero = intents
+                    .prepare("alice", &REPO_ZERO, &name, Some(ONES_CSID), TWOS_CSID, ttl)
+                    .wait()
+                    .unwrap();
+                let token_one = intents
+                    .prepare("alice", &REPO_ONE, &name, None, THREES_CSID, ttl)
+                    .wait()
+                    .unwrap();
+                assert_ne!(token_zero, token_one);
+
+                // Nothing moves until the intents are committed
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), Some(ONES_CSID));
+                assert_eq!(bookmarks.get(&name, &REPO_ONE).wait().unwrap(), None);
+
+                assert!(intents.commit("alice", &token_zero).wait().unwrap());
+                assert!(intents.commit("alice", &token_one).wait().unwrap());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), Some(TWOS_CSID));
+                assert_eq!(bookmarks.get(&name, &REPO_ONE).wait().unwrap(), Some(THREES_CSID));
+
+                // Tokens can be used only once
+                assert!(intents.commit("alice", &token_zero).wait().is_err());
+            }
+
+            #[test]
+            fn test_two_phase_move_abort() {
+                let bookmarks = Arc::new($new_cb());
+                let intents = new_intents(bookmarks.clone());
+                let name = create_bookmark("book");
+                let ttl = Duration::from_secs(60);
+
+                let token = intents
+                    .prepare("alice", &REPO_ZERO, &name, None, ONES_CSID, ttl)
+                    .wait()
+                    .unwrap();
+                intents.abort("alice", &token).wait().unwrap();
+                assert!(intents.abort("alice", &token).wait().is_err());
+                assert!(intents.commit("alice", &token).wait().is_err());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), None);
+
+                // The bookmark can be prepared again after an abort
+                let token = intents
+                    .prepare("alice", &REPO_ZERO, &name, None, TWOS_CSID, ttl)
+                    .wait()
+                    .unwrap();
+                assert!(intents.commit("alice", &token).wait().unwrap());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), Some(TWOS_CSID));
+            }
+
+            #[test]
+            fn test_two_phase_move_expired() {
+                let bookmarks = Arc::new($new_cb());
+                let intents = new_intents(bookmarks.clone());
+                let name = create_bookmark("book");
+                let expired_ttl = Duration::from_secs(0);
+
+                let token = intents
+                    .prepare("alice", &REPO_ZERO, &name, None, ONES_CSID, expired_ttl)
+                    .wait()
+                    .unwrap();
+                assert!(intents.commit("alice", &token).wait().is_err());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), None);
+
+                // An expired intent doesn't block new ones
+                let expired = intents
+                    .prepare("alice", &REPO_ZERO, &name, None, ONES_CSID, expired_ttl)
+                    .wait()
+                    .unwrap();
+                let token = intents
+                    .prepare("bob", &REPO_ZERO, &name, None, TWOS_CSID, Duration::from_secs(60))
+                    .wait()
+                    .unwrap();
+                assert!(intents.commit("alice", &expired).wait().is_err());
+                assert!(intents.commit("bob", &token).wait().unwrap());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), Some(TWOS_CSID));
+            }
+
+            #[test]
+            fn test_two_phase_move_conflicts() {
+                let bookmarks = Arc::new($new_cb());
+                let intents = new_intents(bookmarks.clone());
+                let name = create_bookmark("book");
+                let ttl = Duration::from_secs(60);
+
+                // Preparing fails if the bookmark doesn't point to the expected commit
+                assert!(
+                    intents
+                        .prepare("alice", &REPO_ZERO, &name, Some(ONES_CSID), TWOS_CSID, ttl)
+                        .wait()
+                        .is_err()
+                );
+
+                let token = intents
+                    .prepare("alice", &REPO_ZERO, &name, None, ONES_CSID, ttl)
+                    .wait()
+                    .unwrap();
+                assert!(
+                    intents
+                        .prepare("bob", &REPO_ZERO, &name, None, TWOS_CSID, ttl)
+                        .wait()
+                        .is_err()
+                );
+                // Same bookmark in another repo is not a conflict
+                let other_repo = intents
+                    .prepare("alice", &REPO_ONE, &name, None, TWOS_CSID, ttl)
+                    .wait()
+                    .unwrap();
+
+                // If the bookmark moves behind the intent's back, the commit is rejected
+                let mut txn = bookmarks.create_transaction(&REPO_ZERO);
+                txn.force_set(&name, &THREES_CSID).unwrap();
+                assert!(txn.commit().wait().unwrap());
+                assert!(!intents.commit("alice", &token).wait().unwrap());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), Some(THREES_CSID));
+
+                intents.abort("alice", &other_repo).wait().unwrap();
+            }
+
+            #[test]
+            fn test_two_phase_move_other_owner() {
+                let bookmarks = Arc::new($new_cb());
+                let intents = new_intents(bookmarks.clone());
+                let name = create_bookmark("book");
+                let ttl = Duration::from_secs(60);
+
+                let token = intents
+                    .prepare("alice", &REPO_ZERO, &name, None, ONES_CSID, ttl)
+                    .wait()
+                    .unwrap();
+
+                // Someone who learnt the token can neither commit nor abort the move
+                assert!(intents.commit("bob", &token).wait().is_err());
+                assert!(intents.abort("bob", &token).wait().is_err());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), None);
+
+                assert!(intents.commit("alice", &token).wait().unwrap());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), Some(ONES_CSID));
+            }
+
+            #[test]
+            fn test_two_phase_move_shared_store() {
+                // Two servers of the same repo share the store of its intents
+                let bookmarks = Arc::new($new_cb());
+                let store = Arc::new($new_intents_cb());
+                let first = BookmarkIntents::new(bookmarks.clone(), store.clone());
+                let second = BookmarkIntents::new(bookmarks.clone(), store);
+                let name = create_bookmark("book");
+                let ttl = Duration::from_secs(60);
+
+                let token = first
+                    .prepare("alice", &REPO_ZERO, &name, None, ONES_CSID, ttl)
+                    .wait()
+                    .unwrap();
+                assert!(
+                    second
+                        .prepare("alice", &REPO_ZERO, &name, None, TWOS_CSID, ttl)
+                        .wait()
+                        .is_err()
+                );
+                assert!(second.commit("alice", &token).wait().unwrap());
+                assert_eq!(bookmarks.get(&name, &REPO_ZERO).wait().unwrap(), Some(ONES_CSID));
+            }
+
+            #[test]
+            fn test_snapshot_index_retention() {
+                let index = $new_snapshots_cb();
+                assert!(index.list(&REPO_ZERO).wait().unwrap().is_empty());
+
+                // Snapshots taken in the same second are ordered as they were added
+                for key in &["a", "b", "c"] {
+                    index.add(&REPO_ZERO, key, 1000, 2).wait().unwrap();
+                }
+                index.add(&REPO_ONE, "d", 1000, 2).wait().unwrap();
+                assert_eq!(
+                    index.list(&REPO_ZERO).wait().unwrap(),
+                    vec!["b".to_string(), "c".to_string()]
+                );
+                assert_eq!(index.list(&REPO_ONE).wait().unwrap(), vec!["d".to_string()]);
+
+                index.add(&REPO_ZERO, "e", 1001, 1).wait().unwrap();
+                assert_eq!(index.list(&REPO_ZERO).wait().unwrap(), vec!["e".to_string()]);
+            }
         }
     }
 }
 
 bookmarks_test_impl!(sqlite_tests => {
      new: create_sqlite,
+     new_intents: create_sqlite_intents,
+     new_snapshots: create_sqlite_snapshots,
  });
 
 bookmarks_test_impl!(mysql_tests => {
      new: create_mysql,
+     new_intents: create_mysql_intents,
+     new_snapshots: create_mysql_snapshots,
  });
 
 fn create_sqlite() -> SqliteDbBookmarks {
@@ -367,3 +681,19 @@ fn create_sqlite() -> SqliteDbBookmarks {
 fn create_mysql() -> MysqlDbBookmarks {
     MysqlDbBookmarks::create_test_db("mononokefilenodestest").unwrap()
 }
+
+fn create_sqlite_intents() -> SqliteBookmarkIntentStore {
+    SqliteBookmarkIntentStore::in_memory().unwrap()
+}
+
+fn create_mysql_intents() -> MysqlBookmarkIntentStore {
+    MysqlBookmarkIntentStore::create_test_db("mononokebookmarkintentstest").unwrap()
+}
+
+fn create_sqlite_snapshots() -> SqliteBookmarkSnapshotIndex {
+    SqliteBookmarkSnapshotIndex::in_memory().unwrap()
+}
+
+fn create_mysql_snapshots() -> MysqlBookmarkSnapshotIndex {
+    MysqlBookmarkSnapshotIndex::create_test_db("mononokebookmarksnapshotstest").unwrap()
+}
@@ -0,0 +1,24 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+pub use failure::{Error, Result};
+
+use mercurial_types::RepositoryId;
+use mononoke_types::ChangesetId;
+
+use Bookmark;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "bookmark {} in repo {:?} already has a pending move", _0, _1)]
+    ConflictingBookmarkMove(Bookmark, RepositoryId),
+    #[fail(display = "bookmark {} points to {:?}, expected {:?}", _0, _1, _2)]
+    BookmarkMismatch(Bookmark, Option<ChangesetId>, Option<ChangesetId>),
+    #[fail(display = "unknown bookmark move token {}", _0)]
+    UnknownBookmarkMove(String),
+    #[fail(display = "bookmark move {} has expired", _0)]
+    ExpiredBookmarkMove(String),
+}
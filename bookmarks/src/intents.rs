@@ -0,0 +1,221 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Two-phase bookmark moves.
+//!
+//! An external coordinator that needs to move bookmarks in several repos together first
+//! `prepare`s a move in every repo. Preparing validates that the bookmark currently points to the
+//! expected changeset and records an intent that lives until its TTL runs out. While an intent is
+//! live no other move of the same bookmark can be prepared. If every prepare succeeded the
+//! coordinator `commit`s all of them, otherwise it `abort`s the ones it holds.
+//!
+//! Committing an intent applies it as a regular compare-and-swap transaction, so a bookmark that
+//! was moved by someone else in the meantime makes the commit fail rather than overwrite the
+//! other move. Intents are kept in a `BookmarkIntentStore` that every server of a repo shares, so
+//! a move prepared on one server can be committed on another. An intent is identified by a
+//! random token, and only the identity that prepared it can commit or abort it. An expired or
+//! forgotten intent never blocks a bookmark for longer than its TTL.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::RepositoryId;
+use mononoke_types::ChangesetId;
+use rand::Rng;
+use rand::rngs::OsRng;
+
+use errors::*;
+use {Bookmark, Bookmarks};
+
+/// Number of random bytes in a token
+const TOKEN_BYTES: usize = 16;
+
+/// Identifies a prepared bookmark move: `TOKEN_BYTES` random bytes, in lowercase hex.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BookmarkMoveToken(String);
+
+impl BookmarkMoveToken {
+    fn random() -> Result<Self> {
+        let bytes: [u8; TOKEN_BYTES] = OsRng::new()?.gen();
+        let hex: Vec<_> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(BookmarkMoveToken(hex.concat()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for BookmarkMoveToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for BookmarkMoveToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() == TOKEN_BYTES * 2 && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Ok(BookmarkMoveToken(s.to_ascii_lowercase()))
+        } else {
+            Err(ErrorKind::UnknownBookmarkMove(s.to_string()).into())
+        }
+    }
+}
+
+/// A prepared bookmark move, as it is stored until it is committed or aborted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BookmarkMoveIntent {
+    pub token: BookmarkMoveToken,
+    /// Identity of the client that prepared the move. No one else can commit or abort it.
+    pub owner: String,
+    pub repoid: RepositoryId,
+    pub bookmark: Bookmark,
+    /// `None` if the bookmark didn't exist when the move was prepared
+    pub old_cs: Option<ChangesetId>,
+    pub new_cs: ChangesetId,
+    /// Unix timestamp in seconds from which the intent has expired
+    pub deadline: i64,
+}
+
+impl BookmarkMoveIntent {
+    pub fn is_live(&self, now: i64) -> bool {
+        self.deadline > now
+    }
+}
+
+/// Storage of prepared bookmark moves
+pub trait BookmarkIntentStore: Send + Sync + 'static {
+    /// Records `intent`, unless the same bookmark of the same repo has another intent that is
+    /// still live at `now`. Expired intents of the bookmark are replaced. Resolves to whether
+    /// `intent` was recorded.
+    fn add(&self, intent: BookmarkMoveIntent, now: i64) -> BoxFuture<bool, Error>;
+
+    /// Removes the intent recorded under `token` and resolves to it, if `owner` prepared it.
+    /// Intents of other owners are left alone, as if they didn't exist.
+    fn take(
+        &self,
+        token: &BookmarkMoveToken,
+        owner: &str,
+    ) -> BoxFuture<Option<BookmarkMoveIntent>, Error>;
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Prepares bookmark moves, and applies them on commit.
+#[derive(Clone)]
+pub struct BookmarkIntents {
+    bookmarks: Arc<Bookmarks>,
+    store: Arc<BookmarkIntentStore>,
+}
+
+impl BookmarkIntents {
+    pub fn new(bookmarks: Arc<Bookmarks>, store: Arc<BookmarkIntentStore>) -> Self {
+        Self { bookmarks, store }
+    }
+
+    /// Validates that `bookmark` currently points to `old_cs` (`None` meaning that it doesn't
+    /// exist) and records an intent of `owner` to move it to `new_cs` that is valid for `ttl`.
+    /// Fails if another live intent for the same bookmark exists.
+    pub fn prepare(
+        &self,
+        owner: &str,
+        repoid: &RepositoryId,
+        bookmark: &Bookmark,
+        old_cs: Option<ChangesetId>,
+        new_cs: ChangesetId,
+        ttl: Duration,
+    ) -> BoxFuture<BookmarkMoveToken, Error> {
+        let store = self.store.clone();
+        let owner = owner.to_string();
+        let repoid = *repoid;
+        let bookmark = bookmark.clone();
+
+        self.bookmarks
+            .get(&bookmark, &repoid)
+            .and_then(move |current| {
+                if current != old_cs {
+                    return Err(ErrorKind::BookmarkMismatch(bookmark, current, old_cs).into());
+                }
+
+                let now = unix_now();
+                let intent = BookmarkMoveIntent {
+                    token: BookmarkMoveToken::random()?,
+                    owner,
+                    repoid,
+                    bookmark,
+                    old_cs,
+                    new_cs,
+                    deadline: now + ttl.as_secs() as i64,
+                };
+                Ok((intent, now))
+            })
+            .and_then(move |(intent, now)| {
+                let token = intent.token.clone();
+                let bookmark = intent.bookmark.clone();
+                store.add(intent, now).and_then(move |added| {
+                    if added {
+                        Ok(token)
+                    } else {
+                        Err(ErrorKind::ConflictingBookmarkMove(bookmark, repoid).into())
+                    }
+                })
+            })
+            .boxify()
+    }
+
+    /// Applies the move that `owner` prepared under `token` and forgets the intent. Like
+    /// `Transaction::commit`, resolves to `false` if the bookmark no longer points to the value
+    /// it had when the move was prepared.
+    pub fn commit(&self, owner: &str, token: &BookmarkMoveToken) -> BoxFuture<bool, Error> {
+        let bookmarks = self.bookmarks.clone();
+        let token = token.clone();
+
+        self.store
+            .take(&token, owner)
+            .and_then(move |intent| {
+                let intent = match intent {
+                    Some(intent) => intent,
+                    None => return Err(ErrorKind::UnknownBookmarkMove(token.to_string()).into()),
+                };
+                if !intent.is_live(unix_now()) {
+                    return Err(ErrorKind::ExpiredBookmarkMove(token.to_string()).into());
+                }
+
+                let mut txn = bookmarks.create_transaction(&intent.repoid);
+                match intent.old_cs {
+                    Some(ref old_cs) => txn.update(&intent.bookmark, &intent.new_cs, old_cs)?,
+                    None => txn.create(&intent.bookmark, &intent.new_cs)?,
+                }
+                Ok(txn)
+            })
+            .and_then(|txn| txn.commit())
+            .boxify()
+    }
+
+    /// Forgets the move that `owner` prepared under `token` without applying it.
+    pub fn abort(&self, owner: &str, token: &BookmarkMoveToken) -> BoxFuture<(), Error> {
+        let token = token.clone();
+
+        self.store
+            .take(&token, owner)
+            .and_then(move |intent| match intent {
+                Some(_) => Ok(()),
+                None => Err(ErrorKind::UnknownBookmarkMove(token.to_string()).into()),
+            })
+            .boxify()
+    }
+}
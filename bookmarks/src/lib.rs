@@ -7,21 +7,50 @@
 #![deny(warnings)]
 
 extern crate ascii;
+extern crate asyncmemo;
 #[macro_use]
 extern crate failure_ext as failure;
+extern crate futures;
 extern crate futures_ext;
 extern crate mercurial_types;
 extern crate mononoke_types;
+extern crate rand;
 
 use std::fmt;
+use std::mem;
+use std::str::FromStr;
 
-use ascii::AsciiString;
+use ascii::{AsciiStr, AsciiString};
+use asyncmemo::Weight;
 use failure::{Error, Result};
 use futures_ext::{BoxFuture, BoxStream};
 use mercurial_types::RepositoryId;
 use mononoke_types::ChangesetId;
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+mod errors;
+mod intents;
+mod snapshots;
+
+pub use errors::ErrorKind;
+pub use intents::{BookmarkIntentStore, BookmarkIntents, BookmarkMoveIntent, BookmarkMoveToken};
+pub use snapshots::BookmarkSnapshotIndex;
+
+/// Checks that `name` only has characters that are allowed in the name of a bookmark: printable
+/// ASCII characters and spaces. Control characters are rejected, as tabs and newlines separate
+/// bookmarks from their changesets on the wire. Anything else, e.g. '/' or '%', is sent as is.
+fn check_name(name: &AsciiStr) -> Result<()> {
+    match name.as_bytes().iter().find(|b| **b < b' ' || **b == 0x7f) {
+        Some(b) => bail_msg!(
+            "bookmark name {:?} has an invalid character {:?}",
+            name.as_str(),
+            *b as char
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Name of a bookmark: a non-empty string of the characters that `check_name` allows
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Bookmark {
     bookmark: AsciiString,
 }
@@ -32,16 +61,26 @@ impl fmt::Display for Bookmark {
     }
 }
 
+/// The bookmark and the buffer of its name
+impl Weight for Bookmark {
+    fn get_weight(&self) -> usize {
+        mem::size_of::<Self>() + self.bookmark.capacity()
+    }
+}
+
 impl Bookmark {
     pub fn new<B: AsRef<str>>(bookmark: B) -> Result<Self> {
-        Ok(Self {
-            bookmark: AsciiString::from_ascii(bookmark.as_ref())
-                .map_err(|bytes| format_err!("non-ascii bookmark name: {:?}", bytes))?,
-        })
+        let bookmark = AsciiString::from_ascii(bookmark.as_ref())
+            .map_err(|bytes| format_err!("non-ascii bookmark name: {:?}", bytes))?;
+        Self::new_ascii(bookmark)
     }
 
-    pub fn new_ascii(bookmark: AsciiString) -> Self {
-        Self { bookmark }
+    pub fn new_ascii(bookmark: AsciiString) -> Result<Self> {
+        if bookmark.is_empty() {
+            bail_msg!("bookmark name is empty");
+        }
+        check_name(&bookmark)?;
+        Ok(Self { bookmark })
     }
 
     pub fn to_ascii(&self) -> Result<AsciiString> {
@@ -66,14 +105,15 @@ impl fmt::Display for BookmarkPrefix {
 
 impl BookmarkPrefix {
     pub fn new<B: AsRef<str>>(bookmark_prefix: B) -> Result<Self> {
-        Ok(Self {
-            bookmark_prefix: AsciiString::from_ascii(bookmark_prefix.as_ref())
-                .map_err(|bytes| format_err!("non-ascii bookmark prefix: {:?}", bytes))?,
-        })
+        let bookmark_prefix = AsciiString::from_ascii(bookmark_prefix.as_ref())
+            .map_err(|bytes| format_err!("non-ascii bookmark prefix: {:?}", bytes))?;
+        Self::new_ascii(bookmark_prefix)
     }
 
-    pub fn new_ascii(bookmark_prefix: AsciiString) -> Self {
-        Self { bookmark_prefix }
+    /// A prefix may be empty, to match all bookmarks
+    pub fn new_ascii(bookmark_prefix: AsciiString) -> Result<Self> {
+        check_name(&bookmark_prefix)?;
+        Ok(Self { bookmark_prefix })
     }
 
     pub fn empty() -> Self {
@@ -89,6 +129,63 @@ impl BookmarkPrefix {
     pub fn to_string(&self) -> String {
         self.bookmark_prefix.clone().into()
     }
+
+    /// Returns true if the name of `bookmark` starts with this prefix
+    pub fn is_prefix_of(&self, bookmark: &Bookmark) -> bool {
+        bookmark
+            .bookmark
+            .as_str()
+            .starts_with(self.bookmark_prefix.as_str())
+    }
+}
+
+/// Why bookmarks were updated, as recorded in the update log
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BookmarkUpdateReason {
+    /// The transaction didn't give a reason
+    Unknown,
+    /// Deleted by the retention of a scratch namespace
+    Retention,
+}
+
+impl BookmarkUpdateReason {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BookmarkUpdateReason::Unknown => "unknown",
+            BookmarkUpdateReason::Retention => "retention",
+        }
+    }
+}
+
+impl fmt::Display for BookmarkUpdateReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for BookmarkUpdateReason {
+    type Err = Error;
+
+    fn from_str(reason: &str) -> Result<Self> {
+        match reason {
+            "unknown" => Ok(BookmarkUpdateReason::Unknown),
+            "retention" => Ok(BookmarkUpdateReason::Retention),
+            _ => bail_msg!("unknown bookmark update reason {:?}", reason),
+        }
+    }
+}
+
+/// An update of a bookmark, as recorded in the update log
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BookmarkUpdateLogEntry {
+    pub bookmark: Bookmark,
+    /// None if the bookmark was created, or if it was force set or force deleted
+    pub from_changeset_id: Option<ChangesetId>,
+    /// None if the bookmark was deleted
+    pub to_changeset_id: Option<ChangesetId>,
+    pub reason: BookmarkUpdateReason,
+    /// Unix timestamp, in seconds
+    pub timestamp: i64,
 }
 
 pub trait Bookmarks: Send + Sync + 'static {
@@ -105,8 +202,31 @@ pub trait Bookmarks: Send + Sync + 'static {
         repoid: &RepositoryId,
     ) -> BoxStream<(Bookmark, ChangesetId), Error>;
 
+    /// Like `list_by_prefix`, with the time of the last update of each bookmark, as a unix
+    /// timestamp in seconds. It is None for the bookmarks that weren't updated since update times
+    /// are recorded.
+    fn list_by_prefix_with_timestamps(
+        &self,
+        prefix: &BookmarkPrefix,
+        repoid: &RepositoryId,
+    ) -> BoxStream<(Bookmark, ChangesetId, Option<i64>), Error>;
+
+    /// The updates of a bookmark, oldest first
+    fn read_update_log(
+        &self,
+        name: &Bookmark,
+        repoid: &RepositoryId,
+    ) -> BoxStream<BookmarkUpdateLogEntry, Error>;
+
     /// Creates a transaction that will be used for write operations.
     fn create_transaction(&self, repoid: &RepositoryId) -> Box<Transaction>;
+
+    /// Whether a failed transaction is guaranteed to leave all the bookmarks as they were. Callers
+    /// that need several bookmarks to move together, like pushes, write to a store that can't
+    /// guarantee it one bookmark at a time, and move them back themselves.
+    fn atomic_transactions(&self) -> bool {
+        true
+    }
 }
 
 pub trait Transaction: Send + Sync + 'static {
@@ -133,8 +253,63 @@ pub trait Transaction: Send + Sync + 'static {
     /// Deletes bookmark unconditionally.
     fn force_delete(&mut self, key: &Bookmark) -> Result<()>;
 
+    /// Sets the reason that the update log records for all the operations of the transaction,
+    /// `BookmarkUpdateReason::Unknown` if it is never set.
+    fn set_reason(&mut self, reason: BookmarkUpdateReason);
+
     /// Commits the transaction. Future succeeds if transaction has been
     /// successful, or errors if transaction has failed. Logical failure is indicated by
     /// returning a successful `false` value; infrastructure failure is reported via an Error.
+    /// Either way, none of the operations of a failed transaction is applied.
     fn commit(&self) -> BoxFuture<bool, Error>;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bookmark_names() {
+        for name in &["master", "releases/v1.2", "100%", "with space", "a:b,c;d=e", "x\\y"] {
+            let bookmark = Bookmark::new(name).expect(name);
+            assert_eq!(bookmark.to_string(), *name);
+            assert!(BookmarkPrefix::new(name).is_ok(), "{}", name);
+        }
+        for name in &["", "tab\tname", "new\nline", "nul\0", "del\x7f", "caf\u{e9}"] {
+            assert!(Bookmark::new(name).is_err(), "{:?}", name);
+        }
+        assert!(BookmarkPrefix::new("").is_ok());
+        assert!(BookmarkPrefix::new("releases/\n").is_err());
+    }
+
+    #[test]
+    fn test_bookmark_weights() {
+        use asyncmemo::weight_matches_estimate;
+        use std::collections::HashMap;
+
+        let bookmark = Bookmark::new("releases/v1.2").unwrap();
+        let estimate = mem::size_of::<Bookmark>() + bookmark.to_string().len();
+        assert!(weight_matches_estimate(bookmark.get_weight(), estimate));
+
+        let mut bookmarks = HashMap::new();
+        let mut estimate = mem::size_of::<HashMap<Bookmark, ChangesetId>>();
+        for i in 0..100 {
+            let bookmark = Bookmark::new(format!("releases/v{}", i)).unwrap();
+            estimate += mem::size_of::<Bookmark>() + bookmark.to_string().len();
+            estimate += mem::size_of::<ChangesetId>();
+            bookmarks.insert(bookmark, ChangesetId::from_bytes(&[i as u8; 32]).unwrap());
+        }
+        assert!(weight_matches_estimate(bookmarks.get_weight(), estimate));
+    }
+
+    #[test]
+    fn test_update_reasons() {
+        for reason in &[BookmarkUpdateReason::Unknown, BookmarkUpdateReason::Retention] {
+            assert_eq!(
+                BookmarkUpdateReason::from_str(reason.as_str()).unwrap(),
+                *reason
+            );
+        }
+        assert!(BookmarkUpdateReason::from_str("cleanup").is_err());
+    }
+}
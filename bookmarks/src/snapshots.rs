@@ -0,0 +1,27 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use failure::Error;
+use futures_ext::BoxFuture;
+use mercurial_types::RepositoryId;
+
+/// Index of the snapshots of the bookmarks of repos. The snapshots themselves are blobs, which
+/// can't be listed or deleted, so the index is what says which ones exist and are retained. It is
+/// kept in SQL next to the bookmarks, so that every server of a repo can add to it at once.
+pub trait BookmarkSnapshotIndex: Send + Sync + 'static {
+    /// Records the snapshot stored at `key`, taken at unix time `created_at`, and forgets all but
+    /// the `retain` newest snapshots of the repo
+    fn add(
+        &self,
+        repoid: &RepositoryId,
+        key: &str,
+        created_at: i64,
+        retain: usize,
+    ) -> BoxFuture<(), Error>;
+
+    /// Keys of the snapshots of the repo that are retained, oldest first
+    fn list(&self, repoid: &RepositoryId) -> BoxFuture<Vec<String>, Error>;
+}
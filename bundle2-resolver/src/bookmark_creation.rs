@@ -0,0 +1,129 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use bookmarks::Bookmark;
+use metaconfig::{BookmarkCreationPolicy, PullBookmarksFilter};
+
+use errors::*;
+
+/// Checks that `user` can create `bookmark` by pushing, with pushrebase if `pushrebase` is set.
+/// The error says which part of `policy` forbids it.
+pub fn check_bookmark_creation(
+    policy: &BookmarkCreationPolicy,
+    bookmark: &Bookmark,
+    user: Option<&str>,
+    pushrebase: bool,
+) -> Result<()> {
+    let forbidden = |reason: String| -> Result<()> {
+        Err(ErrorKind::BookmarkCreationForbidden(bookmark.clone(), reason).into())
+    };
+
+    if let Some(ref allowed_names) = policy.allowed_names {
+        if !allowed_names.matches(bookmark) {
+            return forbidden(format!(
+                "only bookmarks matching {} can be created",
+                describe_names(allowed_names)
+            ));
+        }
+    }
+    if let Some(ref allowed_creators) = policy.allowed_creators {
+        let allowed = match user {
+            Some(user) => allowed_creators.iter().any(|creator| creator == user),
+            None => false,
+        };
+        if !allowed {
+            return forbidden(format!(
+                "only {} can create bookmarks, and you are {}",
+                allowed_creators.join(", "),
+                user.unwrap_or("unknown")
+            ));
+        }
+    }
+    if policy.require_pushrebase && !pushrebase {
+        return forbidden("bookmarks can only be created by pushrebase".to_string());
+    }
+    Ok(())
+}
+
+/// Lists the names and prefixes that `filter` selects, like `master, release/*`
+fn describe_names(filter: &PullBookmarksFilter) -> String {
+    let names = filter.names.iter().map(|name| name.to_string());
+    let prefixes = filter.prefixes.iter().map(|prefix| format!("{}*", prefix));
+    let described: Vec<_> = names.chain(prefixes).collect();
+    if described.is_empty() {
+        "nothing".to_string()
+    } else {
+        described.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bookmarks::BookmarkPrefix;
+
+    fn bookmark(name: &str) -> Bookmark {
+        Bookmark::new(name).unwrap()
+    }
+
+    fn check(policy: &BookmarkCreationPolicy, name: &str, user: Option<&str>) -> Result<()> {
+        check_bookmark_creation(policy, &bookmark(name), user, false)
+    }
+
+    #[test]
+    fn test_default_policy_allows_everything() {
+        let policy = BookmarkCreationPolicy::default();
+        assert!(check(&policy, "master", None).is_ok());
+        assert!(check(&policy, "scratch/alice/feature", Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn test_allowed_names() {
+        let policy = BookmarkCreationPolicy {
+            allowed_names: Some(PullBookmarksFilter {
+                names: vec![bookmark("stable")],
+                prefixes: vec![BookmarkPrefix::new("release/").unwrap()],
+            }),
+            ..Default::default()
+        };
+        assert!(check(&policy, "stable", None).is_ok());
+        assert!(check(&policy, "release/1.0", None).is_ok());
+
+        let err = check(&policy, "feature", None).unwrap_err().to_string();
+        assert!(err.contains("feature"), "{}", err);
+        assert!(err.contains("stable, release/*"), "{}", err);
+    }
+
+    #[test]
+    fn test_allowed_creators() {
+        let policy = BookmarkCreationPolicy {
+            allowed_creators: Some(vec!["alice".to_string(), "svcscm".to_string()]),
+            ..Default::default()
+        };
+        assert!(check(&policy, "master", Some("svcscm")).is_ok());
+        assert!(check(&policy, "master", Some("alice")).is_ok());
+
+        let err = check(&policy, "master", Some("bob")).unwrap_err().to_string();
+        assert!(err.contains("only alice, svcscm can create"), "{}", err);
+        // Users who didn't say who they are can't create bookmarks
+        assert!(check(&policy, "master", None).is_err());
+    }
+
+    #[test]
+    fn test_require_pushrebase() {
+        let policy = BookmarkCreationPolicy {
+            require_pushrebase: true,
+            ..Default::default()
+        };
+        let master = bookmark("master");
+        assert!(check_bookmark_creation(&policy, &master, None, true).is_ok());
+        let err = check_bookmark_creation(&policy, &master, None, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("only be created by pushrebase"), "{}", err);
+    }
+}
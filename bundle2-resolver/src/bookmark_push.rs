@@ -0,0 +1,769 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Moving the bookmarks of a push. The bookmark parts of a bundle are collected while it is
+//! resolved, and applied together once the rest of the push succeeded, so that a push moves all
+//! of its bookmarks or none of them.
+//!
+//! If the bookmarks store applies transactions atomically, all the bookmarks are moved in one
+//! transaction, which is retried if it failed without moving any of them. Otherwise their
+//! expected values are checked first, then they are moved one at a time, and the ones already
+//! moved are moved back if one of them fails. Only if moving them back fails too is the push left
+//! half done, and the client then gets the outcome of each bookmark instead of an error.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures_ext::{retry, BoxFuture, FutureExt, RetryPolicy};
+
+use bookmarks::{Bookmark, Bookmarks, Transaction};
+use mercurial_types::RepositoryId;
+use mononoke_types::ChangesetId;
+use stats::*;
+
+use errors::*;
+
+type PartId = u32;
+
+/// How often a transaction that failed without moving any bookmark is attempted
+pub fn bookmark_commit_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        jitter: true,
+    }
+}
+
+/// A bookmark part of a push, with the bonsai changesets it moves the bookmark from and to
+pub struct BonsaiBookmarkPush {
+    pub part_id: PartId,
+    pub name: Bookmark,
+    pub old: Option<ChangesetId>,
+    pub new: Option<ChangesetId>,
+}
+
+impl BonsaiBookmarkPush {
+    fn add_to_transaction(&self, txn: &mut Box<Transaction>) -> Result<()> {
+        match (self.new, self.old) {
+            (Some(new), Some(old)) => txn.update(&self.name, &new, &old),
+            (Some(new), None) => txn.create(&self.name, &new),
+            (None, Some(old)) => txn.delete(&self.name, &old),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds the operation that moves the bookmark back to where the push found it
+    fn add_undo_to_transaction(&self, txn: &mut Box<Transaction>) -> Result<()> {
+        match (self.old, self.new) {
+            (Some(old), Some(new)) => txn.update(&self.name, &old, &new),
+            (Some(old), None) => txn.create(&self.name, &old),
+            (None, Some(new)) => txn.delete(&self.name, &new),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What happened to a bookmark of a push
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BookmarkOutcome {
+    Moved,
+    Failed(String),
+    /// Another bookmark failed before this one was tried
+    NotAttempted,
+}
+
+impl BookmarkOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BookmarkOutcome::Moved => "moved",
+            BookmarkOutcome::Failed(_) => "failed",
+            BookmarkOutcome::NotAttempted => "not-attempted",
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        match *self {
+            BookmarkOutcome::Failed(ref reason) => reason,
+            _ => "",
+        }
+    }
+}
+
+/// The outcome of each bookmark of a push, in the order of their parts
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BookmarkPushReport {
+    pub outcomes: Vec<(PartId, Bookmark, BookmarkOutcome)>,
+}
+
+impl BookmarkPushReport {
+    fn all_moved(pushes: &[BonsaiBookmarkPush]) -> Self {
+        BookmarkPushReport {
+            outcomes: pushes
+                .iter()
+                .map(|bp| (bp.part_id, bp.name.clone(), BookmarkOutcome::Moved))
+                .collect(),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.outcomes
+            .iter()
+            .all(|(_, _, outcome)| *outcome == BookmarkOutcome::Moved)
+    }
+
+    /// The report as the client prints it
+    pub fn render(&self) -> String {
+        let mut message = String::from("the push moved only some of its bookmarks:\n");
+        for (_, name, outcome) in &self.outcomes {
+            let line = match *outcome {
+                BookmarkOutcome::Moved => format!("  {}: moved\n", name),
+                BookmarkOutcome::Failed(ref reason) => format!("  {}: failed, {}\n", name, reason),
+                BookmarkOutcome::NotAttempted => format!("  {}: not moved\n", name),
+            };
+            message.push_str(&line);
+        }
+        message
+    }
+}
+
+/// Moves the bookmarks of `pushes` in `bookmarks`. If none of them could be moved, the push fails
+/// with `ErrorKind::BookmarksNotMoved`. A report is returned otherwise, which says that some of
+/// them weren't moved if that could not be undone.
+pub fn move_bookmarks(
+    bookmarks: Arc<Bookmarks>,
+    repoid: RepositoryId,
+    pushes: Vec<BonsaiBookmarkPush>,
+    retries: RetryPolicy,
+) -> BoxFuture<BookmarkPushReport, Error> {
+    if pushes.is_empty() {
+        return future::ok(BookmarkPushReport { outcomes: vec![] }).boxify();
+    }
+    let pushes = Arc::new(pushes);
+    if bookmarks.atomic_transactions() {
+        move_atomically(bookmarks, repoid, pushes, retries)
+    } else {
+        move_one_by_one(bookmarks, repoid, pushes)
+    }
+}
+
+/// Failure of an attempt to commit a transaction
+enum CommitError {
+    /// The bookmarks are known not to have moved, so the commit can be tried again
+    Unapplied(Error),
+    Failed(Error),
+}
+
+fn move_atomically(
+    bookmarks: Arc<Bookmarks>,
+    repoid: RepositoryId,
+    pushes: Arc<Vec<BonsaiBookmarkPush>>,
+    retries: RetryPolicy,
+) -> BoxFuture<BookmarkPushReport, Error> {
+    let mut attempt = 0;
+    retry(
+        retries,
+        |err: &CommitError| match *err {
+            CommitError::Unapplied(_) => true,
+            CommitError::Failed(_) => false,
+        },
+        {
+            cloned!(pushes);
+            move || {
+                attempt += 1;
+                if attempt > 1 {
+                    STATS::bookmark_commit_retries.add_value(1);
+                }
+                commit_atomically(bookmarks.clone(), repoid, pushes.clone())
+            }
+        },
+    ).map(move |()| BookmarkPushReport::all_moved(&pushes))
+        .map_err(|err| match err {
+            CommitError::Unapplied(err) | CommitError::Failed(err) => err,
+        })
+        .boxify()
+}
+
+fn commit_atomically(
+    bookmarks: Arc<Bookmarks>,
+    repoid: RepositoryId,
+    pushes: Arc<Vec<BonsaiBookmarkPush>>,
+) -> BoxFuture<(), CommitError> {
+    let mut txn = bookmarks.create_transaction(&repoid);
+    for bp in pushes.iter() {
+        if let Err(err) = bp.add_to_transaction(&mut txn) {
+            return future::err(CommitError::Failed(err)).boxify();
+        }
+    }
+
+    txn.commit()
+        .then(move |res| match res {
+            Ok(true) => future::ok(()).boxify(),
+            // A conflict left every bookmark as it was, and retrying won't resolve it
+            Ok(false) => describe_conflicts(&bookmarks, repoid, &pushes)
+                .then(|conflicts| {
+                    let conflicts = conflicts.map_err(CommitError::Failed)?;
+                    Err(CommitError::Failed(
+                        ErrorKind::BookmarksNotMoved(conflicts.join(", ")).into(),
+                    ))
+                })
+                .boxify(),
+            // The commit may or may not have happened, which only the bookmarks can tell
+            Err(err) => current_values(&bookmarks, repoid, &pushes)
+                .then(move |current| {
+                    let current = match current {
+                        Ok(current) => current,
+                        Err(_) => return Err(CommitError::Failed(err)),
+                    };
+                    if pushes.iter().zip(&current).all(|(bp, cur)| *cur == bp.new) {
+                        Ok(())
+                    } else if pushes.iter().zip(&current).all(|(bp, cur)| *cur == bp.old) {
+                        Err(CommitError::Unapplied(err))
+                    } else {
+                        Err(CommitError::Failed(err))
+                    }
+                })
+                .boxify(),
+        })
+        .boxify()
+}
+
+fn move_one_by_one(
+    bookmarks: Arc<Bookmarks>,
+    repoid: RepositoryId,
+    pushes: Arc<Vec<BonsaiBookmarkPush>>,
+) -> BoxFuture<BookmarkPushReport, Error> {
+    // A bookmark that doesn't point where the push expects is caught before any of them moves
+    describe_conflicts(&bookmarks, repoid, &pushes)
+        .and_then(|conflicts| {
+            if conflicts.is_empty() {
+                Ok(())
+            } else {
+                Err(ErrorKind::BookmarksNotMoved(conflicts.join(", ")).into())
+            }
+        })
+        .and_then({
+            cloned!(bookmarks, pushes);
+            move |()| {
+                // Moves the bookmarks in order, until one of them fails
+                stream::iter_ok::<_, Error>(0..pushes.len()).fold(
+                    (0, None),
+                    move |(moved, failure), index| {
+                        if failure.is_some() {
+                            return future::ok((moved, failure)).left_future();
+                        }
+                        let mut txn = bookmarks.create_transaction(&repoid);
+                        if let Err(err) = pushes[index].add_to_transaction(&mut txn) {
+                            return future::ok((moved, Some(describe_error(&err)))).left_future();
+                        }
+                        txn.commit()
+                            .then(move |res| {
+                                Ok::<_, Error>(match res {
+                                    Ok(true) => (moved + 1, None),
+                                    Ok(false) => (
+                                        moved,
+                                        Some("it was moved by another push".to_string()),
+                                    ),
+                                    Err(err) => (moved, Some(describe_error(&err))),
+                                })
+                            })
+                            .right_future()
+                    },
+                )
+            }
+        })
+        .and_then(move |(moved, failure)| match failure {
+            None => future::ok(BookmarkPushReport::all_moved(&pushes)).left_future(),
+            Some(reason) => undo(bookmarks, repoid, pushes, moved, reason).right_future(),
+        })
+        .boxify()
+}
+
+/// Moves back the first `moved` bookmarks of `pushes`, after the next one failed because of
+/// `reason`
+fn undo(
+    bookmarks: Arc<Bookmarks>,
+    repoid: RepositoryId,
+    pushes: Arc<Vec<BonsaiBookmarkPush>>,
+    moved: usize,
+    reason: String,
+) -> BoxFuture<BookmarkPushReport, Error> {
+    let undos: Vec<_> = (0..moved)
+        .rev()
+        .map(|index| {
+            let mut txn = bookmarks.create_transaction(&repoid);
+            let added = pushes[index].add_undo_to_transaction(&mut txn);
+            added
+                .into_future()
+                .and_then(move |()| txn.commit())
+                .then(move |res| Ok::<_, Error>((index, res.unwrap_or(false))))
+        })
+        .collect();
+
+    future::join_all(undos)
+        .and_then(move |undone| {
+            let failed = &pushes[moved].name;
+            if undone.iter().all(|(_, undone)| *undone) {
+                STATS::bookmark_moves_undone.add_value(moved as i64);
+                return Err(ErrorKind::BookmarksNotMoved(format!("{}: {}", failed, reason)).into());
+            }
+
+            STATS::bookmark_pushes_incomplete.add_value(1);
+            let mut outcomes: Vec<_> = pushes
+                .iter()
+                .enumerate()
+                .map(|(index, bp)| {
+                    let outcome = if index == moved {
+                        BookmarkOutcome::Failed(reason.clone())
+                    } else {
+                        BookmarkOutcome::NotAttempted
+                    };
+                    (bp.part_id, bp.name.clone(), outcome)
+                })
+                .collect();
+            for (index, undone) in undone {
+                outcomes[index].2 = if undone {
+                    BookmarkOutcome::Failed(format!("moved back after {} failed", failed))
+                } else {
+                    BookmarkOutcome::Moved
+                };
+            }
+            Ok(BookmarkPushReport { outcomes })
+        })
+        .boxify()
+}
+
+/// The reason of a failed outcome, which is sent on a single line
+fn describe_error(err: &Error) -> String {
+    format!("{}", err).replace(|c| c == '\n' || c == '\t', " ")
+}
+
+/// The values that the bookmarks of `pushes` have now
+fn current_values(
+    bookmarks: &Arc<Bookmarks>,
+    repoid: RepositoryId,
+    pushes: &[BonsaiBookmarkPush],
+) -> impl Future<Item = Vec<Option<ChangesetId>>, Error = Error> {
+    future::join_all(
+        pushes
+            .iter()
+            .map(|bp| bookmarks.get(&bp.name, &repoid))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Describes the bookmarks of `pushes` that don't have the value the push expects
+fn describe_conflicts(
+    bookmarks: &Arc<Bookmarks>,
+    repoid: RepositoryId,
+    pushes: &Arc<Vec<BonsaiBookmarkPush>>,
+) -> impl Future<Item = Vec<String>, Error = Error> {
+    cloned!(pushes);
+    current_values(bookmarks, repoid, &pushes).map(move |current| {
+        pushes
+            .iter()
+            .zip(current)
+            .filter_map(|(bp, current)| {
+                let conflict = match (bp.old, current) {
+                    (None, Some(_)) => "already exists",
+                    (Some(_), None) => "does not exist",
+                    (Some(old), Some(current)) if old != current => "was moved by another push",
+                    _ => return None,
+                };
+                Some(format!("{} {}", bp.name, conflict))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    use async_unit;
+    use dbbookmarks::SqliteDbBookmarks;
+    use failure::err_msg;
+    use futures_ext::BoxStream;
+
+    use bookmarks::{BookmarkPrefix, BookmarkUpdateLogEntry, BookmarkUpdateReason};
+    use mononoke_types_mocks::changesetid::*;
+
+    const REPO: RepositoryId = RepositoryId::new(0);
+
+    fn bookmark(name: &str) -> Bookmark {
+        Bookmark::new(name).unwrap()
+    }
+
+    fn push(
+        part_id: PartId,
+        name: &str,
+        old: Option<ChangesetId>,
+        new: Option<ChangesetId>,
+    ) -> BonsaiBookmarkPush {
+        BonsaiBookmarkPush {
+            part_id,
+            name: bookmark(name),
+            old,
+            new,
+        }
+    }
+
+    fn no_delay() -> RetryPolicy {
+        RetryPolicy {
+            jitter: false,
+            base_delay: Duration::from_millis(0),
+            ..bookmark_commit_retry_policy()
+        }
+    }
+
+    /// A store that doesn't apply transactions atomically, and whose commits can be made to fail.
+    /// The commits are numbered from 1 in the order they are made.
+    struct TestBookmarks {
+        inner: SqliteDbBookmarks,
+        atomic: bool,
+        commits: Arc<Mutex<usize>>,
+        /// Commits that report a conflict without applying anything
+        conflicting: HashSet<usize>,
+        /// Commits that fail with an error, after applying the transaction if the flag is set
+        failing: Vec<(usize, bool)>,
+    }
+
+    impl TestBookmarks {
+        fn new(atomic: bool) -> Self {
+            TestBookmarks {
+                inner: SqliteDbBookmarks::in_memory().unwrap(),
+                atomic,
+                commits: Arc::new(Mutex::new(0)),
+                conflicting: HashSet::new(),
+                failing: vec![],
+            }
+        }
+
+        fn set(&self, name: &str, value: &ChangesetId) {
+            let mut txn = self.inner.create_transaction(&REPO);
+            txn.force_set(&bookmark(name), value).unwrap();
+            assert!(txn.commit().wait().unwrap());
+        }
+
+        fn commits(&self) -> usize {
+            *self.commits.lock().unwrap()
+        }
+    }
+
+    impl Bookmarks for TestBookmarks {
+        fn get(
+            &self,
+            name: &Bookmark,
+            repoid: &RepositoryId,
+        ) -> BoxFuture<Option<ChangesetId>, Error> {
+            self.inner.get(name, repoid)
+        }
+
+        fn list_by_prefix(
+            &self,
+            prefix: &BookmarkPrefix,
+            repoid: &RepositoryId,
+        ) -> BoxStream<(Bookmark, ChangesetId), Error> {
+            self.inner.list_by_prefix(prefix, repoid)
+        }
+
+        fn list_by_prefix_with_timestamps(
+            &self,
+            prefix: &BookmarkPrefix,
+            repoid: &RepositoryId,
+        ) -> BoxStream<(Bookmark, ChangesetId, Option<i64>), Error> {
+            self.inner.list_by_prefix_with_timestamps(prefix, repoid)
+        }
+
+        fn read_update_log(
+            &self,
+            name: &Bookmark,
+            repoid: &RepositoryId,
+        ) -> BoxStream<BookmarkUpdateLogEntry, Error> {
+            self.inner.read_update_log(name, repoid)
+        }
+
+        fn create_transaction(&self, repoid: &RepositoryId) -> Box<Transaction> {
+            Box::new(TestTransaction {
+                inner: self.inner.create_transaction(repoid),
+                commits: self.commits.clone(),
+                conflicting: self.conflicting.clone(),
+                failing: self.failing.clone(),
+            })
+        }
+
+        fn atomic_transactions(&self) -> bool {
+            self.atomic
+        }
+    }
+
+    struct TestTransaction {
+        inner: Box<Transaction>,
+        commits: Arc<Mutex<usize>>,
+        conflicting: HashSet<usize>,
+        failing: Vec<(usize, bool)>,
+    }
+
+    impl Transaction for TestTransaction {
+        fn update(&mut self, key: &Bookmark, new: &ChangesetId, old: &ChangesetId) -> Result<()> {
+            self.inner.update(key, new, old)
+        }
+
+        fn create(&mut self, key: &Bookmark, new: &ChangesetId) -> Result<()> {
+            self.inner.create(key, new)
+        }
+
+        fn force_set(&mut self, key: &Bookmark, new: &ChangesetId) -> Result<()> {
+            self.inner.force_set(key, new)
+        }
+
+        fn delete(&mut self, key: &Bookmark, old: &ChangesetId) -> Result<()> {
+            self.inner.delete(key, old)
+        }
+
+        fn force_delete(&mut self, key: &Bookmark) -> Result<()> {
+            self.inner.force_delete(key)
+        }
+
+        fn set_reason(&mut self, reason: BookmarkUpdateReason) {
+            self.inner.set_reason(reason)
+        }
+
+        fn commit(&self) -> BoxFuture<bool, Error> {
+            let commit = {
+                let mut commits = self.commits.lock().unwrap();
+                *commits += 1;
+                *commits
+            };
+            if self.conflicting.contains(&commit) {
+                return future::ok(false).boxify();
+            }
+            match self.failing.iter().find(|(failing, _)| *failing == commit) {
+                Some(&(_, true)) => self.inner
+                    .commit()
+                    .and_then(|_| Err(err_msg("lost connection after commit")))
+                    .boxify(),
+                Some(&(_, false)) => future::err(err_msg("lost connection")).boxify(),
+                None => self.inner.commit(),
+            }
+        }
+    }
+
+    fn values(bookmarks: &TestBookmarks, names: &[&str]) -> Vec<Option<ChangesetId>> {
+        names
+            .iter()
+            .map(|name| bookmarks.get(&bookmark(name), &REPO).wait().unwrap())
+            .collect()
+    }
+
+    fn run(
+        bookmarks: TestBookmarks,
+        pushes: Vec<BonsaiBookmarkPush>,
+    ) -> (Arc<TestBookmarks>, Result<BookmarkPushReport>) {
+        let bookmarks = Arc::new(bookmarks);
+        let result = move_bookmarks(bookmarks.clone(), REPO, pushes, no_delay()).wait();
+        (bookmarks, result)
+    }
+
+    fn two_bookmarks(atomic: bool) -> TestBookmarks {
+        let bookmarks = TestBookmarks::new(atomic);
+        bookmarks.set("master", &ONES_CSID);
+        bookmarks.set("stable", &TWOS_CSID);
+        bookmarks
+    }
+
+    fn move_both() -> Vec<BonsaiBookmarkPush> {
+        vec![
+            push(1, "master", Some(ONES_CSID), Some(THREES_CSID)),
+            push(2, "stable", Some(TWOS_CSID), Some(FOURS_CSID)),
+            push(3, "new", None, Some(FIVES_CSID)),
+        ]
+    }
+
+    fn assert_not_moved(result: Result<BookmarkPushReport>, expected: &str) {
+        match result.map_err(|err| err.downcast::<ErrorKind>()) {
+            Err(Ok(ErrorKind::BookmarksNotMoved(ref conflicts))) if conflicts == expected => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_atomic_move() {
+        async_unit::tokio_unit_test(|| {
+            let (bookmarks, result) = run(two_bookmarks(true), move_both());
+            let report = result.expect("push failed");
+            assert!(report.is_complete());
+            assert_eq!(report.outcomes.len(), 3);
+            assert_eq!(
+                values(&bookmarks, &["master", "stable", "new"]),
+                vec![Some(THREES_CSID), Some(FOURS_CSID), Some(FIVES_CSID)]
+            );
+            assert_eq!(bookmarks.commits(), 1);
+        })
+    }
+
+    #[test]
+    fn test_atomic_conflict_on_second_bookmark() {
+        async_unit::tokio_unit_test(|| {
+            let bookmarks = two_bookmarks(true);
+            // Another push moved stable since the client saw it
+            bookmarks.set("stable", &SIXES_CSID);
+            let (bookmarks, result) = run(bookmarks, move_both());
+
+            assert_not_moved(result, "stable was moved by another push");
+            // master was rolled back with the rest of the transaction
+            assert_eq!(
+                values(&bookmarks, &["master", "stable", "new"]),
+                vec![Some(ONES_CSID), Some(SIXES_CSID), None]
+            );
+            assert_eq!(bookmarks.commits(), 1);
+        })
+    }
+
+    #[test]
+    fn test_atomic_retry() {
+        async_unit::tokio_unit_test(|| {
+            // Nothing was applied, so the transaction is tried again
+            let mut bookmarks = two_bookmarks(true);
+            bookmarks.failing = vec![(1, false)];
+            let (bookmarks, result) = run(bookmarks, move_both());
+            assert!(result.expect("push failed").is_complete());
+            assert_eq!(bookmarks.commits(), 2);
+            assert_eq!(
+                values(&bookmarks, &["master", "stable"]),
+                vec![Some(THREES_CSID), Some(FOURS_CSID)]
+            );
+
+            // The transaction was applied, so it is not tried again
+            let mut bookmarks = two_bookmarks(true);
+            bookmarks.failing = vec![(1, true)];
+            let (bookmarks, result) = run(bookmarks, move_both());
+            assert!(result.expect("push failed").is_complete());
+            assert_eq!(bookmarks.commits(), 1);
+
+            // Every attempt fails
+            let mut bookmarks = two_bookmarks(true);
+            bookmarks.failing = vec![(1, false), (2, false), (3, false)];
+            let (bookmarks, result) = run(bookmarks, move_both());
+            assert!(result.is_err());
+            assert_eq!(bookmarks.commits(), 3);
+            assert_eq!(
+                values(&bookmarks, &["master", "stable"]),
+                vec![Some(ONES_CSID), Some(TWOS_CSID)]
+            );
+        })
+    }
+
+    #[test]
+    fn test_one_by_one_move() {
+        async_unit::tokio_unit_test(|| {
+            let (bookmarks, result) = run(two_bookmarks(false), move_both());
+            assert!(result.expect("push failed").is_complete());
+            assert_eq!(
+                values(&bookmarks, &["master", "stable", "new"]),
+                vec![Some(THREES_CSID), Some(FOURS_CSID), Some(FIVES_CSID)]
+            );
+            assert_eq!(bookmarks.commits(), 3);
+        })
+    }
+
+    #[test]
+    fn test_one_by_one_conflict_is_found_first() {
+        async_unit::tokio_unit_test(|| {
+            let bookmarks = two_bookmarks(false);
+            bookmarks.set("new", &SIXES_CSID);
+            let (bookmarks, result) = run(bookmarks, move_both());
+            assert_not_moved(result, "new already exists");
+            assert_eq!(bookmarks.commits(), 0);
+        })
+    }
+
+    #[test]
+    fn test_one_by_one_failure_moves_back() {
+        async_unit::tokio_unit_test(|| {
+            // stable is moved by another push between the check and the move
+            let mut bookmarks = two_bookmarks(false);
+            bookmarks.conflicting = hashset![2];
+            let (bookmarks, result) = run(bookmarks, move_both());
+
+            assert_not_moved(result, "stable: it was moved by another push");
+            assert_eq!(
+                values(&bookmarks, &["master", "stable", "new"]),
+                vec![Some(ONES_CSID), Some(TWOS_CSID), None]
+            );
+            // master, stable, and moving master back
+            assert_eq!(bookmarks.commits(), 3);
+        })
+    }
+
+    #[test]
+    fn test_one_by_one_report() {
+        async_unit::tokio_unit_test(|| {
+            // The third bookmark fails, and moving back the second one fails too
+            let mut bookmarks = two_bookmarks(false);
+            bookmarks.failing = vec![(3, false)];
+            bookmarks.conflicting = hashset![4];
+            let (bookmarks, result) = run(bookmarks, move_both());
+
+            let report = result.expect("push failed");
+            assert!(!report.is_complete());
+            assert_eq!(
+                report.outcomes,
+                vec![
+                    (
+                        1,
+                        bookmark("master"),
+                        BookmarkOutcome::Failed("moved back after new failed".to_string()),
+                    ),
+                    (2, bookmark("stable"), BookmarkOutcome::Moved),
+                    (
+                        3,
+                        bookmark("new"),
+                        BookmarkOutcome::Failed("lost connection".to_string()),
+                    ),
+                ]
+            );
+            assert_eq!(
+                report.render(),
+                "the push moved only some of its bookmarks:\n  \
+                 master: failed, moved back after new failed\n  \
+                 stable: moved\n  \
+                 new: failed, lost connection\n"
+            );
+            assert_eq!(
+                values(&bookmarks, &["master", "stable", "new"]),
+                vec![Some(ONES_CSID), Some(FOURS_CSID), None]
+            );
+        })
+    }
+
+    #[test]
+    fn test_not_attempted() {
+        async_unit::tokio_unit_test(|| {
+            let mut bookmarks = two_bookmarks(false);
+            bookmarks.conflicting = hashset![2, 3];
+            let (_, result) = run(bookmarks, move_both());
+
+            let report = result.expect("push failed");
+            assert_eq!(
+                report
+                    .outcomes
+                    .iter()
+                    .map(|(_, _, outcome)| outcome.as_str())
+                    .collect::<Vec<_>>(),
+                vec!["moved", "failed", "not-attempted"]
+            );
+            assert_eq!(
+                report.outcomes[1].2.reason(),
+                "it was moved by another push"
+            );
+        })
+    }
+}
@@ -24,4 +24,27 @@ pub enum ErrorKind {
     WhileUploadingData(Vec<HgNodeHash>),
     #[fail(display = "No common root found between: bookmark:{:?} roots:{:?}", _0, _1)]
     PushrebaseNoCommonRoot(Bookmark, HashSet<ChangesetId>),
+    #[fail(display = "Unknown or expired pull token: {}", _0)] UnknownPullToken(String),
+    #[fail(display = "Pull {} was started with different common or heads", _0)]
+    PullResumeMismatch(String),
+    #[fail(display = "Pull {} can't be resumed from changeset {}, {} were sent", _0, _1, _2)]
+    InvalidPullResumePoint(String, usize, usize),
+    #[fail(display = "Push is too large: it exceeds the limit of {} bytes", _0)]
+    PushTooLarge(u64),
+    #[fail(display = "Push is too large: bundle2 part {} exceeds the limit of {} bytes", _0, _1)]
+    PartTooLarge(u32, u64),
+    #[fail(display = "Push is too large: it exceeds the limit of {} changesets", _0)]
+    TooManyChangesets(usize),
+    #[fail(display = "Message of changeset {} is too large: {} bytes, limit is {}", _0, _1, _2)]
+    CommitMessageTooLarge(HgNodeHash, usize, usize),
+    #[fail(display = "Message of changeset {} contains a NUL byte", _0)]
+    CommitMessageContainsNul(ChangesetId),
+    #[fail(display = "Push contains {} invalid paths:\n{}", _0, _1)] InvalidPaths(usize, String),
+    #[fail(display = "Push grows directories past the limit of {} entries:\n{}", _0, _1)]
+    DirectoryFanoutTooLarge(usize, String),
+    #[fail(display = "Creating bookmark {} is not allowed: {}", _0, _1)]
+    BookmarkCreationForbidden(Bookmark, String),
+    #[fail(display = "Bookmarks of the push were not moved: {}", _0)] BookmarksNotMoved(String),
+    #[fail(display = "Invalid narrow pattern {}: {}", _0, _1)]
+    InvalidNarrowPattern(String, String),
 }
@@ -7,34 +7,430 @@
 use bytes::Bytes;
 use errors::*;
 use failure::err_msg;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::iter::FromIterator;
 use std::sync::Arc;
 
 use blobrepo::BlobRepo;
-use futures::{stream, Future, Stream};
+use bookmarks::Bookmark;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use mercurial::{self, RevlogChangeset};
 use mercurial_bundles::{parts, part_encode::PartEncodeBuilder};
-use mercurial_types::{Changeset, HgBlobNode, HgChangesetId, NULL_CSID};
+use mercurial_types::{Changeset, Entry, HgBlobNode, HgChangesetId, HgNodeHash, MPath, RepoPath,
+                      NULL_CSID};
+use mercurial_types::manifest::EmptyManifest;
+use mercurial_types::manifest_utils::{changed_entry_stream, EntryStatus};
 use revset::DifferenceOfUnionsOfAncestorsNodeStream;
+use slog::Logger;
 
 use mononoke_types::ChangesetId;
 
+use narrow_patterns::NarrowPatterns;
+use resumable_pull::{PullToken, ResumablePulls};
+
+/// Creates the changegroup part of a getbundle response. Changesets are sent from the oldest to
+/// the newest in whatever order their ancestry is walked, which can differ between calls for
+/// changesets of the same generation. If `deterministic` is set, they are sorted in
+/// `canonical_order` instead, so that the same request always gets the same bytes back, at the
+/// cost of the extra lookups of the generation numbers.
 pub fn create_getbundle_response(
     blobrepo: BlobRepo,
     common: Vec<HgChangesetId>,
     heads: Vec<HgChangesetId>,
+    deterministic: bool,
+) -> Result<PartEncodeBuilder> {
+    let blobrepo = Arc::new(blobrepo.clone());
+    let nodestosend = changesets_to_send(&blobrepo, common, heads)?;
+
+    let nodestosend = if deterministic {
+        canonical_order(&blobrepo, nodestosend)
+    } else {
+        // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
+        nodestosend
+            .collect()
+            .map(|nodes| stream::iter_ok(nodes.into_iter().rev()))
+            .flatten_stream()
+            .boxify()
+    };
+
+    parts::changegroup_part(changelog_entries(blobrepo, nodestosend))
+}
+
+/// Same as `create_getbundle_response`, but the pull can be resumed if it gets interrupted. If
+/// `resume` is set, the pull it refers to is continued from the given number of received
+/// changesets, otherwise a new resumable pull is started. The token and the number of skipped
+/// changesets are sent as advisory params of the changegroup part. Changesets are always sent in
+/// `canonical_order`, but every new pull gets a new token, so two responses are never the same.
+pub fn create_resumable_getbundle_response(
+    blobrepo: BlobRepo,
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+    pulls: ResumablePulls,
+    resume: Option<(PullToken, usize)>,
 ) -> Result<PartEncodeBuilder> {
+    let (token, resume_from, changelogentries) =
+        resumable_changelog_entries(blobrepo, common, heads, pulls, resume)?;
+
+    let mut builder = parts::changegroup_part(changelogentries)?;
+    builder.add_aparam("pulltoken", token.to_string())?;
+    builder.add_aparam("resumefrom", resume_from.to_string())?;
+    Ok(builder)
+}
+
+fn resumable_changelog_entries(
+    blobrepo: BlobRepo,
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+    pulls: ResumablePulls,
+    resume: Option<(PullToken, usize)>,
+) -> Result<(
+    PullToken,
+    usize,
+    BoxStream<(HgNodeHash, HgBlobNode), Error>,
+)> {
+    let (token, resume_from) = match resume {
+        Some((token, received)) => {
+            let resume_from = pulls.resume(token, common.clone(), heads.clone(), received)?;
+            (token, resume_from)
+        }
+        None => (pulls.start(common.clone(), heads.clone()), 0),
+    };
+
+    let blobrepo = Arc::new(blobrepo.clone());
+    let nodestosend = changesets_to_send(&blobrepo, common, heads)?;
+
+    // Resuming relies on changesets being sent in the same order every time
+    let nodestosend = canonical_order(&blobrepo, nodestosend).skip(resume_from as u64);
+
+    let group_size = pulls.group_size();
+    let mut sent = resume_from;
+    let changelogentries = changelog_entries(blobrepo, nodestosend)
+        .inspect(move |_| {
+            sent += 1;
+            if sent % group_size == 0 {
+                pulls.record_progress(token, sent);
+            }
+        })
+        .boxify();
+
+    Ok((token, resume_from, changelogentries))
+}
+
+/// A bundle that has everything needed to apply the changesets it sends, unlike a getbundle
+/// response, which leaves trees and files for the client to fetch when it needs them
+pub struct FullBundle {
+    pub changesets: usize,
+    pub trees: usize,
+    pub files: usize,
+    /// Parts of the bundle, to be encoded with `create_bundle_stream`
+    pub parts: Vec<PartEncodeBuilder>,
+}
+
+/// Creates a bundle of the changesets that are ancestors of `heads` but not of `common`, and of
+/// the trees and file revisions they introduce, that a client without remotefilelog can apply,
+/// e.g. with `hg unbundle`. `bookmarks` are created by the bundle. It starts with a replycaps
+/// part, so that it can also be pushed to a Mononoke server as is.
+///
+/// All the changesets are walked before the bundle is sent to find what they introduce, which
+/// keeps a handle for each tree and file revision in memory. Contents are only fetched when the
+/// bundle is encoded.
+pub fn create_full_bundle(
+    blobrepo: BlobRepo,
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+    bookmarks: Vec<(Bookmark, HgChangesetId)>,
+    logger: Logger,
+) -> BoxFuture<FullBundle, Error> {
+    let blobrepo = Arc::new(blobrepo);
+    plan_bundle(
+        blobrepo.clone(),
+        common,
+        heads,
+        NarrowPatterns::everything(),
+        logger,
+    ).and_then(move |plan| plan.into_bundle(blobrepo, bookmarks))
+        .boxify()
+}
+
+/// Creates the parts of a getbundle response for a narrow client, which only has the trees and
+/// files that match `patterns`. Unlike `create_getbundle_response`, they are sent along with the
+/// changesets: a changegroup part with the filelogs of the matching files, then a treepack part
+/// with the trees that lead to them. Like a full bundle, everything the changesets introduce is
+/// walked before anything is sent.
+pub fn create_narrow_getbundle_response(
+    blobrepo: BlobRepo,
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+    patterns: NarrowPatterns,
+    logger: Logger,
+) -> BoxFuture<Vec<PartEncodeBuilder>, Error> {
+    let blobrepo = Arc::new(blobrepo);
+    plan_bundle(blobrepo.clone(), common, heads, patterns, logger)
+        .and_then(move |plan| {
+            let (changegroup, treepack) = plan.into_parts(blobrepo)?;
+            Ok(vec![changegroup, treepack])
+        })
+        .boxify()
+}
+
+fn plan_bundle(
+    blobrepo: Arc<BlobRepo>,
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+    patterns: NarrowPatterns,
+    logger: Logger,
+) -> BoxFuture<FullBundlePlan, Error> {
+    let nodestosend = try_boxfuture!(changesets_to_send(&blobrepo, common, heads));
+
+    canonical_order(&blobrepo, nodestosend)
+        .map({
+            cloned!(blobrepo);
+            move |bonsai| {
+                cloned!(blobrepo);
+                blobrepo
+                    .get_hg_from_bonsai_changeset(bonsai)
+                    .and_then(move |cs| introduced_entries(&blobrepo, cs).map(move |e| (cs, e)))
+            }
+        })
+        .buffered(100)
+        .fold(FullBundlePlan::new(patterns), move |mut plan, (cs, entries)| {
+            plan.add(cs, entries);
+            if plan.changesets.len() % 1000 == 0 {
+                info!(logger, "walked {} changesets", plan.changesets.len());
+            }
+            Ok::<_, Error>(plan)
+        })
+        .boxify()
+}
+
+/// Trees and files that changed in `cs` compared to its first parent, with the directory they
+/// are in. The root tree has no directory.
+fn introduced_entries(
+    blobrepo: &Arc<BlobRepo>,
+    cs: HgChangesetId,
+) -> BoxFuture<Vec<(Option<MPath>, Box<Entry + Sync>)>, Error> {
+    let manifestid = {
+        cloned!(blobrepo);
+        move |cs| blobrepo.get_changeset_by_changesetid(&cs).map(|cs| *cs.manifestid())
+    };
+
+    blobrepo
+        .get_changeset_by_changesetid(&cs)
+        .and_then({
+            cloned!(blobrepo);
+            move |cs| {
+                let p1 = match cs.p1() {
+                    Some(p1) => manifestid(HgChangesetId::new(*p1)).map(Some).left_future(),
+                    None => future::ok(None).right_future(),
+                };
+                let root = *cs.manifestid();
+                p1.and_then(move |p1| {
+                    if p1 == Some(root) {
+                        return future::ok(vec![]).boxify();
+                    }
+                    let root_entry = blobrepo.get_root_entry(&root);
+                    let from = match p1 {
+                        Some(p1) => blobrepo.get_manifest_by_nodeid(&p1).map(Some).left_future(),
+                        None => future::ok(None).right_future(),
+                    };
+                    blobrepo
+                        .get_manifest_by_nodeid(&root)
+                        .join(from)
+                        .and_then(|(to, from)| {
+                            let changed = match from {
+                                Some(from) => changed_entry_stream(&to, &from, None),
+                                None => changed_entry_stream(&to, &EmptyManifest, None),
+                            };
+                            changed
+                                .filter_map(|changed| match changed.status {
+                                    EntryStatus::Added(entry) => Some((changed.dirname, entry)),
+                                    EntryStatus::Modified { to_entry, .. } => {
+                                        Some((changed.dirname, to_entry))
+                                    }
+                                    EntryStatus::Deleted(_) => None,
+                                })
+                                .collect()
+                        })
+                        .map(move |mut entries| {
+                            entries.insert(0, (None, root_entry));
+                            entries
+                        })
+                        .boxify()
+                })
+            }
+        })
+        .boxify()
+}
+
+/// What a full bundle sends. Each tree and file revision is sent with the first changeset that
+/// introduces it, which is its linknode in the bundle. Trees and files that don't match
+/// `patterns` are left out.
+struct FullBundlePlan {
+    patterns: NarrowPatterns,
+    changesets: Vec<HgChangesetId>,
+    seen: HashSet<(RepoPath, HgNodeHash)>,
+    trees: Vec<(Option<MPath>, HgChangesetId, Box<Entry + Sync>)>,
+    /// Revisions of each file, parents before their children
+    files: BTreeMap<MPath, Vec<(HgChangesetId, Box<Entry + Sync>)>>,
+}
+
+impl FullBundlePlan {
+    fn new(patterns: NarrowPatterns) -> Self {
+        FullBundlePlan {
+            patterns,
+            changesets: vec![],
+            seen: HashSet::new(),
+            trees: vec![],
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `cs`, which comes after its parents
+    fn add(&mut self, cs: HgChangesetId, entries: Vec<(Option<MPath>, Box<Entry + Sync>)>) {
+        self.changesets.push(cs);
+        for (dirname, entry) in entries {
+            let path = MPath::join_element_opt(dirname.as_ref(), entry.get_name());
+            let repo_path = match path {
+                None => RepoPath::RootPath,
+                Some(ref path) if entry.get_type().is_tree() => {
+                    RepoPath::DirectoryPath(path.clone())
+                }
+                Some(ref path) => RepoPath::FilePath(path.clone()),
+            };
+            let matches = match repo_path {
+                RepoPath::FilePath(ref path) => self.patterns.matches_file(path),
+                _ => self.patterns.matches_dir(path.as_ref()),
+            };
+            if !matches {
+                continue;
+            }
+            if !self.seen.insert((repo_path, entry.get_hash().into_nodehash())) {
+                continue;
+            }
+            match path {
+                Some(ref path) if !entry.get_type().is_tree() => self.files
+                    .entry(path.clone())
+                    .or_insert_with(Vec::new)
+                    .push((cs, entry)),
+                _ => self.trees.push((dirname, cs, entry)),
+            }
+        }
+    }
+
+    fn into_bundle(
+        self,
+        blobrepo: Arc<BlobRepo>,
+        bookmarks: Vec<(Bookmark, HgChangesetId)>,
+    ) -> Result<FullBundle> {
+        let trees_count = self.trees.len();
+        let files_count = self.files.values().map(|revisions| revisions.len()).sum();
+        let changesets_count = self.changesets.len();
+
+        let (changegroup, treepack) = self.into_parts(blobrepo)?;
+        let mut bundle_parts = vec![parts::replycaps_part(Bytes::from("HG20"))?, changegroup];
+        for (bookmark, cs) in bookmarks {
+            bundle_parts.push(parts::bookmark_pushkey_part(
+                Bytes::from(bookmark.to_string()),
+                None,
+                Some(cs.into_nodehash()),
+            )?);
+        }
+        bundle_parts.push(treepack);
+
+        Ok(FullBundle {
+            changesets: changesets_count,
+            trees: trees_count,
+            files: files_count,
+            parts: bundle_parts,
+        })
+    }
+
+    /// The changegroup part, with the filelogs, and the treepack part
+    fn into_parts(
+        self,
+        blobrepo: Arc<BlobRepo>,
+    ) -> Result<(PartEncodeBuilder, PartEncodeBuilder)> {
+        let FullBundlePlan {
+            changesets,
+            trees,
+            files,
+            ..
+        } = self;
+
+        let buffer_size = 100;
+        let filelogs = stream::iter_ok::<_, Error>(files.into_iter()).map(move |(path, revisions)| {
+            let revisions = stream::iter_ok::<_, Error>(revisions.into_iter())
+                .map(|(linknode, entry)| {
+                    entry
+                        .get_parents()
+                        .join(entry.get_raw_content())
+                        .map(move |(parents, content)| {
+                            let (p1, p2) = parents.get_nodes();
+                            parts::FilelogPartInput {
+                                node: entry.get_hash().into_nodehash(),
+                                p1: p1.cloned(),
+                                p2: p2.cloned(),
+                                linknode: linknode.into_nodehash(),
+                                content: content.into_inner(),
+                            }
+                        })
+                })
+                .buffered(buffer_size)
+                .boxify();
+            (path, revisions)
+        });
+        let changesets = changesets.into_iter().map({
+            cloned!(blobrepo);
+            move |cs| blobrepo.get_bonsai_from_hg(&cs).and_then(move |bonsai| {
+                bonsai.ok_or(ErrorKind::BonsaiNotFoundForHgChangeset(cs).into())
+            })
+        });
+        let changesets = stream::iter_ok(changesets).buffered(buffer_size);
+        let changegroup = parts::changegroup_part_with_filelogs(
+            changelog_entries(blobrepo, changesets),
+            filelogs,
+        )?;
+
+        let trees = stream::iter_ok::<_, Error>(trees.into_iter()).map(
+            |(basepath, linknode, entry)| {
+                entry
+                    .get_parents()
+                    .join(entry.get_raw_content())
+                    .map(move |(parents, content)| {
+                        let (p1, p2) = parents.get_nodes();
+                        parts::TreepackPartInput {
+                            node: entry.get_hash().into_nodehash(),
+                            p1: p1.cloned(),
+                            p2: p2.cloned(),
+                            content: content.into_inner(),
+                            name: entry.get_name().cloned(),
+                            linknode: linknode.into_nodehash(),
+                            basepath,
+                        }
+                    })
+                    .boxify()
+            },
+        );
+        Ok((changegroup, parts::treepack_part(trees, buffer_size)?))
+    }
+}
+
+/// Returns changesets that are ancestors of `heads` but not of `common`, from the newest to the
+/// oldest.
+fn changesets_to_send(
+    blobrepo: &Arc<BlobRepo>,
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+) -> Result<BoxStream<ChangesetId, Error>> {
     if common.is_empty() {
         return Err(err_msg("no 'common' heads specified. Pull will be very inefficient. Please use hg clone instead"));
     }
 
-    let blobrepo = Arc::new(blobrepo.clone());
-
     let common_heads: HashSet<_> = HashSet::from_iter(common.iter());
 
     let heads = hg_to_bonsai_stream(
-        &blobrepo,
+        blobrepo,
         heads
             .iter()
             .filter(|head| !common_heads.contains(head))
@@ -43,7 +439,7 @@ pub fn create_getbundle_response(
     );
 
     let excludes = hg_to_bonsai_stream(
-        &blobrepo,
+        blobrepo,
         common
             .iter()
             .map(|node| node.clone())
@@ -52,7 +448,7 @@ pub fn create_getbundle_response(
     );
 
     let changeset_fetcher = blobrepo.get_changeset_fetcher();
-    let nodestosend = heads
+    Ok(heads
         .join(excludes)
         .map({
             move |(heads, excludes)| {
@@ -63,16 +459,43 @@ pub fn create_getbundle_response(
                 )
             }
         })
-        .flatten_stream();
+        .flatten_stream()
+        .boxify())
+}
 
-    // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
-    let nodestosend = nodestosend
+/// Sorts changesets by generation number and breaks ties by changeset id, which gives the same
+/// order for the same changesets every time. Parents always have smaller generation numbers than
+/// their children, so every prefix of the result applies cleanly.
+fn canonical_order(
+    blobrepo: &Arc<BlobRepo>,
+    nodes: BoxStream<ChangesetId, Error>,
+) -> BoxStream<ChangesetId, Error> {
+    let changeset_fetcher = blobrepo.get_changeset_fetcher();
+    nodes
+        .map(move |bonsai| {
+            changeset_fetcher
+                .get_generation_number(bonsai)
+                .map(move |gen| (gen, bonsai))
+        })
+        .buffered(100)
         .collect()
-        .map(|nodes| stream::iter_ok(nodes.into_iter().rev()))
-        .flatten_stream();
+        .map(|mut nodes| {
+            nodes.sort();
+            stream::iter_ok(nodes.into_iter().map(|(_, bonsai)| bonsai))
+        })
+        .flatten_stream()
+        .boxify()
+}
 
+fn changelog_entries<S>(
+    blobrepo: Arc<BlobRepo>,
+    nodestosend: S,
+) -> impl Stream<Item = (HgNodeHash, HgBlobNode), Error = Error> + Send + 'static
+where
+    S: Stream<Item = ChangesetId, Error = Error> + Send + 'static,
+{
     let buffer_size = 1000; // TODO(stash): make it configurable
-    let changelogentries = nodestosend
+    nodestosend
         .map({
             cloned!(blobrepo);
             move |bonsai| {
@@ -107,9 +530,7 @@ pub fn create_getbundle_response(
                 node,
                 HgBlobNode::new(Bytes::from(v), revlogcs.p1(), revlogcs.p2()),
             ))
-        });
-
-    parts::changegroup_part(changelogentries)
+        })
 }
 
 fn hg_to_bonsai_stream(
@@ -129,3 +550,172 @@ fn hg_to_bonsai_stream(
         .buffered(100)
         .collect()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use async_unit;
+    use fixtures::{linear, many_files_dirs, merge_uneven};
+    use mercurial_bundles::create_bundle_stream;
+    use mercurial_types::NULL_HASH;
+
+    fn hg_cs(hash: &str) -> HgChangesetId {
+        HgChangesetId::from_str(hash).unwrap()
+    }
+
+    fn entries(
+        repo: &BlobRepo,
+        pulls: &ResumablePulls,
+        resume: Option<(PullToken, usize)>,
+    ) -> (PullToken, BoxStream<HgNodeHash, Error>) {
+        // Bottom and top commits of the linear repo
+        let common = vec![hg_cs("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536")];
+        let heads = vec![hg_cs("79a13814c5ce7330173ec04d279bf95ab3f652fb")];
+        let (token, _, entries) =
+            resumable_changelog_entries(repo.clone(), common, heads, pulls.clone(), resume)
+                .unwrap();
+        (token, entries.map(|(node, _)| node).boxify())
+    }
+
+    #[test]
+    fn resume_interrupted_pull() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let pulls = ResumablePulls::new(Duration::from_secs(60), 3);
+
+            let (_, full) = entries(&repo, &pulls, None);
+            let full: Vec<_> = full.collect().wait().unwrap();
+            assert_eq!(full.len(), 10);
+
+            // Interrupt the pull in the middle of the second group: only the first group was
+            // fully received.
+            let (token, first) = entries(&repo, &pulls, None);
+            let first: Vec<_> = first.take(5).collect().wait().unwrap();
+            let received = 3;
+            let (resumed_token, rest) = entries(&repo, &pulls, Some((token, received)));
+            assert_eq!(resumed_token, token);
+            let rest: Vec<_> = rest.collect().wait().unwrap();
+
+            let mut resumed = first[..received].to_vec();
+            resumed.extend(rest);
+            assert_eq!(resumed, full);
+
+            // The concatenation applies cleanly: every changeset comes after its parents
+            let mut seen = HashSet::new();
+            seen.insert(hg_cs("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").into_nodehash());
+            for node in resumed {
+                let cs = repo.get_changeset_by_changesetid(&HgChangesetId::new(node))
+                    .wait()
+                    .unwrap();
+                for parent in cs.parents().into_iter() {
+                    assert!(seen.contains(&parent), "{} sent before its parent", node);
+                }
+                seen.insert(node);
+            }
+
+            // Can't resume from a point that wasn't reached
+            assert!(
+                pulls
+                    .resume(
+                        token,
+                        vec![hg_cs("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536")],
+                        vec![hg_cs("79a13814c5ce7330173ec04d279bf95ab3f652fb")],
+                        30,
+                    )
+                    .is_err()
+            );
+        });
+    }
+
+    /// Encodes the changegroup part of a getbundle response for all of `heads` as a bundle
+    fn getbundle_bytes(repo: &BlobRepo, heads: Vec<HgChangesetId>, deterministic: bool) -> Vec<u8> {
+        let common = vec![HgChangesetId::new(NULL_HASH)];
+        let part = create_getbundle_response(repo.clone(), common, heads, deterministic).unwrap();
+        create_bundle_stream(vec![part], None)
+            .concat2()
+            .wait()
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn deterministic_getbundle() {
+        async_unit::tokio_unit_test(|| {
+            let repo = merge_uneven::getrepo(None);
+            let heads = vec![hg_cs("b47ca72355a0af2c749d45a5689fd5bcce9898c7")];
+
+            let first = getbundle_bytes(&repo, heads.clone(), true);
+            let second = getbundle_bytes(&repo, heads.clone(), true);
+            assert!(first == second, "the same getbundle returned different bytes");
+
+            // The merge has branches of different lengths, so changesets of the same generation
+            // come from different branches and are sorted by changeset id
+            let blobrepo = Arc::new(repo.clone());
+            let nodes = changesets_to_send(&blobrepo, vec![HgChangesetId::new(NULL_HASH)], heads)
+                .unwrap();
+            let sorted: Vec<_> = canonical_order(&blobrepo, nodes)
+                .collect()
+                .wait()
+                .unwrap();
+            let changeset_fetcher = blobrepo.get_changeset_fetcher();
+            let keys: Vec<_> = sorted
+                .iter()
+                .map(|bonsai| {
+                    let gen = changeset_fetcher
+                        .get_generation_number(*bonsai)
+                        .wait()
+                        .unwrap();
+                    (gen, *bonsai)
+                })
+                .collect();
+            let mut expected = keys.clone();
+            expected.sort();
+            assert_eq!(keys, expected);
+            assert_eq!(keys.len(), 13);
+        });
+    }
+
+    #[test]
+    fn narrow_getbundle() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let common = vec![HgChangesetId::new(NULL_HASH)];
+            let heads = vec![hg_cs("2f866e7e549760934e31bf0420a873f65100ad63")];
+            let include = vec![b"path:dir1".to_vec()];
+            let exclude = vec![b"path:dir1/subdir1".to_vec()];
+            let patterns = NarrowPatterns::parse(&include, &exclude).unwrap();
+            let logger = Logger::root(::slog::Discard, o!());
+
+            let plan = plan_bundle(
+                Arc::new(repo.clone()),
+                common.clone(),
+                heads.clone(),
+                patterns.clone(),
+                logger.clone(),
+            ).wait()
+                .unwrap();
+            assert_eq!(plan.changesets.len(), 2);
+            // Files at the root, in dir2 and in the excluded dir1/subdir1 are left out
+            let files: Vec<_> = plan.files.keys().map(|path| path.to_string()).collect();
+            assert_eq!(files, vec!["dir1/file_1_in_dir1", "dir1/file_2_in_dir1"]);
+            // The root tree is sent because it leads to dir1
+            let trees: Vec<_> = plan.trees
+                .iter()
+                .map(|(dirname, _, entry)| {
+                    MPath::join_element_opt(dirname.as_ref(), entry.get_name())
+                })
+                .collect();
+            assert_eq!(trees, vec![None, Some(MPath::new("dir1").unwrap())]);
+
+            let parts = create_narrow_getbundle_response(repo, common, heads, patterns, logger)
+                .wait()
+                .unwrap();
+            assert_eq!(parts.len(), 2);
+            create_bundle_stream(parts, None).concat2().wait().unwrap();
+        });
+    }
+}
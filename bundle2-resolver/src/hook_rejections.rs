@@ -0,0 +1,353 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Renders the hook rejections of a push. A file hook may reject thousands of files of a push
+//! for the same reason, so rejections are grouped by hook and description, and only a few of
+//! the rejected files of each group are listed.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use bookmarks::Bookmark;
+use failure::prelude::*;
+use hooks::{ChangesetHookExecutionID, FileHookExecutionID, HookExecution};
+use mercurial_bundles::parts;
+use mercurial_bundles::part_encode::PartEncodeBuilder;
+
+/// At most this many rejected files or changesets are listed for a group of rejections
+const MAX_LISTED_PER_GROUP: usize = 5;
+
+/// What a hook rejected, and why
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HookRejection {
+    pub hook_name: String,
+    pub description: String,
+    /// Path of the rejected file, or hash of the rejected changeset
+    pub target: String,
+}
+
+impl HookRejection {
+    /// The rejections among the results of changeset and file hooks
+    pub fn from_executions(
+        cs_executions: Vec<(ChangesetHookExecutionID, HookExecution)>,
+        file_executions: Vec<(FileHookExecutionID, HookExecution)>,
+    ) -> Vec<HookRejection> {
+        Self::collect(cs_executions, file_executions, false)
+    }
+
+    /// The rejections by hooks of `Warn` severity among the results of changeset and file hooks
+    pub fn warnings_from_executions(
+        cs_executions: Vec<(ChangesetHookExecutionID, HookExecution)>,
+        file_executions: Vec<(FileHookExecutionID, HookExecution)>,
+    ) -> Vec<HookRejection> {
+        Self::collect(cs_executions, file_executions, true)
+    }
+
+    fn collect(
+        cs_executions: Vec<(ChangesetHookExecutionID, HookExecution)>,
+        file_executions: Vec<(FileHookExecutionID, HookExecution)>,
+        warnings: bool,
+    ) -> Vec<HookRejection> {
+        let info = move |exec: HookExecution| match exec {
+            HookExecution::Rejected(info) if !warnings => Some(info),
+            HookExecution::Warned(info) if warnings => Some(info),
+            _ => None,
+        };
+        let cs_rejections = cs_executions.into_iter().filter_map(move |(exec_id, exec)| {
+            info(exec).map(|info| HookRejection {
+                hook_name: exec_id.hook_name,
+                description: info.description,
+                target: exec_id.cs_id.to_string(),
+            })
+        });
+        let file_rejections = file_executions
+            .into_iter()
+            .filter_map(move |(exec_id, exec)| {
+                info(exec).map(|info| HookRejection {
+                    hook_name: exec_id.hook_name,
+                    description: info.description,
+                    target: exec_id.file.path,
+                })
+            });
+        cs_rejections.chain(file_rejections).collect()
+    }
+}
+
+/// Rejections of one hook for the same reason
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RejectionGroup {
+    pub hook_name: String,
+    pub description: String,
+    /// Rejected files or changesets, sorted. A file rejected in several changesets of the push
+    /// is listed once.
+    pub targets: Vec<String>,
+}
+
+/// Groups rejections by hook and description. Groups with the most rejected files or changesets
+/// come first.
+pub fn group_rejections(rejections: Vec<HookRejection>) -> Vec<RejectionGroup> {
+    let mut groups: HashMap<(String, String), BTreeSet<String>> = HashMap::new();
+    for rejection in rejections {
+        groups
+            .entry((rejection.hook_name, rejection.description))
+            .or_insert_with(BTreeSet::new)
+            .insert(rejection.target);
+    }
+
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .map(|((hook_name, description), targets)| RejectionGroup {
+            hook_name,
+            description,
+            targets: targets.into_iter().collect(),
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.targets
+            .len()
+            .cmp(&a.targets.len())
+            .then_with(|| a.hook_name.cmp(&b.hook_name))
+            .then_with(|| a.description.cmp(&b.description))
+    });
+    groups
+}
+
+/// Renders rejections for the user, one block per group
+pub fn format_rejections(rejections: Vec<HookRejection>) -> String {
+    format_groups("hooks failed:", rejections)
+}
+
+/// Renders rejections for a push that moves several bookmarks, one section per bookmark with
+/// the rejections by its hooks. `bookmarks_of_hook` are the pushed bookmarks that run each hook.
+pub fn format_rejections_by_bookmark(
+    rejections: Vec<HookRejection>,
+    bookmarks_of_hook: &HashMap<String, Vec<Bookmark>>,
+) -> String {
+    let mut unattributed = Vec::new();
+    let mut by_bookmark: BTreeMap<String, Vec<HookRejection>> = BTreeMap::new();
+    for rejection in rejections {
+        match bookmarks_of_hook.get(&rejection.hook_name) {
+            Some(bookmarks) if !bookmarks.is_empty() => for bookmark in bookmarks {
+                by_bookmark
+                    .entry(bookmark.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(rejection.clone());
+            },
+            _ => unattributed.push(rejection),
+        }
+    }
+
+    let mut sections = Vec::new();
+    if !unattributed.is_empty() {
+        sections.push(format_rejections(unattributed));
+    }
+    for (bookmark, rejections) in by_bookmark {
+        let title = format!("hooks failed for bookmark {}:", bookmark);
+        sections.push(format_groups(&title, rejections));
+    }
+    sections.join("\n")
+}
+
+/// Renders the rejections by hooks of `Warn` severity, which didn't fail the push
+pub fn format_warnings(warnings: Vec<HookRejection>) -> String {
+    format_groups("hooks warned (the push was not blocked):", warnings)
+}
+
+/// The part that shows `warnings` to the pusher, if there are any
+pub fn warnings_part(warnings: Vec<HookRejection>) -> Result<Option<PartEncodeBuilder>> {
+    if warnings.is_empty() {
+        return Ok(None);
+    }
+    parts::output_part(format_warnings(warnings)).map(Some)
+}
+
+fn format_groups(title: &str, rejections: Vec<HookRejection>) -> String {
+    let mut lines = vec![title.to_string()];
+    for group in group_rejections(rejections) {
+        lines.push(format!("{}: {}", group.hook_name, group.description));
+        for target in group.targets.iter().take(MAX_LISTED_PER_GROUP) {
+            lines.push(format!("  {}", target));
+        }
+        if group.targets.len() > MAX_LISTED_PER_GROUP {
+            lines.push(format!(
+                "  and {} more",
+                group.targets.len() - MAX_LISTED_PER_GROUP
+            ));
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use futures::Future;
+    use hooks::HookRejectionInfo;
+    use mercurial_bundles::Bundle2EncodeBuilder;
+    use mercurial_types_mocks::nodehash::{ONES_CSID, TWOS_CSID};
+
+    fn rejection(hook_name: &str, description: &str, target: &str) -> HookRejection {
+        HookRejection {
+            hook_name: hook_name.to_string(),
+            description: description.to_string(),
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_group_rejections() {
+        let groups = group_rejections(vec![
+            rejection("no_large_files", "file is too large", "b"),
+            rejection("no_tabs", "file contains tabs", "a"),
+            rejection("no_large_files", "file is too large", "a"),
+            rejection("no_large_files", "file is too large", "b"),
+            rejection("no_large_files", "file is empty", "c"),
+        ]);
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.hook_name.as_str(),
+                    group.description.as_str(),
+                    group.targets.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "no_large_files",
+                    "file is too large",
+                    vec!["a".to_string(), "b".to_string()],
+                ),
+                ("no_large_files", "file is empty", vec!["c".to_string()]),
+                ("no_tabs", "file contains tabs", vec!["a".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_rejections_truncates() {
+        let mut rejections: Vec<_> = (0..3000)
+            .map(|idx| rejection("no_large_files", "file is too large", &format!("f{:04}", idx)))
+            .collect();
+        rejections.push(rejection("check_message", "message is empty", "abc"));
+
+        assert_eq!(
+            format_rejections(rejections),
+            "hooks failed:\n\
+             no_large_files: file is too large\n  \
+             f0000\n  f0001\n  f0002\n  f0003\n  f0004\n  \
+             and 2995 more\n\
+             check_message: message is empty\n  \
+             abc"
+        );
+    }
+
+    #[test]
+    fn test_format_rejections_without_truncation() {
+        let rejections: Vec<_> = (0..MAX_LISTED_PER_GROUP)
+            .map(|idx| rejection("no_tabs", "file contains tabs", &format!("f{}", idx)))
+            .collect();
+        let formatted = format_rejections(rejections);
+        assert!(!formatted.contains("more"), "{}", formatted);
+        assert_eq!(formatted.lines().count(), MAX_LISTED_PER_GROUP + 2);
+    }
+
+    #[test]
+    fn test_format_rejections_by_bookmark() {
+        let master = Bookmark::new("master").unwrap();
+        let release = Bookmark::new("release").unwrap();
+        let bookmarks_of_hook = hashmap! {
+            "no_tabs".to_string() => vec![master.clone(), release.clone()],
+            "check_message".to_string() => vec![master.clone()],
+        };
+        let rejections = vec![
+            rejection("no_tabs", "file contains tabs", "a"),
+            rejection("check_message", "message is empty", "abc"),
+        ];
+
+        assert_eq!(
+            format_rejections_by_bookmark(rejections, &bookmarks_of_hook),
+            "hooks failed for bookmark master:\n\
+             check_message: message is empty\n  \
+             abc\n\
+             no_tabs: file contains tabs\n  \
+             a\n\
+             hooks failed for bookmark release:\n\
+             no_tabs: file contains tabs\n  \
+             a"
+        );
+    }
+
+    #[test]
+    fn test_warnings_from_executions() {
+        let info = || HookRejectionInfo::new("style".to_string(), "".to_string());
+        let executions = || {
+            vec![
+                (
+                    ChangesetHookExecutionID {
+                        cs_id: ONES_CSID,
+                        hook_name: "lint".to_string(),
+                    },
+                    HookExecution::Warned(info()),
+                ),
+                (
+                    ChangesetHookExecutionID {
+                        cs_id: TWOS_CSID,
+                        hook_name: "block".to_string(),
+                    },
+                    HookExecution::Rejected(info()),
+                ),
+                (
+                    ChangesetHookExecutionID {
+                        cs_id: TWOS_CSID,
+                        hook_name: "lint".to_string(),
+                    },
+                    HookExecution::Accepted,
+                ),
+            ]
+        };
+
+        assert_eq!(
+            HookRejection::warnings_from_executions(executions(), vec![]),
+            vec![rejection("lint", "style", &ONES_CSID.to_string())]
+        );
+        assert_eq!(
+            HookRejection::from_executions(executions(), vec![]),
+            vec![rejection("block", "style", &TWOS_CSID.to_string())]
+        );
+        assert_eq!(
+            format_warnings(vec![rejection("lint", "style", "abc")]),
+            "hooks warned (the push was not blocked):\n\
+             lint: style\n  \
+             abc"
+        );
+    }
+
+    #[test]
+    fn test_warnings_part() {
+        assert!(warnings_part(vec![]).unwrap().is_none());
+
+        let part = warnings_part(vec![rejection("lint", "line too long", "README")])
+            .unwrap()
+            .expect("no part for warnings");
+        let mut bundle = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+        bundle.add_part(part);
+        let reply = bundle.build().wait().unwrap().into_inner();
+        let expected = b"lint: line too long\n  README";
+        assert!(
+            reply
+                .windows(expected.len())
+                .any(|window| window == &expected[..]),
+            "{:?}",
+            reply
+        );
+    }
+}
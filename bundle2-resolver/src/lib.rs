@@ -46,6 +46,11 @@ extern crate tokio_io;
 extern crate blobrepo;
 extern crate bonsai_utils;
 extern crate bookmarks;
+extern crate context;
+extern crate cross_repo_index;
+extern crate derived_data;
+#[cfg(test)]
+extern crate dbbookmarks;
 extern crate hooks;
 extern crate mercurial;
 extern crate mercurial_bundles;
@@ -54,15 +59,35 @@ extern crate mercurial_types;
 extern crate mercurial_types_mocks;
 extern crate metaconfig;
 extern crate mononoke_types;
+#[cfg(test)]
+extern crate mononoke_types_mocks;
+extern crate push_journal;
 
+mod bookmark_creation;
+mod bookmark_push;
 mod changegroup;
 pub mod errors;
 mod getbundle_response;
+mod hook_rejections;
+mod manifest_fanout;
+mod narrow_patterns;
+mod path_validation;
+mod push_advisory;
+mod push_limits;
 mod pushrebase;
 mod resolver;
+mod resumable_pull;
 mod stats;
 mod wirepackparser;
 mod upload_blobs;
 
-pub use getbundle_response::create_getbundle_response;
+pub use getbundle_response::{create_full_bundle, create_getbundle_response,
+                             create_narrow_getbundle_response,
+                             create_resumable_getbundle_response, FullBundle};
+pub use hook_rejections::{format_rejections, format_warnings, HookRejection};
+pub use narrow_patterns::NarrowPatterns;
+pub use path_validation::{check_paths, format_violations, PathViolation, PathViolationKind};
+pub use push_advisory::{PushAdvisory, PUSH_ADVISORY_CAPABILITY};
+pub use push_limits::commit_message_fits;
+pub use resumable_pull::{PullToken, ResumablePulls, RESUMABLE_PULL_CAPABILITY};
 pub use resolver::resolve;
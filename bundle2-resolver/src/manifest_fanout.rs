@@ -0,0 +1,234 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Checks the number of direct entries of the directories uploaded by a push against the
+//! `PushLimits` of the repo. Every operation on a tree manifest reads all of its entries, so a
+//! directory with millions of children makes everything that touches it time out.
+
+use std::cmp::Reverse;
+use std::fmt;
+
+use futures::Future;
+use futures::future;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{HgNodeHash, RepoPath};
+use metaconfig::PushLimits;
+
+use errors::*;
+
+/// At most this many directories are listed in a rejection message
+const MAX_REPORTED_DIRECTORIES: usize = 10;
+
+/// A tree manifest uploaded by the push
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PushedDirectory {
+    pub path: RepoPath,
+    /// Number of direct entries of the directory
+    pub fanout: usize,
+    /// Tree manifests of the same directory in the parent changesets
+    pub parents: Vec<HgNodeHash>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirectoryFanout {
+    pub path: RepoPath,
+    pub fanout: usize,
+}
+
+impl fmt::Display for DirectoryFanout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} has {} entries", self.path, self.fanout)
+    }
+}
+
+/// Directories that the push creates or grows past the thresholds of the repo, largest first
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FanoutCheck {
+    /// Directories over `warn_directory_fanout`, up to `max_directory_fanout`
+    pub warnings: Vec<DirectoryFanout>,
+    /// Directories over `max_directory_fanout`
+    pub violations: Vec<DirectoryFanout>,
+}
+
+/// Largest fanout of the directories of a push
+pub fn max_fanout(directories: &[PushedDirectory]) -> usize {
+    directories.iter().map(|dir| dir.fanout).max().unwrap_or(0)
+}
+
+/// Checks `directories` against `limits`. A directory that is over a threshold is only reported
+/// if it has more entries than in each of its parents, so that edits and merges of directories
+/// that were already that large are not blocked. `parent_fanout` returns the number of entries of
+/// the tree manifest of a parent, it is only called for the directories over a threshold.
+pub fn check_fanout<F>(
+    limits: &PushLimits,
+    directories: Vec<PushedDirectory>,
+    parent_fanout: F,
+) -> BoxFuture<FanoutCheck, Error>
+where
+    F: Fn(&RepoPath, HgNodeHash) -> BoxFuture<usize, Error>,
+{
+    let max_directory_fanout = limits.max_directory_fanout;
+    let grown: Vec<_> = directories
+        .into_iter()
+        .filter(|dir| dir.fanout > limits.warn_directory_fanout)
+        .map(|dir| {
+            let parents: Vec<_> = dir.parents
+                .iter()
+                .map(|parent| parent_fanout(&dir.path, *parent))
+                .collect();
+            future::join_all(parents).map(move |parent_fanouts| {
+                if parent_fanouts.into_iter().all(|fanout| fanout < dir.fanout) {
+                    Some(DirectoryFanout {
+                        path: dir.path,
+                        fanout: dir.fanout,
+                    })
+                } else {
+                    None
+                }
+            })
+        })
+        .collect();
+
+    future::join_all(grown)
+        .map(move |grown| {
+            let mut check = FanoutCheck::default();
+            for dir in grown.into_iter().filter_map(|dir| dir) {
+                if dir.fanout > max_directory_fanout {
+                    check.violations.push(dir);
+                } else {
+                    check.warnings.push(dir);
+                }
+            }
+            for dirs in vec![&mut check.warnings, &mut check.violations] {
+                dirs.sort_by_key(|dir| (Reverse(dir.fanout), dir.path.to_string()));
+            }
+            check
+        })
+        .boxify()
+}
+
+pub fn format_fanouts(directories: &[DirectoryFanout]) -> String {
+    let mut lines: Vec<_> = directories
+        .iter()
+        .take(MAX_REPORTED_DIRECTORIES)
+        .map(|dir| dir.to_string())
+        .collect();
+    if directories.len() > MAX_REPORTED_DIRECTORIES {
+        lines.push(format!(
+            "and {} more",
+            directories.len() - MAX_REPORTED_DIRECTORIES
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use mercurial_types::MPath;
+    use mercurial_types_mocks::nodehash::*;
+
+    fn limits() -> PushLimits {
+        PushLimits {
+            max_directory_fanout: 1000,
+            warn_directory_fanout: 800,
+            ..Default::default()
+        }
+    }
+
+    fn dir(path: &str, fanout: usize, parents: Vec<HgNodeHash>) -> PushedDirectory {
+        PushedDirectory {
+            path: RepoPath::DirectoryPath(MPath::new(path).unwrap()),
+            fanout,
+            parents,
+        }
+    }
+
+    fn fanout(path: &str, fanout: usize) -> DirectoryFanout {
+        DirectoryFanout {
+            path: RepoPath::DirectoryPath(MPath::new(path).unwrap()),
+            fanout,
+        }
+    }
+
+    /// Checks `directories` against the tree manifests of the repo, as node and fanout
+    fn check(
+        directories: Vec<PushedDirectory>,
+        existing: Vec<(HgNodeHash, usize)>,
+    ) -> FanoutCheck {
+        let existing: HashMap<_, _> = existing.into_iter().collect();
+        check_fanout(&limits(), directories, move |_, node| {
+            let fanout = existing.get(&node).cloned().expect("parent is in the repo");
+            future::ok(fanout).boxify()
+        }).wait()
+            .expect("check should succeed")
+    }
+
+    #[test]
+    fn test_new_directories() {
+        let result = check(
+            vec![
+                dir("small", 10, vec![]),
+                dir("under", 1000, vec![]),
+                dir("over", 1001, vec![]),
+            ],
+            vec![],
+        );
+        assert_eq!(
+            result,
+            FanoutCheck {
+                warnings: vec![fanout("under", 1000)],
+                violations: vec![fanout("over", 1001)],
+            }
+        );
+        assert_eq!(
+            format_fanouts(&result.violations),
+            "directory 'over' has 1001 entries"
+        );
+    }
+
+    #[test]
+    fn test_existing_large_directories() {
+        let existing = vec![(ONES_HASH, 2000), (TWOS_HASH, 1500)];
+
+        // Modifying an entry, or merging into it a directory that is smaller, doesn't grow it
+        let result = check(
+            vec![
+                dir("same", 2000, vec![ONES_HASH]),
+                dir("shrunk", 1999, vec![ONES_HASH]),
+                dir("merged", 2000, vec![ONES_HASH, TWOS_HASH]),
+            ],
+            existing.clone(),
+        );
+        assert_eq!(result, FanoutCheck::default());
+
+        // A merge that ends up larger than both of its parents grows the directory
+        let result = check(
+            vec![
+                dir("grown", 2001, vec![ONES_HASH]),
+                dir("merged", 2500, vec![ONES_HASH, TWOS_HASH]),
+            ],
+            existing,
+        );
+        assert_eq!(
+            result.violations,
+            vec![fanout("merged", 2500), fanout("grown", 2001)]
+        );
+        assert_eq!(result.warnings, vec![]);
+    }
+
+    #[test]
+    fn test_max_fanout() {
+        assert_eq!(max_fanout(&[]), 0);
+        assert_eq!(
+            max_fanout(&[dir("a", 10, vec![]), dir("b", 20, vec![ONES_HASH])]),
+            20
+        );
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Narrow patterns, which the `includepattern` and `excludepattern` arguments of getbundle are
+//! made of. Narrow clients send them to only get the trees and files of a subset of the paths of
+//! the repo.
+//!
+//! Like in Mercurial, a pattern is either `path:DIR`, which matches DIR and everything under it,
+//! or `rootfilesin:DIR`, which matches the files directly in DIR. An empty DIR, or ".", is the
+//! root of the repo.
+
+use std::str;
+
+use mercurial_types::MPath;
+
+use errors::*;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Pattern {
+    /// The path and everything under it, everything if None
+    Path(Option<MPath>),
+    /// The files directly in the directory, the files at the root if None
+    RootFilesIn(Option<MPath>),
+}
+
+impl Pattern {
+    fn parse(pattern: &[u8]) -> Result<Self> {
+        let invalid = |reason: &str| -> Error {
+            ErrorKind::InvalidNarrowPattern(
+                String::from_utf8_lossy(pattern).into_owned(),
+                reason.to_string(),
+            ).into()
+        };
+
+        let pattern_str = str::from_utf8(pattern).map_err(|_| invalid("not valid UTF-8"))?;
+        let (kind, path) = match pattern_str.find(':') {
+            Some(colon) => (&pattern_str[..colon], &pattern_str[colon + 1..]),
+            None => return Err(invalid("expected path:DIR or rootfilesin:DIR")),
+        };
+
+        let path = path.trim_right_matches('/');
+        let path = if path.is_empty() || path == "." {
+            None
+        } else {
+            if path.starts_with('/') || path.split('/').any(|c| c == "." || c == "..") {
+                return Err(invalid("paths must be relative to the root of the repo"));
+            }
+            Some(MPath::new(path).map_err(|err| invalid(&err.to_string()))?)
+        };
+
+        match kind {
+            "path" => Ok(Pattern::Path(path)),
+            "rootfilesin" => Ok(Pattern::RootFilesIn(path)),
+            _ => Err(invalid("only path: and rootfilesin: patterns are supported")),
+        }
+    }
+
+    fn matches_file(&self, file: &MPath) -> bool {
+        match self {
+            Pattern::Path(path) => is_ancestor_or_self(path.as_ref(), Some(file)),
+            Pattern::RootFilesIn(dir) => file.split_dirname().0.as_ref() == dir.as_ref(),
+        }
+    }
+
+    /// Whether the tree of `dir` has files that this pattern matches
+    fn matches_files_under(&self, dir: Option<&MPath>) -> bool {
+        let pattern_dir = match self {
+            Pattern::Path(path) => path,
+            Pattern::RootFilesIn(dir) => dir,
+        };
+        is_ancestor_or_self(dir, pattern_dir.as_ref())
+            || is_ancestor_or_self(pattern_dir.as_ref(), dir) && match self {
+                Pattern::Path(_) => true,
+                Pattern::RootFilesIn(pattern_dir) => pattern_dir.as_ref() == dir,
+            }
+    }
+
+    /// Whether this pattern matches every file under `dir`
+    fn matches_all_files_under(&self, dir: Option<&MPath>) -> bool {
+        match self {
+            Pattern::Path(path) => is_ancestor_or_self(path.as_ref(), dir),
+            Pattern::RootFilesIn(_) => false,
+        }
+    }
+}
+
+/// Whether `ancestor` is `path` or one of its directories. None is the root.
+fn is_ancestor_or_self(ancestor: Option<&MPath>, path: Option<&MPath>) -> bool {
+    match (ancestor, path) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(ancestor), Some(path)) => ancestor.is_prefix_of(path),
+    }
+}
+
+/// The files and trees that a getbundle response sends
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NarrowPatterns {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl NarrowPatterns {
+    /// Patterns that match every path
+    pub fn everything() -> Self {
+        NarrowPatterns {
+            include: vec![Pattern::Path(None)],
+            exclude: vec![],
+        }
+    }
+
+    /// Parses the patterns of `includepattern` and `excludepattern`. Without any include
+    /// pattern, every path that isn't excluded is matched.
+    pub fn parse(include: &[Vec<u8>], exclude: &[Vec<u8>]) -> Result<Self> {
+        let parse_all = |patterns: &[Vec<u8>]| -> Result<Vec<Pattern>> {
+            patterns
+                .iter()
+                .map(|pattern| Pattern::parse(pattern))
+                .collect()
+        };
+        let mut include = parse_all(include)?;
+        if include.is_empty() {
+            include.push(Pattern::Path(None));
+        }
+        Ok(NarrowPatterns {
+            include,
+            exclude: parse_all(exclude)?,
+        })
+    }
+
+    /// Returns true if every path is matched, so that nothing needs to be filtered
+    pub fn is_everything(&self) -> bool {
+        *self == Self::everything()
+    }
+
+    /// Whether the file at `path` is sent
+    pub fn matches_file(&self, path: &MPath) -> bool {
+        self.include.iter().any(|p| p.matches_file(path))
+            && !self.exclude.iter().any(|p| p.matches_file(path))
+    }
+
+    /// Whether the tree of `dir` is sent: it is if it can have files that are sent. None is the
+    /// root tree.
+    pub fn matches_dir(&self, dir: Option<&MPath>) -> bool {
+        self.include.iter().any(|p| p.matches_files_under(dir))
+            && !self.exclude.iter().any(|p| p.matches_all_files_under(dir))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn patterns(include: &[&str], exclude: &[&str]) -> Result<NarrowPatterns> {
+        let to_bytes = |patterns: &[&str]| -> Vec<Vec<u8>> {
+            patterns.iter().map(|p| p.as_bytes().to_vec()).collect()
+        };
+        NarrowPatterns::parse(&to_bytes(include), &to_bytes(exclude))
+    }
+
+    fn path(path: &str) -> MPath {
+        MPath::new(path).unwrap()
+    }
+
+    #[test]
+    fn test_path_patterns() {
+        let patterns = patterns(&["path:dir/sub"], &["path:dir/sub/excluded"]).unwrap();
+        assert!(patterns.matches_file(&path("dir/sub/file")));
+        assert!(patterns.matches_file(&path("dir/sub/deeper/file")));
+        assert!(!patterns.matches_file(&path("dir/sub/excluded/file")));
+        assert!(!patterns.matches_file(&path("dir/file")));
+        assert!(!patterns.matches_file(&path("dir/sub2/file")));
+        assert!(!patterns.matches_file(&path("other")));
+
+        assert!(patterns.matches_dir(None));
+        assert!(patterns.matches_dir(Some(&path("dir"))));
+        assert!(patterns.matches_dir(Some(&path("dir/sub"))));
+        assert!(patterns.matches_dir(Some(&path("dir/sub/deeper"))));
+        assert!(!patterns.matches_dir(Some(&path("dir/sub/excluded"))));
+        assert!(!patterns.matches_dir(Some(&path("dir/sub2"))));
+        assert!(!patterns.matches_dir(Some(&path("other"))));
+    }
+
+    #[test]
+    fn test_rootfilesin_patterns() {
+        let patterns = patterns(&["rootfilesin:dir"], &[]).unwrap();
+        assert!(patterns.matches_file(&path("dir/file")));
+        assert!(!patterns.matches_file(&path("dir/sub/file")));
+        assert!(!patterns.matches_file(&path("file")));
+        assert!(patterns.matches_dir(None));
+        assert!(patterns.matches_dir(Some(&path("dir"))));
+        assert!(!patterns.matches_dir(Some(&path("dir/sub"))));
+
+        let root = patterns(&["rootfilesin:."], &[]).unwrap();
+        assert!(root.matches_file(&path("file")));
+        assert!(!root.matches_file(&path("dir/file")));
+        assert!(!root.matches_dir(Some(&path("dir"))));
+    }
+
+    #[test]
+    fn test_exclude_only() {
+        let patterns = patterns(&[], &["path:dir"]).unwrap();
+        assert!(!patterns.is_everything());
+        assert!(patterns.matches_file(&path("file")));
+        assert!(!patterns.matches_file(&path("dir/file")));
+        assert!(!patterns.matches_dir(Some(&path("dir"))));
+        assert!(patterns.matches_dir(Some(&path("other"))));
+
+        assert!(self::patterns(&[], &[]).unwrap().is_everything());
+        assert!(self::patterns(&["path:"], &[]).unwrap().is_everything());
+    }
+
+    #[test]
+    fn test_malformed_patterns() {
+        for pattern in &["dir", "glob:*.txt", "path:../dir", "path:/dir", "path:a/./b"] {
+            match patterns(&[pattern], &[]).map_err(|err| err.downcast::<ErrorKind>()) {
+                Err(Ok(ErrorKind::InvalidNarrowPattern(..))) => {}
+                res => panic!("unexpected result for {}: {:?}", pattern, res),
+            }
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Checks the paths of files added or modified by a push against the `PathRules` of the repo,
+//! so that pushes can't introduce paths that some clients can't check out or that break tooling.
+
+use std::fmt;
+use std::str;
+
+use mercurial_types::{MPath, MPathElement};
+use metaconfig::PathRules;
+
+/// At most this many offending paths are listed in a rejection message
+const MAX_REPORTED_VIOLATIONS: usize = 10;
+
+const VCS_COMPONENTS: &[&str] = &[".hg", ".git"];
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PathViolationKind {
+    VcsComponent(String),
+    WindowsReservedName(String),
+    ComponentTooLong(String, usize),
+    PathTooLong(usize),
+    TrailingChar(String, char),
+    NonUtf8,
+}
+
+impl fmt::Display for PathViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PathViolationKind::*;
+
+        match self {
+            VcsComponent(component) => write!(f, "'{}' is a reserved VCS directory", component),
+            WindowsReservedName(component) => {
+                write!(f, "'{}' is a reserved name on Windows", component)
+            }
+            ComponentTooLong(component, max) => write!(
+                f,
+                "component '{}' is longer than {} bytes",
+                component, max
+            ),
+            PathTooLong(max) => write!(f, "path is longer than {} bytes", max),
+            TrailingChar(component, ch) => {
+                write!(f, "component '{}' ends with {:?}", component, ch)
+            }
+            NonUtf8 => write!(f, "path is not valid UTF-8"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathViolation {
+    pub path: MPath,
+    pub kind: PathViolationKind,
+}
+
+impl fmt::Display for PathViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.kind)
+    }
+}
+
+fn component_str(component: &MPathElement) -> String {
+    String::from_utf8_lossy(component.as_bytes()).into_owned()
+}
+
+fn is_windows_reserved(component: &str) -> bool {
+    // Windows ignores the extension, so "nul.txt" is as reserved as "nul"
+    let stem = component.split('.').next().unwrap_or(component);
+    WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+/// Returns all the rules of `rules` that `path` breaks
+pub fn check_path(rules: &PathRules, path: &MPath) -> Vec<PathViolation> {
+    let mut kinds = vec![];
+
+    if rules.forbid_non_utf8 && str::from_utf8(&path.to_vec()).is_err() {
+        kinds.push(PathViolationKind::NonUtf8);
+    }
+
+    if let Some(max) = rules.max_path_length {
+        if path.len() > max {
+            kinds.push(PathViolationKind::PathTooLong(max));
+        }
+    }
+
+    for element in path {
+        let component = component_str(element);
+
+        if rules.forbid_vcs_components
+            && VCS_COMPONENTS
+                .iter()
+                .any(|vcs| vcs.eq_ignore_ascii_case(&component))
+        {
+            kinds.push(PathViolationKind::VcsComponent(component.clone()));
+        }
+
+        if rules.forbid_windows_reserved_names && is_windows_reserved(&component) {
+            kinds.push(PathViolationKind::WindowsReservedName(component.clone()));
+        }
+
+        if let Some(max) = rules.max_component_length {
+            if element.len() > max {
+                kinds.push(PathViolationKind::ComponentTooLong(component.clone(), max));
+            }
+        }
+
+        if let Some(last) = component.chars().last() {
+            if rules.forbidden_trailing_chars.contains(&last) {
+                kinds.push(PathViolationKind::TrailingChar(component.clone(), last));
+            }
+        }
+    }
+
+    kinds
+        .into_iter()
+        .map(|kind| PathViolation {
+            path: path.clone(),
+            kind,
+        })
+        .collect()
+}
+
+/// Returns all the rules of `rules` that any of `paths` breaks, ordered by path
+pub fn check_paths<'a, I>(rules: &PathRules, paths: I) -> Vec<PathViolation>
+where
+    I: IntoIterator<Item = &'a MPath>,
+{
+    if rules.is_empty() {
+        return vec![];
+    }
+
+    let mut paths: Vec<_> = paths.into_iter().collect();
+    paths.sort();
+    paths.dedup();
+    paths
+        .into_iter()
+        .flat_map(|path| check_path(rules, path))
+        .collect()
+}
+
+/// Formats violations for a rejection message, listing at most `MAX_REPORTED_VIOLATIONS`
+pub fn format_violations(violations: &[PathViolation]) -> String {
+    let mut lines: Vec<_> = violations
+        .iter()
+        .take(MAX_REPORTED_VIOLATIONS)
+        .map(|violation| violation.to_string())
+        .collect();
+    if violations.len() > MAX_REPORTED_VIOLATIONS {
+        lines.push(format!(
+            "and {} more",
+            violations.len() - MAX_REPORTED_VIOLATIONS
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(p: &[u8]) -> MPath {
+        MPath::new(p).unwrap()
+    }
+
+    fn kinds(rules: &PathRules, p: &[u8]) -> Vec<PathViolationKind> {
+        check_path(rules, &path(p))
+            .into_iter()
+            .map(|violation| violation.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_no_rules() {
+        let rules = PathRules::default();
+        assert_eq!(kinds(&rules, b"a/.git/con."), vec![]);
+        assert_eq!(check_paths(&rules, &[path(b"a/.hg/x")]), vec![]);
+    }
+
+    #[test]
+    fn test_vcs_components() {
+        let rules = PathRules {
+            forbid_vcs_components: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            kinds(&rules, b"a/.git/config"),
+            vec![PathViolationKind::VcsComponent(".git".into())]
+        );
+        assert_eq!(
+            kinds(&rules, b".HG/store"),
+            vec![PathViolationKind::VcsComponent(".HG".into())]
+        );
+        assert_eq!(kinds(&rules, b".gitignore"), vec![]);
+        assert_eq!(kinds(&rules, b"a/.hgtags"), vec![]);
+    }
+
+    #[test]
+    fn test_windows_reserved_names() {
+        let rules = PathRules {
+            forbid_windows_reserved_names: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            kinds(&rules, b"dir/NUL"),
+            vec![PathViolationKind::WindowsReservedName("NUL".into())]
+        );
+        assert_eq!(
+            kinds(&rules, b"con.txt"),
+            vec![PathViolationKind::WindowsReservedName("con.txt".into())]
+        );
+        assert_eq!(
+            kinds(&rules, b"lpt9/file"),
+            vec![PathViolationKind::WindowsReservedName("lpt9".into())]
+        );
+        assert_eq!(kinds(&rules, b"console/nullable"), vec![]);
+        assert_eq!(kinds(&rules, b"com10"), vec![]);
+    }
+
+    #[test]
+    fn test_max_component_length() {
+        let rules = PathRules {
+            max_component_length: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(kinds(&rules, b"abcde/fghij"), vec![]);
+        assert_eq!(
+            kinds(&rules, b"abcdef/x"),
+            vec![PathViolationKind::ComponentTooLong("abcdef".into(), 5)]
+        );
+    }
+
+    #[test]
+    fn test_max_path_length() {
+        let rules = PathRules {
+            max_path_length: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(kinds(&rules, b"abc/def"), vec![]);
+        assert_eq!(
+            kinds(&rules, b"abc/defg"),
+            vec![PathViolationKind::PathTooLong(7)]
+        );
+    }
+
+    #[test]
+    fn test_trailing_chars() {
+        let rules = PathRules {
+            forbidden_trailing_chars: vec!['.', ' '],
+            ..Default::default()
+        };
+        assert_eq!(
+            kinds(&rules, b"dir./file"),
+            vec![PathViolationKind::TrailingChar("dir.".into(), '.')]
+        );
+        assert_eq!(
+            kinds(&rules, b"dir/file "),
+            vec![PathViolationKind::TrailingChar("file ".into(), ' ')]
+        );
+        assert_eq!(kinds(&rules, b".dir/fi.le"), vec![]);
+    }
+
+    #[test]
+    fn test_non_utf8() {
+        let rules = PathRules {
+            forbid_non_utf8: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            kinds(&rules, b"dir/\xff\xfe"),
+            vec![PathViolationKind::NonUtf8]
+        );
+        assert_eq!(kinds(&rules, "dir/\u{e9}t\u{e9}".as_bytes()), vec![]);
+    }
+
+    #[test]
+    fn test_check_paths_sorted_and_capped() {
+        let rules = PathRules::recommended();
+        let paths: Vec<_> = (0..15)
+            .rev()
+            .map(|i| path(format!("dir{:02}/.git/config", i).as_bytes()))
+            .chain(Some(path(b"ok/file")))
+            .collect();
+
+        let violations = check_paths(&rules, &paths);
+        assert_eq!(violations.len(), 15);
+        assert_eq!(violations[0].path, path(b"dir00/.git/config"));
+
+        let message = format_violations(&violations);
+        let lines: Vec<_> = message.lines().collect();
+        assert_eq!(lines.len(), MAX_REPORTED_VIOLATIONS + 1);
+        assert_eq!(
+            lines[0],
+            "dir00/.git/config: '.git' is a reserved VCS directory"
+        );
+        assert_eq!(lines[MAX_REPORTED_VIOLATIONS], "and 5 more");
+    }
+}
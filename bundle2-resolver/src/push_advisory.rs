@@ -0,0 +1,152 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Messages sent to the client after a successful push, about the commits it landed.
+//!
+//! By default the message is rendered from the template of the repo and sent in an `output`
+//! part, which the client prints. A client that advertises `PUSH_ADVISORY_CAPABILITY` in its
+//! replycaps gets a `b2x:pushadvisory` part with the landed hashes instead, and renders them
+//! itself.
+
+use mercurial_bundles::parts;
+use mercurial_bundles::part_encode::PartEncodeBuilder;
+use mercurial_types::HgNodeHash;
+use metaconfig::{PushAdvisoryParams, PushAdvisoryPlaceholder};
+
+use errors::*;
+
+/// Replycaps capability of clients that want the structured push advisory
+pub const PUSH_ADVISORY_CAPABILITY: &str = "pushadvisory";
+
+const SHORT_HASH_LEN: usize = 12;
+
+/// The push advisory of a repo
+#[derive(Clone, Debug)]
+pub struct PushAdvisory {
+    params: PushAdvisoryParams,
+    reponame: String,
+}
+
+impl PushAdvisory {
+    pub fn new(params: PushAdvisoryParams, reponame: String) -> Self {
+        PushAdvisory { params, reponame }
+    }
+
+    /// Number of landed commits listed in the advisory, the others are only counted
+    pub fn max_listed_commits(&self) -> usize {
+        self.params.max_listed_commits
+    }
+
+    /// Renders the message for `landed`, the last of `total` commits that landed on `bookmark`,
+    /// in the order they landed. Only the last `max_listed_commits` of them are listed.
+    pub fn render(&self, bookmark: &str, landed: &[HgNodeHash], total: usize) -> String {
+        let listed = self.listed(landed);
+        let mut message = String::new();
+        for node in listed {
+            let hash = node.to_hex().to_string();
+            let line = self.params.template.render(|placeholder| match placeholder {
+                PushAdvisoryPlaceholder::Hash => hash.clone(),
+                PushAdvisoryPlaceholder::ShortHash => hash[..SHORT_HASH_LEN].to_string(),
+                PushAdvisoryPlaceholder::Bookmark => bookmark.to_string(),
+                PushAdvisoryPlaceholder::Reponame => self.reponame.clone(),
+            });
+            message.push_str(&line);
+            message.push('\n');
+        }
+        let summarized = total.saturating_sub(listed.len());
+        if summarized > 0 {
+            message.push_str(&format!(
+                "... and {} earlier commit{}\n",
+                summarized,
+                if summarized == 1 { "" } else { "s" }
+            ));
+        }
+        message
+    }
+
+    /// The part that carries the advisory, structured if the client asked for it
+    pub fn part(
+        &self,
+        bookmark: &str,
+        landed: &[HgNodeHash],
+        total: usize,
+        structured: bool,
+    ) -> Result<PartEncodeBuilder> {
+        if structured {
+            parts::pushadvisory_part(&self.reponame, bookmark, self.listed(landed), total)
+        } else {
+            parts::output_part(self.render(bookmark, landed, total))
+        }
+    }
+
+    fn listed<'a>(&self, landed: &'a [HgNodeHash]) -> &'a [HgNodeHash] {
+        let skipped = landed.len().saturating_sub(self.params.max_listed_commits);
+        &landed[skipped..]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use metaconfig::PushAdvisoryTemplate;
+    use mercurial_types_mocks::nodehash::*;
+
+    fn advisory(max_listed_commits: usize) -> PushAdvisory {
+        let template = "landed {short_hash} on {bookmark}: https://example.com/{reponame}/{hash}";
+        PushAdvisory::new(
+            PushAdvisoryParams {
+                template: PushAdvisoryTemplate::parse(template).unwrap(),
+                max_listed_commits,
+            },
+            "fbsource".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_render_single_commit() {
+        assert_eq!(
+            advisory(5).render("master", &[ONES_HASH], 1),
+            format!(
+                "landed 111111111111 on master: https://example.com/fbsource/{}\n",
+                ONES_HASH
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_multiple_commits() {
+        let landed = [ONES_HASH, TWOS_HASH, THREES_HASH];
+        assert_eq!(
+            advisory(5).render("master", &landed, 3),
+            format!(
+                "landed 111111111111 on master: https://example.com/fbsource/{}\n\
+                 landed 222222222222 on master: https://example.com/fbsource/{}\n\
+                 landed 333333333333 on master: https://example.com/fbsource/{}\n",
+                ONES_HASH, TWOS_HASH, THREES_HASH
+            )
+        );
+
+        // Only the last commits are listed, the earlier ones are counted
+        assert_eq!(
+            advisory(2).render("master", &landed, 3),
+            format!(
+                "landed 222222222222 on master: https://example.com/fbsource/{}\n\
+                 landed 333333333333 on master: https://example.com/fbsource/{}\n\
+                 ... and 1 earlier commit\n",
+                TWOS_HASH, THREES_HASH
+            )
+        );
+        assert_eq!(
+            advisory(1).render("", &[THREES_HASH], 10),
+            format!(
+                "landed 333333333333 on : https://example.com/fbsource/{}\n\
+                 ... and 9 earlier commits\n",
+                THREES_HASH
+            )
+        );
+    }
+}
@@ -0,0 +1,481 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Accounting of the payload of an unbundle while its parts are streamed in, so that a push that
+//! exceeds the limits of the repo, or the deadline of the client, is rejected before it is fully
+//! read into memory.
+//!
+//! Items are only accounted for once they are decoded, so a single chunk is bounded earlier: the
+//! bundle2 stream is built with `Bundle2Stream::with_max_chunk_bytes`, which rejects a chunk as
+//! soon as its length header is read.
+
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use context::Deadline;
+use futures::Stream;
+use futures_ext::{BoxStream, StreamExt};
+use mercurial_bundles::Bundle2Item;
+use mercurial_bundles::changegroup::{self, Section};
+use mercurial_bundles::wirepack;
+use mercurial_types::{Delta, HgNodeHash};
+use metaconfig::PushLimits;
+use scuba_ext::ScubaSampleBuilder;
+use slog::Logger;
+
+use errors::*;
+
+// Sizes of the fixed-size fields of the entries, as they are encoded in the bundle
+const CG_CHUNK_HEADER_SIZE: u64 = 5 * 20;
+const HISTORY_ENTRY_HEADER_SIZE: u64 = 4 * 20 + 2;
+const DATA_ENTRY_HEADER_SIZE: u64 = 2 * 20 + 8;
+const DELTA_FRAGMENT_HEADER_SIZE: u64 = 3 * 4;
+
+/// How much of a push was received so far
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PushProgress {
+    /// Bytes of payload in all the parts
+    pub bytes: u64,
+    /// Number of parts that carry payload
+    pub parts: usize,
+    /// Number of changesets in the changegroup parts
+    pub changesets: usize,
+}
+
+/// Counts the payload of the parts of a bundle2 and fails their streams once a limit is exceeded
+#[derive(Clone)]
+pub struct PushAccounting {
+    limits: PushLimits,
+    deadline: Option<Deadline>,
+    progress: Arc<Mutex<PushProgress>>,
+    logger: Logger,
+    scuba_logger: ScubaSampleBuilder,
+}
+
+impl PushAccounting {
+    pub fn new(
+        limits: PushLimits,
+        deadline: Option<Deadline>,
+        logger: Logger,
+        scuba_logger: ScubaSampleBuilder,
+    ) -> Self {
+        Self {
+            limits,
+            deadline,
+            progress: Arc::new(Mutex::new(PushProgress::default())),
+            logger,
+            scuba_logger,
+        }
+    }
+
+    pub fn limits(&self) -> &PushLimits {
+        &self.limits
+    }
+
+    pub fn progress(&self) -> PushProgress {
+        *self.progress.lock().expect("lock poisoned")
+    }
+
+    /// Wraps the streams of the parts of the bundle, so that the payload is counted as the parts
+    /// are consumed.
+    pub fn limit_bundle2(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxStream<Bundle2Item, Error> {
+        let this = self.clone();
+        bundle2.map(move |item| this.limit_part(item)).boxify()
+    }
+
+    fn limit_part(&self, item: Bundle2Item) -> Bundle2Item {
+        match item {
+            Bundle2Item::Changegroup(header, parts) => {
+                let parts = self.limit_stream(header.part_id(), parts, changegroup_part_size);
+                Bundle2Item::Changegroup(header, parts)
+            }
+            Bundle2Item::B2xInfinitepush(header, parts) => {
+                let parts = self.limit_stream(header.part_id(), parts, changegroup_part_size);
+                Bundle2Item::B2xInfinitepush(header, parts)
+            }
+            Bundle2Item::B2xRebase(header, parts) => {
+                let parts = self.limit_stream(header.part_id(), parts, changegroup_part_size);
+                Bundle2Item::B2xRebase(header, parts)
+            }
+            Bundle2Item::B2xTreegroup2(header, parts) => {
+                let parts = self.limit_stream(header.part_id(), parts, wirepack_part_size);
+                Bundle2Item::B2xTreegroup2(header, parts)
+            }
+            Bundle2Item::B2xRebasePack(header, parts) => {
+                let parts = self.limit_stream(header.part_id(), parts, wirepack_part_size);
+                Bundle2Item::B2xRebasePack(header, parts)
+            }
+            Bundle2Item::B2xInfinitepushBookmarks(header, bookmarks) => {
+                let bookmarks =
+                    self.limit_stream(header.part_id(), bookmarks, |bytes: &Bytes| {
+                        (bytes.len() as u64, 0)
+                    });
+                Bundle2Item::B2xInfinitepushBookmarks(header, bookmarks)
+            }
+            item => item,
+        }
+    }
+
+    /// `size` returns the number of bytes and the number of changesets in an item of the part
+    fn limit_stream<T, F>(
+        &self,
+        part_id: u32,
+        stream: BoxStream<T, Error>,
+        size: F,
+    ) -> BoxStream<T, Error>
+    where
+        T: Send + 'static,
+        F: Fn(&T) -> (u64, usize) + Send + 'static,
+    {
+        self.progress.lock().expect("lock poisoned").parts += 1;
+
+        let this = self.clone();
+        let mut part_bytes = 0;
+        stream
+            .and_then(move |item| {
+                let (bytes, changesets) = size(&item);
+                part_bytes += bytes;
+                this.add(part_id, part_bytes, bytes, changesets).map(|()| item)
+            })
+            .boxify()
+    }
+
+    fn add(&self, part_id: u32, part_bytes: u64, bytes: u64, changesets: usize) -> Result<()> {
+        let progress = {
+            let mut progress = self.progress.lock().expect("lock poisoned");
+            progress.bytes += bytes;
+            progress.changesets += changesets;
+            *progress
+        };
+
+        let deadline_check = self.deadline.map(|deadline| deadline.check());
+        let (limit, err) = if let Some(Err(exceeded)) = deadline_check {
+            ("deadline", Error::from(exceeded))
+        } else if part_bytes > self.limits.max_part_bytes {
+            (
+                "max_part_bytes",
+                ErrorKind::PartTooLarge(part_id, self.limits.max_part_bytes).into(),
+            )
+        } else if progress.bytes > self.limits.max_push_bytes {
+            (
+                "max_push_bytes",
+                ErrorKind::PushTooLarge(self.limits.max_push_bytes).into(),
+            )
+        } else if progress.changesets > self.limits.max_changesets {
+            (
+                "max_changesets",
+                ErrorKind::TooManyChangesets(self.limits.max_changesets).into(),
+            )
+        } else {
+            return Ok(());
+        };
+
+        // Blobs of the files and manifests that were received before the limit was hit may
+        // already be in the blobstore, nothing refers to them so they are left for GC
+        warn!(
+            self.logger,
+            "Rejecting push after {} bytes, {} parts, {} changesets: {}. Blobs uploaded so far are \
+             orphaned",
+            progress.bytes,
+            progress.parts,
+            progress.changesets,
+            err
+        );
+        self.scuba_logger
+            .clone()
+            .add("push_bytes", progress.bytes)
+            .add("push_parts", progress.parts)
+            .add("push_changesets", progress.changesets)
+            .add("push_limit", limit)
+            .log_with_msg("Push limit exceeded", format!("{}", err));
+
+        Err(err)
+    }
+
+    /// Rejects the push if the message of the changeset `node` exceeds the limit. Unlike the
+    /// other limits this is checked once the changegroup is parsed, as a message is not split
+    /// across parts
+    pub fn check_commit_message(&self, node: HgNodeHash, message: &[u8]) -> Result<()> {
+        if commit_message_fits(&self.limits, message) {
+            return Ok(());
+        }
+
+        let err = ErrorKind::CommitMessageTooLarge(
+            node,
+            message.len(),
+            self.limits.max_commit_message_bytes,
+        );
+        warn!(self.logger, "Rejecting push: {}", err);
+        self.scuba_logger
+            .clone()
+            .add("commit_message_bytes", message.len())
+            .add("push_limit", "max_commit_message_bytes")
+            .log_with_msg("Push limit exceeded", format!("{}", err));
+
+        Err(err.into())
+    }
+}
+
+/// Whether `message` is within the limit of `limits` on the size of commit messages
+pub fn commit_message_fits(limits: &PushLimits, message: &[u8]) -> bool {
+    message.len() <= limits.max_commit_message_bytes
+}
+
+fn delta_size(delta: &Delta) -> u64 {
+    delta
+        .fragments()
+        .iter()
+        .map(|frag| DELTA_FRAGMENT_HEADER_SIZE + frag.content.len() as u64)
+        .sum()
+}
+
+fn changegroup_part_size(part: &changegroup::Part) -> (u64, usize) {
+    match *part {
+        changegroup::Part::CgChunk(ref section, ref chunk) => {
+            let changesets = match *section {
+                Section::Changeset => 1,
+                _ => 0,
+            };
+            (CG_CHUNK_HEADER_SIZE + delta_size(&chunk.delta), changesets)
+        }
+        changegroup::Part::SectionEnd(_) | changegroup::Part::End => (0, 0),
+    }
+}
+
+fn wirepack_part_size(part: &wirepack::Part) -> (u64, usize) {
+    let bytes = match *part {
+        wirepack::Part::HistoryMeta { ref path, .. } | wirepack::Part::DataMeta { ref path, .. } => {
+            path.len() as u64
+        }
+        wirepack::Part::History(ref entry) => {
+            let copy_from = entry.copy_from.as_ref().map_or(0, |path| path.len());
+            HISTORY_ENTRY_HEADER_SIZE + copy_from as u64
+        }
+        wirepack::Part::Data(ref entry) => DATA_ENTRY_HEADER_SIZE + delta_size(&entry.delta),
+        wirepack::Part::End => 0,
+    };
+    (bytes, 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use context::DeadlineExceeded;
+    use futures::{stream, Future};
+    use futures_ext::BoxFuture;
+    use slog::{Discard, Drain};
+
+    use mercurial_bundles::{PartHeader, PartHeaderBuilder, PartHeaderType};
+    use mercurial_bundles::changegroup::CgDeltaChunk;
+    use mercurial_bundles::wirepack::DataEntry;
+    use mercurial_types::{MPath, RepoPath};
+    use mercurial_types_mocks::nodehash::*;
+
+    fn accounting(limits: PushLimits) -> PushAccounting {
+        accounting_with_deadline(limits, None)
+    }
+
+    fn accounting_with_deadline(limits: PushLimits, deadline: Option<Deadline>) -> PushAccounting {
+        PushAccounting::new(
+            limits,
+            deadline,
+            Logger::root(Discard {}.ignore_res(), o!()),
+            ScubaSampleBuilder::with_discard(),
+        )
+    }
+
+    fn header(part_type: PartHeaderType, part_id: u32) -> PartHeader {
+        PartHeaderBuilder::new(part_type, true)
+            .expect("valid part type")
+            .build(part_id)
+    }
+
+    fn cg_chunk(section: Section, size: usize) -> changegroup::Part {
+        changegroup::Part::CgChunk(
+            section,
+            CgDeltaChunk {
+                node: ONES_HASH,
+                p1: NULL_HASH,
+                p2: NULL_HASH,
+                base: NULL_HASH,
+                linknode: ONES_HASH,
+                delta: Delta::new_fulltext(vec![b'a'; size]),
+                flags: None,
+            },
+        )
+    }
+
+    /// Changegroup part with `changesets` changesets and a file of `file_size` bytes
+    fn changegroup(part_id: u32, changesets: usize, file_size: usize) -> Bundle2Item {
+        let path = MPath::new("file").unwrap();
+        let mut parts: Vec<_> = (0..changesets)
+            .map(|_| cg_chunk(Section::Changeset, 10))
+            .collect();
+        parts.push(changegroup::Part::SectionEnd(Section::Changeset));
+        parts.push(changegroup::Part::SectionEnd(Section::Manifest));
+        parts.push(cg_chunk(Section::Filelog(path.clone()), file_size));
+        parts.push(changegroup::Part::SectionEnd(Section::Filelog(path)));
+        parts.push(changegroup::Part::End);
+
+        Bundle2Item::Changegroup(
+            header(PartHeaderType::Changegroup, part_id),
+            stream::iter_ok(parts).boxify(),
+        )
+    }
+
+    /// Treegroup part with a single tree of `tree_size` bytes
+    fn treegroup(part_id: u32, tree_size: usize) -> Bundle2Item {
+        let parts = vec![
+            wirepack::Part::DataMeta {
+                path: RepoPath::root(),
+                entry_count: 1,
+            },
+            wirepack::Part::Data(DataEntry {
+                node: TWOS_HASH,
+                delta_base: NULL_HASH,
+                delta: Delta::new_fulltext(vec![b'a'; tree_size]),
+            }),
+            wirepack::Part::End,
+        ];
+
+        Bundle2Item::B2xTreegroup2(
+            header(PartHeaderType::B2xTreegroup2, part_id),
+            stream::iter_ok(parts).boxify(),
+        )
+    }
+
+    /// Consumes all the parts of the bundle, like the resolver would
+    fn consume(accounting: &PushAccounting, items: Vec<Bundle2Item>) -> Result<()> {
+        accounting
+            .limit_bundle2(stream::iter_ok(items).boxify())
+            .for_each(|item| -> BoxFuture<(), Error> {
+                match item {
+                    Bundle2Item::Changegroup(_, parts) => parts.for_each(|_| Ok(())).boxify(),
+                    Bundle2Item::B2xTreegroup2(_, parts) => parts.for_each(|_| Ok(())).boxify(),
+                    _ => panic!("unexpected part"),
+                }
+            })
+            .wait()
+    }
+
+    fn limits() -> PushLimits {
+        PushLimits {
+            max_push_bytes: 10_000,
+            max_part_bytes: 6_000,
+            max_changesets: 10,
+            max_commit_message_bytes: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_within_limits() {
+        let accounting = accounting(limits());
+        consume(&accounting, vec![changegroup(1, 10, 1000), treegroup(2, 1000)])
+            .expect("push within limits should be accepted");
+
+        let progress = accounting.progress();
+        assert_eq!(progress.parts, 2);
+        assert_eq!(progress.changesets, 10);
+        assert!(progress.bytes > 2000);
+        assert!(progress.bytes < limits().max_push_bytes);
+    }
+
+    #[test]
+    fn test_part_too_large() {
+        let accounting = accounting(limits());
+        let err = consume(&accounting, vec![changegroup(1, 1, 1000), treegroup(2, 6000)])
+            .expect_err("push with a too large part should be rejected");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::PartTooLarge(2, 6_000)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_push_too_large() {
+        let accounting = accounting(limits());
+        let err = consume(&accounting, vec![changegroup(1, 1, 5000), treegroup(2, 5000)])
+            .expect_err("too large push should be rejected");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::PushTooLarge(10_000)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Rejected while the second part was being read
+        assert_eq!(accounting.progress().parts, 2);
+    }
+
+    #[test]
+    fn test_too_many_changesets() {
+        let accounting = accounting(limits());
+        let err = consume(&accounting, vec![changegroup(1, 11, 10), treegroup(2, 10)])
+            .expect_err("push with too many changesets should be rejected");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::TooManyChangesets(10)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Rejected before the rest of the changegroup and the treegroup were read
+        let progress = accounting.progress();
+        assert_eq!(progress.parts, 1);
+        assert_eq!(progress.changesets, 11);
+    }
+
+    #[test]
+    fn test_deadline_exceeded() {
+        let max = Duration::from_secs(60);
+        let deadline = Deadline::from_hint(Instant::now(), Duration::from_millis(50), max);
+        let accounting = accounting_with_deadline(limits(), Some(deadline));
+
+        // A client that sends its push slowly
+        let slow = match changegroup(1, 10, 10) {
+            Bundle2Item::Changegroup(header, parts) => {
+                let parts = parts.map(|part| {
+                    thread::sleep(Duration::from_millis(20));
+                    part
+                });
+                Bundle2Item::Changegroup(header, parts.boxify())
+            }
+            _ => unreachable!(),
+        };
+        let err = consume(&accounting, vec![slow])
+            .expect_err("push past the deadline should be rejected");
+        match err.downcast::<DeadlineExceeded>() {
+            Ok(DeadlineExceeded(budget)) => assert_eq!(budget, Duration::from_millis(50)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Rejected before the rest of the changegroup was read
+        assert!(accounting.progress().changesets < 10);
+
+        // A deadline that is far enough doesn't get in the way
+        let deadline = Deadline::from_hint(Instant::now(), max, max);
+        let accounting = accounting_with_deadline(limits(), Some(deadline));
+        consume(&accounting, vec![changegroup(1, 10, 1000), treegroup(2, 1000)])
+            .expect("push within the deadline should be accepted");
+    }
+
+    #[test]
+    fn test_commit_message_too_large() {
+        let accounting = accounting(limits());
+        accounting
+            .check_commit_message(ONES_HASH, &[b'a'; 100])
+            .expect("message within the limit should be accepted");
+
+        let err = accounting
+            .check_commit_message(TWOS_HASH, &[b'a'; 101])
+            .expect_err("too large message should be rejected");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::CommitMessageTooLarge(node, 101, 100)) => assert_eq!(node, TWOS_HASH),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
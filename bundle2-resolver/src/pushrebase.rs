@@ -40,6 +40,7 @@
 ///  *rebased set* - subset of pushed set that will be rebased on top of onto bookmark
 ///  Note: Usually rebased set == pushed set. However in case of merges it may differ
 use blobrepo::{save_bonsai_changesets, BlobRepo};
+use blobrepo::storage_attribution::copy_storage_attribution;
 use bonsai_utils::{bonsai_diff, BonsaiDiffResult};
 use bookmarks::Bookmark;
 use errors::*;
@@ -47,7 +48,7 @@ use futures::{Future, IntoFuture, Stream};
 use futures::future::{err, join_all, loop_fn, ok, Loop};
 use futures_ext::{BoxFuture, FutureExt};
 use mercurial_types::{Changeset, HgChangesetId, MPath};
-use metaconfig::PushrebaseParams;
+use metaconfig::{CommitMessageNormalization, PushrebaseDatePolicy, PushrebaseParams};
 use mononoke_types::{check_case_conflicts, BonsaiChangeset, ChangesetId, DateTime, FileChange};
 
 use revset::RangeNodeStream;
@@ -470,50 +471,183 @@ fn create_rebased_changesets(
     head: ChangesetId,
     onto: ChangesetId,
 ) -> impl Future<Item = ChangesetId, Error = PushrebaseError> {
-    find_rebased_set(repo.clone(), root, head.clone()).and_then(move |rebased_set| {
-        let date = if config.rewritedates {
-            Some(DateTime::now())
-        } else {
-            None
-        };
+    let date_policy = config.date_policy;
+    find_rebased_set(repo.clone(), root, head.clone())
+        .and_then({
+            cloned!(repo);
+            move |rebased_set| {
+                fetch_outside_parent_dates(&repo, &rebased_set, root, onto, date_policy)
+                    .map(move |dates| (rebased_set, dates))
+            }
+        })
+        .and_then(move |(rebased_set, mut dates)| {
+            let server_time = DateTime::now();
+
+            // rebased_set already sorted in reverse topological order, which guarantees
+            // that all required nodes will be updated by the time they are needed
+            let mut remapping = hashmap!{ root => onto };
+            let mut rebased = Vec::new();
+            for bcs_old in rebased_set {
+                let id_old = bcs_old.get_changeset_id();
+                let bcs_new = rebased_date(
+                    &bcs_old,
+                    &remapping,
+                    &dates,
+                    date_policy,
+                    server_time,
+                ).and_then(|date| {
+                    rebase_changeset(
+                        bcs_old,
+                        &remapping,
+                        Some(&date),
+                        &config.message_normalization,
+                    )
+                });
+                let bcs_new = match bcs_new {
+                    Ok(bcs_new) => bcs_new,
+                    Err(e) => return err(e.into()).left_future(),
+                };
+                remapping.insert(id_old, bcs_new.get_changeset_id());
+                dates.insert(bcs_new.get_changeset_id(), *bcs_new.author_date());
+                rebased.push(bcs_new);
+            }
 
-        // rebased_set already sorted in reverse topological order, which guarantees
-        // that all required nodes will be updated by the time they are needed
-        let mut remapping = hashmap!{ root => onto };
-        let mut rebased = Vec::new();
-        for bcs_old in rebased_set {
-            let id_old = bcs_old.get_changeset_id();
-            let bcs_new = match rebase_changeset(bcs_old, &remapping, date.as_ref()) {
-                Ok(bcs_new) => bcs_new,
-                Err(e) => return err(e.into()).left_future(),
-            };
-            remapping.insert(id_old, bcs_new.get_changeset_id());
-            rebased.push(bcs_new);
+            // XXX: This can potentially be slow for long stacks. To speed it up we can write
+            // all bonsai changests at once
+            save_bonsai_changesets(rebased, (*repo).clone())
+                .and_then({
+                    cloned!(repo, remapping);
+                    // The originals introduced the contents, so the rebased changesets take over
+                    // their attribution
+                    move |_| {
+                        let copies = remapping
+                            .into_iter()
+                            .filter(|&(id_old, _)| id_old != root)
+                            .map(move |(id_old, id_new)| {
+                                copy_storage_attribution(&repo, id_old, id_new)
+                            });
+                        join_all(copies)
+                    }
+                })
+                .map(move |_| remapping.get(&head).cloned().unwrap_or(head))
+                .from_err()
+                .right_future()
+        })
+}
+
+/// Extra of a rebased changeset with the date that the client wrote, as "<unixtime> <offset>"
+const ORIGINAL_DATE_EXTRA: &str = "pushrebase_original_date";
+/// Extra of a rebased changeset with the `PushrebaseDatePolicy` that chose its date
+const DATE_POLICY_EXTRA: &str = "pushrebase_date_policy";
+
+/// Author date of a rebased changeset, and the policy that chose it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct RebasedDate {
+    date: DateTime,
+    policy: PushrebaseDatePolicy,
+}
+
+/// Dates of the first parents of the rebased set that are not rebased, by their ids after the
+/// rebase, e.g. the date of `onto`. Only `PushrebaseDatePolicy::Clamp` needs them.
+fn fetch_outside_parent_dates(
+    repo: &Arc<BlobRepo>,
+    rebased_set: &[BonsaiChangeset],
+    root: ChangesetId,
+    onto: ChangesetId,
+    policy: PushrebaseDatePolicy,
+) -> impl Future<Item = HashMap<ChangesetId, DateTime>, Error = PushrebaseError> {
+    if policy != PushrebaseDatePolicy::Clamp {
+        return ok(HashMap::new()).left_future();
+    }
+
+    let rebased: HashSet<_> = rebased_set
+        .iter()
+        .map(|bcs| bcs.get_changeset_id())
+        .collect();
+    let outside: HashSet<_> = rebased_set
+        .iter()
+        .filter_map(|bcs| bcs.parents().next().cloned())
+        .filter(|p1| !rebased.contains(p1))
+        .map(|p1| if p1 == root { onto } else { p1 })
+        .collect();
+
+    let dates = outside.into_iter().map({
+        cloned!(repo);
+        move |id| {
+            repo.get_bonsai_changeset(id)
+                .map(move |bcs| (id, *bcs.author_date()))
         }
+    });
+    join_all(dates)
+        .map(|dates| dates.into_iter().collect())
+        .from_err()
+        .right_future()
+}
 
-        // XXX: This can potentially be slow for long stacks. To speed it up we can write
-        // all bonsai changests at once
-        save_bonsai_changesets(rebased, (*repo).clone())
-            .map(move |_| remapping.get(&head).cloned().unwrap_or(head))
-            .from_err()
-            .right_future()
-    })
+/// Chooses the date of the rebased `bcs` with `policy`. `dates` has the dates of its first
+/// parent after the rebase. Clamping only depends on the dates of the changesets, so a replay of
+/// the rebase gives the same dates.
+fn rebased_date(
+    bcs: &BonsaiChangeset,
+    remapping: &HashMap<ChangesetId, ChangesetId>,
+    dates: &HashMap<ChangesetId, DateTime>,
+    policy: PushrebaseDatePolicy,
+    server_time: DateTime,
+) -> Result<RebasedDate> {
+    let original = *bcs.author_date();
+    let date = match policy {
+        PushrebaseDatePolicy::Preserve => original,
+        PushrebaseDatePolicy::ServerTime => server_time,
+        PushrebaseDatePolicy::Clamp => {
+            let parent_date = bcs.parents()
+                .next()
+                .map(|p1| remapping.get(p1).unwrap_or(p1))
+                .and_then(|p1| dates.get(p1));
+            match parent_date {
+                // Keep the timezone of the author
+                Some(parent_date) if parent_date.timestamp_secs() > original.timestamp_secs() => {
+                    DateTime::from_timestamp(
+                        parent_date.timestamp_secs(),
+                        original.tz_offset_secs(),
+                    )?
+                }
+                _ => original,
+            }
+        }
+    };
+    Ok(RebasedDate { date, policy })
 }
 
 fn rebase_changeset(
     bcs: BonsaiChangeset,
     remapping: &HashMap<ChangesetId, ChangesetId>,
-    date: Option<&DateTime>,
+    date: Option<&RebasedDate>,
+    normalization: &CommitMessageNormalization,
 ) -> Result<BonsaiChangeset> {
+    let id = bcs.get_changeset_id();
     let mut bcs = bcs.into_mut();
+    if !normalization.is_empty() {
+        let message = ::std::mem::replace(&mut bcs.message, String::new());
+        bcs.message = normalize_message(id, message, normalization)?;
+    }
     bcs.parents = bcs.parents
         .into_iter()
         .map(|p| remapping.get(&p).cloned().unwrap_or(p))
         .collect();
 
-    match date {
-        Some(date) => bcs.author_date = *date,
-        None => (),
+    if let Some(date) = date {
+        let original = format!(
+            "{} {}",
+            bcs.author_date.timestamp_secs(),
+            bcs.author_date.tz_offset_secs()
+        );
+        bcs.extra
+            .insert(ORIGINAL_DATE_EXTRA.to_string(), original.into_bytes());
+        bcs.extra.insert(
+            DATE_POLICY_EXTRA.to_string(),
+            date.policy.as_str().as_bytes().to_vec(),
+        );
+        bcs.author_date = date.date;
     }
 
     // Copy information in bonsai changeset contains a commit parent. So parent changes, then
@@ -540,6 +674,32 @@ fn rebase_changeset(
     bcs.freeze()
 }
 
+/// Applies the steps of `normalization` that are on to the message of the changeset `id`. A
+/// normalized message is left unchanged, so rebasing it again gives the same hash
+fn normalize_message(
+    id: ChangesetId,
+    message: String,
+    normalization: &CommitMessageNormalization,
+) -> Result<String> {
+    if normalization.reject_nul && message.contains('\0') {
+        return Err(ErrorKind::CommitMessageContainsNul(id).into());
+    }
+
+    let mut message = if normalization.strip_trailing_whitespace {
+        message
+            .split('\n')
+            .map(|line| line.trim_right())
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        message
+    };
+    if normalization.trailing_newline && !message.is_empty() && !message.ends_with('\n') {
+        message.push('\n');
+    }
+    Ok(message)
+}
+
 // Order - from lowest generation number to highest
 fn find_rebased_set(
     repo: Arc<BlobRepo>,
@@ -583,6 +743,7 @@ mod tests {
     use super::*;
     use async_unit;
     use fixtures::{linear, many_files_dirs};
+    use mononoke_types::BonsaiChangesetMut;
     use std::str::FromStr;
     use tests_utils::{create_commit, store_files, store_rename};
 
@@ -599,6 +760,72 @@ mod tests {
         paths.unwrap()
     }
 
+    fn bonsai_with_message(message: &str) -> BonsaiChangeset {
+        BonsaiChangesetMut {
+            parents: vec![],
+            author: "author".to_string(),
+            author_date: DateTime::from_timestamp(0, 0).unwrap(),
+            committer: None,
+            committer_date: None,
+            message: message.to_string(),
+            extra: btreemap!{},
+            file_changes: btreemap!{},
+        }.freeze()
+            .unwrap()
+    }
+
+    #[test]
+    fn pushrebase_message_normalization() {
+        let normalization = CommitMessageNormalization {
+            strip_trailing_whitespace: true,
+            trailing_newline: true,
+            reject_nul: true,
+        };
+        let rebase = |bcs: BonsaiChangeset, normalization: &CommitMessageNormalization| {
+            rebase_changeset(bcs, &HashMap::new(), None, normalization)
+        };
+
+        let messy = bonsai_with_message("title  \n\nbody\t");
+        let clean = bonsai_with_message("title\n\nbody\n");
+
+        let normalized = rebase(messy.clone(), &normalization).unwrap();
+        assert_eq!(normalized.message(), "title\n\nbody\n");
+        assert_eq!(normalized.get_changeset_id(), clean.get_changeset_id());
+        // Normalizing again doesn't change the hash
+        let renormalized = rebase(normalized.clone(), &normalization).unwrap();
+        assert_eq!(renormalized.get_changeset_id(), normalized.get_changeset_id());
+
+        // Nothing changes unless normalization is enabled
+        let untouched = rebase(messy.clone(), &Default::default()).unwrap();
+        assert_eq!(untouched.get_changeset_id(), messy.get_changeset_id());
+    }
+
+    #[test]
+    fn pushrebase_message_with_nul() {
+        let with_nul = bonsai_with_message("title\0\n");
+        let normalization = CommitMessageNormalization {
+            reject_nul: true,
+            ..Default::default()
+        };
+        let err = rebase_changeset(with_nul.clone(), &HashMap::new(), None, &normalization)
+            .expect_err("message with NUL should be rejected");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::CommitMessageContainsNul(id)) => {
+                assert_eq!(id, with_nul.get_changeset_id())
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // NUL bytes are kept if they are not rejected
+        let normalization = CommitMessageNormalization {
+            trailing_newline: true,
+            ..Default::default()
+        };
+        let rebased =
+            rebase_changeset(with_nul, &HashMap::new(), None, &normalization).unwrap();
+        assert_eq!(rebased.message(), "title\0\n");
+    }
+
     #[test]
     fn pushrebase_one_commit() {
         async_unit::tokio_unit_test(|| {
@@ -991,7 +1218,7 @@ mod tests {
                 "a5ffa77602a066db7d5cfb9fb5823a0895717c5a",
             );
             let config = PushrebaseParams {
-                rewritedates: false,
+                date_policy: PushrebaseDatePolicy::Preserve,
                 ..Default::default()
             };
             let bcs_keep_date =
@@ -1005,7 +1232,7 @@ mod tests {
                 "a5ffa77602a066db7d5cfb9fb5823a0895717c5a",
             );
             let config = PushrebaseParams {
-                rewritedates: true,
+                date_policy: PushrebaseDatePolicy::ServerTime,
                 ..Default::default()
             };
             let bcs_rewrite_date = do_pushrebase(Arc::new(repo.clone()), config, book, hgcss)
@@ -1022,6 +1249,165 @@ mod tests {
 
             assert_eq!(bcs.author_date(), bcs_keep_date.author_date());
             assert!(bcs.author_date() < bcs_rewrite_date.author_date());
+
+            // Both record the original date
+            let extra = |bcs: &BonsaiChangeset| -> Vec<(String, String)> {
+                bcs.extra()
+                    .map(|(key, value)| (key.to_string(), String::from_utf8_lossy(value).into()))
+                    .collect()
+            };
+            assert_eq!(
+                extra(&bcs_keep_date),
+                vec![
+                    ("pushrebase_date_policy".to_string(), "preserve".to_string()),
+                    ("pushrebase_original_date".to_string(), "0 0".to_string()),
+                ]
+            );
+            assert_eq!(
+                extra(&bcs_rewrite_date),
+                vec![
+                    ("pushrebase_date_policy".to_string(), "server_time".to_string()),
+                    ("pushrebase_original_date".to_string(), "0 0".to_string()),
+                ]
+            );
+        })
+    }
+
+    fn create_commit_with_date(
+        repo: &BlobRepo,
+        parents: Vec<ChangesetId>,
+        file: &str,
+        date: DateTime,
+    ) -> ChangesetId {
+        let bcs = BonsaiChangesetMut {
+            parents,
+            author: "author".to_string(),
+            author_date: date,
+            committer: None,
+            committer_date: None,
+            message: "message".to_string(),
+            extra: btreemap!{},
+            file_changes: store_files(btreemap!{file => Some("content")}, repo.clone()),
+        }.freeze()
+            .unwrap();
+        let bcs_id = bcs.get_changeset_id();
+        save_bonsai_changesets(vec![bcs], repo.clone())
+            .wait()
+            .unwrap();
+        bcs_id
+    }
+
+    #[test]
+    fn pushrebase_rebased_date() {
+        let date = |secs| DateTime::from_timestamp(secs, 3600).unwrap();
+        let parent = bonsai_with_message("parent").get_changeset_id();
+        let bcs = BonsaiChangesetMut {
+            parents: vec![parent],
+            author_date: date(100),
+            ..bonsai_with_message("child").into_mut()
+        }.freeze()
+            .unwrap();
+        let server_time = DateTime::from_timestamp(500, 0).unwrap();
+        let choose = |parent_secs, policy| {
+            let dates = hashmap!{ parent => date(parent_secs) };
+            rebased_date(&bcs, &HashMap::new(), &dates, policy, server_time)
+                .unwrap()
+                .date
+        };
+
+        assert_eq!(choose(200, PushrebaseDatePolicy::Preserve), date(100));
+        assert_eq!(choose(200, PushrebaseDatePolicy::ServerTime), server_time);
+        assert_eq!(choose(200, PushrebaseDatePolicy::Clamp), date(200));
+        assert_eq!(choose(50, PushrebaseDatePolicy::Clamp), date(100));
+
+        let rebased = rebase_changeset(
+            bcs.clone(),
+            &HashMap::new(),
+            Some(&RebasedDate {
+                date: date(200),
+                policy: PushrebaseDatePolicy::Clamp,
+            }),
+            &Default::default(),
+        ).unwrap();
+        assert_eq!(*rebased.author_date(), date(200));
+        let extra: Vec<_> = rebased.extra().collect();
+        assert_eq!(
+            extra,
+            vec![
+                ("pushrebase_date_policy", &b"clamp"[..]),
+                ("pushrebase_original_date", &b"100 3600"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn pushrebase_clamp_dates() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let root = repo.get_bonsai_from_hg(&HgChangesetId::from_str(
+                "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536",
+            ).unwrap())
+                .wait()
+                .unwrap()
+                .unwrap();
+            let book = Bookmark::new("master").unwrap();
+            let onto = "a5ffa77602a066db7d5cfb9fb5823a0895717c5a";
+            set_bookmark(repo.clone(), &book, onto);
+            let onto = repo.get_bonsai_from_hg(&HgChangesetId::from_str(onto).unwrap())
+                .wait()
+                .unwrap()
+                .unwrap();
+            let onto_secs = repo.get_bonsai_changeset(onto)
+                .wait()
+                .unwrap()
+                .author_date()
+                .timestamp_secs();
+            let date = |secs| DateTime::from_timestamp(secs, 0).unwrap();
+
+            // The client wrote a date later than onto on the middle commit only
+            let bcs1 = create_commit_with_date(&repo, vec![root], "file1", date(0));
+            let bcs2 = create_commit_with_date(&repo, vec![bcs1], "file2", date(onto_secs + 1000));
+            let bcs3 = create_commit_with_date(&repo, vec![bcs2], "file3", date(5));
+            let hgcss: Vec<_> = vec![bcs1, bcs2, bcs3]
+                .into_iter()
+                .map(|bcs| repo.get_hg_from_bonsai_changeset(bcs).wait().unwrap())
+                .collect();
+
+            let config = PushrebaseParams {
+                date_policy: PushrebaseDatePolicy::Clamp,
+                ..Default::default()
+            };
+            let repo_arc = Arc::new(repo.clone());
+            let head = do_pushrebase(repo_arc.clone(), config.clone(), book.clone(), hgcss.clone())
+                .wait()
+                .expect("pushrebase failed")
+                .head;
+
+            // Walk the first parents down to onto
+            let mut dates = Vec::new();
+            let mut id = head;
+            while id != onto {
+                let bcs = repo.get_bonsai_changeset(id).wait().unwrap();
+                dates.push(bcs.author_date().timestamp_secs());
+                id = *bcs.parents().next().unwrap();
+            }
+            dates.reverse();
+            assert_eq!(dates, vec![onto_secs, onto_secs + 1000, onto_secs + 1000]);
+
+            let bcs3_rebased = repo.get_bonsai_changeset(head).wait().unwrap();
+            let original_date: Vec<_> = bcs3_rebased
+                .extra()
+                .filter(|(key, _)| *key == "pushrebase_original_date")
+                .collect();
+            assert_eq!(original_date, vec![("pushrebase_original_date", &b"5 0"[..])]);
+
+            // Replaying the pushrebase gives the same commits
+            set_bookmark(repo.clone(), &book, "a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+            let replayed_head = do_pushrebase(repo_arc, config, book, hgcss)
+                .wait()
+                .expect("pushrebase failed")
+                .head;
+            assert_eq!(replayed_head, head);
         })
     }
 
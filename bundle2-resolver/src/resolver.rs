@@ -4,6 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp;
 use std::collections::HashMap;
 use std::io::Cursor;
 use std::ops::AddAssign;
@@ -12,29 +13,44 @@ use std::sync::Arc;
 use ascii::AsciiString;
 use blobrepo::{BlobRepo, ChangesetHandle, ChangesetMetadata, ContentBlobInfo, CreateChangeset,
                HgBlobEntry};
-use bookmarks::{Bookmark, Transaction};
+use bookmarks::Bookmark;
 use bytes::{Bytes, BytesMut};
+use context::Deadline;
+use cross_repo_index::CrossRepoIndex;
+use derived_data::DerivationQueue;
 use failure::{err_msg, Compat, FutureFailureErrorExt, StreamFailureErrorExt};
 use futures::{Future, IntoFuture, Stream};
-use futures::future::{self, err, ok, Shared};
+use futures::future::{self, err, ok, Loop, Shared};
 use futures::stream;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use futures_stats::Timed;
 use getbundle_response;
 use mercurial::changeset::RevlogChangeset;
 use mercurial::manifest::{Details, ManifestContent};
-use mercurial_bundles::{create_bundle_stream, parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{HgChangesetId, HgManifestId, HgNodeHash, HgNodeKey, MPath, RepoPath,
-                      NULL_HASH};
-use metaconfig::PushrebaseParams;
-use mononoke_types::ChangesetId;
+use mercurial_bundles::{create_bundle_stream, parts, Bundle2EncodeBuilder, Bundle2Item,
+                        Capabilities};
+use mercurial_types::{HgChangesetId, HgManifestId, HgNodeHash, HgNodeKey, MPath, Manifest,
+                      RepoPath, NULL_HASH};
+use metaconfig::{BookmarkCreationPolicy, HookDegradedPolicy, PathRules, PushLimits,
+                 PushrebaseParams};
+use mononoke_types::{ChangesetId, DateTime};
+use path_validation::{check_paths, format_violations};
+use push_advisory::{PushAdvisory, PUSH_ADVISORY_CAPABILITY};
+use push_journal::{PushJournal, PushJournalEntry};
+use push_limits::PushAccounting;
 use pushrebase;
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use slog::Logger;
 use stats::*;
 
+use bookmark_creation::check_bookmark_creation;
+use bookmark_push::{bookmark_commit_retry_policy, move_bookmarks, BonsaiBookmarkPush,
+                    BookmarkOutcome, BookmarkPushReport};
 use changegroup::{convert_to_revlog_changesets, convert_to_revlog_filelog, split_changegroup};
 use errors::*;
+use hook_rejections::{format_rejections, format_rejections_by_bookmark, warnings_part,
+                      HookRejection};
+use manifest_fanout::{check_fanout, format_fanouts, max_fanout, PushedDirectory};
 use hooks::{ChangesetHookExecutionID, FileHookExecutionID, HookExecution, HookManager};
 use upload_blobs::{upload_hg_blobs, UploadBlobsType, UploadableHgBlob};
 use wirepackparser::{TreemanifestBundle2Parser, TreemanifestEntry};
@@ -49,51 +65,109 @@ type UploadedChangesets = HashMap<HgNodeHash, ChangesetHandle>;
 /// The resolve function takes a bundle2, interprets it's content as Changesets, Filelogs and
 /// Manifests and uploades all of them to the provided BlobRepo in the correct order.
 /// It returns a Future that contains the response that should be send back to the requester.
+/// The push is rejected as soon as the received payload exceeds one of the `push_limits`, and
+/// before any changeset is created if it adds or modifies a file whose path breaks `path_rules`
+/// or if the message of one of its changesets is too large.
+/// If there is a `push_journal`, a push that uploads blobs is recorded in it under `session_id`
+/// before the first upload, and marked complete once its bookmarks are moved.
+/// A push that creates a bookmark that `user` can't create according to `bookmark_creation` is
+/// rejected before anything is uploaded.
+/// If there is a `push_advisory`, a successful push that landed commits gets a message about them
+/// in its response.
+/// If there is a `cross_repo_index`, the changesets that the push creates are recorded in it.
+/// If there is a `derivation_queue`, the changesets that the push lands on a bookmark are queued
+/// in it.
 pub fn resolve(
     repo: Arc<BlobRepo>,
     logger: Logger,
     scuba_logger: ScubaSampleBuilder,
     pushrebase: PushrebaseParams,
+    push_limits: PushLimits,
+    path_rules: PathRules,
+    bookmark_creation: BookmarkCreationPolicy,
+    push_journal: Option<Arc<PushJournal>>,
+    push_advisory: Option<PushAdvisory>,
+    cross_repo_index: Option<Arc<CrossRepoIndex>>,
+    derivation_queue: Option<DerivationQueue>,
+    session_id: String,
+    user: Option<String>,
+    deadline: Option<Deadline>,
     _heads: Vec<String>,
     bundle2: BoxStream<Bundle2Item, Error>,
     hook_manager: Arc<HookManager>,
 ) -> BoxFuture<Bytes, Error> {
-    let resolver = Bundle2Resolver::new(repo, logger, scuba_logger, pushrebase, hook_manager);
-
-    let bundle2 = resolver.resolve_start_and_replycaps(bundle2);
+    let resolver = Bundle2Resolver::new(
+        repo,
+        logger,
+        scuba_logger,
+        pushrebase,
+        push_limits,
+        path_rules,
+        bookmark_creation,
+        push_journal,
+        push_advisory,
+        cross_repo_index,
+        derivation_queue,
+        session_id,
+        user,
+        deadline,
+        hook_manager,
+    );
+
+    let bundle2 = resolver.accounting.limit_bundle2(bundle2);
 
     resolver
-        .maybe_resolve_commonheads(bundle2)
-        .and_then(move |(commonheads, bundle2)| match commonheads {
-            Some(commonheads) => resolve_pushrebase(commonheads, resolver, bundle2),
-            None => resolve_push(resolver, bundle2),
+        .resolve_start_and_replycaps(bundle2)
+        .and_then({
+            cloned!(resolver);
+            move |(replycaps, bundle2)| {
+                let structured_advisory = replycaps.contains(PUSH_ADVISORY_CAPABILITY);
+                resolver
+                    .maybe_resolve_commonheads(bundle2)
+                    .map(move |(commonheads, bundle2)| {
+                        (commonheads, bundle2, structured_advisory)
+                    })
+            }
         })
+        .and_then(
+            move |(commonheads, bundle2, structured_advisory)| match commonheads {
+                Some(commonheads) => {
+                    resolve_pushrebase(commonheads, resolver, bundle2, structured_advisory)
+                }
+                None => resolve_push(resolver, bundle2, structured_advisory),
+            },
+        )
         .boxify()
 }
 
 fn resolve_push(
     resolver: Bundle2Resolver,
     bundle2: BoxStream<Bundle2Item, Error>,
+    structured_advisory: bool,
 ) -> BoxFuture<Bytes, Error> {
     resolver
-        .maybe_resolve_changegroup(bundle2)
+        .maybe_resolve_changegroup(bundle2, true)
         .and_then({
             let resolver = resolver.clone();
             move |(cg_push, bundle2)| {
                 resolver
                     .resolve_multiple_parts(bundle2, Bundle2Resolver::maybe_resolve_pushkey)
-                    .map(move |(pushkeys, bundle2)| {
-                        let bookmark_push: Vec<_> = pushkeys
-                            .into_iter()
-                            .filter_map(|pushkey| match pushkey {
-                                Pushkey::Phases => None,
-                                Pushkey::BookmarkPush(bp) => Some(bp),
-                            })
-                            .collect();
+                    .and_then({
+                        let resolver = resolver.clone();
+                        move |(pushkeys, bundle2)| {
+                            let bookmark_push: Vec<_> = pushkeys
+                                .into_iter()
+                                .filter_map(|pushkey| match pushkey {
+                                    Pushkey::Phases => None,
+                                    Pushkey::BookmarkPush(bp) => Some(bp),
+                                })
+                                .collect();
 
-                        STATS::bookmark_pushkeys_count.add_value(bookmark_push.len() as i64);
+                            STATS::bookmark_pushkeys_count.add_value(bookmark_push.len() as i64);
 
-                        (cg_push, bookmark_push, bundle2)
+                            resolver.check_bookmark_creations(&bookmark_push, false)?;
+                            Ok((cg_push, bookmark_push, bundle2))
+                        }
                     })
             }
         })
@@ -116,10 +190,11 @@ fn resolve_push(
             let resolver = resolver.clone();
             move |(cg_and_manifests, bookmark_push, bundle2)| {
                 if let Some((cg_push, manifests)) = cg_and_manifests {
-                    let changegroup_id = Some(cg_push.part_id);
+                    let landed: Vec<_> = cg_push.changesets.iter().map(|(node, _)| *node).collect();
+                    let changegroup = Some((cg_push.part_id, landed));
                     resolver
                         .upload_changesets(cg_push, manifests)
-                        .map(move |()| (changegroup_id, bookmark_push, bundle2))
+                        .map(move |()| (changegroup, bookmark_push, bundle2))
                         .boxify()
                 } else {
                     ok((None, bookmark_push, bundle2)).boxify()
@@ -128,58 +203,119 @@ fn resolve_push(
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(changegroup_id, bookmark_push, bundle2)| {
+            move |(changegroup, bookmark_push, bundle2)| {
                 resolver
                     .maybe_resolve_infinitepush_bookmarks(bundle2)
-                    .map(move |((), bundle2)| (changegroup_id, bookmark_push, bundle2))
+                    .map(move |((), bundle2)| (changegroup, bookmark_push, bundle2))
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(changegroup_id, bookmark_push, bundle2)| {
+            move |(changegroup, bookmark_push, bundle2)| {
                 resolver
                     .ensure_stream_finished(bundle2)
-                    .map(move |()| (changegroup_id, bookmark_push))
+                    .map(move |()| (changegroup, bookmark_push))
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(changegroup_id, bookmarks_push)| {
+            move |(changegroup, bookmark_push)| {
+                // The pushed changesets are checked by the hooks of the bookmarks that the push
+                // moves, e.g. not by the hooks of master if it only creates a scratch bookmark
+                let bookmarks: Vec<_> = bookmark_push
+                    .iter()
+                    .filter(|bp| bp.new.is_some())
+                    .map(|bp| bp.name.clone())
+                    .collect();
+                let hooks = match changegroup {
+                    Some((_, ref landed)) if !bookmarks.is_empty() => {
+                        let changesets = landed.iter().cloned().map(HgChangesetId::new).collect();
+                        resolver
+                            .run_hooks(changesets, None, &bookmarks)
+                            .map_err({
+                                cloned!(resolver);
+                                move |err| resolver.run_hooks_error(err, &bookmarks)
+                            })
+                            .boxify()
+                    }
+                    _ => ok(vec![]).boxify(),
+                };
+                hooks.map(move |warnings| (changegroup, bookmark_push, warnings))
+            }
+        })
+        .and_then({
+            let resolver = resolver.clone();
+            move |(changegroup, bookmarks_push, warnings)| {
                 let bookmarks_push_fut = bookmarks_push
                     .into_iter()
                     .map(|bp| BonsaiBookmarkPush::new(&resolver.repo, bp))
                     .collect::<Vec<_>>();
                 future::join_all(bookmarks_push_fut)
-                    .map(move |bookmakrs_push| (changegroup_id, bookmakrs_push))
+                    .map(move |bookmakrs_push| (changegroup, bookmakrs_push, warnings))
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(changegroup_id, bookmark_push)| {
-                (move || {
-                    let bookmark_ids: Vec<_> = bookmark_push.iter().map(|bp| bp.part_id).collect();
-
-                    let mut txn = resolver.repo.update_bookmark_transaction();
-                    for bp in bookmark_push {
-                        try_boxfuture!(add_bookmark_to_transaction(&mut txn, bp));
-                    }
-                    txn.commit()
-                        .and_then(|ok| {
-                            if ok {
-                                Ok(())
-                            } else {
-                                Err(format_err!("Bookmark transaction failed"))
-                            }
+            move |(changegroup, bookmark_push, warnings)| {
+                let not_deleted: Vec<_> = bookmark_push
+                    .iter()
+                    .map(|bp| bp.new.is_some())
+                    .collect();
+                move_bookmarks(
+                    resolver.repo.get_bookmarks_object(),
+                    resolver.repo.get_repoid(),
+                    bookmark_push,
+                    bookmark_commit_retry_policy(),
+                ).map(move |report| {
+                    let moved = report
+                        .outcomes
+                        .iter()
+                        .zip(not_deleted)
+                        .filter(|((_, _, outcome), not_deleted)| {
+                            *not_deleted && *outcome == BookmarkOutcome::Moved
                         })
-                        .map(move |()| (changegroup_id, bookmark_ids))
-                        .boxify()
-                })()
+                        .map(|((_, name, _), _)| name.to_string())
+                        .collect::<Vec<_>>();
+                    (changegroup, report, moved, warnings)
+                })
                     .context("While updating Bookmarks")
                     .from_err()
             }
         })
-        .and_then(move |(changegroup_id, bookmark_ids)| {
-            resolver.prepare_push_response(changegroup_id, bookmark_ids)
+        .and_then({
+            let resolver = resolver.clone();
+            move |(changegroup, report, moved, warnings)| {
+                // Only pushes with a changegroup upload blobs, and are journaled
+                let complete = if changegroup.is_some() {
+                    resolver.complete_push_journal()
+                } else {
+                    ok(()).boxify()
+                };
+                complete.map(move |()| (changegroup, report, moved, warnings))
+            }
+        })
+        .and_then({
+            let resolver = resolver.clone();
+            move |(changegroup, report, moved, warnings)| {
+                // Changesets that didn't land on a bookmark are derived on their first read
+                let queued = match changegroup {
+                    Some((_, ref landed)) if !moved.is_empty() => {
+                        let landed = landed.iter().cloned().map(HgChangesetId::new).collect();
+                        resolver.queue_derivations(landed)
+                    }
+                    _ => ok(()).boxify(),
+                };
+                queued.map(move |()| (changegroup, report, moved, warnings))
+            }
+        })
+        .and_then(move |(changegroup, report, moved, warnings)| {
+            resolver.prepare_push_response(
+                changegroup,
+                report,
+                moved,
+                warnings,
+                structured_advisory,
+            )
         })
         .context("bundle2-resolver error")
         .from_err()
@@ -190,9 +326,20 @@ fn resolve_pushrebase(
     commonheads: CommonHeads,
     resolver: Bundle2Resolver,
     bundle2: BoxStream<Bundle2Item, Error>,
+    structured_advisory: bool,
 ) -> BoxFuture<Bytes, Error> {
     resolver
         .maybe_resolve_pushvars(bundle2)
+        .and_then({
+            cloned!(resolver);
+            // The trees are uploaded before the changegroup is parsed, so the changesets are not
+            // known yet
+            move |(maybe_pushvars, bundle2)| {
+                resolver
+                    .start_push_journal(&[])
+                    .map(move |()| (maybe_pushvars, bundle2))
+            }
+        })
         .and_then({
             cloned!(resolver);
             move |(maybe_pushvars, bundle2)| resolver.resolve_b2xtreegroup2(bundle2)
@@ -202,7 +349,7 @@ fn resolve_pushrebase(
             cloned!(resolver);
             move |(manifests, maybe_pushvars, bundle2)| {
                 resolver
-                    .maybe_resolve_changegroup(bundle2)
+                    .maybe_resolve_changegroup(bundle2, false)
                     .map(move |(cg_push, bundle2)| (cg_push, manifests, maybe_pushvars, bundle2))
             }
         })
@@ -249,9 +396,13 @@ fn resolve_pushrebase(
                                 })
                                 .collect();
 
+                            try_boxfuture!(
+                                resolver.check_bookmark_creations(&bookmark_pushes, true)
+                            );
                             resolver
                                 .ensure_stream_finished(bundle2)
                                 .map(move |()| (changesets, bookmark_pushes, maybe_pushvars, onto))
+                                .boxify()
                         }
                     })
             }
@@ -259,46 +410,78 @@ fn resolve_pushrebase(
         .and_then({
             cloned!(resolver);
             move |(changesets, bookmark_pushes, maybe_pushvars, onto)| {
+                let total = changesets.len();
+                let hg_changesets = changesets
+                    .iter()
+                    .map(|(node, _)| HgChangesetId::new(*node))
+                    .collect();
+                let bookmarks = vec![onto.clone()];
                 resolver
-                    .run_hooks(changesets.clone(), maybe_pushvars, &onto)
-                    .map_err(|err| {
-                        match err {
-                            RunHooksError::Failures((cs_hook_failures, file_hook_failures)) => {
-                                let mut err_msgs = vec![];
-                                for (exec_id, exec_info) in cs_hook_failures {
-                                    if let HookExecution::Rejected(info) = exec_info {
-                                        err_msgs.push(format!("{}: {}", exec_id.hook_name, info.description));
-                                    }
-                                }
-                                for (exec_id, exec_info) in file_hook_failures {
-                                    if let HookExecution::Rejected(info) = exec_info {
-                                        err_msgs.push(format!("{}: {}", exec_id.hook_name, info.description));
-                                    }
-                                }
-                                err_msg(format!("hooks failed:\n{}", err_msgs.join("\n")))
-                            }
-                            RunHooksError::Error(err) => {
-                                err
-                            }
+                    .run_hooks(hg_changesets, maybe_pushvars, &bookmarks)
+                    .map_err({
+                        cloned!(resolver);
+                        move |err| resolver.run_hooks_error(err, &bookmarks)
+                    })
+                    .and_then({
+                        cloned!(resolver);
+                        move |warnings| {
+                            resolver
+                                .pushrebase(changesets.clone(), bookmark_pushes, &onto)
+                                .map(move |pushrebased_rev| (pushrebased_rev, onto, warnings))
+                        }
+                    })
+                    .and_then({
+                        cloned!(resolver);
+                        move |(pushrebased_rev, onto, warnings)| {
+                            resolver
+                                .record_pushrebased(pushrebased_rev, total)
+                                .map(move |()| (pushrebased_rev, onto, warnings))
                         }
-
                     })
-                    .and_then(move |()| {
+                    .and_then(move |(pushrebased_rev, onto, warnings)| {
                         resolver
-                            .pushrebase(changesets.clone(), bookmark_pushes, &onto)
-                            .map(|pushrebased_rev| (pushrebased_rev, onto))
+                            .complete_push_journal()
+                            .map(move |()| (pushrebased_rev, onto, total, warnings))
                     })
             }
         })
         .and_then({
             cloned!(resolver);
-            move |(pushrebased_rev, onto)| {
-                resolver.prepare_pushrebase_response(commonheads, pushrebased_rev, onto)
+            move |(pushrebased_rev, onto, total, warnings)| {
+                resolver.prepare_pushrebase_response(
+                    commonheads,
+                    pushrebased_rev,
+                    onto,
+                    total,
+                    warnings,
+                    structured_advisory,
+                )
             }
         })
         .boxify()
 }
 
+/// Finds the last `count` commits that landed as `head` and its first-parent ancestors, in the
+/// order they landed
+fn find_landed(
+    repo: BlobRepo,
+    head: HgChangesetId,
+    count: usize,
+) -> BoxFuture<Vec<HgNodeHash>, Error> {
+    future::loop_fn((Some(head), Vec::new()), move |(next, mut landed)| match next {
+        Some(cs) if landed.len() < count => {
+            landed.push(cs.into_nodehash());
+            repo.get_changeset_parents(&cs)
+                .map(move |parents| Loop::Continue((parents.into_iter().next(), landed)))
+                .boxify()
+        }
+        _ => {
+            landed.reverse();
+            ok(Loop::Break(landed)).boxify()
+        }
+    }).boxify()
+}
+
 fn next_item(
     bundle2: BoxStream<Bundle2Item, Error>,
 ) -> BoxFuture<(Option<Bundle2Item>, BoxStream<Bundle2Item, Error>), Error> {
@@ -330,13 +513,6 @@ struct BookmarkPush {
     new: Option<HgChangesetId>,
 }
 
-struct BonsaiBookmarkPush {
-    part_id: PartId,
-    name: Bookmark,
-    old: Option<ChangesetId>,
-    new: Option<ChangesetId>,
-}
-
 impl BonsaiBookmarkPush {
     fn new(
         repo: &Arc<BlobRepo>,
@@ -377,6 +553,15 @@ struct Bundle2Resolver {
     logger: Logger,
     scuba_logger: ScubaSampleBuilder,
     pushrebase: PushrebaseParams,
+    accounting: PushAccounting,
+    path_rules: Arc<PathRules>,
+    bookmark_creation: Arc<BookmarkCreationPolicy>,
+    push_journal: Option<Arc<PushJournal>>,
+    push_advisory: Option<Arc<PushAdvisory>>,
+    cross_repo_index: Option<Arc<CrossRepoIndex>>,
+    derivation_queue: Option<DerivationQueue>,
+    session_id: String,
+    user: Option<String>,
     hook_manager: Arc<HookManager>,
 }
 
@@ -386,32 +571,189 @@ impl Bundle2Resolver {
         logger: Logger,
         scuba_logger: ScubaSampleBuilder,
         pushrebase: PushrebaseParams,
+        push_limits: PushLimits,
+        path_rules: PathRules,
+        bookmark_creation: BookmarkCreationPolicy,
+        push_journal: Option<Arc<PushJournal>>,
+        push_advisory: Option<PushAdvisory>,
+        cross_repo_index: Option<Arc<CrossRepoIndex>>,
+        derivation_queue: Option<DerivationQueue>,
+        session_id: String,
+        user: Option<String>,
+        deadline: Option<Deadline>,
         hook_manager: Arc<HookManager>,
     ) -> Self {
+        let accounting = PushAccounting::new(
+            push_limits,
+            deadline,
+            logger.clone(),
+            scuba_logger.clone(),
+        );
         Self {
             repo,
             logger,
             scuba_logger,
             pushrebase,
+            accounting,
+            path_rules: Arc::new(path_rules),
+            bookmark_creation: Arc::new(bookmark_creation),
+            push_journal,
+            push_advisory: push_advisory.map(Arc::new),
+            cross_repo_index,
+            derivation_queue,
+            session_id,
+            user,
             hook_manager,
         }
     }
 
-    /// Parse Start and Replycaps and ignore their content
+    /// Records in the push journal that the push is about to upload blobs. A push that can't be
+    /// journaled is rejected, as nothing was uploaded yet.
+    fn start_push_journal(&self, changesets: &[HgChangesetId]) -> BoxFuture<(), Error> {
+        match self.push_journal {
+            Some(ref push_journal) => {
+                let entry = PushJournalEntry::new(
+                    self.repo.get_repoid(),
+                    self.session_id.clone(),
+                    DateTime::now().timestamp_secs(),
+                    changesets,
+                );
+                push_journal
+                    .add(entry)
+                    .context("While adding the push to the push journal")
+                    .from_err()
+                    .boxify()
+            }
+            None => ok(()).boxify(),
+        }
+    }
+
+    /// Marks the entry of the push complete. The bookmarks have already moved at this point, so
+    /// a failure is only logged: the entry stays incomplete, and shows up for investigation.
+    fn complete_push_journal(&self) -> BoxFuture<(), Error> {
+        match self.push_journal {
+            Some(ref push_journal) => {
+                let logger = self.logger.clone();
+                let session_id = self.session_id.clone();
+                push_journal
+                    .complete(
+                        self.repo.get_repoid(),
+                        session_id.clone(),
+                        DateTime::now().timestamp_secs(),
+                    )
+                    .then(move |res| {
+                        match res {
+                            Ok(true) => {}
+                            Ok(false) => {
+                                warn!(logger, "push journal has no entry for {}", session_id)
+                            }
+                            Err(err) => warn!(
+                                logger,
+                                "failed to complete push journal entry for {}: {}",
+                                session_id,
+                                err
+                            ),
+                        }
+                        Ok(())
+                    })
+                    .boxify()
+            }
+            None => ok(()).boxify(),
+        }
+    }
+
+    /// Records `changesets` in the cross-repo index. They were already created at this point, so
+    /// a failure is only logged: they are missing from the index until the repo is backfilled.
+    fn record_in_cross_repo_index<F>(&self, changesets: F) -> BoxFuture<(), Error>
+    where
+        F: Future<Item = Vec<HgChangesetId>, Error = Error> + Send + 'static,
+    {
+        match self.cross_repo_index {
+            Some(ref cross_repo_index) => {
+                let logger = self.logger.clone();
+                let repo_id = self.repo.get_repoid();
+                cloned!(cross_repo_index);
+                changesets
+                    .and_then(move |changesets| cross_repo_index.add(repo_id, changesets))
+                    .then(move |res| {
+                        if let Err(err) = res {
+                            warn!(
+                                logger,
+                                "failed to record changesets in the cross-repo index: {}", err
+                            );
+                        }
+                        Ok(())
+                    })
+                    .boxify()
+            }
+            None => ok(()).boxify(),
+        }
+    }
+
+    /// Queues the data derived from `changesets`, which landed on a bookmark. They already
+    /// landed at this point, so a failure is only logged: their data is derived on its first read.
+    fn queue_derivations(&self, changesets: Vec<HgChangesetId>) -> BoxFuture<(), Error> {
+        match self.derivation_queue {
+            Some(ref derivation_queue) => {
+                let logger = self.logger.clone();
+                derivation_queue
+                    .enqueue(changesets)
+                    .then(move |res| {
+                        if let Err(err) = res {
+                            warn!(logger, "failed to queue changesets for derivation: {}", err);
+                        }
+                        Ok(())
+                    })
+                    .boxify()
+            }
+            None => ok(()).boxify(),
+        }
+    }
+
+    /// Records in the cross-repo index, and queues for derivation, the `total` changesets that
+    /// pushrebase created, which are `head` and its first-parent ancestors
+    fn record_pushrebased(&self, head: ChangesetId, total: usize) -> BoxFuture<(), Error> {
+        if self.cross_repo_index.is_none() && self.derivation_queue.is_none() {
+            return ok(()).boxify();
+        }
+        let repo: BlobRepo = (*self.repo).clone();
+        let resolver = self.clone();
+        repo.get_hg_from_bonsai_changeset(head)
+            .and_then(move |head| find_landed(repo, head, total))
+            .then(move |res| match res {
+                Ok(landed) => {
+                    let rebased: Vec<_> = landed.into_iter().map(HgChangesetId::new).collect();
+                    resolver
+                        .record_in_cross_repo_index(ok(rebased.clone()))
+                        .join(resolver.queue_derivations(rebased))
+                        .map(|((), ())| ())
+                        .left_future()
+                }
+                Err(err) => {
+                    warn!(
+                        resolver.logger,
+                        "failed to find the changesets that pushrebase created: {}", err
+                    );
+                    ok(()).right_future()
+                }
+            })
+            .boxify()
+    }
+
+    /// Parse Start and Replycaps, and return the capabilities the client sent in Replycaps
     fn resolve_start_and_replycaps(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
-    ) -> BoxStream<Bundle2Item, Error> {
+    ) -> BoxFuture<(Capabilities, BoxStream<Bundle2Item, Error>), Error> {
         next_item(bundle2)
             .and_then(|(start, bundle2)| match start {
                 Some(Bundle2Item::Start(_)) => next_item(bundle2),
                 _ => err(format_err!("Expected Bundle2 Start")).boxify(),
             })
             .and_then(|(replycaps, bundle2)| match replycaps {
-                Some(Bundle2Item::Replycaps(_, part)) => part.map(|_| bundle2).boxify(),
+                Some(Bundle2Item::Replycaps(_, part)) => part.map(|caps| (caps, bundle2)).boxify(),
                 _ => err(format_err!("Expected Bundle2 Replycaps")).boxify(),
             })
-            .flatten_stream()
             .boxify()
     }
 
@@ -469,10 +811,14 @@ impl Bundle2Resolver {
     /// The Changesets should be parsed as RevlogChangesets and used for uploading changesets
     /// The Filelogs should be scheduled for uploading to BlobRepo and the Future resolving in
     /// their upload should be used for uploading changesets
+    /// With `journal` the push is added to the push journal, with its changesets, before the first
+    /// filelog is uploaded.
     fn maybe_resolve_changegroup(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
+        journal: bool,
     ) -> BoxFuture<(Option<ChangegroupPush>, BoxStream<Bundle2Item, Error>), Error> {
+        let resolver = self.clone();
         let repo = self.repo.clone();
 
         next_item(bundle2)
@@ -486,6 +832,18 @@ impl Bundle2Resolver {
                     let (c, f) = split_changegroup(parts);
                     convert_to_revlog_changesets(c)
                         .collect()
+                        .and_then(move |changesets: Changesets| {
+                            let started = if journal {
+                                let ids: Vec<_> = changesets
+                                    .iter()
+                                    .map(|(node, _)| HgChangesetId::new(*node))
+                                    .collect();
+                                resolver.start_push_journal(&ids)
+                            } else {
+                                ok(()).boxify()
+                            };
+                            started.map(move |()| changesets)
+                        })
                         .and_then(|changesets| {
                             upload_hg_blobs(
                                 repo.clone(),
@@ -545,7 +903,7 @@ impl Bundle2Resolver {
                             let part_id = header.part_id();
                             let mparams = header.mparams();
                             let name = try_boxfuture!(get_ascii_param(mparams, "key"));
-                            let name = Bookmark::new_ascii(name);
+                            let name = try_boxfuture!(Bookmark::new_ascii(name));
                             let old = try_boxfuture!(get_optional_changeset_param(mparams, "old"));
                             let new = try_boxfuture!(get_optional_changeset_param(mparams, "new"));
 
@@ -626,11 +984,109 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// Rejects the push if a file it adds or modifies has a path that breaks the path rules of
+    /// the repo
+    /// Checks the bookmarks that `bookmark_pushes` create against the bookmark creation policy.
+    /// Pushes that move or delete a bookmark are not checked.
+    fn check_bookmark_creations(
+        &self,
+        bookmark_pushes: &[BookmarkPush],
+        pushrebase: bool,
+    ) -> Result<()> {
+        for bp in bookmark_pushes {
+            if bp.old.is_none() && bp.new.is_some() {
+                check_bookmark_creation(
+                    &self.bookmark_creation,
+                    &bp.name,
+                    self.user.as_ref().map(|user| user.as_str()),
+                    pushrebase,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_paths(&self, filelogs: &Filelogs) -> Result<()> {
+        let violations = check_paths(
+            &self.path_rules,
+            filelogs.keys().filter_map(|node_key| node_key.path.mpath()),
+        );
+        if violations.is_empty() {
+            return Ok(());
+        }
+
+        STATS::path_violations_count.add_value(violations.len() as i64);
+        self.scuba_logger
+            .clone()
+            .add("path_violations_count", violations.len())
+            .log_with_msg("Push rejected because of invalid paths", None);
+        Err(ErrorKind::InvalidPaths(violations.len(), format_violations(&violations)).into())
+    }
+
+    /// Rejects the push if it creates a directory, or grows one, past the fanout limit of the
+    /// repo. The parents of the tree manifests are taken from the push when it has them, and
+    /// loaded from the repo otherwise
+    fn check_directory_fanout(
+        &self,
+        directories: Vec<PushedDirectory>,
+        manifests: &Manifests,
+    ) -> BoxFuture<(), Error> {
+        let pushed: HashMap<HgNodeKey, usize> = manifests
+            .iter()
+            .map(|(key, &(ref content, ..))| (key.clone(), content.files.len()))
+            .collect();
+        let repo = self.repo.clone();
+        let parent_fanout = move |path: &RepoPath, node: HgNodeHash| {
+            let key = HgNodeKey {
+                path: path.clone(),
+                hash: node,
+            };
+            match pushed.get(&key) {
+                Some(fanout) => ok(*fanout).boxify(),
+                None => repo.get_manifest_by_nodeid(&HgManifestId::new(node))
+                    .map(|manifest| manifest.list().count())
+                    .with_context(move |_| format!("While loading parent tree manifest {}", node))
+                    .from_err()
+                    .boxify(),
+            }
+        };
+
+        let max_directory_fanout = self.accounting.limits().max_directory_fanout;
+        let logger = self.logger.clone();
+        let scuba_logger = self.scuba_logger.clone();
+        check_fanout(self.accounting.limits(), directories, parent_fanout)
+            .and_then(move |check| {
+                for warning in check.warnings.iter() {
+                    STATS::directory_fanout_warnings.add_value(1);
+                    warn!(logger, "Push grows a large directory: {}", warning);
+                    scuba_logger
+                        .clone()
+                        .add("directory", warning.path.to_string())
+                        .add("directory_fanout", warning.fanout)
+                        .log_with_msg("Directory fanout warning", None);
+                }
+                if check.violations.is_empty() {
+                    return Ok(());
+                }
+
+                STATS::directory_fanout_violations.add_value(check.violations.len() as i64);
+                scuba_logger
+                    .clone()
+                    .add("directory_fanout_violations", check.violations.len())
+                    .log_with_msg("Push rejected because of directory fanout", None);
+                Err(ErrorKind::DirectoryFanoutTooLarge(
+                    max_directory_fanout,
+                    format_fanouts(&check.violations),
+                ).into())
+            })
+            .boxify()
+    }
+
     /// Takes parsed Changesets and scheduled for upload Filelogs and Manifests. The content of
     /// Manifests is used to figure out DAG of dependencies between a given Changeset and the
     /// Manifests and Filelogs it adds.
     /// The Changesets are scheduled for uploading and a Future is returned, whose completion means
-    /// that the changesets were uploaded
+    /// that the changesets were uploaded, and recorded in the cross-repo index if there is one
     fn upload_changesets(
         &self,
         cg_push: ChangegroupPush,
@@ -640,11 +1096,29 @@ impl Bundle2Resolver {
         let filelogs = cg_push.filelogs;
         let content_blobs = cg_push.content_blobs;
 
+        try_boxfuture!(self.check_paths(&filelogs));
+        for &(node, ref revlog_cs) in changesets.iter() {
+            try_boxfuture!(self.accounting.check_commit_message(node, revlog_cs.comments()));
+        }
+
+        let directories: Vec<_> = manifests
+            .iter()
+            .map(|(key, &(ref content, ref p1, ref p2, _))| PushedDirectory {
+                path: key.path.clone(),
+                fanout: content.files.len(),
+                parents: p1.iter().chain(p2.iter()).cloned().collect(),
+            })
+            .collect();
+
+        let progress = self.accounting.progress();
         self.scuba_logger
             .clone()
+            .add("push_bytes", progress.bytes)
+            .add("push_parts", progress.parts)
             .add("changeset_count", changesets.len())
             .add("manifests_count", manifests.len())
             .add("filelogs_count", filelogs.len())
+            .add("max_directory_fanout", max_fanout(&directories))
             .log_with_msg("Size of unbundle", None);
 
         STATS::changesets_count.add_value(changesets.len() as i64);
@@ -700,6 +1174,7 @@ impl Bundle2Resolver {
                         // XXX pass content blobs to CreateChangeset here
                         cs_metadata,
                         must_check_case_conflicts: true,
+                        metrics: None,
                     };
                     let scheduled_uploading = create_changeset.create(&repo, scuba_logger);
 
@@ -712,6 +1187,7 @@ impl Bundle2Resolver {
         let repo = self.repo.clone();
 
         let changesets_hashes: Vec<_> = changesets.iter().map(|(hash, _)| *hash).collect();
+        let resolver = self.clone();
 
         trace!(self.logger, "changesets: {:?}", changesets);
         trace!(self.logger, "filelogs: {:?}", filelogs.keys());
@@ -719,7 +1195,11 @@ impl Bundle2Resolver {
         trace!(self.logger, "content blobs: {:?}", content_blobs.keys());
 
         let scuba_logger = self.scuba_logger.clone();
-        stream::iter_ok(changesets)
+        let mut failure_scuba_logger = self.scuba_logger.clone();
+        let live_handles = self.repo.live_changeset_handles().clone();
+        // Nothing is uploaded by the fold until the check passes, as streams are lazy
+        let check_fanout = self.check_directory_fanout(directories, &manifests);
+        let upload = stream::iter_ok(changesets)
             .fold(
                 HashMap::new(),
                 move |uploaded_changesets, (node, revlog_cs)| {
@@ -743,9 +1223,25 @@ impl Bundle2Resolver {
                 ).map_err(Error::from)
                     .for_each(|_| Ok(()))
             })
-            .chain_err(ErrorKind::WhileUploadingData(changesets_hashes))
+            .then(move |res| {
+                // By now the fold and the pending uploads have been dropped together with all
+                // the handles of this push, so a failed push must not leave any of its own
+                // handles behind
+                if res.is_err() {
+                    failure_scuba_logger
+                        .add("live_changeset_handles", live_handles.count())
+                        .log_with_msg("Changesets upload failed", None);
+                }
+                res
+            })
+            .chain_err(ErrorKind::WhileUploadingData(changesets_hashes.clone()))
             .from_err()
-            .boxify()
+            .and_then(move |()| {
+                let uploaded = changesets_hashes.into_iter().map(HgChangesetId::new).collect();
+                resolver.record_in_cross_repo_index(ok(uploaded))
+            });
+
+        check_fanout.and_then(move |()| upload).boxify()
     }
 
     /// Ensures that the next item in stream is None
@@ -762,11 +1258,18 @@ impl Bundle2Resolver {
     }
 
     /// Takes a changegroup id and prepares a Bytes response containing Bundle2 with reply to
-    /// changegroup part saying that the push was successful
+    /// changegroup part saying that the push was successful. The changesets of the changegroup
+    /// are the landed commits of the push advisory, and `moved` the bookmarks they landed on.
+    /// Each bookmark part is replied to with whether it moved, and a push that moved only some of
+    /// them also gets the outcome of each one.
+    /// `warnings` are the rejections by hooks that don't block pushes.
     fn prepare_push_response(
         &self,
-        changegroup_id: Option<PartId>,
-        bookmark_ids: Vec<PartId>,
+        changegroup: Option<(PartId, Vec<HgNodeHash>)>,
+        report: BookmarkPushReport,
+        moved: Vec<String>,
+        warnings: Vec<HookRejection>,
+        structured_advisory: bool,
     ) -> BoxFuture<Bytes, Error> {
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
@@ -774,14 +1277,44 @@ impl Bundle2Resolver {
         // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
         // TODO: possibly enable compression support once this is fixed.
         bundle.set_compressor_type(None);
-        if let Some(changegroup_id) = changegroup_id {
+        if let Some((changegroup_id, landed)) = changegroup {
             bundle.add_part(try_boxfuture!(parts::replychangegroup_part(
                 parts::ChangegroupApplyResult::Success { heads_num_diff: 0 },
                 changegroup_id,
             )));
+            if let Some(ref push_advisory) = self.push_advisory {
+                bundle.add_part(try_boxfuture!(push_advisory.part(
+                    &moved.join(", "),
+                    &landed,
+                    landed.len(),
+                    structured_advisory,
+                )));
+            }
         }
-        for part_id in bookmark_ids {
-            bundle.add_part(try_boxfuture!(parts::replypushkey_part(true, part_id)));
+        for (part_id, _, outcome) in &report.outcomes {
+            bundle.add_part(try_boxfuture!(parts::replypushkey_part(
+                *outcome == BookmarkOutcome::Moved,
+                *part_id,
+            )));
+        }
+        if !report.is_complete() {
+            let names: Vec<_> = report
+                .outcomes
+                .iter()
+                .map(|(_, name, _)| name.to_string())
+                .collect();
+            bundle.add_part(try_boxfuture!(parts::bookmarkoutcomes_part(
+                names
+                    .iter()
+                    .zip(&report.outcomes)
+                    .map(|(name, (_, _, outcome))| {
+                        (name.as_str(), outcome.as_str(), outcome.reason())
+                    }),
+            )));
+            bundle.add_part(try_boxfuture!(parts::output_part(report.render())));
+        }
+        if let Some(part) = try_boxfuture!(warnings_part(warnings)) {
+            bundle.add_part(part);
         }
         bundle
             .build()
@@ -791,11 +1324,16 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// `total` is the number of pushed commits, which landed on `onto` as the ancestors of
+    /// `pushrebased_rev`. `warnings` are the rejections by hooks that don't block pushes.
     fn prepare_pushrebase_response(
         &self,
         commonheads: CommonHeads,
         pushrebased_rev: ChangesetId,
         onto: Bookmark,
+        total: usize,
+        warnings: Vec<HookRejection>,
+        structured_advisory: bool,
     ) -> impl Future<Item = Bytes, Error = Error> {
         // Send to the client both pushrebased commit and current "onto" bookmark. Normally they
         // should be the same, however they might be different if bookmark
@@ -805,6 +1343,7 @@ impl Bundle2Resolver {
         let maybe_onto_head = repo.get_bookmark(&onto);
 
         let pushrebased_rev = repo.get_hg_from_bonsai_changeset(pushrebased_rev);
+        let push_advisory = self.push_advisory.clone();
 
         let mut scuba_logger = self.scuba_logger.clone();
         maybe_onto_head
@@ -815,11 +1354,34 @@ impl Bundle2Resolver {
                     heads.push(onto_head);
                 }
                 heads.push(pushrebased_rev);
-                getbundle_response::create_getbundle_response(repo, common, heads)
+                let advisory = match push_advisory {
+                    Some(push_advisory) => {
+                        let listed = cmp::min(total, push_advisory.max_listed_commits());
+                        find_landed(repo.clone(), pushrebased_rev, listed)
+                            .and_then(move |landed| {
+                                push_advisory.part(
+                                    &onto.to_string(),
+                                    &landed,
+                                    total,
+                                    structured_advisory,
+                                )
+                            })
+                            .map(Some)
+                            .boxify()
+                    }
+                    None => ok(None).boxify(),
+                };
+                let warnings = warnings_part(warnings);
+                getbundle_response::create_getbundle_response(repo, common, heads, false)
+                    .into_future()
+                    .join3(advisory, warnings)
             })
-            .and_then(|cg_part_builder| {
+            .and_then(|(cg_part_builder, advisory, warnings)| {
                 let compression = None;
-                create_bundle_stream(vec![cg_part_builder], compression)
+                let mut part_builders = vec![cg_part_builder];
+                part_builders.extend(advisory);
+                part_builders.extend(warnings);
+                create_bundle_stream(part_builders, compression)
                     .collect()
                     .map(|chunks| {
                         let mut total_capacity = 0;
@@ -922,64 +1484,134 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// Runs the hooks of `bookmarks` on `changesets`. A hook that several of the bookmarks have
+    /// runs once.
     fn run_hooks(
         &self,
-        changesets: Changesets,
+        changesets: Vec<HgChangesetId>,
         pushvars: Option<HashMap<String, Bytes>>,
-        onto_bookmark: &Bookmark,
-    ) -> BoxFuture<(), RunHooksError> {
+        bookmarks: &[Bookmark],
+    ) -> BoxFuture<Vec<HookRejection>, RunHooksError> {
         let mut futs = stream::FuturesUnordered::new();
-        for (cs_id, _) in changesets {
-            let hg_cs_id = HgChangesetId::new(cs_id.clone());
+        for hg_cs_id in changesets {
             futs.push(
                 self.hook_manager
-                    .run_changeset_hooks_for_bookmark(
+                    .run_changeset_hooks_for_bookmarks(
                         hg_cs_id.clone(),
-                        onto_bookmark,
+                        bookmarks,
                         pushvars.clone(),
+                        self.user.clone(),
                     )
-                    .join(self.hook_manager.run_file_hooks_for_bookmark(
+                    .join(self.hook_manager.run_file_hooks_for_bookmarks(
                         hg_cs_id,
-                        onto_bookmark,
+                        bookmarks,
                         pushvars.clone(),
+                        self.user.clone(),
                     )),
             )
         }
+        let hook_manager = self.hook_manager.clone();
+        let logger = self.logger.clone();
+        let mut scuba_logger = self.scuba_logger.clone();
         futs.collect()
+            .then(move |res| match res {
+                Err(err) => match hook_manager.degraded_policy() {
+                    Some(HookDegradedPolicy::FailOpen) => {
+                        warn!(
+                            logger,
+                            "HOOKS SKIPPED: hooks are degraded and failed to run, so the push is \
+                             accepted without them: {}",
+                            err
+                        );
+                        scuba_logger.log_with_msg(
+                            "Hooks skipped because they are degraded",
+                            format!("{}", err),
+                        );
+                        Ok(vec![])
+                    }
+                    _ => Err(err),
+                },
+                res => res,
+            })
             .from_err()
             .and_then(|res| {
                 let (cs_hook_results, file_hook_results): (Vec<_>, Vec<_>) =
                     res.into_iter().unzip();
-                let cs_hook_failures: Vec<
-                    (ChangesetHookExecutionID, HookExecution),
-                > = cs_hook_results
-                    .into_iter()
-                    .flatten()
-                    .filter(|(_, exec)| match exec {
-                        HookExecution::Accepted => false,
-                        HookExecution::Rejected(_) => true,
-                    })
-                    .collect();
-                let file_hook_failures: Vec<(FileHookExecutionID, HookExecution)> =
-                    file_hook_results
+                let cs_hook_results: Vec<(ChangesetHookExecutionID, HookExecution)> =
+                    cs_hook_results.into_iter().flatten().collect();
+                let file_hook_results: Vec<(FileHookExecutionID, HookExecution)> =
+                    file_hook_results.into_iter().flatten().collect();
+                let is_rejection = |exec: &HookExecution| match exec {
+                    HookExecution::Rejected(_) => true,
+                    HookExecution::Accepted | HookExecution::Warned(_) => false,
+                };
+                if cs_hook_results.iter().any(|(_, exec)| is_rejection(exec))
+                    || file_hook_results.iter().any(|(_, exec)| is_rejection(exec))
+                {
+                    let cs_hook_failures = cs_hook_results
                         .into_iter()
-                        .flatten()
-                        .filter(|(_, exec)| match exec {
-                            HookExecution::Accepted => false,
-                            HookExecution::Rejected(_) => true,
-                        })
+                        .filter(|(_, exec)| is_rejection(exec))
+                        .collect();
+                    let file_hook_failures = file_hook_results
+                        .into_iter()
+                        .filter(|(_, exec)| is_rejection(exec))
                         .collect();
-                if cs_hook_failures.len() > 0 || file_hook_failures.len() > 0 {
                     Err(RunHooksError::Failures((
                         cs_hook_failures,
                         file_hook_failures,
                     )))
                 } else {
-                    Ok(())
+                    Ok(HookRejection::warnings_from_executions(
+                        cs_hook_results,
+                        file_hook_results,
+                    ))
+                }
+            })
+            .map({
+                let logger = self.logger.clone();
+                let mut scuba_logger = self.scuba_logger.clone();
+                move |warnings| {
+                    for warning in &warnings {
+                        info!(
+                            logger,
+                            "hook {} warned about {}: {}",
+                            warning.hook_name,
+                            warning.target,
+                            warning.description
+                        );
+                        scuba_logger
+                            .add("hook", warning.hook_name.clone())
+                            .add("hook_target", warning.target.clone())
+                            .log_with_msg("Hook warning", warning.description.clone());
+                    }
+                    warnings
                 }
             })
             .boxify()
     }
+
+    /// The error of a push that the hooks of `bookmarks` rejected or failed to check. If the push
+    /// moves several bookmarks, rejections are listed under the bookmarks whose hooks rejected.
+    fn run_hooks_error(&self, err: RunHooksError, bookmarks: &[Bookmark]) -> Error {
+        let rejections = match err {
+            RunHooksError::Failures((cs_hook_failures, file_hook_failures)) => {
+                HookRejection::from_executions(cs_hook_failures, file_hook_failures)
+            }
+            RunHooksError::Error(err) => return err,
+        };
+        if bookmarks.len() < 2 {
+            return err_msg(format_rejections(rejections));
+        }
+        let bookmarks_of_hook: HashMap<_, _> = rejections
+            .iter()
+            .map(|rejection| {
+                let hook_bookmarks = self.hook_manager
+                    .bookmarks_of_hook(&rejection.hook_name, bookmarks);
+                (rejection.hook_name.clone(), hook_bookmarks)
+            })
+            .collect();
+        err_msg(format_rejections_by_bookmark(rejections, &bookmarks_of_hook))
+    }
 }
 
 #[derive(Debug)]
@@ -999,18 +1631,6 @@ impl From<Error> for RunHooksError {
     }
 }
 
-fn add_bookmark_to_transaction(
-    txn: &mut Box<Transaction>,
-    bookmark_push: BonsaiBookmarkPush,
-) -> Result<()> {
-    match (bookmark_push.new, bookmark_push.old) {
-        (Some(new), Some(old)) => txn.update(&bookmark_push.name, &new, &old),
-        (Some(new), None) => txn.create(&bookmark_push.name, &new),
-        (None, Some(old)) => txn.delete(&bookmark_push.name, &old),
-        _ => Ok(()),
-    }
-}
-
 /// Retrieves the parent from uploaded changesets, if it is missing then fetches it from BlobRepo
 fn get_parent(
     repo: &BlobRepo,
@@ -0,0 +1,227 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Bookkeeping for resumable pulls.
+//!
+//! A client that advertises the `RESUMABLE_PULL_CAPABILITY` bundlecap gets a pull token in the
+//! changegroup part of the getbundle response. Changesets are sent in a deterministic order, and
+//! the server records how many of them were sent every `group_size` changesets. If the pull is
+//! interrupted, the client can send a new getbundle with the same `common` and `heads`, the token
+//! and the number of changesets it fully received (which must be a group boundary), and the
+//! server resumes from there instead of starting from scratch.
+//!
+//! Progress is kept in memory for `ttl` after the last update. A pull that isn't resumed in time
+//! has to be restarted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mercurial_types::HgChangesetId;
+
+use errors::*;
+
+/// Bundlecap that a client sends to request a resumable pull
+pub const RESUMABLE_PULL_CAPABILITY: &str = "resumablepull";
+
+/// Identifies a resumable pull
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PullToken(u64);
+
+impl fmt::Display for PullToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for PullToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse()
+            .map(PullToken)
+            .map_err(|_| ErrorKind::UnknownPullToken(s.to_string()).into())
+    }
+}
+
+struct PullProgress {
+    common: Vec<HgChangesetId>,
+    heads: Vec<HgChangesetId>,
+    sent: usize,
+    deadline: Instant,
+}
+
+struct PullsState {
+    next_token: u64,
+    pulls: HashMap<PullToken, PullProgress>,
+}
+
+/// Stores progress of resumable pulls
+#[derive(Clone)]
+pub struct ResumablePulls {
+    state: Arc<Mutex<PullsState>>,
+    ttl: Duration,
+    group_size: usize,
+}
+
+fn normalize(mut nodes: Vec<HgChangesetId>) -> Vec<HgChangesetId> {
+    nodes.sort();
+    nodes.dedup();
+    nodes
+}
+
+impl ResumablePulls {
+    pub fn new(ttl: Duration, group_size: usize) -> Self {
+        assert!(group_size > 0, "group size must be positive");
+        Self {
+            state: Arc::new(Mutex::new(PullsState {
+                next_token: 0,
+                pulls: HashMap::new(),
+            })),
+            ttl,
+            group_size,
+        }
+    }
+
+    /// Number of changesets between two resumable boundaries
+    pub fn group_size(&self) -> usize {
+        self.group_size
+    }
+
+    /// Registers a new pull and returns its token
+    pub fn start(&self, common: Vec<HgChangesetId>, heads: Vec<HgChangesetId>) -> PullToken {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.pulls.retain(|_, pull| pull.deadline > now);
+
+        let token = PullToken(state.next_token);
+        state.next_token += 1;
+        state.pulls.insert(
+            token,
+            PullProgress {
+                common: normalize(common),
+                heads: normalize(heads),
+                sent: 0,
+                deadline: now + self.ttl,
+            },
+        );
+        token
+    }
+
+    /// Validates that a pull can be resumed from `received` changesets and returns the number of
+    /// changesets to skip. `common` and `heads` must be the same as in the original request.
+    pub fn resume(
+        &self,
+        token: PullToken,
+        common: Vec<HgChangesetId>,
+        heads: Vec<HgChangesetId>,
+        received: usize,
+    ) -> Result<usize> {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.pulls.retain(|_, pull| pull.deadline > now);
+
+        let pull = state
+            .pulls
+            .get_mut(&token)
+            .ok_or_else(|| ErrorKind::UnknownPullToken(token.to_string()))?;
+
+        if pull.common != normalize(common) || pull.heads != normalize(heads) {
+            bail_err!(ErrorKind::PullResumeMismatch(token.to_string()));
+        }
+        if received % self.group_size != 0 || received > pull.sent {
+            bail_err!(ErrorKind::InvalidPullResumePoint(
+                token.to_string(),
+                received,
+                pull.sent,
+            ));
+        }
+
+        pull.sent = received;
+        pull.deadline = now + self.ttl;
+        Ok(received)
+    }
+
+    /// Records that the first `sent` changesets of the pull have been sent
+    pub fn record_progress(&self, token: PullToken, sent: usize) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        if let Some(pull) = state.pulls.get_mut(&token) {
+            pull.sent = ::std::cmp::max(pull.sent, sent);
+            pull.deadline = Instant::now() + self.ttl;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types_mocks::nodehash::{ONES_CSID, THREES_CSID, TWOS_CSID};
+
+    #[test]
+    fn resume_validation() {
+        let pulls = ResumablePulls::new(Duration::from_secs(60), 10);
+        let token = pulls.start(vec![ONES_CSID], vec![TWOS_CSID, THREES_CSID]);
+
+        // Nothing was sent yet
+        assert!(
+            pulls
+                .resume(token, vec![ONES_CSID], vec![TWOS_CSID, THREES_CSID], 10)
+                .is_err()
+        );
+
+        pulls.record_progress(token, 20);
+        // Order of heads doesn't matter
+        assert_eq!(
+            pulls
+                .resume(token, vec![ONES_CSID], vec![THREES_CSID, TWOS_CSID], 10)
+                .unwrap(),
+            10
+        );
+        // Not a group boundary
+        assert!(
+            pulls
+                .resume(token, vec![ONES_CSID], vec![TWOS_CSID, THREES_CSID], 5)
+                .is_err()
+        );
+        // Different request
+        assert!(
+            pulls
+                .resume(token, vec![ONES_CSID], vec![TWOS_CSID], 10)
+                .is_err()
+        );
+        assert!(
+            pulls
+                .resume(token, vec![TWOS_CSID], vec![TWOS_CSID, THREES_CSID], 10)
+                .is_err()
+        );
+        // Unknown token
+        assert!(
+            pulls
+                .resume(
+                    "1234".parse().unwrap(),
+                    vec![ONES_CSID],
+                    vec![TWOS_CSID, THREES_CSID],
+                    0,
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn resume_expired() {
+        let pulls = ResumablePulls::new(Duration::from_secs(0), 10);
+        let token = pulls.start(vec![ONES_CSID], vec![TWOS_CSID]);
+        pulls.record_progress(token, 10);
+        assert!(
+            pulls
+                .resume(token, vec![ONES_CSID], vec![TWOS_CSID], 10)
+                .is_err()
+        );
+    }
+}
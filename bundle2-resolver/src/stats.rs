@@ -20,4 +20,10 @@ define_stats! {
     per_changeset_manifests_count: timeseries(RATE, AVG, SUM),
     per_changeset_filelogs_count: timeseries(RATE, AVG, SUM),
     per_changeset_content_blobs_count: timeseries(RATE, AVG, SUM),
+    path_violations_count: timeseries(RATE, SUM),
+    directory_fanout_warnings: timeseries(RATE, SUM),
+    directory_fanout_violations: timeseries(RATE, SUM),
+    bookmark_commit_retries: timeseries(RATE, SUM),
+    bookmark_moves_undone: timeseries(RATE, SUM),
+    bookmark_pushes_incomplete: timeseries(RATE, SUM),
 }
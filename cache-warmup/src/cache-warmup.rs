@@ -6,29 +6,51 @@
 
 extern crate bookmarks;
 #[macro_use]
+extern crate cloned;
+#[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_ext;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate slog;
+#[macro_use]
+extern crate stats;
+extern crate tokio;
 
 extern crate blobrepo;
+extern crate blobstore;
 extern crate mercurial_types;
 extern crate metaconfig;
+extern crate ready_state;
 extern crate revset;
 
+#[cfg(test)]
+extern crate mercurial_types_mocks;
+#[cfg(test)]
+extern crate mononoke_types;
+
+mod tasks;
+
+use std::fmt;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use blobrepo::BlobRepo;
+use blobstore::is_transient_blobstore_error;
 use bookmarks::Bookmark;
-use futures::{Future, IntoFuture, Stream};
-use futures_ext::{spawn_future, BoxFuture, FutureExt};
-use mercurial_types::{Changeset, HgChangesetId, MPath, RepoPath};
-use mercurial_types::manifest::{Entry, Type};
-use mercurial_types::manifest_utils::recursive_entry_stream;
-use metaconfig::CacheWarmupParams;
-use revset::AncestorsNodeStream;
+use futures::{future, Future, IntoFuture, Stream};
+use futures_ext::{retry, spawn_future, BoxFuture, BoxStream, FutureExt, RetryPolicy, StreamExt};
+use mercurial_types::HgChangesetId;
+use metaconfig::{CacheWarmupParams, WarmupTaskParams};
+use ready_state::ReadyProgress;
 use slog::Logger;
+use stats::DynamicTimeseries;
+use tokio::util::FutureExt as TokioFutureExt;
+
+pub use tasks::{ChangesetsWarmup, ManifestsWarmup};
 
 mod errors {
     use bookmarks::Bookmark;
@@ -38,110 +60,358 @@ mod errors {
     pub enum ErrorKind {
         #[fail(display = "Bookmark {} does not exist", _0)] BookmarkNotFound(Bookmark),
         #[fail(display = "Bookmark value {} not found", _0)] BookmarkValueNotFound(HgChangesetId),
+        #[fail(display = "{} of {} fetches failed", _0, _1)] TooManyFailures(usize, usize),
+        #[fail(display = "warmup task {} {}", _0, _1)] TaskFailed(&'static str, String),
     }
 }
 
+use errors::ErrorKind;
 use failure::Error;
 
-// Fetches all the manifest entries and their linknodes. Do not fetching files because
-// there can be too many of them.
-fn blobstore_and_filenodes_warmup(
-    repo: Arc<BlobRepo>,
-    revision: HgChangesetId,
+define_stats! {
+    prefix = "mononoke.cache_warmup";
+    fetch_retries: dynamic_timeseries("{}.fetch_retries", (task: &'static str); RATE, SUM),
+    fetch_failures: dynamic_timeseries("{}.fetch_failures", (task: &'static str); RATE, SUM),
+}
+
+/// The progress of a running task is reported after this many items
+const PROGRESS_REPORT_INTERVAL: usize = 1000;
+
+/// A task isn't stopped for too many failed fetches before it made this many, so that a few
+/// failures at its start don't stop it
+const MIN_FETCHES_FOR_FAILURE_RATIO: usize = 100;
+
+/// Limits that apply to all the warmup tasks of a repo
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WarmupLimits {
+    /// Max number of ancestors of the bookmark to warm up
+    pub commit_limit: usize,
+    /// Max number of fetches that a task runs at once
+    pub fetch_concurrency: usize,
+    /// How failed fetches are retried
+    pub fetch_retry: RetryPolicy,
+    /// Max fraction of the fetches of a task that may fail
+    pub max_failure_ratio: f64,
+}
+
+#[derive(Default)]
+struct FetchCounts {
+    attempted: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl FetchCounts {
+    fn check(&self, max_failure_ratio: f64, min_attempted: usize) -> Result<(), Error> {
+        let attempted = self.attempted.load(Ordering::Relaxed);
+        let failed = self.failed.load(Ordering::Relaxed);
+        if attempted > 0 && attempted >= min_attempted
+            && failed as f64 > max_failure_ratio * attempted as f64
+        {
+            Err(ErrorKind::TooManyFailures(failed, attempted).into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Runs `fetch` for every item of `items` with at most `limits.fetch_concurrency` fetches at
+/// once, and retries the failed ones with `limits.fetch_retry`. Items whose fetch still fails are
+/// logged, counted and skipped, unless more than `limits.max_failure_ratio` of the fetches fail:
+/// then the stream fails with `TooManyFailures`. Yields the number of items fetched so far.
+pub fn fetch_tolerantly<S, F, Fut>(
+    name: &'static str,
+    items: S,
+    limits: WarmupLimits,
     logger: Logger,
-) -> BoxFuture<(), Error> {
-    // TODO(stash): Arbitrary number. Tweak somehow?
-    let buffer_size = 100;
-    repo.get_changeset_by_changesetid(&revision)
+    fetch: F,
+) -> BoxStream<usize, Error>
+where
+    S: Stream<Error = Error> + Send + 'static,
+    S::Item: Clone + Send + 'static,
+    F: Fn(S::Item) -> Fut + Send + Sync + 'static,
+    Fut: IntoFuture<Error = Error> + 'static,
+    Fut::Future: Send + 'static,
+    Fut::Item: Send + 'static,
+{
+    let fetch = Arc::new(fetch);
+    let counts = Arc::new(FetchCounts::default());
+
+    let fetched = items
         .map({
-            let repo = repo.clone();
-            move |cs| repo.get_root_entry(&cs.manifestid())
+            cloned!(counts, logger);
+            move |item| {
+                cloned!(counts, fetch, logger);
+                let mut attempt = 0;
+                retry(limits.fetch_retry, is_transient_blobstore_error, move || {
+                    attempt += 1;
+                    if attempt > 1 {
+                        STATS::fetch_retries.add_value(1, (name,));
+                    }
+                    (*fetch)(item.clone())
+                }).then(move |res| {
+                    counts.attempted.fetch_add(1, Ordering::Relaxed);
+                    match res {
+                        Ok(_) => Ok::<_, Error>(true),
+                        Err(err) => {
+                            counts.failed.fetch_add(1, Ordering::Relaxed);
+                            STATS::fetch_failures.add_value(1, (name,));
+                            debug!(logger, "warmup task {}: skipping failed fetch: {}", name, err);
+                            Ok(false)
+                        }
+                    }
+                })
+            }
         })
+        .buffer_unordered(limits.fetch_concurrency)
         .and_then({
-            move |root_entry| {
-                info!(logger, "starting precaching");
-                let rootpath = None;
-                let mut i = 0;
-                recursive_entry_stream(rootpath, root_entry)
-                    .filter(|&(ref _path, ref entry)| entry.get_type() == Type::Tree)
-                    .map(move |(path, entry)| {
-                        let hash = entry.get_hash();
-                        let path = MPath::join_element_opt(path.as_ref(), entry.get_name());
-                        let path = match path {
-                            Some(path) => RepoPath::DirectoryPath(path),
-                            None => RepoPath::RootPath,
-                        };
-                        repo.get_linknode(&path, &hash.into_nodehash())
-                    })
-                    .buffered(buffer_size)
-                    .for_each({
-                        let logger = logger.clone();
-                        move |_| {
-                            i += 1;
-                            if i % 10000 == 0 {
-                                debug!(logger, "manifests warmup: fetched {}th entry", i);
-                            }
-                            Ok(())
-                        }
-                    })
-                    .map(move |()| {
-                        debug!(logger, "finished manifests warmup");
-                    })
+            cloned!(counts);
+            move |ok| {
+                counts
+                    .check(limits.max_failure_ratio, MIN_FETCHES_FOR_FAILURE_RATIO)
+                    .map(|()| ok)
             }
         })
+        .filter(|ok| *ok)
+        .map({
+            let mut fetched = 0;
+            move |_| {
+                fetched += 1;
+                Some(fetched)
+            }
+        });
+
+    // Fewer fetches than MIN_FETCHES_FOR_FAILURE_RATIO are only checked once they are all done
+    let done = future::lazy(move || {
+        counts.check(limits.max_failure_ratio, 0)?;
+        let failed = counts.failed.load(Ordering::Relaxed);
+        if failed > 0 {
+            warn!(
+                logger,
+                "warmup task {}: skipped {} of {} fetches that failed",
+                name,
+                failed,
+                counts.attempted.load(Ordering::Relaxed)
+            );
+        }
+        Ok(None)
+    });
+
+    fetched
+        .chain(done.into_stream())
+        .filter_map(|fetched| fetched)
         .boxify()
 }
 
-// Iterate over first parents, and fetch them
-fn changesets_warmup(
-    start_rev: HgChangesetId,
+/// A part of the cache warmup of a repo. All the tasks of a repo run concurrently.
+pub trait WarmupTask: Send + Sync {
+    /// Name of the task in the config and in progress reports
+    fn name(&self) -> &'static str;
+
+    /// Warms up the caches of `repo` for `revision`. The stream yields the number of items
+    /// warmed up so far, and ends when the task is done.
+    fn warm(
+        &self,
+        repo: Arc<BlobRepo>,
+        revision: HgChangesetId,
+        limits: WarmupLimits,
+        logger: Logger,
+    ) -> BoxStream<usize, Error>;
+}
+
+/// Tasks that run for every repo with a cache warmup config
+pub fn default_warmup_tasks() -> Vec<Arc<WarmupTask>> {
+    vec![Arc::new(ManifestsWarmup), Arc::new(ChangesetsWarmup)]
+}
+
+/// How a warmup task ended
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WarmupOutcome {
+    /// Warmed up this many items
+    Done(usize),
+    /// The task is disabled in the config
+    Disabled,
+    /// Stopped when the time budget ran out, after warming up this many items
+    OutOfBudget(Duration, usize),
+    /// Failed with this error, which doesn't affect the other tasks
+    Failed(String),
+    /// Stopped because this many of this many fetches failed, which fails the whole warmup
+    TooManyFailures(usize, usize),
+}
+
+impl fmt::Display for WarmupOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WarmupOutcome::Done(items) => write!(f, "done, {} items", items),
+            WarmupOutcome::Disabled => write!(f, "disabled"),
+            WarmupOutcome::OutOfBudget(budget, items) => write!(
+                f,
+                "stopped after {}s time budget, {} items",
+                budget.as_secs(),
+                items
+            ),
+            WarmupOutcome::Failed(err) => write!(f, "failed: {}", err),
+            WarmupOutcome::TooManyFailures(failed, attempted) => write!(
+                f,
+                "failed: {} of {} fetches failed",
+                failed,
+                attempted
+            ),
+        }
+    }
+}
+
+fn run_warmup_task(
+    task: Arc<WarmupTask>,
     repo: Arc<BlobRepo>,
-    cs_limit: usize,
+    revision: HgChangesetId,
+    limits: WarmupLimits,
+    params: WarmupTaskParams,
+    progress: ReadyProgress,
     logger: Logger,
-) -> impl Future<Item = (), Error = Error> {
-    info!(logger, "about to start warming up changesets cache");
+) -> BoxFuture<WarmupOutcome, Error> {
+    let name = task.name();
+    if !params.enabled {
+        info!(logger, "warmup task {} is disabled", name);
+        progress.set(name, WarmupOutcome::Disabled.to_string());
+        return future::ok(WarmupOutcome::Disabled).boxify();
+    }
 
-    repo.get_bonsai_from_hg(&start_rev)
-        .and_then({
-            let start_rev = start_rev.clone();
-            move |maybe_node| {
-                maybe_node.ok_or(errors::ErrorKind::BookmarkValueNotFound(start_rev).into())
+    progress.set(name, "running");
+    let warmed = Arc::new(AtomicUsize::new(0));
+    let work = task.warm(repo, revision, limits, logger.clone())
+        .for_each({
+            cloned!(progress, warmed);
+            move |items| {
+                warmed.store(items, Ordering::Relaxed);
+                if items % PROGRESS_REPORT_INTERVAL == 0 {
+                    progress.set(name, format!("running, {} items", items));
+                }
+                Ok(())
+            }
+        })
+        .map({
+            cloned!(warmed);
+            move |()| WarmupOutcome::Done(warmed.load(Ordering::Relaxed))
+        });
+
+    // The budget applies inside of the spawned future, so that the work stops when it runs out
+    let work = match params.time_budget {
+        Some(budget) => work.timeout(budget)
+            .or_else({
+                cloned!(warmed);
+                move |err| {
+                    if err.is_elapsed() {
+                        Ok(WarmupOutcome::OutOfBudget(
+                            budget,
+                            warmed.load(Ordering::Relaxed),
+                        ))
+                    } else if err.is_inner() {
+                        Err(err.into_inner().expect("checked by is_inner"))
+                    } else {
+                        Err(err.into_timer().expect("neither inner nor elapsed").into())
+                    }
+                }
+            })
+            .boxify(),
+        None => work.boxify(),
+    };
+
+    spawn_future(work)
+        .or_else(|err| match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::TooManyFailures(failed, attempted)) => {
+                Ok(WarmupOutcome::TooManyFailures(failed, attempted))
+            }
+            Ok(err) => Ok(WarmupOutcome::Failed(err.to_string())),
+            Err(err) => Ok(WarmupOutcome::Failed(err.to_string())),
+        })
+        .map(move |outcome| {
+            match outcome {
+                WarmupOutcome::Failed(_) | WarmupOutcome::TooManyFailures(..) => {
+                    error!(logger, "warmup task {}: {}", name, outcome)
+                }
+                _ => info!(logger, "warmup task {}: {}", name, outcome),
             }
+            progress.set(name, outcome.to_string());
+            outcome
         })
-        .and_then(move |start_rev| {
-            AncestorsNodeStream::new(&repo.get_changeset_fetcher(), start_rev)
-                .take(cs_limit as u64)
-                .collect()
-                .map(move |_| {
-                    debug!(logger, "finished changesets warmup");
+        .boxify()
+}
+
+/// Runs `tasks` concurrently for `revision` with the settings of `params`, and reports their
+/// progress to `progress`. A task that fails or runs out of its time budget doesn't affect the
+/// others, and the returned future succeeds once every task has ended, unless too many fetches
+/// of a task failed: then the cache is too cold for the repo to be marked as ready.
+pub fn run_warmup_tasks(
+    repo: Arc<BlobRepo>,
+    revision: HgChangesetId,
+    tasks: Vec<Arc<WarmupTask>>,
+    params: &CacheWarmupParams,
+    progress: ReadyProgress,
+    logger: Logger,
+) -> BoxFuture<Vec<(&'static str, WarmupOutcome)>, Error> {
+    for name in params.tasks.keys() {
+        if !tasks.iter().any(|task| task.name() == name) {
+            warn!(logger, "unknown warmup task {} in config", name);
+        }
+    }
+
+    let limits = WarmupLimits {
+        commit_limit: params.commit_limit,
+        fetch_concurrency: params.fetch_concurrency,
+        fetch_retry: params.fetch_retry,
+        max_failure_ratio: params.max_failure_ratio,
+    };
+    let runs = tasks.into_iter().map(|task| {
+        let name = task.name();
+        run_warmup_task(
+            task,
+            repo.clone(),
+            revision,
+            limits,
+            params.task(name),
+            progress.clone(),
+            logger.clone(),
+        ).map(move |outcome| (name, outcome))
+    });
+
+    future::join_all(runs)
+        .and_then(|outcomes| {
+            let failed = outcomes
+                .iter()
+                .find(|(_, outcome)| match outcome {
+                    WarmupOutcome::TooManyFailures(..) => true,
+                    _ => false,
                 })
+                .map(|(name, outcome)| (*name, outcome.to_string()));
+            match failed {
+                Some((name, outcome)) => Err(ErrorKind::TaskFailed(name, outcome).into()),
+                None => Ok(outcomes),
+            }
         })
+        .boxify()
 }
 
 fn do_cache_warmup(
     repo: Arc<BlobRepo>,
-    bookmark: Bookmark,
-    commit_limit: usize,
+    params: CacheWarmupParams,
+    tasks: Vec<Arc<WarmupTask>>,
+    progress: ReadyProgress,
     logger: Logger,
 ) -> BoxFuture<(), Error> {
+    let bookmark = params.bookmark.clone();
     repo.get_bookmark(&bookmark)
         .and_then({
             let logger = logger.clone();
             let repo = repo.clone();
             move |bookmark_rev| match bookmark_rev {
                 Some(bookmark_rev) => {
-                    let blobstore_warmup = spawn_future(blobstore_and_filenodes_warmup(
-                        repo.clone(),
-                        bookmark_rev,
-                        logger.clone(),
-                    ));
-                    let cs_warmup =
-                        spawn_future(changesets_warmup(bookmark_rev, repo, commit_limit, logger));
-                    blobstore_warmup.join(cs_warmup).map(|_| ()).boxify()
+                    run_warmup_tasks(repo, bookmark_rev, tasks, &params, progress, logger)
+                        .map(|_| ())
+                        .boxify()
                 }
                 None => {
                     info!(logger, "{} bookmark not found!", bookmark);
-                    Err(errors::ErrorKind::BookmarkNotFound(bookmark).into())
+                    Err(ErrorKind::BookmarkNotFound(bookmark).into())
                         .into_future()
                         .boxify()
                 }
@@ -154,20 +424,446 @@ fn do_cache_warmup(
         .boxify()
 }
 
-/// Fetch all manifest entries for a bookmark, and fetches up to `commit_warmup_limit`
-/// ancestors of the bookmark.
+/// Runs the `default_warmup_tasks` for a bookmark: fetches all manifest entries of the bookmark,
+/// and up to `commit_limit` ancestors of it. The progress of every task is reported to
+/// `progress`.
 pub fn cache_warmup(
     repo: Arc<BlobRepo>,
     cache_warmup: Option<CacheWarmupParams>,
+    progress: ReadyProgress,
     logger: Logger,
 ) -> BoxFuture<(), Error> {
     match cache_warmup {
         Some(cache_warmup) => do_cache_warmup(
             repo,
-            cache_warmup.bookmark,
-            cache_warmup.commit_limit,
-            logger.clone(),
+            cache_warmup,
+            default_warmup_tasks(),
+            progress,
+            logger,
         ),
         None => Ok(()).into_future().boxify(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::sync::Mutex;
+
+    use blobstore::{Blobstore, EagerMemblob};
+    use futures::stream;
+    use mercurial_types::RepositoryId;
+    use mercurial_types_mocks::nodehash::ONES_CSID;
+    use mononoke_types::BlobstoreBytes;
+    use ready_state::ReadyStateBuilder;
+    use slog::{Discard, Drain};
+    use tokio::runtime::Runtime;
+
+    /// Fails all the gets of some keys, and the first gets of every key
+    #[derive(Debug)]
+    struct FlakyBlobstore {
+        inner: EagerMemblob,
+        failing_keys: HashSet<String>,
+        transient_failures: usize,
+        gets: Mutex<HashMap<String, usize>>,
+    }
+
+    impl Blobstore for FlakyBlobstore {
+        fn get(&self, key: String) -> BoxFuture<Option<BlobstoreBytes>, Error> {
+            let gets = {
+                let mut gets = self.gets.lock().unwrap();
+                let count = gets.entry(key.clone()).or_insert(0);
+                *count += 1;
+                *count
+            };
+            if self.failing_keys.contains(&key) || gets <= self.transient_failures {
+                future::err(format_err!("flaky get of {}", key)).boxify()
+            } else {
+                self.inner.get(key)
+            }
+        }
+
+        fn put(&self, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
+            self.inner.put(key, value)
+        }
+    }
+
+    /// A repo with `count` blobs. The gets of the last `failing_ratio` of them always fail, and
+    /// the first `transient_failures` gets of every blob fail. Returns the keys of the blobs too.
+    fn flaky_repo(
+        count: usize,
+        failing_ratio: f64,
+        transient_failures: usize,
+    ) -> (Arc<BlobRepo>, Arc<FlakyBlobstore>, Vec<String>) {
+        let keys: Vec<_> = (0..count).map(|i| format!("blob{}", i)).collect();
+        let failing = (count as f64 * failing_ratio) as usize;
+        let prefix = RepositoryId::new(0).prefix();
+        let blobstore = Arc::new(FlakyBlobstore {
+            inner: EagerMemblob::new(),
+            failing_keys: keys.iter()
+                .skip(count - failing)
+                .map(|key| prefix.key(key))
+                .collect(),
+            transient_failures,
+            gets: Mutex::new(HashMap::new()),
+        });
+        let repo = BlobRepo::new_memblob_empty(None, Some(blobstore.clone())).unwrap();
+        for key in &keys {
+            repo.get_blobstore()
+                .put(key.clone(), BlobstoreBytes::from_bytes(key.as_bytes()))
+                .wait()
+                .unwrap();
+        }
+        (Arc::new(repo), blobstore, keys)
+    }
+
+    fn limits(max_attempts: usize, max_failure_ratio: f64) -> WarmupLimits {
+        WarmupLimits {
+            commit_limit: 100,
+            fetch_concurrency: 10,
+            fetch_retry: RetryPolicy {
+                max_attempts,
+                ..RetryPolicy::no_retries()
+            },
+            max_failure_ratio,
+        }
+    }
+
+    fn fetch_blobs(
+        repo: Arc<BlobRepo>,
+        keys: Vec<String>,
+        limits: WarmupLimits,
+    ) -> Result<Option<usize>, Error> {
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let fetches = fetch_tolerantly("blobs", stream::iter_ok(keys), limits, logger, move |key| {
+            repo.get_blobstore().get(key)
+        });
+        let mut runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(fetches.collect())
+            .map(|fetched| fetched.last().cloned())
+    }
+
+    #[test]
+    fn test_fetch_failures_are_skipped() {
+        let (repo, _, keys) = flaky_repo(200, 0.03, 0);
+        let fetched = fetch_blobs(repo, keys, limits(1, 0.05)).expect("warmup failed");
+        assert_eq!(fetched, Some(194));
+    }
+
+    #[test]
+    fn test_fetch_retries() {
+        let (repo, blobstore, keys) = flaky_repo(50, 0.0, 2);
+
+        let fetched = fetch_blobs(repo, keys, limits(3, 0.0)).unwrap();
+        assert_eq!(fetched, Some(50));
+        let gets = blobstore.gets.lock().unwrap().clone();
+        assert_eq!(gets.len(), 50);
+        assert!(gets.values().all(|count| *count == 3), "{:?}", gets);
+
+        // Without enough retries every fetch fails
+        let (repo, _, keys) = flaky_repo(50, 0.0, 2);
+        assert!(fetch_blobs(repo, keys, limits(2, 0.0)).is_err());
+    }
+
+    #[test]
+    fn test_too_many_fetch_failures() {
+        let (repo, _, keys) = flaky_repo(200, 0.1, 0);
+        let err = fetch_blobs(repo, keys, limits(1, 0.05)).expect_err("warmup must fail");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::TooManyFailures(failed, attempted)) => {
+                assert!(failed as f64 > 0.05 * attempted as f64)
+            }
+            bad => panic!("unexpected result {:?}", bad),
+        }
+
+        // Few fetches are only checked once they are all done
+        let (repo, _, keys) = flaky_repo(10, 0.2, 0);
+        let err = fetch_blobs(repo, keys, limits(1, 0.05)).expect_err("warmup must fail");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::TooManyFailures(2, 10)) => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
+
+    enum MockBehaviour {
+        Finish(usize),
+        Fail,
+        Hang,
+    }
+
+    struct MockTask {
+        name: &'static str,
+        behaviour: MockBehaviour,
+    }
+
+    /// Fetches the given blobs of the repo
+    struct BlobsTask {
+        keys: Vec<String>,
+    }
+
+    impl WarmupTask for BlobsTask {
+        fn name(&self) -> &'static str {
+            "blobs"
+        }
+
+        fn warm(
+            &self,
+            repo: Arc<BlobRepo>,
+            _revision: HgChangesetId,
+            limits: WarmupLimits,
+            logger: Logger,
+        ) -> BoxStream<usize, Error> {
+            let keys = stream::iter_ok(self.keys.clone());
+            fetch_tolerantly(self.name(), keys, limits, logger, move |key| {
+                repo.get_blobstore().get(key)
+            })
+        }
+    }
+
+    impl MockTask {
+        fn new(name: &'static str, behaviour: MockBehaviour) -> Arc<WarmupTask> {
+            Arc::new(MockTask { name, behaviour })
+        }
+    }
+
+    impl WarmupTask for MockTask {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn warm(
+            &self,
+            _repo: Arc<BlobRepo>,
+            _revision: HgChangesetId,
+            limits: WarmupLimits,
+            _logger: Logger,
+        ) -> BoxStream<usize, Error> {
+            match self.behaviour {
+                MockBehaviour::Finish(items) => {
+                    stream::iter_ok((1..items + 1).take(limits.commit_limit)).boxify()
+                }
+                MockBehaviour::Fail => stream::iter_ok(vec![1, 2])
+                    .chain(stream::iter_result(vec![Err(format_err!("mock failure"))]))
+                    .boxify(),
+                MockBehaviour::Hang => stream::iter_ok(vec![1, 2, 3])
+                    .chain(future::empty().into_stream())
+                    .boxify(),
+            }
+        }
+    }
+
+    fn params(tasks: Vec<(&str, WarmupTaskParams)>) -> CacheWarmupParams {
+        CacheWarmupParams {
+            bookmark: Bookmark::new("master").unwrap(),
+            commit_limit: 100,
+            tasks: tasks
+                .into_iter()
+                .map(|(name, params)| (name.to_string(), params))
+                .collect(),
+            fetch_concurrency: 10,
+            fetch_retry: RetryPolicy::no_retries(),
+            max_failure_ratio: 0.05,
+        }
+    }
+
+    fn run(
+        tasks: Vec<Arc<WarmupTask>>,
+        params: &CacheWarmupParams,
+        progress: ReadyProgress,
+    ) -> BTreeMap<&'static str, WarmupOutcome> {
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None, None).unwrap());
+        let fut = run_warmup_tasks(repo, ONES_CSID, tasks, params, progress, logger);
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(fut).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn test_all_tasks_run() {
+        let progress = ReadyProgress::default();
+        let tasks = vec![
+            MockTask::new("small", MockBehaviour::Finish(10)),
+            MockTask::new("large", MockBehaviour::Finish(1000)),
+        ];
+        let outcomes = run(tasks, &params(vec![]), progress.clone());
+
+        assert_eq!(outcomes.get("small"), Some(&WarmupOutcome::Done(10)));
+        // Limited by the commit limit
+        assert_eq!(outcomes.get("large"), Some(&WarmupOutcome::Done(100)));
+        assert_eq!(
+            progress.get().get("small").map(String::as_str),
+            Some("done, 10 items")
+        );
+        assert_eq!(
+            progress.get().get("large").map(String::as_str),
+            Some("done, 100 items")
+        );
+    }
+
+    #[test]
+    fn test_failure_does_not_abort_others() {
+        let progress = ReadyProgress::default();
+        let tasks = vec![
+            MockTask::new("failing", MockBehaviour::Fail),
+            MockTask::new("working", MockBehaviour::Finish(5)),
+        ];
+        let outcomes = run(tasks, &params(vec![]), progress.clone());
+
+        assert_eq!(
+            outcomes.get("failing"),
+            Some(&WarmupOutcome::Failed("mock failure".to_string()))
+        );
+        assert_eq!(outcomes.get("working"), Some(&WarmupOutcome::Done(5)));
+        assert_eq!(
+            progress.get().get("failing").map(String::as_str),
+            Some("failed: mock failure")
+        );
+    }
+
+    #[test]
+    fn test_disabled_task() {
+        let progress = ReadyProgress::default();
+        let tasks = vec![
+            MockTask::new("disabled", MockBehaviour::Hang),
+            MockTask::new("enabled", MockBehaviour::Finish(3)),
+        ];
+        let params = params(vec![
+            (
+                "disabled",
+                WarmupTaskParams {
+                    enabled: false,
+                    time_budget: None,
+                },
+            ),
+        ]);
+        let outcomes = run(tasks, &params, progress.clone());
+
+        assert_eq!(outcomes.get("disabled"), Some(&WarmupOutcome::Disabled));
+        assert_eq!(outcomes.get("enabled"), Some(&WarmupOutcome::Done(3)));
+        assert_eq!(
+            progress.get().get("disabled").map(String::as_str),
+            Some("disabled")
+        );
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let progress = ReadyProgress::default();
+        let budget = Duration::from_millis(100);
+        let tasks = vec![
+            MockTask::new("hanging", MockBehaviour::Hang),
+            MockTask::new("quick", MockBehaviour::Finish(7)),
+        ];
+        let params = params(vec![
+            (
+                "hanging",
+                WarmupTaskParams {
+                    enabled: true,
+                    time_budget: Some(budget),
+                },
+            ),
+            (
+                "quick",
+                WarmupTaskParams {
+                    enabled: true,
+                    time_budget: Some(Duration::from_secs(60)),
+                },
+            ),
+        ]);
+        let outcomes = run(tasks, &params, progress.clone());
+
+        assert_eq!(
+            outcomes.get("hanging"),
+            Some(&WarmupOutcome::OutOfBudget(budget, 3))
+        );
+        assert_eq!(outcomes.get("quick"), Some(&WarmupOutcome::Done(7)));
+    }
+
+    #[test]
+    fn test_readiness() {
+        let mut ready = ReadyStateBuilder::new();
+        let handle = ready.create_handle("repo");
+        let ready = ready.freeze();
+
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None, None).unwrap());
+        let tasks = vec![
+            MockTask::new("failing", MockBehaviour::Fail),
+            MockTask::new("working", MockBehaviour::Finish(2)),
+        ];
+        let params = params(vec![]);
+        let progress = handle.progress();
+        let fut = handle.wait_for(run_warmup_tasks(
+            repo, ONES_CSID, tasks, &params, progress, logger,
+        ));
+        assert!(!ready.is_ready());
+        assert_eq!(
+            ready.progress().get("repo").and_then(|p| p.get("working")),
+            Some(&"running".to_string())
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(fut).unwrap();
+        assert!(ready.is_ready());
+
+        let progress = ready.progress();
+        let progress = progress.get("repo").unwrap();
+        assert_eq!(
+            progress.get("working").map(String::as_str),
+            Some("done, 2 items")
+        );
+        assert_eq!(
+            progress.get("failing").map(String::as_str),
+            Some("failed: mock failure")
+        );
+    }
+
+    #[test]
+    fn test_too_many_failures_fail_readiness() {
+        let mut ready = ReadyStateBuilder::new();
+        let handle = ready.create_handle("repo");
+        let ready = ready.freeze();
+
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let (repo, _, keys) = flaky_repo(200, 0.1, 0);
+        let tasks: Vec<Arc<WarmupTask>> = vec![
+            Arc::new(BlobsTask { keys }),
+            MockTask::new("working", MockBehaviour::Finish(2)),
+        ];
+        let params = params(vec![]);
+        let progress = handle.progress();
+        let fut = handle.wait_for(run_warmup_tasks(
+            repo, ONES_CSID, tasks, &params, progress, logger,
+        ));
+
+        let mut runtime = Runtime::new().unwrap();
+        assert!(runtime.block_on(fut).is_err());
+        assert!(!ready.is_ready());
+
+        let progress = ready.progress();
+        let progress = progress.get("repo").unwrap();
+        assert!(
+            progress.get("blobs").unwrap().starts_with("failed: "),
+            "{:?}",
+            progress
+        );
+        assert_eq!(
+            progress.get("working").map(String::as_str),
+            Some("done, 2 items")
+        );
+    }
+
+    #[test]
+    fn test_missing_bookmark() {
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let repo = Arc::new(BlobRepo::new_memblob_empty(None, None).unwrap());
+        let progress = ReadyProgress::default();
+        let fut = cache_warmup(repo, Some(params(vec![])), progress.clone(), logger);
+        let mut runtime = Runtime::new().unwrap();
+        assert!(runtime.block_on(fut).is_err());
+        assert!(progress.get().is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Warmup tasks for the data that every repo has
+
+use std::sync::Arc;
+
+use blobrepo::BlobRepo;
+use failure::Error;
+use futures::{Future, Stream};
+use futures_ext::{BoxStream, StreamExt};
+use mercurial_types::{Changeset, HgChangesetId, MPath, RepoPath};
+use mercurial_types::manifest::{Entry, Type};
+use mercurial_types::manifest_utils::recursive_entry_stream;
+use revset::AncestorsNodeStream;
+use slog::Logger;
+
+use errors::ErrorKind;
+use {fetch_tolerantly, WarmupLimits, WarmupTask};
+
+/// Fetches all the manifest entries and their linknodes. Does not fetch files because there can
+/// be too many of them.
+pub struct ManifestsWarmup;
+
+impl WarmupTask for ManifestsWarmup {
+    fn name(&self) -> &'static str {
+        "manifests"
+    }
+
+    fn warm(
+        &self,
+        repo: Arc<BlobRepo>,
+        revision: HgChangesetId,
+        limits: WarmupLimits,
+        logger: Logger,
+    ) -> BoxStream<usize, Error> {
+        let name = self.name();
+        repo.get_changeset_by_changesetid(&revision)
+            .map({
+                let repo = repo.clone();
+                move |cs| repo.get_root_entry(&cs.manifestid())
+            })
+            .map(move |root_entry| {
+                info!(logger, "starting precaching");
+                let rootpath = None;
+                let trees = recursive_entry_stream(rootpath, root_entry)
+                    .filter(|&(ref _path, ref entry)| entry.get_type() == Type::Tree)
+                    .map(|(path, entry)| {
+                        let hash = entry.get_hash().into_nodehash();
+                        let path = MPath::join_element_opt(path.as_ref(), entry.get_name());
+                        let path = match path {
+                            Some(path) => RepoPath::DirectoryPath(path),
+                            None => RepoPath::RootPath,
+                        };
+                        (path, hash)
+                    });
+                fetch_tolerantly(name, trees, limits, logger.clone(), move |(path, hash)| {
+                    repo.get_linknode(&path, &hash)
+                }).inspect(move |i| {
+                    if i % 10000 == 0 {
+                        debug!(logger, "manifests warmup: fetched {}th entry", i);
+                    }
+                })
+            })
+            .flatten_stream()
+            .boxify()
+    }
+}
+
+/// Iterates over the ancestors of the revision, and fetches them
+pub struct ChangesetsWarmup;
+
+impl WarmupTask for ChangesetsWarmup {
+    fn name(&self) -> &'static str {
+        "changesets"
+    }
+
+    fn warm(
+        &self,
+        repo: Arc<BlobRepo>,
+        revision: HgChangesetId,
+        limits: WarmupLimits,
+        logger: Logger,
+    ) -> BoxStream<usize, Error> {
+        info!(logger, "about to start warming up changesets cache");
+
+        repo.get_bonsai_from_hg(&revision)
+            .and_then(move |maybe_node| {
+                maybe_node.ok_or(ErrorKind::BookmarkValueNotFound(revision).into())
+            })
+            .map(move |start_rev| {
+                let mut i = 0;
+                AncestorsNodeStream::new(&repo.get_changeset_fetcher(), start_rev)
+                    .take(limits.commit_limit as u64)
+                    .map(move |_| {
+                        i += 1;
+                        i
+                    })
+            })
+            .flatten_stream()
+            .boxify()
+    }
+}
@@ -12,6 +12,7 @@
 extern crate abomonation;
 #[macro_use]
 extern crate abomonation_derive;
+extern crate asyncmemo;
 extern crate bytes;
 extern crate cachelib;
 extern crate db_conn;
@@ -41,8 +42,10 @@ extern crate stats;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::result;
+use std::mem;
 use std::sync::{Arc, MutexGuard};
 
+use asyncmemo::Weight;
 use bytes::Bytes;
 use db_conn::{MysqlConnInner, SqliteConnInner};
 use diesel::{insert_into, Connection, MysqlConnection, SqliteConnection};
@@ -54,6 +57,7 @@ use diesel::sql_types::HasSqlType;
 use failure::{ResultExt, SyncFailure, chain::*};
 
 use futures_ext::{asynchronize, BoxFuture, FutureExt};
+use heapsize::HeapSizeOf;
 use mercurial_types::RepositoryId;
 use mononoke_types::ChangesetId;
 use mononoke_types::sql_types::ChangesetIdSql;
@@ -84,6 +88,13 @@ pub struct ChangesetEntry {
     pub gen: u64,
 }
 
+/// The entry and the buffer of its parents
+impl Weight for ChangesetEntry {
+    fn get_weight(&self) -> usize {
+        mem::size_of::<Self>() + self.heap_size_of_children()
+    }
+}
+
 pub fn serialize_cs_entries(cs_entries: Vec<ChangesetEntry>) -> Bytes {
     let mut thrift_entries = vec![];
     for entry in cs_entries {
@@ -222,7 +233,7 @@ pub struct MysqlChangesets {
 }
 
 impl MysqlChangesets {
-    fn from(inner: MysqlConnInner) -> MysqlChangesets {
+    pub fn from(inner: MysqlConnInner) -> MysqlChangesets {
         MysqlChangesets { inner } // one true constructor
     }
 
@@ -511,4 +522,29 @@ mod tests {
             .unwrap();
         assert_eq!(vec![entry.clone(), entry], res);
     }
+
+    #[test]
+    fn weight() {
+        let mut entry = ChangesetEntry {
+            repo_id: RepositoryId::new(0),
+            cs_id: mononoke_types_mocks::changesetid::ONES_CSID,
+            parents: vec![],
+            gen: 1,
+        };
+        let root_weight = entry.get_weight();
+        assert!(asyncmemo::weight_matches_estimate(
+            root_weight,
+            abomonation::measure(&entry)
+        ));
+
+        entry.parents = vec![
+            mononoke_types_mocks::changesetid::TWOS_CSID,
+            mononoke_types_mocks::changesetid::THREES_CSID,
+        ];
+        assert!(asyncmemo::weight_matches_estimate(
+            entry.get_weight(),
+            abomonation::measure(&entry)
+        ));
+        assert!(entry.get_weight() >= root_weight + 2 * mem::size_of::<ChangesetId>());
+    }
 }
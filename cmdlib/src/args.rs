@@ -21,11 +21,21 @@ use sloggers::types::{Format, Severity, SourceLocation};
 use cachelib;
 use slog_glog_fmt::default_drain as glog_drain;
 
-use blobrepo::ManifoldArgs;
+use blobrepo::{default_blobstore_retry_policy, default_sql_retry_policy, ManifoldArgs};
+use bookmarks::BookmarkSnapshotIndex;
+use cross_repo_index::CrossRepoIndex;
+use derived_data::DerivedDataStatus;
 use hooks::HookManager;
 use mercurial_types::RepositoryId;
 use metaconfig::RepoType;
-use repo_client::{open_blobrepo, MononokeRepo};
+use push_journal::PushJournal;
+use repo_client::{open_blobrepo,
+                  open_bookmark_snapshot_index as open_repo_bookmark_snapshot_index,
+                  open_cross_repo_index as open_repo_cross_repo_index,
+                  open_derived_data_status as open_repo_derived_data_status,
+                  open_push_journal as open_repo_push_journal, open_repo_flag_overrides,
+                  MononokeRepo, OpenRepoParams, RuntimeRepoFlags};
+use repo_flags::RepoFlagOverrides;
 
 const CACHE_ARGS: &[(&str, &str)] = &[
     ("blob-cache-size", "override size of the blob cache"),
@@ -86,8 +96,7 @@ impl MononokeApp {
                     // This is an old form that some consumers use
                     .alias("repo_id")
                     .value_name("ID")
-                    .default_value("0")
-                    .help("numeric ID of repository")
+                    .help("numeric ID of repository, required by commands that access a repo")
             )
             .arg(
                 Arg::with_name("myrouter-port")
@@ -95,6 +104,13 @@ impl MononokeApp {
                     .value_name("PORT")
                     .help("port for local myrouter instance")
             )
+            .arg(
+                Arg::with_name("repo-open-timeout")
+                    .long("repo-open-timeout")
+                    .value_name("SECS")
+                    .help("how long to wait for the repo and its backends to be ready")
+            )
+
             .arg(
                 Arg::with_name("log-style")
                     .short("l")
@@ -201,13 +217,17 @@ pub fn get_logger<'a>(matches: &ArgMatches<'a>) -> Logger {
     }
 }
 
-pub fn get_repo_id<'a>(matches: &ArgMatches<'a>) -> RepositoryId {
-    let repo_id = matches
-        .value_of("repo-id")
-        .unwrap()
+/// The repo id from `--repo-id`. There is no default, so that a command can't silently use the
+/// keys of another repo when the id is forgotten.
+pub fn get_repo_id<'a>(matches: &ArgMatches<'a>) -> Result<RepositoryId> {
+    let repo_id = match matches.value_of("repo-id") {
+        Some(repo_id) => repo_id,
+        None => bail_msg!("this command needs the id of the repo, pass it with --repo-id"),
+    };
+    let repo_id = repo_id
         .parse::<u32>()
-        .expect("expected repository ID to be a u32");
-    RepositoryId::new(repo_id as i32)
+        .map_err(|_| format_err!("--repo-id must be a non-negative number, got {:?}", repo_id))?;
+    Ok(RepositoryId::new(repo_id as i32))
 }
 
 /// Create a new `MononokeRepo` -- for local instances, expect its contents to be empty.
@@ -222,6 +242,66 @@ pub fn open_repo<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<Monono
     open_repo_internal(logger, matches, false)
 }
 
+/// Open the push journal of an existing repo, e.g. to list the pushes that didn't complete.
+pub fn open_push_journal<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+) -> Result<Arc<PushJournal>> {
+    let (_logger, repo_type) = get_repo_type(logger, matches, false);
+    open_repo_push_journal(&repo_type)
+}
+
+/// Open the index of the bookmark snapshots of an existing repo, e.g. to list them.
+pub fn open_bookmark_snapshot_index<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+) -> Result<Arc<BookmarkSnapshotIndex>> {
+    let (_logger, repo_type) = get_repo_type(logger, matches, false);
+    open_repo_bookmark_snapshot_index(&repo_type)
+}
+
+/// Open the cross-repo index that an existing repo records its changesets in, e.g. to backfill it.
+pub fn open_cross_repo_index<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+) -> Result<Arc<CrossRepoIndex>> {
+    let (_logger, repo_type) = get_repo_type(logger, matches, false);
+    open_repo_cross_repo_index(&repo_type)
+}
+
+/// Open the status of the derived data of an existing repo, e.g. to check if a changeset's data
+/// was derived.
+pub fn open_derived_data_status<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+) -> Result<Arc<DerivedDataStatus>> {
+    let (_logger, repo_type) = get_repo_type(logger, matches, false);
+    open_repo_derived_data_status(&repo_type)
+}
+
+/// Open the runtime overrides of the flags of an existing repo, e.g. to flip a flag without a
+/// config change.
+pub fn open_flag_overrides<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+) -> Result<Arc<RepoFlagOverrides>> {
+    let (_logger, repo_type) = get_repo_type(logger, matches, false);
+    open_repo_flag_overrides(&repo_type)
+}
+
+/// Limits for opening repos, from `--repo-open-timeout` and `--repo-idle-timeout`
+pub fn get_open_repo_params<'a>(matches: &ArgMatches<'a>) -> OpenRepoParams {
+    let default = OpenRepoParams::default();
+    OpenRepoParams {
+        timeout: get_usize_opt(matches, "repo-open-timeout")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(default.timeout),
+        idle_timeout: get_usize_opt(matches, "repo-idle-timeout")
+            .map(|secs| Duration::from_secs(secs as u64)),
+        ..default
+    }
+}
+
 pub fn setup_repo_dir<P: AsRef<Path>>(data_dir: P, create: bool) -> Result<()> {
     let data_dir = data_dir.as_ref();
 
@@ -397,14 +477,12 @@ pub fn init_cachelib<'a>(matches: &ArgMatches<'a>) {
     ).unwrap();
 }
 
-fn open_repo_internal<'a>(
+fn get_repo_type<'a>(
     logger: &Logger,
     matches: &ArgMatches<'a>,
     create: bool,
-) -> Result<MononokeRepo> {
-    let repo_id = get_repo_id(matches);
-
-    let (logger, repo_type) = match matches.value_of("blobstore") {
+) -> (Logger, RepoType) {
+    match matches.value_of("blobstore") {
         Some("files") => {
             let data_dir = matches
                 .value_of("data-dir")
@@ -441,7 +519,16 @@ fn open_repo_internal<'a>(
             (logger, repo_type)
         }
         Some(bad) => panic!("unexpected blobstore type: {}", bad),
-    };
+    }
+}
+
+fn open_repo_internal<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+    create: bool,
+) -> Result<MononokeRepo> {
+    let repo_id = get_repo_id(matches)?;
+    let (logger, repo_type) = get_repo_type(logger, matches, create);
 
     let myrouter_port = match matches.value_of("myrouter-port") {
         Some(port) => Some(
@@ -451,14 +538,34 @@ fn open_repo_internal<'a>(
         None => None,
     };
 
-    let blobrepo = open_blobrepo(logger.clone(), repo_type.clone(), repo_id, myrouter_port)?;
-    let hook_manager = HookManager::new_with_blobrepo(blobrepo.clone(), logger);
+    let blobrepo = open_blobrepo(
+        logger.clone(),
+        repo_type.clone(),
+        repo_id,
+        myrouter_port,
+        get_open_repo_params(matches),
+    )?;
+    let hook_manager = HookManager::new_with_blobrepo(blobrepo.clone(), None, logger);
     // TODO fixup imports
     Ok(MononokeRepo::new(
         blobrepo,
         &Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
+        Default::default(),
         Arc::new(hook_manager),
         None,
+        None,
+        None,
+        RuntimeRepoFlags::fixed(Default::default()),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
     ))
 }
 
@@ -469,6 +576,9 @@ pub fn parse_manifold_args<'a>(matches: &ArgMatches<'a>) -> ManifoldArgs {
         bucket: matches.value_of("manifold-bucket").unwrap().to_string(),
         prefix: matches.value_of("manifold-prefix").unwrap().to_string(),
         db_address: matches.value_of("db-address").unwrap().to_string(),
+        store_db_addresses: Default::default(),
+        blobstore_retry: default_blobstore_retry_policy(),
+        sql_retry: default_sql_retry_policy(),
     }
 }
 
@@ -483,3 +593,39 @@ pub fn get_usize_opt<'a>(matches: &ArgMatches<'a>, key: &str) -> Option<usize> {
 pub fn get_usize<'a>(matches: &ArgMatches<'a>, key: &str, default: usize) -> usize {
     get_usize_opt(matches, key).unwrap_or(default)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches_from<'a>(args: &[&str]) -> ArgMatches<'a> {
+        let app = MononokeApp {
+            safe_writes: true,
+            hide_advanced_args: false,
+            local_instances: true,
+            default_glog: false,
+        };
+        app.build("test")
+            .get_matches_from_safe(args.to_vec())
+            .expect("invalid arguments")
+    }
+
+    #[test]
+    fn test_repo_id() {
+        let repo_id = |args: &[&str]| get_repo_id(&matches_from(args));
+
+        assert_eq!(
+            repo_id(&["test", "--repo-id", "5"]).unwrap(),
+            RepositoryId::new(5)
+        );
+        assert_eq!(
+            repo_id(&["test", "--repo_id", "0"]).unwrap(),
+            RepositoryId::new(0)
+        );
+
+        let err = repo_id(&["test"]).unwrap_err();
+        assert!(err.to_string().contains("--repo-id"), "{}", err);
+        assert!(repo_id(&["test", "--repo-id=-1"]).is_err());
+        assert!(repo_id(&["test", "--repo-id", "repo"]).is_err());
+    }
+}
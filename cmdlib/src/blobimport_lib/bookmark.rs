@@ -8,7 +8,6 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use ascii::AsciiString;
-use failure::err_msg;
 use failure::prelude::*;
 use futures::{stream, prelude::*};
 use futures_ext::{BoxFuture, FutureExt};
@@ -18,6 +17,7 @@ use blobrepo::BlobRepo;
 use bookmarks::Bookmark;
 use mercurial::RevlogRepo;
 use mercurial_types::HgChangesetId;
+use mononoke_types::ChangesetId;
 
 pub fn read_bookmarks(revlogrepo: RevlogRepo) -> BoxFuture<Vec<(Vec<u8>, HgChangesetId)>, Error> {
     let bookmarks = Arc::new(try_boxfuture!(revlogrepo.get_bookmarks()));
@@ -38,95 +38,541 @@ pub fn read_bookmarks(revlogrepo: RevlogRepo) -> BoxFuture<Vec<(Vec<u8>, HgChang
         .boxify()
 }
 
+/// Number of bookmarks whose changesets are looked up at a time
+const BOOKMARK_CHECK_CONCURRENCY: usize = 100;
+/// Bookmarks are uploaded in transactions of this many bookmarks
+const BOOKMARK_CHUNK_SIZE: usize = 100;
+/// Progress is logged each time this many bookmarks are checked
+const BOOKMARK_PROGRESS_INTERVAL: usize = 5000;
+
+/// How many bookmarks of the revlog repo an import had of each outcome
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BookmarkCounts {
+    /// Imported at their current version
+    pub imported: usize,
+    /// Imported at their stale version, because the changeset of their current version was not
+    /// imported yet
+    pub used_stale: usize,
+    /// Not imported, because the changeset of neither version was imported yet
+    pub skipped_missing: usize,
+    /// Not imported, because their names are not ASCII
+    pub invalid_ascii: usize,
+    /// Not imported, because the transaction that they were in failed
+    pub failed: usize,
+}
+
+/// Where a bookmark of the revlog repo is imported to
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum BookmarkOutcome {
+    Current(Bookmark, ChangesetId),
+    Stale(Bookmark, ChangesetId),
+    Missing,
+    InvalidAscii,
+}
+
+/// Imports the bookmarks of the revlog repo. In a live repo, bookmarks are never moved, see
+/// `create_bookmarks_live`. Unless `strict` is set, a transaction of bookmarks that fails is
+/// counted and the others are still uploaded.
 pub fn upload_bookmarks(
     logger: &Logger,
     revlogrepo: RevlogRepo,
     blobrepo: Arc<BlobRepo>,
     stale_bookmarks: Vec<(Vec<u8>, HgChangesetId)>,
-) -> BoxFuture<(), Error> {
+    live_repo: bool,
+    strict: bool,
+) -> BoxFuture<BookmarkCounts, Error> {
+    cloned!(logger);
+    read_bookmarks(revlogrepo)
+        .and_then(move |bookmarks| {
+            import_bookmarks(
+                &logger,
+                blobrepo,
+                bookmarks,
+                stale_bookmarks,
+                live_repo,
+                strict,
+            )
+        })
+        .boxify()
+}
+
+fn import_bookmarks(
+    logger: &Logger,
+    blobrepo: Arc<BlobRepo>,
+    bookmarks: Vec<(Vec<u8>, HgChangesetId)>,
+    stale_bookmarks: Vec<(Vec<u8>, HgChangesetId)>,
+    live_repo: bool,
+    strict: bool,
+) -> BoxFuture<BookmarkCounts, Error> {
     let logger = logger.clone();
     let stale_bookmarks = Arc::new(stale_bookmarks.into_iter().collect::<HashMap<_, _>>());
+    let total = bookmarks.len();
+    info!(logger, "importing {} bookmarks", total);
 
-    read_bookmarks(revlogrepo)
+    stream::iter_ok(bookmarks)
         .map({
-            cloned!(logger, blobrepo, stale_bookmarks);
-            move |bookmarks| {
-                stream::futures_unordered(bookmarks.into_iter().map(|(key, cs_id)| {
-                    blobrepo
-                        .changeset_exists(&cs_id)
-                        .and_then({
-                            cloned!(logger, key, blobrepo, stale_bookmarks);
-                            move |exists| {
-                                match (exists, stale_bookmarks.get(&key).cloned()) {
-                                    (false, Some(stale_cs_id)) => {
-                                        info!(
-                                            logger,
-                                            "current version of bookmark {:?} couldn't be \
-                                            imported, because cs {:?} was not present in blobrepo \
-                                            yet; using stale version instead {:?}",
-                                            key,
-                                            cs_id,
-                                            stale_cs_id,
-                                        );
-
-                                        blobrepo
-                                            .changeset_exists(&stale_cs_id)
-                                            .map(move |exists| (key, stale_cs_id, exists))
-                                            .boxify()
-                                    }
-                                    _ => Ok((key, cs_id, exists)).into_future().boxify(),
-                                }
-                            }})
-                        .and_then({
-                            cloned!(blobrepo, logger);
-                            move |(key, cs_id, exists)| {
-                                if exists {
-                                    blobrepo.get_bonsai_from_hg(&cs_id)
-                                        .and_then(move |bcs_id| bcs_id.ok_or(err_msg(
-                                            format!("failed to resolve hg to bonsai: {}", cs_id),
-                                        )))
-                                        .map(move |bcs_id| Some((key, bcs_id)))
-                                        .left_future()
-                                } else {
-                                    info!(
-                                        logger,
-                                        "did not update bookmark {:?}, because cs {:?} was not imported yet",
-                                        key,
-                                        cs_id,
-                                    );
-                                    Ok(None).into_future().right_future()
-                                }
-                            }
-                        })
-                }))
+            cloned!(logger, blobrepo);
+            move |(key, cs_id)| {
+                resolve_bookmark(logger.clone(), blobrepo.clone(), &stale_bookmarks, key, cs_id)
+            }
+        })
+        .buffer_unordered(BOOKMARK_CHECK_CONCURRENCY)
+        .enumerate()
+        .map({
+            cloned!(logger);
+            move |(index, outcome)| {
+                if (index + 1) % BOOKMARK_PROGRESS_INTERVAL == 0 {
+                    info!(logger, "checked {} of {} bookmarks", index + 1, total);
+                }
+                outcome
             }
         })
-        .flatten_stream()
-        .filter_map(|key_cs_id| key_cs_id)
-        .chunks(100) // send 100 bookmarks in a single transaction
+        .collect()
         .and_then({
-            let blobrepo = blobrepo.clone();
-            move |vec| {
-                let count = vec.len();
-                let mut transaction = blobrepo.update_bookmark_transaction();
-
-                for (key, value) in vec {
-                    let key = Bookmark::new_ascii(try_boxfuture!(AsciiString::from_ascii(key)));
-                    try_boxfuture!(transaction.force_set(&key, &value))
+            cloned!(logger);
+            move |outcomes| {
+                let mut counts = BookmarkCounts::default();
+                let mut bookmarks = Vec::new();
+                for outcome in outcomes {
+                    match outcome {
+                        BookmarkOutcome::Current(key, cs_id) => bookmarks.push((key, cs_id, false)),
+                        BookmarkOutcome::Stale(key, cs_id) => bookmarks.push((key, cs_id, true)),
+                        BookmarkOutcome::Missing => counts.skipped_missing += 1,
+                        BookmarkOutcome::InvalidAscii => counts.invalid_ascii += 1,
+                    }
                 }
 
-                transaction.commit()
-                    .and_then(move |ok| {
-                        if ok {
-                            Ok(count)
+                if live_repo {
+                    for &(_, _, stale) in &bookmarks {
+                        if stale {
+                            counts.used_stale += 1;
                         } else {
-                            Err(format_err!("Bookmark transaction failed"))
+                            counts.imported += 1;
                         }
-                    })
-                    .boxify()
+                    }
+                    let bookmarks = bookmarks
+                        .into_iter()
+                        .map(|(key, cs_id, _)| (key, cs_id))
+                        .collect();
+                    create_bookmarks_live(blobrepo, bookmarks)
+                        .map(move |updates| {
+                            log_live_bookmarks(&logger, &updates);
+                            counts
+                        })
+                        .boxify()
+                } else {
+                    set_bookmarks(logger, blobrepo, bookmarks, counts, strict)
+                }
+            }
+        })
+        .map(move |counts| {
+            info!(
+                logger,
+                "bookmarks: {} imported, {} imported at a stale version, {} skipped because \
+                 their changesets are missing, {} skipped because they are not ascii, {} failed",
+                counts.imported,
+                counts.used_stale,
+                counts.skipped_missing,
+                counts.invalid_ascii,
+                counts.failed
+            );
+            counts
+        })
+        .boxify()
+}
+
+/// Finds out where a bookmark is imported to: its current version, or its stale version if the
+/// changeset of its current version was not imported yet
+fn resolve_bookmark(
+    logger: Logger,
+    blobrepo: Arc<BlobRepo>,
+    stale_bookmarks: &HashMap<Vec<u8>, HgChangesetId>,
+    key: Vec<u8>,
+    cs_id: HgChangesetId,
+) -> BoxFuture<BookmarkOutcome, Error> {
+    let bookmark = match AsciiString::from_ascii(key.clone())
+        .map_err(Error::from)
+        .and_then(Bookmark::new_ascii)
+    {
+        Ok(bookmark) => bookmark,
+        Err(err) => {
+            debug!(logger, "did not import bookmark {:?}: {}", key, err);
+            return future::ok(BookmarkOutcome::InvalidAscii).boxify();
+        }
+    };
+    let stale_cs_id = stale_bookmarks.get(&key).cloned();
+
+    blobrepo
+        .changeset_exists(&cs_id)
+        .and_then(move |exists| {
+            if exists {
+                return get_bonsai(&blobrepo, cs_id)
+                    .map(move |bcs_id| BookmarkOutcome::Current(bookmark, bcs_id))
+                    .boxify();
+            }
+            let stale_cs_id = match stale_cs_id {
+                Some(stale_cs_id) => stale_cs_id,
+                None => {
+                    debug!(
+                        logger,
+                        "did not import bookmark {}, because cs {} was not imported yet",
+                        bookmark,
+                        cs_id,
+                    );
+                    return future::ok(BookmarkOutcome::Missing).boxify();
+                }
+            };
+            blobrepo
+                .changeset_exists(&stale_cs_id)
+                .and_then(move |exists| {
+                    if exists {
+                        debug!(
+                            logger,
+                            "current version of bookmark {} couldn't be imported, because cs {} \
+                             was not imported yet; using stale version {} instead",
+                            bookmark,
+                            cs_id,
+                            stale_cs_id,
+                        );
+                        get_bonsai(&blobrepo, stale_cs_id)
+                            .map(move |bcs_id| BookmarkOutcome::Stale(bookmark, bcs_id))
+                            .left_future()
+                    } else {
+                        debug!(
+                            logger,
+                            "did not import bookmark {}, because neither cs {} nor its stale \
+                             version {} were imported yet",
+                            bookmark,
+                            cs_id,
+                            stale_cs_id,
+                        );
+                        future::ok(BookmarkOutcome::Missing).right_future()
+                    }
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn get_bonsai(blobrepo: &BlobRepo, cs_id: HgChangesetId) -> BoxFuture<ChangesetId, Error> {
+    blobrepo
+        .get_bonsai_from_hg(&cs_id)
+        .and_then(move |bcs_id| {
+            bcs_id.ok_or_else(|| format_err!("failed to resolve hg to bonsai: {}", cs_id))
+        })
+        .boxify()
+}
+
+/// Sets the bookmarks of a repo that is not live, `BOOKMARK_CHUNK_SIZE` bookmarks per
+/// transaction. The flag of each bookmark tells if it is at its stale version.
+fn set_bookmarks(
+    logger: Logger,
+    blobrepo: Arc<BlobRepo>,
+    bookmarks: Vec<(Bookmark, ChangesetId, bool)>,
+    counts: BookmarkCounts,
+    strict: bool,
+) -> BoxFuture<BookmarkCounts, Error> {
+    stream::iter_ok(bookmarks)
+        .chunks(BOOKMARK_CHUNK_SIZE)
+        .and_then(move |chunk| {
+            commit_bookmarks(&blobrepo, &chunk).then(move |res| Ok::<_, Error>((chunk, res)))
+        })
+        .fold(counts, move |mut counts, (chunk, res)| {
+            match res {
+                Ok(()) => {
+                    debug!(logger, "uploaded chunk of {} bookmarks", chunk.len());
+                    for (_, _, stale) in chunk {
+                        if stale {
+                            counts.used_stale += 1;
+                        } else {
+                            counts.imported += 1;
+                        }
+                    }
+                }
+                Err(err) => {
+                    if strict {
+                        return Err(err);
+                    }
+                    warn!(
+                        logger,
+                        "failed to upload chunk of {} bookmarks: {}",
+                        chunk.len(),
+                        err
+                    );
+                    counts.failed += chunk.len();
+                }
+            }
+            Ok(counts)
+        })
+        .boxify()
+}
+
+fn commit_bookmarks(
+    blobrepo: &BlobRepo,
+    bookmarks: &[(Bookmark, ChangesetId, bool)],
+) -> BoxFuture<(), Error> {
+    let mut transaction = blobrepo.update_bookmark_transaction();
+    for &(ref key, ref value, _) in bookmarks {
+        try_boxfuture!(transaction.force_set(key, value));
+    }
+    transaction
+        .commit()
+        .and_then(|ok| {
+            if ok {
+                Ok(())
+            } else {
+                Err(format_err!("Bookmark transaction failed"))
             }
-        }).for_each(move |count| {
-            info!(logger, "uploaded chunk of {:?} bookmarks", count);
-            Ok(())
-        }).boxify()
+        })
+        .boxify()
+}
+
+/// What a live import did with a bookmark
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LiveBookmarkUpdate {
+    Created(ChangesetId),
+    /// The bookmark already pointed to the imported changeset
+    Unchanged(ChangesetId),
+    /// The bookmark points to another changeset in the repo, where it was left
+    Conflict {
+        imported: ChangesetId,
+        current: ChangesetId,
+    },
+}
+
+/// Creates the bookmarks that a live repo doesn't have. Bookmarks that the repo has are never
+/// moved, so that nothing that was pushed to the repo is clobbered. A bookmark that points to
+/// another changeset in the repo, including one that was created while it was imported, is a
+/// conflict.
+pub fn create_bookmarks_live(
+    blobrepo: Arc<BlobRepo>,
+    bookmarks: Vec<(Bookmark, ChangesetId)>,
+) -> BoxFuture<Vec<(Bookmark, LiveBookmarkUpdate)>, Error> {
+    stream::iter_ok(bookmarks)
+        .and_then(move |(bookmark, cs_id)| {
+            cloned!(blobrepo);
+            get_bonsai_bookmark(&blobrepo, &bookmark).and_then(move |current| match current {
+                Some(current) => future::ok((bookmark, compare_bookmark(cs_id, current))).boxify(),
+                None => {
+                    let mut transaction = blobrepo.update_bookmark_transaction();
+                    try_boxfuture!(transaction.create(&bookmark, &cs_id));
+                    transaction
+                        .commit()
+                        .and_then(move |created| {
+                            if created {
+                                return future::ok((bookmark, LiveBookmarkUpdate::Created(cs_id)))
+                                    .boxify();
+                            }
+                            // Created in the repo in the meantime
+                            get_bonsai_bookmark(&blobrepo, &bookmark)
+                                .and_then(move |current| match current {
+                                    Some(current) => {
+                                        Ok((bookmark, compare_bookmark(cs_id, current)))
+                                    }
+                                    None => Err(format_err!(
+                                        "bookmark {} changed while it was imported",
+                                        bookmark
+                                    )),
+                                })
+                                .boxify()
+                        })
+                        .boxify()
+                }
+            })
+        })
+        .collect()
+        .boxify()
+}
+
+fn get_bonsai_bookmark(
+    blobrepo: &BlobRepo,
+    bookmark: &Bookmark,
+) -> BoxFuture<Option<ChangesetId>, Error> {
+    blobrepo
+        .get_bookmarks_object()
+        .get(bookmark, &blobrepo.get_repoid())
+}
+
+fn compare_bookmark(imported: ChangesetId, current: ChangesetId) -> LiveBookmarkUpdate {
+    if imported == current {
+        LiveBookmarkUpdate::Unchanged(current)
+    } else {
+        LiveBookmarkUpdate::Conflict { imported, current }
+    }
+}
+
+fn log_live_bookmarks(logger: &Logger, updates: &[(Bookmark, LiveBookmarkUpdate)]) {
+    let mut created = 0;
+    let mut unchanged = 0;
+    let mut conflicts = 0;
+    for &(ref bookmark, ref update) in updates {
+        match *update {
+            LiveBookmarkUpdate::Created(_) => created += 1,
+            LiveBookmarkUpdate::Unchanged(_) => unchanged += 1,
+            LiveBookmarkUpdate::Conflict { imported, current } => {
+                conflicts += 1;
+                warn!(
+                    logger,
+                    "bookmark {} conflicts: it is {} in the repo, and {} in the import, so it was \
+                     left as it is",
+                    bookmark,
+                    current,
+                    imported
+                );
+            }
+        }
+    }
+    info!(
+        logger,
+        "bookmarks: {} created, {} unchanged, {} conflicts", created, unchanged, conflicts
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use async_unit;
+    use fixtures::linear;
+
+    use mercurial_types_mocks::nodehash::{ONES_CSID, TWOS_CSID};
+    use slog::Discard;
+
+    // The first two commits of linear
+    const ROOT: &str = "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536";
+    const SECOND: &str = "3e0e761030db6e479a7fb58b12881883f9f8c63f";
+
+    fn bonsai(blobrepo: &BlobRepo, csid: &str) -> ChangesetId {
+        let csid = HgChangesetId::from_str(csid).unwrap();
+        blobrepo.get_bonsai_from_hg(&csid).wait().unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_create_bookmarks_live() {
+        async_unit::tokio_unit_test(|| {
+            let blobrepo = Arc::new(linear::getrepo(None));
+            let root = bonsai(&blobrepo, ROOT);
+            let second = bonsai(&blobrepo, SECOND);
+            let master = Bookmark::new("master").unwrap();
+            let pushed = Bookmark::new("pushed").unwrap();
+            let same = Bookmark::new("same").unwrap();
+
+            // Bookmarks that were pushed to the repo before the import
+            let mut transaction = blobrepo.update_bookmark_transaction();
+            transaction.force_set(&pushed, &second).unwrap();
+            transaction.force_set(&same, &root).unwrap();
+            assert!(transaction.commit().wait().unwrap());
+
+            let bookmarks = vec![
+                (master.clone(), root),
+                (pushed.clone(), root),
+                (same.clone(), root),
+            ];
+            let updates = create_bookmarks_live(blobrepo.clone(), bookmarks)
+                .wait()
+                .unwrap();
+            assert_eq!(
+                updates,
+                vec![
+                    (master.clone(), LiveBookmarkUpdate::Created(root)),
+                    (
+                        pushed.clone(),
+                        LiveBookmarkUpdate::Conflict {
+                            imported: root,
+                            current: second,
+                        },
+                    ),
+                    (same.clone(), LiveBookmarkUpdate::Unchanged(root)),
+                ]
+            );
+
+            // Nothing was clobbered
+            assert_eq!(get_bonsai_bookmark(&blobrepo, &master).wait().unwrap(), Some(root));
+            assert_eq!(get_bonsai_bookmark(&blobrepo, &pushed).wait().unwrap(), Some(second));
+            assert_eq!(get_bonsai_bookmark(&blobrepo, &same).wait().unwrap(), Some(root));
+        })
+    }
+
+    #[test]
+    fn test_import_bookmarks() {
+        async_unit::tokio_unit_test(|| {
+            let blobrepo = Arc::new(linear::getrepo(None));
+            let logger = Logger::root(Discard, o!());
+            let root = HgChangesetId::from_str(ROOT).unwrap();
+            let second = HgChangesetId::from_str(SECOND).unwrap();
+
+            // ONES_CSID and TWOS_CSID are not in the repo
+            let bookmarks = vec![
+                (b"current".to_vec(), second),
+                (b"stale".to_vec(), ONES_CSID),
+                (b"missing".to_vec(), ONES_CSID),
+                (b"stale_missing".to_vec(), ONES_CSID),
+                (b"not\xffascii".to_vec(), root),
+            ];
+            let stale_bookmarks = vec![
+                (b"current".to_vec(), root),
+                (b"stale".to_vec(), root),
+                (b"stale_missing".to_vec(), TWOS_CSID),
+            ];
+            let counts = import_bookmarks(
+                &logger,
+                blobrepo.clone(),
+                bookmarks,
+                stale_bookmarks,
+                false,
+                true,
+            ).wait()
+                .unwrap();
+            assert_eq!(
+                counts,
+                BookmarkCounts {
+                    imported: 1,
+                    used_stale: 1,
+                    skipped_missing: 2,
+                    invalid_ascii: 1,
+                    failed: 0,
+                }
+            );
+
+            let get = |name: &str| {
+                get_bonsai_bookmark(&blobrepo, &Bookmark::new(name).unwrap())
+                    .wait()
+                    .unwrap()
+            };
+            assert_eq!(get("current"), Some(bonsai(&blobrepo, SECOND)));
+            assert_eq!(get("stale"), Some(bonsai(&blobrepo, ROOT)));
+            assert_eq!(get("missing"), None);
+            assert_eq!(get("stale_missing"), None);
+        })
+    }
+
+    #[test]
+    fn test_import_bookmarks_live() {
+        async_unit::tokio_unit_test(|| {
+            let blobrepo = Arc::new(linear::getrepo(None));
+            let logger = Logger::root(Discard, o!());
+            let root = HgChangesetId::from_str(ROOT).unwrap();
+
+            let bookmarks = vec![(b"master".to_vec(), root), (b"missing".to_vec(), ONES_CSID)];
+            let counts = import_bookmarks(&logger, blobrepo.clone(), bookmarks, vec![], true, true)
+                .wait()
+                .unwrap();
+            assert_eq!(
+                counts,
+                BookmarkCounts {
+                    imported: 1,
+                    skipped_missing: 1,
+                    ..BookmarkCounts::default()
+                }
+            );
+            assert_eq!(
+                get_bonsai_bookmark(&blobrepo, &Bookmark::new("master").unwrap())
+                    .wait()
+                    .unwrap(),
+                Some(bonsai(&blobrepo, ROOT))
+            );
+        })
+    }
 }
@@ -4,7 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 use bytes::Bytes;
@@ -15,14 +15,43 @@ use futures::future::{self, SharedItem};
 use futures::stream::{self, Stream};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use scuba_ext::ScubaSampleBuilder;
+use slog::Logger;
 
 use blobrepo::{BlobRepo, ChangesetHandle, ChangesetMetadata, CreateChangeset, HgBlobChangeset,
                HgBlobEntry, UploadHgFileContents, UploadHgFileEntry, UploadHgNodeHash,
                UploadHgTreeEntry};
+use bundle2_resolver::{check_paths, format_violations};
 use mercurial::{manifest, RevlogChangeset, RevlogEntry, RevlogRepo};
 use mercurial_types::{HgBlob, HgChangesetId, HgManifestId, HgNodeHash, MPath, RepoPath, Type,
                       NULL_HASH};
-use mononoke_types::BonsaiChangeset;
+use metaconfig::PathRules;
+use mononoke_types::{BonsaiChangeset, MaybeUtf8Bytes};
+
+use super::metrics::ImportMetrics;
+
+/// Changeset metadata has to be UTF-8, so an author or a message that is not is converted lossily.
+/// The hash of the changeset then doesn't match, and its import fails with an error instead of a
+/// panic.
+fn changeset_metadata(
+    logger: &Logger,
+    csid: HgChangesetId,
+    cs: &RevlogChangeset,
+) -> ChangesetMetadata {
+    let user = MaybeUtf8Bytes::from(cs.user());
+    let comments = MaybeUtf8Bytes::from(cs.comments());
+    if !user.is_utf8() || !comments.is_utf8() {
+        warn!(
+            logger,
+            "changeset {} has an author or message that is not UTF-8, author: {}", csid, user
+        );
+    }
+    ChangesetMetadata {
+        user: user.to_lossy_string(),
+        time: cs.time().clone(),
+        extra: cs.extra().clone(),
+        comments: comments.to_lossy_string(),
+    }
+}
 
 struct ParseChangeset {
     revlogcs: BoxFuture<SharedItem<RevlogChangeset>, Error>,
@@ -140,11 +169,41 @@ fn parse_changeset(revlog_repo: RevlogRepo, csid: HgChangesetId) -> ParseChanges
     }
 }
 
+/// Directories over the fanout limit of pushes are logged, but imported anyway as history can't
+/// be fixed
+fn log_large_directory(
+    logger: &Logger,
+    csid: HgNodeHash,
+    path: &RepoPath,
+    content: &[u8],
+    max_directory_fanout: usize,
+) {
+    // A tree manifest has a line per entry
+    let fanout = content
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .count();
+    if fanout > max_directory_fanout {
+        warn!(
+            logger,
+            "changeset {}: {} has {} entries, over the limit of {} entries for pushes",
+            csid,
+            path,
+            fanout,
+            max_directory_fanout
+        );
+    }
+}
+
 fn upload_entry(
+    logger: &Logger,
     blobrepo: &BlobRepo,
+    csid: HgNodeHash,
     entry: RevlogEntry,
     path: Option<MPath>,
+    max_directory_fanout: usize,
 ) -> BoxFuture<(HgBlobEntry, RepoPath), Error> {
+    let logger = logger.clone();
     let blobrepo = blobrepo.clone();
 
     let ty = entry.get_type();
@@ -170,12 +229,20 @@ fn upload_entry(
             let upload_node_id = UploadHgNodeHash::Checked(entry.get_hash().into_nodehash());
             match ty {
                 Type::Tree => {
+                    let path = RepoPath::DirectoryPath(path);
+                    log_large_directory(
+                        &logger,
+                        csid,
+                        &path,
+                        content.as_slice(),
+                        max_directory_fanout,
+                    );
                     let upload = UploadHgTreeEntry {
                         upload_node_id,
                         contents: content.into_inner(),
                         p1: p1.cloned(),
                         p2: p2.cloned(),
-                        path: RepoPath::DirectoryPath(path),
+                        path,
                     };
                     let (_, upload_fut) = try_boxfuture!(upload.upload(&blobrepo));
                     upload_fut
@@ -197,96 +264,237 @@ fn upload_entry(
         .boxify()
 }
 
+/// The changesets of the revlog repo to import, in the order of the revlog
+pub fn select_changesets(
+    revlogrepo: &RevlogRepo,
+    changeset: Option<HgNodeHash>,
+    skip: Option<usize>,
+    commits_limit: Option<usize>,
+) -> BoxStream<HgNodeHash, Error> {
+    let changesets = match changeset {
+        Some(hash) => future::ok(hash).into_stream().boxify(),
+        None => revlogrepo.changesets().boxify(),
+    };
+
+    let changesets = match skip {
+        None => changesets,
+        Some(skip) => changesets.skip(skip as u64).boxify(),
+    };
+
+    match commits_limit {
+        None => changesets,
+        Some(limit) => changesets.take(limit as u64).boxify(),
+    }
+}
+
+/// A changeset of the import, once it is in the repo
+pub enum ImportedChangeset {
+    Uploaded(SharedItem<(BonsaiChangeset, HgBlobChangeset)>),
+    /// The repo already had it
+    Skipped(HgChangesetId),
+}
+
+enum ParsedChangeset {
+    /// The last field has the paths of the files that the changeset added or modified, unlike
+    /// the files of the changeset, which also has the deleted ones
+    New(
+        HgNodeHash,
+        SharedItem<RevlogChangeset>,
+        BoxFuture<Option<(HgBlobEntry, RepoPath)>, Error>,
+        Vec<BoxFuture<(HgBlobEntry, RepoPath), Error>>,
+        HashSet<MPath>,
+    ),
+    Existing(HgNodeHash),
+}
+
+/// Reads a changeset from the revlog repo and uploads its root manifest and entries
+fn parse_and_upload_entries(
+    logger: Logger,
+    revlogrepo: RevlogRepo,
+    blobrepo: Arc<BlobRepo>,
+    csid: HgNodeHash,
+    max_directory_fanout: usize,
+) -> BoxFuture<ParsedChangeset, Error> {
+    let ParseChangeset {
+        revlogcs,
+        rootmf,
+        entries,
+    } = parse_changeset(revlogrepo.clone(), HgChangesetId::new(csid));
+
+    let rootmf = rootmf.map({
+        let logger = logger.clone();
+        let blobrepo = blobrepo.clone();
+        move |rootmf| {
+            match rootmf {
+                None => future::ok(None).boxify(),
+                Some((manifest_id, blob, p1, p2)) => {
+                    log_large_directory(
+                        &logger,
+                        csid,
+                        &RepoPath::root(),
+                        blob.as_slice(),
+                        max_directory_fanout,
+                    );
+                    let upload = UploadHgTreeEntry {
+                        // The root tree manifest is expected to have the wrong hash in hybrid
+                        // mode. This will probably never go away for compatibility with old
+                        // repositories.
+                        upload_node_id: UploadHgNodeHash::Supplied(manifest_id.into_nodehash()),
+                        contents: blob.into_inner(),
+                        p1,
+                        p2,
+                        path: RepoPath::root(),
+                    };
+                    upload
+                        .upload(&blobrepo)
+                        .into_future()
+                        .and_then(|(_, entry)| entry)
+                        .map(Some)
+                        .boxify()
+                }
+            }
+        }
+    });
+
+    let entries = entries.map({
+        let blobrepo = blobrepo.clone();
+        move |(path, entry)| {
+            let file_path = match entry.get_type() {
+                Type::File(_) => MPath::join_element_opt(path.as_ref(), entry.get_name()),
+                Type::Tree => None,
+            };
+            let upload = upload_entry(&logger, &blobrepo, csid, entry, path, max_directory_fanout);
+            (file_path, upload)
+        }
+    });
+
+    revlogcs
+        .join3(rootmf, entries.collect())
+        .map(move |(cs, rootmf, entries)| {
+            let (file_paths, entries): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+            let changed_files = file_paths.into_iter().filter_map(|path| path).collect();
+            ParsedChangeset::New(csid, cs, rootmf, entries, changed_files)
+        })
+        .boxify()
+}
+
 pub struct UploadChangesets {
+    pub logger: Logger,
     pub blobrepo: Arc<BlobRepo>,
     pub revlogrepo: RevlogRepo,
     pub changeset: Option<HgNodeHash>,
     pub skip: Option<usize>,
     pub commits_limit: Option<usize>,
+    pub path_rules: PathRules,
+    pub path_violations_are_warnings: bool,
+    /// Changesets with larger messages are logged, but imported anyway as history can't be fixed
+    pub max_commit_message_bytes: usize,
+    /// Directories with more entries are logged, but imported anyway
+    pub max_directory_fanout: usize,
+    /// The repo serves pushes while it is imported into, so changesets that it already has are
+    /// skipped. A changeset that is pushed after the check is created again, with the same
+    /// content, which doesn't change it.
+    pub live_repo: bool,
+    /// Aggregates the metrics of the created changesets
+    pub metrics: Option<ImportMetrics>,
 }
 
 impl UploadChangesets {
-    pub fn upload(
-        self,
-    ) -> BoxStream<BoxFuture<SharedItem<(BonsaiChangeset, HgBlobChangeset)>, Error>, Error> {
+    pub fn upload(self) -> BoxStream<BoxFuture<ImportedChangeset, Error>, Error> {
         let Self {
+            logger,
             blobrepo,
             revlogrepo,
             changeset,
             skip,
             commits_limit,
+            path_rules,
+            path_violations_are_warnings,
+            max_commit_message_bytes,
+            max_directory_fanout,
+            live_repo,
+            metrics,
         } = self;
 
-        let changesets = match changeset {
-            Some(hash) => future::ok(hash).into_stream().boxify(),
-            None => revlogrepo.changesets().boxify(),
-        };
-
-        let changesets = match skip {
-            None => changesets,
-            Some(skip) => changesets.skip(skip as u64).boxify(),
-        };
-
-        let changesets = match commits_limit {
-            None => changesets,
-            Some(limit) => changesets.take(limit as u64).boxify(),
-        };
+        let changesets = select_changesets(&revlogrepo, changeset, skip, commits_limit);
 
         let is_import_from_beggining = changeset.is_none() && skip.is_none();
         let mut parent_changeset_handles: HashMap<HgNodeHash, ChangesetHandle> = HashMap::new();
 
         changesets
             .map({
+                let logger = logger.clone();
                 let revlogrepo = revlogrepo.clone();
                 let blobrepo = blobrepo.clone();
                 move |csid| {
-                    let ParseChangeset {
-                        revlogcs,
-                        rootmf,
-                        entries,
-                    } = parse_changeset(revlogrepo.clone(), HgChangesetId::new(csid));
-
-                    let rootmf = rootmf.map({
-                        let blobrepo = blobrepo.clone();
-                        move |rootmf| {
-                            match rootmf {
-                                None => future::ok(None).boxify(),
-                                Some((manifest_id, blob, p1, p2)) => {
-                                    let upload = UploadHgTreeEntry {
-                                        // The root tree manifest is expected to have the wrong hash in
-                                        // hybrid mode. This will probably never go away for
-                                        // compatibility with old repositories.
-                                        upload_node_id: UploadHgNodeHash::Supplied(
-                                            manifest_id.into_nodehash(),
-                                        ),
-                                        contents: blob.into_inner(),
-                                        p1,
-                                        p2,
-                                        path: RepoPath::root(),
-                                    };
-                                    upload
-                                        .upload(&blobrepo)
-                                        .into_future()
-                                        .and_then(|(_, entry)| entry)
-                                        .map(Some)
-                                        .boxify()
-                                }
-                            }
+                    let exists = if live_repo {
+                        blobrepo
+                            .changeset_exists(&HgChangesetId::new(csid))
+                            .left_future()
+                    } else {
+                        future::ok(false).right_future()
+                    };
+                    cloned!(logger, revlogrepo, blobrepo);
+                    exists.and_then(move |exists| {
+                        if exists {
+                            future::ok(ParsedChangeset::Existing(csid)).left_future()
+                        } else {
+                            parse_and_upload_entries(
+                                logger,
+                                revlogrepo,
+                                blobrepo,
+                                csid,
+                                max_directory_fanout,
+                            ).right_future()
                         }
-                    });
-
-                    let entries = entries.map({
-                        let blobrepo = blobrepo.clone();
-                        move |(path, entry)| upload_entry(&blobrepo, entry, path)
-                    });
-
-                    revlogcs
-                        .join3(rootmf, entries.collect())
-                        .map(move |(cs, rootmf, entries)| (csid, cs, rootmf, entries))
+                    })
                 }
             })
             .buffered(100)
-            .map(move |(csid, cs, rootmf, entries)| {
+            .map(move |parsed| {
+                let (csid, cs, rootmf, entries, changed_files) = match parsed {
+                    ParsedChangeset::New(csid, cs, rootmf, entries, changed_files) => {
+                        (csid, cs, rootmf, entries, changed_files)
+                    }
+                    ParsedChangeset::Existing(csid) => {
+                        let hg_cs_id = HgChangesetId::new(csid);
+                        let cshandle = ChangesetHandle::ready_cs_handle(blobrepo.clone(), hg_cs_id);
+                        parent_changeset_handles.insert(csid, cshandle);
+                        return future::ok(ImportedChangeset::Skipped(hg_cs_id)).boxify();
+                    }
+                };
+
+                // Deleted paths are not validated: a changeset can always remove an invalid path
+                let violations = check_paths(
+                    &path_rules,
+                    cs.files()
+                        .iter()
+                        .filter(|path| changed_files.contains(path)),
+                );
+                if !violations.is_empty() {
+                    let message = format!(
+                        "changeset {} has invalid paths:\n{}",
+                        csid,
+                        format_violations(&violations)
+                    );
+                    if path_violations_are_warnings {
+                        // History can't be fixed, so the changeset is imported anyway
+                        warn!(logger, "{}", message);
+                    } else {
+                        return future::err(err_msg(message)).boxify();
+                    }
+                }
+                if cs.comments().len() > max_commit_message_bytes {
+                    warn!(
+                        logger,
+                        "changeset {} has a message of {} bytes, over the limit of {} bytes for \
+                         pushes",
+                        csid,
+                        cs.comments().len(),
+                        max_commit_message_bytes
+                    );
+                }
+
                 let entries = stream::futures_unordered(entries).boxify();
 
                 let (p1handle, p2handle) = {
@@ -308,14 +516,7 @@ impl UploadChangesets {
                     (parents.next(), parents.next())
                 };
 
-                let cs_metadata = ChangesetMetadata {
-                    user: String::from_utf8(Vec::from(cs.user()))
-                        .expect(&format!("non-utf8 username for {}", csid)),
-                    time: cs.time().clone(),
-                    extra: cs.extra().clone(),
-                    comments: String::from_utf8(Vec::from(cs.comments()))
-                        .expect(&format!("non-utf8 comments for {}", csid)),
-                };
+                let cs_metadata = changeset_metadata(&logger, csid, &cs);
                 let create_changeset = CreateChangeset {
                     expected_nodeid: Some(csid),
                     expected_files: Some(Vec::from(cs.files())),
@@ -326,6 +527,7 @@ impl UploadChangesets {
                     cs_metadata,
                     // Repositories can contain case conflicts - we still need to import them
                     must_check_case_conflicts: false,
+                    metrics: metrics.as_ref().map(ImportMetrics::collector),
                 };
                 let cshandle =
                     create_changeset.create(&blobrepo, ScubaSampleBuilder::with_discard());
@@ -334,8 +536,61 @@ impl UploadChangesets {
                     .get_completed_changeset()
                     .with_context(move |_| format!("While uploading changeset: {}", csid))
                     .from_err()
+                    .map({
+                        cloned!(metrics);
+                        move |cs| {
+                            if let Some(metrics) = metrics {
+                                metrics.record(&cs.1.get_changeset_id());
+                            }
+                            ImportedChangeset::Uploaded(cs)
+                        }
+                    })
                     .boxify()
             })
             .boxify()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use slog::Discard;
+
+    use mercurial_types::{HgParents, NULL_CSID};
+    use mononoke_types::DateTime;
+
+    fn revlog_changeset(user: &[u8], comments: &[u8]) -> RevlogChangeset {
+        RevlogChangeset::new_from_parts(
+            HgParents::None,
+            HgManifestId::new(NULL_HASH),
+            user.to_vec(),
+            DateTime::from_timestamp(0, 0).unwrap(),
+            BTreeMap::new(),
+            vec![],
+            comments.to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_changeset_metadata() {
+        let logger = Logger::root(Discard, o!());
+
+        let cs = revlog_changeset(b"author", b"message");
+        let metadata = changeset_metadata(&logger, NULL_CSID, &cs);
+        assert_eq!(metadata.user, "author");
+        assert_eq!(metadata.comments, "message");
+
+        let cs = revlog_changeset(b"auth\xffor", b"message");
+        let metadata = changeset_metadata(&logger, NULL_CSID, &cs);
+        assert_eq!(metadata.user, "auth\u{fffd}or");
+        assert_eq!(metadata.comments, "message");
+
+        let cs = revlog_changeset(b"author", b"\xe9t\xe9");
+        let metadata = changeset_metadata(&logger, NULL_CSID, &cs);
+        assert_eq!(metadata.user, "author");
+        assert_eq!(metadata.comments, "\u{fffd}t\u{fffd}");
+    }
+}
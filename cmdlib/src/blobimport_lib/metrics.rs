@@ -0,0 +1,159 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use slog::Logger;
+
+use blobrepo::{ChangesetMetrics, ChangesetMetricsCollector};
+use mercurial_types::HgChangesetId;
+
+/// The phases of the creation of a changeset, as they are logged
+fn phases(metrics: &ChangesetMetrics) -> [(&'static str, Duration); 5] {
+    [
+        ("upload_entries", metrics.upload_entries),
+        ("changed_files", metrics.changed_files),
+        ("bonsai", metrics.bonsai),
+        ("save", metrics.save),
+        ("commit", metrics.commit),
+    ]
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+#[derive(Default)]
+struct ImportMetricsInner {
+    changesets: u64,
+    totals: ChangesetMetrics,
+    /// The changesets since the percentiles were last logged
+    window: Vec<ChangesetMetrics>,
+}
+
+/// Aggregates the metrics of the changesets of an import. The percentiles of the phases are logged
+/// every `log_every` changesets, and the totals at the end of the import.
+#[derive(Clone)]
+pub struct ImportMetrics {
+    logger: Logger,
+    log_every: usize,
+    collector: ChangesetMetricsCollector,
+    inner: Arc<Mutex<ImportMetricsInner>>,
+}
+
+impl ImportMetrics {
+    pub fn new(logger: Logger, log_every: usize) -> Self {
+        ImportMetrics {
+            logger,
+            log_every,
+            collector: ChangesetMetricsCollector::new(),
+            inner: Arc::new(Mutex::new(ImportMetricsInner::default())),
+        }
+    }
+
+    /// The collector to create the changesets with
+    pub fn collector(&self) -> ChangesetMetricsCollector {
+        self.collector.clone()
+    }
+
+    /// Adds the metrics of a changeset that was created with `collector()`
+    pub fn record(&self, csid: &HgChangesetId) {
+        let metrics = match self.collector.take(csid) {
+            Some(metrics) => metrics,
+            None => return,
+        };
+
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        inner.changesets += 1;
+        {
+            let totals = &mut inner.totals;
+            totals.file_entries += metrics.file_entries;
+            totals.tree_entries += metrics.tree_entries;
+            totals.file_bytes += metrics.file_bytes;
+            totals.tree_bytes += metrics.tree_bytes;
+            totals.upload_entries += metrics.upload_entries;
+            totals.changed_files += metrics.changed_files;
+            totals.bonsai += metrics.bonsai;
+            totals.save += metrics.save;
+            totals.commit += metrics.commit;
+        }
+        inner.window.push(metrics);
+
+        if inner.window.len() >= self.log_every {
+            let window = inner.window.split_off(0);
+            log_percentiles(&self.logger, inner.changesets, window);
+        }
+    }
+
+    /// The number of changesets, and the sums of their metrics
+    pub fn totals(&self) -> (u64, ChangesetMetrics) {
+        let inner = self.inner.lock().expect("lock poisoned");
+        (inner.changesets, inner.totals.clone())
+    }
+
+    pub fn log_summary(&self) {
+        let (changesets, totals) = self.totals();
+        info!(
+            self.logger,
+            "metrics of {} changesets: {} file entries of {} bytes, {} tree entries of {} bytes",
+            changesets,
+            totals.file_entries,
+            totals.file_bytes,
+            totals.tree_entries,
+            totals.tree_bytes
+        );
+        let durations: Vec<_> = phases(&totals)
+            .iter()
+            .map(|&(phase, duration)| format!("{} {}ms", phase, as_millis(duration)))
+            .collect();
+        info!(self.logger, "total time of phases: {}", durations.join(", "));
+    }
+}
+
+/// The 50th, 90th and 99th percentiles
+fn percentiles(mut values: Vec<u64>) -> [u64; 3] {
+    values.sort_unstable();
+    let percentile = |p: usize| values[(values.len() - 1) * p / 100];
+    [percentile(50), percentile(90), percentile(99)]
+}
+
+fn log_percentiles(logger: &Logger, changesets: u64, window: Vec<ChangesetMetrics>) {
+    if window.is_empty() {
+        return;
+    }
+    let phase_names = phases(&window[0]);
+    let lines: Vec<_> = phase_names
+        .iter()
+        .enumerate()
+        .map(|(idx, &(phase, _))| {
+            let millis = window
+                .iter()
+                .map(|metrics| as_millis(phases(metrics)[idx].1))
+                .collect();
+            let [p50, p90, p99] = percentiles(millis);
+            format!("{} p50 {}ms p90 {}ms p99 {}ms", phase, p50, p90, p99)
+        })
+        .collect();
+    info!(
+        logger,
+        "phases of the last {} of {} changesets: {}",
+        window.len(),
+        changesets,
+        lines.join(", ")
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        assert_eq!(percentiles(vec![7]), [7, 7, 7]);
+        assert_eq!(percentiles((1..101).rev().collect()), [50, 90, 99]);
+    }
+}
@@ -8,6 +8,8 @@
 
 mod bookmark;
 mod changeset;
+mod metrics;
+mod roots;
 
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -20,8 +22,10 @@ use slog::Logger;
 use blobrepo::BlobRepo;
 use mercurial::RevlogRepo;
 use mercurial_types::HgNodeHash;
+use metaconfig::{PathRules, PushLimits};
 
-use self::changeset::UploadChangesets;
+use self::changeset::{ImportedChangeset, UploadChangesets};
+use self::metrics::ImportMetrics;
 
 pub struct Blobimport {
     pub logger: Logger,
@@ -31,6 +35,17 @@ pub struct Blobimport {
     pub skip: Option<usize>,
     pub commits_limit: Option<usize>,
     pub no_bookmark: bool,
+    /// Fail the import if a transaction of bookmarks fails, instead of uploading the others
+    pub strict_bookmarks: bool,
+    pub path_violations_are_warnings: bool,
+    /// The repo is serving, so pushes may race with the import: skip the changesets that the
+    /// repo has and never move its bookmarks
+    pub live_repo: bool,
+    /// Allow a live import to start new history, not connected to the history of the repo
+    pub allow_new_roots: bool,
+    /// Log the percentiles of the phases of creating changesets, and the totals of what was
+    /// uploaded
+    pub collect_metrics: bool,
 }
 
 impl Blobimport {
@@ -43,6 +58,11 @@ impl Blobimport {
             skip,
             commits_limit,
             no_bookmark,
+            strict_bookmarks,
+            path_violations_are_warnings,
+            live_repo,
+            allow_new_roots,
+            collect_metrics,
         } = self;
 
         let stale_bookmarks = {
@@ -52,23 +72,53 @@ impl Blobimport {
 
         let revlogrepo = RevlogRepo::open(revlogrepo_path).expect("cannot open revlogrepo");
 
+        let check_roots = if live_repo {
+            roots::check_roots(
+                logger.clone(),
+                blobrepo.clone(),
+                roots::read_parents(revlogrepo.clone(), changeset, skip, commits_limit),
+                allow_new_roots,
+            )
+        } else {
+            future::ok(()).boxify()
+        };
+
+        let metrics = if collect_metrics {
+            Some(ImportMetrics::new(logger.clone(), 5000))
+        } else {
+            None
+        };
+
         let upload_changesets = UploadChangesets {
+            logger: logger.clone(),
             blobrepo: blobrepo.clone(),
             revlogrepo: revlogrepo.clone(),
             changeset,
             skip,
             commits_limit,
+            path_rules: PathRules::recommended(),
+            path_violations_are_warnings,
+            max_commit_message_bytes: PushLimits::default().max_commit_message_bytes,
+            max_directory_fanout: PushLimits::default().max_directory_fanout,
+            live_repo,
+            metrics: metrics.clone(),
         }.upload()
             .buffer_unordered(100)
             .enumerate()
             .map({
                 let logger = logger.clone();
-                move |(cs_count, cs)| {
-                    debug!(logger, "{} inserted: {}", cs_count, cs.1.get_changeset_id());
-                    if cs_count % 5000 == 0 {
-                        info!(logger, "inserted commits # {}", cs_count);
+                move |(cs_count, cs)| match cs {
+                    ImportedChangeset::Uploaded(cs) => {
+                        debug!(logger, "{} inserted: {}", cs_count, cs.1.get_changeset_id());
+                        if cs_count % 5000 == 0 {
+                            info!(logger, "inserted commits # {}", cs_count);
+                        }
+                        true
+                    }
+                    ImportedChangeset::Skipped(csid) => {
+                        debug!(logger, "{} skipped: {}", cs_count, csid);
+                        false
                     }
-                    ()
                 }
             })
             .map_err({
@@ -85,16 +135,38 @@ impl Blobimport {
                     err_msg(msg)
                 }
             })
-            .for_each(|()| Ok(()))
-            .inspect({
+            .fold((0, 0), |(uploaded, skipped), is_uploaded| {
+                let counts = if is_uploaded {
+                    (uploaded + 1, skipped)
+                } else {
+                    (uploaded, skipped + 1)
+                };
+                Ok::<_, Error>(counts)
+            })
+            .map({
                 let logger = logger.clone();
-                move |()| {
+                move |(uploaded, skipped)| {
                     info!(logger, "finished uploading changesets");
+                    if let Some(metrics) = metrics {
+                        metrics.log_summary();
+                    }
+                    if live_repo {
+                        info!(
+                            logger,
+                            "uploaded {} changesets, skipped {} that the repo already had",
+                            uploaded,
+                            skipped
+                        );
+                    }
                 }
             });
 
         stale_bookmarks
-            .and_then(move |stale_bookmarks| upload_changesets.map(|()| stale_bookmarks))
+            .and_then(move |stale_bookmarks| {
+                check_roots
+                    .and_then(move |()| upload_changesets)
+                    .map(|()| stale_bookmarks)
+            })
             .and_then(move |stale_bookmarks| {
                 if no_bookmark {
                     info!(
@@ -103,7 +175,15 @@ impl Blobimport {
                     );
                     future::ok(()).boxify()
                 } else {
-                    bookmark::upload_bookmarks(&logger, revlogrepo, blobrepo, stale_bookmarks)
+                    bookmark::upload_bookmarks(
+                        &logger,
+                        revlogrepo,
+                        blobrepo,
+                        stale_bookmarks,
+                        live_repo,
+                        strict_bookmarks,
+                    ).map(|_| ())
+                        .boxify()
                 }
             })
             .boxify()
@@ -0,0 +1,182 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use failure::prelude::*;
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use mercurial::RevlogRepo;
+use mercurial_types::{HgChangesetId, HgNodeHash};
+
+use super::changeset::select_changesets;
+
+/// The changesets of an import with their parents, in the order of the revlog
+pub fn read_parents(
+    revlogrepo: RevlogRepo,
+    changeset: Option<HgNodeHash>,
+    skip: Option<usize>,
+    commits_limit: Option<usize>,
+) -> BoxStream<(HgChangesetId, Vec<HgChangesetId>), Error> {
+    select_changesets(&revlogrepo, changeset, skip, commits_limit)
+        .map(move |csid| {
+            let csid = HgChangesetId::new(csid);
+            revlogrepo
+                .get_changeset(&csid)
+                .map(move |cs| {
+                    let parents = cs.parents().into_iter().map(HgChangesetId::new).collect();
+                    (csid, parents)
+                })
+                .with_context(move |_| format!("While reading changeset {:?}", csid))
+                .from_err()
+        })
+        .buffered(100)
+        .boxify()
+}
+
+/// Checks that the import connects to the history of the repo: the parents of each changeset are
+/// imported before it, or the repo has them already. A changeset without parents that the repo
+/// doesn't have starts new history, which is only allowed with `allow_new_roots`.
+pub fn check_roots(
+    logger: Logger,
+    blobrepo: Arc<BlobRepo>,
+    changesets: BoxStream<(HgChangesetId, Vec<HgChangesetId>), Error>,
+    allow_new_roots: bool,
+) -> BoxFuture<(), Error> {
+    // The changesets without parents, and the parents that are not imported before their child
+    changesets
+        .fold(
+            (HashSet::new(), vec![], vec![]),
+            |(mut imported, mut roots, mut outside_parents), (csid, parents)| {
+                if parents.is_empty() {
+                    roots.push(csid);
+                }
+                for parent in parents {
+                    if !imported.contains(&parent) {
+                        outside_parents.push((csid, parent));
+                    }
+                }
+                imported.insert(csid);
+                Ok::<_, Error>((imported, roots, outside_parents))
+            },
+        )
+        .and_then(move |(_, roots, outside_parents)| {
+            let roots = stream::iter_ok(roots)
+                .map({
+                    cloned!(blobrepo);
+                    move |csid| blobrepo.changeset_exists(&csid).map(move |exists| (csid, exists))
+                })
+                .buffered(100)
+                .collect();
+            let outside_parents = stream::iter_ok(outside_parents)
+                .map({
+                    cloned!(blobrepo);
+                    move |(csid, parent)| {
+                        blobrepo
+                            .changeset_exists(&parent)
+                            .map(move |exists| (csid, parent, exists))
+                    }
+                })
+                .buffered(100)
+                .collect();
+            roots.join(outside_parents)
+        })
+        .and_then(move |(roots, outside_parents)| {
+            for &(csid, parent, exists) in &outside_parents {
+                if !exists {
+                    bail_msg!(
+                        "changeset {} has parent {}, which is neither imported before it nor in \
+                         the repo",
+                        csid,
+                        parent
+                    );
+                }
+            }
+            info!(
+                logger,
+                "the import connects to {} changesets of the repo",
+                outside_parents.len()
+            );
+
+            let new_roots: Vec<_> = roots
+                .into_iter()
+                .filter(|&(_, exists)| !exists)
+                .map(|(csid, _)| csid.to_string())
+                .collect();
+            if !new_roots.is_empty() {
+                if !allow_new_roots {
+                    bail_msg!(
+                        "changesets {} have no parents and start new history, which needs \
+                         --allow-new-roots",
+                        new_roots.join(", ")
+                    );
+                }
+                warn!(logger, "the import starts new history at {}", new_roots.join(", "));
+            }
+            Ok(())
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use async_unit;
+    use fixtures::linear;
+    use slog::Discard;
+
+    use mercurial_types_mocks::nodehash::{ONES_CSID, TWOS_CSID};
+
+    // The first two commits of linear
+    const ROOT: &str = "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536";
+    const SECOND: &str = "3e0e761030db6e479a7fb58b12881883f9f8c63f";
+
+    fn check(
+        changesets: Vec<(HgChangesetId, Vec<HgChangesetId>)>,
+        allow_new_roots: bool,
+    ) -> Result<()> {
+        let logger = Logger::root(Discard, o!());
+        let blobrepo = Arc::new(linear::getrepo(None));
+        check_roots(
+            logger,
+            blobrepo,
+            stream::iter_ok(changesets).boxify(),
+            allow_new_roots,
+        ).wait()
+    }
+
+    #[test]
+    fn test_check_roots() {
+        async_unit::tokio_unit_test(|| {
+            let root = HgChangesetId::from_str(ROOT).unwrap();
+            let second = HgChangesetId::from_str(SECOND).unwrap();
+
+            // The whole history again, or new changesets on top of it
+            assert!(check(vec![(root, vec![]), (second, vec![root])], false).is_ok());
+            assert!(check(vec![(ONES_CSID, vec![second])], false).is_ok());
+            let on_top = vec![(ONES_CSID, vec![second]), (TWOS_CSID, vec![ONES_CSID])];
+            assert!(check(on_top, false).is_ok());
+            assert!(check(vec![], false).is_ok());
+
+            // A parent that comes later, or that the repo doesn't have
+            let parent_later = vec![(TWOS_CSID, vec![ONES_CSID]), (ONES_CSID, vec![second])];
+            assert!(check(parent_later, false).is_err());
+            assert!(check(vec![(TWOS_CSID, vec![root, ONES_CSID])], true).is_err());
+
+            // New history
+            let new_history = vec![(ONES_CSID, vec![]), (TWOS_CSID, vec![ONES_CSID])];
+            assert!(check(new_history.clone(), false).is_err());
+            assert!(check(new_history, true).is_ok());
+        })
+    }
+}
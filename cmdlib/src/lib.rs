@@ -28,13 +28,25 @@ extern crate slog_glog_fmt;
 
 extern crate blobrepo;
 extern crate bookmarks;
+extern crate bundle2_resolver;
+extern crate cross_repo_index;
+extern crate derived_data;
 extern crate hooks;
 extern crate mercurial;
 extern crate mercurial_types;
 extern crate metaconfig;
 extern crate panichandler;
+extern crate push_journal;
 extern crate repo_client;
+extern crate repo_flags;
 extern crate scuba_ext;
 
+#[cfg(test)]
+extern crate async_unit;
+#[cfg(test)]
+extern crate fixtures;
+#[cfg(test)]
+extern crate mercurial_types_mocks;
+
 pub mod args;
 pub mod blobimport_lib;
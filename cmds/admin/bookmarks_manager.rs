@@ -4,18 +4,35 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::fmt;
+use std::sync::Arc;
+
 use clap::{App, Arg, ArgMatches, SubCommand};
 use failure::Error;
-use futures::{future, Future};
-use futures_ext::{BoxFuture, FutureExt};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use serde_json::to_string_pretty;
 use slog::Logger;
 
 use blobrepo::BlobRepo;
-use bookmarks::Bookmark;
+use blobrepo::bookmark_snapshot::{apply_bookmark_snapshot_changes, diff_bookmark_snapshot,
+                                  list_bookmark_snapshots, load_bookmark_snapshot,
+                                  take_bookmark_snapshot, BookmarkSnapshotChange};
+use bookmarks::{Bookmark, BookmarkPrefix, BookmarkSnapshotIndex};
+use mononoke_types::ChangesetId;
+use reachabilityindex::{GenerationNumberBFS, ReachabilityIndex};
 
 const SET_CMD: &'static str = "set";
 const GET_CMD: &'static str = "get";
+const LIST_CMD: &'static str = "list";
+const DELETE_CMD: &'static str = "delete";
+const SNAPSHOT_CMD: &'static str = "snapshot";
+const LIST_SNAPSHOTS_CMD: &'static str = "list-snapshots";
+const RESTORE_CMD: &'static str = "restore";
+const COPY_CMD: &'static str = "copy";
+const MOVE_ALL_CMD: &'static str = "move-all";
+
+const DEFAULT_MOVE_ALL_CHUNK_SIZE: usize = 100;
 
 pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
     let set = SubCommand::with_name(SET_CMD)
@@ -25,7 +42,21 @@ pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
         )
         .args_from_usage(
             "<BOOKMARK_NAME>        'bookmark to target'
-             <HG_CHANGESET_ID>      'revision to which the bookmark should point to'",
+             <HG_CHANGESET_ID>      'revision to which the bookmark should point to'
+             --verbose              'print the changeset the bookmark pointed to before'",
+        )
+        .arg(
+            Arg::with_name("old-changeset")
+                .long("old-changeset")
+                .value_name("HG_CS")
+                .takes_value(true)
+                .conflicts_with("create")
+                .help("only move the bookmark if it still points to this changeset"),
+        )
+        .arg(
+            Arg::with_name("create")
+                .long("create")
+                .help("only create the bookmark, fail if it already exists"),
         );
 
     let get = SubCommand::with_name(GET_CMD)
@@ -46,19 +77,111 @@ pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
                 .help("What changeset type to return, either bonsai or hg. Defaults to hg."),
         );
 
+    let list = SubCommand::with_name(LIST_CMD)
+        .about("lists the bookmarks and the changesets they point to")
+        .args_from_usage(
+            r#"
+            --prefix [PREFIX]      'only list the bookmarks that start with this prefix'
+            --json                 'print one json object per bookmark'
+            "#,
+        )
+        .arg(
+            Arg::with_name("changeset-type")
+                .long("changeset-type")
+                .short("cs")
+                .takes_value(true)
+                .possible_values(&["bonsai", "hg", "both"])
+                .required(false)
+                .help("What changeset ids to print: bonsai, hg or both. Defaults to hg."),
+        );
+
+    let delete = SubCommand::with_name(DELETE_CMD)
+        .about("deletes a bookmark, fails if it does not exist unless --force is given")
+        .args_from_usage(
+            r#"
+            <BOOKMARK_NAME>        'bookmark to delete'
+            --force                'delete the bookmark without checking that it exists'
+            "#,
+        );
+
+    let snapshot = SubCommand::with_name(SNAPSHOT_CMD)
+        .about("takes a snapshot of all the bookmarks and prints its key")
+        .args_from_usage("--retain [N]    'number of most recent snapshots to keep (default 24)'");
+
+    let list_snapshots = SubCommand::with_name(LIST_SNAPSHOTS_CMD)
+        .about("lists the keys of the kept bookmark snapshots, oldest first");
+
+    let restore = SubCommand::with_name(RESTORE_CMD)
+        .about(
+            "shows how bookmarks differ from a snapshot, and restores them from it with --commit",
+        )
+        .args_from_usage(
+            r#"
+            --snapshot <KEY>       'key of the snapshot to restore'
+            --only [PREFIX]        'only restore the bookmarks that start with this prefix'
+            --commit               'apply the changes, otherwise they are only shown'
+            "#,
+        );
+
+    let copy = SubCommand::with_name(COPY_CMD)
+        .about("creates a bookmark at the changeset another bookmark points to")
+        .args_from_usage(
+            r#"
+            <SRC>                       'bookmark to copy'
+            <DST>                       'bookmark to create'
+            --overwrite                 'move DST if it already exists instead of failing'
+            --require-ancestor [CS]     'refuse unless SRC points to a descendant of CS'
+            "#,
+        );
+
+    let move_all = SubCommand::with_name(MOVE_ALL_CMD)
+        .about(
+            "copies all the bookmarks that start with a prefix to the same names under another \
+             prefix, and applies the copies with --commit",
+        )
+        .args_from_usage(
+            r#"
+            --from-prefix <PREFIX>      'prefix of the bookmarks to copy'
+            --to-prefix <PREFIX>        'prefix that replaces --from-prefix in the copies'
+            --delete-source             'delete the copied bookmarks, so that they are renamed'
+            --require-ancestor [CS]     'refuse unless all bookmarks point to descendants of CS'
+            --chunk-size [N]            'number of bookmarks changed per transaction (default 100)'
+            --commit                    'apply the changes, otherwise they are only shown'
+            "#,
+        );
+
     app.about("set of commands to manipulate bookmarks")
         .subcommand(set)
         .subcommand(get)
+        .subcommand(list)
+        .subcommand(delete)
+        .subcommand(snapshot)
+        .subcommand(list_snapshots)
+        .subcommand(restore)
+        .subcommand(copy)
+        .subcommand(move_all)
 }
 
 pub fn handle_command<'a>(
     repo: &BlobRepo,
+    snapshot_index: Arc<BookmarkSnapshotIndex>,
     matches: &ArgMatches<'a>,
     logger: Logger,
 ) -> BoxFuture<(), Error> {
     match matches.subcommand() {
         (GET_CMD, Some(sub_m)) => handle_get(sub_m, logger, repo.clone()),
         (SET_CMD, Some(sub_m)) => handle_set(sub_m, logger, repo.clone()),
+        (LIST_CMD, Some(sub_m)) => handle_list(sub_m, logger, repo.clone()),
+        (DELETE_CMD, Some(sub_m)) => handle_delete(sub_m, logger, repo.clone()),
+        (SNAPSHOT_CMD, Some(sub_m)) => {
+            handle_snapshot(sub_m, logger, repo.clone(), snapshot_index)
+        }
+        (LIST_SNAPSHOTS_CMD, Some(sub_m)) => {
+            handle_list_snapshots(sub_m, logger, repo.clone(), snapshot_index)
+        }
+        (RESTORE_CMD, Some(sub_m)) => handle_restore(sub_m, logger, repo.clone()),
+        (COPY_CMD, Some(sub_m)) => handle_copy(sub_m, logger, repo.clone()),
+        (MOVE_ALL_CMD, Some(sub_m)) => handle_move_all(sub_m, logger, repo.clone()),
         _ => {
             println!("{}", matches.usage());
             ::std::process::exit(1);
@@ -110,16 +233,561 @@ fn handle_get<'a>(args: &ArgMatches<'a>, _logger: Logger, repo: BlobRepo) -> Box
     }
 }
 
+/// How `set` moves a bookmark
+#[derive(Clone, Copy, Debug)]
+enum SetMode {
+    /// Whatever the bookmark points to, if it exists
+    Force,
+    /// Only if the bookmark does not exist, for `--create`
+    Create,
+    /// Only if the bookmark still points to this changeset, for `--old-changeset`
+    Update(ChangesetId),
+}
+
+/// Points `bookmark` to `target`. Fails if `mode` doesn't allow it, so that operators racing
+/// to move the same bookmark don't overwrite each other.
+fn set_bookmark_to(
+    repo: &BlobRepo,
+    bookmark: Bookmark,
+    target: ChangesetId,
+    mode: SetMode,
+) -> BoxFuture<(), Error> {
+    let mut transaction = repo.update_bookmark_transaction();
+    try_boxfuture!(match mode {
+        SetMode::Force => transaction.force_set(&bookmark, &target),
+        SetMode::Create => transaction.create(&bookmark, &target),
+        SetMode::Update(old) => transaction.update(&bookmark, &target, &old),
+    });
+    transaction
+        .commit()
+        .and_then(move |committed| {
+            if committed {
+                return Ok(());
+            }
+            match mode {
+                SetMode::Force => Err(format_err!("failed to set bookmark {}", bookmark)),
+                SetMode::Create => Err(format_err!("bookmark {} already exists", bookmark)),
+                SetMode::Update(old) => Err(format_err!(
+                    "bookmark {} has moved, it does not point to {} anymore",
+                    bookmark,
+                    old
+                )),
+            }
+        })
+        .boxify()
+}
+
 fn handle_set<'a>(args: &ArgMatches<'a>, _logger: Logger, repo: BlobRepo) -> BoxFuture<(), Error> {
     let bookmark_name = args.value_of("BOOKMARK_NAME").unwrap().to_string();
     let rev = args.value_of("HG_CHANGESET_ID").unwrap();
     let bookmark = Bookmark::new(bookmark_name).unwrap();
+    let verbose = args.is_present("verbose");
+
+    let target = ::fetch_bonsai_changeset(rev, &repo);
+    let mode = match args.value_of("old-changeset") {
+        Some(old) => ::fetch_bonsai_changeset(old, &repo)
+            .map(|bonsai_cs| SetMode::Update(bonsai_cs.get_changeset_id()))
+            .left_future(),
+        None if args.is_present("create") => future::ok(SetMode::Create).right_future(),
+        None => future::ok(SetMode::Force).right_future(),
+    };
+
+    let previous = if verbose {
+        repo.get_bookmark(&bookmark)
+            .map(|previous| match previous {
+                Some(cs) => println!(
+                    "previous value: {}",
+                    format_output(false, cs.to_string(), "hg")
+                ),
+                None => println!("previous value: none, the bookmark does not exist"),
+            })
+            .left_future()
+    } else {
+        future::ok(()).right_future()
+    };
+
+    previous
+        .and_then(move |()| target.join(mode))
+        .and_then(move |(bonsai_cs, mode)| {
+            set_bookmark_to(&repo, bookmark, bonsai_cs.get_changeset_id(), mode)
+        })
+        .boxify()
+}
+
+/// A line of `list`: the bookmark, then the ids of its changeset, as `(changeset_type, id)` pairs
+fn format_list_output(json_flag: bool, bookmark: &Bookmark, ids: &[(&str, String)]) -> String {
+    if json_flag {
+        let mut answer = json!({ "bookmark": bookmark.to_string() });
+        for (changeset_type, changeset_id) in ids {
+            answer[*changeset_type] = json!(changeset_id);
+        }
+        answer.to_string()
+    } else {
+        let mut output = bookmark.to_string();
+        for (changeset_type, changeset_id) in ids {
+            output.push(' ');
+            output.push_str(&format_output(false, changeset_id.clone(), changeset_type));
+        }
+        output
+    }
+}
 
-    ::fetch_bonsai_changeset(rev, &repo)
-        .and_then(move |bonsai_cs| {
+/// Lines of `list` for the bookmarks under `prefix`, in the order the bookmarks are listed
+fn list_bookmarks(
+    repo: &BlobRepo,
+    prefix: &BookmarkPrefix,
+    show_hg: bool,
+    show_bonsai: bool,
+    json_flag: bool,
+) -> BoxStream<String, Error> {
+    repo.get_bookmarks_object()
+        .list_by_prefix(prefix, &repo.get_repoid())
+        .and_then({
+            cloned!(repo);
+            move |(bookmark, cs_id)| {
+                let hg_cs_id = if show_hg {
+                    repo.get_hg_from_bonsai_changeset(cs_id)
+                        .map(Some)
+                        .left_future()
+                } else {
+                    future::ok(None).right_future()
+                };
+                hg_cs_id.map(move |hg_cs_id| {
+                    let mut ids = Vec::new();
+                    if let Some(hg_cs_id) = hg_cs_id {
+                        ids.push(("hg", hg_cs_id.to_string()));
+                    }
+                    if show_bonsai {
+                        ids.push(("bonsai", cs_id.to_string()));
+                    }
+                    format_list_output(json_flag, &bookmark, &ids)
+                })
+            }
+        })
+        .boxify()
+}
+
+fn handle_list<'a>(args: &ArgMatches<'a>, _logger: Logger, repo: BlobRepo) -> BoxFuture<(), Error> {
+    let prefix = match args.value_of("prefix") {
+        Some(prefix) => try_boxfuture!(BookmarkPrefix::new(prefix)),
+        None => BookmarkPrefix::empty(),
+    };
+    let changeset_type = args.value_of("changeset-type").unwrap_or("hg");
+    let json_flag = args.is_present("json");
+
+    list_bookmarks(
+        &repo,
+        &prefix,
+        changeset_type != "bonsai",
+        changeset_type != "hg",
+        json_flag,
+    ).for_each(|output| {
+        println!("{}", output);
+        Ok(())
+    })
+        .boxify()
+}
+
+/// Deletes `bookmark`. Unless `force` is set, fails if it does not exist.
+fn delete_bookmark(repo: &BlobRepo, bookmark: Bookmark, force: bool) -> BoxFuture<(), Error> {
+    let exists = if force {
+        future::ok(()).left_future()
+    } else {
+        repo.get_bookmark(&bookmark)
+            .and_then({
+                cloned!(bookmark);
+                move |cs| match cs {
+                    Some(_) => Ok(()),
+                    None => Err(format_err!(
+                        "bookmark {} does not exist, pass --force to delete it anyway",
+                        bookmark
+                    )),
+                }
+            })
+            .right_future()
+    };
+
+    cloned!(repo);
+    exists
+        .and_then(move |()| {
             let mut transaction = repo.update_bookmark_transaction();
-            try_boxfuture!(transaction.force_set(&bookmark, &bonsai_cs.get_changeset_id()));
-            transaction.commit().map(|_| ()).from_err().boxify()
+            try_boxfuture!(transaction.force_delete(&bookmark));
+            transaction
+                .commit()
+                .and_then(move |committed| {
+                    if committed {
+                        Ok(())
+                    } else {
+                        Err(format_err!("failed to delete bookmark {}", bookmark))
+                    }
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn handle_delete<'a>(
+    args: &ArgMatches<'a>,
+    logger: Logger,
+    repo: BlobRepo,
+) -> BoxFuture<(), Error> {
+    let bookmark = try_boxfuture!(Bookmark::new(args.value_of("BOOKMARK_NAME").unwrap()));
+    let force = args.is_present("force");
+
+    delete_bookmark(&repo, bookmark.clone(), force)
+        .map(move |()| info!(logger, "deleted {}", bookmark))
+        .boxify()
+}
+
+fn handle_snapshot<'a>(
+    args: &ArgMatches<'a>,
+    _logger: Logger,
+    repo: BlobRepo,
+    snapshot_index: Arc<BookmarkSnapshotIndex>,
+) -> BoxFuture<(), Error> {
+    let retain = match args.value_of("retain") {
+        Some(retain) => try_boxfuture!(
+            retain
+                .parse()
+                .map_err(|_| format_err!("invalid --retain {}", retain))
+        ),
+        None => 24,
+    };
+
+    take_bookmark_snapshot(&repo, snapshot_index, retain)
+        .map(|key| println!("{}", key))
+        .boxify()
+}
+
+fn handle_list_snapshots<'a>(
+    _args: &ArgMatches<'a>,
+    _logger: Logger,
+    repo: BlobRepo,
+    snapshot_index: Arc<BookmarkSnapshotIndex>,
+) -> BoxFuture<(), Error> {
+    list_bookmark_snapshots(&repo, &*snapshot_index)
+        .map(|keys| {
+            for key in keys {
+                println!("{}", key);
+            }
+        })
+        .boxify()
+}
+
+fn format_restore_output(changes: &[BookmarkSnapshotChange], commit: bool) -> String {
+    if changes.is_empty() {
+        return "bookmarks match the snapshot".to_string();
+    }
+    let mut lines: Vec<_> = changes.iter().map(|change| change.to_string()).collect();
+    if !commit {
+        lines.push("dry run, pass --commit to apply".to_string());
+    }
+    lines.join("\n")
+}
+
+fn handle_restore<'a>(
+    args: &ArgMatches<'a>,
+    logger: Logger,
+    repo: BlobRepo,
+) -> BoxFuture<(), Error> {
+    let key = args.value_of("snapshot").unwrap().to_string();
+    let prefix = match args.value_of("only") {
+        Some(prefix) => try_boxfuture!(BookmarkPrefix::new(prefix)),
+        None => BookmarkPrefix::empty(),
+    };
+    let commit = args.is_present("commit");
+
+    load_bookmark_snapshot(&repo, &key)
+        .and_then({
+            cloned!(repo);
+            move |snapshot| diff_bookmark_snapshot(&repo, &snapshot, &prefix)
+        })
+        .and_then(move |changes| {
+            println!("{}", format_restore_output(&changes, commit));
+            if !commit || changes.is_empty() {
+                return future::ok(()).boxify();
+            }
+            apply_bookmark_snapshot_changes(&repo, &changes)
+                .and_then(move |committed| {
+                    if committed {
+                        info!(logger, "restored {} bookmarks from {}", changes.len(), key);
+                        Ok(())
+                    } else {
+                        Err(format_err!(
+                            "bookmarks were moved while restoring them, nothing was restored"
+                        ))
+                    }
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Resolves the --require-ancestor argument, if any, to a bonsai changeset
+fn required_ancestor<'a>(
+    args: &ArgMatches<'a>,
+    repo: &BlobRepo,
+) -> BoxFuture<Option<ChangesetId>, Error> {
+    match args.value_of("require-ancestor") {
+        Some(rev) => ::fetch_bonsai_changeset(rev, repo)
+            .map(|bcs| Some(bcs.get_changeset_id()))
+            .boxify(),
+        None => future::ok(None).boxify(),
+    }
+}
+
+/// Fails unless every one of `targets` is `ancestor` or one of its descendants
+fn check_descendants(
+    repo: &BlobRepo,
+    ancestor: Option<ChangesetId>,
+    targets: Vec<(Bookmark, ChangesetId)>,
+) -> BoxFuture<(), Error> {
+    let ancestor = match ancestor {
+        Some(ancestor) => ancestor,
+        None => return future::ok(()).boxify(),
+    };
+    let repo = Arc::new(repo.clone());
+
+    repo.get_hg_from_bonsai_changeset(ancestor)
+        .and_then(move |ancestor_hg| {
+            stream::iter_ok(targets)
+                .and_then({
+                    cloned!(repo);
+                    move |(bookmark, target)| {
+                        repo.get_hg_from_bonsai_changeset(target)
+                            .map(move |target_hg| (bookmark, target_hg))
+                    }
+                })
+                .and_then(move |(bookmark, target_hg)| {
+                    GenerationNumberBFS::new()
+                        .query_reachability(
+                            repo.clone(),
+                            target_hg.into_nodehash(),
+                            ancestor_hg.into_nodehash(),
+                        )
+                        .and_then(move |is_descendant| {
+                            if is_descendant {
+                                Ok(())
+                            } else {
+                                Err(format_err!(
+                                    "{} would point to {}, which is not a descendant of {}",
+                                    bookmark,
+                                    target_hg,
+                                    ancestor_hg
+                                ))
+                            }
+                        })
+                })
+                .for_each(|()| Ok(()))
+        })
+        .boxify()
+}
+
+/// Creates `dst` at the changeset `src` points to. Unless `overwrite` is set, fails if `dst`
+/// already exists.
+fn copy_bookmark(
+    repo: &BlobRepo,
+    src: Bookmark,
+    dst: Bookmark,
+    overwrite: bool,
+    ancestor: Option<ChangesetId>,
+) -> BoxFuture<ChangesetId, Error> {
+    let bookmarks = repo.get_bookmarks_object();
+    let repoid = repo.get_repoid();
+
+    bookmarks
+        .get(&src, &repoid)
+        .and_then({
+            cloned!(src);
+            move |target| target.ok_or(format_err!("bookmark {} does not exist", src))
+        })
+        .and_then({
+            cloned!(repo, dst);
+            move |target| {
+                check_descendants(&repo, ancestor, vec![(dst, target)]).map(move |()| target)
+            }
+        })
+        .and_then({
+            cloned!(repo);
+            move |target| {
+                let mut transaction = repo.update_bookmark_transaction();
+                if overwrite {
+                    try_boxfuture!(transaction.force_set(&dst, &target));
+                } else {
+                    try_boxfuture!(transaction.create(&dst, &target));
+                }
+                transaction
+                    .commit()
+                    .and_then(move |committed| {
+                        if committed {
+                            Ok(target)
+                        } else {
+                            Err(format_err!(
+                                "bookmark {} already exists, pass --overwrite to move it",
+                                dst
+                            ))
+                        }
+                    })
+                    .boxify()
+            }
+        })
+        .boxify()
+}
+
+fn handle_copy<'a>(args: &ArgMatches<'a>, logger: Logger, repo: BlobRepo) -> BoxFuture<(), Error> {
+    let src = try_boxfuture!(Bookmark::new(args.value_of("SRC").unwrap()));
+    let dst = try_boxfuture!(Bookmark::new(args.value_of("DST").unwrap()));
+    let overwrite = args.is_present("overwrite");
+
+    required_ancestor(args, &repo)
+        .and_then(move |ancestor| {
+            copy_bookmark(&repo, src.clone(), dst.clone(), overwrite, ancestor).map(
+                move |target| {
+                    info!(logger, "copied {} to {} at {}", src, dst, target);
+                },
+            )
+        })
+        .boxify()
+}
+
+/// A bookmark that `move-all` creates under the new prefix
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct BookmarkMove {
+    from: Bookmark,
+    to: Bookmark,
+    target: ChangesetId,
+}
+
+impl fmt::Display for BookmarkMove {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {} at {}", self.from, self.to, self.target)
+    }
+}
+
+/// Lists the bookmarks under `from_prefix`, and the names they get under `to_prefix`
+fn plan_bookmark_moves(
+    repo: &BlobRepo,
+    from_prefix: BookmarkPrefix,
+    to_prefix: String,
+) -> BoxFuture<Vec<BookmarkMove>, Error> {
+    let prefix_len = from_prefix.to_string().len();
+    repo.get_bookmarks_object()
+        .list_by_prefix(&from_prefix, &repo.get_repoid())
+        .and_then(move |(from, target)| {
+            let suffix = from.to_string()[prefix_len..].to_string();
+            Bookmark::new(format!("{}{}", to_prefix, suffix))
+                .map(|to| BookmarkMove { from, to, target })
+        })
+        .collect()
+        .map(|mut moves| {
+            moves.sort_by(|a, b| a.from.cmp(&b.from));
+            moves
+        })
+        .boxify()
+}
+
+/// Creates the new bookmarks in transactions of at most `chunk_size` bookmarks, and deletes the
+/// old ones in the same transactions if `delete_source` is set. A bookmark that exists under
+/// the new name, or that was moved after it was listed, fails its transaction; the chunks
+/// before it stay applied. Returns the number of moved bookmarks.
+fn apply_bookmark_moves(
+    repo: &BlobRepo,
+    moves: Vec<BookmarkMove>,
+    delete_source: bool,
+    chunk_size: usize,
+) -> BoxFuture<usize, Error> {
+    let chunks: Vec<Vec<BookmarkMove>> = moves
+        .chunks(chunk_size)
+        .map(|chunk| chunk.to_vec())
+        .collect();
+
+    cloned!(repo);
+    stream::iter_ok(chunks)
+        .fold(0, move |applied, chunk| {
+            let mut transaction = repo.update_bookmark_transaction();
+            for mv in chunk.iter() {
+                try_boxfuture!(transaction.create(&mv.to, &mv.target));
+                if delete_source {
+                    try_boxfuture!(transaction.delete(&mv.from, &mv.target));
+                }
+            }
+            transaction
+                .commit()
+                .and_then(move |committed| {
+                    if committed {
+                        Ok(applied + chunk.len())
+                    } else {
+                        Err(format_err!(
+                            "failed to apply the changes starting with {}, a bookmark already \
+                             exists or was moved; {} bookmarks were applied before it",
+                            chunk[0],
+                            applied
+                        ))
+                    }
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn format_move_all_output(moves: &[BookmarkMove], delete_source: bool, commit: bool) -> String {
+    if moves.is_empty() {
+        return "no bookmarks match the prefix".to_string();
+    }
+    let action = if delete_source { "move" } else { "copy" };
+    let mut lines: Vec<_> = moves
+        .iter()
+        .map(|mv| format!("{} {}", action, mv))
+        .collect();
+    if !commit {
+        lines.push("dry run, pass --commit to apply".to_string());
+    }
+    lines.join("\n")
+}
+
+fn handle_move_all<'a>(
+    args: &ArgMatches<'a>,
+    logger: Logger,
+    repo: BlobRepo,
+) -> BoxFuture<(), Error> {
+    let from_prefix = try_boxfuture!(BookmarkPrefix::new(args.value_of("from-prefix").unwrap()));
+    let to_prefix = args.value_of("to-prefix").unwrap().to_string();
+    let delete_source = args.is_present("delete-source");
+    let commit = args.is_present("commit");
+    let chunk_size = match args.value_of("chunk-size") {
+        Some(chunk_size) => try_boxfuture!(
+            chunk_size
+                .parse()
+                .ok()
+                .and_then(|chunk_size| if chunk_size > 0 { Some(chunk_size) } else { None })
+                .ok_or(format_err!("invalid --chunk-size {}", chunk_size))
+        ),
+        None => DEFAULT_MOVE_ALL_CHUNK_SIZE,
+    };
+
+    plan_bookmark_moves(&repo, from_prefix, to_prefix)
+        .join(required_ancestor(args, &repo))
+        .and_then({
+            cloned!(repo);
+            move |(moves, ancestor)| {
+                let targets = moves
+                    .iter()
+                    .map(|mv| (mv.to.clone(), mv.target))
+                    .collect();
+                check_descendants(&repo, ancestor, targets).map(move |()| moves)
+            }
+        })
+        .and_then(move |moves| {
+            println!(
+                "{}",
+                format_move_all_output(&moves, delete_source, commit)
+            );
+            if !commit || moves.is_empty() {
+                return future::ok(()).boxify();
+            }
+            apply_bookmark_moves(&repo, moves, delete_source, chunk_size)
+                .map(move |applied| info!(logger, "applied {} bookmarks", applied))
+                .boxify()
         })
         .boxify()
 }
@@ -144,4 +812,387 @@ mod tests {
     fn plain_output_format() {
         assert_eq!(format_output(false, "123".to_string(), "hg"), "(HG) 123");
     }
+
+    #[test]
+    fn list_output_format() {
+        let bookmark = Bookmark::new("master").unwrap();
+        let ids = vec![("hg", "123".to_string()), ("bonsai", "456".to_string())];
+        assert_eq!(
+            format_list_output(false, &bookmark, &ids),
+            "master (HG) 123 (BONSAI) 456"
+        );
+        assert_eq!(
+            format_list_output(true, &bookmark, &ids),
+            r#"{"bonsai":"456","bookmark":"master","hg":"123"}"#
+        );
+        assert_eq!(
+            format_list_output(true, &bookmark, &ids[1..]),
+            r#"{"bonsai":"456","bookmark":"master"}"#
+        );
+    }
+
+    use async_unit;
+    use dbbookmarks::SqliteBookmarkSnapshotIndex;
+    use tests_utils::{create_commit, store_files};
+
+    fn set_bookmark(repo: &BlobRepo, name: &str, cs_id: Option<ChangesetId>) {
+        let mut transaction = repo.update_bookmark_transaction();
+        let bookmark = Bookmark::new(name).unwrap();
+        match cs_id {
+            Some(cs_id) => transaction.force_set(&bookmark, &cs_id),
+            None => transaction.force_delete(&bookmark),
+        }.unwrap();
+        assert!(transaction.commit().wait().unwrap());
+    }
+
+    fn get_bookmark(repo: &BlobRepo, name: &str) -> Option<ChangesetId> {
+        repo.get_bookmarks_object()
+            .get(&Bookmark::new(name).unwrap(), &repo.get_repoid())
+            .wait()
+            .unwrap()
+    }
+
+    /// Creates a repo with a linear history c1 <- c2, and a commit c3 that is not related to them
+    fn linear_repo() -> (BlobRepo, ChangesetId, ChangesetId, ChangesetId) {
+        let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+        let c1 = create_commit(
+            repo.clone(),
+            vec![],
+            store_files(btreemap!{"a" => Some("1")}, repo.clone()),
+        );
+        let c2 = create_commit(
+            repo.clone(),
+            vec![c1],
+            store_files(btreemap!{"a" => Some("2")}, repo.clone()),
+        );
+        let c3 = create_commit(
+            repo.clone(),
+            vec![],
+            store_files(btreemap!{"b" => Some("3")}, repo.clone()),
+        );
+        (repo, c1, c2, c3)
+    }
+
+    #[test]
+    fn delete_arguments() {
+        let app = prepare_command(App::new("bookmarks"));
+        let matches = app.clone()
+            .get_matches_from_safe(vec!["bookmarks", "delete", "master", "--force"])
+            .unwrap();
+        match matches.subcommand() {
+            (DELETE_CMD, Some(sub_m)) => {
+                assert_eq!(sub_m.value_of("BOOKMARK_NAME"), Some("master"));
+                assert!(sub_m.is_present("force"));
+            }
+            bad => panic!("unexpected subcommand {:?}", bad.0),
+        }
+        assert!(
+            app.get_matches_from_safe(vec!["bookmarks", "delete"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn delete_requires_existing_bookmark() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, c1, c2, _) = linear_repo();
+            set_bookmark(&repo, "master", Some(c2));
+            set_bookmark(&repo, "stable", Some(c1));
+
+            let missing = Bookmark::new("missing").unwrap();
+            let err = delete_bookmark(&repo, missing.clone(), false)
+                .wait()
+                .unwrap_err();
+            assert!(err.to_string().contains("does not exist"), "{}", err);
+            delete_bookmark(&repo, missing, true).wait().unwrap();
+
+            delete_bookmark(&repo, Bookmark::new("master").unwrap(), false)
+                .wait()
+                .unwrap();
+            assert_eq!(get_bookmark(&repo, "master"), None);
+            assert_eq!(get_bookmark(&repo, "stable"), Some(c1));
+        })
+    }
+
+    #[test]
+    fn list_by_prefix() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, c1, c2, _) = linear_repo();
+            set_bookmark(&repo, "master", Some(c2));
+            set_bookmark(&repo, "release/1", Some(c1));
+
+            let prefix = BookmarkPrefix::new("release/").unwrap();
+            let output = list_bookmarks(&repo, &prefix, false, true, false)
+                .collect()
+                .wait()
+                .unwrap();
+            assert_eq!(output, vec![format!("release/1 (BONSAI) {}", c1)]);
+
+            let hg_c2 = repo.get_hg_from_bonsai_changeset(c2).wait().unwrap();
+            let mut output = list_bookmarks(&repo, &BookmarkPrefix::empty(), true, false, false)
+                .collect()
+                .wait()
+                .unwrap();
+            output.sort();
+            assert_eq!(output.len(), 2);
+            assert_eq!(output[0], format!("master (HG) {}", hg_c2));
+        })
+    }
+
+    #[test]
+    fn restore_output_format() {
+        async_unit::tokio_unit_test(|| {
+            let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+            let c1 = create_commit(
+                repo.clone(),
+                vec![],
+                store_files(btreemap!{"a" => Some("1")}, repo.clone()),
+            );
+            let c2 = create_commit(
+                repo.clone(),
+                vec![c1],
+                store_files(btreemap!{"a" => Some("2")}, repo.clone()),
+            );
+            let hg1 = repo.get_hg_from_bonsai_changeset(c1).wait().unwrap();
+            let hg2 = repo.get_hg_from_bonsai_changeset(c2).wait().unwrap();
+
+            set_bookmark(&repo, "master", Some(c2));
+            set_bookmark(&repo, "stable", Some(c1));
+            let snapshot_index = Arc::new(SqliteBookmarkSnapshotIndex::in_memory().unwrap());
+            let key = take_bookmark_snapshot(&repo, snapshot_index, 24)
+                .wait()
+                .unwrap();
+            set_bookmark(&repo, "master", Some(c1));
+            set_bookmark(&repo, "stable", None);
+
+            let snapshot = load_bookmark_snapshot(&repo, &key).wait().unwrap();
+            let changes = diff_bookmark_snapshot(&repo, &snapshot, &BookmarkPrefix::empty())
+                .wait()
+                .unwrap();
+            assert_eq!(
+                format_restore_output(&changes, false),
+                format!(
+                    "move master from {} to {}\ncreate stable at {}\n\
+                     dry run, pass --commit to apply",
+                    hg1, hg2, hg1
+                )
+            );
+            assert_eq!(
+                format_restore_output(&changes, true),
+                format!("move master from {} to {}\ncreate stable at {}", hg1, hg2, hg1)
+            );
+
+            assert!(apply_bookmark_snapshot_changes(&repo, &changes).wait().unwrap());
+            let changes = diff_bookmark_snapshot(&repo, &snapshot, &BookmarkPrefix::empty())
+                .wait()
+                .unwrap();
+            assert_eq!(
+                format_restore_output(&changes, false),
+                "bookmarks match the snapshot"
+            );
+        })
+    }
+
+    #[test]
+    fn set_arguments() {
+        let app = prepare_command(App::new("bookmarks"));
+        let args = vec!["bookmarks", "set", "master", "abc", "--old-changeset", "def"];
+        let matches = app.clone().get_matches_from_safe(args.clone()).unwrap();
+        match matches.subcommand() {
+            (SET_CMD, Some(sub_m)) => assert_eq!(sub_m.value_of("old-changeset"), Some("def")),
+            bad => panic!("unexpected subcommand {:?}", bad.0),
+        }
+
+        let mut with_create = args;
+        with_create.push("--create");
+        assert!(app.get_matches_from_safe(with_create).is_err());
+    }
+
+    #[test]
+    fn set_compare_and_swap() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, c1, c2, c3) = linear_repo();
+            let master = Bookmark::new("master").unwrap();
+            set_bookmark(&repo, "master", Some(c1));
+
+            set_bookmark_to(&repo, master.clone(), c2, SetMode::Update(c1))
+                .wait()
+                .unwrap();
+            assert_eq!(get_bookmark(&repo, "master"), Some(c2));
+
+            // master moved to c2, so it isn't moved from c1 again
+            let err = set_bookmark_to(&repo, master.clone(), c3, SetMode::Update(c1))
+                .wait()
+                .unwrap_err();
+            assert!(err.to_string().contains("has moved"), "{}", err);
+            assert_eq!(get_bookmark(&repo, "master"), Some(c2));
+
+            set_bookmark_to(&repo, master, c3, SetMode::Force)
+                .wait()
+                .unwrap();
+            assert_eq!(get_bookmark(&repo, "master"), Some(c3));
+        })
+    }
+
+    #[test]
+    fn set_create() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, c1, c2, _) = linear_repo();
+            let stable = Bookmark::new("stable").unwrap();
+
+            set_bookmark_to(&repo, stable.clone(), c1, SetMode::Create)
+                .wait()
+                .unwrap();
+            assert_eq!(get_bookmark(&repo, "stable"), Some(c1));
+
+            let err = set_bookmark_to(&repo, stable, c2, SetMode::Create)
+                .wait()
+                .unwrap_err();
+            assert!(err.to_string().contains("already exists"), "{}", err);
+            assert_eq!(get_bookmark(&repo, "stable"), Some(c1));
+        })
+    }
+
+    #[test]
+    fn copy_refuses_existing_bookmark() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, c1, c2, _) = linear_repo();
+            set_bookmark(&repo, "master", Some(c2));
+            set_bookmark(&repo, "stable", Some(c1));
+
+            let master = Bookmark::new("master").unwrap();
+            let stable = Bookmark::new("stable").unwrap();
+            let err = copy_bookmark(&repo, master.clone(), stable.clone(), false, None)
+                .wait()
+                .unwrap_err();
+            assert!(err.to_string().contains("already exists"), "{}", err);
+            assert_eq!(get_bookmark(&repo, "stable"), Some(c1));
+
+            let copied = Bookmark::new("copied").unwrap();
+            assert_eq!(
+                copy_bookmark(&repo, master.clone(), copied, false, None)
+                    .wait()
+                    .unwrap(),
+                c2
+            );
+            assert_eq!(get_bookmark(&repo, "copied"), Some(c2));
+
+            copy_bookmark(&repo, master, stable, true, None)
+                .wait()
+                .unwrap();
+            assert_eq!(get_bookmark(&repo, "stable"), Some(c2));
+        })
+    }
+
+    #[test]
+    fn copy_requires_ancestor() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, c1, c2, c3) = linear_repo();
+            set_bookmark(&repo, "master", Some(c2));
+            set_bookmark(&repo, "other", Some(c3));
+
+            let err = copy_bookmark(
+                &repo,
+                Bookmark::new("other").unwrap(),
+                Bookmark::new("copied").unwrap(),
+                false,
+                Some(c1),
+            ).wait()
+                .unwrap_err();
+            assert!(err.to_string().contains("not a descendant"), "{}", err);
+            assert_eq!(get_bookmark(&repo, "copied"), None);
+
+            copy_bookmark(
+                &repo,
+                Bookmark::new("master").unwrap(),
+                Bookmark::new("copied").unwrap(),
+                false,
+                Some(c1),
+            ).wait()
+                .unwrap();
+            assert_eq!(get_bookmark(&repo, "copied"), Some(c2));
+        })
+    }
+
+    #[test]
+    fn move_all_bookmarks() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, c1, c2, _) = linear_repo();
+            set_bookmark(&repo, "release/a", Some(c1));
+            set_bookmark(&repo, "release/b", Some(c2));
+            set_bookmark(&repo, "master", Some(c2));
+
+            let moves = plan_bookmark_moves(
+                &repo,
+                BookmarkPrefix::new("release/").unwrap(),
+                "old/release/".to_string(),
+            ).wait()
+                .unwrap();
+            assert_eq!(
+                format_move_all_output(&moves, false, false),
+                format!(
+                    "copy release/a -> old/release/a at {}\n\
+                     copy release/b -> old/release/b at {}\n\
+                     dry run, pass --commit to apply",
+                    c1, c2
+                )
+            );
+
+            // A chunk size of 1 applies every bookmark in its own transaction
+            assert_eq!(
+                apply_bookmark_moves(&repo, moves.clone(), false, 1)
+                    .wait()
+                    .unwrap(),
+                2
+            );
+            assert_eq!(get_bookmark(&repo, "old/release/a"), Some(c1));
+            assert_eq!(get_bookmark(&repo, "old/release/b"), Some(c2));
+            assert_eq!(get_bookmark(&repo, "release/a"), Some(c1));
+
+            // The copies exist now, so applying the moves again fails
+            let err = apply_bookmark_moves(&repo, moves, false, 1)
+                .wait()
+                .unwrap_err();
+            assert!(err.to_string().contains("already exists"), "{}", err);
+
+            let moves = plan_bookmark_moves(
+                &repo,
+                BookmarkPrefix::new("release/").unwrap(),
+                "archive/".to_string(),
+            ).wait()
+                .unwrap();
+            assert_eq!(
+                apply_bookmark_moves(&repo, moves, true, 100)
+                    .wait()
+                    .unwrap(),
+                2
+            );
+            assert_eq!(get_bookmark(&repo, "archive/a"), Some(c1));
+            assert_eq!(get_bookmark(&repo, "archive/b"), Some(c2));
+            assert_eq!(get_bookmark(&repo, "release/a"), None);
+            assert_eq!(get_bookmark(&repo, "release/b"), None);
+            assert_eq!(get_bookmark(&repo, "master"), Some(c2));
+        })
+    }
+
+    #[test]
+    fn move_all_requires_ancestor() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, _, c2, c3) = linear_repo();
+            set_bookmark(&repo, "release/a", Some(c2));
+            set_bookmark(&repo, "release/b", Some(c3));
+
+            let moves = plan_bookmark_moves(
+                &repo,
+                BookmarkPrefix::new("release/").unwrap(),
+                "old/".to_string(),
+            ).wait()
+                .unwrap();
+            let targets = moves.iter().map(|mv| (mv.to.clone(), mv.target)).collect();
+            let err = check_descendants(&repo, Some(c2), targets)
+                .wait()
+                .unwrap_err();
+            assert!(err.to_string().contains("old/b"), "{}", err);
+        })
+    }
 }
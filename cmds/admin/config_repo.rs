@@ -334,6 +334,11 @@ fn import(logger: Logger, src: PathBuf, dest: PathBuf) -> BoxFuture<(), Error> {
         skip: None,
         commits_limit: None,
         no_bookmark: false,
+        strict_bookmarks: true,
+        path_violations_are_warnings: false,
+        live_repo: false,
+        allow_new_roots: false,
+        collect_metrics: false,
     }.import()
 }
 
@@ -0,0 +1,419 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writes a bundle of everything reachable from some bookmarks or heads, e.g. for backups or to
+//! seed a mirror. Unlike a pull, the bundle has the trees and file revisions of its changesets,
+//! so `hg unbundle` can apply it to a repo without remotefilelog, and it can also be pushed to
+//! another Mononoke repo as is.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Write};
+
+use async_compression::{Bzip2Compression, CompressorType, FlateCompression};
+use clap::{App, ArgMatches};
+use failure::Error;
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+use tokio;
+use tokio_io::AsyncRead;
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use bookmarks::Bookmark;
+use bundle2_resolver::{create_full_bundle, FullBundle};
+use mercurial_bundles::{create_bundle_stream, Bundle2Item};
+use mercurial_bundles::bundle2::{Bundle2Stream, StreamEvent};
+use mercurial_bundles::changegroup::{self, Section};
+use mercurial_bundles::wirepack;
+use mercurial_types::{HgChangesetId, NULL_CSID};
+use mononoke_types::BlobstoreBytes;
+
+/// How often the number of written bytes is logged
+const PROGRESS_BYTES: usize = 64 * 1024 * 1024;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about(
+        "writes a bundle of the changesets reachable from bookmarks or heads, with their trees \
+         and files, that can be applied with hg unbundle",
+    ).args_from_usage(
+        r#"
+        --bookmark [BOOKMARK]...    'bookmark to export, it is created by the bundle'
+        --head [REV]...             'head to export, a hash or a bookmark'
+        --common [REV]...           'leave out ancestors of these, the target must have them'
+        --compression [TYPE]        'none (default), gzip, bzip2 or zstd'
+        --output [PATH]             'local file to write the bundle to'
+        --blobstore-key [KEY]       'repo blobstore key to write the bundle to, buffers it all'
+        --verify                    'read the bundle back and check that all its parts decode'
+        "#,
+    )
+}
+
+/// Where a bundle is written
+#[derive(Clone, Debug)]
+enum Destination {
+    File(String),
+    Blobstore(String),
+}
+
+/// What a bundle has, as written or as read back
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct BundleCounts {
+    changesets: usize,
+    trees: usize,
+    files: usize,
+}
+
+impl<'a> From<&'a FullBundle> for BundleCounts {
+    fn from(bundle: &'a FullBundle) -> Self {
+        BundleCounts {
+            changesets: bundle.changesets,
+            trees: bundle.trees,
+            files: bundle.files,
+        }
+    }
+}
+
+pub fn handle_command<'a>(
+    repo: &BlobRepo,
+    matches: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let repo = repo.clone();
+    let destination = match (matches.value_of("output"), matches.value_of("blobstore-key")) {
+        (Some(path), None) => Destination::File(path.to_string()),
+        (None, Some(key)) => Destination::Blobstore(key.to_string()),
+        _ => {
+            return future::err(format_err!(
+                "exactly one of --output and --blobstore-key must be given"
+            )).boxify()
+        }
+    };
+    let compression = try_boxfuture!(parse_compression(matches.value_of("compression")));
+    let verify = matches.is_present("verify");
+    let bookmarks: Vec<_> = matches
+        .values_of("bookmark")
+        .map(|values| values.map(|value| value.to_string()).collect())
+        .unwrap_or_default();
+    let heads: Vec<_> = matches
+        .values_of("head")
+        .map(|values| values.map(|value| value.to_string()).collect())
+        .unwrap_or_default();
+    let common: Vec<_> = matches
+        .values_of("common")
+        .map(|values| values.map(|value| value.to_string()).collect())
+        .unwrap_or_default();
+    if bookmarks.is_empty() && heads.is_empty() {
+        return future::err(format_err!("at least one --bookmark or --head must be given"))
+            .boxify();
+    }
+
+    let bookmarks = future::join_all(bookmarks.into_iter().map({
+        cloned!(repo);
+        move |name| {
+            let bookmark = try_boxfuture!(Bookmark::new(&name));
+            repo.get_bookmark(&bookmark)
+                .and_then(move |cs| match cs {
+                    Some(cs) => Ok((bookmark, cs)),
+                    None => Err(format_err!("bookmark {} does not exist", name)),
+                })
+                .boxify()
+        }
+    }));
+    let heads = future::join_all(heads.into_iter().map({
+        cloned!(repo);
+        move |rev| ::resolve_hg_rev(&repo, &rev)
+    }));
+    let common = future::join_all(common.into_iter().map({
+        cloned!(repo);
+        move |rev| ::resolve_hg_rev(&repo, &rev)
+    }));
+
+    bookmarks
+        .join3(heads, common)
+        .and_then({
+            cloned!(repo, logger);
+            move |(bookmarks, mut heads, mut common)| {
+                heads.extend(bookmarks.iter().map(|&(_, cs)| cs));
+                if common.is_empty() {
+                    common.push(NULL_CSID);
+                }
+                create_full_bundle(repo, common, heads, bookmarks, logger)
+            }
+        })
+        .and_then({
+            cloned!(repo, logger, destination);
+            move |bundle| {
+                let counts = BundleCounts::from(&bundle);
+                info!(
+                    logger,
+                    "writing {} changesets, {} trees and {} file revisions",
+                    counts.changesets,
+                    counts.trees,
+                    counts.files
+                );
+                write_bundle(&repo, bundle, compression, destination, logger)
+                    .map(move |()| counts)
+            }
+        })
+        .and_then(move |written| {
+            if !verify {
+                return future::ok(()).boxify();
+            }
+            read_bundle(&repo, destination, logger.clone())
+                .and_then(move |read| {
+                    if read == written {
+                        info!(logger, "verified the bundle");
+                        Ok(())
+                    } else {
+                        Err(format_err!(
+                            "the bundle has {:?}, but {:?} were written",
+                            read,
+                            written
+                        ))
+                    }
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn parse_compression(compression: Option<&str>) -> Result<Option<CompressorType>, Error> {
+    match compression {
+        None | Some("none") => Ok(None),
+        Some("gzip") => Ok(Some(CompressorType::Gzip(FlateCompression::default()))),
+        Some("bzip2") => Ok(Some(CompressorType::Bzip2(Bzip2Compression::Default))),
+        Some("zstd") => Ok(Some(CompressorType::Zstd { level: 0 })),
+        Some(other) => Err(format_err!("unknown --compression {}", other)),
+    }
+}
+
+fn write_bundle(
+    repo: &BlobRepo,
+    bundle: FullBundle,
+    compression: Option<CompressorType>,
+    destination: Destination,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let bytes = create_bundle_stream(bundle.parts, compression);
+
+    match destination {
+        Destination::File(path) => {
+            let mut file = try_boxfuture!(File::create(&path));
+            bytes
+                .fold(0, move |written, chunk| {
+                    file.write_all(chunk.as_ref())?;
+                    let now_written = written + chunk.len();
+                    if now_written / PROGRESS_BYTES > written / PROGRESS_BYTES {
+                        info!(logger, "wrote {} bytes", now_written);
+                    }
+                    Ok::<_, Error>(now_written)
+                })
+                .map(move |written| println!("wrote {} bytes to {}", written, path))
+                .boxify()
+        }
+        Destination::Blobstore(key) => {
+            let blobstore = repo.get_blobstore();
+            bytes
+                .concat2()
+                .and_then(move |bytes| {
+                    let written = bytes.len();
+                    blobstore
+                        .put(key.clone(), BlobstoreBytes::from_bytes(bytes))
+                        .map(move |()| println!("wrote {} bytes to key {}", written, key))
+                })
+                .boxify()
+        }
+    }
+}
+
+fn read_bundle(
+    repo: &BlobRepo,
+    destination: Destination,
+    logger: Logger,
+) -> BoxFuture<BundleCounts, Error> {
+    match destination {
+        Destination::File(path) => tokio::fs::File::open(path)
+            .from_err()
+            .and_then(move |file| count_bundle(BufReader::new(file), logger))
+            .boxify(),
+        Destination::Blobstore(key) => repo.get_blobstore()
+            .get(key.clone())
+            .and_then(move |bytes| match bytes {
+                Some(bytes) => count_bundle(Cursor::new(bytes.into_bytes()), logger),
+                None => future::err(format_err!("key {} is missing", key)).boxify(),
+            })
+            .boxify(),
+    }
+}
+
+/// Decodes every part of a bundle written by `write_bundle`
+fn count_bundle<R>(reader: R, logger: Logger) -> BoxFuture<BundleCounts, Error>
+where
+    R: AsyncRead + BufRead + Send + 'static,
+{
+    Bundle2Stream::new(reader, logger)
+        .fold(BundleCounts::default(), |counts, event| {
+            let item = match event {
+                StreamEvent::Next(item) => item,
+                StreamEvent::Done(_) => return future::ok(counts).boxify(),
+            };
+            match item {
+                Bundle2Item::Start(_) => future::ok(counts).boxify(),
+                Bundle2Item::Replycaps(_, caps) => caps.map(move |_| counts).boxify(),
+                Bundle2Item::Pushkey(_, empty) => empty.map(move |()| counts).boxify(),
+                Bundle2Item::Changegroup(_, parts) => parts
+                    .fold(counts, |mut counts, part| {
+                        match part {
+                            changegroup::Part::CgChunk(Section::Changeset, _) => {
+                                counts.changesets += 1
+                            }
+                            changegroup::Part::CgChunk(Section::Filelog(_), _) => counts.files += 1,
+                            changegroup::Part::CgChunk(section, _) => {
+                                return Err(format_err!("unexpected {:?} section", section))
+                            }
+                            changegroup::Part::SectionEnd(_) | changegroup::Part::End => {}
+                        }
+                        Ok(counts)
+                    })
+                    .boxify(),
+                Bundle2Item::B2xTreegroup2(_, parts) => parts
+                    .fold(counts, |mut counts, part| {
+                        if let wirepack::Part::Data(_) = part {
+                            counts.trees += 1;
+                        }
+                        Ok::<_, Error>(counts)
+                    })
+                    .boxify(),
+                other => future::err(format_err!("unexpected part {:?}", other)).boxify(),
+            }
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use bytes::Bytes;
+    use tempdir::TempDir;
+    use tests_utils::{create_commit, store_files};
+
+    /// Creates a repo with a linear history c1 <- c2 <- c3 and a bookmark at c3
+    fn linear_repo() -> (BlobRepo, HgChangesetId) {
+        let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+        let c1 = create_commit(
+            repo.clone(),
+            vec![],
+            store_files(btreemap!{"a" => Some("1"), "dir/b" => Some("1")}, repo.clone()),
+        );
+        let c2 = create_commit(
+            repo.clone(),
+            vec![c1],
+            store_files(btreemap!{"a" => Some("2")}, repo.clone()),
+        );
+        let c3 = create_commit(
+            repo.clone(),
+            vec![c2],
+            store_files(btreemap!{"dir/b" => None, "dir/c" => Some("3")}, repo.clone()),
+        );
+        let mut transaction = repo.update_bookmark_transaction();
+        transaction
+            .force_set(&Bookmark::new("master").unwrap(), &c3)
+            .unwrap();
+        assert!(transaction.commit().wait().unwrap());
+        let head = repo.get_hg_from_bonsai_changeset(c3).wait().unwrap();
+        (repo, head)
+    }
+
+    fn export(
+        repo: &BlobRepo,
+        common: Vec<HgChangesetId>,
+        head: HgChangesetId,
+        compression: Option<CompressorType>,
+        destination: Destination,
+    ) -> BundleCounts {
+        let logger = Logger::root(::slog::Discard, o!());
+        let bookmarks = vec![(Bookmark::new("master").unwrap(), head)];
+        let bundle = create_full_bundle(repo.clone(), common, vec![head], bookmarks, logger.clone())
+            .wait()
+            .unwrap();
+        let counts = BundleCounts::from(&bundle);
+        write_bundle(repo, bundle, compression, destination, logger)
+            .wait()
+            .unwrap();
+        counts
+    }
+
+    #[test]
+    fn verify_written_bundle() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, head) = linear_repo();
+            let logger = Logger::root(::slog::Discard, o!());
+            let tmpdir = TempDir::new("create_bundle_file").unwrap();
+            let path = tmpdir.path().join("bundle").to_str().unwrap().to_string();
+
+            let compression = parse_compression(Some("gzip")).unwrap();
+            let destination = Destination::File(path.clone());
+            let written = export(&repo, vec![NULL_CSID], head, compression, destination.clone());
+            // Every file revision is sent once, with the changeset that introduced it. The
+            // root and dir trees change in every commit.
+            assert_eq!(
+                written,
+                BundleCounts {
+                    changesets: 3,
+                    trees: 5,
+                    files: 4,
+                }
+            );
+            let read = read_bundle(&repo, destination, logger.clone())
+                .wait()
+                .unwrap();
+            assert_eq!(read, written);
+
+            // A corrupt bundle fails to decode
+            let mut bytes = ::std::fs::read(&path).unwrap();
+            let len = bytes.len();
+            bytes.truncate(len - 10);
+            assert!(
+                count_bundle(Cursor::new(Bytes::from(bytes)), logger)
+                    .wait()
+                    .is_err()
+            );
+        })
+    }
+
+    #[test]
+    fn bundle_to_blobstore_leaves_out_common() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, head) = linear_repo();
+            let logger = Logger::root(::slog::Discard, o!());
+            let parents = repo.get_changeset_parents(&head).wait().unwrap();
+
+            let destination = Destination::Blobstore("bundle".to_string());
+            let written = export(&repo, parents, head, None, destination.clone());
+            assert_eq!(
+                written,
+                BundleCounts {
+                    changesets: 1,
+                    trees: 2,
+                    files: 1,
+                }
+            );
+            let read = read_bundle(&repo, destination, logger).wait().unwrap();
+            assert_eq!(read, written);
+        })
+    }
+
+    #[test]
+    fn compression_types() {
+        assert!(parse_compression(None).unwrap().is_none());
+        assert!(parse_compression(Some("none")).unwrap().is_none());
+        assert!(parse_compression(Some("bzip2")).unwrap().is_some());
+        assert!(parse_compression(Some("lz4")).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use clap::{App, ArgMatches};
+use failure::{Error, Result};
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json;
+use slog::Logger;
+
+use bookmarks::Bookmark;
+use cmdlib::args;
+use hooks::HookManager;
+use hooks::hook_loader::load_hooks;
+use mercurial_types::{HgChangesetId, RepositoryId};
+use mononoke_api::{create_commit, CommitChecks, CommitParent, CommitSpec, FileChangeSpec};
+use mononoke_types::{FileType, MPath};
+use repo_client::MononokeRepo;
+
+use cross_repo_index_manager::read_repo_configs;
+
+/// A file change of the spec file, which is a JSON list of them. Exactly one of `content`,
+/// `content_file` and `delete` must be set.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileChangeEntry {
+    path: String,
+    /// New content of the file
+    content: Option<String>,
+    /// Local file to read the new content of the file from
+    content_file: Option<String>,
+    #[serde(default)]
+    delete: bool,
+    /// "regular" (the default), "executable" or "symlink"
+    #[serde(rename = "type")]
+    file_type: Option<String>,
+}
+
+fn parse_file_type(file_type: Option<&str>) -> Result<FileType> {
+    match file_type {
+        None | Some("regular") => Ok(FileType::Regular),
+        Some("executable") => Ok(FileType::Executable),
+        Some("symlink") => Ok(FileType::Symlink),
+        Some(other) => Err(format_err!("unknown file type {}", other)),
+    }
+}
+
+impl FileChangeEntry {
+    fn into_spec(self) -> Result<FileChangeSpec> {
+        let path = MPath::new(&self.path)?;
+        let file_type = parse_file_type(self.file_type.as_ref().map(|t| t.as_str()))?;
+        let content = match (self.content, self.content_file, self.delete) {
+            (Some(content), None, false) => Some((Bytes::from(content), file_type)),
+            (None, Some(content_file), false) => {
+                Some((Bytes::from(fs::read(content_file)?), file_type))
+            }
+            (None, None, true) => None,
+            _ => {
+                return Err(format_err!(
+                    "{}: exactly one of content, content_file and delete must be set",
+                    self.path
+                ))
+            }
+        };
+        Ok(FileChangeSpec { path, content })
+    }
+}
+
+fn parse_changes(spec: &[u8]) -> Result<Vec<FileChangeSpec>> {
+    let entries: Vec<FileChangeEntry> = serde_json::from_slice(spec)?;
+    entries.into_iter().map(FileChangeEntry::into_spec).collect()
+}
+
+/// A 40 hex digit revision is a changeset, anything else a bookmark
+fn parse_parent(rev: &str) -> Result<CommitParent> {
+    match HgChangesetId::from_str(rev) {
+        Ok(cs_id) => Ok(CommitParent::Changeset(cs_id)),
+        Err(_) => Ok(CommitParent::Bookmark(Bookmark::new(rev)?)),
+    }
+}
+
+/// The checks of the repo as configured in the config repo, or no checks but the default limits
+/// if there is no config repo to read them from
+fn get_checks<'a>(
+    args: &ArgMatches<'a>,
+    repo: &MononokeRepo,
+    repo_id: RepositoryId,
+    logger: &Logger,
+) -> Result<CommitChecks> {
+    let config_repo = match args.value_of("config-repo") {
+        Some(config_repo) => Path::new(config_repo),
+        None => {
+            warn!(
+                logger,
+                "no --config-repo, the commit isn't checked against the hooks and path rules of \
+                 the repo"
+            );
+            return Ok(CommitChecks {
+                hook_manager: repo.hook_manager(),
+                path_rules: repo.path_rules().clone(),
+                push_limits: repo.push_limits(),
+            });
+        }
+    };
+    let config_bookmark = args.value_of("config-bookmark").unwrap_or("master");
+    let configs = read_repo_configs(logger, config_repo, config_bookmark)?;
+    let config = configs
+        .repos
+        .into_iter()
+        .map(|(_, config)| config)
+        .find(|config| config.repoid == repo_id.id())
+        .ok_or_else(|| format_err!("the config repo has no repo with id {}", repo_id.id()))?;
+
+    let mut hook_manager =
+        HookManager::new_with_blobrepo(repo.blobrepo().clone(), None, logger.clone());
+    let path_rules = config.path_rules.clone();
+    let push_limits = config.push_limits;
+    load_hooks(&mut hook_manager, config)?;
+    Ok(CommitChecks {
+        hook_manager: Arc::new(hook_manager),
+        path_rules,
+        push_limits,
+    })
+}
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about(
+        "creates a commit on the server from a list of file changes, checks it as a push would \
+         be checked, and moves a bookmark to it",
+    ).args_from_usage(
+            r#"
+            --parent=<REV>                  'bookmark or hg changeset to commit on top of'
+            --spec=<FILE>                   'JSON list of file changes, each with a "path" and one of "content", "content_file" or "delete", and optionally a "type"'
+            --author=<AUTHOR>               'author of the commit'
+            --message=<MESSAGE>             'message of the commit'
+            --bookmark=[BOOKMARK]           'bookmark to move to the commit, it must point to the parent'
+            --dry-run                       'check the commit, but don't move the bookmark'
+            --config-repo=[PATH]            'path of the config repo, to check the commit against the hooks and path rules of the repo'
+            --config-bookmark=[BOOKMARK]    'bookmark of the config repo to read (default: master)'
+            "#,
+        )
+}
+
+pub fn handle_command<'a>(
+    matches: &ArgMatches<'a>,
+    args: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let parent = try_boxfuture!(parse_parent(args.value_of("parent").unwrap()));
+    let spec = try_boxfuture!(fs::read(args.value_of("spec").unwrap()));
+    let changes = try_boxfuture!(parse_changes(&spec));
+    let bookmark = match args.value_of("bookmark") {
+        Some(bookmark) => Some(try_boxfuture!(Bookmark::new(bookmark))),
+        None => None,
+    };
+    let dry_run = args.is_present("dry-run");
+    let spec = CommitSpec {
+        parent,
+        changes,
+        author: args.value_of("author").unwrap().to_string(),
+        message: args.value_of("message").unwrap().to_string(),
+        bookmark,
+        dry_run,
+    };
+
+    args::init_cachelib(matches);
+    let repo_id = try_boxfuture!(args::get_repo_id(matches));
+    let repo = try_boxfuture!(args::open_repo(&logger, matches));
+    let checks = try_boxfuture!(get_checks(args, &repo, repo_id, &logger));
+
+    create_commit(Arc::new(repo.blobrepo().clone()), checks, spec)
+        .and_then(move |created| {
+            let output = json!({
+                "hg_changeset": created.hg_cs_id.to_string(),
+                "bonsai_changeset": created.bcs_id.to_string(),
+                "bookmark_moved": created.bookmark_moved,
+                "dry_run": dry_run,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+            Ok(())
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_changes() {
+        let spec = br#"[
+            {"path": "dir/config", "content": "value = 1\n"},
+            {"path": "bin/tool", "content": "#!/bin/sh\n", "type": "executable"},
+            {"path": "old", "delete": true}
+        ]"#;
+        let changes = parse_changes(spec).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                FileChangeSpec {
+                    path: MPath::new("dir/config").unwrap(),
+                    content: Some((Bytes::from("value = 1\n"), FileType::Regular)),
+                },
+                FileChangeSpec {
+                    path: MPath::new("bin/tool").unwrap(),
+                    content: Some((Bytes::from("#!/bin/sh\n"), FileType::Executable)),
+                },
+                FileChangeSpec {
+                    path: MPath::new("old").unwrap(),
+                    content: None,
+                },
+            ]
+        );
+
+        assert!(parse_changes(br#"[{"path": "a"}]"#).is_err());
+        assert!(parse_changes(br#"[{"path": "a", "content": "", "delete": true}]"#).is_err());
+        assert!(parse_changes(br#"[{"path": "a", "content": "", "type": "fifo"}]"#).is_err());
+        assert!(parse_changes(br#"[{"path": "a", "contents": ""}]"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_parent() {
+        let cs = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+        assert_eq!(
+            parse_parent(cs).unwrap(),
+            CommitParent::Changeset(HgChangesetId::from_str(cs).unwrap())
+        );
+        assert_eq!(
+            parse_parent("master").unwrap(),
+            CommitParent::Bookmark(Bookmark::new("master").unwrap())
+        );
+    }
+}
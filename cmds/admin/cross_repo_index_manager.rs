@@ -0,0 +1,207 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::{App, ArgMatches, SubCommand};
+use failure::{Error, Result};
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+use tokio::runtime::Runtime;
+
+use blobrepo::BlobRepo;
+use bookmarks::Bookmark;
+use cmdlib::args;
+use cross_repo_index::CrossRepoIndex;
+use mercurial_types::{HgChangesetId, RepositoryId};
+use metaconfig::repoconfig::RepoConfigs;
+use mononoke_api::{backfill_cross_repo_index, find_changeset_repos, IndexedRepo, Publication,
+                   RepoHit};
+use repo_client::{open_blobrepo, open_cross_repo_index};
+
+const WHICH_REPO_CMD: &'static str = "which-repo";
+const BACKFILL_CMD: &'static str = "backfill";
+
+const DEFAULT_MAX_DISTANCE: u64 = 100_000;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    let which_repo = SubCommand::with_name(WHICH_REPO_CMD)
+        .about("lists the repos that have a changeset, and whether they published it")
+        .args_from_usage(
+            "<HG_CS>                         'hg changeset to look for'
+             --config-repo=<PATH>            'path of the config repo, which lists the repos'
+             --config-bookmark=[BOOKMARK]    'bookmark of the config repo to read (default: master)'
+             --max-distance=[GENERATIONS]    'publishing bookmarks further ahead of the changeset are not checked'",
+        );
+
+    let backfill = SubCommand::with_name(BACKFILL_CMD)
+        .about("records the changesets of the repo that are ancestors of its bookmarks");
+
+    app.about("set of commands to find the repos that have a changeset")
+        .subcommand(which_repo)
+        .subcommand(backfill)
+}
+
+pub fn handle_command<'a>(
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    match sub_m.subcommand() {
+        (WHICH_REPO_CMD, Some(sub_m)) => handle_which_repo(matches, sub_m, logger),
+        (BACKFILL_CMD, Some(_)) => handle_backfill(matches, logger),
+        _ => {
+            println!("{}", sub_m.usage());
+            ::std::process::exit(1);
+        }
+    }
+}
+
+/// Reads the configs of the repos from the config repo at `path`, as the server does
+pub fn read_repo_configs(logger: &Logger, path: &Path, bookmark: &str) -> Result<RepoConfigs> {
+    let config_repo = BlobRepo::new_rocksdb(
+        logger.new(o!["repo" => "Config repo"]),
+        path,
+        RepositoryId::new(0),
+    )?;
+    let bookmark = Bookmark::new(bookmark)?;
+
+    let mut runtime = Runtime::new()?;
+    let changesetid = runtime
+        .block_on(config_repo.get_bookmark(&bookmark))?
+        .ok_or_else(|| format_err!("config repo has no bookmark {}", bookmark))?;
+    runtime.block_on(RepoConfigs::read_config_repo(config_repo, changesetid))
+}
+
+fn format_hit(hit: &RepoHit) -> String {
+    let name = match hit.name {
+        Some(ref name) => name.clone(),
+        None => format!("unknown repo {}", hit.repo_id.id()),
+    };
+    let publication = match hit.publication {
+        Publication::Published(ref bookmark) => format!("published in {}", bookmark),
+        Publication::Draft => "not published".to_string(),
+        Publication::Unknown => "publication unknown".to_string(),
+        Publication::Missing => "missing from the repo".to_string(),
+    };
+    format!("{}: {}", name, publication)
+}
+
+fn handle_which_repo<'a>(
+    matches: &ArgMatches<'a>,
+    args: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let changeset = try_boxfuture!(HgChangesetId::from_str(args.value_of("HG_CS").unwrap()));
+    let config_repo = Path::new(args.value_of("config-repo").unwrap());
+    let config_bookmark = args.value_of("config-bookmark").unwrap_or("master");
+    let max_distance = match args.value_of("max-distance") {
+        Some(distance) => try_boxfuture!(
+            distance
+                .parse::<u64>()
+                .map_err(|_| format_err!("--max-distance must be a number, got {:?}", distance))
+        ),
+        None => DEFAULT_MAX_DISTANCE,
+    };
+
+    args::init_cachelib(matches);
+    let configs = try_boxfuture!(read_repo_configs(&logger, config_repo, config_bookmark));
+    let mut index: Option<Arc<CrossRepoIndex>> = None;
+    let mut repos = HashMap::new();
+    for (name, config) in configs.repos {
+        if !config.enabled || !config.cross_repo_index {
+            continue;
+        }
+        // The index is shared, so any of the repos can open it
+        if index.is_none() {
+            index = Some(try_boxfuture!(open_cross_repo_index(&config.repotype)));
+        }
+        let repo_id = RepositoryId::new(config.repoid);
+        let repo = try_boxfuture!(open_blobrepo(
+            logger.new(o!["repo" => name.clone()]),
+            config.repotype.clone(),
+            repo_id,
+            None,
+            args::get_open_repo_params(matches),
+        ));
+        let publishing = config
+            .bookmarks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|params| params.bookmark)
+            .collect();
+        repos.insert(
+            repo_id,
+            IndexedRepo {
+                name,
+                repo: Arc::new(repo),
+                publishing,
+            },
+        );
+    }
+    let index = match index {
+        Some(index) => index,
+        None => {
+            return future::err(format_err!("no repo is recorded in the cross-repo index")).boxify()
+        }
+    };
+
+    find_changeset_repos(index, Arc::new(repos), changeset, max_distance)
+        .map(move |hits| {
+            if hits.is_empty() {
+                println!("{} is not in the cross-repo index", changeset);
+            }
+            for hit in hits {
+                println!("{}", format_hit(&hit));
+            }
+        })
+        .boxify()
+}
+
+fn handle_backfill<'a>(matches: &ArgMatches<'a>, logger: Logger) -> BoxFuture<(), Error> {
+    args::init_cachelib(matches);
+    let repo_id = try_boxfuture!(args::get_repo_id(matches));
+    let index = try_boxfuture!(args::open_cross_repo_index(&logger, matches));
+    let repo = try_boxfuture!(args::open_repo(&logger, matches));
+
+    backfill_cross_repo_index(index, repo_id, Arc::new(repo.blobrepo().clone()))
+        .map(move |recorded| {
+            info!(
+                logger,
+                "recorded {} changesets of repo {} in the cross-repo index",
+                recorded,
+                repo_id.id()
+            )
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_hit() {
+        let bookmark = Bookmark::new("master").unwrap();
+        let hit = RepoHit {
+            repo_id: RepositoryId::new(1),
+            name: Some("fbsource".to_string()),
+            publication: Publication::Published(bookmark),
+        };
+        assert_eq!(format_hit(&hit), "fbsource: published in master");
+
+        let hit = RepoHit {
+            repo_id: RepositoryId::new(7),
+            name: None,
+            publication: Publication::Unknown,
+        };
+        assert_eq!(format_hit(&hit), "unknown repo 7: publication unknown");
+    }
+}
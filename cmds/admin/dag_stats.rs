@@ -0,0 +1,573 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Statistics of the shape of the changeset graph, to size caches and skip lists. The graph is
+//! walked from the bookmarks one generation at a time, from the highest one down: a changeset is
+//! only visited once all its children were, so the walk only keeps its frontier in memory, and
+//! the frontier together with the statistics so far is all a checkpoint needs to resume from.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{App, ArgMatches};
+use failure::{Error, Result};
+use futures::{future, stream, Future, Stream};
+use futures::future::{join_all, loop_fn, Loop};
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json::{self, to_string_pretty};
+use slog::Logger;
+
+use blobrepo::{BlobRepo, ChangesetFetcher};
+use bookmarks::BookmarkPrefix;
+use mononoke_types::{ChangesetId, DateTime};
+
+use storage_report::parse_arg;
+
+const DEFAULT_CONCURRENCY: usize = 100;
+const DEFAULT_CHECKPOINT_EVERY: u64 = 100_000;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DagStats {
+    /// Unix timestamp of --since, if the walk was restricted to the commits authored after it
+    pub since: Option<i64>,
+    pub commits: u64,
+    pub merges: u64,
+    pub merge_ratio: f64,
+    /// Commits without children among the walked commits
+    pub heads: u64,
+    pub max_generation: u64,
+    pub mean_generation: f64,
+    pub generation_sum: u64,
+    /// Number of commits by their number of children
+    pub branching_factor: BTreeMap<u64, u64>,
+    /// Number of commits by the month of their author date, in the timezone of the author
+    pub commits_per_month: BTreeMap<String, u64>,
+    /// False if the walk stopped at --max-commits. Run again with the same --checkpoint to
+    /// continue it.
+    pub complete: bool,
+}
+
+impl DagStats {
+    fn add(&mut self, generation: u64, children: u64, parents: usize, month: String) {
+        self.commits += 1;
+        if parents > 1 {
+            self.merges += 1;
+        }
+        if children == 0 {
+            self.heads += 1;
+        }
+        self.max_generation = ::std::cmp::max(self.max_generation, generation);
+        self.generation_sum += generation;
+        *self.branching_factor.entry(children).or_insert(0) += 1;
+        *self.commits_per_month.entry(month).or_insert(0) += 1;
+    }
+
+    fn compute_ratios(&mut self) {
+        if self.commits > 0 {
+            self.merge_ratio = self.merges as f64 / self.commits as f64;
+            self.mean_generation = self.generation_sum as f64 / self.commits as f64;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DagStatsOptions {
+    /// Only walk the commits authored at or after this date. Their ancestors that are older are
+    /// neither counted nor walked through.
+    pub since: Option<DateTime>,
+    /// Changesets fetched at once
+    pub concurrency: usize,
+    /// Where to save the progress of the walk, and to resume it from
+    pub checkpoint: Option<PathBuf>,
+    /// Commits between two saves of the checkpoint
+    pub checkpoint_every: u64,
+    /// Stop after this many commits, so that a walk can be split over several runs
+    pub max_commits: Option<u64>,
+}
+
+impl Default for DagStatsOptions {
+    fn default() -> Self {
+        DagStatsOptions {
+            since: None,
+            concurrency: DEFAULT_CONCURRENCY,
+            checkpoint: None,
+            checkpoint_every: DEFAULT_CHECKPOINT_EVERY,
+            max_commits: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    stats: DagStats,
+    /// The changesets to visit next, with their generation and their number of children
+    frontier: Vec<(String, u64, u64)>,
+}
+
+/// What a visit of a changeset contributes to the walk
+struct Visit {
+    month: String,
+    parents: Vec<(ChangesetId, u64)>,
+}
+
+struct DagWalk {
+    stats: DagStats,
+    /// Changesets that are yet to be visited, by generation, with the number of their children
+    /// that were visited
+    frontier: BTreeMap<u64, HashMap<ChangesetId, u64>>,
+    /// Commits added by this run
+    commits: u64,
+    commits_since_checkpoint: u64,
+}
+
+impl DagWalk {
+    fn new(since: Option<i64>) -> Self {
+        DagWalk {
+            stats: DagStats {
+                since,
+                ..DagStats::default()
+            },
+            frontier: BTreeMap::new(),
+            commits: 0,
+            commits_since_checkpoint: 0,
+        }
+    }
+
+    fn push(&mut self, cs_id: ChangesetId, generation: u64, children: u64) {
+        *self.frontier
+            .entry(generation)
+            .or_insert_with(HashMap::new)
+            .entry(cs_id)
+            .or_insert(0) += children;
+    }
+
+    fn pop_generation(&mut self) -> Option<(u64, HashMap<ChangesetId, u64>)> {
+        let generation = *self.frontier.keys().next_back()?;
+        self.frontier
+            .remove(&generation)
+            .map(|changesets| (generation, changesets))
+    }
+
+    fn add(&mut self, generation: u64, children: u64, visit: Option<Visit>) {
+        if let Some(Visit { month, parents }) = visit {
+            self.stats.add(generation, children, parents.len(), month);
+            self.commits += 1;
+            self.commits_since_checkpoint += 1;
+            for (parent, parent_generation) in parents {
+                self.push(parent, parent_generation, 1);
+            }
+        }
+    }
+
+    fn to_checkpoint(&self) -> Checkpoint {
+        let frontier = self.frontier
+            .iter()
+            .flat_map(|(generation, changesets)| {
+                changesets.iter().map(move |(cs_id, children)| {
+                    (cs_id.to_hex().to_string(), *generation, *children)
+                })
+            })
+            .collect();
+        Checkpoint {
+            stats: self.stats.clone(),
+            frontier,
+        }
+    }
+
+    fn from_checkpoint(checkpoint: Checkpoint, since: Option<i64>) -> Result<Self> {
+        if checkpoint.stats.since != since {
+            bail_msg!("the checkpoint is of a walk with a different --since");
+        }
+        let mut walk = DagWalk::new(since);
+        walk.stats = checkpoint.stats;
+        for (cs_id, generation, children) in checkpoint.frontier {
+            walk.push(ChangesetId::from_str(&cs_id)?, generation, children);
+        }
+        Ok(walk)
+    }
+}
+
+fn read_checkpoint(path: &Path) -> Result<Checkpoint> {
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Replaces the checkpoint at once, so that an interrupted save leaves the previous one intact
+fn write_checkpoint(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(checkpoint)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn with_generation(
+    changeset_fetcher: &Arc<ChangesetFetcher>,
+    cs_id: ChangesetId,
+) -> impl Future<Item = (ChangesetId, u64), Error = Error> {
+    changeset_fetcher
+        .get_generation_number(cs_id)
+        .map(move |generation| (cs_id, generation.value()))
+}
+
+/// The month of a changeset and its parents, or None if it is older than `since`
+fn visit(
+    repo: &BlobRepo,
+    changeset_fetcher: &Arc<ChangesetFetcher>,
+    cs_id: ChangesetId,
+    since: Option<i64>,
+) -> impl Future<Item = Option<Visit>, Error = Error> {
+    cloned!(changeset_fetcher);
+    repo.get_bonsai_changeset(cs_id).and_then(move |bcs| {
+        let date = bcs.author_date();
+        if since.map_or(false, |since| date.timestamp_secs() < since) {
+            return future::ok(None).left_future();
+        }
+        let month = date.as_chrono().format("%Y-%m").to_string();
+        join_all(
+            bcs.parents()
+                .map(|parent| with_generation(&changeset_fetcher, *parent))
+                .collect::<Vec<_>>(),
+        ).map(move |parents| Some(Visit { month, parents }))
+            .right_future()
+    })
+}
+
+fn start_walk(
+    repo: &BlobRepo,
+    changeset_fetcher: &Arc<ChangesetFetcher>,
+    options: &DagStatsOptions,
+    logger: &Logger,
+) -> BoxFuture<DagWalk, Error> {
+    let since = options.since.map(|since| since.timestamp_secs());
+    if let Some(ref path) = options.checkpoint {
+        if path.exists() {
+            let walk = try_boxfuture!(
+                read_checkpoint(path).and_then(|checkpoint| {
+                    DagWalk::from_checkpoint(checkpoint, since)
+                })
+            );
+            info!(
+                logger,
+                "resuming the walk after {} commits from {}",
+                walk.stats.commits,
+                path.display()
+            );
+            return future::ok(walk).boxify();
+        }
+    }
+
+    cloned!(changeset_fetcher);
+    repo.get_bookmarks_object()
+        .list_by_prefix(&BookmarkPrefix::empty(), &repo.get_repoid())
+        .and_then(move |(_, cs_id)| with_generation(&changeset_fetcher, cs_id))
+        .fold(DagWalk::new(since), |mut walk, (cs_id, generation)| {
+            walk.push(cs_id, generation, 0);
+            Ok::<_, Error>(walk)
+        })
+        .boxify()
+}
+
+/// Walks the changesets that are reachable from the bookmarks and computes the statistics of
+/// their graph
+pub fn dag_stats(
+    repo: BlobRepo,
+    options: DagStatsOptions,
+    logger: Logger,
+) -> BoxFuture<DagStats, Error> {
+    let changeset_fetcher = repo.get_changeset_fetcher();
+    let since = options.since.map(|since| since.timestamp_secs());
+    let checkpoint = options.checkpoint.clone();
+
+    start_walk(&repo, &changeset_fetcher, &options, &logger)
+        .and_then(move |walk| {
+            loop_fn(walk, move |mut walk| {
+                let (generation, changesets) = match walk.pop_generation() {
+                    Some(next) => next,
+                    None => {
+                        walk.stats.complete = true;
+                        return future::ok(Loop::Break(walk)).left_future();
+                    }
+                };
+
+                let visits = changesets.into_iter().map({
+                    cloned!(repo, changeset_fetcher);
+                    move |(cs_id, children)| {
+                        visit(&repo, &changeset_fetcher, cs_id, since)
+                            .map(move |visit| (children, visit))
+                    }
+                });
+                cloned!(options, logger);
+                stream::iter_ok::<_, Error>(visits)
+                    .buffered(options.concurrency)
+                    .fold(walk, move |mut walk, (children, visit)| {
+                        walk.add(generation, children, visit);
+                        Ok::<_, Error>(walk)
+                    })
+                    .and_then(move |mut walk| {
+                        // Nothing is left to resume if the frontier is empty
+                        let stop = match options.max_commits {
+                            Some(max_commits) => {
+                                walk.commits >= max_commits && !walk.frontier.is_empty()
+                            }
+                            None => false,
+                        };
+                        if let Some(ref path) = options.checkpoint {
+                            if stop || walk.commits_since_checkpoint >= options.checkpoint_every {
+                                write_checkpoint(path, &walk.to_checkpoint())?;
+                                walk.commits_since_checkpoint = 0;
+                                info!(logger, "walked {} commits", walk.stats.commits);
+                            }
+                        }
+                        if stop {
+                            Ok(Loop::Break(walk))
+                        } else {
+                            Ok(Loop::Continue(walk))
+                        }
+                    })
+                    .right_future()
+            })
+        })
+        .and_then(move |walk| {
+            let mut stats = walk.stats;
+            if stats.complete {
+                if let Some(ref path) = checkpoint {
+                    if path.exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+            stats.compute_ratios();
+            Ok(stats)
+        })
+        .boxify()
+}
+
+/// A date as YYYY-MM-DD, which is midnight UTC, or in RFC 3339
+fn parse_date(date: &str) -> Result<DateTime> {
+    DateTime::from_rfc3339(date)
+        .or_else(|_| DateTime::from_rfc3339(&format!("{}T00:00:00Z", date)))
+        .map_err(|_| format_err!("invalid date {}, expected YYYY-MM-DD or RFC 3339", date))
+}
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about(
+        "reports the shape of the changeset graph that is reachable from the bookmarks: commits, \
+         merges, generations, branching, heads and commits per month",
+    ).args_from_usage(
+            r#"
+            --since [DATE]              'only walk the commits authored after DATE (YYYY-MM-DD)'
+            --checkpoint [FILE]         'save the progress of the walk to FILE, resume from it'
+            --checkpoint-every [N]      'save the checkpoint every N commits'
+            --max-commits [N]           'stop after N commits, resume with the same --checkpoint'
+            --concurrency [N]           'how many changesets to fetch at once'
+            "#,
+        )
+}
+
+pub fn handle_command<'a>(
+    repo: &BlobRepo,
+    matches: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let default = DagStatsOptions::default();
+    let since = match matches.value_of("since") {
+        Some(date) => Some(try_boxfuture!(parse_date(date))),
+        None => None,
+    };
+    let options = DagStatsOptions {
+        since,
+        concurrency: try_boxfuture!(parse_arg(matches, "concurrency"))
+            .unwrap_or(default.concurrency),
+        checkpoint: matches.value_of("checkpoint").map(PathBuf::from),
+        checkpoint_every: try_boxfuture!(parse_arg(matches, "checkpoint-every"))
+            .unwrap_or(default.checkpoint_every),
+        max_commits: try_boxfuture!(parse_arg(matches, "max-commits")),
+    };
+    if options.max_commits.is_some() && options.checkpoint.is_none() {
+        return future::err(format_err!("--max-commits needs a --checkpoint to resume from"))
+            .boxify();
+    }
+
+    dag_stats(repo.clone(), options, logger)
+        .and_then(|stats| {
+            println!("{}", to_string_pretty(&stats)?);
+            Ok(())
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use tempdir::TempDir;
+
+    use blobrepo::save_bonsai_changesets;
+    use bookmarks::Bookmark;
+    use mononoke_types::BonsaiChangesetMut;
+
+    fn commit(repo: &BlobRepo, parents: Vec<ChangesetId>, date: &str) -> ChangesetId {
+        let bcs = BonsaiChangesetMut {
+            parents,
+            author: "author".to_string(),
+            author_date: DateTime::from_rfc3339(date).unwrap(),
+            committer: None,
+            committer_date: None,
+            message: "message".to_string(),
+            extra: btreemap!{},
+            file_changes: btreemap!{},
+        }.freeze()
+            .unwrap();
+        let bcs_id = bcs.get_changeset_id();
+        save_bonsai_changesets(vec![bcs], repo.clone())
+            .wait()
+            .unwrap();
+        bcs_id
+    }
+
+    fn set_bookmark(repo: &BlobRepo, name: &str, cs_id: ChangesetId) {
+        let mut transaction = repo.update_bookmark_transaction();
+        transaction
+            .force_set(&Bookmark::new(name).unwrap(), &cs_id)
+            .unwrap();
+        assert!(transaction.commit().wait().unwrap());
+    }
+
+    /// Creates the graph below, with the bookmarks master on d, feature on c and old on a, which
+    /// is not a head
+    ///
+    ///         d
+    ///         |
+    ///    c    m
+    ///     \  / \
+    ///      b    a
+    ///       \  /
+    ///        r
+    fn dag_repo() -> BlobRepo {
+        let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+        let r = commit(&repo, vec![], "2018-01-05T10:00:00Z");
+        let a = commit(&repo, vec![r], "2018-01-20T10:00:00Z");
+        let b = commit(&repo, vec![r], "2018-02-03T10:00:00Z");
+        let m = commit(&repo, vec![a, b], "2018-02-10T10:00:00Z");
+        let c = commit(&repo, vec![b], "2018-03-01T10:00:00Z");
+        let d = commit(&repo, vec![m], "2018-03-15T10:00:00Z");
+        set_bookmark(&repo, "master", d);
+        set_bookmark(&repo, "feature", c);
+        set_bookmark(&repo, "old", a);
+        repo
+    }
+
+    fn run(repo: &BlobRepo, options: DagStatsOptions) -> DagStats {
+        let logger = Logger::root(::slog::Discard, o!());
+        dag_stats(repo.clone(), options, logger).wait().unwrap()
+    }
+
+    fn full_stats() -> DagStats {
+        DagStats {
+            since: None,
+            commits: 6,
+            merges: 1,
+            merge_ratio: 1.0 / 6.0,
+            heads: 2,
+            max_generation: 4,
+            mean_generation: 2.5,
+            generation_sum: 15,
+            branching_factor: btreemap!{0 => 2, 1 => 2, 2 => 2},
+            commits_per_month: btreemap!{
+                "2018-01".to_string() => 2,
+                "2018-02".to_string() => 2,
+                "2018-03".to_string() => 2,
+            },
+            complete: true,
+        }
+    }
+
+    #[test]
+    fn test_dag_stats() {
+        async_unit::tokio_unit_test(|| {
+            let repo = dag_repo();
+            assert_eq!(run(&repo, DagStatsOptions::default()), full_stats());
+        })
+    }
+
+    #[test]
+    fn test_dag_stats_since() {
+        async_unit::tokio_unit_test(|| {
+            let repo = dag_repo();
+            let since = parse_date("2018-02-01").unwrap();
+            let options = DagStatsOptions {
+                since: Some(since),
+                ..DagStatsOptions::default()
+            };
+            // r and a are older, so only b, m, c and d are walked
+            let expected = DagStats {
+                since: Some(since.timestamp_secs()),
+                commits: 4,
+                merges: 1,
+                merge_ratio: 0.25,
+                heads: 2,
+                max_generation: 4,
+                mean_generation: 3.0,
+                generation_sum: 12,
+                branching_factor: btreemap!{0 => 2, 1 => 1, 2 => 1},
+                commits_per_month: btreemap!{
+                    "2018-02".to_string() => 2,
+                    "2018-03".to_string() => 2,
+                },
+                complete: true,
+            };
+            assert_eq!(run(&repo, options), expected);
+        })
+    }
+
+    #[test]
+    fn test_dag_stats_resume() {
+        async_unit::tokio_unit_test(|| {
+            let repo = dag_repo();
+            let tmpdir = TempDir::new("dag_stats").unwrap();
+            let checkpoint = tmpdir.path().join("checkpoint");
+            let options = DagStatsOptions {
+                checkpoint: Some(checkpoint.clone()),
+                max_commits: Some(2),
+                ..DagStatsOptions::default()
+            };
+
+            // The walk stops after a whole generation: d, then m and c
+            let partial = run(&repo, options.clone());
+            assert!(!partial.complete);
+            assert_eq!(partial.commits, 3);
+            assert!(checkpoint.exists());
+
+            let rest = DagStatsOptions {
+                max_commits: None,
+                ..options.clone()
+            };
+            assert_eq!(run(&repo, rest.clone()), full_stats());
+            assert!(!checkpoint.exists());
+
+            // A checkpoint can't be resumed with another --since
+            run(&repo, options);
+            let logger = Logger::root(::slog::Discard, o!());
+            let other_since = DagStatsOptions {
+                since: Some(parse_date("2018-02-01").unwrap()),
+                ..rest
+            };
+            assert!(dag_stats(repo, other_since, logger).wait().is_err());
+        })
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("2018-02-01").unwrap().timestamp_secs(),
+            parse_date("2018-02-01T00:00:00Z").unwrap().timestamp_secs()
+        );
+        assert!(parse_date("February").is_err());
+    }
+}
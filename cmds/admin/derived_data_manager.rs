@@ -0,0 +1,141 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use cmdlib::args;
+use derived_data::{derivation_by_name, fetch_derived, DerivationRecord, DerivedDataStatus};
+use mercurial_types::HgChangesetId;
+use mononoke_types::DateTime;
+
+const STATUS_CMD: &'static str = "status";
+const FETCH_CMD: &'static str = "fetch";
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    let status = SubCommand::with_name(STATUS_CMD)
+        .about("shows the status of each type of data derived from a changeset")
+        .arg(
+            Arg::with_name("CS")
+                .required(true)
+                .help("hg changeset id"),
+        );
+
+    let fetch = SubCommand::with_name(FETCH_CMD)
+        .about("prints a type of data derived from a changeset, deriving it if it wasn't yet")
+        .arg(
+            Arg::with_name("CS")
+                .required(true)
+                .help("hg changeset id"),
+        )
+        .arg(
+            Arg::with_name("TYPE")
+                .required(true)
+                .help("type of derived data, e.g. changed_files"),
+        );
+
+    app.about("set of commands to inspect the data derived from changesets")
+        .subcommand(status)
+        .subcommand(fetch)
+}
+
+pub fn handle_command<'a>(
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let status = try_boxfuture!(args::open_derived_data_status(&logger, matches));
+    match sub_m.subcommand() {
+        (STATUS_CMD, Some(sub_m)) => handle_status(matches, sub_m, status),
+        (FETCH_CMD, Some(sub_m)) => {
+            args::init_cachelib(matches);
+            handle_fetch(matches, sub_m, logger, status)
+        }
+        _ => {
+            println!("{}", sub_m.usage());
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn format_record(record: &DerivationRecord, now: i64) -> String {
+    format!(
+        "{}: {} after {} attempts, updated at {} ({}s ago)",
+        record.data_type,
+        record.state,
+        record.attempts,
+        record.updated_at,
+        now - record.updated_at
+    )
+}
+
+fn handle_status<'a>(
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    status: Arc<DerivedDataStatus>,
+) -> BoxFuture<(), Error> {
+    let repo_id = try_boxfuture!(args::get_repo_id(matches));
+    let cs = try_boxfuture!(HgChangesetId::from_str(sub_m.value_of("CS").unwrap()));
+
+    status
+        .get(repo_id, cs)
+        .map(move |records| {
+            if records.is_empty() {
+                println!("no data derived from {} was queued", cs);
+            }
+            let now = DateTime::now().timestamp_secs();
+            for record in records {
+                println!("{}", format_record(&record, now));
+            }
+        })
+        .boxify()
+}
+
+fn handle_fetch<'a>(
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    logger: Logger,
+    status: Arc<DerivedDataStatus>,
+) -> BoxFuture<(), Error> {
+    let cs = try_boxfuture!(HgChangesetId::from_str(sub_m.value_of("CS").unwrap()));
+    let derivation = try_boxfuture!(derivation_by_name(sub_m.value_of("TYPE").unwrap()));
+    let repo = try_boxfuture!(args::open_repo(&logger, matches));
+
+    fetch_derived(repo.blobrepo(), status, derivation, cs)
+        .map(|data| print!("{}", String::from_utf8_lossy(&data)))
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use derived_data::DerivationState;
+    use mercurial_types::RepositoryId;
+
+    #[test]
+    fn test_format_record() {
+        let record = DerivationRecord {
+            repo_id: RepositoryId::new(0),
+            changeset: HgChangesetId::from_str("1111111111111111111111111111111111111111")
+                .unwrap(),
+            data_type: "changed_files".to_string(),
+            state: DerivationState::Failed,
+            attempts: 3,
+            updated_at: 100,
+        };
+        assert_eq!(
+            format_record(&record, 160),
+            "changed_files: failed after 3 attempts, updated at 100 (60s ago)"
+        );
+    }
+}
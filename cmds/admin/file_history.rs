@@ -0,0 +1,734 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Lists changesets that touched a path.
+//!
+//! History is computed from the filenodes table: starting from the path's filenode in the
+//! starting changeset, filenode parents (and copy sources with `--follow`) are looked up one by one
+//! until `--limit` changes are found, and are reported via their linknodes. If the history of the
+//! path contains a merge, a single chain of parents isn't enough: all filenodes of the path are
+//! fetched instead, and the ones reachable from the starting filenode are reported.
+//!
+//! Filenodes don't record deletions, and their rows may be missing. If the path doesn't exist in
+//! the starting changeset, or a filenode can't be found, ancestors of the starting changeset are
+//! walked instead, most recent first, checking which of them changed the path. That walk visits
+//! at most `MANIFEST_WALK_LIMIT` changesets.
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use clap::{App, ArgMatches};
+use failure::Error;
+use futures::{future, Future};
+use futures::future::{join_all, loop_fn, Loop};
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json::to_string_pretty;
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use filenodes::FilenodeInfo;
+use mercurial_types::{Changeset, HgChangesetId, HgFileNodeId, MPath, RepoPath};
+use mononoke_types::{ChangesetId, EscapedPath, FileChange, Generation, MaybeUtf8Bytes};
+
+const DEFAULT_LIMIT: usize = 10;
+const MANIFEST_WALK_LIMIT: usize = 10000;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("lists changesets that touched a path, most recent first")
+        .args_from_usage(
+            r#"
+            <HG_CHANGESET_OR_BOOKMARK>  'revision to start from'
+            <PATH>                      'path of the file'
+            -l, --limit=[LIMIT]         'maximum number of changesets to list, defaults to 10'
+            --follow                    'follow renames using copy metadata'
+            --json                      'if provided json will be returned'
+            "#,
+        )
+}
+
+pub fn handle_command<'a>(
+    repo: &BlobRepo,
+    matches: &ArgMatches<'a>,
+    _logger: Logger,
+) -> BoxFuture<(), Error> {
+    let rev = matches.value_of("HG_CHANGESET_OR_BOOKMARK").unwrap();
+    let path = try_boxfuture!(MPath::new(matches.value_of("PATH").unwrap()));
+    let limit = match matches.value_of("limit") {
+        Some(limit) => try_boxfuture!(limit.parse::<usize>()),
+        None => DEFAULT_LIMIT,
+    };
+    let follow = matches.is_present("follow");
+    let json_flag = matches.is_present("json");
+
+    ::resolve_hg_rev(repo, rev)
+        .and_then({
+            cloned!(repo);
+            move |cs_id| file_history(repo, cs_id, path, limit, follow)
+        })
+        .and_then(move |entries| {
+            if json_flag {
+                println!("{}", to_string_pretty(&entries)?);
+            } else {
+                for entry in entries {
+                    println!("{}", format_entry(&entry));
+                }
+            }
+            Ok(())
+        })
+        .boxify()
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    Copied,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FileHistoryEntry {
+    pub changeset: HgChangesetId,
+    pub author: MaybeUtf8Bytes,
+    pub date: String,
+    /// Path at this changeset, differs from the requested path for changes before a rename
+    pub path: EscapedPath,
+    pub change: ChangeKind,
+    pub copied_from: Option<EscapedPath>,
+}
+
+fn format_entry(entry: &FileHistoryEntry) -> String {
+    let change = match entry.copied_from {
+        Some(ref copied_from) => format!("copied from {}", copied_from),
+        None => format!("{:?}", entry.change).to_lowercase(),
+    };
+    format!(
+        "{} {} {} {} ({})",
+        entry.changeset, entry.date, entry.author, entry.path, change
+    )
+}
+
+/// A change of the path found by one of the walks, before changeset details are fetched
+struct FoundChange {
+    changeset: HgChangesetId,
+    generation: Generation,
+    path: MPath,
+    change: ChangeKind,
+    copied_from: Option<MPath>,
+}
+
+/// Returns up to `limit` most recent changes of `path` in ancestors of `start` (including
+/// `start` itself). If `follow` is set, the history of the copy source is listed after a copy.
+pub fn file_history(
+    repo: BlobRepo,
+    start: HgChangesetId,
+    path: MPath,
+    limit: usize,
+    follow: bool,
+) -> BoxFuture<Vec<FileHistoryEntry>, Error> {
+    repo.get_changeset_by_changesetid(&start)
+        .and_then({
+            cloned!(repo, path);
+            move |cs| repo.find_file_in_manifest(&path, *cs.manifestid())
+        })
+        .and_then({
+            cloned!(repo, path);
+            move |filenode| match filenode {
+                Some(filenode) => {
+                    history_from_filenodes(repo, path, filenode, limit, follow).left_future()
+                }
+                None => future::ok(None).right_future(),
+            }
+        })
+        .and_then({
+            cloned!(repo);
+            move |found| match found {
+                Some(found) => future::ok(found).left_future(),
+                None => history_from_manifests(repo, start, path, limit, follow).right_future(),
+            }
+        })
+        .and_then(move |mut found| {
+            found.sort_by(|a, b| {
+                b.generation
+                    .cmp(&a.generation)
+                    .then_with(|| a.changeset.cmp(&b.changeset))
+            });
+            found.truncate(limit);
+            join_all(
+                found
+                    .into_iter()
+                    .map(move |change| describe_change(&repo, change)),
+            )
+        })
+        .boxify()
+}
+
+fn describe_change(
+    repo: &BlobRepo,
+    change: FoundChange,
+) -> impl Future<Item = FileHistoryEntry, Error = Error> {
+    repo.get_changeset_by_changesetid(&change.changeset)
+        .map(move |cs| FileHistoryEntry {
+            changeset: change.changeset,
+            author: MaybeUtf8Bytes::from(cs.user()),
+            date: cs.time().to_string(),
+            path: change.path.to_escaped(),
+            change: change.change,
+            copied_from: change.copied_from.map(|path| path.to_escaped()),
+        })
+}
+
+/// The filenodes to continue a walk with after `info`: its parents, and its copy source if
+/// `follow` is set
+fn filenode_ancestors(
+    path: &MPath,
+    info: &FilenodeInfo,
+    follow: bool,
+) -> Vec<(MPath, HgFileNodeId)> {
+    let mut ancestors: Vec<_> = info.p1
+        .iter()
+        .chain(info.p2.iter())
+        .map(|parent| (path.clone(), *parent))
+        .collect();
+    if follow {
+        if let Some((ref copy_path, copy_filenode)) = info.copyfrom {
+            if let Some(copy_path) = copy_path.mpath() {
+                ancestors.push((copy_path.clone(), copy_filenode));
+            }
+        }
+    }
+    ancestors
+}
+
+struct ParentChainWalk {
+    next: Option<(MPath, HgFileNodeId)>,
+    found: Vec<(MPath, FilenodeInfo)>,
+}
+
+enum ParentChainEnd {
+    Found(Vec<(MPath, FilenodeInfo)>),
+    /// A filenode with more than one ancestor to follow was found
+    Merge,
+    /// A filenode is missing from the filenodes table
+    Missing,
+}
+
+/// Walks the chain of filenode parents starting from `filenode`, looking filenodes up one by one,
+/// until `limit` changes are found. Falls back to `history_from_all_filenodes` if a filenode has
+/// more than one ancestor to follow. Returns `None` if a filenode is missing from the filenodes
+/// table.
+fn history_from_filenodes(
+    repo: BlobRepo,
+    path: MPath,
+    filenode: HgFileNodeId,
+    limit: usize,
+    follow: bool,
+) -> BoxFuture<Option<Vec<FoundChange>>, Error> {
+    let walk = ParentChainWalk {
+        next: Some((path.clone(), filenode)),
+        found: vec![],
+    };
+
+    loop_fn(walk, {
+        cloned!(repo);
+        move |mut walk| {
+            let (path, filenode) = match walk.next.take() {
+                Some(next) if walk.found.len() < limit => next,
+                _ => return future::ok(Loop::Break(ParentChainEnd::Found(walk.found))).boxify(),
+            };
+
+            repo.get_filenodes()
+                .get_filenode(
+                    &RepoPath::FilePath(path.clone()),
+                    &filenode,
+                    &repo.get_repoid(),
+                )
+                .map(move |info| {
+                    let info = match info {
+                        Some(info) => info,
+                        None => return Loop::Break(ParentChainEnd::Missing),
+                    };
+                    let mut ancestors = filenode_ancestors(&path, &info, follow);
+                    if ancestors.len() > 1 {
+                        return Loop::Break(ParentChainEnd::Merge);
+                    }
+                    walk.next = ancestors.pop();
+                    walk.found.push((path, info));
+                    Loop::Continue(walk)
+                })
+                .boxify()
+        }
+    }).and_then(move |end| match end {
+        ParentChainEnd::Found(found) => found_changes(repo, found).map(Some).boxify(),
+        ParentChainEnd::Merge => history_from_all_filenodes(repo, path, filenode, follow),
+        ParentChainEnd::Missing => future::ok(None).boxify(),
+    })
+        .boxify()
+}
+
+struct FilenodeWalk {
+    pending: Vec<(MPath, HgFileNodeId)>,
+    filenodes: HashMap<MPath, HashMap<HgFileNodeId, FilenodeInfo>>,
+    visited: HashSet<(MPath, HgFileNodeId)>,
+    found: Vec<(MPath, FilenodeInfo)>,
+}
+
+/// Walks all filenode parents starting from `filenode`, fetching all filenodes of each walked
+/// path at once. Returns `None` if a filenode is missing from the filenodes table.
+fn history_from_all_filenodes(
+    repo: BlobRepo,
+    path: MPath,
+    filenode: HgFileNodeId,
+    follow: bool,
+) -> BoxFuture<Option<Vec<FoundChange>>, Error> {
+    let walk = FilenodeWalk {
+        pending: vec![(path, filenode)],
+        filenodes: HashMap::new(),
+        visited: HashSet::new(),
+        found: vec![],
+    };
+
+    loop_fn(walk, {
+        cloned!(repo);
+        move |mut walk| {
+            let (path, filenode) = match walk.pending.pop() {
+                Some(next) => next,
+                None => return future::ok(Loop::Break(Some(walk.found))).boxify(),
+            };
+            if !walk.visited.insert((path.clone(), filenode)) {
+                return future::ok(Loop::Continue(walk)).boxify();
+            }
+
+            let walk = if walk.filenodes.contains_key(&path) {
+                future::ok(walk).left_future()
+            } else {
+                repo.get_all_filenodes(RepoPath::FilePath(path.clone()))
+                    .map({
+                        cloned!(path);
+                        move |infos| {
+                            let infos = infos
+                                .into_iter()
+                                .map(|info| (info.filenode, info))
+                                .collect();
+                            walk.filenodes.insert(path, infos);
+                            walk
+                        }
+                    })
+                    .right_future()
+            };
+
+            walk.map(move |mut walk| {
+                let info = match walk.filenodes
+                    .get(&path)
+                    .and_then(|infos| infos.get(&filenode))
+                {
+                    Some(info) => info.clone(),
+                    None => return Loop::Break(None),
+                };
+
+                walk.pending.extend(filenode_ancestors(&path, &info, follow));
+                walk.found.push((path, info));
+                Loop::Continue(walk)
+            }).boxify()
+        }
+    }).and_then(move |found| match found {
+        None => future::ok(None).left_future(),
+        Some(found) => found_changes(repo, found).map(Some).right_future(),
+    })
+        .boxify()
+}
+
+/// Describes the changes made by the filenodes found by a walk, via their linknodes
+fn found_changes(
+    repo: BlobRepo,
+    found: Vec<(MPath, FilenodeInfo)>,
+) -> impl Future<Item = Vec<FoundChange>, Error = Error> {
+    join_all(found.into_iter().map(move |(path, info)| {
+        let change = if info.copyfrom.is_some() {
+            ChangeKind::Copied
+        } else if info.p1.is_none() && info.p2.is_none() {
+            ChangeKind::Added
+        } else {
+            ChangeKind::Modified
+        };
+        let copied_from = info.copyfrom
+            .as_ref()
+            .and_then(|&(ref copy_path, _)| copy_path.mpath().cloned());
+        let linknode = info.linknode;
+
+        repo.get_generation_number(&linknode)
+            .and_then(move |generation| {
+                generation.ok_or(format_err!("generation number not found for {}", linknode))
+            })
+            .map(move |generation| FoundChange {
+                changeset: linknode,
+                generation,
+                path,
+                change,
+                copied_from,
+            })
+    }))
+}
+
+struct ManifestWalk {
+    heap: BinaryHeap<(Generation, ChangesetId, MPath)>,
+    visited: HashSet<(ChangesetId, MPath)>,
+    steps: usize,
+    found: Vec<FoundChange>,
+}
+
+/// Walks ancestors of `start` from the most recent one and checks if they changed the path.
+/// Stops after `limit` changes were found or `MANIFEST_WALK_LIMIT` changesets were visited.
+fn history_from_manifests(
+    repo: BlobRepo,
+    start: HgChangesetId,
+    path: MPath,
+    limit: usize,
+    follow: bool,
+) -> BoxFuture<Vec<FoundChange>, Error> {
+    repo.get_bonsai_from_hg(&start)
+        .and_then(move |bonsai| bonsai.ok_or(format_err!("bonsai not found for {}", start)))
+        .and_then({
+            cloned!(repo);
+            move |bonsai| with_generations(&repo, vec![(bonsai, path)])
+        })
+        .and_then(move |start| {
+            let walk = ManifestWalk {
+                heap: start.into_iter().collect(),
+                visited: HashSet::new(),
+                steps: 0,
+                found: vec![],
+            };
+
+            loop_fn(walk, move |mut walk| {
+                if walk.found.len() >= limit || walk.steps >= MANIFEST_WALK_LIMIT {
+                    return future::ok(Loop::Break(walk.found)).boxify();
+                }
+                let (generation, cs_id, path) = match walk.heap.pop() {
+                    Some(next) => next,
+                    None => return future::ok(Loop::Break(walk.found)).boxify(),
+                };
+                if !walk.visited.insert((cs_id, path.clone())) {
+                    return future::ok(Loop::Continue(walk)).boxify();
+                }
+                walk.steps += 1;
+
+                repo.get_bonsai_changeset(cs_id)
+                    .and_then({
+                        cloned!(repo);
+                        move |bcs| {
+                            let parents: Vec<_> = bcs.parents().cloned().collect();
+                            let file_change = bcs.file_changes()
+                                .find(|&(changed_path, _)| *changed_path == path)
+                                .map(|(_, file_change)| file_change.cloned());
+                            classify_change(&repo, parents, path, file_change, follow)
+                        }
+                    })
+                    .and_then({
+                        cloned!(repo);
+                        move |(change, next)| {
+                            let change = match change {
+                                Some((path, change, copied_from)) => repo
+                                    .get_hg_from_bonsai_changeset(cs_id)
+                                    .map(move |changeset| {
+                                        Some(FoundChange {
+                                            changeset,
+                                            generation,
+                                            path,
+                                            change,
+                                            copied_from,
+                                        })
+                                    })
+                                    .left_future(),
+                                None => future::ok(None).right_future(),
+                            };
+                            change.join(with_generations(&repo, next))
+                        }
+                    })
+                    .map(move |(change, next)| {
+                        walk.found.extend(change);
+                        walk.heap.extend(next);
+                        Loop::Continue(walk)
+                    })
+                    .boxify()
+            })
+        })
+        .boxify()
+}
+
+/// Given the change of the path in a changeset (`None` if the changeset didn't touch it,
+/// `Some(None)` if it deleted it), returns the found change and the changesets and paths to
+/// continue the walk with.
+fn classify_change(
+    repo: &BlobRepo,
+    parents: Vec<ChangesetId>,
+    path: MPath,
+    file_change: Option<Option<FileChange>>,
+    follow: bool,
+) -> BoxFuture<
+    (
+        Option<(MPath, ChangeKind, Option<MPath>)>,
+        Vec<(ChangesetId, MPath)>,
+    ),
+    Error,
+> {
+    match file_change {
+        None => future::ok((None, with_parents(&parents, &path))).boxify(),
+        Some(None) => {
+            let next = with_parents(&parents, &path);
+            future::ok((Some((path, ChangeKind::Deleted, None)), next)).boxify()
+        }
+        Some(Some(file_change)) => match file_change.copy_from().cloned() {
+            Some((copy_path, copy_cs)) => {
+                let next = if follow {
+                    vec![(copy_cs, copy_path.clone())]
+                } else {
+                    vec![]
+                };
+                future::ok((Some((path, ChangeKind::Copied, Some(copy_path))), next)).boxify()
+            }
+            None => {
+                let next = with_parents(&parents, &path);
+                file_in_any_parent(repo, parents, path.clone())
+                    .map(move |in_parent| {
+                        if in_parent {
+                            (Some((path, ChangeKind::Modified, None)), next)
+                        } else {
+                            (Some((path, ChangeKind::Added, None)), vec![])
+                        }
+                    })
+                    .boxify()
+            }
+        },
+    }
+}
+
+fn with_parents(parents: &Vec<ChangesetId>, path: &MPath) -> Vec<(ChangesetId, MPath)> {
+    parents.iter().map(|p| (*p, path.clone())).collect()
+}
+
+fn file_in_any_parent(
+    repo: &BlobRepo,
+    parents: Vec<ChangesetId>,
+    path: MPath,
+) -> impl Future<Item = bool, Error = Error> {
+    join_all(parents.into_iter().map({
+        cloned!(repo);
+        move |parent| {
+            repo.get_hg_from_bonsai_changeset(parent)
+                .and_then({
+                    cloned!(repo);
+                    move |hg_cs_id| repo.get_changeset_by_changesetid(&hg_cs_id)
+                })
+                .and_then({
+                    cloned!(repo, path);
+                    move |cs| repo.find_file_in_manifest(&path, *cs.manifestid())
+                })
+                .map(|filenode| filenode.is_some())
+        }
+    })).map(|in_parents| in_parents.into_iter().any(|in_parent| in_parent))
+}
+
+fn with_generations(
+    repo: &BlobRepo,
+    changesets: Vec<(ChangesetId, MPath)>,
+) -> impl Future<Item = Vec<(Generation, ChangesetId, MPath)>, Error = Error> {
+    join_all(changesets.into_iter().map({
+        cloned!(repo);
+        move |(cs_id, path)| {
+            repo.get_generation_number_by_bonsai(&cs_id)
+                .and_then(move |generation| {
+                    generation.ok_or(format_err!("generation number not found for {}", cs_id))
+                })
+                .map(move |generation| (generation, cs_id, path))
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use async_unit;
+    use sqlfilenodes::SqlFilenodes;
+    use tests_utils::{create_commit, store_files, store_rename};
+
+    fn history(
+        repo: &BlobRepo,
+        start: ChangesetId,
+        path: &str,
+        limit: usize,
+        follow: bool,
+    ) -> Vec<(HgChangesetId, String, ChangeKind, Option<String>)> {
+        let start = repo.get_hg_from_bonsai_changeset(start).wait().unwrap();
+        file_history(repo.clone(), start, MPath::new(path).unwrap(), limit, follow)
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry.changeset,
+                    entry.path.to_string(),
+                    entry.change,
+                    entry.copied_from.map(|path| path.to_string()),
+                )
+            })
+            .collect()
+    }
+
+    fn hg(repo: &BlobRepo, cs_id: ChangesetId) -> HgChangesetId {
+        repo.get_hg_from_bonsai_changeset(cs_id).wait().unwrap()
+    }
+
+    fn without_filenodes(repo: &BlobRepo) -> BlobRepo {
+        let filenodes = SqlFilenodes::with_sqlite_in_memory().unwrap();
+        repo.clone().with_filenodes(Arc::new(filenodes))
+    }
+
+    #[test]
+    fn test_linear_history() {
+        async_unit::tokio_unit_test(|| {
+            let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+            let c1 = create_commit(
+                repo.clone(),
+                vec![],
+                store_files(btreemap!{"file" => Some("1"), "other" => Some("o")}, repo.clone()),
+            );
+            let c2 = create_commit(
+                repo.clone(),
+                vec![c1],
+                store_files(btreemap!{"file" => Some("2")}, repo.clone()),
+            );
+            let c3 = create_commit(
+                repo.clone(),
+                vec![c2],
+                store_files(btreemap!{"other" => Some("o2")}, repo.clone()),
+            );
+            let c4 = create_commit(
+                repo.clone(),
+                vec![c3],
+                store_files(btreemap!{"file" => Some("3")}, repo.clone()),
+            );
+
+            let expected = vec![
+                (hg(&repo, c4), "file".to_string(), ChangeKind::Modified, None),
+                (hg(&repo, c2), "file".to_string(), ChangeKind::Modified, None),
+                (hg(&repo, c1), "file".to_string(), ChangeKind::Added, None),
+            ];
+            assert_eq!(history(&repo, c4, "file", 10, false), expected);
+            assert_eq!(history(&repo, c4, "file", 2, false), expected[..2].to_vec());
+
+            // Same history is found without filenodes
+            let fallback_repo = without_filenodes(&repo);
+            assert_eq!(history(&fallback_repo, c4, "file", 10, false), expected);
+            assert_eq!(
+                history(&fallback_repo, c4, "file", 2, false),
+                expected[..2].to_vec()
+            );
+
+            // Deleted files are listed by walking manifests
+            let c5 = create_commit(
+                repo.clone(),
+                vec![c4],
+                store_files(btreemap!{"file" => None}, repo.clone()),
+            );
+            let mut expected_deleted =
+                vec![(hg(&repo, c5), "file".to_string(), ChangeKind::Deleted, None)];
+            expected_deleted.extend(expected);
+            assert_eq!(history(&repo, c5, "file", 10, false), expected_deleted);
+        });
+    }
+
+    #[test]
+    fn test_merge_history() {
+        async_unit::tokio_unit_test(|| {
+            let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+            let c1 = create_commit(
+                repo.clone(),
+                vec![],
+                store_files(btreemap!{"file" => Some("1")}, repo.clone()),
+            );
+            let c2 = create_commit(
+                repo.clone(),
+                vec![c1],
+                store_files(btreemap!{"file" => Some("2")}, repo.clone()),
+            );
+            let c3 = create_commit(
+                repo.clone(),
+                vec![c1],
+                store_files(btreemap!{"file" => Some("3")}, repo.clone()),
+            );
+            let c4 = create_commit(
+                repo.clone(),
+                vec![c2, c3],
+                store_files(btreemap!{"file" => Some("4")}, repo.clone()),
+            );
+
+            // Both sides of the merge are listed, in the order of their changesets as they have
+            // the same generation
+            let mut sides = vec![
+                (hg(&repo, c2), "file".to_string(), ChangeKind::Modified, None),
+                (hg(&repo, c3), "file".to_string(), ChangeKind::Modified, None),
+            ];
+            sides.sort_by_key(|side| side.0);
+            let mut expected = vec![
+                (hg(&repo, c4), "file".to_string(), ChangeKind::Modified, None),
+            ];
+            expected.extend(sides);
+            expected.push((hg(&repo, c1), "file".to_string(), ChangeKind::Added, None));
+
+            assert_eq!(history(&repo, c4, "file", 10, false), expected);
+            assert_eq!(history(&repo, c4, "file", 3, false), expected[..3].to_vec());
+        });
+    }
+
+    #[test]
+    fn test_rename_history() {
+        async_unit::tokio_unit_test(|| {
+            let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+            let c1 = create_commit(
+                repo.clone(),
+                vec![],
+                store_files(btreemap!{"a" => Some("content")}, repo.clone()),
+            );
+            let mut renamed = store_files(btreemap!{"a" => None}, repo.clone());
+            let (path, file_change) = store_rename(
+                (MPath::new("a").unwrap(), c1),
+                "b",
+                "content",
+                repo.clone(),
+            );
+            renamed.insert(path, file_change);
+            let c2 = create_commit(repo.clone(), vec![c1], renamed);
+            let c3 = create_commit(
+                repo.clone(),
+                vec![c2],
+                store_files(btreemap!{"b" => Some("changed")}, repo.clone()),
+            );
+
+            let expected = vec![
+                (hg(&repo, c3), "b".to_string(), ChangeKind::Modified, None),
+                (
+                    hg(&repo, c2),
+                    "b".to_string(),
+                    ChangeKind::Copied,
+                    Some("a".to_string()),
+                ),
+            ];
+            let mut expected_follow = expected.clone();
+            expected_follow.push((hg(&repo, c1), "a".to_string(), ChangeKind::Added, None));
+
+            assert_eq!(history(&repo, c3, "b", 10, false), expected);
+            assert_eq!(history(&repo, c3, "b", 10, true), expected_follow);
+
+            let fallback_repo = without_filenodes(&repo);
+            assert_eq!(history(&fallback_repo, c3, "b", 10, false), expected);
+            assert_eq!(history(&fallback_repo, c3, "b", 10, true), expected_follow);
+        });
+    }
+}
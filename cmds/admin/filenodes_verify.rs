@@ -0,0 +1,266 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Prints the filenode of a file and checks it against the blobstore, to debug filenodes that
+//! are out of sync with it, for example after a blobimport that partially failed.
+//!
+//! The filenode is the one of the manifest of the changeset. Its row in the filenodes table must
+//! exist, have the parents of the file envelope in the blobstore, and a linknode that is a
+//! changeset of the repo. Copy sources are printed but not checked: the envelope only has them in
+//! its metadata.
+
+use clap::{App, ArgMatches};
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use filenodes::FilenodeInfo;
+use mercurial_types::{Changeset, HgChangesetId, HgFileNodeId, MPath, RepoPath};
+
+use path_lookup;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about("prints the filenode of a file, and checks it against the blobstore")
+        .args_from_usage(
+            r#"
+            <HG_CHANGESET_OR_BOOKMARK>  'revision of the file'
+            <PATH>                      'path of the file'
+            --all                       'also print all the filenodes of the path'
+            "#,
+        )
+}
+
+pub fn handle_command<'a>(
+    repo: &BlobRepo,
+    matches: &ArgMatches<'a>,
+    _logger: Logger,
+) -> BoxFuture<(), Error> {
+    let rev = matches.value_of("HG_CHANGESET_OR_BOOKMARK").unwrap();
+    let path = try_boxfuture!(path_lookup::parse_path(matches.value_of("PATH").unwrap()));
+    let all = matches.is_present("all");
+
+    let all_filenodes = if all {
+        repo.get_all_filenodes(RepoPath::FilePath(path.clone()))
+            .map(Some)
+            .left_future()
+    } else {
+        future::ok(None).right_future()
+    };
+
+    ::resolve_hg_rev(repo, rev)
+        .and_then({
+            cloned!(repo);
+            move |cs_id| check_filenode(repo, cs_id, path)
+        })
+        .join(all_filenodes)
+        .and_then(|(check, all_filenodes)| {
+            println!("manifest filenode: {}", check.filenode);
+            match check.info {
+                Some(ref info) => println!("{}", format_filenode(info)),
+                None => println!("no filenode in the filenodes table"),
+            }
+            if let Some(all_filenodes) = all_filenodes {
+                println!("all filenodes of the path:");
+                for info in all_filenodes {
+                    println!("{}", format_filenode(&info));
+                }
+            }
+
+            let mismatches = check.mismatches();
+            for mismatch in &mismatches {
+                println!("MISMATCH: {}", mismatch);
+            }
+            if mismatches.is_empty() {
+                Ok(())
+            } else {
+                Err(format_err!(
+                    "{} mismatches between the filenodes table and the blobstore",
+                    mismatches.len()
+                ))
+            }
+        })
+        .boxify()
+}
+
+fn format_filenode(info: &FilenodeInfo) -> String {
+    let node = |node: Option<HgFileNodeId>| match node {
+        Some(node) => node.to_string(),
+        None => "none".to_string(),
+    };
+    let copyfrom = match info.copyfrom {
+        Some((ref path, filenode)) => format!("{}@{}", path, filenode),
+        None => "none".to_string(),
+    };
+    format!(
+        "filenode: {} p1: {} p2: {} copyfrom: {} linknode: {}",
+        info.filenode,
+        node(info.p1),
+        node(info.p2),
+        copyfrom,
+        info.linknode
+    )
+}
+
+/// The filenode of a file in the manifest of a changeset, what the blobstore and the filenodes
+/// table have for it
+struct FilenodeCheck {
+    path: MPath,
+    filenode: HgFileNodeId,
+    /// Parents in the file envelope
+    parents: (Option<HgFileNodeId>, Option<HgFileNodeId>),
+    /// Row of the filenodes table, if there is one
+    info: Option<FilenodeInfo>,
+    /// Whether the linknode of `info` is a changeset of the repo
+    linknode_exists: bool,
+}
+
+impl FilenodeCheck {
+    fn mismatches(&self) -> Vec<String> {
+        let info = match self.info {
+            Some(ref info) => info,
+            None => {
+                return vec![format!(
+                    "filenode {} of {} is missing from the filenodes table",
+                    self.filenode, self.path
+                )]
+            }
+        };
+
+        let mut mismatches = Vec::new();
+        if info.path != RepoPath::FilePath(self.path.clone()) || info.filenode != self.filenode {
+            mismatches.push(format!(
+                "filenodes table returned filenode {} of {} for filenode {} of {}",
+                info.filenode, info.path, self.filenode, self.path
+            ));
+        }
+        if (info.p1, info.p2) != self.parents {
+            mismatches.push(format!(
+                "parents are {:?} in the filenodes table but {:?} in the blobstore",
+                (info.p1, info.p2),
+                self.parents
+            ));
+        }
+        if !self.linknode_exists {
+            mismatches.push(format!("linknode {} is not in the repo", info.linknode));
+        }
+        mismatches
+    }
+}
+
+fn check_filenode(
+    repo: BlobRepo,
+    cs_id: HgChangesetId,
+    path: MPath,
+) -> BoxFuture<FilenodeCheck, Error> {
+    repo.get_changeset_by_changesetid(&cs_id)
+        .and_then({
+            cloned!(repo, path);
+            move |cs| repo.find_file_in_manifest(&path, *cs.manifestid())
+        })
+        .and_then({
+            cloned!(path);
+            move |filenode| filenode.ok_or(format_err!("{} is not a file in {}", path, cs_id))
+        })
+        .and_then({
+            cloned!(repo);
+            move |filenode| {
+                let parents = repo.get_file_envelope(&filenode.into_nodehash())
+                    .map(|envelope| {
+                        let (p1, p2) = envelope.parents();
+                        (p1.cloned().map(HgFileNodeId::new), p2.cloned().map(HgFileNodeId::new))
+                    });
+                let info = repo.get_filenodes().get_filenode(
+                    &RepoPath::FilePath(path.clone()),
+                    &filenode,
+                    &repo.get_repoid(),
+                );
+                parents.join(info).map(move |(parents, info)| FilenodeCheck {
+                    path,
+                    filenode,
+                    parents,
+                    info,
+                    linknode_exists: true,
+                })
+            }
+        })
+        .and_then(move |check| {
+            let linknode = match check.info {
+                Some(ref info) => info.linknode,
+                None => return future::ok(check).left_future(),
+            };
+            repo.changeset_exists(&linknode)
+                .map(move |linknode_exists| FilenodeCheck {
+                    linknode_exists,
+                    ..check
+                })
+                .right_future()
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use async_unit;
+    use sqlfilenodes::SqlFilenodes;
+    use tests_utils::{create_commit, store_files};
+
+    #[test]
+    fn test_check_filenode() {
+        async_unit::tokio_unit_test(|| {
+            let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+            let c1 = create_commit(
+                repo.clone(),
+                vec![],
+                store_files(btreemap!{"dir/file" => Some("1")}, repo.clone()),
+            );
+            let c2 = create_commit(
+                repo.clone(),
+                vec![c1],
+                store_files(btreemap!{"dir/file" => Some("2")}, repo.clone()),
+            );
+            let hg_c2 = repo.get_hg_from_bonsai_changeset(c2).wait().unwrap();
+            let path = MPath::new("dir/file").unwrap();
+
+            let check = check_filenode(repo.clone(), hg_c2, path.clone())
+                .wait()
+                .unwrap();
+            assert!(check.parents.0.is_some());
+            assert_eq!(check.info.as_ref().unwrap().linknode, hg_c2);
+            assert_eq!(check.mismatches(), Vec::<String>::new());
+
+            // Out of sync: the parents of the filenode are wrong, and its linknode is missing
+            let mut info = check.info.clone().unwrap();
+            info.p1 = None;
+            let out_of_sync = FilenodeCheck {
+                info: Some(info),
+                linknode_exists: false,
+                ..check
+            };
+            assert_eq!(out_of_sync.mismatches().len(), 2);
+
+            // The filenodes table is empty
+            let without_filenodes = repo.clone()
+                .with_filenodes(Arc::new(SqlFilenodes::with_sqlite_in_memory().unwrap()));
+            let check = check_filenode(without_filenodes, hg_c2, path.clone())
+                .wait()
+                .unwrap();
+            assert!(check.info.is_none());
+            assert!(check.mismatches()[0].contains("missing from the filenodes table"));
+
+            assert!(
+                check_filenode(repo, hg_c2, MPath::new("dir").unwrap())
+                    .wait()
+                    .is_err()
+            );
+        })
+    }
+}
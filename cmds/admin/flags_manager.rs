@@ -0,0 +1,239 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::env;
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{err_msg, Error};
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use mercurial_types::RepositoryId;
+use metaconfig::RepoFlag;
+use mononoke_types::DateTime;
+use repo_flags::{FlagAuditRecord, FlagOverride, RepoFlagOverrides};
+
+const SET_CMD: &'static str = "set";
+const GET_CMD: &'static str = "get";
+const LIST_CMD: &'static str = "list";
+
+const DEFAULT_AUDIT_LIMIT: usize = 20;
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    let set = SubCommand::with_name(SET_CMD)
+        .about("overrides a flag of the repo, or removes its override with 'unset'")
+        .arg(Arg::with_name("FLAG").required(true).help("name of the flag"))
+        .arg(
+            Arg::with_name("VALUE")
+                .required(true)
+                .possible_values(&["true", "false", "unset"])
+                .help("value of the override"),
+        )
+        .args_from_usage("--author [AUTHOR]    'who makes the change, $USER by default'");
+
+    let get = SubCommand::with_name(GET_CMD)
+        .about("shows the override of a flag of the repo")
+        .arg(Arg::with_name("FLAG").required(true).help("name of the flag"));
+
+    let list = SubCommand::with_name(LIST_CMD)
+        .about("lists the overrides of the flags of the repo")
+        .args_from_usage(
+            "--audit            'list the last changes of the overrides instead, newest first'
+             --limit [LIMIT]    'how many changes to list, 20 by default'",
+        );
+
+    app.about("set of commands to override the flags of a repo at runtime")
+        .subcommand(set)
+        .subcommand(get)
+        .subcommand(list)
+}
+
+pub fn handle_command<'a>(
+    overrides: Arc<RepoFlagOverrides>,
+    repo_id: RepositoryId,
+    matches: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    match matches.subcommand() {
+        (SET_CMD, Some(sub_m)) => handle_set(sub_m, logger, overrides, repo_id),
+        (GET_CMD, Some(sub_m)) => handle_get(sub_m, logger, overrides, repo_id),
+        (LIST_CMD, Some(sub_m)) => handle_list(sub_m, logger, overrides, repo_id),
+        _ => {
+            println!("{}", matches.usage());
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn format_value(value: Option<bool>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "unset".to_string(),
+    }
+}
+
+fn format_override(flag_override: &FlagOverride, now: i64) -> String {
+    format!(
+        "{}={} (set by {} at {}, {}s ago)",
+        flag_override.flag,
+        flag_override.value,
+        flag_override.updated_by,
+        flag_override.updated_at,
+        now - flag_override.updated_at
+    )
+}
+
+fn format_audit_record(record: &FlagAuditRecord, now: i64) -> String {
+    format!(
+        "{}: {} -> {} by {} at {} ({}s ago)",
+        record.flag,
+        format_value(record.old_value),
+        format_value(record.new_value),
+        record.author,
+        record.changed_at,
+        now - record.changed_at
+    )
+}
+
+fn parse_flag<'a>(args: &ArgMatches<'a>) -> Result<RepoFlag, Error> {
+    let flag = args.value_of("FLAG").expect("FLAG is not set");
+    flag.parse().map_err(|_| {
+        let known: Vec<_> = RepoFlag::all().iter().map(|flag| flag.as_str()).collect();
+        err_msg(format!(
+            "unknown flag {}, known flags are: {}",
+            flag,
+            known.join(", ")
+        ))
+    })
+}
+
+fn handle_set<'a>(
+    args: &ArgMatches<'a>,
+    _logger: Logger,
+    overrides: Arc<RepoFlagOverrides>,
+    repo_id: RepositoryId,
+) -> BoxFuture<(), Error> {
+    let flag = try_boxfuture!(parse_flag(args));
+    let value = match args.value_of("VALUE").expect("VALUE is not set") {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    };
+    let author = match args.value_of("author") {
+        Some(author) => author.to_string(),
+        None => try_boxfuture!(
+            env::var("USER").map_err(|_| err_msg("--author is not set, and neither is $USER"))
+        ),
+    };
+    let now = DateTime::now().timestamp_secs();
+
+    overrides
+        .set(repo_id, flag, value, author, now)
+        .map(move |record| println!("{}", format_audit_record(&record, now)))
+        .boxify()
+}
+
+fn handle_get<'a>(
+    args: &ArgMatches<'a>,
+    _logger: Logger,
+    overrides: Arc<RepoFlagOverrides>,
+    repo_id: RepositoryId,
+) -> BoxFuture<(), Error> {
+    let flag = try_boxfuture!(parse_flag(args));
+
+    overrides
+        .get(repo_id)
+        .map(move |overrides| {
+            let now = DateTime::now().timestamp_secs();
+            match overrides.iter().find(|o| o.flag == flag) {
+                Some(flag_override) => println!("{}", format_override(flag_override, now)),
+                None => println!("{} is not overridden, its value is from the config", flag),
+            }
+        })
+        .boxify()
+}
+
+fn handle_list<'a>(
+    args: &ArgMatches<'a>,
+    _logger: Logger,
+    overrides: Arc<RepoFlagOverrides>,
+    repo_id: RepositoryId,
+) -> BoxFuture<(), Error> {
+    if args.is_present("audit") {
+        let limit = match args.value_of("limit") {
+            Some(limit) => try_boxfuture!(
+                limit
+                    .parse::<usize>()
+                    .map_err(|_| err_msg(format!("--limit must be a number, got {}", limit)))
+            ),
+            None => DEFAULT_AUDIT_LIMIT,
+        };
+        overrides
+            .audit(repo_id, limit)
+            .map(|records| {
+                let now = DateTime::now().timestamp_secs();
+                for record in records {
+                    println!("{}", format_audit_record(&record, now));
+                }
+            })
+            .boxify()
+    } else {
+        overrides
+            .get(repo_id)
+            .map(|overrides| {
+                let now = DateTime::now().timestamp_secs();
+                for flag_override in overrides {
+                    println!("{}", format_override(&flag_override, now));
+                }
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_audit_record() {
+        let mut record = FlagAuditRecord {
+            repo_id: RepositoryId::new(0),
+            flag: "readonly".to_string(),
+            old_value: None,
+            new_value: Some(true),
+            author: "alice".to_string(),
+            changed_at: 100,
+        };
+        assert_eq!(
+            format_audit_record(&record, 160),
+            "readonly: unset -> true by alice at 100 (60s ago)"
+        );
+
+        record.old_value = Some(true);
+        record.new_value = None;
+        assert_eq!(
+            format_audit_record(&record, 160),
+            "readonly: true -> unset by alice at 100 (60s ago)"
+        );
+    }
+
+    #[test]
+    fn test_format_override() {
+        let flag_override = FlagOverride {
+            repo_id: RepositoryId::new(0),
+            flag: RepoFlag::DeterministicGetbundle,
+            value: false,
+            updated_by: "bob".to_string(),
+            updated_at: 100,
+        };
+        assert_eq!(
+            format_override(&flag_override, 100),
+            "deterministic_getbundle=false (set by bob at 100, 0s ago)"
+        );
+    }
+}
@@ -6,12 +6,19 @@
 
 #![deny(warnings)]
 
+#[cfg(test)]
+extern crate async_compression;
+extern crate async_unit;
+extern crate bytes;
 extern crate clap;
 #[macro_use]
 extern crate cloned;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
+#[cfg(test)]
+#[macro_use]
+extern crate maplit;
 extern crate promptly;
 #[macro_use]
 extern crate serde_derive;
@@ -23,59 +30,118 @@ extern crate blobrepo;
 extern crate blobstore;
 extern crate bonsai_utils;
 extern crate bookmarks;
+extern crate bundle2_resolver;
 extern crate cmdlib;
+extern crate cross_repo_index;
+extern crate derived_data;
+#[cfg(test)]
+extern crate dbbookmarks;
+extern crate fileblob;
+extern crate filenodes;
 #[macro_use]
 extern crate futures_ext;
+extern crate hooks;
 extern crate manifoldblob;
+extern crate mercurial_bundles;
 extern crate mercurial_types;
+extern crate metaconfig;
+extern crate mononoke_api;
 extern crate mononoke_types;
+#[cfg(test)]
+extern crate mononoke_types_mocks;
+extern crate push_journal;
+extern crate reachabilityindex;
+extern crate repo_client;
+extern crate repo_flags;
 extern crate revset;
 #[macro_use]
 extern crate slog;
+#[cfg(test)]
+extern crate sqlfilenodes;
 extern crate tempdir;
+#[cfg(test)]
+extern crate tests_utils;
 extern crate tokio;
+extern crate tokio_io;
 
 mod config_repo;
 mod bookmarks_manager;
+mod create_bundle_file;
+mod create_commit;
+mod cross_repo_index_manager;
+mod dag_stats;
+mod derived_data_manager;
+mod file_history;
+mod filenodes_verify;
+mod flags_manager;
+mod manifest_diff;
+mod path_lookup;
+mod push_journal_manager;
+mod scratch_cleanup;
+mod storage_attribution;
+mod storage_report;
 
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
 
 use std::fmt;
-use std::io;
-use std::str::FromStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::str::{self, FromStr};
 use std::sync::Arc;
 
+use bytes::Bytes;
 use clap::{App, Arg, SubCommand};
 use failure::{err_msg, Error, Result};
 use futures::future;
 use futures::prelude::*;
-use futures::stream::iter_ok;
 
 use blobrepo::BlobRepo;
-use blobstore::{new_memcache_blobstore, Blobstore, CacheBlobstoreExt, PrefixBlobstore};
+use blobstore::{new_memcache_blobstore, Blobstore, CacheBlobstoreExt};
 use bonsai_utils::{bonsai_diff, BonsaiDiffResult};
 use bookmarks::Bookmark;
 use cmdlib::args;
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
 use manifoldblob::ManifoldBlob;
 use mercurial_types::{Changeset, HgChangesetEnvelope, HgChangesetId, HgFileEnvelope,
-                      HgManifestEnvelope, HgManifestId, MPath, MPathElement, Manifest};
+                      HgManifestEnvelope, HgManifestId, HgNodeHash, MPath, Manifest};
 use mercurial_types::manifest::Content;
-use mononoke_types::{BlobstoreBytes, BlobstoreValue, BonsaiChangeset, FileContents};
-use revset::RangeNodeStream;
+use mononoke_types::{BlobstoreBytes, BlobstoreValue, BonsaiChangeset, ChangesetId, EscapedPath,
+                     FileContents, MaybeUtf8Bytes, RepoPrefix};
+use revset::{filter_by_path, first_parent_range, RangeNodeStream};
 use slog::Logger;
 
+use manifest_diff::{summarize_manifest_diff, write_manifest_diff, ManifestDiffSummary};
+use storage_report::KeyFamily;
+
 const BLOBSTORE_FETCH: &'static str = "blobstore-fetch";
+const BLOBSTORE_STORE: &'static str = "blobstore-store";
 const BONSAI_FETCH: &'static str = "bonsai-fetch";
 const CONTENT_FETCH: &'static str = "content-fetch";
 const CONFIG_REPO: &'static str = "config";
 const BOOKMARKS: &'static str = "bookmarks";
+const FILE_HISTORY: &'static str = "file-history";
+const FILENODES: &'static str = "filenodes";
+const PUSH_JOURNAL: &'static str = "push-journal";
+const STORAGE_REPORT: &'static str = "storage-report";
+const CREATE_BUNDLE_FILE: &'static str = "create-bundle-file";
+const DAG_STATS: &'static str = "dag-stats";
+const CROSS_REPO_INDEX: &'static str = "cross-repo-index";
+const DERIVED_DATA: &'static str = "derived-data";
+const STORAGE_ATTRIBUTION: &'static str = "storage-attribution";
+const CREATE_COMMIT: &'static str = "create-commit";
+const SCRATCH_CLEANUP: &'static str = "scratch-cleanup";
+const FLAGS: &'static str = "flags";
 
 const HG_CHANGESET: &'static str = "hg-changeset";
 const HG_CHANGESET_DIFF: &'static str = "diff";
+const HG_CHANGESET_FETCH: &'static str = "fetch";
+const HG_CHANGESET_INFO: &'static str = "info";
 const HG_CHANGESET_RANGE: &'static str = "range";
 
+const HEXDUMP_PREVIEW_BYTES: usize = 256;
+
 fn setup_app<'a, 'b>() -> App<'a, 'b> {
     let blobstore_fetch = SubCommand::with_name(BLOBSTORE_FETCH)
         .about("fetches blobs from manifold")
@@ -85,10 +151,24 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .long("decode-as")
                 .short("d")
                 .takes_value(true)
-                .possible_values(&["auto", "changeset", "manifest", "file", "contents"])
+                .possible_values(&["auto", "changeset", "manifest", "file", "contents", "raw-hex"])
                 .required(false)
                 .help("if provided decode the value"),
         )
+        .arg(
+            Arg::with_name("hex-bytes")
+                .long("hex-bytes")
+                .takes_value(true)
+                .required(false)
+                .help("number of bytes to hexdump with raw-hex, or when decoding fails"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .takes_value(false)
+                .required(false)
+                .help("exit with an error if the value can't be decoded"),
+        )
         .arg(
             Arg::with_name("use-memcache")
                 .long("use-memcache")
@@ -107,11 +187,41 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .help("Don't prepend a prefix based on the repo id to the key"),
         );
 
+    let blobstore_store = SubCommand::with_name(BLOBSTORE_STORE)
+        .about("stores raw bytes in manifold, to repair blobs")
+        .args_from_usage(
+            "<KEY>    'key of the blob to be stored'
+             --force  'overwrite the blob if the key already exists'",
+        )
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .value_name("PATH")
+                .required_unless("stdin")
+                .conflicts_with("stdin")
+                .help("file with the bytes of the blob"),
+        )
+        .arg(
+            Arg::with_name("stdin")
+                .long("stdin")
+                .takes_value(false)
+                .help("read the bytes of the blob from stdin"),
+        )
+        .arg(
+            Arg::with_name("no-prefix")
+                .long("no-prefix")
+                .short("P")
+                .takes_value(false)
+                .required(false)
+                .help("Don't prepend a prefix based on the repo id to the key"),
+        );
+
     let bonsai_fetch = SubCommand::with_name(CONTENT_FETCH)
         .about("fetches content of the file or manifest from blobrepo")
         .args_from_usage(
             "<CHANGESET_ID>    'revision to fetch file from'
-             <PATH>            'path to fetch'",
+             <PATH>            'path to fetch'
+             -o, --output=[FILE] 'write the file content to FILE in chunks instead of stdout'",
         );
 
     let content_fetch = SubCommand::with_name(BONSAI_FETCH)
@@ -125,7 +235,25 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .about("compare two changeset (used by pushrebase replayer)")
                 .args_from_usage(
                     "<LEFT_CS>  'left changeset id'
-                     <RIGHT_CS> 'right changeset id'",
+                     <RIGHT_CS> 'right changeset id'
+                     --summary  'print the numbers of changed files instead of their paths'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(HG_CHANGESET_FETCH)
+                .about("prints the metadata of a changeset")
+                .args_from_usage(
+                    "<HG_CS_OR_BOOKMARK> 'changeset id or bookmark to print'
+                     --json              'print json instead of text'
+                     --show-bonsai       'also print the id of the bonsai changeset'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name(HG_CHANGESET_INFO)
+                .about("prints the metadata of a batch of changesets as json")
+                .args_from_usage(
+                    "--batch-file=<FILE> 'file with one changeset id per line'
+                     --extra=[KEY]...    'extra to include in the output, if a changeset has it'",
                 ),
         )
         .subcommand(
@@ -133,7 +261,9 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .about("returns `x::y` revset")
                 .args_from_usage(
                     "<START_CS> 'start changeset id'
-                     <STOP_CS>  'stop changeset id'",
+                     <STOP_CS>  'stop changeset id'
+                     --first-parent 'only follow first parents from STOP_CS back to START_CS'
+                     --path=[PATH]  'only return changesets that change files under PATH'",
                 ),
         );
 
@@ -147,6 +277,7 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .version("0.0.0")
         .about("Poke at mononoke internals for debugging and investigating data structures.")
         .subcommand(blobstore_fetch)
+        .subcommand(blobstore_store)
         .subcommand(bonsai_fetch)
         .subcommand(content_fetch)
         .subcommand(config_repo::prepare_command(SubCommand::with_name(
@@ -155,28 +286,45 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .subcommand(bookmarks_manager::prepare_command(SubCommand::with_name(
             BOOKMARKS,
         )))
+        .subcommand(file_history::prepare_command(SubCommand::with_name(
+            FILE_HISTORY,
+        )))
+        .subcommand(filenodes_verify::prepare_command(SubCommand::with_name(
+            FILENODES,
+        )))
+        .subcommand(push_journal_manager::prepare_command(SubCommand::with_name(
+            PUSH_JOURNAL,
+        )))
+        .subcommand(storage_report::prepare_command(SubCommand::with_name(
+            STORAGE_REPORT,
+        )))
+        .subcommand(create_bundle_file::prepare_command(SubCommand::with_name(
+            CREATE_BUNDLE_FILE,
+        )))
+        .subcommand(dag_stats::prepare_command(SubCommand::with_name(
+            DAG_STATS,
+        )))
+        .subcommand(cross_repo_index_manager::prepare_command(
+            SubCommand::with_name(CROSS_REPO_INDEX),
+        ))
+        .subcommand(derived_data_manager::prepare_command(
+            SubCommand::with_name(DERIVED_DATA),
+        ))
+        .subcommand(storage_attribution::prepare_command(
+            SubCommand::with_name(STORAGE_ATTRIBUTION),
+        ))
+        .subcommand(create_commit::prepare_command(SubCommand::with_name(
+            CREATE_COMMIT,
+        )))
+        .subcommand(scratch_cleanup::prepare_command(SubCommand::with_name(
+            SCRATCH_CLEANUP,
+        )))
+        .subcommand(flags_manager::prepare_command(SubCommand::with_name(
+            FLAGS,
+        )))
         .subcommand(hg_changeset)
 }
 
-fn fetch_content_from_manifest(
-    logger: Logger,
-    mf: Box<Manifest + Sync>,
-    element: MPathElement,
-) -> BoxFuture<Content, Error> {
-    match mf.lookup(&element) {
-        Some(entry) => {
-            debug!(
-                logger,
-                "Fetched {:?}, hash: {:?}",
-                element,
-                entry.get_hash()
-            );
-            entry.get_content()
-        }
-        None => try_boxfuture!(Err(format_err!("failed to lookup element {:?}", element))),
-    }
-}
-
 fn resolve_hg_rev(repo: &BlobRepo, rev: &str) -> impl Future<Item = HgChangesetId, Error = Error> {
     let book = Bookmark::new(&rev).unwrap();
     let hash = HgChangesetId::from_str(rev);
@@ -195,40 +343,85 @@ fn fetch_content(
     rev: &str,
     path: &str,
 ) -> BoxFuture<Content, Error> {
-    let path = try_boxfuture!(MPath::new(path));
-    let resolved_cs_id = resolve_hg_rev(repo, rev);
+    let path = try_boxfuture!(path_lookup::parse_path(path));
 
-    let mf = resolved_cs_id
+    resolve_hg_rev(repo, rev)
         .and_then({
             cloned!(repo);
-            move |cs_id| repo.get_changeset_by_changesetid(&cs_id)
+            move |cs_id| path_lookup::lookup_path(logger, &repo, cs_id, path)
         })
-        .map(|cs| cs.manifestid().clone())
-        .and_then({
-            cloned!(repo);
-            move |root_mf_id| repo.get_manifest_by_nodeid(&root_mf_id)
-        });
+        .boxify()
+}
 
-    let all_but_last = iter_ok::<_, Error>(path.clone().into_iter().rev().skip(1).rev());
+fn write_content_to_file(
+    repo: &BlobRepo,
+    rev: &str,
+    path: &str,
+    output: &str,
+) -> BoxFuture<usize, Error> {
+    let path = try_boxfuture!(path_lookup::parse_path(path));
+    let mut file = try_boxfuture!(File::create(output));
 
-    let folded: BoxFuture<_, Error> = mf.and_then({
-        cloned!(logger);
-        move |mf| {
-            all_but_last.fold(mf, move |mf, element| {
-                fetch_content_from_manifest(logger.clone(), mf, element).and_then(|content| {
-                    match content {
-                        Content::Tree(mf) => Ok(mf),
-                        content => Err(format_err!("expected tree entry, found {:?}", content)),
-                    }
-                })
-            })
+    resolve_hg_rev(repo, rev)
+        .map({
+            cloned!(repo);
+            move |cs_id| repo.get_file_content_stream(&cs_id, &path)
+        })
+        .flatten_stream()
+        .fold(0, move |written, chunk| {
+            file.write_all(chunk.as_ref())
+                .map(|()| written + chunk.len())
+                .map_err(Error::from)
+        })
+        .boxify()
+}
+
+fn print_file_content(bytes: &[u8]) {
+    match str::from_utf8(bytes) {
+        Ok(content) => println!("{}", content),
+        Err(_) => {
+            println!("Binary file, {} bytes", bytes.len());
+            let preview_len = cmp::min(bytes.len(), HEXDUMP_PREVIEW_BYTES);
+            print!("{}", hexdump(&bytes[..preview_len]));
+            if preview_len < bytes.len() {
+                println!("... use --output to fetch the whole file");
+            }
         }
-    }).boxify();
+    }
+}
 
-    let basename = path.basename().clone();
-    folded
-        .and_then(move |mf| fetch_content_from_manifest(logger.clone(), mf, basename))
-        .boxify()
+/// Hexdump of the first `limit` bytes, followed by how many bytes were left out
+fn hexdump_prefix(bytes: &[u8], limit: usize) -> String {
+    let preview_len = cmp::min(bytes.len(), limit);
+    let mut res = hexdump(&bytes[..preview_len]);
+    if preview_len < bytes.len() {
+        res.push_str(&format!("... {} more bytes\n", bytes.len() - preview_len));
+    }
+    res
+}
+
+/// Format bytes the way `xxd` does: offset, 16 bytes in hex and their printable characters.
+fn hexdump(bytes: &[u8]) -> String {
+    let mut res = String::new();
+    for (line_num, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<_> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let printable: String = line.iter()
+            .map(|byte| {
+                if byte.is_ascii_graphic() || *byte == b' ' {
+                    *byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        res.push_str(&format!(
+            "{:08x}: {:<47}  {}\n",
+            line_num * 16,
+            hex.join(" "),
+            printable
+        ));
+    }
+    res
 }
 
 pub fn fetch_bonsai_changeset(
@@ -252,6 +445,59 @@ pub fn fetch_bonsai_changeset(
         })
 }
 
+/// The key that blobstore-fetch reads and blobstore-store writes: `key` in the repo of `prefix`,
+/// or `key` as it is if there is no prefix
+fn resolve_blobstore_key(prefix: Option<&RepoPrefix>, key: &str) -> String {
+    match prefix {
+        Some(prefix) => prefix.key(key),
+        None => key.to_string(),
+    }
+}
+
+/// Printed by blobstore-fetch, so that it's clear which blob the output is about
+fn describe_blobstore_key(bucket: &str, manifold_prefix: &str, resolved_key: &str) -> String {
+    format!(
+        "Fetching key {} from manifold bucket {}, manifold prefix {:?}",
+        resolved_key, bucket, manifold_prefix
+    )
+}
+
+/// Puts `value` at `key` for blobstore-store and returns the number of bytes written. Existing
+/// blobs are only overwritten if `force` is set.
+fn store_blob(
+    blobstore: Arc<Blobstore>,
+    key: String,
+    value: Bytes,
+    force: bool,
+) -> BoxFuture<usize, Error> {
+    let check = if force {
+        future::ok(()).left_future()
+    } else {
+        blobstore
+            .is_present(key.clone())
+            .and_then({
+                cloned!(key);
+                move |present| {
+                    if present {
+                        Err(format_err!(
+                            "key {} already exists, pass --force to overwrite it",
+                            key
+                        ))
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .right_future()
+    };
+
+    let len = value.len();
+    check
+        .and_then(move |()| blobstore.put(key, BlobstoreBytes::from_bytes(value)))
+        .map(move |()| len)
+        .boxify()
+}
+
 fn get_cache<B: CacheBlobstoreExt>(
     blobstore: &B,
     key: String,
@@ -266,77 +512,193 @@ fn get_cache<B: CacheBlobstoreExt>(
     }
 }
 
+/// Keys of JSON objects must be strings, so extra keys are rendered like in logs. Everything else
+/// is lossless: paths are escaped, see `EscapedPath`.
 #[derive(Serialize)]
-struct ChangesetDiff {
-    left: HgChangesetId,
-    right: HgChangesetId,
-    diff: Vec<ChangesetAttrDiff>,
+enum ChangesetAttrDiff {
+    #[serde(rename = "user")] User(MaybeUtf8Bytes, MaybeUtf8Bytes),
+    #[serde(rename = "comments")] Comments(MaybeUtf8Bytes, MaybeUtf8Bytes),
+    #[serde(rename = "manifest_summary")] ManifestSummary(ManifestDiffSummary),
+    #[serde(rename = "files")] Files(Vec<EscapedPath>, Vec<EscapedPath>),
+    /// Numbers of files, for `--summary`
+    #[serde(rename = "files_summary")] FilesSummary(usize, usize),
+    #[serde(rename = "extra")]
+    Extra(
+        BTreeMap<String, MaybeUtf8Bytes>,
+        BTreeMap<String, MaybeUtf8Bytes>,
+    ),
 }
 
-#[derive(Serialize)]
-enum ChangesetAttrDiff {
-    #[serde(rename = "user")] User(String, String),
-    #[serde(rename = "comments")] Comments(String, String),
-    #[serde(rename = "manifest")] Manifest(ManifestDiff),
-    #[serde(rename = "files")] Files(Vec<String>, Vec<String>),
-    #[serde(rename = "extra")] Extra(BTreeMap<String, String>, BTreeMap<String, String>),
+fn mpath_escaped<P: Borrow<MPath>>(mpath: P) -> EscapedPath {
+    mpath.borrow().to_escaped()
+}
+
+fn extra_diff(extra: &BTreeMap<Vec<u8>, Vec<u8>>) -> BTreeMap<String, MaybeUtf8Bytes> {
+    extra
+        .iter()
+        .map(|(key, value)| {
+            (
+                MaybeUtf8Bytes::from(key.as_slice()).to_string(),
+                MaybeUtf8Bytes::from(value.as_slice()),
+            )
+        })
+        .collect()
 }
 
+/// Writes the manifest entry of the diff, if the manifests differ. The diff of a mega-commit can
+/// have millions of paths, so they are streamed, or only counted with `summary`.
+fn hg_manifest_diff<W: Write + Send + 'static>(
+    repo: BlobRepo,
+    left: HgManifestId,
+    right: HgManifestId,
+    summary: bool,
+    needs_comma: bool,
+    writer: W,
+) -> BoxFuture<W, Error> {
+    let diffs = move || {
+        bonsai_diff(
+            repo.get_root_entry(&left),
+            Some(repo.get_root_entry(&right)),
+            None,
+        )
+    };
+    if summary {
+        summarize_manifest_diff(diffs())
+            .and_then(move |summary| {
+                let mut writer = writer;
+                if !summary.is_empty() {
+                    if needs_comma {
+                        writer.write_all(b",")?;
+                    }
+                    serde_json::to_writer(
+                        &mut writer,
+                        &ChangesetAttrDiff::ManifestSummary(summary),
+                    )?;
+                }
+                Ok(writer)
+            })
+            .boxify()
+    } else {
+        write_manifest_diff(diffs, writer, needs_comma)
+    }
+}
+
+/// A changeset as `hg-changeset fetch` prints it. Like in `ChangesetAttrDiff`, the user, comments,
+/// extra and paths are lossless in json.
 #[derive(Serialize)]
-struct ManifestDiff {
-    modified: Vec<String>,
-    deleted: Vec<String>,
+struct HgChangesetDescription {
+    id: HgChangesetId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bonsai: Option<ChangesetId>,
+    manifest: HgNodeHash,
+    p1: Option<HgNodeHash>,
+    p2: Option<HgNodeHash>,
+    user: MaybeUtf8Bytes,
+    date: String,
+    extra: BTreeMap<String, MaybeUtf8Bytes>,
+    files: Vec<EscapedPath>,
+    comments: MaybeUtf8Bytes,
 }
 
-fn mpath_to_str<P: Borrow<MPath>>(mpath: P) -> String {
-    let bytes = mpath.borrow().to_vec();
-    String::from_utf8_lossy(bytes.as_ref()).into_owned()
+impl HgChangesetDescription {
+    fn new<C: Changeset>(id: HgChangesetId, changeset: &C, bonsai: Option<ChangesetId>) -> Self {
+        let (p1, p2) = changeset.parents().get_nodes();
+        HgChangesetDescription {
+            id,
+            bonsai,
+            manifest: changeset.manifestid().into_nodehash(),
+            p1: p1.cloned(),
+            p2: p2.cloned(),
+            user: MaybeUtf8Bytes::from(changeset.user()),
+            date: changeset.time().as_chrono().to_rfc3339(),
+            extra: extra_diff(changeset.extra()),
+            files: changeset.files().iter().map(mpath_escaped).collect(),
+            comments: MaybeUtf8Bytes::from(changeset.comments()),
+        }
+    }
+
+    /// Human-readable form, lossy for non-utf8 data
+    fn to_text(&self) -> String {
+        let mut res = format!("changeset: {}\n", self.id);
+        if let Some(bonsai) = self.bonsai {
+            res.push_str(&format!("bonsai: {}\n", bonsai));
+        }
+        res.push_str(&format!("manifest: {}\n", self.manifest));
+        for parent in self.p1.iter().chain(self.p2.iter()) {
+            res.push_str(&format!("parent: {}\n", parent));
+        }
+        res.push_str(&format!("user: {}\n", self.user));
+        res.push_str(&format!("date: {}\n", self.date));
+        for (key, value) in &self.extra {
+            res.push_str(&format!("extra: {}={}\n", key, value));
+        }
+        res.push_str(&format!("files: {}\n", self.files.len()));
+        for file in &self.files {
+            res.push_str(&format!("  {}\n", file));
+        }
+        res.push_str("\n");
+        res.push_str(&format!("{}\n", self.comments));
+        res
+    }
 }
 
-fn slice_to_str(slice: &[u8]) -> String {
-    String::from_utf8_lossy(slice).into_owned()
+/// The changeset `rev`, a changeset id or a bookmark, for `hg-changeset fetch`
+fn hg_changeset_fetch(
+    repo: BlobRepo,
+    rev: &str,
+    show_bonsai: bool,
+) -> impl Future<Item = HgChangesetDescription, Error = Error> {
+    resolve_hg_rev(&repo, rev).and_then(move |id| {
+        let bonsai = if show_bonsai {
+            repo.get_bonsai_from_hg(&id).left_future()
+        } else {
+            future::ok(None).right_future()
+        };
+        repo.get_changeset_by_changesetid(&id)
+            .join(bonsai)
+            .map(move |(changeset, bonsai)| HgChangesetDescription::new(id, &changeset, bonsai))
+    })
 }
 
-fn hg_manifest_diff(
+/// Metadata of the changesets `ids`, one json object per id. Changesets that are not in the repo
+/// are marked as not found instead of failing the whole batch.
+fn hg_changeset_info(
     repo: BlobRepo,
-    left: &HgManifestId,
-    right: &HgManifestId,
-) -> impl Future<Item = Option<ChangesetAttrDiff>, Error = Error> {
-    bonsai_diff(
-        repo.get_root_entry(left),
-        Some(repo.get_root_entry(right)),
-        None,
-    ).collect()
-        .map(|diffs| {
-            let diff = diffs.into_iter().fold(
-                ManifestDiff {
-                    modified: Vec::new(),
-                    deleted: Vec::new(),
-                },
-                |mut mdiff, diff| {
-                    match diff {
-                        BonsaiDiffResult::Changed(path, ..)
-                        | BonsaiDiffResult::ChangedReusedId(path, ..) => {
-                            mdiff.modified.push(mpath_to_str(path))
-                        }
-                        BonsaiDiffResult::Deleted(path) => mdiff.deleted.push(mpath_to_str(path)),
-                    };
-                    mdiff
-                },
-            );
-            if diff.modified.is_empty() && diff.deleted.is_empty() {
-                None
-            } else {
-                Some(ChangesetAttrDiff::Manifest(diff))
-            }
-        })
+    ids: Vec<HgChangesetId>,
+    extras: HashSet<String>,
+) -> impl Future<Item = Vec<serde_json::Value>, Error = Error> {
+    mononoke_api::get_changesets_metadata(Arc::new(repo), ids, extras).map(|metadata| {
+        metadata
+            .into_iter()
+            .map(|(id, metadata)| match metadata {
+                Some(metadata) => json!({
+                    "id": id.to_hex().to_string(),
+                    "author": metadata.author,
+                    "date": metadata.date.as_chrono().to_rfc3339(),
+                    "extras": metadata.extras,
+                    "summary": metadata.summary,
+                    "p1": metadata.p1.map(|p1| p1.to_hex().to_string()),
+                    "p2": metadata.p2.map(|p2| p2.to_hex().to_string()),
+                    "changed_files_count": metadata.changed_files_count,
+                }),
+                None => json!({
+                    "id": id.to_hex().to_string(),
+                    "not_found": true,
+                }),
+            })
+            .collect()
+    })
 }
 
-fn hg_changeset_diff(
+/// Writes `{"left": .., "right": .., "diff": [..]}` to `writer`. The manifest entry of the diff is
+/// written as it is computed, see `hg_manifest_diff`.
+fn hg_changeset_diff<W: Write + Send + 'static>(
     repo: BlobRepo,
     left_id: &HgChangesetId,
     right_id: &HgChangesetId,
-) -> impl Future<Item = ChangesetDiff, Error = Error> {
+    summary: bool,
+    writer: W,
+) -> impl Future<Item = W, Error = Error> {
     (
         repo.get_changeset_by_changesetid(left_id),
         repo.get_changeset_by_changesetid(right_id),
@@ -344,50 +706,68 @@ fn hg_changeset_diff(
         .and_then({
             cloned!(repo, left_id, right_id);
             move |(left, right)| {
-                let mut diff = ChangesetDiff {
-                    left: left_id,
-                    right: right_id,
-                    diff: Vec::new(),
-                };
+                let mut diff = Vec::new();
 
                 if left.user() != right.user() {
-                    diff.diff.push(ChangesetAttrDiff::User(
-                        slice_to_str(left.user()),
-                        slice_to_str(right.user()),
+                    diff.push(ChangesetAttrDiff::User(
+                        MaybeUtf8Bytes::from(left.user()),
+                        MaybeUtf8Bytes::from(right.user()),
                     ));
                 }
 
                 if left.comments() != right.comments() {
-                    diff.diff.push(ChangesetAttrDiff::Comments(
-                        slice_to_str(left.comments()),
-                        slice_to_str(right.comments()),
+                    diff.push(ChangesetAttrDiff::Comments(
+                        MaybeUtf8Bytes::from(left.comments()),
+                        MaybeUtf8Bytes::from(right.comments()),
                     ))
                 }
 
                 if left.files() != right.files() {
-                    diff.diff.push(ChangesetAttrDiff::Files(
-                        left.files().iter().map(mpath_to_str).collect(),
-                        right.files().iter().map(mpath_to_str).collect(),
-                    ))
+                    if summary {
+                        diff.push(ChangesetAttrDiff::FilesSummary(
+                            left.files().len(),
+                            right.files().len(),
+                        ))
+                    } else {
+                        diff.push(ChangesetAttrDiff::Files(
+                            left.files().iter().map(mpath_escaped).collect(),
+                            right.files().iter().map(mpath_escaped).collect(),
+                        ))
+                    }
                 }
 
                 if left.extra() != right.extra() {
-                    diff.diff.push(ChangesetAttrDiff::Extra(
-                        left.extra()
-                            .iter()
-                            .map(|(k, v)| (slice_to_str(k), slice_to_str(v)))
-                            .collect(),
-                        right
-                            .extra()
-                            .iter()
-                            .map(|(k, v)| (slice_to_str(k), slice_to_str(v)))
-                            .collect(),
+                    diff.push(ChangesetAttrDiff::Extra(
+                        extra_diff(left.extra()),
+                        extra_diff(right.extra()),
                     ))
                 }
 
-                hg_manifest_diff(repo, left.manifestid(), right.manifestid()).map(move |mdiff| {
-                    diff.diff.extend(mdiff);
-                    diff
+                let mut writer = writer;
+                try_boxfuture!(write!(
+                    writer,
+                    r#"{{"left":{},"right":{},"diff":["#,
+                    try_boxfuture!(serde_json::to_string(&left_id)),
+                    try_boxfuture!(serde_json::to_string(&right_id)),
+                ));
+                for (i, attr) in diff.iter().enumerate() {
+                    if i > 0 {
+                        try_boxfuture!(writer.write_all(b","));
+                    }
+                    try_boxfuture!(serde_json::to_writer(&mut writer, attr));
+                }
+
+                hg_manifest_diff(
+                    repo,
+                    *left.manifestid(),
+                    *right.manifestid(),
+                    summary,
+                    !diff.is_empty(),
+                    writer,
+                ).and_then(|mut writer| {
+                    writer.write_all(b"]}")?;
+                    writer.flush()?;
+                    Ok(writer)
                 })
             }
         })
@@ -399,65 +779,102 @@ fn main() -> Result<()> {
     let logger = args::get_logger(&matches);
     let manifold_args = args::parse_manifold_args(&matches);
 
-    let repo_id = args::get_repo_id(&matches);
-
     let future = match matches.subcommand() {
         (BLOBSTORE_FETCH, Some(sub_m)) => {
             let key = sub_m.value_of("KEY").unwrap().to_string();
             let decode_as = sub_m.value_of("decode-as").map(|val| val.to_string());
             let use_memcache = sub_m.value_of("use-memcache").map(|val| val.to_string());
-            let no_prefix = sub_m.is_present("no-prefix");
+            let hex_bytes = match sub_m.value_of("hex-bytes") {
+                Some(hex_bytes) => hex_bytes.parse::<usize>()?,
+                None => HEXDUMP_PREVIEW_BYTES,
+            };
+            let strict = sub_m.is_present("strict");
+            let prefix = if sub_m.is_present("no-prefix") {
+                None
+            } else {
+                Some(args::get_repo_id(&matches)?.prefix())
+            };
+
+            let resolved_key = resolve_blobstore_key(prefix.as_ref(), &key);
+            println!(
+                "{}",
+                describe_blobstore_key(&manifold_args.bucket, &manifold_args.prefix, &resolved_key)
+            );
 
             let blobstore =
                 ManifoldBlob::new_with_prefix(&manifold_args.bucket, &manifold_args.prefix);
 
-            match (use_memcache, no_prefix) {
-                (None, false) => {
-                    let blobstore = PrefixBlobstore::new(blobstore, repo_id.prefix());
-                    blobstore.get(key.clone()).boxify()
-                }
-                (None, true) => blobstore.get(key.clone()).boxify(),
-                (Some(mode), false) => {
-                    let blobstore = new_memcache_blobstore(
-                        blobstore,
-                        "manifold",
-                        manifold_args.bucket.as_ref(),
-                    ).unwrap();
-                    let blobstore = PrefixBlobstore::new(blobstore, repo_id.prefix());
-                    get_cache(&blobstore, key.clone(), mode)
-                }
-                (Some(mode), true) => {
+            match use_memcache {
+                None => blobstore.get(resolved_key).boxify(),
+                Some(mode) => {
                     let blobstore = new_memcache_blobstore(
                         blobstore,
                         "manifold",
                         manifold_args.bucket.as_ref(),
                     ).unwrap();
-                    get_cache(&blobstore, key.clone(), mode)
+                    get_cache(&blobstore, resolved_key, mode)
                 }
             }.map(move |value| {
                 println!("{:?}", value);
-                if let Some(value) = value {
-                    let decode_as = decode_as.as_ref().and_then(|val| {
-                        let val = val.as_str();
-                        if val == "auto" {
-                            detect_decode(&key, &logger)
-                        } else {
-                            Some(val)
+                let value = match value {
+                    Some(value) => value,
+                    None => return,
+                };
+                let decoder = match decode_as.as_ref().map(|val| val.as_str()) {
+                    None => return,
+                    Some("auto") => detect_decode(&key, &logger),
+                    Some(val) => Some(val),
+                };
+
+                let decoded = match decoder {
+                    Some(decoder) => decode_blob(decoder, value.clone(), hex_bytes),
+                    None => Err(err_msg("unable to detect how to decode this blob from its key")),
+                };
+                match decoded {
+                    Ok(decoded) => print!("{}", decoded),
+                    Err(err) => {
+                        print!(
+                            "{}",
+                            describe_decode_failure(&key, &err, value.as_bytes(), hex_bytes)
+                        );
+                        if strict {
+                            ::std::process::exit(1);
                         }
-                    });
-
-                    match decode_as {
-                        Some("changeset") => display(&HgChangesetEnvelope::from_blob(value.into())),
-                        Some("manifest") => display(&HgManifestEnvelope::from_blob(value.into())),
-                        Some("file") => display(&HgFileEnvelope::from_blob(value.into())),
-                        // TODO: (rain1) T30974137 add a better way to print out file contents
-                        Some("contents") => println!("{:?}", FileContents::from_blob(value.into())),
-                        _ => (),
                     }
                 }
             })
                 .boxify()
         }
+        (BLOBSTORE_STORE, Some(sub_m)) => {
+            let key = sub_m.value_of("KEY").unwrap().to_string();
+            let force = sub_m.is_present("force");
+            let prefix = if sub_m.is_present("no-prefix") {
+                None
+            } else {
+                Some(args::get_repo_id(&matches)?.prefix())
+            };
+            let value = match sub_m.value_of("file") {
+                Some(path) => fs::read(path)?,
+                None => {
+                    let mut value = Vec::new();
+                    io::stdin().read_to_end(&mut value)?;
+                    value
+                }
+            };
+
+            let resolved_key = resolve_blobstore_key(prefix.as_ref(), &key);
+            let blobstore =
+                ManifoldBlob::new_with_prefix(&manifold_args.bucket, &manifold_args.prefix);
+
+            store_blob(Arc::new(blobstore), resolved_key.clone(), value.into(), force)
+                .map(move |len| {
+                    println!(
+                        "Wrote {} bytes to key {} in manifold bucket {}, manifold prefix {:?}",
+                        len, resolved_key, manifold_args.bucket, manifold_args.prefix
+                    );
+                })
+                .boxify()
+        }
         (BONSAI_FETCH, Some(sub_m)) => {
             let rev = sub_m.value_of("HG_CHANGESET_OR_BOOKMARK").unwrap();
 
@@ -477,55 +894,117 @@ fn main() -> Result<()> {
             args::init_cachelib(&matches);
 
             let repo = args::open_repo(&logger, &matches)?;
-            fetch_content(logger.clone(), repo.blobrepo(), rev, path)
-                .and_then(|content| {
-                    match content {
-                        Content::Executable(_) => {
-                            println!("Binary file");
-                        }
-                        Content::File(contents) | Content::Symlink(contents) => match contents {
-                            FileContents::Bytes(bytes) => {
-                                let content = String::from_utf8(bytes.to_vec())
-                                    .expect("non-utf8 file content");
-                                println!("{}", content);
-                            }
-                        },
-                        Content::Tree(mf) => {
-                            let entries: Vec<_> = mf.list().collect();
-                            let mut longest_len = 0;
-                            for entry in entries.iter() {
-                                let basename_len =
-                                    entry.get_name().map(|basename| basename.len()).unwrap_or(0);
-                                if basename_len > longest_len {
-                                    longest_len = basename_len;
-                                }
-                            }
-                            for entry in entries {
-                                let mut basename = String::from_utf8_lossy(
-                                    entry.get_name().expect("empty basename found").as_bytes(),
-                                ).to_string();
-                                for _ in basename.len()..longest_len {
-                                    basename.push(' ');
+            match sub_m.value_of("output") {
+                Some(output) => {
+                    let output = output.to_string();
+                    write_content_to_file(repo.blobrepo(), rev, path, &output)
+                        .map(move |written| {
+                            println!("Wrote {} bytes to {}", written, output);
+                        })
+                        .boxify()
+                }
+                None => fetch_content(logger.clone(), repo.blobrepo(), rev, path)
+                    .and_then(|content| {
+                        match content {
+                            Content::File(contents)
+                            | Content::Executable(contents)
+                            | Content::Symlink(contents) => match contents {
+                                FileContents::Bytes(bytes) => print_file_content(bytes.as_ref()),
+                            },
+                            Content::Tree(mf) => {
+                                let entries: Vec<_> = mf.list()
+                                    .map(|entry| {
+                                        let basename = entry
+                                            .get_name()
+                                            .expect("empty basename found")
+                                            .to_escaped()
+                                            .to_string();
+                                        (basename, entry)
+                                    })
+                                    .collect();
+                                let longest_len = entries
+                                    .iter()
+                                    .map(|(basename, _)| basename.chars().count())
+                                    .max()
+                                    .unwrap_or(0);
+                                for (basename, entry) in entries {
+                                    println!(
+                                        "{:width$} {} {:?}",
+                                        basename,
+                                        entry.get_hash(),
+                                        entry.get_type(),
+                                        width = longest_len
+                                    );
                                 }
-                                println!(
-                                    "{} {} {:?}",
-                                    basename,
-                                    entry.get_hash(),
-                                    entry.get_type()
-                                );
                             }
                         }
-                    }
-                    future::ok(()).boxify()
-                })
-                .boxify()
+                        future::ok(()).boxify()
+                    })
+                    .boxify(),
+            }
         }
         (CONFIG_REPO, Some(sub_m)) => config_repo::handle_command(sub_m, logger),
         (BOOKMARKS, Some(sub_m)) => {
             args::init_cachelib(&matches);
             let repo = args::open_repo(&logger, &matches)?;
+            let snapshot_index = args::open_bookmark_snapshot_index(&logger, &matches)?;
 
-            bookmarks_manager::handle_command(&repo.blobrepo(), sub_m, logger)
+            bookmarks_manager::handle_command(&repo.blobrepo(), snapshot_index, sub_m, logger)
+        }
+        (FILE_HISTORY, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            let repo = args::open_repo(&logger, &matches)?;
+
+            file_history::handle_command(&repo.blobrepo(), sub_m, logger)
+        }
+        (FILENODES, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            let repo = args::open_repo(&logger, &matches)?;
+
+            filenodes_verify::handle_command(&repo.blobrepo(), sub_m, logger)
+        }
+        (PUSH_JOURNAL, Some(sub_m)) => {
+            let journal = args::open_push_journal(&logger, &matches)?;
+            let repo_id = args::get_repo_id(&matches)?;
+
+            push_journal_manager::handle_command(journal, repo_id, sub_m, logger)
+        }
+        (STORAGE_REPORT, Some(sub_m)) => {
+            let repo_id = args::get_repo_id(&matches)?;
+
+            storage_report::handle_command(&matches, sub_m, repo_id, logger)
+        }
+        (CREATE_BUNDLE_FILE, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            let repo = args::open_repo(&logger, &matches)?;
+
+            create_bundle_file::handle_command(&repo.blobrepo(), sub_m, logger)
+        }
+        (DAG_STATS, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            let repo = args::open_repo(&logger, &matches)?;
+
+            dag_stats::handle_command(&repo.blobrepo(), sub_m, logger)
+        }
+        (CROSS_REPO_INDEX, Some(sub_m)) => {
+            cross_repo_index_manager::handle_command(&matches, sub_m, logger)
+        }
+        (DERIVED_DATA, Some(sub_m)) => {
+            derived_data_manager::handle_command(&matches, sub_m, logger)
+        }
+        (STORAGE_ATTRIBUTION, Some(sub_m)) => {
+            args::init_cachelib(&matches);
+            let repo = args::open_repo(&logger, &matches)?;
+
+            storage_attribution::handle_command(&repo.blobrepo(), sub_m, logger)
+        }
+        (CREATE_COMMIT, Some(sub_m)) => create_commit::handle_command(&matches, sub_m, logger),
+        (SCRATCH_CLEANUP, Some(sub_m)) => scratch_cleanup::handle_command(&matches, sub_m, logger),
+        (FLAGS, Some(sub_m)) => {
+            let overrides = args::open_flag_overrides(&logger, &matches)?;
+            let repo_id = args::get_repo_id(&matches)?;
+
+            flags_manager::handle_command(overrides, repo_id, sub_m, logger)
         }
         (HG_CHANGESET, Some(sub_m)) => match sub_m.subcommand() {
             (HG_CHANGESET_DIFF, Some(sub_m)) => {
@@ -538,16 +1017,59 @@ fn main() -> Result<()> {
                     .ok_or(format_err!("RIGHT_CS argument expected"))
                     .and_then(HgChangesetId::from_str);
 
+                let summary = sub_m.is_present("summary");
+
                 args::init_cachelib(&matches);
                 let repo = args::open_repo(&logger, &matches)?.blobrepo().clone();
 
                 (left_cs, right_cs)
                     .into_future()
                     .and_then(move |(left_cs, right_cs)| {
-                        hg_changeset_diff(repo, &left_cs, &right_cs)
+                        let writer = io::BufWriter::new(io::stdout());
+                        hg_changeset_diff(repo, &left_cs, &right_cs, summary, writer)
+                    })
+                    .map(|_| ())
+                    .boxify()
+            }
+            (HG_CHANGESET_FETCH, Some(sub_m)) => {
+                let rev = sub_m.value_of("HG_CS_OR_BOOKMARK").unwrap();
+                let json = sub_m.is_present("json");
+                let show_bonsai = sub_m.is_present("show-bonsai");
+
+                args::init_cachelib(&matches);
+                let repo = args::open_repo(&logger, &matches)?.blobrepo().clone();
+
+                hg_changeset_fetch(repo, rev, show_bonsai)
+                    .and_then(move |description| -> Result<()> {
+                        if json {
+                            serde_json::to_writer(io::stdout(), &description)?;
+                            println!();
+                        } else {
+                            print!("{}", description.to_text());
+                        }
+                        Ok(())
                     })
-                    .and_then(|diff| {
-                        serde_json::to_writer(io::stdout(), &diff)
+                    .boxify()
+            }
+            (HG_CHANGESET_INFO, Some(sub_m)) => {
+                let batch_file = sub_m.value_of("batch-file").unwrap();
+                let ids = fs::read_to_string(batch_file)?
+                    .lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty())
+                    .map(HgChangesetId::from_str)
+                    .collect::<Result<Vec<_>>>()?;
+                let extras = sub_m
+                    .values_of("extra")
+                    .map(|keys| keys.map(|key| key.to_string()).collect())
+                    .unwrap_or_default();
+
+                args::init_cachelib(&matches);
+                let repo = args::open_repo(&logger, &matches)?.blobrepo().clone();
+
+                hg_changeset_info(repo, ids, extras)
+                    .and_then(|info| {
+                        serde_json::to_writer(io::stdout(), &info)
                             .map(|_| ())
                             .map_err(Error::from)
                     })
@@ -562,6 +1084,11 @@ fn main() -> Result<()> {
                     .value_of("STOP_CS")
                     .ok_or(format_err!("STOP_CS argument expected"))
                     .and_then(HgChangesetId::from_str);
+                let first_parent = sub_m.is_present("first-parent");
+                let path = match sub_m.value_of("path") {
+                    Some(path) => Some(MPath::new(path)?),
+                    None => None,
+                };
 
                 args::init_cachelib(&matches);
                 let repo = args::open_repo(&logger, &matches)?.blobrepo().clone();
@@ -586,7 +1113,17 @@ fn main() -> Result<()> {
                     .and_then({
                         cloned!(repo);
                         move |(start_cs, stop_cs)| {
-                            RangeNodeStream::new(&Arc::new(repo.clone()), start_cs, stop_cs)
+                            let blobrepo = Arc::new(repo.clone());
+                            let range = if first_parent {
+                                first_parent_range(&blobrepo, start_cs, stop_cs)
+                            } else {
+                                RangeNodeStream::new(&blobrepo, start_cs, stop_cs).boxify()
+                            };
+                            let range = match path {
+                                Some(path) => filter_by_path(&blobrepo, range, path, 100),
+                                None => range,
+                            };
+                            range
                                 .map(move |cs| repo.get_hg_from_bonsai_changeset(cs))
                                 .buffered(100)
                                 .map(|cs| cs.to_hex().to_string())
@@ -627,34 +1164,268 @@ fn main() -> Result<()> {
 
 fn detect_decode(key: &str, logger: &Logger) -> Option<&'static str> {
     // Use a simple heuristic to figure out how to decode this key.
-    if key.find("hgchangeset.").is_some() {
-        info!(logger, "Detected changeset key");
-        Some("changeset")
-    } else if key.find("hgmanifest.").is_some() {
-        info!(logger, "Detected manifest key");
-        Some("manifest")
-    } else if key.find("hgfilenode.").is_some() {
-        info!(logger, "Detected file key");
-        Some("file")
-    } else if key.find("content.").is_some() {
-        info!(logger, "Detected content key");
-        Some("contents")
-    } else {
-        warn!(
-            logger,
-            "Unable to detect how to decode this blob based on key";
-            "key" => key,
-        );
-        None
+    let family = KeyFamily::of_key(key);
+    match family.decoder() {
+        Some(decoder) => {
+            info!(logger, "Detected {} key", family.name());
+            Some(decoder)
+        }
+        None => {
+            warn!(
+                logger,
+                "Unable to detect how to decode this blob based on key";
+                "key" => key,
+            );
+            None
+        }
     }
 }
 
-fn display<T>(res: &Result<T>)
-where
-    T: fmt::Display + fmt::Debug,
-{
-    match res {
-        Ok(val) => println!("---\n{}---", val),
-        err => println!("{:?}", err),
+/// Decodes a blob as `decode_as`, one of the values of `blobstore-fetch --decode-as` other than
+/// "auto". "raw-hex" is a hexdump of the first `hex_bytes` bytes.
+fn decode_blob(decode_as: &str, value: BlobstoreBytes, hex_bytes: usize) -> Result<String> {
+    match decode_as {
+        "changeset" => HgChangesetEnvelope::from_blob(value.into()).map(|cs| display(&cs)),
+        "manifest" => HgManifestEnvelope::from_blob(value.into()).map(|mf| display(&mf)),
+        "file" => HgFileEnvelope::from_blob(value.into()).map(|file| display(&file)),
+        // TODO: (rain1) T30974137 add a better way to print out file contents
+        "contents" => FileContents::from_blob(value.into()).map(|fc| format!("{:?}\n", fc)),
+        "raw-hex" => Ok(hexdump_prefix(value.as_bytes(), hex_bytes)),
+        _ => bail_msg!("unknown decoding {}", decode_as),
+    }
+}
+
+fn display<T: fmt::Display>(val: &T) -> String {
+    format!("---\n{}---\n", val)
+}
+
+/// What is known about a blob that failed to decode, to tell a blob stored under the wrong key
+/// from a corrupt one
+fn describe_decode_failure(key: &str, err: &Error, bytes: &[u8], hex_bytes: usize) -> String {
+    let causes: Vec<_> = err.iter_chain().map(|cause| cause.to_string()).collect();
+    let mut res = format!("failed to decode blob: {}\n", causes.join(": "));
+
+    let family = KeyFamily::of_key(key);
+    match family.decoder() {
+        Some(decoder) => res.push_str(&format!(
+            "key family: {} (decodes as {})\n",
+            family.name(),
+            decoder
+        )),
+        None => res.push_str(&format!("key family: {}\n", family.name())),
+    }
+    res.push_str(&format!("blob length: {} bytes\n", bytes.len()));
+    res.push_str(&hexdump_prefix(bytes, hex_bytes));
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use blobstore::LazyMemblob;
+    use mercurial_types::{HgChangesetEnvelopeMut, NULL_HASH};
+    use tests_utils::{create_commit, store_files};
+
+    #[test]
+    fn test_hexdump() {
+        let bytes: Vec<u8> = b"hello\x00world\xff\x01 binary!".to_vec();
+        assert_eq!(
+            hexdump(&bytes),
+            "00000000: 68 65 6c 6c 6f 00 77 6f 72 6c 64 ff 01 20 62 69  hello.world.. bi\n\
+             00000010: 6e 61 72 79 21                                   nary!\n"
+        );
+        assert_eq!(hexdump(&[]), "");
+    }
+
+    #[test]
+    fn test_decode_mis_keyed_blob() {
+        let changeset = HgChangesetEnvelopeMut {
+            node_id: NULL_HASH,
+            p1: None,
+            p2: None,
+            contents: Bytes::from(&b"changeset contents"[..]),
+        }.freeze();
+        let value: BlobstoreBytes = changeset.into_blob().into();
+        let key = "repo0000.hgmanifest.sha1.aa";
+
+        let decoder = KeyFamily::of_key(key).decoder().unwrap();
+        assert_eq!(decoder, "manifest");
+        let err = decode_blob(decoder, value.clone(), HEXDUMP_PREVIEW_BYTES)
+            .expect_err("unexpected OK -- changeset decoded as a manifest");
+        assert!(decode_blob("changeset", value.clone(), HEXDUMP_PREVIEW_BYTES).is_ok());
+
+        let report = describe_decode_failure(key, &err, value.as_bytes(), 16);
+        let lines: Vec<_> = report.lines().collect();
+        assert!(lines[0].starts_with("failed to decode blob: "));
+        assert_eq!(lines[1], "key family: hg_manifests (decodes as manifest)");
+        assert_eq!(lines[2], format!("blob length: {} bytes", value.len()));
+        assert_eq!(lines[3], hexdump(&value.as_bytes()[..16]).trim_right_matches('\n'));
+        assert_eq!(lines[4], format!("... {} more bytes", value.len() - 16));
+        assert_eq!(lines.len(), 5);
+    }
+
+    #[test]
+    fn test_decode_raw_hex() {
+        let value = BlobstoreBytes::from_bytes(&b"0123456789abcdef\x00\x01"[..]);
+        assert_eq!(
+            decode_blob("raw-hex", value.clone(), 16).unwrap(),
+            "00000000: 30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  0123456789abcdef\n\
+             ... 2 more bytes\n"
+        );
+        assert_eq!(
+            decode_blob("raw-hex", value, HEXDUMP_PREVIEW_BYTES).unwrap(),
+            "00000000: 30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  0123456789abcdef\n\
+             00000010: 00 01                                            ..\n"
+        );
+    }
+
+    #[test]
+    fn test_blobstore_fetch_key() {
+        let prefix = RepoPrefix::new(1);
+        let key = "hgchangeset.sha1.aa";
+        assert_eq!(
+            resolve_blobstore_key(Some(&prefix), key),
+            "repo0001.hgchangeset.sha1.aa"
+        );
+        assert_eq!(resolve_blobstore_key(None, key), key);
+
+        assert_eq!(
+            describe_blobstore_key("bucket", "", "repo0001.hgchangeset.sha1.aa"),
+            "Fetching key repo0001.hgchangeset.sha1.aa from manifold bucket bucket, \
+             manifold prefix \"\""
+        );
+    }
+
+    #[test]
+    fn test_blobstore_store() {
+        let blobstore: Arc<Blobstore> = Arc::new(LazyMemblob::new());
+        let key = "repo0001.hgchangeset.sha1.aa".to_string();
+        let store = |value: &'static [u8], force| {
+            store_blob(blobstore.clone(), key.clone(), Bytes::from(value), force).wait()
+        };
+        let get = || {
+            let value = blobstore.get(key.clone()).wait().unwrap();
+            value.map(|value| value.into_bytes())
+        };
+
+        assert_eq!(store(b"corrupted", false).unwrap(), 9);
+        assert_eq!(get(), Some(Bytes::from(&b"corrupted"[..])));
+
+        // Existing blobs are only overwritten with --force
+        assert!(store(b"repaired", false).is_err());
+        assert_eq!(get(), Some(Bytes::from(&b"corrupted"[..])));
+        assert_eq!(store(b"repaired", true).unwrap(), 8);
+        assert_eq!(get(), Some(Bytes::from(&b"repaired"[..])));
+    }
+
+    #[test]
+    fn test_hg_changeset_fetch() {
+        async_unit::tokio_unit_test(|| {
+            let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+            let c1 = create_commit(
+                repo.clone(),
+                vec![],
+                store_files(btreemap!{"a" => Some("1")}, repo.clone()),
+            );
+            let c2 = create_commit(
+                repo.clone(),
+                vec![c1],
+                store_files(btreemap!{"a" => Some("2"), "dir/b" => Some("3")}, repo.clone()),
+            );
+            let hg1 = repo.get_hg_from_bonsai_changeset(c1).wait().unwrap();
+            let hg2 = repo.get_hg_from_bonsai_changeset(c2).wait().unwrap();
+            let manifest = repo.get_changeset_by_changesetid(&hg2)
+                .wait()
+                .unwrap()
+                .manifestid()
+                .into_nodehash();
+            let fetch = |show_bonsai| {
+                let rev = hg2.to_hex().to_string();
+                let description = hg_changeset_fetch(repo.clone(), &rev, show_bonsai)
+                    .wait()
+                    .unwrap();
+                serde_json::to_value(&description).unwrap()
+            };
+
+            assert_eq!(
+                fetch(true),
+                json!({
+                    "id": hg2.to_hex().to_string(),
+                    "bonsai": c2.to_hex().to_string(),
+                    "manifest": manifest.to_hex().to_string(),
+                    "p1": hg1.to_hex().to_string(),
+                    "p2": null,
+                    "user": "author",
+                    "date": "1970-01-01T00:00:00+00:00",
+                    "extra": {},
+                    "files": ["a", "dir/b"],
+                    "comments": "message",
+                })
+            );
+            assert!(fetch(false).get("bonsai").is_none());
+        })
+    }
+
+    #[test]
+    fn test_non_utf8_diff() {
+        let user = ChangesetAttrDiff::User(
+            MaybeUtf8Bytes::from("author"),
+            MaybeUtf8Bytes::from(&b"auth\xffor"[..]),
+        );
+        assert_eq!(
+            serde_json::to_value(&user).unwrap(),
+            json!({"user": ["author", {"lossy": "auth\u{fffd}or", "hex": "61757468ff6f72"}]})
+        );
+
+        let extra = btreemap! {
+            b"key".to_vec() => b"\xff".to_vec(),
+            b"\xff".to_vec() => b"value".to_vec(),
+        };
+        let extra = ChangesetAttrDiff::Extra(extra_diff(&extra), BTreeMap::new());
+        assert_eq!(
+            serde_json::to_value(&extra).unwrap(),
+            json!({"extra": [
+                {"key": {"lossy": "\u{fffd}", "hex": "ff"}, "\u{fffd} [non-utf8]": "value"},
+                {}
+            ]})
+        );
+
+        let files = ChangesetAttrDiff::Files(
+            vec![
+                mpath_escaped(MPath::new(&b"dir/a\xffb"[..]).unwrap()),
+                mpath_escaped(MPath::new(&b"dir/a\xfeb"[..]).unwrap()),
+            ],
+            vec![mpath_escaped(MPath::new("dir/file").unwrap())],
+        );
+        assert_eq!(
+            serde_json::to_value(&files).unwrap(),
+            json!({"files": [
+                [
+                    {"escaped": "dir/a\\xffb", "hex": "6469722f61ff62"},
+                    {"escaped": "dir/a\\xfeb", "hex": "6469722f61fe62"}
+                ],
+                ["dir/file"]
+            ]})
+        );
+    }
+
+    #[test]
+    fn test_summary_diff() {
+        let files = ChangesetAttrDiff::FilesSummary(120_000, 3);
+        assert_eq!(
+            serde_json::to_value(&files).unwrap(),
+            json!({"files_summary": [120_000, 3]})
+        );
+
+        let manifest = ChangesetAttrDiff::ManifestSummary(ManifestDiffSummary {
+            modified: 100_000,
+            deleted: 20_000,
+        });
+        assert_eq!(
+            serde_json::to_value(&manifest).unwrap(),
+            json!({"manifest_summary": {"modified": 100_000, "deleted": 20_000}})
+        );
     }
 }
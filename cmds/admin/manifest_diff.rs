@@ -0,0 +1,312 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The manifest part of `hg-changeset diff`. Mega-commits change millions of files, so the paths
+//! are written as the diff computes them instead of being collected first.
+
+use std::io::Write;
+
+use failure::{Error, Result};
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json;
+
+use bonsai_utils::BonsaiDiffResult;
+use mercurial_types::MPath;
+
+/// Counts of the paths of a manifest diff, for `--summary`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ManifestDiffSummary {
+    pub modified: u64,
+    pub deleted: u64,
+}
+
+impl ManifestDiffSummary {
+    pub fn is_empty(&self) -> bool {
+        self.modified == 0 && self.deleted == 0
+    }
+}
+
+pub fn summarize_manifest_diff<S>(diffs: S) -> BoxFuture<ManifestDiffSummary, Error>
+where
+    S: Stream<Item = BonsaiDiffResult, Error = Error> + Send + 'static,
+{
+    diffs
+        .fold(ManifestDiffSummary::default(), |mut summary, diff| {
+            match diff {
+                BonsaiDiffResult::Changed(..) | BonsaiDiffResult::ChangedReusedId(..) => {
+                    summary.modified += 1
+                }
+                BonsaiDiffResult::Deleted(_) => summary.deleted += 1,
+            }
+            Ok::<_, Error>(summary)
+        })
+        .boxify()
+}
+
+/// Writes `{"manifest":{"modified":[...],"deleted":[...]}}` one path at a time. Nothing is
+/// written if there is no path at all.
+struct ManifestWriter<W> {
+    writer: W,
+    /// Another entry of the diff list precedes the manifest entry
+    needs_comma: bool,
+    started: bool,
+    in_deleted: bool,
+    list_empty: bool,
+}
+
+impl<W: Write> ManifestWriter<W> {
+    fn new(writer: W, needs_comma: bool) -> Self {
+        ManifestWriter {
+            writer,
+            needs_comma,
+            started: false,
+            in_deleted: false,
+            list_empty: true,
+        }
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if !self.started {
+            if self.needs_comma {
+                self.writer.write_all(b",")?;
+            }
+            self.writer.write_all(br#"{"manifest":{"modified":["#)?;
+            self.started = true;
+        }
+        Ok(())
+    }
+
+    fn start_deleted(&mut self) -> Result<()> {
+        if !self.in_deleted {
+            self.writer.write_all(br#"],"deleted":["#)?;
+            self.in_deleted = true;
+            self.list_empty = true;
+        }
+        Ok(())
+    }
+
+    fn write_path(&mut self, path: &MPath, deleted: bool) -> Result<()> {
+        self.start()?;
+        if deleted {
+            self.start_deleted()?;
+        }
+        if !self.list_empty {
+            self.writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut self.writer, &path.to_escaped())?;
+        self.list_empty = false;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        if self.started {
+            self.start_deleted()?;
+            self.writer.write_all(b"]}}")?;
+        }
+        Ok(self.writer)
+    }
+}
+
+/// Streams the manifest entry of a diff to `writer`. `diffs` is called twice, once for the
+/// modified paths and once for the deleted ones, so that neither list is ever held in memory.
+pub fn write_manifest_diff<F, S, W>(diffs: F, writer: W, needs_comma: bool) -> BoxFuture<W, Error>
+where
+    F: Fn() -> S + Send + 'static,
+    S: Stream<Item = BonsaiDiffResult, Error = Error> + Send + 'static,
+    W: Write + Send + 'static,
+{
+    diffs()
+        .fold(
+            ManifestWriter::new(writer, needs_comma),
+            |mut state, diff| -> Result<_> {
+                match diff {
+                    BonsaiDiffResult::Changed(path, ..)
+                    | BonsaiDiffResult::ChangedReusedId(path, ..) => {
+                        state.write_path(&path, false)?
+                    }
+                    BonsaiDiffResult::Deleted(_) => {}
+                }
+                Ok(state)
+            },
+        )
+        .and_then(move |state| {
+            diffs().fold(state, |mut state, diff| -> Result<_> {
+                if let BonsaiDiffResult::Deleted(path) = diff {
+                    state.write_path(&path, true)?;
+                }
+                Ok(state)
+            })
+        })
+        .and_then(|state| state.finish())
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::stream;
+
+    use mercurial_types::HgEntryId;
+    use mercurial_types::nodehash::NULL_HASH;
+    use mononoke_types::FileType;
+
+    const FILES: usize = 100_000;
+    const DIRS: usize = 100;
+
+    /// A mega-commit: FILES files over DIRS directories, every tenth one deleted. The diff is
+    /// generated lazily, and `pulled` counts the paths the consumer took from it.
+    fn mega_diff(
+        pulled: Arc<AtomicUsize>,
+    ) -> impl Stream<Item = BonsaiDiffResult, Error = Error> + Send {
+        stream::iter_ok((0..FILES).map(|i| {
+            let path = MPath::new(format!("dir{}/file{}", i % DIRS, i)).unwrap();
+            if i % 10 == 0 {
+                BonsaiDiffResult::Deleted(path)
+            } else {
+                BonsaiDiffResult::Changed(path, FileType::Regular, HgEntryId::new(NULL_HASH))
+            }
+        })).inspect(move |_| {
+            pulled.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    /// Discards the output, but records how many paths were pulled from the diff between two
+    /// writes. Paths pulled and not written yet would have to be held in memory, so collecting
+    /// the diff shows as a gap of the size of the diff.
+    struct GapWriter {
+        pulled: Arc<AtomicUsize>,
+        pulled_at_last_write: usize,
+        max_gap: usize,
+        quotes: usize,
+        bytes: usize,
+    }
+
+    impl GapWriter {
+        fn new(pulled: Arc<AtomicUsize>) -> Self {
+            GapWriter {
+                pulled,
+                pulled_at_last_write: 0,
+                max_gap: 0,
+                quotes: 0,
+                bytes: 0,
+            }
+        }
+
+        fn written_paths(&self) -> usize {
+            // The keys of the manifest entry are quoted too
+            (self.quotes / 2).saturating_sub(3)
+        }
+    }
+
+    impl Write for GapWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let pulled = self.pulled.load(Ordering::SeqCst);
+            self.max_gap = self.max_gap.max(pulled - self.pulled_at_last_write);
+            self.pulled_at_last_write = pulled;
+            self.quotes += buf.iter().filter(|b| **b == b'"').count();
+            self.bytes += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_manifest_diff_is_streamed() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let writer = write_manifest_diff(
+            {
+                cloned!(pulled);
+                move || mega_diff(pulled.clone())
+            },
+            GapWriter::new(pulled.clone()),
+            false,
+        ).wait()
+            .unwrap();
+
+        assert_eq!(pulled.load(Ordering::SeqCst), 2 * FILES);
+        assert_eq!(writer.written_paths(), FILES);
+        // Each pass skips the paths of the other list, at most 9 in a row
+        assert!(
+            writer.max_gap <= 10,
+            "{} paths were pulled without being written",
+            writer.max_gap
+        );
+        assert!(writer.bytes > FILES * "dir0/file0".len());
+    }
+
+    #[test]
+    fn test_write_manifest_diff_format() {
+        let diffs = || {
+            stream::iter_ok(vec![
+                BonsaiDiffResult::Deleted(MPath::new("gone").unwrap()),
+                BonsaiDiffResult::Changed(
+                    MPath::new(&b"dir/a\xffb"[..]).unwrap(),
+                    FileType::Regular,
+                    HgEntryId::new(NULL_HASH),
+                ),
+                BonsaiDiffResult::ChangedReusedId(
+                    MPath::new("dir/file").unwrap(),
+                    FileType::Executable,
+                    HgEntryId::new(NULL_HASH),
+                ),
+            ])
+        };
+        let output = write_manifest_diff(diffs, Vec::new(), true).wait().unwrap();
+        assert_eq!(output[0], b',');
+        let output: serde_json::Value = serde_json::from_slice(&output[1..]).unwrap();
+        assert_eq!(
+            output,
+            json!({"manifest": {
+                "modified": [
+                    {"escaped": "dir/a\\xffb", "hex": "6469722f61ff62"},
+                    "dir/file"
+                ],
+                "deleted": ["gone"]
+            }})
+        );
+
+        let only_deleted =
+            || stream::iter_ok(vec![BonsaiDiffResult::Deleted(MPath::new("gone").unwrap())]);
+        let output = write_manifest_diff(only_deleted, Vec::new(), false)
+            .wait()
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&output).unwrap(),
+            json!({"manifest": {"modified": [], "deleted": ["gone"]}})
+        );
+
+        let empty = || stream::iter_ok(vec![]);
+        let output = write_manifest_diff(empty, Vec::new(), true).wait().unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_summarize_manifest_diff() {
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let summary = summarize_manifest_diff(mega_diff(pulled.clone()))
+            .wait()
+            .unwrap();
+        assert_eq!(
+            summary,
+            ManifestDiffSummary {
+                modified: (FILES * 9 / 10) as u64,
+                deleted: (FILES / 10) as u64,
+            }
+        );
+        assert_eq!(pulled.load(Ordering::SeqCst), FILES);
+        assert!(!summary.is_empty());
+        assert!(ManifestDiffSummary::default().is_empty());
+    }
+}
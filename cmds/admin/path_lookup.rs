@@ -0,0 +1,216 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use failure::Error;
+use futures::future::{self, Future};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use mercurial_types::{Changeset, HgChangesetId, MPath, MPathElement, Manifest};
+use mercurial_types::manifest::Content;
+
+/// How many entries of the deepest resolved directory are listed when a path is not found
+const MAX_LISTED_ENTRIES: usize = 20;
+
+/// Parses a path typed by a user. Leading and trailing slashes are ignored, so `dir/` and `dir`
+/// resolve to the same entry.
+pub fn parse_path(path: &str) -> Result<MPath, Error> {
+    MPath::new(path.trim_matches('/'))
+}
+
+/// Resolves `path` in the manifest of `cs_id`. If one of the path components is missing, the
+/// error lists the entries of the deepest directory that was resolved, and suggests the entries
+/// that only differ from the missing one in case.
+pub fn lookup_path(
+    logger: Logger,
+    repo: &BlobRepo,
+    cs_id: HgChangesetId,
+    path: MPath,
+) -> BoxFuture<Content, Error> {
+    let elements: Vec<_> = path.clone().into_iter().enumerate().collect();
+
+    repo.get_changeset_by_changesetid(&cs_id)
+        .and_then({
+            cloned!(repo);
+            move |cs| repo.get_manifest_by_nodeid(cs.manifestid())
+        })
+        .and_then(move |root_mf| {
+            // The resolved directory is None for the root manifest
+            let start: (Content, Option<MPath>) = (Content::Tree(root_mf), None);
+            stream::iter_ok(elements)
+                .fold(start, move |(content, resolved), (idx, element)| {
+                    let mf = match content {
+                        Content::Tree(mf) => mf,
+                        content => {
+                            return future::err(format_err!(
+                                "expected tree entry at {}, found {:?}",
+                                describe_dir(resolved.as_ref()),
+                                content
+                            )).left_future();
+                        }
+                    };
+                    match mf.lookup(&element) {
+                        Some(entry) => {
+                            debug!(
+                                logger,
+                                "Fetched {:?}, hash: {:?}",
+                                element,
+                                entry.get_hash()
+                            );
+                            let resolved = MPath::join_opt_element(resolved.as_ref(), &element);
+                            entry
+                                .get_content()
+                                .map(move |content| (content, Some(resolved)))
+                                .right_future()
+                        }
+                        None => {
+                            let rest: Vec<_> = path.clone().into_iter().skip(idx + 1).collect();
+                            future::err(missing_element_error(
+                                &*mf,
+                                resolved.as_ref(),
+                                &element,
+                                &rest,
+                            )).left_future()
+                        }
+                    }
+                })
+                .map(|(content, _)| content)
+        })
+        .boxify()
+}
+
+/// Builds the error for an `element` missing from `mf`, the manifest of `dir`. This is the only
+/// place that lists a manifest, so successful lookups don't pay for it.
+fn missing_element_error(
+    mf: &Manifest,
+    dir: Option<&MPath>,
+    element: &MPathElement,
+    rest: &[MPathElement],
+) -> Error {
+    let mut names: Vec<_> = mf.list()
+        .filter_map(|entry| entry.get_name().cloned())
+        .collect();
+    names.sort();
+
+    let wanted = element.as_bytes().to_ascii_lowercase();
+    let suggestions: Vec<_> = names
+        .iter()
+        .filter(|name| name.as_bytes().to_ascii_lowercase() == wanted)
+        .map(|name| {
+            let suggestion = MPath::join_opt_element(dir, name).join(rest);
+            format!("`{}`", suggestion.to_escaped())
+        })
+        .collect();
+
+    let mut msg = format!(
+        "failed to lookup element `{}` in {}",
+        element.to_escaped(),
+        describe_dir(dir)
+    );
+    if !suggestions.is_empty() {
+        msg.push_str(&format!("; did you mean {}?", suggestions.join(" or ")));
+    }
+    if names.is_empty() {
+        msg.push_str("; the directory is empty");
+    } else {
+        let listed: Vec<_> = names
+            .iter()
+            .take(MAX_LISTED_ENTRIES)
+            .map(|name| name.to_escaped().to_string())
+            .collect();
+        msg.push_str(&format!("; entries: {}", listed.join(", ")));
+        if names.len() > MAX_LISTED_ENTRIES {
+            msg.push_str(&format!(" and {} more", names.len() - MAX_LISTED_ENTRIES));
+        }
+    }
+    format_err!("{}", msg)
+}
+
+fn describe_dir(dir: Option<&MPath>) -> String {
+    match dir {
+        Some(dir) => format!("`{}`", dir.to_escaped()),
+        None => "the root directory".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use mononoke_types::ChangesetId;
+    use tests_utils::{create_commit, store_files};
+
+    fn repo_with_files() -> (BlobRepo, HgChangesetId) {
+        let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+        let bcs_id: ChangesetId = create_commit(
+            repo.clone(),
+            vec![],
+            store_files(
+                btreemap!{
+                    "dir/File.txt" => Some("content"),
+                    "dir/other" => Some("other"),
+                    "top" => Some("top"),
+                },
+                repo.clone(),
+            ),
+        );
+        let cs_id = repo.get_hg_from_bonsai_changeset(bcs_id).wait().unwrap();
+        (repo, cs_id)
+    }
+
+    fn lookup(repo: &BlobRepo, cs_id: HgChangesetId, path: &str) -> Result<Content, Error> {
+        let logger = Logger::root(::slog::Discard, o!());
+        lookup_path(logger, repo, cs_id, parse_path(path).unwrap()).wait()
+    }
+
+    #[test]
+    fn test_lookup_trailing_slash() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, cs_id) = repo_with_files();
+            match lookup(&repo, cs_id, "dir/").unwrap() {
+                Content::Tree(_) => {}
+                content => panic!("expected a tree, found {:?}", content),
+            }
+            match lookup(&repo, cs_id, "/dir/File.txt").unwrap() {
+                Content::File(_) => {}
+                content => panic!("expected a file, found {:?}", content),
+            }
+        });
+    }
+
+    #[test]
+    fn test_lookup_wrong_case() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, cs_id) = repo_with_files();
+
+            let err = lookup(&repo, cs_id, "Dir/File.txt").unwrap_err().to_string();
+            assert!(err.contains("did you mean `dir/File.txt`?"), "{}", err);
+            assert!(err.contains("in the root directory"), "{}", err);
+
+            let err = lookup(&repo, cs_id, "dir/file.txt").unwrap_err().to_string();
+            assert!(err.contains("in `dir`"), "{}", err);
+            assert!(err.contains("did you mean `dir/File.txt`?"), "{}", err);
+            assert!(err.contains("entries: File.txt, other"), "{}", err);
+        });
+    }
+
+    #[test]
+    fn test_lookup_missing() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, cs_id) = repo_with_files();
+
+            let err = lookup(&repo, cs_id, "dir/missing").unwrap_err().to_string();
+            assert!(!err.contains("did you mean"), "{}", err);
+            assert!(err.contains("entries: File.txt, other"), "{}", err);
+
+            let err = lookup(&repo, cs_id, "nope/file").unwrap_err().to_string();
+            assert!(err.contains("entries: dir, top"), "{}", err);
+        });
+    }
+}
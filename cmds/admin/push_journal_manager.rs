@@ -0,0 +1,118 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use clap::{App, ArgMatches, SubCommand};
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use mercurial_types::RepositoryId;
+use mononoke_types::DateTime;
+use push_journal::{PushJournal, PushJournalEntry};
+
+const LIST_CMD: &'static str = "list";
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    let list = SubCommand::with_name(LIST_CMD)
+        .about("lists the journaled pushes of the repo, oldest first")
+        .args_from_usage(
+            "--incomplete    'only list the pushes that uploaded blobs but never completed'",
+        );
+
+    app.about("set of commands to inspect the push journal")
+        .subcommand(list)
+}
+
+pub fn handle_command<'a>(
+    journal: Arc<PushJournal>,
+    repo_id: RepositoryId,
+    matches: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    match matches.subcommand() {
+        (LIST_CMD, Some(sub_m)) => handle_list(sub_m, logger, journal, repo_id),
+        _ => {
+            println!("{}", matches.usage());
+            ::std::process::exit(1);
+        }
+    }
+}
+
+fn format_entry(entry: &PushJournalEntry, now: i64) -> String {
+    let status = match entry.completed_at {
+        Some(completed_at) => format!("complete after {}s", completed_at - entry.started_at),
+        None => "incomplete".to_string(),
+    };
+    let mut lines = vec![
+        format!(
+            "session {} started at {} ({}s ago), {}",
+            entry.session_id,
+            entry.started_at,
+            now - entry.started_at,
+            status
+        ),
+        format!("  {} changesets", entry.changeset_count),
+    ];
+    lines.extend(
+        entry
+            .changesets
+            .iter()
+            .map(|changeset| format!("  {}", changeset)),
+    );
+    if entry.changesets.len() < entry.changeset_count {
+        lines.push("  ...".to_string());
+    }
+    lines.join("\n")
+}
+
+fn handle_list<'a>(
+    args: &ArgMatches<'a>,
+    _logger: Logger,
+    journal: Arc<PushJournal>,
+    repo_id: RepositoryId,
+) -> BoxFuture<(), Error> {
+    let incomplete_only = args.is_present("incomplete");
+
+    journal
+        .list(repo_id, incomplete_only)
+        .map(|entries| {
+            let now = DateTime::now().timestamp_secs();
+            for entry in entries {
+                println!("{}", format_entry(&entry, now));
+            }
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types::HgChangesetId;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_format_entry() {
+        let cs = HgChangesetId::from_str("1111111111111111111111111111111111111111").unwrap();
+        let mut entry = PushJournalEntry::new(RepositoryId::new(0), "abc".to_string(), 100, &[cs]);
+        assert_eq!(
+            format_entry(&entry, 160),
+            "session abc started at 100 (60s ago), incomplete\n  1 changesets\n  \
+             1111111111111111111111111111111111111111"
+        );
+
+        entry.completed_at = Some(102);
+        entry.changeset_count = 2;
+        assert_eq!(
+            format_entry(&entry, 160),
+            "session abc started at 100 (60s ago), complete after 2s\n  2 changesets\n  \
+             1111111111111111111111111111111111111111\n  ..."
+        );
+    }
+}
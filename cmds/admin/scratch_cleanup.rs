@@ -0,0 +1,506 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Enforces the retention of scratch bookmarks, see `ScratchRetentionParams`. Meant to be run
+//! from cron.
+//!
+//! Bookmarks are deleted with a compare-and-swap, so a bookmark that is updated while the cleanup
+//! runs is kept. Deletions are recorded in the update log of the bookmarks with the `retention`
+//! reason. The changesets that only deleted bookmarks pointed to stay in the repo: they become
+//! eligible for garbage collection, but this command doesn't collect them.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{App, ArgMatches};
+use failure::{Error, Result};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use bookmarks::{Bookmark, BookmarkUpdateReason, Bookmarks};
+use cmdlib::args;
+use mercurial_types::RepositoryId;
+use metaconfig::repoconfig::ScratchRetentionParams;
+use mononoke_types::ChangesetId;
+
+use cross_repo_index_manager::read_repo_configs;
+use storage_report::parse_arg;
+
+/// Bookmarks deleted in a single transaction
+const DEFAULT_CHUNK_SIZE: usize = 100;
+
+/// Why the retention doesn't keep a bookmark
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExpiryCause {
+    /// Not updated for longer than the max age
+    Age,
+    /// Its user has more recently updated bookmarks than the max count
+    Count,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExpiredBookmark {
+    pub bookmark: Bookmark,
+    pub changeset_id: ChangesetId,
+    pub last_updated: Option<i64>,
+    pub cause: ExpiryCause,
+}
+
+/// The bookmarks of the namespace of `params` that it doesn't retain at time `now`, by name.
+/// `bookmarks` are the bookmarks of the namespace, with the times of their last updates.
+///
+/// A bookmark whose last update wasn't recorded is never too old, and counts as the oldest of
+/// its user. Excluded bookmarks are kept and don't count towards the max count of their user.
+pub fn select_expired(
+    params: &ScratchRetentionParams,
+    bookmarks: Vec<(Bookmark, ChangesetId, Option<i64>)>,
+    excluded: &HashSet<Bookmark>,
+    now: i64,
+) -> Vec<ExpiredBookmark> {
+    let max_age = params.max_age.map(|max_age| max_age.as_secs() as i64);
+    let prefix_len = params.prefix.to_string().len();
+
+    let mut expired = Vec::new();
+    let mut per_user: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    for (bookmark, changeset_id, last_updated) in bookmarks {
+        if excluded.contains(&bookmark) || params.excluded.contains(&bookmark) {
+            continue;
+        }
+        let too_old = match (max_age, last_updated) {
+            (Some(max_age), Some(last_updated)) => now - last_updated > max_age,
+            _ => false,
+        };
+        if too_old {
+            expired.push(ExpiredBookmark {
+                bookmark,
+                changeset_id,
+                last_updated,
+                cause: ExpiryCause::Age,
+            });
+        } else {
+            let user = {
+                let name = bookmark.to_string();
+                let rest = &name[prefix_len..];
+                rest.split('/').next().unwrap_or(rest).to_string()
+            };
+            per_user
+                .entry(user)
+                .or_insert_with(Vec::new)
+                .push((bookmark, changeset_id, last_updated));
+        }
+    }
+
+    if let Some(max_count) = params.max_count_per_user {
+        for (_, mut user_bookmarks) in per_user {
+            // Most recently updated first
+            user_bookmarks.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+            for (bookmark, changeset_id, last_updated) in user_bookmarks.into_iter().skip(max_count)
+            {
+                expired.push(ExpiredBookmark {
+                    bookmark,
+                    changeset_id,
+                    last_updated,
+                    cause: ExpiryCause::Count,
+                });
+            }
+        }
+    }
+
+    expired.sort_by(|a, b| a.bookmark.cmp(&b.bookmark));
+    expired
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CleanupOutcome {
+    pub deleted: Vec<Bookmark>,
+    /// Bookmarks that were updated or deleted by someone else since they were listed
+    pub skipped: Vec<Bookmark>,
+}
+
+impl CleanupOutcome {
+    fn merge(mut self, other: CleanupOutcome) -> Self {
+        self.deleted.extend(other.deleted);
+        self.skipped.extend(other.skipped);
+        self
+    }
+}
+
+/// Deletes `expired` in transactions of `chunk_size` bookmarks. If a bookmark of a chunk was
+/// updated since it was listed, the transaction fails as a whole, and the bookmarks of the chunk
+/// are deleted one at a time so that only the updated one is kept.
+pub fn delete_expired(
+    bookmarks: Arc<Bookmarks>,
+    repo_id: RepositoryId,
+    expired: Vec<ExpiredBookmark>,
+    chunk_size: usize,
+) -> BoxFuture<CleanupOutcome, Error> {
+    stream::iter_ok(expired)
+        .chunks(chunk_size)
+        .and_then(move |chunk| delete_chunk(bookmarks.clone(), repo_id, chunk))
+        .fold(CleanupOutcome::default(), |outcome, chunk_outcome| {
+            Ok::<_, Error>(outcome.merge(chunk_outcome))
+        })
+        .boxify()
+}
+
+fn delete_chunk(
+    bookmarks: Arc<Bookmarks>,
+    repo_id: RepositoryId,
+    chunk: Vec<ExpiredBookmark>,
+) -> BoxFuture<CleanupOutcome, Error> {
+    let mut txn = bookmarks.create_transaction(&repo_id);
+    txn.set_reason(BookmarkUpdateReason::Retention);
+    for expired in chunk.iter() {
+        try_boxfuture!(txn.delete(&expired.bookmark, &expired.changeset_id));
+    }
+
+    txn.commit()
+        .and_then(move |committed| {
+            if committed {
+                let deleted = chunk.into_iter().map(|expired| expired.bookmark).collect();
+                future::ok(CleanupOutcome {
+                    deleted,
+                    skipped: vec![],
+                }).boxify()
+            } else if chunk.len() == 1 {
+                let skipped = chunk.into_iter().map(|expired| expired.bookmark).collect();
+                future::ok(CleanupOutcome {
+                    deleted: vec![],
+                    skipped,
+                }).boxify()
+            } else {
+                stream::iter_ok(chunk)
+                    .and_then(move |expired| {
+                        delete_chunk(bookmarks.clone(), repo_id, vec![expired])
+                    })
+                    .fold(CleanupOutcome::default(), |outcome, single| {
+                        Ok::<_, Error>(outcome.merge(single))
+                    })
+                    .boxify()
+            }
+        })
+        .boxify()
+}
+
+/// Lists the bookmarks of the namespace of `params`, and selects the ones it doesn't retain
+pub fn find_expired(
+    bookmarks: Arc<Bookmarks>,
+    repo_id: RepositoryId,
+    params: ScratchRetentionParams,
+    excluded: Arc<HashSet<Bookmark>>,
+    now: i64,
+) -> BoxFuture<Vec<ExpiredBookmark>, Error> {
+    bookmarks
+        .list_by_prefix_with_timestamps(&params.prefix, &repo_id)
+        .collect()
+        .map(move |listed| select_expired(&params, listed, &excluded, now))
+        .boxify()
+}
+
+fn get_retention<'a>(
+    args: &ArgMatches<'a>,
+    repo_id: RepositoryId,
+    logger: &Logger,
+) -> Result<Vec<ScratchRetentionParams>> {
+    let config_repo = Path::new(args.value_of("config-repo").unwrap());
+    let config_bookmark = args.value_of("config-bookmark").unwrap_or("master");
+    let configs = read_repo_configs(logger, config_repo, config_bookmark)?;
+    let config = configs
+        .repos
+        .into_iter()
+        .map(|(_, config)| config)
+        .find(|config| config.repoid == repo_id.id())
+        .ok_or_else(|| format_err!("the config repo has no repo with id {}", repo_id.id()))?;
+    Ok(config.scratch_retention)
+}
+
+fn format_expired(expired: &ExpiredBookmark) -> String {
+    let cause = match expired.cause {
+        ExpiryCause::Age => "too old",
+        ExpiryCause::Count => "too many bookmarks of the user",
+    };
+    format!("{} ({})", expired.bookmark, cause)
+}
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about(
+        "deletes the scratch bookmarks that the retention of their namespace doesn't keep. The \
+         changesets they pointed to are not deleted",
+    ).args_from_usage(
+            r#"
+            --config-repo=<PATH>            'path of the config repo, which has the retention of the repo'
+            --config-bookmark=[BOOKMARK]    'bookmark of the config repo to read (default: master)'
+            --dry-run                       'print the bookmarks that would be deleted, but don't delete them'
+            --exclude=[BOOKMARK]...         'bookmarks to keep, on top of the ones that the retention excludes'
+            --chunk-size=[N]                'how many bookmarks to delete in a single transaction'
+            "#,
+        )
+}
+
+pub fn handle_command<'a>(
+    matches: &ArgMatches<'a>,
+    args: &ArgMatches<'a>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let dry_run = args.is_present("dry-run");
+    let excluded = try_boxfuture!(
+        args.values_of("exclude")
+            .map(|names| names.map(Bookmark::new).collect::<Result<HashSet<_>>>())
+            .unwrap_or_else(|| Ok(HashSet::new()))
+    );
+    let chunk_size = try_boxfuture!(parse_arg(args, "chunk-size")).unwrap_or(DEFAULT_CHUNK_SIZE);
+    if chunk_size == 0 {
+        return future::err(format_err!("--chunk-size must be positive")).boxify();
+    }
+
+    args::init_cachelib(matches);
+    let repo_id = try_boxfuture!(args::get_repo_id(matches));
+    let retention = try_boxfuture!(get_retention(args, repo_id, &logger));
+    if retention.is_empty() {
+        info!(logger, "repo {} has no scratch retention", repo_id.id());
+        return future::ok(()).boxify();
+    }
+    let repo = try_boxfuture!(args::open_repo(&logger, matches));
+    let bookmarks = repo.blobrepo().get_bookmarks_object();
+    let excluded = Arc::new(excluded);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as i64)
+        .unwrap_or(0);
+
+    stream::iter_ok(retention)
+        .and_then({
+            cloned!(bookmarks);
+            move |params| find_expired(bookmarks.clone(), repo_id, params, excluded.clone(), now)
+        })
+        .concat2()
+        .and_then(move |expired| {
+            for expired in expired.iter() {
+                let verb = if dry_run { "would delete" } else { "deleting" };
+                println!("{} {}", verb, format_expired(expired));
+            }
+            if dry_run {
+                info!(logger, "{} bookmarks would be deleted", expired.len());
+                return future::ok(()).boxify();
+            }
+            delete_expired(bookmarks, repo_id, expired, chunk_size)
+                .map(move |outcome| {
+                    for skipped in outcome.skipped.iter() {
+                        println!("skipped {}, it was updated", skipped);
+                    }
+                    info!(
+                        logger,
+                        "deleted {} bookmarks, skipped {}",
+                        outcome.deleted.len(),
+                        outcome.skipped.len()
+                    );
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use bookmarks::{BookmarkPrefix, BookmarkUpdateLogEntry};
+    use dbbookmarks::{Clock, SqliteDbBookmarks};
+    use mononoke_types_mocks::changesetid::{ONES_CSID, TWOS_CSID};
+
+    const DAY: i64 = 24 * 60 * 60;
+    const NOW: i64 = 1_000 * DAY;
+
+    fn bookmark(name: &str) -> Bookmark {
+        Bookmark::new(name).unwrap()
+    }
+
+    fn retention(
+        max_age_days: Option<u64>,
+        max_count_per_user: Option<usize>,
+    ) -> ScratchRetentionParams {
+        ScratchRetentionParams {
+            prefix: BookmarkPrefix::new("scratch/").unwrap(),
+            max_age: max_age_days.map(|days| Duration::from_secs(days * DAY as u64)),
+            max_count_per_user,
+            excluded: vec![bookmark("scratch/bob/keep")],
+        }
+    }
+
+    /// Bookmarks of the scratch namespace, with their ages in days. None is an unknown age.
+    fn aged(bookmarks: &[(&str, Option<i64>)]) -> Vec<(Bookmark, ChangesetId, Option<i64>)> {
+        bookmarks
+            .iter()
+            .map(|&(name, age)| (bookmark(name), ONES_CSID, age.map(|age| NOW - age * DAY)))
+            .collect()
+    }
+
+    fn names(expired: &[ExpiredBookmark]) -> Vec<(String, ExpiryCause)> {
+        expired
+            .iter()
+            .map(|expired| (expired.bookmark.to_string(), expired.cause))
+            .collect()
+    }
+
+    #[test]
+    fn test_select_expired_by_age() {
+        let bookmarks = aged(&[
+            ("scratch/alice/old", Some(31)),
+            ("scratch/alice/new", Some(1)),
+            ("scratch/alice/exactly", Some(30)),
+            ("scratch/bob/keep", Some(365)),
+            ("scratch/bob/unknown", None),
+        ]);
+        let params = retention(Some(30), None);
+        let expired = select_expired(&params, bookmarks, &HashSet::new(), NOW);
+        assert_eq!(
+            names(&expired),
+            vec![("scratch/alice/old".to_string(), ExpiryCause::Age)]
+        );
+        assert_eq!(expired[0].last_updated, Some(NOW - 31 * DAY));
+    }
+
+    #[test]
+    fn test_select_expired_by_count() {
+        let bookmarks = aged(&[
+            ("scratch/alice/1", Some(1)),
+            ("scratch/alice/2", Some(2)),
+            ("scratch/alice/3", Some(3)),
+            ("scratch/alice/unknown", None),
+            ("scratch/bob/1", Some(50)),
+            ("scratch/bob/2", Some(60)),
+            ("scratch/bob/keep", Some(70)),
+            ("scratch/carol", Some(100)),
+        ]);
+        let excluded = hashset!{bookmark("scratch/alice/2")};
+        let expired = select_expired(&retention(None, Some(2)), bookmarks, &excluded, NOW);
+        assert_eq!(
+            names(&expired),
+            vec![("scratch/alice/unknown".to_string(), ExpiryCause::Count)]
+        );
+
+        let bookmarks = aged(&[
+            ("scratch/alice/1", Some(1)),
+            ("scratch/alice/2", Some(2)),
+            ("scratch/alice/3", Some(40)),
+            ("scratch/bob/1", Some(5)),
+            ("scratch/bob/2", Some(6)),
+        ]);
+        let params = retention(Some(30), Some(1));
+        let expired = select_expired(&params, bookmarks, &HashSet::new(), NOW);
+        assert_eq!(
+            names(&expired),
+            vec![
+                ("scratch/alice/2".to_string(), ExpiryCause::Count),
+                ("scratch/alice/3".to_string(), ExpiryCause::Age),
+                ("scratch/bob/2".to_string(), ExpiryCause::Count),
+            ]
+        );
+    }
+
+    /// Creates bookmarks at the times of their ages in days with a fake clock, which is left at
+    /// NOW
+    fn aged_fixtures(bookmarks: &[(&str, i64)]) -> Arc<SqliteDbBookmarks> {
+        let time = Arc::new(Mutex::new(0));
+        let clock: Clock = {
+            cloned!(time);
+            Arc::new(move || *time.lock().unwrap())
+        };
+        let db = SqliteDbBookmarks::in_memory().unwrap().with_clock(clock);
+        for &(name, age) in bookmarks {
+            *time.lock().unwrap() = NOW - age * DAY;
+            let mut txn = db.create_transaction(&RepositoryId::new(0));
+            txn.create(&bookmark(name), &ONES_CSID).unwrap();
+            assert!(txn.commit().wait().unwrap());
+        }
+        *time.lock().unwrap() = NOW;
+        Arc::new(db)
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let repo_id = RepositoryId::new(0);
+        let db = aged_fixtures(&[
+            ("scratch/alice/1", 1),
+            ("scratch/alice/2", 2),
+            ("scratch/alice/3", 3),
+            ("scratch/alice/4", 4),
+            ("scratch/bob/old", 90),
+            ("scratch/bob/keep", 90),
+            ("master", 1000),
+        ]);
+        let params = retention(Some(30), Some(2));
+        let excluded = Arc::new(HashSet::new());
+
+        // A dry run selects exactly what a real run deletes
+        let expired = find_expired(db.clone(), repo_id, params.clone(), excluded.clone(), NOW)
+            .wait()
+            .unwrap();
+        assert_eq!(
+            names(&expired),
+            vec![
+                ("scratch/alice/3".to_string(), ExpiryCause::Count),
+                ("scratch/alice/4".to_string(), ExpiryCause::Count),
+                ("scratch/bob/old".to_string(), ExpiryCause::Age),
+            ]
+        );
+
+        // A bookmark that moves after the listing is kept, the rest of its chunk is still deleted
+        let mut txn = db.create_transaction(&repo_id);
+        txn.update(&bookmark("scratch/alice/4"), &TWOS_CSID, &ONES_CSID)
+            .unwrap();
+        assert!(txn.commit().wait().unwrap());
+
+        let outcome = delete_expired(db.clone(), repo_id, expired.clone(), 2)
+            .wait()
+            .unwrap();
+        assert_eq!(
+            outcome,
+            CleanupOutcome {
+                deleted: vec![bookmark("scratch/alice/3"), bookmark("scratch/bob/old")],
+                skipped: vec![bookmark("scratch/alice/4")],
+            }
+        );
+
+        let mut left = db.list_by_prefix(&BookmarkPrefix::empty(), &repo_id)
+            .map(|(name, _)| name.to_string())
+            .collect()
+            .wait()
+            .unwrap();
+        left.sort();
+        assert_eq!(
+            left,
+            vec![
+                "master",
+                "scratch/alice/1",
+                "scratch/alice/2",
+                "scratch/alice/4",
+                "scratch/bob/keep",
+            ]
+        );
+
+        let log = db.read_update_log(&bookmark("scratch/bob/old"), &repo_id)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            log.last(),
+            Some(&BookmarkUpdateLogEntry {
+                bookmark: bookmark("scratch/bob/old"),
+                from_changeset_id: Some(ONES_CSID),
+                to_changeset_id: None,
+                reason: BookmarkUpdateReason::Retention,
+                timestamp: NOW,
+            })
+        );
+    }
+}
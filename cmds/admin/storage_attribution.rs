@@ -0,0 +1,239 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches};
+use failure::Error;
+use futures::{Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json::to_string_pretty;
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use blobrepo::storage_attribution::{get_storage_attribution, StorageAttribution};
+use mercurial_types::HgChangesetId;
+use revset::RangeNodeStream;
+
+use storage_report::parse_arg;
+
+const DEFAULT_CONCURRENCY: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CommitAttribution {
+    pub changeset: String,
+    pub author: String,
+    /// None for the commits that were created before attribution existed
+    pub attribution: Option<StorageAttribution>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AuthorAttribution {
+    pub author: String,
+    pub commits: u64,
+    /// Commits of the author that have no attribution, and aren't counted in the sums
+    pub unattributed_commits: u64,
+    pub new_contents: u64,
+    pub new_bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AttributionReport {
+    pub commits: u64,
+    pub unattributed_commits: u64,
+    pub new_contents: u64,
+    pub new_bytes: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub per_commit: Vec<CommitAttribution>,
+    /// Authors by the bytes they introduced, most first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub per_author: Vec<AuthorAttribution>,
+}
+
+impl AttributionReport {
+    fn new(commits: Vec<CommitAttribution>, by_author: bool) -> Self {
+        let mut report = AttributionReport::default();
+        let mut authors: HashMap<String, AuthorAttribution> = HashMap::new();
+        for commit in commits.iter() {
+            let author = authors
+                .entry(commit.author.clone())
+                .or_insert_with(|| AuthorAttribution {
+                    author: commit.author.clone(),
+                    ..Default::default()
+                });
+            report.commits += 1;
+            author.commits += 1;
+            match commit.attribution {
+                Some(attribution) => {
+                    report.new_contents += attribution.new_contents;
+                    report.new_bytes += attribution.new_bytes;
+                    author.new_contents += attribution.new_contents;
+                    author.new_bytes += attribution.new_bytes;
+                }
+                None => {
+                    report.unattributed_commits += 1;
+                    author.unattributed_commits += 1;
+                }
+            }
+        }
+
+        if by_author {
+            let mut per_author: Vec<_> = authors.into_iter().map(|(_, author)| author).collect();
+            per_author.sort_by(|a, b| {
+                b.new_bytes
+                    .cmp(&a.new_bytes)
+                    .then_with(|| a.author.cmp(&b.author))
+            });
+            report.per_author = per_author;
+        } else {
+            report.per_commit = commits;
+        }
+        report
+    }
+}
+
+/// Attribution of the commits from `start` to `stop`, both included, `stop` first
+pub fn attribute_range(
+    repo: Arc<BlobRepo>,
+    start: HgChangesetId,
+    stop: HgChangesetId,
+    concurrency: usize,
+) -> BoxFuture<Vec<CommitAttribution>, Error> {
+    let resolve = {
+        cloned!(repo);
+        move |cs: HgChangesetId| {
+            repo.get_bonsai_from_hg(&cs)
+                .and_then(move |bcs_id| bcs_id.ok_or_else(|| format_err!("{} not found", cs)))
+        }
+    };
+
+    resolve(start)
+        .join(resolve(stop))
+        .and_then(move |(start, stop)| {
+            let range = RangeNodeStream::new(&repo, start, stop);
+            range
+                .map(move |bcs_id| {
+                    repo.get_bonsai_changeset(bcs_id)
+                        .join3(
+                            repo.get_hg_from_bonsai_changeset(bcs_id),
+                            get_storage_attribution(&repo, bcs_id),
+                        )
+                        .map(|(bcs, hg_cs, attribution)| CommitAttribution {
+                            changeset: hg_cs.to_hex().to_string(),
+                            author: bcs.author().to_string(),
+                            attribution,
+                        })
+                })
+                .buffered(concurrency)
+                .collect()
+        })
+        .boxify()
+}
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about(
+        "reports the bytes of file contents that the commits of a range introduced to the repo. \
+         Copies, moves and reverts don't introduce any bytes",
+    ).arg(
+            Arg::with_name("range")
+                .long("range")
+                .value_names(&["START_CS", "STOP_CS"])
+                .number_of_values(2)
+                .required(true)
+                .help("hg changesets of the range, START_CS being an ancestor of STOP_CS"),
+        )
+        .args_from_usage(
+            r#"
+            --by-author                 'sum the attribution of the commits of each author'
+            --concurrency [N]           'how many commits to fetch at once'
+            "#,
+        )
+}
+
+pub fn handle_command<'a>(
+    repo: &BlobRepo,
+    matches: &ArgMatches<'a>,
+    _logger: Logger,
+) -> BoxFuture<(), Error> {
+    let mut range = matches.values_of("range").unwrap();
+    let start = try_boxfuture!(HgChangesetId::from_str(range.next().unwrap()));
+    let stop = try_boxfuture!(HgChangesetId::from_str(range.next().unwrap()));
+    let by_author = matches.is_present("by-author");
+    let concurrency =
+        try_boxfuture!(parse_arg(matches, "concurrency")).unwrap_or(DEFAULT_CONCURRENCY);
+
+    attribute_range(Arc::new(repo.clone()), start, stop, concurrency)
+        .and_then(move |commits| {
+            let report = AttributionReport::new(commits, by_author);
+            println!("{}", to_string_pretty(&report)?);
+            Ok(())
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn commit(author: &str, attribution: Option<(u64, u64)>) -> CommitAttribution {
+        CommitAttribution {
+            changeset: "0".repeat(40),
+            author: author.to_string(),
+            attribution: attribution.map(|(new_contents, new_bytes)| StorageAttribution {
+                new_contents,
+                new_bytes,
+            }),
+        }
+    }
+
+    fn commits() -> Vec<CommitAttribution> {
+        vec![
+            commit("alice", Some((1, 100))),
+            commit("bob", Some((2, 300))),
+            commit("alice", Some((1, 50))),
+            commit("alice", None),
+            commit("carol", Some((0, 0))),
+        ]
+    }
+
+    #[test]
+    fn test_report_per_commit() {
+        let report = AttributionReport::new(commits(), false);
+        assert_eq!(report.commits, 5);
+        assert_eq!(report.unattributed_commits, 1);
+        assert_eq!(report.new_contents, 4);
+        assert_eq!(report.new_bytes, 450);
+        assert_eq!(report.per_commit, commits());
+        assert!(report.per_author.is_empty());
+    }
+
+    #[test]
+    fn test_report_per_author() {
+        let report = AttributionReport::new(commits(), true);
+        assert_eq!(report.new_bytes, 450);
+        assert!(report.per_commit.is_empty());
+
+        let author = |author: &str, commits, unattributed_commits, new_contents, new_bytes| {
+            AuthorAttribution {
+                author: author.to_string(),
+                commits,
+                unattributed_commits,
+                new_contents,
+                new_bytes,
+            }
+        };
+        assert_eq!(
+            report.per_author,
+            vec![
+                author("bob", 1, 0, 2, 300),
+                author("alice", 3, 1, 2, 150),
+                author("carol", 1, 0, 0, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,618 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::{App, ArgMatches};
+use failure::{Error, Result};
+use futures::{future, stream, Future, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json::to_string_pretty;
+use slog::Logger;
+
+use blobstore::{Blobstore, BlobstoreEnumerate, BlobstoreKeyEntry};
+use fileblob::Fileblob;
+use mercurial_types::RepositoryId;
+use mononoke_types::RepoPrefix;
+
+const DEFAULT_PAGE_SIZE: usize = 10_000;
+const DEFAULT_CONCURRENCY: usize = 100;
+
+/// The kinds of blobs of a repo, told apart by their keys
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum KeyFamily {
+    HgChangeset,
+    HgManifest,
+    HgFilenode,
+    Changeset,
+    Content,
+    Alias,
+    BookmarkSnapshot,
+    Other,
+}
+
+struct KeyFamilyEntry {
+    /// Part of the key that tells the family apart
+    marker: &'static str,
+    family: KeyFamily,
+    /// Name of the family in storage reports
+    name: &'static str,
+    /// Value of `blobstore-fetch --decode-as` that decodes blobs of the family
+    decoder: Option<&'static str>,
+}
+
+/// The families that can be told apart. "hgchangeset." contains "changeset.", so the hg
+/// families come first.
+const KEY_FAMILIES: &[KeyFamilyEntry] = &[
+    KeyFamilyEntry {
+        marker: "hgchangeset.",
+        family: KeyFamily::HgChangeset,
+        name: "hg_changesets",
+        decoder: Some("changeset"),
+    },
+    KeyFamilyEntry {
+        marker: "hgmanifest.",
+        family: KeyFamily::HgManifest,
+        name: "hg_manifests",
+        decoder: Some("manifest"),
+    },
+    KeyFamilyEntry {
+        marker: "hgfilenode.",
+        family: KeyFamily::HgFilenode,
+        name: "hg_filenodes",
+        decoder: Some("file"),
+    },
+    KeyFamilyEntry {
+        marker: "changeset.",
+        family: KeyFamily::Changeset,
+        name: "bonsai_changesets",
+        decoder: None,
+    },
+    KeyFamilyEntry {
+        marker: "content.",
+        family: KeyFamily::Content,
+        name: "file_contents",
+        decoder: Some("contents"),
+    },
+    KeyFamilyEntry {
+        marker: "alias.",
+        family: KeyFamily::Alias,
+        name: "content_aliases",
+        decoder: None,
+    },
+    KeyFamilyEntry {
+        marker: "bookmark_snapshot.",
+        family: KeyFamily::BookmarkSnapshot,
+        name: "bookmark_snapshots",
+        decoder: None,
+    },
+];
+
+impl KeyFamily {
+    fn entry(&self) -> Option<&'static KeyFamilyEntry> {
+        KEY_FAMILIES.iter().find(|entry| entry.family == *self)
+    }
+
+    /// Family of a key, with or without the prefix of the repo
+    pub fn of_key(key: &str) -> Self {
+        KEY_FAMILIES
+            .iter()
+            .find(|entry| key.contains(entry.marker))
+            .map(|entry| entry.family)
+            .unwrap_or(KeyFamily::Other)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.entry().map(|entry| entry.name).unwrap_or("other")
+    }
+
+    /// How `blobstore-fetch` decodes blobs of the family, if it can
+    pub fn decoder(&self) -> Option<&'static str> {
+        self.entry().and_then(|entry| entry.decoder)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct LargeBlob {
+    pub key: String,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct FamilyUsage {
+    pub count: u64,
+    pub bytes: u64,
+    /// The largest blobs of the family, largest first. Only blobs that were measured are
+    /// considered, so with sampling it is a sample too.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub largest: Vec<LargeBlob>,
+}
+
+impl FamilyUsage {
+    fn add(&mut self, key: String, bytes: u64, top: usize) {
+        self.count += 1;
+        self.bytes += bytes;
+        if top > 0 {
+            let pos = self.largest
+                .iter()
+                .position(|blob| blob.bytes < bytes)
+                .unwrap_or(self.largest.len());
+            if pos < top {
+                self.largest.insert(pos, LargeBlob { key, bytes });
+                self.largest.truncate(top);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StorageReport {
+    pub prefix: String,
+    /// Set if only a fraction of the keys was measured, and counts and bytes were scaled up
+    pub estimate: bool,
+    pub sample_fraction: Option<f64>,
+    /// Keys seen by this run, sampled or not
+    pub keys_scanned: u64,
+    pub families: BTreeMap<&'static str, FamilyUsage>,
+    pub total: FamilyUsage,
+    /// Pass as --continuation to scan the keys that this run didn't get to
+    pub continuation: Option<String>,
+}
+
+impl StorageReport {
+    fn new(prefix: String, sample_fraction: Option<f64>) -> Self {
+        StorageReport {
+            prefix,
+            estimate: sample_fraction.is_some(),
+            sample_fraction,
+            keys_scanned: 0,
+            families: BTreeMap::new(),
+            total: FamilyUsage::default(),
+            continuation: None,
+        }
+    }
+
+    fn add(&mut self, key: String, bytes: u64, top: usize) {
+        self.total.count += 1;
+        self.total.bytes += bytes;
+        self.families
+            .entry(KeyFamily::of_key(&key).name())
+            .or_insert_with(FamilyUsage::default)
+            .add(key, bytes, top);
+    }
+
+    /// Scales the measured sample up to the estimate for all the keys
+    fn extrapolate(&mut self) {
+        if let Some(fraction) = self.sample_fraction {
+            let scale = |value: u64| (value as f64 / fraction).round() as u64;
+            for usage in self.families.values_mut().chain(Some(&mut self.total)) {
+                usage.count = scale(usage.count);
+                usage.bytes = scale(usage.bytes);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReportOptions {
+    /// Keys listed per call to the store
+    pub page_size: usize,
+    /// Stop after this many keys, so that a scan can be split over several runs
+    pub max_keys: Option<u64>,
+    /// Fetches of blobs whose size the store doesn't know, at once
+    pub concurrency: usize,
+    /// Only measure this fraction of the keys
+    pub sample_fraction: Option<f64>,
+    /// Number of largest blobs to report per family
+    pub top: usize,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        ReportOptions {
+            page_size: DEFAULT_PAGE_SIZE,
+            max_keys: None,
+            concurrency: DEFAULT_CONCURRENCY,
+            sample_fraction: None,
+            top: 0,
+        }
+    }
+}
+
+/// Whether `key` is in the sample. This only depends on the key, so that a scan split over
+/// several runs measures the same keys as a single run.
+fn is_sampled(key: &str, sample_fraction: Option<f64>) -> bool {
+    match sample_fraction {
+        Some(fraction) => {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            ((hasher.finish() % 1_000_000) as f64) < fraction * 1_000_000.0
+        }
+        None => true,
+    }
+}
+
+/// Scans the keys of `store` that start with `prefix`, from `continuation` on. Sizes come from
+/// the store if it knows them, otherwise the blobs are fetched from `blobstore`.
+pub fn storage_report(
+    store: Arc<BlobstoreEnumerate>,
+    blobstore: Arc<Blobstore>,
+    prefix: RepoPrefix,
+    continuation: Option<String>,
+    options: ReportOptions,
+) -> BoxFuture<StorageReport, Error> {
+    let report = StorageReport::new(prefix.to_string(), options.sample_fraction);
+
+    future::loop_fn((report, continuation), move |(report, continuation)| {
+        let limit = match options.max_keys {
+            Some(max_keys) => ::std::cmp::min(
+                options.page_size as u64,
+                max_keys.saturating_sub(report.keys_scanned),
+            ) as usize,
+            None => options.page_size,
+        };
+        cloned!(blobstore, options);
+        store
+            .enumerate(prefix.to_string(), continuation, limit)
+            .and_then(move |page| {
+                let mut report = report;
+                report.keys_scanned += page.entries.len() as u64;
+
+                let sizes = page.entries
+                    .into_iter()
+                    .filter(|entry| is_sampled(&entry.key, options.sample_fraction))
+                    .map(move |BlobstoreKeyEntry { key, size }| match size {
+                        Some(size) => future::ok((key, size)).left_future(),
+                        None => blobstore
+                            .get(key.clone())
+                            .and_then(move |value| match value {
+                                Some(value) => Ok((key, value.len() as u64)),
+                                None => Err(format_err!("blob {} disappeared", key)),
+                            })
+                            .right_future(),
+                    });
+
+                let top = options.top;
+                let max_keys = options.max_keys;
+                stream::iter_ok::<_, Error>(sizes)
+                    .buffer_unordered(options.concurrency)
+                    .fold(report, move |mut report, (key, size)| {
+                        report.add(key, size, top);
+                        Ok::<_, Error>(report)
+                    })
+                    .map(move |report| {
+                        let done = match max_keys {
+                            Some(max_keys) => report.keys_scanned >= max_keys,
+                            None => false,
+                        };
+                        if done || page.continuation.is_none() {
+                            let mut report = report;
+                            report.continuation = page.continuation;
+                            Loop::Break(report)
+                        } else {
+                            Loop::Continue((report, page.continuation))
+                        }
+                    })
+            })
+    }).map(|mut report| {
+        report.extrapolate();
+        report
+    })
+        .boxify()
+}
+
+pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.about(
+        "reports how many blobs of each kind the repo has, and their size. Only works for repos \
+         whose blobstore can list its keys",
+    ).args_from_usage(
+            r#"
+            --top [N]                   'also list the N largest blobs of each kind'
+            --sample [FRACTION]         'only measure this fraction of the blobs, estimate the rest'
+            --continuation [KEY]        'resume a scan where an earlier run stopped'
+            --max-keys [N]              'stop after N keys, and print where to resume'
+            --concurrency [N]           'how many blobs to fetch at once to get their size'
+            "#,
+        )
+}
+
+pub fn parse_arg<'a, T>(args: &ArgMatches<'a>, name: &str) -> Result<Option<T>>
+where
+    T: ::std::str::FromStr,
+{
+    match args.value_of(name) {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(format_err!("invalid --{} {}", name, value)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn open_enumerable_blobstore<'a>(
+    matches: &ArgMatches<'a>,
+) -> Result<(Arc<BlobstoreEnumerate>, Arc<Blobstore>)> {
+    match matches.value_of("blobstore") {
+        Some("files") => {
+            let data_dir = matches
+                .value_of("data-dir")
+                .ok_or(format_err!("local data directory must be specified"))?;
+            let blobstore = Fileblob::open(Path::new(data_dir).join("blobs"))?;
+            Ok((Arc::new(blobstore.clone()), Arc::new(blobstore)))
+        }
+        Some(other) => Err(format_err!("{} blobstores can't list their keys", other)),
+        None => Err(format_err!("manifold blobstores can't list their keys")),
+    }
+}
+
+pub fn handle_command<'a>(
+    matches: &ArgMatches<'a>,
+    sub_m: &ArgMatches<'a>,
+    repo_id: RepositoryId,
+    _logger: Logger,
+) -> BoxFuture<(), Error> {
+    let (store, blobstore) = try_boxfuture!(open_enumerable_blobstore(matches));
+    let default = ReportOptions::default();
+    let sample_fraction: Option<f64> = try_boxfuture!(parse_arg(sub_m, "sample"));
+    if let Some(fraction) = sample_fraction {
+        if fraction <= 0.0 || fraction > 1.0 {
+            return future::err(format_err!("--sample must be in (0, 1]")).boxify();
+        }
+    }
+    let options = ReportOptions {
+        page_size: default.page_size,
+        max_keys: try_boxfuture!(parse_arg(sub_m, "max-keys")),
+        concurrency: try_boxfuture!(parse_arg(sub_m, "concurrency"))
+            .unwrap_or(default.concurrency),
+        sample_fraction,
+        top: try_boxfuture!(parse_arg(sub_m, "top")).unwrap_or(default.top),
+    };
+    let continuation = sub_m.value_of("continuation").map(|key| key.to_string());
+
+    storage_report(store, blobstore, repo_id.prefix(), continuation, options)
+        .and_then(|report| {
+            println!("{}", to_string_pretty(&report)?);
+            Ok(())
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use blobstore::{BlobstoreEnumeration, EagerMemblob};
+    use mononoke_types::BlobstoreBytes;
+
+    /// A store that doesn't know the size of its blobs
+    struct NoSizes(EagerMemblob);
+
+    impl BlobstoreEnumerate for NoSizes {
+        fn enumerate(
+            &self,
+            prefix: String,
+            continuation: Option<String>,
+            limit: usize,
+        ) -> BoxFuture<BlobstoreEnumeration, Error> {
+            self.0
+                .enumerate(prefix, continuation, limit)
+                .map(|mut page| {
+                    for entry in page.entries.iter_mut() {
+                        entry.size = None;
+                    }
+                    page
+                })
+                .boxify()
+        }
+    }
+
+    const PREFIX: &str = "repo0000.";
+
+    const BLOBS: &[(&str, usize)] = &[
+        ("repo0000.hgchangeset.sha1.aa", 10),
+        ("repo0000.hgchangeset.sha1.bb", 20),
+        ("repo0000.hgmanifest.sha1.aa", 100),
+        ("repo0000.hgfilenode.sha1.aa", 5),
+        ("repo0000.changeset.blake2.aa", 30),
+        ("repo0000.content.blake2.aa", 1000),
+        ("repo0000.content.blake2.bb", 3000),
+        ("repo0000.content.blake2.cc", 2000),
+        ("repo0000.alias.sha256.aa", 64),
+        ("repo0000.bookmark_snapshot.index", 7),
+        ("repo0000.unknown", 1),
+        // Another repo
+        ("repo0001.content.blake2.aa", 50000),
+    ];
+
+    fn fixture() -> EagerMemblob {
+        let blobstore = EagerMemblob::new();
+        for &(key, size) in BLOBS {
+            blobstore
+                .put(key.to_string(), BlobstoreBytes::from_bytes(vec![0; size]))
+                .wait()
+                .unwrap();
+        }
+        blobstore
+    }
+
+    fn usage(count: u64, bytes: u64) -> FamilyUsage {
+        FamilyUsage {
+            count,
+            bytes,
+            largest: vec![],
+        }
+    }
+
+    fn run(
+        store: Arc<BlobstoreEnumerate>,
+        blobstore: EagerMemblob,
+        continuation: Option<String>,
+        options: ReportOptions,
+    ) -> StorageReport {
+        storage_report(
+            store,
+            Arc::new(blobstore),
+            RepoPrefix::new(0),
+            continuation,
+            options,
+        ).wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_key_family() {
+        assert_eq!(KeyFamily::of_key("repo0000.hgchangeset.sha1.aa"), KeyFamily::HgChangeset);
+        assert_eq!(KeyFamily::of_key("hgchangeset.sha1.aa"), KeyFamily::HgChangeset);
+        assert_eq!(KeyFamily::of_key("repo0000.changeset.blake2.aa"), KeyFamily::Changeset);
+        assert_eq!(KeyFamily::of_key("repo0000.alias.sha256.aa"), KeyFamily::Alias);
+        assert_eq!(KeyFamily::of_key("repo0000.something"), KeyFamily::Other);
+        assert_eq!(KeyFamily::Other.name(), "other");
+        assert_eq!(KeyFamily::HgManifest.decoder(), Some("manifest"));
+        assert_eq!(KeyFamily::Alias.decoder(), None);
+    }
+
+    #[test]
+    fn test_storage_report() {
+        async_unit::tokio_unit_test(|| {
+            let blobstore = fixture();
+            let stores: Vec<Arc<BlobstoreEnumerate>> = vec![
+                Arc::new(blobstore.clone()),
+                Arc::new(NoSizes(blobstore.clone())),
+            ];
+            for store in stores {
+                let options = ReportOptions {
+                    page_size: 3,
+                    ..Default::default()
+                };
+                let report = run(store, blobstore.clone(), None, options);
+
+                assert!(!report.estimate);
+                assert_eq!(report.keys_scanned, 11);
+                assert_eq!(report.continuation, None);
+                assert_eq!(report.total, usage(11, 6237));
+                let expected = btreemap! {
+                    "hg_changesets" => usage(2, 30),
+                    "hg_manifests" => usage(1, 100),
+                    "hg_filenodes" => usage(1, 5),
+                    "bonsai_changesets" => usage(1, 30),
+                    "file_contents" => usage(3, 6000),
+                    "content_aliases" => usage(1, 64),
+                    "bookmark_snapshots" => usage(1, 7),
+                    "other" => usage(1, 1),
+                };
+                assert_eq!(report.families, expected);
+            }
+        });
+    }
+
+    #[test]
+    fn test_storage_report_top() {
+        async_unit::tokio_unit_test(|| {
+            let blobstore = fixture();
+            let options = ReportOptions {
+                top: 2,
+                ..Default::default()
+            };
+            let report = run(Arc::new(blobstore.clone()), blobstore, None, options);
+
+            let largest = |key: &str, bytes| LargeBlob {
+                key: key.to_string(),
+                bytes,
+            };
+            assert_eq!(
+                report.families["file_contents"].largest,
+                vec![
+                    largest("repo0000.content.blake2.bb", 3000),
+                    largest("repo0000.content.blake2.cc", 2000),
+                ]
+            );
+            assert_eq!(
+                report.families["hg_manifests"].largest,
+                vec![largest("repo0000.hgmanifest.sha1.aa", 100)]
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_report_resume() {
+        async_unit::tokio_unit_test(|| {
+            let blobstore = fixture();
+            let options = ReportOptions {
+                page_size: 2,
+                max_keys: Some(5),
+                ..Default::default()
+            };
+
+            let first = run(
+                Arc::new(blobstore.clone()),
+                blobstore.clone(),
+                None,
+                options.clone(),
+            );
+            assert_eq!(first.keys_scanned, 5);
+            assert!(first.continuation.is_some());
+
+            let second = run(
+                Arc::new(blobstore.clone()),
+                blobstore.clone(),
+                first.continuation.clone(),
+                options.clone(),
+            );
+            assert_eq!(second.keys_scanned, 5);
+
+            let third = run(
+                Arc::new(blobstore.clone()),
+                blobstore,
+                second.continuation.clone(),
+                options,
+            );
+            assert_eq!(third.keys_scanned, 1);
+            assert_eq!(third.continuation, None);
+
+            let runs = vec![first, second, third];
+            assert_eq!(runs.iter().map(|run| run.total.count).sum::<u64>(), 11);
+            assert_eq!(runs.iter().map(|run| run.total.bytes).sum::<u64>(), 6237);
+        });
+    }
+
+    #[test]
+    fn test_storage_report_sample() {
+        async_unit::tokio_unit_test(|| {
+            let blobstore = fixture();
+
+            // Sampling everything measures everything, but is still labeled as an estimate
+            let options = ReportOptions {
+                sample_fraction: Some(1.0),
+                ..Default::default()
+            };
+            let report = run(Arc::new(blobstore.clone()), blobstore.clone(), None, options);
+            assert!(report.estimate);
+            assert_eq!(report.sample_fraction, Some(1.0));
+            assert_eq!(report.total, usage(11, 6237));
+
+            let options = ReportOptions {
+                sample_fraction: Some(0.5),
+                ..Default::default()
+            };
+            let report = run(Arc::new(blobstore.clone()), blobstore, None, options);
+            assert!(report.estimate);
+            assert_eq!(report.keys_scanned, 11);
+            // Counts and sizes are scaled up from the sample
+            let sampled: Vec<_> = BLOBS
+                .iter()
+                .filter(|(key, _)| key.starts_with(PREFIX) && is_sampled(key, Some(0.5)))
+                .collect();
+            let bytes: usize = sampled.iter().map(|(_, size)| size).sum();
+            assert_eq!(report.total, usage(2 * sampled.len() as u64, 2 * bytes as u64));
+        });
+    }
+}
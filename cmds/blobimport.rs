@@ -40,8 +40,18 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
             <INPUT>                         'input revlog repo'
             --changeset [HASH]              'if provided, the only changeset to be imported'
             --no-bookmark                   'if provided won't update bookmarks'
+            --strict-bookmarks              'fail if a transaction of bookmarks fails'
+            --path-violations-as-warnings   'log paths that break the path rules instead of failing'
+            --live-repo                     'import into a serving repo without clobbering pushes'
+            --collect-metrics               'log what creating the changesets took'
         "#,
         )
+        .arg(
+            Arg::from_usage(
+                "--allow-new-roots 'allow a live import to start history that isn't connected to \
+                 the history of the repo'",
+            ).requires("live-repo"),
+        )
         .arg(
             Arg::from_usage("--skip [SKIP]  'skips commits from the beginning'")
                 .conflicts_with("changeset"),
@@ -85,6 +95,11 @@ fn main() -> Result<()> {
     };
 
     let no_bookmark = matches.is_present("no-bookmark");
+    let strict_bookmarks = matches.is_present("strict-bookmarks");
+    let path_violations_are_warnings = matches.is_present("path-violations-as-warnings");
+    let live_repo = matches.is_present("live-repo");
+    let allow_new_roots = matches.is_present("allow-new-roots");
+    let collect_metrics = matches.is_present("collect-metrics");
 
     let blobimport = Blobimport {
         logger: logger.clone(),
@@ -94,6 +109,11 @@ fn main() -> Result<()> {
         skip,
         commits_limit,
         no_bookmark,
+        strict_bookmarks,
+        path_violations_are_warnings,
+        live_repo,
+        allow_new_roots,
+        collect_metrics,
     }.import()
         .map_err(move |err| {
             error!(logger, "error while blobimporting"; SlogKVError(err));
@@ -0,0 +1,96 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! mononoke_loadgen: generates pull and push load on a Mononoke server, e.g. a staging host,
+//! as described by a scenario file, and writes a JSON report of the latencies and errors of each
+//! command. See `loadgen::Scenario` for the format of the scenario.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate loadgen;
+extern crate mononoke_test_server;
+extern crate serde_json;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio;
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::ToSocketAddrs;
+
+use clap::{App, ArgMatches};
+use failure::Result;
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio::runtime::Runtime;
+
+use loadgen::Scenario;
+use mononoke_test_server::{ClientTls, TestClient};
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("mononoke_loadgen")
+        .version("0.0.0")
+        .about("Generate pull and push load on a Mononoke server")
+        .args_from_usage(
+            r#"
+            <SCENARIO>                      'scenario to run, in TOML'
+            --endpoint <ENDPOINT>           'address of the server, as host:port'
+            --repo <REPO_NAME>              'repo to pull from and push to'
+            --cert <PATH>                   'client certificate'
+            --private-key <PATH>            'private key of the client certificate'
+            --ca-pem <PATH>                 'certificate of the CA of the server'
+            --common-name [NAME]            'name in the certificate of the server [default: host]'
+            --report [PATH]                 'file to write the JSON report to, stdout by default'
+            -d, --debug                     'log every failed request'
+        "#,
+        )
+}
+
+fn get_client<'a>(matches: &ArgMatches<'a>) -> Result<TestClient> {
+    let endpoint = matches.value_of("endpoint").unwrap();
+    let addr = match endpoint.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => bail_msg!("{} did not resolve to any address", endpoint),
+    };
+    let host = endpoint.rsplitn(2, ':').last().unwrap_or(endpoint);
+    let tls = ClientTls {
+        cert: matches.value_of("cert").unwrap().to_string(),
+        private_key: matches.value_of("private-key").unwrap().to_string(),
+        ca_pem: matches.value_of("ca-pem").unwrap().to_string(),
+        common_name: matches.value_of("common-name").unwrap_or(host).to_string(),
+    };
+    TestClient::with_tls(addr, matches.value_of("repo").unwrap(), &tls)
+}
+
+fn main() -> Result<()> {
+    let matches = setup_app().get_matches();
+    let logger = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+        let drain = glog_drain().filter_level(level).fuse();
+        Logger::root(drain, o![])
+    };
+
+    let scenario = Scenario::from_file(matches.value_of("SCENARIO").unwrap())?;
+    let client = get_client(&matches)?;
+
+    let mut runtime = Runtime::new()?;
+    let report = runtime.block_on(loadgen::run(scenario, client, logger))?;
+    let report = serde_json::to_string_pretty(&report)?;
+
+    match matches.value_of("report") {
+        Some(path) => fs::write(path, report)?,
+        None => writeln!(io::stdout(), "{}", report)?,
+    }
+    Ok(())
+}
@@ -39,7 +39,7 @@ extern crate fixtures;
 #[cfg(test)]
 extern crate tempdir;
 
-use blobrepo::{BlobRepo, ManifoldArgs};
+use blobrepo::{default_blobstore_retry_policy, default_sql_retry_policy, BlobRepo, ManifoldArgs};
 use bookmarks::Bookmark;
 use clap::{App, ArgMatches};
 use failure::{Error, Result};
@@ -109,11 +109,8 @@ fn run_hook(
     println!("Hook file is {} revision is {:?}", hook_file, revstr);
     println!("Hook code is {}", code);
     println!("==============================");
-    let mut hook_manager = HookManager::new_with_blobrepo(repo.clone(), logger);
-    let hook = LuaHook {
-        name: String::from("testhook"),
-        code,
-    };
+    let mut hook_manager = HookManager::new_with_blobrepo(repo.clone(), None, logger);
+    let hook = LuaHook::new(String::from("testhook"), code);
     if file_hook {
         hook_manager.register_file_hook("testhook", Arc::new(hook), None);
     } else {
@@ -124,7 +121,7 @@ fn run_hook(
     let id = try_boxfuture!(HgChangesetId::from_str(revstr));
     if file_hook {
         hook_manager
-            .run_file_hooks_for_bookmark(id, &bookmark, None)
+            .run_file_hooks_for_bookmark(id, &bookmark, None, None)
             .map(|executions| {
                 for execution in executions.iter() {
                     if let (_, HookExecution::Rejected(_)) = execution {
@@ -137,7 +134,7 @@ fn run_hook(
             .boxify()
     } else {
         hook_manager
-            .run_changeset_hooks_for_bookmark(id, &bookmark, None)
+            .run_changeset_hooks_for_bookmark(id, &bookmark, None, None)
             .map(|executions| executions.get(0).unwrap().1.clone())
             .boxify()
     }
@@ -162,6 +159,9 @@ fn create_blobrepo(logger: &Logger, matches: &ArgMatches) -> BlobRepo {
             bucket: bucket.to_string(),
             prefix: prefix.to_string(),
             db_address: xdb_tier.to_string(),
+            store_db_addresses: Default::default(),
+            blobstore_retry: default_blobstore_retry_policy(),
+            sql_retry: default_sql_retry_policy(),
         },
         RepositoryId::new(0),
         myrouter_port,
@@ -177,6 +177,9 @@ fn main() -> Result<()> {
             Ok(HookExecution::Rejected(rejection_info)) => {
                 println!("Hook rejected the changeset {}", rejection_info.description)
             }
+            Ok(HookExecution::Warned(rejection_info)) => {
+                println!("Hook warned about the changeset {}", rejection_info.description)
+            }
             Err(e) => println!("Failed to run hook {:?}", e),
         }
         Ok(())
@@ -213,7 +216,7 @@ mod test {
             let changeset_id = String::from("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536");
             match test_hook(code, changeset_id, true /* file hook */) {
                 Ok(HookExecution::Accepted) => (),
-                Ok(HookExecution::Rejected(_)) => assert!(false, "Hook should be accepted"),
+                Ok(_) => assert!(false, "Hook should be accepted"),
                 Err(e) => assert!(false, format!("Unexpected error {:?}", e)),
             }
         });
@@ -231,7 +234,7 @@ mod test {
             let changeset_id = String::from("79a13814c5ce7330173ec04d279bf95ab3f652fb");
             match test_hook(code, changeset_id, false) {
                 Ok(HookExecution::Accepted) => (),
-                Ok(HookExecution::Rejected(_)) => assert!(false, "Hook should be accepted"),
+                Ok(_) => assert!(false, "Hook should be accepted"),
                 Err(e) => assert!(false, format!("Unexpected error {:?}", e)),
             }
         });
@@ -247,7 +250,7 @@ mod test {
             let changeset_id = String::from("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
             match test_hook(code, changeset_id, file) {
                 Ok(HookExecution::Accepted) => (),
-                Ok(HookExecution::Rejected(_)) => assert!(false, "Hook should be accepted"),
+                Ok(_) => assert!(false, "Hook should be accepted"),
                 Err(e) => assert!(false, format!("Unexpected error {:?}", e)),
             }
         });
@@ -278,10 +281,10 @@ mod test {
             let changeset_id = String::from("2f866e7e549760934e31bf0420a873f65100ad63");
             let res = test_hook_with_repo(code, changeset_id, true, test_many_files_dirs);
             match res {
-                Ok(HookExecution::Accepted) => assert!(false, "Hook should be rejected"),
                 Ok(HookExecution::Rejected(rejection_info)) => {
                     assert!(rejection_info.description.starts_with("sausages"))
                 }
+                Ok(_) => assert!(false, "Hook should be rejected"),
                 Err(e) => assert!(false, format!("Unexpected error {:?}", e)),
             }
         });
@@ -296,10 +299,10 @@ mod test {
             );
             let changeset_id = String::from("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
             match test_hook(code, changeset_id, file) {
-                Ok(HookExecution::Accepted) => assert!(false, "Hook should be rejected"),
                 Ok(HookExecution::Rejected(rejection_info)) => {
                     assert!(rejection_info.description.starts_with("sausages"))
                 }
+                Ok(_) => assert!(false, "Hook should be rejected"),
                 Err(e) => assert!(false, format!("Unexpected error {:?}", e)),
             }
         });
@@ -15,6 +15,7 @@ extern crate heapsize;
 extern crate tokio;
 
 extern crate db;
+extern crate fd_accounting;
 extern crate lazy_static;
 
 use std::result;
@@ -22,10 +23,35 @@ use std::sync::{Arc, Mutex, MutexGuard};
 
 use diesel::{Connection, MysqlConnection, SqliteConnection};
 use diesel::connection::SimpleConnection;
-use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PoolError, PooledConnection};
 use failure::{Error, Result};
 
 use db::{get_connection_params, ConnectionParams, InstanceRequirement, ProxyRequirement};
+use fd_accounting::FdComponent;
+
+/// MySQL reports these conditions as generic database errors, so they can only be recognized by
+/// the error message
+const TRANSIENT_MYSQL_ERRORS: &[&str] = &[
+    "Deadlock found",
+    "Lock wait timeout exceeded",
+    "MySQL server has gone away",
+    "Lost connection to MySQL server",
+];
+
+/// Returns true if `err` was caused by a temporary condition of the database, so that running
+/// the same query again may succeed: failing to get a connection from the pool, a deadlock, a
+/// lock wait timeout or a dropped connection.
+pub fn is_transient_sql_error(err: &Error) -> bool {
+    err.iter_chain().any(|cause| {
+        if cause.downcast_ref::<PoolError>().is_some() {
+            return true;
+        }
+        let msg = cause.to_string();
+        TRANSIENT_MYSQL_ERRORS
+            .iter()
+            .any(|transient| msg.contains(transient))
+    })
+}
 
 #[derive(Clone)]
 pub struct SqliteConnInner {
@@ -84,6 +110,22 @@ impl SqliteConnInner {
     }
 }
 
+/// Accounts for the connections of a pool as backend connections, from when they are established
+/// until they are torn down
+#[derive(Debug)]
+struct FdAccountingCustomizer;
+
+impl<C, E> CustomizeConnection<C, E> for FdAccountingCustomizer {
+    fn on_acquire(&self, _conn: &mut C) -> result::Result<(), E> {
+        fd_accounting::fd_registry().opened(FdComponent::BackendConnections);
+        Ok(())
+    }
+
+    fn on_release(&self, _conn: C) {
+        fd_accounting::fd_registry().closed(FdComponent::BackendConnections);
+    }
+}
+
 #[derive(Clone)]
 pub struct MysqlConnInner {
     pool: Pool<ConnectionManager<MysqlConnection>>,
@@ -119,10 +161,12 @@ impl MysqlConnInner {
         let pool = Pool::builder()
             .max_size(10)
             .min_idle(Some(1))
+            .connection_customizer(Box::new(FdAccountingCustomizer))
             .build(ConnectionManager::new(local_url.clone()))?;
         let master_pool = Pool::builder()
             .max_size(1)
             .min_idle(Some(1))
+            .connection_customizer(Box::new(FdAccountingCustomizer))
             .build(ConnectionManager::new(master_url.clone()))?;
         Ok(Self { pool, master_pool })
     }
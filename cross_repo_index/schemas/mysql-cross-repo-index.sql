@@ -0,0 +1,5 @@
+CREATE TABLE cross_repo_index (
+  hg_cs_id BINARY(20) NOT NULL,
+  repo_id INTEGER NOT NULL,
+  PRIMARY KEY (hg_cs_id, repo_id)
+);
@@ -0,0 +1,181 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Index of the repos that contain a changeset.
+//!
+//! Some changesets are mirrored between repos, and finding which repos have one shouldn't need a
+//! query to every repo. Repos that opt in record their new changesets in a table that they all
+//! share, keyed by hg changeset id. The index is best-effort: a changeset whose recording failed
+//! is missing until the history of its repo is backfilled, so a miss doesn't prove that no repo
+//! has the changeset.
+
+#![deny(warnings)]
+// FIXME T34253207, remove when https://github.com/diesel-rs/diesel/issues/1785 fixed
+#![allow(proc_macro_derive_resolution_fallback)]
+#![feature(never_type)]
+
+extern crate db_conn;
+#[macro_use]
+extern crate diesel;
+extern crate failure_ext as failure;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate stats;
+
+extern crate futures_ext;
+extern crate mercurial_types;
+
+use std::result;
+use std::sync::MutexGuard;
+
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{replace_into, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use failure::{Error, Result};
+
+use futures_ext::{asynchronize, BoxFuture, FutureExt};
+use mercurial_types::{HgChangesetId, RepositoryId};
+use stats::Timeseries;
+
+mod models;
+mod schema;
+
+use models::CrossRepoIndexRow;
+use schema::cross_repo_index;
+
+define_stats! {
+    prefix = "mononoke.cross_repo_index";
+    adds: timeseries(RATE, SUM),
+    added_changesets: timeseries(RATE, SUM),
+    finds: timeseries(RATE, SUM),
+}
+
+pub trait CrossRepoIndex: Send + Sync {
+    /// Records that the repo contains `changesets`. Recording a changeset again is a no-op.
+    fn add(&self, repo_id: RepositoryId, changesets: Vec<HgChangesetId>) -> BoxFuture<(), Error>;
+
+    /// Repos that contain `changeset`, by ascending id
+    fn find(&self, changeset: HgChangesetId) -> BoxFuture<Vec<RepositoryId>, Error>;
+}
+
+#[derive(Clone)]
+pub struct SqliteCrossRepoIndex {
+    inner: SqliteConnInner,
+}
+
+impl SqliteCrossRepoIndex {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-cross-repo-index.sql")
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlCrossRepoIndex {
+    inner: MysqlConnInner,
+}
+
+impl MysqlCrossRepoIndex {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-cross-repo-index.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+/// See https://github.com/diesel-rs/diesel/issues/882#issuecomment-300257476
+macro_rules! impl_cross_repo_index {
+    ($struct:ty) => {
+        impl CrossRepoIndex for $struct {
+            fn add(
+                &self,
+                repo_id: RepositoryId,
+                changesets: Vec<HgChangesetId>,
+            ) -> BoxFuture<(), Error> {
+                STATS::adds.add_value(1);
+                STATS::added_changesets.add_value(changesets.len() as i64);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    if changesets.is_empty() {
+                        return Ok(());
+                    }
+                    let rows: Vec<_> = changesets
+                        .into_iter()
+                        .map(|hg_cs_id| CrossRepoIndexRow { hg_cs_id, repo_id })
+                        .collect();
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    replace_into(cross_repo_index::table)
+                        .values(&rows)
+                        .execute(&*connection)?;
+                    Ok(())
+                }).boxify()
+            }
+
+            fn find(&self, changeset: HgChangesetId) -> BoxFuture<Vec<RepositoryId>, Error> {
+                STATS::finds.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    let repo_ids = cross_repo_index::table
+                        .filter(cross_repo_index::hg_cs_id.eq(changeset))
+                        .select(cross_repo_index::repo_id)
+                        .order(cross_repo_index::repo_id.asc())
+                        .load::<RepositoryId>(&*connection)?;
+                    Ok(repo_ids)
+                }).boxify()
+            }
+        }
+    };
+}
+
+impl_cross_repo_index!(SqliteCrossRepoIndex);
+impl_cross_repo_index!(MysqlCrossRepoIndex);
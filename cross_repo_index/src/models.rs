@@ -0,0 +1,17 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::{HgChangesetId, RepositoryId};
+
+use schema::cross_repo_index;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "cross_repo_index"]
+pub(crate) struct CrossRepoIndexRow {
+    pub hg_cs_id: HgChangesetId,
+    pub repo_id: RepositoryId,
+}
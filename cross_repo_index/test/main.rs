@@ -0,0 +1,100 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the cross-repo index.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate futures;
+
+extern crate cross_repo_index;
+extern crate mercurial_types_mocks;
+
+use futures::Future;
+
+use cross_repo_index::{CrossRepoIndex, MysqlCrossRepoIndex, SqliteCrossRepoIndex};
+use mercurial_types_mocks::nodehash::{ONES_CSID, THREES_CSID, TWOS_CSID};
+use mercurial_types_mocks::repo::{REPO_ONE, REPO_TWO, REPO_ZERO};
+
+fn add_and_find<I: CrossRepoIndex>(index: I) {
+    index
+        .add(REPO_TWO, vec![ONES_CSID, TWOS_CSID])
+        .wait()
+        .expect("Adding changesets failed");
+    index
+        .add(REPO_ZERO, vec![ONES_CSID])
+        .wait()
+        .expect("Adding changesets failed");
+
+    let repos = index.find(ONES_CSID).wait().expect("Finding changeset failed");
+    assert_eq!(repos, vec![REPO_ZERO, REPO_TWO]);
+    let repos = index.find(TWOS_CSID).wait().expect("Finding changeset failed");
+    assert_eq!(repos, vec![REPO_TWO]);
+    let repos = index.find(THREES_CSID).wait().expect("Finding changeset failed");
+    assert!(repos.is_empty());
+}
+
+fn add_twice<I: CrossRepoIndex>(index: I) {
+    index
+        .add(REPO_ONE, vec![ONES_CSID, ONES_CSID])
+        .wait()
+        .expect("Adding changesets failed");
+    index
+        .add(REPO_ONE, vec![ONES_CSID])
+        .wait()
+        .expect("Adding changesets failed");
+    index
+        .add(REPO_ONE, vec![])
+        .wait()
+        .expect("Adding no changesets failed");
+
+    let repos = index.find(ONES_CSID).wait().expect("Finding changeset failed");
+    assert_eq!(repos, vec![REPO_ONE]);
+}
+
+macro_rules! cross_repo_index_test_impl {
+    ($mod_name:ident =>  { new: $new_cb:expr, }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_add_and_find() {
+                async_unit::tokio_unit_test(|| {
+                    add_and_find($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_add_twice() {
+                async_unit::tokio_unit_test(|| {
+                    add_twice($new_cb());
+                });
+            }
+        }
+    };
+}
+
+cross_repo_index_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+cross_repo_index_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+fn new_sqlite() -> SqliteCrossRepoIndex {
+    SqliteCrossRepoIndex::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_mysql() -> MysqlCrossRepoIndex {
+    MysqlCrossRepoIndex::create_test_db("cross_repo_index_test")
+        .expect("Failed to create test database")
+}
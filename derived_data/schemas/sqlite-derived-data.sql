@@ -0,0 +1,9 @@
+CREATE TABLE derived_data_status (
+  repo_id INTEGER NOT NULL,
+  hg_cs_id BINARY(20) NOT NULL,
+  data_type VARCHAR(64) NOT NULL,
+  state VARCHAR(16) NOT NULL,
+  attempts INTEGER NOT NULL,
+  updated_at BIGINT NOT NULL,
+  PRIMARY KEY (repo_id, hg_cs_id, data_type)
+);
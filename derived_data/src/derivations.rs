@@ -0,0 +1,150 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The types of data that can be derived from a changeset
+
+use std::str;
+use std::sync::Arc;
+
+use blobrepo::{compute_changed_files, BlobRepo, HgBlobChangeset};
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::{Future, Stream};
+use futures::future::join_all;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::{Changeset, HgChangesetId, MPath, Manifest, Type};
+use mercurial_types::manifest_utils::PathFilter;
+
+pub trait Derivation: Send + Sync {
+    /// Name of the type of data, as in the `derived_data` section of the repo config
+    fn name(&self) -> &'static str;
+
+    /// Derives the data of `changeset`, serialized as it is stored
+    fn derive(&self, repo: &BlobRepo, changeset: HgChangesetId) -> BoxFuture<Bytes, Error>;
+}
+
+/// Returns the derivation of the type of data called `name`
+pub fn derivation_by_name(name: &str) -> Result<Arc<Derivation>> {
+    match name {
+        "changed_files" => Ok(Arc::new(ChangedFiles)),
+        "manifest_stats" => Ok(Arc::new(ManifestStats)),
+        _ => bail_msg!("unknown type of derived data {}", name),
+    }
+}
+
+/// Files that a changeset changed compared to its parents, as in the file list of a Mercurial
+/// changeset. Stored as one path per line.
+pub struct ChangedFiles;
+
+impl ChangedFiles {
+    pub fn parse(data: &Bytes) -> Result<Vec<MPath>> {
+        data.split(|c| *c == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(MPath::new)
+            .collect()
+    }
+}
+
+impl Derivation for ChangedFiles {
+    fn name(&self) -> &'static str {
+        "changed_files"
+    }
+
+    fn derive(&self, repo: &BlobRepo, changeset: HgChangesetId) -> BoxFuture<Bytes, Error> {
+        load_changeset(repo, changeset)
+            .and_then({
+                cloned!(repo);
+                move |(cs, root)| {
+                    let parents = cs.p1()
+                        .into_iter()
+                        .chain(cs.p2())
+                        .map(|parent| load_changeset(&repo, HgChangesetId::new(*parent)));
+                    join_all(parents).map(move |parents| (root, parents))
+                }
+            })
+            .and_then(|(root, parents)| {
+                let mut parents = parents.into_iter().map(|(_, manifest)| manifest);
+                let p1 = parents.next();
+                let p2 = parents.next();
+                compute_changed_files(&root, p1.as_ref(), p2.as_ref())
+            })
+            .map(|files| {
+                let mut data = Vec::new();
+                for file in files {
+                    data.extend(file.to_vec());
+                    data.push(b'\n');
+                }
+                Bytes::from(data)
+            })
+            .boxify()
+    }
+}
+
+/// Number of files and directories in the working copy of a changeset
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TreeStats {
+    pub files: u64,
+    pub directories: u64,
+}
+
+/// `TreeStats` of a changeset, stored as `files <n>` and `directories <n>` lines
+pub struct ManifestStats;
+
+impl ManifestStats {
+    pub fn parse(data: &Bytes) -> Result<TreeStats> {
+        let mut stats = TreeStats::default();
+        for line in str::from_utf8(data)?.lines() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("files"), Some(n)) => stats.files = n.parse()?,
+                (Some("directories"), Some(n)) => stats.directories = n.parse()?,
+                _ => bail_msg!("invalid manifest stats line {:?}", line),
+            }
+        }
+        Ok(stats)
+    }
+}
+
+impl Derivation for ManifestStats {
+    fn name(&self) -> &'static str {
+        "manifest_stats"
+    }
+
+    fn derive(&self, repo: &BlobRepo, changeset: HgChangesetId) -> BoxFuture<Bytes, Error> {
+        cloned!(repo);
+        repo.get_changeset_by_changesetid(&changeset)
+            .and_then(move |cs| {
+                repo.walk_manifest(cs.manifestid(), PathFilter::all())
+                    .fold(TreeStats::default(), |mut stats, (_, entry)| {
+                        match entry.get_type() {
+                            Type::Tree => stats.directories += 1,
+                            Type::File(_) => stats.files += 1,
+                        }
+                        Ok::<_, Error>(stats)
+                    })
+            })
+            .map(|stats| {
+                Bytes::from(format!(
+                    "files {}\ndirectories {}\n",
+                    stats.files, stats.directories
+                ))
+            })
+            .boxify()
+    }
+}
+
+fn load_changeset(
+    repo: &BlobRepo,
+    changeset: HgChangesetId,
+) -> BoxFuture<(HgBlobChangeset, Box<Manifest + Sync>), Error> {
+    cloned!(repo);
+    repo.get_changeset_by_changesetid(&changeset)
+        .and_then(move |cs| {
+            repo.get_manifest_by_nodeid(cs.manifestid())
+                .map(move |manifest| (cs, manifest))
+        })
+        .boxify()
+}
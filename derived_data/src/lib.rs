@@ -0,0 +1,366 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Data derived from the changesets of a repo, e.g. the files that a changeset changed.
+//!
+//! Deriving data is too slow to do on the first read of a changeset that just landed, so when a
+//! push moves a bookmark, its changesets are added to a `DerivationQueue`, which derives the
+//! configured types of data in the background and stores them in the blobstore of the repo.
+//!
+//! Every (changeset, type) that is queued has a record in `DerivedDataStatus`, which is pending
+//! until the data is stored. A queue that is opened again after a restart resumes the records
+//! that are still pending. Data that is read before the queue got to it is derived by the read,
+//! see `fetch_derived`.
+
+#![deny(warnings)]
+// FIXME T34253207, remove when https://github.com/diesel-rs/diesel/issues/1785 fixed
+#![allow(proc_macro_derive_resolution_fallback)]
+#![feature(never_type)]
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate bytes;
+#[macro_use]
+extern crate cloned;
+extern crate db_conn;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate stats;
+
+#[macro_use]
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate metaconfig;
+extern crate mononoke_types;
+
+use std::fmt;
+use std::result;
+use std::str::FromStr;
+use std::sync::MutexGuard;
+
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{insert_into, replace_into, Connection, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use failure::{Error, Result};
+
+use futures_ext::{asynchronize, BoxFuture, FutureExt};
+use mercurial_types::{HgChangesetId, RepositoryId};
+use stats::Timeseries;
+
+mod derivations;
+mod models;
+mod queue;
+mod schema;
+
+pub use derivations::{derivation_by_name, ChangedFiles, Derivation, ManifestStats, TreeStats};
+pub use queue::{derive_and_store, fetch_derived, DerivationQueue};
+
+use models::DerivationRow;
+use schema::derived_data_status;
+
+define_stats! {
+    prefix = "mononoke.derived_data";
+    add_pendings: timeseries(RATE, SUM),
+    sets: timeseries(RATE, SUM),
+    gets: timeseries(RATE, SUM),
+    list_pendings: timeseries(RATE, SUM),
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DerivationState {
+    /// Queued, or being derived
+    Pending,
+    /// Stored in the blobstore
+    Done,
+    /// Every attempt failed. The data is derived on its first read.
+    Failed,
+}
+
+impl DerivationState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DerivationState::Pending => "pending",
+            DerivationState::Done => "done",
+            DerivationState::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for DerivationState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(DerivationState::Pending),
+            "done" => Ok(DerivationState::Done),
+            "failed" => Ok(DerivationState::Failed),
+            _ => bail_msg!("unknown derivation state {}", s),
+        }
+    }
+}
+
+impl fmt::Display for DerivationState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Status of a type of data derived from a changeset
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DerivationRecord {
+    pub repo_id: RepositoryId,
+    pub changeset: HgChangesetId,
+    pub data_type: String,
+    pub state: DerivationState,
+    /// Number of attempts to derive the data in the background
+    pub attempts: usize,
+    /// Unix timestamp, in seconds, of the last change of state. For a pending record, that is
+    /// when it was queued.
+    pub updated_at: i64,
+}
+
+impl DerivationRecord {
+    fn into_row(self) -> DerivationRow {
+        DerivationRow {
+            repo_id: self.repo_id,
+            hg_cs_id: self.changeset,
+            data_type: self.data_type,
+            state: self.state.as_str().to_string(),
+            attempts: self.attempts as i32,
+            updated_at: self.updated_at,
+        }
+    }
+
+    fn from_row(row: DerivationRow) -> Result<Self> {
+        Ok(DerivationRecord {
+            repo_id: row.repo_id,
+            changeset: row.hg_cs_id,
+            data_type: row.data_type,
+            state: row.state.parse()?,
+            attempts: row.attempts as usize,
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+pub trait DerivedDataStatus: Send + Sync {
+    /// Adds a pending record of `data_type` for each of `changesets` that has no record of that
+    /// type yet. Records that already exist, whatever their state, are kept.
+    fn add_pending(
+        &self,
+        repo_id: RepositoryId,
+        data_type: String,
+        changesets: Vec<HgChangesetId>,
+        queued_at: i64,
+    ) -> BoxFuture<(), Error>;
+
+    /// Adds or replaces a record
+    fn set(&self, record: DerivationRecord) -> BoxFuture<(), Error>;
+
+    /// Records of every type of a changeset, sorted by type
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        changeset: HgChangesetId,
+    ) -> BoxFuture<Vec<DerivationRecord>, Error>;
+
+    /// Pending records of the repo, oldest first
+    fn list_pending(&self, repo_id: RepositoryId) -> BoxFuture<Vec<DerivationRecord>, Error>;
+}
+
+#[derive(Clone)]
+pub struct SqliteDerivedDataStatus {
+    inner: SqliteConnInner,
+}
+
+impl SqliteDerivedDataStatus {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-derived-data.sql")
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlDerivedDataStatus {
+    inner: MysqlConnInner,
+}
+
+impl MysqlDerivedDataStatus {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-derived-data.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+/// See https://github.com/diesel-rs/diesel/issues/882#issuecomment-300257476
+macro_rules! impl_derived_data_status {
+    ($struct:ty) => {
+        impl DerivedDataStatus for $struct {
+            fn add_pending(
+                &self,
+                repo_id: RepositoryId,
+                data_type: String,
+                changesets: Vec<HgChangesetId>,
+                queued_at: i64,
+            ) -> BoxFuture<(), Error> {
+                STATS::add_pendings.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    if changesets.is_empty() {
+                        return Ok(());
+                    }
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    connection.transaction::<_, Error, _>(|| {
+                        let existing = derived_data_status::table
+                            .filter(derived_data_status::repo_id.eq(repo_id))
+                            .filter(derived_data_status::data_type.eq(&data_type))
+                            .filter(derived_data_status::hg_cs_id.eq_any(&changesets))
+                            .select(derived_data_status::hg_cs_id)
+                            .load::<HgChangesetId>(&*connection)?;
+                        let rows: Vec<_> = changesets
+                            .iter()
+                            .filter(|cs| !existing.contains(cs))
+                            .map(|cs| {
+                                DerivationRecord {
+                                    repo_id,
+                                    changeset: *cs,
+                                    data_type: data_type.clone(),
+                                    state: DerivationState::Pending,
+                                    attempts: 0,
+                                    updated_at: queued_at,
+                                }.into_row()
+                            })
+                            .collect();
+                        if !rows.is_empty() {
+                            insert_into(derived_data_status::table)
+                                .values(&rows)
+                                .execute(&*connection)?;
+                        }
+                        Ok(())
+                    })
+                }).boxify()
+            }
+
+            fn set(&self, record: DerivationRecord) -> BoxFuture<(), Error> {
+                STATS::sets.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    replace_into(derived_data_status::table)
+                        .values(&record.into_row())
+                        .execute(&*connection)?;
+                    Ok(())
+                }).boxify()
+            }
+
+            fn get(
+                &self,
+                repo_id: RepositoryId,
+                changeset: HgChangesetId,
+            ) -> BoxFuture<Vec<DerivationRecord>, Error> {
+                STATS::gets.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    derived_data_status::table
+                        .filter(derived_data_status::repo_id.eq(repo_id))
+                        .filter(derived_data_status::hg_cs_id.eq(changeset))
+                        .order(derived_data_status::data_type.asc())
+                        .load::<DerivationRow>(&*connection)?
+                        .into_iter()
+                        .map(DerivationRecord::from_row)
+                        .collect()
+                }).boxify()
+            }
+
+            fn list_pending(
+                &self,
+                repo_id: RepositoryId,
+            ) -> BoxFuture<Vec<DerivationRecord>, Error> {
+                STATS::list_pendings.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    derived_data_status::table
+                        .filter(derived_data_status::repo_id.eq(repo_id))
+                        .filter(
+                            derived_data_status::state.eq(DerivationState::Pending.as_str()),
+                        )
+                        .order((
+                            derived_data_status::updated_at.asc(),
+                            derived_data_status::hg_cs_id.asc(),
+                            derived_data_status::data_type.asc(),
+                        ))
+                        .load::<DerivationRow>(&*connection)?
+                        .into_iter()
+                        .map(DerivationRecord::from_row)
+                        .collect()
+                }).boxify()
+            }
+        }
+    };
+}
+
+impl_derived_data_status!(SqliteDerivedDataStatus);
+impl_derived_data_status!(MysqlDerivedDataStatus);
@@ -0,0 +1,21 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::{HgChangesetId, RepositoryId};
+
+use schema::derived_data_status;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "derived_data_status"]
+pub(crate) struct DerivationRow {
+    pub repo_id: RepositoryId,
+    pub hg_cs_id: HgChangesetId,
+    pub data_type: String,
+    pub state: String,
+    pub attempts: i32,
+    pub updated_at: i64,
+}
@@ -0,0 +1,343 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Background derivation of the data of landed changesets, see the crate documentation
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::{Future, Stream};
+use futures::future::{join_all, ok};
+use futures::sync::mpsc;
+use futures_ext::{retry, BoxFuture, FutureExt, RetryPolicy};
+use mercurial_types::HgChangesetId;
+use metaconfig::DerivedDataParams;
+use mononoke_types::{BlobstoreBytes, DateTime};
+use slog::Logger;
+use stats::DynamicTimeseries;
+
+use derivations::{derivation_by_name, Derivation};
+use {DerivationRecord, DerivationState, DerivedDataStatus};
+
+define_stats! {
+    prefix = "mononoke.derived_data.queue";
+    depth: dynamic_timeseries("{}.depth", (data_type: &'static str); AVG, MAX),
+    lag_secs: dynamic_timeseries("{}.lag_secs", (data_type: &'static str); AVG, MAX),
+    derived: dynamic_timeseries("{}.derived", (data_type: &'static str); RATE, SUM),
+    failed: dynamic_timeseries("{}.failed", (data_type: &'static str); RATE, SUM),
+    derived_on_read: dynamic_timeseries("{}.derived_on_read", (data_type: &'static str); RATE, SUM),
+}
+
+/// Derived data of `changeset` is stored under this key in the blobstore of the repo
+fn blob_key(data_type: &str, changeset: HgChangesetId) -> String {
+    format!("derived.{}.{}", data_type, changeset)
+}
+
+fn retry_policy(max_attempts: usize) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(30),
+        jitter: true,
+    }
+}
+
+struct Task {
+    changeset: HgChangesetId,
+    derivation: Arc<Derivation>,
+    /// Unix timestamp, in seconds
+    queued_at: i64,
+}
+
+struct Inner {
+    repo: BlobRepo,
+    status: Arc<DerivedDataStatus>,
+    policy: RetryPolicy,
+    logger: Logger,
+    /// Changesets queued for each type of data, so that a changeset is not queued twice
+    queued: Mutex<HashMap<&'static str, HashSet<HgChangesetId>>>,
+}
+
+impl Inner {
+    /// Removes the task from `queued`, once it is done with
+    fn finish(&self, task: &Task) {
+        let name = task.derivation.name();
+        let mut queued = self.queued.lock().expect("lock poisoned");
+        if let Some(changesets) = queued.get_mut(name) {
+            changesets.remove(&task.changeset);
+            STATS::depth.add_value(changesets.len() as i64, (name,));
+        }
+    }
+}
+
+/// Derives the configured types of data of the changesets that land in a repo
+#[derive(Clone)]
+pub struct DerivationQueue {
+    inner: Arc<Inner>,
+    derivations: Vec<Arc<Derivation>>,
+    sender: mpsc::UnboundedSender<Task>,
+}
+
+impl DerivationQueue {
+    /// Opens the queue of `repo`. Returns the queue, and the task that derives the queued data
+    /// with up to `params.workers` derivations at a time, which ends once every clone of the queue
+    /// is dropped. The pending records of `status` are queued first, so that the derivations that
+    /// a restart interrupted resume.
+    pub fn open(
+        repo: BlobRepo,
+        status: Arc<DerivedDataStatus>,
+        params: &DerivedDataParams,
+        logger: Logger,
+    ) -> BoxFuture<(Self, BoxFuture<(), ()>), Error> {
+        let derivations: Vec<_> = try_boxfuture!(
+            params
+                .types
+                .iter()
+                .map(|name| derivation_by_name(name))
+                .collect::<Result<_>>()
+        );
+        let repo_id = repo.get_repoid();
+        let (sender, receiver) = mpsc::unbounded();
+        let queue = DerivationQueue {
+            inner: Arc::new(Inner {
+                repo,
+                status,
+                policy: retry_policy(params.max_attempts),
+                logger,
+                queued: Mutex::new(HashMap::new()),
+            }),
+            derivations,
+            sender,
+        };
+
+        let worker = {
+            let inner = queue.inner.clone();
+            receiver
+                .map(move |task| run_task(inner.clone(), task))
+                .buffer_unordered(params.workers)
+                .for_each(|()| Ok(()))
+                .boxify()
+        };
+
+        queue
+            .inner
+            .status
+            .list_pending(repo_id)
+            .map(move |pending| {
+                let mut resumed = 0;
+                for record in pending {
+                    let derivation = queue
+                        .derivations
+                        .iter()
+                        .find(|derivation| derivation.name() == record.data_type)
+                        .cloned();
+                    // Types that are no longer configured stay pending
+                    if let Some(derivation) = derivation {
+                        queue.push(record.changeset, derivation, record.updated_at);
+                        resumed += 1;
+                    }
+                }
+                if resumed > 0 {
+                    info!(queue.inner.logger, "resuming {} pending derivations", resumed);
+                }
+                (queue, worker)
+            })
+            .boxify()
+    }
+
+    /// Queues every configured type of data of `changesets`. The returned future resolves once
+    /// their records are pending, the data is derived later.
+    pub fn enqueue(&self, changesets: Vec<HgChangesetId>) -> BoxFuture<(), Error> {
+        let queued_at = DateTime::now().timestamp_secs();
+        let repo_id = self.inner.repo.get_repoid();
+        let adds: Vec<_> = self.derivations
+            .iter()
+            .map(|derivation| {
+                self.inner.status.add_pending(
+                    repo_id,
+                    derivation.name().to_string(),
+                    changesets.clone(),
+                    queued_at,
+                )
+            })
+            .collect();
+
+        let this = self.clone();
+        join_all(adds)
+            .map(move |_| {
+                for derivation in &this.derivations {
+                    for changeset in &changesets {
+                        this.push(*changeset, derivation.clone(), queued_at);
+                    }
+                }
+            })
+            .boxify()
+    }
+
+    /// Number of changesets queued for each type of data
+    pub fn depth(&self) -> HashMap<&'static str, usize> {
+        let queued = self.inner.queued.lock().expect("lock poisoned");
+        queued
+            .iter()
+            .map(|(name, changesets)| (*name, changesets.len()))
+            .collect()
+    }
+
+    fn push(&self, changeset: HgChangesetId, derivation: Arc<Derivation>, queued_at: i64) {
+        let name = derivation.name();
+        {
+            let mut queued = self.inner.queued.lock().expect("lock poisoned");
+            let changesets = queued.entry(name).or_insert_with(HashSet::new);
+            if !changesets.insert(changeset) {
+                return;
+            }
+            STATS::depth.add_value(changesets.len() as i64, (name,));
+        }
+        // This only fails if the worker was dropped, e.g. on shutdown. The record stays pending
+        // and is resumed by the next queue that is opened.
+        let _ = self.sender.unbounded_send(Task {
+            changeset,
+            derivation,
+            queued_at,
+        });
+    }
+}
+
+/// Derives the data of `task`, unless it is already done, and updates its record. Errors are
+/// only logged: a derivation that failed every attempt is marked failed, and if its record can't
+/// be updated, it stays pending and is resumed after a restart.
+fn run_task(inner: Arc<Inner>, task: Task) -> BoxFuture<(), ()> {
+    let name = task.derivation.name();
+    let changeset = task.changeset;
+    let repo_id = inner.repo.get_repoid();
+    let attempts = Arc::new(AtomicUsize::new(0));
+
+    inner
+        .status
+        .get(repo_id, changeset)
+        .and_then({
+            cloned!(inner, attempts);
+            let derivation = task.derivation.clone();
+            move |records| {
+                let done = records.iter().any(|record| {
+                    record.data_type == name && record.state == DerivationState::Done
+                });
+                if done {
+                    // Derived by a read, or queued again after it was derived
+                    return ok(()).left_future();
+                }
+
+                let repo = inner.repo.clone();
+                let derive = retry(inner.policy, |_: &Error| true, {
+                    cloned!(attempts);
+                    move || {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+                        derive_and_store(&repo, &*derivation, changeset)
+                    }
+                });
+                derive
+                    .then(move |res| {
+                        let state = match res {
+                            Ok(_) => {
+                                STATS::derived.add_value(1, (name,));
+                                DerivationState::Done
+                            }
+                            Err(err) => {
+                                STATS::failed.add_value(1, (name,));
+                                error!(
+                                    inner.logger,
+                                    "failed to derive {} of {}: {}", name, changeset, err
+                                );
+                                DerivationState::Failed
+                            }
+                        };
+                        inner.status.set(DerivationRecord {
+                            repo_id,
+                            changeset,
+                            data_type: name.to_string(),
+                            state,
+                            attempts: attempts.load(Ordering::SeqCst),
+                            updated_at: DateTime::now().timestamp_secs(),
+                        })
+                    })
+                    .right_future()
+            }
+        })
+        .then(move |res| {
+            match res {
+                Ok(()) => {
+                    let lag = DateTime::now().timestamp_secs() - task.queued_at;
+                    STATS::lag_secs.add_value(lag, (name,));
+                }
+                Err(err) => warn!(
+                    inner.logger,
+                    "failed to update the status of {} of {}: {}", name, changeset, err
+                ),
+            }
+            inner.finish(&task);
+            Ok(())
+        })
+        .boxify()
+}
+
+/// Derives the data of `changeset` and stores it in the blobstore of `repo`
+pub fn derive_and_store(
+    repo: &BlobRepo,
+    derivation: &Derivation,
+    changeset: HgChangesetId,
+) -> BoxFuture<Bytes, Error> {
+    let blobstore = repo.get_blobstore();
+    let key = blob_key(derivation.name(), changeset);
+    derivation
+        .derive(repo, changeset)
+        .and_then(move |data| {
+            blobstore
+                .put(key, BlobstoreBytes::from_bytes(data.clone()))
+                .map(move |()| data)
+        })
+        .boxify()
+}
+
+/// Returns the data of `changeset`. Data that is not derived yet is derived now, and its record
+/// is marked done, so that a queue that gets to it later doesn't derive it again.
+pub fn fetch_derived(
+    repo: &BlobRepo,
+    status: Arc<DerivedDataStatus>,
+    derivation: Arc<Derivation>,
+    changeset: HgChangesetId,
+) -> BoxFuture<Bytes, Error> {
+    let key = blob_key(derivation.name(), changeset);
+    cloned!(repo);
+    repo.get_blobstore()
+        .get(key)
+        .and_then(move |data| match data {
+            Some(data) => ok(data.into_bytes()).left_future(),
+            None => {
+                let name = derivation.name();
+                STATS::derived_on_read.add_value(1, (name,));
+                derive_and_store(&repo, &*derivation, changeset)
+                    .and_then(move |data| {
+                        let record = DerivationRecord {
+                            repo_id: repo.get_repoid(),
+                            changeset,
+                            data_type: name.to_string(),
+                            state: DerivationState::Done,
+                            attempts: 0,
+                            updated_at: DateTime::now().timestamp_secs(),
+                        };
+                        status.set(record).map(move |()| data)
+                    })
+                    .right_future()
+            }
+        })
+        .boxify()
+}
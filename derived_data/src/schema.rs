@@ -0,0 +1,23 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macros in this module describe the schemas for these tables in SQL storage
+//! (MySQL or SQLite). These descriptions are *not* the source of truth, so if the schema ever
+//! changes it will need to be updated here as well.
+
+table! {
+    use diesel::sql_types::{BigInt, Integer, Text};
+    use mercurial_types::sql_types::HgChangesetIdSql;
+
+    derived_data_status (repo_id, hg_cs_id, data_type) {
+        repo_id -> Integer,
+        hg_cs_id -> HgChangesetIdSql,
+        data_type -> Text,
+        state -> Text,
+        attempts -> Integer,
+        updated_at -> BigInt,
+    }
+}
@@ -0,0 +1,303 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the derived data status and queue.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+
+extern crate blobrepo;
+extern crate derived_data;
+extern crate fixtures;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate metaconfig;
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::Future;
+use slog::{Discard, Logger};
+
+use blobrepo::BlobRepo;
+use derived_data::{derivation_by_name, fetch_derived, ChangedFiles, DerivationQueue,
+                   DerivationRecord, DerivationState, DerivedDataStatus, ManifestStats,
+                   MysqlDerivedDataStatus, SqliteDerivedDataStatus};
+use fixtures::linear;
+use futures_ext::BoxFuture;
+use mercurial_types::{HgChangesetId, MPath};
+use mercurial_types_mocks::nodehash::{ONES_CSID, THREES_CSID, TWOS_CSID};
+use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
+use metaconfig::DerivedDataParams;
+
+const LINEAR_HEAD: &str = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+const LINEAR_PARENT: &str = "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157";
+
+fn record(changeset: HgChangesetId, data_type: &str, state: DerivationState) -> DerivationRecord {
+    DerivationRecord {
+        repo_id: REPO_ZERO,
+        changeset,
+        data_type: data_type.to_string(),
+        state,
+        attempts: 0,
+        updated_at: 100,
+    }
+}
+
+fn add_pending_keeps_records<S: DerivedDataStatus>(status: S) {
+    status
+        .set(record(ONES_CSID, "changed_files", DerivationState::Done))
+        .wait()
+        .expect("Setting record failed");
+    status
+        .add_pending(
+            REPO_ZERO,
+            "changed_files".to_string(),
+            vec![ONES_CSID, TWOS_CSID],
+            100,
+        )
+        .wait()
+        .expect("Adding pending records failed");
+    // Adding them again doesn't reset them
+    status
+        .add_pending(
+            REPO_ZERO,
+            "changed_files".to_string(),
+            vec![TWOS_CSID],
+            200,
+        )
+        .wait()
+        .expect("Adding pending records failed");
+
+    let records = status.get(REPO_ZERO, ONES_CSID).wait().expect("Get failed");
+    assert_eq!(
+        records,
+        vec![record(ONES_CSID, "changed_files", DerivationState::Done)]
+    );
+    let pending = status.list_pending(REPO_ZERO).wait().expect("List failed");
+    assert_eq!(
+        pending,
+        vec![record(TWOS_CSID, "changed_files", DerivationState::Pending)]
+    );
+}
+
+fn list_pending<S: DerivedDataStatus>(status: S) {
+    status
+        .add_pending(
+            REPO_ZERO,
+            "manifest_stats".to_string(),
+            vec![THREES_CSID],
+            300,
+        )
+        .wait()
+        .expect("Adding pending records failed");
+    status
+        .add_pending(
+            REPO_ZERO,
+            "changed_files".to_string(),
+            vec![TWOS_CSID, THREES_CSID],
+            200,
+        )
+        .wait()
+        .expect("Adding pending records failed");
+    status
+        .add_pending(REPO_ONE, "changed_files".to_string(), vec![ONES_CSID], 100)
+        .wait()
+        .expect("Adding pending records failed");
+
+    let mut failed = record(TWOS_CSID, "changed_files", DerivationState::Failed);
+    failed.attempts = 3;
+    status.set(failed.clone()).wait().expect("Setting record failed");
+
+    let pending = status.list_pending(REPO_ZERO).wait().expect("List failed");
+    let pending: Vec<_> = pending
+        .iter()
+        .map(|record| (record.changeset, record.data_type.as_str(), record.updated_at))
+        .collect();
+    assert_eq!(
+        pending,
+        vec![
+            (THREES_CSID, "changed_files", 200),
+            (THREES_CSID, "manifest_stats", 300),
+        ]
+    );
+
+    let records = status.get(REPO_ZERO, TWOS_CSID).wait().expect("Get failed");
+    assert_eq!(records, vec![failed]);
+}
+
+macro_rules! derived_data_test_impl {
+    ($mod_name:ident =>  { new: $new_cb:expr, }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_add_pending_keeps_records() {
+                async_unit::tokio_unit_test(|| {
+                    add_pending_keeps_records($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_list_pending() {
+                async_unit::tokio_unit_test(|| {
+                    list_pending($new_cb());
+                });
+            }
+        }
+    };
+}
+
+derived_data_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+derived_data_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+fn new_sqlite() -> SqliteDerivedDataStatus {
+    SqliteDerivedDataStatus::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_mysql() -> MysqlDerivedDataStatus {
+    MysqlDerivedDataStatus::create_test_db("derived_data_test")
+        .expect("Failed to create test database")
+}
+
+fn params() -> DerivedDataParams {
+    DerivedDataParams {
+        types: vec!["changed_files".to_string(), "manifest_stats".to_string()],
+        workers: 2,
+        max_attempts: 1,
+    }
+}
+
+fn open_queue(
+    repo: &BlobRepo,
+    status: Arc<DerivedDataStatus>,
+) -> (DerivationQueue, BoxFuture<(), ()>) {
+    let logger = Logger::root(Discard, o!());
+    DerivationQueue::open(repo.clone(), status, &params(), logger)
+        .wait()
+        .expect("Opening queue failed")
+}
+
+fn states(
+    repo: &BlobRepo,
+    status: &Arc<DerivedDataStatus>,
+    changeset: HgChangesetId,
+) -> Vec<(String, DerivationState)> {
+    status
+        .get(repo.get_repoid(), changeset)
+        .wait()
+        .expect("Get failed")
+        .into_iter()
+        .map(|record| (record.data_type, record.state))
+        .collect()
+}
+
+fn done() -> Vec<(String, DerivationState)> {
+    vec![
+        ("changed_files".to_string(), DerivationState::Done),
+        ("manifest_stats".to_string(), DerivationState::Done),
+    ]
+}
+
+#[test]
+fn test_landed_changesets_are_derived() {
+    async_unit::tokio_unit_test(|| {
+        let repo = linear::getrepo(None);
+        let status: Arc<DerivedDataStatus> = Arc::new(new_sqlite());
+        let head = HgChangesetId::from_str(LINEAR_HEAD).unwrap();
+        let parent = HgChangesetId::from_str(LINEAR_PARENT).unwrap();
+
+        let (queue, worker) = open_queue(&repo, status.clone());
+        queue
+            .enqueue(vec![parent, head])
+            .wait()
+            .expect("Enqueueing failed");
+        // Landing the same changeset again doesn't derive it twice
+        queue.enqueue(vec![head]).wait().expect("Enqueueing failed");
+        assert_eq!(states(&repo, &status, head).len(), 2);
+
+        // The worker ends once the queue is dropped and every derivation is done
+        drop(queue);
+        worker.wait().expect("Worker failed");
+        assert_eq!(states(&repo, &status, head), done());
+        assert_eq!(states(&repo, &status, parent), done());
+        assert!(status
+            .list_pending(repo.get_repoid())
+            .wait()
+            .unwrap()
+            .is_empty());
+
+        let changed_files = derivation_by_name("changed_files").unwrap();
+        let data = fetch_derived(&repo, status.clone(), changed_files, head)
+            .wait()
+            .expect("Fetching derived data failed");
+        assert_eq!(
+            ChangedFiles::parse(&data).unwrap(),
+            vec![MPath::new("10").unwrap()]
+        );
+        let manifest_stats = derivation_by_name("manifest_stats").unwrap();
+        let data = fetch_derived(&repo, status.clone(), manifest_stats, head)
+            .wait()
+            .expect("Fetching derived data failed");
+        let stats = ManifestStats::parse(&data).unwrap();
+        assert_eq!(stats.directories, 0);
+        assert!(stats.files > 0);
+    })
+}
+
+#[test]
+fn test_pending_derivations_resume() {
+    async_unit::tokio_unit_test(|| {
+        let repo = linear::getrepo(None);
+        let status: Arc<DerivedDataStatus> = Arc::new(new_sqlite());
+        let head = HgChangesetId::from_str(LINEAR_HEAD).unwrap();
+
+        // The server stopped after the changeset was queued, before it was derived
+        for data_type in params().types {
+            status
+                .add_pending(repo.get_repoid(), data_type, vec![head], 100)
+                .wait()
+                .expect("Adding pending records failed");
+        }
+
+        let (queue, worker) = open_queue(&repo, status.clone());
+        drop(queue);
+        worker.wait().expect("Worker failed");
+        assert_eq!(states(&repo, &status, head), done());
+    })
+}
+
+#[test]
+fn test_derived_on_read() {
+    async_unit::tokio_unit_test(|| {
+        let repo = linear::getrepo(None);
+        let status: Arc<DerivedDataStatus> = Arc::new(new_sqlite());
+        let head = HgChangesetId::from_str(LINEAR_HEAD).unwrap();
+
+        let changed_files = derivation_by_name("changed_files").unwrap();
+        fetch_derived(&repo, status.clone(), changed_files, head)
+            .wait()
+            .expect("Fetching derived data failed");
+        assert_eq!(
+            states(&repo, &status, head),
+            vec![("changed_files".to_string(), DerivationState::Done)]
+        );
+    })
+}
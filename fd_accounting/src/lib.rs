@@ -0,0 +1,405 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Accounting of the file descriptors that the process has open, attributed to the component
+//! that owns them, so that running out of file descriptors doesn't come as a surprise.
+//!
+//! Every place that opens a file descriptor holds an `FdGuard` for as long as it is open. The
+//! guard gives the descriptor back when it is dropped, so that error paths can't leak counts.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate stats;
+
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use slog::Logger;
+
+define_stats! {
+    prefix = "mononoke.fd_accounting";
+    open_fds: dynamic_timeseries("{}.open_fds", (component: &'static str); AVG, MAX),
+}
+
+/// The components that file descriptors are attributed to
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FdComponent {
+    /// Sockets of the clients connected to the server
+    ClientSockets,
+    /// Files of the blobstores that keep blobs on local disk
+    LocalBlobstoreFiles,
+    /// Connections to the backends, like the pools of SQL connections
+    BackendConnections,
+}
+
+const COMPONENTS: [FdComponent; 3] = [
+    FdComponent::ClientSockets,
+    FdComponent::LocalBlobstoreFiles,
+    FdComponent::BackendConnections,
+];
+
+impl FdComponent {
+    pub fn all() -> &'static [FdComponent] {
+        &COMPONENTS
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FdComponent::ClientSockets => "client_sockets",
+            FdComponent::LocalBlobstoreFiles => "local_blobstore_files",
+            FdComponent::BackendConnections => "backend_connections",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Warns once the total number of open file descriptors reaches `threshold`, and again after
+/// it has dropped below it and reached it again.
+struct FdWarning {
+    logger: Logger,
+    limit: u64,
+    threshold: usize,
+}
+
+/// Counts of the open file descriptors of each component. Clones share the counts.
+#[derive(Clone)]
+pub struct FdRegistry {
+    inner: Arc<FdRegistryInner>,
+}
+
+struct FdRegistryInner {
+    counts: [AtomicUsize; 3],
+    warning: Mutex<Option<FdWarning>>,
+    // Copy of the threshold of the warning, so that checking it doesn't need the lock. Zero when
+    // there is no warning.
+    threshold: AtomicUsize,
+    above_threshold: AtomicBool,
+}
+
+impl FdRegistry {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(FdRegistryInner {
+                counts: [
+                    AtomicUsize::new(0),
+                    AtomicUsize::new(0),
+                    AtomicUsize::new(0),
+                ],
+                warning: Mutex::new(None),
+                threshold: AtomicUsize::new(0),
+                above_threshold: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Accounts for a file descriptor of `component` until the returned guard is dropped
+    pub fn track(&self, component: FdComponent) -> FdGuard {
+        self.opened(component);
+        FdGuard {
+            registry: self.clone(),
+            component,
+        }
+    }
+
+    /// Accounts for a file descriptor of `component` that was opened. Prefer `track`, this is
+    /// for file descriptors whose owner can't hold a guard, and each call must be paired with a
+    /// call to `closed`.
+    pub fn opened(&self, component: FdComponent) {
+        let count = self.inner.counts[component.index()].fetch_add(1, Ordering::SeqCst) + 1;
+        STATS::open_fds.add_value(count as i64, (component.name(),));
+
+        let threshold = self.inner.threshold.load(Ordering::Relaxed);
+        if threshold > 0 && self.total() >= threshold
+            && !self.inner.above_threshold.swap(true, Ordering::SeqCst)
+        {
+            self.warn();
+        }
+    }
+
+    /// Accounts for a file descriptor of `component` that was closed
+    pub fn closed(&self, component: FdComponent) {
+        let count = self.inner.counts[component.index()].fetch_sub(1, Ordering::SeqCst) - 1;
+        STATS::open_fds.add_value(count as i64, (component.name(),));
+
+        let threshold = self.inner.threshold.load(Ordering::Relaxed);
+        if threshold > 0 && self.total() < threshold {
+            self.inner.above_threshold.store(false, Ordering::SeqCst);
+        }
+    }
+
+    pub fn count(&self, component: FdComponent) -> usize {
+        self.inner.counts[component.index()].load(Ordering::SeqCst)
+    }
+
+    pub fn total(&self) -> usize {
+        self.inner
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::SeqCst))
+            .sum()
+    }
+
+    /// The count of each component, in the order of `FdComponent::all`
+    pub fn usage(&self) -> Vec<(FdComponent, usize)> {
+        FdComponent::all()
+            .iter()
+            .map(|component| (*component, self.count(*component)))
+            .collect()
+    }
+
+    /// Logs a warning to `logger` when the file descriptors accounted for reach `fraction` of
+    /// `limit`
+    pub fn warn_above(&self, logger: Logger, limit: u64, fraction: f64) {
+        let threshold = ((limit as f64 * fraction) as usize).max(1);
+        let mut warning = self.inner.warning.lock().expect("lock poisoned");
+        *warning = Some(FdWarning {
+            logger,
+            limit,
+            threshold,
+        });
+        self.inner.threshold.store(threshold, Ordering::SeqCst);
+    }
+
+    fn warn(&self) {
+        let warning = self.inner.warning.lock().expect("lock poisoned");
+        if let Some(FdWarning {
+            ref logger,
+            limit,
+            threshold,
+        }) = *warning
+        {
+            let usage: Vec<_> = self.usage()
+                .into_iter()
+                .map(|(component, count)| format!("{}: {}", component.name(), count))
+                .collect();
+            warn!(
+                logger,
+                "{} file descriptors are open, the warning threshold is {} of a limit of {} ({})",
+                self.total(),
+                threshold,
+                limit,
+                usage.join(", ")
+            );
+        }
+    }
+}
+
+/// Accounts for an open file descriptor for as long as it is alive
+#[must_use = "the file descriptor is only accounted for while the guard is alive"]
+pub struct FdGuard {
+    registry: FdRegistry,
+    component: FdComponent,
+}
+
+impl Drop for FdGuard {
+    fn drop(&mut self) {
+        self.registry.closed(self.component);
+    }
+}
+
+lazy_static! {
+    static ref FD_REGISTRY: FdRegistry = FdRegistry::new();
+}
+
+/// The registry of the process
+pub fn fd_registry() -> &'static FdRegistry {
+    &FD_REGISTRY
+}
+
+/// Accounts for a file descriptor of `component` in the registry of the process until the
+/// returned guard is dropped
+pub fn track(component: FdComponent) -> FdGuard {
+    FD_REGISTRY.track(component)
+}
+
+/// The soft limit of the number of open files of the process, None if it is unlimited
+pub fn open_files_limit() -> io::Result<Option<u64>> {
+    unsafe {
+        let mut rlimit: libc::rlimit = mem::zeroed();
+        if libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if rlimit.rlim_cur == libc::RLIM_INFINITY {
+            Ok(None)
+        } else {
+            Ok(Some(rlimit.rlim_cur as u64))
+        }
+    }
+}
+
+/// Reads the open files limit of the process and makes the registry of the process warn when
+/// the file descriptors accounted for reach `fraction` of it. Meant to be called at startup.
+pub fn init_fd_warning(logger: &Logger, fraction: f64) {
+    match open_files_limit() {
+        Ok(Some(limit)) => {
+            info!(
+                logger,
+                "Open files limit is {}, warning when {:.0}% of it is used",
+                limit,
+                fraction * 100.0
+            );
+            FD_REGISTRY.warn_above(logger.clone(), limit, fraction);
+        }
+        Ok(None) => info!(logger, "Open files are unlimited, not warning about them"),
+        Err(err) => warn!(logger, "Failed to read the open files limit: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use slog::{Drain, Level, Never, OwnedKVList, Record};
+
+    /// Remembers the level and message of every record
+    #[derive(Clone, Default)]
+    struct RecordingDrain(Arc<Mutex<Vec<(Level, String)>>>);
+
+    impl Drain for RecordingDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.level(), format!("{}", record.msg())));
+            Ok(())
+        }
+    }
+
+    impl RecordingDrain {
+        fn warnings(&self) -> Vec<String> {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|&&(level, _)| level == Level::Warning)
+                .map(|&(_, ref msg)| msg.clone())
+                .collect()
+        }
+    }
+
+    /// A connection that owns a socket for as long as it is alive
+    struct MockConnection {
+        _fd: FdGuard,
+    }
+
+    impl MockConnection {
+        fn connect(registry: &FdRegistry, fail: bool) -> Result<Self, String> {
+            let fd = registry.track(FdComponent::ClientSockets);
+            if fail {
+                // The handshake failed, the guard is dropped with the socket
+                return Err("handshake failed".to_string());
+            }
+            Ok(MockConnection { _fd: fd })
+        }
+    }
+
+    #[test]
+    fn test_open_close() {
+        let registry = FdRegistry::new();
+
+        let connections: Vec<_> = (0..5)
+            .map(|_| MockConnection::connect(&registry, false).unwrap())
+            .collect();
+        let file = registry.track(FdComponent::LocalBlobstoreFiles);
+        registry.opened(FdComponent::BackendConnections);
+
+        assert_eq!(registry.count(FdComponent::ClientSockets), 5);
+        assert_eq!(registry.count(FdComponent::LocalBlobstoreFiles), 1);
+        assert_eq!(registry.count(FdComponent::BackendConnections), 1);
+        assert_eq!(registry.total(), 7);
+
+        drop(connections);
+        drop(file);
+        registry.closed(FdComponent::BackendConnections);
+        assert_eq!(
+            registry.usage(),
+            vec![
+                (FdComponent::ClientSockets, 0),
+                (FdComponent::LocalBlobstoreFiles, 0),
+                (FdComponent::BackendConnections, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_paths() {
+        let registry = FdRegistry::new();
+
+        let ok = MockConnection::connect(&registry, false).unwrap();
+        assert!(MockConnection::connect(&registry, true).is_err());
+        assert_eq!(registry.count(FdComponent::ClientSockets), 1);
+
+        // A connection that panics while it is being served gives its socket back as well
+        let res = ::std::thread::spawn({
+            let registry = registry.clone();
+            move || {
+                let _conn = MockConnection::connect(&registry, false).unwrap();
+                panic!("connection handler panicked");
+            }
+        }).join();
+        assert!(res.is_err());
+        assert_eq!(registry.count(FdComponent::ClientSockets), 1);
+
+        drop(ok);
+        assert_eq!(registry.total(), 0);
+    }
+
+    #[test]
+    fn test_warning_threshold() {
+        let drain = RecordingDrain::default();
+        let logger = Logger::root(drain.clone(), o!());
+        let registry = FdRegistry::new();
+        registry.warn_above(logger, 10, 0.5);
+
+        let mut fds: Vec<_> = (0..4)
+            .map(|_| registry.track(FdComponent::ClientSockets))
+            .collect();
+        assert!(drain.warnings().is_empty());
+
+        // Crossing the threshold warns once, however long usage stays above it
+        fds.push(registry.track(FdComponent::BackendConnections));
+        fds.push(registry.track(FdComponent::LocalBlobstoreFiles));
+        let warnings = drain.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].contains("5 file descriptors are open"),
+            "{}",
+            warnings[0]
+        );
+        assert!(
+            warnings[0].contains("client_sockets: 4, local_blobstore_files: 0"),
+            "{}",
+            warnings[0]
+        );
+
+        // Crossing it again after dropping below it warns again
+        fds.truncate(3);
+        assert_eq!(drain.warnings().len(), 1);
+        fds.push(registry.track(FdComponent::ClientSockets));
+        fds.push(registry.track(FdComponent::ClientSockets));
+        assert_eq!(drain.warnings().len(), 2);
+    }
+
+    #[test]
+    fn test_open_files_limit() {
+        // The limit of the test process is whatever it is, but reading it must work
+        open_files_limit().expect("failed to read the open files limit");
+    }
+}
@@ -35,8 +35,8 @@ use futures_ext::{BoxFuture, BoxStream, FutureExt};
 use sql::{myrouter, Connection, rusqlite::Connection as SqliteConnection};
 use stats::Timeseries;
 
-use filenodes::{FilenodeInfo, Filenodes};
-use mercurial_types::{HgChangesetId, HgFileNodeId, RepoPath, RepositoryId};
+use filenodes::{FilenodeInfo, Filenodes, FilenodesPage, FilenodesPageToken};
+use mercurial_types::{HgChangesetId, HgFileNodeId, RepoPath, RepositoryId, NULL_HASH};
 use mononoke_types::hash;
 
 use errors::ErrorKind;
@@ -54,6 +54,7 @@ define_stats! {
     gets: timeseries(RATE, SUM),
     gets_master: timeseries(RATE, SUM),
     range_gets: timeseries(RATE, SUM),
+    page_gets: timeseries(RATE, SUM),
     adds: timeseries(RATE, SUM),
 }
 
@@ -129,7 +130,25 @@ queries! {
          FROM filenodes
          WHERE repo_id = {repo_id}
            AND path_hash = {path_hash}
-           AND is_tree = {is_tree}"
+           AND is_tree = {is_tree}
+         ORDER BY filenode"
+    }
+
+    read SelectFilenodesPage(
+        repo_id: RepositoryId,
+        path_hash: Vec<u8>,
+        is_tree: i8,
+        after: HgFileNodeId,
+        limit: u64
+    ) -> (HgFileNodeId, HgChangesetId, Option<HgFileNodeId>, Option<HgFileNodeId>, i8) {
+        "SELECT filenode, linknode, p1, p2, has_copyinfo
+         FROM filenodes
+         WHERE repo_id = {repo_id}
+           AND path_hash = {path_hash}
+           AND is_tree = {is_tree}
+           AND filenode > {after}
+         ORDER BY filenode
+         LIMIT {limit}"
     }
 
     read SelectCopyinfo(
@@ -261,27 +280,73 @@ impl Filenodes for SqlFilenodes {
             .chain_err(ErrorKind::FailRangeFetch(path.clone()))
             .from_err()
             .and_then(move |filenode_rows| {
-                let mut futs = vec![];
-                for (filenode, linknode, p1, p2, has_copyinfo) in filenode_rows {
-                    futs.push(convert_to_filenode_info(
-                        &read_connection,
-                        path.clone(),
-                        filenode,
-                        &pwh,
-                        repo_id,
-                        linknode,
-                        p1,
-                        p2,
-                        has_copyinfo,
-                    ))
-                }
+                convert_rows_to_filenode_infos(&read_connection, path, &pwh, repo_id, filenode_rows)
+            })
+            .boxify()
+    }
 
-                join_all(futs)
+    fn get_filenodes_page(
+        &self,
+        path: &RepoPath,
+        repo_id: &RepositoryId,
+        after: Option<FilenodesPageToken>,
+        limit: usize,
+    ) -> BoxFuture<FilenodesPage, Error> {
+        STATS::page_gets.add_value(1);
+        cloned!(self.read_connection, path, repo_id);
+        let pwh = PathWithHash::from_repo_path(&path);
+        // No filenode has the null hash, so every filenode sorts after it
+        let after = after.map_or(HgFileNodeId::new(NULL_HASH), |token| token.after());
+
+        SelectFilenodesPage::query(
+            &read_connection,
+            &repo_id,
+            &pwh.hash,
+            &pwh.is_tree,
+            &after,
+            &(limit as u64),
+        ).chain_err(ErrorKind::FailRangeFetch(path.clone()))
+            .from_err()
+            .and_then(move |filenode_rows| {
+                convert_rows_to_filenode_infos(&read_connection, path, &pwh, repo_id, filenode_rows)
             })
+            .map(move |filenodes| FilenodesPage::new(filenodes, limit))
             .boxify()
     }
 }
 
+/// Keeps the order of the rows
+fn convert_rows_to_filenode_infos(
+    connection: &Connection,
+    path: RepoPath,
+    pwh: &PathWithHash,
+    repo_id: RepositoryId,
+    filenode_rows: Vec<(
+        HgFileNodeId,
+        HgChangesetId,
+        Option<HgFileNodeId>,
+        Option<HgFileNodeId>,
+        i8,
+    )>,
+) -> impl Future<Item = Vec<FilenodeInfo>, Error = Error> {
+    let mut futs = vec![];
+    for (filenode, linknode, p1, p2, has_copyinfo) in filenode_rows {
+        futs.push(convert_to_filenode_info(
+            connection,
+            path.clone(),
+            filenode,
+            pwh,
+            repo_id,
+            linknode,
+            p1,
+            p2,
+            has_copyinfo,
+        ))
+    }
+
+    join_all(futs)
+}
+
 fn ensure_paths_exists(
     connection: &Connection,
     repo_id: &RepositoryId,
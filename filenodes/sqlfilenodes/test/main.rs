@@ -19,12 +19,12 @@ extern crate mercurial_types_mocks;
 extern crate sqlfilenodes;
 extern crate tokio;
 
-use filenodes::{FilenodeInfo, Filenodes};
+use filenodes::{FilenodeInfo, Filenodes, FilenodesPageToken};
 use futures::future::Future;
 use futures_ext::StreamExt;
 use mercurial_types::{HgFileNodeId, RepoPath, RepositoryId};
-use mercurial_types_mocks::nodehash::{ONES_CSID, ONES_FNID, THREES_CSID, THREES_FNID, TWOS_CSID,
-                                      TWOS_FNID};
+use mercurial_types_mocks::nodehash::{FIVES_FNID, FOURS_FNID, ONES_CSID, ONES_FNID, SEVENS_FNID,
+                                      SIXES_FNID, THREES_CSID, THREES_FNID, TWOS_CSID, TWOS_FNID};
 use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
 use sqlfilenodes::SqlFilenodes;
 
@@ -105,6 +105,17 @@ fn copied_filenode() -> FilenodeInfo {
     }
 }
 
+fn file_c_filenode(filenode: HgFileNodeId) -> FilenodeInfo {
+    FilenodeInfo {
+        path: RepoPath::file("c").unwrap(),
+        filenode,
+        p1: None,
+        p2: None,
+        copyfrom: None,
+        linknode: ONES_CSID,
+    }
+}
+
 fn do_add_filenodes(filenodes: &Filenodes, to_insert: Vec<FilenodeInfo>, repo_id: &RepositoryId) {
     let stream = futures::stream::iter_ok(to_insert.into_iter()).boxify();
     filenodes.add_filenodes(stream, repo_id).wait().unwrap();
@@ -155,6 +166,20 @@ fn assert_all_filenodes(
     assert_eq!(&res, expected);
 }
 
+fn get_page(
+    filenodes: &Filenodes,
+    path: &RepoPath,
+    after: Option<FilenodesPageToken>,
+    limit: usize,
+) -> (Vec<HgFileNodeId>, Option<FilenodesPageToken>) {
+    let page = filenodes
+        .get_filenodes_page(path, &REPO_ZERO, after, limit)
+        .wait()
+        .expect("error while fetching page");
+    let hashes = page.filenodes.into_iter().map(|info| info.filenode).collect();
+    (hashes, page.next)
+}
+
 fn create_db() -> SqlFilenodes {
     SqlFilenodes::with_sqlite_in_memory().unwrap()
 }
@@ -463,4 +488,82 @@ mod test {
             Ok(())
         }).expect("test failed");
     }
+
+    #[test]
+    fn get_all_filenodes_in_canonical_order() {
+        async_unit::tokio_unit_test(|| -> Result<_, !> {
+            let filenodes = &create_db();
+            let path = RepoPath::file("c").unwrap();
+            do_add_filenodes(
+                filenodes,
+                vec![file_c_filenode(FIVES_FNID), file_c_filenode(ONES_FNID)],
+                &REPO_ZERO,
+            );
+            do_add_filenodes(
+                filenodes,
+                vec![file_c_filenode(THREES_FNID), file_c_filenode(FOURS_FNID)],
+                &REPO_ZERO,
+            );
+            do_add_filenode(filenodes, file_c_filenode(TWOS_FNID), &REPO_ZERO);
+
+            let expected: Vec<_> = [ONES_FNID, TWOS_FNID, THREES_FNID, FOURS_FNID, FIVES_FNID]
+                .iter()
+                .map(|filenode| file_c_filenode(*filenode))
+                .collect();
+            assert_all_filenodes(filenodes, &path, &REPO_ZERO, &expected);
+            // The same on every read
+            assert_all_filenodes(filenodes, &path, &REPO_ZERO, &expected);
+            Ok(())
+        }).expect("test failed");
+    }
+
+    #[test]
+    fn get_filenodes_pages() {
+        async_unit::tokio_unit_test(|| -> Result<_, !> {
+            let filenodes = &create_db();
+            let path = RepoPath::file("c").unwrap();
+            do_add_filenodes(
+                filenodes,
+                vec![
+                    file_c_filenode(SEVENS_FNID),
+                    file_c_filenode(THREES_FNID),
+                    file_c_filenode(ONES_FNID),
+                    file_c_filenode(FIVES_FNID),
+                ],
+                &REPO_ZERO,
+            );
+
+            let (first, token) = get_page(filenodes, &path, None, 2);
+            assert_eq!(first, vec![ONES_FNID, THREES_FNID]);
+            let token = token.expect("full page without a token");
+            assert_eq!(token.after(), THREES_FNID);
+
+            // Filenodes added between pages: the one before the token is not in any of the
+            // next pages, the one after it is in its place in the order, and none of the
+            // filenodes that were there before is repeated or skipped
+            do_add_filenodes(
+                filenodes,
+                vec![file_c_filenode(TWOS_FNID), file_c_filenode(SIXES_FNID)],
+                &REPO_ZERO,
+            );
+
+            // Tokens survive a round trip through clients
+            let token = token.to_string().parse().unwrap();
+            let (second, token) = get_page(filenodes, &path, Some(token), 2);
+            assert_eq!(second, vec![FIVES_FNID, SIXES_FNID]);
+            let (third, token) = get_page(filenodes, &path, token, 2);
+            assert_eq!(third, vec![SEVENS_FNID]);
+            assert_eq!(token, None);
+
+            // Reading the pages again gives the same pages, with the new filenodes
+            let (first, token) = get_page(filenodes, &path, None, 3);
+            assert_eq!(first, vec![ONES_FNID, TWOS_FNID, THREES_FNID]);
+            let (second, token) = get_page(filenodes, &path, token, 3);
+            assert_eq!(second, vec![FIVES_FNID, SIXES_FNID, SEVENS_FNID]);
+            let (third, token) = get_page(filenodes, &path, token, 3);
+            assert!(third.is_empty());
+            assert_eq!(token, None);
+            Ok(())
+        }).expect("test failed");
+    }
 }
@@ -20,7 +20,8 @@ use rust_thrift::compact_protocol;
 use stats::{Histogram, Timeseries};
 use tokio;
 
-use {thrift, FilenodeInfo, Filenodes, blake2_path_hash};
+use {thrift, FilenodeInfo, Filenodes, FilenodesPage, FilenodesPageToken, blake2_path_hash,
+     sort_filenodes};
 
 define_stats! {
     prefix = "mononoke.filenodes";
@@ -39,7 +40,9 @@ define_stats! {
 // Memcache max size for key + value + overhead is around 1MB, so we are leaving 1KB for key +
 // overhead
 const MEMCACHE_VALUE_MAX_SIZE: usize = 999_000;
-const MC_CODEVER: u32 = 0;
+// Version 1: the lists are in canonical order. Lists that were cached before could be in any
+// order.
+const MC_CODEVER: u32 = 1;
 const MC_SITEVER: u32 = 0;
 const TTL_SEC: u64 = 8 * 60 * 60;
 // Adding a random to TTL helps preventing eviction of all related keys at once
@@ -120,12 +123,18 @@ impl Filenodes for CachingFilenodes {
             repo_id.clone(),
             path_hash.clone(),
         ).then(move |from_memcache| {
-            if let Ok(from_memcache) = from_memcache {
+            if let Ok(mut from_memcache) = from_memcache {
+                sort_filenodes(&mut from_memcache);
                 return future::ok(from_memcache).left_future();
             }
 
             filenodes
                 .get_all_filenodes(&path, &repo_id)
+                .map(|mut all_filenodes| {
+                    // The order is part of what is cached, so don't rely on the backend for it
+                    sort_filenodes(&mut all_filenodes);
+                    all_filenodes
+                })
                 .inspect(move |all_filenodes| {
                     schedule_fill_all_filenodes_memcache(
                         all_filenodes,
@@ -139,6 +148,17 @@ impl Filenodes for CachingFilenodes {
         })
             .boxify()
     }
+
+    /// Pages are not cached: they are read by tools that walk large histories once
+    fn get_filenodes_page(
+        &self,
+        path: &RepoPath,
+        repo_id: &RepositoryId,
+        after: Option<FilenodesPageToken>,
+        limit: usize,
+    ) -> BoxFuture<FilenodesPage, Error> {
+        self.filenodes.get_filenodes_page(path, repo_id, after, limit)
+    }
 }
 
 fn get_mc_key_for_filenodes(
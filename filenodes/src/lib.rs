@@ -9,6 +9,7 @@
 extern crate abomonation;
 #[macro_use]
 extern crate abomonation_derive;
+extern crate asyncmemo;
 extern crate cachelib;
 #[macro_use]
 extern crate cloned;
@@ -29,10 +30,17 @@ extern crate tokio;
 extern crate filenodes_if;
 extern crate futures_ext;
 extern crate mercurial_types;
+#[cfg(test)]
+extern crate mercurial_types_mocks;
 
 mod caching;
 
-use failure::{Error, Result};
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+
+use asyncmemo::Weight;
+use failure::{err_msg, Error, Result};
 use futures_ext::{BoxFuture, BoxStream};
 use mercurial_types::{HgChangesetId, HgFileNodeId, HgNodeHash, RepoPath, RepositoryId};
 use mononoke_types::hash;
@@ -99,6 +107,17 @@ impl FilenodeInfo {
     }
 }
 
+/// The struct, and the path components of the file and of the file it was copied from
+impl Weight for FilenodeInfo {
+    fn get_weight(&self) -> usize {
+        let path_heap = |path: &RepoPath| path.get_weight() - mem::size_of::<RepoPath>();
+        let copyfrom_heap = self.copyfrom
+            .as_ref()
+            .map_or(0, |&(ref path, _)| path_heap(path));
+        mem::size_of::<Self>() + path_heap(&self.path) + copyfrom_heap
+    }
+}
+
 impl Arbitrary for FilenodeInfo {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
         Self {
@@ -112,6 +131,77 @@ impl Arbitrary for FilenodeInfo {
     }
 }
 
+/// Sorts filenodes in the canonical order of `Filenodes::get_all_filenodes`: by filenode hash.
+///
+/// The hash is the one property of a filenode that is unique among the filenodes of a path,
+/// never changes, and is known without fetching anything else. Ordering by the generation of
+/// the linknode instead would take a lookup of every linknode, and would still need the hash to
+/// break the ties between filenodes whose linknodes have the same generation.
+pub fn sort_filenodes(filenodes: &mut Vec<FilenodeInfo>) {
+    filenodes.sort_by(|a, b| a.filenode.cmp(&b.filenode));
+}
+
+/// Position in the canonical order of the filenodes of a path, after the last filenode of a
+/// page. The next page starts with the first filenode whose hash is greater.
+///
+/// Because the position is a hash rather than an offset, the pages are stable while filenodes
+/// are added: a filenode that is added between two pages shows up in a later page if its hash
+/// sorts after the token, and in none otherwise, so every filenode that existed when the first
+/// page was read is returned exactly once.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FilenodesPageToken {
+    after: HgFileNodeId,
+}
+
+impl FilenodesPageToken {
+    /// The filenode that the page before the token ended with
+    pub fn after(&self) -> HgFileNodeId {
+        self.after
+    }
+}
+
+/// The token is sent to clients as a string, the hex of the last filenode
+impl fmt::Display for FilenodesPageToken {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.after)
+    }
+}
+
+impl FromStr for FilenodesPageToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let after = HgNodeHash::from_str(s)
+            .map_err(|_| err_msg(format!("invalid filenodes page token: {}", s)))?;
+        Ok(FilenodesPageToken {
+            after: HgFileNodeId::new(after),
+        })
+    }
+}
+
+/// A page of the filenodes of a path, in canonical order
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FilenodesPage {
+    pub filenodes: Vec<FilenodeInfo>,
+    /// Where the next page starts, None if this page is the last one. A full page always has a
+    /// token, so the page after it may be empty.
+    pub next: Option<FilenodesPageToken>,
+}
+
+impl FilenodesPage {
+    /// Makes the page out of filenodes in canonical order, that at most `limit` were asked for
+    pub fn new(filenodes: Vec<FilenodeInfo>, limit: usize) -> Self {
+        let next = if filenodes.len() >= limit {
+            filenodes.last().map(|last| FilenodesPageToken {
+                after: last.filenode,
+            })
+        } else {
+            None
+        };
+        FilenodesPage { filenodes, next }
+    }
+}
+
 pub trait Filenodes: Send + Sync {
     fn add_filenodes(
         &self,
@@ -126,16 +216,30 @@ pub trait Filenodes: Send + Sync {
         repo_id: &RepositoryId,
     ) -> BoxFuture<Option<FilenodeInfo>, Error>;
 
+    /// All the filenodes of the path, in canonical order, see `sort_filenodes`
     fn get_all_filenodes(
         &self,
         path: &RepoPath,
         repo_id: &RepositoryId,
     ) -> BoxFuture<Vec<FilenodeInfo>, Error>;
+
+    /// At most `limit` filenodes of the path, in canonical order, starting after `after`, or
+    /// with the first filenode if `after` is None
+    fn get_filenodes_page(
+        &self,
+        path: &RepoPath,
+        repo_id: &RepositoryId,
+        after: Option<FilenodesPageToken>,
+        limit: usize,
+    ) -> BoxFuture<FilenodesPage, Error>;
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use asyncmemo::weight_matches_estimate;
+    use mercurial_types::{NULL_CSID, NULL_HASH};
+    use mercurial_types_mocks::nodehash::{ONES_FNID, TWOS_FNID};
 
     quickcheck! {
         fn filenodes_info_thrift_roundtrip(obj: FilenodeInfo) -> bool {
@@ -145,4 +249,53 @@ mod test {
             obj == obj2
         }
     }
+
+    #[test]
+    fn test_filenode_info_weight() {
+        let path = RepoPath::file("dir/subdir/file.txt").unwrap();
+        let mut info = FilenodeInfo {
+            path: path.clone(),
+            filenode: HgFileNodeId::new(NULL_HASH),
+            p1: Some(HgFileNodeId::new(NULL_HASH)),
+            p2: None,
+            copyfrom: None,
+            linknode: NULL_CSID,
+        };
+        let weight = info.get_weight();
+        assert!(weight_matches_estimate(weight, abomonation::measure(&info)));
+
+        info.copyfrom = Some((path, HgFileNodeId::new(NULL_HASH)));
+        assert!(weight_matches_estimate(info.get_weight(), abomonation::measure(&info)));
+        assert!(info.get_weight() > weight);
+    }
+
+    fn filenode(filenode: HgFileNodeId) -> FilenodeInfo {
+        FilenodeInfo {
+            path: RepoPath::file("file").unwrap(),
+            filenode,
+            p1: None,
+            p2: None,
+            copyfrom: None,
+            linknode: NULL_CSID,
+        }
+    }
+
+    #[test]
+    fn test_page_token() {
+        let page = FilenodesPage::new(vec![filenode(ONES_FNID), filenode(TWOS_FNID)], 2);
+        let token = page.next.expect("a full page has a token");
+        assert_eq!(token.after(), TWOS_FNID);
+        assert_eq!(token.to_string().parse::<FilenodesPageToken>().unwrap(), token);
+        assert!("not a hash".parse::<FilenodesPageToken>().is_err());
+
+        let page = FilenodesPage::new(vec![filenode(ONES_FNID)], 2);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn test_sort_filenodes() {
+        let mut filenodes = vec![filenode(TWOS_FNID), filenode(ONES_FNID)];
+        sort_filenodes(&mut filenodes);
+        assert_eq!(filenodes, vec![filenode(ONES_FNID), filenode(TWOS_FNID)]);
+    }
 }
@@ -19,6 +19,7 @@ extern crate futures;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
+extern crate rand;
 extern crate tokio;
 extern crate tokio_io;
 extern crate tokio_threadpool;
@@ -34,6 +35,8 @@ use std::{io as std_io, fmt::Debug};
 
 mod bytes_stream;
 mod futures_ordered;
+mod merge_sorted;
+mod retry;
 mod select_all;
 mod streamfork;
 mod stream_wrappers;
@@ -45,6 +48,8 @@ pub mod io;
 
 pub use bytes_stream::{BytesStream, BytesStreamFuture};
 pub use futures_ordered::{futures_ordered, FuturesOrdered};
+pub use merge_sorted::{merge_sorted_by_key, MergeSortedByKey};
+pub use retry::{retry, CountRetries, RetryCounter, RetryPolicy};
 pub use select_all::{select_all, SelectAll};
 pub use stream_wrappers::{BoxStreamWrapper, CollectNoConsume, StreamWrapper, TakeWhile};
 
@@ -135,6 +140,11 @@ pub trait FutureExt: Future + Sized {
     fn right_future<A>(self) -> future::Either<A, Self> {
         future::Either::B(self)
     }
+
+    /// Add the number of retries made by `retry` futures while polling this future to `counter`.
+    fn count_retries(self, counter: &RetryCounter) -> CountRetries<Self> {
+        retry::count_retries(self, counter)
+    }
 }
 
 impl<T> FutureExt for T
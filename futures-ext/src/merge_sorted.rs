@@ -0,0 +1,143 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Merge of several sorted streams into one sorted stream
+
+use std::mem;
+
+use futures::{Async, Poll, Stream};
+
+/// Stream for the `merge_sorted_by_key` function.
+#[must_use = "streams do nothing unless polled"]
+pub struct MergeSortedByKey<S: Stream, F> {
+    streams: Vec<S>,
+    heads: Vec<Option<S::Item>>,
+    done: Vec<bool>,
+    key: F,
+}
+
+/// Merges streams, each of which must be sorted by `key`, into one stream sorted by `key`.
+///
+/// Unlike `select_all`, the output order doesn't depend on the order in which the streams become
+/// ready: all streams are polled concurrently, but an item is returned only once every
+/// unfinished stream has an item available to compare against. Items with equal keys are
+/// returned in the order of their streams in `streams`.
+pub fn merge_sorted_by_key<I, F, K>(streams: I, key: F) -> MergeSortedByKey<I::Item, F>
+where
+    I: IntoIterator,
+    I::Item: Stream,
+    F: FnMut(&<I::Item as Stream>::Item) -> K,
+    K: Ord,
+{
+    let streams: Vec<_> = streams.into_iter().collect();
+    let heads = streams.iter().map(|_| None).collect();
+    let done = streams.iter().map(|_| false).collect();
+    MergeSortedByKey {
+        streams,
+        heads,
+        done,
+        key,
+    }
+}
+
+impl<S, F, K> Stream for MergeSortedByKey<S, F>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+    K: Ord,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut not_ready = false;
+        for idx in 0..self.streams.len() {
+            if self.heads[idx].is_some() || self.done[idx] {
+                continue;
+            }
+            match self.streams[idx].poll()? {
+                Async::Ready(Some(item)) => self.heads[idx] = Some(item),
+                Async::Ready(None) => self.done[idx] = true,
+                Async::NotReady => not_ready = true,
+            }
+        }
+
+        if not_ready {
+            return Ok(Async::NotReady);
+        }
+
+        let mut min: Option<(usize, K)> = None;
+        for (idx, head) in self.heads.iter().enumerate() {
+            if let Some(item) = head {
+                let key = (self.key)(item);
+                let is_smaller = match min {
+                    Some((_, ref min_key)) => key < *min_key,
+                    None => true,
+                };
+                if is_smaller {
+                    min = Some((idx, key));
+                }
+            }
+        }
+
+        match min {
+            Some((idx, _)) => Ok(Async::Ready(mem::replace(&mut self.heads[idx], None))),
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::Future;
+    use futures::executor::spawn;
+    use futures::future::lazy;
+    use futures::stream::iter_ok;
+    use futures::sync::mpsc;
+
+    use StreamExt;
+
+    #[test]
+    fn merge_sorted() {
+        let streams = vec![
+            iter_ok::<_, ()>(vec![1, 4, 7]),
+            iter_ok(vec![]),
+            iter_ok(vec![2, 3, 8, 9]),
+            iter_ok(vec![5]),
+        ];
+        let res = merge_sorted_by_key(streams, |x| *x).collect().wait();
+        assert_eq!(res, Ok(vec![1, 2, 3, 4, 5, 7, 8, 9]));
+    }
+
+    #[test]
+    fn merge_sorted_ties_keep_stream_order() {
+        let streams = vec![
+            iter_ok::<_, ()>(vec![(1, "a"), (2, "a")]),
+            iter_ok(vec![(1, "b"), (2, "b")]),
+        ];
+        let res = merge_sorted_by_key(streams, |&(k, _)| k).collect().wait();
+        assert_eq!(res, Ok(vec![(1, "a"), (1, "b"), (2, "a"), (2, "b")]));
+    }
+
+    #[test]
+    fn merge_sorted_waits_for_all_streams() {
+        let (send, recv) = mpsc::unbounded();
+        let streams = vec![iter_ok::<_, ()>(vec![2]).boxify(), recv.boxify()];
+        let mut merged = merge_sorted_by_key(streams, |x| *x);
+
+        // The first stream is ready, but the second one is not, so nothing can be returned yet
+        assert_eq!(
+            spawn(lazy(|| merged.poll())).wait_future(),
+            Ok(Async::NotReady)
+        );
+
+        send.unbounded_send(1).unwrap();
+        drop(send);
+        assert_eq!(merged.collect().wait(), Ok(vec![1, 2]));
+    }
+}
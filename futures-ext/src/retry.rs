@@ -0,0 +1,300 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Retrying of futures that fail with transient errors.
+//!
+//! `retry` runs a future created by a closure until it succeeds, fails with an error that the
+//! given predicate doesn't consider retryable, or the attempts allowed by the `RetryPolicy` are
+//! exhausted. Retries made by the current task are counted, so that the number of retries a
+//! request needed can be reported with `FutureExt::count_retries`.
+
+use std::cell::Cell;
+use std::cmp::min;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, IntoFuture, Poll};
+use futures::future::{loop_fn, Either, Loop};
+use rand::random;
+use tokio::timer::Delay;
+
+task_local! {
+    static TASK_RETRIES: Cell<usize> = Cell::new(0)
+}
+
+/// How many times and how quickly a failed operation is retried.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: usize,
+    /// Delay before the first retry, doubled for every following retry
+    pub base_delay: Duration,
+    /// Upper bound of the delay between attempts
+    pub max_delay: Duration,
+    /// If set, every delay is randomly chosen between half of the backoff and the full backoff,
+    /// so that clients that failed at the same time don't retry at the same time
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    /// Policy that makes only a single attempt
+    pub fn no_retries() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            jitter: false,
+        }
+    }
+
+    /// Delay before the `retry`-th retry (starting from 1), without jitter
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exp = min(retry.saturating_sub(1), 31) as u32;
+        let delay = self.base_delay
+            .checked_mul(1 << exp)
+            .unwrap_or(self.max_delay);
+        min(delay, self.max_delay)
+    }
+
+    /// Delay before the `retry`-th retry (starting from 1), with jitter if it's enabled
+    pub fn delay(&self, retry: usize) -> Duration {
+        let backoff = self.backoff(retry);
+        if !self.jitter {
+            return backoff;
+        }
+
+        let millis = backoff.as_secs() * 1000 + (backoff.subsec_nanos() / 1_000_000) as u64;
+        let half = millis / 2;
+        Duration::from_millis(millis - half + random::<u64>() % (half + 1))
+    }
+}
+
+/// Runs the future returned by `func` until it succeeds or fails with an error for which
+/// `is_retryable` returns false. At most `policy.max_attempts` attempts are made, and the error
+/// of the last one is returned.
+pub fn retry<F, Fut, P>(
+    policy: RetryPolicy,
+    is_retryable: P,
+    func: F,
+) -> impl Future<Item = Fut::Item, Error = Fut::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: IntoFuture,
+    P: Fn(&Fut::Error) -> bool,
+{
+    loop_fn(
+        (func, is_retryable, 1),
+        move |(mut func, is_retryable, attempt)| {
+            func().into_future().then(move |res| match res {
+                Ok(item) => Either::A(future::ok(Loop::Break(item))),
+                Err(err) => {
+                    if attempt >= policy.max_attempts || !is_retryable(&err) {
+                        return Either::A(future::err(err));
+                    }
+
+                    TASK_RETRIES.with(|retries| retries.set(retries.get() + 1));
+                    let next = Loop::Continue((func, is_retryable, attempt + 1));
+                    let delay = policy.delay(attempt);
+                    if delay == Duration::from_millis(0) {
+                        Either::A(future::ok(next))
+                    } else {
+                        // A timer error only means that the retry happens earlier
+                        Either::B(Delay::new(Instant::now() + delay).then(move |_| Ok(next)))
+                    }
+                }
+            })
+        },
+    )
+}
+
+/// Number of retries made by futures wrapped with `FutureExt::count_retries`.
+#[derive(Clone, Debug, Default)]
+pub struct RetryCounter(Arc<AtomicUsize>);
+
+impl RetryCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Future returned by `FutureExt::count_retries`. Retries are only counted if they are made
+/// while polling the inner future: retries made by futures spawned onto other tasks are missed.
+pub struct CountRetries<F> {
+    inner: F,
+    counter: RetryCounter,
+}
+
+pub fn count_retries<F: Future>(inner: F, counter: &RetryCounter) -> CountRetries<F> {
+    CountRetries {
+        inner,
+        counter: counter.clone(),
+    }
+}
+
+impl<F: Future> Future for CountRetries<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let before = TASK_RETRIES.with(|retries| retries.get());
+        let res = self.inner.poll();
+        let after = TASK_RETRIES.with(|retries| retries.get());
+        self.counter.0.fetch_add(after - before, Ordering::Relaxed);
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    use FutureExt;
+
+    #[derive(Debug, Eq, PartialEq)]
+    enum TestError {
+        Transient,
+        Permanent,
+    }
+
+    fn policy(max_attempts: usize) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            jitter: false,
+        }
+    }
+
+    /// Returns a closure that fails with `errors` and then succeeds, and a counter of its calls
+    fn failing_then_succeeding(
+        errors: Vec<TestError>,
+    ) -> (
+        impl FnMut() -> Result<u32, TestError>,
+        Arc<AtomicUsize>,
+    ) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut errors = errors.into_iter();
+        let func = {
+            let attempts = attempts.clone();
+            move || {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                match errors.next() {
+                    Some(err) => Err(err),
+                    None => Ok(42),
+                }
+            }
+        };
+        (func, attempts)
+    }
+
+    fn is_transient(err: &TestError) -> bool {
+        *err == TestError::Transient
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let (func, attempts) =
+            failing_then_succeeding(vec![TestError::Transient, TestError::Transient]);
+        let counter = RetryCounter::new();
+        let res = retry(policy(5), is_transient, func)
+            .count_retries(&counter)
+            .wait();
+        assert_eq!(res, Ok(42));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let (func, attempts) = failing_then_succeeding(vec![
+            TestError::Transient,
+            TestError::Transient,
+            TestError::Transient,
+        ]);
+        let counter = RetryCounter::new();
+        let res = retry(policy(2), is_transient, func)
+            .count_retries(&counter)
+            .wait();
+        assert_eq!(res, Err(TestError::Transient));
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(counter.get(), 1);
+    }
+
+    #[test]
+    fn permanent_error_fails_immediately() {
+        let (func, attempts) =
+            failing_then_succeeding(vec![TestError::Permanent, TestError::Transient]);
+        let counter = RetryCounter::new();
+        let res = retry(policy(5), is_transient, func)
+            .count_retries(&counter)
+            .wait();
+        assert_eq!(res, Err(TestError::Permanent));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        assert_eq!(counter.get(), 0);
+    }
+
+    #[test]
+    fn backoff_grows_exponentially() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            jitter: false,
+        };
+        let delays: Vec<_> = (1..7).map(|retry| policy.delay(retry)).collect();
+        assert_eq!(
+            delays,
+            vec![10, 20, 40, 80, 100, 100]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(policy.backoff(1000), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter_stays_within_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            jitter: true,
+        };
+        for retry in 1..7 {
+            let backoff = policy.backoff(retry);
+            for _ in 0..100 {
+                let delay = policy.delay(retry);
+                assert!(delay <= backoff, "{:?} > {:?}", delay, backoff);
+                assert!(delay >= backoff / 2, "{:?} < {:?}", delay, backoff / 2);
+            }
+        }
+    }
+
+    #[test]
+    fn waits_between_attempts() {
+        let (func, attempts) =
+            failing_then_succeeding(vec![TestError::Transient, TestError::Transient]);
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            jitter: false,
+        };
+        let mut runtime = Runtime::new().unwrap();
+        let start = Instant::now();
+        let res = runtime.block_on(retry(policy, is_transient, func));
+        assert_eq!(res, Ok(42));
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+}
@@ -107,12 +107,17 @@ impl<'a> StdioRelay<'a> {
                 .map(|hostname| hostname.to_owned())
         };
 
-        let preamble = Preamble::new(
+        let mut preamble = Preamble::new(
             self.repo.to_owned(),
             session_uuid.clone(),
             unix_username,
             source_hostname,
         );
+        // A client that won't wait longer than this for the response, e.g. a CI step with a time
+        // budget, lets the server abort the request early
+        if let Ok(deadline_ms) = var("MONONOKE_DEADLINE_MS") {
+            preamble.misc.insert("deadline_ms".to_owned(), deadline_ms);
+        }
 
         scuba_logger.add_preamble(&preamble);
 
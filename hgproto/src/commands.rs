@@ -18,7 +18,7 @@ use slog::Logger;
 
 use bytes::{Bytes, BytesMut};
 use failure::{err_msg, FutureFailureErrorExt};
-use futures::IntoFuture;
+use futures::{Async, IntoFuture, Poll};
 use futures::future::{self, err, ok, Either, Future};
 use futures::stream::{self, futures_ordered, once, Stream};
 use futures::sync::oneshot;
@@ -28,11 +28,11 @@ use dechunker::Dechunker;
 use futures_ext::{BoxFuture, BoxStream, BytesStream, FutureExt, StreamExt};
 use mercurial_bundles::Bundle2Item;
 use mercurial_bundles::bundle2::{self, Bundle2Stream, StreamEvent};
-use mercurial_types::MPath;
+use mercurial_types::{HgManifestId, MPath};
 use tokio_io::AsyncRead;
 use tokio_io::codec::Decoder;
 
-use {GetbundleArgs, GettreepackArgs, SingleRequest, SingleResponse};
+use {CommandClass, GetbundleArgs, GettreepackArgs, SingleRequest, SingleResponse};
 
 use hooks::HookManager;
 
@@ -71,6 +71,16 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
     {
         let hgcmds = &self.commands;
 
+        // Writes are rejected here, for every command at once, so that a new command can't
+        // forget to check. Implementations may check again.
+        if req.class() == CommandClass::Write && hgcmds.is_read_only() {
+            info!(self.logger, "rejecting {} on a read-only repo", req.name());
+            return (
+                once(Err(ErrorKind::ReadOnly(req.name().into()).into())).boxify(),
+                ok(instream).boxify(),
+            );
+        }
+
         match req {
             SingleRequest::Between { pairs } => (
                 hgcmds
@@ -160,9 +170,34 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .boxify(),
                 ok(instream).boxify(),
             ),
+            SingleRequest::Knowntrees { bookmark, nodes } => (
+                hgcmds
+                    .knowntrees(bookmark, nodes)
+                    .map(SingleResponse::Knowntrees)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
             SingleRequest::Unbundle { heads } => {
-                let bundle2stream =
-                    Bundle2Stream::new(Dechunker::new(instream), self.logger.new(o!()));
+                let dechunker = Dechunker::new(instream);
+
+                if hgcmds.forwards_writes() {
+                    let (bundle, remainder) = dechunk_bytes(dechunker);
+                    let remainder = remainder.and_then(check_dechunker_is_done).boxify();
+
+                    let resps = once(Ok(SingleResponse::ReadyForStream)).chain(
+                        hgcmds
+                            .unbundle_raw(heads, bundle)
+                            .map(SingleResponse::Unbundle),
+                    );
+                    return (resps.boxify(), remainder);
+                }
+
+                let mut bundle2stream = Bundle2Stream::new(dechunker, self.logger.new(o!()));
+                if let Some(max_chunk_bytes) = hgcmds.max_bundle2_chunk_bytes() {
+                    bundle2stream = bundle2stream.with_max_chunk_bytes(max_chunk_bytes);
+                }
                 let (bundle2stream, remainder) = extract_remainder_from_bundle2(bundle2stream);
 
                 let remainder = remainder
@@ -176,21 +211,9 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                                 String::from_utf8_lossy(bytes.as_ref()).into_owned(),
                             ).into()))
                         } else {
-                            Either::B(remainder.check_is_done().from_err())
+                            Either::B(check_dechunker_is_done(remainder))
                         }
                     })
-                    .then(
-                        |check_is_done: Result<(bool, Dechunker<_>)>| match check_is_done {
-                            Ok((true, remainder)) => ok(remainder.into_inner()),
-                            Ok((false, mut remainder)) => match remainder.fill_buf() {
-                                Err(e) => err(e.into()),
-                                Ok(buf) => err(ErrorKind::UnconsumedData(
-                                    String::from_utf8_lossy(buf).into_owned(),
-                                ).into()),
-                            },
-                            Err(e) => err(e.into()),
-                        },
-                    )
                     .boxify();
 
                 let resps = futures_ordered(vec![
@@ -230,6 +253,33 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .boxify(),
                 ok(instream).boxify(),
             ),
+            SingleRequest::PrepareBookmarkMove { key, old, new, ttl } => (
+                hgcmds
+                    .preparebookmarkmove(key, old, new, ttl)
+                    .map(SingleResponse::PrepareBookmarkMove)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
+            SingleRequest::CommitBookmarkMove { token } => (
+                hgcmds
+                    .commitbookmarkmove(token)
+                    .map(SingleResponse::CommitBookmarkMove)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
+            SingleRequest::AbortBookmarkMove { token } => (
+                hgcmds
+                    .abortbookmarkmove(token)
+                    .map(SingleResponse::AbortBookmarkMove)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
         }
     }
 
@@ -443,6 +493,83 @@ where
     )
 }
 
+/// Resolves to the input that follows the chunked data, failing if the chunked data wasn't fully
+/// consumed.
+fn check_dechunker_is_done<R>(remainder: Dechunker<R>) -> impl Future<Item = R, Error = Error>
+where
+    R: AsyncRead + BufRead,
+{
+    remainder.check_is_done().then(
+        |check_is_done| match check_is_done {
+            Ok((true, remainder)) => ok(remainder.into_inner()),
+            Ok((false, mut remainder)) => match remainder.fill_buf() {
+                Err(e) => err(e.into()),
+                Ok(buf) => err(ErrorKind::UnconsumedData(
+                    String::from_utf8_lossy(buf).into_owned(),
+                ).into()),
+            },
+            Err(e) => err(e.into()),
+        },
+    )
+}
+
+/// Streams the chunked data without parsing it. The `Dechunker` is sent to the returned future
+/// once the terminating empty chunk has been read.
+fn dechunk_bytes<R>(
+    dechunker: Dechunker<R>,
+) -> (BoxStream<Bytes, Error>, BoxFuture<Dechunker<R>, Error>)
+where
+    R: AsyncRead + BufRead + Send + 'static,
+{
+    let (send, recv) = oneshot::channel();
+    let bytes = DechunkedBytes {
+        dechunker: Some(dechunker),
+        send: Some(send),
+    };
+
+    (
+        bytes.boxify(),
+        recv.from_err()
+            .with_context(|_| format!("While extracting unbundle remainder"))
+            .from_err()
+            .boxify(),
+    )
+}
+
+struct DechunkedBytes<R> {
+    dechunker: Option<Dechunker<R>>,
+    send: Option<oneshot::Sender<Dechunker<R>>>,
+}
+
+impl<R> Stream for DechunkedBytes<R>
+where
+    R: AsyncRead + BufRead,
+{
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        let bytes = match self.dechunker {
+            None => return Ok(Async::Ready(None)),
+            Some(ref mut dechunker) => Bytes::from(try_nb!(dechunker.fill_buf())),
+        };
+
+        if bytes.is_empty() {
+            let dechunker = self.dechunker.take().expect("dechunker is present");
+            if let Some(send) = self.send.take() {
+                // Receiving end will deal with failures
+                let _ = send.send(dechunker);
+            }
+            return Ok(Async::Ready(None));
+        }
+
+        if let Some(ref mut dechunker) = self.dechunker {
+            dechunker.consume(bytes.len());
+        }
+        Ok(Async::Ready(Some(bytes)))
+    }
+}
+
 #[inline]
 fn get_or_none<'a>(map: &'a HashMap<Vec<u8>, Vec<u8>>, key: &'a [u8]) -> &'a [u8] {
     match map.get(key) {
@@ -519,6 +646,15 @@ pub trait HgCommands {
         unimplemented("known")
     }
 
+    // knowntrees 'bookmark nodes'
+    fn knowntrees(
+        &self,
+        _bookmark: String,
+        _nodes: Vec<(Bytes, HgManifestId)>,
+    ) -> HgCommandRes<Vec<bool>> {
+        unimplemented("knowntrees")
+    }
+
     // @wireprotocommand('unbundle', 'heads')
     fn unbundle(
         &self,
@@ -529,6 +665,33 @@ pub trait HgCommands {
         unimplemented("unbundle")
     }
 
+    // Servers that proxy writes to another server return true here. They get the bundle of an
+    // unbundle request unparsed, through `unbundle_raw`, instead of `unbundle`, and the reply they
+    // return is sent to the client chunk by chunk as it comes.
+    fn forwards_writes(&self) -> bool {
+        false
+    }
+
+    fn unbundle_raw(
+        &self,
+        _heads: Vec<String>,
+        _bundle: BoxStream<Bytes, Error>,
+    ) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("unbundle".into()).into())).boxify()
+    }
+
+    // Read-only repos return true here. Commands of class `CommandClass::Write` are then
+    // rejected without being passed to the methods of this trait.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    // Largest chunk, in bytes, that the bundle of an unbundle request may announce. A chunk over
+    // it fails the push as soon as its length is read, before its payload is buffered.
+    fn max_bundle2_chunk_bytes(&self) -> Option<u64> {
+        None
+    }
+
     // @wireprotocommand('gettreepack', 'rootdir mfnodes basemfnodes directories')
     fn gettreepack(&self, _params: GettreepackArgs) -> BoxStream<Bytes, Error> {
         once(Err(ErrorKind::Unimplemented("gettreepack".into()).into())).boxify()
@@ -543,6 +706,30 @@ pub trait HgCommands {
     fn stream_out_shallow(&self) -> BoxStream<Bytes, Error> {
         once(Err(ErrorKind::Unimplemented("stream_out_shallow".into()).into())).boxify()
     }
+
+    // Mononoke-specific commands used by an external coordinator to move bookmarks in several
+    // repos together. They are not advertised in capabilities.
+
+    // preparebookmarkmove 'key old new ttl'
+    fn preparebookmarkmove(
+        &self,
+        _key: String,
+        _old: String,
+        _new: String,
+        _ttl: u64,
+    ) -> HgCommandRes<Bytes> {
+        unimplemented("preparebookmarkmove")
+    }
+
+    // commitbookmarkmove 'token'
+    fn commitbookmarkmove(&self, _token: String) -> HgCommandRes<Bytes> {
+        unimplemented("commitbookmarkmove")
+    }
+
+    // abortbookmarkmove 'token'
+    fn abortbookmarkmove(&self, _token: String) -> HgCommandRes<Bytes> {
+        unimplemented("abortbookmarkmove")
+    }
 }
 
 #[cfg(test)]
@@ -677,6 +864,204 @@ mod test {
         assert!(paramstream.collect().wait().is_err());
     }
 
+    struct Forwarding;
+    impl HgCommands for Forwarding {
+        fn forwards_writes(&self) -> bool {
+            true
+        }
+
+        fn unbundle_raw(
+            &self,
+            heads: Vec<String>,
+            bundle: BoxStream<Bytes, Error>,
+        ) -> BoxStream<Bytes, Error> {
+            let mut prefix = heads.join(" ").into_bytes();
+            prefix.push(b':');
+            once(Ok(Bytes::from(prefix))).chain(bundle).boxify()
+        }
+    }
+
+    #[test]
+    fn unbundle_raw() {
+        let logger = Logger::root(Discard, o!());
+        let handler = HgCommandHandler::new(Forwarding, logger, create_hook_manager());
+
+        let input = stream::once(Ok(Bytes::from(&b"3\nabc4\ndefg0\nrest"[..])));
+        let (r, remainder) = handler.handle(
+            SingleRequest::Unbundle {
+                heads: vec!["force".into()],
+            },
+            BytesStream::new(input),
+        );
+        let r = r.collect().wait().expect("unbundle failed");
+        // The reply is sent chunk by chunk as it comes, after the request for the bundle
+        assert!(r.len() > 2, "{:?}", r);
+        let mut reply = Vec::new();
+        for resp in &r[1..] {
+            match *resp {
+                SingleResponse::Unbundle(ref bytes) => reply.extend_from_slice(bytes),
+                ref bad => panic!("Bad result {:?}", bad),
+            }
+        }
+        assert_eq!(&reply[..], b"force:abcdefg");
+
+        let (rest, _) = remainder
+            .wait()
+            .expect("remainder failed")
+            .into_parts();
+        assert_eq!(rest.as_ref(), b"rest");
+    }
+
+    struct ReadOnly;
+    impl HgCommands for ReadOnly {
+        fn is_read_only(&self) -> bool {
+            true
+        }
+
+        fn heads(&self) -> HgCommandRes<HashSet<HgNodeHash>> {
+            future::ok(HashSet::new()).boxify()
+        }
+
+        fn unbundle(
+            &self,
+            _heads: Vec<String>,
+            _stream: BoxStream<Bundle2Item, Error>,
+            _hook_manager: Arc<HookManager>,
+        ) -> HgCommandRes<Bytes> {
+            panic!("unbundle must not be called on a read-only repo")
+        }
+
+        fn preparebookmarkmove(
+            &self,
+            _key: String,
+            _old: String,
+            _new: String,
+            _ttl: u64,
+        ) -> HgCommandRes<Bytes> {
+            panic!("preparebookmarkmove must not be called on a read-only repo")
+        }
+
+        fn commitbookmarkmove(&self, _token: String) -> HgCommandRes<Bytes> {
+            panic!("commitbookmarkmove must not be called on a read-only repo")
+        }
+
+        fn abortbookmarkmove(&self, _token: String) -> HgCommandRes<Bytes> {
+            panic!("abortbookmarkmove must not be called on a read-only repo")
+        }
+    }
+
+    /// One request of every command
+    fn all_requests() -> Vec<SingleRequest> {
+        vec![
+            SingleRequest::Between { pairs: vec![] },
+            SingleRequest::Branchmap,
+            SingleRequest::Capabilities,
+            SingleRequest::Debugwireargs {
+                one: vec![],
+                two: vec![],
+                all_args: HashMap::new(),
+            },
+            SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![],
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                pulltoken: None,
+                pullresumefrom: None,
+                includepattern: vec![],
+                excludepattern: vec![],
+                unknown_args: vec![],
+            }),
+            SingleRequest::Heads,
+            SingleRequest::Hello,
+            SingleRequest::Listkeys {
+                namespace: "bookmarks".into(),
+            },
+            SingleRequest::Lookup { key: "master".into() },
+            SingleRequest::Known { nodes: vec![] },
+            SingleRequest::Knowntrees {
+                bookmark: "master".into(),
+                nodes: vec![],
+            },
+            SingleRequest::Unbundle { heads: vec![] },
+            SingleRequest::Gettreepack(GettreepackArgs {
+                rootdir: Bytes::new(),
+                mfnodes: vec![],
+                basemfnodes: vec![],
+                directories: vec![],
+                depth: None,
+                designatednodes: vec![],
+                unknown_args: vec![],
+            }),
+            SingleRequest::Getfiles,
+            SingleRequest::StreamOutShallow,
+            SingleRequest::PrepareBookmarkMove {
+                key: "master".into(),
+                old: "".into(),
+                new: "".into(),
+                ttl: 0,
+            },
+            SingleRequest::CommitBookmarkMove { token: "".into() },
+            SingleRequest::AbortBookmarkMove { token: "".into() },
+        ]
+    }
+
+    #[test]
+    fn command_classes() {
+        let requests = all_requests();
+        let names: HashSet<_> = requests.iter().map(|req| req.name()).collect();
+        assert_eq!(names.len(), requests.len(), "a command is listed twice");
+
+        let writes: HashSet<_> = requests
+            .iter()
+            .filter(|req| req.class() == CommandClass::Write)
+            .map(|req| req.name())
+            .collect();
+        assert_eq!(
+            writes,
+            hashset! {
+                "unbundle",
+                "preparebookmarkmove",
+                "commitbookmarkmove",
+                "abortbookmarkmove",
+            }
+        );
+
+        for req in &requests {
+            assert_eq!(CommandClass::of_command(req.name()), Some(req.class()));
+        }
+        assert_eq!(CommandClass::of_command("batch"), None);
+    }
+
+    #[test]
+    fn read_only() {
+        let logger = Logger::root(Discard, o!());
+        let handler = HgCommandHandler::new(ReadOnly, logger, create_hook_manager());
+
+        let (r, _) = handler.handle(SingleRequest::Heads, BytesStream::new(stream::empty()));
+        let r = assert_one(r.wait().collect::<Vec<_>>());
+        match r {
+            Ok(SingleResponse::Heads(ref heads)) if heads.is_empty() => (),
+            bad => panic!("Bad result {:?}", bad),
+        }
+
+        for req in all_requests() {
+            if req.class() == CommandClass::Read {
+                continue;
+            }
+            let name = req.name();
+            let (r, _) = handler.handle(req, BytesStream::new(stream::empty()));
+            let r = assert_one(r.wait().collect::<Vec<_>>());
+            match r {
+                Err(err) => match err.downcast::<ErrorKind>() {
+                    Ok(ErrorKind::ReadOnly(ref command)) if command == name => (),
+                    bad => panic!("{}: bad error {:?}", name, bad),
+                },
+                bad => panic!("{}: bad result {:?}", name, bad),
+            }
+        }
+    }
+
     fn create_hook_manager() -> Arc<HookManager> {
         let changeset_store = InMemoryChangesetStore::new();
         let content_store = InMemoryFileContentStore::new();
@@ -687,6 +1072,7 @@ mod test {
             Arc::new(content_store),
             1024,
             1024 * 1024,
+            None,
             logger,
         ))
     }
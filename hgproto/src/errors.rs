@@ -16,4 +16,5 @@ pub enum ErrorKind {
     #[fail(display = "unknown escape character in batch command '{}'", _0)] BatchEscape(u8),
     #[fail(display = "Repo error")] RepoError,
     #[fail(display = "cannot serve revlog repos")] CantServeRevlogRepo,
+    #[fail(display = "repo is read-only, '{}' is not allowed", _0)] ReadOnly(String),
 }
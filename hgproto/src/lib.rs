@@ -51,7 +51,7 @@ use std::sync::Mutex;
 
 use bytes::Bytes;
 
-use mercurial_types::HgNodeHash;
+use mercurial_types::{HgChangesetId, HgManifestId, HgNodeHash};
 
 mod batch;
 mod dechunker;
@@ -102,12 +102,31 @@ pub enum SingleRequest {
     Known {
         nodes: Vec<HgNodeHash>,
     },
+    /// Whether the trees that a client has are still the trees at their directory in the
+    /// current root manifest of `bookmark`, so it can skip gettreepack for them
+    Knowntrees {
+        bookmark: String,
+        /// (directory, manifest node) pairs, like the designated nodes of gettreepack
+        nodes: Vec<(Bytes, HgManifestId)>,
+    },
     Unbundle {
         heads: Vec<String>,
     },
     Gettreepack(GettreepackArgs),
     Getfiles,
     StreamOutShallow,
+    PrepareBookmarkMove {
+        key: String,
+        old: String,
+        new: String,
+        ttl: u64,
+    },
+    CommitBookmarkMove {
+        token: String,
+    },
+    AbortBookmarkMove {
+        token: String,
+    },
 }
 
 impl SingleRequest {
@@ -123,22 +142,87 @@ impl SingleRequest {
             &SingleRequest::Listkeys { .. } => "listkeys",
             &SingleRequest::Lookup { .. } => "lookup",
             &SingleRequest::Known { .. } => "known",
+            &SingleRequest::Knowntrees { .. } => "knowntrees",
             &SingleRequest::Unbundle { .. } => "unbundle",
             &SingleRequest::Gettreepack(_) => "gettreepack",
             &SingleRequest::Getfiles => "getfiles",
             &SingleRequest::StreamOutShallow => "stream_out_shallow",
+            &SingleRequest::PrepareBookmarkMove { .. } => "preparebookmarkmove",
+            &SingleRequest::CommitBookmarkMove { .. } => "commitbookmarkmove",
+            &SingleRequest::AbortBookmarkMove { .. } => "abortbookmarkmove",
+        }
+    }
+}
+
+impl SingleRequest {
+    /// Whether this command only reads the repo or can change it. Every command must have a
+    /// class, so there is no catch-all arm here.
+    pub fn class(&self) -> CommandClass {
+        match self {
+            &SingleRequest::Between { .. }
+            | &SingleRequest::Branchmap
+            | &SingleRequest::Capabilities
+            | &SingleRequest::Debugwireargs { .. }
+            | &SingleRequest::Getbundle(_)
+            | &SingleRequest::Heads
+            | &SingleRequest::Hello
+            | &SingleRequest::Listkeys { .. }
+            | &SingleRequest::Lookup { .. }
+            | &SingleRequest::Known { .. }
+            | &SingleRequest::Knowntrees { .. }
+            | &SingleRequest::Gettreepack(_)
+            | &SingleRequest::Getfiles
+            | &SingleRequest::StreamOutShallow => CommandClass::Read,
+            &SingleRequest::Unbundle { .. }
+            | &SingleRequest::PrepareBookmarkMove { .. }
+            | &SingleRequest::CommitBookmarkMove { .. }
+            | &SingleRequest::AbortBookmarkMove { .. } => CommandClass::Write,
+        }
+    }
+}
+
+impl CommandClass {
+    /// The class of a command given by its name, e.g. one of the names recorded for a session.
+    /// `None` if there is no such command. A test keeps this in sync with `SingleRequest::class`.
+    pub fn of_command(name: &str) -> Option<CommandClass> {
+        match name {
+            "between" | "branchmap" | "capabilities" | "debugwireargs" | "getbundle" | "heads"
+            | "hello" | "listkeys" | "lookup" | "known" | "knowntrees" | "gettreepack"
+            | "getfiles" | "stream_out_shallow" => Some(CommandClass::Read),
+            "unbundle" | "preparebookmarkmove" | "commitbookmarkmove" | "abortbookmarkmove" => {
+                Some(CommandClass::Write)
+            }
+            _ => None,
         }
     }
 }
 
+/// Commands that change the repo are rejected on read-only repos before they are handled
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandClass {
+    Read,
+    Write,
+}
+
 /// The arguments that `getbundle` accepts, in a separate struct for
 /// the convenience of callers.
 #[derive(Eq, PartialEq)]
 pub struct GetbundleArgs {
-    pub heads: Vec<HgNodeHash>,
-    pub common: Vec<HgNodeHash>,
+    pub heads: Vec<HgChangesetId>,
+    pub common: Vec<HgChangesetId>,
     pub bundlecaps: Vec<Vec<u8>>,
     pub listkeys: Vec<Vec<u8>>,
+    /// Token of an interrupted resumable pull that the client wants to continue.
+    pub pulltoken: Option<String>,
+    /// Number of changesets of the interrupted pull that the client has fully received.
+    pub pullresumefrom: Option<usize>,
+    /// Narrow patterns, e.g. "path:dir", of the files and trees to send. Everything is sent if
+    /// there are none.
+    pub includepattern: Vec<Vec<u8>>,
+    /// Narrow patterns of the files and trees not to send, even if they are included
+    pub excludepattern: Vec<Vec<u8>>,
+    /// Arguments that are neither modeled above nor known to be safe to ignore, sorted by name.
+    pub unknown_args: Vec<(Bytes, Bytes)>,
 }
 
 impl Debug for GetbundleArgs {
@@ -151,8 +235,24 @@ impl Debug for GetbundleArgs {
             .iter()
             .map(|s| String::from_utf8_lossy(&s))
             .collect();
-        let heads: Vec<_> = self.heads.iter().take(MAX_NODES_TO_LOG).collect();
-        let common: Vec<_> = self.common.iter().take(MAX_NODES_TO_LOG).collect();
+        let heads: Vec<_> = self.heads
+            .iter()
+            .take(MAX_NODES_TO_LOG)
+            .map(HgChangesetId::as_nodehash)
+            .collect();
+        let common: Vec<_> = self.common
+            .iter()
+            .take(MAX_NODES_TO_LOG)
+            .map(HgChangesetId::as_nodehash)
+            .collect();
+        let includepattern: Vec<_> = self.includepattern
+            .iter()
+            .map(|s| String::from_utf8_lossy(&s))
+            .collect();
+        let excludepattern: Vec<_> = self.excludepattern
+            .iter()
+            .map(|s| String::from_utf8_lossy(&s))
+            .collect();
         fmt.debug_struct("GetbundleArgs")
             .field("heads_len", &self.heads.len())
             .field("heads", &heads)
@@ -160,6 +260,11 @@ impl Debug for GetbundleArgs {
             .field("common", &common)
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
+            .field("pulltoken", &self.pulltoken)
+            .field("pullresumefrom", &self.pullresumefrom)
+            .field("includepattern", &includepattern)
+            .field("excludepattern", &excludepattern)
+            .field("unknown_args", &unknown_arg_names(&self.unknown_args))
             .finish()
     }
 }
@@ -172,14 +277,28 @@ pub struct GettreepackArgs {
     /// "root of the repo".
     pub rootdir: Bytes,
     /// The manifest nodes of the specified root directory to send.
-    pub mfnodes: Vec<HgNodeHash>,
+    pub mfnodes: Vec<HgManifestId>,
     /// The manifest nodes of the rootdir that are already on the client.
-    pub basemfnodes: Vec<HgNodeHash>,
+    pub basemfnodes: Vec<HgManifestId>,
     /// The fullpath (not relative path) of directories underneath
     /// the rootdir that should be sent.
     pub directories: Vec<Bytes>,
     /// The depth from the root that should be sent.
     pub depth: Option<usize>,
+    /// The exact (directory, manifest node) pairs to send, from clients that know about the
+    /// "designatednodes" capability. They are sent as they are instead of diffing mfnodes
+    /// against basemfnodes, so they can't be combined with them.
+    pub designatednodes: Vec<(Bytes, HgManifestId)>,
+    /// Arguments that are not modeled above, sorted by name.
+    pub unknown_args: Vec<(Bytes, Bytes)>,
+}
+
+/// Names of the arguments in `unknown_args` of a request, lossily converted to strings
+pub fn unknown_arg_names(unknown_args: &[(Bytes, Bytes)]) -> Vec<String> {
+    unknown_args
+        .iter()
+        .map(|(name, _)| String::from_utf8_lossy(name).into_owned())
+        .collect()
 }
 
 #[derive(Debug)]
@@ -200,11 +319,15 @@ pub enum SingleResponse {
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
     Lookup(Bytes),
     Known(Vec<bool>),
+    Knowntrees(Vec<bool>),
     ReadyForStream,
     Unbundle(Bytes),
     Gettreepack(Bytes),
     Getfiles(Bytes),
     StreamOutShallow(Bytes),
+    PrepareBookmarkMove(Bytes),
+    CommitBookmarkMove(Bytes),
+    AbortBookmarkMove(Bytes),
 }
 
 impl SingleResponse {
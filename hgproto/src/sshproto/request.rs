@@ -11,7 +11,7 @@ use std::str::{self, FromStr};
 use bytes::{Bytes, BytesMut};
 use nom::{is_alphanumeric, is_digit, ErrorKind, FindSubstring, IResult, Needed, Slice};
 
-use HgNodeHash;
+use {HgChangesetId, HgManifestId, HgNodeHash};
 
 use {GetbundleArgs, GettreepackArgs, Request, SingleRequest};
 use batch;
@@ -188,6 +188,36 @@ named!(
     separated_list_complete!(tag!(" "), nodehash)
 );
 
+/// A space-separated list of changeset hashes
+named!(
+    csidlist<Vec<HgChangesetId>>,
+    map!(hashlist, |hashes| hashes.into_iter().map(HgChangesetId::new).collect())
+);
+
+/// A space-separated list of manifest hashes
+named!(
+    mfidlist<Vec<HgManifestId>>,
+    map!(hashlist, |hashes| hashes.into_iter().map(HgManifestId::new).collect())
+);
+
+/// A designated node of gettreepack: a manifest hash followed by its batch-escaped directory,
+/// which is empty for the root manifest
+named!(
+    designatednode<(Bytes, HgManifestId)>,
+    map_res!(
+        do_parse!(node: nodehash >> dir: take_while!(notcomma) >> ((node, dir))),
+        |(node, dir): (HgNodeHash, &[u8])| {
+            Ok::<_, Error>((Bytes::from(batch::unescape(dir)?), HgManifestId::new(node)))
+        }
+    )
+);
+
+/// A comma-separated list of designated nodes
+named!(
+    designatednodes<Vec<(Bytes, HgManifestId)>>,
+    separated_list_complete!(tag!(","), designatednode)
+);
+
 /// A space-separated list of strings
 named!(
     stringlist<Vec<String>>,
@@ -258,6 +288,55 @@ where
     }
 }
 
+/// Arguments of `getbundle` that are parsed into `GetbundleArgs`
+const GETBUNDLE_ARGS: &[&str] = &[
+    "heads",
+    "common",
+    "bundlecaps",
+    "listkeys",
+    "pulltoken",
+    "pullresumefrom",
+    "includepattern",
+    "excludepattern",
+];
+
+/// Arguments of `getbundle` that every client sends, but that don't change the response:
+/// - cg: the changegroup is always sent
+/// - cbattempted: clonebundles are not supported
+/// - obsmarkers: obsolescence markers are not supported
+const GETBUNDLE_IGNORED_ARGS: &[&str] = &["cg", "cbattempted", "obsmarkers"];
+
+/// Arguments of `gettreepack` that are parsed into `GettreepackArgs`
+const GETTREEPACK_ARGS: &[&str] = &[
+    "rootdir",
+    "mfnodes",
+    "basemfnodes",
+    "directories",
+    "depth",
+    "designatednodes",
+];
+
+/// Returns the parameters that are neither in `known` nor in `ignored`, sorted by name, so that
+/// they can be logged instead of being silently dropped.
+fn unknown_args(
+    params: &HashMap<Vec<u8>, Vec<u8>>,
+    known: &[&str],
+    ignored: &[&str],
+) -> Vec<(Bytes, Bytes)> {
+    let mut unknown: Vec<_> = params
+        .iter()
+        .filter(|(key, _)| {
+            !known
+                .iter()
+                .chain(ignored)
+                .any(|name| name.as_bytes() == key.as_slice())
+        })
+        .map(|(key, value)| (Bytes::from(key.as_slice()), Bytes::from(value.as_slice())))
+        .collect();
+    unknown.sort();
+    unknown
+}
+
 /// Given a hash of parameters, look up a parameter by name, and if it exists,
 /// apply a parser to its value. If it doesn't, return the default value.
 fn parseval_default<'a, F, T>(
@@ -489,15 +568,20 @@ fn parse_with_params(
             }))
         | call!(parse_command, "getbundle", parse_params, 0+1,
             |kv| Ok(Getbundle(GetbundleArgs {
-                // Some params are currently ignored, like:
-                // - obsmarkers
-                // - cg
-                // - cbattempted
-                // If those params are needed, they should be parsed here.
-                heads: parseval_default(&kv, "heads", hashlist)?,
-                common: parseval_default(&kv, "common", hashlist)?,
+                heads: parseval_default(&kv, "heads", csidlist)?,
+                common: parseval_default(&kv, "common", csidlist)?,
                 bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
                 listkeys: parseval_default(&kv, "listkeys", commavalues)?,
+                pulltoken: parseval_option(&kv, "pulltoken", utf8_string_complete)?,
+                pullresumefrom: parseval_option(&kv, "pullresumefrom", closure!(
+                    map_res!(
+                        map_res!(take_while1!(is_digit), str::from_utf8),
+                        usize::from_str
+                    )
+                ))?,
+                includepattern: parseval_default(&kv, "includepattern", commavalues)?,
+                excludepattern: parseval_default(&kv, "excludepattern", commavalues)?,
+                unknown_args: unknown_args(&kv, GETBUNDLE_ARGS, GETBUNDLE_IGNORED_ARGS),
             })))
         | command!("heads", Heads, parse_params, {})
         | command!("hello", Hello, parse_params, {})
@@ -510,24 +594,49 @@ fn parse_with_params(
         | command_star!("known", Known, parse_params, {
               nodes => hashlist,
           })
+        | call!(parse_command, "knowntrees", parse_params, 2,
+            |kv| Ok(Knowntrees {
+                bookmark: parseval(&kv, "bookmark", utf8_string_complete)?,
+                nodes: parseval(&kv, "nodes", designatednodes)?,
+            }))
         | command!("unbundle", Unbundle, parse_params, {
               heads => stringlist,
           })
         | call!(parse_command, "gettreepack", parse_params, 0+1,
             |kv| Ok(Gettreepack(GettreepackArgs {
-                rootdir: parseval(&kv, "rootdir", bytes_complete)?,
-                mfnodes: parseval(&kv, "mfnodes", hashlist)?,
-                basemfnodes: parseval(&kv, "basemfnodes", hashlist)?,
-                directories: parseval(&kv, "directories", gettreepack_directories)?,
+                rootdir: parseval_default(&kv, "rootdir", bytes_complete)?,
+                mfnodes: parseval_default(&kv, "mfnodes", mfidlist)?,
+                basemfnodes: parseval_default(&kv, "basemfnodes", mfidlist)?,
+                directories: parseval_default(&kv, "directories", gettreepack_directories)?,
                 depth: parseval_option(&kv, "depth", closure!(
                     map_res!(
                         map_res!(take_while1!(is_digit), str::from_utf8),
                         usize::from_str
                     )
                 ))?,
+                designatednodes: parseval_default(&kv, "designatednodes", designatednodes)?,
+                unknown_args: unknown_args(&kv, GETTREEPACK_ARGS, &[]),
             })))
         | command!("getfiles", Getfiles, parse_params, {})
         | call!(parse_command, "stream_out_shallow", parse_params, 0+1, |_kv| Ok(StreamOutShallow))
+        | call!(parse_command, "preparebookmarkmove", parse_params, 4,
+            |kv| Ok(PrepareBookmarkMove {
+                key: parseval(&kv, "key", utf8_string_complete)?,
+                old: parseval(&kv, "old", utf8_string_complete)?,
+                new: parseval(&kv, "new", utf8_string_complete)?,
+                ttl: parseval(&kv, "ttl", closure!(
+                    map_res!(
+                        map_res!(take_while1!(is_digit), str::from_utf8),
+                        u64::from_str
+                    )
+                ))?,
+            }))
+        | command!("commitbookmarkmove", CommitBookmarkMove, parse_params, {
+              token => utf8_string_complete,
+          })
+        | command!("abortbookmarkmove", AbortBookmarkMove, parse_params, {
+              token => utf8_string_complete,
+          })
     )
 }
 
@@ -930,6 +1039,39 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_typed_hashlists() {
+        // The typed lists accept exactly the input of hashlist, and wrap the same hashes
+        let p = b"1111111111111111111111111111111111111111 \
+                  0000000000000000000000000000000000000000";
+        let hashes = vec![
+            "1111111111111111111111111111111111111111".parse().unwrap(),
+            NULL_HASH,
+        ];
+        assert_eq!(hashlist(p), IResult::Done(&b""[..], hashes.clone()));
+        assert_eq!(
+            csidlist(p),
+            IResult::Done(
+                &b""[..],
+                hashes.iter().cloned().map(HgChangesetId::new).collect()
+            )
+        );
+        assert_eq!(
+            mfidlist(p),
+            IResult::Done(
+                &b""[..],
+                hashes.iter().cloned().map(HgManifestId::new).collect()
+            )
+        );
+
+        let p = b"";
+        assert_eq!(csidlist(p), IResult::Done(&b""[..], vec![]));
+
+        // incomplete should leave bytes on the wire
+        let p = b"00000000000000000000000000000";
+        assert_eq!(mfidlist(p), IResult::Done(&p[..], vec![]));
+    }
+
     #[test]
     fn test_commavalues() {
         // Empty list
@@ -1021,6 +1163,14 @@ mod test_parse {
         "4444444444444444444444444444444444444444".parse().unwrap()
     }
 
+    fn csids(hashes: &[HgNodeHash]) -> Vec<HgChangesetId> {
+        hashes.iter().cloned().map(HgChangesetId::new).collect()
+    }
+
+    fn mfids(hashes: &[HgNodeHash]) -> Vec<HgManifestId> {
+        hashes.iter().cloned().map(HgManifestId::new).collect()
+    }
+
     /// Common code for testing parsing:
     /// - check all truncated inputs return "Ok(None)"
     /// - complete inputs return the expected result, and leave any remainder in
@@ -1188,6 +1338,11 @@ mod test_parse {
                 common: vec![],
                 bundlecaps: vec![],
                 listkeys: vec![],
+                pulltoken: None,
+                pullresumefrom: None,
+                includepattern: vec![],
+                excludepattern: vec![],
+                unknown_args: vec![],
             })),
         );
 
@@ -1208,10 +1363,96 @@ mod test_parse {
         test_parse(
             inp,
             Request::Single(SingleRequest::Getbundle(GetbundleArgs {
-                heads: vec![hash_ones()],
-                common: vec![hash_twos(), hash_threes()],
+                heads: csids(&[hash_ones()]),
+                common: csids(&[hash_twos(), hash_threes()]),
                 bundlecaps: vec![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
+                pulltoken: None,
+                pullresumefrom: None,
+                includepattern: vec![],
+                excludepattern: vec![],
+                unknown_args: vec![(Bytes::from("extra"), Bytes::from("extra"))],
+            })),
+        );
+
+        // resuming an interrupted pull
+        let inp = "getbundle\n\
+                   * 4\n\
+                   heads 40\n\
+                   1111111111111111111111111111111111111111\
+                   common 40\n\
+                   2222222222222222222222222222222222222222\
+                   pulltoken 2\n\
+                   42\
+                   pullresumefrom 4\n\
+                   1000";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: csids(&[hash_ones()]),
+                common: csids(&[hash_twos()]),
+                bundlecaps: vec![],
+                listkeys: vec![],
+                pulltoken: Some("42".to_string()),
+                pullresumefrom: Some(1000),
+                includepattern: vec![],
+                excludepattern: vec![],
+                unknown_args: vec![],
+            })),
+        );
+
+        // arguments that are ignored are not captured, unknown ones are
+        let inp = "getbundle\n\
+                   * 5\n\
+                   heads 40\n\
+                   1111111111111111111111111111111111111111\
+                   cg 1\n\
+                   1\
+                   cbattempted 1\n\
+                   1\
+                   narrow 1\n\
+                   1\
+                   bookmarks 1\n\
+                   1";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: csids(&[hash_ones()]),
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                pulltoken: None,
+                pullresumefrom: None,
+                includepattern: vec![],
+                excludepattern: vec![],
+                unknown_args: vec![
+                    (Bytes::from("bookmarks"), Bytes::from("1")),
+                    (Bytes::from("narrow"), Bytes::from("1")),
+                ],
+            })),
+        );
+
+        // narrow patterns
+        let inp = "getbundle\n\
+                   * 3\n\
+                   heads 40\n\
+                   1111111111111111111111111111111111111111\
+                   includepattern 21\n\
+                   path:foo/bar,path:baz\
+                   excludepattern 16\n\
+                   path:foo/bar/qux";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: csids(&[hash_ones()]),
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                pulltoken: None,
+                pullresumefrom: None,
+                includepattern: vec![b"path:foo/bar".to_vec(), b"path:baz".to_vec()],
+                excludepattern: vec![b"path:foo/bar/qux".to_vec()],
+                unknown_args: vec![],
             })),
         );
     }
@@ -1272,6 +1513,41 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_parse_lookup_bookmark_names() {
+        for key in &["releases/v1.2", "100%2F", "with space", "a:b,c;d=e"] {
+            let inp = format!("lookup\nkey {}\n{}", key.len(), key);
+            test_parse(
+                inp,
+                Request::Single(SingleRequest::Lookup {
+                    key: key.to_string(),
+                }),
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_batch_lookup_bookmark_names() {
+        // Only ':', ',', ';' and '=' are escaped in batches
+        let cmds = "lookup key=releases/v1.2;\
+                    lookup key=100%2F;\
+                    lookup key=with space;\
+                    lookup key=a:cb:oc:sd:ee";
+        let inp = format!("batch\n* 0\ncmds {}\n{}", cmds.len(), cmds);
+
+        test_parse(
+            inp,
+            Request::Batch(
+                vec!["releases/v1.2", "100%2F", "with space", "a:b,c;d=e"]
+                    .into_iter()
+                    .map(|key| SingleRequest::Lookup {
+                        key: key.to_string(),
+                    })
+                    .collect(),
+            ),
+        );
+    }
+
     #[test]
     fn test_parse_gettreepack() {
         let inp = "gettreepack\n\
@@ -1287,10 +1563,12 @@ mod test_parse {
             inp,
             Request::Single(SingleRequest::Gettreepack(GettreepackArgs {
                 rootdir: Bytes::new(),
-                mfnodes: vec![hash_ones()],
-                basemfnodes: vec![hash_ones()],
+                mfnodes: mfids(&[hash_ones()]),
+                basemfnodes: mfids(&[hash_ones()]),
                 directories: vec![],
                 depth: None,
+                designatednodes: vec![],
+                unknown_args: vec![],
             })),
         );
 
@@ -1312,10 +1590,61 @@ mod test_parse {
             inp,
             Request::Single(SingleRequest::Gettreepack(GettreepackArgs {
                 rootdir: Bytes::from("ololo".as_bytes()),
-                mfnodes: vec![hash_ones(), hash_twos()],
-                basemfnodes: vec![hash_twos(), hash_ones()],
+                mfnodes: mfids(&[hash_ones(), hash_twos()]),
+                basemfnodes: mfids(&[hash_twos(), hash_ones()]),
                 directories: vec![Bytes::from(",".as_bytes()), Bytes::from(";".as_bytes())],
                 depth: Some(1),
+                designatednodes: vec![],
+                unknown_args: vec![],
+            })),
+        );
+
+        let inp = "gettreepack\n\
+                   * 5\n\
+                   rootdir 0\n\
+                   mfnodes 40\n\
+                   1111111111111111111111111111111111111111\
+                   basemfnodes 40\n\
+                   2222222222222222222222222222222222222222\
+                   directories 0\n\
+                   cacheonly 1\n\
+                   1";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Gettreepack(GettreepackArgs {
+                rootdir: Bytes::new(),
+                mfnodes: mfids(&[hash_ones()]),
+                basemfnodes: mfids(&[hash_twos()]),
+                directories: vec![],
+                depth: None,
+                designatednodes: vec![],
+                unknown_args: vec![(Bytes::from("cacheonly"), Bytes::from("1"))],
+            })),
+        );
+
+        // Designated nodes don't come with mfnodes and basemfnodes
+        let inp = "gettreepack\n\
+                   * 2\n\
+                   depth 1\n\
+                   2\
+                   designatednodes 91\n\
+                   1111111111111111111111111111111111111111,\
+                   2222222222222222222222222222222222222222dir/a:o:cb";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Gettreepack(GettreepackArgs {
+                rootdir: Bytes::new(),
+                mfnodes: vec![],
+                basemfnodes: vec![],
+                directories: vec![],
+                depth: Some(2),
+                designatednodes: vec![
+                    (Bytes::new(), HgManifestId::new(hash_ones())),
+                    (Bytes::from("dir/a,:b"), HgManifestId::new(hash_twos())),
+                ],
+                unknown_args: vec![],
             })),
         );
     }
@@ -1344,6 +1673,39 @@ mod test_parse {
         test_parse(inp, Request::Single(SingleRequest::Known { nodes: vec![] }));
     }
 
+    #[test]
+    fn test_parse_knowntrees() {
+        let inp = "knowntrees\n\
+                   bookmark 6\n\
+                   master\n\
+                   nodes 91\n\
+                   1111111111111111111111111111111111111111,\
+                   2222222222222222222222222222222222222222dir/a:o:cb";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Knowntrees {
+                bookmark: "master".into(),
+                nodes: vec![
+                    (Bytes::new(), HgManifestId::new(hash_ones())),
+                    (Bytes::from("dir/a,:b"), HgManifestId::new(hash_twos())),
+                ],
+            }),
+        );
+
+        let inp = "knowntrees\n\
+                   bookmark 6\n\
+                   master\n\
+                   nodes 0\n";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Knowntrees {
+                bookmark: "master".into(),
+                nodes: vec![],
+            }),
+        );
+    }
+
     fn test_parse_unbundle_with(bundle: &[u8]) {
         let inp = b"unbundle\n\
                     heads 10\n\
@@ -1416,4 +1778,50 @@ mod test_parse {
         test_parse(inp, Request::Single(SingleRequest::StreamOutShallow));
     }
 
+    #[test]
+    fn test_parse_prepare_bookmark_move() {
+        let inp = "preparebookmarkmove\n\
+                   key 6\n\
+                   master\
+                   old 0\n\
+                   new 40\n\
+                   1111111111111111111111111111111111111111\
+                   ttl 2\n\
+                   30";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::PrepareBookmarkMove {
+                key: "master".to_string(),
+                old: "".to_string(),
+                new: "1111111111111111111111111111111111111111".to_string(),
+                ttl: 30,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_commit_and_abort_bookmark_move() {
+        let inp = "commitbookmarkmove\n\
+                   token 2\n\
+                   12";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::CommitBookmarkMove {
+                token: "12".to_string(),
+            }),
+        );
+
+        let inp = "abortbookmarkmove\n\
+                   token 2\n\
+                   12";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::AbortBookmarkMove {
+                token: "12".to_string(),
+            }),
+        );
+    }
 }
@@ -99,7 +99,7 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
-        Known(knowns) => {
+        Known(knowns) | Knowntrees(knowns) => {
             let out: Vec<_> = knowns
                 .into_iter()
                 .map(|known| if known { b'1' } else { b'0' })
@@ -122,6 +122,9 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
         Lookup(res) => res,
 
         Listkeys(res) => {
+            // Sorted so that the same keys are always sent in the same order
+            let mut res: Vec<_> = res.into_iter().collect();
+            res.sort();
             let mut bytes = BytesMut::new();
             for (name, key) in res {
                 bytes.extend_from_slice(&name);
@@ -140,6 +143,8 @@ fn encode_cmd(response: SingleResponse) -> Bytes {
 
         StreamOutShallow(res) => res,
 
+        PrepareBookmarkMove(res) | CommitBookmarkMove(res) | AbortBookmarkMove(res) => res,
+
         r => panic!("Response for {:?} unimplemented", r),
     }
 }
@@ -50,7 +50,7 @@ use futures_ext::{BoxFuture, FutureExt};
 use manifold::{ManifoldHttpClient, RequestContext};
 use mercurial_types::{HgNodeHash, RepositoryId};
 use metaconfig::RepoConfigs;
-use repo_client::open_blobrepo;
+use repo_client::{open_blobrepo, OpenRepoParams};
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::{kv_categorizer, kv_defaults, GlogFormat};
 use slog_logview::LogViewDrain;
@@ -91,6 +91,7 @@ fn main() -> Result<()> {
         config.repotype.clone(),
         RepositoryId::new(config.repoid),
         myrouter_port,
+        OpenRepoParams::default(),
     )?;
 
     let rc = RequestContext {
@@ -49,6 +49,7 @@ impl Tailer {
             Arc::new(content_store),
             1024 * 1024, // TODO make configurable T34438181
             1024 * 1024 * 1024,
+            None,
             logger.clone(),
         );
 
@@ -107,12 +108,12 @@ impl Tailer {
             })
             .and_then(move |hg_cs| {
                 info!(logger, "Running file hooks for changeset {:?}", hg_cs);
-                hm.run_file_hooks_for_bookmark(hg_cs.clone(), &bm, None)
+                hm.run_file_hooks_for_bookmark(hg_cs.clone(), &bm, None, None)
                 .map(move |res| (hg_cs, res))
             })
             .and_then(move |(hg_cs, file_res)| {
                 info!(logger2, "Running changeset hooks for changeset {:?}", hg_cs);
-                hm2.run_changeset_hooks_for_bookmark(hg_cs.clone(), &bm2, None)
+                hm2.run_changeset_hooks_for_bookmark(hg_cs.clone(), &bm2, None, None)
                 .map(|res| (file_res, res))
             })
             .collect()
@@ -0,0 +1,282 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Reuse of the accepts of content-only hooks.
+//!
+//! A content-only hook only looks at what a changeset changes, its message and its author, so it
+//! returns the same verdict for a changeset that was rebased onto new parents. When a pushrebase
+//! loses a race and the client retries, the same changesets come back with new hashes: their
+//! accepts are recorded under a fingerprint of the parts that hooks can see, and reused for a
+//! while. Rejections are never reused, so a rejected push always runs its hooks again.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use mononoke_types::{BlobstoreValue, ContentId, FileContents, MononokeId};
+use mononoke_types::hash::{Blake2, Context};
+
+use {ChangedFileType, HookChangeset, HookExecution};
+
+/// How long an accept of a content-only hook is reused by default, in seconds
+pub const DEFAULT_CONTENT_ONLY_TTL_SECS: u64 = 60 * 60;
+
+/// Maximum number of accepts that are kept
+const MAX_ACCEPTS: usize = 100_000;
+
+/// Hash of the message, the author and the changed files of a changeset, with the content of the
+/// added and modified files. Parents and dates are left out, so it doesn't change on rebase.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct HookFingerprint(Blake2);
+
+impl HookFingerprint {
+    pub fn from_changeset(changeset: &HookChangeset) -> BoxFuture<Self, Error> {
+        let files = changeset.files.iter().map(|file| {
+            let path = file.path.clone();
+            let (tag, content_id) = match file.ty {
+                ChangedFileType::Added => ("added", Some(file.file_content())),
+                ChangedFileType::Modified => ("modified", Some(file.file_content())),
+                ChangedFileType::Deleted => ("deleted", None),
+            };
+            let content_id = match content_id {
+                Some(content) => content
+                    .map(|bytes| Some(FileContents::new_bytes(bytes).into_blob().id().clone()))
+                    .left_future(),
+                None => future::ok(None).right_future(),
+            };
+            content_id.map(move |content_id| (path, tag, content_id))
+        });
+
+        let author = changeset.author.clone();
+        let comments = changeset.comments.clone();
+        future::join_all(files)
+            .map(move |mut files| {
+                files.sort_by(|a, b| a.0.cmp(&b.0));
+                HookFingerprint::compute(&author, &comments, &files)
+            })
+            .boxify()
+    }
+
+    fn compute(
+        author: &str,
+        comments: &str,
+        files: &[(String, &'static str, Option<ContentId>)],
+    ) -> Self {
+        let mut context = Context::new(b"hookfingerprint");
+        {
+            // Every field is prefixed with its length, so that they can't run into each other
+            let mut update = |data: &[u8]| {
+                context.update(format!("{}:", data.len()));
+                context.update(data);
+            };
+            update(author.as_bytes());
+            update(comments.as_bytes());
+            for &(ref path, tag, ref content_id) in files {
+                update(path.as_bytes());
+                update(tag.as_bytes());
+                match *content_id {
+                    Some(ref content_id) => update(content_id.blake2().as_ref()),
+                    None => update(b""),
+                }
+            }
+        }
+        HookFingerprint(context.finish())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+struct AcceptKey {
+    hook_name: String,
+    fingerprint: HookFingerprint,
+    /// The file a file hook accepted, none for changeset hooks
+    path: Option<String>,
+}
+
+/// Recent accepts of content-only hooks
+#[derive(Clone)]
+pub struct ContentOnlyAccepts {
+    ttl: Duration,
+    accepts: Arc<Mutex<HashMap<AcceptKey, Instant>>>,
+}
+
+impl ContentOnlyAccepts {
+    pub fn new(ttl: Duration) -> Self {
+        ContentOnlyAccepts {
+            ttl,
+            accepts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Whether the hook accepted a changeset with the same fingerprint less than the TTL ago
+    pub fn is_accepted(
+        &self,
+        hook_name: &str,
+        fingerprint: HookFingerprint,
+        path: Option<&str>,
+    ) -> bool {
+        let key = AcceptKey {
+            hook_name: hook_name.to_string(),
+            fingerprint,
+            path: path.map(|path| path.to_string()),
+        };
+        let accepts = self.accepts.lock().expect("lock poisoned");
+        match accepts.get(&key) {
+            Some(accepted_at) => accepted_at.elapsed() < self.ttl,
+            None => false,
+        }
+    }
+
+    /// Records the verdict of a hook, if it accepted
+    pub fn record(
+        &self,
+        hook_name: &str,
+        fingerprint: HookFingerprint,
+        path: Option<&str>,
+        execution: &HookExecution,
+    ) {
+        if *execution != HookExecution::Accepted {
+            return;
+        }
+
+        let key = AcceptKey {
+            hook_name: hook_name.to_string(),
+            fingerprint,
+            path: path.map(|path| path.to_string()),
+        };
+        let mut accepts = self.accepts.lock().expect("lock poisoned");
+        if accepts.len() >= MAX_ACCEPTS {
+            let ttl = self.ttl;
+            accepts.retain(|_, accepted_at| accepted_at.elapsed() < ttl);
+            if accepts.len() >= MAX_ACCEPTS {
+                return;
+            }
+        }
+        accepts.insert(key, Instant::now());
+    }
+}
+
+/// Reuse of accepts while the hooks of one changeset run
+#[derive(Clone)]
+pub(crate) struct ContentOnlyRun {
+    accepts: ContentOnlyAccepts,
+    hooks: Arc<HashSet<String>>,
+    /// Only computed if one of the hooks that run is content-only
+    fingerprint: Option<HookFingerprint>,
+}
+
+impl ContentOnlyRun {
+    pub(crate) fn start(
+        accepts: ContentOnlyAccepts,
+        hooks: HashSet<String>,
+        changeset: &HookChangeset,
+        hook_names: &[String],
+    ) -> BoxFuture<Self, Error> {
+        let fingerprint = if hook_names.iter().any(|name| hooks.contains(name)) {
+            HookFingerprint::from_changeset(changeset)
+                .map(Some)
+                .left_future()
+        } else {
+            future::ok(None).right_future()
+        };
+        fingerprint
+            .map(move |fingerprint| ContentOnlyRun {
+                accepts,
+                hooks: Arc::new(hooks),
+                fingerprint,
+            })
+            .boxify()
+    }
+
+    /// Runs the hook with `run`, unless it is content-only and a recent accept can be reused
+    pub(crate) fn run<F>(
+        &self,
+        hook_name: &str,
+        path: Option<&str>,
+        run: F,
+    ) -> BoxFuture<HookExecution, Error>
+    where
+        F: FnOnce() -> BoxFuture<HookExecution, Error>,
+    {
+        let fingerprint = match self.fingerprint {
+            Some(fingerprint) if self.hooks.contains(hook_name) => fingerprint,
+            _ => return run(),
+        };
+        if self.accepts.is_accepted(hook_name, fingerprint, path) {
+            return future::ok(HookExecution::Accepted).boxify();
+        }
+
+        let accepts = self.accepts.clone();
+        let hook_name = hook_name.to_string();
+        let path = path.map(|path| path.to_string());
+        run()
+            .inspect(move |execution| {
+                accepts.record(
+                    &hook_name,
+                    fingerprint,
+                    path.as_ref().map(|path| path.as_str()),
+                    execution,
+                )
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fingerprint(author: &str, comments: &str, content: &[u8]) -> HookFingerprint {
+        let content_id = FileContents::new_bytes(content.to_vec())
+            .into_blob()
+            .id()
+            .clone();
+        let files = vec![("a".to_string(), "added", Some(content_id))];
+        HookFingerprint::compute(author, comments, &files)
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let base = fingerprint("alice", "message", b"content");
+        assert_eq!(base, fingerprint("alice", "message", b"content"));
+        assert_ne!(base, fingerprint("bob", "message", b"content"));
+        assert_ne!(base, fingerprint("alice", "other message", b"content"));
+        assert_ne!(base, fingerprint("alice", "message", b"other content"));
+        // Fields can't run into each other
+        assert_ne!(
+            fingerprint("alice", "message", b""),
+            fingerprint("alicemessage", "", b"")
+        );
+    }
+
+    #[test]
+    fn test_accepts() {
+        let accepts = ContentOnlyAccepts::new(Duration::from_secs(DEFAULT_CONTENT_ONLY_TTL_SECS));
+        let fp = fingerprint("alice", "message", b"content");
+        let rejection = HookExecution::Rejected(::HookRejectionInfo::new(
+            "desc".into(),
+            "long_desc".into(),
+        ));
+
+        accepts.record("hook1", fp, None, &rejection);
+        assert!(!accepts.is_accepted("hook1", fp, None));
+
+        accepts.record("hook1", fp, None, &HookExecution::Accepted);
+        assert!(accepts.is_accepted("hook1", fp, None));
+        assert!(!accepts.is_accepted("hook2", fp, None));
+        assert!(!accepts.is_accepted("hook1", fp, Some("a")));
+
+        let expired = ContentOnlyAccepts::new(Duration::from_secs(0));
+        expired.record("hook1", fp, None, &HookExecution::Accepted);
+        assert!(!expired.is_accepted("hook1", fp, None));
+    }
+}
@@ -14,9 +14,13 @@ pub enum ErrorKind {
 
     #[fail(display = "Error while parsing hook '{}'", _0)] HookParseError(String),
     #[fail(display = "Error while running hook '{}'", _0)] HookRuntimeError(String),
+    #[fail(display = "Invalid config of hook '{}': {}", _0, _1)] InvalidHookConfig(String, String),
+    #[fail(display = "Invalid regex '{}': {}", _0, _1)] InvalidRegex(String, String),
 
     #[fail(display = "invalid file structure: {}", _0)] InvalidFileStructure(String),
     #[fail(display = "invalid path: {}", _0)] InvalidPath(MPath),
 
     #[fail(display = "No file content for '{}'", _0)] NoFileContent(HgChangesetId, MPath),
+    #[fail(display = "Changeset {} changes more than {} files, hooks can't check it", _0, _1)]
+    TooManyChangedFiles(HgChangesetId, usize),
 }
@@ -0,0 +1,381 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The builtin `external_message_check` changeset hook, which asks a service whether the message
+//! of a changeset is acceptable, e.g. whether the tasks that it references exist.
+//!
+//! This crate doesn't know how to reach the service: deployments implement `MessageCheckClient`
+//! for their transport, and give it to the `HookManager` when they create it. The hook can't be
+//! loaded by a `HookManager` that has no client.
+//!
+//! The verdicts of the service are cached by the hash of the message, so a changeset that is
+//! pushed again, or rebased, isn't checked again while its verdict is cached. Failures to get a
+//! verdict are not cached.
+//!
+//! The hook config is a table of:
+//! - `endpoint`: where the service is, e.g. a URL or a tier name, which is given as is to the
+//!   client
+//! - `timeout_ms`: how long to wait for the service, 5000 by default
+//! - `policy`: what happens if the service fails or times out, `fail_closed` (the default) to
+//!   reject the changeset, or `fail_open` to accept it
+//! - `cache_ttl_secs`: how long verdicts are cached, 300 by default, 0 to not cache them
+//! - `cache_size`: how many verdicts are cached at most, 10000 by default
+
+#![deny(warnings)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::{finished, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use openssl::sha::sha256;
+use tokio::util::FutureExt as TokioFutureExt;
+use toml;
+
+use super::{Hook, HookChangeset, HookContext, HookExecution, HookRejectionInfo};
+use errors::*;
+
+/// Name of the hook in the `builtin` field of hook configs
+pub const EXTERNAL_MESSAGE_CHECK: &str = "external_message_check";
+
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_CACHE_TTL_SECS: u64 = 300;
+const DEFAULT_CACHE_SIZE: usize = 10000;
+
+/// What the service is asked about a changeset
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MessageCheckRequest {
+    pub repo_name: String,
+    pub author: String,
+    pub message: String,
+}
+
+/// The answer of the service
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessageCheckVerdict {
+    Allow,
+    Deny { reason: String },
+}
+
+/// Transport to the message checking service
+pub trait MessageCheckClient: Send + Sync + 'static {
+    /// Asks the service at `endpoint` for its verdict on `request`. Errors are for failures to
+    /// get a verdict, not for denials.
+    fn check(
+        &self,
+        endpoint: &str,
+        request: MessageCheckRequest,
+    ) -> BoxFuture<MessageCheckVerdict, Error>;
+}
+
+/// What the hook does when the service can't be asked
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailurePolicy {
+    FailOpen,
+    FailClosed,
+}
+
+/// Verdicts of the service by the SHA-256 of the message they are for
+struct VerdictCache {
+    ttl: Duration,
+    max_entries: usize,
+    verdicts: Mutex<HashMap<[u8; 32], (Instant, MessageCheckVerdict)>>,
+}
+
+impl VerdictCache {
+    fn get(&self, key: &[u8; 32]) -> Option<MessageCheckVerdict> {
+        let verdicts = self.verdicts.lock().expect("lock poisoned");
+        match verdicts.get(key) {
+            Some((checked_at, verdict)) if checked_at.elapsed() < self.ttl => {
+                Some(verdict.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&self, key: [u8; 32], verdict: MessageCheckVerdict) {
+        if self.ttl == Duration::from_secs(0) {
+            return;
+        }
+        let mut verdicts = self.verdicts.lock().expect("lock poisoned");
+        if verdicts.len() >= self.max_entries && !verdicts.contains_key(&key) {
+            let ttl = self.ttl;
+            verdicts.retain(|_, (checked_at, _)| checked_at.elapsed() < ttl);
+            if verdicts.len() >= self.max_entries {
+                return;
+            }
+        }
+        verdicts.insert(key, (Instant::now(), verdict));
+    }
+}
+
+pub struct ExternalMessageCheck {
+    client: Arc<MessageCheckClient>,
+    endpoint: String,
+    timeout: Duration,
+    policy: FailurePolicy,
+    cache: Arc<VerdictCache>,
+}
+
+impl ExternalMessageCheck {
+    pub fn new(
+        name: &str,
+        config: Option<&toml::Value>,
+        client: Arc<MessageCheckClient>,
+    ) -> Result<Self, Error> {
+        let invalid =
+            |msg: String| Error::from(ErrorKind::InvalidHookConfig(name.to_string(), msg));
+        let empty = toml::value::Table::new();
+        let config = match config {
+            Some(config) => config
+                .as_table()
+                .ok_or_else(|| invalid(format!("expected a table, found {}", config.type_str())))?,
+            None => &empty,
+        };
+
+        let get_str = |key: &str| match config.get(key) {
+            Some(value) => value
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| invalid(format!("{} must be a string", key))),
+            None => Ok(None),
+        };
+        let get_u64 = |key: &str, default: u64| match config.get(key) {
+            Some(value) => value
+                .as_integer()
+                .and_then(|value| if value >= 0 { Some(value as u64) } else { None })
+                .ok_or_else(|| invalid(format!("{} must be a non-negative integer", key))),
+            None => Ok(default),
+        };
+
+        let endpoint = get_str("endpoint")?.ok_or_else(|| invalid("no endpoint".into()))?;
+        let policy = match get_str("policy")? {
+            None | Some("fail_closed") => FailurePolicy::FailClosed,
+            Some("fail_open") => FailurePolicy::FailOpen,
+            Some(policy) => return Err(invalid(format!("unknown policy {}", policy))),
+        };
+        let timeout = Duration::from_millis(get_u64("timeout_ms", DEFAULT_TIMEOUT_MS)?);
+        let cache = VerdictCache {
+            ttl: Duration::from_secs(get_u64("cache_ttl_secs", DEFAULT_CACHE_TTL_SECS)?),
+            max_entries: get_u64("cache_size", DEFAULT_CACHE_SIZE as u64)? as usize,
+            verdicts: Mutex::new(HashMap::new()),
+        };
+
+        Ok(ExternalMessageCheck {
+            client,
+            endpoint: endpoint.to_string(),
+            timeout,
+            policy,
+            cache: Arc::new(cache),
+        })
+    }
+}
+
+fn rejected(description: &str, long_description: String) -> HookExecution {
+    HookExecution::Rejected(HookRejectionInfo::new(
+        description.to_string(),
+        long_description,
+    ))
+}
+
+fn execution(verdict: MessageCheckVerdict) -> HookExecution {
+    match verdict {
+        MessageCheckVerdict::Allow => HookExecution::Accepted,
+        MessageCheckVerdict::Deny { reason } => {
+            rejected("Commit message was rejected by the message check", reason)
+        }
+    }
+}
+
+impl Hook<HookChangeset> for ExternalMessageCheck {
+    fn run(&self, context: HookContext<HookChangeset>) -> BoxFuture<HookExecution, Error> {
+        let changeset = context.data;
+        let key = sha256(changeset.comments.as_bytes());
+        if let Some(verdict) = self.cache.get(&key) {
+            return finished(execution(verdict)).boxify();
+        }
+
+        let request = MessageCheckRequest {
+            repo_name: context.repo_name,
+            author: changeset.author.clone(),
+            message: changeset.comments.clone(),
+        };
+        let endpoint = self.endpoint.clone();
+        let timeout = self.timeout;
+        let policy = self.policy;
+        let cache = self.cache.clone();
+
+        self.client
+            .check(&self.endpoint, request)
+            .timeout(timeout)
+            .then(move |res| {
+                let failure = match res {
+                    Ok(verdict) => {
+                        cache.insert(key, verdict.clone());
+                        return Ok(execution(verdict));
+                    }
+                    Err(err) => match err.into_inner() {
+                        Some(err) => format!("{} failed: {}", endpoint, err),
+                        None => format!("{} did not answer in {:?}", endpoint, timeout),
+                    },
+                };
+                match policy {
+                    FailurePolicy::FailOpen => Ok(HookExecution::Accepted),
+                    FailurePolicy::FailClosed => Ok(rejected(
+                        "Commit message could not be checked",
+                        failure,
+                    )),
+                }
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::future;
+    use hook_testlib::{ChangesetFixture, TEST_REPO_NAME};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::Runtime;
+
+    /// A service that denies messages without "#task", and counts the requests it gets
+    #[derive(Default)]
+    struct StubClient {
+        requests: AtomicUsize,
+    }
+
+    impl MessageCheckClient for StubClient {
+        fn check(
+            &self,
+            endpoint: &str,
+            request: MessageCheckRequest,
+        ) -> BoxFuture<MessageCheckVerdict, Error> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            match endpoint {
+                "stub" => {}
+                "broken" => return future::err(format_err!("connection refused")).boxify(),
+                "hanging" => return future::empty().boxify(),
+                _ => panic!("unexpected endpoint {}", endpoint),
+            }
+            assert_eq!(request.repo_name, TEST_REPO_NAME);
+            if request.message.contains("#task") {
+                finished(MessageCheckVerdict::Allow).boxify()
+            } else {
+                finished(MessageCheckVerdict::Deny {
+                    reason: format!("{} references no task", request.author),
+                }).boxify()
+            }
+        }
+    }
+
+    fn hook(config: &str, client: &Arc<StubClient>) -> Result<ExternalMessageCheck, Error> {
+        let config: toml::Value = toml::from_str(config).unwrap();
+        ExternalMessageCheck::new("testhook", Some(&config), client.clone())
+    }
+
+    /// Runs the hook on a runtime, as timeouts need its timer
+    fn run(hook: &ExternalMessageCheck, message: &str) -> HookExecution {
+        let changeset = ChangesetFixture::new()
+            .author("Jane Doe <jane@example.com>")
+            .comments(message)
+            .build();
+        let context = HookContext::new(
+            "testhook".to_string(),
+            TEST_REPO_NAME.to_string(),
+            changeset,
+        );
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(hook.run(context)).unwrap()
+    }
+
+    fn description(execution: HookExecution) -> Option<String> {
+        match execution {
+            HookExecution::Accepted | HookExecution::Warned(_) => None,
+            HookExecution::Rejected(info) => Some(info.description),
+        }
+    }
+
+    #[test]
+    fn test_allow_and_deny() {
+        let client = Arc::new(StubClient::default());
+        let check = hook("endpoint = \"stub\"", &client).unwrap();
+
+        assert_eq!(run(&check, "fix the build #task"), HookExecution::Accepted);
+        match run(&check, "fix the build") {
+            HookExecution::Rejected(info) => {
+                assert_eq!(
+                    info.description,
+                    "Commit message was rejected by the message check"
+                );
+                assert_eq!(
+                    info.long_description,
+                    "Jane Doe <jane@example.com> references no task"
+                );
+            }
+            _ => panic!("message without a task was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_failure_policy() {
+        let client = Arc::new(StubClient::default());
+        let not_checked = Some("Commit message could not be checked".to_string());
+        for endpoint in &["broken", "hanging"] {
+            let config = format!("endpoint = \"{}\"\ntimeout_ms = 100", endpoint);
+            let closed = hook(&config, &client).unwrap();
+            assert_eq!(description(run(&closed, "#task")), not_checked, "{}", endpoint);
+
+            let open = hook(&format!("{}\npolicy = \"fail_open\"", config), &client).unwrap();
+            assert_eq!(run(&open, "#task"), HookExecution::Accepted, "{}", endpoint);
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let client = Arc::new(StubClient::default());
+        let check = hook("endpoint = \"stub\"", &client).unwrap();
+
+        assert_eq!(run(&check, "one #task"), HookExecution::Accepted);
+        assert_eq!(run(&check, "one #task"), HookExecution::Accepted);
+        assert!(description(run(&check, "two")).is_some());
+        assert!(description(run(&check, "two")).is_some());
+        assert_eq!(client.requests.load(Ordering::SeqCst), 2);
+
+        // Failures are not cached
+        let broken = hook("endpoint = \"broken\"", &client).unwrap();
+        run(&broken, "three #task");
+        run(&broken, "three #task");
+        assert_eq!(client.requests.load(Ordering::SeqCst), 4);
+
+        let uncached = hook("endpoint = \"stub\"\ncache_ttl_secs = 0", &client).unwrap();
+        run(&uncached, "one #task");
+        run(&uncached, "one #task");
+        assert_eq!(client.requests.load(Ordering::SeqCst), 6);
+
+        let small = hook("endpoint = \"stub\"\ncache_size = 1", &client).unwrap();
+        run(&small, "one #task");
+        run(&small, "two #task");
+        run(&small, "one #task");
+        run(&small, "two #task");
+        assert_eq!(client.requests.load(Ordering::SeqCst), 9);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let client = Arc::new(StubClient::default());
+        assert!(ExternalMessageCheck::new("testhook", None, client.clone()).is_err());
+        assert!(hook("", &client).is_err());
+        assert!(hook("endpoint = 1", &client).is_err());
+        assert!(hook("endpoint = \"stub\"", &client).is_ok());
+        assert!(hook("endpoint = \"stub\"\npolicy = \"fail_open\"", &client).is_ok());
+        assert!(hook("endpoint = \"stub\"\npolicy = \"maybe\"", &client).is_err());
+        assert!(hook("endpoint = \"stub\"\ntimeout_ms = -1", &client).is_err());
+        assert!(hook("endpoint = \"stub\"\ncache_ttl_secs = \"1\"", &client).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Health of the hooks of a repo. A hook that fails to run, e.g. because the content of a file
+//! can't be fetched, is a failure of the hook infrastructure, unlike a hook that rejects a
+//! changeset. While too many of the latest hook runs failed, hooks are degraded, and pushes follow
+//! the `HookDegradedPolicy` of the repo.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::HgChangesetId;
+use metaconfig::repoconfig::{HookDegradedPolicy, HookHealthParams};
+use mononoke_types::MPath;
+use slog::Logger;
+use stats::Timeseries;
+
+use errors::ErrorKind;
+use {FileContentStore, HookExecution};
+
+define_stats! {
+    prefix = "mononoke.hooks";
+    content_fetch_errors: timeseries(RATE, SUM),
+    runtime_errors: timeseries(RATE, SUM),
+    other_errors: timeseries(RATE, SUM),
+    degraded: timeseries(AVG, MAX),
+}
+
+/// Why a hook failed to run
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum HookFailureClass {
+    /// The content of a file couldn't be fetched from the content store
+    ContentFetch,
+    /// The code of the hook failed, e.g. with a Lua error
+    Runtime,
+    Other,
+}
+
+impl HookFailureClass {
+    /// The class of an error of a hook run. Errors of the content store are counted by
+    /// `HealthCheckedContentStore` where they happen, as hooks may report them as their own.
+    pub fn of(err: &Error) -> Self {
+        let is_runtime_error = err.iter_chain()
+            .any(|cause| match cause.downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::HookRuntimeError(_)) => true,
+                _ => false,
+            });
+        if is_runtime_error {
+            HookFailureClass::Runtime
+        } else {
+            HookFailureClass::Other
+        }
+    }
+
+    fn add_stat(&self) {
+        match *self {
+            HookFailureClass::ContentFetch => STATS::content_fetch_errors.add_value(1),
+            HookFailureClass::Runtime => STATS::runtime_errors.add_value(1),
+            HookFailureClass::Other => STATS::other_errors.add_value(1),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HookHealthState {
+    Healthy,
+    Degraded,
+}
+
+struct HealthInner {
+    params: HookHealthParams,
+    /// Whether each of the latest hook runs failed, oldest first
+    runs: VecDeque<bool>,
+    failures: usize,
+    degraded: bool,
+    /// Set by an admin, wins over the state that the runs give
+    override_state: Option<HookHealthState>,
+    failure_counts: HashMap<HookFailureClass, u64>,
+}
+
+impl HealthInner {
+    fn state(&self) -> HookHealthState {
+        match self.override_state {
+            Some(state) => state,
+            None if self.degraded => HookHealthState::Degraded,
+            None => HookHealthState::Healthy,
+        }
+    }
+}
+
+/// Tracks the outcomes of the latest hook runs of a repo
+pub struct HookHealth {
+    logger: Logger,
+    inner: Mutex<HealthInner>,
+}
+
+impl HookHealth {
+    pub fn new(params: HookHealthParams, logger: Logger) -> Self {
+        HookHealth {
+            logger,
+            inner: Mutex::new(HealthInner {
+                params,
+                runs: VecDeque::new(),
+                failures: 0,
+                degraded: false,
+                override_state: None,
+                failure_counts: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Changes the params, which forgets the latest runs
+    pub fn set_params(&self, params: HookHealthParams) {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        inner.params = params;
+        inner.runs.clear();
+        inner.failures = 0;
+        inner.degraded = false;
+    }
+
+    pub fn params(&self) -> HookHealthParams {
+        self.inner.lock().expect("lock poisoned").params
+    }
+
+    pub fn record_result(&self, result: &Result<HookExecution, Error>) {
+        match result {
+            // A rejection is a hook that worked
+            Ok(_) => self.record_run(None),
+            Err(err) => self.record_run(Some(HookFailureClass::of(err))),
+        }
+    }
+
+    /// Counts an error of the content store. The hook run that got it is recorded separately.
+    pub fn record_content_fetch_error(&self) {
+        self.count_failure(HookFailureClass::ContentFetch);
+    }
+
+    fn count_failure(&self, class: HookFailureClass) {
+        class.add_stat();
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        *inner.failure_counts.entry(class).or_insert(0) += 1;
+    }
+
+    fn record_run(&self, failure_class: Option<HookFailureClass>) {
+        if let Some(class) = failure_class {
+            self.count_failure(class);
+        }
+
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        inner.runs.push_back(failure_class.is_some());
+        if failure_class.is_some() {
+            inner.failures += 1;
+        }
+        while inner.runs.len() > inner.params.window {
+            if inner.runs.pop_front() == Some(true) {
+                inner.failures -= 1;
+            }
+        }
+
+        // The ratio is over the whole window, so that a few early failures don't degrade hooks
+        let threshold = inner.params.max_failure_ratio * inner.params.window as f64;
+        let degraded = inner.failures as f64 >= threshold;
+        if degraded != inner.degraded {
+            inner.degraded = degraded;
+            if degraded {
+                warn!(
+                    self.logger,
+                    "hooks are degraded: {} of the latest {} hook runs failed, pushes are {:?}",
+                    inner.failures,
+                    inner.runs.len(),
+                    inner.params.degraded_policy
+                );
+            } else {
+                info!(self.logger, "hooks are healthy again");
+            }
+        }
+        let gauge = match inner.state() {
+            HookHealthState::Healthy => 0,
+            HookHealthState::Degraded => 1,
+        };
+        STATS::degraded.add_value(gauge);
+    }
+
+    pub fn state(&self) -> HookHealthState {
+        self.inner.lock().expect("lock poisoned").state()
+    }
+
+    /// Forces the state of the hooks until it is unset again, e.g. to stop pushes from failing
+    /// during an outage of the content store, or to degrade hooks before they fail
+    pub fn set_override(&self, state: Option<HookHealthState>) {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        if inner.override_state != state {
+            warn!(self.logger, "state of hooks overridden: {:?}", state);
+        }
+        inner.override_state = state;
+    }
+
+    /// The policy that pushes follow, if hooks are degraded
+    pub fn degraded_policy(&self) -> Option<HookDegradedPolicy> {
+        let inner = self.inner.lock().expect("lock poisoned");
+        match inner.state() {
+            HookHealthState::Healthy => None,
+            HookHealthState::Degraded => Some(inner.params.degraded_policy),
+        }
+    }
+
+    /// Number of failures of this class since the hooks were loaded
+    pub fn failure_count(&self, class: HookFailureClass) -> u64 {
+        let inner = self.inner.lock().expect("lock poisoned");
+        inner.failure_counts.get(&class).cloned().unwrap_or(0)
+    }
+}
+
+/// Counts the errors of the content store that hooks read files from
+pub struct HealthCheckedContentStore {
+    inner: Arc<FileContentStore>,
+    health: Arc<HookHealth>,
+}
+
+impl HealthCheckedContentStore {
+    pub fn new(inner: Arc<FileContentStore>, health: Arc<HookHealth>) -> Self {
+        HealthCheckedContentStore { inner, health }
+    }
+}
+
+impl FileContentStore for HealthCheckedContentStore {
+    fn get_file_content_for_changeset(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let health = self.health.clone();
+        self.inner
+            .get_file_content_for_changeset(changesetid, path)
+            .map_err(move |err| {
+                health.record_content_fetch_error();
+                err
+            })
+            .boxify()
+    }
+
+    fn get_file_content_range_for_changeset(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+        offset: u64,
+        len: u64,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let health = self.health.clone();
+        self.inner
+            .get_file_content_range_for_changeset(changesetid, path, offset, len)
+            .map_err(move |err| {
+                health.record_content_fetch_error();
+                err
+            })
+            .boxify()
+    }
+
+    fn get_file_content_in_parent(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let health = self.health.clone();
+        self.inner
+            .get_file_content_in_parent(changesetid, path)
+            .map_err(move |err| {
+                health.record_content_fetch_error();
+                err
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use slog::{Discard, Drain};
+
+    fn health(degraded_policy: HookDegradedPolicy) -> HookHealth {
+        let params = HookHealthParams {
+            window: 4,
+            max_failure_ratio: 0.5,
+            degraded_policy,
+        };
+        HookHealth::new(params, Logger::root(Discard {}.ignore_res(), o!()))
+    }
+
+    fn failure() -> Result<HookExecution, Error> {
+        Err(ErrorKind::HookRuntimeError("boom".into()).into())
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        let health = health(HookDegradedPolicy::ReadOnly);
+        health.record_result(&Ok(HookExecution::Accepted));
+        health.record_result(&failure());
+        assert_eq!(health.state(), HookHealthState::Healthy);
+        assert_eq!(health.degraded_policy(), None);
+
+        // 2 of the 4 latest runs failed
+        health.record_result(&failure());
+        assert_eq!(health.state(), HookHealthState::Degraded);
+        assert_eq!(health.degraded_policy(), Some(HookDegradedPolicy::ReadOnly));
+        assert_eq!(health.failure_count(HookFailureClass::Runtime), 2);
+        assert_eq!(health.failure_count(HookFailureClass::ContentFetch), 0);
+
+        // The failures leave the window
+        for _ in 0..3 {
+            health.record_result(&Ok(HookExecution::Accepted));
+        }
+        assert_eq!(health.state(), HookHealthState::Healthy);
+    }
+
+    #[test]
+    fn test_override() {
+        let health = health(HookDegradedPolicy::FailOpen);
+        health.set_override(Some(HookHealthState::Degraded));
+        assert_eq!(health.degraded_policy(), Some(HookDegradedPolicy::FailOpen));
+
+        for _ in 0..4 {
+            health.record_result(&failure());
+        }
+        health.set_override(Some(HookHealthState::Healthy));
+        assert_eq!(health.degraded_policy(), None);
+        health.set_override(None);
+        assert_eq!(health.state(), HookHealthState::Degraded);
+    }
+}
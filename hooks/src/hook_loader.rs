@@ -8,21 +8,54 @@
 
 #![deny(warnings)]
 
-use super::HookManager;
+use super::{Hook, HookChangeset, HookManager};
+use super::external_message_check::{ExternalMessageCheck, MessageCheckClient,
+                                    EXTERNAL_MESSAGE_CHECK};
 use super::lua_hook::LuaHook;
+use super::verify_signature::{VerifyCommitSignature, VERIFY_COMMIT_SIGNATURE};
 use bookmarks::Bookmark;
 use failure::Error;
-use metaconfig::repoconfig::{HookType, RepoConfig};
-use std::collections::HashSet;
+use metaconfig::repoconfig::{HookParams, HookType, RepoConfig};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 pub fn load_hooks(hook_manager: &mut HookManager, config: RepoConfig) -> Result<(), Error> {
     match config.hooks {
         Some(hooks) => {
             let mut hook_set = HashSet::new();
+            let mut bookmark_hooks: HashMap<Bookmark, Vec<String>> = HashMap::new();
             for hook in hooks {
+                for bookmark in &hook.bookmarks.names {
+                    bookmark_hooks
+                        .entry(bookmark.clone())
+                        .or_insert_with(Vec::new)
+                        .push(hook.name.clone());
+                }
+                for regex in hook.bookmarks.compile_regexes()? {
+                    hook_manager.set_hooks_for_bookmark_regex(regex, vec![hook.name.clone()]);
+                }
+                if let Some(ref builtin) = hook.builtin {
+                    let client = hook_manager.message_check_client().cloned();
+                    let builtin_hook = load_builtin_hook(builtin, &hook, client)?;
+                    if hook.content_only {
+                        hook_manager.set_content_only(&hook.name);
+                    }
+                    hook_manager.set_severity(&hook.name, hook.severity);
+                    hook_manager.register_changeset_hook(&hook.name, builtin_hook, hook.bypass);
+                    hook_set.insert(hook.name);
+                    continue;
+                }
                 let name = hook.name;
-                let lua_hook = LuaHook::new(name.clone(), hook.code.clone());
+                let lua_hook = match hook.config {
+                    Some(ref config) => {
+                        LuaHook::new_with_config(name.clone(), hook.code.clone(), config)?
+                    }
+                    None => LuaHook::new(name.clone(), hook.code.clone()),
+                };
+                if hook.content_only {
+                    hook_manager.set_content_only(&name);
+                }
+                hook_manager.set_severity(&name, hook.severity);
                 match hook.hook_type {
                     HookType::PerAddedOrModifiedFile => {
                         hook_manager.register_file_hook(&name, Arc::new(lua_hook), hook.bypass)
@@ -43,22 +76,59 @@ pub fn load_hooks(hook_manager: &mut HookManager, config: RepoConfig) -> Result<
                         if diff.len() != 0 {
                             return Err(ErrorKind::NoSuchBookmarkHook(bookmark).into());
                         } else {
-                            hook_manager.set_hooks_for_bookmark(bookmark, hooks);
+                            bookmark_hooks
+                                .entry(bookmark)
+                                .or_insert_with(Vec::new)
+                                .extend(hooks);
                         }
                     };
                 },
                 None => (),
             }
+            for (bookmark, hooks) in bookmark_hooks {
+                hook_manager.set_hooks_for_bookmark(bookmark, hooks);
+            }
             Ok(())
         }
         None => Ok(()),
     }
 }
 
+fn load_builtin_hook(
+    builtin: &str,
+    hook: &HookParams,
+    message_check_client: Option<Arc<MessageCheckClient>>,
+) -> Result<Arc<Hook<HookChangeset>>, Error> {
+    match builtin {
+        VERIFY_COMMIT_SIGNATURE | EXTERNAL_MESSAGE_CHECK
+            if hook.hook_type != HookType::PerChangeset =>
+        {
+            Err(ErrorKind::WrongBuiltinHookType(hook.name.clone(), builtin.to_string()).into())
+        }
+        VERIFY_COMMIT_SIGNATURE => {
+            let verify = VerifyCommitSignature::new(&hook.name, hook.config.as_ref())?;
+            Ok(Arc::new(verify))
+        }
+        EXTERNAL_MESSAGE_CHECK => {
+            let client = message_check_client
+                .ok_or_else(|| ErrorKind::NoMessageCheckClient(hook.name.clone()))?;
+            let check = ExternalMessageCheck::new(&hook.name, hook.config.as_ref(), client)?;
+            Ok(Arc::new(check))
+        }
+        _ => Err(ErrorKind::NoSuchBuiltinHook(hook.name.clone(), builtin.to_string()).into()),
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Hook(s) referenced in bookmark {} do not exist", _0)]
     NoSuchBookmarkHook(Bookmark),
+    #[fail(display = "Hook {} is the builtin hook {}, which does not exist", _0, _1)]
+    NoSuchBuiltinHook(String, String),
+    #[fail(display = "Hook {} is the builtin hook {}, which is a PerChangeset hook", _0, _1)]
+    WrongBuiltinHookType(String, String),
+    #[fail(display = "Hook {} checks messages with a service that this server can't reach", _0)]
+    NoMessageCheckClient(String),
 }
 
 #[cfg(test)]
@@ -68,7 +138,11 @@ mod test {
     use super::super::*;
     use async_unit;
     use fixtures::many_files_dirs;
-    use metaconfig::repoconfig::{BookmarkParams, HookParams, RepoType};
+    use external_message_check::{MessageCheckRequest, MessageCheckVerdict};
+    use futures::finished;
+    use futures_ext::{BoxFuture, FutureExt};
+    use metaconfig::repoconfig::{BookmarkParams, HookBookmarks, HookParams, HookSeverity,
+                                 RepoType};
     use slog::{Discard, Drain};
 
     #[test]
@@ -97,21 +171,58 @@ mod test {
                         code: "hook1 code".into(),
                         hook_type: HookType::PerAddedOrModifiedFile,
                         bypass: None,
+                        config: None,
+                        content_only: false,
+                        builtin: None,
+                        severity: HookSeverity::Block,
+                        bookmarks: HookBookmarks::default(),
                     },
                     HookParams {
                         name: "hook2".into(),
                         code: "hook2 code".into(),
                         hook_type: HookType::PerAddedOrModifiedFile,
                         bypass: None,
+                        config: None,
+                        content_only: false,
+                        builtin: None,
+                        severity: HookSeverity::Block,
+                        bookmarks: HookBookmarks::default(),
                     },
                     HookParams {
                         name: "hook3".into(),
                         code: "hook3 code".into(),
                         hook_type: HookType::PerChangeset,
                         bypass: None,
+                        config: None,
+                        content_only: false,
+                        builtin: None,
+                        severity: HookSeverity::Block,
+                        bookmarks: HookBookmarks::default(),
                     },
                 ]),
+                hook_health: Default::default(),
                 pushrebase: Default::default(),
+                push_limits: Default::default(),
+                push_advisory: None,
+                write_forwarding: None,
+                mirroring: None,
+                bookmark_snapshots: None,
+                derived_data: None,
+                strict_wireproto_args: false,
+                deterministic_getbundle: false,
+                file_prefetch: Default::default(),
+                fetch_limits: Default::default(),
+                path_rules: Default::default(),
+                pull_bookmarks: Default::default(),
+                bookmark_creation: Default::default(),
+                scratch_retention: vec![],
+                push_journal: false,
+                cross_repo_index: false,
+                always_hot: false,
+                aliases: vec![],
+                readonly: false,
+                allowed_identities: None,
+                bookmark_move_identities: vec![],
             };
 
             let mut hm = hook_manager_blobrepo();
@@ -143,10 +254,37 @@ mod test {
                         name: "hook1".into(),
                         code: "hook1 code".into(),
                         hook_type: HookType::PerAddedOrModifiedFile,
-                        bypass: None
+                        bypass: None,
+                        config: None,
+                        content_only: false,
+                        builtin: None,
+                        severity: HookSeverity::Block,
+                        bookmarks: HookBookmarks::default(),
                     },
                 ]),
+                hook_health: Default::default(),
                 pushrebase: Default::default(),
+                push_limits: Default::default(),
+                push_advisory: None,
+                write_forwarding: None,
+                mirroring: None,
+                bookmark_snapshots: None,
+                derived_data: None,
+                strict_wireproto_args: false,
+                deterministic_getbundle: false,
+                file_prefetch: Default::default(),
+                fetch_limits: Default::default(),
+                path_rules: Default::default(),
+                pull_bookmarks: Default::default(),
+                bookmark_creation: Default::default(),
+                scratch_retention: vec![],
+                push_journal: false,
+                cross_repo_index: false,
+                always_hot: false,
+                aliases: vec![],
+                readonly: false,
+                allowed_identities: None,
+                bookmark_move_identities: vec![],
             };
 
             let mut hm = hook_manager_blobrepo();
@@ -163,10 +301,127 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_load_builtin_hooks() {
+        async_unit::tokio_unit_test(|| {
+            let builtin_hook = |builtin: &str, hook_type| HookParams {
+                name: "signed".into(),
+                code: "".into(),
+                hook_type,
+                bypass: None,
+                config: Some(toml::Value::Table(btreemap! {
+                    "trusted_keys".to_string() => toml::Value::Array(vec![
+                        toml::Value::String(
+                            "-----BEGIN PUBLIC KEY-----\n\
+                             MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAER3yLh4zluyioFp7MVlA5Eu+vohgg\n\
+                             y/Mi5sKmZl7u4Te9wH5Q57kmfJwKJTw5pbHv7py3yqeiLbkPrC8xbrwyQg==\n\
+                             -----END PUBLIC KEY-----\n"
+                                .into(),
+                        ),
+                    ]),
+                })),
+                content_only: false,
+                builtin: Some(builtin.to_string()),
+                severity: HookSeverity::Block,
+                bookmarks: HookBookmarks::default(),
+            };
+            let load_with = |hook_manager: &mut HookManager, hook: HookParams| {
+                let config = RepoConfig {
+                    repotype: RepoType::Revlog("whatev".into()),
+                    enabled: true,
+                    generation_cache_size: 1,
+                    repoid: 1,
+                    scuba_table: None,
+                    cache_warmup: None,
+                    bookmarks: None,
+                    hooks: Some(vec![hook]),
+                    hook_health: Default::default(),
+                    pushrebase: Default::default(),
+                    push_limits: Default::default(),
+                    push_advisory: None,
+                    write_forwarding: None,
+                    mirroring: None,
+                    bookmark_snapshots: None,
+                    derived_data: None,
+                    strict_wireproto_args: false,
+                    deterministic_getbundle: false,
+                    file_prefetch: Default::default(),
+                    fetch_limits: Default::default(),
+                    path_rules: Default::default(),
+                    pull_bookmarks: Default::default(),
+                    bookmark_creation: Default::default(),
+                    scratch_retention: vec![],
+                    push_journal: false,
+                    cross_repo_index: false,
+                    always_hot: false,
+                    aliases: vec![],
+                    readonly: false,
+                    allowed_identities: None,
+                    bookmark_move_identities: vec![],
+                };
+                load_hooks(hook_manager, config)
+            };
+            let load = |hook: HookParams| load_with(&mut hook_manager_blobrepo(), hook);
+
+            assert!(load(builtin_hook("verify_commit_signature", HookType::PerChangeset)).is_ok());
+            match load(builtin_hook("verify_commit_signature", HookType::PerAddedOrModifiedFile))
+                .unwrap_err()
+                .downcast::<ErrorKind>()
+            {
+                Ok(ErrorKind::WrongBuiltinHookType(..)) => {}
+                _ => assert!(false, "Unexpected err type"),
+            };
+            match load(builtin_hook("no_such_hook", HookType::PerChangeset))
+                .unwrap_err()
+                .downcast::<ErrorKind>()
+            {
+                Ok(ErrorKind::NoSuchBuiltinHook(name, builtin)) => {
+                    assert_eq!(name, "signed");
+                    assert_eq!(builtin, "no_such_hook");
+                }
+                _ => assert!(false, "Unexpected err type"),
+            };
+
+            // The message check needs a client, which only the hook manager has
+            let message_check = HookParams {
+                config: Some(toml::Value::Table(btreemap! {
+                    "endpoint".to_string() => toml::Value::String("checker".into()),
+                })),
+                ..builtin_hook("external_message_check", HookType::PerChangeset)
+            };
+            match load(message_check.clone())
+                .unwrap_err()
+                .downcast::<ErrorKind>()
+            {
+                Ok(ErrorKind::NoMessageCheckClient(name)) => assert_eq!(name, "signed"),
+                _ => assert!(false, "Unexpected err type"),
+            };
+            let mut hook_manager = HookManager::new_with_blobrepo(
+                many_files_dirs::getrepo(None),
+                Some(Arc::new(AllowAll)),
+                Logger::root(Discard {}.ignore_res(), o!()),
+            );
+            assert!(load_with(&mut hook_manager, message_check).is_ok());
+            assert!(hook_manager.changeset_hook_names().contains("signed"));
+        });
+    }
+
+    struct AllowAll;
+
+    impl MessageCheckClient for AllowAll {
+        fn check(
+            &self,
+            _endpoint: &str,
+            _request: MessageCheckRequest,
+        ) -> BoxFuture<MessageCheckVerdict, Error> {
+            finished(MessageCheckVerdict::Allow).boxify()
+        }
+    }
+
     fn hook_manager_blobrepo() -> HookManager {
         let repo = many_files_dirs::getrepo(None);
         let logger = Logger::root(Discard {}.ignore_res(), o!());
-        HookManager::new_with_blobrepo(repo, logger)
+        HookManager::new_with_blobrepo(repo, None, logger)
     }
 
 }
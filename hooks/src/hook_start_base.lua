@@ -22,12 +22,42 @@ __set_common_file_functions = function(path, type)
     return file
 end
 
+__readonly = function(t)
+    if type(t) ~= "table" then
+        return t
+    end
+    local proxy = {}
+    setmetatable(proxy, {
+        __index = function(_, k) return __readonly(t[k]) end,
+        __newindex = function() error("hook config is read-only") end,
+        __len = function() return #t end,
+        __pairs = function()
+            return function(_, k)
+                local nk, nv = next(t, k)
+                return nk, __readonly(nv)
+            end, proxy, nil
+        end,
+        __ipairs = function()
+            return function(_, i)
+                i = i + 1
+                local v = t[i]
+                if v ~= nil then
+                    return i, __readonly(v)
+                end
+            end, proxy, 0
+        end,
+        __metatable = false,
+    })
+    return proxy
+end
+
 __hook_start_base = function(info, arg, setup)
      if hook == nil then
         error("no hook function")
      end
      local ctx = {}
      ctx.info=info
+     ctx.config=__readonly(__hook_config)
      setup(arg, ctx)
      io = nil
      os = nil
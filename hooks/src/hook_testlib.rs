@@ -0,0 +1,462 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Helpers to unit test hooks without a repo.
+//!
+//! Fixtures build the `HookChangeset` or `HookFile` that a hook is run on, with the contents of
+//! their files kept in memory, and `run_changeset_hook` / `run_file_hook` run a hook on them.
+//! A hook is either Lua code or anything implementing `Hook`.
+//!
+//! ```
+//! extern crate hooks;
+//!
+//! use hooks::HookExecution;
+//! use hooks::hook_testlib::{run_changeset_hook, ChangesetFixture};
+//!
+//! # fn main() {
+//! let code = "hook = function (ctx)\n\
+//!             for _, f in ipairs(ctx.files) do\n\
+//!             if f.path == \"secrets.txt\" then return false, \"no secrets\" end\n\
+//!             end\n\
+//!             return true\n\
+//!             end";
+//!
+//! let ok = ChangesetFixture::new().added("README", "hello").build();
+//! assert_eq!(run_changeset_hook(code, ok).unwrap(), HookExecution::Accepted);
+//!
+//! let leak = ChangesetFixture::new().added("secrets.txt", "hunter2").build();
+//! match run_changeset_hook(code, leak).unwrap() {
+//!     HookExecution::Rejected(info) => assert_eq!(info.description, "no secrets"),
+//!     _ => panic!("secrets were accepted"),
+//! }
+//! # }
+//! ```
+//!
+//! Hooks don't see copy information, so there are no fixtures for it: a rename looks like the
+//! deletion of the old path and the addition of the new one, which is what `rename_changeset`
+//! builds.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::Future;
+
+use mercurial_types::{HgChangesetId, MPath};
+
+use super::{ChangedFileType, Hook, HookChangeset, HookChangesetParents, HookContext,
+            HookExecution, HookFile, InMemoryFileContentStore};
+use lua_hook::LuaHook;
+
+/// Name of the hooks run by `run_changeset_hook` and `run_file_hook`
+pub const TEST_HOOK_NAME: &str = "testhook";
+/// Name of the repo that the hooks are run in, available to them as `ctx.info.repo_name`
+pub const TEST_REPO_NAME: &str = "some-repo";
+/// Hash of the changesets of the fixtures, unless they are given another one
+pub const TEST_CHANGESET_HASH: &str = "473b2e715e0df6b2316010908879a3c78e275dd9";
+/// Hash of the first parent of the changesets of the fixtures, that has the parent contents
+pub const TEST_PARENT_CHANGESET_HASH: &str = "1c3cf4bd2e0e7f0ffc3f1cd3c5fc0b4d5e3d4a2b";
+
+/// A hook for the test helpers to run: Lua code, a `LuaHook` or any other `Hook`
+pub trait IntoTestHook<T: Clone> {
+    fn into_test_hook(self) -> Arc<Hook<T>>;
+}
+
+impl<'a, T: Clone> IntoTestHook<T> for &'a str
+where
+    LuaHook: Hook<T>,
+{
+    fn into_test_hook(self) -> Arc<Hook<T>> {
+        self.to_string().into_test_hook()
+    }
+}
+
+impl<T: Clone> IntoTestHook<T> for String
+where
+    LuaHook: Hook<T>,
+{
+    fn into_test_hook(self) -> Arc<Hook<T>> {
+        LuaHook::new(TEST_HOOK_NAME.to_string(), self).into_test_hook()
+    }
+}
+
+impl<T: Clone> IntoTestHook<T> for LuaHook
+where
+    LuaHook: Hook<T>,
+{
+    fn into_test_hook(self) -> Arc<Hook<T>> {
+        Arc::new(self)
+    }
+}
+
+impl<T: Clone> IntoTestHook<T> for Arc<Hook<T>> {
+    fn into_test_hook(self) -> Arc<Hook<T>> {
+        self
+    }
+}
+
+/// Runs a changeset hook on `changeset` in the `TEST_REPO_NAME` repo, and waits for its verdict
+pub fn run_changeset_hook<H>(hook: H, changeset: HookChangeset) -> Result<HookExecution, Error>
+where
+    H: IntoTestHook<HookChangeset>,
+{
+    run_hook(hook, changeset)
+}
+
+/// Runs a file hook on `file` in the `TEST_REPO_NAME` repo, and waits for its verdict
+pub fn run_file_hook<H>(hook: H, file: HookFile) -> Result<HookExecution, Error>
+where
+    H: IntoTestHook<HookFile>,
+{
+    run_hook(hook, file)
+}
+
+fn run_hook<H, T>(hook: H, data: T) -> Result<HookExecution, Error>
+where
+    H: IntoTestHook<T>,
+    T: Clone,
+{
+    let context = HookContext::new(
+        TEST_HOOK_NAME.to_string(),
+        TEST_REPO_NAME.to_string(),
+        data,
+    );
+    hook.into_test_hook().run(context).wait()
+}
+
+fn test_changeset_id() -> HgChangesetId {
+    HgChangesetId::from_str(TEST_CHANGESET_HASH).expect("invalid test changeset hash")
+}
+
+fn test_parent_changeset_id() -> HgChangesetId {
+    HgChangesetId::from_str(TEST_PARENT_CHANGESET_HASH).expect("invalid test changeset hash")
+}
+
+fn to_mpath(path: &str) -> MPath {
+    MPath::new(path.as_bytes()).expect("invalid path in hook fixture")
+}
+
+/// Builder of a `HookChangeset`. It is authored by "some-author" with "some-comments" as its
+/// message and "p1-hash" as its only parent, and has no files or extras, until told otherwise.
+#[derive(Clone, Debug)]
+pub struct ChangesetFixture {
+    changeset_id: HgChangesetId,
+    author: String,
+    comments: String,
+    parents: HookChangesetParents,
+    extras: BTreeMap<String, String>,
+    files: Vec<(String, ChangedFileType, Option<Bytes>)>,
+    /// Contents of files in the first parent
+    parent_files: Vec<(String, Bytes)>,
+}
+
+impl ChangesetFixture {
+    pub fn new() -> Self {
+        Self {
+            changeset_id: test_changeset_id(),
+            author: "some-author".to_string(),
+            comments: "some-comments".to_string(),
+            parents: HookChangesetParents::One("p1-hash".to_string()),
+            extras: BTreeMap::new(),
+            files: vec![],
+            parent_files: vec![],
+        }
+    }
+
+    pub fn changeset_id(mut self, changeset_id: HgChangesetId) -> Self {
+        self.changeset_id = changeset_id;
+        self
+    }
+
+    pub fn author<S: Into<String>>(mut self, author: S) -> Self {
+        self.author = author.into();
+        self
+    }
+
+    pub fn comments<S: Into<String>>(mut self, comments: S) -> Self {
+        self.comments = comments.into();
+        self
+    }
+
+    pub fn parents(mut self, parents: HookChangesetParents) -> Self {
+        self.parents = parents;
+        self
+    }
+
+    pub fn extra<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.extras.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn added<S: Into<String>, B: Into<Bytes>>(mut self, path: S, content: B) -> Self {
+        self.files
+            .push((path.into(), ChangedFileType::Added, Some(content.into())));
+        self
+    }
+
+    pub fn modified<S: Into<String>, B: Into<Bytes>>(mut self, path: S, content: B) -> Self {
+        self.files
+            .push((path.into(), ChangedFileType::Modified, Some(content.into())));
+        self
+    }
+
+    pub fn deleted<S: Into<String>>(mut self, path: S) -> Self {
+        self.files.push((path.into(), ChangedFileType::Deleted, None));
+        self
+    }
+
+    /// The content of `path` in the first parent, for the `parent_content` of modified and
+    /// deleted files
+    pub fn parent_content<S: Into<String>, B: Into<Bytes>>(mut self, path: S, content: B) -> Self {
+        self.parent_files.push((path.into(), content.into()));
+        self
+    }
+
+    /// The changeset, with its files in the order they were added to the fixture
+    pub fn build(self) -> HookChangeset {
+        let mut content_store = InMemoryFileContentStore::new();
+        for &(ref path, _, ref content) in &self.files {
+            if let Some(ref content) = *content {
+                content_store.insert((self.changeset_id, to_mpath(path)), content.clone());
+            }
+        }
+        let parent_id = test_parent_changeset_id();
+        content_store.insert_parent(self.changeset_id, parent_id);
+        for (path, content) in self.parent_files {
+            content_store.insert((parent_id, to_mpath(&path)), content);
+        }
+        let content_store = Arc::new(content_store);
+
+        let changeset_id = self.changeset_id;
+        let files = self.files
+            .into_iter()
+            .map(|(path, ty, _)| HookFile::new(path, content_store.clone(), changeset_id, ty))
+            .collect();
+
+        HookChangeset::new(
+            self.author,
+            files,
+            self.comments,
+            self.parents,
+            self.extras,
+            changeset_id,
+            content_store,
+        )
+    }
+}
+
+/// Builder of a `HookFile`, for file hooks
+#[derive(Clone, Debug)]
+pub struct FileFixture {
+    changeset_id: HgChangesetId,
+    path: String,
+    ty: ChangedFileType,
+    content: Option<Bytes>,
+    parent_content: Option<Bytes>,
+}
+
+impl FileFixture {
+    pub fn added<S: Into<String>, B: Into<Bytes>>(path: S, content: B) -> Self {
+        Self::new(path.into(), ChangedFileType::Added, Some(content.into()))
+    }
+
+    pub fn modified<S: Into<String>, B: Into<Bytes>>(path: S, content: B) -> Self {
+        Self::new(path.into(), ChangedFileType::Modified, Some(content.into()))
+    }
+
+    pub fn deleted<S: Into<String>>(path: S) -> Self {
+        Self::new(path.into(), ChangedFileType::Deleted, None)
+    }
+
+    fn new(path: String, ty: ChangedFileType, content: Option<Bytes>) -> Self {
+        Self {
+            changeset_id: test_changeset_id(),
+            path,
+            ty,
+            content,
+            parent_content: None,
+        }
+    }
+
+    pub fn changeset_id(mut self, changeset_id: HgChangesetId) -> Self {
+        self.changeset_id = changeset_id;
+        self
+    }
+
+    /// The content of the file in the first parent, for modified and deleted files
+    pub fn parent_content<B: Into<Bytes>>(mut self, content: B) -> Self {
+        self.parent_content = Some(content.into());
+        self
+    }
+
+    pub fn build(self) -> HookFile {
+        let mut content_store = InMemoryFileContentStore::new();
+        if let Some(content) = self.content {
+            content_store.insert((self.changeset_id, to_mpath(&self.path)), content);
+        }
+        let parent_id = test_parent_changeset_id();
+        content_store.insert_parent(self.changeset_id, parent_id);
+        if let Some(content) = self.parent_content {
+            content_store.insert((parent_id, to_mpath(&self.path)), content);
+        }
+        HookFile::new(
+            self.path,
+            Arc::new(content_store),
+            self.changeset_id,
+            self.ty,
+        )
+    }
+}
+
+/// A changeset with a file of each type: "file1", "file2" and "file3" are added, "deleted" is
+/// deleted and "modified" is modified. The content of each file is its path followed by
+/// "sausages".
+pub fn default_changeset() -> HookChangeset {
+    let sausages = |path: &str| format!("{}sausages", path);
+    ChangesetFixture::new()
+        .added("file1", sausages("file1"))
+        .added("file2", sausages("file2"))
+        .added("file3", sausages("file3"))
+        .deleted("deleted")
+        .modified("modified", sausages("modified"))
+        .build()
+}
+
+/// A merge of "p1-hash" and "p2-hash" that resolves a conflict in "conflicted"
+pub fn merge_changeset() -> HookChangeset {
+    ChangesetFixture::new()
+        .parents(HookChangesetParents::Two(
+            "p1-hash".to_string(),
+            "p2-hash".to_string(),
+        ))
+        .comments("Merge")
+        .modified("conflicted", "resolved")
+        .build()
+}
+
+/// A changeset that only renames `from` to `to`, which has `content`
+pub fn rename_changeset<B: Into<Bytes>>(from: &str, to: &str, content: B) -> HookChangeset {
+    ChangesetFixture::new()
+        .deleted(from)
+        .added(to, content)
+        .build()
+}
+
+/// A changeset that adds `count` files, "dir/file0" to "dir/file<count - 1>", each with its
+/// path as its content
+pub fn mega_changeset(count: usize) -> HookChangeset {
+    (0..count)
+        .fold(ChangesetFixture::new(), |fixture, i| {
+            let path = format!("dir/file{}", i);
+            fixture.added(path.clone(), path)
+        })
+        .build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_unit;
+    use futures::finished;
+    use futures_ext::{BoxFuture, FutureExt};
+
+    use super::super::HookRejectionInfo;
+
+    struct MaxFilesHook(usize);
+
+    impl Hook<HookChangeset> for MaxFilesHook {
+        fn run(&self, context: HookContext<HookChangeset>) -> BoxFuture<HookExecution, Error> {
+            let execution = if context.data.files.len() <= self.0 {
+                HookExecution::Accepted
+            } else {
+                HookExecution::Rejected(HookRejectionInfo::new(
+                    "too many files".into(),
+                    format!("at most {} files can be changed", self.0),
+                ))
+            };
+            finished(execution).boxify()
+        }
+    }
+
+    #[test]
+    fn test_rust_hook() {
+        async_unit::tokio_unit_test(|| {
+            let hook: Arc<Hook<HookChangeset>> = Arc::new(MaxFilesHook(100));
+            assert_eq!(
+                run_changeset_hook(hook.clone(), mega_changeset(100)).unwrap(),
+                HookExecution::Accepted
+            );
+            assert_matches!(
+                run_changeset_hook(hook, mega_changeset(101)),
+                Ok(HookExecution::Rejected(HookRejectionInfo { ref description, .. }))
+                    if description == "too many files"
+            );
+        });
+    }
+
+    #[test]
+    fn test_mega_changeset() {
+        async_unit::tokio_unit_test(|| {
+            let code = "hook = function (ctx)\n\
+                        local last = ctx.files[1000]\n\
+                        return #ctx.files == 1000 and last.path == \"dir/file999\" and\n\
+                        last.content() == \"dir/file999\"\n\
+                        end";
+            assert_matches!(
+                run_changeset_hook(code, mega_changeset(1000)),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_merge_changeset() {
+        async_unit::tokio_unit_test(|| {
+            let code = "hook = function (ctx)\n\
+                        return ctx.info.parent2_hash ~= nil and\n\
+                        ctx.file_content(\"conflicted\") == \"resolved\"\n\
+                        end";
+            assert_matches!(
+                run_changeset_hook(code, merge_changeset()),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_rename_changeset() {
+        async_unit::tokio_unit_test(|| {
+            let code = "hook = function (ctx)\n\
+                        local old, new = ctx.files[1], ctx.files[2]\n\
+                        return #ctx.files == 2 and old.path == \"old\" and old.is_deleted() and\n\
+                        new.path == \"new\" and new.is_added() and new.content() == \"text\" and\n\
+                        ctx.file_content(\"old\") == nil\n\
+                        end";
+            assert_matches!(
+                run_changeset_hook(code, rename_changeset("old", "new", "text")),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_file_fixture_changeset_id() {
+        async_unit::tokio_unit_test(|| {
+            let cs_id =
+                HgChangesetId::from_str("2d7d4ba9ce0a6ffd222de7785b249ead9c51c536").unwrap();
+            let file = FileFixture::modified("a.txt", "text")
+                .changeset_id(cs_id)
+                .build();
+            let code = "hook = function (ctx)\n\
+                        return ctx.file.is_modified() and ctx.file.content() == \"text\"\n\
+                        end";
+            assert_matches!(run_file_hook(code, file), Ok(HookExecution::Accepted));
+        });
+    }
+}
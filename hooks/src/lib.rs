@@ -36,39 +36,68 @@ extern crate futures_ext;
 extern crate hlua;
 extern crate hlua_futures;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate maplit;
 extern crate mercurial_types;
 extern crate metaconfig;
 extern crate mononoke_types;
+extern crate openssl;
+extern crate regex;
 #[macro_use]
 extern crate slog;
+#[macro_use]
+extern crate stats;
 #[cfg(test)]
 extern crate tempdir;
+extern crate tokio;
+extern crate toml;
 
 pub mod lua_hook;
 pub mod rust_hook;
 pub mod hook_loader;
 pub mod errors;
+pub mod content_only;
+pub mod health;
+pub mod hook_testlib;
+pub mod verify_signature;
+pub mod external_message_check;
 
 use asyncmemo::{Asyncmemo, Filler, Weight};
-use blobrepo::{BlobRepo, HgBlobChangeset};
+use blobrepo::{file_contents_range, BlobRepo, HgBlobChangeset};
 use bookmarks::Bookmark;
 use bytes::Bytes;
+use content_only::{ContentOnlyAccepts, ContentOnlyRun, DEFAULT_CONTENT_ONLY_TTL_SECS};
 pub use errors::*;
+use external_message_check::MessageCheckClient;
+use health::{HealthCheckedContentStore, HookHealth};
 use failure::Error;
 use futures::{failed, finished, Future, IntoFuture, Stream};
 use futures_ext::{BoxFuture, FutureExt};
 use mercurial_types::{Changeset, HgChangesetId, HgParents, MPath, manifest::get_empty_manifest,
                       manifest_utils::{self, EntryStatus}};
-use metaconfig::repoconfig::HookBypass;
-use mononoke_types::FileContents;
+use metaconfig::repoconfig::{HookBypass, HookDegradedPolicy, HookHealthParams, HookSeverity};
+use mononoke_types::{FileContents, MaybeUtf8Bytes};
+use regex::bytes::Regex;
 use slog::Logger;
-use std::collections::{HashMap, HashSet};
+use stats::DynamicTimeseries;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::mem;
+use std::slice;
 use std::str;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+define_stats! {
+    prefix = "mononoke.hooks";
+    warnings: dynamic_timeseries("{}.warnings", (hook: String); RATE, SUM),
+}
+
+/// Hooks see all the changed files of a changeset at once, so a changeset that changes more files
+/// than this can't be checked, and is rejected
+pub const DEFAULT_MAX_CHANGED_FILES: usize = 1_000_000;
 
 type ChangesetHooks = HashMap<String, (Arc<Hook<HookChangeset>>, Option<HookBypass>)>;
 type FileHooks = Arc<Mutex<HashMap<String, (Arc<Hook<HookFile>>, Option<HookBypass>)>>>;
@@ -82,9 +111,19 @@ pub struct HookManager {
     changeset_hooks: ChangesetHooks,
     file_hooks: FileHooks,
     bookmark_hooks: HashMap<Bookmark, Vec<String>>,
+    /// Hooks of the bookmarks whose names match a regex
+    bookmark_regex_hooks: Vec<(regex::Regex, Vec<String>)>,
+    content_only_hooks: HashSet<String>,
+    content_only_accepts: ContentOnlyAccepts,
+    /// Hooks whose rejections are warnings
+    warn_hooks: Arc<HashSet<String>>,
     repo_name: String,
     changeset_store: Box<ChangesetStore>,
     content_store: Arc<FileContentStore>,
+    health: Arc<HookHealth>,
+    /// Transport of the `external_message_check` hooks, if the deployment has one
+    message_check_client: Option<Arc<MessageCheckClient>>,
+    max_changed_files: usize,
     logger: Logger,
 }
 
@@ -95,14 +134,24 @@ impl HookManager {
         content_store: Arc<FileContentStore>,
         entrylimit: usize,
         weightlimit: usize,
+        message_check_client: Option<Arc<MessageCheckClient>>,
         logger: Logger,
     ) -> HookManager {
         let changeset_hooks = HashMap::new();
         let file_hooks = Arc::new(Mutex::new(HashMap::new()));
+        let health = Arc::new(HookHealth::new(
+            HookHealthParams::default(),
+            logger.clone(),
+        ));
+        let content_store = Arc::new(HealthCheckedContentStore::new(
+            content_store,
+            health.clone(),
+        ));
 
         let filler = HookCacheFiller {
             file_hooks: file_hooks.clone(),
             repo_name: repo_name.clone(),
+            health: health.clone(),
         };
         let cache = Asyncmemo::with_limits("hooks", filler, entrylimit, weightlimit);
 
@@ -111,24 +160,42 @@ impl HookManager {
             changeset_hooks,
             file_hooks,
             bookmark_hooks: HashMap::new(),
+            bookmark_regex_hooks: Vec::new(),
+            content_only_hooks: HashSet::new(),
+            content_only_accepts: ContentOnlyAccepts::new(Duration::from_secs(
+                DEFAULT_CONTENT_ONLY_TTL_SECS,
+            )),
+            warn_hooks: Arc::new(HashSet::new()),
             repo_name,
             changeset_store,
             content_store,
+            health,
+            message_check_client,
+            max_changed_files: DEFAULT_MAX_CHANGED_FILES,
             logger,
         }
     }
 
-    pub fn new_with_blobrepo(blobrepo: BlobRepo, logger: Logger) -> HookManager {
+    pub fn new_with_blobrepo(
+        blobrepo: BlobRepo,
+        message_check_client: Option<Arc<MessageCheckClient>>,
+        logger: Logger,
+    ) -> HookManager {
         HookManager::new(
             format!("repo-{:?}", blobrepo.get_repoid()),
             Box::new(BlobRepoChangesetStore::new(blobrepo.clone())),
             Arc::new(BlobRepoFileContentStore::new(blobrepo.clone())),
             1024 * 1024, // TODO make configurable T34438181
             1024 * 1024 * 1024,
+            message_check_client,
             logger,
         )
     }
 
+    pub fn message_check_client(&self) -> Option<&Arc<MessageCheckClient>> {
+        self.message_check_client.as_ref()
+    }
+
     pub fn register_changeset_hook(
         &mut self,
         hook_name: &str,
@@ -153,6 +220,89 @@ impl HookManager {
         self.bookmark_hooks.insert(bookmark, hooks);
     }
 
+    /// Runs `hooks` on the bookmarks whose names match `regex`, in addition to the hooks set for
+    /// them by name
+    pub fn set_hooks_for_bookmark_regex(&mut self, regex: regex::Regex, hooks: Vec<String>) {
+        self.bookmark_regex_hooks.push((regex, hooks));
+    }
+
+    /// The hooks of `bookmarks`: a push that moves several bookmarks runs the hooks of each of
+    /// them, once. None if none of the bookmarks has hooks set.
+    fn hooks_for_bookmarks(&self, bookmarks: &[Bookmark]) -> Option<Vec<String>> {
+        let mut hooks: Option<Vec<String>> = None;
+        for bookmark in bookmarks {
+            let name = bookmark.to_string();
+            let bookmark_hooks = self.bookmark_hooks.get(bookmark).into_iter().chain(
+                self.bookmark_regex_hooks
+                    .iter()
+                    .filter(|(regex, _)| regex.is_match(&name))
+                    .map(|(_, hooks)| hooks),
+            );
+            for bookmark_hooks in bookmark_hooks {
+                let hooks = hooks.get_or_insert_with(Vec::new);
+                for hook in bookmark_hooks {
+                    if !hooks.contains(hook) {
+                        hooks.push(hook.clone());
+                    }
+                }
+            }
+        }
+        hooks
+    }
+
+    /// The bookmarks among `bookmarks` that run the hook `hook_name`, which its rejections are
+    /// attributed to
+    pub fn bookmarks_of_hook(&self, hook_name: &str, bookmarks: &[Bookmark]) -> Vec<Bookmark> {
+        bookmarks
+            .iter()
+            .filter(|bookmark| {
+                self.hooks_for_bookmarks(slice::from_ref(*bookmark))
+                    .map_or(false, |hooks| hooks.iter().any(|hook| hook == hook_name))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Marks a hook as only looking at the content of changesets, so that its accept of a
+    /// changeset is reused for a rebased copy of it. See the `content_only` module.
+    pub fn set_content_only(&mut self, hook_name: &str) {
+        self.content_only_hooks.insert(hook_name.to_string());
+    }
+
+    /// Rejections by a hook of `Warn` severity don't fail the push: they are returned as
+    /// `HookExecution::Warned` instead
+    pub fn set_severity(&mut self, hook_name: &str, severity: HookSeverity) {
+        let warn_hooks = Arc::make_mut(&mut self.warn_hooks);
+        match severity {
+            HookSeverity::Block => warn_hooks.remove(hook_name),
+            HookSeverity::Warn => warn_hooks.insert(hook_name.to_string()),
+        };
+    }
+
+    /// How long accepts of content-only hooks are reused
+    pub fn set_content_only_ttl(&mut self, ttl: Duration) {
+        self.content_only_accepts.set_ttl(ttl);
+    }
+
+    /// When hooks are considered degraded, and what pushes do then
+    pub fn set_health_params(&mut self, params: HookHealthParams) {
+        self.health.set_params(params);
+    }
+
+    /// Changesets that change more files than this are rejected instead of being checked
+    pub fn set_max_changed_files(&mut self, max_changed_files: usize) {
+        self.max_changed_files = max_changed_files;
+    }
+
+    pub fn health(&self) -> &Arc<HookHealth> {
+        &self.health
+    }
+
+    /// The policy that pushes follow, if hooks are degraded. See the `health` module.
+    pub fn degraded_policy(&self) -> Option<HookDegradedPolicy> {
+        self.health.degraded_policy()
+    }
+
     pub fn changeset_hook_names(&self) -> HashSet<String> {
         self.changeset_hooks
             .iter()
@@ -176,15 +326,36 @@ impl HookManager {
         changeset_id: HgChangesetId,
         bookmark: &Bookmark,
         maybe_pushvars: Option<HashMap<String, Bytes>>,
+        pusher: Option<String>,
     ) -> BoxFuture<Vec<(ChangesetHookExecutionID, HookExecution)>, Error> {
-        match self.bookmark_hooks.get(bookmark) {
+        self.run_changeset_hooks_for_bookmarks(
+            changeset_id,
+            slice::from_ref(bookmark),
+            maybe_pushvars,
+            pusher,
+        )
+    }
+
+    /// Runs the changeset hooks of all of `bookmarks` on the changeset
+    pub fn run_changeset_hooks_for_bookmarks(
+        &self,
+        changeset_id: HgChangesetId,
+        bookmarks: &[Bookmark],
+        maybe_pushvars: Option<HashMap<String, Bytes>>,
+        pusher: Option<String>,
+    ) -> BoxFuture<Vec<(ChangesetHookExecutionID, HookExecution)>, Error> {
+        match self.hooks_for_bookmarks(bookmarks) {
             Some(hooks) => {
                 let hooks = hooks
-                    .clone()
                     .into_iter()
                     .filter(|name| self.changeset_hooks.contains_key(name))
                     .collect();
-                self.run_changeset_hooks_for_changeset_id(changeset_id, hooks, maybe_pushvars)
+                self.run_changeset_hooks_for_changeset_id(
+                    changeset_id,
+                    hooks,
+                    maybe_pushvars,
+                    pusher,
+                )
             }
             None => return finished(Vec::new()).boxify(),
         }
@@ -195,6 +366,7 @@ impl HookManager {
         changeset_id: HgChangesetId,
         hooks: Vec<String>,
         maybe_pushvars: Option<HashMap<String, Bytes>>,
+        pusher: Option<String>,
     ) -> BoxFuture<Vec<(ChangesetHookExecutionID, HookExecution)>, Error> {
         let hooks: Result<Vec<(String, (Arc<Hook<HookChangeset>>, _))>, Error> = hooks
             .iter()
@@ -207,21 +379,41 @@ impl HookManager {
             .collect();
         let hooks = try_boxfuture!(hooks);
         let repo_name = self.repo_name.clone();
+        let content_only_accepts = self.content_only_accepts.clone();
+        let content_only_hooks = self.content_only_hooks.clone();
+        let health = self.health.clone();
+        let warn_hooks = self.warn_hooks.clone();
         self.get_hook_changeset(changeset_id)
             .and_then({
                 move |hcs| {
-                    let hooks = HookManager::filter_bypassed_hooks(hooks, &hcs.comments, maybe_pushvars.as_ref());
-
-                    HookManager::run_changeset_hooks_for_changeset(
-                        repo_name,
-                        hcs.clone(),
-                        hooks.clone(),
-                    )
+                    let hooks = HookManager::filter_bypassed_hooks(
+                        hooks,
+                        &hcs.comments,
+                        maybe_pushvars.as_ref(),
+                        pusher.as_ref(),
+                    );
+                    let hook_names: Vec<_> = hooks.iter().map(|(name, _)| name.clone()).collect();
+
+                    ContentOnlyRun::start(
+                        content_only_accepts,
+                        content_only_hooks,
+                        &hcs,
+                        &hook_names,
+                    ).and_then(move |content_only| {
+                        HookManager::run_changeset_hooks_for_changeset(
+                            repo_name,
+                            hcs.clone(),
+                            hooks.clone(),
+                            content_only,
+                            health,
+                        )
+                    })
                 }
             })
             .map(move |res| {
                 res.into_iter()
                     .map(|(hook_name, exec)| {
+                        let exec = apply_severity(&warn_hooks, &hook_name, exec);
                         (
                             ChangesetHookExecutionID {
                                 cs_id: changeset_id,
@@ -239,13 +431,25 @@ impl HookManager {
         repo_name: String,
         changeset: HookChangeset,
         hooks: Vec<(String, Arc<Hook<HookChangeset>>)>,
+        content_only: ContentOnlyRun,
+        health: Arc<HookHealth>,
     ) -> BoxFuture<Vec<(String, HookExecution)>, Error> {
         let v: Vec<BoxFuture<(String, HookExecution), _>> = hooks
             .iter()
             .map(move |(hook_name, hook)| {
                 let hook_context: HookContext<HookChangeset> =
                     HookContext::new(hook_name.clone(), repo_name.clone(), changeset.clone());
-                HookManager::run_changeset_hook(hook.clone(), hook_context)
+                let hook = hook.clone();
+                let hook_name = hook_name.clone();
+                let health = health.clone();
+                content_only
+                    .run(&hook_name, None, move || {
+                        HookManager::run_changeset_hook(hook, hook_context, health)
+                            .map(|(_, he)| he)
+                            .boxify()
+                    })
+                    .map(move |he| (hook_name, he))
+                    .boxify()
             })
             .collect();
         futures::future::join_all(v).boxify()
@@ -254,9 +458,14 @@ impl HookManager {
     fn run_changeset_hook(
         hook: Arc<Hook<HookChangeset>>,
         hook_context: HookContext<HookChangeset>,
+        health: Arc<HookHealth>,
     ) -> BoxFuture<(String, HookExecution), Error> {
         let hook_name = hook_context.hook_name.clone();
         hook.run(hook_context)
+            .then(move |res| {
+                health.record_result(&res);
+                res
+            })
             .map(move |he| (hook_name, he))
             .boxify()
     }
@@ -268,17 +477,33 @@ impl HookManager {
         changeset_id: HgChangesetId,
         bookmark: &Bookmark,
         maybe_pushvars: Option<HashMap<String, Bytes>>,
+        pusher: Option<String>,
+    ) -> BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, Error> {
+        self.run_file_hooks_for_bookmarks(
+            changeset_id,
+            slice::from_ref(bookmark),
+            maybe_pushvars,
+            pusher,
+        )
+    }
+
+    /// Runs the file hooks of all of `bookmarks` on the files of the changeset
+    pub fn run_file_hooks_for_bookmarks(
+        &self,
+        changeset_id: HgChangesetId,
+        bookmarks: &[Bookmark],
+        maybe_pushvars: Option<HashMap<String, Bytes>>,
+        pusher: Option<String>,
     ) -> BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, Error> {
         debug!(
             self.logger.clone(),
-            "Running file hooks for bookmark {:?}",
-            bookmark
+            "Running file hooks for bookmarks {:?}",
+            bookmarks
         );
-        match self.bookmark_hooks.get(bookmark) {
+        match self.hooks_for_bookmarks(bookmarks) {
             Some(hooks) => {
                 let file_hooks = self.file_hooks.lock().unwrap();
                 let hooks = hooks
-                    .clone()
                     .into_iter()
                     .filter_map(|name| file_hooks.get(&name).map(|hook| (name, hook.clone())))
                     .collect();
@@ -286,6 +511,7 @@ impl HookManager {
                     changeset_id,
                     hooks,
                     maybe_pushvars,
+                    pusher,
                     self.logger.clone(),
                 )
             }
@@ -298,6 +524,7 @@ impl HookManager {
         changeset_id: HgChangesetId,
         hooks: Vec<(String, (Arc<Hook<HookFile>>, Option<HookBypass>))>,
         maybe_pushvars: Option<HashMap<String, Bytes>>,
+        pusher: Option<String>,
         logger: Logger,
     ) -> BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, Error> {
         debug!(
@@ -305,22 +532,38 @@ impl HookManager {
             "Running file hooks for changeset id {:?}", changeset_id
         );
         let cache = self.cache.clone();
+        let content_only_accepts = self.content_only_accepts.clone();
+        let content_only_hooks = self.content_only_hooks.clone();
+        let warn_hooks = self.warn_hooks.clone();
         self.get_hook_changeset(changeset_id)
             .and_then(move |hcs| {
                 let hooks = HookManager::filter_bypassed_hooks(
                     hooks.clone(),
                     &hcs.comments,
                     maybe_pushvars.as_ref(),
+                    pusher.as_ref(),
                 );
-                let hooks = hooks.into_iter().map(|(name, _)| name).collect();
+                let hooks: Vec<_> = hooks.into_iter().map(|(name, _)| name).collect();
 
-                HookManager::run_file_hooks_for_changeset(
-                    changeset_id,
-                    hcs.clone(),
-                    hooks,
-                    cache,
-                    logger,
-                )
+                ContentOnlyRun::start(content_only_accepts, content_only_hooks, &hcs, &hooks)
+                    .and_then(move |content_only| {
+                        HookManager::run_file_hooks_for_changeset(
+                            changeset_id,
+                            hcs.clone(),
+                            hooks,
+                            cache,
+                            logger,
+                            content_only,
+                        )
+                    })
+            })
+            .map(move |res| {
+                res.into_iter()
+                    .map(|(id, exec)| {
+                        let exec = apply_severity(&warn_hooks, &id.hook_name, exec);
+                        (id, exec)
+                    })
+                    .collect()
             })
             .boxify()
     }
@@ -331,6 +574,7 @@ impl HookManager {
         hooks: Vec<String>,
         cache: Cache,
         logger: Logger,
+        content_only: ContentOnlyRun,
     ) -> BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, Error> {
         let v: Vec<BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, _>> = changeset
             .files
@@ -345,6 +589,7 @@ impl HookManager {
                             hooks.clone(),
                             cache.clone(),
                             logger.clone(),
+                            content_only.clone(),
                         )
                     ),
                     ChangedFileType::Deleted => None,
@@ -362,6 +607,7 @@ impl HookManager {
         hooks: Vec<String>,
         cache: Cache,
         logger: Logger,
+        content_only: ContentOnlyRun,
     ) -> BoxFuture<Vec<(FileHookExecutionID, HookExecution)>, Error> {
         let v: Vec<BoxFuture<(FileHookExecutionID, HookExecution), _>> = hooks
             .iter()
@@ -374,6 +620,7 @@ impl HookManager {
                     },
                     cache.clone(),
                     logger.clone(),
+                    &content_only,
                 )
             })
             .collect();
@@ -384,32 +631,60 @@ impl HookManager {
         key: FileHookExecutionID,
         cache: Cache,
         logger: Logger,
+        content_only: &ContentOnlyRun,
     ) -> BoxFuture<(FileHookExecutionID, HookExecution), Error> {
         debug!(logger, "Running file hook {:?}", key);
-        cache.get(key.clone()).map(|he| (key, he)).boxify()
+        let run = {
+            cloned!(key);
+            move || cache.get(key).boxify()
+        };
+        content_only
+            .run(&key.hook_name, Some(&key.file.path), run)
+            .map(|he| (key, he))
+            .boxify()
     }
 
     fn get_hook_changeset(&self, changeset_id: HgChangesetId) -> BoxFuture<HookChangeset, Error> {
         let content_store = self.content_store.clone();
         let hg_changeset = self.changeset_store
             .get_changeset_by_changesetid(&changeset_id);
-        let changed_files = self.changeset_store.get_changed_files(&changeset_id);
+        let max_changed_files = self.max_changed_files;
+        let changed_files = self.changeset_store
+            .get_changed_files(&changeset_id, max_changed_files);
+        let logger = self.logger.clone();
         Box::new((hg_changeset, changed_files).into_future().and_then(
             move |(changeset, changed_files)| {
-                let author = str::from_utf8(changeset.user())?.into();
+                if changed_files.len() > max_changed_files {
+                    return Err(
+                        ErrorKind::TooManyChangedFiles(changeset_id, max_changed_files).into(),
+                    );
+                }
+                let author = lossy_field(&logger, changeset_id, "author", changeset.user());
                 let files = changed_files
                     .into_iter()
                     .map(|(path, ty)| {
                         HookFile::new(path, content_store.clone(), changeset_id.clone(), ty)
                     })
                     .collect();
-                let comments = str::from_utf8(changeset.comments())?.into();
+                let comments =
+                    lossy_field(&logger, changeset_id, "comments", changeset.comments());
                 let parents = HookChangesetParents::from(changeset.parents());
+                let extras = changeset
+                    .extra()
+                    .iter()
+                    .map(|(key, value)| {
+                        (
+                            String::from_utf8_lossy(key).into_owned(),
+                            String::from_utf8_lossy(value).into_owned(),
+                        )
+                    })
+                    .collect();
                 Ok(HookChangeset::new(
                     author,
                     files,
                     comments,
                     parents,
+                    extras,
                     changeset_id,
                     content_store,
                 ))
@@ -421,13 +696,14 @@ impl HookManager {
         hooks: Vec<(String, (T, Option<HookBypass>))>,
         commit_msg: &String,
         maybe_pushvars: Option<&HashMap<String, Bytes>>,
+        pusher: Option<&String>,
     ) -> Vec<(String, T)> {
         hooks
             .clone()
             .into_iter()
             .filter_map(|(hook_name, (hook, bypass))| match bypass {
                 Some(bypass) => {
-                    if HookManager::is_hook_bypassed(&bypass, commit_msg, maybe_pushvars) {
+                    if HookManager::is_hook_bypassed(&bypass, commit_msg, maybe_pushvars, pusher) {
                         None
                     } else {
                         Some((hook_name, hook))
@@ -442,10 +718,23 @@ impl HookManager {
         bypass: &HookBypass,
         cs_msg: &String,
         maybe_pushvars: Option<&HashMap<String, Bytes>>,
+        pusher: Option<&String>,
     ) -> bool {
         match bypass {
             HookBypass::CommitMessage(bypass_string) => cs_msg.contains(bypass_string),
-            HookBypass::Pushvar { name, value } => {
+            HookBypass::Pushvar {
+                name,
+                value,
+                identities,
+            } => {
+                // The pusher comes from the connection, never from the pushvars, which the
+                // client controls
+                if let Some(identities) = identities {
+                    match pusher {
+                        Some(pusher) if identities.contains(pusher) => {}
+                        _ => return false,
+                    }
+                }
                 if let Some(pushvars) = maybe_pushvars {
                     let pushvar_val = pushvars
                         .get(name)
@@ -462,6 +751,32 @@ impl HookManager {
     }
 }
 
+/// Turns a rejection by a hook of `Warn` severity into a warning. This happens after the
+/// executions are cached, so that the cache doesn't depend on the severity of hooks.
+fn apply_severity(
+    warn_hooks: &HashSet<String>,
+    hook_name: &str,
+    exec: HookExecution,
+) -> HookExecution {
+    match exec {
+        HookExecution::Rejected(info) if warn_hooks.contains(hook_name) => {
+            STATS::warnings.add_value(1, (hook_name.to_string(),));
+            HookExecution::Warned(info)
+        }
+        exec => exec,
+    }
+}
+
+/// Hooks see the author and comments of a changeset as strings, so bytes that are not valid
+/// UTF-8 are replaced rather than failing every hook on the changeset
+fn lossy_field(logger: &Logger, changeset_id: HgChangesetId, field: &str, bytes: &[u8]) -> String {
+    let bytes = MaybeUtf8Bytes::from(bytes);
+    if !bytes.is_utf8() {
+        warn!(logger, "{} of changeset {} is {}", field, changeset_id, bytes);
+    }
+    bytes.to_lossy_string()
+}
+
 pub trait Hook<T>: Send + Sync
 where
     T: Clone,
@@ -477,6 +792,7 @@ pub struct HookChangeset {
     pub files: Vec<HookFile>,
     pub comments: String,
     pub parents: HookChangesetParents,
+    pub extras: BTreeMap<String, String>,
     content_store: Arc<FileContentStore>,
     changeset_id: HgChangesetId,
 }
@@ -497,7 +813,7 @@ impl PartialEq for HookChangeset {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum ChangedFileType {
     Added,
     Deleted,
@@ -516,6 +832,8 @@ impl From<EntryStatus> for ChangedFileType {
 
 #[derive(Clone)]
 pub struct HookFile {
+    /// The escaped form of the path, see `EscapedPath`. Paths that are not valid UTF-8 stay
+    /// distinct, and the file can still be fetched.
     pub path: String,
     content_store: Arc<FileContentStore>,
     changeset_id: HgChangesetId,
@@ -578,6 +896,15 @@ impl HookFile {
             .boxify()
     }
 
+    /// Whether the content of the file matches the regex `pattern`. The content doesn't have to
+    /// be UTF-8, and is matched without being copied to the hook.
+    pub fn matches_regex(&self, pattern: &str) -> BoxFuture<bool, Error> {
+        let regex = try_boxfuture!(compile_regex(pattern));
+        self.file_content()
+            .map(move |bytes| regex.is_match(&bytes))
+            .boxify()
+    }
+
     pub fn len(&self) -> BoxFuture<u64, Error> {
         self.file_content()
             .and_then(|bytes| Ok(bytes.len() as u64))
@@ -585,7 +912,7 @@ impl HookFile {
     }
 
     pub fn file_content(&self) -> BoxFuture<Bytes, Error> {
-        let path = try_boxfuture!(MPath::new(self.path.as_bytes()));
+        let path = try_boxfuture!(MPath::from_escaped(&self.path));
         let changeset_id = self.changeset_id.clone();
         self.content_store
             .get_file_content_for_changeset(self.changeset_id, path.clone())
@@ -594,6 +921,30 @@ impl HookFile {
             })
             .boxify()
     }
+
+    /// The first `len` bytes of the file, or all of it if it is shorter. The whole content is
+    /// fetched, then truncated to `len` bytes
+    pub fn prefix(&self, len: u64) -> BoxFuture<Bytes, Error> {
+        let path = try_boxfuture!(MPath::from_escaped(&self.path));
+        let changeset_id = self.changeset_id.clone();
+        self.content_store
+            .get_file_content_range_for_changeset(self.changeset_id, path.clone(), 0, len)
+            .and_then(move |opt| {
+                opt.ok_or(ErrorKind::NoFileContent(changeset_id, path.into()).into())
+            })
+            .boxify()
+    }
+
+    /// The content of the file in the first parent of the changeset, to compare it with the new
+    /// content. None for added files, and if the changeset has no parents.
+    pub fn parent_content(&self) -> BoxFuture<Option<Bytes>, Error> {
+        if let ChangedFileType::Added = self.ty {
+            return finished(None).boxify();
+        }
+        let path = try_boxfuture!(MPath::from_escaped(&self.path));
+        self.content_store
+            .get_file_content_in_parent(self.changeset_id, path)
+    }
 }
 
 impl HookChangeset {
@@ -602,6 +953,7 @@ impl HookChangeset {
         files: Vec<HookFile>,
         comments: String,
         parents: HookChangesetParents,
+        extras: BTreeMap<String, String>,
         changeset_id: HgChangesetId,
         content_store: Arc<FileContentStore>,
     ) -> HookChangeset {
@@ -610,30 +962,51 @@ impl HookChangeset {
             files,
             comments,
             parents,
+            extras,
             content_store,
             changeset_id,
         }
     }
 
+    /// `path` is in the escaped form, like the paths of `HookFile`
     pub fn file_content(&self, path: String) -> BoxFuture<Option<Bytes>, Error> {
-        let path = try_boxfuture!(MPath::new(path.as_bytes()));
+        let path = try_boxfuture!(MPath::from_escaped(&path));
         self.content_store
             .get_file_content_for_changeset(self.changeset_id, path.clone())
             .boxify()
     }
+
+    /// Whether the file at `path` matches the regex `pattern`, see `HookFile::matches_regex`.
+    /// None if there is no file at `path`.
+    pub fn regex_match(&self, path: String, pattern: &str) -> BoxFuture<Option<bool>, Error> {
+        let regex = try_boxfuture!(compile_regex(pattern));
+        self.file_content(path)
+            .map(move |opt| opt.map(|bytes| regex.is_match(&bytes)))
+            .boxify()
+    }
+}
+
+fn compile_regex(pattern: &str) -> Result<Regex, Error> {
+    Regex::new(pattern)
+        .map_err(|err| ErrorKind::InvalidRegex(pattern.to_string(), err.to_string()).into())
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum HookExecution {
     Accepted,
     Rejected(HookRejectionInfo),
+    /// A rejection by a hook of `Warn` severity, which doesn't fail the push. Hooks themselves
+    /// never return this: the `HookManager` turns their rejections into warnings.
+    Warned(HookRejectionInfo),
 }
 
 impl Weight for HookExecution {
     fn get_weight(&self) -> usize {
         match self {
             HookExecution::Accepted => mem::size_of::<Self>(),
-            HookExecution::Rejected(info) => mem::size_of::<Self>() + info.get_weight(),
+            HookExecution::Rejected(info) | HookExecution::Warned(info) => {
+                mem::size_of::<Self>() + info.get_weight()
+            }
         }
     }
 }
@@ -666,9 +1039,12 @@ pub trait ChangesetStore: Send + Sync {
         changesetid: &HgChangesetId,
     ) -> BoxFuture<HgBlobChangeset, Error>;
 
+    /// The files that the changeset changes, but no more than `max_files + 1` of them, so that
+    /// a changeset with too many files can be told apart without listing all of them
     fn get_changed_files(
         &self,
         changesetid: &HgChangesetId,
+        max_files: usize,
     ) -> BoxFuture<Vec<(String, ChangedFileType)>, Error>;
 }
 
@@ -687,6 +1063,7 @@ impl ChangesetStore for BlobRepoChangesetStore {
     fn get_changed_files(
         &self,
         changesetid: &HgChangesetId,
+        max_files: usize,
     ) -> BoxFuture<Vec<(String, ChangedFileType)>, Error> {
         cloned!(self.repo);
         self.repo
@@ -708,14 +1085,15 @@ impl ChangesetStore for BlobRepoChangesetStore {
                 };
                 (mf, p_mf)
             })
-            .and_then(|(mf, p_mf)| {
+            .and_then(move |(mf, p_mf)| {
                 manifest_utils::changed_file_stream(&mf, &p_mf, None)
+                    .take(max_files as u64 + 1)
                     .map(|changed_entry| {
                         let path = changed_entry
                             .get_full_path()
                             .expect("File should have a path");
                         let ty = ChangedFileType::from(changed_entry.status);
-                        (String::from_utf8_lossy(&path.to_vec()).into_owned(), ty)
+                        (path.to_escaped().to_string(), ty)
                     })
                     .collect()
             })
@@ -749,12 +1127,14 @@ impl ChangesetStore for InMemoryChangesetStore {
     fn get_changed_files(
         &self,
         changesetid: &HgChangesetId,
+        max_files: usize,
     ) -> BoxFuture<Vec<(String, ChangedFileType)>, Error> {
         match self.map.get(changesetid) {
             Some(cs) => Box::new(finished(
                 cs.files()
                     .into_iter()
-                    .map(|arr| String::from_utf8_lossy(&arr.to_vec()).into_owned())
+                    .take(max_files + 1)
+                    .map(|path| path.to_escaped().to_string())
                     .map(|path| (path, ChangedFileType::Added))
                     .collect(),
             )),
@@ -783,11 +1163,30 @@ pub trait FileContentStore: Send + Sync {
         changesetid: HgChangesetId,
         path: MPath,
     ) -> BoxFuture<Option<Bytes>, Error>;
+
+    /// The `len` bytes at `offset` of the file, truncated at the end of the file
+    fn get_file_content_range_for_changeset(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+        offset: u64,
+        len: u64,
+    ) -> BoxFuture<Option<Bytes>, Error>;
+
+    /// The content of the file in the first parent of the changeset. None if the changeset has
+    /// no parents, or if the file is not in its first parent
+    fn get_file_content_in_parent(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<Bytes>, Error>;
 }
 
 #[derive(Clone)]
 pub struct InMemoryFileContentStore {
     map: HashMap<(HgChangesetId, MPath), Bytes>,
+    /// First parents of the changesets
+    parents: HashMap<HgChangesetId, HgChangesetId>,
 }
 
 impl FileContentStore for InMemoryFileContentStore {
@@ -801,18 +1200,47 @@ impl FileContentStore for InMemoryFileContentStore {
             .map(|bytes| bytes.clone());
         finished(opt).boxify()
     }
+
+    fn get_file_content_range_for_changeset(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+        offset: u64,
+        len: u64,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let opt = self.map
+            .get(&(changesetid, path))
+            .map(|bytes| file_contents_range(FileContents::Bytes(bytes.clone()), offset, len));
+        finished(opt).boxify()
+    }
+
+    fn get_file_content_in_parent(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        match self.parents.get(&changesetid) {
+            Some(parent) => self.get_file_content_for_changeset(*parent, path),
+            None => finished(None).boxify(),
+        }
+    }
 }
 
 impl InMemoryFileContentStore {
     pub fn new() -> InMemoryFileContentStore {
         InMemoryFileContentStore {
             map: HashMap::new(),
+            parents: HashMap::new(),
         }
     }
 
     pub fn insert(&mut self, key: (HgChangesetId, MPath), content: Bytes) {
         self.map.insert(key, content);
     }
+
+    pub fn insert_parent(&mut self, changeset_id: HgChangesetId, parent: HgChangesetId) {
+        self.parents.insert(changeset_id, parent);
+    }
 }
 
 // TODO this can cache file content locally to prevent unnecessary lookup of changeset,
@@ -850,6 +1278,46 @@ impl FileContentStore for BlobRepoFileContentStore {
             })
             .boxify()
     }
+
+    fn get_file_content_range_for_changeset(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+        offset: u64,
+        len: u64,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let repo = self.repo.clone();
+        self.repo
+            .get_changeset_by_changesetid(&changesetid)
+            .and_then({
+                cloned!(repo);
+                move |changeset| repo.find_file_in_manifest(&path, changeset.manifestid().clone())
+            })
+            .and_then(move |opt| match opt {
+                Some(hash) => repo.get_file_content_range(&hash.into_nodehash(), offset, len)
+                    .map(Some)
+                    .boxify(),
+                None => finished(None).boxify(),
+            })
+            .boxify()
+    }
+
+    fn get_file_content_in_parent(
+        &self,
+        changesetid: HgChangesetId,
+        path: MPath,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let store = BlobRepoFileContentStore::new(self.repo.clone());
+        self.repo
+            .get_changeset_by_changesetid(&changesetid)
+            .and_then(move |changeset| match changeset.parents().get_nodes() {
+                (Some(p1), _) => {
+                    store.get_file_content_for_changeset(HgChangesetId::new(*p1), path)
+                }
+                (None, _) => finished(None).boxify(),
+            })
+            .boxify()
+    }
 }
 
 impl BlobRepoFileContentStore {
@@ -861,6 +1329,7 @@ impl BlobRepoFileContentStore {
 struct HookCacheFiller {
     repo_name: String,
     file_hooks: FileHooks,
+    health: Arc<HookHealth>,
 }
 
 impl Filler for HookCacheFiller {
@@ -877,7 +1346,15 @@ impl Filler for HookCacheFiller {
                     self.repo_name.clone(),
                     key.file.clone(),
                 );
-                arc_hook.0.run(hook_context)
+                let health = self.health.clone();
+                arc_hook
+                    .0
+                    .run(hook_context)
+                    .then(move |res| {
+                        health.record_result(&res);
+                        res
+                    })
+                    .boxify()
             }
             None => panic!("Can't find hook {}", key.hook_name), // TODO
         }
@@ -951,12 +1428,14 @@ where
 mod test {
     use super::*;
     use fixtures::many_files_dirs;
+    use health::{HookFailureClass, HookHealthState};
     use futures::{stream, Stream};
     use futures::Future;
     use futures::future::finished;
     use slog::{Discard, Drain};
     use std::collections::hash_map::Entry;
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[derive(Clone, Debug)]
     struct FnChangesetHook {
@@ -1279,6 +1758,33 @@ mod test {
         Box::new(LengthMatchingFileHook { length })
     }
 
+    /// Counts how often it runs
+    #[derive(Clone, Debug)]
+    struct CountingHook {
+        runs: Arc<AtomicUsize>,
+        execution: HookExecution,
+    }
+
+    impl CountingHook {
+        fn new(execution: HookExecution) -> CountingHook {
+            CountingHook {
+                runs: Arc::new(AtomicUsize::new(0)),
+                execution,
+            }
+        }
+
+        fn runs(&self) -> usize {
+            self.runs.load(Ordering::SeqCst)
+        }
+    }
+
+    impl<T: Clone> Hook<T> for CountingHook {
+        fn run(&self, _context: HookContext<T>) -> BoxFuture<HookExecution, Error> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            finished(self.execution.clone()).boxify()
+        }
+    }
+
     #[test]
     fn test_changeset_hook_accepted() {
         async_unit::tokio_unit_test(|| {
@@ -1332,6 +1838,48 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_changeset_hook_warn_severity() {
+        async_unit::tokio_unit_test(|| {
+            let bookmarks = hashmap! {
+                "bm1".to_string() => vec!["warn".to_string(), "block".to_string()]
+            };
+            let mut hook_manager = setup_hook_manager(bookmarks, true);
+            hook_manager.register_changeset_hook(
+                "warn",
+                always_rejecting_changeset_hook().into(),
+                None,
+            );
+            hook_manager.register_changeset_hook(
+                "block",
+                always_rejecting_changeset_hook().into(),
+                None,
+            );
+            hook_manager.set_severity("warn", HookSeverity::Warn);
+            hook_manager.set_severity("block", HookSeverity::Block);
+
+            let res = hook_manager
+                .run_changeset_hooks_for_bookmark(
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                    None,
+                )
+                .wait()
+                .unwrap();
+            let map: HashMap<String, HookExecution> = res.into_iter()
+                .map(|(exec_id, exec)| (exec_id.hook_name, exec))
+                .collect();
+            assert_eq!(
+                map,
+                hashmap! {
+                    "warn".to_string() => default_warning(),
+                    "block".to_string() => default_rejection(),
+                }
+            );
+        });
+    }
+
     #[test]
     fn test_changeset_hook_context() {
         async_unit::tokio_unit_test(|| {
@@ -1360,6 +1908,7 @@ mod test {
                 hook_files,
                 "3".into(),
                 parents,
+                BTreeMap::new(),
                 cs_id,
                 content_store,
             );
@@ -1512,6 +2061,42 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_changeset_hook_max_changed_files() {
+        async_unit::tokio_unit_test(|| {
+            for inmem in vec![true, false] {
+                let bookmarks = hashmap! {
+                    "bm1".to_string() => vec!["hook1".to_string()]
+                };
+                let mut hook_manager = setup_hook_manager(bookmarks, inmem);
+                hook_manager.register_changeset_hook(
+                    "hook1",
+                    always_accepting_changeset_hook().into(),
+                    None,
+                );
+                let bookmark = Bookmark::new("bm1").unwrap();
+
+                // The default changeset changes 3 files
+                hook_manager.set_max_changed_files(3);
+                let res = hook_manager
+                    .run_changeset_hooks_for_bookmark(default_changeset_id(), &bookmark, None, None)
+                    .wait()
+                    .unwrap();
+                assert_eq!(res.len(), 1);
+
+                hook_manager.set_max_changed_files(2);
+                let err = hook_manager
+                    .run_changeset_hooks_for_bookmark(default_changeset_id(), &bookmark, None, None)
+                    .wait()
+                    .expect_err("the changeset has too many files");
+                assert_matches!(
+                    err.downcast::<ErrorKind>(),
+                    Ok(ErrorKind::TooManyChangedFiles(_, 2))
+                );
+            }
+        });
+    }
+
     #[test]
     fn test_file_hook_accepted() {
         async_unit::tokio_unit_test(|| {
@@ -1578,6 +2163,47 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_file_hook_warn_severity() {
+        async_unit::tokio_unit_test(move || {
+            let bookmarks = hashmap! {
+                "bm1".to_string() => vec!["warn".to_string()]
+            };
+            let mut hook_manager = setup_hook_manager(bookmarks, true);
+            hook_manager.register_file_hook("warn", always_rejecting_file_hook().into(), None);
+            hook_manager.set_severity("warn", HookSeverity::Warn);
+
+            let res = hook_manager
+                .run_file_hooks_for_bookmark(
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                    None,
+                )
+                .wait()
+                .unwrap();
+            assert_eq!(res.len(), 3);
+            for (_, exec) in res {
+                assert_eq!(exec, default_warning());
+            }
+
+            // Back to blocking, the rejections that were cached are rejections again
+            hook_manager.set_severity("warn", HookSeverity::Block);
+            let res = hook_manager
+                .run_file_hooks_for_bookmark(
+                    default_changeset_id(),
+                    &Bookmark::new("bm1").unwrap(),
+                    None,
+                    None,
+                )
+                .wait()
+                .unwrap();
+            for (_, exec) in res {
+                assert_eq!(exec, default_rejection());
+            }
+        });
+    }
+
     #[test]
     fn test_file_hooks_paths() {
         async_unit::tokio_unit_test(move || {
@@ -1773,6 +2399,354 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_content_only_changeset_hooks_on_retry() {
+        async_unit::tokio_unit_test(|| {
+            let content_only = CountingHook::new(HookExecution::Accepted);
+            let other = CountingHook::new(HookExecution::Accepted);
+            let rejecting = CountingHook::new(default_rejection());
+            let mut hook_manager = hook_manager_with_rebased_changeset();
+            hook_manager.register_changeset_hook(
+                "content_only",
+                Arc::new(content_only.clone()),
+                None,
+            );
+            hook_manager.register_changeset_hook("other", Arc::new(other.clone()), None);
+            hook_manager.register_changeset_hook("rejecting", Arc::new(rejecting.clone()), None);
+            hook_manager.set_content_only("content_only");
+            hook_manager.set_content_only("rejecting");
+            let bookmark = Bookmark::new("bm1").unwrap();
+            hook_manager.set_hooks_for_bookmark(
+                bookmark.clone(),
+                vec!["content_only".into(), "other".into(), "rejecting".into()],
+            );
+
+            // The push, and its retry after losing a pushrebase race
+            for cs_id in vec![default_changeset_id(), rebased_changeset_id()] {
+                let res = hook_manager
+                    .run_changeset_hooks_for_bookmark(cs_id, &bookmark, None, None)
+                    .wait()
+                    .unwrap();
+                let map: HashMap<String, HookExecution> = res.into_iter()
+                    .map(|(exec_id, exec)| (exec_id.hook_name, exec))
+                    .collect();
+                assert_eq!(
+                    map,
+                    hashmap! {
+                        "content_only".to_string() => HookExecution::Accepted,
+                        "other".to_string() => HookExecution::Accepted,
+                        "rejecting".to_string() => default_rejection(),
+                    }
+                );
+            }
+
+            assert_eq!(content_only.runs(), 1);
+            assert_eq!(other.runs(), 2);
+            // Rejections are never reused
+            assert_eq!(rejecting.runs(), 2);
+        });
+    }
+
+    #[test]
+    fn test_content_only_file_hooks_on_retry() {
+        async_unit::tokio_unit_test(|| {
+            let content_only = CountingHook::new(HookExecution::Accepted);
+            let other = CountingHook::new(HookExecution::Accepted);
+            let mut hook_manager = hook_manager_with_rebased_changeset();
+            hook_manager.register_file_hook("content_only", Arc::new(content_only.clone()), None);
+            hook_manager.register_file_hook("other", Arc::new(other.clone()), None);
+            hook_manager.set_content_only("content_only");
+            let bookmark = Bookmark::new("bm1").unwrap();
+            hook_manager.set_hooks_for_bookmark(
+                bookmark.clone(),
+                vec!["content_only".into(), "other".into()],
+            );
+
+            for cs_id in vec![default_changeset_id(), rebased_changeset_id()] {
+                let res = hook_manager
+                    .run_file_hooks_for_bookmark(cs_id, &bookmark, None, None)
+                    .wait()
+                    .unwrap();
+                assert_eq!(res.len(), 6);
+                assert!(res.iter().all(|(_, exec)| *exec == HookExecution::Accepted));
+            }
+
+            // Once per file
+            assert_eq!(content_only.runs(), 3);
+            assert_eq!(other.runs(), 6);
+        });
+    }
+
+    #[test]
+    fn test_content_only_ttl() {
+        async_unit::tokio_unit_test(|| {
+            let content_only = CountingHook::new(HookExecution::Accepted);
+            let mut hook_manager = hook_manager_with_rebased_changeset();
+            hook_manager.register_changeset_hook(
+                "content_only",
+                Arc::new(content_only.clone()),
+                None,
+            );
+            hook_manager.set_content_only("content_only");
+            hook_manager.set_content_only_ttl(Duration::from_secs(0));
+            let bookmark = Bookmark::new("bm1").unwrap();
+            hook_manager.set_hooks_for_bookmark(bookmark.clone(), vec!["content_only".into()]);
+
+            for cs_id in vec![default_changeset_id(), rebased_changeset_id()] {
+                hook_manager
+                    .run_changeset_hooks_for_bookmark(cs_id, &bookmark, None, None)
+                    .wait()
+                    .unwrap();
+            }
+
+            // The accept expired before the retry
+            assert_eq!(content_only.runs(), 2);
+        });
+    }
+
+    #[test]
+    fn test_hook_file_non_utf8_path() {
+        let cs_id = default_changeset_id();
+        let latin1 = MPath::new(&b"dir/caf\xe9"[..]).unwrap();
+        let utf8 = MPath::new("dir/caf\u{e9}").unwrap();
+        let mut content_store = InMemoryFileContentStore::new();
+        content_store.insert((cs_id, latin1.clone()), "latin1".into());
+        content_store.insert((cs_id, utf8.clone()), "utf8".into());
+        let content_store = Arc::new(content_store);
+
+        let file = |path: &MPath| {
+            HookFile::new(
+                path.to_escaped().to_string(),
+                content_store.clone(),
+                cs_id,
+                ChangedFileType::Added,
+            )
+        };
+        let latin1_file = file(&latin1);
+        let utf8_file = file(&utf8);
+        assert_eq!(latin1_file.path, "dir/caf\\xe9");
+        assert_eq!(utf8_file.path, "dir/caf\u{e9}");
+        assert_eq!(
+            latin1_file.file_content().wait().unwrap(),
+            Bytes::from("latin1")
+        );
+        assert_eq!(utf8_file.file_content().wait().unwrap(), Bytes::from("utf8"));
+    }
+
+    #[test]
+    fn test_lossy_field() {
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let cs_id = default_changeset_id();
+        assert_eq!(lossy_field(&logger, cs_id, "author", b"Jane Doe"), "Jane Doe");
+        assert_eq!(
+            lossy_field(&logger, cs_id, "comments", b"fix \xff bug"),
+            "fix \u{fffd} bug"
+        );
+    }
+
+    #[test]
+    fn test_pushvar_bypass_identities() {
+        let pushvars = hashmap! {
+            "BYPASS".to_string() => Bytes::from("true"),
+        };
+        let msg = "msg".to_string();
+        let alice = "alice".to_string();
+        let mallory = "mallory".to_string();
+        let bypassed = |identities: Option<Vec<String>>, pusher: Option<&String>| {
+            let bypass = HookBypass::Pushvar {
+                name: "BYPASS".into(),
+                value: "true".into(),
+                identities,
+            };
+            HookManager::is_hook_bypassed(&bypass, &msg, Some(&pushvars), pusher)
+        };
+
+        assert!(bypassed(None, None));
+        assert!(bypassed(None, Some(&mallory)));
+        assert!(bypassed(Some(vec![alice.clone()]), Some(&alice)));
+        assert!(!bypassed(Some(vec![alice.clone()]), Some(&mallory)));
+        assert!(!bypassed(Some(vec![alice.clone()]), None));
+
+        let bypass = HookBypass::Pushvar {
+            name: "BYPASS".into(),
+            value: "true".into(),
+            identities: Some(vec![alice.clone()]),
+        };
+        assert!(!HookManager::is_hook_bypassed(
+            &bypass,
+            &msg,
+            None,
+            Some(&alice)
+        ));
+    }
+
+    #[test]
+    fn test_bookmark_scoped_hooks() {
+        async_unit::tokio_unit_test(|| {
+            let bookmarks = hashmap! {
+                "master".to_string() => vec!["master_only".to_string()]
+            };
+            let mut hook_manager = setup_hook_manager(bookmarks, true);
+            hook_manager.set_hooks_for_bookmark_regex(
+                ::regex::Regex::new("^(?:master|release/.*)$").unwrap(),
+                vec!["public".to_string()],
+            );
+            hook_manager.register_changeset_hook(
+                "master_only",
+                always_rejecting_changeset_hook().into(),
+                None,
+            );
+            hook_manager.register_changeset_hook(
+                "public",
+                always_accepting_changeset_hook().into(),
+                None,
+            );
+
+            let run = |bookmarks: &[&str]| {
+                let bookmarks: Vec<_> = bookmarks
+                    .iter()
+                    .map(|name| Bookmark::new(name).unwrap())
+                    .collect();
+                let mut hook_names: Vec<_> = hook_manager
+                    .run_changeset_hooks_for_bookmarks(
+                        default_changeset_id(),
+                        &bookmarks,
+                        None,
+                        None,
+                    )
+                    .wait()
+                    .unwrap()
+                    .into_iter()
+                    .map(|(exec_id, _)| exec_id.hook_name)
+                    .collect();
+                hook_names.sort();
+                hook_names
+            };
+
+            // The hooks scoped to master are skipped for scratch bookmarks
+            assert_eq!(run(&["scratch/alice/feature"]), Vec::<String>::new());
+            assert_eq!(run(&["master"]), vec!["master_only", "public"]);
+            assert_eq!(run(&["scratch/alice/feature", "release/1.0"]), vec!["public"]);
+            // Hooks of several bookmarks run once
+            assert_eq!(run(&["master", "release/1.0"]), vec!["master_only", "public"]);
+
+            let master = Bookmark::new("master").unwrap();
+            let release = Bookmark::new("release/1.0").unwrap();
+            let pushed = vec![master.clone(), release.clone()];
+            assert_eq!(
+                hook_manager.bookmarks_of_hook("master_only", &pushed),
+                vec![master.clone()]
+            );
+            assert_eq!(hook_manager.bookmarks_of_hook("public", &pushed), pushed);
+        });
+    }
+
+    /// A content store whose backend is down
+    struct FailingContentStore;
+
+    impl FileContentStore for FailingContentStore {
+        fn get_file_content_for_changeset(
+            &self,
+            _changesetid: HgChangesetId,
+            _path: MPath,
+        ) -> BoxFuture<Option<Bytes>, Error> {
+            failed(format_err!("content store is down")).boxify()
+        }
+
+        fn get_file_content_range_for_changeset(
+            &self,
+            _changesetid: HgChangesetId,
+            _path: MPath,
+            _offset: u64,
+            _len: u64,
+        ) -> BoxFuture<Option<Bytes>, Error> {
+            failed(format_err!("content store is down")).boxify()
+        }
+
+        fn get_file_content_in_parent(
+            &self,
+            _changesetid: HgChangesetId,
+            _path: MPath,
+        ) -> BoxFuture<Option<Bytes>, Error> {
+            failed(format_err!("content store is down")).boxify()
+        }
+    }
+
+    #[test]
+    fn test_hooks_degraded_by_content_store() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let logger = Logger::root(Discard {}.ignore_res(), o!());
+            let mut hook_manager = HookManager::new(
+                "some_repo".into(),
+                Box::new(BlobRepoChangesetStore::new(repo)),
+                Arc::new(FailingContentStore),
+                1024,
+                1024 * 1024,
+                None,
+                logger,
+            );
+            for degraded_policy in vec![
+                HookDegradedPolicy::FailClosed,
+                HookDegradedPolicy::FailOpen,
+                HookDegradedPolicy::ReadOnly,
+            ] {
+                hook_manager.set_health_params(HookHealthParams {
+                    window: 4,
+                    max_failure_ratio: 0.5,
+                    degraded_policy,
+                });
+                let bm1 = Bookmark::new("bm1").unwrap();
+                hook_manager.set_hooks_for_bookmark(bm1.clone(), vec!["hook1".to_string()]);
+                let expected_content = hashmap! {
+                    "dir1/subdir1/subsubdir1/file_1".to_string() => "elephants".to_string(),
+                };
+                hook_manager.register_changeset_hook(
+                    "hook1",
+                    file_content_matching_changeset_hook(expected_content).into(),
+                    None,
+                );
+                let run = |hook_manager: &HookManager| {
+                    hook_manager
+                        .run_changeset_hooks_for_bookmark(default_changeset_id(), &bm1, None, None)
+                        .wait()
+                };
+
+                assert!(run(&hook_manager).is_err());
+                assert_eq!(hook_manager.degraded_policy(), None);
+                // Half of the window failed
+                assert!(run(&hook_manager).is_err());
+                assert_eq!(hook_manager.degraded_policy(), Some(degraded_policy));
+            }
+
+            let health = hook_manager.health().clone();
+            assert_eq!(health.state(), HookHealthState::Degraded);
+            assert_eq!(health.failure_count(HookFailureClass::ContentFetch), 6);
+            assert_eq!(health.failure_count(HookFailureClass::Other), 6);
+            assert_eq!(health.failure_count(HookFailureClass::Runtime), 0);
+
+            // Rejections are hooks that work
+            hook_manager.register_changeset_hook(
+                "hook1",
+                always_rejecting_changeset_hook().into(),
+                None,
+            );
+            for _ in 0..3 {
+                let res = hook_manager
+                    .run_changeset_hooks_for_bookmark(
+                        default_changeset_id(),
+                        &Bookmark::new("bm1").unwrap(),
+                        None,
+                        None,
+                    )
+                    .wait()
+                    .unwrap();
+                assert_eq!(res[0].1, default_rejection());
+            }
+            assert_eq!(health.state(), HookHealthState::Healthy);
+        })
+    }
+
     fn run_changeset_hooks(
         bookmark_name: &str,
         hooks: HashMap<String, Box<Hook<HookChangeset>>>,
@@ -1797,6 +2771,7 @@ mod test {
             default_changeset_id(),
             &Bookmark::new(bookmark_name).unwrap(),
             None,
+            None,
         );
         let res = fut.wait().unwrap();
         let map: HashMap<String, HookExecution> = res.into_iter()
@@ -1830,6 +2805,7 @@ mod test {
                 default_changeset_id(),
                 &Bookmark::new(bookmark_name).unwrap(),
                 None,
+                None,
             );
         let res = fut.wait().unwrap();
         let map: HashMap<String, HashMap<String, HookExecution>> = res.into_iter().fold(
@@ -1861,10 +2837,20 @@ mod test {
         HookExecution::Rejected(HookRejectionInfo::new("desc".into(), "long_desc".into()))
     }
 
+    fn default_warning() -> HookExecution {
+        HookExecution::Warned(HookRejectionInfo::new("desc".into(), "long_desc".into()))
+    }
+
     fn default_changeset_id() -> HgChangesetId {
         HgChangesetId::from_str("d261bc7900818dea7c86935b3fb17a33b2e3a6b4").unwrap()
     }
 
+    /// The commit of `default_changeset_id` after it was rebased onto other parents: it has a new
+    /// hash, but the same message, author and files
+    fn rebased_changeset_id() -> HgChangesetId {
+        HgChangesetId::from_str("1111111111111111111111111111111111111111").unwrap()
+    }
+
     fn hook_manager_blobrepo() -> HookManager {
         let repo = many_files_dirs::getrepo(None);
         let changeset_store = BlobRepoChangesetStore::new(repo.clone());
@@ -1876,6 +2862,7 @@ mod test {
             Arc::new(content_store),
             1024,
             1024 * 1024,
+            None,
             logger,
         )
     }
@@ -1907,6 +2894,41 @@ mod test {
             Arc::new(content_store),
             1024,
             1024 * 1024,
+            None,
+            logger,
+        )
+    }
+
+    fn hook_manager_with_rebased_changeset() -> HookManager {
+        let repo = many_files_dirs::getrepo(None);
+        let cs = repo.get_changeset_by_changesetid(&default_changeset_id())
+            .wait()
+            .unwrap();
+        let mut changeset_store = InMemoryChangesetStore::new();
+        let mut content_store = InMemoryFileContentStore::new();
+        for cs_id in vec![default_changeset_id(), rebased_changeset_id()] {
+            changeset_store.insert(&cs_id, &cs);
+            content_store.insert(
+                (cs_id.clone(), to_mpath("dir1/subdir1/subsubdir1/file_1")),
+                "elephants".into(),
+            );
+            content_store.insert(
+                (cs_id.clone(), to_mpath("dir1/subdir1/subsubdir2/file_1")),
+                "hippopatami".into(),
+            );
+            content_store.insert(
+                (cs_id.clone(), to_mpath("dir1/subdir1/subsubdir2/file_2")),
+                "eels".into(),
+            );
+        }
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        HookManager::new(
+            "some_repo".into(),
+            Box::new(changeset_store),
+            Arc::new(content_store),
+            1024,
+            1024 * 1024,
+            None,
             logger,
         )
     }
@@ -19,6 +19,7 @@ use hlua::{AnyLuaString, AnyLuaValue, Lua, LuaError, LuaFunctionCallError, LuaTa
            TuplePushError, Void, function0, function1, function2};
 use hlua_futures::{AnyFuture, LuaCoroutine, LuaCoroutineBuilder};
 use std::collections::HashMap;
+use toml;
 
 const HOOK_START_CODE_BASE: &str = include_str!("hook_start_base.lua");
 
@@ -34,12 +35,16 @@ __hook_start = function(info, arg)
                 file.contains_string = function(s) return coroutine.yield(__contains_string(file.path, s)) end
                 file.len = function() return coroutine.yield(__file_len(file.path)) end
                 file.content = function() return coroutine.yield(__file_content(file.path)) end
+                file.prefix = function(n) return coroutine.yield(__file_prefix(file.path, n)) end
+                file.matches_regex = function(p) return coroutine.yield(__matches_regex(file.path, p)) end
             end
+            file.parent_content = function() return coroutine.yield(__parent_file_content(file.path)) end
             files[#files+1] = file
         end
 
         ctx.files = files
         ctx.file_content = function(path) return coroutine.yield(__file_content(path)) end
+        ctx.regex_match = function(path, p) return coroutine.yield(__regex_match(path, p)) end
     end)
 end
 ";
@@ -53,17 +58,25 @@ __hook_start = function(info, arg)
             file.contains_string = function(s) return coroutine.yield(__contains_string(s)) end
             file.len = function() return coroutine.yield(__file_len()) end
             file.content = function() return coroutine.yield(__file_content()) end
+            file.prefix = function(n) return coroutine.yield(__file_prefix(n)) end
+            file.matches_regex = function(p) return coroutine.yield(__matches_regex(p)) end
         end
+        file.parent_content = function() return coroutine.yield(__parent_file_content()) end
         ctx.file = file
     end)
 end
 ";
 
+// Integers with a larger absolute value can't be represented exactly as Lua numbers
+const MAX_LUA_INTEGER: i64 = 1 << 53;
+
 #[derive(Clone)]
 pub struct LuaHook {
     pub name: String,
     /// The Lua code of the hook
     pub code: String,
+    /// The hook config, available to the hook as read-only `ctx.config`
+    pub config: AnyLuaValue,
 }
 
 impl Hook<HookChangeset> for LuaHook {
@@ -94,6 +107,9 @@ impl Hook<HookChangeset> for LuaHook {
             .map(|file| (file.path.clone(), file.clone()))
             .collect();
         let files_map2 = files_map.clone();
+        let files_map3 = files_map.clone();
+        let files_map4 = files_map.clone();
+        let files_map5 = files_map.clone();
 
         let contains_string = {
             move |path: String, string: String| -> Result<AnyFuture, Error> {
@@ -150,12 +166,93 @@ impl Hook<HookChangeset> for LuaHook {
             }
         };
         let file_len = function1(file_len);
+        let file_prefix = {
+            move |path: String, len: u32| -> Result<AnyFuture, Error> {
+                match files_map3.get(&path) {
+                    Some(file) => {
+                        let future = file.prefix(len as u64)
+                            .map_err(|err| {
+                                LuaError::ExecutionError(format!(
+                                    "failed to get file content: {}",
+                                    err
+                                ))
+                            })
+                            .map(|prefix| AnyLuaValue::LuaAnyString(AnyLuaString(prefix.to_vec())));
+                        Ok(AnyFuture::new(future))
+                    }
+                    None => Ok(AnyFuture::new(ok(AnyLuaValue::LuaNil))),
+                }
+            }
+        };
+        let file_prefix = function2(file_prefix);
+        let parent_file_content = {
+            move |path: String| -> Result<AnyFuture, Error> {
+                match files_map4.get(&path) {
+                    Some(file) => {
+                        let future = file.parent_content()
+                            .map_err(|err| {
+                                LuaError::ExecutionError(format!(
+                                    "failed to get parent file content: {}",
+                                    err
+                                ))
+                            })
+                            .map(|opt| match opt {
+                                Some(content) => {
+                                    AnyLuaValue::LuaAnyString(AnyLuaString(content.to_vec()))
+                                }
+                                None => AnyLuaValue::LuaNil,
+                            });
+                        Ok(AnyFuture::new(future))
+                    }
+                    None => Ok(AnyFuture::new(ok(AnyLuaValue::LuaNil))),
+                }
+            }
+        };
+        let parent_file_content = function1(parent_file_content);
+        let matches_regex = {
+            move |path: String, pattern: String| -> Result<AnyFuture, Error> {
+                match files_map5.get(&path) {
+                    Some(file) => {
+                        let future = file.matches_regex(&pattern)
+                            .map_err(|err| {
+                                LuaError::ExecutionError(format!("failed to match regex: {}", err))
+                            })
+                            .map(|matches| AnyLuaValue::LuaBoolean(matches));
+                        Ok(AnyFuture::new(future))
+                    }
+                    None => Ok(AnyFuture::new(ok(AnyLuaValue::LuaBoolean(false)))),
+                }
+            }
+        };
+        let matches_regex = function2(matches_regex);
+        let regex_match = {
+            let context2 = context.clone();
+            move |path: String, pattern: String| -> Result<AnyFuture, Error> {
+                let future = context2
+                    .data
+                    .regex_match(path, &pattern)
+                    .map_err(|err| {
+                        LuaError::ExecutionError(format!("failed to match regex: {}", err))
+                    })
+                    .map(|opt| match opt {
+                        Some(matches) => AnyLuaValue::LuaBoolean(matches),
+                        None => AnyLuaValue::LuaNil,
+                    });
+                Ok(AnyFuture::new(future))
+            }
+        };
+        let regex_match = function2(regex_match);
 
         let mut lua = Lua::new();
         lua.openlibs();
         lua.set("__contains_string", contains_string);
         lua.set("__file_len", file_len);
         lua.set("__file_content", file_content);
+        lua.set("__file_prefix", file_prefix);
+        lua.set("__parent_file_content", parent_file_content);
+        lua.set("__matches_regex", matches_regex);
+        lua.set("__regex_match", regex_match);
+        lua.set("__hook_config", self.config.clone());
         let res: Result<(), Error> = lua.execute::<()>(&code)
             .map_err(|e| ErrorKind::HookParseError(e.to_string()).into());
         if let Err(e) = res {
@@ -239,11 +336,63 @@ impl Hook<HookFile> for LuaHook {
             }
         };
         let file_len = function0(file_len);
+        let file_prefix = {
+            cloned!(context);
+            move |len: u32| -> Result<AnyFuture, Error> {
+                let future = context
+                    .data
+                    .prefix(len as u64)
+                    .map_err(|err| {
+                        LuaError::ExecutionError(format!("failed to get file content: {}", err))
+                    })
+                    .map(|prefix| AnyLuaValue::LuaAnyString(AnyLuaString(prefix.to_vec())));
+                Ok(AnyFuture::new(future))
+            }
+        };
+        let file_prefix = function1(file_prefix);
+        let parent_file_content = {
+            cloned!(context);
+            move || -> Result<AnyFuture, Error> {
+                let future = context
+                    .data
+                    .parent_content()
+                    .map_err(|err| {
+                        LuaError::ExecutionError(format!(
+                            "failed to get parent file content: {}",
+                            err
+                        ))
+                    })
+                    .map(|opt| match opt {
+                        Some(content) => AnyLuaValue::LuaAnyString(AnyLuaString(content.to_vec())),
+                        None => AnyLuaValue::LuaNil,
+                    });
+                Ok(AnyFuture::new(future))
+            }
+        };
+        let parent_file_content = function0(parent_file_content);
+        let matches_regex = {
+            cloned!(context);
+            move |pattern: String| -> Result<AnyFuture, Error> {
+                let future = context
+                    .data
+                    .matches_regex(&pattern)
+                    .map_err(|err| {
+                        LuaError::ExecutionError(format!("failed to match regex: {}", err))
+                    })
+                    .map(|matches| AnyLuaValue::LuaBoolean(matches));
+                Ok(AnyFuture::new(future))
+            }
+        };
+        let matches_regex = function1(matches_regex);
         let mut lua = Lua::new();
         lua.openlibs();
         lua.set("__contains_string", contains_string);
         lua.set("__file_len", file_len);
         lua.set("__file_content", file_content);
+        lua.set("__file_prefix", file_prefix);
+        lua.set("__parent_file_content", parent_file_content);
+        lua.set("__matches_regex", matches_regex);
+        lua.set("__hook_config", self.config.clone());
         let res: Result<(), Error> = lua.execute::<()>(&code)
             .map_err(|e| ErrorKind::HookParseError(e.to_string()).into());
         if let Err(e) = res {
@@ -273,7 +422,28 @@ impl Hook<HookFile> for LuaHook {
 
 impl LuaHook {
     pub fn new(name: String, code: String) -> LuaHook {
-        LuaHook { name, code }
+        LuaHook {
+            name,
+            code,
+            config: AnyLuaValue::LuaArray(vec![]),
+        }
+    }
+
+    /// Creates a hook with a config from the repo config. `config` must be a table.
+    pub fn new_with_config(
+        name: String,
+        code: String,
+        config: &toml::Value,
+    ) -> Result<LuaHook, Error> {
+        if !config.is_table() {
+            return Err(ErrorKind::InvalidHookConfig(
+                name,
+                format!("expected a table, found {}", config.type_str()),
+            ).into());
+        }
+        let config = toml_to_lua(config)
+            .map_err(|msg| Error::from(ErrorKind::InvalidHookConfig(name.clone(), msg)))?;
+        Ok(LuaHook { name, code, config })
     }
 
     fn convert_coroutine_res(
@@ -310,18 +480,51 @@ impl LuaHook {
     }
 }
 
+/// Converts a TOML value to a Lua value:
+/// - strings, booleans and floats are converted to Lua strings, booleans and numbers
+/// - integers are converted to Lua numbers, which fails if they can't be represented exactly
+/// - datetimes are converted to strings in RFC 3339 format
+/// - arrays are converted to tables with indexes starting at 1
+/// - tables are converted to tables with string keys
+fn toml_to_lua(value: &toml::Value) -> Result<AnyLuaValue, String> {
+    match value {
+        toml::Value::String(s) => Ok(AnyLuaValue::LuaString(s.clone())),
+        toml::Value::Integer(i) => {
+            if *i > MAX_LUA_INTEGER || *i < -MAX_LUA_INTEGER {
+                Err(format!("integer {} is too large", i))
+            } else {
+                Ok(AnyLuaValue::LuaNumber(*i as f64))
+            }
+        }
+        toml::Value::Float(f) => Ok(AnyLuaValue::LuaNumber(*f)),
+        toml::Value::Boolean(b) => Ok(AnyLuaValue::LuaBoolean(*b)),
+        toml::Value::Datetime(dt) => Ok(AnyLuaValue::LuaString(dt.to_string())),
+        toml::Value::Array(array) => array
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                Ok((
+                    AnyLuaValue::LuaNumber((idx + 1) as f64),
+                    toml_to_lua(value)?,
+                ))
+            })
+            .collect::<Result<_, _>>()
+            .map(AnyLuaValue::LuaArray),
+        toml::Value::Table(table) => table
+            .iter()
+            .map(|(key, value)| Ok((AnyLuaValue::LuaString(key.clone()), toml_to_lua(value)?)))
+            .collect::<Result<_, _>>()
+            .map(AnyLuaValue::LuaArray),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use super::super::{ChangedFileType, HookChangeset, HookChangesetParents,
-                       InMemoryFileContentStore};
+    use super::super::{HookChangeset, HookChangesetParents};
+    use super::super::hook_testlib::{default_changeset, run_changeset_hook, run_file_hook,
+                                     ChangesetFixture, FileFixture, TEST_HOOK_NAME};
     use async_unit;
-    use bytes::Bytes;
-    use futures::Future;
-    use mercurial_types::HgChangesetId;
-    use std::str::FromStr;
-    use std::sync::Arc;
-    use test::to_mpath;
 
     #[test]
     fn test_cs_hook_simple_rejected() {
@@ -429,6 +632,24 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_cs_hook_file_prefix_match() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return ctx.files[1].prefix(5) == \"file1\" and\n
+                 ctx.files[5].prefix(0) == \"\" and\n
+                 ctx.files[5].prefix(1000) == \"modifiedsausages\"\n
+                 end",
+            );
+            assert_matches!(
+                run_changeset_hook(code, changeset),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
     #[test]
     fn test_cs_hook_other_file_content_match() {
         async_unit::tokio_unit_test(|| {
@@ -512,6 +733,96 @@ mod test {
         });
     }
 
+    /// Only allows appending to "log"
+    const APPEND_ONLY_HOOK: &str = "hook = function (ctx)\n\
+                                    for _, f in ipairs(ctx.files) do\n\
+                                    local old = f.parent_content()\n\
+                                    if f.path == \"log\" and old ~= nil then\n\
+                                    if f.is_deleted() or f.content():sub(1, #old) ~= old then\n\
+                                    return false, \"log is append-only\"\n\
+                                    end\n\
+                                    end\n\
+                                    end\n\
+                                    return true\n\
+                                    end";
+
+    #[test]
+    fn test_cs_hook_parent_content() {
+        async_unit::tokio_unit_test(|| {
+            let appended = ChangesetFixture::new()
+                .modified("log", "line1\nline2\n")
+                .parent_content("log", "line1\n")
+                .build();
+            assert_matches!(
+                run_changeset_hook(APPEND_ONLY_HOOK, appended),
+                Ok(HookExecution::Accepted)
+            );
+
+            let shrunk = ChangesetFixture::new()
+                .modified("log", "line")
+                .parent_content("log", "line1\n")
+                .build();
+            assert_matches!(
+                run_changeset_hook(APPEND_ONLY_HOOK, shrunk),
+                Ok(HookExecution::Rejected(HookRejectionInfo { ref description, .. }))
+                    if description == "log is append-only"
+            );
+
+            let deleted = ChangesetFixture::new()
+                .deleted("log")
+                .parent_content("log", "line1\n")
+                .build();
+            assert_matches!(
+                run_changeset_hook(APPEND_ONLY_HOOK, deleted),
+                Ok(HookExecution::Rejected(_))
+            );
+
+            // Added files have no parent content
+            let added = ChangesetFixture::new()
+                .added("log", "")
+                .parent_content("log", "line1\n")
+                .build();
+            assert_matches!(
+                run_changeset_hook(APPEND_ONLY_HOOK, added),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_regex_match() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = "hook = function (ctx)\n\
+                        return ctx.files[1].matches_regex(\"^file1\") and\n\
+                        not ctx.files[1].matches_regex(\"^file2\") and\n\
+                        ctx.regex_match(\"file2\", \"sau+sages$\") and\n\
+                        not ctx.regex_match(\"file3\", \"^sausages\") and\n\
+                        ctx.regex_match(\"no/such/path\", \".*\") == nil\n\
+                        end";
+            assert_matches!(
+                run_changeset_hook(code, changeset),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_regex_match_invalid_pattern() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = "hook = function (ctx)\n\
+                        return ctx.regex_match(\"file1\", \"(unclosed\")\n\
+                        end";
+            assert_matches!(
+                err_downcast!(run_changeset_hook(code, changeset).unwrap_err(), err: ErrorKind => err),
+                Ok(ErrorKind::HookRuntimeError(ref err_msg))
+                    if err_msg.contains("Invalid regex '(unclosed'")
+                        && err_msg.contains("unclosed group")
+            );
+        });
+    }
+
     #[test]
     fn test_cs_hook_file_len() {
         async_unit::tokio_unit_test(|| {
@@ -834,6 +1145,98 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_file_hook_prefix_matches() {
+        async_unit::tokio_unit_test(|| {
+            let hook_file = default_hook_added_file();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return ctx.file.prefix(3) == \"sau\" and ctx.file.prefix(100) == \"sausages\"\n\
+                 end",
+            );
+            assert_matches!(run_file_hook(code, hook_file), Ok(HookExecution::Accepted));
+        });
+    }
+
+    #[test]
+    fn test_file_hook_parent_content() {
+        async_unit::tokio_unit_test(|| {
+            let code = "hook = function (ctx)\n\
+                        local old = ctx.file.parent_content()\n\
+                        if old ~= nil and ctx.file.len() < #old then\n\
+                        return false, \"file shrunk\"\n\
+                        end\n\
+                        return true\n\
+                        end";
+            let grown = FileFixture::modified("a.txt", "longer")
+                .parent_content("long")
+                .build();
+            assert_matches!(run_file_hook(code, grown), Ok(HookExecution::Accepted));
+
+            let shrunk = FileFixture::modified("a.txt", "sh")
+                .parent_content("long")
+                .build();
+            assert_matches!(
+                run_file_hook(code, shrunk),
+                Ok(HookExecution::Rejected(HookRejectionInfo { ref description, .. }))
+                    if description == "file shrunk"
+            );
+
+            let added = FileFixture::added("a.txt", "").build();
+            assert_matches!(run_file_hook(code, added), Ok(HookExecution::Accepted));
+        });
+    }
+
+    #[test]
+    fn test_file_hook_matches_regex() {
+        async_unit::tokio_unit_test(|| {
+            let code = "hook = function (ctx)\n\
+                        return ctx.file.matches_regex(\"^sau[s]+ages$\")\n\
+                        end";
+            assert_matches!(
+                run_file_hook(code, default_hook_added_file()),
+                Ok(HookExecution::Accepted)
+            );
+
+            let code = "hook = function (ctx)\n\
+                        return ctx.file.matches_regex(\"(?i)BACON\")\n\
+                        end";
+            assert_matches!(
+                run_file_hook(code, default_hook_added_file()),
+                Ok(HookExecution::Rejected(_))
+            );
+        });
+    }
+
+    #[test]
+    fn test_file_hook_matches_regex_binary_content() {
+        async_unit::tokio_unit_test(|| {
+            let binary = FileFixture::added("image.png", &b"\x89PNG\r\n\x1a\n\x00\xff"[..]).build();
+            let code = "hook = function (ctx)\n\
+                        return ctx.file.matches_regex(\"^(?-u)\\\\x89PNG\") and\n\
+                        ctx.file.matches_regex(\"(?-u)\\\\x00\\\\xff$\")\n\
+                        end";
+            assert_matches!(run_file_hook(code, binary), Ok(HookExecution::Accepted));
+        });
+    }
+
+    #[test]
+    fn test_file_hook_matches_regex_invalid_pattern() {
+        async_unit::tokio_unit_test(|| {
+            let code = "hook = function (ctx)\n\
+                        return ctx.file.matches_regex(\"[a-\")\n\
+                        end";
+            assert_matches!(
+                err_downcast!(
+                    run_file_hook(code, default_hook_added_file()).unwrap_err(),
+                    err: ErrorKind => err
+                ),
+                Ok(ErrorKind::HookRuntimeError(ref err_msg))
+                    if err_msg.contains("Invalid regex '[a-'")
+            );
+        });
+    }
+
     #[test]
     fn test_file_hook_len_matches() {
         async_unit::tokio_unit_test(|| {
@@ -1005,83 +1408,150 @@ mod test {
         });
     }
 
-    fn run_changeset_hook(code: String, changeset: HookChangeset) -> Result<HookExecution, Error> {
-        let hook = LuaHook::new(String::from("testhook"), code.to_string());
-        let context = HookContext::new(hook.name.clone(), "some-repo".into(), changeset);
-        hook.run(context).wait()
+    #[test]
+    fn test_cs_hook_config_list() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 for _, author in ipairs(ctx.config.allowed_authors) do\n\
+                 if author == ctx.info.author then\n\
+                 return true\n\
+                 end\n\
+                 end\n\
+                 return false, \"author is not allowed\"\n\
+                 end",
+            );
+            let config = r#"allowed_authors = ["someone-else", "some-author"]"#;
+            assert_matches!(
+                run_changeset_hook_with_config(code.clone(), config, default_changeset()),
+                Ok(HookExecution::Accepted)
+            );
+
+            let config = r#"allowed_authors = ["someone-else"]"#;
+            assert_matches!(
+                run_changeset_hook_with_config(code, config, default_changeset()),
+                Ok(HookExecution::Rejected(_))
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_config_types() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 local c = ctx.config\n\
+                 return c.str == \"s\" and c.int == 1 and c.float == 1.5 and c.bool and\n\
+                 c.date == \"1979-05-27T07:32:00Z\" and #c.list == 2 and c.list[2] == 3 and\n\
+                 c.nested.inner.value == \"v\" and c.missing == nil\n\
+                 end",
+            );
+            let config = r#"
+                str = "s"
+                int = 1
+                float = 1.5
+                bool = true
+                date = 1979-05-27T07:32:00Z
+                list = [2, 3]
+                [nested.inner]
+                value = "v"
+            "#;
+            assert_matches!(
+                run_changeset_hook_with_config(code, config, default_changeset()),
+                Ok(HookExecution::Accepted)
+            );
+        });
     }
 
-    fn run_file_hook(code: String, hook_file: HookFile) -> Result<HookExecution, Error> {
-        let hook = LuaHook::new(String::from("testhook"), code.to_string());
-        let context = HookContext::new(hook.name.clone(), "some-repo".into(), hook_file);
-        hook.run(context).wait()
+    #[test]
+    fn test_cs_hook_no_config() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 for _ in pairs(ctx.config) do\n\
+                 return false\n\
+                 end\n\
+                 return true\n\
+                 end",
+            );
+            assert_matches!(
+                run_changeset_hook(code, changeset),
+                Ok(HookExecution::Accepted)
+            );
+        });
     }
 
-    fn default_changeset() -> HookChangeset {
-        let added = vec!["file1".into(), "file2".into(), "file3".into()];
-        let deleted = vec!["deleted".into()];
-        let modified = vec!["modified".into()];
-        create_hook_changeset(added, deleted, modified)
+    #[test]
+    fn test_cs_hook_config_read_only() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 ctx.config.list[1] = 5\n\
+                 return true\n\
+                 end",
+            );
+            assert_matches!(
+                run_changeset_hook_with_config(code, "list = [1]", default_changeset()),
+                Err(ref err) if err.to_string().contains("read-only")
+            );
+        });
     }
 
-    fn create_hook_changeset(
-        added: Vec<String>,
-        deleted: Vec<String>,
-        modified: Vec<String>,
-    ) -> HookChangeset {
-        let mut content_store = InMemoryFileContentStore::new();
-        let cs_id = HgChangesetId::from_str("473b2e715e0df6b2316010908879a3c78e275dd9").unwrap();
-        for path in added.iter().chain(modified.iter()) {
-            let content = path.clone() + "sausages";
-            let content_bytes: Bytes = content.into();
-            content_store.insert((cs_id.clone(), to_mpath(&path)), content_bytes.into());
-        }
-        let content_store = Arc::new(content_store);
-        let content_store2 = content_store.clone();
-
-        let create_hook_files = move |files: Vec<String>, ty: ChangedFileType| -> Vec<HookFile> {
-            files
-                .into_iter()
-                .map(|path| HookFile::new(path.clone(), content_store.clone(), cs_id, ty.clone()))
-                .collect()
-        };
+    #[test]
+    fn test_invalid_hook_config() {
+        let config: toml::Value = toml::from_str("big = 9007199254740993").unwrap();
+        assert!(LuaHook::new_with_config("testhook".into(), "".into(), &config).is_err());
+
+        let config = toml::Value::Integer(1);
+        assert!(LuaHook::new_with_config("testhook".into(), "".into(), &config).is_err());
+    }
+
+    #[test]
+    fn test_file_hook_config_threshold() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return ctx.file.len() <= ctx.config.max_size\n\
+                 end",
+            );
+            // "sausages" is 8 bytes long
+            assert_matches!(
+                run_file_hook_with_config(code.clone(), "max_size = 10", default_hook_added_file()),
+                Ok(HookExecution::Accepted)
+            );
+            assert_matches!(
+                run_file_hook_with_config(code, "max_size = 5", default_hook_added_file()),
+                Ok(HookExecution::Rejected(_))
+            );
+        });
+    }
 
-        let mut hook_files = vec![];
-        hook_files.extend(create_hook_files(added, ChangedFileType::Added));
-        hook_files.extend(create_hook_files(deleted, ChangedFileType::Deleted));
-        hook_files.extend(create_hook_files(modified, ChangedFileType::Modified));
+    fn run_changeset_hook_with_config(
+        code: String,
+        config: &str,
+        changeset: HookChangeset,
+    ) -> Result<HookExecution, Error> {
+        let config: toml::Value = toml::from_str(config).unwrap();
+        let hook = LuaHook::new_with_config(TEST_HOOK_NAME.into(), code, &config).unwrap();
+        run_changeset_hook(hook, changeset)
+    }
 
-        HookChangeset::new(
-            "some-author".into(),
-            hook_files,
-            "some-comments".into(),
-            HookChangesetParents::One("p1-hash".into()),
-            cs_id,
-            content_store2,
-        )
+    fn run_file_hook_with_config(
+        code: String,
+        config: &str,
+        hook_file: HookFile,
+    ) -> Result<HookExecution, Error> {
+        let config: toml::Value = toml::from_str(config).unwrap();
+        let hook = LuaHook::new_with_config(TEST_HOOK_NAME.into(), code, &config).unwrap();
+        run_file_hook(hook, hook_file)
     }
 
     fn default_hook_added_file() -> HookFile {
-        let mut content_store = InMemoryFileContentStore::new();
-        let cs_id = HgChangesetId::from_str("473b2e715e0df6b2316010908879a3c78e275dd9").unwrap();
-        content_store.insert((cs_id.clone(), to_mpath("/a/b/c.txt")), "sausages".into());
-        HookFile::new(
-            "/a/b/c.txt".into(),
-            Arc::new(content_store),
-            cs_id,
-            ChangedFileType::Added,
-        )
+        FileFixture::added("/a/b/c.txt", "sausages").build()
     }
 
     fn default_hook_removed_file() -> HookFile {
-        let content_store = InMemoryFileContentStore::new();
-        let cs_id = HgChangesetId::from_str("473b2e715e0df6b2316010908879a3c78e275dd9").unwrap();
-        HookFile::new(
-            "/a/b/c.txt".into(),
-            Arc::new(content_store),
-            cs_id,
-            ChangedFileType::Deleted,
-        )
+        FileFixture::deleted("/a/b/c.txt").build()
     }
-
 }
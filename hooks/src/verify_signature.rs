@@ -0,0 +1,553 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The builtin `verify_commit_signature` changeset hook, which rejects changesets that are not
+//! signed by one of a set of trusted keys.
+//!
+//! The signature is kept hex encoded in an extra of the changeset, `signature` unless the hook
+//! config says otherwise. It is an ECDSA (DER encoded) or RSA PKCS#1 v1.5 signature, over the
+//! SHA-256 of the canonical form of the changeset.
+//!
+//! The `v1` canonical form is, with `<len>` the length in bytes of what follows it as a decimal
+//! number, and every line ending with a single `\n`:
+//!
+//! ```text
+//! mononoke-commit-signature-v1
+//! author <len>
+//! <author>
+//! message <len>
+//! <message>
+//! files <count>
+//! ```
+//!
+//! followed by two lines for each changed file, in the byte order of their paths:
+//!
+//! ```text
+//! <A|M|D> <sha256> <len>
+//! <path>
+//! ```
+//!
+//! where `A`, `M` and `D` stand for added, modified and deleted, and `<sha256>` is the lowercase
+//! hex SHA-256 of the content of the file, or `-` for a deleted file. The content is hashed
+//! rather than identified by its Mononoke content id so that signers don't need Mononoke to
+//! compute it. As the author, the message and the lengths are written out as is, the canonical
+//! form of a changeset is never that of another one.
+//!
+//! Hooks see the author and the message as UTF-8, so a changeset with other bytes in them can't
+//! be verified, and is rejected.
+//!
+//! The hook config is a table of:
+//! - `extra_key`: the extra that has the signature, `signature` by default
+//! - `scheme`: the canonical form, only `v1` (the default) exists
+//! - `trusted_keys`: PEM encoded public keys
+//! - `trusted_key_files`: paths of PEM encoded public keys, read when the hook is loaded
+//!
+//! and there must be at least one trusted key.
+
+#![deny(warnings)]
+
+use std::fs;
+use std::sync::Arc;
+
+use failure::Error;
+use futures::{finished, Future};
+use futures::future::join_all;
+use futures_ext::{BoxFuture, FutureExt};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Public};
+use openssl::sha::sha256;
+use openssl::sign::Verifier;
+use toml;
+
+use super::{ChangedFileType, Hook, HookChangeset, HookContext, HookExecution,
+            HookRejectionInfo};
+use errors::*;
+
+/// Name of the hook in the `builtin` field of hook configs
+pub const VERIFY_COMMIT_SIGNATURE: &str = "verify_commit_signature";
+/// The extra that has the signature, unless the hook config says otherwise
+pub const DEFAULT_EXTRA_KEY: &str = "signature";
+
+const V1_HEADER: &[u8] = b"mononoke-commit-signature-v1\n";
+
+/// A changed file, as it is signed
+#[derive(Clone, Debug)]
+pub struct SignedFile {
+    pub path: String,
+    pub ty: ChangedFileType,
+    /// SHA-256 of the new content of the file, `None` if it was deleted
+    pub content_sha256: Option<[u8; 32]>,
+}
+
+/// The bytes whose signature is verified, in the `v1` canonical form described in the module
+/// docs
+pub fn canonical_form(author: &str, message: &str, files: &[SignedFile]) -> Vec<u8> {
+    let mut out = V1_HEADER.to_vec();
+    push_field(&mut out, "author", author.as_bytes());
+    push_field(&mut out, "message", message.as_bytes());
+    out.extend_from_slice(format!("files {}\n", files.len()).as_bytes());
+
+    let mut files: Vec<_> = files.iter().collect();
+    files.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()));
+    for file in files {
+        let ty = match file.ty {
+            ChangedFileType::Added => "A",
+            ChangedFileType::Modified => "M",
+            ChangedFileType::Deleted => "D",
+        };
+        let hash = match file.content_sha256 {
+            Some(ref hash) => to_hex(hash),
+            None => "-".to_string(),
+        };
+        push_field(&mut out, &format!("{} {}", ty, hash), file.path.as_bytes());
+    }
+    out
+}
+
+fn push_field(out: &mut Vec<u8>, name: &str, value: &[u8]) {
+    out.extend_from_slice(format!("{} {}\n", name, value.len()).as_bytes());
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
+
+pub struct VerifyCommitSignature {
+    extra_key: String,
+    trusted_keys: Arc<Vec<PKey<Public>>>,
+}
+
+impl VerifyCommitSignature {
+    pub fn new(name: &str, config: Option<&toml::Value>) -> Result<Self, Error> {
+        let invalid =
+            |msg: String| Error::from(ErrorKind::InvalidHookConfig(name.to_string(), msg));
+        let empty = toml::value::Table::new();
+        let config = match config {
+            Some(config) => config
+                .as_table()
+                .ok_or_else(|| invalid(format!("expected a table, found {}", config.type_str())))?,
+            None => &empty,
+        };
+
+        let get_str = |key: &str| match config.get(key) {
+            Some(value) => value
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| invalid(format!("{} must be a string", key))),
+            None => Ok(None),
+        };
+        let get_strs = |key: &str| match config.get(key) {
+            Some(value) => value
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|value| value.as_str())
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| invalid(format!("{} must be a list of strings", key))),
+            None => Ok(vec![]),
+        };
+
+        let extra_key = get_str("extra_key")?.unwrap_or(DEFAULT_EXTRA_KEY);
+        match get_str("scheme")? {
+            None | Some("v1") => {}
+            Some(scheme) => return Err(invalid(format!("unknown scheme {}", scheme))),
+        }
+
+        let mut pems: Vec<(String, Vec<u8>)> = get_strs("trusted_keys")?
+            .into_iter()
+            .enumerate()
+            .map(|(i, pem)| (format!("trusted_keys[{}]", i), pem.as_bytes().to_vec()))
+            .collect();
+        for path in get_strs("trusted_key_files")? {
+            let pem = fs::read(path).map_err(|err| invalid(format!("{}: {}", path, err)))?;
+            pems.push((path.to_string(), pem));
+        }
+        if pems.is_empty() {
+            return Err(invalid("no trusted_keys or trusted_key_files".into()));
+        }
+        let trusted_keys = pems.into_iter()
+            .map(|(origin, pem)| {
+                PKey::public_key_from_pem(&pem)
+                    .map_err(|err| invalid(format!("{} is not a public key: {}", origin, err)))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(VerifyCommitSignature {
+            extra_key: extra_key.to_string(),
+            trusted_keys: Arc::new(trusted_keys),
+        })
+    }
+}
+
+fn is_trusted(keys: &[PKey<Public>], data: &[u8], signature: &[u8]) -> bool {
+    keys.iter().any(|key| {
+        let verified = Verifier::new(MessageDigest::sha256(), key).and_then(|mut verifier| {
+            verifier.update(data)?;
+            verifier.verify(signature)
+        });
+        // Errors are for signatures that are not even well formed
+        verified.unwrap_or(false)
+    })
+}
+
+fn rejected(description: &str, long_description: String) -> HookExecution {
+    HookExecution::Rejected(HookRejectionInfo::new(
+        description.to_string(),
+        long_description,
+    ))
+}
+
+impl Hook<HookChangeset> for VerifyCommitSignature {
+    fn run(&self, context: HookContext<HookChangeset>) -> BoxFuture<HookExecution, Error> {
+        let changeset = context.data;
+        let signature = match changeset.extras.get(&self.extra_key) {
+            Some(signature) => signature,
+            None => {
+                return finished(rejected(
+                    "Commit is not signed",
+                    format!("The {} extra with the signature is missing", self.extra_key),
+                )).boxify()
+            }
+        };
+        let signature = match from_hex(signature) {
+            Some(signature) => signature,
+            None => {
+                return finished(rejected(
+                    "Commit signature is invalid",
+                    format!("The {} extra is not hex encoded", self.extra_key),
+                )).boxify()
+            }
+        };
+
+        let files = changeset.files.iter().map(|file| {
+            let signed = SignedFile {
+                path: file.path.clone(),
+                ty: file.ty.clone(),
+                content_sha256: None,
+            };
+            match file.ty {
+                ChangedFileType::Deleted => finished(signed).boxify(),
+                ChangedFileType::Added | ChangedFileType::Modified => file.file_content()
+                    .map(move |content| SignedFile {
+                        content_sha256: Some(sha256(&content)),
+                        ..signed
+                    })
+                    .boxify(),
+            }
+        });
+        let trusted_keys = self.trusted_keys.clone();
+        let author = changeset.author.clone();
+        let message = changeset.comments.clone();
+        join_all(files)
+            .map(move |files| {
+                let signed = canonical_form(&author, &message, &files);
+                if is_trusted(&trusted_keys, &signed, &signature) {
+                    HookExecution::Accepted
+                } else {
+                    rejected(
+                        "Commit signature is invalid",
+                        "The commit is not signed by a trusted key, or was changed after it \
+                         was signed"
+                            .into(),
+                    )
+                }
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hook_testlib::{run_changeset_hook, ChangesetFixture};
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    const TRUSTED_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAER3yLh4zluyioFp7MVlA5Eu+vohgg
+y/Mi5sKmZl7u4Te9wH5Q57kmfJwKJTw5pbHv7py3yqeiLbkPrC8xbrwyQg==
+-----END PUBLIC KEY-----
+";
+    const OTHER_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEzB0RJ5OVnm4FOzxWUxp2MWSD/e7g
+6fR7VPgfZCETwSLI6p/J+S6arA0HsDEkaCNpD4wxSNrFInb3Pt+lbWAVCA==
+-----END PUBLIC KEY-----
+";
+
+    /// `FIXTURE_CANONICAL_FORM` signed by the private key of `TRUSTED_KEY`, with
+    /// `openssl dgst -sha256 -sign`
+    const FIXTURE_SIGNATURE: &str = "304402203b27cae8ddd0261830f45161fe05adf72e69e142df3f02\
+                                     3e605ae9ba7a5d337502206e605a911cfec79eb7feac980c686822\
+                                     55b970b05e8c7e86494a0fcc07958cb0";
+
+    const FIXTURE_CANONICAL_FORM: &[u8] = b"mononoke-commit-signature-v1\n\
+        author 27\n\
+        Jane Doe <jane@example.com>\n\
+        message 21\n\
+        fix the build\n\nsigned\n\
+        files 3\n\
+        M 5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03 5\n\
+        a.txt\n\
+        A 7aa7a5359173d05b63cfd682e3c38487f3cb4f7f1d60659fe59fab1505977d4c 9\n\
+        b/new.txt\n\
+        D - 9\n\
+        c/old.txt\n";
+
+    fn fixture() -> ChangesetFixture {
+        ChangesetFixture::new()
+            .author("Jane Doe <jane@example.com>")
+            .comments("fix the build\n\nsigned")
+            .added("b/new.txt", "new\n")
+            .modified("a.txt", "hello\n")
+            .deleted("c/old.txt")
+    }
+
+    fn fixture_files() -> Vec<SignedFile> {
+        vec![
+            SignedFile {
+                path: "b/new.txt".into(),
+                ty: ChangedFileType::Added,
+                content_sha256: Some(sha256(b"new\n")),
+            },
+            SignedFile {
+                path: "a.txt".into(),
+                ty: ChangedFileType::Modified,
+                content_sha256: Some(sha256(b"hello\n")),
+            },
+            SignedFile {
+                path: "c/old.txt".into(),
+                ty: ChangedFileType::Deleted,
+                content_sha256: None,
+            },
+        ]
+    }
+
+    fn hook(config: &str) -> Result<Arc<Hook<HookChangeset>>, Error> {
+        let config: toml::Value = toml::from_str(config).unwrap();
+        let hook = VerifyCommitSignature::new("testhook", Some(&config))?;
+        Ok(Arc::new(hook))
+    }
+
+    fn trusting(keys: &[&str]) -> Arc<Hook<HookChangeset>> {
+        let keys: Vec<_> = keys.iter().map(|key| toml::Value::from(*key)).collect();
+        let config = toml::to_string(&btreemap! { "trusted_keys" => keys }).unwrap();
+        hook(&config).unwrap()
+    }
+
+    fn is_rejected(execution: HookExecution, description: &str) -> bool {
+        match execution {
+            HookExecution::Rejected(info) => info.description == description,
+            HookExecution::Accepted | HookExecution::Warned(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_canonical_form() {
+        let canonical = canonical_form(
+            "Jane Doe <jane@example.com>",
+            "fix the build\n\nsigned",
+            &fixture_files(),
+        );
+        assert_eq!(canonical, FIXTURE_CANONICAL_FORM);
+    }
+
+    #[test]
+    fn test_canonical_form_empty() {
+        assert_eq!(
+            canonical_form("", "", &[]),
+            &b"mononoke-commit-signature-v1\nauthor 0\n\nmessage 0\n\nfiles 0\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_canonical_form_is_unambiguous() {
+        // Moving bytes from the author to the message changes the canonical form, even when
+        // they look like the framing
+        let a = canonical_form("a\nmessage 1\nb", "c", &[]);
+        let b = canonical_form("a", "b\nmessage 1\nc", &[]);
+        assert_ne!(a, b);
+
+        // Files are sorted by the bytes of their paths, not by how they were changed
+        let mut files = fixture_files();
+        files.reverse();
+        assert_eq!(
+            canonical_form("Jane Doe <jane@example.com>", "fix the build\n\nsigned", &files),
+            FIXTURE_CANONICAL_FORM
+        );
+        let files = vec![
+            SignedFile {
+                path: "a-b".into(),
+                ty: ChangedFileType::Deleted,
+                content_sha256: None,
+            },
+            SignedFile {
+                path: "a/b".into(),
+                ty: ChangedFileType::Deleted,
+                content_sha256: None,
+            },
+        ];
+        let canonical = canonical_form("", "", &files);
+        assert!(canonical.ends_with(b"D - 3\na-b\nD - 3\na/b\n"));
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("00AB10\n"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(from_hex("0ab"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(from_hex("é"), None);
+    }
+
+    #[test]
+    fn test_fixture_signature() {
+        let key = PKey::public_key_from_pem(TRUSTED_KEY.as_bytes()).unwrap();
+        let other = PKey::public_key_from_pem(OTHER_KEY.as_bytes()).unwrap();
+        let signature = from_hex(FIXTURE_SIGNATURE).unwrap();
+        assert!(is_trusted(&[key.clone()], FIXTURE_CANONICAL_FORM, &signature));
+        assert!(is_trusted(&[other.clone(), key], FIXTURE_CANONICAL_FORM, &signature));
+        assert!(!is_trusted(&[other], FIXTURE_CANONICAL_FORM, &signature));
+    }
+
+    #[test]
+    fn test_signed() {
+        let changeset = fixture().extra("signature", FIXTURE_SIGNATURE).build();
+        assert_eq!(
+            run_changeset_hook(trusting(&[TRUSTED_KEY]), changeset.clone()).unwrap(),
+            HookExecution::Accepted
+        );
+        assert_eq!(
+            run_changeset_hook(trusting(&[OTHER_KEY, TRUSTED_KEY]), changeset.clone()).unwrap(),
+            HookExecution::Accepted
+        );
+        assert!(is_rejected(
+            run_changeset_hook(trusting(&[OTHER_KEY]), changeset).unwrap(),
+            "Commit signature is invalid"
+        ));
+    }
+
+    #[test]
+    fn test_extra_key() {
+        let changeset = fixture().extra("gpgsig", FIXTURE_SIGNATURE).build();
+        let config = format!(
+            "extra_key = \"gpgsig\"\ntrusted_keys = [\"\"\"\n{}\"\"\"]",
+            TRUSTED_KEY
+        );
+        assert_eq!(
+            run_changeset_hook(hook(&config).unwrap(), changeset.clone()).unwrap(),
+            HookExecution::Accepted
+        );
+        assert!(is_rejected(
+            run_changeset_hook(trusting(&[TRUSTED_KEY]), changeset).unwrap(),
+            "Commit is not signed"
+        ));
+    }
+
+    #[test]
+    fn test_unsigned_or_tampered() {
+        let hook = trusting(&[TRUSTED_KEY]);
+        let rejected_as = |changeset: ChangesetFixture, description: &str| {
+            let execution = run_changeset_hook(hook.clone(), changeset.build()).unwrap();
+            assert!(is_rejected(execution, description), "{}", description);
+        };
+
+        rejected_as(fixture(), "Commit is not signed");
+        rejected_as(
+            fixture().extra("signature", "not hex"),
+            "Commit signature is invalid",
+        );
+        rejected_as(
+            fixture().extra("signature", "abcd"),
+            "Commit signature is invalid",
+        );
+        rejected_as(
+            fixture()
+                .author("Mallory <mallory@example.com>")
+                .extra("signature", FIXTURE_SIGNATURE),
+            "Commit signature is invalid",
+        );
+        rejected_as(
+            fixture()
+                .comments("fix the build\n\nsigned, honest")
+                .extra("signature", FIXTURE_SIGNATURE),
+            "Commit signature is invalid",
+        );
+        rejected_as(
+            fixture()
+                .added("backdoor", "oops\n")
+                .extra("signature", FIXTURE_SIGNATURE),
+            "Commit signature is invalid",
+        );
+        // Same paths, other content
+        let changeset = ChangesetFixture::new()
+            .author("Jane Doe <jane@example.com>")
+            .comments("fix the build\n\nsigned")
+            .added("b/new.txt", "evil\n")
+            .modified("a.txt", "hello\n")
+            .deleted("c/old.txt")
+            .extra("signature", FIXTURE_SIGNATURE);
+        rejected_as(changeset, "Commit signature is invalid");
+    }
+
+    #[test]
+    fn test_key_file() {
+        let dir = TempDir::new("verify_signature").unwrap();
+        let path = dir.path().join("trusted.pem");
+        File::create(&path)
+            .unwrap()
+            .write_all(TRUSTED_KEY.as_bytes())
+            .unwrap();
+        let config = toml::to_string(&btreemap! {
+            "trusted_key_files" => vec![path.to_str().unwrap()],
+        }).unwrap();
+
+        let changeset = fixture().extra("signature", FIXTURE_SIGNATURE).build();
+        assert_eq!(
+            run_changeset_hook(hook(&config).unwrap(), changeset).unwrap(),
+            HookExecution::Accepted
+        );
+
+        let missing = dir.path().join("missing.pem");
+        let config = toml::to_string(&btreemap! {
+            "trusted_key_files" => vec![missing.to_str().unwrap()],
+        }).unwrap();
+        assert!(hook(&config).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(VerifyCommitSignature::new("testhook", None).is_err());
+        assert!(hook("").is_err());
+        assert!(hook("trusted_keys = []").is_err());
+        assert!(hook("trusted_keys = [\"not a key\"]").is_err());
+        assert!(hook("trusted_keys = 5").is_err());
+        let key = format!("trusted_keys = [\"\"\"\n{}\"\"\"]", TRUSTED_KEY);
+        assert!(hook(&key).is_ok());
+        assert!(hook(&format!("{}\nscheme = \"v1\"", key)).is_ok());
+        assert!(hook(&format!("{}\nscheme = \"v2\"", key)).is_err());
+        assert!(hook(&format!("{}\nextra_key = 1", key)).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Load generation for a Mononoke server, e.g. a staging host.
+//!
+//! A `Scenario` describes how many pullers and pushers to run and for how long. Pullers send
+//! getbundle requests for the heads of the repo, with common points picked at random from its
+//! history. Pushers either replay recorded bundles or push synthetic commits. Each worker has its
+//! own random generator, seeded from the seed of the scenario, so that a run can be reproduced.
+//! The latencies and errors of each command are collected in a `Report`.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate futures_ext;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate slog;
+extern crate tokio;
+extern crate toml;
+
+extern crate mercurial;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+extern crate mononoke_test_server;
+extern crate mononoke_types;
+
+mod report;
+mod runner;
+mod scenario;
+mod synthetic;
+
+pub use report::{CommandReport, Latencies, Report};
+pub use runner::run;
+pub use scenario::{PullScenario, PushScenario, Scenario};
+pub use synthetic::SyntheticCommit;
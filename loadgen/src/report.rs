@@ -0,0 +1,165 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use scenario::Scenario;
+
+/// Results of a run
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub scenario: Scenario,
+    /// How long the run actually took, e.g. including the requests that were still running at the
+    /// end of its duration
+    pub elapsed_secs: f64,
+    /// Results of each wireproto command, e.g. "getbundle"
+    pub commands: BTreeMap<String, CommandReport>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandReport {
+    pub requests: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    /// Latencies of the successful requests. None if there were none.
+    pub latency_ms: Option<Latencies>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Latencies {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Latencies {
+    /// Nearest-rank percentiles of `latencies`, which must not be empty
+    fn new(mut latencies: Vec<Duration>) -> Self {
+        latencies.sort();
+        let percentile = |p: usize| {
+            let rank = (latencies.len() * p + 99) / 100;
+            as_millis(latencies[rank.max(1) - 1])
+        };
+        Latencies {
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: percentile(100),
+        }
+    }
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0
+}
+
+#[derive(Default)]
+struct CommandResults {
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+/// Collects the results of the requests of all the workers
+#[derive(Clone, Default)]
+pub struct Recorder {
+    results: Arc<Mutex<HashMap<&'static str, CommandResults>>>,
+}
+
+impl Recorder {
+    pub fn success(&self, command: &'static str, latency: Duration) {
+        let mut results = self.results.lock().expect("lock poisoned");
+        results.entry(command).or_default().latencies.push(latency);
+    }
+
+    pub fn error(&self, command: &'static str) {
+        let mut results = self.results.lock().expect("lock poisoned");
+        results.entry(command).or_default().errors += 1;
+    }
+
+    pub fn report(&self, scenario: Scenario, elapsed: Duration) -> Report {
+        let mut results = self.results.lock().expect("lock poisoned");
+        let commands = results
+            .drain()
+            .map(|(command, results)| {
+                let requests = results.latencies.len() as u64 + results.errors;
+                let latency_ms = if results.latencies.is_empty() {
+                    None
+                } else {
+                    Some(Latencies::new(results.latencies))
+                };
+                let report = CommandReport {
+                    requests,
+                    errors: results.errors,
+                    error_rate: results.errors as f64 / requests as f64,
+                    latency_ms,
+                };
+                (command.to_string(), report)
+            })
+            .collect();
+
+        Report {
+            scenario,
+            elapsed_secs: as_millis(elapsed) / 1000.0,
+            commands,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use scenario::{PullScenario, PushScenario};
+
+    fn scenario() -> Scenario {
+        Scenario {
+            seed: 0,
+            ramp_up_secs: 0,
+            duration_secs: 1,
+            pull: PullScenario { concurrency: 1 },
+            push: PushScenario::default(),
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let latencies = (1..101).map(Duration::from_millis).collect();
+        let latencies = Latencies::new(latencies);
+        assert_eq!(latencies.p50, 50.0);
+        assert_eq!(latencies.p90, 90.0);
+        assert_eq!(latencies.p99, 99.0);
+        assert_eq!(latencies.max, 100.0);
+
+        let latencies = Latencies::new(vec![Duration::from_millis(3)]);
+        assert_eq!(latencies.p50, 3.0);
+        assert_eq!(latencies.max, 3.0);
+    }
+
+    #[test]
+    fn test_report() {
+        let recorder = Recorder::default();
+        recorder.success("getbundle", Duration::from_millis(10));
+        recorder.success("getbundle", Duration::from_millis(30));
+        recorder.error("getbundle");
+        recorder.error("unbundle");
+
+        let report = recorder.report(scenario(), Duration::from_millis(1500));
+        assert_eq!(report.elapsed_secs, 1.5);
+
+        let getbundle = &report.commands["getbundle"];
+        assert_eq!(getbundle.requests, 3);
+        assert_eq!(getbundle.errors, 1);
+        assert_eq!(getbundle.latency_ms.as_ref().unwrap().max, 30.0);
+
+        let unbundle = &report.commands["unbundle"];
+        assert_eq!(unbundle.requests, 1);
+        assert_eq!(unbundle.error_rate, 1.0);
+        assert!(unbundle.latency_ms.is_none());
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fs;
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use failure::{Error, FutureFailureErrorExt, Result, ResultExt};
+use futures::future::{self, loop_fn, Loop};
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use rand::{Isaac64Rng, Rng};
+use slog::Logger;
+use tokio::timer::Delay;
+
+use mercurial_types::{HgChangesetId, NULL_CSID};
+use mononoke_test_server::TestClient;
+
+use report::{Recorder, Report};
+use scenario::Scenario;
+use synthetic::SyntheticCommit;
+
+/// Runs `scenario` against the repo that `client` talks to. Failed requests are counted in the
+/// report and don't stop the run.
+pub fn run(scenario: Scenario, client: TestClient, logger: Logger) -> BoxFuture<Report, Error> {
+    try_boxfuture!(scenario.validate());
+    let bundles: Vec<Bytes> = try_boxfuture!(
+        scenario
+            .push
+            .bundles
+            .iter()
+            .map(|path| -> Result<Bytes> {
+                let bundle = fs::read(path).with_context(|_| format!("reading {:?}", path))?;
+                Ok(Bytes::from(bundle))
+            })
+            .collect::<Result<_>>()
+    );
+
+    let history = if scenario.pull.concurrency > 0 {
+        discover_history(&client)
+    } else {
+        future::ok(History::default()).boxify()
+    };
+
+    history
+        .and_then(move |history| {
+            info!(
+                logger,
+                "starting {} pullers and {} pushers over {}s",
+                scenario.pull.concurrency,
+                scenario.push.concurrency,
+                scenario.ramp_up_secs;
+                "heads" => history.heads.len(),
+                "common_points" => history.common.len(),
+            );
+
+            let recorder = Recorder::default();
+            let history = Arc::new(history);
+            let bundles = Arc::new(bundles);
+            let mut seeds = Isaac64Rng::new_from_u64(scenario.seed);
+            let start = Instant::now();
+            let deadline = start + scenario.duration();
+
+            let workers: Vec<_> = (0..scenario.workers())
+                .map(|index| {
+                    let worker = Worker {
+                        index,
+                        client: client.clone(),
+                        recorder: recorder.clone(),
+                        logger: logger.clone(),
+                        rng: Isaac64Rng::new_from_u64(seeds.gen()),
+                        deadline,
+                    };
+                    let work = if scenario.is_pusher(index) {
+                        worker.push(bundles.clone())
+                    } else {
+                        worker.pull(history.clone())
+                    };
+                    Delay::new(start + scenario.start_offset(index))
+                        .from_err()
+                        .and_then(move |()| work)
+                })
+                .collect();
+
+            future::join_all(workers).map(move |_| {
+                let report = recorder.report(scenario, start.elapsed());
+                info!(logger, "run finished after {:.1}s", report.elapsed_secs);
+                report
+            })
+        })
+        .boxify()
+}
+
+/// Commits that pullers request, and the common points they request them from
+#[derive(Debug, Default)]
+struct History {
+    heads: Vec<HgChangesetId>,
+    /// The heads, ancestors of the heads at distance 1, 2, 4, ... from them, and the null commit
+    common: Vec<HgChangesetId>,
+}
+
+fn discover_history(client: &TestClient) -> BoxFuture<History, Error> {
+    let client = client.clone();
+    client
+        .heads()
+        .and_then(move |heads| {
+            let mut heads: Vec<_> = heads.into_iter().filter(|head| *head != NULL_CSID).collect();
+            if heads.is_empty() {
+                bail_msg!("the repo has no commits to pull");
+            }
+            // The order of the heads is not defined, and the choices of the pullers must only
+            // depend on the seed
+            heads.sort();
+            Ok(heads)
+        })
+        .and_then(move |heads| {
+            let ancestors: Vec<_> = heads
+                .iter()
+                .map(|head| client.between(*head, NULL_CSID))
+                .collect();
+            future::join_all(ancestors).map(move |ancestors| {
+                let mut common = vec![NULL_CSID];
+                common.extend(heads.iter().cloned());
+                common.extend(ancestors.into_iter().flat_map(|ancestors| ancestors));
+                common.sort();
+                common.dedup();
+                History { heads, common }
+            })
+        })
+        .context("discovering the history of the repo")
+        .from_err()
+        .boxify()
+}
+
+struct Worker {
+    index: usize,
+    client: TestClient,
+    recorder: Recorder,
+    logger: Logger,
+    rng: Isaac64Rng,
+    deadline: Instant,
+}
+
+impl Worker {
+    /// Requests bundles of random heads from random common points until the deadline
+    fn pull(self, history: Arc<History>) -> BoxFuture<(), Error> {
+        let Worker {
+            client,
+            recorder,
+            logger,
+            rng,
+            deadline,
+            ..
+        } = self;
+
+        loop_fn(rng, move |mut rng| {
+            if Instant::now() >= deadline {
+                return future::ok(Loop::Break(())).boxify();
+            }
+            let head = *rng.choose(&history.heads).expect("history has heads");
+            let common = *rng.choose(&history.common).expect("history has common points");
+            timed(
+                &recorder,
+                &logger,
+                "getbundle",
+                client.getbundle(&[head], &[common], &[]),
+            ).map(move |()| Loop::Continue(rng))
+                .boxify()
+        }).boxify()
+    }
+
+    /// Pushes random recorded bundles, or synthetic commits if there are none, until the deadline
+    fn push(self, bundles: Arc<Vec<Bytes>>) -> BoxFuture<(), Error> {
+        let Worker {
+            index,
+            client,
+            recorder,
+            logger,
+            rng,
+            deadline,
+        } = self;
+
+        loop_fn((rng, 0), move |(mut rng, count)| {
+            if Instant::now() >= deadline {
+                return future::ok(Loop::Break(())).boxify();
+            }
+            let bundle = match rng.choose(bundles.as_slice()) {
+                Some(bundle) => future::ok(bundle.clone()).boxify(),
+                None => {
+                    let name = format!("loadgen-{}-{}", index, count);
+                    try_boxfuture!(SyntheticCommit::generate(&mut rng, &name)).bundle()
+                }
+            };
+            let client = client.clone();
+            let recorder = recorder.clone();
+            let logger = logger.clone();
+            bundle
+                .and_then(move |bundle| {
+                    timed(&recorder, &logger, "unbundle", client.unbundle(bundle))
+                })
+                .map(move |()| Loop::Continue((rng, count + 1)))
+                .boxify()
+        }).boxify()
+    }
+}
+
+/// Records the latency of `request` if it succeeds, or its failure
+fn timed<F>(
+    recorder: &Recorder,
+    logger: &Logger,
+    command: &'static str,
+    request: F,
+) -> BoxFuture<(), Error>
+where
+    F: Future<Error = Error> + Send + 'static,
+{
+    let recorder = recorder.clone();
+    let logger = logger.clone();
+    let start = Instant::now();
+    request
+        .then(move |result| {
+            match result {
+                Ok(_) => recorder.success(command, start.elapsed()),
+                Err(err) => {
+                    debug!(logger, "{} failed: {}", command, err);
+                    recorder.error(command);
+                }
+            }
+            Ok(())
+        })
+        .boxify()
+}
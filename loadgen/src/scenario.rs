@@ -0,0 +1,171 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use failure::Result;
+use toml;
+
+/// What load to generate. It is read from a TOML file like this:
+///
+/// ```toml
+/// seed = 42
+/// ramp_up_secs = 60
+/// duration_secs = 600
+///
+/// [pull]
+/// concurrency = 20
+///
+/// [push]
+/// concurrency = 2
+/// # Bundles to replay. Synthetic single-file commits are pushed if there are none.
+/// bundles = ["/data/bundles/1.bundle", "/data/bundles/2.bundle"]
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Scenario {
+    /// Seed of all the random choices of the workers
+    #[serde(default)]
+    pub seed: u64,
+    /// The workers are started one after another during this time, so that the load grows
+    /// gradually
+    #[serde(default)]
+    pub ramp_up_secs: u64,
+    /// How long the run lasts, ramp-up included
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub pull: PullScenario,
+    #[serde(default)]
+    pub push: PushScenario,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PullScenario {
+    /// Number of concurrent pullers
+    #[serde(default)]
+    pub concurrency: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PushScenario {
+    /// Number of concurrent pushers
+    #[serde(default)]
+    pub concurrency: usize,
+    /// Recorded bundles to replay
+    #[serde(default)]
+    pub bundles: Vec<PathBuf>,
+}
+
+impl Scenario {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_toml(&fs::read(path)?)
+    }
+
+    pub fn from_toml(bytes: &[u8]) -> Result<Self> {
+        let scenario: Self = toml::from_slice(bytes)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.workers() == 0 {
+            bail_msg!("the scenario has neither pullers nor pushers");
+        }
+        if self.duration_secs == 0 {
+            bail_msg!("duration_secs must be positive");
+        }
+        if self.ramp_up_secs > self.duration_secs {
+            bail_msg!(
+                "ramp_up_secs ({}) is longer than duration_secs ({})",
+                self.ramp_up_secs,
+                self.duration_secs
+            );
+        }
+        Ok(())
+    }
+
+    pub fn workers(&self) -> usize {
+        self.pull.concurrency + self.push.concurrency
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration_secs)
+    }
+
+    /// Whether the worker `index` is a pusher. Pushers are spread evenly among the pullers, so
+    /// that both are started throughout the ramp-up.
+    pub fn is_pusher(&self, index: usize) -> bool {
+        let pushers_until = |index: usize| index * self.push.concurrency / self.workers();
+        pushers_until(index + 1) > pushers_until(index)
+    }
+
+    /// When the worker `index` starts, relative to the start of the run
+    pub fn start_offset(&self, index: usize) -> Duration {
+        let ramp_up_ms = self.ramp_up_secs * 1000;
+        Duration::from_millis(ramp_up_ms * index as u64 / self.workers() as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let scenario = Scenario::from_toml(
+            br#"
+            seed = 7
+            ramp_up_secs = 10
+            duration_secs = 20
+
+            [pull]
+            concurrency = 3
+
+            [push]
+            concurrency = 1
+            bundles = ["a.bundle"]
+            "#,
+        ).unwrap();
+
+        assert_eq!(scenario.seed, 7);
+        assert_eq!(scenario.workers(), 4);
+        assert_eq!(scenario.push.bundles, vec![PathBuf::from("a.bundle")]);
+        assert_eq!(scenario.start_offset(0), Duration::from_secs(0));
+        assert_eq!(scenario.start_offset(2), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_is_pusher() {
+        let mut scenario = Scenario::from_toml(b"duration_secs = 1\n[pull]\nconcurrency = 6")
+            .unwrap();
+        scenario.push.concurrency = 2;
+        let pushers: Vec<_> = (0..8).filter(|index| scenario.is_pusher(*index)).collect();
+        assert_eq!(pushers, vec![3, 7]);
+
+        scenario.pull.concurrency = 0;
+        assert!((0..2).all(|index| scenario.is_pusher(index)));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let scenario = Scenario::from_toml(b"duration_secs = 1\n[push]\nconcurrency = 1").unwrap();
+        assert_eq!(scenario.seed, 0);
+        assert_eq!(scenario.ramp_up_secs, 0);
+        assert_eq!(scenario.pull.concurrency, 0);
+        assert!(scenario.push.bundles.is_empty());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Scenario::from_toml(b"duration_secs = 1").is_err());
+        assert!(Scenario::from_toml(b"duration_secs = 0\n[pull]\nconcurrency = 1").is_err());
+        assert!(
+            Scenario::from_toml(b"duration_secs = 1\nramp_up_secs = 2\n[pull]\nconcurrency = 1")
+                .is_err()
+        );
+    }
+}
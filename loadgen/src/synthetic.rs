@@ -0,0 +1,194 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Synthetic commits for pushers that don't replay recorded bundles.
+//!
+//! A synthetic commit adds a single file at the root of the repo and has no parents, so that its
+//! bundle can be built without knowing anything about the repo it is pushed to. The bundle has
+//! the same parts as the bundle of a treemanifest `hg push`: replycaps, a changegroup with the
+//! changeset and the file, and a treegroup2 with the root manifest.
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::{future, stream, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use rand::Rng;
+
+use mercurial::RevlogChangeset;
+use mercurial_bundles::Bundle2EncodeBuilder;
+use mercurial_bundles::PartHeaderType;
+use mercurial_bundles::changegroup::{CgDeltaChunk, Part, Section};
+use mercurial_bundles::changegroup::packer::CgPacker;
+use mercurial_bundles::part_encode::PartEncodeBuilder;
+use mercurial_bundles::parts::{treepack_part, TreepackPartInput};
+use mercurial_types::{Delta, HgBlobNode, HgChangesetId, HgManifestId, HgNodeHash, HgParents,
+                      MPath, NULL_HASH};
+use mononoke_types::DateTime;
+
+const USER: &[u8] = b"mononoke_loadgen <loadgen@mononoke>";
+const REPLYCAPS: &[u8] = b"HG20\nb2x:treegroup2=1\nchangegroup=02\npushkey";
+/// Number of random lines in the file of a commit
+const FILE_LINES: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct SyntheticCommit {
+    pub id: HgChangesetId,
+    path: MPath,
+    content: Bytes,
+    filenode: HgNodeHash,
+    manifest: Bytes,
+    manifestnode: HgNodeHash,
+    changeset: Bytes,
+}
+
+impl SyntheticCommit {
+    /// A commit that adds the file `name` with random content. The same `name` and the same
+    /// state of `rng` give the same commit.
+    pub fn generate<R: Rng>(rng: &mut R, name: &str) -> Result<Self> {
+        let path = MPath::new(name)?;
+        if path.num_components() != 1 {
+            bail_msg!("synthetic files must be at the root of the repo: {}", path);
+        }
+
+        let mut content = Vec::new();
+        for _ in 0..FILE_LINES {
+            content.extend_from_slice(format!("{:016x}\n", rng.gen::<u64>()).as_bytes());
+        }
+        let content = Bytes::from(content);
+        let filenode = HgBlobNode::new(content.clone(), None, None).nodeid();
+
+        let mut manifest = path.to_vec();
+        manifest.push(0);
+        manifest.extend_from_slice(format!("{}\n", filenode).as_bytes());
+        let manifest = Bytes::from(manifest);
+        let manifestnode = HgBlobNode::new(manifest.clone(), None, None).nodeid();
+
+        let changeset = RevlogChangeset::new_from_parts(
+            HgParents::None,
+            HgManifestId::new(manifestnode),
+            USER.to_vec(),
+            DateTime::from_timestamp(rng.gen_range(0, 1 << 31), 0)?,
+            BTreeMap::new(),
+            vec![path.clone()],
+            format!("loadgen: add {}", name).into_bytes(),
+        );
+        let changeset = changeset.get_node()?;
+        let id = HgChangesetId::new(changeset.nodeid());
+
+        Ok(SyntheticCommit {
+            id,
+            path,
+            content,
+            filenode,
+            manifest,
+            manifestnode,
+            changeset: changeset.as_blob().as_inner().clone(),
+        })
+    }
+
+    /// The bundle2 that pushes this commit
+    pub fn bundle(&self) -> BoxFuture<Bytes, Error> {
+        let linknode = self.id.into_nodehash();
+        let fulltext = |node, text: &Bytes| CgDeltaChunk {
+            node,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode,
+            delta: Delta::new_fulltext(text.to_vec()),
+            flags: None,
+        };
+
+        let mut replycaps = try_boxfuture!(PartEncodeBuilder::mandatory(
+            PartHeaderType::Replycaps
+        ));
+        try_boxfuture!(replycaps.set_data_bytes(REPLYCAPS));
+
+        let mut changegroup = try_boxfuture!(PartEncodeBuilder::mandatory(
+            PartHeaderType::Changegroup
+        ));
+        try_boxfuture!(changegroup.add_mparam("version", "02"));
+        let filelog = Section::Filelog(self.path.clone());
+        let cgparts = vec![
+            Part::CgChunk(Section::Changeset, fulltext(linknode, &self.changeset)),
+            Part::SectionEnd(Section::Changeset),
+            // There are no flat manifests in a treemanifest push
+            Part::SectionEnd(Section::Manifest),
+            Part::CgChunk(filelog.clone(), fulltext(self.filenode, &self.content)),
+            Part::SectionEnd(filelog),
+            Part::End,
+        ];
+        changegroup.set_data_generated(CgPacker::new(stream::iter_ok::<_, Error>(cgparts)));
+
+        let root_manifest = TreepackPartInput {
+            node: self.manifestnode,
+            p1: None,
+            p2: None,
+            content: self.manifest.clone(),
+            name: None,
+            linknode,
+            basepath: None,
+        };
+        let treegroup = try_boxfuture!(treepack_part(
+            stream::once(Ok(future::ok(root_manifest).boxify())),
+            1,
+        ));
+
+        let mut builder = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
+        builder
+            .add_part(replycaps)
+            .add_part(changegroup)
+            .add_part(treegroup);
+        builder
+            .build()
+            .map(|cursor| Bytes::from(cursor.into_inner()))
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::Isaac64Rng;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_deterministic() {
+        let first = SyntheticCommit::generate(&mut Isaac64Rng::new_from_u64(1), "a").unwrap();
+        let second = SyntheticCommit::generate(&mut Isaac64Rng::new_from_u64(1), "a").unwrap();
+        assert_eq!(first.id, second.id);
+
+        let other_seed = SyntheticCommit::generate(&mut Isaac64Rng::new_from_u64(2), "a").unwrap();
+        assert_ne!(first.id, other_seed.id);
+        let other_name = SyntheticCommit::generate(&mut Isaac64Rng::new_from_u64(1), "b").unwrap();
+        assert_ne!(first.id, other_name.id);
+    }
+
+    #[test]
+    fn test_root_files_only() {
+        let mut rng = Isaac64Rng::new_from_u64(1);
+        assert!(SyntheticCommit::generate(&mut rng, "dir/a").is_err());
+    }
+
+    #[test]
+    fn test_bundle() {
+        let commit = SyntheticCommit::generate(&mut Isaac64Rng::new_from_u64(1), "a").unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        let bundle = runtime.block_on(commit.bundle()).unwrap();
+
+        assert!(bundle.starts_with(b"HG20"));
+        let contains = |needle: &[u8]| bundle.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"REPLYCAPS"));
+        assert!(contains(b"CHANGEGROUP"));
+        assert!(contains(b"B2X:TREEGROUP2"));
+        // The changeset text refers to the root manifest
+        assert!(contains(commit.manifestnode.to_string().as_bytes()));
+    }
+}
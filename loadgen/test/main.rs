@@ -0,0 +1,117 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate loadgen;
+extern crate mononoke_test_server;
+extern crate serde_json;
+#[macro_use]
+extern crate slog;
+extern crate tempdir;
+
+use std::fs;
+
+use bytes::Bytes;
+use slog::{Discard, Drain, Logger};
+use tempdir::TempDir;
+
+use loadgen::{PullScenario, PushScenario, Report, Scenario};
+use mononoke_test_server::TestServer;
+
+// A bundle2 that adds a single commit and points the bookmark "master" to it
+const PUSH_ONE_COMMIT: &[u8] = include_bytes!("../../tests/server/fixtures/push_one_commit.bundle");
+
+fn logger() -> Logger {
+    Logger::root(Discard {}.ignore_res(), o!())
+}
+
+fn run(server: &mut TestServer, scenario: Scenario) -> Report {
+    let client = server.client("repo").expect("failed to create a client");
+    server
+        .block_on(loadgen::run(scenario, client, logger()))
+        .expect("run failed")
+}
+
+#[test]
+fn test_pull_and_synthetic_push() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+
+    let scenario = Scenario {
+        seed: 1,
+        ramp_up_secs: 1,
+        duration_secs: 2,
+        pull: PullScenario { concurrency: 2 },
+        push: PushScenario {
+            concurrency: 1,
+            bundles: vec![],
+        },
+    };
+    let report = run(&mut server, scenario);
+
+    assert!(report.elapsed_secs >= 2.0, "{:?}", report);
+    for command in &["getbundle", "unbundle"] {
+        let results = &report.commands[*command];
+        assert!(results.requests > 0, "{}: {:?}", command, results);
+        assert_eq!(results.errors, 0, "{}: {:?}", command, results);
+        assert!(results.latency_ms.is_some(), "{}: {:?}", command, results);
+    }
+
+    // Every synthetic commit is a new head
+    let heads = server.block_on(client.heads()).expect("heads failed");
+    assert!(heads.len() > 1, "{:?}", heads);
+
+    let json = serde_json::to_value(&report).expect("failed to serialize the report");
+    assert_eq!(json["scenario"]["pull"]["concurrency"], 2);
+    assert!(json["commands"]["getbundle"]["latency_ms"]["p99"].is_number());
+    assert!(json["commands"]["unbundle"]["error_rate"].is_number());
+}
+
+#[test]
+fn test_replay_bundles() {
+    let dir = TempDir::new("loadgen").expect("failed to create a temporary directory");
+    let bundle = dir.path().join("push_one_commit.bundle");
+    fs::write(&bundle, PUSH_ONE_COMMIT).expect("failed to write the bundle");
+
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let scenario = Scenario {
+        seed: 1,
+        ramp_up_secs: 0,
+        duration_secs: 1,
+        pull: PullScenario::default(),
+        push: PushScenario {
+            concurrency: 1,
+            bundles: vec![bundle],
+        },
+    };
+    let report = run(&mut server, scenario);
+
+    assert_eq!(report.commands.keys().collect::<Vec<_>>(), vec!["unbundle"]);
+    let unbundle = &report.commands["unbundle"];
+    assert!(unbundle.requests > 0, "{:?}", unbundle);
+    assert_eq!(unbundle.errors, 0, "{:?}", unbundle);
+}
+
+#[test]
+fn test_pull_from_empty_repo() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    let scenario = Scenario {
+        seed: 1,
+        ramp_up_secs: 0,
+        duration_secs: 1,
+        pull: PullScenario { concurrency: 1 },
+        push: PushScenario::default(),
+    };
+
+    let result = server.block_on(loadgen::run(scenario, client, logger()));
+    assert!(result.is_err());
+}
@@ -64,6 +64,7 @@ where
 struct Bundle2StreamInner {
     logger: slog::Logger,
     app_errors: Vec<ErrorKind>,
+    max_chunk_bytes: Option<u64>,
 }
 
 enum CurrentStream<R>
@@ -130,6 +131,7 @@ where
             inner: Bundle2StreamInner {
                 logger: logger,
                 app_errors: Vec::new(),
+                max_chunk_bytes: None,
             },
             current_stream: CurrentStream::Start(Framed::from_parts(FramedParts::new(
                 read,
@@ -138,6 +140,13 @@ where
         }
     }
 
+    /// Rejects any bundle2 or changegroup chunk that announces more than `max_chunk_bytes` bytes,
+    /// before its payload is read.
+    pub fn with_max_chunk_bytes(mut self, max_chunk_bytes: u64) -> Self {
+        self.inner.max_chunk_bytes = Some(max_chunk_bytes);
+        self
+    }
+
     pub fn app_errors(&self) -> &[ErrorKind] {
         &self.inner.app_errors
     }
@@ -190,7 +199,12 @@ impl Bundle2StreamInner {
                             "write_buf must be empty, since io is not AsyncWrite"
                         );
 
-                        match outer_stream(&start, Cursor::new(read_buf).chain(io), &self.logger) {
+                        match outer_stream(
+                            &start,
+                            Cursor::new(read_buf).chain(io),
+                            &self.logger,
+                            self.max_chunk_bytes,
+                        ) {
                             Err(e) => {
                                 // Can't do much if reading stream level params
                                 // failed -- go to the invalid state.
@@ -225,7 +239,8 @@ impl Bundle2StreamInner {
                         (Ok(Async::Ready(None)), CurrentStream::Outer(stream))
                     }
                     Ok(Async::Ready(Some(OuterFrame::Header(header)))) => {
-                        let (bundle2item, remainder) = inner_stream(header, stream, &self.logger);
+                        let (bundle2item, remainder) =
+                            inner_stream(header, stream, &self.logger, self.max_chunk_bytes);
                         (
                             Ok(Async::Ready(Some(StreamEvent::Next(bundle2item)))),
                             CurrentStream::Inner(remainder),
@@ -15,12 +15,11 @@ use failure::prelude::*;
 use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
 use futures::stream::Forward;
 use futures_ext::io::Either::{self, A as UncompressedRead, B as CompressedRead};
-use tokio_codec::FramedWrite;
 use tokio_io::AsyncWrite;
 
 use async_compression::{Compressor, CompressorType};
 
-use chunk::{Chunk, ChunkEncoder};
+use chunk::{Chunk, ChunkWriter};
 use errors::*;
 use mercurial_types::percent_encode;
 use part_encode::{PartEncode, PartEncodeBuilder};
@@ -154,8 +153,9 @@ where
     }
 }
 
-/// A sink that chunks generated by PartEncodes goes into.
-type PartSink<W> = NotClosingSink<FramedWrite<Either<W, Compressor<W>>, ChunkEncoder>>;
+/// A sink that chunks generated by PartEncodes goes into. Large payloads, like the contents of
+/// files, are written from the chunks that carry them rather than copied into a frame.
+type PartSink<W> = NotClosingSink<ChunkWriter<Either<W, Compressor<W>>>>;
 
 /// A future to drive writing a part to a sink.
 type PartFuture<W> = Forward<PartEncode, PartSink<W>>;
@@ -223,15 +223,12 @@ where
         (
             self.parts.into_iter(),
             NotClosingSink {
-                inner: FramedWrite::new(
-                    match self.compressor_type {
-                        None => UncompressedRead(self.writer),
-                        Some(compressor_type) => {
-                            CompressedRead(Compressor::new(self.writer, compressor_type))
-                        }
-                    },
-                    ChunkEncoder,
-                ),
+                inner: ChunkWriter::new(match self.compressor_type {
+                    None => UncompressedRead(self.writer),
+                    Some(compressor_type) => {
+                        CompressedRead(Compressor::new(self.writer, compressor_type))
+                    }
+                }),
             },
         )
     }
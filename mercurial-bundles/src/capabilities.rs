@@ -20,6 +20,13 @@ pub struct Capabilities {
     caps: HashMap<String, Vec<String>>,
 }
 
+impl Capabilities {
+    /// Returns true if the sender has the capability `key`, whatever its values
+    pub fn contains(&self, key: &str) -> bool {
+        self.caps.contains_key(key)
+    }
+}
+
 /// This is a tokio_io Decoder for capabilities used f.e. in "replycaps" part of bundle2
 ///
 /// The format is as follows:
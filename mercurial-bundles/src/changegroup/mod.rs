@@ -50,18 +50,20 @@ pub struct CgDeltaChunk {
 mod test {
     use std::io::{self, Cursor};
 
+    use bytes::{BufMut, BytesMut};
     use futures::{Future, Stream};
     use quickcheck::{QuickCheck, StdGen, TestResult};
     use quickcheck::rand;
     use slog::{Drain, Logger};
     use slog_term;
     use tokio;
-    use tokio_codec::{FramedRead, FramedWrite};
+    use tokio_codec::{Decoder, FramedRead, FramedWrite};
 
     use futures_ext::StreamLayeredExt;
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
     use chunk::{ChunkDecoder, ChunkEncoder};
+    use errors::ErrorKind;
     use quickcheck_types::CgPartSequence;
 
     use super::*;
@@ -102,6 +104,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_oversized_chunk() {
+        // Only the length header of the chunk is buffered: it must be rejected without waiting
+        // for its payload.
+        let mut buf = BytesMut::with_capacity(4);
+        buf.put_i32_be(1024 * 1024);
+
+        let mut unpacker =
+            unpacker::CgUnpacker::new(make_root_logger(), unpacker::CgVersion::Cg2Version)
+                .with_max_chunk_bytes(Some(4096));
+        let err = unpacker
+            .decode(&mut buf)
+            .expect_err("an oversized chunk should be rejected");
+        assert_matches!(
+            err.downcast::<ErrorKind>().unwrap(),
+            ErrorKind::ChunkTooLarge(1048576, 4096)
+        );
+    }
+
     fn roundtrip(
         seq: CgPartSequence,
         write_ops: PartialWithErrors<GenWouldBlock>,
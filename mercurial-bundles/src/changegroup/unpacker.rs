@@ -19,7 +19,7 @@ use mercurial_types::MPath;
 
 use delta;
 use errors::*;
-use utils::BytesExt;
+use utils::{check_chunk_len, BytesExt};
 
 use super::{CgDeltaChunk, Part, Section};
 
@@ -57,6 +57,7 @@ pub struct CgUnpacker {
     logger: slog::Logger,
     state: State,
     version: CgVersion,
+    max_chunk_bytes: Option<u64>,
 }
 
 impl Part {
@@ -91,7 +92,7 @@ impl Decoder for CgUnpacker {
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        match Self::decode_next(buf, self.state.take(), &self.version) {
+        match Self::decode_next(buf, self.state.take(), &self.version, self.max_chunk_bytes) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -139,9 +140,17 @@ impl CgUnpacker {
             logger,
             state: State::Changeset,
             version,
+            max_chunk_bytes: None,
         }
     }
 
+    /// Rejects any chunk or filename that announces more than `max_chunk_bytes` bytes, before
+    /// its payload is buffered.
+    pub fn with_max_chunk_bytes(mut self, max_chunk_bytes: Option<u64>) -> Self {
+        self.max_chunk_bytes = max_chunk_bytes;
+        self
+    }
+
     fn chunk_header_len(version: &CgVersion) -> usize {
         match version {
             CgVersion::Cg2Version => CHUNK_HEADER2_LEN,
@@ -153,9 +162,10 @@ impl CgUnpacker {
         buf: &mut BytesMut,
         state: State,
         version: &CgVersion,
+        max_len: Option<u64>,
     ) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf, version)? {
+            State::Changeset => match Self::decode_chunk(buf, version, max_len)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => {
                     Ok((Some(Part::SectionEnd(Section::Changeset)), State::Manifest))
@@ -165,7 +175,7 @@ impl CgUnpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf, version)? {
+            State::Manifest => match Self::decode_chunk(buf, version, max_len)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
                     let next_state = match version {
@@ -179,7 +189,7 @@ impl CgUnpacker {
                     State::Manifest,
                 )),
             },
-            State::Treemanifest => match Self::decode_chunk(buf, version)? {
+            State::Treemanifest => match Self::decode_chunk(buf, version, max_len)? {
                 None => Ok((None, State::Treemanifest)),
                 Some(CgChunk::Empty) => Ok((
                     Some(Part::SectionEnd(Section::Treemanifest)),
@@ -190,14 +200,16 @@ impl CgUnpacker {
                 }
             },
             State::Filename => {
-                let filename = Self::decode_filename(buf)?;
+                let filename = Self::decode_filename(buf, max_len)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f, version),
+                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f, version, max_len),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename, version),
+            State::Filelog(filename) => {
+                Self::decode_filelog_chunk(buf, filename, version, max_len)
+            }
             State::End => Ok((None, State::End)),
             State::Invalid => Err(ErrorKind::CgDecode("byte stream corrupt".into()).into()),
         }
@@ -207,8 +219,9 @@ impl CgUnpacker {
         buf: &mut BytesMut,
         f: MPath,
         version: &CgVersion,
+        max_len: Option<u64>,
     ) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf, version)? {
+        match Self::decode_chunk(buf, version, max_len)? {
             None => Ok((None, State::Filelog(f))),
             Some(CgChunk::Empty) => {
                 Ok((Some(Part::SectionEnd(Section::Filelog(f))), State::Filename))
@@ -220,7 +233,11 @@ impl CgUnpacker {
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut, version: &CgVersion) -> Result<Option<CgChunk>> {
+    fn decode_chunk(
+        buf: &mut BytesMut,
+        version: &CgVersion,
+        max_len: Option<u64>,
+    ) -> Result<Option<CgChunk>> {
         if buf.len() < 4 {
             return Ok(None);
         }
@@ -242,6 +259,7 @@ impl CgUnpacker {
             );
             bail_err!(ErrorKind::CgDecode(msg));
         }
+        check_chunk_len(chunk_len as u64, max_len)?;
 
         if buf.len() < chunk_len {
             return Ok(None);
@@ -289,7 +307,7 @@ impl CgUnpacker {
         })));
     }
 
-    fn decode_filename(buf: &mut BytesMut) -> Result<DecodeRes<MPath>> {
+    fn decode_filename(buf: &mut BytesMut, max_len: Option<u64>) -> Result<DecodeRes<MPath>> {
         if buf.len() < 4 {
             return Ok(DecodeRes::None);
         }
@@ -300,6 +318,7 @@ impl CgUnpacker {
             return Ok(DecodeRes::End);
         }
         let filename_len = filename_len as usize;
+        check_chunk_len(filename_len as u64, max_len)?;
         // filename_len includes the 4 bytes for the length field.
         if buf.len() < filename_len {
             return Ok(DecodeRes::None);
@@ -4,8 +4,13 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::VecDeque;
+use std::io;
+
 use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend};
 use tokio_codec::{Decoder, Encoder};
+use tokio_io::AsyncWrite;
 
 use errors::*;
 use utils::BytesExt;
@@ -114,6 +119,132 @@ impl Encoder for ChunkEncoder {
     }
 }
 
+/// Payloads at least this large are written from the chunk itself instead of being copied next to
+/// the other chunks, which are coalesced so that small chunks don't cost a write each.
+pub const COALESCE_LIMIT: usize = 16 * 1024;
+
+/// Once this many bytes are waiting to be written, the writer stops accepting chunks.
+const BACKPRESSURE_LIMIT: usize = 1024 * 1024;
+
+/// Writes bundle2 chunks, like `FramedWrite` with a `ChunkEncoder` does, without copying large
+/// payloads into a framing buffer. The output is a rope of the coalesced small chunks and the
+/// large payloads, which are written out as they are.
+#[derive(Debug)]
+pub struct ChunkWriter<W> {
+    writer: W,
+    /// What is left to write, in order
+    segments: VecDeque<Bytes>,
+    /// Chunk lengths and small payloads that follow the last segment
+    buffer: BytesMut,
+    pending: usize,
+    copied: u64,
+}
+
+impl<W: AsyncWrite> ChunkWriter<W> {
+    pub fn new(writer: W) -> Self {
+        ChunkWriter {
+            writer,
+            segments: VecDeque::new(),
+            buffer: BytesMut::new(),
+            pending: 0,
+            copied: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Bytes that were copied into the framing buffer so far
+    pub fn copied_bytes(&self) -> u64 {
+        self.copied
+    }
+
+    fn copy(&mut self, bytes: &[u8]) {
+        self.buffer.reserve(bytes.len());
+        self.buffer.put_slice(bytes);
+        self.copied += bytes.len() as u64;
+    }
+
+    fn push_buffer(&mut self) {
+        if !self.buffer.is_empty() {
+            let buffer = self.buffer.take().freeze();
+            self.segments.push_back(buffer);
+        }
+    }
+}
+
+impl<W: AsyncWrite> Sink for ChunkWriter<W> {
+    type SinkItem = Chunk;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Chunk) -> StartSend<Chunk, Error> {
+        if self.pending >= BACKPRESSURE_LIMIT {
+            self.poll_complete()?;
+            if self.pending >= BACKPRESSURE_LIMIT {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        match item.0 {
+            ChunkInner::Normal(bytes) => {
+                let mut len = [0; 4];
+                (&mut len[..]).put_i32_be(bytes.len() as i32);
+                self.copy(&len);
+                self.pending += 4 + bytes.len();
+                if bytes.len() < COALESCE_LIMIT {
+                    self.copy(&bytes);
+                } else {
+                    self.push_buffer();
+                    self.segments.push_back(bytes);
+                }
+            }
+            ChunkInner::Error => {
+                let mut len = [0; 4];
+                (&mut len[..]).put_i32_be(-1);
+                self.copy(&len);
+                self.pending += 4;
+            }
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.push_buffer();
+        while let Some(mut segment) = self.segments.pop_front() {
+            let written = match self.writer.poll_write(&segment) {
+                Ok(Async::Ready(written)) => written,
+                Ok(Async::NotReady) => {
+                    self.segments.push_front(segment);
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => {
+                    self.segments.push_front(segment);
+                    return Err(err.into());
+                }
+            };
+            if written == 0 {
+                self.segments.push_front(segment);
+                let err = io::Error::new(io::ErrorKind::WriteZero, "failed to write chunk");
+                return Err(err.into());
+            }
+            self.pending -= written;
+            segment.advance(written);
+            if !segment.is_empty() {
+                self.segments.push_front(segment);
+            }
+        }
+        try_ready!(self.writer.poll_flush());
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        try_ready!(self.poll_complete());
+        try_ready!(self.writer.shutdown());
+        Ok(Async::Ready(()))
+    }
+}
+
 /// Decode a bytestream into bundle2 chunks.
 #[allow(dead_code)]
 pub struct ChunkDecoder;
@@ -155,6 +286,7 @@ mod test {
     use std::io::Cursor;
 
     use futures::{stream, Future, Sink, Stream};
+    use partial_io::{PartialAsyncWrite, PartialOp};
     use quickcheck::{quickcheck, TestResult};
     use tokio;
     use tokio_codec::{FramedRead, FramedWrite};
@@ -204,6 +336,85 @@ mod test {
         );
     }
 
+    /// Chunks of a synthetic pull of 10 MB: large file contents between small chunks, like
+    /// headers and deltas of manifests
+    fn large_pull() -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        for i in 0..40 {
+            chunks.push(Chunk::new(vec![i as u8; 100]).unwrap());
+            chunks.push(Chunk::new(vec![i as u8; 256 * 1024]).unwrap());
+            chunks.push(Chunk::new(vec![i as u8; COALESCE_LIMIT - 1]).unwrap());
+            chunks.push(Chunk::empty());
+        }
+        chunks.push(Chunk::error());
+        chunks.push(Chunk::new(vec![0; COALESCE_LIMIT]).unwrap());
+        chunks
+    }
+
+    #[test]
+    fn test_chunk_writer() {
+        let chunks = large_pull();
+        let payload: usize = chunks
+            .iter()
+            .map(|chunk| chunk.clone().into_bytes().map_or(0, |bytes| bytes.len()))
+            .sum();
+
+        // The old path encodes every chunk into the frame buffer of FramedWrite
+        let mut copied_before = 0;
+        let mut expected = BytesMut::new();
+        for chunk in chunks.clone() {
+            let len = expected.len();
+            ChunkEncoder.encode(chunk, &mut expected).unwrap();
+            copied_before += expected.len() - len;
+        }
+        let old_output = FramedWrite::new(Cursor::new(Vec::new()), ChunkEncoder)
+            .send_all(stream::iter_ok::<_, Error>(chunks.clone()))
+            .wait()
+            .unwrap()
+            .0
+            .into_inner()
+            .into_inner();
+        assert_eq!(old_output, expected.to_vec());
+
+        let (writer, _) = ChunkWriter::new(Cursor::new(Vec::new()))
+            .send_all(stream::iter_ok::<_, Error>(chunks.clone()))
+            .wait()
+            .unwrap();
+        let copied_after = writer.copied_bytes() as usize;
+        assert_eq!(writer.into_inner().into_inner(), old_output);
+
+        assert!(copied_before >= payload);
+        // Only chunk lengths and payloads below the limit are copied
+        let large = 40 * 256 * 1024 + COALESCE_LIMIT;
+        assert_eq!(copied_after, copied_before - large);
+        assert!(copied_after * 10 < copied_before);
+    }
+
+    #[test]
+    fn test_chunk_writer_partial_writes() {
+        let chunks = large_pull();
+        let mut expected = BytesMut::new();
+        for chunk in chunks.clone() {
+            ChunkEncoder.encode(chunk, &mut expected).unwrap();
+        }
+
+        let writer = PartialAsyncWrite::new(
+            Cursor::new(Vec::new()),
+            vec![
+                PartialOp::Limited(1),
+                PartialOp::Limited(5000),
+                PartialOp::Unlimited,
+            ].into_iter()
+                .cycle()
+                .take(10_000),
+        );
+        let (writer, _) = ChunkWriter::new(writer)
+            .send_all(stream::iter_ok::<_, Error>(chunks))
+            .wait()
+            .unwrap();
+        assert_eq!(writer.into_inner().into_inner().into_inner(), expected.to_vec());
+    }
+
     #[test]
     fn test_roundtrip() {
         // Avoid using the quickcheck! macro because it eats up line numbers in
@@ -17,6 +17,8 @@ pub enum ErrorKind {
     #[fail(display = "wirepack encode error: {}", _0)] WirePackEncode(String),
     #[fail(display = "bundle2 encode error: {}", _0)] Bundle2Encode(String),
     #[fail(display = "bundle2 chunk error: {}", _0)] Bundle2Chunk(String),
+    #[fail(display = "chunk of {} bytes exceeds the limit of {} bytes", _0, _1)]
+    ChunkTooLarge(u64, u64),
     #[fail(display = "invalid delta: {}", _0)] InvalidDelta(String),
     #[fail(display = "invalid wire pack entry: {}", _0)] InvalidWirePackEntry(String),
     #[fail(display = "unknown part type: {:?}", _0)] BundleUnknownPart(PartHeader),
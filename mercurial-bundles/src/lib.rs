@@ -85,7 +85,8 @@ use std::fmt;
 use futures_ext::{BoxFuture, BoxStream};
 
 pub use bundle2_encode::Bundle2EncodeBuilder;
-pub use part_header::{PartHeader, PartHeaderType};
+pub use capabilities::Capabilities;
+pub use part_header::{PartHeader, PartHeaderBuilder, PartHeaderType};
 pub use types::StreamHeader;
 
 pub enum Bundle2Item {
@@ -53,12 +53,17 @@ pub enum PartHeaderType {
     ReplyPushkey,
     /// Contains parameters that can be used by hooks
     Pushvars,
+    /// Text that the client shows to the user, e.g. a message about the commits a push landed
+    Output,
+    /// Commits landed by a push, for clients that show them themselves rather than as output
+    B2xPushAdvisory,
+    /// What happened to each bookmark of a push that moved some of its bookmarks but not all
+    B2xBookmarkOutcomes,
     // RemoteChangegroup,       // We don't wish to support this functionality
     // CheckBookmarks,          // TODO Do we want to support this?
     // CheckHeads,              // TODO Do we want to support this?
     // CheckUpdatedHeads,       // TODO Do we want to support this?
     // CheckPhases,             // TODO Do we want to support this?
-    // Output,                  // TODO Do we want to support this?
     // ErrorAbort,              // TODO Do we want to support this?
     // ErrorPushkey,            // TODO Do we want to support this?
     // ErrorUnsupportedContent, // TODO Do we want to support this?
@@ -90,6 +95,9 @@ impl PartHeaderType {
             "pushkey" => Ok(Pushkey),
             "reply:pushkey" => Ok(ReplyPushkey),
             "pushvars" => Ok(Pushvars),
+            "output" => Ok(Output),
+            "b2x:pushadvisory" => Ok(B2xPushAdvisory),
+            "b2x:bookmarkoutcomes" => Ok(B2xBookmarkOutcomes),
             bad => bail_msg!("unknown header type {}", bad),
         }
     }
@@ -111,6 +119,9 @@ impl PartHeaderType {
             Pushkey => "pushkey",
             Pushvars => "pushvars",
             ReplyPushkey => "reply:pushkey",
+            Output => "output",
+            B2xPushAdvisory => "b2x:pushadvisory",
+            B2xBookmarkOutcomes => "b2x:bookmarkoutcomes",
         }
     }
 }
@@ -102,6 +102,7 @@ pub fn get_cg_version(header: PartHeader) -> Result<changegroup::unpacker::CgVer
 pub fn get_cg_unpacker(
     header: PartHeader,
     logger: slog::Logger,
+    max_chunk_bytes: Option<u64>,
 ) -> changegroup::unpacker::CgUnpacker {
     // TODO(anastasiyaz): T34812941 return Result here, no default packer (version should be specified)
     get_cg_version(header)
@@ -112,6 +113,7 @@ pub fn get_cg_unpacker(
         let default_version = changegroup::unpacker::CgVersion::Cg2Version;
         changegroup::unpacker::CgUnpacker::new(logger, default_version)
     })
+    .with_max_chunk_bytes(max_chunk_bytes)
 }
 
 /// Convert an OuterStream into an InnerStream using the part header.
@@ -119,6 +121,7 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
     header: PartHeader,
     stream: OuterStream<R>,
     logger: &slog::Logger,
+    max_chunk_bytes: Option<u64>,
 ) -> (Bundle2Item, BoxFuture<OuterStream<R>, Error>) {
     let wrapped_stream = stream
         .take_while_wrapper(|frame| future::ok(frame.is_payload()))
@@ -130,6 +133,7 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             let cg2_stream = wrapped_stream.decode(get_cg_unpacker(
                 header.clone(),
                 logger.new(o!("stream" => "cg2")),
+                max_chunk_bytes,
             ));
             Bundle2Item::Changegroup(header, Box::new(cg2_stream))
         }
@@ -141,6 +145,7 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             let cg2_stream = wrapped_stream.decode(get_cg_unpacker(
                 header.clone(),
                 logger.new(o!("stream" => "cg2")),
+                max_chunk_bytes,
             ));
             Bundle2Item::B2xInfinitepush(header, Box::new(cg2_stream))
         }
@@ -150,12 +155,14 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             Bundle2Item::B2xInfinitepushBookmarks(header, Box::new(bookmarks_stream))
         }
         &PartHeaderType::B2xTreegroup2 => {
-            let wirepack_stream = wrapped_stream.decode(wirepack::unpacker::new(
-                logger.new(o!("stream" => "wirepack")),
-                // Mercurial only knows how to send trees at the moment.
-                // TODO: add support for file wirepacks once that's a thing
-                wirepack::Kind::Tree,
-            ));
+            let wirepack_stream = wrapped_stream.decode(
+                wirepack::unpacker::new(
+                    logger.new(o!("stream" => "wirepack")),
+                    // Mercurial only knows how to send trees at the moment.
+                    // TODO: add support for file wirepacks once that's a thing
+                    wirepack::Kind::Tree,
+                ).with_max_chunk_bytes(max_chunk_bytes),
+            );
             Bundle2Item::B2xTreegroup2(header, Box::new(wirepack_stream))
         }
         &PartHeaderType::Replycaps => {
@@ -169,18 +176,21 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             Bundle2Item::Replycaps(header, Box::new(caps))
         }
         &PartHeaderType::B2xRebasePack => {
-            let wirepack_stream = wrapped_stream.decode(wirepack::unpacker::new(
-                logger.new(o!("stream" => "wirepack")),
-                // Mercurial only knows how to send trees at the moment.
-                // TODO: add support for file wirepacks once that's a thing
-                wirepack::Kind::Tree,
-            ));
+            let wirepack_stream = wrapped_stream.decode(
+                wirepack::unpacker::new(
+                    logger.new(o!("stream" => "wirepack")),
+                    // Mercurial only knows how to send trees at the moment.
+                    // TODO: add support for file wirepacks once that's a thing
+                    wirepack::Kind::Tree,
+                ).with_max_chunk_bytes(max_chunk_bytes),
+            );
             Bundle2Item::B2xRebasePack(header, Box::new(wirepack_stream))
         }
         &PartHeaderType::B2xRebase => {
             let cg2_stream = wrapped_stream.decode(get_cg_unpacker(
                 header.clone(),
                 logger.new(o!("stream" => "cg2")),
+                max_chunk_bytes,
             ));
             Bundle2Item::B2xRebase(header, Box::new(cg2_stream))
         }
@@ -22,12 +22,13 @@ use errors::*;
 use part_header::{self, PartHeader, PartHeaderType};
 use part_inner::validate_header;
 use types::StreamHeader;
-use utils::{get_decompressor_type, BytesExt};
+use utils::{check_chunk_len, get_decompressor_type, BytesExt};
 
 pub fn outer_stream<R: AsyncRead + BufRead + Send>(
     stream_header: &StreamHeader,
     r: R,
     logger: &slog::Logger,
+    max_chunk_bytes: Option<u64>,
 ) -> Result<OuterStream<R>> {
     let decompressor_type = get_decompressor_type(
         stream_header
@@ -41,7 +42,7 @@ pub fn outer_stream<R: AsyncRead + BufRead + Send>(
             None => UncompressedRead(r),
             Some(decompressor_type) => CompressedRead(Decompressor::new(r, decompressor_type)),
         },
-        OuterDecoder::new(logger.new(o!("stream" => "outer"))),
+        OuterDecoder::new(logger.new(o!("stream" => "outer")), max_chunk_bytes),
     )))
 }
 
@@ -95,6 +96,7 @@ impl OuterState {
 pub struct OuterDecoder {
     logger: slog::Logger,
     state: OuterState,
+    max_chunk_bytes: Option<u64>,
 }
 
 impl Decoder for OuterDecoder {
@@ -102,17 +104,19 @@ impl Decoder for OuterDecoder {
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        let (ret, next_state) = Self::decode_next(buf, self.state.take(), &self.logger);
+        let (ret, next_state) =
+            Self::decode_next(buf, self.state.take(), &self.logger, self.max_chunk_bytes);
         self.state = next_state;
         ret
     }
 }
 
 impl OuterDecoder {
-    pub fn new(logger: slog::Logger) -> Self {
+    pub fn new(logger: slog::Logger, max_chunk_bytes: Option<u64>) -> Self {
         OuterDecoder {
             logger: logger,
             state: OuterState::Header,
+            max_chunk_bytes,
         }
     }
 
@@ -120,6 +124,7 @@ impl OuterDecoder {
         buf: &mut BytesMut,
         mut state: OuterState,
         logger: &slog::Logger,
+        max_chunk_bytes: Option<u64>,
     ) -> (Result<Option<OuterFrame>>, OuterState) {
         // TODO: the only state valid when the stream terminates is
         // StreamEnd. Communicate that to callers.
@@ -136,6 +141,9 @@ impl OuterDecoder {
                 }
 
                 let header_len = buf.peek_u32() as usize;
+                if let Err(e) = check_chunk_len(header_len as u64, max_chunk_bytes) {
+                    return (Err(e), OuterState::Invalid);
+                }
                 if buf.len() < 4 + header_len {
                     return (Ok(None), OuterState::Header);
                 }
@@ -178,7 +186,7 @@ impl OuterDecoder {
             }
 
             cur_state @ OuterState::Payload { .. } | cur_state @ OuterState::DiscardPayload => {
-                let (payload, next_state) = Self::decode_payload(buf, cur_state);
+                let (payload, next_state) = Self::decode_payload(buf, cur_state, max_chunk_bytes);
                 (payload.map_err(|e| e.into()), next_state)
             }
 
@@ -208,6 +216,7 @@ impl OuterDecoder {
     fn decode_payload(
         buf: &mut BytesMut,
         state: OuterState,
+        max_chunk_bytes: Option<u64>,
     ) -> (Result<Option<OuterFrame>>, OuterState) {
         if buf.len() < 4 {
             return (Ok(None), state);
@@ -232,6 +241,11 @@ impl OuterDecoder {
             // header state.
             (Ok(Some(state.part_end_frame())), OuterState::Header)
         } else {
+            if total_len > 0 {
+                if let Err(e) = check_chunk_len(total_len as u64, max_chunk_bytes) {
+                    return (Err(e), OuterState::Invalid);
+                }
+            }
             let payload = Self::decode_payload_chunk(buf, &state, total_len as usize);
             (Ok(payload), state)
         }
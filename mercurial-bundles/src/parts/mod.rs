@@ -9,8 +9,8 @@ use std::fmt;
 use bytes::Bytes;
 use failure::prelude::*;
 use futures::{Future, Stream};
-use futures::stream::{iter_ok, once};
-use futures_ext::BoxFuture;
+use futures::stream::{self, iter_ok, once};
+use futures_ext::{BoxFuture, BoxStream};
 
 use super::changegroup::{CgDeltaChunk, Part, Section};
 use super::changegroup::packer::CgPacker;
@@ -51,6 +51,30 @@ where
 pub fn changegroup_part<S>(changelogentries: S) -> Result<PartEncodeBuilder>
 where
     S: Stream<Item = (HgNodeHash, HgBlobNode), Error = Error> + Send + 'static,
+{
+    changegroup_part_with_filelogs(changelogentries, stream::empty())
+}
+
+/// A file revision sent in the filelog section of a changegroup
+pub struct FilelogPartInput {
+    pub node: HgNodeHash,
+    pub p1: Option<HgNodeHash>,
+    pub p2: Option<HgNodeHash>,
+    pub linknode: HgNodeHash,
+    /// Raw filelog content, including copy metadata
+    pub content: Bytes,
+}
+
+/// Same as `changegroup_part`, but the changegroup also has a filelog section for each of
+/// `filelogs`. All the revisions of a file must come in the same item, and parents before their
+/// children. Manifests are never sent, they go in a treepack part.
+pub fn changegroup_part_with_filelogs<S, F>(
+    changelogentries: S,
+    filelogs: F,
+) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = (HgNodeHash, HgBlobNode), Error = Error> + Send + 'static,
+    F: Stream<Item = (MPath, BoxStream<FilelogPartInput, Error>), Error = Error> + Send + 'static,
 {
     let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Changegroup)?;
     builder.add_mparam("version", "02")?;
@@ -77,12 +101,36 @@ where
         Part::CgChunk(Section::Changeset, deltachunk)
     });
 
+    let filelogentries = filelogs
+        .map(|(path, revisions)| {
+            let section = Section::Filelog(path);
+            revisions
+                .map({
+                    let section = section.clone();
+                    move |input| {
+                        let deltachunk = CgDeltaChunk {
+                            node: input.node,
+                            p1: input.p1.unwrap_or(NULL_HASH),
+                            p2: input.p2.unwrap_or(NULL_HASH),
+                            base: NULL_HASH,
+                            linknode: input.linknode,
+                            delta: Delta::new_fulltext(input.content.to_vec()),
+                            flags: None,
+                        };
+                        Part::CgChunk(section.clone(), deltachunk)
+                    }
+                })
+                .chain(once(Ok(Part::SectionEnd(section))))
+        })
+        .flatten();
+
     let changelogentries = changelogentries
         .chain(once(Ok(Part::SectionEnd(Section::Changeset))))
-        // One more SectionEnd entry is necessary because hg client excepts filelog section
-        // even if it's empty. Add a fake SectionEnd part (the choice of
-        // Manifest is just for convenience).
+        // The manifest section is always empty, because Mononoke sends tree manifests in a
+        // separate part. Its SectionEnd is still necessary, because hg client expects the
+        // section to be there.
         .chain(once(Ok(Part::SectionEnd(Section::Manifest))))
+        .chain(filelogentries)
         .chain(once(Ok(Part::End)));
 
     let cgdata = CgPacker::new(changelogentries);
@@ -101,7 +149,9 @@ pub struct TreepackPartInput {
     pub basepath: Option<MPath>,
 }
 
-pub fn treepack_part<S>(entries: S) -> Result<PartEncodeBuilder>
+/// Treepack part of `entries`, which fetch the trees. Up to `buffer_size` of them are fetched at
+/// the same time.
+pub fn treepack_part<S>(entries: S, buffer_size: usize) -> Result<PartEncodeBuilder>
 where
     S: Stream<Item = BoxFuture<TreepackPartInput, Error>, Error = Error> + Send + 'static,
 {
@@ -110,7 +160,6 @@ where
     builder.add_mparam("cache", "True")?;
     builder.add_mparam("category", "manifests")?;
 
-    let buffer_size = 10000; // TODO(stash): make it configurable
     let wirepack_parts = entries
         .buffered(buffer_size)
         .map(|input| {
@@ -155,6 +204,32 @@ where
     Ok(builder)
 }
 
+/// Capabilities of the sender of a bundle, which a server expects at the start of a push
+pub fn replycaps_part(caps: Bytes) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Replycaps)?;
+    builder.set_data_bytes(caps)?;
+    Ok(builder)
+}
+
+/// Moves `bookmark` from `old` to `new`. None as `old` creates the bookmark, and None as `new`
+/// deletes it.
+pub fn bookmark_pushkey_part(
+    bookmark: Bytes,
+    old: Option<HgNodeHash>,
+    new: Option<HgNodeHash>,
+) -> Result<PartEncodeBuilder> {
+    let hex = |node: Option<HgNodeHash>| match node {
+        Some(node) => Bytes::from(node.to_hex().as_bytes()),
+        None => Bytes::new(),
+    };
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Pushkey)?;
+    builder.add_mparam("namespace", "bookmarks")?;
+    builder.add_mparam("key", bookmark)?;
+    builder.add_mparam("old", hex(old))?;
+    builder.add_mparam("new", hex(new))?;
+    Ok(builder)
+}
+
 pub enum ChangegroupApplyResult {
     Success { heads_num_diff: i64 },
     Error,
@@ -217,3 +292,43 @@ pub fn replypushkey_part(res: bool, in_reply_to: u32) -> Result<PartEncodeBuilde
 
     Ok(builder)
 }
+
+/// Text that the client prints to the user, prefixed with "remote: "
+pub fn output_part<T: Into<Bytes>>(message: T) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::Output)?;
+    builder.set_data_bytes(message)?;
+    Ok(builder)
+}
+
+/// Commits landed by a push on `bookmark`, the last of `total` landed commits. The data of the
+/// part is their hashes, one per line.
+pub fn pushadvisory_part(
+    reponame: &str,
+    bookmark: &str,
+    landed: &[HgNodeHash],
+    total: usize,
+) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::B2xPushAdvisory)?;
+    builder.add_aparam("reponame", reponame.to_string())?;
+    builder.add_aparam("bookmark", bookmark.to_string())?;
+    builder.add_aparam("total", format!("{}", total))?;
+    let hashes: Vec<_> = landed.iter().map(|node| node.to_hex().to_string()).collect();
+    builder.set_data_bytes(hashes.join("\n"))?;
+    Ok(builder)
+}
+
+/// Outcome of each bookmark of a push, as `(bookmark, outcome, reason)`. The data of the part is
+/// one line per bookmark, with the three fields separated by tabs; none of them can contain a tab
+/// or a newline.
+pub fn bookmarkoutcomes_part<'a, I>(outcomes: I) -> Result<PartEncodeBuilder>
+where
+    I: IntoIterator<Item = (&'a str, &'a str, &'a str)>,
+{
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::B2xBookmarkOutcomes)?;
+    let lines: Vec<_> = outcomes
+        .into_iter()
+        .map(|(bookmark, outcome, reason)| format!("{}\t{}\t{}", bookmark, outcome, reason))
+        .collect();
+    builder.set_data_bytes(lines.join("\n"))?;
+    Ok(builder)
+}
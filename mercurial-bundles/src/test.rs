@@ -11,8 +11,9 @@ use std::io::{self, BufRead, BufReader, Cursor};
 use std::iter::Iterator;
 use std::str::FromStr;
 
-use futures::stream::Stream;
-use futures_ext::BoxStream;
+use bytes::Bytes;
+use futures::stream::{self, Stream};
+use futures_ext::{BoxStream, StreamExt};
 use slog::{Drain, Logger};
 use slog_term;
 use tokio::runtime::Runtime;
@@ -20,7 +21,7 @@ use tokio_io::AsyncRead;
 
 use async_compression::{Bzip2Compression, CompressorType, FlateCompression};
 use async_compression::membuf::MemBuf;
-use mercurial_types::{HgNodeHash, MPath, RepoPath, NULL_HASH};
+use mercurial_types::{HgBlobNode, HgNodeHash, MPath, RepoPath, NULL_HASH};
 use partial_io::{GenWouldBlock, PartialAsyncRead, PartialWithErrors};
 use quickcheck::{QuickCheck, StdGen};
 use quickcheck::rand;
@@ -32,6 +33,8 @@ use changegroup;
 use errors::*;
 use part_encode::PartEncodeBuilder;
 use part_header::{PartHeaderBuilder, PartHeaderType};
+use parts::{bookmark_pushkey_part, changegroup_part_with_filelogs, replycaps_part,
+            FilelogPartInput};
 use types::StreamHeader;
 use utils::get_compression_param;
 use wirepack;
@@ -79,6 +82,29 @@ fn test_parse_unknown_compression() {
                     ErrorKind::Bundle2Decode(ref msg) if msg == "unknown compression 'IL'");
 }
 
+#[test]
+fn test_parse_oversized_chunk() {
+    let mut runtime = Runtime::new().unwrap();
+    let bundle2_buf = BufReader::new(MemBuf::from(Vec::from(UNCOMP_BUNDLE2)));
+    // The header of the changegroup part alone is larger than this.
+    let stream = Bundle2Stream::new(bundle2_buf, make_root_logger()).with_max_chunk_bytes(16);
+
+    let (item, stream) = runtime
+        .block_on(stream.into_future())
+        .map_err(|(e, _)| e)
+        .unwrap();
+    assert_matches!(item, Some(StreamEvent::Next(Bundle2Item::Start(_))));
+
+    let err = match runtime.block_on(stream.into_future()) {
+        Ok(_) => panic!("an oversized chunk should be rejected"),
+        Err((err, _)) => err,
+    };
+    assert_matches!(
+        err.downcast::<ErrorKind>().unwrap(),
+        ErrorKind::ChunkTooLarge(_, 16)
+    );
+}
+
 #[test]
 fn test_empty_bundle_roundtrip_bzip() {
     empty_bundle_roundtrip(Some(CompressorType::Bzip2(Bzip2Compression::Default)));
@@ -522,6 +548,116 @@ fn parse_wirepack(read_ops: PartialWithErrors<GenWouldBlock>) {
     assert!(stream.app_errors().is_empty());
 }
 
+#[test]
+fn test_changegroup_with_filelogs_roundtrip() {
+    let cs1 = HgNodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let cs2 = HgNodeHash::from_str(CHANGESET2_HASH_STR).unwrap();
+    let abc = HgNodeHash::from_str(ABC_HASH_STR).unwrap();
+    let def = HgNodeHash::from_str(DEF_HASH_STR).unwrap();
+
+    let changesets = vec![
+        (cs1, HgBlobNode::new(Bytes::from("cs1"), None, None)),
+        (cs2, HgBlobNode::new(Bytes::from("cs2"), Some(cs1), None)),
+    ];
+    let revision = |node, p1, linknode, content| FilelogPartInput {
+        node,
+        p1,
+        p2: None,
+        linknode,
+        content: Bytes::from(content),
+    };
+    let filelogs = vec![
+        (
+            path(b"abc"),
+            stream::iter_ok(vec![
+                revision(abc, None, cs1, "abc"),
+                revision(def, Some(abc), cs2, "def"),
+            ]).boxify(),
+        ),
+        (
+            path(b"dir/def"),
+            stream::iter_ok(vec![revision(def, None, cs2, "def")]).boxify(),
+        ),
+    ];
+
+    let mut builder = Bundle2EncodeBuilder::new(Cursor::new(Vec::with_capacity(32 * 1024)));
+    builder.add_part(replycaps_part(Bytes::from("HG20")).unwrap());
+    builder.add_part(
+        changegroup_part_with_filelogs(stream::iter_ok(changesets), stream::iter_ok(filelogs))
+            .unwrap(),
+    );
+    builder.add_part(bookmark_pushkey_part(Bytes::from("master"), None, Some(cs2)).unwrap());
+
+    let mut runtime = Runtime::new().unwrap();
+    let mut buf = runtime.block_on(builder.build()).unwrap();
+    buf.set_position(0);
+    let stream = parse_stream_start(&mut runtime, BufReader::new(buf), None).unwrap();
+
+    let (res, stream) = runtime.next_stream(stream);
+    match res.unwrap().into_next().unwrap() {
+        Bundle2Item::Replycaps(_, caps) => {
+            runtime.block_on(caps).unwrap();
+        }
+        bad => panic!("Unexpected Bundle2Item: {:?}", bad),
+    }
+
+    let (res, stream) = runtime.next_stream(stream);
+    let cgparts = match res.unwrap().into_next().unwrap() {
+        Bundle2Item::Changegroup(_, cgparts) => runtime.block_on(cgparts.collect()).unwrap(),
+        bad => panic!("Unexpected Bundle2Item: {:?}", bad),
+    };
+    let summary: Vec<_> = cgparts
+        .into_iter()
+        .map(|part| match part {
+            changegroup::Part::CgChunk(section, chunk) => {
+                (Some(section), Some((chunk.node, chunk.p1, chunk.linknode)))
+            }
+            changegroup::Part::SectionEnd(section) => (Some(section), None),
+            changegroup::Part::End => (None, None),
+        })
+        .collect();
+    let abc_section = changegroup::Section::Filelog(path(b"abc"));
+    let def_section = changegroup::Section::Filelog(path(b"dir/def"));
+    assert_eq!(
+        summary,
+        vec![
+            (
+                Some(changegroup::Section::Changeset),
+                Some((cs1, NULL_HASH, cs1)),
+            ),
+            (
+                Some(changegroup::Section::Changeset),
+                Some((cs2, cs1, cs2)),
+            ),
+            (Some(changegroup::Section::Changeset), None),
+            (Some(changegroup::Section::Manifest), None),
+            (Some(abc_section.clone()), Some((abc, NULL_HASH, cs1))),
+            (Some(abc_section.clone()), Some((def, abc, cs2))),
+            (Some(abc_section), None),
+            (Some(def_section.clone()), Some((def, NULL_HASH, cs2))),
+            (Some(def_section), None),
+            (None, None),
+        ]
+    );
+
+    let (res, stream) = runtime.next_stream(stream);
+    match res.unwrap().into_next().unwrap() {
+        Bundle2Item::Pushkey(header, empty) => {
+            let mparams = header.mparams();
+            assert_eq!(mparams.get("namespace"), Some(&Bytes::from("bookmarks")));
+            assert_eq!(mparams.get("key"), Some(&Bytes::from("master")));
+            assert_eq!(mparams.get("old"), Some(&Bytes::new()));
+            assert_eq!(mparams.get("new"), Some(&Bytes::from(CHANGESET2_HASH_STR)));
+            runtime.block_on(empty).unwrap();
+        }
+        bad => panic!("Unexpected Bundle2Item: {:?}", bad),
+    }
+
+    let (res, stream) = runtime.next_stream(stream);
+    assert_matches!(res, Some(StreamEvent::Done(_)));
+    assert!(stream.app_errors().is_empty());
+}
+
 fn path(bytes: &[u8]) -> MPath {
     MPath::new(bytes).unwrap()
 }
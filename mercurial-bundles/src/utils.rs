@@ -115,6 +115,15 @@ where
     }
 }
 
+/// Fails if a chunk whose length header announces `len` bytes is larger than `max_len`. Decoders
+/// call this before waiting for the payload, so that an oversized chunk is never buffered.
+pub fn check_chunk_len(len: u64, max_len: Option<u64>) -> Result<()> {
+    match max_len {
+        Some(max_len) if len > max_len => bail_err!(ErrorKind::ChunkTooLarge(len, max_len)),
+        _ => Ok(()),
+    }
+}
+
 pub fn is_mandatory_param(s: &str) -> Result<bool> {
     match s.chars().next() {
         Some(ch) => {
@@ -15,7 +15,7 @@ use mercurial_types::{Delta, HgNodeHash, RepoPath, NULL_HASH};
 
 use delta;
 use errors::*;
-use utils::BytesExt;
+use utils::{check_chunk_len, BytesExt};
 
 pub mod converter;
 pub mod packer;
@@ -223,7 +223,7 @@ pub struct DataEntry {
 }
 
 impl DataEntry {
-    pub(crate) fn decode(buf: &mut BytesMut) -> Result<Option<Self>> {
+    pub(crate) fn decode(buf: &mut BytesMut, max_delta_len: Option<u64>) -> Result<Option<Self>> {
         if buf.len() < DATA_HEADER_SIZE {
             return Ok(None);
         }
@@ -238,7 +238,9 @@ impl DataEntry {
         // There's a bit of a wart in the current format: if delta base is NULL_HASH, instead of
         // storing a delta with start = 0 and end = 0, we store the full text directly. This
         // should be fixed in a future wire protocol revision.
-        let delta_len = BigEndian::read_u64(&buf[DATA_DELTA_OFFSET..DATA_HEADER_SIZE]) as usize;
+        let delta_len = BigEndian::read_u64(&buf[DATA_DELTA_OFFSET..DATA_HEADER_SIZE]);
+        check_chunk_len(delta_len, max_delta_len)?;
+        let delta_len = delta_len as usize;
         if buf.len() < DATA_HEADER_SIZE + delta_len {
             return Ok(None);
         }
@@ -449,7 +451,7 @@ mod test {
                 encoded_bytes.set_len(reduced_len);
                 reduced_len
             };
-            let decoded = DataEntry::decode(&mut encoded_bytes)
+            let decoded = DataEntry::decode(&mut encoded_bytes, None)
                 .expect("decoding this data entry should succeed");
             assert_eq!(decoded, None);
             // Ensure that no bytes in encoded actually got read.
@@ -460,7 +462,7 @@ mod test {
                 encoded_bytes.set_len(bytes_len);
             }
 
-            let decoded = DataEntry::decode(&mut encoded_bytes)
+            let decoded = DataEntry::decode(&mut encoded_bytes, None)
                 .expect("decoding this history entry should succeed");
             assert_eq!(Some(entry), decoded);
             assert_eq!(encoded_bytes.len(), 0);
@@ -75,7 +75,20 @@ impl Decoder for WirePackUnpacker {
 pub fn new(logger: slog::Logger, kind: Kind) -> WirePackUnpacker {
     WirePackUnpacker {
         state: State::Filename,
-        inner: UnpackerInner { logger, kind },
+        inner: UnpackerInner {
+            logger,
+            kind,
+            max_delta_bytes: None,
+        },
+    }
+}
+
+impl WirePackUnpacker {
+    /// Rejects any data entry whose delta announces more than `max_chunk_bytes` bytes, before
+    /// the delta is buffered.
+    pub fn with_max_chunk_bytes(mut self, max_chunk_bytes: Option<u64>) -> Self {
+        self.inner.max_delta_bytes = max_chunk_bytes;
+        self
     }
 }
 
@@ -83,6 +96,7 @@ pub fn new(logger: slog::Logger, kind: Kind) -> WirePackUnpacker {
 struct UnpackerInner {
     logger: slog::Logger,
     kind: Kind,
+    max_delta_bytes: Option<u64>,
 }
 
 impl UnpackerInner {
@@ -225,7 +239,7 @@ impl UnpackerInner {
 
     #[inline]
     fn decode_data(&mut self, buf: &mut BytesMut) -> Result<Option<DataEntry>> {
-        DataEntry::decode(buf)
+        DataEntry::decode(buf, self.max_delta_bytes)
     }
 }
 
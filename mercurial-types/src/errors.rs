@@ -12,6 +12,8 @@ pub enum ErrorKind {
     #[fail(display = "invalid fragment list: {}", _0)] InvalidFragmentList(String),
     #[fail(display = "invalid Thrift structure '{}': {}", _0, _1)] InvalidThrift(String, String),
     #[fail(display = "error while deserializing blob for '{}'", _0)] BlobDeserializeError(String),
+    #[fail(display = "invalid path pattern '{}': {}", _0, _1)] InvalidPathPattern(String, String),
+    #[fail(display = "invalid changeset id prefix '{}'", _0)] InvalidChangesetIdPrefix(String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
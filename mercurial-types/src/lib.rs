@@ -72,6 +72,7 @@ extern crate futures;
 
 #[cfg_attr(test, macro_use)]
 extern crate quickcheck;
+extern crate regex;
 
 extern crate heapsize;
 #[macro_use]
@@ -96,6 +97,7 @@ pub mod nodehash;
 pub mod utils;
 pub mod manifest;
 pub mod manifest_utils;
+pub mod path_matcher;
 pub mod blob;
 pub mod blobnode;
 pub mod changeset;
@@ -113,8 +115,9 @@ pub use envelope::{HgChangesetEnvelope, HgChangesetEnvelopeMut, HgFileEnvelope,
 pub use fsencode::{fncache_fsencode, simple_fsencode};
 pub use manifest::{Entry, Manifest, Type};
 pub use node::Node;
-pub use nodehash::{HgChangesetId, HgEntryId, HgFileNodeId, HgManifestId, HgNodeHash, HgNodeKey,
-                   NULL_CSID, NULL_HASH};
+pub use path_matcher::{PathMatcher, PathPattern};
+pub use nodehash::{HgChangesetId, HgChangesetIdPrefix, HgEntryId, HgFileNodeId, HgManifestId,
+                   HgNodeHash, HgNodeKey, NULL_CSID, NULL_HASH};
 pub use repo::RepositoryId;
 pub use utils::percent_encode;
 
@@ -132,18 +135,21 @@ mod thrift {
     pub use mononoke_types_thrift::*;
 }
 
+/// Only the hash itself, which owns no heap memory
 impl asyncmemo::Weight for HgChangesetId {
     fn get_weight(&self) -> usize {
         std::mem::size_of::<HgChangesetId>()
     }
 }
 
+/// Only the hash itself, which owns no heap memory
 impl asyncmemo::Weight for HgFileNodeId {
     fn get_weight(&self) -> usize {
         std::mem::size_of::<HgFileNodeId>()
     }
 }
 
+/// Only the id itself, which owns no heap memory
 impl asyncmemo::Weight for RepositoryId {
     fn get_weight(&self) -> usize {
         std::mem::size_of::<RepositoryId>()
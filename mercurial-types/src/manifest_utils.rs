@@ -12,11 +12,12 @@ use std::sync::{Arc, Mutex};
 
 use futures::IntoFuture;
 use futures::future::{self, Future};
-use futures::stream::{empty, once, Stream};
+use futures::stream::{self, empty, once, Stream};
 use futures_ext::{select_all, BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use super::{Entry, HgNodeHash, MPath, MPathElement, Manifest};
 use super::manifest::{Content, EmptyManifest, Type};
+use super::path_matcher::{PathMatcher, PathPattern};
 
 use errors::*;
 
@@ -407,20 +408,16 @@ where
         .boxify()
 }
 
-/// Given a ChangedEntry, return a stream that consists of this entry, and all subentries
-/// that differ. If input isn't a tree, then a stream with a single entry is returned, otherwise
-/// subtrees are recursively compared.
-fn recursive_changed_entry_stream(
-    changed_entry: ChangedEntry,
-    depth: usize,
-    pruner: impl Pruner + Send + Clone + 'static,
-    max_depth: Option<usize>,
-) -> BoxStream<ChangedEntry, Error> {
-    if !changed_entry.status.is_tree() || (max_depth.is_some() && max_depth <= Some(depth)) {
-        return once(Ok(changed_entry)).boxify();
-    }
-
-    let (to_mf, from_mf, path) = match &changed_entry.status {
+/// For a tree entry, returns futures for the "to" and "from" manifests of the subtree, and the
+/// path of the subtree.
+fn subtree_manifests(
+    changed_entry: &ChangedEntry,
+) -> (
+    BoxFuture<Box<Manifest>, Error>,
+    BoxFuture<Box<Manifest>, Error>,
+    Option<MPath>,
+) {
+    match &changed_entry.status {
         EntryStatus::Added(entry) => {
             let empty_mf: Box<Manifest> = Box::new(EmptyManifest {});
             let to_mf = entry.get_content().map(get_tree_content).boxify();
@@ -459,7 +456,100 @@ fn recursive_changed_entry_stream(
 
             (to_mf, from_mf, path)
         }
-    };
+    }
+}
+
+/// Same as `changed_entry_stream_with_pruner`, but the order of the output is deterministic.
+/// Entries are returned depth-first: a directory comes right before its changed content, and the
+/// entries of a directory are sorted by name. This is the same order as sorting the entries by
+/// their full path. The pruner is called in this order as well.
+///
+/// To keep the cost of ordering low, the manifests of up to `ORDERED_DIFF_PREFETCH` sibling
+/// subtrees are fetched ahead of the subtree that is currently being returned.
+pub fn ordered_changed_entry_stream_with_pruner<TM, FM, P>(
+    to: &TM,
+    from: &FM,
+    path: Option<MPath>,
+    pruner: P,
+    max_depth: Option<usize>,
+) -> BoxStream<ChangedEntry, Error>
+where
+    TM: Manifest,
+    FM: Manifest,
+    P: Pruner + Send + Clone + 'static,
+{
+    if max_depth == Some(0) {
+        return empty().boxify();
+    }
+
+    diff_manifests(path, to, from)
+        .map(move |diff| ordered_changed_entries(diff, 1, pruner, max_depth))
+        .flatten_stream()
+        .boxify()
+}
+
+const ORDERED_DIFF_PREFETCH: usize = 100;
+
+fn ordered_changed_entries<P>(
+    diff: Vec<ChangedEntry>,
+    depth: usize,
+    mut pruner: P,
+    max_depth: Option<usize>,
+) -> BoxStream<ChangedEntry, Error>
+where
+    P: Pruner + Send + Clone + 'static,
+{
+    let entries: Vec<_> = diff.into_iter().filter(|entry| pruner.keep(entry)).collect();
+
+    stream::iter_ok(entries)
+        .map(move |changed_entry| {
+            if !changed_entry.status.is_tree()
+                || (max_depth.is_some() && max_depth <= Some(depth))
+            {
+                return Ok((changed_entry, None)).into_future().boxify();
+            }
+            let (to_mf, from_mf, path) = subtree_manifests(&changed_entry);
+            to_mf
+                .join(from_mf)
+                .map(move |manifests| (changed_entry, Some((manifests, path))))
+                .boxify()
+        })
+        .buffered(ORDERED_DIFF_PREFETCH)
+        .map(move |(changed_entry, subtree)| {
+            let substream = match subtree {
+                Some(((to_mf, from_mf), path)) => {
+                    // Note that the subtree is pruned only when the substream is polled, i.e.
+                    // after everything that comes before it has been returned.
+                    let pruner = pruner.clone();
+                    diff_manifests(path, &to_mf, &from_mf)
+                        .map(move |diff| {
+                            ordered_changed_entries(diff, depth + 1, pruner, max_depth)
+                        })
+                        .flatten_stream()
+                        .boxify()
+                }
+                None => empty().boxify(),
+            };
+            once(Ok(changed_entry)).chain(substream)
+        })
+        .flatten()
+        .boxify()
+}
+
+/// Given a ChangedEntry, return a stream that consists of this entry, and all subentries
+/// that differ. If input isn't a tree, then a stream with a single entry is returned, otherwise
+/// subtrees are recursively compared.
+fn recursive_changed_entry_stream(
+    changed_entry: ChangedEntry,
+    depth: usize,
+    pruner: impl Pruner + Send + Clone + 'static,
+    max_depth: Option<usize>,
+) -> BoxStream<ChangedEntry, Error> {
+    if !changed_entry.status.is_tree() || (max_depth.is_some() && max_depth <= Some(depth)) {
+        return once(Ok(changed_entry)).boxify();
+    }
+
+    let (to_mf, from_mf, path) = subtree_manifests(&changed_entry);
 
     let substream = to_mf
         .join(from_mf)
@@ -519,6 +609,140 @@ pub fn recursive_entry_stream(
     once(Ok((rootpath, entry))).chain(subentries).boxify()
 }
 
+/// Selects the entries returned by `walk_manifest`. An entry is selected if its path matches the
+/// `PathMatcher` of the filter, and it is at most `max_depth` levels below the root of the walk.
+/// Paths are full paths from the root of the repo.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PathFilter {
+    matcher: PathMatcher,
+    max_depth: Option<usize>,
+}
+
+impl PathFilter {
+    /// Filter that selects every entry
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Filter that selects the entries that `matcher` matches
+    pub fn with_matcher(matcher: PathMatcher) -> Self {
+        PathFilter {
+            matcher,
+            max_depth: None,
+        }
+    }
+
+    pub fn include(mut self, path: MPath) -> Self {
+        self.matcher = self.matcher.include(PathPattern::path(path));
+        self
+    }
+
+    pub fn exclude(mut self, path: MPath) -> Self {
+        self.matcher = self.matcher.exclude(PathPattern::path(path));
+        self
+    }
+
+    /// Entries of the root of the walk are at depth 1
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Whether the entry at `path`, `depth` levels below the root of the walk, is selected
+    pub fn matches(&self, path: &MPath, depth: usize) -> bool {
+        !self.too_deep(depth) && self.matcher.matches(path)
+    }
+
+    /// Whether anything below the tree at `path`, `depth` levels below the root of the walk, can
+    /// be selected, i.e. whether the walk has to fetch the tree
+    pub fn descends_into(&self, path: &MPath, depth: usize) -> bool {
+        !self.too_deep(depth + 1) && self.matcher.could_match_below(Some(path))
+    }
+
+    fn too_deep(&self, depth: usize) -> bool {
+        match self.max_depth {
+            Some(max_depth) => depth > max_depth,
+            None => false,
+        }
+    }
+}
+
+const WALK_PREFETCH: usize = 100;
+
+/// Returns the entries below `manifest`, whose path from the root of the repo is `path`, that are
+/// selected by `filter`, together with their full paths.
+///
+/// Entries are returned depth-first, with the entries of a tree sorted by name, i.e. in the order
+/// of their full paths. A tree is fetched only if `filter` can select something below it, so
+/// excluded subtrees are never fetched. Up to `WALK_PREFETCH` sibling trees are fetched ahead of
+/// the one that is currently being returned.
+pub fn walk_manifest<M>(
+    manifest: &M,
+    path: Option<MPath>,
+    filter: PathFilter,
+) -> BoxStream<(MPath, Box<Entry + Sync>), Error>
+where
+    M: Manifest,
+{
+    walk_tree_entries(manifest.list().collect(), path, 1, Arc::new(filter))
+}
+
+fn walk_tree_entries(
+    entries: Vec<Box<Entry + Sync>>,
+    path: Option<MPath>,
+    depth: usize,
+    filter: Arc<PathFilter>,
+) -> BoxStream<(MPath, Box<Entry + Sync>), Error> {
+    let mut entries: Vec<_> = entries
+        .into_iter()
+        .filter_map(|entry| {
+            let entry_path = MPath::join_opt_element(
+                path.as_ref(),
+                entry.get_name().expect("only root entries have no name"),
+            );
+            let selected = filter.matches(&entry_path, depth);
+            let descend =
+                entry.get_type() == Type::Tree && filter.descends_into(&entry_path, depth);
+            if selected || descend {
+                Some((entry_path, entry, selected, descend))
+            } else {
+                None
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    stream::iter_ok(entries)
+        .map(|(entry_path, entry, selected, descend)| {
+            let content = if descend {
+                entry.get_content().map(Some).boxify()
+            } else {
+                Ok(None).into_future().boxify()
+            };
+            content.map(move |content| (entry_path, entry, selected, content))
+        })
+        .buffered(WALK_PREFETCH)
+        .map(move |(entry_path, entry, selected, content)| {
+            let subentries = match content {
+                Some(content) => walk_tree_entries(
+                    get_tree_content(content).list().collect(),
+                    Some(entry_path.clone()),
+                    depth + 1,
+                    filter.clone(),
+                ),
+                None => empty().boxify(),
+            };
+            let this = if selected {
+                Some((entry_path, entry))
+            } else {
+                None
+            };
+            stream::iter_ok(this).chain(subentries)
+        })
+        .flatten()
+        .boxify()
+}
+
 /// Difference between manifests, non-recursive.
 /// It fetches manifest content, sorts it and compares.
 fn diff_manifests<TM, FM>(
@@ -267,6 +267,45 @@ impl Arbitrary for HgChangesetId {
     }
 }
 
+/// Prefix of the hex representation of changeset ids, like the short hashes that users type. The
+/// changesets that it is a prefix of are the ones between `min_cs_id` and `max_cs_id`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub struct HgChangesetIdPrefix {
+    min: HgChangesetId,
+    max: HgChangesetId,
+}
+
+impl HgChangesetIdPrefix {
+    #[inline]
+    pub fn min_cs_id(&self) -> HgChangesetId {
+        self.min
+    }
+
+    #[inline]
+    pub fn max_cs_id(&self) -> HgChangesetId {
+        self.max
+    }
+
+    #[inline]
+    pub fn is_prefix_of(&self, cs_id: &HgChangesetId) -> bool {
+        self.min <= *cs_id && *cs_id <= self.max
+    }
+}
+
+impl FromStr for HgChangesetIdPrefix {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HgChangesetIdPrefix> {
+        if s.is_empty() || s.len() > 40 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail_err!(ErrorKind::InvalidChangesetIdPrefix(s.to_string()));
+        }
+        Ok(HgChangesetIdPrefix {
+            min: HgChangesetId::from_str(&format!("{:0<40}", s))?,
+            max: HgChangesetId::from_str(&format!("{:f<40}", s))?,
+        })
+    }
+}
+
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 #[derive(HeapSizeOf, FromSqlRow, AsExpression)]
 #[sql_type = "HgManifestIdSql"]
@@ -420,3 +459,30 @@ impl_hash!(HgChangesetId);
 impl_hash!(HgManifestId);
 impl_hash!(HgFileNodeId);
 impl_hash!(HgEntryId);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changeset_id_prefix() {
+        let cs_id = |s: &str| HgChangesetId::from_str(s).unwrap();
+
+        let prefix = HgChangesetIdPrefix::from_str("1f0de").unwrap();
+        assert_eq!(prefix.min_cs_id(), cs_id("1f0de00000000000000000000000000000000000"));
+        assert_eq!(prefix.max_cs_id(), cs_id("1f0defffffffffffffffffffffffffffffffffff"));
+        assert!(prefix.is_prefix_of(&cs_id("1f0dee641bb7258c56bd60e93edfa2405381c41e")));
+        assert!(!prefix.is_prefix_of(&cs_id("1f0df0641bb7258c56bd60e93edfa2405381c41e")));
+        assert_eq!(HgChangesetIdPrefix::from_str("1F0DE").unwrap(), prefix);
+
+        // A full changeset id is the prefix of itself only
+        let full = "1f0dee641bb7258c56bd60e93edfa2405381c41e";
+        let full = HgChangesetIdPrefix::from_str(full).unwrap();
+        assert_eq!(full.min_cs_id(), full.max_cs_id());
+
+        let too_long = "1".repeat(41);
+        for bad in &["", "master", "1f0dx", too_long.as_str()] {
+            assert!(HgChangesetIdPrefix::from_str(bad).is_err(), "{}", bad);
+        }
+    }
+}
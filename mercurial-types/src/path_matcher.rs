@@ -0,0 +1,672 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Matching of repo paths against lists of include and exclude patterns, shared by everything
+//! that selects paths by pattern (narrow clones, path ACLs, hooks) so that they agree on what a
+//! pattern means.
+//!
+//! A pattern is one of:
+//!
+//! - `path:<path>` matches `<path>` and everything below it. `path:` on its own matches every
+//!   path.
+//! - `glob:<glob>` matches the paths that the glob matches, and everything below them. The glob is
+//!   split into components at `/`. Within a component `*` matches any number of bytes, `?` matches
+//!   exactly one byte and `\` escapes the next character. A component that is exactly `**`
+//!   matches any number of components, including none. Elsewhere `**` is the same as `*`. No
+//!   wildcard ever matches a `/`.
+//! - `re:<regex>` matches the paths that start with a match of `<regex>`, i.e. the regex is
+//!   anchored at the start of the path but not at the end. The regex is matched against the bytes
+//!   of the path with Unicode support disabled, so `.` matches any byte but a newline.
+//!
+//! All patterns are relative to the root of the repo, and are always anchored at the root.
+//! Matching is case-sensitive (regexes can use `(?i)` for ASCII case-insensitivity). Paths don't
+//! have to be valid UTF-8: patterns are matched against the raw bytes of paths, and a pattern
+//! only has to be UTF-8 itself.
+
+use regex::bytes::{Regex, RegexBuilder};
+
+use errors::*;
+use MPath;
+
+/// A single compiled pattern
+#[derive(Clone, Debug)]
+pub struct PathPattern {
+    kind: PatternKind,
+}
+
+#[derive(Clone, Debug)]
+enum PatternKind {
+    /// `None` is the root of the repo
+    Path(Option<MPath>),
+    Glob(Vec<GlobComponent>),
+    Regex {
+        regex: Regex,
+        /// Every path that the regex matches starts with this
+        prefix: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum GlobComponent {
+    /// `**`
+    AnyComponents,
+    Segment(Vec<GlobToken>),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum GlobToken {
+    Byte(u8),
+    /// `?`
+    AnyByte,
+    /// `*`
+    AnyBytes,
+}
+
+impl PathPattern {
+    /// Parses a `path:`, `glob:` or `re:` pattern. Patterns without a kind are rejected.
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let invalid = |reason: String| -> Error {
+            ErrorKind::InvalidPathPattern(pattern.to_string(), reason).into()
+        };
+
+        let kind = if pattern.starts_with("path:") {
+            let path = &pattern["path:".len()..];
+            if path.is_empty() {
+                PatternKind::Path(None)
+            } else {
+                let path = MPath::new(path).map_err(|err| invalid(err.to_string()))?;
+                PatternKind::Path(Some(path))
+            }
+        } else if pattern.starts_with("glob:") {
+            let glob = parse_glob(&pattern["glob:".len()..]).map_err(invalid)?;
+            PatternKind::Glob(glob)
+        } else if pattern.starts_with("re:") {
+            let regex = &pattern["re:".len()..];
+            let compiled = RegexBuilder::new(&format!("^(?:{})", regex))
+                .unicode(false)
+                .build()
+                .map_err(|err| invalid(err.to_string()))?;
+            PatternKind::Regex {
+                regex: compiled,
+                prefix: regex_literal_prefix(regex),
+            }
+        } else {
+            return Err(invalid(
+                "expected a 'path:', 'glob:' or 're:' pattern".to_string(),
+            ));
+        };
+
+        Ok(PathPattern { kind })
+    }
+
+    /// Pattern that matches `path` and everything below it, like `path:<path>`
+    pub fn path(path: MPath) -> Self {
+        PathPattern {
+            kind: PatternKind::Path(Some(path)),
+        }
+    }
+
+    /// Whether the pattern matches `path`
+    pub fn matches(&self, path: &MPath) -> bool {
+        match self.kind {
+            PatternKind::Path(None) => true,
+            PatternKind::Path(Some(ref prefix)) => prefix.is_prefix_of(path),
+            PatternKind::Glob(ref glob) => {
+                let mut states = GlobStates::new(glob);
+                for element in path {
+                    if states.matched() {
+                        return true;
+                    }
+                    if !states.step(element.as_bytes()) {
+                        return false;
+                    }
+                }
+                states.matched()
+            }
+            PatternKind::Regex { ref regex, .. } => regex.is_match(&path.to_vec()),
+        }
+    }
+
+    /// Whether the pattern might match something below the directory `dir`, where `None` is the
+    /// root of the repo. This can be true even if nothing below `dir` matches, but if it's false
+    /// then nothing below `dir` matches.
+    pub fn could_match_below(&self, dir: Option<&MPath>) -> bool {
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return true,
+        };
+
+        match self.kind {
+            PatternKind::Path(None) => true,
+            PatternKind::Path(Some(ref prefix)) => {
+                prefix.is_prefix_of(dir) || dir.is_prefix_of(prefix)
+            }
+            PatternKind::Glob(ref glob) => {
+                let mut states = GlobStates::new(glob);
+                for element in dir {
+                    if states.matched() {
+                        return true;
+                    }
+                    if !states.step(element.as_bytes()) {
+                        return false;
+                    }
+                }
+                // Whatever components are still expected can come from below `dir`
+                true
+            }
+            PatternKind::Regex { ref prefix, .. } => {
+                let mut dir = dir.to_vec();
+                dir.push(b'/');
+                dir.starts_with(prefix) || prefix.starts_with(&dir)
+            }
+        }
+    }
+
+    /// Whether the pattern matches everything below the directory `dir` if it matches `dir`.
+    /// True for `path:` and `glob:` patterns, but not for regexes, which can end with `$`.
+    fn covers_descendants(&self) -> bool {
+        match self.kind {
+            PatternKind::Path(_) | PatternKind::Glob(_) => true,
+            PatternKind::Regex { .. } => false,
+        }
+    }
+}
+
+impl PartialEq for PathPattern {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.kind, &other.kind) {
+            (PatternKind::Path(this), PatternKind::Path(other)) => this == other,
+            (PatternKind::Glob(this), PatternKind::Glob(other)) => this == other,
+            (
+                PatternKind::Regex { regex: this, .. },
+                PatternKind::Regex { regex: other, .. },
+            ) => this.as_str() == other.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for PathPattern {}
+
+fn parse_glob(glob: &str) -> ::std::result::Result<Vec<GlobComponent>, String> {
+    if glob.is_empty() {
+        return Err("empty glob".to_string());
+    }
+
+    glob.split('/')
+        .map(|component| {
+            if component.is_empty() {
+                return Err("glob has an empty component".to_string());
+            }
+            if component == "**" {
+                return Ok(GlobComponent::AnyComponents);
+            }
+
+            let mut tokens = vec![];
+            let mut bytes = component.bytes();
+            while let Some(byte) = bytes.next() {
+                let token = match byte {
+                    b'*' => {
+                        // Consecutive stars are the same as one
+                        if tokens.last() == Some(&GlobToken::AnyBytes) {
+                            continue;
+                        }
+                        GlobToken::AnyBytes
+                    }
+                    b'?' => GlobToken::AnyByte,
+                    b'\\' => match bytes.next() {
+                        Some(escaped) => GlobToken::Byte(escaped),
+                        None => return Err("glob ends with an escape".to_string()),
+                    },
+                    byte => GlobToken::Byte(byte),
+                };
+                tokens.push(token);
+            }
+            Ok(GlobComponent::Segment(tokens))
+        })
+        .collect()
+}
+
+/// Matches one path component against one glob component. Linear in the product of their lengths
+/// however many wildcards there are, because a star only ever has to be retried from the last
+/// star seen.
+fn segment_matches(tokens: &[GlobToken], component: &[u8]) -> bool {
+    let mut token = 0;
+    let mut byte = 0;
+    // Position of the last star and of the byte it was last tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while byte < component.len() {
+        match tokens.get(token) {
+            Some(&GlobToken::Byte(b)) if b == component[byte] => {
+                token += 1;
+                byte += 1;
+            }
+            Some(&GlobToken::AnyByte) => {
+                token += 1;
+                byte += 1;
+            }
+            Some(&GlobToken::AnyBytes) => {
+                backtrack = Some((token, byte));
+                token += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_byte)) => {
+                    // Let the star match one more byte
+                    backtrack = Some((star, star_byte + 1));
+                    token = star + 1;
+                    byte = star_byte + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    tokens[token..]
+        .iter()
+        .all(|token| *token == GlobToken::AnyBytes)
+}
+
+/// Simulation of the glob as a non-deterministic automaton over path components, where state `i`
+/// means that the first `i` glob components have matched.
+struct GlobStates<'a> {
+    glob: &'a [GlobComponent],
+    states: Vec<bool>,
+}
+
+impl<'a> GlobStates<'a> {
+    fn new(glob: &'a [GlobComponent]) -> Self {
+        let mut states = vec![false; glob.len() + 1];
+        states[0] = true;
+        let mut this = GlobStates { glob, states };
+        this.skip_any_components();
+        this
+    }
+
+    /// `**` can match no components at all
+    fn skip_any_components(&mut self) {
+        for i in 0..self.glob.len() {
+            if self.states[i] && self.glob[i] == GlobComponent::AnyComponents {
+                self.states[i + 1] = true;
+            }
+        }
+    }
+
+    /// Consumes a path component. Returns whether any state is left.
+    fn step(&mut self, component: &[u8]) -> bool {
+        let mut next = vec![false; self.states.len()];
+        for (i, glob_component) in self.glob.iter().enumerate() {
+            if !self.states[i] {
+                continue;
+            }
+            match glob_component {
+                GlobComponent::AnyComponents => next[i] = true,
+                GlobComponent::Segment(tokens) => if segment_matches(tokens, component) {
+                    next[i + 1] = true;
+                },
+            }
+        }
+        self.states = next;
+        self.skip_any_components();
+        self.states.iter().any(|state| *state)
+    }
+
+    /// Whether all the glob components have matched
+    fn matched(&self) -> bool {
+        self.states[self.glob.len()]
+    }
+}
+
+/// The literal bytes that every match of `regex` starts with. Conservative: returns an empty
+/// prefix whenever the regex is too complicated to tell.
+fn regex_literal_prefix(regex: &str) -> Vec<u8> {
+    // An alternation can be anywhere, even after what looks like a literal prefix
+    if regex.contains('|') {
+        return vec![];
+    }
+
+    let mut prefix = String::new();
+    let mut last_len = 0;
+    for c in regex.chars() {
+        match c {
+            // Quantifiers that allow the previous character not to appear at all
+            '*' | '?' | '{' => {
+                let len = prefix.len();
+                prefix.truncate(len - last_len);
+                break;
+            }
+            '\\' | '.' | '+' | '(' | ')' | '[' | ']' | '}' | '^' | '$' => break,
+            c => {
+                last_len = c.len_utf8();
+                prefix.push(c);
+            }
+        }
+    }
+    prefix.into_bytes()
+}
+
+/// A list of include and exclude patterns. A path matches if no exclude pattern matches it, and
+/// either an include pattern matches it or there are no include patterns at all.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PathMatcher {
+    includes: Vec<PathPattern>,
+    excludes: Vec<PathPattern>,
+}
+
+impl PathMatcher {
+    /// Parses the patterns of `includes` and `excludes`
+    pub fn new<I, E, S>(includes: I, excludes: E) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        E: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let parse = |pattern: S| PathPattern::parse(pattern.as_ref());
+        Ok(PathMatcher {
+            includes: includes.into_iter().map(&parse).collect::<Result<_>>()?,
+            excludes: excludes.into_iter().map(&parse).collect::<Result<_>>()?,
+        })
+    }
+
+    /// Matcher that matches every path
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: PathPattern) -> Self {
+        self.includes.push(pattern);
+        self
+    }
+
+    pub fn exclude(mut self, pattern: PathPattern) -> Self {
+        self.excludes.push(pattern);
+        self
+    }
+
+    /// Whether `path` matches
+    pub fn matches(&self, path: &MPath) -> bool {
+        !self.excludes.iter().any(|exclude| exclude.matches(path))
+            && (self.includes.is_empty()
+                || self.includes.iter().any(|include| include.matches(path)))
+    }
+
+    /// Whether anything below the directory `dir` might match, where `None` is the root of the
+    /// repo. Used to skip directories when walking a tree: this can be true even if nothing below
+    /// `dir` matches, but if it's false then nothing below `dir` matches.
+    pub fn could_match_below(&self, dir: Option<&MPath>) -> bool {
+        if let Some(dir) = dir {
+            let excluded = self.excludes
+                .iter()
+                .any(|exclude| exclude.covers_descendants() && exclude.matches(dir));
+            if excluded {
+                return false;
+            }
+        }
+
+        self.includes.is_empty()
+            || self.includes
+                .iter()
+                .any(|include| include.could_match_below(dir))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(p: &str) -> MPath {
+        MPath::new(p).unwrap()
+    }
+
+    fn pattern(p: &str) -> PathPattern {
+        PathPattern::parse(p).unwrap()
+    }
+
+    fn matches(p: &str, paths: &[&str]) -> Vec<bool> {
+        let p = pattern(p);
+        paths.iter().map(|path_| p.matches(&path(path_))).collect()
+    }
+
+    fn could_match_below(p: &str, dir: Option<&str>) -> bool {
+        pattern(p).could_match_below(dir.map(path).as_ref())
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for p in &[
+            "foo",
+            "file:foo",
+            "path:/",
+            "glob:",
+            "glob:foo/",
+            "glob:foo\\",
+            "re:(foo",
+        ] {
+            assert!(PathPattern::parse(p).is_err(), "{} should be invalid", p);
+        }
+        assert!(PathMatcher::new(vec!["path:foo"], vec!["bar"]).is_err());
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            matches("path:foo/bar", &["foo/bar", "foo/bar/baz", "foo/barbaz", "foo", "foo/ba"]),
+            vec![true, true, false, false, false]
+        );
+        assert_eq!(matches("path:", &["foo", "foo/bar"]), vec![true, true]);
+        assert_eq!(pattern("path:foo/bar/"), pattern("path:foo/bar"));
+    }
+
+    #[test]
+    fn test_path_could_match_below() {
+        assert!(could_match_below("path:foo/bar", None));
+        assert!(could_match_below("path:foo/bar", Some("foo")));
+        assert!(could_match_below("path:foo/bar", Some("foo/bar")));
+        assert!(could_match_below("path:foo/bar", Some("foo/bar/baz")));
+        assert!(!could_match_below("path:foo/bar", Some("foo/baz")));
+        assert!(!could_match_below("path:foo/bar", Some("foobar")));
+        assert!(could_match_below("path:", Some("anything")));
+    }
+
+    #[test]
+    fn test_glob_star() {
+        assert_eq!(
+            matches(
+                "glob:*.rs",
+                &["main.rs", ".rs", "main.rsx", "src/main.rs", "main.rs/inner"]
+            ),
+            vec![true, true, false, false, true]
+        );
+        assert_eq!(
+            matches("glob:src/*/lib.rs", &["src/a/lib.rs", "src/lib.rs", "src/a/b/lib.rs"]),
+            vec![true, false, false]
+        );
+        assert_eq!(
+            matches("glob:a*b*c", &["abc", "aXbYc", "aXc", "abcX", "acb"]),
+            vec![true, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_glob_question_mark_and_escapes() {
+        assert_eq!(
+            matches("glob:file?.txt", &["file1.txt", "file.txt", "file12.txt"]),
+            vec![true, false, false]
+        );
+        assert_eq!(
+            matches("glob:\\*\\?", &["*?", "a?", "*a"]),
+            vec![true, false, false]
+        );
+        // One byte, not one character
+        assert_eq!(
+            matches("glob:caf?", &["caf\u{e9}", "cafe"]),
+            vec![false, true]
+        );
+        assert_eq!(matches("glob:caf??", &["caf\u{e9}"]), vec![true]);
+    }
+
+    #[test]
+    fn test_glob_double_star() {
+        assert_eq!(
+            matches(
+                "glob:**/BUCK",
+                &["BUCK", "a/BUCK", "a/b/c/BUCK", "a/BUCKS", "a/BUCK/file"]
+            ),
+            vec![true, true, true, false, true]
+        );
+        assert_eq!(
+            matches("glob:a/**/z", &["a/z", "a/b/z", "a/b/c/z", "z", "a/b/c"]),
+            vec![true, true, true, false, false]
+        );
+        assert_eq!(
+            matches("glob:a/**", &["a", "a/b", "a/b/c", "b"]),
+            vec![true, true, true, false]
+        );
+        assert_eq!(matches("glob:**", &["a", "a/b"]), vec![true, true]);
+        // Not a whole component, so the same as `*`
+        assert_eq!(
+            matches("glob:a**z", &["az", "abcz", "ab/cz"]),
+            vec![true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_glob_is_case_sensitive() {
+        assert_eq!(
+            matches("glob:Foo/*", &["Foo/a", "foo/a", "FOO/a"]),
+            vec![true, false, false]
+        );
+    }
+
+    #[test]
+    fn test_glob_could_match_below() {
+        assert!(could_match_below("glob:src/*/lib.rs", None));
+        assert!(could_match_below("glob:src/*/lib.rs", Some("src")));
+        assert!(could_match_below("glob:src/*/lib.rs", Some("src/anything")));
+        assert!(!could_match_below("glob:src/*/lib.rs", Some("test")));
+        assert!(!could_match_below("glob:src/*/lib.rs", Some("src/a/b")));
+        // `src/a/lib.rs` itself matches, so everything below it does too
+        assert!(could_match_below("glob:src/*/lib.rs", Some("src/a/lib.rs")));
+
+        assert!(could_match_below("glob:**/BUCK", Some("a/b/c")));
+        assert!(could_match_below("glob:a/**/z", Some("a/b/c")));
+        assert!(!could_match_below("glob:a/**/z", Some("b")));
+        assert!(could_match_below("glob:*.rs", Some("main.rs")));
+        assert!(!could_match_below("glob:*.rs", Some("src")));
+    }
+
+    #[test]
+    fn test_regex() {
+        assert_eq!(
+            matches("re:foo/.*\\.rs$", &["foo/a.rs", "foo/b/c.rs", "bar/foo/a.rs", "foo/a.rsx"]),
+            vec![true, true, false, false]
+        );
+        // Anchored at the start only
+        assert_eq!(
+            matches("re:foo", &["foo", "foobar", "foo/bar", "afoo"]),
+            vec![true, true, true, false]
+        );
+        assert_eq!(
+            matches("re:(?i)foo/", &["FOO/a", "foo/a", "fo/a"]),
+            vec![true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_regex_could_match_below() {
+        assert!(could_match_below("re:foo/bar/.*", None));
+        assert!(could_match_below("re:foo/bar/.*", Some("foo")));
+        assert!(could_match_below("re:foo/bar/.*", Some("foo/bar")));
+        assert!(could_match_below("re:foo/bar/.*", Some("foo/bar/baz")));
+        assert!(!could_match_below("re:foo/bar/.*", Some("foo/baz")));
+        assert!(!could_match_below("re:foo/bar/.*", Some("bar")));
+        // The last literal character is optional
+        assert!(could_match_below("re:foo/bx?", Some("foo/b")));
+        // Alternations and groups can't be pruned
+        assert!(could_match_below("re:foo|bar", Some("bar")));
+        assert!(could_match_below("re:(foo)", Some("bar")));
+    }
+
+    #[test]
+    fn test_regex_literal_prefix() {
+        assert_eq!(regex_literal_prefix("foo/bar"), b"foo/bar".to_vec());
+        assert_eq!(regex_literal_prefix("foo/b.*"), b"foo/b".to_vec());
+        assert_eq!(regex_literal_prefix("foo/bx*"), b"foo/b".to_vec());
+        assert_eq!(regex_literal_prefix("foo/bx+"), b"foo/bx".to_vec());
+        assert_eq!(regex_literal_prefix("caf\u{e9}?"), b"caf".to_vec());
+        assert_eq!(regex_literal_prefix("a|b"), b"".to_vec());
+        assert_eq!(regex_literal_prefix("\\.hg"), b"".to_vec());
+        assert_eq!(regex_literal_prefix("(?i)foo"), b"".to_vec());
+    }
+
+    #[test]
+    fn test_non_utf8_paths() {
+        let non_utf8 = MPath::new(b"dir/\xff\xfe.bin").unwrap();
+        assert!(pattern("path:dir").matches(&non_utf8));
+        assert!(pattern("glob:dir/*.bin").matches(&non_utf8));
+        assert!(pattern("glob:dir/??.bin").matches(&non_utf8));
+        assert!(pattern("re:dir/..\\.bin$").matches(&non_utf8));
+        assert!(!pattern("glob:dir/?.bin").matches(&non_utf8));
+    }
+
+    #[test]
+    fn test_matcher() {
+        let matcher = PathMatcher::new(
+            vec!["path:src", "glob:**/*.md"],
+            vec!["path:src/generated", "re:.*\\.orig$"],
+        ).unwrap();
+
+        assert!(matcher.matches(&path("src/lib.rs")));
+        assert!(matcher.matches(&path("docs/README.md")));
+        assert!(!matcher.matches(&path("src/generated/lib.rs")));
+        assert!(!matcher.matches(&path("src/lib.rs.orig")));
+        assert!(!matcher.matches(&path("test/lib.rs")));
+
+        assert!(matcher.could_match_below(None));
+        assert!(matcher.could_match_below(Some(&path("src"))));
+        assert!(matcher.could_match_below(Some(&path("test"))));
+        assert!(!matcher.could_match_below(Some(&path("src/generated"))));
+        assert!(!matcher.could_match_below(Some(&path("src/generated/inner"))));
+        // Excluding regexes can't exclude a whole directory
+        assert!(matcher.could_match_below(Some(&path("dir.orig"))));
+    }
+
+    #[test]
+    fn test_matcher_without_includes() {
+        let all = PathMatcher::all();
+        assert!(all.matches(&path("anything")));
+        assert!(all.could_match_below(Some(&path("anything"))));
+
+        let matcher = PathMatcher::new(Vec::<&str>::new(), vec!["glob:**/.git"]).unwrap();
+        assert!(matcher.matches(&path("a/file")));
+        assert!(!matcher.matches(&path("a/.git/config")));
+        assert!(!matcher.could_match_below(Some(&path("a/.git"))));
+        assert!(matcher.could_match_below(Some(&path("a/.gitignore"))));
+    }
+
+    #[test]
+    fn test_pathological_glob() {
+        // Exponential with naive backtracking
+        let glob = format!("glob:{}b", "*a".repeat(30));
+        let component = "a".repeat(1000);
+        assert!(!pattern(&glob).matches(&path(&component)));
+        assert!(pattern(&glob).matches(&path(&format!("{}b", component))));
+
+        let glob = format!("glob:{}x", "**/".repeat(30));
+        let deep = vec!["d"; 200].join("/");
+        assert!(!pattern(&glob).matches(&path(&deep)));
+        assert!(pattern(&glob).matches(&path(&format!("{}/x", deep))));
+        assert!(pattern(&glob).could_match_below(Some(&path(&deep))));
+    }
+
+    #[test]
+    fn test_pathological_regex() {
+        // Exponential with a backtracking regex engine
+        let p = pattern("re:(a*)*b");
+        assert!(!p.matches(&path(&"a".repeat(1000))));
+        assert!(p.matches(&path(&format!("{}b", "a".repeat(1000)))));
+    }
+}
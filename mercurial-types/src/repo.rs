@@ -6,6 +6,8 @@
 
 use diesel::sql_types::Integer;
 
+use mononoke_types::RepoPrefix;
+
 // XXX RepositoryId might want to be a short string like a Phabricator callsign.
 // TODO: (rain1) T31391673 move this to the mononoke-types crate
 
@@ -28,11 +30,10 @@ impl RepositoryId {
         self.0
     }
 
-    /// Generate a prefix suitable for a blobstore.
+    /// Prefix of the blobstore keys of this repo
     #[inline]
-    pub fn prefix(&self) -> String {
-        // Generate repo0001, repo0002, etc.
-        format!("repo{:04}.", self.0)
+    pub fn prefix(&self) -> RepoPrefix {
+        RepoPrefix::new(self.0)
     }
 }
 
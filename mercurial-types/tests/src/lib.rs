@@ -27,14 +27,15 @@ use blobrepo::BlobRepo;
 use fixtures::{linear, many_files_dirs};
 use futures::{Future, Stream};
 use futures::executor::spawn;
-use futures_ext::select_all;
+use futures_ext::{merge_sorted_by_key, select_all};
 use mercurial_types::{Changeset, Entry, FileType, MPath, MPathElement, Manifest, RepoPath, Type,
                       NULL_HASH};
 use mercurial_types::manifest::{Content, EmptyManifest};
 use mercurial_types::manifest_utils::{changed_entry_stream, changed_entry_stream_with_pruner,
-                                      diff_sorted_vecs, recursive_entry_stream, ChangedEntry,
-                                      CombinatorPruner, DeletedPruner, EntryStatus, FilePruner,
-                                      NoopPruner, Pruner, VisitedPruner};
+                                      diff_sorted_vecs, ordered_changed_entry_stream_with_pruner,
+                                      recursive_entry_stream, ChangedEntry, CombinatorPruner,
+                                      DeletedPruner, EntryStatus, FilePruner, NoopPruner, Pruner,
+                                      VisitedPruner};
 use mercurial_types::nodehash::{HgChangesetId, HgEntryId, HgNodeHash};
 use mercurial_types_mocks::manifest::{ContentFactory, MockEntry, MockManifest};
 use mercurial_types_mocks::nodehash;
@@ -769,6 +770,108 @@ fn test_recursive_changed_entry_prune_visited() {
     }).expect("test failed")
 }
 
+#[test]
+fn test_ordered_changed_entry_stream() {
+    async_unit::tokio_unit_test(|| -> Result<_, !> {
+        let repo = Arc::new(many_files_dirs::getrepo(None));
+        let main_hash = HgNodeHash::from_str("d261bc7900818dea7c86935b3fb17a33b2e3a6b4").unwrap();
+        let base_hash = HgNodeHash::from_str("5a28e25f924a5d209b82ce0713d8d83e68982bc8").unwrap();
+
+        let manifest = get_root_manifest(repo.clone(), &HgChangesetId::new(main_hash));
+        let basemanifest = get_root_manifest(repo.clone(), &HgChangesetId::new(base_hash));
+
+        let get_paths = || {
+            let stream = ordered_changed_entry_stream_with_pruner(
+                &manifest,
+                &basemanifest,
+                None,
+                NoopPruner,
+                None,
+            );
+            let res = spawn(stream.collect()).wait_future().unwrap();
+            res.into_iter()
+                .map(|entry| entry.get_full_path())
+                .collect::<Vec<_>>()
+        };
+
+        let paths = get_paths();
+        let mut sorted_paths = paths.clone();
+        sorted_paths.sort();
+        assert_eq!(paths, sorted_paths);
+
+        // Same entries as the unordered version
+        let unordered = find_changed_entry_status_stream(
+            get_root_manifest(repo.clone(), &HgChangesetId::new(main_hash)),
+            get_root_manifest(repo.clone(), &HgChangesetId::new(base_hash)),
+            NoopPruner,
+            None,
+        );
+        let mut unordered_paths: Vec<_> = unordered
+            .into_iter()
+            .map(|entry| entry.get_full_path())
+            .collect();
+        unordered_paths.sort();
+        assert_eq!(paths, unordered_paths);
+
+        for _ in 0..10 {
+            assert_eq!(paths, get_paths());
+        }
+
+        Ok(())
+    }).expect("test failed")
+}
+
+#[test]
+fn test_ordered_changed_entry_stream_merge_is_deterministic() {
+    async_unit::tokio_unit_test(|| -> Result<_, !> {
+        let repo = Arc::new(many_files_dirs::getrepo(None));
+        let main_hash_1 = HgNodeHash::from_str("2f866e7e549760934e31bf0420a873f65100ad63").unwrap();
+        let main_hash_2 = HgNodeHash::from_str("d261bc7900818dea7c86935b3fb17a33b2e3a6b4").unwrap();
+        let base_hash = HgNodeHash::from_str("5a28e25f924a5d209b82ce0713d8d83e68982bc8").unwrap();
+
+        let manifest_1 = get_root_manifest(repo.clone(), &HgChangesetId::new(main_hash_1));
+        let manifest_2 = get_root_manifest(repo.clone(), &HgChangesetId::new(main_hash_2));
+        let basemanifest = get_root_manifest(repo.clone(), &HgChangesetId::new(base_hash));
+
+        let merge = |manifests: Vec<&Box<Manifest>>| {
+            let pruner = VisitedPruner::new();
+            let streams = manifests.into_iter().map(|manifest| {
+                ordered_changed_entry_stream_with_pruner(
+                    manifest,
+                    &basemanifest,
+                    None,
+                    pruner.clone(),
+                    None,
+                )
+            });
+            let merged = merge_sorted_by_key(streams, |entry: &ChangedEntry| {
+                entry.get_full_path()
+            });
+            let res = spawn(merged.collect()).wait_future().unwrap();
+            res.into_iter()
+                .map(|entry| {
+                    let hash = match entry.status {
+                        EntryStatus::Added(ref entry) => *entry.get_hash(),
+                        EntryStatus::Deleted(ref entry) => *entry.get_hash(),
+                        EntryStatus::Modified { ref to_entry, .. } => *to_entry.get_hash(),
+                    };
+                    (entry.get_full_path(), hash)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let res = merge(vec![&manifest_1, &manifest_2]);
+        // Same number of unique entries as in test_recursive_changed_entry_prune_visited
+        assert_eq!(res.len(), 15);
+        for _ in 0..10 {
+            assert_eq!(res, merge(vec![&manifest_1, &manifest_2]));
+            assert_eq!(res, merge(vec![&manifest_2, &manifest_1]));
+        }
+
+        Ok(())
+    }).expect("test failed")
+}
+
 #[test]
 fn test_recursive_changed_entry_prune_visited_no_files() {
     async_unit::tokio_unit_test(|| -> Result<_, !> {
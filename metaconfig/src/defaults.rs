@@ -0,0 +1,385 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Merging of the shared `defaults.toml` into per-repo configs.
+//!
+//! The metaconfig repo may contain a `defaults.toml` file at its root. Its content is merged
+//! under every repo's `server.toml` before the repo config is parsed, using these rules:
+//!
+//! * A key present only in the defaults is copied into the repo config.
+//! * A key present only in the repo config is kept as is.
+//! * If a key is present in both and both values are tables, they are merged recursively with
+//!   the same rules.
+//! * If a key is present in both and both values are arrays, the repo's array replaces the
+//!   default one, unless the repo config asks for appending (see below). When appending, the
+//!   default entries come first, followed by the repo's entries.
+//! * If a key is present in both and both values are scalars of the same type, the repo's value
+//!   wins.
+//! * If a key is present in both and the values have different types, that's a parse error that
+//!   names the key and the repo.
+//!
+//! The array strategy is selected with an optional top-level `merge_strategy` table in the repo
+//! config, mapping a dotted key path to either `"append"` or `"replace"`:
+//!
+//! ```toml
+//! [merge_strategy]
+//! hooks = "append"
+//! bookmarks = "replace"
+//! ```
+//!
+//! The `merge_strategy` table is removed from the repo config before merging. The merge iterates
+//! over keys in sorted order, so the result (and the first reported error) is deterministic.
+
+use std::collections::HashMap;
+
+use toml::Value;
+use toml::value::Table;
+
+use errors::*;
+
+/// Name of the file with defaults at the root of the metaconfig repo
+pub const DEFAULTS_FILE: &str = "defaults.toml";
+
+const MERGE_STRATEGY_KEY: &str = "merge_strategy";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ArrayMerge {
+    Append,
+    Replace,
+}
+
+/// Merge `defaults` under `repo_config` for repo `repo_name`, following the rules described in
+/// the module documentation.
+pub fn merge_with_defaults(repo_name: &str, defaults: &Value, repo_config: Value) -> Result<Value> {
+    let mut repo_table = match repo_config {
+        Value::Table(table) => table,
+        other => {
+            return Err(ErrorKind::DefaultsTypeMismatch(
+                repo_name.to_string(),
+                "<root>".into(),
+                "table",
+                other.type_str(),
+            ).into())
+        }
+    };
+    let default_table = match defaults {
+        Value::Table(table) => table,
+        other => {
+            return Err(ErrorKind::InvalidConfig(format!(
+                "{} must be a table, found {}",
+                DEFAULTS_FILE,
+                other.type_str()
+            )).into())
+        }
+    };
+
+    let strategies = match repo_table.remove(MERGE_STRATEGY_KEY) {
+        Some(value) => parse_strategies(repo_name, value)?,
+        None => HashMap::new(),
+    };
+
+    let merged = merge_tables(repo_name, "", &strategies, default_table, repo_table)?;
+    Ok(Value::Table(merged))
+}
+
+fn parse_strategies(repo_name: &str, value: Value) -> Result<HashMap<String, ArrayMerge>> {
+    let table = match value {
+        Value::Table(table) => table,
+        other => {
+            return Err(ErrorKind::InvalidMergeStrategy(
+                repo_name.to_string(),
+                MERGE_STRATEGY_KEY.into(),
+                format!("expected a table, found {}", other.type_str()),
+            ).into())
+        }
+    };
+
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let strategy = match value.as_str() {
+                Some("append") => ArrayMerge::Append,
+                Some("replace") => ArrayMerge::Replace,
+                _ => {
+                    return Err(ErrorKind::InvalidMergeStrategy(
+                        repo_name.to_string(),
+                        key,
+                        format!("expected \"append\" or \"replace\", found {}", value),
+                    ).into())
+                }
+            };
+            Ok((key, strategy))
+        })
+        .collect()
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn merge_tables(
+    repo_name: &str,
+    prefix: &str,
+    strategies: &HashMap<String, ArrayMerge>,
+    defaults: &Table,
+    mut overrides: Table,
+) -> Result<Table> {
+    // toml::value::Table is a BTreeMap, so iteration order (and thus error reporting) is
+    // deterministic.
+    let mut merged = Table::new();
+    for (key, default_value) in defaults {
+        let full_key = join_key(prefix, key);
+        let value = match overrides.remove(key) {
+            None => default_value.clone(),
+            Some(override_value) => merge_values(
+                repo_name,
+                &full_key,
+                strategies,
+                default_value,
+                override_value,
+            )?,
+        };
+        merged.insert(key.clone(), value);
+    }
+    merged.extend(overrides);
+    Ok(merged)
+}
+
+fn merge_values(
+    repo_name: &str,
+    key: &str,
+    strategies: &HashMap<String, ArrayMerge>,
+    default_value: &Value,
+    override_value: Value,
+) -> Result<Value> {
+    match (default_value, override_value) {
+        (Value::Table(defaults), Value::Table(overrides)) => Ok(Value::Table(merge_tables(
+            repo_name,
+            key,
+            strategies,
+            defaults,
+            overrides,
+        )?)),
+        (Value::Array(defaults), Value::Array(overrides)) => {
+            match strategies.get(key).cloned().unwrap_or(ArrayMerge::Replace) {
+                ArrayMerge::Replace => Ok(Value::Array(overrides)),
+                ArrayMerge::Append => {
+                    let mut merged = defaults.clone();
+                    merged.extend(overrides);
+                    Ok(Value::Array(merged))
+                }
+            }
+        }
+        (default_value, override_value) => {
+            if default_value.same_type(&override_value) {
+                Ok(override_value)
+            } else {
+                Err(ErrorKind::DefaultsTypeMismatch(
+                    repo_name.to_string(),
+                    key.to_string(),
+                    default_value.type_str(),
+                    override_value.type_str(),
+                ).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use toml;
+
+    fn merge(defaults: &str, repo_config: &str) -> Result<Value> {
+        let defaults: Value = toml::from_str(defaults).unwrap();
+        let repo_config: Value = toml::from_str(repo_config).unwrap();
+        merge_with_defaults("fbsource", &defaults, repo_config)
+    }
+
+    fn value(content: &str) -> Value {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn test_scalar_override() {
+        let defaults = r#"
+            generation_cache_size=1024
+            scuba_table="default_table"
+        "#;
+        let repo_config = r#"
+            repoid=0
+            scuba_table="repo_table"
+        "#;
+        assert_eq!(
+            merge(defaults, repo_config).unwrap(),
+            value(
+                r#"
+                generation_cache_size=1024
+                repoid=0
+                scuba_table="repo_table"
+            "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_table_merge() {
+        let defaults = r#"
+            [pushrebase]
+            rewritedates=false
+            recursion_limit=1024
+            [cache_warmup]
+            bookmark="master"
+        "#;
+        let repo_config = r#"
+            [pushrebase]
+            recursion_limit=16
+        "#;
+        assert_eq!(
+            merge(defaults, repo_config).unwrap(),
+            value(
+                r#"
+                [pushrebase]
+                rewritedates=false
+                recursion_limit=16
+                [cache_warmup]
+                bookmark="master"
+            "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_list_replace_by_default() {
+        let defaults = r#"
+            [[hooks]]
+            name="hook1"
+        "#;
+        let repo_config = r#"
+            [[hooks]]
+            name="hook2"
+        "#;
+        assert_eq!(
+            merge(defaults, repo_config).unwrap(),
+            value(
+                r#"
+                [[hooks]]
+                name="hook2"
+            "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_list_append() {
+        let defaults = r#"
+            [[hooks]]
+            name="hook1"
+        "#;
+        let repo_config = r#"
+            [merge_strategy]
+            hooks="append"
+            [[hooks]]
+            name="hook2"
+        "#;
+        assert_eq!(
+            merge(defaults, repo_config).unwrap(),
+            value(
+                r#"
+                [[hooks]]
+                name="hook1"
+                [[hooks]]
+                name="hook2"
+            "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_nested_list_append() {
+        let defaults = r#"
+            [outer]
+            list=[1, 2]
+        "#;
+        let repo_config = r#"
+            [merge_strategy]
+            "outer.list"="append"
+            [outer]
+            list=[3]
+        "#;
+        assert_eq!(
+            merge(defaults, repo_config).unwrap(),
+            value(
+                r#"
+                [outer]
+                list=[1, 2, 3]
+            "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_explicit_replace() {
+        let defaults = r#"
+            list=[1, 2]
+        "#;
+        let repo_config = r#"
+            merge_strategy={list="replace"}
+            list=[3]
+        "#;
+        assert_eq!(merge(defaults, repo_config).unwrap(), value("list=[3]"));
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        let defaults = r#"
+            [pushrebase]
+            recursion_limit=1024
+        "#;
+        let repo_config = r#"
+            [pushrebase]
+            recursion_limit="a lot"
+        "#;
+        match merge(defaults, repo_config)
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::DefaultsTypeMismatch(repo, key, default_type, repo_type)) => {
+                assert_eq!(repo, "fbsource");
+                assert_eq!(key, "pushrebase.recursion_limit");
+                assert_eq!(default_type, "integer");
+                assert_eq!(repo_type, "string");
+            }
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        // Table vs scalar is also a mismatch
+        assert!(merge("[pushrebase]\nrewritedates=true", "pushrebase=1").is_err());
+    }
+
+    #[test]
+    fn test_invalid_merge_strategy() {
+        let repo_config = r#"
+            [merge_strategy]
+            hooks="prepend"
+        "#;
+        match merge("", repo_config)
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidMergeStrategy(repo, key, _)) => {
+                assert_eq!(repo, "fbsource");
+                assert_eq!(key, "hooks");
+            }
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+}
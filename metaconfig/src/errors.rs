@@ -30,4 +30,19 @@ pub enum ErrorKind {
     /// Too many bypass options for a hook
     #[fail(display = "Only one bypass option is allowed. Hook: {}", _0)]
     TooManyBypassOptions(String),
+    /// A key has a different type in defaults and in the repo config
+    #[fail(display = "repo {}: key {} is {} in defaults but {} in repo config", _0, _1, _2, _3)]
+    DefaultsTypeMismatch(String, String, &'static str, &'static str),
+    /// Invalid entry in the merge_strategy table of a repo config
+    #[fail(display = "repo {}: invalid merge strategy for {}: {}", _0, _1, _2)]
+    InvalidMergeStrategy(String, String, String),
+    /// The hook_config of a hook is invalid
+    #[fail(display = "invalid hook_config for hook {}: {}", _0, _1)]
+    InvalidHookConfig(String, String),
+    /// Two repos claim the same name, as their name or as an alias
+    #[fail(display = "repo name {} is claimed by both {} and {}", _0, _1, _2)]
+    DuplicateRepoName(String, String, String),
+    /// A repo flag that doesn't exist
+    #[fail(display = "unknown repo flag: {}", _0)]
+    UnknownRepoFlag(String),
 }
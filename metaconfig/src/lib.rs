@@ -20,6 +20,7 @@ extern crate futures_ext;
 #[macro_use]
 #[cfg(test)]
 extern crate maplit;
+extern crate regex;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -33,9 +34,18 @@ extern crate mercurial_types_mocks;
 extern crate mononoke_types;
 extern crate vfs;
 
+mod defaults;
 pub mod errors;
 pub mod repoconfig;
 
-pub use repoconfig::{CacheWarmupParams, PushrebaseParams, RepoConfigs, RepoType};
+pub use repoconfig::{check_repo_names, default_warmup_fetch_retry_policy,
+                     BookmarkCreationPolicy, BookmarkSnapshotParams, CacheWarmupParams,
+                     CommitMessageNormalization, DerivedDataParams, FetchLimits,
+                     FilePrefetchParams, HookBookmarks, HookDegradedPolicy, HookHealthParams,
+                     MirroringParams,
+                     PathRules, PullBookmarksFilter, PullBookmarksParams, PushAdvisoryParams,
+                     PushAdvisoryPlaceholder, PushAdvisoryTemplate, PushLimits,
+                     PushrebaseDatePolicy, PushrebaseParams, RepoAlias, RepoConfigs, RepoFlag,
+                     RepoFlags, RepoType, WarmupTaskParams, WriteForwardingParams};
 
 pub use errors::{Error, ErrorKind};
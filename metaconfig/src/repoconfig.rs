@@ -7,26 +7,32 @@
 //! Contains structures describing configuration of the entire repo. Those structures are
 //! deserialized from TOML files from metaconfig repo
 
-use blobrepo::{BlobRepo, ManifoldArgs};
-use bookmarks::Bookmark;
+use blobrepo::{default_blobstore_retry_policy, default_sql_retry_policy, BlobRepo,
+               ManifoldArgs, StoreDbAddresses};
+use bookmarks::{Bookmark, BookmarkPrefix};
 use bytes::Bytes;
+use defaults::{merge_with_defaults, DEFAULTS_FILE};
 use errors::*;
 use failure::FutureFailureErrorExt;
 use futures::{finished, future, Future};
 use futures::Stream;
-use futures_ext::FutureExt;
+use futures_ext::{FutureExt, RetryPolicy};
 use mercurial_types::{Changeset, MPath, MPathElement, Manifest};
 use mercurial_types::manifest::Content;
 use mercurial_types::nodehash::HgChangesetId;
 use mononoke_types::FileContents;
-use std::collections::HashMap;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::iter;
 use std::path::PathBuf;
 use std::str;
+use std::time::Duration;
 use toml;
 use vfs::{vfs_from_manifest, ManifestVfsDir, ManifestVfsFile, VfsDir, VfsFile, VfsNode, VfsWalker};
 
 /// Configuration of a single repository
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct RepoConfig {
     /// If false, this repo config is completely ignored.
     pub enabled: bool,
@@ -44,8 +50,61 @@ pub struct RepoConfig {
     pub bookmarks: Option<Vec<BookmarkParams>>,
     /// Configuration for hooks
     pub hooks: Option<Vec<HookParams>>,
+    /// When hooks are considered unhealthy, and what pushes do then
+    pub hook_health: HookHealthParams,
     /// Pushrebase configuration options
     pub pushrebase: PushrebaseParams,
+    /// Limits on the size of a single push
+    pub push_limits: PushLimits,
+    /// If set, a message about the landed commits is sent to the client after a successful push
+    pub push_advisory: Option<PushAdvisoryParams>,
+    /// If set, writes are not applied to this repo but forwarded to the primary server of the repo
+    pub write_forwarding: Option<WriteForwardingParams>,
+    /// If set, a sample of the read-only sessions of this repo is replayed against a shadow
+    /// server
+    pub mirroring: Option<MirroringParams>,
+    /// If set, snapshots of the bookmarks of this repo are periodically written to its blobstore
+    pub bookmark_snapshots: Option<BookmarkSnapshotParams>,
+    /// If set, data derived from the changesets of this repo is derived in the background when
+    /// they land, instead of on their first read
+    pub derived_data: Option<DerivedDataParams>,
+    /// If set, wireproto requests with arguments that Mononoke doesn't understand are rejected
+    /// instead of being served without them
+    pub strict_wireproto_args: bool,
+    /// If set, getbundle sends changesets in a canonical order, so that the same request always
+    /// gets the same response. It costs a lookup of the generation number of every changeset.
+    pub deterministic_getbundle: bool,
+    /// Which files are prefetched into the caches when gettreepack serves their directory
+    pub file_prefetch: FilePrefetchParams,
+    /// How many blobs the wireproto commands that fetch data fetch at the same time
+    pub fetch_limits: FetchLimits,
+    /// Rules that the paths of files added or modified by a push must follow
+    pub path_rules: PathRules,
+    /// Which bookmarks are sent to clients when they pull
+    pub pull_bookmarks: PullBookmarksParams,
+    /// Which bookmarks pushes can create, and who can create them
+    pub bookmark_creation: BookmarkCreationPolicy,
+    /// Retention of scratch bookmarks, per namespace
+    pub scratch_retention: Vec<ScratchRetentionParams>,
+    /// If set, pushes are recorded in the push journal of the repo before their blobs are
+    /// uploaded, so that pushes abandoned half way can be found
+    pub push_journal: bool,
+    /// If set, the changesets of this repo are recorded in the cross-repo index, which finds the
+    /// repos that have a changeset
+    pub cross_repo_index: bool,
+    /// If set, the resources of this repo are never reclaimed when it has no traffic
+    pub always_hot: bool,
+    /// Other names that clients can use for this repo, e.g. its old name after a rename
+    pub aliases: Vec<RepoAlias>,
+    /// If set, commands that could change the repo, like unbundle, are rejected. Read-only
+    /// replicas use this, unless they forward writes to a primary.
+    pub readonly: bool,
+    /// If set, only clients that identify as one of these users can use the repo, over wireproto
+    /// and over the repo service. Otherwise every client that passed TLS can.
+    pub allowed_identities: Option<Vec<String>>,
+    /// Users that can move bookmarks in two phases, with preparebookmarkmove and then
+    /// commitbookmarkmove or abortbookmarkmove. No one can if empty.
+    pub bookmark_move_identities: Vec<String>,
 }
 
 impl RepoConfig {
@@ -56,16 +115,199 @@ impl RepoConfig {
             _ => None,
         }
     }
+
+    /// The flags of this repo as configured, before any runtime override
+    pub fn flags(&self) -> RepoFlags {
+        RepoFlags {
+            readonly: self.readonly,
+            strict_wireproto_args: self.strict_wireproto_args,
+            deterministic_getbundle: self.deterministic_getbundle,
+        }
+    }
 }
 
-/// Configuration of warming up the Mononoke cache. This warmup happens on startup
+/// A feature of a repo that can be turned on and off at runtime, without a push of the config
+/// repo. Its configured value is overridden by a value in the flag overrides of the repo.
+#[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum RepoFlag {
+    /// See `RepoConfig::readonly`
+    Readonly,
+    /// See `RepoConfig::strict_wireproto_args`
+    StrictWireprotoArgs,
+    /// See `RepoConfig::deterministic_getbundle`
+    DeterministicGetbundle,
+}
+
+impl RepoFlag {
+    /// Every flag, in the order they are listed
+    pub fn all() -> &'static [RepoFlag] {
+        &[
+            RepoFlag::Readonly,
+            RepoFlag::StrictWireprotoArgs,
+            RepoFlag::DeterministicGetbundle,
+        ]
+    }
+
+    /// Name of the flag, as in the repo config and in overrides
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepoFlag::Readonly => "readonly",
+            RepoFlag::StrictWireprotoArgs => "strict_wireproto_args",
+            RepoFlag::DeterministicGetbundle => "deterministic_getbundle",
+        }
+    }
+}
+
+impl str::FromStr for RepoFlag {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        RepoFlag::all()
+            .iter()
+            .find(|flag| flag.as_str() == s)
+            .cloned()
+            .ok_or_else(|| ErrorKind::UnknownRepoFlag(s.to_string()).into())
+    }
+}
+
+impl fmt::Display for RepoFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The value of every `RepoFlag` of a repo
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct RepoFlags {
+    /// See `RepoConfig::readonly`
+    pub readonly: bool,
+    /// See `RepoConfig::strict_wireproto_args`
+    pub strict_wireproto_args: bool,
+    /// See `RepoConfig::deterministic_getbundle`
+    pub deterministic_getbundle: bool,
+}
+
+impl RepoFlags {
+    /// Value of `flag`
+    pub fn get(&self, flag: RepoFlag) -> bool {
+        match flag {
+            RepoFlag::Readonly => self.readonly,
+            RepoFlag::StrictWireprotoArgs => self.strict_wireproto_args,
+            RepoFlag::DeterministicGetbundle => self.deterministic_getbundle,
+        }
+    }
+
+    /// Sets the value of `flag`
+    pub fn set(&mut self, flag: RepoFlag, value: bool) {
+        match flag {
+            RepoFlag::Readonly => self.readonly = value,
+            RepoFlag::StrictWireprotoArgs => self.strict_wireproto_args = value,
+            RepoFlag::DeterministicGetbundle => self.deterministic_getbundle = value,
+        }
+    }
+}
+
+impl fmt::Display for RepoFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags: Vec<_> = RepoFlag::all()
+            .iter()
+            .map(|flag| format!("{}={}", flag, self.get(*flag)))
+            .collect();
+        write!(f, "{}", flags.join(" "))
+    }
+}
+
+/// Another name of a repo. Clients that connect to it are served by the repo as if they used its
+/// name, but are logged with the name they used.
 #[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RepoAlias {
+    pub name: String,
+    /// If set, sent to clients that use this name, to get them to move to the name of the repo
+    pub deprecation_notice: Option<String>,
+}
+
+/// Checks that no name is claimed by two repos, whether as the name of a repo or as an alias
+pub fn check_repo_names<'a, I>(repos: I) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a String, &'a RepoConfig)>,
+{
+    let mut claimed: HashMap<&str, &str> = HashMap::new();
+    for (reponame, config) in repos {
+        let names = iter::once(reponame.as_str())
+            .chain(config.aliases.iter().map(|alias| alias.name.as_str()));
+        for name in names {
+            if let Some(other) = claimed.insert(name, reponame) {
+                return Err(ErrorKind::DuplicateRepoName(
+                    name.to_string(),
+                    other.to_string(),
+                    reponame.to_string(),
+                ).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Max number of fetches that a warmup task runs at once, unless set in the config
+pub const DEFAULT_WARMUP_FETCH_CONCURRENCY: usize = 100;
+
+/// Fraction of the fetches of a warmup task that may fail before the warmup fails, unless set in
+/// the config
+pub const DEFAULT_WARMUP_MAX_FAILURE_RATIO: f64 = 0.05;
+
+/// Retry policy for the fetches of warmup tasks, unless overridden in the config
+pub fn default_warmup_fetch_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(2),
+        jitter: true,
+    }
+}
+
+/// Configuration of warming up the Mononoke cache. This warmup happens on startup
+#[derive(Debug, Clone, PartialEq)]
 pub struct CacheWarmupParams {
     /// Bookmark to warmup cache for at the startup. If not set then the cache will be cold.
     pub bookmark: Bookmark,
     /// Max number to fetch during commit warmup. If not set in the config, then set to a default
     /// value.
     pub commit_limit: usize,
+    /// Settings of individual warmup tasks, keyed by task name. Tasks that are not listed here
+    /// are enabled and have no time budget.
+    pub tasks: BTreeMap<String, WarmupTaskParams>,
+    /// Max number of fetches that each task runs at once
+    pub fetch_concurrency: usize,
+    /// How failed fetches are retried
+    pub fetch_retry: RetryPolicy,
+    /// Fetches that still fail after their retries are skipped, unless more than this fraction of
+    /// the fetches of a task fail. Then the warmup fails, and the repo isn't marked as ready.
+    pub max_failure_ratio: f64,
+}
+
+impl CacheWarmupParams {
+    /// Returns the settings of the warmup task `name`
+    pub fn task(&self, name: &str) -> WarmupTaskParams {
+        self.tasks.get(name).cloned().unwrap_or_default()
+    }
+}
+
+/// Settings of a single cache warmup task
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WarmupTaskParams {
+    /// If false, the task doesn't run
+    pub enabled: bool,
+    /// The task is stopped if it takes longer than this. The cache stays partially warm.
+    pub time_budget: Option<Duration>,
+}
+
+impl Default for WarmupTaskParams {
+    fn default() -> Self {
+        WarmupTaskParams {
+            enabled: true,
+            time_budget: None,
+        }
+    }
 }
 
 /// Configuration for a bookmark
@@ -97,11 +339,56 @@ pub enum HookBypass {
         name: String,
         /// Value of the pushvar
         value: String,
+        /// If set, only pushes by these users can use the bypass
+        identities: Option<Vec<String>>,
     },
 }
 
+/// What a rejection by a hook does to the push
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HookSeverity {
+    /// The push fails
+    Block,
+    /// The push goes ahead, and the pusher gets the rejection as a warning
+    Warn,
+}
+
+impl Default for HookSeverity {
+    fn default() -> Self {
+        HookSeverity::Block
+    }
+}
+
+/// The bookmarks that a hook runs on, in addition to the bookmarks that list the hook in their
+/// `hooks`. Pushes that move several bookmarks run the hooks of all of them.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct HookBookmarks {
+    /// Bookmarks with exactly these names
+    pub names: Vec<Bookmark>,
+    /// Bookmarks whose whole names match one of these regexes
+    pub regexes: Vec<String>,
+}
+
+impl HookBookmarks {
+    /// Compiles `regexes`, anchored so that they only match whole bookmark names
+    pub fn compile_regexes(&self) -> Result<Vec<Regex>> {
+        self.regexes
+            .iter()
+            .map(|regex| {
+                Regex::new(&format!("^(?:{})$", regex)).map_err(|err| {
+                    ErrorKind::InvalidConfig(format!("invalid bookmark regex {}: {}", regex, err))
+                        .into()
+                })
+            })
+            .collect()
+    }
+}
+
+/// Maximum size of a serialized `hook_config` table of a single hook
+pub const MAX_HOOK_CONFIG_SIZE: usize = 64 * 1024;
+
 /// Configuration for a hook
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HookParams {
     /// The name of the hook
     pub name: String,
@@ -111,26 +398,494 @@ pub struct HookParams {
     pub code: String,
     /// An optional way to bypass a hook
     pub bypass: Option<HookBypass>,
+    /// Free-form table that is passed to the hook as read-only `ctx.config`, so that the same
+    /// hook code can behave differently in different repos
+    pub config: Option<toml::Value>,
+    /// The hook only looks at the message, author and changed files of a changeset, so its
+    /// accept can be reused for the same changeset rebased onto other parents
+    pub content_only: bool,
+    /// If set, the hook is the hook of this name that is built into Mononoke, configured by
+    /// `config`, and `code` is empty
+    pub builtin: Option<String>,
+    pub severity: HookSeverity,
+    /// Bookmarks the hook runs on, besides the ones that list it
+    pub bookmarks: HookBookmarks,
+}
+
+/// What pushes do while the hooks of a repo are unhealthy, i.e. while hooks fail to run, e.g.
+/// because file contents can't be fetched, rather than reject changesets
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HookDegradedPolicy {
+    /// Pushes fail with the errors of the hooks, as they do while hooks are healthy
+    FailClosed,
+    /// Pushes are accepted without the hooks that failed to run, which is logged
+    FailOpen,
+    /// The repo is read-only until hooks are healthy again
+    ReadOnly,
+}
+
+/// When the hooks of a repo are considered unhealthy, and what pushes do then
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HookHealthParams {
+    /// Number of the latest hook runs that the failure ratio is computed over
+    pub window: usize,
+    /// Hooks are unhealthy while at least this fraction of the runs in the window failed to run.
+    /// Rejections are not failures.
+    pub max_failure_ratio: f64,
+    pub degraded_policy: HookDegradedPolicy,
+}
+
+impl Default for HookHealthParams {
+    fn default() -> Self {
+        HookHealthParams {
+            window: 100,
+            max_failure_ratio: 0.5,
+            degraded_policy: HookDegradedPolicy::FailClosed,
+        }
+    }
 }
 
 /// Pushrebase configuration options
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PushrebaseParams {
-    /// Update dates of rebased commits
-    pub rewritedates: bool,
+    /// How the dates of rebased commits are chosen
+    pub date_policy: PushrebaseDatePolicy,
     /// How far will we go from bookmark to find rebase root
     pub recursion_limit: usize,
+    /// How the messages of rebased commits are normalized
+    pub message_normalization: CommitMessageNormalization,
 }
 
 impl Default for PushrebaseParams {
     fn default() -> Self {
         PushrebaseParams {
-            rewritedates: true,
+            date_policy: PushrebaseDatePolicy::default(),
             recursion_limit: 16384, // this number is fairly arbirary
+            message_normalization: Default::default(),
+        }
+    }
+}
+
+/// How the author dates of rebased commits are chosen. Whatever the policy, the original date and
+/// the policy are recorded in the extras of rebased commits.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PushrebaseDatePolicy {
+    /// Dates are left as the client wrote them
+    Preserve,
+    /// Dates are the time of the pushrebase on the server
+    ServerTime,
+    /// The date of a commit is the latest of its own date and the date of its first parent, so
+    /// that dates never decrease along first parents
+    Clamp,
+}
+
+impl PushrebaseDatePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PushrebaseDatePolicy::Preserve => "preserve",
+            PushrebaseDatePolicy::ServerTime => "server_time",
+            PushrebaseDatePolicy::Clamp => "clamp",
+        }
+    }
+}
+
+impl Default for PushrebaseDatePolicy {
+    fn default() -> Self {
+        PushrebaseDatePolicy::Preserve
+    }
+}
+
+/// Normalization of the messages of rebased commits, done before they are hashed. Every step
+/// that changes a message also changes the hash of its commit, so all of them are off by default
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CommitMessageNormalization {
+    /// Strip whitespace at the end of every line
+    pub strip_trailing_whitespace: bool,
+    /// Make sure that a non-empty message ends with a newline
+    pub trailing_newline: bool,
+    /// Reject messages that contain NUL bytes
+    pub reject_nul: bool,
+}
+
+impl CommitMessageNormalization {
+    /// Returns true if no step is on, so that messages are left untouched
+    pub fn is_empty(&self) -> bool {
+        *self == CommitMessageNormalization::default()
+    }
+}
+
+/// Limits on the size of a single push, checked while the bundle is being received
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PushLimits {
+    /// Max number of bytes of payload in all parts of the bundle
+    pub max_push_bytes: u64,
+    /// Max number of bytes of payload in a single part of the bundle
+    pub max_part_bytes: u64,
+    /// Max number of changesets in the bundle
+    pub max_changesets: usize,
+    /// Max number of bytes in the message of a single changeset
+    pub max_commit_message_bytes: usize,
+    /// Max number of direct entries of a directory that the push creates or grows
+    pub max_directory_fanout: usize,
+    /// Directories that the push creates or grows past this many direct entries are logged, but
+    /// the push is accepted up to `max_directory_fanout`
+    pub warn_directory_fanout: usize,
+}
+
+impl Default for PushLimits {
+    fn default() -> Self {
+        PushLimits {
+            max_push_bytes: 4 * 1024 * 1024 * 1024,
+            max_part_bytes: 2 * 1024 * 1024 * 1024,
+            max_changesets: 100_000,
+            max_commit_message_bytes: 1024 * 1024,
+            max_directory_fanout: 500_000,
+            warn_directory_fanout: 100_000,
+        }
+    }
+}
+
+/// Max size of a push advisory template, in bytes
+pub const MAX_PUSH_ADVISORY_TEMPLATE_BYTES: usize = 1024;
+/// Max number of commits listed in a push advisory, the others are only counted
+pub const MAX_PUSH_ADVISORY_LISTED_COMMITS: usize = 100;
+/// Number of commits listed in a push advisory, unless set in the config
+pub const DEFAULT_PUSH_ADVISORY_LISTED_COMMITS: usize = 5;
+
+/// Message sent to the client after a successful push, e.g. with links to the landed commits
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PushAdvisoryParams {
+    /// Rendered once for every listed commit
+    pub template: PushAdvisoryTemplate,
+    /// If more commits landed, only the last ones are listed, and the others are summarized
+    pub max_listed_commits: usize,
+}
+
+/// Values that a push advisory template can refer to, as `{name}`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PushAdvisoryPlaceholder {
+    /// `{hash}`, the full hash of the landed commit
+    Hash,
+    /// `{short_hash}`, the first 12 hex digits of the hash of the landed commit
+    ShortHash,
+    /// `{bookmark}`, the bookmark the commit landed on, empty if the push moved no bookmark
+    Bookmark,
+    /// `{reponame}`, the name of the repo
+    Reponame,
+}
+
+impl PushAdvisoryPlaceholder {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hash" => Some(PushAdvisoryPlaceholder::Hash),
+            "short_hash" => Some(PushAdvisoryPlaceholder::ShortHash),
+            "bookmark" => Some(PushAdvisoryPlaceholder::Bookmark),
+            "reponame" => Some(PushAdvisoryPlaceholder::Reponame),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum TemplateSegment {
+    Text(String),
+    Placeholder(PushAdvisoryPlaceholder),
+}
+
+/// A push advisory template, checked when the config is parsed so that rendering can't fail
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PushAdvisoryTemplate {
+    segments: Vec<TemplateSegment>,
+}
+
+impl PushAdvisoryTemplate {
+    /// Parses `template`, in which `{{` and `}}` stand for literal braces
+    pub fn parse(template: &str) -> Result<Self> {
+        let invalid = |msg: String| -> Error {
+            ErrorKind::InvalidConfig(format!("push_advisory: template {:?}: {}", template, msg))
+                .into()
+        };
+        if template.len() > MAX_PUSH_ADVISORY_TEMPLATE_BYTES {
+            return Err(invalid(format!(
+                "it is {} bytes, at most {} bytes are allowed",
+                template.len(),
+                MAX_PUSH_ADVISORY_TEMPLATE_BYTES
+            )));
+        }
+
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}')
+                        .ok_or_else(|| invalid("unclosed {".to_string()))?;
+                    let name = &rest[..end];
+                    let placeholder = PushAdvisoryPlaceholder::from_name(name)
+                        .ok_or_else(|| invalid(format!("unknown placeholder {{{}}}", name)))?;
+                    if !text.is_empty() {
+                        segments.push(TemplateSegment::Text(text.split_off(0)));
+                    }
+                    segments.push(TemplateSegment::Placeholder(placeholder));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(invalid("unmatched }".to_string())),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(TemplateSegment::Text(text));
+        }
+        Ok(PushAdvisoryTemplate { segments })
+    }
+
+    /// Renders the template, with `value` giving the value of every placeholder
+    pub fn render<F>(&self, value: F) -> String
+    where
+        F: Fn(PushAdvisoryPlaceholder) -> String,
+    {
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                TemplateSegment::Text(text) => rendered.push_str(text),
+                TemplateSegment::Placeholder(placeholder) => {
+                    rendered.push_str(&value(*placeholder))
+                }
+            }
+        }
+        rendered
+    }
+}
+
+/// Where and how writes of a secondary server are forwarded to the primary server of the repo
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WriteForwardingParams {
+    /// Address of the primary server, as "host:port"
+    pub primary: String,
+    /// Name of the repo on the primary server
+    pub primary_reponame: String,
+    /// Common name the certificate of the primary server is verified against
+    pub ssl_common_name: String,
+    /// Path to the client certificate presented to the primary server
+    pub cert: String,
+    /// Path to the private key of the client certificate
+    pub private_key: String,
+    /// Path to the CA certificate the primary server's certificate is verified with
+    pub ca_pem: String,
+    /// How long a single forwarded request may take
+    pub timeout: Duration,
+    /// After this many consecutive failures to reach the primary, forwarded requests fail fast
+    pub breaker_failures: usize,
+    /// How long requests fail fast before the primary is tried again
+    pub breaker_cooldown: Duration,
+}
+
+/// Where a sample of the read traffic of a repo is mirrored to, e.g. to validate a new build on a
+/// shadow server before it serves clients
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirroringParams {
+    /// Address of the shadow server, as "host:port"
+    pub shadow: String,
+    /// Name of the repo on the shadow server
+    pub shadow_reponame: String,
+    /// Common name the certificate of the shadow server is verified against
+    pub ssl_common_name: String,
+    /// Path to the client certificate presented to the shadow server
+    pub cert: String,
+    /// Path to the private key of the client certificate
+    pub private_key: String,
+    /// Path to the CA certificate the shadow server's certificate is verified with
+    pub ca_pem: String,
+    /// Fraction of the read-only sessions that are mirrored, between 0 and 1
+    pub sample_rate: f64,
+    /// If set, the response of the shadow is compared with the response that the client got
+    pub compare: bool,
+    /// How long a mirrored session may take
+    pub timeout: Duration,
+    /// Sessions whose requests are larger are not mirrored
+    pub max_request_bytes: usize,
+    /// Responses larger than this are mirrored but not compared
+    pub max_compared_bytes: usize,
+}
+
+/// How often the bookmarks of a repo are snapshotted, and how many snapshots are kept
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BookmarkSnapshotParams {
+    /// Time between two snapshots
+    pub interval: Duration,
+    /// Number of most recent snapshots that are kept
+    pub retain: usize,
+}
+
+/// Which types of data are derived from the changesets of a repo when they land
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DerivedDataParams {
+    /// Names of the types of derived data
+    pub types: Vec<String>,
+    /// Max number of derivations that run at the same time
+    pub workers: usize,
+    /// Max number of attempts to derive a type of data of a changeset
+    pub max_attempts: usize,
+}
+
+/// Files that are prefetched into the caches of a repo in the background, because clients usually
+/// getfiles most of the files of a directory right after they got its tree with gettreepack
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FilePrefetchParams {
+    /// Whether the envelopes of the files of the trees that gettreepack serves are prefetched
+    pub on_gettreepack: bool,
+    /// Whether the content of those files is prefetched too, if it is at most
+    /// `max_content_bytes` long
+    pub content: bool,
+    pub max_content_bytes: u64,
+}
+
+impl Default for FilePrefetchParams {
+    fn default() -> Self {
+        FilePrefetchParams {
+            on_gettreepack: false,
+            content: false,
+            max_content_bytes: 64 * 1024,
         }
     }
 }
 
+/// How many fetches each wireproto command that fetches data has in flight at the same time.
+/// Higher limits lower the latency of large requests, at the cost of the memory of the blobs
+/// that are fetched but not sent yet.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FetchLimits {
+    /// Max number of files that a getfiles fetches at the same time
+    pub getfiles_buffer_size: usize,
+    /// Max number of trees that a gettreepack fetches at the same time
+    pub gettreepack_buffer_size: usize,
+    /// Max number of pairs of a between that are walked at the same time
+    pub between_buffer_size: usize,
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        FetchLimits {
+            getfiles_buffer_size: 100,
+            gettreepack_buffer_size: 10000,
+            between_buffer_size: 10,
+        }
+    }
+}
+
+/// Rules that the paths of files added or modified by a push must follow. Every rule is off by
+/// default
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PathRules {
+    /// Reject paths that have a `.hg` or `.git` component
+    pub forbid_vcs_components: bool,
+    /// Reject paths that have a component reserved on Windows, like `con` or `nul.txt`
+    pub forbid_windows_reserved_names: bool,
+    /// Max length of a single path component, in bytes
+    pub max_component_length: Option<usize>,
+    /// Max length of the full path, in bytes
+    pub max_path_length: Option<usize>,
+    /// Characters that a path component must not end with
+    pub forbidden_trailing_chars: Vec<char>,
+    /// Reject paths that are not valid UTF-8
+    pub forbid_non_utf8: bool,
+}
+
+impl PathRules {
+    /// Rules for places that have no repo config, like blobimport. Length limits depend on the
+    /// repo, so only the rules that no repo should break are on
+    pub fn recommended() -> Self {
+        PathRules {
+            forbid_vcs_components: true,
+            forbid_windows_reserved_names: true,
+            max_component_length: None,
+            max_path_length: None,
+            forbidden_trailing_chars: vec!['.', ' '],
+            forbid_non_utf8: true,
+        }
+    }
+
+    /// Returns true if no rule is on, so that paths don't need to be checked at all
+    pub fn is_empty(&self) -> bool {
+        *self == PathRules::default()
+    }
+}
+
+/// Which bookmarks are sent in the listkeys part of getbundle responses. The listkeys command
+/// is not affected, so clients can still look up any bookmark by name
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PullBookmarksParams {
+    /// If set, only the bookmarks it selects are sent, otherwise all of them are
+    pub filter: Option<PullBookmarksFilter>,
+    /// Max number of bookmarks in a single part, the others are dropped
+    pub max_count: Option<usize>,
+    /// Bookmarks whose names start with one of these are never sent, e.g. the scratch bookmarks
+    /// of infinitepush. They can still be looked up with listkeys and moved with pushkey.
+    pub exclude_prefixes: Vec<BookmarkPrefix>,
+}
+
+impl PullBookmarksParams {
+    /// Returns true if `bookmark` is never sent
+    pub fn excludes(&self, bookmark: &Bookmark) -> bool {
+        self.exclude_prefixes
+            .iter()
+            .any(|prefix| prefix.is_prefix_of(bookmark))
+    }
+}
+
+/// Selects bookmarks by name
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PullBookmarksFilter {
+    /// Bookmarks with exactly these names
+    pub names: Vec<Bookmark>,
+    /// Bookmarks whose names start with one of these
+    pub prefixes: Vec<BookmarkPrefix>,
+}
+
+impl PullBookmarksFilter {
+    /// Returns true if `bookmark` is selected by this filter
+    pub fn matches(&self, bookmark: &Bookmark) -> bool {
+        self.names.contains(bookmark) || self.prefixes.iter().any(|p| p.is_prefix_of(bookmark))
+    }
+}
+
+/// Which bookmarks pushes can create, and who can create them. Bookmarks that exist can be moved
+/// and deleted regardless. The default lets anyone create any bookmark.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BookmarkCreationPolicy {
+    /// If set, only the bookmarks it selects can be created
+    pub allowed_names: Option<PullBookmarksFilter>,
+    /// If set, only users with one of these unix names can create bookmarks
+    pub allowed_creators: Option<Vec<String>>,
+    /// If set, bookmarks can only be created by pushrebase, not by plain pushes
+    pub require_pushrebase: bool,
+}
+
+/// Retention of the bookmarks of a scratch namespace, which pushes fill without bound. The
+/// `scratch-cleanup` admin command deletes the bookmarks that aren't retained.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ScratchRetentionParams {
+    /// The namespace, e.g. "scratch/". The component of a bookmark name that follows it is the
+    /// user of the bookmark, e.g. "alice" for "scratch/alice/feature".
+    pub prefix: BookmarkPrefix,
+    /// If set, bookmarks that weren't updated for longer than this are deleted
+    pub max_age: Option<Duration>,
+    /// If set, only this many of the most recently updated bookmarks of each user are kept
+    pub max_count_per_user: Option<usize>,
+    /// Bookmarks that are never deleted
+    pub excluded: Vec<Bookmark>,
+}
+
 /// Types of repositories supported
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum RepoType {
@@ -209,26 +964,72 @@ impl RepoConfigs {
                     }
                     VfsNode::Dir(dir) => Ok(dir),
                 })
-                .and_then(move |repos_dir| {
+                .join(Self::read_defaults(root_node.clone()))
+                .and_then(move |(repos_dir, defaults)| {
                     let repodirs: Vec<_> = repos_dir.read().into_iter().cloned().collect();
                     let repos_node = repos_dir.into_node();
                     future::join_all(repodirs.into_iter().map(move |repodir| {
-                        Self::read_repo(root_node.clone(), repos_node.clone(), repodir)
+                        Self::read_repo(
+                            root_node.clone(),
+                            repos_node.clone(),
+                            repodir,
+                            defaults.clone(),
+                        )
                     }))
                 })
-                .map(|repos| RepoConfigs {
-                    metaconfig: MetaConfig {},
-                    repos: repos.into_iter().collect(),
+                .and_then(|repos| {
+                    let repos: HashMap<_, _> = repos.into_iter().collect();
+                    check_repo_names(&repos)?;
+                    Ok(RepoConfigs {
+                        metaconfig: MetaConfig {},
+                        repos,
+                    })
                 }),
         )
     }
 
+    /// Read the optional defaults file at the root of the metaconfig repo
+    fn read_defaults(
+        root_node: VfsNode<ManifestVfsDir, ManifestVfsFile>,
+    ) -> Box<Future<Item = Option<toml::Value>, Error = Error> + Send> {
+        let has_defaults = match root_node {
+            VfsNode::Dir(ref dir) => dir.read()
+                .into_iter()
+                .any(|element| element.as_bytes() == DEFAULTS_FILE.as_bytes()),
+            VfsNode::File(_) => false,
+        };
+        if !has_defaults {
+            return future::ok(None).boxify();
+        }
+
+        RepoConfigs::read_file(root_node, try_boxfuture!(MPath::new(DEFAULTS_FILE)))
+            .and_then(|bytes| Ok(Some(toml::from_slice::<toml::Value>(bytes.as_ref())?)))
+            .boxify()
+    }
+
+    fn parse_raw_config(
+        repo_name: &str,
+        defaults: Option<&toml::Value>,
+        bytes: &[u8],
+    ) -> Result<RawRepoConfig> {
+        match defaults {
+            Some(defaults) => {
+                let repo_config = toml::from_slice::<toml::Value>(bytes)?;
+                let merged = merge_with_defaults(repo_name, defaults, repo_config)?;
+                Ok(merged.try_into::<RawRepoConfig>()?)
+            }
+            None => Ok(toml::from_slice::<RawRepoConfig>(bytes)?),
+        }
+    }
+
     fn read_repo(
         root_node: VfsNode<ManifestVfsDir, ManifestVfsFile>,
         repos_dir: VfsNode<ManifestVfsDir, ManifestVfsFile>,
         repo_dir: MPathElement,
+        defaults: Option<toml::Value>,
     ) -> Box<Future<Item = (String, RepoConfig), Error = Error> + Send> {
         let repo_name = try_boxfuture!(str::from_utf8(repo_dir.as_bytes())).to_string();
+        let config_repo_name = repo_name.clone();
 
         VfsWalker::new(repos_dir, repo_dir.into_iter().cloned())
             .walk()
@@ -244,31 +1045,47 @@ impl RepoConfigs {
                 ).map(move |bytes| (bytes, repo_dir))
                     .boxify()
             })
-            .and_then(|(bytes, repo_dir)| {
-                let raw_config = try_boxfuture!(toml::from_slice::<RawRepoConfig>(bytes.as_ref()));
+            .and_then(move |(bytes, repo_dir)| {
+                let raw_config = try_boxfuture!(Self::parse_raw_config(
+                    &config_repo_name,
+                    defaults.as_ref(),
+                    bytes.as_ref()
+                ));
                 let hooks = raw_config.hooks.clone();
                 // Easier to deal with empty vector than Option
                 let hooks = hooks.unwrap_or(Vec::new());
                 future::join_all(hooks.into_iter().map(move |raw_hook_config| {
-                    let path = raw_hook_config.path.clone();
-                    let relative_prefix = "./";
-                    let is_relative = path.starts_with(relative_prefix);
-                    let path_node;
-                    let path_adjusted;
-                    if is_relative {
-                        path_node = repo_dir.clone().into_node();
-                        path_adjusted = path.chars().skip(relative_prefix.len()).collect();
-                    } else {
-                        path_node = root_node.clone();
-                        path_adjusted = path;
-                    }
-                    RepoConfigs::read_file(
-                        path_node,
-                        try_boxfuture!(MPath::new(path_adjusted.as_bytes().to_vec())),
-                    ).and_then(|bytes| {
-                        let code = str::from_utf8(&bytes)?;
-                        let code = code.to_string();
-
+                    let code = match (&raw_hook_config.path, &raw_hook_config.builtin) {
+                        (Some(path), None) => {
+                            let relative_prefix = "./";
+                            let is_relative = path.starts_with(relative_prefix);
+                            let path_node;
+                            let path_adjusted: String;
+                            if is_relative {
+                                path_node = repo_dir.clone().into_node();
+                                path_adjusted = path.chars().skip(relative_prefix.len()).collect();
+                            } else {
+                                path_node = root_node.clone();
+                                path_adjusted = path.clone();
+                            }
+                            RepoConfigs::read_file(
+                                path_node,
+                                try_boxfuture!(MPath::new(path_adjusted.as_bytes().to_vec())),
+                            ).and_then(|bytes| Ok(str::from_utf8(&bytes)?.to_string()))
+                                .boxify()
+                        }
+                        // Builtin hooks have no code
+                        (None, Some(_)) => future::ok(String::new()).boxify(),
+                        _ => {
+                            return future::err(
+                                ErrorKind::InvalidConfig(format!(
+                                    "hook {} must have exactly one of path and builtin",
+                                    raw_hook_config.name
+                                )).into(),
+                            ).boxify()
+                        }
+                    };
+                    code.and_then(|code| {
                         let bypass_commit_message = raw_hook_config
                             .bypass_commit_string
                             .map(|s| HookBypass::CommitMessage(s));
@@ -287,20 +1104,66 @@ impl RepoConfigs {
                             Some(Err(err)) => {
                                 return Err(err);
                             }
-                            Some(Ok((name, value))) => Some(HookBypass::Pushvar { name, value }),
+                            Some(Ok((name, value))) => Some(HookBypass::Pushvar {
+                                name,
+                                value,
+                                identities: raw_hook_config.bypass_identities.clone(),
+                            }),
                             None => None,
                         };
+                        if bypass_pushvar.is_none() && raw_hook_config.bypass_identities.is_some() {
+                            return Err(ErrorKind::InvalidConfig(format!(
+                                "hook {} has bypass_identities without bypass_pushvar",
+                                raw_hook_config.name
+                            )).into());
+                        }
 
                         if bypass_commit_message.is_some() && bypass_pushvar.is_some() {
                             return Err(ErrorKind::TooManyBypassOptions(raw_hook_config.name).into());
                         }
                         let bypass = bypass_commit_message.or(bypass_pushvar);
 
+                        let config = match raw_hook_config.hook_config {
+                            Some(config) => {
+                                Some(validate_hook_config(&raw_hook_config.name, config)?)
+                            }
+                            None => None,
+                        };
+
+                        let severity = match raw_hook_config.severity.as_ref().map(|s| s.as_str())
+                        {
+                            None => HookSeverity::default(),
+                            Some("block") => HookSeverity::Block,
+                            Some("warn") => HookSeverity::Warn,
+                            Some(severity) => {
+                                return Err(ErrorKind::InvalidConfig(format!(
+                                    "hook {}: unknown severity {}, expected block or warn",
+                                    raw_hook_config.name, severity
+                                )).into())
+                            }
+                        };
+
+                        let bookmarks = HookBookmarks {
+                            names: raw_hook_config
+                                .bookmarks
+                                .unwrap_or_default()
+                                .into_iter()
+                                .map(Bookmark::new)
+                                .collect::<Result<_>>()?,
+                            regexes: raw_hook_config.bookmark_regexes.unwrap_or_default(),
+                        };
+                        bookmarks.compile_regexes()?;
+
                         Ok(HookParams {
                             name: raw_hook_config.name,
                             code,
                             hook_type: raw_hook_config.hook_type,
                             bypass,
+                            config,
+                            content_only: raw_hook_config.content_only.unwrap_or(false),
+                            builtin: raw_hook_config.builtin,
+                            severity,
+                            bookmarks,
                         })
                     })
                         .boxify()
@@ -353,6 +1216,15 @@ impl RepoConfigs {
             })
         }
 
+        match (&this.repotype, &this.store_db_addresses) {
+            (RawRepoType::TestBlobManifold, _) | (_, None) => {}
+            _ => {
+                return Err(ErrorKind::InvalidConfig(
+                    "store_db_addresses are only supported by blob:testmanifold repos".into(),
+                ).into())
+            }
+        }
+
         let repotype = match this.repotype {
             RawRepoType::Revlog => RepoType::Revlog(get_path(&this)?),
             RawRepoType::Files => RepoType::BlobFiles(get_path(&this)?),
@@ -361,11 +1233,30 @@ impl RepoConfigs {
                 let manifold_bucket = this.manifold_bucket.ok_or(ErrorKind::InvalidConfig(
                     "manifold bucket must be specified".into(),
                 ))?;
-                let db_address = this.db_address.expect("xdb tier was not specified");
+                let db_address = this.db_address.ok_or(ErrorKind::InvalidConfig(
+                    "db_address must be specified".into(),
+                ))?;
+                let store_db_addresses = match this.store_db_addresses {
+                    Some(raw) => raw.into_addresses(),
+                    None => Default::default(),
+                };
+                let blobstore_retry = match this.blobstore_retry {
+                    Some(raw) => {
+                        raw.into_policy("blobstore_retry", default_blobstore_retry_policy())?
+                    }
+                    None => default_blobstore_retry_policy(),
+                };
+                let sql_retry = match this.sql_retry {
+                    Some(raw) => raw.into_policy("sql_retry", default_sql_retry_policy())?,
+                    None => default_sql_retry_policy(),
+                };
                 RepoType::BlobManifold(ManifoldArgs {
                     bucket: manifold_bucket,
                     prefix: this.manifold_prefix.unwrap_or("".into()),
                     db_address,
+                    store_db_addresses,
+                    blobstore_retry,
+                    sql_retry,
                 })
             }
             RawRepoType::TestBlobDelayRocks => RepoType::TestBlobDelayRocks(
@@ -379,10 +1270,10 @@ impl RepoConfigs {
         let generation_cache_size = this.generation_cache_size.unwrap_or(10 * 1024 * 1024);
         let repoid = this.repoid;
         let scuba_table = this.scuba_table;
-        let cache_warmup = this.cache_warmup.map(|cache_warmup| CacheWarmupParams {
-            bookmark: Bookmark::new(cache_warmup.bookmark).expect("bookmark name must be ascii"),
-            commit_limit: cache_warmup.commit_limit.unwrap_or(200000),
-        });
+        let cache_warmup = match this.cache_warmup {
+            Some(raw) => Some(raw.into_params()?),
+            None => None,
+        };
         let bookmarks = match this.bookmarks {
             Some(bookmarks) => Some(
                 bookmarks
@@ -408,54 +1299,305 @@ impl RepoConfigs {
             hooks_opt = None;
         }
 
-        let pushrebase = this.pushrebase
+        let hook_health = match this.hook_health {
+            Some(raw) => raw.into_params()?,
+            None => HookHealthParams::default(),
+        };
+
+        let pushrebase = match this.pushrebase {
+            Some(raw) => raw.into_params()?,
+            None => PushrebaseParams::default(),
+        };
+
+        let push_limits = this.push_limits
             .map(|raw| {
-                let default = PushrebaseParams::default();
-                PushrebaseParams {
-                    rewritedates: raw.rewritedates.unwrap_or(default.rewritedates),
-                    recursion_limit: raw.recursion_limit.unwrap_or(default.recursion_limit),
+                let default = PushLimits::default();
+                PushLimits {
+                    max_push_bytes: raw.max_push_bytes.unwrap_or(default.max_push_bytes),
+                    max_part_bytes: raw.max_part_bytes.unwrap_or(default.max_part_bytes),
+                    max_changesets: raw.max_changesets.unwrap_or(default.max_changesets),
+                    max_commit_message_bytes: raw.max_commit_message_bytes
+                        .unwrap_or(default.max_commit_message_bytes),
+                    max_directory_fanout: raw.max_directory_fanout
+                        .unwrap_or(default.max_directory_fanout),
+                    warn_directory_fanout: raw.warn_directory_fanout
+                        .unwrap_or(default.warn_directory_fanout),
                 }
             })
             .unwrap_or_default();
+        if push_limits.warn_directory_fanout > push_limits.max_directory_fanout {
+            return Err(ErrorKind::InvalidConfig(
+                "push_limits: warn_directory_fanout must not exceed max_directory_fanout"
+                    .to_string(),
+            ).into());
+        }
 
-        Ok(RepoConfig {
-            enabled,
-            repotype,
-            generation_cache_size,
-            repoid,
-            scuba_table,
-            cache_warmup,
-            bookmarks,
-            hooks: hooks_opt,
-            pushrebase,
-        })
-    }
-}
+        let push_advisory = match this.push_advisory {
+            Some(raw) => Some(raw.into_params()?),
+            None => None,
+        };
 
-#[derive(Debug, Deserialize, Clone)]
-struct RawRepoConfig {
-    path: Option<PathBuf>,
-    repotype: RawRepoType,
-    enabled: Option<bool>,
-    generation_cache_size: Option<usize>,
-    manifold_bucket: Option<String>,
-    manifold_prefix: Option<String>,
-    repoid: i32,
-    db_address: Option<String>,
-    scuba_table: Option<String>,
+        let write_forwarding = match this.write_forwarding {
+            Some(raw) => Some(raw.into_params()?),
+            None => None,
+        };
+
+        let mirroring = match this.mirroring {
+            Some(raw) => Some(raw.into_params()?),
+            None => None,
+        };
+
+        let readonly = this.readonly.unwrap_or(false);
+        if readonly && write_forwarding.is_some() {
+            return Err(ErrorKind::InvalidConfig(
+                "a readonly repo can't forward writes".into(),
+            ).into());
+        }
+
+        let bookmark_snapshots = match this.bookmark_snapshots {
+            Some(raw) => Some(raw.into_params()?),
+            None => None,
+        };
+
+        let derived_data = match this.derived_data {
+            Some(raw) => Some(raw.into_params()?),
+            None => None,
+        };
+
+        let file_prefetch = match this.file_prefetch {
+            Some(raw) => raw.into_params()?,
+            None => FilePrefetchParams::default(),
+        };
+
+        let fetch_limits = match this.fetch_limits {
+            Some(raw) => raw.into_limits()?,
+            None => FetchLimits::default(),
+        };
+
+        let path_rules = match this.path_rules {
+            Some(raw) => raw.into_rules()?,
+            None => PathRules::default(),
+        };
+
+        let pull_bookmarks = match this.pull_bookmarks {
+            Some(raw) => {
+                let publishing = bookmarks.as_ref().map(|b| b.as_slice()).unwrap_or(&[]);
+                raw.into_params(publishing)?
+            }
+            None => PullBookmarksParams::default(),
+        };
+
+        let bookmark_creation = match this.bookmark_creation {
+            Some(raw) => raw.into_policy()?,
+            None => BookmarkCreationPolicy::default(),
+        };
+
+        let scratch_retention = this.scratch_retention
+            .unwrap_or_default()
+            .into_iter()
+            .map(RawScratchRetention::into_params)
+            .collect::<Result<Vec<_>>>()?;
+
+        let aliases = convert_aliases(
+            this.aliases.unwrap_or_default(),
+            this.alias_deprecation_notices.unwrap_or_default(),
+        )?;
+
+        Ok(RepoConfig {
+            enabled,
+            repotype,
+            generation_cache_size,
+            repoid,
+            scuba_table,
+            cache_warmup,
+            bookmarks,
+            hooks: hooks_opt,
+            hook_health,
+            pushrebase,
+            push_limits,
+            push_advisory,
+            write_forwarding,
+            mirroring,
+            bookmark_snapshots,
+            derived_data,
+            strict_wireproto_args: this.strict_wireproto_args.unwrap_or(false),
+            deterministic_getbundle: this.deterministic_getbundle.unwrap_or(false),
+            file_prefetch,
+            fetch_limits,
+            path_rules,
+            pull_bookmarks,
+            bookmark_creation,
+            scratch_retention,
+            push_journal: this.push_journal.unwrap_or(false),
+            cross_repo_index: this.cross_repo_index.unwrap_or(false),
+            always_hot: this.always_hot.unwrap_or(false),
+            aliases,
+            readonly,
+            allowed_identities: this.allowed_identities,
+            bookmark_move_identities: this.bookmark_move_identities.unwrap_or_default(),
+        })
+    }
+}
+
+/// Pairs the aliases of a repo with their deprecation notices, which must be for one of them
+fn convert_aliases(
+    names: Vec<String>,
+    mut deprecation_notices: HashMap<String, String>,
+) -> Result<Vec<RepoAlias>> {
+    let mut aliases: Vec<RepoAlias> = Vec::new();
+    for name in names {
+        if aliases.iter().any(|alias| alias.name == name) {
+            return Err(ErrorKind::InvalidConfig(format!("alias {} is listed twice", name)).into());
+        }
+        let deprecation_notice = deprecation_notices.remove(&name);
+        aliases.push(RepoAlias {
+            name,
+            deprecation_notice,
+        });
+    }
+    match deprecation_notices.keys().next() {
+        Some(name) => Err(ErrorKind::InvalidConfig(format!(
+            "alias_deprecation_notices: {} is not an alias",
+            name
+        )).into()),
+        None => Ok(aliases),
+    }
+}
+
+/// Checks that a `hook_config` is a table that is not larger than `MAX_HOOK_CONFIG_SIZE` when
+/// serialized.
+fn validate_hook_config(hook_name: &str, config: toml::Value) -> Result<toml::Value> {
+    if !config.is_table() {
+        return Err(ErrorKind::InvalidHookConfig(
+            hook_name.to_string(),
+            format!("expected a table, found {}", config.type_str()),
+        ).into());
+    }
+    let size = toml::to_string(&config)
+        .map_err(|err| ErrorKind::InvalidHookConfig(hook_name.to_string(), err.to_string()))?
+        .len();
+    if size > MAX_HOOK_CONFIG_SIZE {
+        return Err(ErrorKind::InvalidHookConfig(
+            hook_name.to_string(),
+            format!(
+                "config is {} bytes, at most {} bytes are allowed",
+                size, MAX_HOOK_CONFIG_SIZE
+            ),
+        ).into());
+    }
+    Ok(config)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct RawRepoConfig {
+    path: Option<PathBuf>,
+    repotype: RawRepoType,
+    enabled: Option<bool>,
+    generation_cache_size: Option<usize>,
+    manifold_bucket: Option<String>,
+    manifold_prefix: Option<String>,
+    repoid: i32,
+    db_address: Option<String>,
+    store_db_addresses: Option<RawStoreDbAddresses>,
+    scuba_table: Option<String>,
     delay_mean: Option<u64>,
     delay_stddev: Option<u64>,
     io_thread_num: Option<usize>,
     cache_warmup: Option<RawCacheWarmupConfig>,
     bookmarks: Option<Vec<RawBookmarkConfig>>,
     hooks: Option<Vec<RawHookConfig>>,
+    hook_health: Option<RawHookHealthParams>,
     pushrebase: Option<RawPushrebaseParams>,
+    push_limits: Option<RawPushLimits>,
+    push_advisory: Option<RawPushAdvisoryParams>,
+    write_forwarding: Option<RawWriteForwardingParams>,
+    mirroring: Option<RawMirroringParams>,
+    bookmark_snapshots: Option<RawBookmarkSnapshotParams>,
+    derived_data: Option<RawDerivedDataParams>,
+    strict_wireproto_args: Option<bool>,
+    deterministic_getbundle: Option<bool>,
+    file_prefetch: Option<RawFilePrefetchParams>,
+    fetch_limits: Option<RawFetchLimits>,
+    path_rules: Option<RawPathRules>,
+    pull_bookmarks: Option<RawPullBookmarks>,
+    bookmark_creation: Option<RawBookmarkCreationPolicy>,
+    scratch_retention: Option<Vec<RawScratchRetention>>,
+    push_journal: Option<bool>,
+    cross_repo_index: Option<bool>,
+    always_hot: Option<bool>,
+    aliases: Option<Vec<String>>,
+    alias_deprecation_notices: Option<HashMap<String, String>>,
+    readonly: Option<bool>,
+    allowed_identities: Option<Vec<String>>,
+    bookmark_move_identities: Option<Vec<String>>,
+    blobstore_retry: Option<RawRetryPolicy>,
+    sql_retry: Option<RawRetryPolicy>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 struct RawCacheWarmupConfig {
     bookmark: String,
     commit_limit: Option<usize>,
+    tasks: Option<HashMap<String, RawWarmupTaskParams>>,
+    fetch_concurrency: Option<usize>,
+    fetch_retry: Option<RawRetryPolicy>,
+    max_failure_ratio: Option<f64>,
+}
+
+impl RawCacheWarmupConfig {
+    fn into_params(self) -> Result<CacheWarmupParams> {
+        let mut tasks = BTreeMap::new();
+        for (name, raw) in self.tasks.unwrap_or_default() {
+            if raw.time_budget_secs == Some(0) {
+                return Err(ErrorKind::InvalidConfig(format!(
+                    "cache_warmup.tasks.{}: time_budget_secs must be positive",
+                    name
+                )).into());
+            }
+            let params = WarmupTaskParams {
+                enabled: raw.enabled.unwrap_or(true),
+                time_budget: raw.time_budget_secs.map(Duration::from_secs),
+            };
+            tasks.insert(name, params);
+        }
+
+        let fetch_concurrency = self.fetch_concurrency
+            .unwrap_or(DEFAULT_WARMUP_FETCH_CONCURRENCY);
+        if fetch_concurrency == 0 {
+            return Err(ErrorKind::InvalidConfig(
+                "cache_warmup.fetch_concurrency must be positive".into(),
+            ).into());
+        }
+        let fetch_retry = match self.fetch_retry {
+            Some(raw) => raw.into_policy(
+                "cache_warmup.fetch_retry",
+                default_warmup_fetch_retry_policy(),
+            )?,
+            None => default_warmup_fetch_retry_policy(),
+        };
+        let max_failure_ratio = self.max_failure_ratio
+            .unwrap_or(DEFAULT_WARMUP_MAX_FAILURE_RATIO);
+        if !(max_failure_ratio >= 0.0 && max_failure_ratio <= 1.0) {
+            return Err(ErrorKind::InvalidConfig(
+                "cache_warmup.max_failure_ratio must be between 0 and 1".into(),
+            ).into());
+        }
+
+        Ok(CacheWarmupParams {
+            bookmark: Bookmark::new(self.bookmark).expect("bookmark name must be ascii"),
+            commit_limit: self.commit_limit.unwrap_or(200000),
+            tasks,
+            fetch_concurrency,
+            fetch_retry,
+            max_failure_ratio,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct RawWarmupTaskParams {
+    enabled: Option<bool>,
+    time_budget_secs: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -472,10 +1614,17 @@ struct RawBookmarkHook {
 #[derive(Debug, Deserialize, Clone)]
 struct RawHookConfig {
     name: String,
-    path: String,
+    path: Option<String>,
+    builtin: Option<String>,
     hook_type: HookType,
     bypass_commit_string: Option<String>,
     bypass_pushvar: Option<String>,
+    bypass_identities: Option<Vec<String>>,
+    hook_config: Option<toml::Value>,
+    content_only: Option<bool>,
+    severity: Option<String>,
+    bookmarks: Option<Vec<String>>,
+    bookmark_regexes: Option<Vec<String>>,
 }
 
 /// Types of repositories supported
@@ -490,8 +1639,543 @@ enum RawRepoType {
 
 #[derive(Clone, Debug, Deserialize)]
 struct RawPushrebaseParams {
+    date_policy: Option<String>,
     rewritedates: Option<bool>,
     recursion_limit: Option<usize>,
+    message_normalization: Option<RawCommitMessageNormalization>,
+}
+
+impl RawPushrebaseParams {
+    fn into_params(self) -> Result<PushrebaseParams> {
+        let default = PushrebaseParams::default();
+        let date_policy = match (self.date_policy.as_ref(), self.rewritedates) {
+            (None, None) => default.date_policy,
+            // rewritedates is the older name of the server_time and preserve policies
+            (None, Some(true)) => PushrebaseDatePolicy::ServerTime,
+            (None, Some(false)) => PushrebaseDatePolicy::Preserve,
+            (Some(policy), None) => match policy.as_str() {
+                "preserve" => PushrebaseDatePolicy::Preserve,
+                "server_time" => PushrebaseDatePolicy::ServerTime,
+                "clamp" => PushrebaseDatePolicy::Clamp,
+                _ => {
+                    return Err(ErrorKind::InvalidConfig(format!(
+                        "pushrebase.date_policy: unknown policy {}, expected preserve, \
+                         server_time or clamp",
+                        policy
+                    )).into())
+                }
+            },
+            (Some(_), Some(_)) => {
+                return Err(ErrorKind::InvalidConfig(
+                    "pushrebase: only one of date_policy and rewritedates can be set".into(),
+                ).into())
+            }
+        };
+        Ok(PushrebaseParams {
+            date_policy,
+            recursion_limit: self.recursion_limit.unwrap_or(default.recursion_limit),
+            message_normalization: self.message_normalization
+                .map(|raw| CommitMessageNormalization {
+                    strip_trailing_whitespace: raw.strip_trailing_whitespace.unwrap_or(false),
+                    trailing_newline: raw.trailing_newline.unwrap_or(false),
+                    reject_nul: raw.reject_nul.unwrap_or(false),
+                })
+                .unwrap_or(default.message_normalization),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawCommitMessageNormalization {
+    strip_trailing_whitespace: Option<bool>,
+    trailing_newline: Option<bool>,
+    reject_nul: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawHookHealthParams {
+    window: Option<usize>,
+    max_failure_ratio: Option<f64>,
+    degraded_policy: Option<String>,
+}
+
+impl RawHookHealthParams {
+    fn into_params(self) -> Result<HookHealthParams> {
+        let default = HookHealthParams::default();
+        let window = self.window.unwrap_or(default.window);
+        if window == 0 {
+            return Err(ErrorKind::InvalidConfig(
+                "hook_health.window must be positive".into(),
+            ).into());
+        }
+        let max_failure_ratio = self.max_failure_ratio
+            .unwrap_or(default.max_failure_ratio);
+        if !(max_failure_ratio > 0.0 && max_failure_ratio <= 1.0) {
+            return Err(ErrorKind::InvalidConfig(
+                "hook_health.max_failure_ratio must be larger than 0 and at most 1".into(),
+            ).into());
+        }
+        let degraded_policy = match self.degraded_policy.as_ref().map(|p| p.as_str()) {
+            None => default.degraded_policy,
+            Some("fail_closed") => HookDegradedPolicy::FailClosed,
+            Some("fail_open") => HookDegradedPolicy::FailOpen,
+            Some("read_only") => HookDegradedPolicy::ReadOnly,
+            Some(policy) => {
+                return Err(ErrorKind::InvalidConfig(format!(
+                    "hook_health.degraded_policy: unknown policy {}, expected fail_closed, \
+                     fail_open or read_only",
+                    policy
+                )).into())
+            }
+        };
+        Ok(HookHealthParams {
+            window,
+            max_failure_ratio,
+            degraded_policy,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPushLimits {
+    max_push_bytes: Option<u64>,
+    max_part_bytes: Option<u64>,
+    max_changesets: Option<usize>,
+    max_commit_message_bytes: Option<usize>,
+    max_directory_fanout: Option<usize>,
+    warn_directory_fanout: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPushAdvisoryParams {
+    template: String,
+    max_listed_commits: Option<usize>,
+}
+
+impl RawPushAdvisoryParams {
+    fn into_params(self) -> Result<PushAdvisoryParams> {
+        let template = PushAdvisoryTemplate::parse(&self.template)?;
+        let max_listed_commits = self.max_listed_commits
+            .unwrap_or(DEFAULT_PUSH_ADVISORY_LISTED_COMMITS);
+        if max_listed_commits == 0 || max_listed_commits > MAX_PUSH_ADVISORY_LISTED_COMMITS {
+            return Err(ErrorKind::InvalidConfig(format!(
+                "push_advisory: max_listed_commits must be between 1 and {}",
+                MAX_PUSH_ADVISORY_LISTED_COMMITS
+            )).into());
+        }
+        Ok(PushAdvisoryParams {
+            template,
+            max_listed_commits,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawWriteForwardingParams {
+    primary: String,
+    primary_reponame: String,
+    ssl_common_name: String,
+    cert: String,
+    private_key: String,
+    ca_pem: String,
+    timeout_secs: Option<u64>,
+    breaker_failures: Option<usize>,
+    breaker_cooldown_secs: Option<u64>,
+}
+
+impl RawWriteForwardingParams {
+    fn into_params(self) -> Result<WriteForwardingParams> {
+        let breaker_failures = self.breaker_failures.unwrap_or(5);
+        if breaker_failures == 0 {
+            return Err(ErrorKind::InvalidConfig(
+                "write_forwarding: breaker_failures must be positive".into(),
+            ).into());
+        }
+        Ok(WriteForwardingParams {
+            primary: self.primary,
+            primary_reponame: self.primary_reponame,
+            ssl_common_name: self.ssl_common_name,
+            cert: self.cert,
+            private_key: self.private_key,
+            ca_pem: self.ca_pem,
+            timeout: Duration::from_secs(self.timeout_secs.unwrap_or(300)),
+            breaker_failures,
+            breaker_cooldown: Duration::from_secs(self.breaker_cooldown_secs.unwrap_or(30)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawMirroringParams {
+    shadow: String,
+    shadow_reponame: String,
+    ssl_common_name: String,
+    cert: String,
+    private_key: String,
+    ca_pem: String,
+    sample_rate: f64,
+    compare: Option<bool>,
+    timeout_secs: Option<u64>,
+    max_request_bytes: Option<usize>,
+    max_compared_bytes: Option<usize>,
+}
+
+impl RawMirroringParams {
+    fn into_params(self) -> Result<MirroringParams> {
+        if !(self.sample_rate >= 0.0 && self.sample_rate <= 1.0) {
+            return Err(ErrorKind::InvalidConfig(
+                "mirroring: sample_rate must be between 0 and 1".into(),
+            ).into());
+        }
+        Ok(MirroringParams {
+            shadow: self.shadow,
+            shadow_reponame: self.shadow_reponame,
+            ssl_common_name: self.ssl_common_name,
+            cert: self.cert,
+            private_key: self.private_key,
+            ca_pem: self.ca_pem,
+            sample_rate: self.sample_rate,
+            compare: self.compare.unwrap_or(false),
+            timeout: Duration::from_secs(self.timeout_secs.unwrap_or(60)),
+            max_request_bytes: self.max_request_bytes.unwrap_or(1024 * 1024),
+            max_compared_bytes: self.max_compared_bytes.unwrap_or(64 * 1024 * 1024),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawBookmarkSnapshotParams {
+    interval_secs: u64,
+    retain: Option<usize>,
+}
+
+impl RawBookmarkSnapshotParams {
+    fn into_params(self) -> Result<BookmarkSnapshotParams> {
+        let retain = self.retain.unwrap_or(24);
+        if self.interval_secs == 0 || retain == 0 {
+            return Err(ErrorKind::InvalidConfig(
+                "bookmark_snapshots: interval_secs and retain must be positive".into(),
+            ).into());
+        }
+        Ok(BookmarkSnapshotParams {
+            interval: Duration::from_secs(self.interval_secs),
+            retain,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawDerivedDataParams {
+    types: Vec<String>,
+    workers: Option<usize>,
+    max_attempts: Option<usize>,
+}
+
+impl RawDerivedDataParams {
+    fn into_params(self) -> Result<DerivedDataParams> {
+        let workers = self.workers.unwrap_or(4);
+        let max_attempts = self.max_attempts.unwrap_or(3);
+        if self.types.is_empty() || workers == 0 || max_attempts == 0 {
+            return Err(ErrorKind::InvalidConfig(
+                "derived_data: types, workers and max_attempts must not be empty or zero".into(),
+            ).into());
+        }
+        Ok(DerivedDataParams {
+            types: self.types,
+            workers,
+            max_attempts,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawFilePrefetchParams {
+    on_gettreepack: Option<bool>,
+    content: Option<bool>,
+    max_content_bytes: Option<u64>,
+}
+
+impl RawFilePrefetchParams {
+    fn into_params(self) -> Result<FilePrefetchParams> {
+        let default = FilePrefetchParams::default();
+        let params = FilePrefetchParams {
+            on_gettreepack: self.on_gettreepack.unwrap_or(default.on_gettreepack),
+            content: self.content.unwrap_or(default.content),
+            max_content_bytes: self.max_content_bytes.unwrap_or(default.max_content_bytes),
+        };
+        if params.content && params.max_content_bytes == 0 {
+            return Err(ErrorKind::InvalidConfig(
+                "file_prefetch: max_content_bytes must be positive to prefetch content".into(),
+            ).into());
+        }
+        Ok(params)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawFetchLimits {
+    getfiles_buffer_size: Option<usize>,
+    gettreepack_buffer_size: Option<usize>,
+    between_buffer_size: Option<usize>,
+}
+
+impl RawFetchLimits {
+    fn into_limits(self) -> Result<FetchLimits> {
+        let default = FetchLimits::default();
+        let limits = FetchLimits {
+            getfiles_buffer_size: self.getfiles_buffer_size
+                .unwrap_or(default.getfiles_buffer_size),
+            gettreepack_buffer_size: self.gettreepack_buffer_size
+                .unwrap_or(default.gettreepack_buffer_size),
+            between_buffer_size: self.between_buffer_size
+                .unwrap_or(default.between_buffer_size),
+        };
+        if limits.getfiles_buffer_size == 0 || limits.gettreepack_buffer_size == 0
+            || limits.between_buffer_size == 0
+        {
+            return Err(ErrorKind::InvalidConfig(
+                "fetch_limits: buffer sizes must be positive".into(),
+            ).into());
+        }
+        Ok(limits)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPathRules {
+    forbid_vcs_components: Option<bool>,
+    forbid_windows_reserved_names: Option<bool>,
+    max_component_length: Option<usize>,
+    max_path_length: Option<usize>,
+    forbidden_trailing_chars: Option<String>,
+    forbid_non_utf8: Option<bool>,
+}
+
+impl RawPathRules {
+    fn into_rules(self) -> Result<PathRules> {
+        if self.max_component_length == Some(0) || self.max_path_length == Some(0) {
+            return Err(ErrorKind::InvalidConfig(
+                "path_rules: max_component_length and max_path_length must be positive".into(),
+            ).into());
+        }
+        Ok(PathRules {
+            forbid_vcs_components: self.forbid_vcs_components.unwrap_or(false),
+            forbid_windows_reserved_names: self.forbid_windows_reserved_names.unwrap_or(false),
+            max_component_length: self.max_component_length,
+            max_path_length: self.max_path_length,
+            forbidden_trailing_chars: self.forbidden_trailing_chars
+                .map(|chars| chars.chars().collect())
+                .unwrap_or_default(),
+            forbid_non_utf8: self.forbid_non_utf8.unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawPullBookmarks {
+    names: Option<Vec<String>>,
+    prefixes: Option<Vec<String>>,
+    publishing: Option<bool>,
+    max_count: Option<usize>,
+    /// Prefixes, with an optional trailing "*", e.g. "scratch/*"
+    exclude_prefixes: Option<Vec<String>>,
+}
+
+impl RawPullBookmarks {
+    /// `publishing` are the bookmarks configured for the repo, which `publishing=true` selects
+    fn into_params(self, publishing: &[BookmarkParams]) -> Result<PullBookmarksParams> {
+        if self.max_count == Some(0) {
+            return Err(ErrorKind::InvalidConfig(
+                "pull_bookmarks: max_count must be positive".into(),
+            ).into());
+        }
+
+        let invalid = |err: Error| ErrorKind::InvalidConfig(format!("pull_bookmarks: {}", err));
+        let filter = match (self.names, self.prefixes, self.publishing) {
+            (None, None, None) => None,
+            (names, prefixes, publishing_only) => {
+                let mut names = names
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|name| Bookmark::new(name).map_err(&invalid))
+                    .collect::<::std::result::Result<Vec<_>, _>>()?;
+                if publishing_only.unwrap_or(false) {
+                    names.extend(publishing.iter().map(|params| params.bookmark.clone()));
+                }
+                let prefixes = prefixes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|prefix| BookmarkPrefix::new(prefix).map_err(&invalid))
+                    .collect::<::std::result::Result<Vec<_>, _>>()?;
+                Some(PullBookmarksFilter { names, prefixes })
+            }
+        };
+
+        let exclude_prefixes = self.exclude_prefixes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|glob| -> Result<BookmarkPrefix> {
+                let prefix = if glob.ends_with('*') {
+                    &glob[..glob.len() - 1]
+                } else {
+                    &glob[..]
+                };
+                // An empty prefix would exclude every bookmark
+                if prefix.is_empty() || prefix.contains('*') {
+                    return Err(ErrorKind::InvalidConfig(format!(
+                        "pull_bookmarks: exclude_prefixes can only have a trailing *, got {:?}",
+                        glob
+                    )).into());
+                }
+                Ok(BookmarkPrefix::new(prefix).map_err(&invalid)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PullBookmarksParams {
+            filter,
+            max_count: self.max_count,
+            exclude_prefixes,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawStoreDbAddresses {
+    bookmarks: Option<String>,
+    changesets: Option<String>,
+    filenodes: Option<String>,
+    bonsai_hg_mapping: Option<String>,
+}
+
+impl RawStoreDbAddresses {
+    fn into_addresses(self) -> StoreDbAddresses {
+        StoreDbAddresses {
+            bookmarks: self.bookmarks,
+            changesets: self.changesets,
+            filenodes: self.filenodes,
+            bonsai_hg_mapping: self.bonsai_hg_mapping,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawBookmarkCreationPolicy {
+    names: Option<Vec<String>>,
+    prefixes: Option<Vec<String>>,
+    allowed_creators: Option<Vec<String>>,
+    require_pushrebase: Option<bool>,
+}
+
+impl RawBookmarkCreationPolicy {
+    fn into_policy(self) -> Result<BookmarkCreationPolicy> {
+        let invalid =
+            |err: Error| ErrorKind::InvalidConfig(format!("bookmark_creation: {}", err));
+        let allowed_names = match (self.names, self.prefixes) {
+            (None, None) => None,
+            (names, prefixes) => {
+                let names = names
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|name| Bookmark::new(name).map_err(&invalid))
+                    .collect::<::std::result::Result<Vec<_>, _>>()?;
+                let prefixes = prefixes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|prefix| BookmarkPrefix::new(prefix).map_err(&invalid))
+                    .collect::<::std::result::Result<Vec<_>, _>>()?;
+                Some(PullBookmarksFilter { names, prefixes })
+            }
+        };
+
+        Ok(BookmarkCreationPolicy {
+            allowed_names,
+            allowed_creators: self.allowed_creators,
+            require_pushrebase: self.require_pushrebase.unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RawScratchRetention {
+    prefix: String,
+    max_age_secs: Option<u64>,
+    max_count_per_user: Option<usize>,
+    excluded: Option<Vec<String>>,
+}
+
+impl RawScratchRetention {
+    fn into_params(self) -> Result<ScratchRetentionParams> {
+        let invalid = |msg: String| {
+            Error::from(ErrorKind::InvalidConfig(format!(
+                "scratch_retention {}: {}",
+                self.prefix, msg
+            )))
+        };
+        if self.prefix.is_empty() {
+            return Err(invalid("prefix must not be empty".into()));
+        }
+        if self.max_age_secs.is_none() && self.max_count_per_user.is_none() {
+            return Err(invalid(
+                "one of max_age_secs and max_count_per_user must be set".into(),
+            ));
+        }
+        if self.max_age_secs == Some(0) || self.max_count_per_user == Some(0) {
+            return Err(invalid(
+                "max_age_secs and max_count_per_user must be positive".into(),
+            ));
+        }
+
+        let prefix = BookmarkPrefix::new(&self.prefix).map_err(|err| invalid(err.to_string()))?;
+        let excluded = self.excluded
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|name| Bookmark::new(name).map_err(|err| invalid(err.to_string())))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ScratchRetentionParams {
+            prefix,
+            max_age: self.max_age_secs.map(Duration::from_secs),
+            max_count_per_user: self.max_count_per_user,
+            excluded,
+        })
+    }
+}
+
+/// Overrides of the default retry policy of a backend, unset fields keep their default values
+#[derive(Clone, Debug, Deserialize)]
+struct RawRetryPolicy {
+    max_attempts: Option<usize>,
+    base_delay_ms: Option<u64>,
+    max_delay_ms: Option<u64>,
+    jitter: Option<bool>,
+}
+
+impl RawRetryPolicy {
+    fn into_policy(self, key: &str, default: RetryPolicy) -> Result<RetryPolicy> {
+        let policy = RetryPolicy {
+            max_attempts: self.max_attempts.unwrap_or(default.max_attempts),
+            base_delay: self.base_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            max_delay: self.max_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_delay),
+            jitter: self.jitter.unwrap_or(default.jitter),
+        };
+        if policy.max_attempts == 0 {
+            return Err(ErrorKind::InvalidConfig(format!(
+                "{}: max_attempts must be positive",
+                key
+            )).into());
+        }
+        if policy.base_delay > policy.max_delay {
+            return Err(ErrorKind::InvalidConfig(format!(
+                "{}: base_delay_ms must not be larger than max_delay_ms",
+                key
+            )).into());
+        }
+        Ok(policy)
+    }
 }
 
 #[cfg(test)]
@@ -511,9 +2195,28 @@ mod test {
             generation_cache_size=1048576
             repoid=0
             scuba_table="scuba_table"
+            strict_wireproto_args=true
+            deterministic_getbundle=true
+            push_journal=true
+            cross_repo_index=true
+            always_hot=true
+            readonly=true
+            allowed_identities=["alice", "bob"]
+            bookmark_move_identities=["coordinator"]
+            aliases=["fbsource_old", "fbs"]
+            [alias_deprecation_notices]
+            fbsource_old="fbsource_old was renamed to fbsource"
             [cache_warmup]
             bookmark="master"
             commit_limit=100
+            fetch_concurrency=10
+            max_failure_ratio=0.5
+            [cache_warmup.fetch_retry]
+            max_attempts=5
+            [cache_warmup.tasks.manifests]
+            time_budget_secs=600
+            [cache_warmup.tasks.changesets]
+            enabled=false
             [[bookmarks]]
             name="master"
             [[bookmarks.hooks]]
@@ -525,20 +2228,83 @@ mod test {
             path="common/hooks/hook1.lua"
             hook_type="PerAddedOrModifiedFile"
             bypass_commit_string="@allow_hook1"
+            content_only=true
+            severity="warn"
             [[hooks]]
             name="hook2"
             path="./hooks/hook2.lua"
             hook_type="PerChangeset"
             bypass_pushvar="pushvar=pushval"
+            [hooks.hook_config]
+            max_files=100
+            allowed_authors=["alice", "bob"]
+            [hook_health]
+            window=20
+            degraded_policy="fail_open"
             [pushrebase]
             rewritedates = false
             recursion_limit = 1024
+            [pushrebase.message_normalization]
+            trailing_newline = true
+            [push_limits]
+            max_changesets = 1000
+            max_commit_message_bytes = 65536
+            max_directory_fanout = 20000
+            warn_directory_fanout = 10000
+            [bookmark_snapshots]
+            interval_secs = 3600
+            [derived_data]
+            types = ["changed_files", "manifest_stats"]
+            workers = 2
+            [file_prefetch]
+            on_gettreepack = true
+            content = true
+            max_content_bytes = 10000
+            [fetch_limits]
+            getfiles_buffer_size = 20
+            between_buffer_size = 4
+            [path_rules]
+            forbid_vcs_components = true
+            max_component_length = 255
+            forbidden_trailing_chars = ". "
+            [pull_bookmarks]
+            names = ["stable"]
+            prefixes = ["release/"]
+            publishing = true
+            max_count = 1000
+            exclude_prefixes = ["scratch/*"]
+            [bookmark_creation]
+            prefixes = ["release/"]
+            allowed_creators = ["svcscm"]
+            require_pushrebase = true
+            [[scratch_retention]]
+            prefix = "scratch/"
+            max_age_secs = 2592000
+            max_count_per_user = 100
+            excluded = ["scratch/svcscm/keep"]
+            [mirroring]
+            shadow="shadow.example.com:8367"
+            shadow_reponame="fbsource"
+            ssl_common_name="shadow.example.com"
+            cert="/etc/certs/client.crt"
+            private_key="/etc/certs/client.key"
+            ca_pem="/etc/certs/ca.pem"
+            sample_rate=0.05
+            compare=true
         "#;
         let www_content = r#"
             path="/tmp/www"
             repotype="revlog"
             repoid=1
             scuba_table="scuba_table"
+            [write_forwarding]
+            primary="primary.example.com:8367"
+            primary_reponame="www"
+            ssl_common_name="primary.example.com"
+            cert="/etc/certs/client.crt"
+            private_key="/etc/certs/client.key"
+            ca_pem="/etc/certs/ca.pem"
+            timeout_secs=60
         "#;
 
         let paths = btreemap! {
@@ -565,6 +2331,22 @@ mod test {
                 cache_warmup: Some(CacheWarmupParams {
                     bookmark: Bookmark::new("master").unwrap(),
                     commit_limit: 100,
+                    tasks: btreemap! {
+                        "manifests".to_string() => WarmupTaskParams {
+                            enabled: true,
+                            time_budget: Some(Duration::from_secs(600)),
+                        },
+                        "changesets".to_string() => WarmupTaskParams {
+                            enabled: false,
+                            time_budget: None,
+                        },
+                    },
+                    fetch_concurrency: 10,
+                    fetch_retry: RetryPolicy {
+                        max_attempts: 5,
+                        ..default_warmup_fetch_retry_policy()
+                    },
+                    max_failure_ratio: 0.5,
                 }),
                 bookmarks: Some(vec![
                     BookmarkParams {
@@ -578,6 +2360,11 @@ mod test {
                         code: "this is hook1".to_string(),
                         hook_type: HookType::PerAddedOrModifiedFile,
                         bypass: Some(HookBypass::CommitMessage("@allow_hook1".into())),
+                        config: None,
+                        content_only: true,
+                        builtin: None,
+                        severity: HookSeverity::Warn,
+                        bookmarks: HookBookmarks::default(),
                     },
                     HookParams {
                         name: "hook2".to_string(),
@@ -586,13 +2373,128 @@ mod test {
                         bypass: Some(HookBypass::Pushvar {
                             name: "pushvar".into(),
                             value: "pushval".into(),
+                            identities: None,
                         }),
+                        config: Some(toml::Value::Table(btreemap! {
+                            "max_files".to_string() => toml::Value::Integer(100),
+                            "allowed_authors".to_string() => toml::Value::Array(vec![
+                                toml::Value::String("alice".into()),
+                                toml::Value::String("bob".into()),
+                            ]),
+                        })),
+                        content_only: false,
+                        builtin: None,
+                        severity: HookSeverity::Block,
+                        bookmarks: HookBookmarks::default(),
                     },
                 ]),
+                hook_health: HookHealthParams {
+                    window: 20,
+                    degraded_policy: HookDegradedPolicy::FailOpen,
+                    ..Default::default()
+                },
                 pushrebase: PushrebaseParams {
-                    rewritedates: false,
+                    date_policy: PushrebaseDatePolicy::Preserve,
                     recursion_limit: 1024,
+                    message_normalization: CommitMessageNormalization {
+                        trailing_newline: true,
+                        ..Default::default()
+                    },
+                },
+                push_limits: PushLimits {
+                    max_changesets: 1000,
+                    max_commit_message_bytes: 65536,
+                    max_directory_fanout: 20000,
+                    warn_directory_fanout: 10000,
+                    ..Default::default()
+                },
+                push_advisory: None,
+                write_forwarding: None,
+                mirroring: Some(MirroringParams {
+                    shadow: "shadow.example.com:8367".to_string(),
+                    shadow_reponame: "fbsource".to_string(),
+                    ssl_common_name: "shadow.example.com".to_string(),
+                    cert: "/etc/certs/client.crt".to_string(),
+                    private_key: "/etc/certs/client.key".to_string(),
+                    ca_pem: "/etc/certs/ca.pem".to_string(),
+                    sample_rate: 0.05,
+                    compare: true,
+                    timeout: Duration::from_secs(60),
+                    max_request_bytes: 1024 * 1024,
+                    max_compared_bytes: 64 * 1024 * 1024,
+                }),
+                bookmark_snapshots: Some(BookmarkSnapshotParams {
+                    interval: Duration::from_secs(3600),
+                    retain: 24,
+                }),
+                derived_data: Some(DerivedDataParams {
+                    types: vec!["changed_files".to_string(), "manifest_stats".to_string()],
+                    workers: 2,
+                    max_attempts: 3,
+                }),
+                strict_wireproto_args: true,
+                deterministic_getbundle: true,
+                file_prefetch: FilePrefetchParams {
+                    on_gettreepack: true,
+                    content: true,
+                    max_content_bytes: 10000,
+                },
+                fetch_limits: FetchLimits {
+                    getfiles_buffer_size: 20,
+                    between_buffer_size: 4,
+                    ..Default::default()
+                },
+                path_rules: PathRules {
+                    forbid_vcs_components: true,
+                    max_component_length: Some(255),
+                    forbidden_trailing_chars: vec!['.', ' '],
+                    ..Default::default()
+                },
+                pull_bookmarks: PullBookmarksParams {
+                    filter: Some(PullBookmarksFilter {
+                        names: vec![
+                            Bookmark::new("stable").unwrap(),
+                            Bookmark::new("master").unwrap(),
+                        ],
+                        prefixes: vec![BookmarkPrefix::new("release/").unwrap()],
+                    }),
+                    max_count: Some(1000),
+                    exclude_prefixes: vec![BookmarkPrefix::new("scratch/").unwrap()],
                 },
+                bookmark_creation: BookmarkCreationPolicy {
+                    allowed_names: Some(PullBookmarksFilter {
+                        names: vec![],
+                        prefixes: vec![BookmarkPrefix::new("release/").unwrap()],
+                    }),
+                    allowed_creators: Some(vec!["svcscm".to_string()]),
+                    require_pushrebase: true,
+                },
+                scratch_retention: vec![
+                    ScratchRetentionParams {
+                        prefix: BookmarkPrefix::new("scratch/").unwrap(),
+                        max_age: Some(Duration::from_secs(2592000)),
+                        max_count_per_user: Some(100),
+                        excluded: vec![Bookmark::new("scratch/svcscm/keep").unwrap()],
+                    },
+                ],
+                push_journal: true,
+                cross_repo_index: true,
+                always_hot: true,
+                aliases: vec![
+                    RepoAlias {
+                        name: "fbsource_old".to_string(),
+                        deprecation_notice: Some(
+                            "fbsource_old was renamed to fbsource".to_string(),
+                        ),
+                    },
+                    RepoAlias {
+                        name: "fbs".to_string(),
+                        deprecation_notice: None,
+                    },
+                ],
+                readonly: true,
+                allowed_identities: Some(vec!["alice".to_string(), "bob".to_string()]),
+                bookmark_move_identities: vec!["coordinator".to_string()],
             },
         );
         repos.insert(
@@ -606,7 +2508,39 @@ mod test {
                 cache_warmup: None,
                 bookmarks: None,
                 hooks: None,
+                hook_health: Default::default(),
                 pushrebase: Default::default(),
+                push_limits: Default::default(),
+                push_advisory: None,
+                write_forwarding: Some(WriteForwardingParams {
+                    primary: "primary.example.com:8367".to_string(),
+                    primary_reponame: "www".to_string(),
+                    ssl_common_name: "primary.example.com".to_string(),
+                    cert: "/etc/certs/client.crt".to_string(),
+                    private_key: "/etc/certs/client.key".to_string(),
+                    ca_pem: "/etc/certs/ca.pem".to_string(),
+                    timeout: Duration::from_secs(60),
+                    breaker_failures: 5,
+                    breaker_cooldown: Duration::from_secs(30),
+                }),
+                mirroring: None,
+                bookmark_snapshots: None,
+                derived_data: None,
+                strict_wireproto_args: false,
+                deterministic_getbundle: false,
+                file_prefetch: Default::default(),
+                fetch_limits: Default::default(),
+                path_rules: Default::default(),
+                pull_bookmarks: Default::default(),
+                bookmark_creation: Default::default(),
+                scratch_retention: vec![],
+                push_journal: false,
+                cross_repo_index: false,
+                always_hot: false,
+                aliases: vec![],
+                readonly: false,
+                allowed_identities: None,
+                bookmark_move_identities: vec![],
             },
         );
         assert_eq!(
@@ -619,13 +2553,16 @@ mod test {
     }
 
     #[test]
-    fn test_broken_config() {
-        // Two bypasses for one hook
+    fn test_read_manifest_with_defaults() {
         let hook1_content = "this is hook1";
-        let content = r#"
-            path="/tmp/fbsource"
+        let hook2_content = "this is hook2";
+        let defaults_content = r#"
             repotype="blob:rocks"
-            repoid=0
+            generation_cache_size=1048576
+            scuba_table="scuba_table"
+            [pushrebase]
+            rewritedates=false
+            recursion_limit=1024
             [[bookmarks]]
             name="master"
             [[bookmarks.hooks]]
@@ -634,8 +2571,732 @@ mod test {
             name="hook1"
             path="common/hooks/hook1.lua"
             hook_type="PerAddedOrModifiedFile"
-            bypass_commit_string="@allow_hook1"
-            bypass_pushvar="var=val"
+        "#;
+        let fbsource_content = r#"
+            path="/tmp/fbsource"
+            repoid=0
+            [merge_strategy]
+            hooks="append"
+            [pushrebase]
+            recursion_limit=16
+            [[hooks]]
+            name="hook2"
+            path="common/hooks/hook2.lua"
+            hook_type="PerChangeset"
+        "#;
+        let www_content = r#"
+            path="/tmp/www"
+            repotype="revlog"
+            repoid=1
+            [[bookmarks]]
+            name="stable"
+        "#;
+
+        let paths = btreemap! {
+            "defaults.toml" => (FileType::Regular, defaults_content),
+            "common/hooks/hook1.lua" => (FileType::Regular, hook1_content),
+            "common/hooks/hook2.lua" => (FileType::Regular, hook2_content),
+            "repos/fbsource/server.toml" => (FileType::Regular, fbsource_content),
+            "repos/www/server.toml" => (FileType::Regular, www_content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        let repoconfig = RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .expect("failed to read config from manifest");
+
+        let hook1 = HookParams {
+            name: "hook1".to_string(),
+            code: "this is hook1".to_string(),
+            hook_type: HookType::PerAddedOrModifiedFile,
+            bypass: None,
+            config: None,
+            content_only: false,
+            builtin: None,
+            severity: HookSeverity::Block,
+            bookmarks: HookBookmarks::default(),
+        };
+        let hook2 = HookParams {
+            name: "hook2".to_string(),
+            code: "this is hook2".to_string(),
+            hook_type: HookType::PerChangeset,
+            bypass: None,
+            config: None,
+            content_only: false,
+            builtin: None,
+            severity: HookSeverity::Block,
+            bookmarks: HookBookmarks::default(),
+        };
+
+        let fbsource = repoconfig.repos.get("fbsource").expect("fbsource is missing");
+        assert_eq!(fbsource.repotype, RepoType::BlobRocks("/tmp/fbsource".into()));
+        assert_eq!(fbsource.generation_cache_size, 1024 * 1024);
+        assert_eq!(fbsource.scuba_table, Some("scuba_table".to_string()));
+        assert_eq!(
+            fbsource.pushrebase,
+            PushrebaseParams {
+                date_policy: PushrebaseDatePolicy::Preserve,
+                recursion_limit: 16,
+                message_normalization: Default::default(),
+            }
+        );
+        assert_eq!(fbsource.hooks, Some(vec![hook1.clone(), hook2]));
+        assert_eq!(
+            fbsource.bookmarks,
+            Some(vec![
+                BookmarkParams {
+                    bookmark: Bookmark::new("master").unwrap(),
+                    hooks: Some(vec!["hook1".to_string()]),
+                },
+            ])
+        );
+
+        let www = repoconfig.repos.get("www").expect("www is missing");
+        assert_eq!(www.repotype, RepoType::Revlog("/tmp/www".into()));
+        assert_eq!(www.hooks, Some(vec![hook1]));
+        // Arrays are replaced by default
+        assert_eq!(
+            www.bookmarks,
+            Some(vec![
+                BookmarkParams {
+                    bookmark: Bookmark::new("stable").unwrap(),
+                    hooks: None,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_builtin_hook() {
+        let read = |hook: &str| {
+            let content = format!(
+                r#"
+                path="/tmp/fbsource"
+                repotype="revlog"
+                repoid=0
+                [[hooks]]
+                name="signed"
+                hook_type="PerChangeset"
+                {}
+            "#,
+                hook
+            );
+            let paths = btreemap! {
+                "common/hooks/hook1.lua" => (FileType::Regular, "this is hook1"),
+                "repos/fbsource/server.toml" => (FileType::Regular, content.as_str()),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest).wait()
+        };
+
+        let repoconfig = read(
+            r#"
+                builtin="verify_commit_signature"
+                bypass_pushvar="UNSIGNED=true"
+                bypass_identities=["alice", "bob"]
+                [hooks.hook_config]
+                extra_key="sig"
+            "#,
+        ).expect("failed to read config from manifest");
+        assert_eq!(
+            repoconfig.repos["fbsource"].hooks,
+            Some(vec![
+                HookParams {
+                    name: "signed".to_string(),
+                    code: "".to_string(),
+                    hook_type: HookType::PerChangeset,
+                    bypass: Some(HookBypass::Pushvar {
+                        name: "UNSIGNED".into(),
+                        value: "true".into(),
+                        identities: Some(vec!["alice".to_string(), "bob".to_string()]),
+                    }),
+                    config: Some(toml::Value::Table(btreemap! {
+                        "extra_key".to_string() => toml::Value::String("sig".into()),
+                    })),
+                    content_only: false,
+                    builtin: Some("verify_commit_signature".to_string()),
+                    severity: HookSeverity::Block,
+                    bookmarks: HookBookmarks::default(),
+                },
+            ])
+        );
+
+        // Exactly one of path and builtin must be set
+        assert!(read("").is_err());
+        assert!(
+            read(
+                r#"
+                builtin="verify_commit_signature"
+                path="common/hooks/hook1.lua"
+            "#
+            ).is_err()
+        );
+
+        // Identities restrict a bypass pushvar, so they need one
+        assert!(
+            read(
+                r#"
+                builtin="verify_commit_signature"
+                bypass_identities=["alice"]
+            "#
+            ).is_err()
+        );
+        assert!(
+            read(
+                r#"
+                builtin="verify_commit_signature"
+                bypass_commit_string="@unsigned"
+                bypass_identities=["alice"]
+            "#
+            ).is_err()
+        );
+    }
+
+    #[test]
+    fn test_hook_bookmarks() {
+        let read = |bookmarks: &str| {
+            let content = format!(
+                r#"
+                path="/tmp/fbsource"
+                repotype="revlog"
+                repoid=0
+                [[hooks]]
+                name="hook1"
+                path="common/hooks/hook1.lua"
+                hook_type="PerChangeset"
+                {}
+            "#,
+                bookmarks
+            );
+            let paths = btreemap! {
+                "common/hooks/hook1.lua" => (FileType::Regular, "this is hook1"),
+                "repos/fbsource/server.toml" => (FileType::Regular, content.as_str()),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest)
+                .wait()
+                .map(|repoconfig| repoconfig.repos["fbsource"].hooks.clone().unwrap()[0].clone())
+        };
+
+        let hook = read(
+            r#"
+                bookmarks=["master", "stable"]
+                bookmark_regexes=["release/.*"]
+            "#,
+        ).expect("failed to read config from manifest");
+        assert_eq!(
+            hook.bookmarks,
+            HookBookmarks {
+                names: vec![
+                    Bookmark::new("master").unwrap(),
+                    Bookmark::new("stable").unwrap(),
+                ],
+                regexes: vec!["release/.*".to_string()],
+            }
+        );
+        let regexes = hook.bookmarks.compile_regexes().unwrap();
+        assert!(regexes[0].is_match("release/1.0"));
+        // Regexes match whole names
+        assert!(!regexes[0].is_match("scratch/release/1.0"));
+
+        assert_eq!(read("").unwrap().bookmarks, HookBookmarks::default());
+        assert!(read(r#"bookmark_regexes=["release/(.*"]"#).is_err());
+    }
+
+    #[test]
+    fn test_repo_aliases() {
+        let read = |fbsource: &str, www: &str| {
+            let paths = btreemap! {
+                "repos/fbsource/server.toml" => (FileType::Regular, fbsource),
+                "repos/www/server.toml" => (FileType::Regular, www),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest).wait()
+        };
+        let www = r#"
+            path="/tmp/www"
+            repotype="revlog"
+            repoid=1
+            aliases=["web"]
+        "#;
+
+        let repoconfig = read(
+            r#"
+            path="/tmp/fbsource"
+            repotype="revlog"
+            repoid=0
+        "#,
+            www,
+        ).expect("failed to read config from manifest");
+        assert_eq!(repoconfig.repos["fbsource"].aliases, vec![]);
+        assert_eq!(
+            repoconfig.repos["www"].aliases,
+            vec![
+                RepoAlias {
+                    name: "web".to_string(),
+                    deprecation_notice: None,
+                },
+            ]
+        );
+
+        // A name can only be claimed by one repo
+        for fbsource_aliases in &[r#"["web"]"#, r#"["www"]"#, r#"["fbs", "fbs"]"#] {
+            let fbsource = format!(
+                r#"
+                path="/tmp/fbsource"
+                repotype="revlog"
+                repoid=0
+                aliases={}
+            "#,
+                fbsource_aliases
+            );
+            assert!(read(&fbsource, www).is_err(), "{}", fbsource_aliases);
+        }
+        match read(
+            r#"
+            path="/tmp/fbsource"
+            repotype="revlog"
+            repoid=0
+            aliases=["web"]
+        "#,
+            www,
+        ).unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::DuplicateRepoName(name, ..)) => assert_eq!(name, "web"),
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        // Deprecation notices are only for aliases
+        let fbsource = r#"
+            path="/tmp/fbsource"
+            repotype="revlog"
+            repoid=0
+            aliases=["fbs"]
+            [alias_deprecation_notices]
+            fbsource="use fbs"
+        "#;
+        match read(fbsource, www).unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+
+    #[test]
+    fn test_defaults_type_mismatch() {
+        let defaults_content = r#"
+            repotype="blob:rocks"
+            generation_cache_size=1048576
+        "#;
+        let content = r#"
+            path="/tmp/fbsource"
+            repoid=0
+            generation_cache_size="big"
+        "#;
+
+        let paths = btreemap! {
+            "defaults.toml" => (FileType::Regular, defaults_content),
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        let res = RepoConfigs::read_manifest(&root_manifest).wait();
+        match res.unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::DefaultsTypeMismatch(repo, key, ..)) => {
+                assert_eq!(repo, "fbsource");
+                assert_eq!(key, "generation_cache_size");
+            }
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+
+    #[test]
+    fn test_retry_config() {
+        let content = r#"
+            repotype="blob:testmanifold"
+            repoid=0
+            manifold_bucket="bucket"
+            db_address="db"
+            [sql_retry]
+            max_attempts=5
+            jitter=false
+        "#;
+
+        let paths = btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        let repoconfig = RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .expect("failed to read config from manifest");
+
+        let fbsource = repoconfig.repos.get("fbsource").expect("fbsource is missing");
+        let default_sql_retry = default_sql_retry_policy();
+        assert_eq!(
+            fbsource.repotype,
+            RepoType::BlobManifold(ManifoldArgs {
+                bucket: "bucket".to_string(),
+                prefix: "".to_string(),
+                db_address: "db".to_string(),
+                store_db_addresses: Default::default(),
+                blobstore_retry: default_blobstore_retry_policy(),
+                sql_retry: RetryPolicy {
+                    max_attempts: 5,
+                    jitter: false,
+                    ..default_sql_retry
+                },
+            })
+        );
+
+        let content = r#"
+            repotype="blob:testmanifold"
+            repoid=0
+            manifold_bucket="bucket"
+            db_address="db"
+            [blobstore_retry]
+            max_attempts=0
+        "#;
+
+        let paths = btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+
+    #[test]
+    fn test_store_db_addresses_config() {
+        let read = |defaults: Option<&str>, content: &str| {
+            let mut paths = btreemap! {
+                "repos/fbsource/server.toml" => (FileType::Regular, content),
+            };
+            if let Some(defaults) = defaults {
+                paths.insert("defaults.toml", (FileType::Regular, defaults));
+            }
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest).wait()
+        };
+        let manifold_args = |config: &RepoConfigs| match config.repos["fbsource"].repotype {
+            RepoType::BlobManifold(ref args) => args.clone(),
+            ref repotype => panic!("unexpected repotype {:?}", repotype),
+        };
+
+        // The main address of the repo comes from the defaults, and filenodes have their own
+        let defaults = r#"
+            db_address="xdb.main"
+        "#;
+        let content = r#"
+            repotype="blob:testmanifold"
+            repoid=0
+            manifold_bucket="bucket"
+            [store_db_addresses]
+            filenodes="xdb.filenodes"
+        "#;
+        let args = manifold_args(&read(Some(defaults), content).unwrap());
+        assert_eq!(args.db_address, "xdb.main");
+        assert_eq!(
+            args.store_db_addresses,
+            StoreDbAddresses {
+                filenodes: Some("xdb.filenodes".to_string()),
+                ..Default::default()
+            }
+        );
+
+        // Each store can have its own address, and the repo's address overrides the default
+        let content = r#"
+            repotype="blob:testmanifold"
+            repoid=0
+            manifold_bucket="bucket"
+            db_address="xdb.repo"
+            [store_db_addresses]
+            bookmarks="xdb.bookmarks"
+            changesets="xdb.changesets"
+            filenodes="xdb.filenodes"
+            bonsai_hg_mapping="xdb.mapping"
+        "#;
+        let args = manifold_args(&read(Some(defaults), content).unwrap());
+        assert_eq!(args.db_address, "xdb.repo");
+        assert_eq!(
+            args.store_db_addresses,
+            StoreDbAddresses {
+                bookmarks: Some("xdb.bookmarks".to_string()),
+                changesets: Some("xdb.changesets".to_string()),
+                filenodes: Some("xdb.filenodes".to_string()),
+                bonsai_hg_mapping: Some("xdb.mapping".to_string()),
+            }
+        );
+
+        // A manifold repo needs a main address
+        let content = r#"
+            repotype="blob:testmanifold"
+            repoid=0
+            manifold_bucket="bucket"
+        "#;
+        match read(None, content).unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        // Other repos don't have SQL stores to move
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [store_db_addresses]
+            filenodes="xdb.filenodes"
+        "#;
+        match read(None, content).unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+
+    #[test]
+    fn test_pull_bookmarks_config() {
+        let read = |content: &str| {
+            let paths = btreemap! {
+                "repos/fbsource/server.toml" => (FileType::Regular, content),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest)
+                .wait()
+                .map(|mut configs| configs.repos.remove("fbsource").unwrap().pull_bookmarks)
+        };
+
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [pull_bookmarks]
+            max_count=10
+        "#;
+        assert_eq!(
+            read(content).unwrap(),
+            PullBookmarksParams {
+                filter: None,
+                max_count: Some(10),
+                exclude_prefixes: vec![],
+            }
+        );
+
+        // publishing=true without configured bookmarks selects nothing
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [pull_bookmarks]
+            publishing=true
+        "#;
+        assert_eq!(
+            read(content).unwrap(),
+            PullBookmarksParams {
+                filter: Some(PullBookmarksFilter::default()),
+                max_count: None,
+                exclude_prefixes: vec![],
+            }
+        );
+
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [pull_bookmarks]
+            max_count=0
+        "#;
+        match read(content).unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [pull_bookmarks]
+            exclude_prefixes=["scratch/*", "infinitepush/"]
+        "#;
+        let params = read(content).unwrap();
+        assert_eq!(
+            params.exclude_prefixes,
+            vec![
+                BookmarkPrefix::new("scratch/").unwrap(),
+                BookmarkPrefix::new("infinitepush/").unwrap(),
+            ]
+        );
+        assert_eq!(params.filter, None);
+        assert!(params.excludes(&Bookmark::new("scratch/alice/feature").unwrap()));
+        assert!(!params.excludes(&Bookmark::new("master").unwrap()));
+
+        for glob in &["*", "scratch/*/feature"] {
+            let content = format!(
+                r#"
+                path="/tmp/fbsource"
+                repotype="blob:rocks"
+                repoid=0
+                [pull_bookmarks]
+                exclude_prefixes=["{}"]
+                "#,
+                glob
+            );
+            match read(&content).unwrap_err().downcast::<ErrorKind>() {
+                Ok(ErrorKind::InvalidConfig(_)) => {}
+                _ => assert!(false, "Unexpected err type"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_scratch_retention_config() {
+        let read = |content: &str| {
+            let paths = btreemap! {
+                "repos/fbsource/server.toml" => (FileType::Regular, content),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest)
+                .wait()
+                .map(|mut configs| configs.repos.remove("fbsource").unwrap().scratch_retention)
+        };
+
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[scratch_retention]]
+            prefix="scratch/"
+            max_count_per_user=10
+            [[scratch_retention]]
+            prefix="ci/"
+            max_age_secs=86400
+        "#;
+        assert_eq!(
+            read(content).unwrap(),
+            vec![
+                ScratchRetentionParams {
+                    prefix: BookmarkPrefix::new("scratch/").unwrap(),
+                    max_age: None,
+                    max_count_per_user: Some(10),
+                    excluded: vec![],
+                },
+                ScratchRetentionParams {
+                    prefix: BookmarkPrefix::new("ci/").unwrap(),
+                    max_age: Some(Duration::from_secs(86400)),
+                    max_count_per_user: None,
+                    excluded: vec![],
+                },
+            ]
+        );
+
+        for retention in &[
+            "prefix=\"scratch/\"",
+            "prefix=\"\"\nmax_age_secs=10",
+            "prefix=\"scratch/\"\nmax_age_secs=0",
+            "prefix=\"scratch/\"\nmax_count_per_user=0",
+        ] {
+            let content = format!(
+                "path=\"/tmp/fbsource\"\nrepotype=\"blob:rocks\"\nrepoid=0\n\
+                 [[scratch_retention]]\n{}\n",
+                retention
+            );
+            match read(&content).unwrap_err().downcast::<ErrorKind>() {
+                Ok(ErrorKind::InvalidConfig(_)) => {}
+                _ => assert!(false, "Unexpected err type for {}", retention),
+            };
+        }
+    }
+
+    #[test]
+    fn test_push_advisory_config() {
+        let read = |content: &str| {
+            let paths = btreemap! {
+                "repos/fbsource/server.toml" => (FileType::Regular, content),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest)
+                .wait()
+                .map(|mut configs| configs.repos.remove("fbsource").unwrap().push_advisory)
+        };
+
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [push_advisory]
+            template="{short_hash} landed on {bookmark}: https://example.com/{reponame}/{hash} {{}}"
+        "#;
+        let params = read(content).unwrap().expect("push_advisory is missing");
+        assert_eq!(params.max_listed_commits, DEFAULT_PUSH_ADVISORY_LISTED_COMMITS);
+        let rendered = params.template.render(|placeholder| match placeholder {
+            PushAdvisoryPlaceholder::Hash => "1234567890abcdef".to_string(),
+            PushAdvisoryPlaceholder::ShortHash => "123456".to_string(),
+            PushAdvisoryPlaceholder::Bookmark => "master".to_string(),
+            PushAdvisoryPlaceholder::Reponame => "fbsource".to_string(),
+        });
+        assert_eq!(
+            rendered,
+            "123456 landed on master: https://example.com/fbsource/1234567890abcdef {}"
+        );
+
+        let invalid = |template: &str, max_listed_commits: usize| {
+            let content = format!(
+                r#"
+                path="/tmp/fbsource"
+                repotype="blob:rocks"
+                repoid=0
+                [push_advisory]
+                template="{}"
+                max_listed_commits={}
+                "#,
+                template, max_listed_commits
+            );
+            match read(&content).unwrap_err().downcast::<ErrorKind>() {
+                Ok(ErrorKind::InvalidConfig(msg)) => msg,
+                _ => panic!("Unexpected err type"),
+            }
+        };
+        assert!(invalid("landed {hash} by {author}", 5).contains("unknown placeholder {author}"));
+        assert!(invalid("landed {hash", 5).contains("unclosed {"));
+        assert!(invalid("landed } {hash}", 5).contains("unmatched }"));
+        assert!(invalid("{hash}", 0).contains("max_listed_commits"));
+        let long = "x".repeat(MAX_PUSH_ADVISORY_TEMPLATE_BYTES + 1);
+        assert!(invalid(&long, 5).contains("at most 1024 bytes"));
+    }
+
+    #[test]
+    fn test_pull_bookmarks_filter() {
+        let filter = PullBookmarksFilter {
+            names: vec![Bookmark::new("master").unwrap()],
+            prefixes: vec![BookmarkPrefix::new("release/").unwrap()],
+        };
+        assert!(filter.matches(&Bookmark::new("master").unwrap()));
+        assert!(filter.matches(&Bookmark::new("release/1.0").unwrap()));
+        assert!(!filter.matches(&Bookmark::new("master2").unwrap()));
+        assert!(!filter.matches(&Bookmark::new("release").unwrap()));
+        assert!(!filter.matches(&Bookmark::new("scratch/release/1.0").unwrap()));
+    }
+
+    #[test]
+    fn test_broken_config() {
+        // Two bypasses for one hook
+        let hook1_content = "this is hook1";
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[bookmarks]]
+            name="master"
+            [[bookmarks.hooks]]
+            hook_name="hook1"
+            [[hooks]]
+            name="hook1"
+            path="common/hooks/hook1.lua"
+            hook_type="PerAddedOrModifiedFile"
+            bypass_commit_string="@allow_hook1"
+            bypass_pushvar="var=val"
         "#;
 
         let paths = btreemap! {
@@ -670,5 +3331,311 @@ mod test {
         let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
         let res = RepoConfigs::read_manifest(&root_manifest).wait();
         assert!(res.is_err());
+
+        // Unknown severity
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[hooks]]
+            name="hook1"
+            path="common/hooks/hook1.lua"
+            hook_type="PerAddedOrModifiedFile"
+            severity="info"
+        "#;
+
+        let paths = btreemap! {
+            "common/hooks/hook1.lua" => (FileType::Regular, hook1_content),
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        // hook_config is not a table
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[hooks]]
+            name="hook1"
+            path="common/hooks/hook1.lua"
+            hook_type="PerAddedOrModifiedFile"
+            hook_config=5
+        "#;
+
+        let paths = btreemap! {
+            "common/hooks/hook1.lua" => (FileType::Regular, hook1_content),
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        let res = RepoConfigs::read_manifest(&root_manifest).wait();
+        assert!(res.is_err());
+
+        // hook_config is too large
+        let content = format!(
+            r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[hooks]]
+            name="hook1"
+            path="common/hooks/hook1.lua"
+            hook_type="PerAddedOrModifiedFile"
+            [hooks.hook_config]
+            big="{}"
+        "#,
+            "a".repeat(MAX_HOOK_CONFIG_SIZE)
+        );
+
+        let paths = btreemap! {
+            "common/hooks/hook1.lua" => (FileType::Regular, Bytes::from(hook1_content)),
+            "repos/fbsource/server.toml" => (FileType::Regular, Bytes::from(content)),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidHookConfig(hook, _)) => assert_eq!(hook, "hook1"),
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        // A readonly repo can't forward writes
+        let content = r#"
+            path="/tmp/www"
+            repotype="revlog"
+            repoid=1
+            readonly=true
+            [write_forwarding]
+            primary="primary.example.com:8367"
+            primary_reponame="www"
+            ssl_common_name="primary.example.com"
+            cert="/etc/certs/client.crt"
+            private_key="/etc/certs/client.key"
+            ca_pem="/etc/certs/ca.pem"
+        "#;
+
+        let paths = btreemap! {
+            "repos/www/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        // Unknown degraded mode of hooks
+        let content = r#"
+            path="/tmp/www"
+            repotype="revlog"
+            repoid=1
+            [hook_health]
+            degraded_policy="ignore"
+        "#;
+
+        let paths = btreemap! {
+            "repos/www/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        // A sample rate that is not a fraction
+        let content = r#"
+            path="/tmp/www"
+            repotype="revlog"
+            repoid=1
+            [mirroring]
+            shadow="shadow.example.com:8367"
+            shadow_reponame="www"
+            ssl_common_name="shadow.example.com"
+            cert="/etc/certs/client.crt"
+            private_key="/etc/certs/client.key"
+            ca_pem="/etc/certs/ca.pem"
+            sample_rate=5.0
+        "#;
+
+        let paths = btreemap! {
+            "repos/www/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+
+    #[test]
+    fn test_directory_fanout_warning_over_limit() {
+        let content = r#"
+            path="/tmp/www"
+            repotype="revlog"
+            repoid=1
+            [push_limits]
+            max_directory_fanout = 1000
+            warn_directory_fanout = 2000
+        "#;
+
+        let paths = btreemap! {
+            "repos/www/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+
+    #[test]
+    fn test_derived_data_without_types() {
+        let content = r#"
+            path="/tmp/www"
+            repotype="revlog"
+            repoid=1
+            [derived_data]
+            types = []
+        "#;
+
+        let paths = btreemap! {
+            "repos/www/server.toml" => (FileType::Regular, content),
+        };
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        match RepoConfigs::read_manifest(&root_manifest)
+            .wait()
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::InvalidConfig(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+    }
+
+    #[test]
+    fn test_pushrebase_date_policy() {
+        let read_policy = |pushrebase: &str| {
+            let content = format!(
+                r#"
+                path="/tmp/www"
+                repotype="revlog"
+                repoid=1
+                [pushrebase]
+                {}
+                "#,
+                pushrebase
+            );
+            let paths = btreemap! {
+                "repos/www/server.toml" => (FileType::Regular, content.as_str()),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest)
+                .wait()
+                .map(|configs| configs.repos["www"].pushrebase.date_policy)
+        };
+
+        assert_eq!(read_policy("").unwrap(), PushrebaseDatePolicy::Preserve);
+        assert_eq!(
+            read_policy("date_policy=\"clamp\"").unwrap(),
+            PushrebaseDatePolicy::Clamp
+        );
+        assert_eq!(
+            read_policy("rewritedates=true").unwrap(),
+            PushrebaseDatePolicy::ServerTime
+        );
+        for invalid in &[
+            "date_policy=\"latest\"",
+            "date_policy=\"clamp\"\nrewritedates=false",
+        ] {
+            match read_policy(invalid).unwrap_err().downcast::<ErrorKind>() {
+                Ok(ErrorKind::InvalidConfig(_)) => {}
+                _ => assert!(false, "Unexpected err type"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_fetch_limits() {
+        let read_limits = |fetch_limits: &str| {
+            let content = format!(
+                r#"
+                path="/tmp/www"
+                repotype="revlog"
+                repoid=1
+                [fetch_limits]
+                {}
+                "#,
+                fetch_limits
+            );
+            let paths = btreemap! {
+                "repos/www/server.toml" => (FileType::Regular, content.as_str()),
+            };
+            let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+            RepoConfigs::read_manifest(&root_manifest)
+                .wait()
+                .map(|configs| configs.repos["www"].fetch_limits)
+        };
+
+        assert_eq!(read_limits("").unwrap(), FetchLimits::default());
+        assert_eq!(
+            read_limits("gettreepack_buffer_size = 1").unwrap(),
+            FetchLimits {
+                gettreepack_buffer_size: 1,
+                ..Default::default()
+            }
+        );
+        for invalid in &[
+            "getfiles_buffer_size = 0",
+            "gettreepack_buffer_size = 0",
+            "between_buffer_size = 0",
+        ] {
+            match read_limits(invalid).unwrap_err().downcast::<ErrorKind>() {
+                Ok(ErrorKind::InvalidConfig(_)) => {}
+                _ => assert!(false, "Unexpected err type"),
+            };
+        }
+    }
+
+    #[test]
+    fn test_repo_flags() {
+        for flag in RepoFlag::all() {
+            assert_eq!(flag.as_str().parse::<RepoFlag>().unwrap(), *flag);
+        }
+        match "read_only".parse::<RepoFlag>().unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::UnknownRepoFlag(_)) => {}
+            _ => assert!(false, "Unexpected err type"),
+        };
+
+        let mut flags = RepoFlags::default();
+        flags.set(RepoFlag::StrictWireprotoArgs, true);
+        assert!(flags.get(RepoFlag::StrictWireprotoArgs));
+        assert_eq!(
+            flags.to_string(),
+            "readonly=false strict_wireproto_args=true deterministic_getbundle=false"
+        );
     }
 }
@@ -0,0 +1,501 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Creation of commits on the server, for automation that changes a few files and shouldn't need
+//! a client and a working copy for it. A commit goes through the checks of a push: the path
+//! rules and the message limit of the repo, then the hooks of the bookmark it lands on.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::{get_sha256_alias, save_bonsai_changesets, BlobRepo};
+use bookmarks::Bookmark;
+use bundle2_resolver::{check_paths, commit_message_fits, format_rejections, format_violations,
+                       HookRejection};
+use hooks::HookManager;
+use mercurial_types::{Changeset, HgChangesetId};
+use metaconfig::{PathRules, PushLimits};
+use mononoke_types::{BlobstoreValue, BonsaiChangesetMut, ChangesetId, DateTime, FileChange,
+                     FileContents, FileType, MPath};
+
+use errors::ErrorKind;
+use get_changeset_by_bookmark;
+
+/// What the new commit is based on
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CommitParent {
+    Bookmark(Bookmark),
+    Changeset(HgChangesetId),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileChangeSpec {
+    pub path: MPath,
+    /// New content and type of the file, `None` to delete it
+    pub content: Option<(Bytes, FileType)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommitSpec {
+    pub parent: CommitParent,
+    pub changes: Vec<FileChangeSpec>,
+    pub author: String,
+    pub message: String,
+    /// Bookmark to move to the new commit. It must point to the parent, or not exist yet.
+    pub bookmark: Option<Bookmark>,
+    /// Check the commit without moving the bookmark
+    pub dry_run: bool,
+}
+
+impl CommitSpec {
+    /// The bookmark whose hooks the commit must pass
+    fn hooks_bookmark(&self) -> Option<&Bookmark> {
+        match (&self.bookmark, &self.parent) {
+            (Some(bookmark), _) => Some(bookmark),
+            (None, CommitParent::Bookmark(bookmark)) => Some(bookmark),
+            (None, CommitParent::Changeset(_)) => None,
+        }
+    }
+}
+
+/// The checks that a push of the repo goes through
+#[derive(Clone)]
+pub struct CommitChecks {
+    pub hook_manager: Arc<HookManager>,
+    pub path_rules: PathRules,
+    pub push_limits: PushLimits,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CreatedCommit {
+    pub hg_cs_id: HgChangesetId,
+    pub bcs_id: ChangesetId,
+    /// False for a dry run, or if there was no bookmark to move
+    pub bookmark_moved: bool,
+}
+
+fn check_spec(spec: &CommitSpec, checks: &CommitChecks) -> Result<(), Error> {
+    if spec.changes.is_empty() {
+        return Err(ErrorKind::InvalidInput("commit without file changes".to_string()).into());
+    }
+    if !commit_message_fits(&checks.push_limits, spec.message.as_bytes()) {
+        return Err(ErrorKind::CommitMessageTooLarge(
+            spec.message.len(),
+            checks.push_limits.max_commit_message_bytes,
+        ).into());
+    }
+    let violations = check_paths(
+        &checks.path_rules,
+        spec.changes
+            .iter()
+            .filter(|change| change.content.is_some())
+            .map(|change| &change.path),
+    );
+    if !violations.is_empty() {
+        return Err(ErrorKind::InvalidPaths(format_violations(&violations)).into());
+    }
+    Ok(())
+}
+
+fn resolve_parent(
+    repo: Arc<BlobRepo>,
+    parent: CommitParent,
+) -> BoxFuture<(HgChangesetId, ChangesetId), Error> {
+    let hg_cs_id = match parent {
+        CommitParent::Bookmark(bookmark) => {
+            get_changeset_by_bookmark(repo.clone(), bookmark).boxify()
+        }
+        CommitParent::Changeset(hg_cs_id) => future::ok(hg_cs_id).boxify(),
+    };
+    hg_cs_id
+        .and_then(move |hg_cs_id| {
+            repo.get_bonsai_from_hg(&hg_cs_id)
+                .and_then(move |bcs_id| match bcs_id {
+                    Some(bcs_id) => Ok((hg_cs_id, bcs_id)),
+                    None => Err(ErrorKind::NotFound(hg_cs_id.to_string()).into()),
+                })
+        })
+        .boxify()
+}
+
+/// Rejects the deletion of a file that the parent doesn't have
+fn check_deletions(
+    repo: Arc<BlobRepo>,
+    parent: HgChangesetId,
+    changes: &[FileChangeSpec],
+) -> BoxFuture<(), Error> {
+    let deleted: Vec<_> = changes
+        .iter()
+        .filter(|change| change.content.is_none())
+        .map(|change| change.path.clone())
+        .collect();
+    if deleted.is_empty() {
+        return future::ok(()).boxify();
+    }
+
+    repo.get_changeset_by_changesetid(&parent)
+        .and_then(move |cs| {
+            let manifest_id = *cs.manifestid();
+            future::join_all(deleted.into_iter().map(move |path| {
+                repo.find_file_in_manifest(&path, manifest_id)
+                    .and_then(move |node| match node {
+                        Some(_) => Ok(()),
+                        None => Err(ErrorKind::NotFound(path.to_string()).into()),
+                    })
+            }))
+        })
+        .map(|_| ())
+        .boxify()
+}
+
+fn store_file_change(
+    repo: &BlobRepo,
+    change: FileChangeSpec,
+) -> BoxFuture<(MPath, Option<FileChange>), Error> {
+    match change.content {
+        Some((content, file_type)) => {
+            let size = content.len() as u64;
+            let alias_key = get_sha256_alias(&content);
+            let blob = FileContents::Bytes(content).into_blob();
+            let path = change.path;
+            repo.upload_blob(blob, alias_key)
+                .map(move |content_id| {
+                    let change = FileChange::new(content_id, file_type, size, None);
+                    (path, Some(change))
+                })
+                .boxify()
+        }
+        None => future::ok((change.path, None)).boxify(),
+    }
+}
+
+fn run_hooks(
+    hook_manager: Arc<HookManager>,
+    hg_cs_id: HgChangesetId,
+    bookmark: Option<Bookmark>,
+) -> BoxFuture<(), Error> {
+    let bookmark = match bookmark {
+        Some(bookmark) => bookmark,
+        None => return future::ok(()).boxify(),
+    };
+
+    hook_manager
+        .run_changeset_hooks_for_bookmark(hg_cs_id, &bookmark, None, None)
+        .join(hook_manager.run_file_hooks_for_bookmark(hg_cs_id, &bookmark, None, None))
+        .and_then(|(cs_executions, file_executions)| {
+            let rejections = HookRejection::from_executions(cs_executions, file_executions);
+            if rejections.is_empty() {
+                Ok(())
+            } else {
+                Err(ErrorKind::HooksRejected(format_rejections(rejections)).into())
+            }
+        })
+        .boxify()
+}
+
+/// Moves `bookmark` from `parent` to `bcs_id`, or creates it if it doesn't exist. Fails if the
+/// bookmark points elsewhere, or was moved since it was read.
+fn move_bookmark(
+    repo: Arc<BlobRepo>,
+    bookmark: Bookmark,
+    parent: ChangesetId,
+    bcs_id: ChangesetId,
+) -> BoxFuture<(), Error> {
+    repo.get_bookmark(&bookmark)
+        .and_then(move |current| {
+            let mut txn = repo.update_bookmark_transaction();
+            match current {
+                Some(_) => try_boxfuture!(txn.update(&bookmark, &bcs_id, &parent)),
+                None => try_boxfuture!(txn.create(&bookmark, &bcs_id)),
+            }
+            txn.commit()
+                .and_then(move |success| {
+                    if success {
+                        Ok(())
+                    } else {
+                        Err(ErrorKind::BookmarkMoved(bookmark.to_string()).into())
+                    }
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Creates a commit on top of the parent of `spec`, and moves the bookmark of `spec` to it.
+/// The commit is checked as a push would be, and the bookmark only moves if it passes and still
+/// points to the parent.
+///
+/// Hooks read the commit from the repo, so even a dry run writes it. The commit is then not
+/// reachable from any bookmark, like a commit whose push was rejected.
+pub fn create_commit(
+    repo: Arc<BlobRepo>,
+    checks: CommitChecks,
+    spec: CommitSpec,
+) -> BoxFuture<CreatedCommit, Error> {
+    try_boxfuture!(check_spec(&spec, &checks));
+
+    let hooks_bookmark = spec.hooks_bookmark().cloned();
+    let CommitSpec {
+        parent,
+        changes,
+        author,
+        message,
+        bookmark,
+        dry_run,
+    } = spec;
+
+    resolve_parent(repo.clone(), parent)
+        .and_then({
+            cloned!(repo);
+            move |(parent_hg, parent_bcs)| {
+                let check = check_deletions(repo.clone(), parent_hg, &changes);
+                let changes = changes.into_iter().map({
+                    cloned!(repo);
+                    move |change| store_file_change(&repo, change)
+                });
+                check
+                    .and_then(move |()| future::join_all(changes))
+                    .map(move |changes| (parent_bcs, changes))
+            }
+        })
+        .and_then({
+            cloned!(repo);
+            move |(parent_bcs, changes)| {
+                let bcs = try_boxfuture!(
+                    BonsaiChangesetMut {
+                        parents: vec![parent_bcs],
+                        author,
+                        author_date: DateTime::now(),
+                        committer: None,
+                        committer_date: None,
+                        message,
+                        extra: Default::default(),
+                        file_changes: changes.into_iter().collect(),
+                    }.freeze()
+                );
+                let bcs_id = bcs.get_changeset_id();
+                save_bonsai_changesets(vec![bcs], (*repo).clone())
+                    .and_then(move |()| repo.get_hg_from_bonsai_changeset(bcs_id))
+                    .map(move |hg_cs_id| (parent_bcs, bcs_id, hg_cs_id))
+                    .boxify()
+            }
+        })
+        .and_then({
+            let hook_manager = checks.hook_manager;
+            move |(parent_bcs, bcs_id, hg_cs_id)| {
+                run_hooks(hook_manager, hg_cs_id, hooks_bookmark)
+                    .map(move |()| (parent_bcs, bcs_id, hg_cs_id))
+            }
+        })
+        .and_then(move |(parent_bcs, bcs_id, hg_cs_id)| {
+            let created = CreatedCommit {
+                hg_cs_id,
+                bcs_id,
+                bookmark_moved: false,
+            };
+            match bookmark {
+                Some(ref bookmark) if !dry_run => {
+                    move_bookmark(repo, bookmark.clone(), parent_bcs, bcs_id)
+                        .map(move |()| CreatedCommit {
+                            bookmark_moved: true,
+                            ..created
+                        })
+                        .left_future()
+                }
+                _ => future::ok(created).right_future(),
+            }
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_unit;
+    use fixtures::linear;
+    use hooks::{Hook, HookChangeset, HookContext, HookExecution, HookRejectionInfo};
+    use mercurial_types::manifest::Content;
+    use slog::{Discard, Logger};
+
+    use get_content_by_path;
+
+    const LINEAR_HEAD: &str = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+    const LINEAR_PARENT: &str = "a5ffa77602a066db7d5cfb9fb5823a0895717c5a";
+
+    /// Counts its runs, and rejects the commits whose message mentions it
+    struct CountingHook(Arc<AtomicUsize>);
+
+    impl Hook<HookChangeset> for CountingHook {
+        fn run(&self, context: HookContext<HookChangeset>) -> BoxFuture<HookExecution, Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let execution = if context.data.comments.contains("reject me") {
+                HookExecution::Rejected(HookRejectionInfo::new(
+                    "asked to be rejected".to_string(),
+                    "".to_string(),
+                ))
+            } else {
+                HookExecution::Accepted
+            };
+            future::ok(execution).boxify()
+        }
+    }
+
+    fn head_bookmark() -> Bookmark {
+        Bookmark::new(format!("bookmark-{}", LINEAR_HEAD)).unwrap()
+    }
+
+    fn setup() -> (Arc<BlobRepo>, CommitChecks, Arc<AtomicUsize>) {
+        let repo = linear::getrepo(None);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let logger = Logger::root(Discard, o!());
+        let mut hook_manager = HookManager::new_with_blobrepo(repo.clone(), None, logger);
+        hook_manager.register_changeset_hook("counting", Arc::new(CountingHook(runs.clone())), None);
+        hook_manager.set_hooks_for_bookmark(head_bookmark(), vec!["counting".to_string()]);
+        let checks = CommitChecks {
+            hook_manager: Arc::new(hook_manager),
+            path_rules: PathRules::default(),
+            push_limits: PushLimits::default(),
+        };
+        (Arc::new(repo), checks, runs)
+    }
+
+    fn spec(message: &str) -> CommitSpec {
+        CommitSpec {
+            parent: CommitParent::Bookmark(head_bookmark()),
+            changes: vec![
+                FileChangeSpec {
+                    path: MPath::new("dir/new").unwrap(),
+                    content: Some((Bytes::from("new content\n"), FileType::Regular)),
+                },
+                FileChangeSpec {
+                    path: MPath::new("1").unwrap(),
+                    content: None,
+                },
+            ],
+            author: "automation".to_string(),
+            message: message.to_string(),
+            bookmark: Some(head_bookmark()),
+            dry_run: false,
+        }
+    }
+
+    fn head(repo: &Arc<BlobRepo>) -> HgChangesetId {
+        repo.get_bookmark(&head_bookmark()).wait().unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_create_commit() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, checks, runs) = setup();
+
+            let created = create_commit(repo.clone(), checks, spec("bump version"))
+                .wait()
+                .unwrap();
+            assert!(created.bookmark_moved);
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+            assert_eq!(head(&repo), created.hg_cs_id);
+
+            // The hg changeset is there for clients to pull
+            let cs = repo.get_changeset_by_changesetid(&created.hg_cs_id)
+                .wait()
+                .unwrap();
+            assert_eq!(cs.comments(), b"bump version");
+            assert_eq!(
+                cs.parents().get_nodes().0.map(|p| HgChangesetId::new(*p)),
+                Some(HgChangesetId::from_str(LINEAR_HEAD).unwrap())
+            );
+            let path = MPath::new("dir/new").unwrap();
+            match get_content_by_path(repo.clone(), created.hg_cs_id, Some(path)).wait() {
+                Ok(Content::File(contents)) => {
+                    assert_eq!(contents.as_bytes(), &Bytes::from("new content\n"))
+                }
+                _ => panic!("dir/new should be a file"),
+            }
+            let deleted = MPath::new("1").unwrap();
+            assert!(
+                get_content_by_path(repo.clone(), created.hg_cs_id, Some(deleted))
+                    .wait()
+                    .is_err()
+            );
+        });
+    }
+
+    #[test]
+    fn test_create_commit_dry_run() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, checks, runs) = setup();
+
+            let mut spec = spec("bump version");
+            spec.dry_run = true;
+            let created = create_commit(repo.clone(), checks, spec).wait().unwrap();
+            assert!(!created.bookmark_moved);
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+            assert_eq!(head(&repo), HgChangesetId::from_str(LINEAR_HEAD).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_create_commit_rejected() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, checks, runs) = setup();
+
+            // Rejected by the hooks
+            let err = create_commit(repo.clone(), checks.clone(), spec("please reject me"))
+                .wait()
+                .expect_err("the hook should reject the commit");
+            match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::HooksRejected(message)) => {
+                    assert!(message.contains("asked to be rejected"))
+                }
+                _ => panic!("unexpected error"),
+            }
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+            // Rejected before any hook runs
+            let mut checks = checks;
+            checks.push_limits.max_commit_message_bytes = 4;
+            let err = create_commit(repo.clone(), checks, spec("bump version"))
+                .wait()
+                .expect_err("the message is too large");
+            match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::CommitMessageTooLarge(12, 4)) => {}
+                _ => panic!("unexpected error"),
+            }
+            assert_eq!(runs.load(Ordering::SeqCst), 1);
+            assert_eq!(head(&repo), HgChangesetId::from_str(LINEAR_HEAD).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_create_commit_bookmark_moved() {
+        async_unit::tokio_unit_test(|| {
+            let (repo, checks, _) = setup();
+
+            // The bookmark doesn't point to the parent any more
+            let mut spec = spec("bump version");
+            spec.parent =
+                CommitParent::Changeset(HgChangesetId::from_str(LINEAR_PARENT).unwrap());
+            let err = create_commit(repo.clone(), checks, spec)
+                .wait()
+                .expect_err("the bookmark should not move");
+            match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::BookmarkMoved(bookmark)) => {
+                    assert_eq!(bookmark, head_bookmark().to_string())
+                }
+                _ => panic!("unexpected error"),
+            }
+            assert_eq!(head(&repo), HgChangesetId::from_str(LINEAR_HEAD).unwrap());
+        });
+    }
+}
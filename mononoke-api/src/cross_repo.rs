@@ -0,0 +1,387 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Lookups in the cross-repo index, which finds the repos that have a changeset without asking
+//! each of them.
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::Arc;
+
+use failure::Error;
+use futures::{future, Future, Stream};
+use futures::future::{loop_fn, Loop};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use bookmarks::Bookmark;
+use cross_repo_index::CrossRepoIndex;
+use mercurial_types::{HgChangesetId, RepositoryId};
+use reachabilityindex::{GenerationNumberBFS, ReachabilityIndex};
+
+/// Changesets recorded at once by `backfill_cross_repo_index`
+const BACKFILL_BATCH_SIZE: usize = 1000;
+
+/// A repo whose changesets are recorded in the cross-repo index
+#[derive(Clone)]
+pub struct IndexedRepo {
+    pub name: String,
+    pub repo: Arc<BlobRepo>,
+    /// The ancestors of these bookmarks are published
+    pub publishing: Vec<Bookmark>,
+}
+
+/// Whether a changeset is published in a repo
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Publication {
+    /// The changeset is an ancestor of this publishing bookmark
+    Published(Bookmark),
+    /// The changeset is not an ancestor of any publishing bookmark
+    Draft,
+    /// The changeset is not an ancestor of the publishing bookmarks that were checked, and the
+    /// others are more than the allowed distance ahead of it
+    Unknown,
+    /// The index has the changeset, but the repo doesn't
+    Missing,
+}
+
+/// A repo that the cross-repo index has a changeset for
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RepoHit {
+    pub repo_id: RepositoryId,
+    /// `None` for a repo of the index that is not among the queried repos, e.g. one that was
+    /// removed from the config. Its publication is then `Unknown`.
+    pub name: Option<String>,
+    pub publication: Publication,
+}
+
+/// Finds the repos that have `changeset` according to `index`, by ascending id, and whether they
+/// published it. A publishing bookmark that is more than `max_distance` generations ahead of the
+/// changeset is not checked, so that a lookup stays cheap for old bookmarks or old changesets.
+pub fn find_changeset_repos(
+    index: Arc<CrossRepoIndex>,
+    repos: Arc<HashMap<RepositoryId, IndexedRepo>>,
+    changeset: HgChangesetId,
+    max_distance: u64,
+) -> BoxFuture<Vec<RepoHit>, Error> {
+    index
+        .find(changeset)
+        .and_then(move |repo_ids| {
+            let hits = repo_ids.into_iter().map(move |repo_id| match repos.get(&repo_id) {
+                Some(indexed) => {
+                    let name = indexed.name.clone();
+                    publication(
+                        indexed.repo.clone(),
+                        indexed.publishing.clone(),
+                        changeset,
+                        max_distance,
+                    ).map(move |publication| RepoHit {
+                        repo_id,
+                        name: Some(name),
+                        publication,
+                    })
+                        .left_future()
+                }
+                None => future::ok(RepoHit {
+                    repo_id,
+                    name: None,
+                    publication: Publication::Unknown,
+                }).right_future(),
+            });
+            future::join_all(hits)
+        })
+        .boxify()
+}
+
+fn publication(
+    repo: Arc<BlobRepo>,
+    publishing: Vec<Bookmark>,
+    changeset: HgChangesetId,
+    max_distance: u64,
+) -> BoxFuture<Publication, Error> {
+    repo.get_generation_number(&changeset)
+        .and_then(move |generation| {
+            let generation = match generation {
+                Some(generation) => generation,
+                None => return future::ok(Publication::Missing).boxify(),
+            };
+            // Bookmarks are checked one at a time, as the first one that has the changeset is
+            // enough
+            loop_fn(
+                (publishing.into_iter(), false),
+                move |(mut bookmarks, skipped)| {
+                    let bookmark = match bookmarks.next() {
+                        Some(bookmark) => bookmark,
+                        None => {
+                            let publication = if skipped {
+                                Publication::Unknown
+                            } else {
+                                Publication::Draft
+                            };
+                            return future::ok(Loop::Break(publication)).boxify();
+                        }
+                    };
+                    cloned!(repo);
+                    repo.get_bookmark(&bookmark)
+                        .and_then({
+                            cloned!(repo);
+                            move |head| match head {
+                                Some(head) => repo.get_generation_number(&head)
+                                    .map(move |head_generation| {
+                                        head_generation.map(|gen| (head, gen))
+                                    })
+                                    .left_future(),
+                                None => future::ok(None).right_future(),
+                            }
+                        })
+                        .and_then(move |head| {
+                            let (head, distance) = match head {
+                                Some((head, head_generation)) => {
+                                    match head_generation.difference_from(generation) {
+                                        Some(distance) => (head, distance),
+                                        // A changeset can't be an ancestor of an older one
+                                        None => {
+                                            return future::ok(Loop::Continue((bookmarks, skipped)))
+                                                .boxify()
+                                        }
+                                    }
+                                }
+                                None => {
+                                    return future::ok(Loop::Continue((bookmarks, skipped)))
+                                        .boxify()
+                                }
+                            };
+                            if distance > max_distance {
+                                return future::ok(Loop::Continue((bookmarks, true))).boxify();
+                            }
+                            GenerationNumberBFS::new()
+                                .query_reachability(
+                                    repo,
+                                    head.into_nodehash(),
+                                    changeset.into_nodehash(),
+                                )
+                                .map(move |reachable| {
+                                    if reachable {
+                                        Loop::Break(Publication::Published(bookmark))
+                                    } else {
+                                        Loop::Continue((bookmarks, skipped))
+                                    }
+                                })
+                                .boxify()
+                        })
+                        .boxify()
+                },
+            ).boxify()
+        })
+        .boxify()
+}
+
+/// Records in `index` that the repo `repo_id` has the changesets of `repo` that are ancestors of
+/// its bookmarks, e.g. the history from before the repo opted in to the index. Returns how many
+/// changesets were recorded.
+pub fn backfill_cross_repo_index(
+    index: Arc<CrossRepoIndex>,
+    repo_id: RepositoryId,
+    repo: Arc<BlobRepo>,
+) -> BoxFuture<usize, Error> {
+    repo.get_bookmarks()
+        .map(|(_, head)| head)
+        .collect()
+        .and_then(move |heads| {
+            let mut visited = HashSet::new();
+            let frontier: Vec<_> = heads
+                .into_iter()
+                .filter(|head| visited.insert(*head))
+                .collect();
+            loop_fn(
+                (frontier, visited, Vec::new(), 0),
+                move |(mut frontier, mut visited, mut batch, recorded)| {
+                    let next = frontier.pop();
+                    let flush = match next {
+                        Some(_) => batch.len() >= BACKFILL_BATCH_SIZE,
+                        None => !batch.is_empty(),
+                    };
+                    if flush {
+                        let count = batch.len();
+                        let batch = mem::replace(&mut batch, Vec::new());
+                        frontier.extend(next);
+                        return index
+                            .add(repo_id, batch)
+                            .map(move |()| {
+                                Loop::Continue((frontier, visited, Vec::new(), recorded + count))
+                            })
+                            .boxify();
+                    }
+                    match next {
+                        Some(changeset) => repo.get_changeset_parents(&changeset)
+                            .map(move |parents| {
+                                frontier.extend(
+                                    parents.into_iter().filter(|parent| visited.insert(*parent)),
+                                );
+                                batch.push(changeset);
+                                Loop::Continue((frontier, visited, batch, recorded))
+                            })
+                            .boxify(),
+                        None => future::ok(Loop::Break(recorded)).boxify(),
+                    }
+                },
+            )
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use cross_repo_index::SqliteCrossRepoIndex;
+    use fixtures::{branch_even, linear};
+
+    fn cs_id(hash: &str) -> HgChangesetId {
+        HgChangesetId::from_str(hash).unwrap()
+    }
+
+    fn bookmark(cs: &str) -> Bookmark {
+        Bookmark::new(format!("bookmark-{}", cs)).unwrap()
+    }
+
+    const LINEAR_HEAD: &str = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+    const LINEAR_PARENT: &str = "a5ffa77602a066db7d5cfb9fb5823a0895717c5a";
+    const LINEAR_ROOT: &str = "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536";
+
+    /// Two copies of the linear repo, which share all their changesets, and the branch_even repo,
+    /// which shares none of them
+    fn indexed_repos(publishing: Vec<Bookmark>) -> HashMap<RepositoryId, IndexedRepo> {
+        hashmap! {
+            RepositoryId::new(1) => IndexedRepo {
+                name: "linear".to_string(),
+                repo: Arc::new(linear::getrepo(None)),
+                publishing: publishing.clone(),
+            },
+            RepositoryId::new(2) => IndexedRepo {
+                name: "linear-mirror".to_string(),
+                repo: Arc::new(linear::getrepo(None)),
+                publishing: vec![],
+            },
+            RepositoryId::new(3) => IndexedRepo {
+                name: "branch".to_string(),
+                repo: Arc::new(branch_even::getrepo(None)),
+                publishing: vec![],
+            },
+        }
+    }
+
+    fn backfill_all(
+        index: Arc<CrossRepoIndex>,
+        repos: &HashMap<RepositoryId, IndexedRepo>,
+    ) -> usize {
+        repos
+            .iter()
+            .map(|(repo_id, indexed)| {
+                backfill_cross_repo_index(index.clone(), *repo_id, indexed.repo.clone())
+                    .wait()
+                    .unwrap()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_backfill() {
+        async_unit::tokio_unit_test(|| {
+            let index: Arc<CrossRepoIndex> = Arc::new(SqliteCrossRepoIndex::in_memory().unwrap());
+            let repos = indexed_repos(vec![]);
+
+            assert_eq!(index.find(cs_id(LINEAR_ROOT)).wait().unwrap(), vec![]);
+            // 11 changesets in each linear repo, 7 in the branch repo
+            assert_eq!(backfill_all(index.clone(), &repos), 29);
+            assert_eq!(
+                index.find(cs_id(LINEAR_ROOT)).wait().unwrap(),
+                vec![RepositoryId::new(1), RepositoryId::new(2)]
+            );
+            // Backfilling again records the same changesets
+            assert_eq!(backfill_all(index.clone(), &repos), 29);
+            assert_eq!(
+                index.find(cs_id(LINEAR_ROOT)).wait().unwrap(),
+                vec![RepositoryId::new(1), RepositoryId::new(2)]
+            );
+        })
+    }
+
+    #[test]
+    fn test_find_changeset_repos() {
+        async_unit::tokio_unit_test(|| {
+            let index: Arc<CrossRepoIndex> = Arc::new(SqliteCrossRepoIndex::in_memory().unwrap());
+            let publishing = vec![bookmark(LINEAR_PARENT), bookmark(LINEAR_HEAD)];
+            let repos = indexed_repos(publishing);
+            backfill_all(index.clone(), &repos);
+            let repos = Arc::new(repos);
+
+            let hits = find_changeset_repos(
+                index.clone(),
+                repos.clone(),
+                cs_id(LINEAR_HEAD),
+                100,
+            ).wait()
+                .unwrap();
+            assert_eq!(
+                hits,
+                vec![
+                    RepoHit {
+                        repo_id: RepositoryId::new(1),
+                        name: Some("linear".to_string()),
+                        publication: Publication::Published(bookmark(LINEAR_HEAD)),
+                    },
+                    RepoHit {
+                        repo_id: RepositoryId::new(2),
+                        name: Some("linear-mirror".to_string()),
+                        publication: Publication::Draft,
+                    },
+                ]
+            );
+
+            // The first publishing bookmark that has the changeset is reported
+            let hits = find_changeset_repos(index.clone(), repos.clone(), cs_id(LINEAR_ROOT), 100)
+                .wait()
+                .unwrap();
+            assert_eq!(hits.len(), 2);
+            assert_eq!(
+                hits[0].publication,
+                Publication::Published(bookmark(LINEAR_PARENT))
+            );
+
+            // The publishing bookmarks are too far ahead of the root to be checked
+            let hits = find_changeset_repos(index.clone(), repos.clone(), cs_id(LINEAR_ROOT), 5)
+                .wait()
+                .unwrap();
+            assert_eq!(hits[0].publication, Publication::Unknown);
+
+            let missing = cs_id("1111111111111111111111111111111111111111");
+            let hits = find_changeset_repos(index.clone(), repos.clone(), missing, 100)
+                .wait()
+                .unwrap();
+            assert!(hits.is_empty());
+
+            // A repo of the index that was not queried is still reported
+            index
+                .add(RepositoryId::new(4), vec![cs_id(LINEAR_HEAD)])
+                .wait()
+                .unwrap();
+            let hits = find_changeset_repos(index, repos, cs_id(LINEAR_HEAD), 100)
+                .wait()
+                .unwrap();
+            assert_eq!(
+                hits.last(),
+                Some(&RepoHit {
+                    repo_id: RepositoryId::new(4),
+                    name: None,
+                    publication: Publication::Unknown,
+                })
+            );
+        })
+    }
+}
@@ -8,4 +8,12 @@
 pub enum ErrorKind {
     #[fail(display = "{} not found", _0)] NotFound(String),
     #[fail(display = "{} is invalid", _0)] InvalidInput(String),
+    #[fail(display = "{} ids were requested, at most {} are allowed", _0, _1)]
+    TooManyIds(usize, usize),
+    #[fail(display = "commit message of {} bytes exceeds the limit of {} bytes", _0, _1)]
+    CommitMessageTooLarge(usize, usize),
+    #[fail(display = "invalid paths:\n{}", _0)] InvalidPaths(String),
+    #[fail(display = "{}", _0)] HooksRejected(String),
+    #[fail(display = "bookmark {} does not point to the parent any more", _0)]
+    BookmarkMoved(String),
 }
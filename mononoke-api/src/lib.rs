@@ -6,33 +6,61 @@
 
 #![deny(warnings)]
 
+#[cfg(test)]
+extern crate async_unit;
 #[macro_use]
 extern crate cloned;
 
 extern crate blobrepo;
 extern crate bookmarks;
+extern crate bundle2_resolver;
+extern crate bytes;
+extern crate cross_repo_index;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
+#[macro_use]
 extern crate futures_ext;
+extern crate hooks;
 extern crate mercurial_types;
+extern crate metaconfig;
 extern crate mononoke_types;
+extern crate reachabilityindex;
+
+#[cfg(test)]
+extern crate fixtures;
+#[cfg(test)]
+#[macro_use]
+extern crate maplit;
+#[cfg(test)]
+#[macro_use]
+extern crate slog;
 
+mod create_commit;
+mod cross_repo;
 pub mod errors;
 
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
 
+use bytes::Bytes;
 use failure::Error;
-use futures::Future;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
 
-use blobrepo::BlobRepo;
+use blobrepo::{BlobRepo, ErrorKind as BlobRepoErrorKind};
 use bookmarks::Bookmark;
 use mercurial_types::{Changeset, HgChangesetId};
 use mercurial_types::manifest::Content;
-use mononoke_types::MPath;
+use mononoke_types::{DateTime, MPath, MaybeUtf8Bytes};
 
 use errors::ErrorKind;
 
+pub use create_commit::{create_commit, CommitChecks, CommitParent, CommitSpec, CreatedCommit,
+                        FileChangeSpec};
+pub use cross_repo::{backfill_cross_repo_index, find_changeset_repos, IndexedRepo, Publication,
+                     RepoHit};
+
 pub fn get_content_by_path(
     repo: Arc<BlobRepo>,
     changesetid: HgChangesetId,
@@ -51,6 +79,22 @@ pub fn get_content_by_path(
         })
 }
 
+/// The content of the file at `path` in bounded chunks. See `BlobRepo::get_file_content_stream`.
+pub fn get_file_content_stream(
+    repo: Arc<BlobRepo>,
+    changesetid: HgChangesetId,
+    path: MPath,
+) -> impl Stream<Item = Bytes, Error = Error> {
+    repo.get_file_content_stream(&changesetid, &path)
+        .map_err(|err| match err.downcast::<BlobRepoErrorKind>() {
+            Ok(BlobRepoErrorKind::PathNotFound(path)) => {
+                ErrorKind::NotFound(path.to_string()).into()
+            }
+            Ok(err) => err.into(),
+            Err(err) => err,
+        })
+}
+
 pub fn get_changeset_by_bookmark(
     repo: Arc<BlobRepo>,
     bookmark: Bookmark,
@@ -67,3 +111,201 @@ pub fn get_changeset_by_bookmark(
             }
         })
 }
+
+/// Max number of changesets that a single `get_changesets_metadata` call can ask for
+pub const MAX_CHANGESETS_METADATA_BATCH: usize = 1000;
+
+/// How many changesets `get_changesets_metadata` fetches at once
+const CHANGESETS_METADATA_CONCURRENCY: usize = 100;
+
+/// What tooling usually wants to know about a changeset, short of its content
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChangesetMetadata {
+    pub author: MaybeUtf8Bytes,
+    pub date: DateTime,
+    /// Only the extras with an allowed key
+    pub extras: BTreeMap<String, MaybeUtf8Bytes>,
+    /// First line of the message
+    pub summary: MaybeUtf8Bytes,
+    pub p1: Option<HgChangesetId>,
+    pub p2: Option<HgChangesetId>,
+    pub changed_files_count: usize,
+}
+
+impl ChangesetMetadata {
+    fn new<C: Changeset>(cs: &C, extras_allowlist: &HashSet<String>) -> Self {
+        let (p1, p2) = cs.parents().get_nodes();
+        ChangesetMetadata {
+            author: MaybeUtf8Bytes::from(cs.user()),
+            date: *cs.time(),
+            extras: filter_extras(cs.extra(), extras_allowlist),
+            summary: MaybeUtf8Bytes::from(first_line(cs.comments())),
+            p1: p1.map(|p| HgChangesetId::new(*p)),
+            p2: p2.map(|p| HgChangesetId::new(*p)),
+            changed_files_count: cs.files().len(),
+        }
+    }
+}
+
+/// The first line of `message`, without its line ending
+fn first_line(message: &[u8]) -> &[u8] {
+    let line = message.split(|byte| *byte == b'\n').next().unwrap_or(b"");
+    if line.ends_with(b"\r") {
+        &line[..line.len() - 1]
+    } else {
+        line
+    }
+}
+
+/// Allowed keys are strings, so only the extras whose key is valid UTF-8 can be allowed
+fn filter_extras(
+    extras: &BTreeMap<Vec<u8>, Vec<u8>>,
+    allowlist: &HashSet<String>,
+) -> BTreeMap<String, MaybeUtf8Bytes> {
+    extras
+        .iter()
+        .filter_map(|(key, value)| {
+            let key = String::from_utf8(key.clone()).ok()?;
+            if allowlist.contains(&key) {
+                Some((key, MaybeUtf8Bytes::from(value.as_slice())))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Fetches the metadata of a batch of changesets, in the order of `ids`. A changeset that is not
+/// in the repo gets `None` instead of failing the batch, but a batch of more than
+/// `MAX_CHANGESETS_METADATA_BATCH` ids is rejected.
+pub fn get_changesets_metadata(
+    repo: Arc<BlobRepo>,
+    ids: Vec<HgChangesetId>,
+    extras_allowlist: HashSet<String>,
+) -> BoxFuture<Vec<(HgChangesetId, Option<ChangesetMetadata>)>, Error> {
+    if ids.len() > MAX_CHANGESETS_METADATA_BATCH {
+        return future::err(
+            ErrorKind::TooManyIds(ids.len(), MAX_CHANGESETS_METADATA_BATCH).into(),
+        ).boxify();
+    }
+
+    let extras_allowlist = Arc::new(extras_allowlist);
+    stream::iter_ok(ids)
+        .map(move |id| {
+            cloned!(repo, extras_allowlist);
+            repo.changeset_exists(&id)
+                .and_then(move |exists| {
+                    if exists {
+                        repo.get_changeset_by_changesetid(&id)
+                            .map(move |cs| Some(ChangesetMetadata::new(&cs, &extras_allowlist)))
+                            .left_future()
+                    } else {
+                        future::ok(None).right_future()
+                    }
+                })
+                .map(move |metadata| (id, metadata))
+        })
+        .buffered(CHANGESETS_METADATA_CONCURRENCY)
+        .collect()
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use fixtures::linear;
+
+    fn cs_id(hash: &str) -> HgChangesetId {
+        HgChangesetId::from_str(hash).unwrap()
+    }
+
+    #[test]
+    fn test_changesets_metadata() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(linear::getrepo(None));
+            let head = cs_id("79a13814c5ce7330173ec04d279bf95ab3f652fb");
+            let parent = cs_id("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
+            let missing = cs_id("1111111111111111111111111111111111111111");
+
+            let metadata = get_changesets_metadata(
+                repo,
+                vec![head, missing, parent],
+                HashSet::new(),
+            ).wait()
+                .unwrap();
+
+            let ids: Vec<_> = metadata.iter().map(|(id, _)| *id).collect();
+            assert_eq!(ids, vec![head, missing, parent]);
+            assert_eq!(metadata[1].1, None);
+            assert_eq!(
+                metadata[2].1.as_ref().and_then(|m| m.summary.as_str()),
+                Some("added 10")
+            );
+
+            let head_metadata = metadata[0].1.clone().expect("head should be found");
+            assert_eq!(head_metadata.author, "Jeremy Fitzhardinge <jsgf@fb.com>");
+            assert_eq!(head_metadata.date.timestamp_secs(), 1504041761);
+            assert_eq!(head_metadata.summary, "modified 10");
+            assert_eq!(head_metadata.p1, Some(parent));
+            assert_eq!(head_metadata.p2, None);
+            assert_eq!(head_metadata.changed_files_count, 1);
+        })
+    }
+
+    #[test]
+    fn test_changesets_metadata_cap() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(linear::getrepo(None));
+            let head = cs_id("79a13814c5ce7330173ec04d279bf95ab3f652fb");
+
+            let at_cap = vec![head; MAX_CHANGESETS_METADATA_BATCH];
+            let metadata = get_changesets_metadata(repo.clone(), at_cap, HashSet::new())
+                .wait()
+                .unwrap();
+            assert_eq!(metadata.len(), MAX_CHANGESETS_METADATA_BATCH);
+
+            let over_cap = vec![head; MAX_CHANGESETS_METADATA_BATCH + 1];
+            match get_changesets_metadata(repo, over_cap, HashSet::new())
+                .wait()
+                .unwrap_err()
+                .downcast::<ErrorKind>()
+            {
+                Ok(ErrorKind::TooManyIds(1001, 1000)) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        })
+    }
+
+    #[test]
+    fn test_filter_extras() {
+        let extras = btreemap! {
+            b"branch".to_vec() => b"default".to_vec(),
+            b"convert_revision".to_vec() => b"abc".to_vec(),
+            b"secret".to_vec() => b"\xff".to_vec(),
+            b"\xff".to_vec() => b"value".to_vec(),
+        };
+        let allowlist: HashSet<_> = vec!["branch".to_string(), "secret".to_string()]
+            .into_iter()
+            .collect();
+        // Values that are not UTF-8 are kept as they are
+        assert_eq!(
+            filter_extras(&extras, &allowlist),
+            btreemap! {
+                "branch".to_string() => MaybeUtf8Bytes::from("default"),
+                "secret".to_string() => MaybeUtf8Bytes::from(&b"\xff"[..]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_first_line() {
+        assert_eq!(first_line(b""), b"");
+        assert_eq!(first_line(b"summary"), b"summary");
+        assert_eq!(first_line(b"summary\n\nbody"), b"summary");
+        assert_eq!(first_line(b"summary\r\nbody"), b"summary");
+        assert_eq!(first_line(b"\xffsummary\nbody"), b"\xffsummary");
+    }
+}
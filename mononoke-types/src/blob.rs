@@ -72,10 +72,11 @@ impl BlobstoreBytes {
     }
 }
 
+/// The wrapper and the bytes of the blob
 impl Weight for BlobstoreBytes {
     #[inline]
     fn get_weight(&self) -> usize {
-        self.len()
+        self.0.get_weight()
     }
 }
 
@@ -102,3 +103,16 @@ pub trait BlobstoreValue: Sized + Send {
     fn into_blob(self) -> Blob<Self::Key>;
     fn from_blob(Blob<Self::Key>) -> Result<Self>;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use asyncmemo::weight_matches_estimate;
+
+    #[test]
+    fn test_blobstore_bytes_weight() {
+        let bytes = BlobstoreBytes::from_bytes(vec![0u8; 4096]);
+        assert!(weight_matches_estimate(bytes.get_weight(), bytes.len()));
+        assert!(bytes.get_weight() > bytes.len());
+    }
+}
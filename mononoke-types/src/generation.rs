@@ -8,6 +8,7 @@ use std::{usize, u64};
 use std::mem;
 
 use asyncmemo::Weight;
+
 /// Generation number
 ///
 /// The generation number for a changeset is defined as the max of the changeset's parents'
@@ -15,6 +16,7 @@ use asyncmemo::Weight;
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, HeapSizeOf, Serialize)]
 pub struct Generation(u64);
 
+/// Only the number itself, which owns no heap memory
 impl Weight for Generation {
     fn get_weight(&self) -> usize {
         mem::size_of::<Self>()
@@ -27,6 +29,11 @@ impl Generation {
         Generation(gen)
     }
 
+    /// The generation number as an integer
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
     /// Create a maximum possible generation number
     pub fn max_gen() -> Self {
         Generation(u64::MAX)
@@ -41,3 +48,17 @@ impl Generation {
         self_gen.checked_sub(other_gen)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use asyncmemo::weight_matches_estimate;
+    use bincode;
+
+    #[test]
+    fn test_weight() {
+        let gen = Generation::new(123);
+        let serialized = bincode::serialize(&gen).expect("serializing a Generation failed");
+        assert!(weight_matches_estimate(gen.get_weight(), serialized.len()));
+    }
+}
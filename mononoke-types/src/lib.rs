@@ -40,6 +40,8 @@ extern crate quickcheck;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(test)]
+extern crate serde_json;
 
 extern crate rust_thrift;
 
@@ -53,7 +55,9 @@ pub mod file_change;
 pub mod file_contents;
 pub mod generation;
 pub mod hash;
+pub mod maybe_utf8;
 pub mod path;
+pub mod repo_prefix;
 pub mod sql_types;
 pub mod typed_hash;
 
@@ -63,7 +67,9 @@ pub use datetime::DateTime;
 pub use file_change::{FileChange, FileType};
 pub use file_contents::FileContents;
 pub use generation::Generation;
-pub use path::{check_case_conflicts, MPath, MPathElement, RepoPath};
+pub use maybe_utf8::MaybeUtf8Bytes;
+pub use path::{check_case_conflicts, EscapedPath, MPath, MPathElement, RepoPath};
+pub use repo_prefix::RepoPrefix;
 pub use typed_hash::{ChangesetId, ContentId, MononokeId};
 
 mod thrift {
@@ -0,0 +1,234 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Bytes that are usually, but not always, UTF-8, like the author, message and extras of a
+//! Mercurial changeset, or paths sent by clients.
+//!
+//! Invalid UTF-8 is handled the same way everywhere:
+//! - JSON outputs are lossless. Valid UTF-8 is a plain string. Anything else is an object with a
+//!   lossy preview and the exact bytes in hex, e.g. `{"lossy": "a\u{fffd}b", "hex": "61ff62"}`.
+//! - Logs are lossy, with a marker: invalid sequences are replaced by U+FFFD, and
+//!   `NON_UTF8_MARKER` is appended.
+//! - Code that needs a `String`, e.g. to build changeset metadata or to run hooks, uses
+//!   `to_lossy_string`, which replaces invalid sequences by U+FFFD without a marker.
+//!
+//! Nothing panics or fails because of invalid UTF-8.
+
+use std::borrow::Cow;
+use std::fmt::{self, Debug, Display};
+use std::str;
+
+use bytes::Bytes;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Appended to bytes that are not valid UTF-8 when they are logged
+pub const NON_UTF8_MARKER: &str = " [non-utf8]";
+
+#[derive(Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MaybeUtf8Bytes(Bytes);
+
+impl MaybeUtf8Bytes {
+    pub fn new<B: Into<Bytes>>(bytes: B) -> Self {
+        MaybeUtf8Bytes(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+
+    /// None if the bytes are not valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(self.as_bytes()).ok()
+    }
+
+    pub fn is_utf8(&self) -> bool {
+        self.as_str().is_some()
+    }
+
+    /// The bytes with invalid sequences replaced by U+FFFD
+    pub fn to_lossy_string(&self) -> String {
+        self.lossy().into_owned()
+    }
+
+    fn lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(self.as_bytes())
+    }
+
+    fn hex(&self) -> String {
+        self.as_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return None;
+        }
+        let bytes: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|start| u8::from_str_radix(&hex[start..start + 2], 16).ok())
+            .collect();
+        bytes.map(MaybeUtf8Bytes::from)
+    }
+}
+
+impl From<Bytes> for MaybeUtf8Bytes {
+    fn from(bytes: Bytes) -> Self {
+        MaybeUtf8Bytes(bytes)
+    }
+}
+
+impl From<Vec<u8>> for MaybeUtf8Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        MaybeUtf8Bytes(Bytes::from(bytes))
+    }
+}
+
+impl<'a> From<&'a [u8]> for MaybeUtf8Bytes {
+    fn from(bytes: &'a [u8]) -> Self {
+        MaybeUtf8Bytes(Bytes::from(bytes))
+    }
+}
+
+impl From<String> for MaybeUtf8Bytes {
+    fn from(string: String) -> Self {
+        MaybeUtf8Bytes(Bytes::from(string))
+    }
+}
+
+impl<'a> From<&'a str> for MaybeUtf8Bytes {
+    fn from(string: &'a str) -> Self {
+        MaybeUtf8Bytes(Bytes::from(string))
+    }
+}
+
+impl PartialEq<str> for MaybeUtf8Bytes {
+    fn eq(&self, other: &str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<'a> PartialEq<&'a str> for MaybeUtf8Bytes {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+/// Lossy, with `NON_UTF8_MARKER` if the bytes are not valid UTF-8. This is what logs should use.
+impl Display for MaybeUtf8Bytes {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.as_str() {
+            Some(string) => write!(fmt, "{}", string),
+            None => write!(fmt, "{}{}", self.lossy(), NON_UTF8_MARKER),
+        }
+    }
+}
+
+impl Debug for MaybeUtf8Bytes {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.as_str() {
+            Some(string) => write!(fmt, "MaybeUtf8Bytes({:?})", string),
+            None => write!(fmt, "MaybeUtf8Bytes(hex {})", self.hex()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Repr<'a> {
+    Utf8(Cow<'a, str>),
+    NonUtf8 { lossy: Cow<'a, str>, hex: Cow<'a, str> },
+}
+
+impl Serialize for MaybeUtf8Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self.as_str() {
+            Some(string) => Repr::Utf8(Cow::Borrowed(string)),
+            None => Repr::NonUtf8 {
+                lossy: self.lossy(),
+                hex: Cow::Owned(self.hex()),
+            },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MaybeUtf8Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Repr::deserialize(deserializer)? {
+            Repr::Utf8(string) => Ok(MaybeUtf8Bytes::from(string.into_owned())),
+            Repr::NonUtf8 { hex, .. } => MaybeUtf8Bytes::from_hex(&hex)
+                .ok_or_else(|| de::Error::custom(format!("invalid hex {:?}", hex))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn test_utf8() {
+        let bytes = MaybeUtf8Bytes::from("caf\u{e9}");
+        assert!(bytes.is_utf8());
+        assert_eq!(bytes.as_str(), Some("caf\u{e9}"));
+        assert_eq!(bytes.to_lossy_string(), "caf\u{e9}");
+        assert_eq!(bytes.to_string(), "caf\u{e9}");
+        assert_eq!(bytes, "caf\u{e9}");
+        assert_eq!(serde_json::to_string(&bytes).unwrap(), "\"caf\u{e9}\"");
+    }
+
+    #[test]
+    fn test_non_utf8() {
+        let bytes = MaybeUtf8Bytes::from(&b"a\xffb"[..]);
+        assert!(!bytes.is_utf8());
+        assert_eq!(bytes.as_str(), None);
+        assert_eq!(bytes.as_bytes(), b"a\xffb");
+        assert_eq!(bytes.to_lossy_string(), "a\u{fffd}b");
+        assert_eq!(bytes.to_string(), "a\u{fffd}b [non-utf8]");
+        assert_eq!(format!("{:?}", bytes), "MaybeUtf8Bytes(hex 61ff62)");
+        assert_eq!(
+            serde_json::to_string(&bytes).unwrap(),
+            "{\"lossy\":\"a\u{fffd}b\",\"hex\":\"61ff62\"}"
+        );
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let values = vec![
+            MaybeUtf8Bytes::from(""),
+            MaybeUtf8Bytes::from("plain"),
+            MaybeUtf8Bytes::from(&b"\xff"[..]),
+            MaybeUtf8Bytes::from(&b"\xc3"[..]),
+            MaybeUtf8Bytes::from(&b"ok \xe9t\xe9"[..]),
+        ];
+        for value in values {
+            let json = serde_json::to_string(&value).unwrap();
+            let parsed: MaybeUtf8Bytes = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, value, "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        let invalid = [
+            r#"{"lossy": "", "hex": "f"}"#,
+            r#"{"lossy": "", "hex": "zz"}"#,
+            r#"{"lossy": ""}"#,
+            "42",
+        ];
+        for json in invalid.iter() {
+            assert!(serde_json::from_str::<MaybeUtf8Bytes>(json).is_err(), "{}", json);
+        }
+    }
+}
@@ -12,6 +12,7 @@ use std::io::{self, Write};
 use std::iter::{once, FromIterator, Once};
 use std::mem;
 use std::slice::Iter;
+use std::str;
 
 use asyncmemo::Weight;
 use bincode;
@@ -19,6 +20,7 @@ use failure::{err_msg, chain::*};
 use heapsize::HeapSizeOf;
 
 use quickcheck::{Arbitrary, Gen};
+use serde::{Serialize, Serializer};
 
 use errors::*;
 use thrift;
@@ -28,6 +30,7 @@ lazy_static! {
     pub static ref DOTDOT: MPathElement = MPathElement(b"..".to_vec());
 }
 
+/// The path and the buffers of its components
 impl Weight for RepoPath {
     fn get_weight(&self) -> usize {
         self.heap_size_of_children() + mem::size_of::<Self>()
@@ -268,6 +271,11 @@ impl MPathElement {
         self.0.len()
     }
 
+    /// See `EscapedPath`
+    pub fn to_escaped(&self) -> EscapedPath {
+        EscapedPath::new(&self.0)
+    }
+
     #[inline]
     pub(crate) fn into_thrift(self) -> thrift::MPathElement {
         thrift::MPathElement(self.0)
@@ -513,6 +521,51 @@ impl MPath {
         }
     }
 
+    /// The path as a string, or an error if it is not valid UTF-8. Use this instead of a lossy
+    /// conversion when the string is used to look the path up again.
+    pub fn to_utf8(&self) -> Result<String> {
+        String::from_utf8(self.to_vec()).map_err(|_| {
+            ErrorKind::InvalidPath(
+                self.to_escaped().to_string(),
+                "path is not valid UTF-8".into(),
+            ).into()
+        })
+    }
+
+    /// See `EscapedPath`
+    pub fn to_escaped(&self) -> EscapedPath {
+        EscapedPath::new(&self.to_vec())
+    }
+
+    /// Parses the escaped form of a path, see `EscapedPath`
+    pub fn from_escaped(escaped: &str) -> Result<MPath> {
+        let invalid = |msg: &str| ErrorKind::InvalidPath(escaped.to_string(), msg.to_string());
+        let mut bytes = Vec::with_capacity(escaped.len());
+        let mut rest = escaped.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            rest = tail;
+            if byte != b'\\' {
+                bytes.push(byte);
+                continue;
+            }
+            match rest.split_first() {
+                Some((&b'\\', tail)) => {
+                    bytes.push(b'\\');
+                    rest = tail;
+                }
+                Some((&b'x', tail)) if tail.len() >= 2 => {
+                    let hex = str::from_utf8(&tail[..2]).map_err(|_| invalid("invalid escape"))?;
+                    let escaped_byte =
+                        u8::from_str_radix(hex, 16).map_err(|_| invalid("invalid escape"))?;
+                    bytes.push(escaped_byte);
+                    rest = &tail[2..];
+                }
+                _ => return Err(invalid("invalid escape").into()),
+            }
+        }
+        MPath::new(bytes)
+    }
+
     pub(crate) fn into_thrift(self) -> thrift::MPath {
         thrift::MPath(
             self.elements
@@ -538,6 +591,103 @@ impl<'a> Display for DisplayOpt<'a> {
     }
 }
 
+/// How paths are shown to people and in JSON outputs. Unlike the lossy `Display` of `MPath`, every
+/// path has a distinct escaped form, which `MPath::from_escaped` parses back:
+/// - bytes that are not valid UTF-8, and control characters, are written as `\xNN`
+/// - a backslash is written as `\\`
+/// - everything else is unchanged, so most paths are their own escaped form
+///
+/// In JSON, a path that needed no escaping is a plain string. Otherwise it is an object with the
+/// escaped form and the exact bytes in hex, e.g. `{"escaped": "a\\xffb", "hex": "61ff62"}`.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct EscapedPath {
+    escaped: String,
+    hex: Option<String>,
+}
+
+impl EscapedPath {
+    fn new(bytes: &[u8]) -> Self {
+        let mut escaped = String::with_capacity(bytes.len());
+        let mut rest = bytes;
+        loop {
+            match str::from_utf8(rest) {
+                Ok(valid) => {
+                    escape_str(valid, &mut escaped);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    escape_str(
+                        str::from_utf8(valid).expect("prefix must be valid UTF-8"),
+                        &mut escaped,
+                    );
+                    let invalid_len = err.error_len().unwrap_or(invalid.len());
+                    for byte in &invalid[..invalid_len] {
+                        escaped.push_str(&format!("\\x{:02x}", byte));
+                    }
+                    rest = &invalid[invalid_len..];
+                }
+            }
+        }
+
+        let hex = if escaped.as_bytes() == bytes {
+            None
+        } else {
+            Some(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+        };
+        EscapedPath { escaped, hex }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.escaped
+    }
+
+    /// The exact bytes in hex, if the path needed escaping
+    pub fn hex(&self) -> Option<&str> {
+        self.hex.as_ref().map(|hex| hex.as_str())
+    }
+
+    pub fn is_escaped(&self) -> bool {
+        self.hex.is_some()
+    }
+}
+
+fn escape_str(valid: &str, out: &mut String) {
+    for ch in valid.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            ch if ch.is_ascii_control() => out.push_str(&format!("\\x{:02x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+}
+
+impl Display for EscapedPath {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.escaped)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum EscapedPathRepr<'a> {
+    Plain(&'a str),
+    Escaped { escaped: &'a str, hex: &'a str },
+}
+
+impl Serialize for EscapedPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        let repr = match self.hex {
+            None => EscapedPathRepr::Plain(&self.escaped),
+            Some(ref hex) => EscapedPathRepr::Escaped {
+                escaped: &self.escaped,
+                hex,
+            },
+        };
+        repr.serialize(serializer)
+    }
+}
+
 /// Check that a sorted list of (MPath, is_changed) pairs is path-conflict-free. This means that
 /// no changed path in the list (is_changed is true) is a directory of another path.
 pub fn check_pcf<'a, I>(sorted_paths: I) -> Result<()>
@@ -776,6 +926,7 @@ where
 #[cfg(test)]
 mod test {
     use quickcheck::TestResult;
+    use serde_json;
 
     use super::*;
 
@@ -978,6 +1129,57 @@ mod test {
         );
     }
 
+    #[test]
+    fn escaped_paths() {
+        // The first four show that distinct paths are rendered distinctly, even where a lossy
+        // conversion would render them all as "a\u{fffd}b"
+        let cases = vec![
+            (&b"a\xffb"[..], "a\\xffb", Some("61ff62")),
+            (&b"a\xfeb"[..], "a\\xfeb", Some("61fe62")),
+            (&b"a\xc3b"[..], "a\\xc3b", Some("61c362")),
+            (&b"a\xef\xbf\xbdb"[..], "a\u{fffd}b", None),
+            (&b"dir/file.txt"[..], "dir/file.txt", None),
+            (&b"caf\xc3\xa9/menu"[..], "caf\u{e9}/menu", None),
+            (&b"tab\there"[..], "tab\\x09here", Some("7461620968657265")),
+            (&b"back\\slash"[..], "back\\\\slash", Some("6261636b5c736c617368")),
+            (&b"lit\\xff"[..], "lit\\\\xff", Some("6c69745c786666")),
+        ];
+        for (bytes, escaped, hex) in cases {
+            let path = MPath::new(bytes).unwrap();
+            let rendered = path.to_escaped();
+            assert_eq!(rendered.as_str(), escaped);
+            assert_eq!(rendered.hex(), hex);
+            assert_eq!(rendered.is_escaped(), hex.is_some());
+            assert_eq!(MPath::from_escaped(escaped).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn escaped_paths_json() {
+        let plain = MPath::new("dir/file").unwrap().to_escaped();
+        assert_eq!(serde_json::to_string(&plain).unwrap(), "\"dir/file\"");
+        let escaped = MPath::new(&b"dir/a\xffb"[..]).unwrap().to_escaped();
+        assert_eq!(
+            serde_json::to_string(&escaped).unwrap(),
+            r#"{"escaped":"dir/a\\xffb","hex":"6469722f61ff62"}"#
+        );
+    }
+
+    #[test]
+    fn invalid_escaped_paths() {
+        for escaped in &["a\\", "a\\x", "a\\xf", "a\\xzz", "a\\n", "a/\\x00", ""] {
+            assert!(MPath::from_escaped(escaped).is_err(), "{}", escaped);
+        }
+    }
+
+    #[test]
+    fn utf8_paths() {
+        let path = MPath::new("caf\u{e9}").unwrap();
+        assert_eq!(path.to_utf8().unwrap(), "caf\u{e9}");
+        let path = MPath::new(&b"caf\xe9"[..]).unwrap();
+        assert!(path.to_utf8().is_err());
+    }
+
     fn check_pcf_paths<I, T>(paths: I) -> Result<()>
     where
         I: IntoIterator<Item = (T, bool)>,
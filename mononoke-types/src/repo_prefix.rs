@@ -0,0 +1,59 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fmt::{self, Display};
+
+/// Prefix of the blobstore keys of a repo, e.g. `repo0001.`. Keys of a repo are built with
+/// `key` rather than by hand, so that every tool prefixes them the same way.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct RepoPrefix(String);
+
+impl RepoPrefix {
+    /// Prefix of the repo with the numeric id `repo_id`
+    pub fn new(repo_id: i32) -> Self {
+        // Generate repo0001, repo0002, etc.
+        RepoPrefix(format!("repo{:04}.", repo_id))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The full key of `key` in this repo
+    pub fn key(&self, key: &str) -> String {
+        [self.0.as_str(), key].concat()
+    }
+
+    /// `key` without this prefix, or None if it's not a key of this repo
+    pub fn strip<'a>(&self, key: &'a str) -> Option<&'a str> {
+        if key.starts_with(self.as_str()) {
+            Some(&key[self.0.len()..])
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for RepoPrefix {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let prefix = RepoPrefix::new(1);
+        assert_eq!(prefix.as_str(), "repo0001.");
+        assert_eq!(prefix.key("hgchangeset.sha1.aa"), "repo0001.hgchangeset.sha1.aa");
+        assert_eq!(prefix.strip("repo0001.content.aa"), Some("content.aa"));
+        assert_eq!(prefix.strip("repo0010.content.aa"), None);
+        assert_eq!(RepoPrefix::new(12000).key("a"), "repo12000.a");
+    }
+}
@@ -144,6 +144,7 @@ macro_rules! impl_typed_hash {
             }
         }
 
+        /// Only the hash itself, which owns no heap memory
         impl asyncmemo::Weight for $typed {
             fn get_weight(&self) -> usize {
                 ::std::mem::size_of::<Blake2>()
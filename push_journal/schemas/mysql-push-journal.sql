@@ -0,0 +1,10 @@
+CREATE TABLE push_journal (
+  repo_id INTEGER NOT NULL,
+  session_id VARCHAR(64) NOT NULL,
+  started_at BIGINT NOT NULL,
+  completed_at BIGINT,
+  changeset_count BIGINT NOT NULL,
+  changesets TEXT NOT NULL,
+  PRIMARY KEY (repo_id, session_id),
+  INDEX repo_completed_at (repo_id, completed_at)
+);
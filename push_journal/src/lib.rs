@@ -0,0 +1,291 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Journal of the pushes that upload blobs.
+//!
+//! A push uploads its blobs first and only then creates its changesets and moves bookmarks, so a
+//! server that dies in between leaves blobs behind that nothing refers to. An entry is added
+//! before the blobs of a push are uploaded, and marked complete once its bookmarks are moved.
+//! Entries that stay incomplete are pushes that were abandoned half way: operators can list them
+//! to investigate, or to feed their changesets to garbage collection.
+//!
+//! Each of the two writes of a push is a single small row, so only the first
+//! `MAX_JOURNALED_CHANGESETS` changesets of a push are recorded, along with their total count.
+
+#![deny(warnings)]
+// FIXME T34253207, remove when https://github.com/diesel-rs/diesel/issues/1785 fixed
+#![allow(proc_macro_derive_resolution_fallback)]
+#![feature(never_type)]
+
+extern crate db_conn;
+#[macro_use]
+extern crate diesel;
+extern crate failure_ext as failure;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate stats;
+
+extern crate futures_ext;
+extern crate mercurial_types;
+
+use std::result;
+use std::str::FromStr;
+use std::sync::MutexGuard;
+
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{replace_into, update, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use failure::{Error, Result};
+
+use futures_ext::{asynchronize, BoxFuture, FutureExt};
+use mercurial_types::{HgChangesetId, RepositoryId};
+use stats::Timeseries;
+
+mod models;
+mod schema;
+
+use models::PushJournalRow;
+use schema::push_journal;
+
+define_stats! {
+    prefix = "mononoke.push_journal";
+    adds: timeseries(RATE, SUM),
+    completes: timeseries(RATE, SUM),
+    lists: timeseries(RATE, SUM),
+}
+
+/// Maximum number of changesets recorded in an entry
+pub const MAX_JOURNALED_CHANGESETS: usize = 100;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PushJournalEntry {
+    pub repo_id: RepositoryId,
+    /// Session of the push, as in the logs of the server
+    pub session_id: String,
+    /// Unix timestamps, in seconds
+    pub started_at: i64,
+    pub completed_at: Option<i64>,
+    /// Number of changesets of the push
+    pub changeset_count: usize,
+    /// The first `MAX_JOURNALED_CHANGESETS` changesets of the push
+    pub changesets: Vec<HgChangesetId>,
+}
+
+impl PushJournalEntry {
+    /// Entry of a push of `changesets` that starts now
+    pub fn new(
+        repo_id: RepositoryId,
+        session_id: String,
+        started_at: i64,
+        changesets: &[HgChangesetId],
+    ) -> Self {
+        PushJournalEntry {
+            repo_id,
+            session_id,
+            started_at,
+            completed_at: None,
+            changeset_count: changesets.len(),
+            changesets: changesets
+                .iter()
+                .take(MAX_JOURNALED_CHANGESETS)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed_at.is_some()
+    }
+
+    fn into_row(self) -> PushJournalRow {
+        let changesets: Vec<_> = self.changesets
+            .iter()
+            .map(|cs| cs.to_hex().to_string())
+            .collect();
+        PushJournalRow {
+            repo_id: self.repo_id,
+            session_id: self.session_id,
+            started_at: self.started_at,
+            completed_at: self.completed_at,
+            changeset_count: self.changeset_count as i64,
+            changesets: changesets.join(" "),
+        }
+    }
+
+    fn from_row(row: PushJournalRow) -> Result<Self> {
+        let changesets = row.changesets
+            .split_whitespace()
+            .map(HgChangesetId::from_str)
+            .collect::<Result<_>>()?;
+        Ok(PushJournalEntry {
+            repo_id: row.repo_id,
+            session_id: row.session_id,
+            started_at: row.started_at,
+            completed_at: row.completed_at,
+            changeset_count: row.changeset_count as usize,
+            changesets,
+        })
+    }
+}
+
+pub trait PushJournal: Send + Sync {
+    /// Records that a push started. An entry of the same session is replaced.
+    fn add(&self, entry: PushJournalEntry) -> BoxFuture<(), Error>;
+
+    /// Marks the entry of a session as complete. Returns false if there is no such entry.
+    fn complete(
+        &self,
+        repo_id: RepositoryId,
+        session_id: String,
+        completed_at: i64,
+    ) -> BoxFuture<bool, Error>;
+
+    /// Entries of the repo, oldest first
+    fn list(
+        &self,
+        repo_id: RepositoryId,
+        incomplete_only: bool,
+    ) -> BoxFuture<Vec<PushJournalEntry>, Error>;
+}
+
+#[derive(Clone)]
+pub struct SqlitePushJournal {
+    inner: SqliteConnInner,
+}
+
+impl SqlitePushJournal {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-push-journal.sql")
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlPushJournal {
+    inner: MysqlConnInner,
+}
+
+impl MysqlPushJournal {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-push-journal.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+/// See https://github.com/diesel-rs/diesel/issues/882#issuecomment-300257476
+macro_rules! impl_push_journal {
+    ($struct:ty) => {
+        impl PushJournal for $struct {
+            fn add(&self, entry: PushJournalEntry) -> BoxFuture<(), Error> {
+                STATS::adds.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    replace_into(push_journal::table)
+                        .values(&entry.into_row())
+                        .execute(&*connection)?;
+                    Ok(())
+                }).boxify()
+            }
+
+            fn complete(
+                &self,
+                repo_id: RepositoryId,
+                session_id: String,
+                completed_at: i64,
+            ) -> BoxFuture<bool, Error> {
+                STATS::completes.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    let num_affected_rows = update(
+                        push_journal::table
+                            .filter(push_journal::repo_id.eq(repo_id))
+                            .filter(push_journal::session_id.eq(session_id)),
+                    ).set(push_journal::completed_at.eq(Some(completed_at)))
+                        .execute(&*connection)?;
+                    Ok(num_affected_rows > 0)
+                }).boxify()
+            }
+
+            fn list(
+                &self,
+                repo_id: RepositoryId,
+                incomplete_only: bool,
+            ) -> BoxFuture<Vec<PushJournalEntry>, Error> {
+                STATS::lists.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    let mut query = push_journal::table
+                        .filter(push_journal::repo_id.eq(repo_id))
+                        .into_boxed();
+                    if incomplete_only {
+                        query = query.filter(push_journal::completed_at.is_null());
+                    }
+                    query
+                        .order((push_journal::started_at.asc(), push_journal::session_id.asc()))
+                        .load::<PushJournalRow>(&*connection)?
+                        .into_iter()
+                        .map(PushJournalEntry::from_row)
+                        .collect()
+                }).boxify()
+            }
+        }
+    };
+}
+
+impl_push_journal!(SqlitePushJournal);
+impl_push_journal!(MysqlPushJournal);
@@ -0,0 +1,22 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::RepositoryId;
+
+use schema::push_journal;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "push_journal"]
+pub(crate) struct PushJournalRow {
+    pub repo_id: RepositoryId,
+    pub session_id: String,
+    pub started_at: i64,
+    pub completed_at: Option<i64>,
+    pub changeset_count: i64,
+    /// Space-separated hex hashes
+    pub changesets: String,
+}
@@ -0,0 +1,22 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macros in this module describe the schemas for these tables in SQL storage
+//! (MySQL or SQLite). These descriptions are *not* the source of truth, so if the schema ever
+//! changes it will need to be updated here as well.
+
+table! {
+    use diesel::sql_types::{BigInt, Integer, Nullable, Text};
+
+    push_journal (repo_id, session_id) {
+        repo_id -> Integer,
+        session_id -> Text,
+        started_at -> BigInt,
+        completed_at -> Nullable<BigInt>,
+        changeset_count -> BigInt,
+        changesets -> Text,
+    }
+}
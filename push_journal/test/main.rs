@@ -0,0 +1,138 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the push journal.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate futures;
+
+extern crate mercurial_types_mocks;
+extern crate push_journal;
+
+use futures::Future;
+
+use mercurial_types_mocks::nodehash::{ONES_CSID, TWOS_CSID};
+use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
+use push_journal::{MysqlPushJournal, PushJournal, PushJournalEntry, SqlitePushJournal,
+                   MAX_JOURNALED_CHANGESETS};
+
+fn session_ids(entries: &[PushJournalEntry]) -> Vec<&str> {
+    entries
+        .iter()
+        .map(|entry| entry.session_id.as_str())
+        .collect()
+}
+
+fn add_and_complete<J: PushJournal>(journal: J) {
+    let first = PushJournalEntry::new(REPO_ZERO, "first".to_string(), 100, &[ONES_CSID]);
+    let second = PushJournalEntry::new(REPO_ZERO, "second".to_string(), 200, &[TWOS_CSID]);
+    journal.add(second.clone()).wait().expect("Adding entry failed");
+    journal.add(first.clone()).wait().expect("Adding entry failed");
+
+    // Both pushes uploaded their blobs, and neither completed
+    let entries = journal.list(REPO_ZERO, true).wait().expect("Listing failed");
+    assert_eq!(entries, vec![first.clone(), second.clone()]);
+
+    assert!(
+        journal
+            .complete(REPO_ZERO, "second".to_string(), 250)
+            .wait()
+            .expect("Completing entry failed")
+    );
+
+    let entries = journal.list(REPO_ZERO, true).wait().expect("Listing failed");
+    assert_eq!(entries, vec![first.clone()]);
+
+    let entries = journal.list(REPO_ZERO, false).wait().expect("Listing failed");
+    assert_eq!(session_ids(&entries), vec!["first", "second"]);
+    assert!(!entries[0].is_complete());
+    assert_eq!(entries[1].completed_at, Some(250));
+}
+
+fn complete_missing<J: PushJournal>(journal: J) {
+    let entry = PushJournalEntry::new(REPO_ZERO, "session".to_string(), 100, &[]);
+    journal.add(entry).wait().expect("Adding entry failed");
+
+    // Entries are per repo
+    assert!(
+        !journal
+            .complete(REPO_ONE, "session".to_string(), 200)
+            .wait()
+            .expect("Completing entry failed")
+    );
+    assert!(
+        !journal
+            .complete(REPO_ZERO, "other".to_string(), 200)
+            .wait()
+            .expect("Completing entry failed")
+    );
+    let entries = journal.list(REPO_ONE, false).wait().expect("Listing failed");
+    assert!(entries.is_empty());
+    let entries = journal.list(REPO_ZERO, true).wait().expect("Listing failed");
+    assert_eq!(session_ids(&entries), vec!["session"]);
+}
+
+fn many_changesets<J: PushJournal>(journal: J) {
+    let changesets = vec![ONES_CSID; MAX_JOURNALED_CHANGESETS + 10];
+    let entry = PushJournalEntry::new(REPO_ZERO, "session".to_string(), 100, &changesets);
+    assert_eq!(entry.changeset_count, MAX_JOURNALED_CHANGESETS + 10);
+    assert_eq!(entry.changesets.len(), MAX_JOURNALED_CHANGESETS);
+
+    journal.add(entry.clone()).wait().expect("Adding entry failed");
+    let entries = journal.list(REPO_ZERO, false).wait().expect("Listing failed");
+    assert_eq!(entries, vec![entry]);
+}
+
+macro_rules! push_journal_test_impl {
+    ($mod_name:ident =>  { new: $new_cb:expr, }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_add_and_complete() {
+                async_unit::tokio_unit_test(|| {
+                    add_and_complete($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_complete_missing() {
+                async_unit::tokio_unit_test(|| {
+                    complete_missing($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_many_changesets() {
+                async_unit::tokio_unit_test(|| {
+                    many_changesets($new_cb());
+                });
+            }
+        }
+    };
+}
+
+push_journal_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+push_journal_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+fn new_sqlite() -> SqlitePushJournal {
+    SqlitePushJournal::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_mysql() -> MysqlPushJournal {
+    MysqlPushJournal::create_test_db("push_journal_test").expect("Failed to create test database")
+}
@@ -9,7 +9,8 @@
 
 extern crate futures;
 
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
 
 use futures::{Async, Future, Poll};
 
@@ -22,7 +23,7 @@ use futures::{Async, Future, Poll};
 /// this `ReadyHandle` to another thread if necessary, then operating on it.
 #[derive(Debug)]
 pub struct ReadyStateBuilder {
-    markers: Vec<(String, Arc<AtomicBool>)>,
+    markers: Vec<(String, Arc<AtomicBool>, ReadyProgress)>,
 }
 
 impl ReadyStateBuilder {
@@ -36,9 +37,11 @@ impl ReadyStateBuilder {
     pub fn create_handle<S: Into<String>>(&mut self, name: S) -> ReadyHandle {
         let name = name.into();
         let marker = Arc::new(AtomicBool::new(false));
-        self.markers.push((name.clone(), marker.clone()));
+        let progress = ReadyProgress::default();
+        self.markers.push((name.clone(), marker.clone(), progress.clone()));
         ReadyHandle {
             inner: Some(ReadyHandleInner { name, marker }),
+            progress,
         }
     }
 
@@ -53,13 +56,42 @@ impl ReadyStateBuilder {
 #[derive(Debug)]
 pub struct ReadyState {
     // (possible optimization here: set a flag once all waiting is done)
-    markers: Vec<(String, Arc<AtomicBool>)>,
+    markers: Vec<(String, Arc<AtomicBool>, ReadyProgress)>,
 }
 
 impl ReadyState {
     #[inline]
     pub fn is_ready(&self) -> bool {
-        self.markers.iter().all(|(_, b)| b.load(Ordering::Relaxed))
+        self.markers.iter().all(|(_, b, _)| b.load(Ordering::Relaxed))
+    }
+
+    /// Progress reported through every handle that reported any, keyed by the name of the handle
+    pub fn progress(&self) -> BTreeMap<String, BTreeMap<String, String>> {
+        self.markers
+            .iter()
+            .map(|(name, _, progress)| (name.clone(), progress.get()))
+            .filter(|(_, progress)| !progress.is_empty())
+            .collect()
+    }
+}
+
+/// Free-form progress of the work that a `ReadyHandle` stands for, as key-value pairs. It's
+/// shared with the `ReadyState` so that the state of the work can be reported before it's ready.
+#[derive(Clone, Debug, Default)]
+pub struct ReadyProgress {
+    inner: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl ReadyProgress {
+    pub fn set<K: Into<String>, V: Into<String>>(&self, key: K, value: V) {
+        self.inner
+            .lock()
+            .expect("lock poisoned")
+            .insert(key.into(), value.into());
+    }
+
+    pub fn get(&self) -> BTreeMap<String, String> {
+        self.inner.lock().expect("lock poisoned").clone()
     }
 }
 
@@ -70,6 +102,7 @@ pub struct ReadyHandle {
     // The Option is so that the name and marker can be moved into a ReadyFuture without
     // conflicting with the Drop implementation.
     inner: Option<ReadyHandleInner>,
+    progress: ReadyProgress,
 }
 
 #[derive(Debug)]
@@ -93,6 +126,11 @@ impl ReadyHandle {
         }
     }
 
+    /// Returns the progress of this handle, which stays usable after `wait_for`
+    pub fn progress(&self) -> ReadyProgress {
+        self.progress.clone()
+    }
+
     // XXX can implement direct setting of readiness if required
 }
 
@@ -248,6 +286,31 @@ mod test {
         assert!(ready.is_ready());
     }
 
+    #[test]
+    fn ready_progress() {
+        let mut ready = ReadyStateBuilder::new();
+        let foo = ready.create_handle("foo");
+        let _bar = ready.create_handle("bar");
+        let ready = ready.freeze();
+        assert!(ready.progress().is_empty());
+
+        let progress = foo.progress();
+        let fut = foo.wait_for(future::ok::<_, !>(123));
+        progress.set("task", "running");
+        progress.set("task", "done");
+        progress.set("other", "failed");
+
+        let expected: BTreeMap<_, _> = vec![("other", "failed"), ("task", "done")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(ready.progress().get("foo"), Some(&expected));
+        assert_eq!(ready.progress().get("bar"), None);
+
+        let _ = fut.wait();
+        assert_eq!(ready.progress().get("foo"), Some(&expected));
+    }
+
     struct LaterFuture<T> {
         value: T,
         remaining_polls: usize,
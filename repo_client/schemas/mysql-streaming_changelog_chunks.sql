@@ -5,5 +5,7 @@ CREATE TABLE streaming_changelog_chunks (
   idx_size INT UNSIGNED) NOT NULL,
   data_blob_name VARBINARY(4096) NOT NULL,
   data_size INT UNSIGNED) NOT NULL,
+  idx_sha1 BINARY(20),
+  data_sha1 BINARY(20),
   PRIMARY KEY (repo_id,chunk_num)
 );
\ No newline at end of file
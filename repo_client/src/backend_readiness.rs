@@ -0,0 +1,288 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Waiting for the backends of a repo, such as myrouter, to be up before opening the repo, so
+//! that opening a repo never blocks a thread on a backend that isn't there.
+
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures_ext::{asynchronize, BoxFuture, FutureExt};
+use slog::Logger;
+use sql::myrouter;
+use tokio::timer::Interval;
+use tokio::util::FutureExt as TokioFutureExt;
+
+use errors::*;
+
+/// Limits for opening a repo
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OpenRepoParams {
+    /// Opening a repo fails if it takes longer than this, including waiting for its backends
+    pub timeout: Duration,
+    /// How often to log that a backend is still being waited for
+    pub progress_interval: Duration,
+    /// If set, the resources of a repo that had no request for this long are released, and the
+    /// repo is opened again on its next request
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for OpenRepoParams {
+    fn default() -> Self {
+        OpenRepoParams {
+            timeout: Duration::from_secs(600),
+            progress_interval: Duration::from_secs(5),
+            idle_timeout: None,
+        }
+    }
+}
+
+/// A backend that has to be up before a repo that uses it can be opened
+pub trait BackendReadiness: Send + Sync {
+    /// Name of the backend in progress and error messages
+    fn description(&self) -> String;
+
+    /// Resolves once the backend is up
+    fn wait(&self) -> BoxFuture<(), Error>;
+}
+
+pub struct MyrouterReadiness {
+    port: u16,
+    db_address: String,
+}
+
+impl MyrouterReadiness {
+    pub fn new<S: Into<String>>(port: u16, db_address: S) -> Self {
+        MyrouterReadiness {
+            port,
+            db_address: db_address.into(),
+        }
+    }
+}
+
+impl BackendReadiness for MyrouterReadiness {
+    fn description(&self) -> String {
+        format!("myrouter on port {} for {}", self.port, self.db_address)
+    }
+
+    fn wait(&self) -> BoxFuture<(), Error> {
+        myrouter::wait_for_myrouter(self.port, &self.db_address).boxify()
+    }
+}
+
+fn flatten_timeout<T>(
+    res: ::std::result::Result<T, ::tokio::timer::timeout::Error<Error>>,
+    on_elapsed: ErrorKind,
+) -> Result<T> {
+    match res {
+        Ok(value) => Ok(value),
+        Err(err) => if err.is_inner() {
+            Err(err.into_inner().expect("checked by is_inner"))
+        } else if err.is_elapsed() {
+            Err(on_elapsed.into())
+        } else {
+            Err(err.into_timer().expect("neither inner nor elapsed").into())
+        },
+    }
+}
+
+fn wait_for_backend(
+    backend: &BackendReadiness,
+    params: OpenRepoParams,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let description = backend.description();
+    let start = Instant::now();
+
+    // Never finishes successfully, so it only stops the wait if the timer fails
+    let progress = Interval::new(start + params.progress_interval, params.progress_interval)
+        .from_err()
+        .for_each({
+            cloned!(description, logger);
+            move |_| {
+                info!(
+                    logger,
+                    "waiting for {}... {}s",
+                    description,
+                    start.elapsed().as_secs()
+                );
+                Ok(())
+            }
+        });
+
+    backend
+        .wait()
+        .select(progress)
+        .map(|((), _)| ())
+        .map_err(|(err, _)| err)
+        .timeout(params.timeout)
+        .then(move |res| {
+            let on_elapsed = ErrorKind::BackendTimeout(description.clone(), params.timeout);
+            let res = flatten_timeout(res, on_elapsed);
+            if res.is_ok() {
+                debug!(logger, "{} is ready", description);
+            }
+            res
+        })
+        .boxify()
+}
+
+/// Waits for all of `backends` concurrently, logging which ones are still being waited for every
+/// `params.progress_interval`. Fails with `ErrorKind::BackendTimeout` naming the backend if any of
+/// them isn't ready within `params.timeout`.
+pub fn wait_for_backends(
+    backends: Vec<Box<BackendReadiness>>,
+    params: OpenRepoParams,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let waits: Vec<_> = backends
+        .iter()
+        .map(|backend| wait_for_backend(&**backend, params, logger.clone()))
+        .collect();
+    future::join_all(waits).map(|_| ()).boxify()
+}
+
+/// Waits for `backends`, then calls the blocking `open` on a thread that is allowed to block.
+/// Both together must take less than `params.timeout`. Note that `open` isn't interrupted if it
+/// runs out of time, its result is just ignored.
+pub fn open_after_backends<T, F>(
+    backends: Vec<Box<BackendReadiness>>,
+    params: OpenRepoParams,
+    logger: Logger,
+    open: F,
+) -> BoxFuture<T, Error>
+where
+    T: Send + 'static,
+    F: FnMut() -> Result<T> + Send + 'static,
+{
+    let start = Instant::now();
+    wait_for_backends(backends, params, logger)
+        .and_then(move |()| {
+            let remaining = params
+                .timeout
+                .checked_sub(start.elapsed())
+                .unwrap_or(Duration::from_secs(0));
+            asynchronize(open).timeout(remaining).then(move |res| {
+                flatten_timeout(res, ErrorKind::OpenRepoTimeout(params.timeout))
+            })
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use slog::{Discard, Drain};
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    struct NeverReady;
+
+    impl BackendReadiness for NeverReady {
+        fn description(&self) -> String {
+            "stub backend".to_string()
+        }
+
+        fn wait(&self) -> BoxFuture<(), Error> {
+            future::empty().boxify()
+        }
+    }
+
+    struct ReadyAfter(Duration);
+
+    impl BackendReadiness for ReadyAfter {
+        fn description(&self) -> String {
+            "slow backend".to_string()
+        }
+
+        fn wait(&self) -> BoxFuture<(), Error> {
+            Delay::new(Instant::now() + self.0).from_err().boxify()
+        }
+    }
+
+    fn logger() -> Logger {
+        Logger::root(Discard {}.ignore_res(), o!())
+    }
+
+    fn params(timeout_ms: u64) -> OpenRepoParams {
+        OpenRepoParams {
+            timeout: Duration::from_millis(timeout_ms),
+            progress_interval: Duration::from_millis(10),
+            idle_timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_never_ready_backend_times_out() {
+        let opened = Arc::new(AtomicBool::new(false));
+        let backends: Vec<Box<BackendReadiness>> = vec![
+            Box::new(ReadyAfter(Duration::from_millis(1))),
+            Box::new(NeverReady),
+        ];
+        let fut = open_after_backends(
+            backends,
+            params(100),
+            logger(),
+            {
+                cloned!(opened);
+                move || {
+                    opened.store(true, Ordering::Relaxed);
+                    Ok(())
+                }
+            },
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        let err = runtime.block_on(fut).expect_err("opening must time out");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::BackendTimeout(ref backend, timeout)) => {
+                assert_eq!(backend, "stub backend");
+                assert_eq!(timeout, Duration::from_millis(100));
+            }
+            bad => panic!("unexpected result {:?}", bad),
+        }
+        assert!(!opened.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_timeout_message_names_backend() {
+        let err: Error = ErrorKind::BackendTimeout(
+            NeverReady.description(),
+            Duration::from_secs(5),
+        ).into();
+        assert!(err.to_string().ends_with("waiting for stub backend"));
+    }
+
+    #[test]
+    fn test_opens_after_backends_are_ready() {
+        let backends: Vec<Box<BackendReadiness>> =
+            vec![Box::new(ReadyAfter(Duration::from_millis(30)))];
+        let fut = open_after_backends(
+            backends,
+            params(10_000),
+            logger(),
+            || Ok(42),
+        );
+
+        let mut runtime = Runtime::new().unwrap();
+        assert_eq!(runtime.block_on(fut).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_open_failure() {
+        let fut = open_after_backends(vec![], params(10_000), logger(), || -> Result<()> {
+            Err(format_err!("cannot open"))
+        });
+
+        let mut runtime = Runtime::new().unwrap();
+        let err = runtime.block_on(fut).expect_err("opening must fail");
+        assert_eq!(err.to_string(), "cannot open");
+    }
+}
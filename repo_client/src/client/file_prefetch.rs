@@ -0,0 +1,414 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Prefetching of the files of the trees that gettreepack serves.
+//!
+//! Clients usually follow a gettreepack of a directory with a getfiles of most of the files in
+//! it, so once a tree is served, the envelopes of its files, and optionally their small contents,
+//! are fetched in the background to warm up the caches of the blobstore. The response never
+//! waits for prefetches: they are queued after the tree was fetched for the response, and a
+//! prefetch that doesn't fit in the budget of the server is dropped.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use futures::sync::mpsc;
+use futures_ext::{BoxFuture, FutureExt};
+use stats::Timeseries;
+use tokio;
+
+use blobrepo::BlobRepo;
+use mercurial_types::{HgManifestId, HgNodeHash, Type};
+use metaconfig::FilePrefetchParams;
+
+/// Max number of prefetches that are queued or running at a time, for all the repos of a server
+const MAX_PREFETCHES_IN_FLIGHT: usize = 10_000;
+/// Max number of prefetches of a repo that run at the same time
+const PREFETCH_CONCURRENCY: usize = 100;
+/// How long a file isn't prefetched again after it was, and how long a getfiles of it counts as
+/// a hit of the prefetch
+const RECENT_PREFETCH_TTL_SECS: u64 = 300;
+/// Number of recent prefetches that are remembered by a repo. Expired ones are dropped when it
+/// would have more, and all of them if that is not enough.
+const MAX_RECENT_PREFETCHES: usize = 100_000;
+
+define_stats! {
+    prefix = "mononoke.repo_client.file_prefetch";
+    issued: timeseries(RATE, SUM),
+    content_issued: timeseries(RATE, SUM),
+    deduplicated: timeseries(RATE, SUM),
+    over_budget: timeseries(RATE, SUM),
+    failed: timeseries(RATE, SUM),
+    hits: timeseries(RATE, SUM),
+}
+
+lazy_static! {
+    static ref GLOBAL_BUDGET: PrefetchBudget = PrefetchBudget::new(MAX_PREFETCHES_IN_FLIGHT);
+}
+
+/// Bounds the number of prefetches that are queued or running at a time, across the prefetchers
+/// that share it
+#[derive(Clone, Debug)]
+pub struct PrefetchBudget {
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: usize,
+}
+
+impl PrefetchBudget {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight,
+        }
+    }
+
+    /// The budget that all the repos of the server share
+    pub fn global() -> Self {
+        GLOBAL_BUDGET.clone()
+    }
+
+    /// Number of prefetches that are queued or running
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn try_acquire(&self) -> Option<PrefetchPermit> {
+        if self.in_flight.fetch_add(1, Ordering::SeqCst) >= self.max_in_flight {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(PrefetchPermit {
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+/// A prefetch that counts against the budget until it is dropped
+struct PrefetchPermit {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for PrefetchPermit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct FilePrefetch {
+    node: HgNodeHash,
+    _permit: PrefetchPermit,
+}
+
+/// Prefetches the files of the trees of a repo that gettreepack serves
+#[derive(Clone)]
+pub struct FilePrefetcher {
+    repo: BlobRepo,
+    budget: PrefetchBudget,
+    ttl: Duration,
+    /// Files prefetched recently, with the time until which they are not prefetched again
+    recent: Arc<Mutex<HashMap<HgNodeHash, Instant>>>,
+    sender: mpsc::UnboundedSender<FilePrefetch>,
+}
+
+impl FilePrefetcher {
+    /// Returns the prefetcher, and the task that runs the prefetches it queues, which ends once
+    /// every clone of the prefetcher is dropped
+    pub fn new(
+        repo: BlobRepo,
+        params: FilePrefetchParams,
+        budget: PrefetchBudget,
+    ) -> (Self, BoxFuture<(), ()>) {
+        let (prefetcher, queue) = Self::with_queue(repo.clone(), budget);
+        let worker = queue
+            .map(move |prefetch| prefetch_file(&repo, &params, prefetch))
+            .buffer_unordered(PREFETCH_CONCURRENCY)
+            .for_each(|()| Ok(()))
+            .boxify();
+        (prefetcher, worker)
+    }
+
+    fn with_queue(
+        repo: BlobRepo,
+        budget: PrefetchBudget,
+    ) -> (Self, mpsc::UnboundedReceiver<FilePrefetch>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let prefetcher = Self {
+            repo,
+            budget,
+            ttl: Duration::from_secs(RECENT_PREFETCH_TTL_SECS),
+            recent: Arc::new(Mutex::new(HashMap::new())),
+            sender,
+        };
+        (prefetcher, receiver)
+    }
+
+    /// Queues the prefetch of the files of the tree `mfid` in the background, and returns
+    /// without waiting for anything
+    pub fn prefetch_tree(&self, mfid: HgManifestId) {
+        let this = self.clone();
+        let files = self.repo
+            .get_manifest_by_nodeid(&mfid)
+            .map(move |manifest| {
+                let files = manifest
+                    .list()
+                    .filter(|entry| entry.get_type() != Type::Tree)
+                    .map(|entry| entry.get_hash().into_nodehash());
+                this.enqueue(files);
+            })
+            .or_else(|_| {
+                STATS::failed.add_value(1);
+                Ok::<_, ()>(())
+            });
+        tokio::spawn(files);
+    }
+
+    /// Queues the files of `nodes` that were not prefetched recently, as long as the budget
+    /// allows. Files that don't fit in the budget are not remembered, so that they can be
+    /// prefetched with a later tree.
+    fn enqueue<I>(&self, nodes: I)
+    where
+        I: IntoIterator<Item = HgNodeHash>,
+    {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().expect("lock poisoned");
+        for node in nodes {
+            if recent.get(&node).map_or(false, |deadline| *deadline > now) {
+                STATS::deduplicated.add_value(1);
+                continue;
+            }
+            let permit = match self.budget.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    STATS::over_budget.add_value(1);
+                    continue;
+                }
+            };
+            if recent.len() >= MAX_RECENT_PREFETCHES {
+                recent.retain(|_, deadline| *deadline > now);
+                if recent.len() >= MAX_RECENT_PREFETCHES {
+                    recent.clear();
+                }
+            }
+            recent.insert(node, now + self.ttl);
+            STATS::issued.add_value(1);
+            // This only fails if the worker was dropped, e.g. on shutdown
+            let _ = self.sender.unbounded_send(FilePrefetch {
+                node,
+                _permit: permit,
+            });
+        }
+    }
+
+    /// Called when a client fetches the file `node`. Returns whether it was prefetched recently,
+    /// in which case it counts as a hit of the prefetch, at most once. Whether the fetch was
+    /// served from the cache is not known here, so this is an approximation.
+    pub fn record_fetch(&self, node: &HgNodeHash) -> bool {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().expect("lock poisoned");
+        let hit = recent.get(node).map_or(false, |deadline| *deadline > now);
+        if hit {
+            STATS::hits.add_value(1);
+            recent.remove(node);
+        }
+        hit
+    }
+}
+
+/// Fetches the envelope of the file, and its content if it is small enough and the repo is
+/// configured to prefetch contents. Failures are only counted.
+fn prefetch_file(
+    repo: &BlobRepo,
+    params: &FilePrefetchParams,
+    prefetch: FilePrefetch,
+) -> BoxFuture<(), ()> {
+    let node = prefetch.node;
+    let content = params.content;
+    let max_content_bytes = params.max_content_bytes;
+    cloned!(repo);
+    repo.get_file_envelope(&node)
+        .and_then(move |envelope| {
+            if content && envelope.content_size() <= max_content_bytes {
+                STATS::content_issued.add_value(1);
+                repo.get_file_content(&node).map(|_| ()).left_future()
+            } else {
+                future::ok(()).right_future()
+            }
+        })
+        .then(move |res| {
+            if res.is_err() {
+                STATS::failed.add_value(1);
+            }
+            drop(prefetch);
+            Ok(())
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use async_unit;
+    use fixtures::many_files_dirs;
+    use mercurial_types::{Changeset, HgChangesetId};
+    use mercurial_types::manifest_utils::{walk_manifest, PathFilter};
+    use tracing::TraceContext;
+    use uuid::Uuid;
+
+    use client::{create_treepack_stream, get_all_manifests_stream};
+
+    /// Commit of many_files_dirs with files at the root, in dir1, dir1/subdir1 and dir2
+    const WITH_DIRS: &str = "2f866e7e549760934e31bf0420a873f65100ad63";
+
+    fn root_manifest(repo: &BlobRepo) -> HgManifestId {
+        let csid = HgChangesetId::from_str(WITH_DIRS).unwrap();
+        *repo.get_changeset_by_changesetid(&csid)
+            .wait()
+            .unwrap()
+            .manifestid()
+    }
+
+    /// Serves every tree of WITH_DIRS with `prefetcher`, and returns what it queued
+    fn serve_treepack(
+        repo: &BlobRepo,
+        prefetcher: FilePrefetcher,
+        queue: mpsc::UnboundedReceiver<FilePrefetch>,
+    ) -> Vec<FilePrefetch> {
+        let trace = TraceContext::new(Uuid::new_v4(), Instant::now());
+        let entries = get_all_manifests_stream(
+            repo,
+            &root_manifest(repo),
+            None,
+            2 << 16,
+            trace.clone(),
+        );
+        create_treepack_stream(repo, entries, None, Some(prefetcher), 100, trace)
+            .collect()
+            .wait()
+            .unwrap();
+        // The queue ends once the response and the prefetches it started are done with the
+        // prefetcher
+        queue.collect().wait().unwrap()
+    }
+
+    fn all_files(repo: &BlobRepo) -> HashSet<HgNodeHash> {
+        let root = repo.get_manifest_by_nodeid(&root_manifest(repo))
+            .wait()
+            .unwrap();
+        walk_manifest(&root, None, PathFilter::all())
+            .filter(|&(_, ref entry)| entry.get_type() != Type::Tree)
+            .map(|(_, entry)| entry.get_hash().into_nodehash())
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_treepack_prefetches_files() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let budget = PrefetchBudget::new(1000);
+            let (prefetcher, queue) = FilePrefetcher::with_queue(repo.clone(), budget.clone());
+
+            let prefetched = serve_treepack(&repo, prefetcher, queue);
+            let nodes: Vec<_> = prefetched.iter().map(|prefetch| prefetch.node).collect();
+            let unique: HashSet<_> = nodes.iter().cloned().collect();
+            assert_eq!(nodes.len(), unique.len());
+            assert_eq!(unique, all_files(&repo));
+            assert_eq!(budget.in_flight(), nodes.len());
+            drop(prefetched);
+            assert_eq!(budget.in_flight(), 0);
+        })
+    }
+
+    #[test]
+    fn test_treepack_prefetch_budget() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let budget = PrefetchBudget::new(3);
+            let (prefetcher, queue) = FilePrefetcher::with_queue(repo.clone(), budget.clone());
+
+            let prefetched = serve_treepack(&repo, prefetcher, queue);
+            assert_eq!(prefetched.len(), 3);
+            assert_eq!(budget.in_flight(), 3);
+            let files = all_files(&repo);
+            assert!(files.len() > 3);
+            for prefetch in &prefetched {
+                assert!(files.contains(&prefetch.node));
+            }
+            drop(prefetched);
+            assert_eq!(budget.in_flight(), 0);
+        })
+    }
+
+    fn prefetched(queue: mpsc::UnboundedReceiver<FilePrefetch>) -> Vec<HgNodeHash> {
+        queue
+            .map(|prefetch| prefetch.node)
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    fn nodes(count: u8) -> Vec<HgNodeHash> {
+        (1..count + 1)
+            .map(|i| HgNodeHash::from_bytes(&[i; 20]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_enqueue_budget_and_dedup() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let budget = PrefetchBudget::new(3);
+            let (prefetcher, queue) = FilePrefetcher::with_queue(repo, budget.clone());
+            let nodes = nodes(5);
+
+            prefetcher.enqueue(nodes.clone());
+            // Only the first 3 fit in the budget, the same node twice is queued once
+            prefetcher.enqueue(nodes[..1].to_vec());
+            assert_eq!(budget.in_flight(), 3);
+            assert!(prefetcher.record_fetch(&nodes[0]));
+            // A hit counts once
+            assert!(!prefetcher.record_fetch(&nodes[0]));
+            assert!(!prefetcher.record_fetch(&nodes[4]));
+
+            drop(prefetcher);
+            assert_eq!(prefetched(queue), nodes[..3].to_vec());
+            assert_eq!(budget.in_flight(), 0);
+        })
+    }
+
+    #[test]
+    fn test_over_budget_is_prefetched_later() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let budget = PrefetchBudget::new(2);
+            let (prefetcher, queue) = FilePrefetcher::with_queue(repo, budget.clone());
+            let nodes = nodes(4);
+
+            prefetcher.enqueue(nodes.clone());
+            // The budget is freed once the queued prefetches are done
+            let mut queue = queue.wait();
+            let first: Vec<_> = (0..2).map(|_| queue.next().unwrap().unwrap()).collect();
+            assert_eq!(budget.in_flight(), 2);
+            drop(first);
+            assert_eq!(budget.in_flight(), 0);
+
+            prefetcher.enqueue(nodes.clone());
+            drop(prefetcher);
+            let rest: HashSet<_> = queue.map(|prefetch| prefetch.unwrap().node).collect();
+            assert_eq!(rest, nodes[2..].iter().cloned().collect());
+        })
+    }
+}
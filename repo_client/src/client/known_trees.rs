@@ -0,0 +1,343 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Answers to `knowntrees`, which tells clients which of the trees they have are still the tree
+//! at their directory in the current root manifest of a bookmark, so that they don't need to
+//! gettreepack them again.
+//!
+//! A request looks up each of its directories with a walk down from the root manifest, which is
+//! as deep as the directory. Clients of a repo ask about the same few bookmarks and directories
+//! over and over, so the root manifest of a bookmark and the trees that were looked up in it are
+//! cached for a short time.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobrepo::BlobRepo;
+use bookmarks::Bookmark;
+use mercurial_types::{Changeset, HgManifestId, MPath};
+
+use errors::*;
+
+/// Number of directories whose tree is cached for a bookmark. The cache of a bookmark is
+/// cleared when it would have more.
+const MAX_CACHED_TREES: usize = 100_000;
+
+/// What is known of the root manifest of a bookmark
+struct BookmarkTrees {
+    deadline: Instant,
+    /// None if the bookmark doesn't exist
+    root: Option<HgManifestId>,
+    /// Tree at each directory that was looked up, None if there is no such directory
+    trees: HashMap<Bytes, Option<HgManifestId>>,
+}
+
+#[derive(Clone)]
+pub struct KnownTrees {
+    bookmarks: Arc<Mutex<HashMap<Bookmark, BookmarkTrees>>>,
+    ttl: Duration,
+    max_nodes: usize,
+}
+
+impl KnownTrees {
+    /// Answers are at most `ttl` old, and requests ask about at most `max_nodes` trees
+    pub fn new(ttl: Duration, max_nodes: usize) -> Self {
+        Self {
+            bookmarks: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+            max_nodes,
+        }
+    }
+
+    /// Number of (directory, manifest) pairs that a request can ask about
+    pub fn max_nodes(&self) -> usize {
+        self.max_nodes
+    }
+
+    /// Whether each manifest of `nodes` is the tree at its directory, where the empty directory
+    /// is the root, in the root manifest of `bookmark`. A manifest is not if the bookmark or the
+    /// directory doesn't exist, or if the repo doesn't know about it.
+    pub fn check(
+        &self,
+        repo: &BlobRepo,
+        bookmark: Bookmark,
+        nodes: Vec<(Bytes, HgManifestId)>,
+    ) -> BoxFuture<Vec<bool>, Error> {
+        if nodes.len() > self.max_nodes {
+            return future::err(ErrorKind::TooManyKnownTrees(nodes.len(), self.max_nodes).into())
+                .boxify();
+        }
+
+        let now = Instant::now();
+        let cached = {
+            let mut bookmarks = self.bookmarks.lock().expect("lock poisoned");
+            bookmarks.retain(|_, known| known.deadline > now);
+            bookmarks.get(&bookmark).map(|known| {
+                let trees: HashMap<_, _> = nodes
+                    .iter()
+                    .filter_map(|&(ref dir, _)| {
+                        known.trees.get(dir).map(|tree| (dir.clone(), *tree))
+                    })
+                    .collect();
+                (known.root, trees)
+            })
+        };
+        let (root, cached_trees) = match cached {
+            Some((root, trees)) => (future::ok(root).left_future(), trees),
+            None => (get_root(repo, &bookmark).right_future(), HashMap::new()),
+        };
+
+        cloned!(repo, self.bookmarks, self.ttl);
+        root.and_then(move |root| {
+            let mut lookups: Vec<_> = nodes
+                .iter()
+                .map(|&(ref dir, _)| dir.clone())
+                .filter(|dir| !cached_trees.contains_key(dir))
+                .collect();
+            lookups.sort();
+            lookups.dedup();
+            let lookups = lookups.into_iter().map(move |dir| {
+                find_tree(&repo, root, &dir).map(move |tree| (dir, tree))
+            });
+
+            future::join_all(lookups).map(move |found| {
+                let mut bookmarks = bookmarks.lock().expect("lock poisoned");
+                let known = bookmarks.entry(bookmark).or_insert_with(|| BookmarkTrees {
+                    deadline: now + ttl,
+                    root,
+                    trees: HashMap::new(),
+                });
+                // The bookmark could have been cached again, with another root, in the meantime
+                if known.root == root {
+                    if known.trees.len() + found.len() > MAX_CACHED_TREES {
+                        known.trees.clear();
+                    }
+                    known.trees.extend(found.iter().cloned());
+                }
+
+                let mut trees = cached_trees;
+                trees.extend(found);
+                nodes
+                    .iter()
+                    .map(|&(ref dir, node)| trees.get(dir) == Some(&Some(node)))
+                    .collect()
+            })
+        }).boxify()
+    }
+}
+
+fn get_root(repo: &BlobRepo, bookmark: &Bookmark) -> BoxFuture<Option<HgManifestId>, Error> {
+    cloned!(repo);
+    repo.get_bookmark(bookmark)
+        .and_then(move |csid| match csid {
+            Some(csid) => repo.get_changeset_by_changesetid(&csid)
+                .map(|cs| Some(*cs.manifestid()))
+                .left_future(),
+            None => future::ok(None).right_future(),
+        })
+        .boxify()
+}
+
+fn find_tree(
+    repo: &BlobRepo,
+    root: Option<HgManifestId>,
+    dir: &Bytes,
+) -> BoxFuture<Option<HgManifestId>, Error> {
+    let root = match root {
+        Some(root) => root,
+        None => return future::ok(None).boxify(),
+    };
+    let path = if dir.is_empty() {
+        None
+    } else {
+        match MPath::new(dir.as_ref()) {
+            Ok(path) => Some(path),
+            // Not a directory that can be in the repo
+            Err(_) => return future::ok(None).boxify(),
+        }
+    };
+    repo.find_tree_in_manifest(path, root).boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use async_unit;
+    use fixtures::many_files_dirs;
+    use mercurial_types::{HgChangesetId, HgNodeHash};
+
+    // Commits of many_files_dirs. The second one changes dir1/subdir1 but not dir2, and the
+    // third one replaces dir1 with a file.
+    const WITH_DIRS: &str = "2f866e7e549760934e31bf0420a873f65100ad63";
+    const SUBDIR_CHANGED: &str = "d261bc7900818dea7c86935b3fb17a33b2e3a6b4";
+    const DIR_REPLACED: &str = "0c59c8d0da93cbf9d7f4b888f28823ffb2e3e480";
+
+    /// many_files_dirs has a bookmark at each of its commits
+    fn bookmark(csid: &str) -> Bookmark {
+        Bookmark::new(format!("bookmark-{}", csid)).unwrap()
+    }
+
+    /// The trees at the root, dir1, dir1/subdir1 and dir2 in WITH_DIRS
+    fn trees(repo: &BlobRepo) -> Vec<(Bytes, HgManifestId)> {
+        let csid = HgChangesetId::from_str(WITH_DIRS).unwrap();
+        let root = *repo.get_changeset_by_changesetid(&csid)
+            .wait()
+            .unwrap()
+            .manifestid();
+        let mut trees = vec![(Bytes::new(), root)];
+        for dir in &["dir1", "dir1/subdir1", "dir2"] {
+            let path = MPath::new(dir).unwrap();
+            let tree = repo.find_tree_in_manifest(Some(path), root)
+                .wait()
+                .unwrap()
+                .expect("tree is missing");
+            trees.push((Bytes::from(*dir), tree));
+        }
+        trees
+    }
+
+    fn check(
+        known_trees: &KnownTrees,
+        repo: &BlobRepo,
+        csid: &str,
+        nodes: Vec<(Bytes, HgManifestId)>,
+    ) -> Vec<bool> {
+        known_trees
+            .check(repo, bookmark(csid), nodes)
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_known_trees() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let trees = trees(&repo);
+            let known_trees = KnownTrees::new(Duration::from_secs(60), 100);
+
+            assert_eq!(
+                check(&known_trees, &repo, WITH_DIRS, trees.clone()),
+                vec![true, true, true, true]
+            );
+            // The root and dir1 changed with dir1/subdir1, dir2 didn't
+            assert_eq!(
+                check(&known_trees, &repo, SUBDIR_CHANGED, trees.clone()),
+                vec![false, false, false, true]
+            );
+            assert_eq!(
+                check(&known_trees, &repo, DIR_REPLACED, trees.clone()),
+                vec![false, false, false, true]
+            );
+            assert_eq!(check(&known_trees, &repo, WITH_DIRS, vec![]), vec![]);
+        })
+    }
+
+    #[test]
+    fn test_unknown_trees() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let trees = trees(&repo);
+            let dir2 = trees[3].1;
+            let unknown = HgManifestId::new(HgNodeHash::from_str(&"1".repeat(40)).unwrap());
+            let known_trees = KnownTrees::new(Duration::from_secs(60), 100);
+
+            let nodes = vec![
+                // A tree that the repo doesn't know about
+                (Bytes::from("dir2"), unknown),
+                // A tree at another directory
+                (Bytes::from("dir1"), dir2),
+                // Directories that don't exist, or can't
+                (Bytes::from("dir3"), dir2),
+                (Bytes::from("2"), dir2),
+                (Bytes::from("/"), dir2),
+                // The same pair twice
+                (Bytes::from("dir2"), dir2),
+                (Bytes::from("dir2"), dir2),
+            ];
+            assert_eq!(
+                check(&known_trees, &repo, WITH_DIRS, nodes.clone()),
+                vec![false, false, false, false, false, true, true]
+            );
+
+            // Nothing is known about a bookmark that doesn't exist
+            let res = known_trees
+                .check(&repo, Bookmark::new("nope").unwrap(), trees)
+                .wait()
+                .unwrap();
+            assert_eq!(res, vec![false, false, false, false]);
+        })
+    }
+
+    #[test]
+    fn test_known_trees_max_nodes() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let trees = trees(&repo);
+            let known_trees = KnownTrees::new(Duration::from_secs(60), 3);
+
+            assert_eq!(
+                check(&known_trees, &repo, WITH_DIRS, trees[..3].to_vec()),
+                vec![true, true, true]
+            );
+            let err = known_trees
+                .check(&repo, bookmark(WITH_DIRS), trees)
+                .wait()
+                .expect_err("too many nodes must be rejected");
+            match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::TooManyKnownTrees(4, 3)) => {}
+                bad => panic!("unexpected result {:?}", bad),
+            }
+        })
+    }
+
+    #[test]
+    fn test_known_trees_cache() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let trees = trees(&repo);
+            let moving = Bookmark::new("moving").unwrap();
+            let move_to = |csid: &str| {
+                let csid = HgChangesetId::from_str(csid).unwrap();
+                let bcs_id = repo.get_bonsai_from_hg(&csid).wait().unwrap().unwrap();
+                let mut txn = repo.update_bookmark_transaction();
+                txn.force_set(&moving, &bcs_id).unwrap();
+                txn.commit().wait().unwrap();
+            };
+            let check_moving = |known_trees: &KnownTrees| {
+                known_trees
+                    .check(&repo, moving.clone(), trees.clone())
+                    .wait()
+                    .unwrap()
+            };
+
+            move_to(WITH_DIRS);
+            let cached = KnownTrees::new(Duration::from_secs(3600), 100);
+            let uncached = KnownTrees::new(Duration::from_secs(0), 100);
+            let check_some = |known_trees: &KnownTrees, nodes: &[(Bytes, HgManifestId)]| {
+                known_trees
+                    .check(&repo, moving.clone(), nodes.to_vec())
+                    .wait()
+                    .unwrap()
+            };
+            assert_eq!(check_some(&cached, &trees[..2]), vec![true, true]);
+            assert_eq!(check_some(&uncached, &trees[..2]), vec![true, true]);
+
+            // Answers come from the root manifest in the cache until it expires, including for
+            // directories that weren't asked about before
+            move_to(SUBDIR_CHANGED);
+            assert_eq!(check_moving(&cached), vec![true, true, true, true]);
+            assert_eq!(check_moving(&uncached), vec![false, false, false, true]);
+        })
+    }
+}
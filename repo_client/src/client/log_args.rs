@@ -0,0 +1,204 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Formats the arguments of commands for scuba. The pipeline drops samples that are too large
+//! with all their columns, so lists of arguments are truncated and logged in a column of their
+//! own, while their exact sizes are logged as numbers.
+
+use std::borrow::Cow;
+
+use bytes::Bytes;
+use itertools::Itertools;
+
+use hgproto::GettreepackArgs;
+use mercurial_types::{HgManifestId, HgNodeHash, MPath};
+use mononoke_types::maybe_utf8::NON_UTF8_MARKER;
+use scuba_ext::ScubaSampleBuilder;
+
+/// At most this many entries of each list argument are logged
+pub const MAX_LIST_ENTRIES_TO_LOG: usize = 20;
+/// Paths are cut to at most this many bytes when logged
+pub const MAX_PATH_BYTES_TO_LOG: usize = 256;
+/// Names of arguments are cut to at most this many bytes when logged
+pub const MAX_ARG_NAME_BYTES_TO_LOG: usize = 64;
+
+/// Joins the first `max` entries, followed by the number of the ones left out
+pub fn format_truncated_list<I>(entries: I, max: usize) -> String
+where
+    I: ExactSizeIterator<Item = String>,
+{
+    let total = entries.len();
+    let mut formatted = entries.take(max).join(" ");
+    if total > max {
+        if !formatted.is_empty() {
+            formatted.push(' ');
+        }
+        formatted.push_str(&format!("... and {} more", total - max));
+    }
+    formatted
+}
+
+/// Formats a path that a client sent as arbitrary bytes. Invalid UTF-8 is replaced and marked,
+/// as described in `mononoke_types::maybe_utf8`, and paths longer than `max_bytes` are cut at a
+/// character boundary and followed by their full length.
+pub fn format_path_lossy(path: &[u8], max_bytes: usize) -> String {
+    let formatted = String::from_utf8_lossy(path);
+    let marker = match formatted {
+        Cow::Borrowed(_) => "",
+        Cow::Owned(_) => NON_UTF8_MARKER,
+    };
+    if formatted.len() <= max_bytes {
+        return format!("{}{}", formatted, marker);
+    }
+    let mut end = max_bytes;
+    while !formatted.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes){}", &formatted[..end], path.len(), marker)
+}
+
+fn format_nodes_list(nodes: &[HgManifestId]) -> String {
+    let mut nodes: Vec<_> = nodes.iter().collect();
+    nodes.sort();
+    format_truncated_list(
+        nodes.into_iter().map(|node| format!("{}", node)),
+        MAX_LIST_ENTRIES_TO_LOG,
+    )
+}
+
+fn format_paths_list(paths: &[Bytes]) -> String {
+    let mut paths: Vec<_> = paths.iter().collect();
+    paths.sort();
+    format_truncated_list(
+        paths
+            .into_iter()
+            .map(|path| format_path_lossy(path, MAX_PATH_BYTES_TO_LOG)),
+        MAX_LIST_ENTRIES_TO_LOG,
+    )
+}
+
+fn format_designated_nodes(nodes: &[(Bytes, HgManifestId)]) -> String {
+    format_truncated_list(
+        nodes.iter().map(|(dir, node)| {
+            format!("{}:{}", format_path_lossy(dir, MAX_PATH_BYTES_TO_LOG), node)
+        }),
+        MAX_LIST_ENTRIES_TO_LOG,
+    )
+}
+
+/// Adds the arguments of gettreepack to `scuba`: the root directory and the sizes of the lists
+/// in columns of their own, and the truncated lists in `command_args_lists` if there are any
+pub fn add_gettreepack_args(scuba: &mut ScubaSampleBuilder, params: &GettreepackArgs) {
+    scuba
+        .add(
+            "rootdir",
+            format_path_lossy(&params.rootdir, MAX_PATH_BYTES_TO_LOG),
+        )
+        .add("mfnodes_count", params.mfnodes.len())
+        .add("basemfnodes_count", params.basemfnodes.len())
+        .add("directories_count", params.directories.len())
+        .add("designatednodes_count", params.designatednodes.len());
+
+    if params.mfnodes.is_empty() && params.basemfnodes.is_empty() && params.directories.is_empty()
+        && params.designatednodes.is_empty()
+    {
+        return;
+    }
+    let lists = format!(
+        "mfnodes: {}, basemfnodes: {}, directories: {}, designatednodes: {}",
+        format_nodes_list(&params.mfnodes),
+        format_nodes_list(&params.basemfnodes),
+        format_paths_list(&params.directories),
+        format_designated_nodes(&params.designatednodes),
+    );
+    scuba.add("command_args_lists", lists);
+}
+
+/// Formats the names of arguments that a client sent and that the command doesn't understand.
+/// Clients choose them freely, so both their number and their lengths are capped.
+pub fn format_unknown_args(unknown_args: &[(Bytes, Bytes)]) -> String {
+    format_truncated_list(
+        unknown_args
+            .iter()
+            .map(|(name, _)| format_path_lossy(name, MAX_ARG_NAME_BYTES_TO_LOG)),
+        MAX_LIST_ENTRIES_TO_LOG,
+    )
+}
+
+/// Formats the arguments of one file of getfiles
+pub fn format_getfiles_args(node: &HgNodeHash, path: &MPath) -> String {
+    format!(
+        "node: {}, path: {}",
+        node,
+        format_path_lossy(&path.to_vec(), MAX_PATH_BYTES_TO_LOG)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entries(count: usize) -> Vec<String> {
+        (0..count).map(|idx| format!("e{}", idx)).collect()
+    }
+
+    #[test]
+    fn test_format_truncated_list() {
+        assert_eq!(format_truncated_list(entries(0).into_iter(), 3), "");
+        assert_eq!(format_truncated_list(entries(3).into_iter(), 3), "e0 e1 e2");
+        assert_eq!(
+            format_truncated_list(entries(4).into_iter(), 3),
+            "e0 e1 e2 ... and 1 more"
+        );
+        assert_eq!(
+            format_truncated_list(entries(30000).into_iter(), 2),
+            "e0 e1 ... and 29998 more"
+        );
+        assert_eq!(
+            format_truncated_list(entries(2).into_iter(), 0),
+            "... and 2 more"
+        );
+    }
+
+    #[test]
+    fn test_format_path_lossy() {
+        assert_eq!(format_path_lossy(b"dir/file", 8), "dir/file");
+        assert_eq!(format_path_lossy(b"dir/file", 3), "dir... (8 bytes)");
+        assert_eq!(format_path_lossy(b"", 3), "");
+
+        // Invalid UTF-8 is replaced and marked instead of failing
+        assert_eq!(format_path_lossy(b"a\xffb", 10), "a\u{fffd}b [non-utf8]");
+
+        // Multi-byte characters are not cut in half. "\u{e9}" is two bytes, and a replacement
+        // character is three.
+        assert_eq!(
+            format_path_lossy("\u{e9}\u{e9}".as_bytes(), 3),
+            "\u{e9}... (4 bytes)"
+        );
+        assert_eq!(
+            format_path_lossy(b"\xff\xff", 4),
+            "\u{fffd}... (2 bytes) [non-utf8]"
+        );
+        assert_eq!(format_path_lossy(b"\xffa", 2), "... (2 bytes) [non-utf8]");
+    }
+
+    #[test]
+    fn test_format_unknown_args() {
+        let long_name = Bytes::from(vec![b'n'; 1000]);
+        let unknown_args: Vec<_> = (0..MAX_LIST_ENTRIES_TO_LOG + 5)
+            .map(|_| (long_name.clone(), Bytes::from("1")))
+            .collect();
+        let cut_name = format!("{}... (1000 bytes)", "n".repeat(MAX_ARG_NAME_BYTES_TO_LOG));
+        let expected = format!(
+            "{} ... and 5 more",
+            vec![cut_name; MAX_LIST_ENTRIES_TO_LOG].join(" ")
+        );
+        assert_eq!(format_unknown_args(&unknown_args), expected);
+
+        let unknown_args = vec![(Bytes::from("narrow"), Bytes::from("1"))];
+        assert_eq!(format_unknown_args(&unknown_args), "narrow");
+    }
+}
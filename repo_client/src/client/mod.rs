@@ -4,50 +4,68 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+mod file_prefetch;
+mod known_trees;
+mod log_args;
+mod pull_bookmarks;
 mod remotefilelog;
 pub mod streaming_clone;
 
+pub use self::file_prefetch::{FilePrefetcher, PrefetchBudget};
+pub use self::known_trees::KnownTrees;
+
+use std::ascii;
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::mem;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use failure::err_msg;
 use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream, stream::empty};
-use futures_ext::{select_all, BoxFuture, BoxStream, FutureExt, StreamExt};
+use futures_ext::{merge_sorted_by_key, BoxFuture, BoxStream, FutureExt, StreamExt};
 use futures_stats::{Timed, TimedStreamTrait};
-use itertools::Itertools;
 use slog::Logger;
-use stats::Histogram;
+use stats::{DynamicTimeseries, Histogram};
 use time_ext::DurationExt;
 use uuid::Uuid;
 
 use blobrepo::HgBlobChangeset;
-use bookmarks::Bookmark;
-use bundle2_resolver;
-use context::CoreContext;
+use bookmarks::{Bookmark, BookmarkIntents, BookmarkMoveToken, BookmarkPrefix};
+use bundle2_resolver::{self, NarrowPatterns, PullToken, RESUMABLE_PULL_CAPABILITY};
+use context::{CoreContext, Deadline};
 use mercurial_bundles::{create_bundle_stream, parts, Bundle2Item};
-use mercurial_types::{percent_encode, Entry, HgChangesetId, HgManifestId, HgNodeHash, MPath,
-                      RepoPath, Type, NULL_HASH};
-use mercurial_types::manifest_utils::{changed_entry_stream_with_pruner, CombinatorPruner,
-                                      DeletedPruner, EntryStatus, FilePruner, Pruner,
-                                      VisitedPruner};
+use mercurial_types::{percent_encode, Entry, HgChangesetId, HgChangesetIdPrefix, HgManifestId,
+                      HgNodeHash, MPath, RepoPath, Type, NULL_HASH};
+use mercurial_types::manifest_utils::{ordered_changed_entry_stream_with_pruner, walk_manifest,
+                                      CombinatorPruner, DeletedPruner, EntryStatus, FilePruner,
+                                      PathFilter, Pruner, VisitedPruner};
+use mononoke_types::ChangesetId;
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use tracing::{TraceContext, Traced};
 
 use blobrepo::BlobRepo;
+use blobrepo::ErrorKind as BlobRepoErrorKind;
 use hgproto::{self, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands};
 
+use self::log_args::{add_gettreepack_args, format_getfiles_args, format_unknown_args};
+use self::pull_bookmarks::select_pull_bookmarks;
 use self::remotefilelog::create_remotefilelog_blob;
-use self::streaming_clone::RevlogStreamingChunks;
+use self::streaming_clone::{verify_file_stream, RevlogStreamingChunks, StreamingChunk};
 
 use errors::*;
 use hooks::HookManager;
 use mononoke_repo::{MononokeRepo, MysqlStreamingCloneConfig};
 
 const MAX_NODES_TO_LOG: usize = 5;
+/// Number of changesets that `lookup` lists when a prefix is ambiguous
+const MAX_LOOKUP_CANDIDATES: usize = 5;
+/// Number of changesets that `between` walks from the top of a pair before giving up on reaching
+/// its bottom
+const MAX_BETWEEN_WALK: usize = 100_000;
 
 define_stats! {
     prefix = "mononoke.repo_client";
@@ -57,6 +75,8 @@ define_stats! {
         histogram(500, 0, 20_000, AVG, SUM, COUNT; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
     getfiles_ms:
         histogram(500, 0, 20_000, AVG, SUM, COUNT; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
+    pull_bookmarks_truncated: timeseries(RATE, SUM),
+    unknown_args: dynamic_timeseries("{}.unknown_args", (command: &'static str); RATE, SUM),
 }
 
 mod ops {
@@ -66,38 +86,100 @@ mod ops {
     pub static LOOKUP: &str = "lookup";
     pub static LISTKEYS: &str = "listkeys";
     pub static KNOWN: &str = "known";
+    pub static KNOWNTREES: &str = "knowntrees";
     pub static BETWEEN: &str = "between";
     pub static GETBUNDLE: &str = "getbundle";
     pub static GETTREEPACK: &str = "gettreepack";
     pub static GETFILES: &str = "getfiles";
+    pub static PREPAREBOOKMARKMOVE: &str = "preparebookmarkmove";
+    pub static COMMITBOOKMARKMOVE: &str = "commitbookmarkmove";
+    pub static ABORTBOOKMARKMOVE: &str = "abortbookmarkmove";
 }
 
-fn format_nodes_list(mut nodes: Vec<HgNodeHash>) -> String {
-    nodes.sort();
-    nodes.into_iter().map(|node| format!("{}", node)).join(" ")
-}
-
-fn format_utf8_bytes_list(mut entries: Vec<Bytes>) -> String {
-    entries.sort();
-    entries
-        .into_iter()
-        .map(|entry| String::from_utf8_lossy(&entry).into_owned())
-        .join(" ")
+/// Formats a response for commands that report success or failure with a message, like lookup
+fn generate_resp_buf(success: bool, message: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(message.len() + 3);
+    if success {
+        buf.put(b'1');
+    } else {
+        buf.put(b'0');
+    }
+    buf.put(b' ');
+    buf.put(message);
+    buf.put(b'\n');
+    buf.freeze()
 }
 
-fn wireprotocaps() -> Vec<String> {
-    vec![
+/// Capabilities of the wire protocol. The streaming clone ones are only sent by repos that have
+/// streaming clone data, so that other clients don't ask for a stream that would be refused.
+fn wireprotocaps(streaming_clone: bool) -> Vec<String> {
+    let mut caps = vec![
         "lookup".to_string(),
         "known".to_string(),
         "getbundle".to_string(),
         "unbundle=HG10GZ,HG10BZ,HG10UN".to_string(),
         "gettreepack".to_string(),
+        "designatednodes".to_string(),
         "remotefilelog".to_string(),
         "pushkey".to_string(),
-        "stream-preferred".to_string(),
-        "stream_option".to_string(),
-        "streamreqs=generaldelta,lz4revlog,revlogv1".to_string(),
-    ]
+    ];
+    if streaming_clone {
+        caps.push("stream-preferred".to_string());
+        caps.push("stream_option".to_string());
+        caps.push(format!("streamreqs={}", STREAM_REQUIREMENTS));
+    }
+    caps
+}
+
+/// Status lines of a streaming clone response
+const STREAM_OK: &[u8] = b"0\n";
+const STREAM_FORBIDDEN: &[u8] = b"1\n";
+
+/// Requirements of the revlogs of a streaming clone. They are sent in the streamreqs capability
+/// rather than in the stream, so clients that don't support them don't ask for one.
+const STREAM_REQUIREMENTS: &str = "generaldelta,lz4revlog,revlogv1";
+
+/// Response of `stream_out_shallow` in the format of the v1 streaming clone of hg: a status
+/// line, a line with the number of files and their total size, then each file as a
+/// "name\0size\n" line followed by exactly that many bytes. Nothing ends the stream, clients
+/// stop after the advertised number of files. Only the changelog is sent, remotefilelog clients
+/// fetch trees and files on demand.
+fn stream_out_shallow_response(
+    logger: Logger,
+    changelog: RevlogStreamingChunks,
+) -> impl Stream<Item = Bytes, Error = Error> + Send {
+    fn build_file_stream(
+        logger: &Logger,
+        name: &'static str,
+        size: usize,
+        chunks: Vec<StreamingChunk>,
+    ) -> impl Stream<Item = Bytes, Error = Error> + Send {
+        let header = format!("{}\0{}\n", name, size);
+
+        stream::once(Ok(header.into_bytes().into()))
+            .chain(verify_file_stream(logger.clone(), name, size, chunks))
+    }
+
+    let file_count = 2;
+    let total_size = changelog.index_size + changelog.data_size;
+    let header = vec![
+        Bytes::from_static(STREAM_OK),
+        Bytes::from(format!("{} {}\n", file_count, total_size)),
+    ];
+
+    stream::iter_ok(header)
+        .chain(build_file_stream(
+            &logger,
+            "00changelog.i",
+            changelog.index_size,
+            changelog.index_blobs,
+        ))
+        .chain(build_file_stream(
+            &logger,
+            "00changelog.d",
+            changelog.data_size,
+            changelog.data_blobs,
+        ))
 }
 
 fn bundle2caps() -> String {
@@ -176,6 +258,14 @@ impl RepoClient {
     }
 
     fn scuba_logger(&self, op: &str, args: Option<String>) -> ScubaSampleBuilder {
+        let mut scuba_logger = self.new_scuba_logger(op, args);
+        scuba_logger.log_with_msg("Start processing", None);
+        scuba_logger
+    }
+
+    /// Like `scuba_logger`, but doesn't log the start of the command, so that more columns can be
+    /// added to it first
+    fn new_scuba_logger(&self, op: &str, args: Option<String>) -> ScubaSampleBuilder {
         let mut scuba_logger = self.ctxt.scuba().clone();
 
         scuba_logger.add("command", op);
@@ -184,24 +274,136 @@ impl RepoClient {
             scuba_logger.add("command_args", args);
         }
 
-        scuba_logger.log_with_msg("Start processing", None);
         scuba_logger
     }
 
+    /// Counts the arguments of a request that hgproto doesn't understand, and returns their names,
+    /// formatted for logging, if there are any. On repos with strict wireproto arguments such a
+    /// request is rejected instead.
+    fn check_unknown_args(
+        &self,
+        command: &'static str,
+        unknown_args: &[(Bytes, Bytes)],
+    ) -> Result<Option<String>> {
+        check_unknown_args(
+            self.logger(),
+            command,
+            unknown_args,
+            self.repo.flags().strict_wireproto_args,
+        )
+    }
+
+    /// Bookmarks for the listkeys part of a getbundle response for `heads`, selected by
+    /// `select_pull_bookmarks` from a single snapshot of the bookmarks of the repo
+    fn pull_bookmarks(&self, heads: Vec<HgChangesetId>) -> BoxStream<(String, Vec<u8>), Error> {
+        let blobrepo = self.repo.blobrepo().clone();
+        let params = self.repo.pull_bookmarks().clone();
+        let logger = self.logger().clone();
+        let mut scuba_logger = self.ctxt.scuba().clone();
+
+        let snapshot = blobrepo
+            .get_bookmarks_object()
+            .list_by_prefix(&BookmarkPrefix::empty(), &blobrepo.get_repoid())
+            .collect();
+        let heads = future::join_all(
+            heads
+                .into_iter()
+                .map({
+                    cloned!(blobrepo);
+                    move |head| blobrepo.get_bonsai_from_hg(&head)
+                })
+                .collect::<Vec<_>>(),
+        ).map(|heads| heads.into_iter().filter_map(|head| head).collect::<HashSet<_>>());
+
+        snapshot
+            .join(heads)
+            .map(move |(snapshot, heads)| {
+                let selected = select_pull_bookmarks(&params, &heads, snapshot);
+                if selected.truncated > 0 {
+                    STATS::pull_bookmarks_truncated.add_value(1);
+                    warn!(
+                        logger,
+                        "listkeys part has {} bookmarks, {} more were dropped",
+                        selected.bookmarks.len(),
+                        selected.truncated
+                    );
+                    scuba_logger
+                        .add("command", ops::GETBUNDLE)
+                        .add("pull_bookmarks_sent", selected.bookmarks.len())
+                        .add("pull_bookmarks_truncated", selected.truncated)
+                        .log_with_msg("Pull bookmarks truncated", None);
+                }
+                stream::iter_ok(selected.bookmarks)
+            })
+            .flatten_stream()
+            .and_then(move |(name, cs)| {
+                blobrepo
+                    .get_hg_from_bonsai_changeset(cs)
+                    .map(move |cs| {
+                        let hash: Vec<u8> = cs.into_nodehash().to_hex().into();
+                        (name.to_string(), hash)
+                    })
+            })
+            .boxify()
+    }
+
     fn create_bundle(&self, args: GetbundleArgs) -> Result<BoxStream<Bytes, Error>> {
         let blobrepo = self.repo.blobrepo();
         let mut bundle2_parts = vec![];
-        let cg_part_builder = bundle2_resolver::create_getbundle_response(
-            blobrepo.clone(),
-            args.common
-                .into_iter()
-                .map(|head| HgChangesetId::new(head))
-                .collect(),
-            args.heads
-                .into_iter()
-                .map(|head| HgChangesetId::new(head))
-                .collect(),
-        )?;
+        let common = args.common;
+        let heads = args.heads;
+        let requested_heads = heads.clone();
+        let resumable = args.bundlecaps
+            .iter()
+            .any(|cap| cap.as_slice() == RESUMABLE_PULL_CAPABILITY.as_bytes());
+        let patterns = NarrowPatterns::parse(&args.includepattern, &args.excludepattern)?;
+        if !patterns.is_everything() {
+            if resumable {
+                return Err(err_msg("narrow patterns can't be used with resumable pulls"));
+            }
+            let listkeys = if args.listkeys.contains(&b"bookmarks".to_vec()) {
+                let items = self.pull_bookmarks(requested_heads);
+                Some(parts::listkey_part("bookmarks", items)?)
+            } else {
+                None
+            };
+            return Ok(bundle2_resolver::create_narrow_getbundle_response(
+                blobrepo.clone(),
+                common,
+                heads,
+                patterns,
+                self.logger().clone(),
+            ).map(move |mut bundle2_parts| {
+                bundle2_parts.extend(listkeys);
+                create_bundle_stream(bundle2_parts, None)
+            })
+                .flatten_stream()
+                .boxify());
+        }
+
+        let cg_part_builder = if resumable {
+            let resume = match args.pulltoken {
+                Some(token) => Some((
+                    PullToken::from_str(&token)?,
+                    args.pullresumefrom.unwrap_or(0),
+                )),
+                None => None,
+            };
+            bundle2_resolver::create_resumable_getbundle_response(
+                blobrepo.clone(),
+                common,
+                heads,
+                self.repo.resumable_pulls().clone(),
+                resume,
+            )?
+        } else {
+            bundle2_resolver::create_getbundle_response(
+                blobrepo.clone(),
+                common,
+                heads,
+                self.repo.flags().deterministic_getbundle,
+            )?
+        };
         bundle2_parts.push(cg_part_builder);
 
         // XXX Note that listkeys is NOT returned as a bundle2 capability -- see comment in
@@ -210,13 +412,9 @@ impl RepoClient {
         // TODO: generalize this to other listkey types
         // (note: just calling &b"bookmarks"[..] doesn't work because https://fburl.com/0p0sq6kp)
         if args.listkeys.contains(&b"bookmarks".to_vec()) {
-            let items = blobrepo.get_bookmarks().map(|(name, cs)| {
-                let hash: Vec<u8> = cs.into_nodehash().to_hex().into();
-                (name.to_string(), hash)
-            });
+            let items = self.pull_bookmarks(requested_heads);
             bundle2_parts.push(parts::listkey_part("bookmarks", items)?);
         }
-        // TODO(stash): handle includepattern= and excludepattern=
 
         let compression = None;
         Ok(create_bundle_stream(bundle2_parts, compression).boxify())
@@ -225,17 +423,26 @@ impl RepoClient {
     fn gettreepack_untimed(&self, params: GettreepackArgs) -> BoxStream<Bytes, Error> {
         debug!(self.logger(), "gettreepack");
 
-        // 65536 matches the default TREE_DEPTH_MAX value from Mercurial
-        let fetchdepth = params.depth.unwrap_or(2 << 16);
-
+        if !params.designatednodes.is_empty() {
+            return self.gettreepack_designated(params);
+        }
         if !params.directories.is_empty() {
-            // This param is not used by core hg, don't worry about implementing it now
-            return stream::once(Err(err_msg("directories param is not supported"))).boxify();
+            return self.gettreepack_directories(params);
         }
 
-        // TODO(stash): T25850889 only one basemfnodes is used. That means that trees that client
-        // already has can be sent to the client.
-        let basemfnode = params.basemfnodes.get(0).cloned().unwrap_or(NULL_HASH);
+        // 65536 matches the default TREE_DEPTH_MAX value from Mercurial
+        let fetchdepth = params.depth.unwrap_or(2 << 16);
+
+        // The client has all the trees of all the base manifests. The null manifest has none.
+        let null_mfid = HgManifestId::new(NULL_HASH);
+        let mut basemfnodes: Vec<_> = params
+            .basemfnodes
+            .iter()
+            .cloned()
+            .filter(|basemfnode| *basemfnode != null_mfid)
+            .collect();
+        basemfnodes.sort();
+        basemfnodes.dedup();
 
         let rootpath = if params.rootdir.is_empty() {
             None
@@ -246,24 +453,42 @@ impl RepoClient {
         let default_pruner = CombinatorPruner::new(FilePruner, DeletedPruner);
 
         let changed_entries = if params.mfnodes.len() > 1 {
+            // The response must not depend on the order of mfnodes in the request, nor on the
+            // order in which the backend answers. Each per-mfnode stream is sorted by path (see
+            // get_changed_manifests_stream), so merge them keeping that order.
+            let mut mfnodes = params.mfnodes.clone();
+            mfnodes.sort();
+            mfnodes.dedup();
+
             let visited_pruner = VisitedPruner::new();
-            select_all(params.mfnodes.iter().map(|manifest_id| {
-                get_changed_manifests_stream(
+            merge_sorted_by_key(
+                mfnodes.iter().map(|manifest_id| {
+                    get_changed_manifests_stream(
+                        self.repo.blobrepo(),
+                        &manifest_id,
+                        &basemfnodes,
+                        rootpath.clone(),
+                        CombinatorPruner::new(default_pruner.clone(), visited_pruner.clone()),
+                        fetchdepth,
+                        self.trace().clone(),
+                    )
+                }),
+                treepack_entry_order_key,
+            ).boxify()
+        } else {
+            match params.mfnodes.get(0) {
+                // Nothing to diff against, so just walk the trees of the manifest
+                Some(mfnode) if basemfnodes.is_empty() => get_all_manifests_stream(
                     self.repo.blobrepo(),
-                    &manifest_id,
-                    &basemfnode,
+                    &mfnode,
                     rootpath.clone(),
-                    CombinatorPruner::new(default_pruner.clone(), visited_pruner.clone()),
                     fetchdepth,
                     self.trace().clone(),
-                )
-            })).boxify()
-        } else {
-            match params.mfnodes.get(0) {
+                ),
                 Some(mfnode) => get_changed_manifests_stream(
                     self.repo.blobrepo(),
                     &mfnode,
-                    &basemfnode,
+                    &basemfnodes,
                     rootpath.clone(),
                     default_pruner,
                     fetchdepth,
@@ -273,103 +498,92 @@ impl RepoClient {
             }
         };
 
-        let changed_entries = changed_entries
-            .filter({
-                let mut used_hashes = HashSet::new();
-                move |entry| used_hashes.insert(*entry.0.get_hash())
-            })
-            .map({
-                let blobrepo = self.repo.blobrepo().clone();
-                let trace = self.trace().clone();
-                move |(entry, basepath)| {
-                    fetch_treepack_part_input(&blobrepo, entry, basepath, trace.clone())
-                }
-            });
-
-        let part = parts::treepack_part(changed_entries);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        let compression = None;
-        part.into_future()
-            .map(move |part| create_bundle_stream(vec![part], compression))
-            .flatten_stream()
-            .boxify()
+        self.treepack_response(changed_entries)
     }
-}
 
-impl HgCommands for RepoClient {
-    // @wireprotocommand('between', 'pairs')
-    fn between(&self, pairs: Vec<(HgNodeHash, HgNodeHash)>) -> HgCommandRes<Vec<Vec<HgNodeHash>>> {
-        info!(self.logger(), "between pairs {:?}", pairs);
-
-        struct ParentStream<CS> {
-            repo: MononokeRepo,
-            n: HgNodeHash,
-            bottom: HgNodeHash,
-            wait_cs: Option<CS>,
-        };
-
-        impl<CS> ParentStream<CS> {
-            fn new(repo: &MononokeRepo, top: HgNodeHash, bottom: HgNodeHash) -> Self {
-                ParentStream {
-                    repo: repo.clone(),
-                    n: top,
-                    bottom: bottom,
-                    wait_cs: None,
-                }
-            }
+    /// Sends exactly the (directory, manifest) pairs that the client asked for, without diffing
+    /// against anything. Each manifest comes with its subtrees down to the requested depth, by
+    /// default none.
+    fn gettreepack_designated(&self, params: GettreepackArgs) -> BoxStream<Bytes, Error> {
+        if !params.rootdir.is_empty() || !params.mfnodes.is_empty()
+            || !params.basemfnodes.is_empty() || !params.directories.is_empty()
+        {
+            return stream::once(Err(ErrorKind::DesignatedNodesMixed.into())).boxify();
         }
 
-        impl Stream for ParentStream<BoxFuture<HgBlobChangeset, hgproto::Error>> {
-            type Item = HgNodeHash;
-            type Error = hgproto::Error;
+        let entries = try_boxstream!(get_designated_manifests_stream(
+            self.repo.blobrepo(),
+            params.designatednodes,
+            params.depth.unwrap_or(1),
+            self.trace().clone(),
+        ));
+        self.treepack_response(entries)
+    }
 
-            fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-                if self.n == self.bottom || self.n == NULL_HASH {
-                    return Ok(Async::Ready(None));
-                }
+    /// Sends the trees of the given directories in each of mfnodes, for clients that fetch trees
+    /// on demand. Nothing is diffed against basemfnodes. Each tree comes with its subtrees down
+    /// to the requested depth, by default none.
+    fn gettreepack_directories(&self, params: GettreepackArgs) -> BoxStream<Bytes, Error> {
+        let rootpath = if params.rootdir.is_empty() {
+            None
+        } else {
+            Some(try_boxstream!(MPath::new(params.rootdir)))
+        };
 
-                self.wait_cs = self.wait_cs.take().or_else(|| {
-                    Some(
-                        self.repo
-                            .blobrepo()
-                            .get_changeset_by_changesetid(&HgChangesetId::new(self.n)),
-                    )
-                });
-                let cs = try_ready!(self.wait_cs.as_mut().unwrap().poll());
-                self.wait_cs = None; // got it
+        let entries = get_directories_manifests_stream(
+            self.repo.blobrepo(),
+            rootpath,
+            params.mfnodes,
+            params.directories,
+            params.depth.unwrap_or(1),
+            self.trace().clone(),
+        );
+        self.treepack_response(entries)
+    }
 
-                let p = cs.p1().cloned().unwrap_or(NULL_HASH);
-                let prev_n = mem::replace(&mut self.n, p);
+    /// Bundle2 with a treepack part of `entries`
+    fn treepack_response(
+        &self,
+        entries: BoxStream<(Box<Entry + Sync>, Option<MPath>), Error>,
+    ) -> BoxStream<Bytes, Error> {
+        create_treepack_stream(
+            self.repo.blobrepo(),
+            entries,
+            self.ctxt.deadline(),
+            self.repo.file_prefetcher().cloned(),
+            self.repo.fetch_limits().gettreepack_buffer_size,
+            self.trace().clone(),
+        )
+    }
 
-                Ok(Async::Ready(Some(prev_n)))
+    /// The bookmark intents of the repo and the identity of the client, if the client can move
+    /// bookmarks of the repo in two phases
+    fn bookmark_intents(&self) -> Result<(BookmarkIntents, String)> {
+        match (self.repo.bookmark_moves(), self.ctxt.user()) {
+            (Some(moves), Some(user)) if moves.allows(user) => {
+                Ok((moves.intents().clone(), user.to_string()))
             }
+            (_, user) => Err(ErrorKind::BookmarkMoveNotAllowed(
+                user.unwrap_or("unidentified client").to_string(),
+            ).into()),
         }
+    }
+}
+
+impl HgCommands for RepoClient {
+    // @wireprotocommand('between', 'pairs')
+    fn between(&self, pairs: Vec<(HgNodeHash, HgNodeHash)>) -> HgCommandRes<Vec<Vec<HgNodeHash>>> {
+        info!(self.logger(), "between pairs {:?}", pairs);
 
         let mut scuba_logger = self.scuba_logger(ops::BETWEEN, None);
 
-        // TODO(jsgf): do pairs in parallel?
         // TODO: directly return stream of streams
-        let repo = self.repo.clone();
-        stream::iter_ok(pairs.into_iter())
-            .and_then(move |(top, bottom)| {
-                let mut f = 1;
-                ParentStream::new(&repo, top, bottom)
-                    .enumerate()
-                    .filter(move |&(i, _)| {
-                        if i == f {
-                            f *= 2;
-                            true
-                        } else {
-                            false
-                        }
-                    })
-                    .map(|(_, v)| v)
-                    .collect()
-            })
-            .collect()
-            .traced(self.trace(), ops::BETWEEN, trace_args!())
+        between_pairs(
+            self.repo.blobrepo().clone(),
+            pairs,
+            self.repo.fetch_limits().between_buffer_size,
+            MAX_BETWEEN_WALK,
+        ).traced(self.trace(), ops::BETWEEN, trace_args!())
             .timed(move |stats, _| {
                 scuba_logger
                     .add_future_stats(&stats)
@@ -404,58 +618,10 @@ impl HgCommands for RepoClient {
     // @wireprotocommand('lookup', 'key')
     fn lookup(&self, key: String) -> HgCommandRes<Bytes> {
         info!(self.logger(), "lookup: {:?}", key);
-        // TODO(stash): T25928839 lookup should support prefixes
         let repo = self.repo.blobrepo().clone();
         let mut scuba_logger = self.scuba_logger(ops::LOOKUP, None);
 
-        fn generate_resp_buf(success: bool, message: &[u8]) -> Bytes {
-            let mut buf = BytesMut::with_capacity(message.len() + 3);
-            if success {
-                buf.put(b'1');
-            } else {
-                buf.put(b'0');
-            }
-            buf.put(b' ');
-            buf.put(message);
-            buf.put(b'\n');
-            buf.freeze()
-        }
-
-        fn check_bookmark_exists(repo: BlobRepo, bookmark: Bookmark) -> HgCommandRes<Bytes> {
-            repo.get_bookmark(&bookmark)
-                .map(move |csid| match csid {
-                    Some(csid) => generate_resp_buf(true, csid.to_hex().as_bytes()),
-                    None => generate_resp_buf(false, format!("{} not found", bookmark).as_bytes()),
-                })
-                .boxify()
-        }
-
-        let node = HgNodeHash::from_str(&key).ok();
-        let bookmark = Bookmark::new(&key).ok();
-
-        let lookup_fut = match (node, bookmark) {
-            (Some(node), Some(bookmark)) => {
-                let csid = HgChangesetId::new(node);
-                repo.changeset_exists(&csid)
-                    .and_then(move |exists| {
-                        if exists {
-                            Ok(generate_resp_buf(true, node.to_hex().as_bytes()))
-                                .into_future()
-                                .boxify()
-                        } else {
-                            check_bookmark_exists(repo, bookmark)
-                        }
-                    })
-                    .boxify()
-            }
-            (None, Some(bookmark)) => check_bookmark_exists(repo, bookmark),
-            // Failed to parse as a hash or bookmark.
-            _ => Ok(generate_resp_buf(false, "invalid input".as_bytes()))
-                .into_future()
-                .boxify(),
-        };
-
-        lookup_fut
+        lookup_key(repo, key)
             .traced(self.trace(), ops::LOOKUP, trace_args!())
             .timed(move |stats, _| {
                 scuba_logger
@@ -491,14 +657,56 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    // @wireprotocommand('knowntrees', 'bookmark nodes')
+    fn knowntrees(
+        &self,
+        bookmark: String,
+        nodes: Vec<(Bytes, HgManifestId)>,
+    ) -> HgCommandRes<Vec<bool>> {
+        if nodes.len() > MAX_NODES_TO_LOG {
+            info!(
+                self.logger(),
+                "knowntrees {}: {:?}...",
+                bookmark,
+                &nodes[..MAX_NODES_TO_LOG]
+            );
+        } else {
+            info!(self.logger(), "knowntrees {}: {:?}", bookmark, nodes);
+        }
+        let bookmark = try_boxfuture!(Bookmark::new(&bookmark));
+
+        let mut scuba_logger = self.scuba_logger(ops::KNOWNTREES, None);
+        scuba_logger.add("knowntrees_nodes", nodes.len());
+
+        self.repo
+            .known_trees()
+            .check(self.repo.blobrepo(), bookmark, nodes)
+            .traced(self.trace(), ops::KNOWNTREES, trace_args!())
+            .timed(move |stats, _| {
+                scuba_logger
+                    .add_future_stats(&stats)
+                    .log_with_msg("Command processed", None);
+                Ok(())
+            })
+            .boxify()
+    }
+
     // @wireprotocommand('getbundle', '*')
     fn getbundle(&self, args: GetbundleArgs) -> BoxStream<Bytes, Error> {
         info!(self.logger(), "Getbundle: {:?}", args);
 
         let mut scuba_logger = self.scuba_logger(ops::GETBUNDLE, None);
 
-        match self.create_bundle(args) {
-            Ok(res) => res.boxify(),
+        let response = self.check_unknown_args(ops::GETBUNDLE, &args.unknown_args)
+            .and_then(|names| {
+                if let Some(names) = names {
+                    scuba_logger.add("unknown_args", names);
+                }
+                self.create_bundle(args)
+            });
+
+        match response {
+            Ok(res) => with_deadline(res, self.ctxt.deadline()),
             Err(err) => stream::once(Err(err)).boxify(),
         }.traced(self.trace(), ops::GETBUNDLE, trace_args!())
             .timed(move |stats, _| {
@@ -516,8 +724,9 @@ impl HgCommands for RepoClient {
         info!(self.logger(), "Hello -> capabilities");
 
         let mut res = HashMap::new();
-        let mut caps = wireprotocaps();
+        let mut caps = wireprotocaps(self.repo.streaming_clone().is_some());
         caps.push(format!("bundle2={}", bundle2caps()));
+        caps.push(format!("knowntrees={}", self.repo.known_trees().max_nodes()));
         res.insert("capabilities".to_string(), caps);
 
         let mut scuba_logger = self.scuba_logger(ops::HELLO, None);
@@ -584,6 +793,16 @@ impl HgCommands for RepoClient {
             self.logger().new(o!("command" => "unbundle")),
             scuba_logger.clone(),
             self.repo.pushrebase_params().clone(),
+            self.repo.push_limits(),
+            self.repo.path_rules().clone(),
+            self.repo.bookmark_creation().clone(),
+            self.repo.push_journal().cloned(),
+            self.repo.push_advisory().cloned(),
+            self.repo.cross_repo_index().cloned(),
+            self.repo.derivation_queue().cloned(),
+            self.ctxt.session().to_string(),
+            self.ctxt.user().map(|user| user.to_string()),
+            self.ctxt.deadline(),
             heads,
             stream,
             hook_manager,
@@ -599,19 +818,64 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    fn forwards_writes(&self) -> bool {
+        self.repo.write_forwarder().is_some()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.repo.readonly()
+    }
+
+    fn max_bundle2_chunk_bytes(&self) -> Option<u64> {
+        // A chunk never spans parts, so nothing larger than a whole part can be accepted.
+        let limits = self.repo.push_limits();
+        Some(cmp::min(limits.max_part_bytes, limits.max_push_bytes))
+    }
+
+    // @wireprotocommand('unbundle') on a secondary server
+    fn unbundle_raw(
+        &self,
+        heads: Vec<String>,
+        bundle: BoxStream<Bytes, Error>,
+    ) -> BoxStream<Bytes, Error> {
+        let forwarder = match self.repo.write_forwarder() {
+            Some(forwarder) => forwarder.clone(),
+            None => return stream::once(Err(err_msg("repo doesn't forward writes"))).boxify(),
+        };
+
+        info!(self.logger(), "forwarding unbundle to {}", forwarder.primary());
+        let mut scuba_logger = self.scuba_logger(ops::UNBUNDLE, None);
+        scuba_logger.add("forwarded_to", forwarder.primary());
+
+        forwarder
+            .unbundle(&self.ctxt, heads, bundle)
+            .traced(self.trace(), ops::UNBUNDLE, trace_args!())
+            .timed(move |stats, _| {
+                scuba_logger
+                    .add_stream_stats(&stats)
+                    .log_with_msg("Command processed", None);
+                Ok(())
+            })
+            .boxify()
+    }
+
     // @wireprotocommand('gettreepack', 'rootdir mfnodes basemfnodes directories')
     fn gettreepack(&self, params: GettreepackArgs) -> BoxStream<Bytes, Error> {
-        let args = format!(
-            "rootdir: {}, mfnodes: {}, basemfnodes: {}, directories: {}",
-            String::from_utf8_lossy(&params.rootdir),
-            format_nodes_list(params.mfnodes.clone()),
-            format_nodes_list(params.basemfnodes.clone()),
-            format_utf8_bytes_list(params.directories.clone()),
-        );
+        let mut scuba_logger = self.new_scuba_logger(ops::GETTREEPACK, None);
+        add_gettreepack_args(&mut scuba_logger, &params);
+        scuba_logger.log_with_msg("Start processing", None);
 
-        let mut scuba_logger = self.scuba_logger(ops::GETTREEPACK, Some(args));
+        let response = match self.check_unknown_args(ops::GETTREEPACK, &params.unknown_args) {
+            Ok(names) => {
+                if let Some(names) = names {
+                    scuba_logger.add("unknown_args", names);
+                }
+                self.gettreepack_untimed(params)
+            }
+            Err(err) => stream::once(Err(err)).boxify(),
+        };
 
-        self.gettreepack_untimed(params)
+        response
             .traced(self.trace(), ops::GETTREEPACK, trace_args!())
             .timed(move |stats, _| {
                 STATS::gettreepack_ms.add_value(stats.completion_time.as_millis_unchecked() as i64);
@@ -630,13 +894,16 @@ impl HgCommands for RepoClient {
         info!(logger, "getfiles");
 
         let this = self.clone();
-        let getfiles_buffer_size = 100; // TODO(stash): make it configurable
+        let getfiles_buffer_size = self.repo.fetch_limits().getfiles_buffer_size;
         params
             .map(move |(node, path)| {
-                let args = format!("node: {}, path: {}", node, path);
+                let args = format_getfiles_args(&node, &path);
                 let mut scuba_logger = this.scuba_logger(ops::GETFILES, Some(args));
 
                 let repo = this.repo.clone();
+                if let Some(prefetcher) = repo.file_prefetcher() {
+                    prefetcher.record_fetch(&node);
+                }
                 create_remotefilelog_blob(
                     Arc::new(repo.blobrepo().clone()),
                     node,
@@ -664,84 +931,396 @@ impl HgCommands for RepoClient {
     fn stream_out_shallow(&self) -> BoxStream<Bytes, Error> {
         info!(self.logger(), "stream_out_shallow");
         let changelog = match self.repo.streaming_clone() {
-            None => Ok(RevlogStreamingChunks::new()).into_future().left_future(),
+            // Like hg when streaming clones are not allowed: an empty changelog would leave the
+            // client with an empty clone instead of an error
+            None => return stream::once(Ok(Bytes::from_static(STREAM_FORBIDDEN))).boxify(),
             Some(MysqlStreamingCloneConfig {
                 blobstore,
                 fetcher,
                 repoid,
-            }) => fetcher
-                .fetch_changelog(*repoid, blobstore.clone())
-                .right_future(),
+            }) => fetcher.fetch_changelog(*repoid, Arc::new(blobstore.clone())),
         };
 
+        let logger = self.logger().clone();
         changelog
-            .map({
-                let logger = self.logger().clone();
-                move |changelog_chunks| {
-                    debug!(
-                        logger,
-                        "streaming changelog {} index bytes, {} data bytes",
-                        changelog_chunks.index_size,
-                        changelog_chunks.data_size
-                    );
-                    let mut response_header = Vec::new();
-                    // TODO(t34058163): actually send a real streaming response, not an empty one
-                    // Send OK response.
-                    response_header.push(Bytes::from_static(b"0\n"));
-                    // send header.
-                    let total_size = changelog_chunks.index_size + changelog_chunks.data_size;
-                    let file_count = 2;
-                    let header = format!("{} {}\n", file_count, total_size);
-                    response_header.push(header.into_bytes().into());
-                    let response = stream::iter_ok(response_header);
-
-                    fn build_file_stream(
-                        name: &str,
-                        size: usize,
-                        data: Vec<BoxFuture<Bytes, Error>>,
-                    ) -> impl Stream<Item = Bytes, Error = Error> + Send {
-                        let header = format!("{}\0{}\n", name, size);
-
-                        stream::once(Ok(header.into_bytes().into()))
-                            .chain(stream::iter_ok(data.into_iter()).buffered(100))
+            .map(move |changelog_chunks| {
+                debug!(
+                    logger,
+                    "streaming changelog {} index bytes, {} data bytes",
+                    changelog_chunks.index_size,
+                    changelog_chunks.data_size
+                );
+                stream_out_shallow_response(logger, changelog_chunks)
+            })
+            .flatten_stream()
+            .boxify()
+    }
+
+    fn preparebookmarkmove(
+        &self,
+        key: String,
+        old: String,
+        new: String,
+        ttl: u64,
+    ) -> HgCommandRes<Bytes> {
+        info!(
+            self.logger(),
+            "preparebookmarkmove: {} {:?} -> {} (ttl {}s)", key, old, new, ttl
+        );
+        let mut scuba_logger = self.scuba_logger(
+            ops::PREPAREBOOKMARKMOVE,
+            Some(format!("{} {} {} {}", key, old, new, ttl)),
+        );
+        let repo = self.repo.blobrepo().clone();
+        // Checked first, so that nothing is recorded for clients that aren't allowed
+        let allowed = self.bookmark_intents();
+
+        let bookmark = try_boxfuture!(Bookmark::new(&key));
+        let old_cs = if old.is_empty() {
+            Ok(None).into_future().left_future()
+        } else {
+            resolve_bonsai(&repo, &old).map(Some).right_future()
+        };
+        let new_cs = resolve_bonsai(&repo, &new);
+
+        allowed
+            .into_future()
+            .and_then(move |(intents, owner)| {
+                old_cs.join(new_cs).and_then(move |(old_cs, new_cs)| {
+                    intents.prepare(
+                        &owner,
+                        &repo.get_repoid(),
+                        &bookmark,
+                        old_cs,
+                        new_cs,
+                        Duration::from_secs(ttl),
+                    )
+                })
+            })
+            .then(|res| match res {
+                Ok(token) => Ok(generate_resp_buf(true, token.to_string().as_bytes())),
+                Err(err) => Ok(generate_resp_buf(false, format!("{}", err).as_bytes())),
+            })
+            .traced(self.trace(), ops::PREPAREBOOKMARKMOVE, trace_args!())
+            .timed(move |stats, _| {
+                scuba_logger
+                    .add_future_stats(&stats)
+                    .log_with_msg("Command processed", None);
+                Ok(())
+            })
+            .boxify()
+    }
+
+    fn commitbookmarkmove(&self, token: String) -> HgCommandRes<Bytes> {
+        info!(self.logger(), "commitbookmarkmove: {}", token);
+        let mut scuba_logger = self.scuba_logger(ops::COMMITBOOKMARKMOVE, Some(token.clone()));
+
+        self.bookmark_intents()
+            .and_then(|(intents, owner)| Ok((intents, owner, token.parse::<BookmarkMoveToken>()?)))
+            .into_future()
+            .and_then(|(intents, owner, token)| intents.commit(&owner, &token))
+            .then(|res| match res {
+                Ok(true) => Ok(generate_resp_buf(true, b"committed")),
+                Ok(false) => Ok(generate_resp_buf(false, b"bookmark was moved concurrently")),
+                Err(err) => Ok(generate_resp_buf(false, format!("{}", err).as_bytes())),
+            })
+            .traced(self.trace(), ops::COMMITBOOKMARKMOVE, trace_args!())
+            .timed(move |stats, _| {
+                scuba_logger
+                    .add_future_stats(&stats)
+                    .log_with_msg("Command processed", None);
+                Ok(())
+            })
+            .boxify()
+    }
+
+    fn abortbookmarkmove(&self, token: String) -> HgCommandRes<Bytes> {
+        info!(self.logger(), "abortbookmarkmove: {}", token);
+        let mut scuba_logger = self.scuba_logger(ops::ABORTBOOKMARKMOVE, Some(token.clone()));
+
+        self.bookmark_intents()
+            .and_then(|(intents, owner)| Ok((intents, owner, token.parse::<BookmarkMoveToken>()?)))
+            .into_future()
+            .and_then(|(intents, owner, token)| intents.abort(&owner, &token))
+            .then(|res| match res {
+                Ok(()) => Ok(generate_resp_buf(true, b"aborted")),
+                Err(err) => Ok(generate_resp_buf(false, format!("{}", err).as_bytes())),
+            })
+            .traced(self.trace(), ops::ABORTBOOKMARKMOVE, trace_args!())
+            .timed(move |stats, _| {
+                scuba_logger
+                    .add_future_stats(&stats)
+                    .log_with_msg("Command processed", None);
+                Ok(())
+            })
+            .boxify()
+    }
+}
+
+/// The first-parent chain of `top` down to `bottom`, or to the root if `bottom` isn't an
+/// ancestor of `top`. Neither `bottom` nor the null changeset are part of it. Fails once more than
+/// `limit` changesets were walked.
+struct ParentStream {
+    repo: BlobRepo,
+    top: HgNodeHash,
+    n: HgNodeHash,
+    bottom: HgNodeHash,
+    walked: usize,
+    limit: usize,
+    wait_cs: Option<BoxFuture<HgBlobChangeset, Error>>,
+}
+
+impl ParentStream {
+    fn new(repo: BlobRepo, top: HgNodeHash, bottom: HgNodeHash, limit: usize) -> Self {
+        ParentStream {
+            repo,
+            top,
+            n: top,
+            bottom,
+            walked: 0,
+            limit,
+            wait_cs: None,
+        }
+    }
+}
+
+impl Stream for ParentStream {
+    type Item = HgNodeHash;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.n == self.bottom || self.n == NULL_HASH {
+            return Ok(Async::Ready(None));
+        }
+        if self.walked == self.limit {
+            return Err(ErrorKind::BetweenWalkTooLong(self.top, self.bottom, self.limit).into());
+        }
+
+        self.wait_cs = self.wait_cs.take().or_else(|| {
+            Some(
+                self.repo
+                    .get_changeset_by_changesetid(&HgChangesetId::new(self.n)),
+            )
+        });
+        let cs = try_ready!(self.wait_cs.as_mut().unwrap().poll());
+        self.wait_cs = None; // got it
+        self.walked += 1;
+
+        let p = cs.p1().cloned().unwrap_or(NULL_HASH);
+        let prev_n = mem::replace(&mut self.n, p);
+
+        Ok(Async::Ready(Some(prev_n)))
+    }
+}
+
+/// Answers a `between`: for each pair, the changesets of the first-parent chain from its top to
+/// its bottom that are 1, 2, 4, 8... parents away from the top. Up to `buffer_size` pairs are
+/// walked at the same time, and results are in the order of `pairs`.
+fn between_pairs(
+    repo: BlobRepo,
+    pairs: Vec<(HgNodeHash, HgNodeHash)>,
+    buffer_size: usize,
+    walk_limit: usize,
+) -> BoxFuture<Vec<Vec<HgNodeHash>>, Error> {
+    stream::iter_ok(pairs.into_iter())
+        .map(move |(top, bottom)| {
+            let mut f = 1;
+            ParentStream::new(repo.clone(), top, bottom, walk_limit)
+                .enumerate()
+                .filter(move |&(i, _)| {
+                    if i == f {
+                        f *= 2;
+                        true
+                    } else {
+                        false
                     }
+                })
+                .map(|(_, v)| v)
+                .collect()
+        })
+        .buffered(buffer_size)
+        .collect()
+        .boxify()
+}
 
-                    response
-                        .chain(build_file_stream(
-                            "00changelog.i",
-                            changelog_chunks.index_size,
-                            changelog_chunks.index_blobs,
-                        ))
-                        .chain(build_file_stream(
-                            "00changelog.d",
-                            changelog_chunks.data_size,
-                            changelog_chunks.data_blobs,
-                        ))
+/// Resolves `key` of a `lookup` to a changeset, which can be a changeset id, a bookmark, or a
+/// prefix of a changeset id
+fn lookup_key(repo: BlobRepo, key: String) -> HgCommandRes<Bytes> {
+    fn check_bookmark_exists(repo: BlobRepo, bookmark: Bookmark) -> HgCommandRes<Bytes> {
+        repo.get_bookmark(&bookmark)
+            .map(move |csid| match csid {
+                Some(csid) => generate_resp_buf(true, csid.to_hex().as_bytes()),
+                None => generate_resp_buf(false, format!("{} not found", bookmark).as_bytes()),
+            })
+            .boxify()
+    }
+
+    fn lookup_prefix(
+        repo: BlobRepo,
+        key: String,
+        prefix: HgChangesetIdPrefix,
+    ) -> HgCommandRes<Bytes> {
+        repo.get_hg_changesets_by_prefix(prefix, MAX_LOOKUP_CANDIDATES + 1)
+            .map(move |csids| match csids.len() {
+                0 => generate_resp_buf(false, format!("{} not found", key).as_bytes()),
+                1 => generate_resp_buf(true, csids[0].to_hex().as_bytes()),
+                _ => {
+                    let mut candidates: Vec<_> = csids
+                        .iter()
+                        .take(MAX_LOOKUP_CANDIDATES)
+                        .map(|csid| csid.to_string())
+                        .collect();
+                    if csids.len() > MAX_LOOKUP_CANDIDATES {
+                        candidates.push("...".to_string());
+                    }
+                    let msg = format!(
+                        "{}: ambiguous identifier, candidates: {}",
+                        key,
+                        candidates.join(", ")
+                    );
+                    generate_resp_buf(false, msg.as_bytes())
                 }
             })
-            .flatten_stream()
             .boxify()
     }
+
+    let node = HgNodeHash::from_str(&key).ok();
+    let bookmark = Bookmark::new(&key).ok();
+    let prefix = match node {
+        Some(_) => None,
+        None => HgChangesetIdPrefix::from_str(&key).ok(),
+    };
+
+    match (node, bookmark, prefix) {
+        (Some(node), Some(bookmark), _) => {
+            let csid = HgChangesetId::new(node);
+            repo.changeset_exists(&csid)
+                .and_then(move |exists| {
+                    if exists {
+                        Ok(generate_resp_buf(true, node.to_hex().as_bytes()))
+                            .into_future()
+                            .boxify()
+                    } else {
+                        check_bookmark_exists(repo, bookmark)
+                    }
+                })
+                .boxify()
+        }
+        // Like hg, a bookmark takes precedence over the changesets that its name is a prefix of
+        (None, Some(bookmark), Some(prefix)) => repo.get_bookmark(&bookmark)
+            .and_then(move |csid| match csid {
+                Some(csid) => Ok(generate_resp_buf(true, csid.to_hex().as_bytes()))
+                    .into_future()
+                    .boxify(),
+                None => lookup_prefix(repo, key, prefix),
+            })
+            .boxify(),
+        (None, Some(bookmark), None) => check_bookmark_exists(repo, bookmark),
+        (None, None, Some(prefix)) => lookup_prefix(repo, key, prefix),
+        // Failed to parse as a hash, bookmark or prefix. The key is sent back, so that keys that
+        // were mangled on their way here can be told apart.
+        _ => {
+            let msg = format!("invalid input: {}", escape_key(key.as_bytes()));
+            Ok(generate_resp_buf(false, msg.as_bytes()))
+                .into_future()
+                .boxify()
+        }
+    }
 }
 
+/// `key` with its bytes that are not printable ASCII characters hex-escaped
+fn escape_key(key: &[u8]) -> String {
+    key.iter()
+        .flat_map(|b| ascii::escape_default(*b))
+        .map(char::from)
+        .collect()
+}
+
+/// Converts a hex hg changeset hash sent over the wire into a bonsai changeset id
+fn resolve_bonsai(repo: &BlobRepo, hash: &str) -> BoxFuture<ChangesetId, Error> {
+    let node = try_boxfuture!(HgNodeHash::from_str(hash));
+    let csid = HgChangesetId::new(node);
+    repo.get_bonsai_from_hg(&csid)
+        .and_then(move |bonsai| bonsai.ok_or(BlobRepoErrorKind::BonsaiMappingNotFound(csid).into()))
+        .boxify()
+}
+
+/// Key that defines the order of entries in a gettreepack response: entries are sorted by their
+/// full path, except that the root of the request comes after everything else, as hg expects.
+/// Entries with the same path are ordered by hash.
+fn treepack_entry_order_key(
+    &(ref entry, ref basepath): &(Box<Entry + Sync>, Option<MPath>),
+) -> (bool, Option<MPath>, HgNodeHash) {
+    let is_root = entry.get_name().is_none();
+    let path = MPath::join_element_opt(basepath.as_ref(), entry.get_name());
+    (is_root, path, entry.get_hash().into_nodehash())
+}
+
+/// Clients choose the names of unknown arguments freely, so they are only counted per command,
+/// and logged truncated
+fn check_unknown_args(
+    logger: &Logger,
+    command: &'static str,
+    unknown_args: &[(Bytes, Bytes)],
+    strict: bool,
+) -> Result<Option<String>> {
+    if unknown_args.is_empty() {
+        return Ok(None);
+    }
+
+    STATS::unknown_args.add_value(unknown_args.len() as i64, (command,));
+    let names = format_unknown_args(unknown_args);
+    if strict {
+        return Err(ErrorKind::UnknownWireprotoArgs(command.to_string(), names).into());
+    }
+    debug!(logger, "ignoring unknown {} arguments: {}", command, names);
+    Ok(Some(names))
+}
+
+/// Fails `stream` with `DeadlineExceeded` once the deadline of the client is exceeded. It is
+/// checked as each item is produced, so a slow item is not interrupted.
+fn with_deadline<T>(
+    stream: BoxStream<T, Error>,
+    deadline: Option<Deadline>,
+) -> BoxStream<T, Error>
+where
+    T: Send + 'static,
+{
+    match deadline {
+        Some(deadline) => stream
+            .and_then(move |item| {
+                deadline.check()?;
+                Ok::<_, Error>(item)
+            })
+            .boxify(),
+        None => stream,
+    }
+}
+
+/// Returns the tree entries of `mfid` that are in none of `basemfids`, at the same path. No
+/// base manifest is the same as the null manifest. The order of the output is deterministic and
+/// follows `treepack_entry_order_key`.
 fn get_changed_manifests_stream(
     repo: &BlobRepo,
-    mfid: &HgNodeHash,
-    basemfid: &HgNodeHash,
+    mfid: &HgManifestId,
+    basemfids: &[HgManifestId],
     rootpath: Option<MPath>,
     pruner: impl Pruner + Send + Clone + 'static,
     max_depth: usize,
     trace: TraceContext,
 ) -> BoxStream<(Box<Entry + Sync>, Option<MPath>), Error> {
-    let mfid = HgManifestId::new(*mfid);
-    let manifest = repo.get_manifest_by_nodeid(&mfid)
+    let (basemfid, otherbasemfids) = match basemfids.split_first() {
+        Some((basemfid, otherbasemfids)) => (*basemfid, otherbasemfids),
+        None => (HgManifestId::new(NULL_HASH), &[][..]),
+    };
+
+    let manifest = repo.get_manifest_by_nodeid(mfid)
         .traced(&trace, "fetch rootmf", trace_args!());
-    let basemfid = HgManifestId::new(*basemfid);
     let basemanifest =
         repo.get_manifest_by_nodeid(&basemfid)
             .traced(&trace, "fetch baserootmf", trace_args!());
 
-    let root_entry_stream = stream::once(Ok((repo.get_root_entry(&mfid), rootpath.clone())));
+    let root_entry_stream = stream::once(Ok((repo.get_root_entry(mfid), rootpath.clone())));
 
     if max_depth == 1 {
         return root_entry_stream.boxify();
@@ -752,7 +1331,13 @@ fn get_changed_manifests_stream(
         .map({
             let rootpath = rootpath.clone();
             move |(mf, basemf)| {
-                changed_entry_stream_with_pruner(&mf, &basemf, rootpath, pruner, Some(max_depth))
+                ordered_changed_entry_stream_with_pruner(
+                    &mf,
+                    &basemf,
+                    rootpath,
+                    pruner,
+                    Some(max_depth),
+                )
             }
         })
         .flatten_stream();
@@ -771,7 +1356,196 @@ fn get_changed_manifests_stream(
     });
 
     // Append root manifest as well
-    changed_entries.chain(root_entry_stream).boxify()
+    let changed_entries = changed_entries.chain(root_entry_stream);
+    if otherbasemfids.is_empty() {
+        return changed_entries.boxify();
+    }
+
+    // An entry that is not in the diff against another base manifest is in that base manifest,
+    // and so are its subtrees, so the client already has it. The diffs against the other base
+    // manifests are small when they are close to `mfid`, which they usually are.
+    let default_pruner = CombinatorPruner::new(FilePruner, DeletedPruner);
+    let not_in_other_bases = otherbasemfids.iter().map(|basemfid| {
+        get_changed_manifests_stream(
+            repo,
+            mfid,
+            &[*basemfid],
+            rootpath.clone(),
+            default_pruner.clone(),
+            max_depth,
+            trace.clone(),
+        ).map(|entry| treepack_entry_order_key(&entry))
+            .collect()
+            .map(HashSet::<_>::from_iter)
+    });
+
+    future::join_all(not_in_other_bases)
+        .map(move |not_in_other_bases| {
+            changed_entries.filter(move |entry| {
+                let key = treepack_entry_order_key(entry);
+                not_in_other_bases.iter().all(|entries| entries.contains(&key))
+            })
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+/// Same as `get_changed_manifests_stream` against an empty base manifest, i.e. returns all the
+/// trees of the manifest up to `max_depth`, in the same order.
+fn get_all_manifests_stream(
+    repo: &BlobRepo,
+    mfid: &HgManifestId,
+    rootpath: Option<MPath>,
+    max_depth: usize,
+    trace: TraceContext,
+) -> BoxStream<(Box<Entry + Sync>, Option<MPath>), Error> {
+    let root_entry_stream = stream::once(Ok((repo.get_root_entry(mfid), rootpath.clone())));
+
+    if max_depth == 1 {
+        return root_entry_stream.boxify();
+    }
+
+    let manifest = repo.get_manifest_by_nodeid(mfid)
+        .traced(&trace, "fetch rootmf", trace_args!());
+
+    let entries = manifest
+        .map(move |mf| walk_manifest(&mf, rootpath, PathFilter::all().max_depth(max_depth)))
+        .flatten_stream()
+        .filter(|&(_, ref entry)| entry.get_type() == Type::Tree)
+        .map(|(path, entry)| (entry, path.split_dirname().0));
+
+    // Append root manifest as well
+    entries.chain(root_entry_stream).boxify()
+}
+
+/// Returns the tree entries of each designated (directory, manifest) pair, down to `max_depth`,
+/// in the order of the request. The designated manifest comes after its subtrees.
+fn get_designated_manifests_stream(
+    repo: &BlobRepo,
+    designatednodes: Vec<(Bytes, HgManifestId)>,
+    max_depth: usize,
+    trace: TraceContext,
+) -> Result<BoxStream<(Box<Entry + Sync>, Option<MPath>), Error>> {
+    let mut streams = Vec::with_capacity(designatednodes.len());
+    for (dir, mfnode) in designatednodes {
+        let dir = if dir.is_empty() {
+            None
+        } else {
+            Some(MPath::new(dir)?)
+        };
+        streams.push(get_all_manifests_stream(
+            repo,
+            &mfnode,
+            dir,
+            max_depth,
+            trace.clone(),
+        ));
+    }
+    Ok(stream::iter_ok(streams).flatten().boxify())
+}
+
+/// Returns the tree entries of each of `directories` in each of `mfnodes`, which are manifests of
+/// `rootpath`, down to `max_depth`. Directories are full paths under `rootpath`, an empty one is
+/// the root of the repo. Trees are in the order of the request, mfnode by mfnode. A directory
+/// that isn't in a manifest, e.g. because it was deleted, is skipped for that manifest.
+fn get_directories_manifests_stream(
+    repo: &BlobRepo,
+    rootpath: Option<MPath>,
+    mfnodes: Vec<HgManifestId>,
+    directories: Vec<Bytes>,
+    max_depth: usize,
+    trace: TraceContext,
+) -> BoxStream<(Box<Entry + Sync>, Option<MPath>), Error> {
+    // Paths of the directories relative to rootpath, to look them up in mfnodes
+    let mut relative_dirs = Vec::with_capacity(directories.len());
+    for dir in directories {
+        let dir = if dir.is_empty() {
+            None
+        } else {
+            Some(try_boxstream!(MPath::new(&dir)))
+        };
+        let under_rootpath = match (&rootpath, &dir) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(rootpath), Some(dir)) => rootpath.is_prefix_of(dir),
+        };
+        if !under_rootpath {
+            return stream::once(Err(err_msg(format!(
+                "directory {} is not under rootdir {}",
+                MPath::display_opt(dir.as_ref()),
+                MPath::display_opt(rootpath.as_ref()),
+            )))).boxify();
+        }
+        let skipped = rootpath.as_ref().map_or(0, MPath::num_components);
+        let relative = MPath::join_opt(None, MPath::iter_opt(dir.as_ref()).skip(skipped));
+        relative_dirs.push((dir, relative));
+    }
+
+    let mut lookups = Vec::with_capacity(mfnodes.len() * relative_dirs.len());
+    for mfnode in mfnodes {
+        for (dir, relative) in &relative_dirs {
+            let dir = dir.as_ref().map(MPath::to_vec).unwrap_or_default();
+            lookups.push(
+                repo.find_tree_in_manifest(relative.clone(), mfnode)
+                    .map(move |tree| tree.map(|tree| (Bytes::from(dir), tree))),
+            );
+        }
+    }
+
+    cloned!(repo);
+    stream::iter_ok(lookups)
+        .buffered(100)
+        .filter_map(|designatednode| designatednode)
+        .collect()
+        .and_then(move |designatednodes| {
+            get_designated_manifests_stream(&repo, designatednodes, max_depth, trace)
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+/// Bundle2 with a treepack part of `entries`, up to `buffer_size` of which are fetched at the same
+/// time. Once a tree is fetched for the response, the files in it are prefetched in the
+/// background by `prefetcher`, if there is one.
+fn create_treepack_stream(
+    repo: &BlobRepo,
+    entries: BoxStream<(Box<Entry + Sync>, Option<MPath>), Error>,
+    deadline: Option<Deadline>,
+    prefetcher: Option<FilePrefetcher>,
+    buffer_size: usize,
+    trace: TraceContext,
+) -> BoxStream<Bytes, Error> {
+    let changed_entries = with_deadline(entries, deadline)
+        .filter({
+            let mut used_hashes = HashSet::new();
+            move |entry| used_hashes.insert(*entry.0.get_hash())
+        })
+        .map({
+            cloned!(repo);
+            move |(entry, basepath)| {
+                let mfid = HgManifestId::new(entry.get_hash().into_nodehash());
+                let part_input = fetch_treepack_part_input(&repo, entry, basepath, trace.clone());
+                match prefetcher {
+                    Some(ref prefetcher) => {
+                        cloned!(prefetcher);
+                        part_input
+                            .inspect(move |_| prefetcher.prefetch_tree(mfid))
+                            .boxify()
+                    }
+                    None => part_input,
+                }
+            }
+        });
+
+    let part = parts::treepack_part(changed_entries, buffer_size);
+    // Mercurial currently hangs while trying to read compressed bundles over the wire:
+    // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
+    // TODO: possibly enable compression support once this is fixed.
+    let compression = None;
+    part.into_future()
+        .map(move |part| create_bundle_stream(vec![part], compression))
+        .flatten_stream()
+        .boxify()
 }
 
 fn fetch_treepack_part_input(
@@ -843,3 +1617,603 @@ fn fetch_treepack_part_input(
         })
         .boxify()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+    use std::time::Instant;
+
+    use async_unit;
+    use blobstore::Blobstore;
+    use context::DeadlineExceeded;
+    use fixtures::{linear, many_files_dirs};
+    use mercurial_types::{Changeset, MPathElement, RepositoryId};
+    use repo_flags::RuntimeRepoFlags;
+    use slog::Discard;
+
+    use client::streaming_clone::StreamingChunksFetcher;
+
+    fn designated_paths(
+        repo: &BlobRepo,
+        designatednodes: Vec<(Bytes, HgManifestId)>,
+        max_depth: usize,
+    ) -> Vec<(String, HgNodeHash)> {
+        let trace = TraceContext::new(Uuid::new_v4(), Instant::now());
+        get_designated_manifests_stream(repo, designatednodes, max_depth, trace)
+            .unwrap()
+            .map(|(entry, basepath)| {
+                let path = MPath::join_element_opt(basepath.as_ref(), entry.get_name());
+                let path = path.map(|path| path.to_string()).unwrap_or_default();
+                (path, entry.get_hash().into_nodehash())
+            })
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_designated_manifests() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let csid = HgChangesetId::from_str("2f866e7e549760934e31bf0420a873f65100ad63").unwrap();
+            let mfid = *repo.get_changeset_by_changesetid(&csid)
+                .wait()
+                .unwrap()
+                .manifestid();
+            let root = repo.get_manifest_by_nodeid(&mfid).wait().unwrap();
+            let tree = |name: &str| {
+                let entry = root.lookup(&MPathElement::new(name.as_bytes().to_vec()).unwrap())
+                    .unwrap();
+                entry.get_hash().into_nodehash()
+            };
+            let dir1 = tree("dir1");
+            let dir2 = tree("dir2");
+            let designatednodes = vec![
+                (Bytes::from("dir1"), HgManifestId::new(dir1)),
+                (Bytes::from("dir2"), HgManifestId::new(dir2)),
+            ];
+
+            // Only the designated manifests, in the order of the request
+            assert_eq!(
+                designated_paths(&repo, designatednodes.clone(), 1),
+                vec![("dir1".to_string(), dir1), ("dir2".to_string(), dir2)]
+            );
+
+            // With their subtrees, which come first
+            let paths: Vec<_> = designated_paths(&repo, designatednodes, 2)
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            assert_eq!(paths, vec!["dir1/subdir1", "dir1", "dir2"]);
+
+            // The root manifest has an empty directory
+            assert_eq!(
+                designated_paths(&repo, vec![(Bytes::new(), mfid)], 1),
+                vec![("".to_string(), mfid.into_nodehash())]
+            );
+        })
+    }
+
+    fn directories_paths(
+        repo: &BlobRepo,
+        rootdir: &str,
+        csids: &[&str],
+        directories: &[&str],
+        max_depth: usize,
+    ) -> Result<Vec<(String, HgNodeHash)>> {
+        let trace = TraceContext::new(Uuid::new_v4(), Instant::now());
+        let rootpath = if rootdir.is_empty() {
+            None
+        } else {
+            Some(MPath::new(rootdir).unwrap())
+        };
+        let mfnodes = csids.iter().map(|csid| manifest_id(repo, csid)).collect();
+        let directories = directories.iter().map(|dir| Bytes::from(*dir)).collect();
+        get_directories_manifests_stream(repo, rootpath, mfnodes, directories, max_depth, trace)
+            .map(|(entry, basepath)| {
+                let path = MPath::join_element_opt(basepath.as_ref(), entry.get_name());
+                let path = path.map(|path| path.to_string()).unwrap_or_default();
+                (path, entry.get_hash().into_nodehash())
+            })
+            .collect()
+            .wait()
+    }
+
+    #[test]
+    fn test_directories_manifests() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            let with_dirs = "2f866e7e549760934e31bf0420a873f65100ad63";
+            let subdir_changed = "d261bc7900818dea7c86935b3fb17a33b2e3a6b4";
+            let dir_replaced = "0c59c8d0da93cbf9d7f4b888f28823ffb2e3e480";
+            let tree = |csid: &str, dir: &str| {
+                repo.find_tree_in_manifest(Some(MPath::new(dir).unwrap()), manifest_id(&repo, csid))
+                    .wait()
+                    .unwrap()
+                    .expect("tree is missing")
+                    .into_nodehash()
+            };
+
+            // A nested directory and the root, in the order of the request
+            assert_eq!(
+                directories_paths(&repo, "", &[with_dirs], &["dir1/subdir1", ""], 1).unwrap(),
+                vec![
+                    ("dir1/subdir1".to_string(), tree(with_dirs, "dir1/subdir1")),
+                    ("".to_string(), manifest_id(&repo, with_dirs).into_nodehash()),
+                ]
+            );
+
+            // The same directory in several manifests, mfnode by mfnode. dir1 is a file in
+            // dir_replaced, so it has no tree there.
+            assert_eq!(
+                directories_paths(
+                    &repo,
+                    "",
+                    &[with_dirs, dir_replaced, subdir_changed],
+                    &["dir1"],
+                    1,
+                ).unwrap(),
+                vec![
+                    ("dir1".to_string(), tree(with_dirs, "dir1")),
+                    ("dir1".to_string(), tree(subdir_changed, "dir1")),
+                ]
+            );
+
+            // With their subtrees, which come first
+            let paths: Vec<_> = directories_paths(&repo, "", &[with_dirs], &["dir1", "dir2"], 2)
+                .unwrap()
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            assert_eq!(paths, vec!["dir1/subdir1", "dir1", "dir2"]);
+
+            // mfnodes are manifests of the rootdir, directories are full paths under it
+            let dir1_mfid = HgManifestId::new(tree(with_dirs, "dir1"));
+            let trace = TraceContext::new(Uuid::new_v4(), Instant::now());
+            let paths: Vec<_> = get_directories_manifests_stream(
+                &repo,
+                Some(MPath::new("dir1").unwrap()),
+                vec![dir1_mfid],
+                vec![Bytes::from("dir1/subdir1")],
+                1,
+                trace,
+            ).map(|(entry, basepath)| (basepath, entry.get_hash().into_nodehash()))
+                .collect()
+                .wait()
+                .unwrap();
+            assert_eq!(
+                paths,
+                vec![
+                    (
+                        Some(MPath::new("dir1/subdir1").unwrap()),
+                        tree(with_dirs, "dir1/subdir1"),
+                    ),
+                ]
+            );
+            assert!(directories_paths(&repo, "dir1", &[with_dirs], &["dir2"], 1).is_err());
+        })
+    }
+
+    fn manifest_id(repo: &BlobRepo, csid: &str) -> HgManifestId {
+        let csid = HgChangesetId::from_str(csid).unwrap();
+        *repo.get_changeset_by_changesetid(&csid)
+            .wait()
+            .unwrap()
+            .manifestid()
+    }
+
+    fn changed_paths(repo: &BlobRepo, csid: &str, base_csids: &[&str]) -> Vec<String> {
+        let trace = TraceContext::new(Uuid::new_v4(), Instant::now());
+        let basemfids: Vec<_> = base_csids
+            .iter()
+            .map(|base_csid| manifest_id(repo, base_csid))
+            .collect();
+        get_changed_manifests_stream(
+            repo,
+            &manifest_id(repo, csid),
+            &basemfids,
+            None,
+            CombinatorPruner::new(FilePruner, DeletedPruner),
+            2 << 16,
+            trace,
+        ).map(|(entry, basepath)| {
+            let path = MPath::join_element_opt(basepath.as_ref(), entry.get_name());
+            path.map(|path| path.to_string()).unwrap_or_default()
+        })
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_changed_manifests_multiple_bases() {
+        async_unit::tokio_unit_test(|| {
+            let repo = many_files_dirs::getrepo(None);
+            // Commits of many_files_dirs: the first one has no directories, the second one adds
+            // dir1, dir1/subdir1 and dir2, the third one changes dir1/subdir1 only, and the
+            // fourth one replaces dir1 with a file.
+            let no_dirs = "5a28e25f924a5d209b82ce0713d8d83e68982bc8";
+            let with_dirs = "2f866e7e549760934e31bf0420a873f65100ad63";
+            let subdir_changed = "d261bc7900818dea7c86935b3fb17a33b2e3a6b4";
+            let dir_replaced = "0c59c8d0da93cbf9d7f4b888f28823ffb2e3e480";
+
+            assert_eq!(
+                changed_paths(&repo, subdir_changed, &[no_dirs]),
+                vec!["dir1", "dir1/subdir1", "dir2", ""]
+            );
+            assert_eq!(
+                changed_paths(&repo, subdir_changed, &[with_dirs]),
+                vec!["dir1", "dir1/subdir1", ""]
+            );
+
+            // dir2 is in one of the base manifests, whichever it is
+            assert_eq!(
+                changed_paths(&repo, subdir_changed, &[no_dirs, with_dirs]),
+                vec!["dir1", "dir1/subdir1", ""]
+            );
+            assert_eq!(
+                changed_paths(&repo, subdir_changed, &[with_dirs, no_dirs]),
+                vec!["dir1", "dir1/subdir1", ""]
+            );
+            // A base manifest that has no dir1 at all still has dir2
+            assert_eq!(
+                changed_paths(&repo, subdir_changed, &[no_dirs, dir_replaced]),
+                vec!["dir1", "dir1/subdir1", ""]
+            );
+            // Each base manifest has a different subtree: dir1 and dir1/subdir1 are in
+            // subdir_changed, and dir2 is in dir_replaced
+            assert_eq!(
+                changed_paths(&repo, subdir_changed, &[subdir_changed, dir_replaced]),
+                vec![""]
+            );
+
+            // Nothing changed since a base manifest but the root, which is always sent
+            assert_eq!(
+                changed_paths(&repo, subdir_changed, &[no_dirs, subdir_changed]),
+                vec![""]
+            );
+        })
+    }
+
+    #[test]
+    fn test_lookup_key() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let lookup = |key: &str| {
+                let res = lookup_key(repo.clone(), key.to_string()).wait().unwrap();
+                String::from_utf8(res.to_vec()).unwrap()
+            };
+            // Commits of linear whose ids start with "3"
+            let cs_3c = "3c15267ebf11807f3d772eb891272b911ec68759";
+            let cs_3e = "3e0e761030db6e479a7fb58b12881883f9f8c63f";
+
+            assert_eq!(lookup(cs_3c), format!("1 {}\n", cs_3c));
+            assert_eq!(lookup("3c"), format!("1 {}\n", cs_3c));
+            assert_eq!(lookup("3E0E7"), format!("1 {}\n", cs_3e));
+            assert_eq!(
+                lookup("3"),
+                format!("0 3: ambiguous identifier, candidates: {}, {}\n", cs_3c, cs_3e)
+            );
+            assert_eq!(lookup("3f"), "0 3f not found\n");
+            assert_eq!(lookup(&"3".repeat(40)), format!("0 {} not found\n", "3".repeat(40)));
+            assert_eq!(lookup("master"), "0 master not found\n");
+
+            // A bookmark takes precedence over the commits that its name is a prefix of
+            let cs_id = HgChangesetId::from_str(cs_3e).unwrap();
+            let bcs_id = repo.get_bonsai_from_hg(&cs_id).wait().unwrap().unwrap();
+            let mut txn = repo.update_bookmark_transaction();
+            txn.force_set(&Bookmark::new("3c").unwrap(), &bcs_id).unwrap();
+            txn.force_set(&Bookmark::new("master").unwrap(), &bcs_id).unwrap();
+            txn.commit().wait().unwrap();
+            assert_eq!(lookup("3c"), format!("1 {}\n", cs_3e));
+            assert_eq!(lookup("master"), format!("1 {}\n", cs_3e));
+        })
+    }
+
+    #[test]
+    fn test_lookup_bookmark_names() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+            let lookup = |key: &str| {
+                let res = lookup_key(repo.clone(), key.to_string()).wait().unwrap();
+                String::from_utf8(res.to_vec()).unwrap()
+            };
+            let cs = "3e0e761030db6e479a7fb58b12881883f9f8c63f";
+            let names = vec!["releases/v1.2", "100%2F", "with space", "a:b,c;d=e"];
+
+            let cs_id = HgChangesetId::from_str(cs).unwrap();
+            let bcs_id = repo.get_bonsai_from_hg(&cs_id).wait().unwrap().unwrap();
+            let mut txn = repo.update_bookmark_transaction();
+            for name in &names {
+                txn.force_set(&Bookmark::new(name).unwrap(), &bcs_id).unwrap();
+            }
+            txn.commit().wait().unwrap();
+
+            for name in &names {
+                assert_eq!(lookup(name), format!("1 {}\n", cs), "{}", name);
+            }
+            assert_eq!(lookup("releases"), "0 releases not found\n");
+            assert_eq!(lookup("releases/v1.3"), "0 releases/v1.3 not found\n");
+
+            // Keys that can't be bookmarks are sent back escaped
+            assert_eq!(lookup("caf\u{e9}"), "0 invalid input: caf\\xc3\\xa9\n");
+            assert_eq!(lookup("new\nline"), "0 invalid input: new\\nline\n");
+        })
+    }
+
+    #[test]
+    fn test_between() {
+        async_unit::tokio_unit_test(|| {
+            let repo = linear::getrepo(None);
+
+            // The first-parent chain of the top commit of linear, down to the root
+            let mut chain = vec![
+                HgNodeHash::from_str("79a13814c5ce7330173ec04d279bf95ab3f652fb").unwrap(),
+            ];
+            loop {
+                let csid = HgChangesetId::new(*chain.last().unwrap());
+                let cs = repo.get_changeset_by_changesetid(&csid)
+                    .wait()
+                    .unwrap();
+                match cs.p1() {
+                    Some(p1) => chain.push(*p1),
+                    None => break,
+                }
+            }
+            assert_eq!(chain.len(), 11);
+
+            // The commits 1, 2, 4, 8... parents away from chain[top], up to chain[bottom]
+            let sampled = |top: usize, bottom: usize| -> Vec<HgNodeHash> {
+                let mut sampled = vec![];
+                let mut distance = 1;
+                while top + distance < bottom {
+                    sampled.push(chain[top + distance]);
+                    distance *= 2;
+                }
+                sampled
+            };
+
+            // More pairs than are walked at the same time, with walks of different lengths
+            let bounds = vec![(0, 10), (3, 5), (0, 11), (7, 8), (1, 10), (2, 2), (0, 9)];
+            let pairs = bounds
+                .iter()
+                .map(|&(top, bottom)| (chain[top], *chain.get(bottom).unwrap_or(&NULL_HASH)))
+                .collect();
+            let res = between_pairs(repo.clone(), pairs, 2, 100).wait().unwrap();
+            let expected: Vec<_> = bounds
+                .iter()
+                .map(|&(top, bottom)| sampled(top, bottom))
+                .collect();
+            assert_eq!(res, expected);
+            assert_eq!(res[0], vec![chain[1], chain[2], chain[4], chain[8]]);
+
+            // A bottom that isn't an ancestor of the top is never reached
+            let unknown = HgNodeHash::from_str(&"1".repeat(40)).unwrap();
+            assert_eq!(
+                between_pairs(repo.clone(), vec![(chain[0], unknown)], 2, 100)
+                    .wait()
+                    .unwrap(),
+                vec![sampled(0, 11)]
+            );
+            let err = between_pairs(repo.clone(), vec![(chain[0], unknown)], 2, 5)
+                .wait()
+                .expect_err("the walk must stop at the limit");
+            match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::BetweenWalkTooLong(top, bottom, 5)) => {
+                    assert_eq!((top, bottom), (chain[0], unknown));
+                }
+                bad => panic!("unexpected result {:?}", bad),
+            }
+            // Walks that stay under the limit are fine
+            assert_eq!(
+                between_pairs(repo, vec![(chain[0], chain[5])], 2, 5)
+                    .wait()
+                    .unwrap(),
+                vec![sampled(0, 5)]
+            );
+        })
+    }
+
+    #[test]
+    fn test_check_unknown_args() {
+        let logger = Logger::root(Discard, o!());
+        let unknown_args = vec![
+            (Bytes::from("narrow"), Bytes::from("1")),
+            (Bytes::from("narrowacl"), Bytes::from("foo")),
+        ];
+
+        let names = check_unknown_args(&logger, ops::GETBUNDLE, &[], true).unwrap();
+        assert_eq!(names, None);
+
+        let names = check_unknown_args(&logger, ops::GETBUNDLE, &unknown_args, false).unwrap();
+        assert_eq!(names, Some("narrow narrowacl".to_string()));
+
+        let err = check_unknown_args(&logger, ops::GETBUNDLE, &unknown_args, true)
+            .expect_err("unknown args must be rejected in strict mode");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::UnknownWireprotoArgs(ref command, ref names)) => {
+                assert_eq!(command, "getbundle");
+                assert_eq!(names, "narrow narrowacl");
+            }
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
+
+    #[test]
+    fn test_with_deadline() {
+        let max = Duration::from_secs(60);
+
+        // An operation that produces its items slowly
+        let slow = || {
+            stream::iter_ok::<_, Error>(0..10)
+                .map(|item| {
+                    thread::sleep(Duration::from_millis(20));
+                    item
+                })
+                .boxify()
+        };
+
+        let deadline = Deadline::from_hint(Instant::now(), Duration::from_millis(50), max);
+        let mut items = vec![];
+        let err = with_deadline(slow(), Some(deadline))
+            .for_each(|item| {
+                items.push(item);
+                Ok(())
+            })
+            .wait()
+            .expect_err("the deadline should be exceeded");
+        match err.downcast::<DeadlineExceeded>() {
+            Ok(DeadlineExceeded(budget)) => assert_eq!(budget, Duration::from_millis(50)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Aborted early
+        assert!(items.len() < 10);
+
+        // A hint over the timeout of the server is clamped to it
+        let deadline = Deadline::from_hint(Instant::now(), Duration::from_secs(3600), max);
+        assert_eq!(deadline.budget(), max);
+        let items = with_deadline(slow(), Some(deadline)).collect().wait().unwrap();
+        assert_eq!(items.len(), 10);
+        let items = with_deadline(slow(), None).collect().wait().unwrap();
+        assert_eq!(items.len(), 10);
+    }
+
+    fn streaming_chunk(content: &'static [u8]) -> StreamingChunk {
+        StreamingChunk {
+            blob_name: "blob".to_string(),
+            size: content.len(),
+            sha1: None,
+            data: future::ok(Bytes::from_static(content)).boxify(),
+        }
+    }
+
+    #[test]
+    fn test_stream_out_shallow_response() {
+        let changelog = RevlogStreamingChunks {
+            index_size: 5,
+            data_size: 3,
+            index_blobs: vec![streaming_chunk(b"ind"), streaming_chunk(b"ex")],
+            data_blobs: vec![streaming_chunk(b"dat")],
+        };
+        let logger = Logger::root(Discard, o!());
+        let response = stream_out_shallow_response(logger, changelog)
+            .concat2()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            response,
+            Bytes::from(&b"0\n2 8\n00changelog.i\x005\nindex00changelog.d\x003\ndat"[..])
+        );
+    }
+
+    /// Serves the chunks of `streaming_chunk` instead of reading them from a database
+    struct FakeStreamingChunksFetcher;
+
+    impl StreamingChunksFetcher for FakeStreamingChunksFetcher {
+        fn fetch_changelog(
+            &self,
+            _repo: RepositoryId,
+            _blobstore: Arc<Blobstore>,
+        ) -> BoxFuture<RevlogStreamingChunks, Error> {
+            future::ok(RevlogStreamingChunks {
+                index_size: 2,
+                data_size: 3,
+                index_blobs: vec![streaming_chunk(b"ix")],
+                data_blobs: vec![streaming_chunk(b"dat")],
+            }).boxify()
+        }
+    }
+
+    fn repo_client(streaming_clone: bool) -> RepoClient {
+        let repo = linear::getrepo(None);
+        let logger = Logger::root(Discard, o!());
+        let hook_manager = Arc::new(HookManager::new_with_blobrepo(
+            repo.clone(),
+            None,
+            logger.clone(),
+        ));
+        let streaming_clone = if streaming_clone {
+            Some(MysqlStreamingCloneConfig {
+                blobstore: repo.get_blobstore(),
+                fetcher: Arc::new(FakeStreamingChunksFetcher),
+                repoid: repo.get_repoid(),
+            })
+        } else {
+            None
+        };
+        let repo = MononokeRepo::new(
+            repo,
+            &Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            hook_manager,
+            streaming_clone,
+            None,
+            None,
+            RuntimeRepoFlags::fixed(Default::default()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let session = Uuid::new_v4();
+        let ctxt = CoreContext {
+            session,
+            logger,
+            scuba: ScubaSampleBuilder::with_discard(),
+            trace: TraceContext::new(session, Instant::now()),
+            user: None,
+            source_hostname: None,
+            deadline: None,
+        };
+        RepoClient::new(repo, ctxt)
+    }
+
+    fn streaming_caps(client: &RepoClient) -> Vec<String> {
+        let mut caps = client.hello().wait().unwrap();
+        caps.remove("capabilities")
+            .unwrap()
+            .into_iter()
+            .filter(|cap| cap.starts_with("stream"))
+            .collect()
+    }
+
+    #[test]
+    fn test_streaming_clone_caps() {
+        async_unit::tokio_unit_test(|| {
+            let client = repo_client(true);
+            assert_eq!(
+                streaming_caps(&client),
+                vec![
+                    "stream-preferred".to_string(),
+                    "stream_option".to_string(),
+                    format!("streamreqs={}", STREAM_REQUIREMENTS),
+                ]
+            );
+
+            let response = client.stream_out_shallow().concat2().wait().unwrap();
+            assert_eq!(
+                response,
+                Bytes::from(&b"0\n2 5\n00changelog.i\x002\nix00changelog.d\x003\ndat"[..])
+            );
+        })
+    }
+
+    #[test]
+    fn test_no_streaming_clone_caps() {
+        async_unit::tokio_unit_test(|| {
+            let client = repo_client(false);
+            assert!(streaming_caps(&client).is_empty());
+
+            let response = client.stream_out_shallow().concat2().wait().unwrap();
+            assert_eq!(response, Bytes::from_static(STREAM_FORBIDDEN));
+        })
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Selection of the bookmarks that are sent in the listkeys part of a getbundle response. Repos
+//! can have far more bookmarks than clients care about, so `PullBookmarksParams` limits which of
+//! them every pull carries.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use bookmarks::Bookmark;
+use metaconfig::PullBookmarksParams;
+
+/// Bookmarks selected for a listkeys part
+#[derive(Debug, Eq, PartialEq)]
+pub struct PullBookmarks<T> {
+    /// The bookmarks to send, sorted by name
+    pub bookmarks: Vec<(Bookmark, T)>,
+    /// Number of bookmarks that matched the filter but were dropped because of the cap
+    pub truncated: usize,
+}
+
+/// Selects the bookmarks to send out of `bookmarks`, a snapshot of all the bookmarks of the repo.
+///
+/// Bookmarks that `params` excludes are never selected. Of the others:
+///
+/// getbundle doesn't say which bookmarks a client asked for, but `hg pull -B name` sends the
+/// changeset of `name` as one of `heads`. So bookmarks that point to one of `heads` are always
+/// selected, even if the filter doesn't match them or there are more of them than the cap. The
+/// other bookmarks matching the filter are selected in name order until there are
+/// `params.max_count` bookmarks in total.
+pub fn select_pull_bookmarks<T>(
+    params: &PullBookmarksParams,
+    heads: &HashSet<T>,
+    mut bookmarks: Vec<(Bookmark, T)>,
+) -> PullBookmarks<T>
+where
+    T: Eq + Hash,
+{
+    bookmarks.retain(|&(ref name, _)| !params.excludes(name));
+    bookmarks.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let (requested, rest): (Vec<_>, Vec<_>) = bookmarks
+        .into_iter()
+        .partition(|&(_, ref cs)| heads.contains(cs));
+    let mut matching: Vec<_> = rest.into_iter()
+        .filter(|&(ref name, _)| match params.filter {
+            Some(ref filter) => filter.matches(name),
+            None => true,
+        })
+        .collect();
+
+    let room = params
+        .max_count
+        .map(|max_count| max_count.saturating_sub(requested.len()))
+        .unwrap_or(matching.len());
+    let truncated = matching.len().saturating_sub(room);
+    matching.truncate(room);
+
+    let mut selected = requested;
+    selected.extend(matching);
+    selected.sort_by(|a, b| a.0.cmp(&b.0));
+
+    PullBookmarks {
+        bookmarks: selected,
+        truncated,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bookmarks::BookmarkPrefix;
+    use metaconfig::PullBookmarksFilter;
+
+    fn bookmarks(names: &[(&str, u32)]) -> Vec<(Bookmark, u32)> {
+        names
+            .iter()
+            .map(|&(name, cs)| (Bookmark::new(name).unwrap(), cs))
+            .collect()
+    }
+
+    fn filter(names: &[&str], prefixes: &[&str]) -> Option<PullBookmarksFilter> {
+        Some(PullBookmarksFilter {
+            names: names.iter().map(|n| Bookmark::new(n).unwrap()).collect(),
+            prefixes: prefixes
+                .iter()
+                .map(|p| BookmarkPrefix::new(p).unwrap())
+                .collect(),
+        })
+    }
+
+    fn repo_bookmarks() -> Vec<(Bookmark, u32)> {
+        bookmarks(&[
+            ("scratch/alice/feature", 5),
+            ("release/2.0", 3),
+            ("master", 1),
+            ("release/1.0", 2),
+            ("stable", 4),
+        ])
+    }
+
+    #[test]
+    fn test_default_sends_everything() {
+        let selected = select_pull_bookmarks(
+            &PullBookmarksParams::default(),
+            &HashSet::new(),
+            repo_bookmarks(),
+        );
+        assert_eq!(
+            selected,
+            PullBookmarks {
+                bookmarks: bookmarks(&[
+                    ("master", 1),
+                    ("release/1.0", 2),
+                    ("release/2.0", 3),
+                    ("scratch/alice/feature", 5),
+                    ("stable", 4),
+                ]),
+                truncated: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let params = PullBookmarksParams {
+            filter: filter(&["master"], &["release/"]),
+            max_count: None,
+            exclude_prefixes: vec![],
+        };
+        let selected = select_pull_bookmarks(&params, &HashSet::new(), repo_bookmarks());
+        assert_eq!(
+            selected.bookmarks,
+            bookmarks(&[("master", 1), ("release/1.0", 2), ("release/2.0", 3)])
+        );
+        assert_eq!(selected.truncated, 0);
+    }
+
+    #[test]
+    fn test_cap() {
+        let params = PullBookmarksParams {
+            filter: filter(&["master"], &["release/"]),
+            max_count: Some(2),
+            exclude_prefixes: vec![],
+        };
+        let selected = select_pull_bookmarks(&params, &HashSet::new(), repo_bookmarks());
+        assert_eq!(
+            selected.bookmarks,
+            bookmarks(&[("master", 1), ("release/1.0", 2)])
+        );
+        assert_eq!(selected.truncated, 1);
+    }
+
+    #[test]
+    fn test_requested_bookmarks_always_sent() {
+        // "scratch/alice/feature" doesn't match the filter and the cap is already reached by
+        // the matching bookmarks, but it is pulled by name
+        let params = PullBookmarksParams {
+            filter: filter(&["master"], &["release/"]),
+            max_count: Some(2),
+            exclude_prefixes: vec![],
+        };
+        let heads: HashSet<_> = vec![5].into_iter().collect();
+        let selected = select_pull_bookmarks(&params, &heads, repo_bookmarks());
+        assert_eq!(
+            selected.bookmarks,
+            bookmarks(&[("master", 1), ("scratch/alice/feature", 5)])
+        );
+        assert_eq!(selected.truncated, 2);
+
+        // More requested bookmarks than the cap
+        let params = PullBookmarksParams {
+            filter: filter(&[], &[]),
+            max_count: Some(1),
+            exclude_prefixes: vec![],
+        };
+        let heads: HashSet<_> = vec![2, 4].into_iter().collect();
+        let selected = select_pull_bookmarks(&params, &heads, repo_bookmarks());
+        assert_eq!(
+            selected.bookmarks,
+            bookmarks(&[("release/1.0", 2), ("stable", 4)])
+        );
+        assert_eq!(selected.truncated, 0);
+    }
+
+    #[test]
+    fn test_excluded_prefixes() {
+        let params = PullBookmarksParams {
+            filter: None,
+            max_count: Some(3),
+            exclude_prefixes: vec![BookmarkPrefix::new("scratch/").unwrap()],
+        };
+        // Not even when pulled by name
+        let heads: HashSet<_> = vec![5].into_iter().collect();
+        let selected = select_pull_bookmarks(&params, &heads, repo_bookmarks());
+        assert_eq!(
+            selected.bookmarks,
+            bookmarks(&[("master", 1), ("release/1.0", 2), ("release/2.0", 3)])
+        );
+        // Excluded bookmarks don't count as truncated
+        assert_eq!(selected.truncated, 1);
+    }
+}
@@ -4,7 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashSet, VecDeque};
 use std::io::{Cursor, Write};
 use std::sync::Arc;
 
@@ -62,14 +62,9 @@ pub fn create_remotefilelog_blob(
 
     // Do bulk prefetch of the filenodes first. That saves lots of db roundtrips.
     // Prefetched filenodes are used as a cache. If filenode is not in the cache, then it will
-    // be fetched again.
+    // be fetched again. They come in canonical order, i.e. sorted by hash, so they are looked
+    // up with a binary search.
     let prefetched_filenodes = repo.get_all_filenodes(RepoPath::FilePath(path.clone()))
-        .map(move |filenodes| {
-            filenodes
-                .into_iter()
-                .map(|filenode| (filenode.filenode.into_nodehash(), filenode))
-                .collect()
-        })
         .traced(&trace, "prefetching file history", trace_args.clone());
 
     let file_history_bytes = prefetched_filenodes
@@ -129,11 +124,14 @@ pub fn create_remotefilelog_blob(
         .boxify()
 }
 
+/// The history of the file at `startnode`: a breadth-first walk of its ancestors, with p1 before
+/// p2. The order only depends on the history, and not on the order that the filenodes are
+/// stored or prefetched in, so every host sends the same history for a file.
 fn get_file_history(
     repo: Arc<BlobRepo>,
     startnode: HgNodeHash,
     path: MPath,
-    prefetched_history: HashMap<HgNodeHash, FilenodeInfo>,
+    prefetched_history: Vec<FilenodeInfo>,
 ) -> BoxStream<
     (
         HgNodeHash,
@@ -156,8 +154,10 @@ fn get_file_history(
         move |(mut nodes, mut seen_nodes): (VecDeque<HgNodeHash>, HashSet<HgNodeHash>)| {
             let node = nodes.pop_front()?;
 
-            let fut = if let Some(filenode) = prefetched_history.get(&node) {
-                Either::A(Ok(filenode.clone()).into_future())
+            let prefetched = prefetched_history
+                .binary_search_by(|filenode| filenode.filenode.into_nodehash().cmp(&node));
+            let fut = if let Ok(index) = prefetched {
+                Either::A(Ok(prefetched_history[index].clone()).into_future())
             } else {
                 Either::B(repo.get_filenode(&path, &node))
             };
@@ -4,6 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::sync::Arc;
 use std::vec::Vec;
 
 use bytes::Bytes;
@@ -11,23 +12,36 @@ use db_conn::MysqlConnInner;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
 use failure::Error;
-use futures::Future;
+use futures::{future, Future};
 use futures_ext::{asynchronize, BoxFuture, FutureExt};
 
 use blobstore::Blobstore;
 use mercurial_types::RepositoryId;
+use mercurial_types::hash::Sha1;
 use mononoke_types::BlobstoreBytes;
 
 use errors::*;
 
 mod schema;
 mod models;
+mod verify;
+
+pub use self::verify::verify_file_stream;
+
+/// One chunk of a revlog, with the size and checksum that the index recorded for it when the
+/// streaming clone data was generated. Rows written before checksums were recorded have none.
+pub struct StreamingChunk {
+    pub blob_name: String,
+    pub size: usize,
+    pub sha1: Option<Sha1>,
+    pub data: BoxFuture<Bytes, Error>,
+}
 
 pub struct RevlogStreamingChunks {
     pub index_size: usize,
     pub data_size: usize,
-    pub index_blobs: Vec<BoxFuture<Bytes, Error>>,
-    pub data_blobs: Vec<BoxFuture<Bytes, Error>>,
+    pub index_blobs: Vec<StreamingChunk>,
+    pub data_blobs: Vec<StreamingChunk>,
 }
 
 impl RevlogStreamingChunks {
@@ -41,6 +55,15 @@ impl RevlogStreamingChunks {
     }
 }
 
+/// Where `stream_out_shallow` gets the chunks of the changelog of a repo from
+pub trait StreamingChunksFetcher: Send + Sync + 'static {
+    fn fetch_changelog(
+        &self,
+        repo: RepositoryId,
+        blobstore: Arc<Blobstore>,
+    ) -> BoxFuture<RevlogStreamingChunks, Error>;
+}
+
 #[derive(Clone)]
 pub struct MysqlStreamingChunksFetcher {
     inner: MysqlConnInner,
@@ -58,11 +81,13 @@ impl MysqlStreamingChunksFetcher {
     fn get_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
         self.inner.get_conn()
     }
+}
 
-    pub fn fetch_changelog(
+impl StreamingChunksFetcher for MysqlStreamingChunksFetcher {
+    fn fetch_changelog(
         &self,
         repo: RepositoryId,
-        blobstore: impl Blobstore + Clone,
+        blobstore: Arc<Blobstore>,
     ) -> BoxFuture<RevlogStreamingChunks, Error> {
         let db = self.clone();
 
@@ -84,32 +109,18 @@ impl MysqlStreamingChunksFetcher {
                         move |mut res, row: self::models::StreamingChangelogChunksRow| {
                             res.data_size += row.data_size as usize;
                             res.index_size += row.idx_size as usize;
-                            let data_blob_key =
-                                String::from_utf8_lossy(&row.data_blob_name).into_owned();
-                            res.data_blobs.push(
-                                blobstore
-                                    .get(data_blob_key.clone())
-                                    .and_then(|data| {
-                                        data.ok_or(
-                                            ErrorKind::MissingStreamingBlob(data_blob_key).into(),
-                                        )
-                                    })
-                                    .map(BlobstoreBytes::into_bytes)
-                                    .boxify(),
-                            );
-                            let idx_blob_key =
-                                String::from_utf8_lossy(&row.idx_blob_name).into_owned();
-                            res.index_blobs.push(
-                                blobstore
-                                    .get(idx_blob_key.clone())
-                                    .and_then(|data| {
-                                        data.ok_or(
-                                            ErrorKind::MissingStreamingBlob(idx_blob_key).into(),
-                                        )
-                                    })
-                                    .map(BlobstoreBytes::into_bytes)
-                                    .boxify(),
-                            );
+                            res.data_blobs.push(fetch_chunk(
+                                &blobstore,
+                                &row.data_blob_name,
+                                row.data_size,
+                                row.data_sha1,
+                            ));
+                            res.index_blobs.push(fetch_chunk(
+                                &blobstore,
+                                &row.idx_blob_name,
+                                row.idx_size,
+                                row.idx_sha1,
+                            ));
                             res
                         },
                     )
@@ -118,3 +129,42 @@ impl MysqlStreamingChunksFetcher {
         }).boxify()
     }
 }
+
+fn fetch_chunk(
+    blobstore: &impl Blobstore,
+    blob_name: &[u8],
+    size: i32,
+    sha1: Option<Vec<u8>>,
+) -> StreamingChunk {
+    let blob_name = String::from_utf8_lossy(blob_name).into_owned();
+    let sha1 = match sha1 {
+        Some(sha1) => match Sha1::from_bytes(&sha1) {
+            Ok(sha1) => Some(sha1),
+            Err(_) => {
+                return StreamingChunk {
+                    data: future::err(ErrorKind::InvalidStreamingChecksum(blob_name.clone()).into())
+                        .boxify(),
+                    blob_name,
+                    size: size as usize,
+                    sha1: None,
+                }
+            }
+        },
+        None => None,
+    };
+    let data = blobstore
+        .get(blob_name.clone())
+        .and_then({
+            cloned!(blob_name);
+            move |data| data.ok_or(ErrorKind::MissingStreamingBlob(blob_name).into())
+        })
+        .map(BlobstoreBytes::into_bytes)
+        .boxify();
+
+    StreamingChunk {
+        blob_name,
+        size: size as usize,
+        sha1,
+        data,
+    }
+}
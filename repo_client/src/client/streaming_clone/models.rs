@@ -18,4 +18,6 @@ pub(crate) struct StreamingChangelogChunksRow {
     pub idx_size: i32,
     pub data_blob_name: Vec<u8>,
     pub data_size: i32,
+    pub idx_sha1: Option<Vec<u8>>,
+    pub data_sha1: Option<Vec<u8>>,
 }
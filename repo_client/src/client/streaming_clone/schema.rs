@@ -9,7 +9,7 @@
 //! changes it will need to be updated here as well.
 
 table! {
-    use diesel::sql_types::{Integer, Binary};
+    use diesel::sql_types::{Binary, Integer, Nullable};
 
     streaming_changelog_chunks (repo_id, chunk_num) {
         repo_id -> Integer,
@@ -18,5 +18,7 @@ table! {
         idx_size -> Integer,
         data_blob_name -> Binary,
         data_size -> Integer,
+        idx_sha1 -> Nullable<Binary>,
+        data_sha1 -> Nullable<Binary>,
     }
 }
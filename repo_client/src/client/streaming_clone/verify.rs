@@ -0,0 +1,272 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Verifies streaming clone chunks while they are served. A corrupt chunk would silently corrupt
+//! the changelog of every new clone, so the response is aborted instead.
+
+use bytes::Bytes;
+use failure::Error;
+use futures::{stream, Async, Future, Poll, Stream};
+use futures_ext::{BoxStream, StreamExt};
+use slog::Logger;
+
+use mercurial_types::hash::Sha1;
+
+use errors::*;
+
+use super::StreamingChunk;
+
+/// Streams the chunks of the revlog `name` in order, checking that each one has the size and
+/// checksum that the index recorded for it, and that together they have the `size` advertised
+/// in the response header. The stream fails on the first mismatch.
+pub fn verify_file_stream(
+    logger: Logger,
+    name: &'static str,
+    size: usize,
+    chunks: Vec<StreamingChunk>,
+) -> impl Stream<Item = Bytes, Error = Error> + Send {
+    let unchecked = chunks.iter().filter(|chunk| chunk.sha1.is_none()).count();
+    if unchecked > 0 {
+        warn!(
+            logger,
+            "{} of {} chunks of {} have no checksum, serving them unverified",
+            unchecked,
+            chunks.len(),
+            name
+        );
+    }
+
+    let chunks = stream::iter_ok(chunks.into_iter().map({
+        cloned!(logger);
+        move |chunk| {
+            let StreamingChunk {
+                blob_name,
+                size,
+                sha1,
+                data,
+            } = chunk;
+            cloned!(logger);
+            data.and_then(move |data| verify_chunk(&logger, name, blob_name, size, sha1, data))
+        }
+    })).buffered(100);
+
+    VerifiedFileStream {
+        logger,
+        name,
+        advertised_size: size,
+        streamed_size: 0,
+        chunks: chunks.boxify(),
+    }
+}
+
+fn verify_chunk(
+    logger: &Logger,
+    name: &str,
+    blob_name: String,
+    size: usize,
+    sha1: Option<Sha1>,
+    data: Bytes,
+) -> Result<Bytes> {
+    let problem = if data.len() != size {
+        Some(format!("expected {} bytes, got {}", size, data.len()))
+    } else {
+        sha1.and_then(|expected| {
+            let actual = Sha1::from(data.as_ref());
+            if actual == expected {
+                None
+            } else {
+                Some(format!("expected sha1 {}, got {}", expected, actual))
+            }
+        })
+    };
+
+    match problem {
+        None => Ok(data),
+        Some(problem) => {
+            crit!(
+                logger,
+                "aborting streaming clone, chunk {} of {} is corrupt: {}",
+                blob_name,
+                name,
+                problem
+            );
+            Err(ErrorKind::CorruptStreamingBlob(blob_name, problem).into())
+        }
+    }
+}
+
+struct VerifiedFileStream {
+    logger: Logger,
+    name: &'static str,
+    advertised_size: usize,
+    streamed_size: usize,
+    chunks: BoxStream<Bytes, Error>,
+}
+
+impl Stream for VerifiedFileStream {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        match try_ready!(self.chunks.poll()) {
+            Some(data) => {
+                self.streamed_size += data.len();
+                Ok(Async::Ready(Some(data)))
+            }
+            None if self.streamed_size != self.advertised_size => {
+                crit!(
+                    self.logger,
+                    "aborting streaming clone, streamed {} bytes of {} but advertised {}",
+                    self.streamed_size,
+                    self.name,
+                    self.advertised_size
+                );
+                Err(ErrorKind::StreamingSizeMismatch(
+                    self.name.to_string(),
+                    self.advertised_size,
+                    self.streamed_size,
+                ).into())
+            }
+            None => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use futures::future;
+    use futures_ext::FutureExt;
+    use slog::{Drain, Level, Never, OwnedKVList, Record};
+
+    /// Remembers the level and message of every record
+    #[derive(Clone, Default)]
+    struct RecordingDrain(Arc<Mutex<Vec<(Level, String)>>>);
+
+    impl Drain for RecordingDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _values: &OwnedKVList) -> ::std::result::Result<(), Never> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.level(), format!("{}", record.msg())));
+            Ok(())
+        }
+    }
+
+    impl RecordingDrain {
+        fn records(&self, level: Level) -> Vec<String> {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|&&(record_level, _)| record_level == level)
+                .map(|&(_, ref msg)| msg.clone())
+                .collect()
+        }
+    }
+
+    fn chunk(blob_name: &str, content: &'static [u8], checksum: bool) -> StreamingChunk {
+        StreamingChunk {
+            blob_name: blob_name.to_string(),
+            size: content.len(),
+            sha1: if checksum {
+                Some(Sha1::from(content))
+            } else {
+                None
+            },
+            data: future::ok(Bytes::from_static(content)).boxify(),
+        }
+    }
+
+    fn stream_file(
+        size: usize,
+        chunks: Vec<StreamingChunk>,
+    ) -> (Result<Vec<Bytes>>, RecordingDrain) {
+        let drain = RecordingDrain::default();
+        let logger = Logger::root(drain.clone(), o!());
+        let res = verify_file_stream(logger, "00changelog.i", size, chunks)
+            .collect()
+            .wait();
+        (res, drain)
+    }
+
+    #[test]
+    fn test_verified_chunks() {
+        let chunks = vec![chunk("chunk0", b"hello ", true), chunk("chunk1", b"world", true)];
+        let (res, drain) = stream_file(11, chunks);
+        assert_eq!(
+            res.unwrap(),
+            vec![Bytes::from_static(b"hello "), Bytes::from_static(b"world")]
+        );
+        assert!(drain.records(Level::Critical).is_empty());
+        assert!(drain.records(Level::Warning).is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_chunk() {
+        let mut corrupt = chunk("chunk1", b"world", true);
+        corrupt.data = future::ok(Bytes::from_static(b"w0rld")).boxify();
+        let chunks = vec![
+            chunk("chunk0", b"hello ", true),
+            corrupt,
+            chunk("chunk2", b"!", true),
+        ];
+        let (res, drain) = stream_file(12, chunks);
+
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("streaming blob chunk1 is corrupt"), "{}", err);
+        let crits = drain.records(Level::Critical);
+        assert_eq!(crits.len(), 1);
+        assert!(crits[0].contains("chunk chunk1 of 00changelog.i"), "{}", crits[0]);
+        assert!(crits[0].contains("expected sha1"), "{}", crits[0]);
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        let mut truncated = chunk("chunk0", b"hello", false);
+        truncated.data = future::ok(Bytes::from_static(b"hel")).boxify();
+        let (res, drain) = stream_file(5, vec![truncated]);
+
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("expected 5 bytes, got 3"), "{}", err);
+        assert_eq!(drain.records(Level::Critical).len(), 1);
+    }
+
+    #[test]
+    fn test_chunks_without_checksums() {
+        let chunks = vec![chunk("chunk0", b"hello ", false), chunk("chunk1", b"world", true)];
+        let (res, drain) = stream_file(11, chunks);
+        assert_eq!(res.unwrap().len(), 2);
+        assert!(drain.records(Level::Critical).is_empty());
+        let warnings = drain.records(Level::Warning);
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].contains("1 of 2 chunks of 00changelog.i have no checksum"),
+            "{}",
+            warnings[0]
+        );
+    }
+
+    #[test]
+    fn test_advertised_size_mismatch() {
+        let chunks = vec![chunk("chunk0", b"hello", true)];
+        let (res, drain) = stream_file(6, chunks);
+
+        let err = res.unwrap_err().to_string();
+        assert!(
+            err.contains("streamed 5 bytes of 00changelog.i but advertised 6"),
+            "{}",
+            err
+        );
+        assert_eq!(drain.records(Level::Critical).len(), 1);
+    }
+}
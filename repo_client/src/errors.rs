@@ -6,11 +6,43 @@
 
 pub use failure::{Error, Result, ResultExt};
 
-use mercurial_types::RepoPath;
+use std::time::Duration;
+
+use mercurial_types::{HgNodeHash, RepoPath};
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "internal error: file {} copied from directory {}", _0, _1)]
     InconsistentCopyInfo(RepoPath, RepoPath),
     #[fail(display = "internal error: streaming blob {} missing", _0)] MissingStreamingBlob(String),
+    #[fail(display = "internal error: streaming blob {} has an invalid checksum in the index", _0)]
+    InvalidStreamingChecksum(String),
+    #[fail(display = "streaming blob {} is corrupt: {}", _0, _1)]
+    CorruptStreamingBlob(String, String),
+    #[fail(display = "streamed {} bytes of {} but advertised {}", _2, _0, _1)]
+    StreamingSizeMismatch(String, usize, usize),
+    #[fail(display = "failed to connect to primary {}", _0)] PrimaryConnectFailed(String),
+    #[fail(display = "primary {} is unavailable, not forwarding writes", _0)]
+    PrimaryUnavailable(String),
+    #[fail(display = "request to primary {} timed out", _0)] PrimaryTimeout(String),
+    #[fail(display = "invalid response from primary: {}", _0)] PrimaryInvalidResponse(String),
+    #[fail(display = "primary failed the request: {}", _0)] PrimaryError(String),
+    #[fail(display = "failed to connect to shadow {}", _0)] ShadowConnectFailed(String),
+    #[fail(display = "mirrored request to shadow {} timed out", _0)] ShadowTimeout(String),
+    #[fail(display = "invalid response from shadow: {}", _0)] ShadowInvalidResponse(String),
+    #[fail(display = "{} arguments are not supported: {}", _0, _1)]
+    UnknownWireprotoArgs(String, String),
+    #[fail(display = "timed out after {:?} waiting for {}", _1, _0)]
+    BackendTimeout(String, Duration),
+    #[fail(display = "timed out after {:?} opening repo", _0)] OpenRepoTimeout(Duration),
+    #[fail(display = "gettreepack designatednodes can't be combined with rootdir, mfnodes, \
+                      basemfnodes or directories")]
+    DesignatedNodesMixed,
+    #[fail(display = "knowntrees asked about {} trees, at most {} are allowed", _0, _1)]
+    TooManyKnownTrees(usize, usize),
+    #[fail(display = "between {} and {}: walked {} changesets without reaching the bottom", _0,
+           _1, _2)]
+    BetweenWalkTooLong(HgNodeHash, HgNodeHash, usize),
+    #[fail(display = "{} is not allowed to move bookmarks in two phases", _0)]
+    BookmarkMoveNotAllowed(String),
 }
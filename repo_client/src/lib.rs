@@ -11,6 +11,8 @@
 
 //! State for a single source control Repo
 
+#[cfg(test)]
+extern crate async_unit;
 extern crate bytes;
 #[macro_use]
 extern crate cloned;
@@ -27,14 +29,19 @@ extern crate futures_stats;
 extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
+extern crate openssl;
 extern crate pylz4;
 extern crate rand;
 extern crate scribe_cxx;
+extern crate sql;
 #[macro_use]
 extern crate slog;
 #[macro_use]
 extern crate stats;
 extern crate time_ext;
+extern crate tokio;
+extern crate tokio_io;
+extern crate tokio_openssl;
 #[macro_use]
 extern crate tracing;
 extern crate uuid;
@@ -44,7 +51,12 @@ extern crate blobstore;
 extern crate bookmarks;
 extern crate bundle2_resolver;
 extern crate context;
+extern crate cross_repo_index;
+extern crate dbbookmarks;
+extern crate derived_data;
 extern crate filenodes;
+#[cfg(test)]
+extern crate fixtures;
 extern crate hgproto;
 extern crate hooks;
 extern crate mercurial;
@@ -52,13 +64,29 @@ extern crate mercurial_bundles;
 extern crate mercurial_types;
 extern crate metaconfig;
 extern crate mononoke_types;
+extern crate push_journal;
+extern crate repo_flags;
 extern crate revset;
 extern crate scuba_ext;
+extern crate secure_utils;
+extern crate sshrelay;
 
+mod backend_readiness;
 mod client;
 mod errors;
+mod mirroring;
 mod mononoke_repo;
+mod write_forwarding;
 
-pub use client::RepoClient;
-pub use client::streaming_clone::MysqlStreamingChunksFetcher;
-pub use mononoke_repo::{open_blobrepo, streaming_clone, MononokeRepo};
+pub use backend_readiness::{open_after_backends, wait_for_backends, BackendReadiness,
+                            MyrouterReadiness, OpenRepoParams};
+pub use bundle2_resolver::PushAdvisory;
+pub use client::{FilePrefetcher, PrefetchBudget, RepoClient};
+pub use client::streaming_clone::{MysqlStreamingChunksFetcher, StreamingChunksFetcher};
+pub use mirroring::{RequestMirror, ResponseDigest, ResponseDigester};
+pub use mononoke_repo::{open_blobrepo, open_blobrepo_async, open_bookmark_intent_store,
+                        open_bookmark_snapshot_index, open_cross_repo_index,
+                        open_derived_data_status, open_push_journal, open_repo_flag_overrides,
+                        streaming_clone, BookmarkMoves, MononokeRepo};
+pub use repo_flags::RuntimeRepoFlags;
+pub use write_forwarding::WriteForwarder;
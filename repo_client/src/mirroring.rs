@@ -0,0 +1,264 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Mirroring of read traffic to a shadow server, e.g. one that runs a new build.
+//!
+//! A sampled session that only ran read commands is replayed on the shadow after the client got
+//! its response: the stdin of the session is sent as is in a new session, and the stdout of the
+//! shadow is digested as it arrives. Comparing the digest with the digest of the response of
+//! this server finds the requests that the shadow answers differently. Nothing that the shadow
+//! does can reach the client.
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::chain::*;
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use openssl::ssl::{SslConnector, SslMethod};
+use rand::random;
+use tokio::net::TcpStream;
+use tokio::util::FutureExt as TokioFutureExt;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{FramedRead, FramedWrite};
+use tokio_openssl::SslConnectorExt;
+use uuid::Uuid;
+
+use metaconfig::MirroringParams;
+use mononoke_types::hash::{Blake2, Context};
+use secure_utils::{build_identity, read_x509};
+use sshrelay::{Preamble, SshDecoder, SshEncoder, SshMsg, SshStream};
+
+use errors::*;
+
+const DIGEST_KEY: &[u8] = b"mononoke-mirroring";
+
+/// Size and hash of everything a server wrote to the stdout of a session
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResponseDigest {
+    pub size: u64,
+    pub hash: Blake2,
+}
+
+/// Computes the `ResponseDigest` of a response from its chunks
+#[derive(Clone)]
+pub struct ResponseDigester {
+    size: u64,
+    context: Context,
+}
+
+impl Default for ResponseDigester {
+    fn default() -> Self {
+        ResponseDigester {
+            size: 0,
+            context: Context::new(DIGEST_KEY),
+        }
+    }
+}
+
+impl ResponseDigester {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.size += chunk.len() as u64;
+        self.context.update(chunk);
+    }
+
+    pub fn finish(self) -> ResponseDigest {
+        ResponseDigest {
+            size: self.size,
+            hash: self.context.finish(),
+        }
+    }
+}
+
+/// Client of the shadow server of a repo
+#[derive(Clone)]
+pub struct RequestMirror {
+    inner: Arc<RequestMirrorInner>,
+}
+
+struct RequestMirrorInner {
+    params: MirroringParams,
+    addr: SocketAddr,
+    connector: SslConnector,
+}
+
+impl RequestMirror {
+    pub fn new(params: MirroringParams) -> Result<Self> {
+        let addr = params
+            .shadow
+            .to_socket_addrs()
+            .chain_err(ErrorKind::ShadowConnectFailed(params.shadow.clone()))?
+            .next()
+            .ok_or_else(|| ErrorKind::ShadowConnectFailed(params.shadow.clone()))?;
+
+        let connector = {
+            let mut connector = SslConnector::builder(SslMethod::tls())?;
+            let pkcs12 = build_identity(params.cert.clone(), params.private_key.clone())?;
+            connector.set_certificate(&pkcs12.cert)?;
+            connector.set_private_key(&pkcs12.pkey)?;
+            connector
+                .cert_store_mut()
+                .add_cert(read_x509(&params.ca_pem)?)?;
+            connector.build()
+        };
+
+        Ok(RequestMirror {
+            inner: Arc::new(RequestMirrorInner {
+                params,
+                addr,
+                connector,
+            }),
+        })
+    }
+
+    pub fn params(&self) -> &MirroringParams {
+        &self.inner.params
+    }
+
+    /// Whether a session is mirrored
+    pub fn sample(&self) -> bool {
+        self.is_sampled(random())
+    }
+
+    /// Whether a session is mirrored, given a number drawn uniformly from [0, 1)
+    fn is_sampled(&self, draw: f64) -> bool {
+        draw < self.inner.params.sample_rate
+    }
+
+    /// Sends `input` to the shadow as the stdin of a new session and digests everything the
+    /// shadow writes to its stdout until it closes the session. `session` is reused, so that the
+    /// mirrored session can be matched with the original one in the logs of both servers.
+    pub fn replay(&self, session: Uuid, input: Bytes) -> BoxFuture<ResponseDigest, Error> {
+        let inner = self.inner.clone();
+        let shadow = inner.params.shadow.clone();
+        let preamble = Preamble::new(inner.params.shadow_reponame.clone(), session, None, None);
+
+        let connect = TcpStream::connect(&inner.addr)
+            .from_err::<Error>()
+            .and_then({
+                cloned!(inner);
+                move |socket| {
+                    inner
+                        .connector
+                        .connect_async(&inner.params.ssl_common_name, socket)
+                        .map_err(|err| format_err!("tls handshake failed: {}", err))
+                }
+            })
+            .chain_err(ErrorKind::ShadowConnectFailed(shadow.clone()))
+            .from_err::<Error>();
+
+        connect
+            .and_then(move |socket| {
+                let (socket_read, socket_write) = socket.split();
+                let rx = FramedRead::new(socket_read, SshDecoder::new());
+                let tx = FramedWrite::new(socket_write, SshEncoder::new());
+
+                let send = stream::iter_ok(vec![
+                    SshMsg::new(SshStream::Preamble(preamble), Bytes::new()),
+                    SshMsg::new(SshStream::Stdin, input),
+                ]).forward(tx)
+                    .map(|_| ());
+
+                // The stderr of the shadow is not part of the response
+                let recv = rx.from_err()
+                    .fold(ResponseDigester::new(), |mut digester, msg| {
+                        match msg.stream() {
+                            SshStream::Stdout => digester.update(msg.as_ref()),
+                            SshStream::Stderr => {}
+                            bad => {
+                                return Err(ErrorKind::ShadowInvalidResponse(format!(
+                                    "unexpected stream {:?}",
+                                    bad
+                                )).into())
+                            }
+                        }
+                        Ok(digester)
+                    });
+
+                send.join(recv).map(|((), digester)| digester.finish())
+            })
+            .timeout(inner.params.timeout)
+            .map_err(move |err| {
+                if err.is_inner() {
+                    err.into_inner().expect("checked by is_inner")
+                } else if err.is_elapsed() {
+                    ErrorKind::ShadowTimeout(shadow).into()
+                } else {
+                    err.into_timer().expect("neither inner nor elapsed").into()
+                }
+            })
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn params(sample_rate: f64) -> MirroringParams {
+        MirroringParams {
+            shadow: "127.0.0.1:1".to_string(),
+            shadow_reponame: "repo".to_string(),
+            ssl_common_name: "localhost".to_string(),
+            cert: "".to_string(),
+            private_key: "".to_string(),
+            ca_pem: "".to_string(),
+            sample_rate,
+            compare: true,
+            timeout: Duration::from_secs(1),
+            max_request_bytes: 1024,
+            max_compared_bytes: 1024,
+        }
+    }
+
+    fn is_sampled(sample_rate: f64, draw: f64) -> bool {
+        // Only the sampling is tested, so the mirror is built without a connector
+        let mirror = RequestMirror {
+            inner: Arc::new(RequestMirrorInner {
+                params: params(sample_rate),
+                addr: "127.0.0.1:1".parse().unwrap(),
+                connector: SslConnector::builder(SslMethod::tls()).unwrap().build(),
+            }),
+        };
+        mirror.is_sampled(draw)
+    }
+
+    #[test]
+    fn test_sampling() {
+        assert!(!is_sampled(0.0, 0.0));
+        assert!(!is_sampled(0.0, 0.5));
+        assert!(is_sampled(1.0, 0.0));
+        assert!(is_sampled(1.0, 0.999));
+        assert!(is_sampled(0.25, 0.2));
+        assert!(!is_sampled(0.25, 0.25));
+    }
+
+    #[test]
+    fn test_digest_of_chunks() {
+        let mut whole = ResponseDigester::new();
+        whole.update(b"hello world");
+
+        let mut chunked = ResponseDigester::new();
+        chunked.update(b"hello");
+        chunked.update(b"");
+        chunked.update(b" world");
+
+        let digest = chunked.finish();
+        assert_eq!(digest, whole.finish());
+        assert_eq!(digest.size, 11);
+
+        let mut other = ResponseDigester::new();
+        other.update(b"hello World");
+        assert_ne!(other.finish(), digest);
+    }
+}
@@ -4,27 +4,55 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::HashSet;
 use std::fmt::{self, Debug};
 use std::sync::Arc;
 use std::time::Duration;
 
 use failure::err_msg;
+use futures_ext::BoxFuture;
 use rand::Isaac64Rng;
 use rand::distributions::{Distribution, LogNormal};
 use slog::Logger;
+use tokio::runtime::Runtime;
 
 use scribe_cxx::ScribeCxxClient;
 
 use blobrepo::BlobRepo;
 use blobstore::{Blobstore, PrefixBlobstore};
+use bookmarks::{BookmarkIntentStore, BookmarkIntents, BookmarkSnapshotIndex};
+use bundle2_resolver::{PushAdvisory, ResumablePulls};
+use cross_repo_index::{CrossRepoIndex, MysqlCrossRepoIndex, SqliteCrossRepoIndex};
+use dbbookmarks::{MysqlBookmarkIntentStore, MysqlBookmarkSnapshotIndex, SqliteBookmarkIntentStore,
+                  SqliteBookmarkSnapshotIndex};
+use derived_data::{DerivationQueue, DerivedDataStatus, MysqlDerivedDataStatus,
+                   SqliteDerivedDataStatus};
 use hooks::HookManager;
 use mercurial_types::RepositoryId;
-use metaconfig::PushrebaseParams;
+use metaconfig::{BookmarkCreationPolicy, FetchLimits, HookDegradedPolicy, PathRules,
+                 PullBookmarksParams, PushLimits, PushrebaseParams, RepoFlags};
 use metaconfig::repoconfig::RepoType;
+use push_journal::{MysqlPushJournal, PushJournal, SqlitePushJournal};
+use repo_flags::{MysqlRepoFlagOverrides, RepoFlagOverrides, RuntimeRepoFlags,
+                 SqliteRepoFlagOverrides};
 
+use backend_readiness::{open_after_backends, BackendReadiness, MyrouterReadiness, OpenRepoParams};
 use errors::*;
+use mirroring::RequestMirror;
+use write_forwarding::WriteForwarder;
 
-use client::streaming_clone::MysqlStreamingChunksFetcher;
+use client::{FilePrefetcher, KnownTrees};
+use client::streaming_clone::{MysqlStreamingChunksFetcher, StreamingChunksFetcher};
+
+// How long progress of an interrupted resumable pull is kept, and how many changesets are sent
+// between two points the pull can be resumed from.
+const RESUMABLE_PULL_TTL_SECS: u64 = 600;
+const RESUMABLE_PULL_GROUP_SIZE: usize = 1000;
+
+// How long answers to knowntrees can be out of date, and how many trees a client can ask about
+// at once.
+const KNOWNTREES_TTL_SECS: u64 = 10;
+const MAX_KNOWNTREES_NODES: usize = 1000;
 
 struct LogNormalGenerator {
     rng: Isaac64Rng,
@@ -34,16 +62,57 @@ struct LogNormalGenerator {
 #[derive(Clone)]
 pub struct MysqlStreamingCloneConfig {
     pub blobstore: PrefixBlobstore<Arc<Blobstore>>,
-    pub fetcher: MysqlStreamingChunksFetcher,
+    pub fetcher: Arc<StreamingChunksFetcher>,
     pub repoid: RepositoryId,
 }
 
+/// Two-phase bookmark moves of a repo, and the users that can make them
+#[derive(Clone)]
+pub struct BookmarkMoves {
+    intents: BookmarkIntents,
+    allowed_identities: Arc<HashSet<String>>,
+}
+
+impl BookmarkMoves {
+    pub fn new(intents: BookmarkIntents, allowed_identities: Vec<String>) -> Self {
+        BookmarkMoves {
+            intents,
+            allowed_identities: Arc::new(allowed_identities.into_iter().collect()),
+        }
+    }
+
+    pub fn intents(&self) -> &BookmarkIntents {
+        &self.intents
+    }
+
+    /// Whether a client that identifies as `user` can move bookmarks of the repo in two phases
+    pub fn allows(&self, user: &str) -> bool {
+        self.allowed_identities.contains(user)
+    }
+}
+
 #[derive(Clone)]
 pub struct MononokeRepo {
     blobrepo: BlobRepo,
     pushrebase_params: PushrebaseParams,
+    push_limits: PushLimits,
+    path_rules: PathRules,
+    pull_bookmarks: PullBookmarksParams,
+    bookmark_creation: BookmarkCreationPolicy,
+    fetch_limits: FetchLimits,
     hook_manager: Arc<HookManager>,
     streaming_clone: Option<MysqlStreamingCloneConfig>,
+    write_forwarder: Option<WriteForwarder>,
+    request_mirror: Option<RequestMirror>,
+    flags: RuntimeRepoFlags,
+    push_journal: Option<Arc<PushJournal>>,
+    push_advisory: Option<PushAdvisory>,
+    cross_repo_index: Option<Arc<CrossRepoIndex>>,
+    derivation_queue: Option<DerivationQueue>,
+    file_prefetcher: Option<FilePrefetcher>,
+    bookmark_moves: Option<BookmarkMoves>,
+    resumable_pulls: ResumablePulls,
+    known_trees: KnownTrees,
 }
 
 impl MononokeRepo {
@@ -51,14 +120,50 @@ impl MononokeRepo {
     pub fn new(
         blobrepo: BlobRepo,
         pushrebase_params: &PushrebaseParams,
+        push_limits: PushLimits,
+        path_rules: PathRules,
+        pull_bookmarks: PullBookmarksParams,
+        bookmark_creation: BookmarkCreationPolicy,
+        fetch_limits: FetchLimits,
         hook_manager: Arc<HookManager>,
         streaming_clone: Option<MysqlStreamingCloneConfig>,
+        write_forwarder: Option<WriteForwarder>,
+        request_mirror: Option<RequestMirror>,
+        flags: RuntimeRepoFlags,
+        push_journal: Option<Arc<PushJournal>>,
+        push_advisory: Option<PushAdvisory>,
+        cross_repo_index: Option<Arc<CrossRepoIndex>>,
+        derivation_queue: Option<DerivationQueue>,
+        file_prefetcher: Option<FilePrefetcher>,
+        bookmark_moves: Option<BookmarkMoves>,
     ) -> Self {
         MononokeRepo {
             blobrepo,
             pushrebase_params: pushrebase_params.clone(),
+            push_limits,
+            path_rules,
+            pull_bookmarks,
+            bookmark_creation,
+            fetch_limits,
             hook_manager,
             streaming_clone,
+            write_forwarder,
+            request_mirror,
+            flags,
+            push_journal,
+            push_advisory,
+            cross_repo_index,
+            derivation_queue,
+            file_prefetcher,
+            bookmark_moves,
+            resumable_pulls: ResumablePulls::new(
+                Duration::from_secs(RESUMABLE_PULL_TTL_SECS),
+                RESUMABLE_PULL_GROUP_SIZE,
+            ),
+            known_trees: KnownTrees::new(
+                Duration::from_secs(KNOWNTREES_TTL_SECS),
+                MAX_KNOWNTREES_NODES,
+            ),
         }
     }
 
@@ -71,6 +176,29 @@ impl MononokeRepo {
         &self.pushrebase_params
     }
 
+    pub fn push_limits(&self) -> PushLimits {
+        self.push_limits
+    }
+
+    pub fn path_rules(&self) -> &PathRules {
+        &self.path_rules
+    }
+
+    /// Which bookmarks are sent in the listkeys part of getbundle responses
+    pub fn pull_bookmarks(&self) -> &PullBookmarksParams {
+        &self.pull_bookmarks
+    }
+
+    /// Which bookmarks pushes can create, and who can create them
+    pub fn bookmark_creation(&self) -> &BookmarkCreationPolicy {
+        &self.bookmark_creation
+    }
+
+    /// How many fetches the commands that fetch data have in flight at the same time
+    pub fn fetch_limits(&self) -> FetchLimits {
+        self.fetch_limits
+    }
+
     pub fn hook_manager(&self) -> Arc<HookManager> {
         self.hook_manager.clone()
     }
@@ -78,13 +206,119 @@ impl MononokeRepo {
     pub fn streaming_clone(&self) -> &Option<MysqlStreamingCloneConfig> {
         &self.streaming_clone
     }
+
+    /// Set on secondary servers, which forward writes to the primary server of the repo
+    pub fn write_forwarder(&self) -> Option<&WriteForwarder> {
+        self.write_forwarder.as_ref()
+    }
+
+    /// Set if a sample of the read traffic of the repo is mirrored to a shadow server
+    pub fn request_mirror(&self) -> Option<&RequestMirror> {
+        self.request_mirror.as_ref()
+    }
+
+    /// The flags of the repo as configured, with their runtime overrides applied. Flags are
+    /// read with every request, so that a change of an override applies to the next one.
+    pub fn flags(&self) -> RepoFlags {
+        self.flags.get()
+    }
+
+    /// Whether commands that change the repo are rejected, because the repo is flagged so, or
+    /// because its hooks are degraded and its policy is then to be read-only
+    pub fn readonly(&self) -> bool {
+        self.flags().readonly
+            || self.hook_manager.degraded_policy() == Some(HookDegradedPolicy::ReadOnly)
+    }
+
+    /// Set if pushes to the repo are journaled
+    pub fn push_journal(&self) -> Option<&Arc<PushJournal>> {
+        self.push_journal.as_ref()
+    }
+
+    /// Set if successful pushes get a message about the commits they landed
+    pub fn push_advisory(&self) -> Option<&PushAdvisory> {
+        self.push_advisory.as_ref()
+    }
+
+    /// Set if the changesets of the repo are recorded in the cross-repo index
+    pub fn cross_repo_index(&self) -> Option<&Arc<CrossRepoIndex>> {
+        self.cross_repo_index.as_ref()
+    }
+
+    /// Set if data derived from the changesets that land in the repo is derived in the background
+    pub fn derivation_queue(&self) -> Option<&DerivationQueue> {
+        self.derivation_queue.as_ref()
+    }
+
+    /// Set if the files of the trees that gettreepack serves are prefetched in the background
+    pub fn file_prefetcher(&self) -> Option<&FilePrefetcher> {
+        self.file_prefetcher.as_ref()
+    }
+
+    /// Set if some users can move bookmarks of the repo in two phases
+    pub fn bookmark_moves(&self) -> Option<&BookmarkMoves> {
+        self.bookmark_moves.as_ref()
+    }
+
+    pub fn resumable_pulls(&self) -> &ResumablePulls {
+        &self.resumable_pulls
+    }
+
+    pub fn known_trees(&self) -> &KnownTrees {
+        &self.known_trees
+    }
 }
 
+/// Blocking version of `open_blobrepo_async` for tools that don't run in a tokio runtime. The
+/// progress of waiting for the backends of the repo is logged to `logger`.
 pub fn open_blobrepo(
     logger: Logger,
     repotype: RepoType,
     repoid: RepositoryId,
     myrouter_port: Option<u16>,
+    params: OpenRepoParams,
+) -> Result<BlobRepo> {
+    let mut runtime = Runtime::new()?;
+    runtime.block_on(open_blobrepo_async(
+        logger,
+        repotype,
+        repoid,
+        myrouter_port,
+        params,
+    ))
+}
+
+/// Opens a repo once all the backends it needs are up, without blocking the calling thread
+/// while it waits for them. Fails if the repo isn't open within `params.timeout`.
+pub fn open_blobrepo_async(
+    logger: Logger,
+    repotype: RepoType,
+    repoid: RepositoryId,
+    myrouter_port: Option<u16>,
+    params: OpenRepoParams,
+) -> BoxFuture<BlobRepo, Error> {
+    let backends: Vec<Box<BackendReadiness>> = match (&repotype, myrouter_port) {
+        // Stores in the same database share a myrouter tier, which only needs to be checked once
+        (RepoType::BlobManifold(args), Some(myrouter_port)) => args.distinct_db_addresses()
+            .into_iter()
+            .map(|db_address| -> Box<BackendReadiness> {
+                Box::new(MyrouterReadiness::new(myrouter_port, db_address))
+            })
+            .collect(),
+        // A missing myrouter port is reported by create_blobrepo
+        _ => vec![],
+    };
+
+    open_after_backends(backends, params, logger.clone(), move || {
+        create_blobrepo(logger.clone(), repotype.clone(), repoid, myrouter_port)
+    })
+}
+
+fn create_blobrepo(
+    logger: Logger,
+    repotype: RepoType,
+    repoid: RepositoryId,
+    myrouter_port: Option<u16>,
 ) -> Result<BlobRepo> {
     use hgproto::ErrorKind;
     use metaconfig::repoconfig::RepoType::*;
@@ -147,12 +381,126 @@ pub fn open_blobrepo(
     Ok(blobrepo)
 }
 
+/// Opens the push journal of a repo. It is kept next to the bookmarks of the repo: in a SQLite
+/// database in the directory of local repos, and in the database of the other repos.
+pub fn open_push_journal(repotype: &RepoType) -> Result<Arc<PushJournal>> {
+    use hgproto::ErrorKind;
+    use metaconfig::repoconfig::RepoType::*;
+
+    let push_journal: Arc<PushJournal> = match *repotype {
+        Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
+        BlobFiles(ref path) | BlobRocks(ref path) | TestBlobDelayRocks(ref path, ..) => Arc::new(
+            SqlitePushJournal::open_or_create(path.join("push_journal").to_string_lossy())?,
+        ),
+        BlobManifold(ref args) => Arc::new(MysqlPushJournal::open(&args.db_address)?),
+    };
+
+    Ok(push_journal)
+}
+
+/// Opens the store of the two-phase bookmark moves of a repo. It is kept next to the bookmarks of
+/// the repo, so that every server of the repo shares it.
+pub fn open_bookmark_intent_store(repotype: &RepoType) -> Result<Arc<BookmarkIntentStore>> {
+    use hgproto::ErrorKind;
+    use metaconfig::repoconfig::RepoType::*;
+
+    let store: Arc<BookmarkIntentStore> = match *repotype {
+        Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
+        BlobFiles(ref path) | BlobRocks(ref path) | TestBlobDelayRocks(ref path, ..) => Arc::new(
+            SqliteBookmarkIntentStore::open_or_create(
+                path.join("bookmark_intents").to_string_lossy(),
+            )?,
+        ),
+        BlobManifold(ref args) => {
+            Arc::new(MysqlBookmarkIntentStore::open(args.bookmarks_db_address())?)
+        }
+    };
+
+    Ok(store)
+}
+
+/// Opens the index of the bookmark snapshots of a repo. Like the bookmark move intents, it is kept
+/// next to the bookmarks of the repo.
+pub fn open_bookmark_snapshot_index(repotype: &RepoType) -> Result<Arc<BookmarkSnapshotIndex>> {
+    use hgproto::ErrorKind;
+    use metaconfig::repoconfig::RepoType::*;
+
+    let index: Arc<BookmarkSnapshotIndex> = match *repotype {
+        Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
+        BlobFiles(ref path) | BlobRocks(ref path) | TestBlobDelayRocks(ref path, ..) => Arc::new(
+            SqliteBookmarkSnapshotIndex::open_or_create(
+                path.join("bookmark_snapshots").to_string_lossy(),
+            )?,
+        ),
+        BlobManifold(ref args) => {
+            Arc::new(MysqlBookmarkSnapshotIndex::open(args.bookmarks_db_address())?)
+        }
+    };
+
+    Ok(index)
+}
+
+/// Opens the cross-repo index that a repo records its changesets in. The index is shared by
+/// repos: local repos in the same directory share a SQLite database in that directory, and the
+/// other repos use their database.
+pub fn open_cross_repo_index(repotype: &RepoType) -> Result<Arc<CrossRepoIndex>> {
+    use hgproto::ErrorKind;
+    use metaconfig::repoconfig::RepoType::*;
+
+    let index: Arc<CrossRepoIndex> = match *repotype {
+        Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
+        BlobFiles(ref path) | BlobRocks(ref path) | TestBlobDelayRocks(ref path, ..) => {
+            let dir = path.parent().unwrap_or(path);
+            Arc::new(SqliteCrossRepoIndex::open_or_create(
+                dir.join("cross_repo_index").to_string_lossy(),
+            )?)
+        }
+        BlobManifold(ref args) => Arc::new(MysqlCrossRepoIndex::open(&args.db_address)?),
+    };
+
+    Ok(index)
+}
+
+/// Opens the status of the derived data of a repo. Like the push journal, it is kept next to the
+/// bookmarks of the repo.
+pub fn open_derived_data_status(repotype: &RepoType) -> Result<Arc<DerivedDataStatus>> {
+    use hgproto::ErrorKind;
+    use metaconfig::repoconfig::RepoType::*;
+
+    let status: Arc<DerivedDataStatus> = match *repotype {
+        Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
+        BlobFiles(ref path) | BlobRocks(ref path) | TestBlobDelayRocks(ref path, ..) => Arc::new(
+            SqliteDerivedDataStatus::open_or_create(path.join("derived_data").to_string_lossy())?,
+        ),
+        BlobManifold(ref args) => Arc::new(MysqlDerivedDataStatus::open(&args.db_address)?),
+    };
+
+    Ok(status)
+}
+
+/// Opens the runtime overrides of the flags of a repo. Like the push journal, they are kept next
+/// to the bookmarks of the repo.
+pub fn open_repo_flag_overrides(repotype: &RepoType) -> Result<Arc<RepoFlagOverrides>> {
+    use hgproto::ErrorKind;
+    use metaconfig::repoconfig::RepoType::*;
+
+    let overrides: Arc<RepoFlagOverrides> = match *repotype {
+        Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
+        BlobFiles(ref path) | BlobRocks(ref path) | TestBlobDelayRocks(ref path, ..) => Arc::new(
+            SqliteRepoFlagOverrides::open_or_create(path.join("repo_flags").to_string_lossy())?,
+        ),
+        BlobManifold(ref args) => Arc::new(MysqlRepoFlagOverrides::open(&args.db_address)?),
+    };
+
+    Ok(overrides)
+}
+
 pub fn streaming_clone(
     blobrepo: BlobRepo,
     db_address: &str,
     repoid: RepositoryId,
 ) -> Result<MysqlStreamingCloneConfig> {
-    let fetcher = MysqlStreamingChunksFetcher::open(db_address)?;
+    let fetcher = Arc::new(MysqlStreamingChunksFetcher::open(db_address)?);
     let streaming_clone = MysqlStreamingCloneConfig {
         fetcher,
         blobstore: blobrepo.get_blobstore(),
@@ -0,0 +1,442 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Forwarding of writes from a secondary server to the primary server of a repo.
+//!
+//! A secondary server serves reads itself, but it doesn't apply pushes: the bundle of an
+//! `unbundle` request is streamed unparsed to the primary over a new connection, and the reply of
+//! the primary is relayed to the client as it is, chunk by chunk as it comes. The forwarded
+//! request carries the identity of the client, so that the primary applies its hooks and
+//! permissions to the client rather than to the secondary. Bookmark moves (pushkeys) are parts of
+//! that bundle, so they are forwarded along with it.
+//!
+//! Bookmarks and heads are read from storage by every request, so a secondary has no local caches
+//! that would have to be invalidated after a forwarded push.
+
+use std::cmp;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use failure::chain::*;
+use futures::{stream, Async, Future, Poll, Stream};
+use futures_ext::{BoxStream, StreamExt};
+use openssl::ssl::{SslConnector, SslMethod};
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{FramedRead, FramedWrite};
+use tokio_openssl::SslConnectorExt;
+use uuid::Uuid;
+
+use context::CoreContext;
+use metaconfig::WriteForwardingParams;
+use secure_utils::{build_identity, read_x509};
+use sshrelay::{Preamble, SshDecoder, SshEncoder, SshMsg, SshStream};
+
+use errors::*;
+
+/// Client of the primary server of a repo. Clones share the breaker.
+#[derive(Clone)]
+pub struct WriteForwarder {
+    inner: Arc<WriteForwarderInner>,
+}
+
+struct WriteForwarderInner {
+    params: WriteForwardingParams,
+    addr: SocketAddr,
+    connector: SslConnector,
+    breaker: Breaker,
+}
+
+impl WriteForwarder {
+    pub fn new(params: WriteForwardingParams) -> Result<Self> {
+        let addr = params
+            .primary
+            .to_socket_addrs()
+            .chain_err(ErrorKind::PrimaryConnectFailed(params.primary.clone()))?
+            .next()
+            .ok_or_else(|| ErrorKind::PrimaryConnectFailed(params.primary.clone()))?;
+
+        let connector = {
+            let mut connector = SslConnector::builder(SslMethod::tls())?;
+            let pkcs12 = build_identity(params.cert.clone(), params.private_key.clone())?;
+            connector.set_certificate(&pkcs12.cert)?;
+            connector.set_private_key(&pkcs12.pkey)?;
+            connector
+                .cert_store_mut()
+                .add_cert(read_x509(&params.ca_pem)?)?;
+            connector.build()
+        };
+
+        let breaker = Breaker::new(params.breaker_failures, params.breaker_cooldown);
+
+        Ok(WriteForwarder {
+            inner: Arc::new(WriteForwarderInner {
+                params,
+                addr,
+                connector,
+                breaker,
+            }),
+        })
+    }
+
+    /// Address of the primary server
+    pub fn primary(&self) -> &str {
+        &self.inner.params.primary
+    }
+
+    /// Sends an `unbundle` request with the given (dechunked) bundle to the primary, and returns
+    /// the bundle2 reply of the primary chunk by chunk, as it comes. The session and the identity
+    /// of the client in `ctxt` are reused, so that the forwarded request is attributed to the
+    /// client and can be matched with the request of the client in the logs of both servers.
+    pub fn unbundle(
+        &self,
+        ctxt: &CoreContext<Uuid>,
+        heads: Vec<String>,
+        bundle: BoxStream<Bytes, Error>,
+    ) -> BoxStream<Bytes, Error> {
+        let command = encode_command("unbundle", &[("heads", Bytes::from(heads.join(" ")))]);
+        let input = stream::once(Ok(command))
+            .chain(bundle.filter(|chunk| !chunk.is_empty()).map(encode_chunk))
+            .chain(stream::once(Ok(Bytes::from(&b"0\n"[..]))));
+
+        UnbundleReply::new(self.request(ctxt, input.boxify())).boxify()
+    }
+
+    /// Sends `input` to the primary as the stdin of a new session and returns what the primary
+    /// writes, until it closes the session. Only failures to talk to the primary are recorded by
+    /// the breaker, errors reported by the primary itself are not.
+    fn request(
+        &self,
+        ctxt: &CoreContext<Uuid>,
+        input: BoxStream<Bytes, Error>,
+    ) -> BoxStream<SshMsg, Error> {
+        let inner = self.inner.clone();
+        let primary = inner.params.primary.clone();
+
+        if !inner.breaker.allows(Instant::now()) {
+            return stream::once(Err(ErrorKind::PrimaryUnavailable(primary).into())).boxify();
+        }
+
+        let preamble = Preamble::new(
+            inner.params.primary_reponame.clone(),
+            *ctxt.session(),
+            ctxt.user().map(|user| user.to_string()),
+            ctxt.source_hostname().map(|host| host.to_string()),
+        );
+        let deadline = Delay::new(Instant::now() + inner.params.timeout);
+
+        let connect = TcpStream::connect(&inner.addr)
+            .from_err::<Error>()
+            .and_then({
+                cloned!(inner);
+                move |socket| {
+                    inner
+                        .connector
+                        .connect_async(&inner.params.ssl_common_name, socket)
+                        .map_err(|err| format_err!("tls handshake failed: {}", err))
+                }
+            })
+            .chain_err(ErrorKind::PrimaryConnectFailed(primary.clone()))
+            .from_err::<Error>();
+
+        let messages = connect
+            .map(move |socket| {
+                let (socket_read, socket_write) = socket.split();
+                let rx = FramedRead::new(socket_read, SshDecoder::new());
+                let tx = FramedWrite::new(socket_write, SshEncoder::new());
+
+                // Forwarding closes the socket for writes once the input is sent, which ends the
+                // session on the primary after it has replied
+                let send = stream::once(Ok(SshMsg::new(
+                    SshStream::Preamble(preamble),
+                    Bytes::new(),
+                ))).chain(input.map(|buf| SshMsg::new(SshStream::Stdin, buf)))
+                    .forward(tx)
+                    .map(|_| None::<SshMsg>);
+
+                // The input is sent while the reply is read, and contributes no messages
+                rx.from_err::<Error>()
+                    .map(Some)
+                    .select(send.into_stream())
+                    .filter_map(|msg| msg)
+            })
+            .flatten_stream();
+
+        WithDeadline::new(messages, deadline, primary)
+            .then(move |res| {
+                inner.breaker.record(res.is_ok(), Instant::now());
+                res
+            })
+            .boxify()
+    }
+}
+
+/// Fails a stream that talks to the primary with `PrimaryTimeout` if it hasn't ended by the time
+/// `deadline` fires
+struct WithDeadline<S> {
+    stream: S,
+    deadline: Delay,
+    primary: String,
+}
+
+impl<S> WithDeadline<S> {
+    fn new(stream: S, deadline: Delay, primary: String) -> Self {
+        WithDeadline {
+            stream,
+            deadline,
+            primary,
+        }
+    }
+}
+
+impl<S: Stream<Error = Error>> Stream for WithDeadline<S> {
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        if let Async::Ready(item) = self.stream.poll()? {
+            return Ok(Async::Ready(item));
+        }
+        match self.deadline.poll()? {
+            Async::Ready(()) => Err(ErrorKind::PrimaryTimeout(self.primary.clone()).into()),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// The bundle2 reply of the primary to an `unbundle` request, out of the messages the primary
+/// writes. The primary asks for the bundle with "0\n" and then replies with an unframed bundle2,
+/// which is passed on as it comes. If the push fails, the reply is missing and the reason is in
+/// the stderr of the primary.
+struct UnbundleReply<S> {
+    messages: S,
+    /// What came of stdout until the "0\n" the reply starts with is complete
+    prefix: BytesMut,
+    stderr: BytesMut,
+    replied: bool,
+    rejected: bool,
+}
+
+/// The stderr of the primary is only kept for the error message of a failed push
+const MAX_PRIMARY_STDERR_BYTES: usize = 64 * 1024;
+
+impl<S> UnbundleReply<S> {
+    fn new(messages: S) -> Self {
+        UnbundleReply {
+            messages,
+            prefix: BytesMut::new(),
+            stderr: BytesMut::new(),
+            replied: false,
+            rejected: false,
+        }
+    }
+
+    /// Takes the part of `stdout` that comes after the "0\n" the reply starts with
+    fn strip_prefix(&mut self, mut stdout: Bytes) -> Bytes {
+        if self.rejected {
+            return Bytes::new();
+        }
+        if self.prefix.len() < 2 {
+            let len = cmp::min(2 - self.prefix.len(), stdout.len());
+            self.prefix.extend_from_slice(&stdout.split_to(len));
+            if self.prefix.len() == 2 && self.prefix.as_ref() != b"0\n" {
+                self.rejected = true;
+                return Bytes::new();
+            }
+        }
+        stdout
+    }
+
+    fn primary_error(&self) -> Error {
+        let stderr = String::from_utf8_lossy(&self.stderr).trim().to_string();
+        if stderr.is_empty() {
+            ErrorKind::PrimaryError("no reply".into()).into()
+        } else {
+            ErrorKind::PrimaryError(stderr).into()
+        }
+    }
+}
+
+impl<S: Stream<Item = SshMsg, Error = Error>> Stream for UnbundleReply<S> {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        loop {
+            let msg = match try_ready!(self.messages.poll()) {
+                Some(msg) => msg,
+                None if self.replied && !self.rejected => return Ok(Async::Ready(None)),
+                None => return Err(self.primary_error()),
+            };
+            match msg.stream() {
+                SshStream::Stdout => {
+                    let reply = self.strip_prefix(msg.data());
+                    if !reply.is_empty() {
+                        self.replied = true;
+                        return Ok(Async::Ready(Some(reply)));
+                    }
+                }
+                SshStream::Stderr => {
+                    let room = MAX_PRIMARY_STDERR_BYTES.saturating_sub(self.stderr.len());
+                    let data = msg.as_ref();
+                    self.stderr
+                        .extend_from_slice(&data[..cmp::min(room, data.len())]);
+                }
+                bad => {
+                    return Err(ErrorKind::PrimaryInvalidResponse(format!(
+                        "unexpected stream {:?}",
+                        bad
+                    )).into())
+                }
+            }
+        }
+    }
+}
+
+/// Stops requests to the primary for `cooldown` once `max_failures` of them failed in a row, so
+/// that pushes fail fast instead of waiting for a timeout when the primary is down. After the
+/// cooldown requests are let through again, and the first failing one restarts the cooldown.
+struct Breaker {
+    max_failures: usize,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: usize,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn new(max_failures: usize, cooldown: Duration) -> Self {
+        Breaker {
+            max_failures,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    fn allows(&self, now: Instant) -> bool {
+        let state = self.state.lock().expect("lock poisoned");
+        match state.open_until {
+            Some(open_until) => now >= open_until,
+            None => true,
+        }
+    }
+
+    fn record(&self, success: bool, now: Instant) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        if success {
+            *state = BreakerState::default();
+        } else {
+            state.failures += 1;
+            if state.failures >= self.max_failures {
+                state.open_until = Some(now + self.cooldown);
+            }
+        }
+    }
+}
+
+// Request format:
+// command\n
+// (argname len\nvalue)*
+fn encode_command(command: &str, args: &[(&str, Bytes)]) -> Bytes {
+    let mut buf = BytesMut::from(format!("{}\n", command));
+    for &(name, ref value) in args {
+        buf.extend_from_slice(format!("{} {}\n", name, value.len()).as_bytes());
+        buf.extend_from_slice(value);
+    }
+    buf.freeze()
+}
+
+fn encode_chunk(chunk: Bytes) -> Bytes {
+    let mut buf = BytesMut::from(format!("{}\n", chunk.len()));
+    buf.extend_from_slice(&chunk);
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_request() {
+        let command = encode_command("unbundle", &[("heads", Bytes::from("666f726365"))]);
+        assert_eq!(command.as_ref(), &b"unbundle\nheads 10\n666f726365"[..]);
+
+        let chunk = encode_chunk(Bytes::from("HG20"));
+        assert_eq!(chunk.as_ref(), &b"4\nHG20"[..]);
+    }
+
+    fn msg(stream: SshStream, data: &[u8]) -> SshMsg {
+        SshMsg::new(stream, Bytes::from(data))
+    }
+
+    fn unbundle_reply(messages: Vec<SshMsg>) -> Result<Vec<Bytes>> {
+        UnbundleReply::new(stream::iter_ok(messages)).collect().wait()
+    }
+
+    #[test]
+    fn test_unbundle_reply() {
+        // The reply is passed on chunk by chunk, wherever the "0\n" before it is split
+        let reply = unbundle_reply(vec![
+            msg(SshStream::Stdout, b"0"),
+            msg(SshStream::Stdout, b"\nHG20"),
+            msg(SshStream::Stderr, b"remote: pushing 1 commit\n"),
+            msg(SshStream::Stdout, b"\0\0\0\0\0\0\0\0"),
+        ]).expect("reply expected");
+        assert_eq!(
+            reply,
+            vec![Bytes::from("HG20"), Bytes::from(&b"\0\0\0\0\0\0\0\0"[..])]
+        );
+
+        let err = unbundle_reply(vec![
+            msg(SshStream::Stdout, b"0\n"),
+            msg(SshStream::Stderr, b"remote: Command failed\n"),
+        ]).expect_err("error expected");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::PrimaryError(ref msg)) => assert_eq!(msg, "remote: Command failed"),
+            bad => panic!("unexpected result {:?}", bad),
+        }
+
+        assert!(unbundle_reply(vec![]).is_err());
+        assert!(unbundle_reply(vec![msg(SshStream::Stdout, b"1\nHG20")]).is_err());
+    }
+
+    #[test]
+    fn test_breaker_opens_after_failures() {
+        let breaker = Breaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+
+        assert!(breaker.allows(now));
+        breaker.record(false, now);
+        assert!(breaker.allows(now));
+        breaker.record(false, now);
+        assert!(!breaker.allows(now));
+        assert!(!breaker.allows(now + Duration::from_secs(29)));
+        assert!(breaker.allows(now + Duration::from_secs(30)));
+
+        // The first failure after the cooldown opens the breaker again
+        let later = now + Duration::from_secs(30);
+        breaker.record(false, later);
+        assert!(!breaker.allows(later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_breaker_success_resets_failures() {
+        let breaker = Breaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+
+        breaker.record(false, now);
+        breaker.record(true, now);
+        breaker.record(false, now);
+        assert!(breaker.allows(now));
+    }
+}
@@ -0,0 +1,20 @@
+CREATE TABLE repo_flag_overrides (
+  repo_id INTEGER NOT NULL,
+  flag VARCHAR(64) NOT NULL,
+  value BOOLEAN NOT NULL,
+  updated_by VARCHAR(255) NOT NULL,
+  updated_at BIGINT NOT NULL,
+  PRIMARY KEY (repo_id, flag)
+);
+
+CREATE TABLE repo_flag_audit (
+  -- Sqlite doesn't support autoincrement UNSIGNED BIGINT
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  repo_id INTEGER NOT NULL,
+  flag VARCHAR(64) NOT NULL,
+  -- NULL if the flag had no override before the change, or has none after it
+  old_value BOOLEAN,
+  new_value BOOLEAN,
+  author VARCHAR(255) NOT NULL,
+  changed_at BIGINT NOT NULL
+);
@@ -0,0 +1,329 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Runtime overrides of the flags of repos.
+//!
+//! The flags of a repo, `metaconfig::RepoFlags`, are set in its config. Changing the config
+//! takes a push to the config repo and a restart, which is too slow to turn a feature off when
+//! it misbehaves. A flag that has an override in `RepoFlagOverrides` takes the value of the
+//! override instead. Servers reload the overrides in the background, see `RuntimeRepoFlags`, so
+//! a change takes effect within seconds.
+//!
+//! Every change of an override is recorded in an audit trail: who changed it, when, and its
+//! value before and after the change.
+
+#![deny(warnings)]
+// FIXME T34253207, remove when https://github.com/diesel-rs/diesel/issues/1785 fixed
+#![allow(proc_macro_derive_resolution_fallback)]
+#![feature(never_type)]
+
+#[macro_use]
+extern crate cloned;
+extern crate db_conn;
+#[macro_use]
+extern crate diesel;
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate stats;
+extern crate tokio;
+
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate metaconfig;
+
+use std::result;
+use std::sync::MutexGuard;
+
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{delete, insert_into, replace_into, Connection, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use failure::{Error, Result};
+
+use futures_ext::{asynchronize, BoxFuture, FutureExt};
+use mercurial_types::RepositoryId;
+use metaconfig::RepoFlag;
+use stats::Timeseries;
+
+mod models;
+mod runtime;
+mod schema;
+
+pub use runtime::RuntimeRepoFlags;
+
+use models::{FlagAuditInsertRow, FlagAuditRow, FlagOverrideRow};
+use schema::{repo_flag_audit, repo_flag_overrides};
+
+define_stats! {
+    prefix = "mononoke.repo_flags";
+    gets: timeseries(RATE, SUM),
+    sets: timeseries(RATE, SUM),
+    audits: timeseries(RATE, SUM),
+}
+
+/// The value that a flag of a repo is overridden with
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlagOverride {
+    pub repo_id: RepositoryId,
+    pub flag: RepoFlag,
+    pub value: bool,
+    /// Who set the override
+    pub updated_by: String,
+    /// Unix timestamp, in seconds, of when the override was set
+    pub updated_at: i64,
+}
+
+impl FlagOverride {
+    /// None for the overrides of flags that this version doesn't know of, e.g. a flag that was
+    /// removed
+    fn from_row(row: FlagOverrideRow) -> Option<Self> {
+        let flag = row.flag.parse().ok()?;
+        Some(FlagOverride {
+            repo_id: row.repo_id,
+            flag,
+            value: row.value,
+            updated_by: row.updated_by,
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+/// A change of the override of a flag
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlagAuditRecord {
+    pub repo_id: RepositoryId,
+    /// Name of the flag. It is kept as a string, so that the changes of flags that were removed
+    /// since are still listed.
+    pub flag: String,
+    /// Value of the override before the change, None if the flag had none
+    pub old_value: Option<bool>,
+    /// Value of the override after the change, None if the override was removed
+    pub new_value: Option<bool>,
+    pub author: String,
+    /// Unix timestamp, in seconds
+    pub changed_at: i64,
+}
+
+impl FlagAuditRecord {
+    fn into_row(self) -> FlagAuditInsertRow {
+        FlagAuditInsertRow {
+            repo_id: self.repo_id,
+            flag: self.flag,
+            old_value: self.old_value,
+            new_value: self.new_value,
+            author: self.author,
+            changed_at: self.changed_at,
+        }
+    }
+
+    fn from_row(row: FlagAuditRow) -> Self {
+        FlagAuditRecord {
+            repo_id: row.repo_id,
+            flag: row.flag,
+            old_value: row.old_value,
+            new_value: row.new_value,
+            author: row.author,
+            changed_at: row.changed_at,
+        }
+    }
+}
+
+pub trait RepoFlagOverrides: Send + Sync {
+    /// Overrides of the flags of the repo, sorted by flag name
+    fn get(&self, repo_id: RepositoryId) -> BoxFuture<Vec<FlagOverride>, Error>;
+
+    /// Overrides `flag` with `value`, or removes its override if `value` is None, and records
+    /// the change in the audit trail. Returns the record of the change.
+    fn set(
+        &self,
+        repo_id: RepositoryId,
+        flag: RepoFlag,
+        value: Option<bool>,
+        author: String,
+        changed_at: i64,
+    ) -> BoxFuture<FlagAuditRecord, Error>;
+
+    /// The last `limit` changes of the overrides of the repo, newest first
+    fn audit(
+        &self,
+        repo_id: RepositoryId,
+        limit: usize,
+    ) -> BoxFuture<Vec<FlagAuditRecord>, Error>;
+}
+
+#[derive(Clone)]
+pub struct SqliteRepoFlagOverrides {
+    inner: SqliteConnInner,
+}
+
+impl SqliteRepoFlagOverrides {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-repo-flags.sql")
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlRepoFlagOverrides {
+    inner: MysqlConnInner,
+}
+
+impl MysqlRepoFlagOverrides {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-repo-flags.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+/// See https://github.com/diesel-rs/diesel/issues/882#issuecomment-300257476
+macro_rules! impl_repo_flag_overrides {
+    ($struct:ty) => {
+        impl RepoFlagOverrides for $struct {
+            fn get(&self, repo_id: RepositoryId) -> BoxFuture<Vec<FlagOverride>, Error> {
+                STATS::gets.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    let rows = repo_flag_overrides::table
+                        .filter(repo_flag_overrides::repo_id.eq(repo_id))
+                        .order(repo_flag_overrides::flag.asc())
+                        .load::<FlagOverrideRow>(&*connection)?;
+                    Ok(rows.into_iter().filter_map(FlagOverride::from_row).collect())
+                }).boxify()
+            }
+
+            fn set(
+                &self,
+                repo_id: RepositoryId,
+                flag: RepoFlag,
+                value: Option<bool>,
+                author: String,
+                changed_at: i64,
+            ) -> BoxFuture<FlagAuditRecord, Error> {
+                STATS::sets.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    connection.transaction::<_, Error, _>(|| {
+                        let old_value = repo_flag_overrides::table
+                            .filter(repo_flag_overrides::repo_id.eq(repo_id))
+                            .filter(repo_flag_overrides::flag.eq(flag.as_str()))
+                            .select(repo_flag_overrides::value)
+                            .first::<bool>(&*connection)
+                            .optional()?;
+                        match value {
+                            Some(value) => {
+                                let row = FlagOverrideRow {
+                                    repo_id,
+                                    flag: flag.as_str().to_string(),
+                                    value,
+                                    updated_by: author.clone(),
+                                    updated_at: changed_at,
+                                };
+                                replace_into(repo_flag_overrides::table)
+                                    .values(&row)
+                                    .execute(&*connection)?;
+                            }
+                            None => {
+                                delete(
+                                    repo_flag_overrides::table
+                                        .filter(repo_flag_overrides::repo_id.eq(repo_id))
+                                        .filter(repo_flag_overrides::flag.eq(flag.as_str())),
+                                ).execute(&*connection)?;
+                            }
+                        }
+
+                        let record = FlagAuditRecord {
+                            repo_id,
+                            flag: flag.as_str().to_string(),
+                            old_value,
+                            new_value: value,
+                            author,
+                            changed_at,
+                        };
+                        insert_into(repo_flag_audit::table)
+                            .values(&record.clone().into_row())
+                            .execute(&*connection)?;
+                        Ok(record)
+                    })
+                }).boxify()
+            }
+
+            fn audit(
+                &self,
+                repo_id: RepositoryId,
+                limit: usize,
+            ) -> BoxFuture<Vec<FlagAuditRecord>, Error> {
+                STATS::audits.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    #[allow(unreachable_code, unreachable_patterns)] // sqlite can't fail
+                    let connection = db.get_master_conn()?;
+                    let rows = repo_flag_audit::table
+                        .filter(repo_flag_audit::repo_id.eq(repo_id))
+                        .order(repo_flag_audit::id.desc())
+                        .limit(limit as i64)
+                        .load::<FlagAuditRow>(&*connection)?;
+                    Ok(rows.into_iter().map(FlagAuditRecord::from_row).collect())
+                }).boxify()
+            }
+        }
+    };
+}
+
+impl_repo_flag_overrides!(SqliteRepoFlagOverrides);
+impl_repo_flag_overrides!(MysqlRepoFlagOverrides);
@@ -0,0 +1,44 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use mercurial_types::RepositoryId;
+
+use schema::{repo_flag_audit, repo_flag_overrides};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "repo_flag_overrides"]
+pub(crate) struct FlagOverrideRow {
+    pub repo_id: RepositoryId,
+    pub flag: String,
+    pub value: bool,
+    pub updated_by: String,
+    pub updated_at: i64,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable)]
+pub(crate) struct FlagAuditRow {
+    pub id: i64,
+    pub repo_id: RepositoryId,
+    pub flag: String,
+    pub old_value: Option<bool>,
+    pub new_value: Option<bool>,
+    pub author: String,
+    pub changed_at: i64,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Insertable)]
+#[table_name = "repo_flag_audit"]
+pub(crate) struct FlagAuditInsertRow {
+    pub repo_id: RepositoryId,
+    pub flag: String,
+    pub old_value: Option<bool>,
+    pub new_value: Option<bool>,
+    pub author: String,
+    pub changed_at: i64,
+}
@@ -0,0 +1,221 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The flags of a repo as a server sees them, with their overrides reloaded in the background
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial_types::RepositoryId;
+use metaconfig::{RepoFlag, RepoFlags};
+use slog::{Discard, Logger};
+use stats::Timeseries;
+use tokio::timer::Interval;
+
+use RepoFlagOverrides;
+
+define_stats! {
+    prefix = "mononoke.repo_flags.runtime";
+    refreshes: timeseries(RATE, SUM),
+    refresh_failures: timeseries(RATE, SUM),
+}
+
+struct State {
+    flags: RepoFlags,
+    /// Flags whose value comes from an override rather than from the config
+    overridden: HashSet<RepoFlag>,
+    /// The flags when they were last read, so that a change of a flag is logged once
+    last_read: Option<RepoFlags>,
+}
+
+/// The effective flags of a repo: its configured flags, with its overrides applied on top. The
+/// overrides are reloaded every `ttl` by the worker that `open` returns, so a change of an
+/// override takes effect at most `ttl` later.
+#[derive(Clone)]
+pub struct RuntimeRepoFlags {
+    repo_id: RepositoryId,
+    config: RepoFlags,
+    overrides: Option<Arc<RepoFlagOverrides>>,
+    state: Arc<Mutex<State>>,
+    logger: Logger,
+}
+
+impl RuntimeRepoFlags {
+    /// Flags that are never overridden, for tools and tests
+    pub fn fixed(flags: RepoFlags) -> Self {
+        Self::with_overrides(
+            RepositoryId::new(0),
+            flags,
+            None,
+            Logger::root(Discard, o!()),
+        )
+    }
+
+    /// Loads the overrides of the repo, and returns the flags with the worker that reloads them
+    /// every `ttl`. The worker stops once every clone of the flags is dropped.
+    pub fn open(
+        repo_id: RepositoryId,
+        config: RepoFlags,
+        overrides: Arc<RepoFlagOverrides>,
+        ttl: Duration,
+        logger: Logger,
+    ) -> BoxFuture<(Self, BoxFuture<(), ()>), Error> {
+        let flags = Self::with_overrides(repo_id, config, Some(overrides), logger);
+        flags
+            .refresh()
+            .map(move |_| {
+                let worker = flags.worker(ttl);
+                (flags, worker)
+            })
+            .boxify()
+    }
+
+    fn with_overrides(
+        repo_id: RepositoryId,
+        config: RepoFlags,
+        overrides: Option<Arc<RepoFlagOverrides>>,
+        logger: Logger,
+    ) -> Self {
+        let state = State {
+            flags: config,
+            overridden: HashSet::new(),
+            last_read: None,
+        };
+        RuntimeRepoFlags {
+            repo_id,
+            config,
+            overrides,
+            state: Arc::new(Mutex::new(state)),
+            logger,
+        }
+    }
+
+    /// The flags of the repo. A flag is logged on its first read, and on the first read after
+    /// its value changed.
+    pub fn get(&self) -> RepoFlags {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let flags = state.flags;
+        if state.last_read != Some(flags) {
+            for flag in RepoFlag::all() {
+                let value = flags.get(*flag);
+                if state.last_read.map(|last| last.get(*flag)) != Some(value) {
+                    let source = if state.overridden.contains(flag) {
+                        "override"
+                    } else {
+                        "config"
+                    };
+                    info!(
+                        self.logger,
+                        "flag {} of repo {} is {} (from {})",
+                        flag,
+                        self.repo_id.id(),
+                        value,
+                        source
+                    );
+                }
+            }
+            state.last_read = Some(flags);
+        }
+        flags
+    }
+
+    /// Every flag with its value, and where the value comes from, e.g. for the startup summary
+    /// of the repo
+    pub fn describe(&self) -> String {
+        let state = self.state.lock().expect("lock poisoned");
+        let flags: Vec<_> = RepoFlag::all()
+            .iter()
+            .map(|flag| {
+                let overridden = if state.overridden.contains(flag) {
+                    " (overridden)"
+                } else {
+                    ""
+                };
+                format!("{}={}{}", flag, state.flags.get(*flag), overridden)
+            })
+            .collect();
+        flags.join(" ")
+    }
+
+    /// Reloads the overrides now, and returns the flags with them applied
+    pub fn refresh(&self) -> BoxFuture<RepoFlags, Error> {
+        let overrides = match self.overrides {
+            Some(ref overrides) => overrides.clone(),
+            None => return future::ok(self.config).boxify(),
+        };
+        refresh(
+            self.repo_id,
+            self.config,
+            overrides,
+            Arc::downgrade(&self.state),
+        )
+    }
+
+    fn worker(&self, ttl: Duration) -> BoxFuture<(), ()> {
+        let overrides = match self.overrides {
+            Some(ref overrides) => overrides.clone(),
+            None => return future::ok(()).boxify(),
+        };
+        let repo_id = self.repo_id;
+        let config = self.config;
+        // Only a weak reference, so that the worker doesn't keep the flags alive
+        let state = Arc::downgrade(&self.state);
+        let logger = self.logger.clone();
+
+        Interval::new(Instant::now() + ttl, ttl)
+            .map_err({
+                cloned!(logger);
+                move |err| error!(logger, "repo flags timer failed: {}", err)
+            })
+            .take_while({
+                cloned!(state);
+                move |_| Ok(state.upgrade().is_some())
+            })
+            .for_each(move |_| {
+                cloned!(logger);
+                refresh(repo_id, config, overrides.clone(), state.clone()).then(move |res| {
+                    if let Err(err) = res {
+                        STATS::refresh_failures.add_value(1);
+                        warn!(logger, "failed to reload the repo flag overrides: {}", err);
+                    }
+                    Ok(())
+                })
+            })
+            .boxify()
+    }
+}
+
+/// Applies the overrides of the repo to its configured flags, and stores the result in `state`
+/// if the flags are still alive
+fn refresh(
+    repo_id: RepositoryId,
+    config: RepoFlags,
+    overrides: Arc<RepoFlagOverrides>,
+    state: Weak<Mutex<State>>,
+) -> BoxFuture<RepoFlags, Error> {
+    STATS::refreshes.add_value(1);
+    overrides
+        .get(repo_id)
+        .map(move |overrides| {
+            let mut flags = config;
+            let mut overridden = HashSet::new();
+            for flag_override in overrides {
+                flags.set(flag_override.flag, flag_override.value);
+                overridden.insert(flag_override.flag);
+            }
+            if let Some(state) = state.upgrade() {
+                let mut state = state.lock().expect("lock poisoned");
+                state.flags = flags;
+                state.overridden = overridden;
+            }
+            flags
+        })
+        .boxify()
+}
@@ -0,0 +1,35 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macros in this module describe the schemas for these tables in SQL storage
+//! (MySQL or SQLite). These descriptions are *not* the source of truth, so if the schema ever
+//! changes it will need to be updated here as well.
+
+table! {
+    use diesel::sql_types::{BigInt, Bool, Integer, Text};
+
+    repo_flag_overrides (repo_id, flag) {
+        repo_id -> Integer,
+        flag -> Text,
+        value -> Bool,
+        updated_by -> Text,
+        updated_at -> BigInt,
+    }
+}
+
+table! {
+    use diesel::sql_types::{BigInt, Bool, Integer, Nullable, Text};
+
+    repo_flag_audit {
+        id -> BigInt,
+        repo_id -> Integer,
+        flag -> Text,
+        old_value -> Nullable<Bool>,
+        new_value -> Nullable<Bool>,
+        author -> Text,
+        changed_at -> BigInt,
+    }
+}
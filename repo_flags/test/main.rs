@@ -0,0 +1,225 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the repo flag overrides and the runtime flags.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate tokio;
+
+extern crate futures_ext;
+extern crate mercurial_types_mocks;
+extern crate metaconfig;
+extern crate repo_flags;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures_ext::BoxFuture;
+use slog::{Discard, Logger};
+
+use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
+use metaconfig::{RepoFlag, RepoFlags};
+use repo_flags::{FlagAuditRecord, MysqlRepoFlagOverrides, RepoFlagOverrides,
+                 RuntimeRepoFlags, SqliteRepoFlagOverrides};
+
+fn set<O: RepoFlagOverrides>(
+    overrides: &O,
+    flag: RepoFlag,
+    value: Option<bool>,
+    author: &str,
+    changed_at: i64,
+) -> FlagAuditRecord {
+    overrides
+        .set(REPO_ZERO, flag, value, author.to_string(), changed_at)
+        .wait()
+        .expect("Setting override failed")
+}
+
+fn set_and_get<O: RepoFlagOverrides>(overrides: O) {
+    set(&overrides, RepoFlag::Readonly, Some(true), "alice", 100);
+    set(&overrides, RepoFlag::DeterministicGetbundle, Some(false), "bob", 200);
+    overrides
+        .set(REPO_ONE, RepoFlag::Readonly, Some(false), "bob".to_string(), 300)
+        .wait()
+        .expect("Setting override failed");
+
+    let got: Vec<_> = overrides
+        .get(REPO_ZERO)
+        .wait()
+        .expect("Get failed")
+        .into_iter()
+        .map(|o| (o.flag, o.value, o.updated_by, o.updated_at))
+        .collect();
+    assert_eq!(
+        got,
+        vec![
+            (RepoFlag::DeterministicGetbundle, false, "bob".to_string(), 200),
+            (RepoFlag::Readonly, true, "alice".to_string(), 100),
+        ]
+    );
+
+    // Removing an override
+    set(&overrides, RepoFlag::Readonly, None, "alice", 400);
+    let got = overrides.get(REPO_ZERO).wait().expect("Get failed");
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0].flag, RepoFlag::DeterministicGetbundle);
+}
+
+fn audit_records<O: RepoFlagOverrides>(overrides: O) {
+    let first = set(&overrides, RepoFlag::Readonly, Some(true), "alice", 100);
+    assert_eq!(
+        first,
+        FlagAuditRecord {
+            repo_id: REPO_ZERO,
+            flag: "readonly".to_string(),
+            old_value: None,
+            new_value: Some(true),
+            author: "alice".to_string(),
+            changed_at: 100,
+        }
+    );
+    set(&overrides, RepoFlag::Readonly, Some(false), "bob", 200);
+    set(&overrides, RepoFlag::Readonly, None, "carol", 300);
+
+    let audit = overrides.audit(REPO_ZERO, 10).wait().expect("Audit failed");
+    let changes: Vec<_> = audit
+        .iter()
+        .map(|r| (r.author.as_str(), r.old_value, r.new_value, r.changed_at))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            ("carol", Some(false), None, 300),
+            ("bob", Some(true), Some(false), 200),
+            ("alice", None, Some(true), 100),
+        ]
+    );
+
+    let audit = overrides.audit(REPO_ZERO, 1).wait().expect("Audit failed");
+    assert_eq!(audit.len(), 1);
+    assert_eq!(audit[0].author, "carol");
+    assert!(overrides.audit(REPO_ONE, 10).wait().unwrap().is_empty());
+}
+
+macro_rules! repo_flags_test_impl {
+    ($mod_name:ident =>  { new: $new_cb:expr, }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_set_and_get() {
+                async_unit::tokio_unit_test(|| {
+                    set_and_get($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_audit_records() {
+                async_unit::tokio_unit_test(|| {
+                    audit_records($new_cb());
+                });
+            }
+        }
+    };
+}
+
+repo_flags_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+repo_flags_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+fn new_sqlite() -> SqliteRepoFlagOverrides {
+    SqliteRepoFlagOverrides::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_mysql() -> MysqlRepoFlagOverrides {
+    MysqlRepoFlagOverrides::create_test_db("repo_flags_test")
+        .expect("Failed to create test database")
+}
+
+fn config() -> RepoFlags {
+    RepoFlags {
+        readonly: false,
+        strict_wireproto_args: true,
+        deterministic_getbundle: false,
+    }
+}
+
+fn open(
+    overrides: &Arc<SqliteRepoFlagOverrides>,
+    ttl: Duration,
+) -> (RuntimeRepoFlags, BoxFuture<(), ()>) {
+    let overrides = overrides.clone() as Arc<RepoFlagOverrides>;
+    let logger = Logger::root(Discard, o!());
+    RuntimeRepoFlags::open(REPO_ZERO, config(), overrides, ttl, logger)
+        .wait()
+        .expect("Opening flags failed")
+}
+
+#[test]
+fn test_override_beats_config() {
+    async_unit::tokio_unit_test(|| {
+        let overrides = Arc::new(new_sqlite());
+        set(&*overrides, RepoFlag::StrictWireprotoArgs, Some(false), "alice", 100);
+        set(&*overrides, RepoFlag::Readonly, Some(true), "alice", 100);
+
+        let (flags, _worker) = open(&overrides, Duration::from_secs(3600));
+        let expected = RepoFlags {
+            readonly: true,
+            strict_wireproto_args: false,
+            deterministic_getbundle: false,
+        };
+        assert_eq!(flags.get(), expected);
+        assert_eq!(
+            flags.describe(),
+            "readonly=true (overridden) strict_wireproto_args=false (overridden) \
+             deterministic_getbundle=false"
+        );
+
+        // Once the override is removed, the configured value is back
+        set(&*overrides, RepoFlag::StrictWireprotoArgs, None, "alice", 200);
+        // Not before the overrides are reloaded
+        assert_eq!(flags.get(), expected);
+        flags.refresh().wait().expect("Refresh failed");
+        assert_eq!(flags.get().strict_wireproto_args, true);
+        assert_eq!(flags.get().readonly, true);
+
+        assert_eq!(RuntimeRepoFlags::fixed(config()).get(), config());
+    })
+}
+
+#[test]
+fn test_overrides_reloaded_after_ttl() {
+    async_unit::tokio_unit_test(|| {
+        let overrides = Arc::new(new_sqlite());
+        let (flags, worker) = open(&overrides, Duration::from_millis(50));
+        tokio::spawn(worker);
+        assert_eq!(flags.get(), config());
+
+        set(&*overrides, RepoFlag::Readonly, Some(true), "alice", 100);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !flags.get().readonly {
+            assert!(Instant::now() < deadline, "the override was never reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The worker stops once the flags are dropped
+        drop(flags);
+    })
+}
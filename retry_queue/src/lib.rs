@@ -0,0 +1,717 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Durable retries of writes to sinks like scribe categories.
+//!
+//! Most sinks are fire-and-forget: a record that can't be written because of a transient failure
+//! is dropped. That is fine for best-effort notifications, but not for records that must not be
+//! lost, like audit logs. Sinks that opt in write through a `RetryQueue` instead, which appends a
+//! failed record to a journal on local disk and keeps retrying it in the background, with
+//! backoff, until it is delivered or too old.
+//!
+//! Every record has an id, which is its idempotency key:
+//! - the queue holds at most one record per id, and remembers in the journal which records were
+//!   delivered, so that a queue opened again over the same directory after a restart doesn't
+//!   send them again
+//! - a write can succeed even though the sink reported a failure, e.g. if the response timed
+//!   out, so sinks must make writes with the same id idempotent, e.g. by sending the id along
+//!   with the record so that readers can deduplicate
+//!
+//! The journal is bounded: pending records may use at most half of `max_spill_bytes`, and records
+//! that don't fit are dropped. When the journal grows past `max_spill_bytes`, it is rotated: the
+//! pending records are written to a new journal, which replaces the old one.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate cloned;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[macro_use]
+extern crate slog;
+#[macro_use]
+extern crate stats;
+extern crate tokio;
+
+#[cfg(test)]
+extern crate tempdir;
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::{Error, Result};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt, RetryPolicy};
+use slog::Logger;
+use stats::DynamicTimeseries;
+use tokio::timer::Interval;
+
+define_stats! {
+    prefix = "mononoke.retry_queue";
+    depth: dynamic_timeseries("{}.depth", (queue: &'static str); AVG, MAX),
+    spilled: dynamic_timeseries("{}.spilled", (queue: &'static str); RATE, SUM),
+    retry_success: dynamic_timeseries("{}.retry.success", (queue: &'static str); RATE, SUM),
+    retry_failure: dynamic_timeseries("{}.retry.failure", (queue: &'static str); RATE, SUM),
+    dropped_too_old: dynamic_timeseries("{}.dropped.too_old", (queue: &'static str); RATE, SUM),
+    dropped_full: dynamic_timeseries("{}.dropped.full", (queue: &'static str); RATE, SUM),
+}
+
+/// A record for a sink, e.g. a line of a scribe category
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SinkRecord {
+    /// Idempotency key of the record, see the module documentation
+    pub id: String,
+    pub payload: String,
+}
+
+impl SinkRecord {
+    pub fn new<I: Into<String>, P: Into<String>>(id: I, payload: P) -> Self {
+        SinkRecord {
+            id: id.into(),
+            payload: payload.into(),
+        }
+    }
+}
+
+pub trait RecordSink: Send + Sync + 'static {
+    /// Writes `record`. Writing a record with an id that was already written must not write it
+    /// twice, because a write may be retried after it actually succeeded.
+    fn write(&self, record: &SinkRecord) -> BoxFuture<(), Error>;
+}
+
+/// How the writes of a sink are delivered
+#[derive(Clone)]
+pub enum SinkWriter {
+    /// A failed write is dropped
+    BestEffort(Arc<RecordSink>),
+    /// A failed write is retried by a `RetryQueue`
+    Durable(RetryQueue),
+}
+
+impl SinkWriter {
+    pub fn write(&self, record: SinkRecord) -> BoxFuture<(), Error> {
+        match self {
+            SinkWriter::BestEffort(sink) => sink.write(&record),
+            SinkWriter::Durable(queue) => queue.write(record),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryQueueConfig {
+    /// Upper bound of the size of the journal, see the module documentation
+    pub max_spill_bytes: u64,
+    /// Delay before the first retry of a record, doubled for every following retry
+    pub base_delay: Duration,
+    /// Upper bound of the delay between retries of a record
+    pub max_delay: Duration,
+    /// Records that are not delivered this long after they first failed are dropped
+    pub max_age: Duration,
+    /// How often `retry_task` looks for records that are due to be retried
+    pub poll_interval: Duration,
+}
+
+impl Default for RetryQueueConfig {
+    fn default() -> Self {
+        RetryQueueConfig {
+            max_spill_bytes: 64 * 1024 * 1024,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            max_age: Duration::from_secs(24 * 60 * 60),
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A line of the journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalEntry {
+    /// A record failed at `failed_at` (seconds since the epoch) and is pending
+    Add {
+        id: String,
+        payload: String,
+        failed_at: u64,
+    },
+    /// The record was delivered or dropped
+    Done { id: String },
+}
+
+impl JournalEntry {
+    fn add(record: &SinkRecord, failed_at: u64) -> Self {
+        JournalEntry::Add {
+            id: record.id.clone(),
+            payload: record.payload.clone(),
+            failed_at,
+        }
+    }
+
+    fn to_line(&self) -> Result<Vec<u8>> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+struct Pending {
+    record: SinkRecord,
+    failed_at: u64,
+    /// Size of the `Add` line of the record in the journal
+    line_len: u64,
+    retries: usize,
+    next_retry: Instant,
+    in_flight: bool,
+}
+
+struct State {
+    journal: File,
+    journal_bytes: u64,
+    live_bytes: u64,
+    pending: VecDeque<Pending>,
+    ids: HashSet<String>,
+}
+
+struct Inner {
+    name: &'static str,
+    path: PathBuf,
+    sink: Arc<RecordSink>,
+    config: RetryQueueConfig,
+    backoff: RetryPolicy,
+    logger: Logger,
+    state: Mutex<State>,
+}
+
+/// Retries the failed writes of a sink, see the module documentation
+#[derive(Clone)]
+pub struct RetryQueue {
+    inner: Arc<Inner>,
+}
+
+impl RetryQueue {
+    /// Opens the queue called `name`, whose journal is stored in `dir`. Records that were
+    /// pending when the queue was last open are retried again.
+    pub fn open<P: AsRef<Path>>(
+        name: &'static str,
+        dir: P,
+        sink: Arc<RecordSink>,
+        config: RetryQueueConfig,
+        logger: Logger,
+    ) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(format!("{}.journal", name));
+
+        let mut pending: VecDeque<Pending> = VecDeque::new();
+        let mut ids = HashSet::new();
+        if path.exists() {
+            for (line_num, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                // A line may be cut short if the process died while writing it
+                let entry = match serde_json::from_str::<JournalEntry>(&line?) {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        warn!(
+                            logger,
+                            "skipping invalid line {} of {}: {}",
+                            line_num + 1,
+                            path.display(),
+                            err
+                        );
+                        continue;
+                    }
+                };
+                match entry {
+                    JournalEntry::Add {
+                        id,
+                        payload,
+                        failed_at,
+                    } => {
+                        if ids.insert(id.clone()) {
+                            let record = SinkRecord { id, payload };
+                            pending.push_back(Pending::new(record, failed_at)?);
+                        }
+                    }
+                    JournalEntry::Done { id } => {
+                        if ids.remove(&id) {
+                            pending.retain(|pending| pending.record.id != id);
+                        }
+                    }
+                }
+            }
+        }
+
+        let live_bytes: u64 = pending.iter().map(|pending| pending.line_len).sum();
+        let journal = write_journal(&path, &pending)?;
+        if !pending.is_empty() {
+            info!(
+                logger,
+                "{} records of {} are pending from before",
+                pending.len(),
+                path.display()
+            );
+        }
+        STATS::depth.add_value(pending.len() as i64, (name,));
+
+        Ok(RetryQueue {
+            inner: Arc::new(Inner {
+                name,
+                path,
+                sink,
+                backoff: RetryPolicy {
+                    max_attempts: usize::max_value(),
+                    base_delay: config.base_delay,
+                    max_delay: config.max_delay,
+                    jitter: true,
+                },
+                config,
+                logger,
+                state: Mutex::new(State {
+                    journal,
+                    journal_bytes: live_bytes,
+                    live_bytes,
+                    pending,
+                    ids,
+                }),
+            }),
+        })
+    }
+
+    /// Writes `record` to the sink, and if that fails, adds it to the queue to be retried. The
+    /// returned future fails only if the record could not be added to the queue either.
+    pub fn write(&self, record: SinkRecord) -> BoxFuture<(), Error> {
+        if self.is_pending(&record.id) {
+            // It will be delivered by a retry
+            return future::ok(()).boxify();
+        }
+
+        let this = self.clone();
+        let write = self.inner.sink.write(&record);
+        write
+            .or_else(move |err| {
+                warn!(
+                    this.inner.logger,
+                    "write of {} to {} failed, will retry: {}", record.id, this.inner.name, err
+                );
+                this.spill(record)
+            })
+            .boxify()
+    }
+
+    /// Number of records waiting to be retried
+    pub fn depth(&self) -> usize {
+        self.inner.state.lock().expect("lock poisoned").pending.len()
+    }
+
+    pub fn is_pending(&self, id: &str) -> bool {
+        self.inner.state.lock().expect("lock poisoned").ids.contains(id)
+    }
+
+    /// Retries every record whose backoff has passed, one at a time. Records that are older than
+    /// `max_age` are dropped instead. Errors are only logged.
+    pub fn retry_due(&self) -> impl Future<Item = (), Error = ()> + Send + 'static {
+        let due = self.take_due();
+        let this = self.clone();
+        stream::iter_ok(due).for_each(move |record| {
+            let this = this.clone();
+            let write = this.inner.sink.write(&record);
+            write.then(move |res| {
+                this.retried(&record, res);
+                Ok(())
+            })
+        })
+    }
+
+    /// Calls `retry_due` every `poll_interval`, forever
+    pub fn retry_task(&self) -> impl Future<Item = (), Error = ()> + Send + 'static {
+        let this = self.clone();
+        let interval = self.inner.config.poll_interval;
+        Interval::new(Instant::now() + interval, interval)
+            .map_err({
+                cloned!(this);
+                move |err| error!(this.inner.logger, "retry queue timer failed: {}", err)
+            })
+            .for_each(move |_| this.retry_due())
+    }
+
+    fn spill(&self, record: SinkRecord) -> Result<()> {
+        let inner = &self.inner;
+        let mut state = inner.state.lock().expect("lock poisoned");
+        if state.ids.contains(&record.id) {
+            return Ok(());
+        }
+
+        let pending = Pending::new(record, unix_now())?;
+        if state.live_bytes + pending.line_len > inner.config.max_spill_bytes / 2 {
+            STATS::dropped_full.add_value(1, (inner.name,));
+            error!(
+                inner.logger,
+                "{} is full, dropping record {}", inner.name, pending.record.id
+            );
+            bail_msg!("{} is full", inner.name);
+        }
+
+        let entry = JournalEntry::add(&pending.record, pending.failed_at);
+        inner.append(&mut state, &entry)?;
+        STATS::spilled.add_value(1, (inner.name,));
+        state.live_bytes += pending.line_len;
+        state.ids.insert(pending.record.id.clone());
+        state.pending.push_back(pending);
+        STATS::depth.add_value(state.pending.len() as i64, (inner.name,));
+        inner.maybe_rotate(&mut state);
+        Ok(())
+    }
+
+    fn take_due(&self) -> Vec<SinkRecord> {
+        let inner = &self.inner;
+        let mut state = inner.state.lock().expect("lock poisoned");
+        let now = Instant::now();
+        let oldest_allowed = unix_now().saturating_sub(inner.config.max_age.as_secs());
+
+        let too_old: Vec<_> = state
+            .pending
+            .iter()
+            .filter(|pending| !pending.in_flight && pending.failed_at < oldest_allowed)
+            .map(|pending| pending.record.id.clone())
+            .collect();
+        for id in too_old {
+            STATS::dropped_too_old.add_value(1, (inner.name,));
+            error!(
+                inner.logger,
+                "dropping record {} of {}, it is older than {:?}",
+                id,
+                inner.name,
+                inner.config.max_age
+            );
+            inner.remove(&mut state, &id);
+        }
+
+        state
+            .pending
+            .iter_mut()
+            .filter(|pending| !pending.in_flight && pending.next_retry <= now)
+            .map(|pending| {
+                pending.in_flight = true;
+                pending.record.clone()
+            })
+            .collect()
+    }
+
+    fn retried(&self, record: &SinkRecord, res: Result<()>) {
+        let inner = &self.inner;
+        let mut state = inner.state.lock().expect("lock poisoned");
+        match res {
+            Ok(()) => {
+                STATS::retry_success.add_value(1, (inner.name,));
+                inner.remove(&mut state, &record.id);
+            }
+            Err(err) => {
+                STATS::retry_failure.add_value(1, (inner.name,));
+                debug!(
+                    inner.logger,
+                    "retry of {} to {} failed: {}", record.id, inner.name, err
+                );
+                let backoff = inner.backoff;
+                if let Some(pending) = state
+                    .pending
+                    .iter_mut()
+                    .find(|pending| pending.record.id == record.id)
+                {
+                    pending.retries += 1;
+                    pending.next_retry = Instant::now() + backoff.delay(pending.retries);
+                    pending.in_flight = false;
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn append(&self, state: &mut State, entry: &JournalEntry) -> Result<()> {
+        let line = entry.to_line()?;
+        state.journal.write_all(&line)?;
+        state.journal.sync_data()?;
+        state.journal_bytes += line.len() as u64;
+        Ok(())
+    }
+
+    /// Marks the record `id` as done, so that it's not retried even after a restart
+    fn remove(&self, state: &mut State, id: &str) {
+        if !state.ids.remove(id) {
+            return;
+        }
+        if let Some(pos) = state.pending.iter().position(|pending| pending.record.id == id) {
+            if let Some(pending) = state.pending.remove(pos) {
+                state.live_bytes -= pending.line_len;
+            }
+        }
+        STATS::depth.add_value(state.pending.len() as i64, (self.name,));
+
+        let done = JournalEntry::Done { id: id.to_string() };
+        if let Err(err) = self.append(state, &done) {
+            // The record is retried once more if the queue is opened again
+            warn!(self.logger, "failed to mark {} as done: {}", id, err);
+        }
+        self.maybe_rotate(state);
+    }
+
+    fn maybe_rotate(&self, state: &mut State) {
+        if state.journal_bytes <= self.config.max_spill_bytes {
+            return;
+        }
+        match write_journal(&self.path, &state.pending) {
+            Ok(journal) => {
+                state.journal = journal;
+                state.journal_bytes = state.live_bytes;
+            }
+            Err(err) => warn!(
+                self.logger,
+                "failed to rotate {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+}
+
+impl Pending {
+    fn new(record: SinkRecord, failed_at: u64) -> Result<Self> {
+        let line_len = JournalEntry::add(&record, failed_at).to_line()?.len() as u64;
+        Ok(Pending {
+            line_len,
+            record,
+            failed_at,
+            retries: 0,
+            next_retry: Instant::now(),
+            in_flight: false,
+        })
+    }
+}
+
+/// Writes a journal with only the `pending` records, which atomically replaces the one at `path`,
+/// and returns it opened for appending
+fn write_journal(path: &Path, pending: &VecDeque<Pending>) -> Result<File> {
+    let tmp_path = path.with_extension("journal.tmp");
+    {
+        let mut tmp = File::create(&tmp_path)?;
+        for pending in pending {
+            let entry = JournalEntry::add(&pending.record, pending.failed_at);
+            tmp.write_all(&entry.to_line()?)?;
+        }
+        tmp.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use failure::err_msg;
+    use slog::{Discard, Drain};
+    use tempdir::TempDir;
+
+    /// Sink that fails while `failing` is set, and counts how often each record was delivered
+    #[derive(Default)]
+    struct FlakySink {
+        failing: AtomicBool,
+        delivered: Mutex<HashMap<String, usize>>,
+    }
+
+    impl FlakySink {
+        fn failing() -> Arc<Self> {
+            let sink = Self::default();
+            sink.failing.store(true, Ordering::SeqCst);
+            Arc::new(sink)
+        }
+
+        fn deliveries(&self, id: &str) -> usize {
+            *self.delivered.lock().unwrap().get(id).unwrap_or(&0)
+        }
+    }
+
+    impl RecordSink for FlakySink {
+        fn write(&self, record: &SinkRecord) -> BoxFuture<(), Error> {
+            if self.failing.load(Ordering::SeqCst) {
+                return future::err(err_msg("sink is down")).boxify();
+            }
+            *self.delivered
+                .lock()
+                .unwrap()
+                .entry(record.id.clone())
+                .or_insert(0) += 1;
+            future::ok(()).boxify()
+        }
+    }
+
+    fn logger() -> Logger {
+        Logger::root(Discard {}.ignore_res(), o!())
+    }
+
+    fn config() -> RetryQueueConfig {
+        RetryQueueConfig {
+            base_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            ..Default::default()
+        }
+    }
+
+    fn open(dir: &TempDir, sink: Arc<FlakySink>, config: RetryQueueConfig) -> RetryQueue {
+        RetryQueue::open("audit", dir.path(), sink, config, logger()).unwrap()
+    }
+
+    fn record(id: &str) -> SinkRecord {
+        SinkRecord::new(id, format!("payload of {}", id))
+    }
+
+    #[test]
+    fn test_delivered_without_retries() {
+        let dir = TempDir::new("retry_queue").unwrap();
+        let sink = Arc::new(FlakySink::default());
+        let queue = open(&dir, sink.clone(), config());
+
+        queue.write(record("a")).wait().unwrap();
+        assert_eq!(sink.deliveries("a"), 1);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn test_single_delivery_after_restart() {
+        let dir = TempDir::new("retry_queue").unwrap();
+        let sink = FlakySink::failing();
+
+        {
+            let queue = open(&dir, sink.clone(), config());
+            queue.write(record("a")).wait().unwrap();
+            queue.write(record("b")).wait().unwrap();
+            // Same id, e.g. written again by a caller that retried
+            queue.write(record("a")).wait().unwrap();
+            queue.retry_due().wait().unwrap();
+            assert_eq!(queue.depth(), 2);
+        }
+
+        sink.failing.store(false, Ordering::SeqCst);
+        {
+            let queue = open(&dir, sink.clone(), config());
+            assert_eq!(queue.depth(), 2);
+            queue.retry_due().wait().unwrap();
+            assert_eq!(queue.depth(), 0);
+        }
+        assert_eq!(sink.deliveries("a"), 1);
+        assert_eq!(sink.deliveries("b"), 1);
+
+        // Delivered records are not sent again by the next instance
+        let queue = open(&dir, sink.clone(), config());
+        assert_eq!(queue.depth(), 0);
+        queue.retry_due().wait().unwrap();
+        assert_eq!(sink.deliveries("a"), 1);
+        assert_eq!(sink.deliveries("b"), 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let dir = TempDir::new("retry_queue").unwrap();
+        let sink = FlakySink::failing();
+        let config = RetryQueueConfig {
+            base_delay: Duration::from_secs(3600),
+            max_delay: Duration::from_secs(3600),
+            ..config()
+        };
+        let queue = open(&dir, sink.clone(), config);
+        queue.write(record("a")).wait().unwrap();
+
+        // The first retry is immediate, the next one only after the backoff
+        queue.retry_due().wait().unwrap();
+        sink.failing.store(false, Ordering::SeqCst);
+        queue.retry_due().wait().unwrap();
+        assert_eq!(sink.deliveries("a"), 0);
+        assert_eq!(queue.depth(), 1);
+    }
+
+    #[test]
+    fn test_drop_too_old() {
+        let dir = TempDir::new("retry_queue").unwrap();
+        let sink = Arc::new(FlakySink::default());
+
+        // A record that failed long ago, and one that failed just now
+        let journal = [
+            JournalEntry::add(&record("old"), 1),
+            JournalEntry::add(&record("new"), unix_now()),
+        ];
+        let mut file = File::create(dir.path().join("audit.journal")).unwrap();
+        for entry in journal.iter() {
+            file.write_all(&entry.to_line().unwrap()).unwrap();
+        }
+
+        let queue = open(&dir, sink.clone(), config());
+        assert_eq!(queue.depth(), 2);
+        queue.retry_due().wait().unwrap();
+        assert_eq!(queue.depth(), 0);
+        assert_eq!(sink.deliveries("old"), 0);
+        assert_eq!(sink.deliveries("new"), 1);
+    }
+
+    #[test]
+    fn test_bounded_journal() {
+        let dir = TempDir::new("retry_queue").unwrap();
+        let sink = FlakySink::failing();
+        let line_len = JournalEntry::add(&record("000"), unix_now())
+            .to_line()
+            .unwrap()
+            .len() as u64;
+        let config = RetryQueueConfig {
+            max_spill_bytes: 8 * line_len,
+            ..config()
+        };
+        let queue = open(&dir, sink.clone(), config);
+
+        // Half of the journal is for pending records, the rest are dropped
+        for id in 0..6 {
+            let res = queue.write(record(&format!("{:03}", id))).wait();
+            assert_eq!(res.is_ok(), id < 4, "{}", id);
+        }
+        assert_eq!(queue.depth(), 4);
+
+        // Journal is rotated as records are delivered and new ones fail
+        let path = dir.path().join("audit.journal");
+        for round in 0..10 {
+            sink.failing.store(false, Ordering::SeqCst);
+            queue.retry_due().wait().unwrap();
+            assert_eq!(queue.depth(), 0);
+            sink.failing.store(true, Ordering::SeqCst);
+            for id in 0..4 {
+                let id = format!("{:03}", 100 + round * 4 + id);
+                queue.write(record(&id)).wait().unwrap();
+            }
+            let len = fs::metadata(&path).unwrap().len();
+            assert!(len <= config.max_spill_bytes, "{}", len);
+        }
+
+        // A journal with a line cut short, e.g. by a crash, can be opened
+        let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+        journal.write_all(b"{\"op\":\"add\",\"id\":\"cut").unwrap();
+        let queue = open(&dir, sink.clone(), config);
+        assert_eq!(queue.depth(), 4);
+    }
+}
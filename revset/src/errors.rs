@@ -13,5 +13,6 @@ pub enum ErrorKind {
     #[fail(display = "repo error checking for node: {}", _0)] RepoError(HgNodeHash),
     #[fail(display = "could not fetch node generation")] GenerationFetchFailed,
     #[fail(display = "failed to fetch parent nodes")] ParentsFetchFailed,
+    #[fail(display = "failed to fetch changed files")] ChangedFilesFetchFailed,
     #[fail(display = "Bonsai mapping not found for {}", _0)] BonsaiMappingNotFound(HgChangesetId),
 }
@@ -52,7 +52,7 @@ mod ancestorscombinators;
 pub use ancestorscombinators::DifferenceOfUnionsOfAncestorsNodeStream;
 
 mod range;
-pub use range::RangeNodeStream;
+pub use range::{filter_by_path, first_parent_range, RangeNodeStream};
 
 mod uniqueheap;
 use uniqueheap::UniqueHeap;
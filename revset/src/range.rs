@@ -13,11 +13,12 @@ use std::sync::Arc;
 use failure::{err_msg, prelude::*};
 
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{self, loop_fn, Future, Loop};
 use futures::stream::{self, iter_ok, Stream};
+use futures_ext::{BoxStream, FutureExt, StreamExt};
 
 use blobrepo::BlobRepo;
-use mononoke_types::ChangesetId;
+use mononoke_types::{ChangesetId, MPath};
 use mononoke_types::Generation;
 
 use errors::*;
@@ -77,6 +78,85 @@ fn make_pending(
     )
 }
 
+fn fetch_generation(
+    repo: &Arc<BlobRepo>,
+    node: ChangesetId,
+) -> impl Future<Item = Generation, Error = Error> {
+    repo.get_generation_number_by_bonsai(&node)
+        .and_then(move |genopt| genopt.ok_or_else(|| err_msg(format!("{} not found", node))))
+        .map_err(|err| Error::from(err.chain_err(ErrorKind::GenerationFetchFailed)))
+}
+
+/// Returns the first-parent chain from `end_node` back to `start_node`, both included, highest
+/// generation first. Only p1 edges are followed, so this is a single walk that is much cheaper
+/// than `RangeNodeStream` on merge-heavy history. The stream is empty if `start_node` is not on
+/// the first-parent chain of `end_node`.
+pub fn first_parent_range(
+    repo: &Arc<BlobRepo>,
+    start_node: ChangesetId,
+    end_node: ChangesetId,
+) -> BoxStream<ChangesetId, Error> {
+    let repo = repo.clone();
+    fetch_generation(&repo, start_node)
+        .and_then(move |start_generation| {
+            loop_fn((end_node, vec![]), move |(node, mut chain)| {
+                let repo = repo.clone();
+                fetch_generation(&repo, node).and_then(move |generation| {
+                    if node == start_node {
+                        chain.push(node);
+                        return future::ok(Loop::Break(chain)).left_future();
+                    }
+                    if generation <= start_generation {
+                        return future::ok(Loop::Break(vec![])).left_future();
+                    }
+                    chain.push(node);
+                    repo.get_bonsai_changeset(node)
+                        .map_err(|err| Error::from(err.chain_err(ErrorKind::ParentsFetchFailed)))
+                        .map(move |cs| match cs.parents().next() {
+                            Some(p1) => Loop::Continue((*p1, chain)),
+                            None => Loop::Break(vec![]),
+                        })
+                        .right_future()
+                })
+            })
+        })
+        .map(iter_ok)
+        .flatten_stream()
+        .boxify()
+}
+
+/// Keeps the changesets of `nodes` that add, change or remove a file under `path`, as recorded in
+/// their bonsai changesets. Order is preserved, and at most `buffer_size` changesets are fetched
+/// at a time, so filtering a long range doesn't hold all of it in memory.
+pub fn filter_by_path<S>(
+    repo: &Arc<BlobRepo>,
+    nodes: S,
+    path: MPath,
+    buffer_size: usize,
+) -> BoxStream<ChangesetId, Error>
+where
+    S: Stream<Item = ChangesetId, Error = Error> + Send + 'static,
+{
+    let repo = repo.clone();
+    nodes
+        .map(move |node| {
+            repo.get_bonsai_changeset(node)
+                .map_err(|err| Error::from(err.chain_err(ErrorKind::ChangedFilesFetchFailed)))
+                .map(move |cs| (node, cs))
+        })
+        .buffered(buffer_size)
+        .filter_map(move |(node, cs)| {
+            if cs.file_changes()
+                .any(|(changed, _)| path.is_prefix_of(changed))
+            {
+                Some(node)
+            } else {
+                None
+            }
+        })
+        .boxify()
+}
+
 impl RangeNodeStream {
     // `start_node` should have a lower generation number than end_node,
     // otherwise stream will be empty
@@ -230,7 +310,6 @@ mod test {
     use async_unit;
     use fixtures::linear;
     use fixtures::merge_uneven;
-    use futures_ext::StreamExt;
     use mercurial_types::HgChangesetId;
     use tests::assert_changesets_sequence;
     use tests::string_to_nodehash;
@@ -243,6 +322,61 @@ mod test {
             .unwrap()
     }
 
+    const MERGE_UNEVEN_NODES: &[&str] = &[
+        "b47ca72355a0af2c749d45a5689fd5bcce9898c7",
+        "264f01429683b3dd8042cb3979e8bf37007118bc",
+        "5d43888a3c972fe68c224f93d41b30e9f888df7c",
+        "fc2cef43395ff3a7b28159007f63d6529d2f41ca",
+        "bc7b4d0f858c19e2474b03e442b8495fd7aeef33",
+        "795b8133cf375f6d68d27c6c23db24cd5d0cd00f",
+        "4f7f3fd428bec1a48f9314414b063c706d9c1aed",
+        "16839021e338500b3cf7c9b871c8a07351697d68",
+        "1d8a907f7b4bf50c6a09c16361e2205047ecc5e5",
+        "b65231269f651cfe784fd1d97ef02a049a37b8a0",
+        "d7542c9db7f4c77dab4b315edd328edf1514952f",
+        "3cda5c78aa35f0f5b09780d971197b51cad4613a",
+        "15c40d0abc36d47fb51c8eaec51ac7aad31f669c",
+    ];
+
+    /// Walks p1 edges one changeset at a time
+    fn brute_force_first_parent_range(
+        repo: &Arc<BlobRepo>,
+        start_node: ChangesetId,
+        end_node: ChangesetId,
+    ) -> Vec<ChangesetId> {
+        let mut chain = vec![end_node];
+        let mut node = end_node;
+        while node != start_node {
+            let cs = repo.get_bonsai_changeset(node).wait().unwrap();
+            match cs.parents().next() {
+                Some(p1) => node = *p1,
+                None => return vec![],
+            }
+            chain.push(node);
+        }
+        chain
+    }
+
+    /// Filters the whole range after the fact
+    fn brute_force_path_range(
+        repo: &Arc<BlobRepo>,
+        start_node: ChangesetId,
+        end_node: ChangesetId,
+        path: &MPath,
+    ) -> HashSet<ChangesetId> {
+        RangeNodeStream::new(repo, start_node, end_node)
+            .collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .filter(|node| {
+                let cs = repo.get_bonsai_changeset(*node).wait().unwrap();
+                let touched = cs.file_changes().any(|(changed, _)| path.is_prefix_of(changed));
+                touched
+            })
+            .collect()
+    }
+
     #[test]
     fn linear_range() {
         async_unit::tokio_unit_test(|| {
@@ -382,4 +516,141 @@ mod test {
             );
         })
     }
+
+    #[test]
+    fn merge_first_parent_range() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+
+            let nodestream = first_parent_range(
+                &repo,
+                string_to_bonsai(&repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+                string_to_bonsai(&repo, "b47ca72355a0af2c749d45a5689fd5bcce9898c7"),
+            );
+
+            assert_changesets_sequence(
+                &repo,
+                vec![
+                    string_to_bonsai(&repo, "b47ca72355a0af2c749d45a5689fd5bcce9898c7"),
+                    string_to_bonsai(&repo, "264f01429683b3dd8042cb3979e8bf37007118bc"),
+                    string_to_bonsai(&repo, "5d43888a3c972fe68c224f93d41b30e9f888df7c"),
+                    string_to_bonsai(&repo, "fc2cef43395ff3a7b28159007f63d6529d2f41ca"),
+                    string_to_bonsai(&repo, "bc7b4d0f858c19e2474b03e442b8495fd7aeef33"),
+                    string_to_bonsai(&repo, "795b8133cf375f6d68d27c6c23db24cd5d0cd00f"),
+                    string_to_bonsai(&repo, "4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                    string_to_bonsai(&repo, "b65231269f651cfe784fd1d97ef02a049a37b8a0"),
+                    string_to_bonsai(&repo, "d7542c9db7f4c77dab4b315edd328edf1514952f"),
+                    string_to_bonsai(&repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+                ],
+                nodestream,
+            );
+        })
+    }
+
+    #[test]
+    fn merge_first_parent_range_skips_second_parent() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+
+            // 1d8a907f is only reachable through the second parent of the merge
+            let nodestream = first_parent_range(
+                &repo,
+                string_to_bonsai(&repo, "1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+                string_to_bonsai(&repo, "b47ca72355a0af2c749d45a5689fd5bcce9898c7"),
+            );
+
+            assert_changesets_sequence(&repo, vec![], nodestream);
+        })
+    }
+
+    #[test]
+    fn merge_first_parent_range_all_pairs() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+            let nodes: Vec<_> = MERGE_UNEVEN_NODES
+                .iter()
+                .map(|node| string_to_bonsai(&repo, node))
+                .collect();
+
+            for start_node in &nodes {
+                for end_node in &nodes {
+                    let actual: Vec<_> = first_parent_range(&repo, *start_node, *end_node)
+                        .collect()
+                        .wait()
+                        .unwrap();
+                    let expected = brute_force_first_parent_range(&repo, *start_node, *end_node);
+                    assert_eq!(actual, expected, "range {}::{}", start_node, end_node);
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn merge_path_range_all_pairs() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+            let nodes: Vec<_> = MERGE_UNEVEN_NODES
+                .iter()
+                .map(|node| string_to_bonsai(&repo, node))
+                .collect();
+            let paths = vec![
+                MPath::new("base").unwrap(),
+                MPath::new("branch").unwrap(),
+                MPath::new("3").unwrap(),
+                MPath::new("nonexistent").unwrap(),
+            ];
+
+            for path in &paths {
+                for start_node in &nodes {
+                    for end_node in &nodes {
+                        let actual: HashSet<_> = filter_by_path(
+                            &repo,
+                            RangeNodeStream::new(&repo, *start_node, *end_node),
+                            path.clone(),
+                            2,
+                        ).collect()
+                            .wait()
+                            .unwrap()
+                            .into_iter()
+                            .collect();
+                        let expected =
+                            brute_force_path_range(&repo, *start_node, *end_node, path);
+                        assert_eq!(
+                            actual, expected,
+                            "range {}::{} touching {}",
+                            start_node, end_node, path
+                        );
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn merge_path_first_parent_range() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+
+            let nodestream = filter_by_path(
+                &repo,
+                first_parent_range(
+                    &repo,
+                    string_to_bonsai(&repo, "15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+                    string_to_bonsai(&repo, "b47ca72355a0af2c749d45a5689fd5bcce9898c7"),
+                ),
+                MPath::new("branch").unwrap(),
+                10,
+            );
+
+            assert_changesets_sequence(
+                &repo,
+                vec![
+                    string_to_bonsai(&repo, "b47ca72355a0af2c749d45a5689fd5bcce9898c7"),
+                    string_to_bonsai(&repo, "b65231269f651cfe784fd1d97ef02a049a37b8a0"),
+                    string_to_bonsai(&repo, "d7542c9db7f4c77dab4b315edd328edf1514952f"),
+                ],
+                nodestream,
+            );
+        })
+    }
 }
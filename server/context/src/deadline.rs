@@ -0,0 +1,104 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A client that knows how long it will wait for the response of its request hints its deadline
+//! with the `deadline_ms` field of the preamble of the session, in milliseconds from the start of
+//! the session. The long-running commands check the deadline as they make progress, and fail with
+//! `DeadlineExceeded` instead of producing a response that nobody reads.
+
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+/// Name of the field of the preamble with the hint
+pub const DEADLINE_PREAMBLE_KEY: &str = "deadline_ms";
+
+#[derive(Debug, Fail)]
+#[fail(display = "the deadline of {:?} that the client asked for was exceeded", _0)]
+pub struct DeadlineExceeded(pub Duration);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Deadline {
+    at: Instant,
+    budget: Duration,
+}
+
+impl Deadline {
+    /// The deadline `hint` from `start`. A hint longer than `max`, the server's own timeout for
+    /// a request, is clamped to it.
+    pub fn from_hint(start: Instant, hint: Duration, max: Duration) -> Self {
+        let budget = ::std::cmp::min(hint, max);
+        Deadline {
+            at: start + budget,
+            budget,
+        }
+    }
+
+    /// Parses the hint of a preamble field
+    pub fn parse_hint(start: Instant, hint: &str, max: Duration) -> Option<Self> {
+        hint.parse()
+            .ok()
+            .map(|ms| Self::from_hint(start, Duration::from_millis(ms), max))
+    }
+
+    /// How long the request may take, after clamping
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    pub fn check(&self) -> Result<(), DeadlineExceeded> {
+        if Instant::now() >= self.at {
+            Err(DeadlineExceeded(self.budget))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Whether `err` was caused by an exceeded deadline, which is an error of the client rather than
+/// of the server
+pub fn is_deadline_exceeded(err: &Error) -> bool {
+    err.iter_chain()
+        .any(|cause| cause.downcast_ref::<DeadlineExceeded>().is_some())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clamping() {
+        let start = Instant::now();
+        let max = Duration::from_secs(60);
+
+        let deadline = Deadline::from_hint(start, Duration::from_secs(5), max);
+        assert_eq!(deadline.budget(), Duration::from_secs(5));
+
+        let deadline = Deadline::from_hint(start, Duration::from_secs(3600), max);
+        assert_eq!(deadline.budget(), max);
+
+        let deadline = Deadline::parse_hint(start, "1500", max).unwrap();
+        assert_eq!(deadline.budget(), Duration::from_millis(1500));
+        assert_eq!(Deadline::parse_hint(start, "soon", max), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let max = Duration::from_secs(60);
+        let deadline = Deadline::from_hint(Instant::now(), Duration::from_secs(60), max);
+        assert!(deadline.check().is_ok());
+
+        let deadline = Deadline::from_hint(Instant::now(), Duration::from_millis(0), max);
+        let err: Error = deadline.check().unwrap_err().into();
+        assert!(is_deadline_exceeded(&err));
+        assert!(is_deadline_exceeded(&err.context("while serving").into()));
+        assert!(!is_deadline_exceeded(&Error::from(OtherError)));
+    }
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "something else")]
+    struct OtherError;
+}
@@ -4,20 +4,32 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+#[macro_use]
+extern crate failure_ext as failure;
 extern crate scuba_ext;
 extern crate slog;
 extern crate tracing;
 
+mod deadline;
+
 use scuba_ext::ScubaSampleBuilder;
 use slog::Logger;
 use tracing::TraceContext;
 
+pub use deadline::{is_deadline_exceeded, Deadline, DeadlineExceeded, DEADLINE_PREAMBLE_KEY};
+
 #[derive(Debug, Clone)]
 pub struct CoreContext<T> {
     pub session: T,
     pub logger: Logger,
     pub scuba: ScubaSampleBuilder,
     pub trace: TraceContext,
+    /// Unix name of the user of the session, as the client reported it
+    pub user: Option<String>,
+    /// Host the client runs on, as the client reported it
+    pub source_hostname: Option<String>,
+    /// When the client stops waiting for the response, as it hinted
+    pub deadline: Option<Deadline>,
 }
 
 impl<T> CoreContext<T> {
@@ -33,4 +45,22 @@ impl<T> CoreContext<T> {
     pub fn trace(&self) -> &TraceContext {
         &self.trace
     }
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_ref().map(|user| user.as_str())
+    }
+    pub fn source_hostname(&self) -> Option<&str> {
+        self.source_hostname
+            .as_ref()
+            .map(|source_hostname| source_hostname.as_str())
+    }
+    pub fn deadline(&self) -> Option<Deadline> {
+        self.deadline
+    }
+    /// Fails if the client hinted a deadline that is exceeded
+    pub fn check_deadline(&self) -> Result<(), DeadlineExceeded> {
+        match self.deadline {
+            Some(deadline) => deadline.check(),
+            None => Ok(()),
+        }
+    }
 }
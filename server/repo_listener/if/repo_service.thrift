@@ -0,0 +1,105 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Read access to the repos of a Mononoke server for tools that don't speak wireproto. The
+// service runs in the server process, on its own port.
+
+include "common/fb303/if/fb303.thrift"
+
+enum RepoServiceExceptionKind {
+  InvalidInput = 1,
+  NotFound = 2,
+  PermissionDenied = 3,
+  TooLarge = 4,
+  InternalError = 5,
+}
+
+exception RepoServiceException {
+  1: RepoServiceExceptionKind kind,
+  2: string reason,
+}
+
+// Every request names the repo, or one of its aliases, and the user that the request is made
+// for. Repos that restrict their users reject requests without a user.
+struct RepoRequest {
+  1: string repo,
+  2: optional string user,
+}
+
+struct ResolveBookmarkParams {
+  1: RepoRequest request,
+  2: string bookmark,
+}
+
+struct ChangesetInfoParams {
+  1: RepoRequest request,
+  // Hex of the hg changeset id
+  2: string changeset,
+}
+
+struct ChangesetInfo {
+  1: string changeset,
+  2: binary author,
+  // Seconds since the epoch
+  3: i64 date,
+  // Offset of the timezone of the author, in seconds west of UTC
+  4: i32 tz_offset,
+  // First line of the commit message
+  5: binary summary,
+  6: list<string> parents,
+  7: i64 changed_files_count,
+}
+
+struct ReadFileParams {
+  1: RepoRequest request,
+  2: string changeset,
+  3: binary path,
+  // Files larger than this are not sent. The server has a cap of its own, which this can only
+  // lower.
+  4: optional i64 max_size,
+}
+
+struct ListDirectoryParams {
+  1: RepoRequest request,
+  2: string changeset,
+  // The root directory if empty
+  3: binary path,
+}
+
+enum DirectoryEntryType {
+  File = 1,
+  Executable = 2,
+  Symlink = 3,
+  Directory = 4,
+}
+
+struct DirectoryEntry {
+  1: binary name,
+  2: DirectoryEntryType type,
+}
+
+struct RepoFlagsParams {
+  1: RepoRequest request,
+}
+
+service MononokeRepoService extends fb303.FacebookService {
+  // Hex of the hg changeset id that the bookmark points to
+  string resolve_bookmark(1: ResolveBookmarkParams params)
+    throws (1: RepoServiceException e),
+
+  ChangesetInfo changeset_info(1: ChangesetInfoParams params)
+    throws (1: RepoServiceException e),
+
+  binary read_file(1: ReadFileParams params)
+    throws (1: RepoServiceException e),
+
+  list<DirectoryEntry> list_directory(1: ListDirectoryParams params)
+    throws (1: RepoServiceException e),
+
+  // The effective flags of the repo, with their runtime overrides applied, by flag name
+  map<string, bool> repo_flags(1: RepoFlagsParams params)
+    throws (1: RepoServiceException e),
+}
@@ -0,0 +1,43 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::{Future, Stream};
+use slog::Logger;
+use tokio::timer::Interval;
+
+use blobrepo::BlobRepo;
+use blobrepo::bookmark_snapshot::take_bookmark_snapshot;
+use bookmarks::BookmarkSnapshotIndex;
+use metaconfig::BookmarkSnapshotParams;
+
+/// Takes a snapshot of the bookmarks of the repo every `params.interval`, starting one interval
+/// from now. The task runs separately from the serving of requests, and a failed snapshot is
+/// only logged, the next one is taken as usual.
+pub fn bookmark_snapshots(
+    repo: BlobRepo,
+    index: Arc<BookmarkSnapshotIndex>,
+    params: BookmarkSnapshotParams,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> + Send + 'static {
+    Interval::new(Instant::now() + params.interval, params.interval)
+        .map_err({
+            cloned!(logger);
+            move |err| error!(logger, "bookmark snapshot timer failed: {}", err)
+        })
+        .for_each(move |_| {
+            cloned!(logger);
+            take_bookmark_snapshot(&repo, index.clone(), params.retain).then(move |res| {
+                match res {
+                    Ok(key) => info!(logger, "took bookmark snapshot {}", key),
+                    Err(err) => warn!(logger, "failed to take bookmark snapshot: {}", err),
+                }
+                Ok(())
+            })
+        })
+}
@@ -16,39 +16,58 @@ use futures::sync::mpsc;
 use futures_ext::{BoxFuture, FutureExt, StreamExt};
 use openssl::ssl::SslAcceptor;
 use slog::Logger;
+use stats::{DynamicTimeseries, Timeseries};
 use tokio;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_codec::{FramedRead, FramedWrite};
-use tokio_io::{AsyncRead, AsyncWrite, IoStream};
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_openssl::SslAcceptorExt;
 
+use fd_accounting::{self, FdComponent, FdGuard};
 use sshrelay::{SshDecoder, SshEncoder, SshMsg, SshStream, Stdio};
 
 use errors::*;
+use proxy_protocol::{read_proxy_header, ProxyProtocol};
 use repo_handlers::RepoHandler;
 use request_handler::request_handler;
 
 const CHUNK_SIZE: usize = 10000;
 
+define_stats! {
+    prefix = "mononoke.connection_acceptor";
+    // Connections to each repo by the name that they asked for, which is one of the aliases of
+    // the repo or its name
+    connections: dynamic_timeseries(
+        "{}.requested_as.{}", (reponame: String, requested: String); RATE, SUM),
+    // Connections rejected because the user is not allowed to use the repo
+    denied: dynamic_timeseries("{}.denied", (reponame: String); RATE, SUM),
+    // Connections rejected because their PROXY protocol header is missing or invalid
+    proxy_header_rejected: timeseries(RATE, SUM),
+}
+
 /// This function accepts connections, reads Preamble and routes request to a thread responsible for
-/// a particular repo
+/// a particular repo. With `proxy_protocol`, the address of the client is read from the PROXY
+/// protocol header of the connection, before the TLS handshake.
 pub fn connection_acceptor(
-    sockname: String,
+    listener: TcpListener,
     root_log: Logger,
-    repo_handlers: HashMap<String, RepoHandler>,
+    repo_handlers: Arc<HashMap<String, RepoHandler>>,
     tls_acceptor: SslAcceptor,
+    proxy_protocol: ProxyProtocol,
 ) -> BoxFuture<(), Error> {
-    let repo_handlers = Arc::new(repo_handlers);
     let tls_acceptor = Arc::new(tls_acceptor);
 
-    listener(sockname)
-        .expect("failed to create listener")
+    listener
+        .incoming()
         .map_err(Error::from)
         .for_each(move |sock| {
+            // The socket is accounted for until its connection is done with, whether it was
+            // served or failed
+            let fd = fd_accounting::track(FdComponent::ClientSockets);
             // Accept the request without blocking the listener
             cloned!(root_log, repo_handlers, tls_acceptor);
             tokio::spawn(future::lazy(move || {
-                accept(sock, root_log, repo_handlers, tls_acceptor)
+                accept(sock, fd, root_log, repo_handlers, tls_acceptor, proxy_protocol)
             }));
             Ok(())
         })
@@ -57,37 +76,56 @@ pub fn connection_acceptor(
 
 fn accept(
     sock: TcpStream,
+    fd: FdGuard,
     root_log: Logger,
     repo_handlers: Arc<HashMap<String, RepoHandler>>,
     tls_acceptor: Arc<SslAcceptor>,
+    proxy_protocol: ProxyProtocol,
 ) -> impl Future<Item = (), Error = ()> {
-    let addr = sock.peer_addr();
+    let peer_addr = sock.peer_addr();
 
-    tls_acceptor
-        .accept_async(sock)
+    read_proxy_header(sock, proxy_protocol)
         .map_err({
             cloned!(root_log);
             move |err| {
+                STATS::proxy_header_rejected.add_value(1);
                 error!(
                     root_log,
-                    "Error while establishing tls connection";
-                    SlogKVError(Error::from(err)),
+                    "Error while reading PROXY protocol header";
+                    SlogKVError(err),
                 )
             }
         })
         .and_then({
             cloned!(root_log);
-            move |sock| {
-                ssh_server_mux(sock).map_err(move |err| {
-                    error!(
-                        root_log,
-                        "Error while reading preamble";
-                        SlogKVError(Error::from(err)),
-                    )
-                })
+            move |(source, sock)| {
+                tls_acceptor
+                    .accept_async(sock)
+                    .map(move |sock| (source, sock))
+                    .map_err(move |err| {
+                        error!(
+                            root_log,
+                            "Error while establishing tls connection";
+                            SlogKVError(Error::from(err)),
+                        )
+                    })
+            }
+        })
+        .and_then({
+            cloned!(root_log);
+            move |(source, sock)| {
+                ssh_server_mux(sock)
+                    .map(move |stdio| (source, stdio))
+                    .map_err(move |err| {
+                        error!(
+                            root_log,
+                            "Error while reading preamble";
+                            SlogKVError(Error::from(err)),
+                        )
+                    })
             }
         })
-        .join(addr.into_future().map_err({
+        .join(peer_addr.into_future().map_err({
             cloned!(root_log);
             move |err| {
                 crit!(
@@ -96,19 +134,71 @@ fn accept(
                 )
             }
         }))
-        .and_then(move |(stdio, addr)| {
+        .and_then(move |((source, stdio), peer_addr)| {
+            // Behind a load balancer, the peer is the load balancer
+            let addr = source.unwrap_or(peer_addr);
             repo_handlers
                 .get(&stdio.preamble.reponame)
                 .cloned()
                 .ok_or_else(|| error!(root_log, "Unknown repo: {}", stdio.preamble.reponame))
                 .into_future()
-                .and_then(move |handler| {
-                    request_handler(handler.clone(), stdio, addr, handler.repo.hook_manager())
+                .and_then({
+                    cloned!(root_log);
+                    move |handler| {
+                        let user = stdio.preamble.misc.get("unix_username").cloned();
+                        if handler.allows(user.as_ref().map(|user| user.as_str())) {
+                            Ok((handler, stdio))
+                        } else {
+                            STATS::denied.add_value(1, (handler.reponame.clone(),));
+                            let err = ErrorKind::PermissionDenied(user, handler.reponame.clone());
+                            Err(error!(root_log, "{}", err))
+                        }
+                    }
                 })
+                .and_then(move |(handler, stdio)| {
+                    let RepoHandler {
+                        logger,
+                        mut scuba,
+                        repo,
+                        reponame,
+                        deprecation_notice,
+                        ..
+                    } = handler;
+                    let requested = stdio.preamble.reponame.clone();
+                    STATS::connections.add_value(1, (reponame.clone(), requested.clone()));
+                    let logger = if requested == reponame {
+                        logger
+                    } else {
+                        logger.new(o!("requested_repo" => requested.clone()))
+                    };
+                    scuba
+                        .add("canonical_repo", reponame)
+                        .add("requested_repo", requested);
+                    repo.get()
+                        .map_err(move |err| {
+                            error!(root_log, "Failed to open repo"; SlogKVError(err))
+                        })
+                        .and_then(move |repo| {
+                            let hook_manager = repo.hook_manager();
+                            request_handler(
+                                logger,
+                                scuba,
+                                repo,
+                                stdio,
+                                addr,
+                                hook_manager,
+                                deprecation_notice,
+                            )
+                        })
+                })
+        })
+        .then(move |res| {
+            drop(fd);
+            res
         })
 }
 
-fn listener<P>(sockname: P) -> io::Result<IoStream<TcpStream>>
+pub fn bind<P>(sockname: P) -> io::Result<TcpListener>
 where
     P: AsRef<str>,
 {
@@ -131,7 +221,7 @@ where
         }
     }
 
-    Ok(listener.incoming().boxify())
+    Ok(listener)
 }
 
 // As a server, given a stream to a client, return an Io pair with stdin/stdout, and an
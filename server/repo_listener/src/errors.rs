@@ -6,8 +6,20 @@
 
 pub use failure::{Error, Result, ResultExt};
 
+use mercurial_types::MPath;
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "connection does not start with preamble")] NoConnectionPreamble,
     #[fail(display = "connection error while reading preamble")] ConnectionError,
+    #[fail(display = "unknown repo: {}", _0)] UnknownRepo(String),
+    #[fail(display = "user {:?} is not allowed to use repo {}", _0, _1)]
+    PermissionDenied(Option<String>, String),
+    #[fail(display = "{} is larger than {} bytes", _0, _1)] FileTooLarge(MPath, u64),
+    #[fail(display = "{:?} is not a directory", _0)] NotADirectory(String),
+    #[fail(display = "connection does not start with a PROXY protocol header")]
+    ProxyHeaderMissing,
+    #[fail(display = "connection closed before the end of its PROXY protocol header")]
+    ProxyHeaderTruncated,
+    #[fail(display = "invalid PROXY protocol header: {}", _0)] InvalidProxyHeader(String),
 }
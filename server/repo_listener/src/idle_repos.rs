@@ -0,0 +1,441 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Reclamation of the resources of repos that get no traffic.
+//!
+//! A repo holds SQL connections, per-repo caches and background tasks for as long as it is
+//! open. A repo that has had no request for the idle timeout is reclaimed: the server drops its
+//! handle to the repo, which releases all of these once the last request that still uses it is
+//! done, and stops its background tasks. The next request opens the repo again and pays for it.
+
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::prelude::*;
+use futures::{future, Future, Stream};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+use tokio;
+use tokio::timer::Interval;
+
+/// Source of the current time, so that tests can make repos idle without waiting
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Tasks that run in the background for as long as a repo is open
+pub type BackgroundTasks = Vec<BoxFuture<(), ()>>;
+
+/// Opens a repo again after it was reclaimed, and returns it with its background tasks
+pub type RepoOpener<R> = Arc<Fn() -> BoxFuture<(R, BackgroundTasks), Error> + Send + Sync>;
+
+enum RepoState<R: Clone> {
+    Open {
+        repo: R,
+        last_activity: Instant,
+        // Dropping these stops the background tasks
+        _background: Vec<oneshot::Sender<()>>,
+    },
+    Reopening(Shared<BoxFuture<R, Error>>),
+    Reclaimed,
+}
+
+/// A repo that is reclaimed after it has been idle for a while, and opened again when it is
+/// needed
+#[derive(Clone)]
+pub struct IdleRepo<R: Clone> {
+    inner: Arc<IdleRepoInner<R>>,
+}
+
+struct IdleRepoInner<R: Clone> {
+    logger: Logger,
+    clock: Arc<Clock>,
+    // None if the repo is always hot
+    idle_timeout: Option<Duration>,
+    opener: RepoOpener<R>,
+    state: Mutex<RepoState<R>>,
+}
+
+impl<R> IdleRepo<R>
+where
+    R: Clone + Send + Sync + 'static,
+{
+    /// Manages `repo`, which was just opened. It is reclaimed once it has had no request for
+    /// `idle_timeout`, or never if that is None.
+    pub fn new(
+        logger: Logger,
+        clock: Arc<Clock>,
+        idle_timeout: Option<Duration>,
+        opener: RepoOpener<R>,
+        repo: R,
+        background: BackgroundTasks,
+    ) -> Self {
+        let state = RepoState::Open {
+            repo,
+            last_activity: clock.now(),
+            _background: spawn_background(background),
+        };
+        IdleRepo {
+            inner: Arc::new(IdleRepoInner {
+                logger,
+                clock,
+                idle_timeout,
+                opener,
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    /// The repo, for a request. The repo is opened again if it was reclaimed.
+    pub fn get(&self) -> BoxFuture<R, Error> {
+        let mut state = self.inner.state.lock().expect("lock poisoned");
+        if let RepoState::Reclaimed = *state {
+            *state = RepoState::Reopening(self.reopen().shared());
+        }
+        let reopening = match *state {
+            RepoState::Open {
+                ref repo,
+                ref mut last_activity,
+                ..
+            } => {
+                *last_activity = self.inner.clock.now();
+                return future::ok(repo.clone()).boxify();
+            }
+            RepoState::Reopening(ref reopening) => reopening.clone(),
+            RepoState::Reclaimed => unreachable!("reclaimed repo is reopened above"),
+        };
+        reopening
+            .map(|repo| (*repo).clone())
+            .map_err(|err| format_err!("failed to reopen repo: {}", *err))
+            .boxify()
+    }
+
+    fn reopen(&self) -> BoxFuture<R, Error> {
+        info!(self.inner.logger, "Reopening idle repo");
+        let start = self.inner.clock.now();
+        let inner = self.inner.clone();
+
+        (self.inner.opener)()
+            .then(move |res| {
+                let mut state = inner.state.lock().expect("lock poisoned");
+                match res {
+                    Ok((repo, background)) => {
+                        let now = inner.clock.now();
+                        info!(
+                            inner.logger,
+                            "Reopened idle repo in {:?}, the request waited for it",
+                            now - start
+                        );
+                        *state = RepoState::Open {
+                            repo: repo.clone(),
+                            last_activity: now,
+                            _background: spawn_background(background),
+                        };
+                        Ok(repo)
+                    }
+                    Err(err) => {
+                        // The next request tries again
+                        error!(inner.logger, "Failed to reopen idle repo: {}", err);
+                        *state = RepoState::Reclaimed;
+                        Err(err)
+                    }
+                }
+            })
+            .boxify()
+    }
+
+    /// Reclaims the repo if it is open and has had no request for the idle timeout. Returns
+    /// whether it was reclaimed.
+    pub fn reclaim_if_idle(&self) -> bool {
+        let idle_timeout = match self.inner.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return false,
+        };
+
+        let mut state = self.inner.state.lock().expect("lock poisoned");
+        let idle_for = match *state {
+            RepoState::Open { last_activity, .. } => self.inner.clock.now() - last_activity,
+            RepoState::Reopening(_) | RepoState::Reclaimed => return false,
+        };
+        if idle_for < idle_timeout {
+            return false;
+        }
+
+        info!(
+            self.inner.logger,
+            "Repo had no request for {:?}, reclaiming its resources", idle_for
+        );
+        // Drops the handle to the repo and stops the background tasks
+        let _reclaimed = mem::replace(&mut *state, RepoState::Reclaimed);
+        true
+    }
+
+    pub fn is_open(&self) -> bool {
+        match *self.inner.state.lock().expect("lock poisoned") {
+            RepoState::Open { .. } => true,
+            RepoState::Reopening(_) | RepoState::Reclaimed => false,
+        }
+    }
+}
+
+impl<R> fmt::Debug for IdleRepo<R>
+where
+    R: Clone + Send + Sync + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IdleRepo")
+            .field("idle_timeout", &self.inner.idle_timeout)
+            .field("open", &self.is_open())
+            .finish()
+    }
+}
+
+/// Spawns the tasks, which run until the returned senders are dropped
+fn spawn_background(tasks: BackgroundTasks) -> Vec<oneshot::Sender<()>> {
+    tasks
+        .into_iter()
+        .map(|task| {
+            let (stop, stopped) = oneshot::channel();
+            tokio::spawn(
+                task.select(stopped.then(|_| Ok(())))
+                    .map(|_| ())
+                    .map_err(|_| ()),
+            );
+            stop
+        })
+        .collect()
+}
+
+/// Checks every `interval` whether the repos are idle, and reclaims those that are
+pub fn reclaim_idle_repos<R>(
+    repos: Vec<IdleRepo<R>>,
+    interval: Duration,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    R: Clone + Send + Sync + 'static,
+{
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |err| error!(logger, "idle repo timer failed: {}", err))
+        .for_each(move |_| {
+            for repo in &repos {
+                repo.reclaim_if_idle();
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    use slog::Discard;
+    use tokio::runtime::Runtime;
+
+    const IDLE_TIMEOUT_SECS: u64 = 60;
+
+    fn idle_timeout() -> Duration {
+        Duration::from_secs(IDLE_TIMEOUT_SECS)
+    }
+
+    struct FakeClock(Mutex<Instant>);
+
+    impl FakeClock {
+        fn new() -> Arc<Self> {
+            Arc::new(FakeClock(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Opener of a repo that is the number of times it was opened, and which fails to open
+    /// while `failing` is set
+    fn counting_opener(opens: Arc<AtomicUsize>, failing: Arc<AtomicUsize>) -> RepoOpener<usize> {
+        Arc::new(move || {
+            if failing.load(Ordering::SeqCst) > 0 {
+                failing.fetch_sub(1, Ordering::SeqCst);
+                return future::err(format_err!("backend is down")).boxify();
+            }
+            let opened = opens.fetch_add(1, Ordering::SeqCst) + 1;
+            future::ok((opened, vec![])).boxify()
+        })
+    }
+
+    fn idle_repo(
+        clock: Arc<FakeClock>,
+        idle_timeout: Option<Duration>,
+    ) -> (IdleRepo<usize>, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let failing = Arc::new(AtomicUsize::new(0));
+        let repo = IdleRepo::new(
+            Logger::root(Discard, o!()),
+            clock,
+            idle_timeout,
+            counting_opener(opens.clone(), failing.clone()),
+            0,
+            vec![],
+        );
+        (repo, opens, failing)
+    }
+
+    #[test]
+    fn test_reclaim_and_reopen() {
+        let clock = FakeClock::new();
+        let (repo, opens, _) = idle_repo(clock.clone(), Some(idle_timeout()));
+
+        clock.advance(Duration::from_secs(50));
+        assert!(!repo.reclaim_if_idle());
+        // A request resets the idle time
+        assert_eq!(repo.get().wait().unwrap(), 0);
+        clock.advance(Duration::from_secs(50));
+        assert!(!repo.reclaim_if_idle());
+        assert!(repo.is_open());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(repo.reclaim_if_idle());
+        assert!(!repo.is_open());
+        assert!(!repo.reclaim_if_idle());
+        assert_eq!(opens.load(Ordering::SeqCst), 0);
+
+        // The next request opens the repo again, and the ones after it reuse it
+        assert_eq!(repo.get().wait().unwrap(), 1);
+        assert!(repo.is_open());
+        assert_eq!(repo.get().wait().unwrap(), 1);
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+
+        clock.advance(idle_timeout());
+        assert!(repo.reclaim_if_idle());
+        assert_eq!(repo.get().wait().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_always_hot() {
+        let clock = FakeClock::new();
+        let (repo, opens, _) = idle_repo(clock.clone(), None);
+
+        clock.advance(Duration::from_secs(365 * 24 * 60 * 60));
+        assert!(!repo.reclaim_if_idle());
+        assert!(repo.is_open());
+        assert_eq!(repo.get().wait().unwrap(), 0);
+        assert_eq!(opens.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_failed_reopen() {
+        let clock = FakeClock::new();
+        let (repo, opens, failing) = idle_repo(clock.clone(), Some(idle_timeout()));
+
+        clock.advance(idle_timeout());
+        assert!(repo.reclaim_if_idle());
+
+        failing.store(1, Ordering::SeqCst);
+        let err = repo.get().wait().unwrap_err();
+        assert!(err.to_string().contains("backend is down"), "{}", err);
+        assert!(!repo.is_open());
+
+        // The failure isn't remembered, the next request tries again
+        assert_eq!(repo.get().wait().unwrap(), 1);
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_concurrent_reopen() {
+        let clock = FakeClock::new();
+        let (repo, opens, _) = idle_repo(clock.clone(), Some(idle_timeout()));
+        clock.advance(idle_timeout());
+        assert!(repo.reclaim_if_idle());
+
+        // Requests that arrive while the repo is being opened wait for the same open
+        let first = repo.get();
+        let second = repo.get();
+        assert_eq!(first.join(second).wait().unwrap(), (1, 1));
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+    }
+
+    /// Sends its generation when it is dropped, which is when its task stops
+    struct StopNotifier(mpsc::Sender<usize>, usize);
+
+    impl Drop for StopNotifier {
+        fn drop(&mut self) {
+            let _ = self.0.send(self.1);
+        }
+    }
+
+    fn background_task(generation: usize, stopped: mpsc::Sender<usize>) -> BackgroundTasks {
+        let notifier = StopNotifier(stopped, generation);
+        vec![
+            future::empty::<(), ()>()
+                .map(move |()| drop(notifier))
+                .boxify(),
+        ]
+    }
+
+    #[test]
+    fn test_background_tasks_stop_while_reclaimed() {
+        let mut runtime = Runtime::new().unwrap();
+        let clock = FakeClock::new();
+        let (stopped_send, stopped) = mpsc::channel();
+
+        let opener: RepoOpener<usize> = {
+            let stopped_send = Mutex::new(stopped_send.clone());
+            Arc::new(move || {
+                let stopped_send = stopped_send.lock().unwrap().clone();
+                future::ok((1, background_task(1, stopped_send))).boxify()
+            })
+        };
+        let repo = runtime
+            .block_on(future::lazy({
+                let clock = clock.clone();
+                move || {
+                    Ok::<_, ()>(IdleRepo::new(
+                        Logger::root(Discard, o!()),
+                        clock,
+                        Some(idle_timeout()),
+                        opener,
+                        0,
+                        background_task(0, stopped_send),
+                    ))
+                }
+            }))
+            .unwrap();
+
+        let timeout = Duration::from_secs(10);
+        assert!(stopped.try_recv().is_err());
+        clock.advance(idle_timeout());
+        assert!(repo.reclaim_if_idle());
+        assert_eq!(stopped.recv_timeout(timeout).unwrap(), 0);
+
+        // Reopening the repo starts its background tasks again
+        assert_eq!(runtime.block_on(repo.get()).unwrap(), 1);
+        assert!(stopped.try_recv().is_err());
+        clock.advance(idle_timeout());
+        assert!(repo.reclaim_if_idle());
+        assert_eq!(stopped.recv_timeout(timeout).unwrap(), 1);
+    }
+}
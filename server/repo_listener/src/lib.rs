@@ -15,8 +15,10 @@ extern crate bytes;
 extern crate cloned;
 extern crate context;
 extern crate dns_lookup;
+extern crate fb303;
 #[macro_use]
 extern crate failure_ext as failure;
+extern crate fd_accounting;
 extern crate futures;
 #[macro_use]
 extern crate futures_ext;
@@ -30,7 +32,7 @@ extern crate openssl;
 extern crate slog;
 extern crate slog_kvfilter;
 extern crate slog_term;
-extern crate sql;
+extern crate srserver;
 #[macro_use]
 extern crate stats;
 extern crate time_ext;
@@ -42,47 +44,147 @@ extern crate tokio_openssl;
 extern crate tracing;
 extern crate uuid;
 
+extern crate blobrepo;
+extern crate bookmarks;
 extern crate cache_warmup;
+extern crate derived_data;
 extern crate hgproto;
 extern crate hooks;
 extern crate mercurial_types;
 extern crate metaconfig;
+extern crate mononoke_api as api;
 extern crate ready_state;
 extern crate repo_client;
+extern crate repo_service_thrift;
 extern crate scuba_ext;
 extern crate sshrelay;
 
+mod bookmark_snapshots;
 mod connection_acceptor;
 mod errors;
+mod idle_repos;
+mod proxy_protocol;
 mod request_handler;
+mod request_mirroring;
 mod repo_handlers;
+mod repo_service;
 
-use futures::Future;
+use std::cmp;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, Future};
 use futures_ext::{BoxFuture, FutureExt};
 use openssl::ssl::SslAcceptor;
 use slog::Logger;
+use tokio::net::TcpListener;
 
 use metaconfig::repoconfig::RepoConfig;
+use repo_client::OpenRepoParams;
 
 use connection_acceptor::connection_acceptor;
 use errors::*;
+use idle_repos::reclaim_idle_repos;
 use repo_handlers::repo_handlers;
+use repo_service::start_repo_service;
+
+pub use proxy_protocol::ProxyProtocol;
+pub use repo_service::MAX_READ_FILE_SIZE;
+
+/// How often repos are checked for idleness, at most
+const IDLE_CHECK_INTERVAL_SECS: u64 = 60;
 
+/// Serves the repos over wireproto on `sockname`, and over the repo service on `service_addr` if
+/// it is set. `proxy_protocol` tells if wireproto connections start with a PROXY protocol header.
 pub fn create_repo_listeners(
     repos: impl IntoIterator<Item = (String, RepoConfig)>,
     myrouter_port: Option<u16>,
+    open_params: OpenRepoParams,
     root_log: &Logger,
     sockname: &str,
     tls_acceptor: SslAcceptor,
+    proxy_protocol: ProxyProtocol,
+    service_addr: Option<SocketAddr>,
 ) -> (BoxFuture<(), Error>, ready_state::ReadyState) {
     let sockname = String::from(sockname);
+    create_listeners(
+        repos,
+        myrouter_port,
+        open_params,
+        root_log,
+        move || connection_acceptor::bind(sockname).expect("failed to create listener"),
+        tls_acceptor,
+        proxy_protocol,
+        service_addr,
+    )
+}
+
+/// Same as `create_repo_listeners`, but accepts connections on a listener that is already bound,
+/// so that the caller can bind to an ephemeral port and find out the address before serving.
+pub fn create_repo_listeners_on(
+    repos: impl IntoIterator<Item = (String, RepoConfig)>,
+    myrouter_port: Option<u16>,
+    open_params: OpenRepoParams,
+    root_log: &Logger,
+    listener: TcpListener,
+    tls_acceptor: SslAcceptor,
+    proxy_protocol: ProxyProtocol,
+    service_addr: Option<SocketAddr>,
+) -> (BoxFuture<(), Error>, ready_state::ReadyState) {
+    create_listeners(
+        repos,
+        myrouter_port,
+        open_params,
+        root_log,
+        move || listener,
+        tls_acceptor,
+        proxy_protocol,
+        service_addr,
+    )
+}
+
+fn create_listeners<L>(
+    repos: impl IntoIterator<Item = (String, RepoConfig)>,
+    myrouter_port: Option<u16>,
+    open_params: OpenRepoParams,
+    root_log: &Logger,
+    listener: L,
+    tls_acceptor: SslAcceptor,
+    proxy_protocol: ProxyProtocol,
+    service_addr: Option<SocketAddr>,
+) -> (BoxFuture<(), Error>, ready_state::ReadyState)
+where
+    L: FnOnce() -> TcpListener + Send + 'static,
+{
     let root_log = root_log.clone();
     let mut ready = ready_state::ReadyStateBuilder::new();
 
     (
-        repo_handlers(repos, myrouter_port, &root_log, &mut ready)
+        repo_handlers(repos, myrouter_port, open_params, &root_log, &mut ready)
             .and_then(move |handlers| {
-                connection_acceptor(sockname, root_log, handlers, tls_acceptor)
+                if let Some(idle_timeout) = open_params.idle_timeout {
+                    info!(root_log, "Reclaiming repos idle for {:?}", idle_timeout);
+                    let interval =
+                        cmp::min(idle_timeout, Duration::from_secs(IDLE_CHECK_INTERVAL_SECS));
+                    // Aliases share the repo of their handler
+                    let repos = handlers
+                        .iter()
+                        .filter(|(name, handler)| **name == handler.reponame)
+                        .map(|(_, handler)| handler.repo.clone())
+                        .collect();
+                    tokio::spawn(reclaim_idle_repos(repos, interval, root_log.clone()));
+                }
+                let handlers = Arc::new(handlers);
+                if let Some(service_addr) = service_addr {
+                    if let Err(err) =
+                        start_repo_service(root_log.clone(), service_addr, handlers.clone())
+                    {
+                        return future::err(err).left_future();
+                    }
+                }
+                connection_acceptor(listener(), root_log, handlers, tls_acceptor, proxy_protocol)
+                    .right_future()
             })
             .boxify(),
         ready.freeze(),
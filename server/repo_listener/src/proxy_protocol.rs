@@ -0,0 +1,415 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! PROXY protocol headers, which load balancers send before the data of a connection to tell the
+//! address of the client that they proxy.
+//!
+//! Behind an L4 load balancer, the peer address of a connection is the address of the load
+//! balancer. A listener that has the PROXY protocol enabled reads a header of either version,
+//! before the TLS handshake, and the source address of the header is used as the address of the
+//! client. See https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+
+use futures::{future, Future, Poll};
+use futures::future::{loop_fn, Loop};
+use futures_ext::{BoxFuture, FutureExt};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::read;
+
+use errors::*;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, including the final CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, family and protocol, and length of the addresses
+const V2_HEADER_LEN: usize = 16;
+/// Bytes read from the connection at a time while looking for the header
+const READ_CHUNK_SIZE: usize = 256;
+
+/// Whether the connections of a listener start with a PROXY protocol header
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProxyProtocol {
+    Disabled,
+    /// Connections may start with a header. This lets a listener move to the load balancer, but
+    /// clients that connect directly can send a header to spoof their address.
+    Optional,
+    /// Connections without a header are rejected, so that clients that connect directly can't
+    /// spoof their address
+    Required,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(ProxyProtocol::Disabled),
+            "optional" => Ok(ProxyProtocol::Optional),
+            "required" => Ok(ProxyProtocol::Required),
+            _ => bail_msg!(
+                "unknown PROXY protocol mode {}, expected off, optional or required",
+                s
+            ),
+        }
+    }
+}
+
+impl Default for ProxyProtocol {
+    fn default() -> Self {
+        ProxyProtocol::Disabled
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum ParsedHeader {
+    /// More bytes are needed to tell
+    Incomplete,
+    /// The connection doesn't start with a header
+    Absent,
+    /// A header of `len` bytes. The source is not set for connections that the load balancer made
+    /// itself, e.g. for health checks, which are served with the peer address.
+    Complete {
+        len: usize,
+        source: Option<SocketAddr>,
+    },
+}
+
+fn invalid(msg: &str) -> Error {
+    ErrorKind::InvalidProxyHeader(msg.to_string()).into()
+}
+
+/// True if `buf` is a prefix of `expected`, or the other way around
+fn matches_prefix(buf: &[u8], expected: &[u8]) -> bool {
+    let len = cmp::min(buf.len(), expected.len());
+    buf[..len] == expected[..len]
+}
+
+fn parse_header(buf: &[u8]) -> Result<ParsedHeader> {
+    if buf.is_empty() {
+        Ok(ParsedHeader::Incomplete)
+    } else if matches_prefix(buf, V1_PREFIX) {
+        parse_v1(buf)
+    } else if matches_prefix(buf, V2_SIGNATURE) {
+        parse_v2(buf)
+    } else {
+        Ok(ParsedHeader::Absent)
+    }
+}
+
+/// Parses a header such as "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+fn parse_v1(buf: &[u8]) -> Result<ParsedHeader> {
+    let searched = &buf[..cmp::min(buf.len(), V1_MAX_LEN)];
+    let end = match searched.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return Err(invalid("v1 header is too long")),
+        None => return Ok(ParsedHeader::Incomplete),
+    };
+    let line = str::from_utf8(&buf[..end]).map_err(|_| invalid("v1 header is not ascii"))?;
+
+    let fields: Vec<_> = line.split(' ').collect();
+    let source = match fields.get(1).cloned() {
+        Some("UNKNOWN") => None,
+        Some(proto @ "TCP4") | Some(proto @ "TCP6") => {
+            if fields.len() != 6 {
+                return Err(invalid("v1 header doesn't have 6 fields"));
+            }
+            let ip =
+                IpAddr::from_str(fields[2]).map_err(|_| invalid("invalid v1 source address"))?;
+            if ip.is_ipv4() != (proto == "TCP4") {
+                return Err(invalid("v1 source address is not of the protocol"));
+            }
+            let port = u16::from_str(fields[4]).map_err(|_| invalid("invalid v1 source port"))?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid("unknown v1 protocol")),
+    };
+    Ok(ParsedHeader::Complete {
+        len: end + 2,
+        source,
+    })
+}
+
+fn parse_v2(buf: &[u8]) -> Result<ParsedHeader> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(ParsedHeader::Incomplete);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0xf;
+    let family = buf[13];
+    let addrs_len = (buf[14] as usize) << 8 | buf[15] as usize;
+    if version != 2 {
+        return Err(invalid("unknown v2 version"));
+    }
+    let len = V2_HEADER_LEN + addrs_len;
+    if buf.len() < len {
+        return Ok(ParsedHeader::Incomplete);
+    }
+    let addrs = &buf[V2_HEADER_LEN..len];
+
+    let source = match (command, family) {
+        // LOCAL, made by the load balancer itself
+        (0, _) => None,
+        // PROXY over TCP on IPv4
+        (1, 0x11) => {
+            if addrs.len() < 12 {
+                return Err(invalid("v2 IPv4 addresses are truncated"));
+            }
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = (addrs[8] as u16) << 8 | addrs[9] as u16;
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // PROXY over TCP on IPv6
+        (1, 0x21) => {
+            if addrs.len() < 36 {
+                return Err(invalid("v2 IPv6 addresses are truncated"));
+            }
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = (addrs[32] as u16) << 8 | addrs[33] as u16;
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        // Other families, e.g. unix sockets, have no address that is worth using
+        (1, _) => None,
+        _ => return Err(invalid("unknown v2 command")),
+    };
+    Ok(ParsedHeader::Complete { len, source })
+}
+
+/// Reads the PROXY protocol header that `sock` starts with, as `mode` allows. Returns the source
+/// address of the header, if there is one, and the rest of the connection.
+pub fn read_proxy_header<S>(
+    sock: S,
+    mode: ProxyProtocol,
+) -> BoxFuture<(Option<SocketAddr>, Rewind<S>), Error>
+where
+    S: AsyncRead + Send + 'static,
+{
+    if mode == ProxyProtocol::Disabled {
+        return future::ok((None, Rewind::new(sock, Vec::new()))).boxify();
+    }
+
+    loop_fn((sock, Vec::new()), move |(sock, mut buf)| {
+        match try_boxfuture!(parse_header(&buf)) {
+            ParsedHeader::Complete { len, source } => {
+                let rest = buf.split_off(len);
+                return future::ok(Loop::Break((source, Rewind::new(sock, rest)))).boxify();
+            }
+            ParsedHeader::Absent => {
+                if mode == ProxyProtocol::Required {
+                    return future::err(ErrorKind::ProxyHeaderMissing.into()).boxify();
+                }
+                return future::ok(Loop::Break((None, Rewind::new(sock, buf)))).boxify();
+            }
+            ParsedHeader::Incomplete => {}
+        }
+
+        read(sock, vec![0; READ_CHUNK_SIZE])
+            .from_err()
+            .and_then(move |(sock, chunk, len)| {
+                if len == 0 {
+                    return Err(ErrorKind::ProxyHeaderTruncated.into());
+                }
+                buf.extend_from_slice(&chunk[..len]);
+                Ok(Loop::Continue((sock, buf)))
+            })
+            .boxify()
+    }).boxify()
+}
+
+/// A stream that gives back the bytes that were read ahead of it before its own bytes
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    fn new(inner: S, prefix: Vec<u8>) -> Self {
+        Rewind {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: Read> Read for Rewind<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let len = cmp::min(buf.len(), self.prefix.len() - self.pos);
+            buf[..len].copy_from_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            return Ok(len);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Rewind<S> {}
+
+impl<S: Write> Write for Rewind<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Rewind<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use tokio_io::io::read_to_end;
+
+    const TLS_HELLO: &[u8] = b"\x16\x03\x01 client hello";
+
+    /// A connection that gives one byte per read, like a header that arrives in many packets
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = cmp::min(buf.len(), 1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    impl AsyncRead for Trickle {}
+
+    fn v2_header(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.push((addrs.len() >> 8) as u8);
+        header.push(addrs.len() as u8);
+        header.extend_from_slice(addrs);
+        header
+    }
+
+    fn accept<S>(sock: S, mode: ProxyProtocol) -> Result<(Option<SocketAddr>, Vec<u8>)>
+    where
+        S: AsyncRead + Send + 'static,
+    {
+        let (source, rest) = read_proxy_header(sock, mode).wait()?;
+        let (_, rest) = read_to_end(rest, Vec::new()).wait()?;
+        Ok((source, rest))
+    }
+
+    fn connection(header: &[u8]) -> Cursor<Vec<u8>> {
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(TLS_HELLO);
+        Cursor::new(bytes)
+    }
+
+    #[test]
+    fn test_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        let (source, rest) = accept(connection(header), ProxyProtocol::Required).unwrap();
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, TLS_HELLO);
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let (source, _) = accept(connection(header), ProxyProtocol::Optional).unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let header = b"PROXY UNKNOWN\r\n";
+        let (source, rest) = accept(connection(header), ProxyProtocol::Required).unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, TLS_HELLO);
+
+        for invalid in &[
+            &b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\n"[..],
+            &b"PROXY TCP4 192.0.2.1 192.0.2.2 port 443\r\n"[..],
+            &b"PROXY UDP4 192.0.2.1 192.0.2.2 56324 443\r\n"[..],
+        ] {
+            assert!(accept(connection(invalid), ProxyProtocol::Required).is_err());
+        }
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut addrs = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addrs.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let header = v2_header(1, 0x11, &addrs);
+        let sock = Trickle(connection(&header));
+        let (source, rest) = accept(sock, ProxyProtocol::Required).unwrap();
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, TLS_HELLO);
+
+        let mut addrs = Ipv6Addr::from_str("2001:db8::1").unwrap().octets().to_vec();
+        addrs.extend_from_slice(&Ipv6Addr::from_str("2001:db8::2").unwrap().octets());
+        addrs.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let header = v2_header(1, 0x21, &addrs);
+        let (source, _) = accept(connection(&header), ProxyProtocol::Required).unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // A health check of the load balancer
+        let header = v2_header(0, 0, &[]);
+        let (source, rest) = accept(connection(&header), ProxyProtocol::Required).unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, TLS_HELLO);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let header = b"PROXY TCP4 192.0.2.1";
+        match accept(Cursor::new(header.to_vec()), ProxyProtocol::Required) {
+            Err(err) => match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::ProxyHeaderTruncated) => {}
+                other => panic!("unexpected error: {:?}", other),
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let header = v2_header(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1]);
+        let truncated = &header[..header.len() - 4];
+        assert!(accept(Cursor::new(truncated.to_vec()), ProxyProtocol::Required).is_err());
+
+        // The addresses are shorter than their family needs
+        let header = v2_header(1, 0x11, &[192, 0, 2, 1]);
+        assert!(accept(connection(&header), ProxyProtocol::Required).is_err());
+
+        let header = vec![b'P'; V1_MAX_LEN];
+        let header = [&b"PROXY "[..], &header[..]].concat();
+        assert!(accept(connection(&header), ProxyProtocol::Required).is_err());
+    }
+
+    #[test]
+    fn test_missing() {
+        match accept(connection(b""), ProxyProtocol::Required) {
+            Err(err) => match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::ProxyHeaderMissing) => {}
+                other => panic!("unexpected error: {:?}", other),
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Connections without a header are served as they are, with the peer address
+        let (source, rest) = accept(connection(b""), ProxyProtocol::Optional).unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, TLS_HELLO);
+
+        // A header is not looked for unless the PROXY protocol is enabled
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        let (source, rest) = accept(connection(header), ProxyProtocol::Disabled).unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, [&header[..], TLS_HELLO].concat());
+    }
+}
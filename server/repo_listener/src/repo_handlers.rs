@@ -4,36 +4,73 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 
 use failure::prelude::*;
 use futures::{future, Future};
 use futures_ext::{BoxFuture, FutureExt};
 use slog::Logger;
-use sql::myrouter;
 
+use bookmark_snapshots::bookmark_snapshots;
+use bookmarks::BookmarkIntents;
 use cache_warmup::cache_warmup;
+use derived_data::DerivationQueue;
 use hooks::{HookManager, hook_loader::load_hooks};
 use mercurial_types::RepositoryId;
+use metaconfig::check_repo_names;
 use metaconfig::repoconfig::{RepoConfig, RepoType};
-use ready_state::ReadyStateBuilder;
-use repo_client::{open_blobrepo, streaming_clone, MononokeRepo};
+use ready_state::{ReadyProgress, ReadyStateBuilder};
+use repo_client::{open_blobrepo_async, open_bookmark_intent_store, open_bookmark_snapshot_index,
+                  open_cross_repo_index, open_derived_data_status, open_push_journal,
+                  open_repo_flag_overrides, streaming_clone, BookmarkMoves, FilePrefetcher,
+                  MononokeRepo, OpenRepoParams, PrefetchBudget, PushAdvisory, RequestMirror,
+                  RuntimeRepoFlags, WriteForwarder};
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 
+use idle_repos::{BackgroundTasks, IdleRepo, RepoOpener, SystemClock};
+
+/// How long a change of a flag override can take to apply to a repo
+const REPO_FLAGS_TTL_SECS: u64 = 10;
+
 #[derive(Clone, Debug)]
 pub struct RepoHandler {
     pub logger: Logger,
     pub scuba: ScubaSampleBuilder,
-    pub repo: MononokeRepo,
+    pub repo: IdleRepo<MononokeRepo>,
+    /// Name of the repo in the config. Clients may have asked for it by one of its aliases.
+    pub reponame: String,
+    /// Sent to clients that ask for the repo by a deprecated alias
+    pub deprecation_notice: Option<String>,
+    /// If set, only these users can use the repo
+    pub allowed_identities: Option<Arc<HashSet<String>>>,
+}
+
+impl RepoHandler {
+    /// Whether a client that identifies as `user` can use the repo, whether it comes over
+    /// wireproto or over the repo service
+    pub fn allows(&self, user: Option<&str>) -> bool {
+        match (&self.allowed_identities, user) {
+            (None, _) => true,
+            (Some(allowed), Some(user)) => allowed.contains(user),
+            (Some(_), None) => false,
+        }
+    }
 }
 
 pub fn repo_handlers(
     repos: impl IntoIterator<Item = (String, RepoConfig)>,
     myrouter_port: Option<u16>,
+    open_params: OpenRepoParams,
     root_log: &Logger,
     ready: &mut ReadyStateBuilder,
 ) -> BoxFuture<HashMap<String, RepoHandler>, Error> {
+    let repos: Vec<_> = repos.into_iter().collect();
+    try_boxfuture!(check_repo_names(
+        repos.iter().map(|(reponame, config)| (reponame, config))
+    ));
+
     // compute eagerly to avoid lifetime issues
     let repos: Vec<_> = repos
         .into_iter()
@@ -48,85 +85,312 @@ pub fn repo_handlers(
                 root_log,
                 "Start warming for repo {}, type {:?}", reponame, config.repotype
             );
-            let ensure_myrouter_ready = match config.get_db_address() {
-                None => future::ok(()).left_future(),
-                Some(db_address) => {
-                    let myrouter_port = try_boxfuture!(myrouter_port.ok_or_else(|| format_err!(
-                        "No port for MyRouter provided, but repo {} needs to connect do db {}",
-                        reponame,
-                        db_address
-                    )));
-                    myrouter::wait_for_myrouter(myrouter_port, db_address).right_future()
-                }
+            let ready_handle = ready.create_handle(reponame.as_ref());
+            let warmup_progress = ready_handle.progress();
+
+            let listen_log = root_log.new(o!("repo" => reponame.clone()));
+            let mut scuba_logger = ScubaSampleBuilder::with_opt_table(config.scuba_table.clone());
+            scuba_logger.add_common_server_data();
+
+            // Opens the repo again after it was reclaimed for being idle
+            let opener: RepoOpener<MononokeRepo> = {
+                cloned!(root_log, reponame, config);
+                Arc::new(move || {
+                    open_repo(
+                        reponame.clone(),
+                        config.clone(),
+                        myrouter_port,
+                        open_params,
+                        root_log.clone(),
+                        ReadyProgress::default(),
+                    )
+                })
+            };
+            let idle_timeout = if config.always_hot {
+                None
+            } else {
+                open_params.idle_timeout
             };
+            let aliases = config.aliases.clone();
+            let allowed_identities = config
+                .allowed_identities
+                .clone()
+                .map(|identities| Arc::new(identities.into_iter().collect()));
 
-            let ready_handle = ready.create_handle(reponame.as_ref());
+            let initial_open = open_repo(
+                reponame.clone(),
+                config,
+                myrouter_port,
+                open_params,
+                root_log.clone(),
+                warmup_progress,
+            );
+            ready_handle
+                .wait_for(initial_open)
+                .map({
+                    cloned!(root_log);
+                    move |(repo, background)| {
+                        info!(root_log, "Repo warmup for {} complete", reponame);
+                        let repo = IdleRepo::new(
+                            listen_log.clone(),
+                            Arc::new(SystemClock),
+                            idle_timeout,
+                            opener,
+                            repo,
+                            background,
+                        );
+                        let handler = RepoHandler {
+                            logger: listen_log,
+                            scuba: scuba_logger,
+                            repo,
+                            reponame: reponame.clone(),
+                            deprecation_notice: None,
+                            allowed_identities,
+                        };
+                        (reponame, handler, aliases)
+                    }
+                })
+                .boxify()
+        })
+        .collect();
 
-            let logger = root_log.new(o!("repo" => reponame.clone()));
-            let repoid = RepositoryId::new(config.repoid);
-            let blobrepo = try_boxfuture!(open_blobrepo(
-                logger.clone(),
-                config.repotype.clone(),
+    future::join_all(repos)
+        .map(|repos| {
+            // Every alias of a repo gets a copy of its handler, so they share the open repo
+            let mut handlers = HashMap::new();
+            for (reponame, handler, aliases) in repos {
+                for alias in aliases {
+                    let mut alias_handler = handler.clone();
+                    alias_handler.deprecation_notice = alias.deprecation_notice;
+                    handlers.insert(alias.name, alias_handler);
+                }
+                handlers.insert(reponame, handler);
+            }
+            handlers
+        })
+        .boxify()
+}
+
+/// Opens the repo and warms up its caches, and returns it with the tasks that run in the
+/// background while it is open
+fn open_repo(
+    reponame: String,
+    config: RepoConfig,
+    myrouter_port: Option<u16>,
+    open_params: OpenRepoParams,
+    root_log: Logger,
+    warmup_progress: ReadyProgress,
+) -> BoxFuture<(MononokeRepo, BackgroundTasks), Error> {
+    let logger = root_log.new(o!("repo" => reponame.clone()));
+    let repoid = RepositoryId::new(config.repoid);
+    let blobrepo = open_blobrepo_async(
+        logger.clone(),
+        config.repotype.clone(),
+        repoid,
+        myrouter_port,
+        open_params,
+    );
+
+    // The queue resumes the derivations that were pending when the repo was last open
+    let blobrepo = blobrepo.and_then({
+        cloned!(root_log, reponame, config, logger);
+        move |blobrepo| match config.derived_data {
+            Some(ref params) => {
+                info!(
+                    root_log,
+                    "Deriving {} of the changesets that land in {}",
+                    params.types.join(", "),
+                    reponame
+                );
+                let status = try_boxfuture!(open_derived_data_status(&config.repotype));
+                DerivationQueue::open(blobrepo.clone(), status, params, logger)
+                    .map(move |derivation| (blobrepo, Some(derivation)))
+                    .boxify()
+            }
+            None => future::ok((blobrepo, None)).boxify(),
+        }
+    });
+
+    // Overrides that were set while the repo was closed apply from its first request
+    let blobrepo = blobrepo.and_then({
+        cloned!(config, logger);
+        move |(blobrepo, derivation)| {
+            let overrides = try_boxfuture!(open_repo_flag_overrides(&config.repotype));
+            RuntimeRepoFlags::open(
                 repoid,
-                myrouter_port,
-            ));
+                config.flags(),
+                overrides,
+                Duration::from_secs(REPO_FLAGS_TTL_SECS),
+                logger,
+            ).map(move |flags| (blobrepo, derivation, flags))
+                .boxify()
+        }
+    });
 
-            let mut hook_manager = HookManager::new_with_blobrepo(blobrepo.clone(), logger);
+    let repo = blobrepo.and_then({
+        cloned!(root_log, reponame, config, logger);
+        move |(blobrepo, derivation, flags)| -> Result<(MononokeRepo, BackgroundTasks, _)> {
+            let (flags, flags_worker) = flags;
+            let mut hook_manager = HookManager::new_with_blobrepo(blobrepo.clone(), None, logger);
+            hook_manager.set_health_params(config.hook_health);
 
             info!(root_log, "Loading hooks");
-            try_boxfuture!(load_hooks(&mut hook_manager, config.clone()));
+            load_hooks(&mut hook_manager, config.clone())?;
 
             let streaming_clone = match config.repotype {
-                RepoType::BlobManifold(ref args) => Some(try_boxfuture!(streaming_clone(
+                RepoType::BlobManifold(ref args) => Some(streaming_clone(
                     blobrepo.clone(),
                     &args.db_address,
-                    repoid
-                ))),
+                    repoid,
+                )?),
                 _ => None,
             };
 
+            let write_forwarder = match config.write_forwarding {
+                Some(ref params) => {
+                    info!(
+                        root_log,
+                        "Repo {} forwards writes to {}", reponame, params.primary
+                    );
+                    Some(WriteForwarder::new(params.clone())?)
+                }
+                None => None,
+            };
+
+            let request_mirror = match config.mirroring {
+                Some(ref params) => {
+                    info!(
+                        root_log,
+                        "Repo {} mirrors {} of its reads to {}",
+                        reponame,
+                        params.sample_rate,
+                        params.shadow
+                    );
+                    Some(RequestMirror::new(params.clone())?)
+                }
+                None => None,
+            };
+
+            let push_journal = if config.push_journal {
+                info!(root_log, "Pushes to repo {} are journaled", reponame);
+                Some(open_push_journal(&config.repotype)?)
+            } else {
+                None
+            };
+
+            let bookmark_moves = if config.bookmark_move_identities.is_empty() {
+                None
+            } else {
+                info!(
+                    root_log,
+                    "Bookmarks of repo {} can be moved in two phases by {:?}",
+                    reponame,
+                    config.bookmark_move_identities
+                );
+                let intents = BookmarkIntents::new(
+                    blobrepo.get_bookmarks_object(),
+                    open_bookmark_intent_store(&config.repotype)?,
+                );
+                Some(BookmarkMoves::new(
+                    intents,
+                    config.bookmark_move_identities.clone(),
+                ))
+            };
+
+            let cross_repo_index = if config.cross_repo_index {
+                info!(
+                    root_log,
+                    "Changesets of repo {} are recorded in the cross-repo index", reponame
+                );
+                Some(open_cross_repo_index(&config.repotype)?)
+            } else {
+                None
+            };
+
+            let push_advisory = config
+                .push_advisory
+                .clone()
+                .map(|params| PushAdvisory::new(params, reponame.clone()));
+
+            let (derivation_queue, derivation_worker) = match derivation {
+                Some((queue, worker)) => (Some(queue), Some(worker)),
+                None => (None, None),
+            };
+
+            let (file_prefetcher, prefetch_worker) = if config.file_prefetch.on_gettreepack {
+                info!(
+                    root_log,
+                    "Files of the trees of {} that gettreepack serves are prefetched", reponame
+                );
+                let (prefetcher, worker) = FilePrefetcher::new(
+                    blobrepo.clone(),
+                    config.file_prefetch.clone(),
+                    PrefetchBudget::global(),
+                );
+                (Some(prefetcher), Some(worker))
+            } else {
+                (None, None)
+            };
+
+            let snapshots = match config.bookmark_snapshots {
+                Some(params) => Some((params, open_bookmark_snapshot_index(&config.repotype)?)),
+                None => None,
+            };
+
+            info!(root_log, "Repo {} flags: {}", reponame, flags.describe());
+
             let repo = MononokeRepo::new(
                 blobrepo,
                 &config.pushrebase,
+                config.push_limits,
+                config.path_rules.clone(),
+                config.pull_bookmarks.clone(),
+                config.bookmark_creation.clone(),
+                config.fetch_limits,
                 Arc::new(hook_manager),
                 streaming_clone,
+                write_forwarder,
+                request_mirror,
+                flags,
+                push_journal,
+                push_advisory,
+                cross_repo_index,
+                derivation_queue,
+                file_prefetcher,
+                bookmark_moves,
             );
+            let background: BackgroundTasks = derivation_worker
+                .into_iter()
+                .chain(prefetch_worker)
+                .chain(Some(flags_worker))
+                .collect();
+            Ok((repo, background, snapshots))
+        }
+    });
 
-            let listen_log = root_log.new(o!("repo" => reponame.clone()));
-            let mut scuba_logger = ScubaSampleBuilder::with_opt_table(config.scuba_table.clone());
-            scuba_logger.add_common_server_data();
-
-            // TODO (T32873881): Arc<BlobRepo> should become BlobRepo
-            let initial_warmup = ensure_myrouter_ready.and_then({
-                cloned!(reponame, listen_log);
-                let blobrepo = repo.blobrepo().clone();
-                move |()| {
-                    cache_warmup(Arc::new(blobrepo), config.cache_warmup, listen_log)
-                        .chain_err(format!("while warming up cache for repo: {}", reponame))
-                        .from_err()
+    // TODO (T32873881): Arc<BlobRepo> should become BlobRepo
+    repo.and_then(move |(repo, workers, snapshots)| {
+        cache_warmup(
+            Arc::new(repo.blobrepo().clone()),
+            config.cache_warmup,
+            warmup_progress,
+            logger.clone(),
+        ).chain_err(format!("while warming up cache for repo: {}", reponame))
+            .from_err()
+            .map(move |()| {
+                let mut background: BackgroundTasks = vec![];
+                if let Some((params, index)) = snapshots {
+                    info!(
+                        root_log,
+                        "Snapshotting bookmarks of {} every {:?}", reponame, params.interval
+                    );
+                    background.push(
+                        bookmark_snapshots(repo.blobrepo().clone(), index, params, logger)
+                            .boxify(),
+                    );
                 }
-            });
-            ready_handle
-                .wait_for(initial_warmup)
-                .map({
-                    cloned!(root_log);
-                    move |()| {
-                        info!(root_log, "Repo warmup for {} complete", reponame);
-                        (
-                            reponame,
-                            RepoHandler {
-                                logger: listen_log,
-                                scuba: scuba_logger,
-                                repo: repo,
-                            },
-                        )
-                    }
-                })
-                .boxify()
-        })
-        .collect();
-
-    future::join_all(repos)
-        .map(|repos| repos.into_iter().collect())
-        .boxify()
+                background.extend(workers);
+                (repo, background)
+            })
+    }).boxify()
 }
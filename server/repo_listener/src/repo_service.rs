@@ -0,0 +1,413 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Thrift service that gives tools which don't speak wireproto read access to the repos of the
+//! server: resolving bookmarks, describing changesets, reading small files, listing directories
+//! and showing the effective flags of repos. It serves the same repo handles as the wireproto
+//! listener, and applies the same per-repo identity checks.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use bytes::BytesMut;
+use futures::{future, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use futures_stats::Timed;
+use slog::Logger;
+use time_ext::DurationExt;
+use tokio::runtime::{Runtime, TaskExecutor};
+use tracing::{TraceContext, Traced};
+use uuid::Uuid;
+
+use api;
+use api::errors::ErrorKind as ApiErrorKind;
+use bookmarks::Bookmark;
+use context::CoreContext;
+use fb303::fb_status;
+use fb303::server::{make_FacebookService_server, FacebookService};
+use fb303::services::facebook_service::{GetNameExn, GetStatusExn};
+use mercurial_types::{HgChangesetId, MPath, Type};
+use mercurial_types::manifest::Content;
+use metaconfig::RepoFlag;
+use repo_client::MononokeRepo;
+use repo_service_thrift::server::{make_MononokeRepoService_server, MononokeRepoService};
+use repo_service_thrift::services::mononoke_repo_service::{ChangesetInfoExn, ListDirectoryExn,
+                                                           ReadFileExn, RepoFlagsExn,
+                                                           ResolveBookmarkExn};
+use repo_service_thrift::types::{ChangesetInfo, ChangesetInfoParams, DirectoryEntry,
+                                 DirectoryEntryType, ListDirectoryParams, ReadFileParams,
+                                 RepoFlagsParams, RepoRequest, RepoServiceException,
+                                 RepoServiceExceptionKind, ResolveBookmarkParams};
+use scuba_ext::ScubaSampleBuilderExt;
+use srserver::{ThriftExecutor, ThriftServerBuilder};
+
+use errors::*;
+use repo_handlers::RepoHandler;
+
+define_stats! {
+    prefix = "mononoke.repo_service";
+    requests: dynamic_timeseries("{}.{}", (reponame: String, method: &'static str); RATE, SUM),
+    failures: dynamic_timeseries(
+        "{}.{}.failure", (reponame: String, method: &'static str); RATE, SUM),
+    // Requests rejected because the user is not allowed to use the repo
+    denied: dynamic_timeseries("{}.denied", (reponame: String); RATE, SUM),
+    request_ms:
+        histogram(100, 0, 10_000, AVG, SUM, COUNT; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
+}
+
+/// Files larger than this are never sent by `read_file`, whatever size the client accepts
+pub const MAX_READ_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Starts serving the repo service on `addr`, on a thread of its own
+pub fn start_repo_service(
+    logger: Logger,
+    addr: SocketAddr,
+    handlers: Arc<HashMap<String, RepoHandler>>,
+) -> Result<JoinHandle<()>> {
+    // The requests are served on a runtime of their own, so that they can't hold up wireproto
+    let runtime = Runtime::new()?;
+    let executor = ServiceExecutor(runtime.executor());
+    let service = RepoServiceImpl { handlers };
+
+    info!(logger, "Starting repo service at {}", addr);
+    let mut server = ThriftServerBuilder::new()
+        .with_address(&addr.ip().to_string(), addr.port() as i32, false)
+        .map_err(|err| format_err!("cannot bind to {}: {}", addr, err))?
+        .with_tls()
+        .map_err(|err| format_err!("cannot set up tls for {}: {}", addr, err))?
+        .with_factory(executor, move || {
+            cloned!(service);
+            move |proto| {
+                make_MononokeRepoService_server(proto, service.clone(), |proto| {
+                    make_FacebookService_server(proto, FacebookServiceImpl)
+                })
+            }
+        })
+        .build();
+
+    thread::Builder::new()
+        .name("repo_service".to_owned())
+        .spawn(move || {
+            if let Err(err) = server.serve() {
+                crit!(logger, "Repo service stopped: {}", err);
+            }
+            // Dropped only once the server is done with it
+            drop(runtime);
+        })
+        .map_err(Error::from)
+}
+
+#[derive(Clone)]
+struct ServiceExecutor(TaskExecutor);
+
+impl ThriftExecutor for ServiceExecutor {
+    fn spawn<F>(&self, future: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        self.0.spawn(future)
+    }
+}
+
+#[derive(Clone)]
+struct FacebookServiceImpl;
+
+impl FacebookService for FacebookServiceImpl {
+    fn getName(&self) -> BoxFuture<String, GetNameExn> {
+        Ok("Mononoke repo service".to_string())
+            .into_future()
+            .boxify()
+    }
+
+    fn getStatus(&self) -> BoxFuture<fb_status, GetStatusExn> {
+        // The service is only started once all the repos are open
+        Ok(fb_status::ALIVE).into_future().boxify()
+    }
+}
+
+#[derive(Clone)]
+struct RepoServiceImpl {
+    /// The handlers of the wireproto listener, by repo name and alias
+    handlers: Arc<HashMap<String, RepoHandler>>,
+}
+
+impl RepoServiceImpl {
+    /// Runs `method` on the repo of `request` with a context of its own, once the user of the
+    /// request is known to be allowed to use the repo. Every request is counted and logged to
+    /// the scuba table of the repo.
+    fn serve<T, F, Fut>(
+        &self,
+        method: &'static str,
+        request: RepoRequest,
+        f: F,
+    ) -> BoxFuture<T, RepoServiceException>
+    where
+        F: FnOnce(CoreContext<Uuid>, MononokeRepo) -> Fut + Send + 'static,
+        Fut: Future<Item = T, Error = Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let RepoRequest {
+            repo: requested,
+            user,
+        } = request;
+
+        let handler = match self.handlers.get(&requested) {
+            Some(handler) => handler.clone(),
+            None => {
+                return future::err(to_exception(ErrorKind::UnknownRepo(requested).into()))
+                    .boxify()
+            }
+        };
+        let reponame = handler.reponame.clone();
+        STATS::requests.add_value(1, (reponame.clone(), method));
+
+        if !handler.allows(user.as_ref().map(|user| user.as_str())) {
+            STATS::denied.add_value(1, (reponame.clone(),));
+            let err = ErrorKind::PermissionDenied(user, reponame);
+            warn!(handler.logger, "{}", err; "method" => method);
+            return future::err(to_exception(err.into())).boxify();
+        }
+
+        let session = Uuid::new_v4();
+        let trace = TraceContext::new(session, Instant::now());
+        let mut scuba = handler.scuba.clone();
+        scuba
+            .add("canonical_repo", reponame.clone())
+            .add("requested_repo", requested)
+            .add("service_method", method)
+            .add("session_uuid", format!("{}", session));
+        if let Some(ref user) = user {
+            scuba.add("unix_username", user.clone());
+        }
+        let ctxt = CoreContext {
+            session,
+            logger: handler.logger.new(o!(
+                "session_uuid" => format!("{}", session),
+                "service_method" => method,
+            )),
+            scuba: scuba.clone(),
+            trace: trace.clone(),
+            user,
+            source_hostname: None,
+            deadline: None,
+        };
+        let logger = ctxt.logger.clone();
+
+        handler
+            .repo
+            .get()
+            .and_then(move |repo| f(ctxt, repo))
+            .traced(&trace, method, trace_args!())
+            .timed(move |stats, result| {
+                STATS::request_ms.add_value(stats.completion_time.as_millis_unchecked() as i64);
+                scuba.add_future_stats(&stats);
+                match result {
+                    Ok(_) => scuba.log_with_msg("Request finished - Success", None),
+                    Err(err) => {
+                        scuba.log_with_msg("Request finished - Failure", format!("{:#?}", err))
+                    }
+                }
+                scuba.log_with_trace(&trace)
+            })
+            .map_err(move |err| {
+                STATS::failures.add_value(1, (reponame, method));
+                debug!(logger, "Request failed: {}", err);
+                to_exception(err)
+            })
+            .boxify()
+    }
+}
+
+impl MononokeRepoService for RepoServiceImpl {
+    fn resolve_bookmark(
+        &self,
+        params: ResolveBookmarkParams,
+    ) -> BoxFuture<String, ResolveBookmarkExn> {
+        let ResolveBookmarkParams { request, bookmark } = params;
+        self.serve("resolve_bookmark", request, move |_ctxt, repo| {
+            Bookmark::new(&bookmark)
+                .map_err(|_| ApiErrorKind::InvalidInput(bookmark).into())
+                .into_future()
+                .and_then(move |bookmark| {
+                    api::get_changeset_by_bookmark(Arc::new(repo.blobrepo().clone()), bookmark)
+                })
+                .map(|changeset| changeset.to_hex().to_string())
+        }).map_err(ResolveBookmarkExn::e)
+            .boxify()
+    }
+
+    fn changeset_info(
+        &self,
+        params: ChangesetInfoParams,
+    ) -> BoxFuture<ChangesetInfo, ChangesetInfoExn> {
+        let ChangesetInfoParams { request, changeset } = params;
+        self.serve("changeset_info", request, move |_ctxt, repo| {
+            parse_changeset(&changeset)
+                .into_future()
+                .and_then(move |id| {
+                    api::get_changesets_metadata(
+                        Arc::new(repo.blobrepo().clone()),
+                        vec![id],
+                        HashSet::new(),
+                    )
+                })
+                .and_then(move |mut metadata| match metadata.pop() {
+                    Some((id, Some(metadata))) => Ok(ChangesetInfo {
+                        changeset: id.to_hex().to_string(),
+                        author: metadata.author.as_bytes().to_vec(),
+                        date: metadata.date.timestamp_secs(),
+                        tz_offset: metadata.date.tz_offset_secs(),
+                        summary: metadata.summary.as_bytes().to_vec(),
+                        parents: metadata
+                            .p1
+                            .into_iter()
+                            .chain(metadata.p2)
+                            .map(|parent| parent.to_hex().to_string())
+                            .collect(),
+                        changed_files_count: metadata.changed_files_count as i64,
+                    }),
+                    _ => Err(ApiErrorKind::NotFound(changeset).into()),
+                })
+        }).map_err(ChangesetInfoExn::e)
+            .boxify()
+    }
+
+    fn read_file(&self, params: ReadFileParams) -> BoxFuture<Vec<u8>, ReadFileExn> {
+        let ReadFileParams {
+            request,
+            changeset,
+            path,
+            max_size,
+        } = params;
+        self.serve("read_file", request, move |_ctxt, repo| {
+            let max_size = match max_size {
+                Some(max_size) if max_size < 0 => {
+                    let err = ApiErrorKind::InvalidInput(format!("max_size {}", max_size));
+                    return future::err::<Vec<u8>, _>(err.into()).left_future();
+                }
+                Some(max_size) => cmp::min(max_size as u64, MAX_READ_FILE_SIZE),
+                None => MAX_READ_FILE_SIZE,
+            };
+            parse_changeset(&changeset)
+                .and_then(|id| Ok((id, parse_path(&path)?)))
+                .into_future()
+                .and_then(move |(id, path)| {
+                    let repo = Arc::new(repo.blobrepo().clone());
+                    // Fails before copying more than `max_size` bytes into the reply
+                    api::get_file_content_stream(repo, id, path.clone())
+                        .fold(BytesMut::new(), move |mut content, chunk| {
+                            if (content.len() + chunk.len()) as u64 > max_size {
+                                return Err(ErrorKind::FileTooLarge(path.clone(), max_size).into());
+                            }
+                            content.extend_from_slice(&chunk);
+                            Ok::<_, Error>(content)
+                        })
+                })
+                .map(|content| content.to_vec())
+                .right_future()
+        }).map_err(ReadFileExn::e)
+            .boxify()
+    }
+
+    fn list_directory(
+        &self,
+        params: ListDirectoryParams,
+    ) -> BoxFuture<Vec<DirectoryEntry>, ListDirectoryExn> {
+        let ListDirectoryParams {
+            request,
+            changeset,
+            path,
+        } = params;
+        self.serve("list_directory", request, move |_ctxt, repo| {
+            let path = if path.is_empty() {
+                Ok(None)
+            } else {
+                parse_path(&path).map(Some)
+            };
+            parse_changeset(&changeset)
+                .and_then(|id| Ok((id, path?)))
+                .into_future()
+                .and_then(move |(id, path)| {
+                    api::get_content_by_path(Arc::new(repo.blobrepo().clone()), id, path.clone())
+                        .and_then(move |content| match content {
+                            Content::Tree(manifest) => Ok(manifest
+                                .list()
+                                .filter_map(|entry| {
+                                    let name = entry.get_name()?.to_bytes();
+                                    Some(DirectoryEntry {
+                                        name,
+                                        type_: entry_type(entry.get_type()),
+                                    })
+                                })
+                                .collect()),
+                            _ => Err(ErrorKind::NotADirectory(
+                                path.map(|path| path.to_string()).unwrap_or_default(),
+                            ).into()),
+                        })
+                })
+        }).map_err(ListDirectoryExn::e)
+            .boxify()
+    }
+
+    fn repo_flags(
+        &self,
+        params: RepoFlagsParams,
+    ) -> BoxFuture<BTreeMap<String, bool>, RepoFlagsExn> {
+        let RepoFlagsParams { request } = params;
+        self.serve("repo_flags", request, move |_ctxt, repo| {
+            let flags = repo.flags();
+            future::ok(
+                RepoFlag::all()
+                    .iter()
+                    .map(|flag| (flag.as_str().to_string(), flags.get(*flag)))
+                    .collect(),
+            )
+        }).map_err(RepoFlagsExn::e)
+            .boxify()
+    }
+}
+
+fn entry_type(ty: Type) -> DirectoryEntryType {
+    use mercurial_types::FileType;
+
+    match ty {
+        Type::Tree => DirectoryEntryType::Directory,
+        Type::File(FileType::Regular) => DirectoryEntryType::File,
+        Type::File(FileType::Executable) => DirectoryEntryType::Executable,
+        Type::File(FileType::Symlink) => DirectoryEntryType::Symlink,
+    }
+}
+
+fn parse_changeset(changeset: &str) -> Result<HgChangesetId> {
+    HgChangesetId::from_str(changeset)
+        .map_err(|_| ApiErrorKind::InvalidInput(changeset.to_string()).into())
+}
+
+fn parse_path(path: &[u8]) -> Result<MPath> {
+    MPath::new(path)
+        .map_err(|_| ApiErrorKind::InvalidInput(String::from_utf8_lossy(path).into_owned()).into())
+}
+
+fn to_exception(err: Error) -> RepoServiceException {
+    let kind = match (err.downcast_ref::<ErrorKind>(), err.downcast_ref::<ApiErrorKind>()) {
+        (Some(ErrorKind::UnknownRepo(_)), _) => RepoServiceExceptionKind::NotFound,
+        (Some(ErrorKind::PermissionDenied(..)), _) => RepoServiceExceptionKind::PermissionDenied,
+        (Some(ErrorKind::FileTooLarge(..)), _) => RepoServiceExceptionKind::TooLarge,
+        (Some(ErrorKind::NotADirectory(_)), _) => RepoServiceExceptionKind::InvalidInput,
+        (_, Some(ApiErrorKind::NotFound(_))) => RepoServiceExceptionKind::NotFound,
+        (_, Some(ApiErrorKind::InvalidInput(_))) => RepoServiceExceptionKind::InvalidInput,
+        _ => RepoServiceExceptionKind::InternalError,
+    };
+    RepoServiceException {
+        kind,
+        reason: err.to_string(),
+    }
+}
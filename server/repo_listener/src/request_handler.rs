@@ -7,11 +7,13 @@
 use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use dns_lookup::getnameinfo;
 use failure::{SlogKVError, prelude::*};
 use futures::{Future, Sink, Stream};
+use futures_ext::{FutureExt, RetryCounter};
 use futures_stats::Timed;
 use slog::{self, Drain, Level, Logger};
 use slog_kvfilter::KVFilter;
@@ -23,30 +25,36 @@ use tracing::{TraceContext, Traced};
 use uuid::Uuid;
 
 use hgproto::{sshproto, HgProtoHandler};
-use repo_client::RepoClient;
-use scuba_ext::ScubaSampleBuilderExt;
+use repo_client::{MononokeRepo, RepoClient};
+use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use sshrelay::{SenderBytesWrite, Stdio};
 
-use repo_handlers::RepoHandler;
-
-use context::CoreContext;
+use context::{is_deadline_exceeded, CoreContext, Deadline, DEADLINE_PREAMBLE_KEY};
 use hooks::HookManager;
 
+use request_mirroring::SessionMirror;
+
 define_stats! {
     prefix = "mononoke.request_handler";
     wireproto_ms:
         histogram(500, 0, 100_000, AVG, SUM, COUNT; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
+    deprecated_alias_connections: timeseries(RATE, SUM),
+    deadlines_exceeded: timeseries(RATE, SUM),
 }
 
+// Don't wait for more that 15 mins for a request. Deadlines that clients hint are clamped to it.
+const REQUEST_TIMEOUT_SECS: u64 = 15 * 60;
+
+/// Serves a session of a client. If `deprecation_notice` is set, the client asked for the repo
+/// by a deprecated alias, and the notice is sent to it before anything else.
 pub fn request_handler(
-    RepoHandler {
-        logger,
-        scuba,
-        repo,
-    }: RepoHandler,
+    logger: Logger,
+    scuba: ScubaSampleBuilder,
+    repo: MononokeRepo,
     stdio: Stdio,
     addr: SocketAddr,
     hook_manager: Arc<HookManager>,
+    deprecation_notice: Option<String>,
 ) -> impl Future<Item = (), Error = ()> {
     let mut scuba_logger = scuba;
     let Stdio {
@@ -73,7 +81,20 @@ pub fn request_handler(
 
     // Info per wireproto command within this session
     let wireproto_calls = Arc::new(Mutex::new(Vec::new()));
-    let trace = TraceContext::new(session_uuid, Instant::now());
+    let session_start = Instant::now();
+    let trace = TraceContext::new(session_uuid, session_start);
+    let deadline = preamble.misc.get(DEADLINE_PREAMBLE_KEY).and_then(|hint| {
+        Deadline::parse_hint(
+            session_start,
+            hint,
+            Duration::from_secs(REQUEST_TIMEOUT_SECS),
+        )
+    });
+
+    // A sample of the read-only sessions is replayed on the shadow server of the repo, if it has
+    // one, once the client got its response
+    let mirror = SessionMirror::sample(repo.request_mirror(), session_uuid).map(Arc::new);
+    let mirror_log = logger.clone();
 
     // Per-connection logging drain that forks output to normal log and back to client stderr
     let conn_log = {
@@ -113,9 +134,17 @@ pub fn request_handler(
         scuba_logger
             .add_preamble(&preamble)
             .add("client_hostname", client_hostname);
+        if let Some(deadline) = deadline {
+            scuba_logger.add("deadline_ms", deadline.budget().as_millis_unchecked());
+        }
         scuba_logger
     };
 
+    if let Some(notice) = deprecation_notice {
+        STATS::deprecated_alias_connections.add_value(1);
+        warn!(conn_log, "{}", notice; "remote" => "true");
+    }
+
     scuba_logger.log_with_msg("Connection established", None);
 
     let ctxt = CoreContext {
@@ -123,8 +152,20 @@ pub fn request_handler(
         logger: conn_log.clone(),
         scuba: scuba_logger.clone(),
         trace: trace.clone(),
+        user: preamble.misc.get("unix_username").cloned(),
+        source_hostname: preamble.misc.get("source_hostname").cloned(),
+        deadline,
     };
 
+    let stdin = stdin.inspect({
+        cloned!(mirror);
+        move |chunk| {
+            if let Some(ref mirror) = mirror {
+                mirror.record_input(chunk);
+            }
+        }
+    });
+
     // Construct a hg protocol handler
     let proto_handler = HgProtoHandler::new(
         stdin,
@@ -136,47 +177,90 @@ pub fn request_handler(
         hook_manager,
     );
 
+    // Retries of backend operations made while handling this request
+    let retries = RetryCounter::new();
+
+    // An exceeded deadline is an error of the client, not of the server
+    let deadline_exceeded = Arc::new(AtomicBool::new(false));
+
     // send responses back
     let endres = proto_handler
+        .inspect({
+            cloned!(mirror);
+            move |chunk| {
+                if let Some(ref mirror) = mirror {
+                    mirror.record_output(chunk);
+                }
+            }
+        })
         .map_err(Error::from)
         .forward(stdout)
-        .map(|_| ());
+        .map(|_| ())
+        .count_retries(&retries)
+        .map_err({
+            cloned!(deadline_exceeded);
+            move |err| {
+                if is_deadline_exceeded(&err) {
+                    STATS::deadlines_exceeded.add_value(1);
+                    deadline_exceeded.store(true, Ordering::Relaxed);
+                }
+                err
+            }
+        });
 
     // If we got an error at this point, then catch it and print a message
     endres
-        // Don't wait for more that 15 mins for a request
-        .timeout(Duration::from_secs(15 * 60))
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
         .traced(
             &trace,
             "wireproto request",
             trace_args!(),
         )
-        .timed(move |stats, result| {
-            let mut wireproto_calls = wireproto_calls.lock().expect("lock poisoned");
-            let wireproto_calls = mem::replace(&mut *wireproto_calls, Vec::new());
-
-            STATS::wireproto_ms.add_value(stats.completion_time.as_millis_unchecked() as i64);
-            scuba_logger
-                .add_future_stats(&stats)
-                .add("wireproto_commands", wireproto_calls);
-
-            match result {
-                Ok(_) => scuba_logger.log_with_msg("Request finished - Success", None),
-                Err(err) => if err.is_inner() {
-                    scuba_logger.log_with_msg("Request finished - Failure", format!("{:#?}", err));
-                } else if err.is_elapsed() {
-                    scuba_logger.log_with_msg("Request finished - Timeout", None);
-                } else {
-                    scuba_logger.log_with_msg(
-                        "Request finished - Unexpected timer error",
-                        format!("{:#?}", err),
-                    );
-                },
+        .timed({
+            cloned!(deadline_exceeded);
+            move |stats, result| {
+                let mut wireproto_calls = wireproto_calls.lock().expect("lock poisoned");
+                let wireproto_calls = mem::replace(&mut *wireproto_calls, Vec::new());
+
+                // The response was sent, so the client doesn't wait for the mirroring
+                if let (Some(mirror), true) = (mirror, result.is_ok()) {
+                    mirror.spawn_replay(&wireproto_calls, mirror_log, scuba_logger.clone());
+                }
+
+                STATS::wireproto_ms.add_value(stats.completion_time.as_millis_unchecked() as i64);
+                scuba_logger
+                    .add_future_stats(&stats)
+                    .add("wireproto_commands", wireproto_calls)
+                    .add("backend_retries", retries.get());
+
+                match result {
+                    Ok(_) => scuba_logger.log_with_msg("Request finished - Success", None),
+                    Err(err) => if err.is_inner() && deadline_exceeded.load(Ordering::Relaxed) {
+                        scuba_logger.add("error_class", "user").log_with_msg(
+                            "Request finished - Deadline exceeded",
+                            format!("{:#?}", err),
+                        );
+                    } else if err.is_inner() {
+                        scuba_logger
+                            .log_with_msg("Request finished - Failure", format!("{:#?}", err));
+                    } else if err.is_elapsed() {
+                        scuba_logger.log_with_msg("Request finished - Timeout", None);
+                    } else {
+                        scuba_logger.log_with_msg(
+                            "Request finished - Unexpected timer error",
+                            format!("{:#?}", err),
+                        );
+                    },
+                }
+                scuba_logger.log_with_trace(&trace)
             }
-            scuba_logger.log_with_trace(&trace)
         })
         .map_err(move |err| {
-            if err.is_inner() {
+            if err.is_inner() && deadline_exceeded.load(Ordering::Relaxed) {
+                warn!(conn_log, "Deadline exceeded";
+                SlogKVError(err.into_inner().unwrap()),
+                "remote" => "true");
+            } else if err.is_inner() {
                 error!(conn_log, "Command failed";
                 SlogKVError(err.into_inner().unwrap()),
                 "remote" => "true");
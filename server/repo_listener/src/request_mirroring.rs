@@ -0,0 +1,258 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Mirroring of sampled sessions to the shadow server of a repo, see `repo_client::RequestMirror`.
+//! A session is captured while it is served, and only replayed once its response was sent, so
+//! that mirroring never delays the client.
+
+use std::sync::Mutex;
+
+use bytes::{Bytes, BytesMut};
+use failure::Error;
+use futures::{future, Future};
+use slog::Logger;
+use tokio;
+use uuid::Uuid;
+
+use hgproto::CommandClass;
+use repo_client::{RequestMirror, ResponseDigest, ResponseDigester};
+use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
+
+define_stats! {
+    prefix = "mononoke.request_mirroring";
+    mirrored_sessions: timeseries(RATE, SUM),
+    mismatches: timeseries(RATE, SUM),
+    failures: timeseries(RATE, SUM),
+}
+
+/// What the replay of a session on the shadow found
+#[derive(Debug, Eq, PartialEq)]
+pub enum MirrorOutcome {
+    /// The responses were not compared
+    Replayed(ResponseDigest),
+    Match(ResponseDigest),
+    Mismatch {
+        primary: ResponseDigest,
+        shadow: ResponseDigest,
+    },
+}
+
+#[derive(Default)]
+struct CapturedOutput {
+    size: usize,
+    chunks: Vec<Bytes>,
+}
+
+/// A sampled session, captured to be replayed on the shadow
+pub struct SessionMirror {
+    mirror: RequestMirror,
+    session: Uuid,
+    /// The stdin of the session. It is dropped once it is larger than allowed, and the session
+    /// is not mirrored then.
+    input: Mutex<Option<BytesMut>>,
+    /// The response of the session, if it is compared. It is dropped once it is larger than
+    /// allowed, and the session is mirrored without comparing the responses then.
+    output: Mutex<Option<CapturedOutput>>,
+}
+
+impl SessionMirror {
+    /// A mirror of the session, if the repo mirrors its reads and the session is in the sample
+    pub fn sample(mirror: Option<&RequestMirror>, session: Uuid) -> Option<Self> {
+        mirror.filter(|mirror| mirror.sample()).map(|mirror| {
+            let output = if mirror.params().compare {
+                Some(CapturedOutput::default())
+            } else {
+                None
+            };
+            SessionMirror {
+                mirror: mirror.clone(),
+                session,
+                input: Mutex::new(Some(BytesMut::new())),
+                output: Mutex::new(output),
+            }
+        })
+    }
+
+    pub fn record_input(&self, chunk: &Bytes) {
+        let mut input = self.input.lock().expect("lock poisoned");
+        if let Some(mut captured) = input.take() {
+            if captured.len() + chunk.len() <= self.mirror.params().max_request_bytes {
+                captured.extend_from_slice(chunk);
+                *input = Some(captured);
+            }
+        }
+    }
+
+    /// Keeps a reference to a chunk of the response, which is only digested after the session
+    pub fn record_output(&self, chunk: &Bytes) {
+        let mut output = self.output.lock().expect("lock poisoned");
+        if let Some(mut captured) = output.take() {
+            if captured.size + chunk.len() <= self.mirror.params().max_compared_bytes {
+                captured.size += chunk.len();
+                captured.chunks.push(chunk.clone());
+                *output = Some(captured);
+            }
+        }
+    }
+
+    /// Replays the session on the shadow in the background if it only ran the read `commands`.
+    /// Must only be called once the response of the session was sent.
+    pub fn spawn_replay(&self, commands: &[String], logger: Logger, scuba: ScubaSampleBuilder) {
+        if !is_read_only(commands) {
+            return;
+        }
+        let input = match self.input.lock().expect("lock poisoned").take() {
+            Some(input) => input.freeze(),
+            None => return,
+        };
+        let output = self.output.lock().expect("lock poisoned").take();
+        let mirror = self.mirror.clone();
+        let session = self.session;
+
+        tokio::spawn(future::lazy(move || {
+            let shadow = mirror.params().shadow.clone();
+            mirror
+                .replay(session, input)
+                .map(move |digest| outcome(output, digest))
+                .then(move |res| {
+                    log_outcome(&logger, scuba, &shadow, session, res);
+                    Ok::<_, ()>(())
+                })
+        }));
+    }
+}
+
+/// Whether a session ran commands, and none of them was a write. Sessions that ran commands
+/// that are unknown are not read-only either.
+fn is_read_only(commands: &[String]) -> bool {
+    !commands.is_empty()
+        && commands
+            .iter()
+            .all(|command| CommandClass::of_command(command) == Some(CommandClass::Read))
+}
+
+fn outcome(output: Option<CapturedOutput>, shadow: ResponseDigest) -> MirrorOutcome {
+    match output {
+        Some(output) => {
+            let mut digester = ResponseDigester::new();
+            for chunk in output.chunks {
+                digester.update(&chunk);
+            }
+            let primary = digester.finish();
+            if primary == shadow {
+                MirrorOutcome::Match(shadow)
+            } else {
+                MirrorOutcome::Mismatch { primary, shadow }
+            }
+        }
+        None => MirrorOutcome::Replayed(shadow),
+    }
+}
+
+fn log_outcome(
+    logger: &Logger,
+    mut scuba: ScubaSampleBuilder,
+    shadow: &str,
+    session: Uuid,
+    res: Result<MirrorOutcome, Error>,
+) {
+    STATS::mirrored_sessions.add_value(1);
+    let session = format!("{}", session);
+    scuba.add("mirror_shadow", shadow);
+
+    match res {
+        Ok(MirrorOutcome::Replayed(digest)) => {
+            scuba
+                .add("mirror_shadow_bytes", digest.size)
+                .log_with_msg("Mirrored - Replayed", None);
+            info!(logger, "session replayed on {}", shadow; "session_uuid" => session);
+        }
+        Ok(MirrorOutcome::Match(digest)) => {
+            scuba
+                .add("mirror_shadow_bytes", digest.size)
+                .log_with_msg("Mirrored - Match", None);
+            info!(logger, "session replayed on {}: same response", shadow;
+                "session_uuid" => session);
+        }
+        Ok(MirrorOutcome::Mismatch { primary, shadow: digest }) => {
+            STATS::mismatches.add_value(1);
+            scuba
+                .add("mirror_primary_bytes", primary.size)
+                .add("mirror_primary_hash", primary.hash.to_string())
+                .add("mirror_shadow_bytes", digest.size)
+                .add("mirror_shadow_hash", digest.hash.to_string())
+                .log_with_msg("Mirrored - Mismatch", None);
+            warn!(
+                logger,
+                "session replayed on {}: different response, {} bytes instead of {}",
+                shadow,
+                digest.size,
+                primary.size;
+                "session_uuid" => session
+            );
+        }
+        Err(err) => {
+            STATS::failures.add_value(1);
+            scuba.log_with_msg("Mirrored - Failure", format!("{:#?}", err));
+            warn!(logger, "replaying session on {} failed: {}", shadow, err;
+                "session_uuid" => session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn commands(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_read_only() {
+        assert!(is_read_only(&commands(&["hello", "between"])));
+        assert!(is_read_only(&commands(&["heads", "known", "getbundle"])));
+        assert!(!is_read_only(&commands(&[])));
+        assert!(!is_read_only(&commands(&["hello", "unbundle"])));
+        assert!(!is_read_only(&commands(&["commitbookmarkmove"])));
+        assert!(!is_read_only(&commands(&["heads", "unknown"])));
+    }
+
+    fn digest(chunks: &[&str]) -> ResponseDigest {
+        let mut digester = ResponseDigester::new();
+        for chunk in chunks {
+            digester.update(chunk.as_bytes());
+        }
+        digester.finish()
+    }
+
+    fn captured(chunks: &[&str]) -> CapturedOutput {
+        CapturedOutput {
+            size: chunks.iter().map(|chunk| chunk.len()).sum(),
+            chunks: chunks.iter().map(|chunk| Bytes::from(*chunk)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_outcome() {
+        let shadow = digest(&["4\nabcd"]);
+        assert_eq!(
+            outcome(Some(captured(&["4\n", "abcd"])), shadow.clone()),
+            MirrorOutcome::Match(shadow.clone())
+        );
+        assert_eq!(
+            outcome(Some(captured(&["4\n", "abce"])), shadow.clone()),
+            MirrorOutcome::Mismatch {
+                primary: digest(&["4\nabce"]),
+                shadow: shadow.clone(),
+            }
+        );
+        assert_eq!(
+            outcome(None, shadow.clone()),
+            MirrorOutcome::Replayed(shadow)
+        );
+    }
+}
@@ -27,6 +27,7 @@ extern crate blobrepo;
 extern crate bookmarks;
 extern crate cachelib;
 extern crate cmdlib;
+extern crate fd_accounting;
 extern crate mercurial_types;
 extern crate metaconfig;
 extern crate panichandler;
@@ -36,6 +37,7 @@ extern crate repo_listener;
 mod monitoring;
 
 use std::io;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -55,6 +57,8 @@ mod errors {
 }
 use errors::*;
 
+const DEFAULT_FD_WARNING_FRACTION: f64 = 0.8;
+
 fn setup_app<'a, 'b>() -> App<'a, 'b> {
     cmdlib::args::add_cachelib_args(App::new("mononoke server")
         .version("0.0.0")
@@ -68,6 +72,7 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
             <crbook>      -C, --configrepo_book [BOOK]           'config repo bookmark'
 
                           --listening-host-port <PATH>           'tcp address to listen to in format `host:port`'
+                          --service-host-port [ADDR]             'if provided the repo service will listen to this tcp address, in format `host:port`'
 
             -p, --thrift_port [PORT] 'if provided the thrift server will start on this port'
 
@@ -77,6 +82,10 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
 
             -d, --debug                                          'print debug level output'
             --myrouter-port=[PORT]                               'port for local myrouter instance'
+            --repo-open-timeout=[SECS]                           'timeout for opening a repo'
+            --repo-idle-timeout=[SECS]                           'release idle repos after this long'
+            --fd-warning-fraction=[FRACTION]                     'fraction of the open files limit to warn at'
+            --proxy-protocol=[MODE]                              'PROXY protocol headers on connections: off, optional or required'
             "#,
         ),
         false /* hide_advanced_args */
@@ -158,6 +167,14 @@ fn main() {
         let stats_aggregation = stats::schedule_stats_aggregation()
             .expect("failed to create stats aggregation scheduler");
 
+        let fd_warning_fraction = match matches.value_of("fd-warning-fraction") {
+            Some(fraction) => fraction
+                .parse::<f64>()
+                .expect("Provided --fd-warning-fraction is not a number"),
+            None => DEFAULT_FD_WARNING_FRACTION,
+        };
+        fd_accounting::init_fd_warning(root_log, fd_warning_fraction);
+
         let config = get_config(root_log, &matches)?;
         let cert = matches.value_of("cert").unwrap().to_string();
         let private_key = matches.value_of("private_key").unwrap().to_string();
@@ -177,14 +194,27 @@ fn main() {
             None => None,
         };
 
+        let service_addr = matches.value_of("service-host-port").map(|addr| {
+            addr.parse::<SocketAddr>()
+                .expect("Provided --service-host-port is not a host:port address")
+        });
+
+        let proxy_protocol = match matches.value_of("proxy-protocol") {
+            Some(mode) => mode.parse::<repo_listener::ProxyProtocol>()?,
+            None => repo_listener::ProxyProtocol::default(),
+        };
+
         let (repo_listeners, ready) = repo_listener::create_repo_listeners(
             config.repos.into_iter(),
             myrouter_port,
+            cmdlib::args::get_open_repo_params(&matches),
             root_log,
             matches
                 .value_of("listening-host-port")
                 .expect("listening path must be specified"),
             secure_utils::build_tls_acceptor(ssl).expect("failed to build tls acceptor"),
+            proxy_protocol,
+            service_addr,
         );
 
         tracing_fb303::register();
@@ -0,0 +1,73 @@
+#!/usr/bin/env python
+# Copyright (c) 2018-present, Facebook, Inc.
+# All Rights Reserved.
+#
+# This software may be used and distributed according to the terms of the
+# GNU General Public License version 2 or any later version.
+
+"""Records the replies of a stock hg server to conformance test requests.
+
+For every <name>.request in CASES_DIR, sends it to `hg serve --stdio` in REPO and
+writes everything the server replies to <name>.response. To add a case, write its
+request and run this script, e.g.
+
+    printf 'lookup\\nkey 6\\nmaster' > cases/linear/lookup_bookmark.request
+    ./record.py /path/to/reference/repo cases/linear
+"""
+
+from __future__ import print_function
+
+import argparse
+import os
+import subprocess
+import sys
+
+
+def record(hg, repo, request):
+    proc = subprocess.Popen(
+        [hg, "--config", "ui.quiet=true", "-R", repo, "serve", "--stdio"],
+        stdin=subprocess.PIPE,
+        stdout=subprocess.PIPE,
+        stderr=subprocess.PIPE,
+    )
+    # The server exits once stdin is closed, so the reply is everything it writes
+    stdout, stderr = proc.communicate(request)
+    if proc.returncode != 0:
+        raise RuntimeError(
+            "hg serve failed with %d: %s" % (proc.returncode, stderr.decode("utf-8"))
+        )
+    return stdout
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n")[0])
+    parser.add_argument("repo", help="reference repo to serve")
+    parser.add_argument("cases_dir", help="directory of the cases to record")
+    parser.add_argument("--hg", default="hg", help="stock hg to record with")
+    parser.add_argument(
+        "--only", action="append", default=[], help="only record these cases"
+    )
+    args = parser.parse_args()
+
+    names = sorted(
+        name[: -len(".request")]
+        for name in os.listdir(args.cases_dir)
+        if name.endswith(".request")
+    )
+    if args.only:
+        names = [name for name in names if name in args.only]
+
+    for name in names:
+        path = os.path.join(args.cases_dir, name)
+        with open(path + ".request", "rb") as f:
+            request = f.read()
+        response = record(args.hg, args.repo, request)
+        with open(path + ".response", "wb") as f:
+            f.write(response)
+        print("recorded %s (%d bytes)" % (name, len(response)))
+
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())
@@ -0,0 +1,308 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Decodes a bundle2 into what its parts mean, so that bundles that only differ in encoding
+//! details compare equal. Servers are free to chunk payloads, number parts, choose delta bases and
+//! add advisory parameters as they like, so none of these are compared.
+
+use std::collections::BTreeMap;
+use std::str;
+
+use bytes::{Bytes, BytesMut};
+use failure::Result;
+use slog::{Discard, Logger};
+use tokio_io::codec::Decoder;
+
+use mercurial_bundles::changegroup::Part;
+use mercurial_bundles::changegroup::unpacker::{CgUnpacker, CgVersion};
+use mercurial_types::HgNodeHash;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BundlePart {
+    /// Lowercased, because the case of a part type only says whether it is mandatory
+    pub part_type: String,
+    pub mandatory_params: BTreeMap<String, String>,
+    pub payload: PartPayload,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PartPayload {
+    /// Nodes of a changegroup, sorted. Deltas are left out, as the nodes already identify the
+    /// content.
+    Changegroup(Vec<ChangegroupNode>),
+    /// Keys and values of a listkeys part
+    Listkeys(BTreeMap<String, String>),
+    Raw(Bytes),
+}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ChangegroupNode {
+    pub section: String,
+    pub node: HgNodeHash,
+    pub p1: HgNodeHash,
+    pub p2: HgNodeHash,
+    pub linknode: HgNodeHash,
+}
+
+/// Decodes an uncompressed bundle2
+pub fn decode_bundle2(bundle: Bytes) -> Result<Vec<BundlePart>> {
+    let mut reader = Reader { bytes: bundle };
+    if reader.take(4)?.as_ref() != b"HG20" {
+        bail_msg!("not a bundle2");
+    }
+    let params_len = reader.u32()? as usize;
+    let params = reader.take(params_len)?;
+    for param in str::from_utf8(&params)?.split_whitespace() {
+        if param.starts_with("Compression=") && param != "Compression=UN" {
+            bail_msg!("compressed bundles are not supported, record them uncompressed");
+        }
+    }
+
+    let mut parts = vec![];
+    loop {
+        let header_len = reader.u32()? as usize;
+        if header_len == 0 {
+            break;
+        }
+        let mut header = Reader {
+            bytes: reader.take(header_len)?,
+        };
+        let type_len = header.u8()? as usize;
+        let part_type = str::from_utf8(&header.take(type_len)?)?.to_string();
+        let _part_id = header.u32()?;
+        let mandatory_count = header.u8()? as usize;
+        let advisory_count = header.u8()? as usize;
+        let mut sizes = vec![];
+        for _ in 0..mandatory_count + advisory_count {
+            sizes.push((header.u8()? as usize, header.u8()? as usize));
+        }
+        let mut mandatory_params = BTreeMap::new();
+        for (idx, (key_len, value_len)) in sizes.into_iter().enumerate() {
+            let key = str::from_utf8(&header.take(key_len)?)?.to_string();
+            let value = str::from_utf8(&header.take(value_len)?)?.to_string();
+            if idx < mandatory_count {
+                mandatory_params.insert(key, value);
+            }
+        }
+
+        let mut payload = BytesMut::new();
+        loop {
+            let chunk_len = reader.u32()? as i32;
+            if chunk_len == 0 {
+                break;
+            } else if chunk_len < 0 {
+                bail_msg!("interrupted parts are not supported");
+            }
+            payload.extend_from_slice(&reader.take(chunk_len as usize)?);
+        }
+
+        let part_type = part_type.to_lowercase();
+        let payload = decode_payload(&part_type, &mandatory_params, payload)?;
+        parts.push(BundlePart {
+            part_type,
+            mandatory_params,
+            payload,
+        });
+    }
+
+    if !reader.bytes.is_empty() {
+        bail_msg!("{} bytes after the end of the bundle", reader.bytes.len());
+    }
+    Ok(parts)
+}
+
+fn decode_payload(
+    part_type: &str,
+    params: &BTreeMap<String, String>,
+    mut payload: BytesMut,
+) -> Result<PartPayload> {
+    match part_type {
+        "changegroup" => {
+            let version = match params.get("version").map(String::as_str) {
+                None | Some("02") => CgVersion::Cg2Version,
+                Some("03") => CgVersion::Cg3Version,
+                Some(version) => bail_msg!("unsupported changegroup version {}", version),
+            };
+            let mut unpacker = CgUnpacker::new(Logger::root(Discard, o!()), version);
+            let mut nodes = vec![];
+            while let Some(part) = unpacker.decode_eof(&mut payload)? {
+                if let Part::CgChunk(section, chunk) = part {
+                    nodes.push(ChangegroupNode {
+                        section: format!("{:?}", section),
+                        node: chunk.node,
+                        p1: chunk.p1,
+                        p2: chunk.p2,
+                        linknode: chunk.linknode,
+                    });
+                }
+            }
+            nodes.sort();
+            Ok(PartPayload::Changegroup(nodes))
+        }
+        "listkeys" => {
+            let payload = str::from_utf8(&payload)?;
+            let keys = payload
+                .lines()
+                .map(|line| match line.find('\t') {
+                    Some(tab) => Ok((line[..tab].to_string(), line[tab + 1..].to_string())),
+                    None => Err(format_err!("malformed listkeys line {:?}", line)),
+                })
+                .collect::<Result<_>>()?;
+            Ok(PartPayload::Listkeys(keys))
+        }
+        _ => Ok(PartPayload::Raw(payload.freeze())),
+    }
+}
+
+pub fn compare(expected: &[BundlePart], actual: &[BundlePart]) -> Result<()> {
+    let types = |parts: &[BundlePart]| -> Vec<String> {
+        parts.iter().map(|part| part.part_type.clone()).collect()
+    };
+    if types(expected) != types(actual) {
+        bail_msg!(
+            "bundle has parts {:?}, expected {:?}",
+            types(actual),
+            types(expected)
+        );
+    }
+
+    for (expected, actual) in expected.iter().zip(actual) {
+        if expected.mandatory_params != actual.mandatory_params {
+            bail_msg!(
+                "{} part has parameters {:?}, expected {:?}",
+                expected.part_type,
+                actual.mandatory_params,
+                expected.mandatory_params
+            );
+        }
+        if expected.payload != actual.payload {
+            bail_msg!(
+                "{} part has payload {:?}, expected {:?}",
+                expected.part_type,
+                actual.payload,
+                expected.payload
+            );
+        }
+    }
+    Ok(())
+}
+
+struct Reader {
+    bytes: Bytes,
+}
+
+impl Reader {
+    fn take(&mut self, len: usize) -> Result<Bytes> {
+        if self.bytes.len() < len {
+            bail_msg!("bundle is truncated");
+        }
+        Ok(self.bytes.split_to(len))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(bytes
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u32::from(*byte)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A bundle2 with the given parts, each one a type, mandatory parameters and payload chunks
+    fn bundle(parts: &[(&str, &[(&str, &str)], &[&[u8]])]) -> Bytes {
+        fn u32(out: &mut Vec<u8>, value: usize) {
+            out.extend_from_slice(&[
+                (value >> 24) as u8,
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ]);
+        }
+
+        let mut out = b"HG20".to_vec();
+        u32(&mut out, 0);
+        for (id, &(part_type, params, chunks)) in parts.iter().enumerate() {
+            let mut header = vec![part_type.len() as u8];
+            header.extend_from_slice(part_type.as_bytes());
+            u32(&mut header, id);
+            header.extend_from_slice(&[params.len() as u8, 0]);
+            for &(key, value) in params {
+                header.extend_from_slice(&[key.len() as u8, value.len() as u8]);
+            }
+            for &(key, value) in params {
+                header.extend_from_slice(key.as_bytes());
+                header.extend_from_slice(value.as_bytes());
+            }
+            u32(&mut out, header.len());
+            out.extend_from_slice(&header);
+            for chunk in chunks {
+                u32(&mut out, chunk.len());
+                out.extend_from_slice(chunk);
+            }
+            u32(&mut out, 0);
+        }
+        u32(&mut out, 0);
+        Bytes::from(out)
+    }
+
+    // Ends of the changeset, manifest and filelog sections of a changegroup
+    const EMPTY_CHANGEGROUP: &[u8] = &[0; 12];
+
+    #[test]
+    fn test_compare_structurally() {
+        let expected = decode_bundle2(bundle(&[
+            ("CHANGEGROUP", &[("version", "02")], &[EMPTY_CHANGEGROUP]),
+            ("listkeys", &[("namespace", "bookmarks")], &[b"a\t1\nb\t2\n"]),
+        ])).unwrap();
+        assert_eq!(
+            expected[0].payload,
+            PartPayload::Changegroup(vec![])
+        );
+        assert_eq!(
+            expected[1].payload,
+            PartPayload::Listkeys(btreemap!{
+                "a".to_string() => "1".to_string(),
+                "b".to_string() => "2".to_string(),
+            })
+        );
+
+        // Chunking, case of part types and order of keys don't matter
+        let actual = decode_bundle2(bundle(&[
+            (
+                "changegroup",
+                &[("version", "02")],
+                &[&EMPTY_CHANGEGROUP[..5], &EMPTY_CHANGEGROUP[5..]],
+            ),
+            ("LISTKEYS", &[("namespace", "bookmarks")], &[b"b\t2\n", b"a\t1\n"]),
+        ])).unwrap();
+        assert!(compare(&expected, &actual).is_ok());
+
+        let actual = decode_bundle2(bundle(&[
+            ("changegroup", &[("version", "02")], &[EMPTY_CHANGEGROUP]),
+            ("listkeys", &[("namespace", "bookmarks")], &[b"a\t1\n"]),
+        ])).unwrap();
+        assert!(compare(&expected, &actual).is_err());
+
+        let actual = decode_bundle2(bundle(&[
+            ("changegroup", &[("version", "02")], &[EMPTY_CHANGEGROUP]),
+        ])).unwrap();
+        assert!(compare(&expected, &actual).is_err());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let full = bundle(&[("listkeys", &[], &[b"a\t1\n"])]);
+        assert!(decode_bundle2(full.clone()).is_ok());
+        assert!(decode_bundle2(full.slice_to(full.len() - 1)).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::str;
+
+use failure::Result;
+
+/// Parses the reply to `hello` into capability names and values. Capabilities without a value,
+/// like `lookup`, have an empty one.
+pub fn parse_capabilities(reply: &[u8]) -> Result<BTreeMap<String, String>> {
+    let reply = str::from_utf8(reply)?;
+    let line = reply
+        .lines()
+        .find(|line| line.starts_with("capabilities: "))
+        .ok_or_else(|| format_err!("no capabilities in {:?}", reply))?;
+
+    Ok(line["capabilities: ".len()..]
+        .split_whitespace()
+        .map(|cap| match cap.find('=') {
+            Some(eq) => (cap[..eq].to_string(), cap[eq + 1..].to_string()),
+            None => (cap.to_string(), String::new()),
+        })
+        .collect())
+}
+
+/// Parses a list of capability names, one per line. Everything after a '#' is a comment.
+pub fn parse_allowlist(allowlist: &str) -> BTreeSet<String> {
+    allowlist
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+pub fn compare(
+    expected: &BTreeMap<String, String>,
+    actual: &BTreeMap<String, String>,
+    allowlist: &BTreeSet<String>,
+) -> Result<()> {
+    let mut diffs = vec![];
+    for (name, value) in expected {
+        if allowlist.contains(name) {
+            continue;
+        }
+        match actual.get(name) {
+            None => diffs.push(format!("missing capability {}", name)),
+            Some(actual) if actual != value => diffs.push(format!(
+                "capability {} is {:?}, expected {:?}",
+                name, actual, value
+            )),
+            Some(_) => {}
+        }
+    }
+    for name in actual.keys() {
+        if !expected.contains_key(name) && !allowlist.contains(name) {
+            diffs.push(format!("unexpected capability {}", name));
+        }
+    }
+
+    if diffs.is_empty() {
+        Ok(())
+    } else {
+        bail_msg!("{}", diffs.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compare_capabilities() {
+        let expected =
+            parse_capabilities(b"capabilities: lookup known unbundle=HG10GZ,HG10UN batch\n")
+                .unwrap();
+        assert_eq!(expected.get("lookup").map(String::as_str), Some(""));
+        assert_eq!(
+            expected.get("unbundle").map(String::as_str),
+            Some("HG10GZ,HG10UN")
+        );
+
+        let actual =
+            parse_capabilities(b"capabilities: known lookup unbundle=HG10GZ gettreepack\n")
+                .unwrap();
+        let err = compare(&expected, &actual, &BTreeSet::new())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("missing capability batch"), "{}", err);
+        assert!(err.contains("capability unbundle is"), "{}", err);
+        assert!(err.contains("unexpected capability gettreepack"), "{}", err);
+
+        let allowlist = parse_allowlist("# not in Mononoke\nbatch\nunbundle  # compression\n\n");
+        assert!(compare(&expected, &actual, &allowlist).is_err());
+        let allowlist = parse_allowlist("batch\nunbundle\ngettreepack # remotefilelog\n");
+        assert!(compare(&expected, &actual, &allowlist).is_ok());
+    }
+}
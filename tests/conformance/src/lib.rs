@@ -0,0 +1,320 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Wireproto conformance tests: requests recorded against a vanilla hg server are replayed
+//! against `RepoClient`, and its replies are compared with the recorded ones.
+//!
+//! A case is a pair of files in a directory of cases: `<name>.request` is what the client wrote
+//! to the stdin of `hg serve --stdio`, and `<name>.response` is what the server wrote to its
+//! stdout. A `<name>.divergence` file marks a case in which Mononoke is known to reply
+//! differently, and explains why; such cases are replayed but not compared. `record.py` records
+//! the responses for all the requests in a directory.
+//!
+//! Replies are compared exactly, except for:
+//! - `hello`, whose capabilities are compared one by one. Capabilities in the allowlist may be
+//!   missing, extra or have a different value.
+//! - `getbundle`, whose bundle2 is decoded and compared part by part.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+#[cfg(test)]
+#[macro_use]
+extern crate maplit;
+extern crate scuba_ext;
+#[macro_use]
+extern crate slog;
+extern crate tokio_io;
+extern crate tracing;
+extern crate uuid;
+
+extern crate blobrepo;
+extern crate bookmarks;
+extern crate context;
+extern crate hgproto;
+extern crate hooks;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+extern crate metaconfig;
+extern crate repo_client;
+
+mod bundle;
+mod capabilities;
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::{Bytes, BytesMut};
+use failure::{Error, Result};
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use scuba_ext::ScubaSampleBuilder;
+use slog::{Discard, Logger};
+use tracing::TraceContext;
+use uuid::Uuid;
+
+use blobrepo::BlobRepo;
+use bookmarks::{Bookmark, BookmarkPrefix};
+use context::CoreContext;
+use hgproto::{sshproto, HgProtoHandler};
+use hooks::HookManager;
+use mercurial_types::HgChangesetId;
+use metaconfig::{FetchLimits, PullBookmarksParams, RepoFlags};
+use repo_client::{MononokeRepo, RepoClient, RuntimeRepoFlags};
+
+pub use bundle::{decode_bundle2, BundlePart, PartPayload};
+pub use capabilities::{parse_allowlist, parse_capabilities};
+
+/// A recorded request and the reply of the vanilla hg server to it
+#[derive(Clone, Debug)]
+pub struct Case {
+    pub name: String,
+    pub request: Bytes,
+    pub response: Bytes,
+    /// Why Mononoke replies differently, if it is known to
+    pub divergence: Option<String>,
+}
+
+impl Case {
+    /// Name of the command of the request
+    pub fn command(&self) -> String {
+        let end = self.request
+            .iter()
+            .position(|b| *b == b'\n')
+            .unwrap_or(self.request.len());
+        String::from_utf8_lossy(&self.request[..end]).into_owned()
+    }
+}
+
+/// Loads all the cases in `dir`, sorted by name. Fails if a request has no recorded response.
+pub fn load_cases(dir: &Path) -> Result<Vec<Case>> {
+    let mut names = BTreeSet::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "request") {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                names.insert(name.to_string());
+            }
+        }
+    }
+
+    names
+        .into_iter()
+        .map(|name| {
+            let request = read_case_file(dir, &name, "request")?;
+            let response = read_case_file(dir, &name, "response")
+                .map_err(|err| format_err!("no response recorded for {}: {}", name, err))?;
+            let divergence = match read_case_file(dir, &name, "divergence") {
+                Ok(reason) => Some(String::from_utf8_lossy(&reason).trim().to_string()),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            Ok(Case {
+                name,
+                request: Bytes::from(request),
+                response: Bytes::from(response),
+                divergence,
+            })
+        })
+        .collect()
+}
+
+fn read_case_file(dir: &Path, name: &str, ext: &str) -> io::Result<Vec<u8>> {
+    fs::read(dir.join(format!("{}.{}", name, ext)))
+}
+
+/// Replaces the bookmarks of `repo` with `bookmarks`. Repos created by the fixtures have a
+/// bookmark for every commit, while the reference repos that the cases were recorded in only have
+/// the bookmarks that the cases need, and `heads` replies with the bookmarked commits.
+pub fn set_bookmarks(repo: &BlobRepo, bookmarks: &[(&str, HgChangesetId)]) -> Result<()> {
+    let existing: Vec<_> = repo.get_bookmarks_object()
+        .list_by_prefix(&BookmarkPrefix::empty(), &repo.get_repoid())
+        .collect()
+        .wait()?;
+
+    let mut transaction = repo.update_bookmark_transaction();
+    for (bookmark, _) in existing {
+        transaction.force_delete(&bookmark)?;
+    }
+    for &(name, cs_id) in bookmarks {
+        let bcs_id = repo.get_bonsai_from_hg(&cs_id)
+            .wait()?
+            .ok_or_else(|| format_err!("{} is not in the repo", cs_id))?;
+        transaction.force_set(&Bookmark::new(name)?, &bcs_id)?;
+    }
+    if !transaction.commit().wait()? {
+        bail_msg!("failed to set the bookmarks");
+    }
+    Ok(())
+}
+
+/// Sends `request` as the stdin of a new session of `repo`, served with the default config, and
+/// returns what the server writes to stdout. getbundle is served in deterministic mode, so that
+/// replaying the same request gives the same bytes.
+pub fn replay(repo: &BlobRepo, request: Bytes) -> BoxFuture<Bytes, Error> {
+    replay_with_config(repo, request, Default::default(), Default::default())
+}
+
+/// Like `replay`, with `pull_bookmarks` selecting the bookmarks that getbundle sends
+pub fn replay_with_pull_bookmarks(
+    repo: &BlobRepo,
+    request: Bytes,
+    pull_bookmarks: PullBookmarksParams,
+) -> BoxFuture<Bytes, Error> {
+    replay_with_config(repo, request, pull_bookmarks, Default::default())
+}
+
+/// Like `replay`, with `fetch_limits` bounding the fetches that commands have in flight
+pub fn replay_with_fetch_limits(
+    repo: &BlobRepo,
+    request: Bytes,
+    fetch_limits: FetchLimits,
+) -> BoxFuture<Bytes, Error> {
+    replay_with_config(repo, request, Default::default(), fetch_limits)
+}
+
+fn replay_with_config(
+    repo: &BlobRepo,
+    request: Bytes,
+    pull_bookmarks: PullBookmarksParams,
+    fetch_limits: FetchLimits,
+) -> BoxFuture<Bytes, Error> {
+    let logger = Logger::root(Discard, o!());
+    let hook_manager = Arc::new(HookManager::new_with_blobrepo(
+        repo.clone(),
+        None,
+        logger.clone(),
+    ));
+    let mononoke_repo = MononokeRepo::new(
+        repo.clone(),
+        &Default::default(),
+        Default::default(),
+        Default::default(),
+        pull_bookmarks,
+        Default::default(),
+        fetch_limits,
+        hook_manager.clone(),
+        None,
+        None,
+        None,
+        RuntimeRepoFlags::fixed(RepoFlags {
+            deterministic_getbundle: true,
+            ..Default::default()
+        }),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    );
+    let session = Uuid::new_v4();
+    let ctxt = CoreContext {
+        session,
+        logger: logger.clone(),
+        scuba: ScubaSampleBuilder::with_discard(),
+        trace: TraceContext::new(session, Instant::now()),
+        user: None,
+        source_hostname: None,
+        deadline: None,
+    };
+
+    HgProtoHandler::new(
+        stream::once::<_, io::Error>(Ok(request)),
+        RepoClient::new(mononoke_repo, ctxt),
+        sshproto::HgSshCommandDecode,
+        sshproto::HgSshCommandEncode,
+        &logger,
+        Arc::new(Mutex::new(Vec::new())),
+        hook_manager,
+    ).fold(BytesMut::new(), |mut out, bytes| {
+        out.extend_from_slice(&bytes);
+        Ok::<_, Error>(out)
+    })
+        .map(|out| out.freeze())
+        .boxify()
+}
+
+/// Compares the reply of Mononoke to a case with the recorded one. `allowlist` lists the
+/// capabilities in which the replies to `hello` may differ.
+pub fn compare(case: &Case, actual: &Bytes, allowlist: &BTreeSet<String>) -> Result<()> {
+    match case.command().as_str() {
+        "hello" => {
+            let expected = parse_capabilities(&decode_framed(&case.response)?)?;
+            let actual = parse_capabilities(&decode_framed(actual)?)?;
+            capabilities::compare(&expected, &actual, allowlist)
+        }
+        "getbundle" => {
+            let expected = decode_bundle2(case.response.clone())?;
+            let actual = decode_bundle2(actual.clone())?;
+            bundle::compare(&expected, &actual)
+        }
+        _ => {
+            if &case.response == actual {
+                Ok(())
+            } else {
+                bail_msg!(
+                    "expected {:?}, got {:?}",
+                    String::from_utf8_lossy(&case.response),
+                    String::from_utf8_lossy(actual)
+                )
+            }
+        }
+    }
+}
+
+/// Most replies are framed as "len\nvalue"
+fn decode_framed(reply: &Bytes) -> Result<Bytes> {
+    let newline = reply
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(|| format_err!("reply is not framed: {:?}", reply))?;
+    let len: usize = str::from_utf8(&reply[..newline])?.parse()?;
+    let start = newline + 1;
+    if reply.len() != start + len {
+        bail_msg!(
+            "reply has {} bytes, but its frame has {}",
+            reply.len() - start,
+            len
+        );
+    }
+    Ok(reply.slice_from(start))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_framed() {
+        assert_eq!(
+            decode_framed(&Bytes::from("5\nhello")).unwrap().as_ref(),
+            b"hello"
+        );
+        assert!(decode_framed(&Bytes::from("10\nhello")).is_err());
+        assert!(decode_framed(&Bytes::from("hello")).is_err());
+    }
+
+    #[test]
+    fn test_case_command() {
+        let case = Case {
+            name: "lookup_master".to_string(),
+            request: Bytes::from("lookup\nkey 6\nmaster"),
+            response: Bytes::new(),
+            divergence: None,
+        };
+        assert_eq!(case.command(), "lookup");
+    }
+}
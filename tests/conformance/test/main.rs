@@ -0,0 +1,265 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate bytes;
+extern crate futures;
+
+extern crate blobrepo;
+extern crate bookmarks;
+extern crate fixtures;
+extern crate mercurial_types;
+extern crate metaconfig;
+extern crate mononoke_conformance;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use bytes::Bytes;
+use futures::Future;
+
+use blobrepo::BlobRepo;
+use bookmarks::{Bookmark, BookmarkPrefix};
+use fixtures::{linear, merge_uneven};
+use mercurial_types::{Changeset, HgChangesetId, Manifest};
+use metaconfig::{FetchLimits, PullBookmarksFilter, PullBookmarksParams};
+use mononoke_conformance::{compare, decode_bundle2, load_cases, parse_allowlist, replay,
+                           replay_with_fetch_limits, replay_with_pull_bookmarks, set_bookmarks,
+                           PartPayload};
+
+fn cases_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("cases")
+}
+
+/// Replays all the cases in `dir` against `repo`, and fails with all the mismatches
+fn run_cases(repo: &BlobRepo, dir: &Path) {
+    let allowlist = fs::read_to_string(cases_dir().join("capabilities_allowlist"))
+        .expect("failed to read the capabilities allowlist");
+    let allowlist = parse_allowlist(&allowlist);
+    let cases = load_cases(dir).expect("failed to load the cases");
+    assert!(!cases.is_empty(), "no cases in {}", dir.display());
+
+    let mut failures = vec![];
+    for case in cases {
+        let actual = match replay(repo, case.request.clone()).wait() {
+            Ok(actual) => actual,
+            Err(err) => {
+                failures.push(format!("{}: request failed: {}", case.name, err));
+                continue;
+            }
+        };
+        match case.divergence {
+            Some(ref reason) => println!("{}: not compared: {}", case.name, reason),
+            None => if let Err(err) = compare(&case, &actual, &allowlist) {
+                failures.push(format!("{}: {}", case.name, err));
+            },
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_linear() {
+    async_unit::tokio_unit_test(|| {
+        // The reference repo has the history of the linear fixture, and "master" at its head
+        let repo = linear::getrepo(None);
+        let head = HgChangesetId::from_str("79a13814c5ce7330173ec04d279bf95ab3f652fb").unwrap();
+        set_bookmarks(&repo, &[("master", head)]).expect("failed to set the bookmarks");
+
+        run_cases(&repo, &cases_dir().join("linear"));
+    })
+}
+
+/// A getbundle request for the ancestors of `head`, with the bookmarks, as sent over ssh
+fn getbundle_request(head: &str) -> Bytes {
+    let common = "0000000000000000000000000000000000000000";
+    let args = [("heads", head), ("common", common), ("listkeys", "bookmarks")];
+    let mut request = format!("getbundle\n* {}\n", args.len());
+    for &(name, value) in &args {
+        request.push_str(&format!("{} {}\n{}", name, value.len(), value));
+    }
+    Bytes::from(request)
+}
+
+#[test]
+fn test_getbundle_is_reproducible() {
+    async_unit::tokio_unit_test(|| {
+        // Branches of different lengths have changesets of the same generation, which are sent
+        // in the order of their ids in deterministic mode
+        let repo = merge_uneven::getrepo(None);
+        let head = "b47ca72355a0af2c749d45a5689fd5bcce9898c7";
+        let bookmarks = [
+            ("master", HgChangesetId::from_str(head).unwrap()),
+            (
+                "branch",
+                HgChangesetId::from_str("16839021e338500b3cf7c9b871c8a07351697d68").unwrap(),
+            ),
+        ];
+        set_bookmarks(&repo, &bookmarks).expect("failed to set the bookmarks");
+
+        let first = replay(&repo, getbundle_request(head)).wait().unwrap();
+        let second = replay(&repo, getbundle_request(head)).wait().unwrap();
+        assert!(!first.is_empty());
+        assert!(first == second, "the same getbundle returned different bytes");
+    })
+}
+
+/// The bookmarks in the listkeys part of a getbundle reply
+fn listkeys_bookmarks(reply: Bytes) -> BTreeMap<String, String> {
+    let parts = decode_bundle2(reply).expect("the reply is not a bundle2");
+    let listkeys: Vec<_> = parts
+        .into_iter()
+        .filter_map(|part| match part.payload {
+            PartPayload::Listkeys(keys) => Some(keys),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(listkeys.len(), 1, "expected a single listkeys part");
+    listkeys.into_iter().next().unwrap()
+}
+
+#[test]
+fn test_getbundle_excluded_bookmarks() {
+    async_unit::tokio_unit_test(|| {
+        let repo = linear::getrepo(None);
+        let head = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+        let other = "0ed509bf086fadcb8a8a5384dc3b550729b0fc17";
+        set_bookmarks(
+            &repo,
+            &[
+                ("master", HgChangesetId::from_str(head).unwrap()),
+                ("scratch/alice/feature", HgChangesetId::from_str(other).unwrap()),
+                ("scratch/bob/fix", HgChangesetId::from_str(head).unwrap()),
+            ],
+        ).expect("failed to set the bookmarks");
+
+        // Without the filter, every bookmark is sent
+        let reply = replay(&repo, getbundle_request(head)).wait().unwrap();
+        let sent: Vec<_> = listkeys_bookmarks(reply).into_iter().collect();
+        assert_eq!(
+            sent,
+            vec![
+                ("master".to_string(), head.to_string()),
+                ("scratch/alice/feature".to_string(), other.to_string()),
+                ("scratch/bob/fix".to_string(), head.to_string()),
+            ]
+        );
+
+        // With it, the scratch bookmarks are not, even the one at the requested head
+        let params = PullBookmarksParams {
+            exclude_prefixes: vec![BookmarkPrefix::new("scratch/").unwrap()],
+            ..Default::default()
+        };
+        let reply = replay_with_pull_bookmarks(&repo, getbundle_request(head), params)
+            .wait()
+            .unwrap();
+        let sent: Vec<_> = listkeys_bookmarks(reply).into_iter().collect();
+        assert_eq!(sent, vec![("master".to_string(), head.to_string())]);
+    })
+}
+
+#[test]
+fn test_getbundle_filtered_bookmarks() {
+    async_unit::tokio_unit_test(|| {
+        let repo = linear::getrepo(None);
+        let head = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+        let other = "0ed509bf086fadcb8a8a5384dc3b550729b0fc17";
+        set_bookmarks(
+            &repo,
+            &[
+                ("master", HgChangesetId::from_str(head).unwrap()),
+                ("release/1.0", HgChangesetId::from_str(other).unwrap()),
+                ("scratch/alice/feature", HgChangesetId::from_str(other).unwrap()),
+                ("stable", HgChangesetId::from_str(head).unwrap()),
+            ],
+        ).expect("failed to set the bookmarks");
+        let params = PullBookmarksParams {
+            filter: Some(PullBookmarksFilter {
+                names: vec![Bookmark::new("master").unwrap()],
+                ..Default::default()
+            }),
+            exclude_prefixes: vec![BookmarkPrefix::new("scratch/").unwrap()],
+            ..Default::default()
+        };
+
+        // The bookmarks that match the filter are sent, and those at the requested heads unless
+        // they are excluded
+        let reply = replay_with_pull_bookmarks(&repo, getbundle_request(head), params.clone())
+            .wait()
+            .unwrap();
+        let sent: Vec<_> = listkeys_bookmarks(reply).into_iter().collect();
+        assert_eq!(
+            sent,
+            vec![
+                ("master".to_string(), head.to_string()),
+                ("stable".to_string(), head.to_string()),
+            ]
+        );
+
+        let reply = replay_with_pull_bookmarks(&repo, getbundle_request(other), params)
+            .wait()
+            .unwrap();
+        let sent: Vec<_> = listkeys_bookmarks(reply).into_iter().collect();
+        assert_eq!(
+            sent,
+            vec![
+                ("master".to_string(), head.to_string()),
+                ("release/1.0".to_string(), other.to_string()),
+            ]
+        );
+    })
+}
+
+/// A getfiles request for every file of `head`, as sent over ssh
+fn getfiles_request(repo: &BlobRepo, head: &str) -> Bytes {
+    let cs = repo.get_changeset_by_changesetid(&HgChangesetId::from_str(head).unwrap())
+        .wait()
+        .unwrap();
+    let manifest = repo.get_manifest_by_nodeid(cs.manifestid())
+        .wait()
+        .unwrap();
+    let mut request = "getfiles\n".to_string();
+    for entry in manifest.list() {
+        let name = entry.get_name().expect("files have a name");
+        request.push_str(&format!(
+            "{}{}\n",
+            entry.get_hash().into_nodehash(),
+            String::from_utf8_lossy(name.as_bytes())
+        ));
+    }
+    request.push('\n');
+    Bytes::from(request)
+}
+
+#[test]
+fn test_small_fetch_limits() {
+    async_unit::tokio_unit_test(|| {
+        let repo = linear::getrepo(None);
+        let head = "79a13814c5ce7330173ec04d279bf95ab3f652fb";
+        let between = fs::read(cases_dir().join("linear/between_many_pairs.request"))
+            .expect("failed to read the between request");
+        let requests = vec![getfiles_request(&repo, head), Bytes::from(between)];
+
+        // One fetch at a time gives the same replies, in the same order
+        let limits = FetchLimits {
+            getfiles_buffer_size: 1,
+            gettreepack_buffer_size: 1,
+            between_buffer_size: 1,
+        };
+        for request in requests {
+            let expected = replay(&repo, request.clone()).wait().unwrap();
+            let actual = replay_with_fetch_limits(&repo, request, limits)
+                .wait()
+                .unwrap();
+            assert!(!expected.is_empty());
+            assert!(actual == expected, "small fetch limits changed the reply");
+        }
+    })
+}
@@ -20,6 +20,8 @@ function sslcurl {
   curl --cert "$TESTDIR/testcert.crt" --cacert "$TESTDIR/testcert.crt" --key "$TESTDIR/testcert.key" "$@"
 }
 
+# Starts a Mononoke server. MONONOKE_CONFIG_PATH can point it to a config repo other than the
+# default one, e.g. when a test runs more than one server.
 function mononoke {
   export MONONOKE_SOCKET
   MONONOKE_SOCKET=$(get_free_socket)
@@ -28,7 +30,7 @@ function mononoke {
   --cert "$TESTDIR/testcert.crt" \
   --debug \
   --listening-host-port 127.0.0.1:"$MONONOKE_SOCKET" \
-  -P "$TESTTMP/mononoke-config-rocks" \
+  -P "${MONONOKE_CONFIG_PATH:-$TESTTMP/mononoke-config-rocks}" \
    --configrepo_book local_master \
    --do-not-init-cachelib >> "$TESTTMP/mononoke.out" 2>&1 &
   echo $! >> "$DAEMON_PIDS"
@@ -0,0 +1,42 @@
+  $ . $TESTDIR/library.sh
+
+setup repo
+
+  $ hg init repo-hg
+
+Init treemanifest and remotefilelog
+  $ cd repo-hg
+  $ cat >> .hg/hgrc <<EOF
+  > [extensions]
+  > treemanifest=
+  > remotefilelog=
+  > [treemanifest]
+  > server=True
+  > [remotefilelog]
+  > server=True
+  > shallowtrees=True
+  > EOF
+
+  $ echo a > a
+  $ hg add a
+  $ hg ci -ma
+  $ mkdir dir
+  $ echo bb > dir/b
+  $ hg add dir/b
+  $ hg ci -mb
+  $ cd $TESTTMP
+
+blobimport with metrics: the first commit adds a root tree and a file, the second one a root
+tree, a tree and a file
+
+  $ blobimport rocksdb repo-hg/.hg repo --collect-metrics
+  $ grep "metrics of" < $TESTTMP/blobimport.out
+  * INFO metrics of 2 changesets: 2 file entries of 5 bytes, 3 tree entries of 175 bytes (glob)
+  $ grep -c "total time of phases: upload_entries" < $TESTTMP/blobimport.out
+  1
+
+without the flag nothing is logged
+
+  $ blobimport rocksdb repo-hg/.hg repo2
+  $ grep -c "metrics of" < $TESTTMP/blobimport.out
+  1
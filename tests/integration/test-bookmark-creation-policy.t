@@ -0,0 +1,59 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+  $ setup_hg_config_repo
+  $ cd "$TESTTMP/mononoke-config"
+  $ cat >> repos/repo/server.toml <<CONFIG
+  > [bookmark_creation]
+  > prefixes=["release/"]
+  > CONFIG
+  $ commit_and_blobimport_config_repo
+  $ setup_common_hg_configs
+  $ cd $TESTTMP
+
+setup common configuration
+  $ cat >> $HGRCPATH <<EOF
+  > [ui]
+  > ssh="$DUMMYSSH"
+  > EOF
+
+setup repo
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ setup_hg_server
+  $ hg debugdrawdag <<EOF
+  > A
+  > EOF
+
+create master bookmark
+  $ hg bookmark master_bookmark -r tip
+
+blobimport them into Mononoke storage and start Mononoke
+  $ cd ..
+  $ blobimport rocksdb repo-hg/.hg repo
+  $ mononoke
+  $ wait_for_mononoke $TESTTMP/repo
+
+Clone the repo
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo2 --noupdate --config extensions.remotenames= -q
+  $ cd repo2
+  $ setup_hg_client
+  $ enableextension remotenames
+
+A bookmark that the policy doesn't allow can't be created
+  $ hg up -q master_bookmark
+  $ echo feature > feature && hg add -q feature && hg ci -m feature
+  $ hgmn push -r . --to feature --create 2>&1 | grep "^remote: [^ ]" | grep -v DEBG
+  remote: * ERRO Command failed, remote: true, error: Creating bookmark feature is not allowed: only bookmarks matching release/* can be created, root_cause: * (glob)
+  remote: *, backtrace: , session_uuid: * (glob)
+
+A bookmark that matches the policy can be created
+  $ hgmn push -r . --to release/1.0 --create -q
+
+Existing bookmarks can still be moved
+  $ hgmn push -r . --to master_bookmark -q
+
+  $ hgmn pull -q
+  $ hg book --remote
+     default/master_bookmark   1:* (glob)
+     default/release/1.0       1:* (glob)
@@ -0,0 +1,97 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+
+  $ setup_common_config
+
+  $ cd $TESTTMP
+
+setup repo
+
+  $ hginit_treemanifest repo-hg
+  $ cd repo-hg
+  $ echo "a file content" > a
+  $ hg add a
+  $ hg ci -ma
+
+setup master bookmark
+
+  $ hg bookmark master_bookmark -r tip
+
+blobimport
+
+  $ cd $TESTTMP
+  $ blobimport rocksdb repo-hg/.hg repo
+
+setup two repos: one will be used to push from, another will be used
+to pull these pushed commits
+
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo-push
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo-pull
+
+start mononoke
+
+  $ mononoke
+  $ wait_for_mononoke $TESTTMP/repo
+
+Push bookmarks whose names have characters that are special in urls or in the wire protocol
+  $ cd repo-push
+  $ enableextension remotenames
+  $ echo withbook > withbook && hg addremove && hg ci -m withbook
+  adding withbook
+  $ hgmn push --to releases/v1.2 --create
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  pushing rev 11f53bbd855a to destination ssh://user@dummy/repo bookmark releases/v1.2
+  searching for changes
+  exporting bookmark releases/v1.2
+  $ hgmn push --to 100%2F --create
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  pushing rev 11f53bbd855a to destination ssh://user@dummy/repo bookmark 100%2F
+  searching for changes
+  no changes found
+  exporting bookmark 100%2F
+  [1]
+
+Pull the bookmarks
+  $ cd ../repo-pull
+  $ enableextension remotenames
+  $ hgmn pull -q
+  $ hg book --remote
+     default/100%2F            1:11f53bbd855a
+     default/master_bookmark   0:0e7ec5675652
+     default/releases/v1.2     1:11f53bbd855a
+
+Look the bookmarks up
+  $ hgmn id -r releases/v1.2 ssh://user@dummy/repo
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  11f53bbd855a
+  $ hgmn id -r 100%2F ssh://user@dummy/repo
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  11f53bbd855a
+  $ hgmn id -r releases ssh://user@dummy/repo
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  abort: releases not found!
+  [255]
+
+Move and delete a bookmark with a slash
+  $ cd ../repo-push
+  $ echo update > update && hg addremove && hg ci -m update
+  adding update
+  $ hgmn push --to releases/v1.2
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  pushing rev 66b9c137712a to destination ssh://user@dummy/repo bookmark releases/v1.2
+  searching for changes
+  updating bookmark releases/v1.2
+  $ hgmn push --delete releases/v1.2
+  pushing to ssh://user@dummy/repo
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  searching for changes
+  no changes found
+  deleting remote bookmark releases/v1.2
+  [1]
+  $ cd ../repo-pull
+  $ hgmn pull -q
+  devel-warn: applied empty changegroup * (glob)
+  $ hg book --remote
+     default/100%2F            1:11f53bbd855a
+     default/master_bookmark   0:0e7ec5675652
@@ -0,0 +1,56 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+  $ setup_hg_config_repo
+  $ cd "$TESTTMP/mononoke-config"
+  $ cat >> repos/repo/server.toml <<CONFIG
+  > [pull_bookmarks]
+  > exclude_prefixes=["scratch/*"]
+  > CONFIG
+  $ commit_and_blobimport_config_repo
+  $ setup_common_hg_configs
+  $ cd $TESTTMP
+
+setup common configuration
+  $ cat >> $HGRCPATH <<EOF
+  > [ui]
+  > ssh="$DUMMYSSH"
+  > EOF
+
+setup repo
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ setup_hg_server
+  $ hg debugdrawdag <<EOF
+  > B
+  > |
+  > A
+  > EOF
+
+create a regular and a scratch bookmark
+  $ hg bookmark master_bookmark -r B
+  $ hg bookmark scratch/alice -r A
+
+blobimport them into Mononoke storage and start Mononoke
+  $ cd ..
+  $ blobimport rocksdb repo-hg/.hg repo
+  $ mononoke
+  $ wait_for_mononoke $TESTTMP/repo
+
+A pull doesn't get the scratch bookmark
+  $ hg init repo-pull
+  $ cd repo-pull
+  $ setup_hg_client
+  $ enableextension remotenames
+  $ hgmn pull -q
+  $ hg book --remote
+     default/master_bookmark   1:* (glob)
+
+Pushkey can still move it
+  $ hg up -q default/master_bookmark
+  $ echo content > file
+  $ hg add -q file
+  $ hg ci -m 'scratch work'
+  $ hgmn push -q -r . --to scratch/alice
+  $ hgmn debugpushkey ssh://user@dummy/repo bookmarks | grep scratch/alice | cut -f2 > moved
+  $ hg log -r . -T '{node}\n' | diff - moved
@@ -0,0 +1,67 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+  $ setup_hg_config_repo
+  $ cd "$TESTTMP/mononoke-config"
+  $ cat >> repos/repo/server.toml <<CONFIG
+  > [pull_bookmarks]
+  > names=["master_bookmark"]
+  > prefixes=["release/"]
+  > max_count=2
+  > CONFIG
+  $ commit_and_blobimport_config_repo
+  $ setup_common_hg_configs
+  $ cd $TESTTMP
+
+setup common configuration
+  $ cat >> $HGRCPATH <<EOF
+  > [ui]
+  > ssh="$DUMMYSSH"
+  > EOF
+
+setup repo
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ setup_hg_server
+  $ hg debugdrawdag <<EOF
+  > C
+  > |
+  > B
+  > |
+  > A
+  > EOF
+
+create matching and non-matching bookmarks
+  $ hg bookmark master_bookmark -r A
+  $ hg bookmark release/1.0 -r A
+  $ hg bookmark release/2.0 -r B
+  $ hg bookmark scratch/alice -r C
+
+blobimport them into Mononoke storage and start Mononoke
+  $ cd ..
+  $ blobimport rocksdb repo-hg/.hg repo
+  $ mononoke
+  $ wait_for_mononoke $TESTTMP/repo
+
+A pull only gets the matching bookmarks, up to the cap
+  $ hg init repo-pull
+  $ cd repo-pull
+  $ setup_hg_client
+  $ enableextension remotenames
+  $ hgmn pull -q
+  $ hg book --remote
+     default/master_bookmark   0:* (glob)
+     default/release/1.0       0:* (glob)
+
+A bookmark that is pulled by name is always sent
+  $ hgmn pull -q -B scratch/alice
+  $ hg book --remote
+     default/master_bookmark   0:* (glob)
+     default/scratch/alice     2:* (glob)
+
+The listkeys command still sees every bookmark
+  $ hgmn debugpushkey ssh://user@dummy/repo bookmarks | cut -f1
+  master_bookmark
+  release/1.0
+  release/2.0
+  scratch/alice
@@ -0,0 +1,101 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+  $ setup_hg_config_repo
+  $ cd "$TESTTMP/mononoke-config"
+  $ cat >> repos/repo/server.toml <<CONFIG
+  > [path_rules]
+  > forbid_vcs_components=true
+  > forbid_windows_reserved_names=true
+  > max_component_length=32
+  > forbidden_trailing_chars=". "
+  > CONFIG
+  $ commit_and_blobimport_config_repo
+  $ setup_common_hg_configs
+  $ cd $TESTTMP
+
+setup common configuration
+  $ cat >> $HGRCPATH <<EOF
+  > [ui]
+  > ssh="$DUMMYSSH"
+  > EOF
+
+setup repo
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ setup_hg_server
+  $ hg debugdrawdag <<EOF
+  > B
+  > |
+  > A
+  > EOF
+
+create master bookmark
+  $ hg bookmark master_bookmark -r tip
+
+blobimport them into Mononoke storage and start Mononoke
+  $ cd ..
+  $ blobimport rocksdb repo-hg/.hg repo
+  $ mononoke
+  $ wait_for_mononoke $TESTTMP/repo
+
+Clone the repo
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo2 --noupdate --config extensions.remotenames= -q
+  $ cd repo2
+  $ setup_hg_client
+  $ cat >> .hg/hgrc <<EOF
+  > [extensions]
+  > pushrebase =
+  > remotenames =
+  > EOF
+
+A push that adds a file inside a .git directory is rejected
+  $ hg up -q master_bookmark
+  $ mkdir -p a/.git
+  $ echo "[core]" > a/.git/config
+  $ hg add -q a/.git/config
+  $ hg ci -m 'add git config'
+  $ hgmn push -r . --to master_bookmark 2>&1 | grep "^remote: [^ ]" | grep -v DEBG
+  remote: * ERRO Command failed, remote: true, error: Push contains 1 invalid paths: (glob)
+  remote: a/.git/config: '.git' is a reserved VCS directory, root_cause: * (glob)
+  remote: *, backtrace: , session_uuid: * (glob)
+
+All the offending paths are listed
+  $ hg up -q master_bookmark
+  $ mkdir -p "dir."
+  $ echo content > "dir./aux.txt"
+  $ echo content > this_file_name_is_longer_than_the_limit
+  $ hg add -q "dir./aux.txt" this_file_name_is_longer_than_the_limit
+  warning: filename ends with '.', which is not allowed on Windows: dir./aux.txt
+  $ hg ci -m 'add bad paths'
+  $ hgmn push -r . --to master_bookmark 2>&1 | grep "^remote: [^ ]" | grep -v DEBG
+  remote: * ERRO Command failed, remote: true, error: Push contains 3 invalid paths: (glob)
+  remote: dir./aux.txt: component 'dir.' ends with '.'
+  remote: dir./aux.txt: 'aux.txt' is a reserved name on Windows
+  remote: this_file_name_is_longer_than_the_limit: component 'this_file_name_is_longer_than_the_limit' is longer than 32 bytes, root_cause: * (glob)
+  remote: *, backtrace: , session_uuid: * (glob)
+
+A push with valid paths is accepted
+  $ hg up -q master_bookmark
+  $ echo content > valid_file
+  $ hg add -q valid_file
+  $ hg ci -m 'add valid file'
+  $ hgmn push -r . --to master_bookmark -q
+  server ignored bookmark master_bookmark update
+
+Blobimport only validates the paths that a changeset adds or modifies, so removing an invalid
+path is not reported
+  $ cd $TESTTMP
+  $ hg init repo-invalid-paths
+  $ cd repo-invalid-paths
+  $ mkdir -p a/.git
+  $ echo "[core]" > a/.git/config
+  $ hg add -q a/.git/config
+  $ hg ci -m 'add git config'
+  $ hg rm -q a/.git/config
+  $ hg ci -m 'remove git config'
+  $ cd ..
+  $ blobimport rocksdb repo-invalid-paths/.hg repo-invalid-paths --path-violations-as-warnings
+  $ grep "has invalid paths" -A1 < $TESTTMP/blobimport.out
+  * changeset * has invalid paths: (glob)
+  a/.git/config: '.git' is a reserved VCS directory
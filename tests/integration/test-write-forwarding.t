@@ -0,0 +1,104 @@
+  $ . $TESTDIR/library.sh
+
+Both servers serve the same blob:files repo, a RocksDB repo can't be opened by two processes
+
+setup configuration
+  $ setup_common_config "blob:files"
+  $ cd $TESTTMP
+
+setup repo
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ setup_hg_server
+  $ hg debugdrawdag <<EOF
+  > C
+  > |
+  > B
+  > |
+  > A
+  > EOF
+
+create master bookmark
+  $ hg bookmark master_bookmark -r tip
+
+blobimport them into Mononoke storage and start the primary
+  $ cd ..
+  $ blobimport files repo-hg/.hg repo
+  $ mononoke
+  $ wait_for_mononoke $TESTTMP/repo
+  $ PRIMARY_SOCKET=$MONONOKE_SOCKET
+  $ PRIMARY_PID=$(tail -n 1 "$DAEMON_PIDS")
+
+configure the secondary to forward writes to the primary, and start it
+  $ cd mononoke-config
+  $ cat >> repos/repo/server.toml <<CONFIG
+  > [write_forwarding]
+  > primary="127.0.0.1:$PRIMARY_SOCKET"
+  > primary_reponame="repo"
+  > ssl_common_name="localhost"
+  > cert="$TESTDIR/testcert.crt"
+  > private_key="$TESTDIR/testcert.key"
+  > ca_pem="$TESTDIR/testcert.crt"
+  > timeout_secs=60
+  > CONFIG
+  $ hg ci -qm "forward writes"
+  $ hg backfilltree
+  $ cd ..
+  $ blobimport rocksdb mononoke-config/.hg mononoke-config-secondary
+  $ MONONOKE_CONFIG_PATH="$TESTTMP/mononoke-config-secondary" mononoke
+  $ wait_for_mononoke $TESTTMP/repo
+  $ SECONDARY_SOCKET=$MONONOKE_SOCKET
+
+Clone the repo from the secondary
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo2 --noupdate --config extensions.remotenames= -q
+  $ cd repo2
+  $ setup_hg_client
+  $ cat >> .hg/hgrc <<EOF
+  > [extensions]
+  > pushrebase =
+  > remotenames =
+  > EOF
+
+Push to the secondary, the push is applied by the primary
+  $ hg up -q 0
+  $ echo 1 > 1 && hg add 1 && hg ci -m 1
+  $ hgmn push -r . --to master_bookmark
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  pushing rev a0c9c5791058 to destination ssh://user@dummy/repo bookmark master_bookmark
+  searching for changes
+  adding changesets
+  adding manifests
+  adding file changes
+  added 1 changesets with 0 changes to 0 files
+  server ignored bookmark master_bookmark update
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  $ grep -c "forwarding unbundle to 127.0.0.1:$PRIMARY_SOCKET" "$TESTTMP/mononoke.out"
+  1
+
+The pushed commit is visible from the secondary
+  $ hgmn pull -q
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  $ hg log -r default/master_bookmark -T '{desc}\n'
+  1
+
+And from the primary
+  $ cd ..
+  $ MONONOKE_SOCKET=$PRIMARY_SOCKET hgclone_treemanifest ssh://user@dummy/repo-hg repo3 --noupdate --config extensions.remotenames= -q
+  $ hg log -R repo3 -r default/master_bookmark -T '{desc}\n'
+  1
+
+Pushes through the secondary fail once the primary is down
+  $ kill $PRIMARY_PID
+  $ cd repo2
+  $ hg up -q 0
+  $ echo 2 > 2 && hg add 2 && hg ci -m 2
+  $ hgmn push -r . --to master_bookmark > push.out 2>&1
+  [255]
+  $ grep -o "failed to connect to primary 127.0.0.1:$PRIMARY_SOCKET" push.out
+  failed to connect to primary 127.0.0.1:* (glob)
+
+Reads are still served by the secondary
+  $ hgmn pull -q
+  remote: * DEBG Session with Mononoke started with uuid: * (glob)
+  $ hg log -r default/master_bookmark -T '{desc}\n'
+  1
@@ -0,0 +1,401 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A minimal wireproto client. Every request is sent in its own session, and the session ends
+//! once the client is done writing, so the reply is everything the server writes until then.
+
+use std::net::SocketAddr;
+use std::str::{self, FromStr};
+
+use bytes::{Bytes, BytesMut};
+use failure::{Error, Result};
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use openssl::ssl::{SslConnector, SslMethod};
+use tokio::net::TcpStream;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{FramedRead, FramedWrite};
+use tokio_openssl::SslConnectorExt;
+use uuid::Uuid;
+
+use mercurial_types::{HgChangesetId, HgManifestId};
+use secure_utils::{build_identity, read_x509};
+use sshrelay::{Preamble, SshDecoder, SshEncoder, SshMsg, SshStream};
+
+use {TestCerts, TEST_COMMON_NAME};
+
+/// Everything the server wrote in a session
+#[derive(Clone, Debug, Default)]
+pub struct SessionOutput {
+    pub stdout: Bytes,
+    pub stderr: Bytes,
+}
+
+/// TLS settings of a client. `TestClient::new` uses the test certificate, which is also the CA,
+/// but a client of another server, e.g. a staging host, needs its own.
+#[derive(Clone, Debug)]
+pub struct ClientTls {
+    pub cert: String,
+    pub private_key: String,
+    pub ca_pem: String,
+    /// Name that the certificate of the server is issued for
+    pub common_name: String,
+}
+
+impl<'a> From<&'a TestCerts> for ClientTls {
+    fn from(certs: &'a TestCerts) -> Self {
+        ClientTls {
+            cert: certs.cert.clone(),
+            private_key: certs.private_key.clone(),
+            ca_pem: certs.cert.clone(),
+            common_name: TEST_COMMON_NAME.to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TestClient {
+    addr: SocketAddr,
+    reponame: String,
+    connector: SslConnector,
+    common_name: String,
+    user: Option<String>,
+}
+
+impl TestClient {
+    pub fn new(addr: SocketAddr, reponame: &str, certs: &TestCerts) -> Result<Self> {
+        Self::with_tls(addr, reponame, &ClientTls::from(certs))
+    }
+
+    /// A client of the repo `reponame` of any server listening on `addr`
+    pub fn with_tls(addr: SocketAddr, reponame: &str, tls: &ClientTls) -> Result<Self> {
+        let connector = {
+            let mut connector = SslConnector::builder(SslMethod::tls())?;
+            let pkcs12 = build_identity(tls.cert.clone(), tls.private_key.clone())?;
+            connector.set_certificate(&pkcs12.cert)?;
+            connector.set_private_key(&pkcs12.pkey)?;
+            connector.cert_store_mut().add_cert(read_x509(&tls.ca_pem)?)?;
+            connector.build()
+        };
+
+        Ok(TestClient {
+            addr,
+            reponame: reponame.to_string(),
+            connector,
+            common_name: tls.common_name.clone(),
+            user: None,
+        })
+    }
+
+    /// The same client, identifying as `user` in the preamble of its sessions
+    pub fn as_user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Sends `input` as the stdin of a new session and collects what the server writes until it
+    /// ends the session
+    pub fn request(&self, input: Bytes) -> BoxFuture<SessionOutput, Error> {
+        let preamble = Preamble::new(
+            self.reponame.clone(),
+            Uuid::new_v4(),
+            self.user.clone(),
+            None,
+        );
+        let connector = self.connector.clone();
+        let common_name = self.common_name.clone();
+
+        TcpStream::connect(&self.addr)
+            .from_err::<Error>()
+            .and_then(move |socket| {
+                connector
+                    .connect_async(&common_name, socket)
+                    .map_err(|err| format_err!("tls handshake failed: {}", err))
+            })
+            .and_then(move |socket| {
+                let (socket_read, socket_write) = socket.split();
+                let rx = FramedRead::new(socket_read, SshDecoder::new());
+                let tx = FramedWrite::new(socket_write, SshEncoder::new());
+
+                let send = stream::iter_ok(vec![
+                    SshMsg::new(SshStream::Preamble(preamble), Bytes::new()),
+                    SshMsg::new(SshStream::Stdin, input),
+                ]).forward(tx)
+                    .map(|_| ());
+
+                let recv = rx.from_err().fold(
+                    (BytesMut::new(), BytesMut::new()),
+                    |(mut stdout, mut stderr), msg| {
+                        match msg.stream() {
+                            SshStream::Stdout => stdout.extend_from_slice(msg.as_ref()),
+                            SshStream::Stderr => stderr.extend_from_slice(msg.as_ref()),
+                            bad => bail_msg!("unexpected stream {:?}", bad),
+                        }
+                        Ok((stdout, stderr))
+                    },
+                );
+
+                send.join(recv).map(|((), (stdout, stderr))| SessionOutput {
+                    stdout: stdout.freeze(),
+                    stderr: stderr.freeze(),
+                })
+            })
+            .boxify()
+    }
+
+    /// Capabilities of the server, as sent in the reply to `hello`
+    pub fn hello(&self) -> BoxFuture<Bytes, Error> {
+        self.request(encode_command("hello", &[]))
+            .and_then(|output| decode_framed(&output))
+            .boxify()
+    }
+
+    pub fn heads(&self) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        self.request(encode_command("heads", &[]))
+            .and_then(|output| decode_framed(&output))
+            .and_then(|reply| {
+                str::from_utf8(&reply)?
+                    .split_whitespace()
+                    .map(HgChangesetId::from_str)
+                    .collect()
+            })
+            .boxify()
+    }
+
+    /// Ancestors of `top` at distance 1, 2, 4, 8, ... from it, that are descendants of `bottom`
+    pub fn between(
+        &self,
+        top: HgChangesetId,
+        bottom: HgChangesetId,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        let pair = format!("{}-{}", top.to_hex(), bottom.to_hex());
+        self.request(encode_command("between", &[("pairs", Bytes::from(pair))]))
+            .and_then(|output| decode_framed(&output))
+            .and_then(|reply| {
+                str::from_utf8(&reply)?
+                    .split_whitespace()
+                    .map(HgChangesetId::from_str)
+                    .collect()
+            })
+            .boxify()
+    }
+
+    /// Resolves `key`, e.g. a bookmark or a hash prefix, to a changeset
+    pub fn lookup(&self, key: &str) -> BoxFuture<HgChangesetId, Error> {
+        let key = key.to_string();
+        self.request(encode_command("lookup", &[("key", Bytes::from(key.as_bytes()))]))
+            .and_then(|output| decode_framed(&output))
+            .and_then(move |reply| {
+                let reply = str::from_utf8(&reply)?.trim();
+                if reply.starts_with("1 ") {
+                    HgChangesetId::from_str(&reply[2..])
+                } else {
+                    bail_msg!("lookup of {} failed: {}", key, reply)
+                }
+            })
+            .boxify()
+    }
+
+    /// Whether each tree of `nodes`, given with its directory, is still the tree at that
+    /// directory in `bookmark`
+    pub fn knowntrees(
+        &self,
+        bookmark: &str,
+        nodes: &[(&str, HgManifestId)],
+    ) -> BoxFuture<Vec<bool>, Error> {
+        let nodes: Vec<_> = nodes
+            .iter()
+            .map(|&(dir, node)| format!("{}{}", node.to_hex(), escape_batch(dir)))
+            .collect();
+        let args = [
+            ("bookmark", Bytes::from(bookmark)),
+            ("nodes", Bytes::from(nodes.join(","))),
+        ];
+        self.request(encode_command("knowntrees", &args))
+            .and_then(|output| decode_framed(&output))
+            .map(|reply| reply.iter().map(|known| *known == b'1').collect())
+            .boxify()
+    }
+
+    /// Prepares a move of `bookmark` from `old`, `None` if it doesn't exist, to `new`. Returns
+    /// the reply of the server: "1 <token>" if the move was prepared, "0 <error>" otherwise.
+    pub fn preparebookmarkmove(
+        &self,
+        bookmark: &str,
+        old: Option<HgChangesetId>,
+        new: HgChangesetId,
+        ttl: u64,
+    ) -> BoxFuture<String, Error> {
+        let old = old.map(|old| old.to_hex().to_string()).unwrap_or_default();
+        let args = [
+            ("key", Bytes::from(bookmark)),
+            ("old", Bytes::from(old)),
+            ("new", Bytes::from(new.to_hex().to_string())),
+            ("ttl", Bytes::from(ttl.to_string())),
+        ];
+        self.bookmark_move_request(encode_command("preparebookmarkmove", &args))
+    }
+
+    /// Commits the move prepared under `token`. Returns the reply of the server, "1 committed"
+    /// if the bookmark moved.
+    pub fn commitbookmarkmove(&self, token: &str) -> BoxFuture<String, Error> {
+        let args = [("token", Bytes::from(token))];
+        self.bookmark_move_request(encode_command("commitbookmarkmove", &args))
+    }
+
+    fn bookmark_move_request(&self, input: Bytes) -> BoxFuture<String, Error> {
+        self.request(input)
+            .and_then(|output| decode_framed(&output))
+            .and_then(|reply| Ok(str::from_utf8(&reply)?.trim().to_string()))
+            .boxify()
+    }
+
+    /// Raw bundle2 that the server sends in reply to `getbundle`
+    pub fn getbundle(
+        &self,
+        heads: &[HgChangesetId],
+        common: &[HgChangesetId],
+        listkeys: &[&str],
+    ) -> BoxFuture<Bytes, Error> {
+        let hashes = |ids: &[HgChangesetId]| {
+            let hex: Vec<_> = ids.iter().map(|id| id.to_hex().to_string()).collect();
+            Bytes::from(hex.join(" "))
+        };
+        let args = [
+            ("heads", hashes(heads)),
+            ("common", hashes(common)),
+            ("bundlecaps", Bytes::from("HG20")),
+            ("listkeys", Bytes::from(listkeys.join(","))),
+        ];
+
+        self.request(encode_star_command("getbundle", &args))
+            .and_then(|output| {
+                if output.stdout.is_empty() {
+                    Err(server_error(&output))
+                } else {
+                    Ok(output.stdout)
+                }
+            })
+            .boxify()
+    }
+
+    /// Pushes `bundle`, a bundle2 as created by `hg bundle` or read from a fixture, and returns
+    /// the bundle2 reply of the server. The push is forced, i.e. the server doesn't check that
+    /// its heads didn't change.
+    pub fn unbundle(&self, bundle: Bytes) -> BoxFuture<Bytes, Error> {
+        let mut input = BytesMut::from(encode_command(
+            "unbundle",
+            &[("heads", Bytes::from("666f726365"))],
+        ));
+        input.extend_from_slice(format!("{}\n", bundle.len()).as_bytes());
+        input.extend_from_slice(&bundle);
+        input.extend_from_slice(b"0\n");
+
+        self.request(input.freeze())
+            .and_then(|output| {
+                // The server asks for the bundle with "0\n" and replies with an unframed bundle2
+                if output.stdout.starts_with(b"0\n") && output.stdout.len() > 2 {
+                    Ok(output.stdout.slice_from(2))
+                } else {
+                    Err(server_error(&output))
+                }
+            })
+            .boxify()
+    }
+}
+
+// Request format:
+// command\n
+// (argname len\nvalue)*
+fn encode_command(command: &str, args: &[(&str, Bytes)]) -> Bytes {
+    let mut buf = BytesMut::from(format!("{}\n", command));
+    encode_args(&mut buf, args);
+    buf.freeze()
+}
+
+// Commands that take any arguments, like getbundle, announce how many there are:
+// command\n
+// * count\n
+// (argname len\nvalue)*
+fn encode_star_command(command: &str, args: &[(&str, Bytes)]) -> Bytes {
+    let mut buf = BytesMut::from(format!("{}\n* {}\n", command, args.len()));
+    encode_args(&mut buf, args);
+    buf.freeze()
+}
+
+fn encode_args(buf: &mut BytesMut, args: &[(&str, Bytes)]) {
+    for &(name, ref value) in args {
+        buf.extend_from_slice(format!("{} {}\n", name, value.len()).as_bytes());
+        buf.extend_from_slice(value);
+    }
+}
+
+/// Escapes a value in a list of arguments the way batch does
+fn escape_batch(value: &str) -> String {
+    value
+        .replace(':', ":c")
+        .replace(',', ":o")
+        .replace(';', ":s")
+        .replace('=', ":e")
+}
+
+/// Replies to most commands are framed as "len\nvalue"
+fn decode_framed(output: &SessionOutput) -> Result<Bytes> {
+    let stdout = &output.stdout;
+    let newline = match stdout.iter().position(|b| *b == b'\n') {
+        Some(newline) => newline,
+        None => return Err(server_error(output)),
+    };
+    let len: usize = str::from_utf8(&stdout[..newline])?.parse()?;
+    let start = newline + 1;
+    if stdout.len() < start + len {
+        bail_msg!("reply is truncated: expected {} bytes", len);
+    }
+    Ok(stdout.slice(start, start + len))
+}
+
+fn server_error(output: &SessionOutput) -> Error {
+    format_err!(
+        "server replied with an error: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_command() {
+        let command = encode_command("lookup", &[("key", Bytes::from("master"))]);
+        assert_eq!(command.as_ref(), &b"lookup\nkey 6\nmaster"[..]);
+
+        let command = encode_star_command("getbundle", &[("heads", Bytes::from("abc"))]);
+        assert_eq!(command.as_ref(), &b"getbundle\n* 1\nheads 3\nabc"[..]);
+    }
+
+    #[test]
+    fn test_decode_framed() {
+        let output = SessionOutput {
+            stdout: Bytes::from("5\nhello"),
+            stderr: Bytes::new(),
+        };
+        assert_eq!(decode_framed(&output).unwrap().as_ref(), b"hello");
+
+        let output = SessionOutput {
+            stdout: Bytes::from("10\nhello"),
+            stderr: Bytes::new(),
+        };
+        assert!(decode_framed(&output).is_err());
+
+        let output = SessionOutput {
+            stdout: Bytes::new(),
+            stderr: Bytes::from("remote: Command failed\n"),
+        };
+        let err = decode_framed(&output).expect_err("error expected");
+        assert!(err.to_string().ends_with("remote: Command failed"));
+    }
+}
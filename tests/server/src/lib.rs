@@ -0,0 +1,286 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A full Mononoke server for end-to-end tests, running in the test process.
+//!
+//! `TestServer` serves repos stored in a temporary directory on an ephemeral port, with the same
+//! listener, TLS and wireproto code as the real server. `TestClient` talks to it the way hgcli
+//! does, one session per request. The repo service of the server is served too, and
+//! `TestServer::service_client` creates a client of it.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate openssl;
+#[macro_use]
+extern crate slog;
+extern crate srclient;
+extern crate tempdir;
+extern crate tokio;
+extern crate tokio_io;
+extern crate tokio_openssl;
+extern crate uuid;
+
+extern crate mercurial_types;
+extern crate metaconfig;
+extern crate repo_client;
+extern crate repo_listener;
+extern crate repo_service_thrift;
+extern crate secure_utils;
+extern crate sshrelay;
+
+mod client;
+
+use std::fs;
+use std::net::{self, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Result;
+use futures::Future;
+use slog::{Discard, Drain, Logger};
+use tempdir::TempDir;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+use metaconfig::repoconfig::{RepoConfig, RepoType};
+use repo_client::OpenRepoParams;
+use repo_listener::ProxyProtocol;
+use repo_service_thrift::client::{make_MononokeRepoService, MononokeRepoService};
+use srclient::SRChannelBuilder;
+
+pub use client::{ClientTls, SessionOutput, TestClient};
+
+/// A client of the repo service of a `TestServer`
+pub type ServiceClient = Arc<MononokeRepoService + Send + Sync + 'static>;
+
+const TEST_CERT: &[u8] = include_bytes!("../../integration/testcert.crt");
+const TEST_KEY: &[u8] = include_bytes!("../../integration/testcert.key");
+
+/// The test certificate is issued for this name
+pub const TEST_COMMON_NAME: &str = "localhost";
+
+/// How long `TestServer::start` waits for the repos to be opened
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Paths of the certificate and private key that the server and the clients use. The certificate
+/// is self-signed, so it is also the CA.
+#[derive(Clone, Debug)]
+pub struct TestCerts {
+    pub cert: String,
+    pub private_key: String,
+}
+
+impl TestCerts {
+    fn write(dir: &Path) -> Result<Self> {
+        let cert = dir.join("testcert.crt");
+        let private_key = dir.join("testcert.key");
+        fs::write(&cert, TEST_CERT)?;
+        fs::write(&private_key, TEST_KEY)?;
+        Ok(TestCerts {
+            cert: cert.to_string_lossy().into_owned(),
+            private_key: private_key.to_string_lossy().into_owned(),
+        })
+    }
+}
+
+/// Config of a repo stored in rocksdb at `path`, with everything else at its default
+pub fn test_repo_config(path: PathBuf, repoid: i32) -> RepoConfig {
+    RepoConfig {
+        enabled: true,
+        repotype: RepoType::BlobRocks(path),
+        generation_cache_size: 10 * 1024 * 1024,
+        repoid,
+        scuba_table: None,
+        cache_warmup: None,
+        bookmarks: None,
+        hooks: None,
+        hook_health: Default::default(),
+        pushrebase: Default::default(),
+        push_limits: Default::default(),
+        push_advisory: None,
+        write_forwarding: None,
+        mirroring: None,
+        bookmark_snapshots: None,
+        derived_data: None,
+        strict_wireproto_args: false,
+        deterministic_getbundle: false,
+        file_prefetch: Default::default(),
+        fetch_limits: Default::default(),
+        path_rules: Default::default(),
+        pull_bookmarks: Default::default(),
+        bookmark_creation: Default::default(),
+        scratch_retention: vec![],
+        push_journal: false,
+        cross_repo_index: false,
+        always_hot: false,
+        aliases: vec![],
+        readonly: false,
+        allowed_identities: None,
+        bookmark_move_identities: vec![],
+    }
+}
+
+/// A running server. It stops, and its repos are deleted, when this is dropped.
+pub struct TestServer {
+    // Dropped first, so that the repos are closed before their directory is deleted
+    runtime: Runtime,
+    addr: SocketAddr,
+    service_addr: SocketAddr,
+    certs: TestCerts,
+    dir: TempDir,
+}
+
+impl TestServer {
+    /// Starts a server with a single empty repo called `reponame`
+    pub fn start(reponame: &str) -> Result<Self> {
+        Self::start_with_configs(vec![reponame], |_, _| ())
+    }
+
+    /// Starts a server with an empty repo for each of `reponames`. Their configs are created by
+    /// `test_repo_config` and then passed to `customize`, so that tests can change settings.
+    pub fn start_with_configs<F>(reponames: Vec<&str>, customize: F) -> Result<Self>
+    where
+        F: FnMut(&str, &mut RepoConfig),
+    {
+        Self::start_with_configs_and_logger(
+            reponames,
+            customize,
+            Logger::root(Discard {}.ignore_res(), o!()),
+        )
+    }
+
+    /// Like `start_with_configs`, with the server logging to `logger`
+    pub fn start_with_configs_and_logger<F>(
+        reponames: Vec<&str>,
+        mut customize: F,
+        logger: Logger,
+    ) -> Result<Self>
+    where
+        F: FnMut(&str, &mut RepoConfig),
+    {
+        let dir = TempDir::new("mononoke_test_server")?;
+        let repos: Vec<_> = reponames
+            .into_iter()
+            .enumerate()
+            .map(|(repoid, reponame)| {
+                let mut config = test_repo_config(dir.path().join(reponame), repoid as i32);
+                customize(reponame, &mut config);
+                (reponame.to_string(), config)
+            })
+            .collect();
+        Self::start_with_repos(dir, repos, logger)
+    }
+
+    /// Starts a server with the given repos, which may be stored in `dir`
+    pub fn start_with_repos(
+        dir: TempDir,
+        repos: Vec<(String, RepoConfig)>,
+        logger: Logger,
+    ) -> Result<Self> {
+        let certs = TestCerts::write(dir.path())?;
+        let tls_acceptor = secure_utils::build_tls_acceptor(secure_utils::SslConfig {
+            cert: certs.cert.clone(),
+            private_key: certs.private_key.clone(),
+            ca_pem: certs.cert.clone(),
+        })?;
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse()?)?;
+        let addr = listener.local_addr()?;
+        // The repo service binds its port itself, so it is given one that was free a moment ago
+        let service_addr = net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+        let (listeners, ready) = repo_listener::create_repo_listeners_on(
+            repos,
+            None,
+            OpenRepoParams::default(),
+            &logger,
+            listener,
+            tls_acceptor,
+            ProxyProtocol::Disabled,
+            Some(service_addr),
+        );
+
+        let mut runtime = Runtime::new()?;
+        runtime.spawn(listeners.map_err({
+            let logger = logger.clone();
+            move |err| error!(logger, "test server failed: {}", err)
+        }));
+
+        let start = Instant::now();
+        while !ready.is_ready() {
+            if start.elapsed() > READY_TIMEOUT {
+                bail_msg!("repos were not opened in {:?}", READY_TIMEOUT);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The repo service is started once the repos are open
+        while net::TcpStream::connect(service_addr).is_err() {
+            if start.elapsed() > READY_TIMEOUT {
+                bail_msg!("repo service was not started in {:?}", READY_TIMEOUT);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Ok(TestServer {
+            runtime,
+            addr,
+            service_addr,
+            certs,
+            dir,
+        })
+    }
+
+    /// Address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Address the repo service is listening on
+    pub fn service_addr(&self) -> SocketAddr {
+        self.service_addr
+    }
+
+    pub fn certs(&self) -> &TestCerts {
+        &self.certs
+    }
+
+    /// Directory that the repos are stored in
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// A client of the repo `reponame` of this server
+    pub fn client(&self, reponame: &str) -> Result<TestClient> {
+        TestClient::new(self.addr, reponame, &self.certs)
+    }
+
+    /// A client of the repo service of this server
+    pub fn service_client(&self) -> Result<ServiceClient> {
+        SRChannelBuilder::from_host_port(
+            &self.service_addr.ip().to_string(),
+            self.service_addr.port() as i32,
+        ).and_then(|builder| builder.build_client(make_MononokeRepoService))
+            .map_err(|err| format_err!("cannot create a repo service client: {}", err))
+    }
+
+    /// Runs `future`, e.g. a request of a `TestClient`, to completion on the runtime of the
+    /// server
+    pub fn block_on<F>(&mut self, future: F) -> ::std::result::Result<F::Item, F::Error>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send + 'static,
+        F::Error: Send + 'static,
+    {
+        self.runtime.block_on(future)
+    }
+}
@@ -0,0 +1,725 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bookmarks;
+extern crate bundle2_resolver;
+extern crate bytes;
+extern crate cross_repo_index;
+extern crate fixtures;
+extern crate futures;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+extern crate metaconfig;
+extern crate mononoke_test_server;
+extern crate repo_client;
+extern crate repo_service_thrift;
+#[macro_use]
+extern crate slog;
+
+use std::net::{SocketAddr, TcpListener};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use slog::{Discard, Drain, Logger, Never, OwnedKVList, Record};
+
+use bookmarks::Bookmark;
+use bundle2_resolver::create_full_bundle;
+use cross_repo_index::CrossRepoIndex;
+use fixtures::many_files_dirs;
+use mercurial_bundles::create_bundle_stream;
+use mercurial_types::{HgChangesetId, HgManifestId, HgNodeHash, RepositoryId, NULL_CSID};
+use metaconfig::{MirroringParams, WriteForwardingParams};
+use metaconfig::repoconfig::{RepoAlias, RepoType};
+use mononoke_test_server::{ServiceClient, TestCerts, TestClient, TestServer, TEST_COMMON_NAME};
+use repo_client::{open_cross_repo_index, open_push_journal};
+use repo_service_thrift::types::{ChangesetInfoParams, DirectoryEntry, DirectoryEntryType,
+                                 ListDirectoryParams, ReadFileParams, RepoRequest,
+                                 ResolveBookmarkParams};
+
+// A bundle2 that adds a file "a" with content "a\n" in a single commit and points the bookmark
+// "master" to it. It has a treegroup2 part, so it can be pushed to a treemanifest repo.
+const PUSH_ONE_COMMIT: &[u8] = include_bytes!("../fixtures/push_one_commit.bundle");
+const PUSHED_COMMIT: &str = "1f0dee641bb7258c56bd60e93edfa2405381c41e";
+// Root manifest of PUSHED_COMMIT
+const PUSHED_ROOT: &str = "a0c8bcbbb45c63b90b70ad007bf38961f64f2af0";
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Keeps the messages that a server logs, so that tests can check what it did in the background
+#[derive(Clone, Default)]
+struct CapturedLogs {
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl Drain for CapturedLogs {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+        self.messages
+            .lock()
+            .expect("lock poisoned")
+            .push(format!("{}", record.msg()));
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn logger(&self) -> Logger {
+        Logger::root(self.clone(), o!())
+    }
+
+    fn count(&self, needle: &str) -> usize {
+        self.messages
+            .lock()
+            .expect("lock poisoned")
+            .iter()
+            .filter(|message| message.contains(needle))
+            .count()
+    }
+
+    /// Waits until `count` messages that contain `needle` were logged
+    fn wait_for(&self, needle: &str, count: usize) {
+        let start = Instant::now();
+        while self.count(needle) < count {
+            assert!(
+                start.elapsed() < Duration::from_secs(60),
+                "{:?} was not logged",
+                needle
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Starts a server with a repo that mirrors `sample_rate` of its reads to `shadow`. The server
+/// authenticates to the shadow with `certs`, e.g. the ones of the shadow itself.
+fn start_mirrored(
+    shadow: SocketAddr,
+    certs: &TestCerts,
+    sample_rate: f64,
+    logs: &CapturedLogs,
+) -> TestServer {
+    let params = MirroringParams {
+        shadow: shadow.to_string(),
+        shadow_reponame: "repo".to_string(),
+        ssl_common_name: TEST_COMMON_NAME.to_string(),
+        cert: certs.cert.clone(),
+        private_key: certs.private_key.clone(),
+        ca_pem: certs.cert.clone(),
+        sample_rate,
+        compare: true,
+        timeout: Duration::from_secs(60),
+        max_request_bytes: 1024 * 1024,
+        max_compared_bytes: 1024 * 1024,
+    };
+    TestServer::start_with_configs_and_logger(
+        vec!["repo"],
+        |_, config| config.mirroring = Some(params.clone()),
+        logs.logger(),
+    ).expect("failed to start the server")
+}
+
+#[test]
+fn test_hello() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+
+    let caps = server.block_on(client.hello()).expect("hello failed");
+    assert!(contains(&caps, b"capabilities:"), "{:?}", caps);
+    assert!(contains(&caps, b"unbundle"), "{:?}", caps);
+    assert!(contains(&caps, b"designatednodes"), "{:?}", caps);
+    assert!(contains(&caps, b"knowntrees="), "{:?}", caps);
+}
+
+#[test]
+fn test_empty_repo() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+
+    let heads = server.block_on(client.heads()).expect("heads failed");
+    assert!(heads.is_empty() || heads == vec![NULL_CSID], "{:?}", heads);
+    assert!(server.block_on(client.lookup("master")).is_err());
+}
+
+#[test]
+fn test_push_then_pull() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    let pushed = HgChangesetId::from_str(PUSHED_COMMIT).unwrap();
+
+    let reply = server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+    assert!(reply.starts_with(b"HG20"), "{:?}", reply);
+
+    let master = server.block_on(client.lookup("master")).expect("lookup failed");
+    assert_eq!(master, pushed);
+
+    let heads = server.block_on(client.heads()).expect("heads failed");
+    assert_eq!(heads, vec![pushed]);
+
+    // A fresh client, e.g. one that clones the repo, gets the commit and the bookmark
+    let client = server.client("repo").expect("failed to create a client");
+    let bundle = server
+        .block_on(client.getbundle(&[pushed], &[NULL_CSID], &["bookmarks"]))
+        .expect("getbundle failed");
+    assert!(bundle.starts_with(b"HG20"), "{:?}", bundle);
+    assert!(contains(&bundle, pushed.as_nodehash().as_bytes()));
+    assert!(contains(&bundle, b"master"));
+}
+
+#[test]
+fn test_knowntrees() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    let root = HgManifestId::new(HgNodeHash::from_str(PUSHED_ROOT).unwrap());
+    let unknown = HgManifestId::new(HgNodeHash::from_str(&"1".repeat(40)).unwrap());
+
+    server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+
+    let known = server
+        .block_on(client.knowntrees("master", &[("", root), ("", unknown), ("dir", root)]))
+        .expect("knowntrees failed");
+    assert_eq!(known, vec![true, false, false]);
+
+    let known = server
+        .block_on(client.knowntrees("nope", &[("", root)]))
+        .expect("knowntrees failed");
+    assert_eq!(known, vec![false]);
+
+    let too_many = vec![("", root); 1001];
+    assert!(server.block_on(client.knowntrees("master", &too_many)).is_err());
+}
+
+#[test]
+fn test_push_full_bundle() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    // Head of many_files_dirs, which replaced a directory with a file
+    let head = HgChangesetId::from_str("0c59c8d0da93cbf9d7f4b888f28823ffb2e3e480").unwrap();
+
+    // A full bundle of a repo pushed to an empty repo recreates it
+    let bundle = server
+        .block_on(future::lazy(move || {
+            let source = many_files_dirs::getrepo(None);
+            let bookmarks = vec![(Bookmark::new("master").unwrap(), head)];
+            let logger = Logger::root(Discard, o!());
+            create_full_bundle(source, vec![NULL_CSID], vec![head], bookmarks, logger)
+                .and_then(|bundle| create_bundle_stream(bundle.parts, None).concat2())
+        }))
+        .expect("failed to create the bundle");
+    let reply = server
+        .block_on(client.unbundle(bundle))
+        .expect("push failed");
+    assert!(reply.starts_with(b"HG20"), "{:?}", reply);
+
+    let heads = server.block_on(client.heads()).expect("heads failed");
+    assert_eq!(heads, vec![head]);
+    let master = server.block_on(client.lookup("master")).expect("lookup failed");
+    assert_eq!(master, head);
+}
+
+#[test]
+fn test_push_journal() {
+    let mut server = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.push_journal = true
+    }).expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    let pushed = HgChangesetId::from_str(PUSHED_COMMIT).unwrap();
+    let repo_id = RepositoryId::new(0);
+
+    server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+
+    let journal = open_push_journal(&RepoType::BlobRocks(server.path().join("repo")))
+        .expect("failed to open the push journal");
+    let entries = journal.list(repo_id, false).wait().expect("listing failed");
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert!(entries[0].is_complete());
+    assert_eq!(entries[0].changesets, vec![pushed]);
+
+    // The second push uploads its blobs again, and then fails to create the bookmark that
+    // already exists, like a push that was abandoned half way
+    let client = server.client("repo").expect("failed to create a client");
+    assert!(
+        server
+            .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+            .is_err()
+    );
+
+    let entries = journal.list(repo_id, true).wait().expect("listing failed");
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert!(!entries[0].is_complete());
+    assert_eq!(entries[0].changesets, vec![pushed]);
+    assert_eq!(journal.list(repo_id, false).wait().unwrap().len(), 2);
+}
+
+#[test]
+fn test_cross_repo_index() {
+    let mut server =
+        TestServer::start_with_configs(vec!["repo", "mirror", "other"], |name, config| {
+            config.cross_repo_index = name != "other"
+        }).expect("failed to start the server");
+    let pushed = HgChangesetId::from_str(PUSHED_COMMIT).unwrap();
+
+    for reponame in vec!["repo", "mirror", "other"] {
+        let client = server.client(reponame).expect("failed to create a client");
+        server
+            .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+            .expect("push failed");
+    }
+
+    // The repos share the index, and only the ones that opted in are recorded in it
+    let index = open_cross_repo_index(&RepoType::BlobRocks(server.path().join("repo")))
+        .expect("failed to open the cross-repo index");
+    let repos = index.find(pushed).wait().expect("finding failed");
+    assert_eq!(repos, vec![RepositoryId::new(0), RepositoryId::new(1)]);
+}
+
+#[test]
+fn test_repo_alias() {
+    let notice = "repo old was renamed to repo";
+    let mut server = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.aliases = vec![
+            RepoAlias {
+                name: "old".to_string(),
+                deprecation_notice: Some(notice.to_string()),
+            },
+            RepoAlias {
+                name: "other".to_string(),
+                deprecation_notice: None,
+            },
+        ];
+    }).expect("failed to start the server");
+    let clients: Vec<_> = vec!["repo", "old", "other"]
+        .into_iter()
+        .map(|reponame| server.client(reponame).expect("failed to create a client"))
+        .collect();
+    let pushed = HgChangesetId::from_str(PUSHED_COMMIT).unwrap();
+
+    // A push to an alias is a push to the repo
+    server
+        .block_on(clients[1].unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+
+    let mut caps = vec![];
+    for (reponame, client) in vec!["repo", "old", "other"].into_iter().zip(&clients) {
+        let heads = server.block_on(client.heads()).expect("heads failed");
+        assert_eq!(heads, vec![pushed], "{}", reponame);
+
+        // Only clients that use a deprecated alias are told so
+        let output = server
+            .block_on(client.request(Bytes::from("hello\n")))
+            .expect("hello failed");
+        assert_eq!(
+            contains(&output.stderr, notice.as_bytes()),
+            reponame == "old",
+            "{}: {:?}",
+            reponame,
+            output.stderr
+        );
+        caps.push(output.stdout);
+    }
+    assert!(caps.iter().all(|c| *c == caps[0]), "{:?}", caps);
+}
+
+#[test]
+fn test_readonly_repo() {
+    let mut server = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.readonly = true;
+    }).expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+
+    let caps = server.block_on(client.hello()).expect("hello failed");
+    assert!(contains(&caps, b"capabilities:"), "{:?}", caps);
+
+    assert!(
+        server
+            .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+            .is_err()
+    );
+    let heads = server.block_on(client.heads()).expect("heads failed");
+    assert!(heads.is_empty() || heads == vec![NULL_CSID], "{:?}", heads);
+}
+
+#[test]
+fn test_unknown_repo() {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("other").expect("failed to create a client");
+
+    assert!(server.block_on(client.hello()).is_err());
+}
+
+#[test]
+fn test_allowed_identities() {
+    let mut server = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.allowed_identities = Some(vec!["alice".to_string()]);
+    }).expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+
+    let caps = server
+        .block_on(client.clone().as_user("alice").hello())
+        .expect("hello failed");
+    assert!(contains(&caps, b"capabilities:"), "{:?}", caps);
+    assert!(server.block_on(client.clone().as_user("bob").hello()).is_err());
+    assert!(server.block_on(client.hello()).is_err());
+}
+
+/// Starts a server with PUSHED_COMMIT in its repo "repo", whose bookmarks "alice" can move in two
+/// phases
+fn start_with_bookmark_mover() -> (TestServer, TestClient) {
+    let mut server = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.bookmark_move_identities = vec!["alice".to_string()];
+    }).expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+    (server, client)
+}
+
+#[test]
+fn test_bookmark_move_denied() {
+    let (mut server, client) = start_with_bookmark_mover();
+    let pushed = HgChangesetId::from_str(PUSHED_COMMIT).unwrap();
+
+    for client in vec![client.clone().as_user("bob"), client.clone()] {
+        let reply = server
+            .block_on(client.preparebookmarkmove("other", None, pushed, 60))
+            .expect("preparebookmarkmove failed");
+        assert!(reply.starts_with("0 "), "{}", reply);
+        assert!(reply.contains("not allowed"), "{}", reply);
+    }
+
+    // Nothing was recorded for the denied clients, so the bookmark isn't blocked
+    let reply = server
+        .block_on(client.as_user("alice").preparebookmarkmove("other", None, pushed, 60))
+        .expect("preparebookmarkmove failed");
+    assert!(reply.starts_with("1 "), "{}", reply);
+}
+
+#[test]
+fn test_bookmark_move_allowed() {
+    let (mut server, client) = start_with_bookmark_mover();
+    let alice = client.clone().as_user("alice");
+    let pushed = HgChangesetId::from_str(PUSHED_COMMIT).unwrap();
+
+    let reply = server
+        .block_on(alice.preparebookmarkmove("other", None, pushed, 60))
+        .expect("preparebookmarkmove failed");
+    assert!(reply.starts_with("1 "), "{}", reply);
+    let token = &reply[2..];
+
+    // The move is bound to alice, even if another client learns its token
+    let reply = server
+        .block_on(client.as_user("bob").commitbookmarkmove(token))
+        .expect("commitbookmarkmove failed");
+    assert!(reply.starts_with("0 "), "{}", reply);
+
+    let reply = server
+        .block_on(alice.commitbookmarkmove(token))
+        .expect("commitbookmarkmove failed");
+    assert_eq!(reply, "1 committed");
+    let other = server.block_on(alice.lookup("other")).expect("lookup failed");
+    assert_eq!(other, pushed);
+}
+
+fn repo_request(repo: &str, user: Option<&str>) -> RepoRequest {
+    RepoRequest {
+        repo: repo.to_string(),
+        user: user.map(|user| user.to_string()),
+    }
+}
+
+/// Starts a server with PUSHED_COMMIT in its repo "repo", and returns it with a client of its
+/// repo service
+fn start_with_pushed_commit() -> (TestServer, ServiceClient) {
+    let mut server = TestServer::start("repo").expect("failed to start the server");
+    let client = server.client("repo").expect("failed to create a client");
+    server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+    let service = server
+        .service_client()
+        .expect("failed to create a service client");
+    (server, service)
+}
+
+#[test]
+fn test_service_resolve_bookmark() {
+    let (mut server, service) = start_with_pushed_commit();
+    let resolve = |bookmark: &str| ResolveBookmarkParams {
+        request: repo_request("repo", None),
+        bookmark: bookmark.to_string(),
+    };
+
+    let master = server
+        .block_on(service.resolve_bookmark(&resolve("master")))
+        .expect("resolve_bookmark failed");
+    assert_eq!(master, PUSHED_COMMIT);
+
+    let err = server
+        .block_on(service.resolve_bookmark(&resolve("nope")))
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("NotFound"), "{:?}", err);
+
+    let err = server
+        .block_on(service.resolve_bookmark(&ResolveBookmarkParams {
+            request: repo_request("other", None),
+            bookmark: "master".to_string(),
+        }))
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("NotFound"), "{:?}", err);
+}
+
+#[test]
+fn test_service_changeset_info() {
+    let (mut server, service) = start_with_pushed_commit();
+    let info = |changeset: &str| ChangesetInfoParams {
+        request: repo_request("repo", None),
+        changeset: changeset.to_string(),
+    };
+
+    let pushed = server
+        .block_on(service.changeset_info(&info(PUSHED_COMMIT)))
+        .expect("changeset_info failed");
+    assert_eq!(pushed.changeset, PUSHED_COMMIT);
+    assert!(pushed.parents.is_empty(), "{:?}", pushed.parents);
+    assert_eq!(pushed.changed_files_count, 1);
+
+    let err = server
+        .block_on(service.changeset_info(&info(&"1".repeat(40))))
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("NotFound"), "{:?}", err);
+
+    let err = server
+        .block_on(service.changeset_info(&info("master")))
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("InvalidInput"), "{:?}", err);
+}
+
+#[test]
+fn test_service_read_file() {
+    let (mut server, service) = start_with_pushed_commit();
+    let read = |path: &str, max_size: Option<i64>| ReadFileParams {
+        request: repo_request("repo", None),
+        changeset: PUSHED_COMMIT.to_string(),
+        path: path.as_bytes().to_vec(),
+        max_size,
+    };
+
+    let content = server
+        .block_on(service.read_file(&read("a", None)))
+        .expect("read_file failed");
+    assert_eq!(content, b"a\n".to_vec());
+
+    let content = server
+        .block_on(service.read_file(&read("a", Some(2))))
+        .expect("read_file failed");
+    assert_eq!(content, b"a\n".to_vec());
+
+    let err = server
+        .block_on(service.read_file(&read("a", Some(1))))
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("TooLarge"), "{:?}", err);
+
+    let err = server
+        .block_on(service.read_file(&read("b", None)))
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("NotFound"), "{:?}", err);
+}
+
+#[test]
+fn test_service_list_directory() {
+    let (mut server, service) = start_with_pushed_commit();
+    let list = |path: &str| ListDirectoryParams {
+        request: repo_request("repo", None),
+        changeset: PUSHED_COMMIT.to_string(),
+        path: path.as_bytes().to_vec(),
+    };
+
+    let entries = server
+        .block_on(service.list_directory(&list("")))
+        .expect("list_directory failed");
+    assert_eq!(
+        entries,
+        vec![
+            DirectoryEntry {
+                name: b"a".to_vec(),
+                type_: DirectoryEntryType::File,
+            },
+        ]
+    );
+
+    let err = server
+        .block_on(service.list_directory(&list("a")))
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("InvalidInput"), "{:?}", err);
+}
+
+#[test]
+fn test_service_allowed_identities() {
+    let mut server = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.allowed_identities = Some(vec!["alice".to_string()]);
+    }).expect("failed to start the server");
+    let client = server
+        .client("repo")
+        .expect("failed to create a client")
+        .as_user("alice");
+    server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+    let service = server
+        .service_client()
+        .expect("failed to create a service client");
+    let resolve = |user: Option<&str>| ResolveBookmarkParams {
+        request: repo_request("repo", user),
+        bookmark: "master".to_string(),
+    };
+
+    let master = server
+        .block_on(service.resolve_bookmark(&resolve(Some("alice"))))
+        .expect("resolve_bookmark failed");
+    assert_eq!(master, PUSHED_COMMIT);
+
+    for user in vec![Some("bob"), None] {
+        let err = server
+            .block_on(service.resolve_bookmark(&resolve(user)))
+            .unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("PermissionDenied"),
+            "{:?}: {:?}",
+            user,
+            err
+        );
+    }
+}
+
+#[test]
+fn test_mirroring_same_response() {
+    let shadow = TestServer::start("repo").expect("failed to start the shadow");
+    let logs = CapturedLogs::default();
+    let mut server = start_mirrored(shadow.addr(), shadow.certs(), 1.0, &logs);
+    let client = server.client("repo").expect("failed to create a client");
+
+    let heads = server.block_on(client.heads()).expect("heads failed");
+    assert!(heads.is_empty() || heads == vec![NULL_CSID], "{:?}", heads);
+    server.block_on(client.hello()).expect("hello failed");
+
+    logs.wait_for("same response", 2);
+    assert_eq!(logs.count("different response"), 0);
+}
+
+#[test]
+fn test_mirroring_sample_rate() {
+    let shadow = TestServer::start("repo").expect("failed to start the shadow");
+    let logs = CapturedLogs::default();
+    let mut server = start_mirrored(shadow.addr(), shadow.certs(), 0.0, &logs);
+    let client = server.client("repo").expect("failed to create a client");
+
+    for _ in 0..10 {
+        server.block_on(client.heads()).expect("heads failed");
+    }
+    // Replays are logged shortly after the responses, give them some time to show up
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(logs.count("session replayed"), 0);
+}
+
+#[test]
+fn test_mirroring_mismatch() {
+    let mut shadow = TestServer::start("repo").expect("failed to start the shadow");
+    let logs = CapturedLogs::default();
+    let mut server = start_mirrored(shadow.addr(), shadow.certs(), 1.0, &logs);
+    let client = server.client("repo").expect("failed to create a client");
+    let pushed = HgChangesetId::from_str(PUSHED_COMMIT).unwrap();
+
+    // The push is a write, so it only reaches the primary, which then has different heads
+    server
+        .block_on(client.unbundle(Bytes::from(PUSH_ONE_COMMIT)))
+        .expect("push failed");
+    let heads = server.block_on(client.heads()).expect("heads failed");
+    assert_eq!(heads, vec![pushed]);
+
+    logs.wait_for("different response", 1);
+    assert_eq!(logs.count("session replayed"), 1);
+
+    let shadow_client = shadow.client("repo").expect("failed to create a client");
+    let heads = shadow.block_on(shadow_client.heads()).expect("heads failed");
+    assert!(heads.is_empty() || heads == vec![NULL_CSID], "{:?}", heads);
+}
+
+#[test]
+fn test_mirroring_does_not_block() {
+    // A shadow that accepts connections, but never answers
+    let shadow = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+    // Only started for its certificates
+    let other = TestServer::start("repo").expect("failed to start a server");
+    let logs = CapturedLogs::default();
+    let mut server = start_mirrored(
+        shadow.local_addr().unwrap(),
+        other.certs(),
+        1.0,
+        &logs,
+    );
+    let client = server.client("repo").expect("failed to create a client");
+
+    // The replays wait for the shadow until they time out after a minute, the clients don't
+    let start = Instant::now();
+    for _ in 0..3 {
+        server.block_on(client.heads()).expect("heads failed");
+    }
+    assert!(start.elapsed() < Duration::from_secs(30), "{:?}", start.elapsed());
+    assert_eq!(logs.count("session replayed"), 0);
+}
+
+#[test]
+fn test_write_forwarding_keeps_identity() {
+    let mut primary = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.allowed_identities = Some(vec!["alice".to_string()]);
+    }).expect("failed to start the primary");
+    let params = WriteForwardingParams {
+        primary: primary.addr().to_string(),
+        primary_reponame: "repo".to_string(),
+        ssl_common_name: TEST_COMMON_NAME.to_string(),
+        cert: primary.certs().cert.clone(),
+        private_key: primary.certs().private_key.clone(),
+        ca_pem: primary.certs().cert.clone(),
+        timeout: Duration::from_secs(60),
+        breaker_failures: 5,
+        breaker_cooldown: Duration::from_secs(1),
+    };
+    let mut secondary = TestServer::start_with_configs(vec!["repo"], |_, config| {
+        config.write_forwarding = Some(params.clone());
+    }).expect("failed to start the secondary");
+    let client = secondary.client("repo").expect("failed to create a client");
+
+    // The primary sees the user of the client, not the secondary, and only lets alice in
+    let push = |user: &str| client.clone().as_user(user).unbundle(Bytes::from(PUSH_ONE_COMMIT));
+    assert!(secondary.block_on(push("bob")).is_err());
+    let reply = secondary.block_on(push("alice")).expect("push failed");
+    assert!(reply.starts_with(b"HG20"), "{:?}", reply);
+
+    let primary_client = primary
+        .client("repo")
+        .expect("failed to create a client")
+        .as_user("alice");
+    let heads = primary.block_on(primary_client.heads()).expect("heads failed");
+    assert_eq!(heads, vec![HgChangesetId::from_str(PUSHED_COMMIT).unwrap()]);
+}
@@ -7,11 +7,11 @@
 use std::fmt;
 use std::sync::Arc;
 
-use futures::{stream, Future, Stream};
+use futures::{Future, Stream};
 
 use mercurial_types::{Entry, Manifest, Type};
 use mercurial_types::manifest::Content;
-use mercurial_types::manifest_utils::recursive_entry_stream;
+use mercurial_types::manifest_utils::{walk_manifest, PathFilter};
 use mononoke_types::path::{MPath, MPathElement, DOT, DOTDOT};
 
 use node::{VfsDir, VfsFile, VfsNode};
@@ -31,22 +31,15 @@ pub fn vfs_from_manifest<M>(
 where
     M: Manifest,
 {
-    let entry_streams = manifest
-        .list()
-        .map(|entry| recursive_entry_stream(None, entry));
-    stream::iter_ok::<_, Error>(entry_streams)
-        .flatten()
+    walk_manifest(manifest, None, PathFilter::all())
         .filter(|pathentry| pathentry.1.get_type() != Type::Tree)
         .collect()
         .and_then(|pathentries| {
             let mut path_tree = Tree::new();
             let mut entries = vec![];
             for (entry_idx, (path, entry)) in pathentries.into_iter().enumerate() {
-                let name = entry.get_name().cloned();
-                let name = name.ok_or_else(|| {
-                    ErrorKind::ManifestInvalidPath("name shouldn't be empty".into())
-                })?;
-                path_tree.insert(MPath::into_iter_opt(path), name, TEntryId(entry_idx))?;
+                let (dirname, name) = path.split_dirname();
+                path_tree.insert(MPath::into_iter_opt(dirname), name.clone(), TEntryId(entry_idx))?;
                 entries.push(entry);
             }
             Ok(ManifestVfsDir {